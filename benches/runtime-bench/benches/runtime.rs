@@ -0,0 +1,129 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The criterion benchmarks of the hot paths of the runtime (see
+//! [`runtime_bench`]).
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use datafusion::physical_plan::expressions::Column;
+use datafusion::physical_plan::Partitioning;
+use flock::prelude::*;
+use flock::runtime::context::{marshal, unmarshal};
+use runtime_bench::workloads::*;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+
+/// The number of output partitions of the repartition.
+const PARTITIONS: usize = 8;
+
+/// The target batch size of the coalesce, as the functions use.
+const TARGET_BATCH_SIZE: usize = 4096;
+
+fn coalesce_batches_benchmark(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let input = tiny_batches();
+    c.bench_function(
+        &format!("coalesce_batches/{}x{}", TINY_BATCHES, TINY_BATCH_ROWS),
+        |b| {
+            b.iter_batched(
+                || vec![input.clone()],
+                |input| {
+                    rt.block_on(coalesce_batches(input, TARGET_BATCH_SIZE))
+                        .unwrap()
+                },
+                BatchSize::LargeInput,
+            )
+        },
+    );
+}
+
+fn repartition_benchmark(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let input = batches(PAYLOAD_ROWS, BATCH_ROWS);
+    let mut group = c.benchmark_group("repartition");
+    let schemes = [
+        ("round_robin", Partitioning::RoundRobinBatch(PARTITIONS)),
+        (
+            "hash",
+            Partitioning::Hash(vec![Arc::new(Column::new("c1", 0))], PARTITIONS),
+        ),
+    ];
+    for (name, partitioning) in schemes {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || (vec![input.clone()], partitioning.clone()),
+                |(input, partitioning)| rt.block_on(repartition(input, partitioning)).unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn payload_benchmark(c: &mut Criterion) {
+    let input = batches(PAYLOAD_ROWS, BATCH_ROWS);
+    let mut group = c.benchmark_group("payload");
+    group.sample_size(10);
+    for encoding in PAYLOAD_ENCODINGS {
+        let uuid = UuidBuilder::new_with_ts("bench-00", 1, 1).next_uuid();
+        group.bench_function(format!("{:?}", encoding), |b| {
+            b.iter(|| {
+                let payload = to_payload_with_encoding(
+                    &input,
+                    &[],
+                    uuid.clone(),
+                    false,
+                    &[],
+                    encoding.clone(),
+                )
+                .unwrap();
+                payload.to_record_batch().unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn feed_data_sources_benchmark(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let (mut ctx, sources) = rt.block_on(join_context(JOIN_LEAVES, BATCH_ROWS)).unwrap();
+    c.bench_function(&format!("feed_data_sources/{}_leaves", JOIN_LEAVES), |b| {
+        b.iter_batched(
+            || sources.clone(),
+            |sources| rt.block_on(ctx.feed_data_sources(sources)).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn context_benchmark(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let ctx = rt.block_on(nexmark_q4_context()).unwrap();
+    let encoded = marshal(&ctx, Encoding::Zstd).unwrap();
+    let mut group = c.benchmark_group("context");
+    group.bench_function("marshal/q4", |b| {
+        b.iter(|| marshal(&ctx, Encoding::Zstd).unwrap())
+    });
+    group.bench_function("unmarshal/q4", |b| b.iter(|| unmarshal(&encoded).unwrap()));
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    coalesce_batches_benchmark,
+    repartition_benchmark,
+    payload_benchmark,
+    feed_data_sources_benchmark,
+    context_benchmark
+);
+criterion_main!(benches);
@@ -0,0 +1,355 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The baseline of the microbenchmarks and the regression check against it.
+//!
+//! criterion keeps the estimates of the last run of each benchmark under its
+//! output directory, i.e. `<target>/criterion/<benchmark>/new/`, where
+//! `benchmark.json` names the benchmark and `estimates.json` holds the
+//! statistics of its measurements. [`Measurements::from_criterion`] collects
+//! the mean time of every benchmark from there, and the baseline is the same
+//! measurements of a reference run written to a JSON file, e.g.
+//!
+//! ```json
+//! {
+//!   "benchmarks": {
+//!     "coalesce_batches/10000x4": 1520431.7,
+//!     "repartition/round_robin": 880212.3
+//!   }
+//! }
+//! ```
+//!
+//! A benchmark regresses if its mean time exceeds the baseline's by more than
+//! the threshold (see [`compare`]). The benchmarks that are new or missing in
+//! the run are reported, but don't fail the check.
+
+use flock::error::{FlockError, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+/// The directory of the last run of a benchmark in the criterion output.
+const CRITERION_RUN_DIR: &str = "new";
+
+/// The mean time of the benchmarks in nanoseconds by their criterion ids.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Measurements {
+    /// The mean time of each benchmark in nanoseconds.
+    pub benchmarks: BTreeMap<String, f64>,
+}
+
+impl Measurements {
+    /// Reads the measurements from the JSON file of a baseline.
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// Writes the measurements to the JSON file of a baseline.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Collects the last measurements of the benchmarks whose ids contain the
+    /// filter, or of all benchmarks without a filter, from the criterion
+    /// output directory.
+    pub fn from_criterion(dir: &Path, filter: Option<&str>) -> Result<Self> {
+        let mut measurements = Measurements::default();
+        if !dir.is_dir() {
+            return Err(FlockError::Internal(format!(
+                "No criterion output in {}",
+                dir.display()
+            )));
+        }
+        collect_estimates(dir, &mut measurements.benchmarks)?;
+        if let Some(filter) = filter {
+            measurements.benchmarks.retain(|id, _| id.contains(filter));
+        }
+        Ok(measurements)
+    }
+
+    /// Updates the baseline with the measurements of a run. The benchmarks
+    /// that didn't run keep their baseline.
+    pub fn update(&mut self, run: &Measurements) {
+        self.benchmarks
+            .extend(run.benchmarks.iter().map(|(id, mean)| (id.clone(), *mean)));
+    }
+}
+
+/// Reads the estimates of the benchmarks under the directory recursively, as
+/// criterion nests the benchmarks of a group and their parameters.
+fn collect_estimates(dir: &Path, benchmarks: &mut BTreeMap<String, f64>) -> Result<()> {
+    let run = dir.join(CRITERION_RUN_DIR);
+    let (benchmark, estimates) = (run.join("benchmark.json"), run.join("estimates.json"));
+    if benchmark.is_file() && estimates.is_file() {
+        let benchmark: Value = serde_json::from_slice(&fs::read(&benchmark)?)?;
+        let estimates: Value = serde_json::from_slice(&fs::read(&estimates)?)?;
+        let id = benchmark["full_id"]
+            .as_str()
+            .ok_or_else(|| FlockError::Internal(format!("No benchmark id in {}", run.display())))?;
+        let mean = estimates["mean"]["point_estimate"]
+            .as_f64()
+            .ok_or_else(|| {
+                FlockError::Internal(format!("No mean estimate in {}", run.display()))
+            })?;
+        benchmarks.insert(id.to_string(), mean);
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        // The former runs and the reports aren't benchmarks.
+        if path.is_dir() && !path.ends_with(CRITERION_RUN_DIR) && !path.ends_with("base") {
+            collect_estimates(&path, benchmarks)?;
+        }
+    }
+    Ok(())
+}
+
+/// The comparison of a benchmark with its baseline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchmarkDiff {
+    /// The criterion id of the benchmark.
+    pub id:       String,
+    /// The mean time in the baseline, or `None` if the benchmark is new.
+    pub baseline: Option<f64>,
+    /// The mean time in the run, or `None` if the benchmark didn't run.
+    pub current:  Option<f64>,
+}
+
+impl BenchmarkDiff {
+    /// Returns the change of the mean time relative to the baseline, e.g. 0.2
+    /// if the benchmark is 20% slower, or `None` if either side is missing.
+    pub fn change(&self) -> Option<f64> {
+        match (self.baseline, self.current) {
+            (Some(baseline), Some(current)) if baseline > 0.0 => Some(current / baseline - 1.0),
+            _ => None,
+        }
+    }
+}
+
+/// The comparison of a run with the baseline.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BenchmarkReport {
+    /// The allowed slowdown relative to the baseline, e.g. 0.1 for 10%.
+    pub threshold:  f64,
+    /// The comparison of each benchmark of the baseline or the run, by id.
+    pub benchmarks: Vec<BenchmarkDiff>,
+}
+
+impl BenchmarkReport {
+    /// Returns the benchmarks slower than the baseline beyond the threshold.
+    pub fn regressions(&self) -> impl Iterator<Item = &BenchmarkDiff> {
+        self.benchmarks
+            .iter()
+            .filter(|b| b.change().map_or(false, |change| change > self.threshold))
+    }
+
+    /// Returns true if no benchmark regressed.
+    pub fn is_ok(&self) -> bool {
+        self.regressions().next().is_none()
+    }
+
+    /// Renders the report with a line per benchmark.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        for b in &self.benchmarks {
+            let time = |t: Option<f64>| t.map_or_else(|| "-".to_string(), format_nanos);
+            let verdict = match b.change() {
+                Some(change) if change > self.threshold => "REGRESSED",
+                Some(change) if change < -self.threshold => "improved",
+                Some(_) => "ok",
+                None if b.baseline.is_none() => "new",
+                None => "missing",
+            };
+            let change = b
+                .change()
+                .map_or_else(String::new, |c| format!(" ({:+.1}%)", c * 100.0));
+            output.push_str(&format!(
+                "{:<48} {:>12} -> {:>12}{} {}\n",
+                b.id,
+                time(b.baseline),
+                time(b.current),
+                change,
+                verdict
+            ));
+        }
+        let regressions = self.regressions().count();
+        output.push_str(&format!(
+            "{} benchmarks, {} regressed beyond {:.1}%\n",
+            self.benchmarks.len(),
+            regressions,
+            self.threshold * 100.0
+        ));
+        output
+    }
+}
+
+/// Formats the time in nanoseconds with the largest fitting unit.
+fn format_nanos(nanos: f64) -> String {
+    match nanos {
+        n if n >= 1e9 => format!("{:.2} s", n / 1e9),
+        n if n >= 1e6 => format!("{:.2} ms", n / 1e6),
+        n if n >= 1e3 => format!("{:.2} us", n / 1e3),
+        n => format!("{:.2} ns", n),
+    }
+}
+
+/// Compares the measurements of a run with the baseline.
+///
+/// # Arguments
+/// * `baseline` - The measurements of the reference run.
+/// * `current` - The measurements of the run.
+/// * `threshold` - The allowed slowdown relative to the baseline, e.g. 0.1 for
+///   10%.
+pub fn compare(baseline: &Measurements, current: &Measurements, threshold: f64) -> BenchmarkReport {
+    let ids = baseline
+        .benchmarks
+        .keys()
+        .chain(current.benchmarks.keys())
+        .collect::<BTreeSet<_>>();
+    BenchmarkReport {
+        threshold,
+        benchmarks: ids
+            .into_iter()
+            .map(|id| BenchmarkDiff {
+                id:       id.clone(),
+                baseline: baseline.benchmarks.get(id).copied(),
+                current:  current.benchmarks.get(id).copied(),
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn measurements(benchmarks: &[(&str, f64)]) -> Measurements {
+        Measurements {
+            benchmarks: benchmarks
+                .iter()
+                .map(|(id, mean)| (id.to_string(), *mean))
+                .collect(),
+        }
+    }
+
+    /// Writes the output of a criterion run of the benchmark.
+    fn write_run(dir: &Path, id: &str, mean: f64) -> Result<()> {
+        let run = dir.join(id.replace('/', "_")).join(CRITERION_RUN_DIR);
+        fs::create_dir_all(&run)?;
+        fs::write(
+            run.join("benchmark.json"),
+            serde_json::json!({ "full_id": id }).to_string(),
+        )?;
+        fs::write(
+            run.join("estimates.json"),
+            serde_json::json!({ "mean": { "point_estimate": mean } }).to_string(),
+        )?;
+        Ok(())
+    }
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("flock-runtime-bench-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn flag_regressions_beyond_threshold() {
+        let baseline = measurements(&[("a", 100.0), ("b", 100.0), ("c", 100.0), ("d", 100.0)]);
+        let current = measurements(&[("a", 109.0), ("b", 125.0), ("c", 50.0), ("e", 10.0)]);
+        let report = compare(&baseline, &current, 0.1);
+
+        let ids = report
+            .benchmarks
+            .iter()
+            .map(|b| b.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["a", "b", "c", "d", "e"]);
+        let regressions = report
+            .regressions()
+            .map(|b| b.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(regressions, vec!["b"]);
+        assert!(!report.is_ok());
+        assert!((report.benchmarks[1].change().unwrap() - 0.25).abs() < 1e-9);
+        // The new and the missing benchmarks don't fail the check.
+        assert_eq!(report.benchmarks[3].change(), None);
+        assert_eq!(report.benchmarks[4].change(), None);
+
+        let rendered = report.render();
+        assert!(rendered.contains("+25.0%) REGRESSED"));
+        assert!(rendered.contains("improved"));
+        assert!(rendered.contains("missing"));
+        assert!(rendered.contains("new"));
+        assert!(rendered.ends_with("5 benchmarks, 1 regressed beyond 10.0%\n"));
+
+        // A looser threshold accepts the slowdown.
+        assert!(compare(&baseline, &current, 0.3).is_ok());
+    }
+
+    #[test]
+    fn update_baseline() -> Result<()> {
+        let dir = temp_dir();
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("baseline.json");
+
+        let mut baseline = measurements(&[("a", 100.0), ("b", 200.0)]);
+        baseline.save(&path)?;
+        let mut loaded = Measurements::load(&path)?;
+        assert_eq!(loaded, baseline);
+
+        // A filtered run only replaces the benchmarks it measured.
+        loaded.update(&measurements(&[("b", 150.0), ("c", 10.0)]));
+        loaded.save(&path)?;
+        baseline = Measurements::load(&path)?;
+        assert_eq!(
+            baseline,
+            measurements(&[("a", 100.0), ("b", 150.0), ("c", 10.0)])
+        );
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn read_criterion_output() -> Result<()> {
+        let dir = temp_dir();
+        write_run(&dir, "coalesce_batches/10000x4", 1500.0)?;
+        write_run(&dir, "repartition/hash", 800.0)?;
+        write_run(&dir, "payload/Zstd", 2500.5)?;
+        // The measurements of the former run aren't read.
+        let base = dir.join("repartition_hash").join("base");
+        fs::create_dir_all(&base)?;
+        fs::write(
+            base.join("estimates.json"),
+            serde_json::json!({ "mean": { "point_estimate": 1.0 } }).to_string(),
+        )?;
+
+        let all = Measurements::from_criterion(&dir, None)?;
+        assert_eq!(
+            all,
+            measurements(&[
+                ("coalesce_batches/10000x4", 1500.0),
+                ("payload/Zstd", 2500.5),
+                ("repartition/hash", 800.0),
+            ])
+        );
+        let filtered = Measurements::from_criterion(&dir, Some("repartition"))?;
+        assert_eq!(filtered, measurements(&[("repartition/hash", 800.0)]));
+        assert!(Measurements::from_criterion(&dir.join("missing"), None).is_err());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
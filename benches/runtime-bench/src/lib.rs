@@ -0,0 +1,46 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The microbenchmarks of the hot paths of the Flock runtime.
+//!
+//! The criterion benchmarks in `benches/runtime.rs` measure:
+//!
+//! * `coalesce_batches` - [`flock::transmute::coalesce_batches`] over 10k tiny
+//!   batches.
+//! * `repartition` - [`flock::transmute::repartition`] round-robin and by the
+//!   hash of a key.
+//! * `payload` - the round trip of
+//!   [`flock::transmute::to_payload_with_encoding`] and
+//!   [`flock::runtime::payload::Payload::to_record_batch`] of 1M rows at each
+//!   codec of the payloads.
+//! * `feed_data_sources` - [`ExecutionContext::feed_data_sources`] on a plan
+//!   joining 5 leaves.
+//! * `context` - [`flock::runtime::context::marshal`] and
+//!   [`flock::runtime::context::unmarshal`] of the context of NEXMark Q4.
+//!
+//! The `runtime-bench` binary runs them and compares the results with a stored
+//! baseline (see [`baseline`]):
+//!
+//! ```text
+//! # Records the baseline.
+//! cargo run --release -p runtime-bench -- --update baseline.json
+//! # Fails if a benchmark is more than 10% slower than the baseline.
+//! cargo run --release -p runtime-bench -- --check baseline.json --threshold 0.1
+//! ```
+//!
+//! [`ExecutionContext::feed_data_sources`]: flock::runtime::context::ExecutionContext::feed_data_sources
+
+pub mod baseline;
+pub use baseline::{compare, BenchmarkDiff, BenchmarkReport, Measurements};
+
+pub mod workloads;
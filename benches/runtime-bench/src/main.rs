@@ -0,0 +1,135 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The command line of the microbenchmarks, which runs them with criterion
+//! and checks the results against a baseline or updates it.
+
+use flock::error::{FlockError, Result};
+use log::info;
+use runtime_bench::{compare, Measurements};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "runtime-bench")]
+struct RuntimeBenchOpt {
+    /// Compares the results with the baseline, and fails if a benchmark
+    /// regressed beyond the threshold.
+    #[structopt(long = "check", parse(from_os_str))]
+    check: Option<PathBuf>,
+
+    /// Writes the results into the baseline. The benchmarks that didn't run
+    /// keep their baseline.
+    #[structopt(long = "update", parse(from_os_str))]
+    update: Option<PathBuf>,
+
+    /// The allowed slowdown relative to the baseline, e.g. 0.1 for 10%.
+    #[structopt(long = "threshold", default_value = "0.1")]
+    threshold: f64,
+
+    /// Only runs the benchmarks whose ids contain the filter.
+    #[structopt(long = "filter")]
+    filter: Option<String>,
+
+    /// Reads the results of the last run instead of running the benchmarks.
+    #[structopt(long = "no-run")]
+    no_run: bool,
+
+    /// The output directory of criterion, `<target>/criterion` by default.
+    #[structopt(long = "criterion-dir", parse(from_os_str))]
+    criterion_dir: Option<PathBuf>,
+}
+
+/// Returns the output directory of criterion in the target directory of the
+/// workspace.
+fn default_criterion_dir() -> PathBuf {
+    std::env::var_os("CARGO_TARGET_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("../../target"))
+        .join("criterion")
+}
+
+/// Runs the criterion benchmarks of the crate.
+fn run_benchmarks(filter: Option<&str>) -> Result<()> {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let mut command = Command::new(cargo);
+    command.args(&[
+        "bench",
+        "-p",
+        "runtime-bench",
+        "--bench",
+        "runtime",
+        "--",
+        "--noplot",
+    ]);
+    if let Some(filter) = filter {
+        command.arg(filter);
+    }
+    info!("Running {:?}", command);
+    let status = command.status()?;
+    if !status.success() {
+        return Err(FlockError::Execution(format!(
+            "The benchmarks failed with {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let opt = RuntimeBenchOpt::from_args();
+    if opt.threshold < 0.0 {
+        return Err(FlockError::Internal(format!(
+            "The threshold must not be negative: {}",
+            opt.threshold
+        )));
+    }
+
+    if !opt.no_run {
+        run_benchmarks(opt.filter.as_deref())?;
+    }
+    let dir = opt.criterion_dir.unwrap_or_else(default_criterion_dir);
+    let current = Measurements::from_criterion(&dir, opt.filter.as_deref())?;
+
+    if let Some(path) = &opt.update {
+        let mut baseline = if path.exists() {
+            Measurements::load(path)?
+        } else {
+            Measurements::default()
+        };
+        baseline.update(&current);
+        baseline.save(path)?;
+        println!(
+            "Updated {} benchmarks in {}",
+            current.benchmarks.len(),
+            path.display()
+        );
+    }
+
+    if let Some(path) = &opt.check {
+        let report = compare(&Measurements::load(path)?, &current, opt.threshold);
+        print!("{}", report.render());
+        if !report.is_ok() {
+            std::process::exit(1);
+        }
+    } else if opt.update.is_none() {
+        // The results without a baseline.
+        print!(
+            "{}",
+            compare(&Measurements::default(), &current, opt.threshold).render()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,185 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The inputs of the microbenchmarks, shaped after the workloads of the cloud
+//! functions.
+
+use datafusion::arrow::array::{Float64Array, Int64Array, StringArray};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::datasource::MemTable;
+use datafusion::execution::context::ExecutionContext as DataFusionContext;
+use flock::datasource::nexmark::event::{Auction, Bid};
+use flock::prelude::*;
+use std::sync::Arc;
+
+/// The number of tiny batches coalesced by the benchmark, i.e. the output of a
+/// busy stage that filters most of its input.
+pub const TINY_BATCHES: usize = 10_000;
+
+/// The number of rows of a tiny batch.
+pub const TINY_BATCH_ROWS: usize = 4;
+
+/// The number of rows of the payloads serialized by the benchmark.
+pub const PAYLOAD_ROWS: usize = 1_000_000;
+
+/// The number of rows of the record batches. The payloads are built from
+/// batches of this size, since a zstd block of a single batch of
+/// [`PAYLOAD_ROWS`] rows would exceed the decompression limit of the payloads.
+pub const BATCH_ROWS: usize = 65_536;
+
+/// The number of distinct keys of the batches.
+pub const DISTINCT_KEYS: usize = 1024;
+
+/// The number of leaves of the join plan fed by the benchmark.
+pub const JOIN_LEAVES: usize = 5;
+
+/// The codecs of the payloads. `Zlib` isn't supported by the payloads.
+pub const PAYLOAD_ENCODINGS: [Encoding; 4] = [
+    Encoding::None,
+    Encoding::Snappy,
+    Encoding::Lz4,
+    Encoding::Zstd,
+];
+
+/// Returns the schema of the batches: a key, a measure and a label.
+pub fn schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("c1", DataType::Int64, false),
+        Field::new("c2", DataType::Float64, false),
+        Field::new("c3", DataType::Utf8, false),
+    ]))
+}
+
+/// Returns a batch of the given rows, whose values are derived from the
+/// position of each row in the input starting from `offset`.
+pub fn batch(offset: usize, rows: usize) -> RecordBatch {
+    let positions = offset..offset + rows;
+    RecordBatch::try_new(
+        schema(),
+        vec![
+            Arc::new(Int64Array::from(
+                positions
+                    .clone()
+                    .map(|i| (i % DISTINCT_KEYS) as i64)
+                    .collect::<Vec<_>>(),
+            )),
+            Arc::new(Float64Array::from(
+                positions
+                    .clone()
+                    .map(|i| i as f64 * 0.5)
+                    .collect::<Vec<_>>(),
+            )),
+            Arc::new(StringArray::from(
+                positions
+                    .map(|i| format!("label-{}", i % 100))
+                    .collect::<Vec<_>>(),
+            )),
+        ],
+    )
+    .unwrap()
+}
+
+/// Returns `rows` rows in batches of at most `batch_rows` rows.
+pub fn batches(rows: usize, batch_rows: usize) -> Vec<RecordBatch> {
+    (0..rows)
+        .step_by(batch_rows)
+        .map(|offset| batch(offset, batch_rows.min(rows - offset)))
+        .collect()
+}
+
+/// Returns [`TINY_BATCHES`] batches of [`TINY_BATCH_ROWS`] rows.
+pub fn tiny_batches() -> Vec<RecordBatch> {
+    (0..TINY_BATCHES)
+        .map(|i| batch(i * TINY_BATCH_ROWS, TINY_BATCH_ROWS))
+        .collect()
+}
+
+/// Registers an empty memory table of the schema, as the query stages only
+/// receive their data at the invocations.
+fn register_table(ctx: &mut DataFusionContext, name: &str, schema: SchemaRef) -> Result<()> {
+    let table = MemTable::try_new(schema.clone(), vec![vec![RecordBatch::new_empty(schema)]])?;
+    ctx.register_table(name, Arc::new(table))?;
+    Ok(())
+}
+
+/// Returns the context of a stage that joins `leaves` tables on their keys,
+/// and the data sources of its leaves with `rows` rows each.
+pub async fn join_context(
+    leaves: usize,
+    rows: usize,
+) -> Result<(ExecutionContext, Vec<Vec<Vec<RecordBatch>>>)> {
+    let mut df_ctx = DataFusionContext::new();
+    let mut sources = vec![];
+    for i in 0..leaves {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new(&format!("k{}", i), DataType::Int64, false),
+            Field::new(&format!("v{}", i), DataType::Int64, false),
+        ]));
+        register_table(&mut df_ctx, &format!("t{}", i), schema.clone())?;
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int64Array::from(
+                    (0..rows)
+                        .map(|r| (r % DISTINCT_KEYS) as i64)
+                        .collect::<Vec<_>>(),
+                )),
+                Arc::new(Int64Array::from(
+                    (0..rows).map(|r| (r * i) as i64).collect::<Vec<_>>(),
+                )),
+            ],
+        )?;
+        sources.push(vec![vec![batch]]);
+    }
+
+    let columns = (0..leaves)
+        .map(|i| format!("v{}", i))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let joins = (1..leaves)
+        .map(|i| format!(" JOIN t{} ON k0 = k{}", i, i))
+        .collect::<String>();
+    let sql = format!("SELECT {} FROM t0{}", columns, joins);
+    let plan = physical_plan(&df_ctx, sql).await?;
+    let ctx = ExecutionContext {
+        plan: CloudExecutionPlan::new(vec![plan], None),
+        name: "bench-00".to_string(),
+        next: CloudFunction::Sink(DataSinkType::Blackhole),
+        ..Default::default()
+    };
+    Ok((ctx, sources))
+}
+
+/// Returns the context of the first stage of NEXMark Q4, the largest context
+/// of the NEXMark queries.
+pub async fn nexmark_q4_context() -> Result<ExecutionContext> {
+    let mut df_ctx = DataFusionContext::new();
+    register_table(&mut df_ctx, "auction", Arc::new(Auction::schema()))?;
+    register_table(&mut df_ctx, "bid", Arc::new(Bid::schema()))?;
+    let sql = concat!(
+        "SELECT category, Avg(final) FROM (",
+        "SELECT Max(price) AS final, category FROM auction ",
+        "INNER JOIN bid ON a_id = auction ",
+        "WHERE b_date_time BETWEEN a_date_time AND expires ",
+        "GROUP BY a_id, category) AS Q ",
+        "GROUP BY category"
+    );
+    let plan = physical_plan(&df_ctx, sql).await?;
+    Ok(ExecutionContext {
+        plan: CloudExecutionPlan::new(vec![plan], None),
+        name: "q4-00".to_string(),
+        next: CloudFunction::Group(("q4-01".to_string(), 8)),
+        ..Default::default()
+    })
+}
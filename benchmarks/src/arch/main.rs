@@ -21,16 +21,24 @@
 //! * projection and filter
 //! * sort
 //! * aggregation (group by)
+//!
+//! With `--embedded-stage`, the benchmark compares the cold starts of the
+//! function that loads the plans of the stage from its environment with the
+//! function that runs the plans embedded in its binary (see
+//! `flock::runtime::embedded`). The function is created anew for each run, so
+//! both runs start cold, and their init durations are reported in the logs.
 
-#[path = "../rainbow.rs"]
-mod rainbow;
+use benchmarks::rainbow;
 
+use datafusion::physical_plan::ExecutionPlan;
 use flock::aws::{cloudwatch, lambda};
 use flock::prelude::*;
 use humantime::parse_duration;
 use lazy_static::lazy_static;
 use log::info;
 use rainbow::{rainbow_println, rainbow_string};
+use std::path::PathBuf;
+use std::sync::Arc;
 use structopt::StructOpt;
 
 lazy_static! {
@@ -50,6 +58,21 @@ pub struct ArchBenchmarkOpt {
     /// The system architecture to use
     #[structopt(short = "a", long = "arch", default_value = "x86_64")]
     pub architecture: String,
+
+    /// The AWS region to deploy and run the benchmark. If not specified, the
+    /// default region from the environment is used.
+    #[structopt(long = "region", default_value = "")]
+    pub region: String,
+
+    /// The embedded stage to compare the cold starts with, e.g. `q3-00`. The
+    /// function binary must embed the plans exported to the export directory
+    #[structopt(long = "embedded-stage")]
+    pub embedded_stage: Option<String>,
+
+    /// The directory of the plans exported by `flock-cli nexmark plan
+    /// --export-dir`
+    #[structopt(long = "export-dir", default_value = "/tmp/flock-plans")]
+    pub export_dir: PathBuf,
 }
 
 #[allow(dead_code)]
@@ -61,26 +84,68 @@ async fn main() -> Result<()> {
 }
 
 pub async fn arch_benchmark(opt: &mut ArchBenchmarkOpt) -> Result<()> {
+    set_flock_region(&opt.region)?;
     rainbow_println("================================================================");
     rainbow_println("                    Running the benchmark                       ");
     rainbow_println("================================================================");
     info!("Running the ARCH benchmark with the following options:\n");
     rainbow_println(format!("{:#?}\n", opt));
 
-    let arch_source_ctx = ExecutionContext {
+    let mut arch_source_ctx = ExecutionContext {
         plan: CloudExecutionPlan::new(vec![FLOCK_EMPTY_PLAN.clone()], None),
         name: FLOCK_DATA_SOURCE_FUNC_NAME.clone(),
         next: CloudFunction::Sink(DataSinkType::Blackhole),
+        region: flock_region(),
         ..Default::default()
     };
 
-    // Create the function for the arch benchmark.
-    info!(
-        "Creating lambda function: {}",
-        rainbow_string(FLOCK_DATA_SOURCE_FUNC_NAME.clone())
-    );
-    lambda::create_function(&arch_source_ctx, opt.memory_size, &opt.architecture).await?;
+    let stage = match &opt.embedded_stage {
+        Some(stage) => stage,
+        None => {
+            // Create the function for the arch benchmark.
+            info!(
+                "Creating lambda function: {}",
+                rainbow_string(FLOCK_DATA_SOURCE_FUNC_NAME.clone())
+            );
+            lambda::create_function(&arch_source_ctx, opt.memory_size, &opt.architecture).await?;
+            return run_arch_function(opt).await;
+        }
+    };
+
+    // The plans of the stage are loaded from the environment first, and then
+    // from the function binary.
+    let path = opt.export_dir.join(format!("{}.json", stage));
+    let plans: Vec<Arc<dyn ExecutionPlan>> = serde_json::from_slice(&std::fs::read(&path)?)?;
+    arch_source_ctx.plan = CloudExecutionPlan::new(plans, None);
+    for embedded in [false, true] {
+        if lambda::function_exists(&FLOCK_DATA_SOURCE_FUNC_NAME).await {
+            lambda::delete_function(&FLOCK_DATA_SOURCE_FUNC_NAME).await?;
+        }
+        info!(
+            "Creating lambda function: {} ({} plans of {})",
+            rainbow_string(FLOCK_DATA_SOURCE_FUNC_NAME.clone()),
+            if embedded { "embedded" } else { "environment" },
+            stage
+        );
+        if embedded {
+            lambda::create_embedded_function(
+                &arch_source_ctx,
+                stage,
+                opt.memory_size,
+                &opt.architecture,
+            )
+            .await?;
+        } else {
+            lambda::create_function(&arch_source_ctx, opt.memory_size, &opt.architecture).await?;
+        }
+        run_arch_function(opt).await?;
+    }
+    Ok(())
+}
 
+/// Invokes the function of the arch benchmark, and prints its logs, which
+/// include the time to load its execution context and its init duration.
+async fn run_arch_function(opt: &ArchBenchmarkOpt) -> Result<()> {
     let p = serde_json::to_vec(&Payload {
         datasource: DataSource::Arch(opt.events),
         ..Default::default()
@@ -0,0 +1,87 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The benchmark drivers use this module to wait for the asynchronous runs
+//! instead of sleeping for a fixed amount of time, and to delete the state
+//! buckets of the runs once they are completed.
+
+use flock::prelude::*;
+use flock::runtime::completion::CompletionManifest;
+use log::{info, warn};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// The interval between two polls of the completion manifest.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Waits until all windows of the query are written to the data sink, or the
+/// timeout expires.
+///
+/// # Arguments
+/// * `query_code` - The query code of the run, e.g. `q3` or `ysb`.
+/// * `expected_windows` - The number of windows to wait for. If `None`, the
+///   number reported by the data source functions is used.
+/// * `timeout` - The maximum time to wait for.
+///
+/// # Returns
+/// The last observed completion manifest of the query.
+pub async fn wait_for_completion(
+    query_code: &str,
+    expected_windows: Option<usize>,
+    timeout: Duration,
+) -> Result<CompletionManifest> {
+    let start = Instant::now();
+    loop {
+        let manifest = CompletionManifest::fetch(query_code).await?;
+        if manifest.is_complete(expected_windows) {
+            info!(
+                "[OK] All {} windows of {} are processed in {:?}.",
+                manifest.completed_windows(),
+                query_code,
+                start.elapsed()
+            );
+            return Ok(manifest);
+        }
+        if start.elapsed() >= timeout {
+            let expected = expected_windows
+                .or_else(|| manifest.expected_windows())
+                .map(|n| n.to_string())
+                .unwrap_or_else(|| "unknown".to_string());
+            warn!(
+                "Timed out after {:?}: {} of {} windows of {} are processed.",
+                timeout,
+                manifest.completed_windows(),
+                expected,
+                query_code
+            );
+            return Ok(manifest);
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Deletes the state buckets of the query in the background, and returns the
+/// handle of the cleanup task.
+pub fn cleanup_state_buckets(query_code: &str) -> JoinHandle<Result<()>> {
+    let query_code = query_code.to_string();
+    tokio::spawn(async move {
+        let reports = StateCleanup::default().run_query(&query_code).await?;
+        info!(
+            "[OK] Deleted {} state buckets of {} with {} objects.",
+            reports.len(),
+            query_code,
+            reports.iter().map(|r| r.deleted).sum::<usize>()
+        );
+        Ok(())
+    })
+}
@@ -0,0 +1,760 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The benchmark drivers use this module to compare the results of two runs of
+//! the same queries, e.g. before and after a change of the executor.
+//!
+//! The results of a run are the committed windows in the layout of
+//! [`flock::datasink::results`], i.e. the parts listed by
+//! `<query code>/results/<window id>/manifest.json`, or the Arrow IPC (or
+//! Parquet) files `<query code>/results/<window id>.*` converted from them,
+//! under an S3 prefix or a local directory. The window ids of two runs differ,
+//! as they are named after the time the data source emitted them, so the
+//! windows of a query are aligned by their position in the run, ordered by the
+//! end of the window recorded in the window columns (see
+//! [`flock::datasink::enrich`]), or in the window id if the results don't have
+//! the window columns. The window columns themselves are left out of the
+//! comparison.
+
+use datafusion::arrow::array::{
+    Array, ArrayRef, Float32Array, Float64Array, TimestampMillisecondArray,
+};
+use datafusion::arrow::datatypes::DataType;
+use datafusion::arrow::ipc::reader::FileReader;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::arrow::util::display::array_value_to_string;
+use datafusion::parquet::arrow::{ArrowReader, ParquetFileArrowReader};
+use datafusion::parquet::file::serialized_reader::SerializedFileReader;
+use datafusion::parquet::util::cursor::SliceableCursor;
+use flock::aws::s3;
+use flock::datasink::enrich::{
+    window_bounds, QUERY_CODE_COLUMN, WINDOW_END_COLUMN, WINDOW_START_COLUMN,
+};
+use flock::datasink::results::{parse_results_key, ResultStore, WindowManifest};
+use flock::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The key prefix of the results under the prefix of the query.
+const RESULTS_DIR: &str = "results";
+
+/// The number of rows read at once from the Parquet files.
+const PARQUET_BATCH_SIZE: usize = 1024;
+
+/// The window of a run, whose results may be split into several files by the
+/// shuffle of the last stage.
+#[derive(Debug, Clone, Default)]
+pub struct RunWindow {
+    /// The id of the window without the shuffle id, i.e. its qid.
+    pub window_id: String,
+    /// The results of the window.
+    pub batches:   Vec<RecordBatch>,
+}
+
+/// The windows of a run by query code, in the order of the run.
+pub type RunResults = BTreeMap<String, Vec<RunWindow>>;
+
+/// The options of the comparison.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiffOptions {
+    /// The tolerance of the floating-point values. Two values are equal if
+    /// they differ by at most the tolerance, relative to the larger magnitude
+    /// if it's above 1.
+    pub float_tol: f64,
+    /// Whether the rows of a window are compared in order. By default, the
+    /// rows are compared as multisets.
+    pub ordered:   bool,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            float_tol: 1e-9,
+            ordered:   false,
+        }
+    }
+}
+
+/// The comparison of a window of the two runs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WindowDiff {
+    /// The query code of the window.
+    pub query_code:       String,
+    /// The position of the window in the runs.
+    pub index:            usize,
+    /// The id of the window in the baseline run, or `None` if it's missing.
+    pub baseline_window:  Option<String>,
+    /// The id of the window in the candidate run, or `None` if it's missing.
+    pub candidate_window: Option<String>,
+    /// The number of rows of the window in the baseline run.
+    pub baseline_rows:    usize,
+    /// The number of rows of the window in the candidate run.
+    pub candidate_rows:   usize,
+    /// The difference of the columns, if any. Only the common columns are
+    /// compared.
+    pub columns:          Option<String>,
+    /// The rows of the baseline run that the candidate run doesn't have.
+    pub missing:          Vec<String>,
+    /// The rows of the candidate run that the baseline run doesn't have.
+    pub extra:            Vec<String>,
+}
+
+impl WindowDiff {
+    /// Returns true if the window is the same in both runs.
+    pub fn is_match(&self) -> bool {
+        self.baseline_window.is_some()
+            && self.candidate_window.is_some()
+            && self.columns.is_none()
+            && self.missing.is_empty()
+            && self.extra.is_empty()
+    }
+
+    /// Returns the number of rows of the candidate run minus the baseline's.
+    pub fn row_delta(&self) -> i64 {
+        self.candidate_rows as i64 - self.baseline_rows as i64
+    }
+}
+
+/// The comparison of two runs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DiffReport {
+    /// The comparison of each window of the runs.
+    pub windows: Vec<WindowDiff>,
+}
+
+impl DiffReport {
+    /// Returns true if all windows are the same in both runs.
+    pub fn is_match(&self) -> bool {
+        self.windows.iter().all(|w| w.is_match())
+    }
+
+    /// Returns the windows that differ.
+    pub fn differences(&self) -> impl Iterator<Item = &WindowDiff> {
+        self.windows.iter().filter(|w| !w.is_match())
+    }
+
+    /// Renders the report with at most `max_rows` differing rows per window.
+    pub fn render(&self, max_rows: usize) -> String {
+        let mut output = String::new();
+        let mut queries: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+        for window in &self.windows {
+            let entry = queries.entry(&window.query_code).or_default();
+            entry.0 += 1;
+            entry.1 += usize::from(!window.is_match());
+        }
+        for (query_code, (windows, differences)) in &queries {
+            output.push_str(&format!(
+                "{}: {} windows, {} differ\n",
+                query_code, windows, differences
+            ));
+        }
+        for window in self.differences() {
+            let id = |id: &Option<String>| id.clone().unwrap_or_else(|| "missing".to_string());
+            output.push_str(&format!(
+                "=== {} window {} (baseline: {}, candidate: {}) rows: {} -> {} ({:+}) ===\n",
+                window.query_code,
+                window.index,
+                id(&window.baseline_window),
+                id(&window.candidate_window),
+                window.baseline_rows,
+                window.candidate_rows,
+                window.row_delta()
+            ));
+            if let Some(columns) = &window.columns {
+                output.push_str(&format!("columns: {}\n", columns));
+            }
+            for (sign, rows) in [("-", &window.missing), ("+", &window.extra)] {
+                for row in rows.iter().take(max_rows) {
+                    output.push_str(&format!("{} {}\n", sign, row));
+                }
+                if rows.len() > max_rows {
+                    output.push_str(&format!(
+                        "{} ... {} more rows\n",
+                        sign,
+                        rows.len() - max_rows
+                    ));
+                }
+            }
+        }
+        output
+    }
+}
+
+/// A value of a row. The floating-point values are kept apart to be compared
+/// with the tolerance.
+#[derive(Debug, Clone, PartialEq)]
+enum Cell {
+    Null,
+    Float(f64),
+    Value(String),
+}
+
+impl Cell {
+    fn new(array: &ArrayRef, i: usize) -> Result<Self> {
+        if array.is_null(i) {
+            return Ok(Cell::Null);
+        }
+        Ok(match array.data_type() {
+            DataType::Float64 => Cell::Float(
+                array
+                    .as_any()
+                    .downcast_ref::<Float64Array>()
+                    .unwrap()
+                    .value(i),
+            ),
+            DataType::Float32 => Cell::Float(
+                array
+                    .as_any()
+                    .downcast_ref::<Float32Array>()
+                    .unwrap()
+                    .value(i) as f64,
+            ),
+            _ => Cell::Value(array_value_to_string(array, i)?),
+        })
+    }
+
+    fn matches(&self, other: &Cell, tol: f64) -> bool {
+        match (self, other) {
+            (Cell::Float(a), Cell::Float(b)) => {
+                a == b
+                    || (a.is_nan() && b.is_nan())
+                    || (a - b).abs() <= tol * a.abs().max(b.abs()).max(1.0)
+            }
+            _ => self == other,
+        }
+    }
+
+    /// Returns the value if it isn't a floating-point value, so that the rows
+    /// can be grouped by their exact values.
+    fn exact(&self) -> Option<Option<&str>> {
+        match self {
+            Cell::Null => Some(None),
+            Cell::Float(_) => None,
+            Cell::Value(v) => Some(Some(v.as_str())),
+        }
+    }
+}
+
+type Row = Vec<Cell>;
+
+fn format_row(row: &[Cell]) -> String {
+    row.iter()
+        .map(|cell| match cell {
+            Cell::Null => "NULL".to_string(),
+            Cell::Float(f) => f.to_string(),
+            Cell::Value(v) => v.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn rows_match(a: &[Cell], b: &[Cell], tol: f64) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.matches(y, tol))
+}
+
+/// Returns true if the column is one of the window columns.
+fn is_window_column(name: &str) -> bool {
+    [WINDOW_START_COLUMN, WINDOW_END_COLUMN, QUERY_CODE_COLUMN].contains(&name)
+}
+
+/// Returns the compared columns of the window, or `None` if it has no results.
+fn columns(batches: &[RecordBatch]) -> Option<Vec<String>> {
+    batches.first().map(|batch| {
+        batch
+            .schema()
+            .fields()
+            .iter()
+            .map(|f| f.name().clone())
+            .filter(|name| !is_window_column(name))
+            .collect()
+    })
+}
+
+/// Returns the rows of the batches with the given columns in order.
+fn rows(batches: &[RecordBatch], columns: &[String]) -> Result<Vec<Row>> {
+    let mut rows = vec![];
+    for batch in batches {
+        let schema = batch.schema();
+        let arrays = columns
+            .iter()
+            .map(|c| Ok(batch.column(schema.index_of(c)?).clone()))
+            .collect::<Result<Vec<_>>>()?;
+        for i in 0..batch.num_rows() {
+            rows.push(
+                arrays
+                    .iter()
+                    .map(|array| Cell::new(array, i))
+                    .collect::<Result<Row>>()?,
+            );
+        }
+    }
+    Ok(rows)
+}
+
+/// Compares the rows of the window of both runs, and returns the rows missing
+/// from the candidate run and the extra rows of the candidate run.
+fn diff_rows(
+    baseline: Vec<Row>,
+    candidate: Vec<Row>,
+    options: &DiffOptions,
+) -> (Vec<Row>, Vec<Row>) {
+    let tol = options.float_tol;
+    if options.ordered {
+        let (mut missing, mut extra) = (vec![], vec![]);
+        let len = baseline.len().max(candidate.len());
+        let mut baseline = baseline.into_iter();
+        let mut candidate = candidate.into_iter();
+        for _ in 0..len {
+            match (baseline.next(), candidate.next()) {
+                (Some(a), Some(b)) if rows_match(&a, &b, tol) => {}
+                (a, b) => {
+                    missing.extend(a);
+                    extra.extend(b);
+                }
+            }
+        }
+        return (missing, extra);
+    }
+
+    // The rows are grouped by their exact values, and the floating-point values
+    // are matched with the tolerance within each group.
+    let key = |row: &Row| -> Vec<Option<String>> {
+        row.iter()
+            .filter_map(|c| c.exact().map(|v| v.map(String::from)))
+            .collect()
+    };
+    let mut unmatched: BTreeMap<Vec<Option<String>>, Vec<Row>> = BTreeMap::new();
+    for row in candidate {
+        unmatched.entry(key(&row)).or_default().push(row);
+    }
+    let mut missing = vec![];
+    for row in baseline {
+        let group = unmatched.entry(key(&row)).or_default();
+        match group.iter().position(|c| rows_match(&row, c, tol)) {
+            Some(i) => {
+                group.swap_remove(i);
+            }
+            None => missing.push(row),
+        }
+    }
+    let extra = unmatched.into_values().flatten().collect();
+    (missing, extra)
+}
+
+/// Compares the window of both runs.
+fn diff_window(
+    query_code: &str,
+    index: usize,
+    baseline: Option<&RunWindow>,
+    candidate: Option<&RunWindow>,
+    options: &DiffOptions,
+) -> Result<WindowDiff> {
+    let batches = |w: Option<&RunWindow>| w.map(|w| w.batches.clone()).unwrap_or_default();
+    let (a, b) = (batches(baseline), batches(candidate));
+    let num_rows = |batches: &[RecordBatch]| batches.iter().map(|b| b.num_rows()).sum::<usize>();
+    let mut diff = WindowDiff {
+        query_code: query_code.to_string(),
+        index,
+        baseline_window: baseline.map(|w| w.window_id.clone()),
+        candidate_window: candidate.map(|w| w.window_id.clone()),
+        baseline_rows: num_rows(&a),
+        candidate_rows: num_rows(&b),
+        ..Default::default()
+    };
+
+    // The columns are matched by name, so their order doesn't matter.
+    let common = match (columns(&a), columns(&b)) {
+        (Some(x), Some(y)) => {
+            let mut sorted = (x.clone(), y.clone());
+            sorted.0.sort();
+            sorted.1.sort();
+            if sorted.0 != sorted.1 {
+                diff.columns = Some(format!("[{}] -> [{}]", x.join(", "), y.join(", ")));
+            }
+            x.into_iter().filter(|c| y.contains(c)).collect()
+        }
+        (Some(x), None) | (None, Some(x)) => x,
+        (None, None) => vec![],
+    };
+    let (missing, extra) = diff_rows(rows(&a, &common)?, rows(&b, &common)?, options);
+    diff.missing = missing.iter().map(|r| format_row(r)).collect();
+    diff.extra = extra.iter().map(|r| format_row(r)).collect();
+    Ok(diff)
+}
+
+/// Compares the results of two runs.
+///
+/// # Arguments
+/// * `baseline` - The results of the baseline run.
+/// * `candidate` - The results of the candidate run.
+/// * `options` - The options of the comparison.
+pub fn diff_runs(
+    baseline: &RunResults,
+    candidate: &RunResults,
+    options: &DiffOptions,
+) -> Result<DiffReport> {
+    let mut report = DiffReport::default();
+    let queries = baseline
+        .keys()
+        .chain(candidate.keys())
+        .collect::<BTreeSet<_>>();
+    for query_code in queries {
+        let empty = vec![];
+        let a = baseline.get(query_code).unwrap_or(&empty);
+        let b = candidate.get(query_code).unwrap_or(&empty);
+        for index in 0..a.len().max(b.len()) {
+            report.windows.push(diff_window(
+                query_code,
+                index,
+                a.get(index),
+                b.get(index),
+                options,
+            )?);
+        }
+    }
+    Ok(report)
+}
+
+/// Returns the end of the window in milliseconds: the smallest end in the
+/// window columns, or the end derived from the window id.
+fn window_end(window: &RunWindow) -> i64 {
+    window
+        .batches
+        .iter()
+        .filter_map(|batch| {
+            let schema = batch.schema();
+            batch
+                .column(schema.index_of(WINDOW_END_COLUMN).ok()?)
+                .as_any()
+                .downcast_ref::<TimestampMillisecondArray>()?
+                .iter()
+                .flatten()
+                .min()
+        })
+        .min()
+        .or_else(|| window_bounds(&window.window_id, None).ok().map(|b| b.end))
+        .unwrap_or_default()
+}
+
+/// Groups the result files of a run into windows, and orders the windows of
+/// each query by their end.
+///
+/// # Arguments
+/// * `files` - The query code, the window id with the shuffle id, and the
+///   results of each result file of the run.
+pub fn align(files: Vec<(String, String, Vec<RecordBatch>)>) -> RunResults {
+    let mut windows: BTreeMap<String, BTreeMap<String, RunWindow>> = BTreeMap::new();
+    for (query_code, window_id, batches) in files {
+        // The shuffle id is the last part of the window id.
+        let qid = window_id
+            .rsplit_once('-')
+            .map_or(window_id.as_str(), |(qid, _)| qid)
+            .to_string();
+        let window = windows
+            .entry(query_code)
+            .or_default()
+            .entry(qid.clone())
+            .or_insert_with(|| RunWindow {
+                window_id: qid,
+                batches:   vec![],
+            });
+        window.batches.extend(batches);
+    }
+    windows
+        .into_iter()
+        .map(|(query_code, windows)| {
+            let mut windows = windows.into_values().collect::<Vec<_>>();
+            windows.sort_by_cached_key(|w| (window_end(w), w.window_id.clone()));
+            (query_code, windows)
+        })
+        .collect()
+}
+
+/// Decodes a result file by its extension, or returns `None` if it isn't one.
+fn decode_file(name: &str, bytes: Vec<u8>) -> Result<Option<Vec<RecordBatch>>> {
+    if name.ends_with(".arrow") {
+        let reader = FileReader::try_new(Cursor::new(bytes))?;
+        Ok(Some(reader.collect::<std::result::Result<Vec<_>, _>>()?))
+    } else if name.ends_with(".parquet") {
+        let file_reader = SerializedFileReader::new(SliceableCursor::new(bytes))?;
+        let mut arrow_reader = ParquetFileArrowReader::new(Arc::new(file_reader));
+        let reader = arrow_reader.get_record_reader(PARQUET_BATCH_SIZE)?;
+        Ok(Some(reader.collect::<std::result::Result<Vec<_>, _>>()?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Returns the query code and the window id of the path of a result file
+/// relative to the root of the run, i.e. `<query code>/results/<window id>.*`.
+/// These are the files converted from the committed windows, e.g. to Parquet.
+fn parse_result_path(path: &str) -> Option<(String, String)> {
+    let mut parts = path.split('/');
+    let (query_code, dir, name) = (parts.next()?, parts.next()?, parts.next()?);
+    if dir != RESULTS_DIR || parts.next().is_some() {
+        return None;
+    }
+    let (window_id, _) = name.rsplit_once('.')?;
+    Some((query_code.to_string(), window_id.to_string()))
+}
+
+/// Loads the results of a run from an S3 prefix, e.g. `s3://bucket/baseline/`,
+/// or from a local directory. Only the windows with a manifest are loaded, so
+/// the windows still being written are left out.
+pub async fn load_run(location: &str) -> Result<RunResults> {
+    let mut files = vec![];
+    if let Some(path) = location.strip_prefix("s3://") {
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        let prefix = if prefix.is_empty() || prefix.ends_with('/') {
+            prefix.to_string()
+        } else {
+            format!("{}/", prefix)
+        };
+        for key in s3::get_matched_keys(bucket, &prefix).await? {
+            let path = &key[prefix.len()..];
+            if let Some((query_code, window_id)) = parse_results_key(path) {
+                // The keys of the parts are relative to the root of the run.
+                let manifest: WindowManifest =
+                    serde_json::from_slice(&s3::get_object(bucket, &key).await?)?;
+                let mut batches = vec![];
+                for part in manifest.parts {
+                    let part_key = format!("{}{}", prefix, part.key);
+                    let bytes = s3::get_object(bucket, &part_key).await?;
+                    batches.extend(decode_file(&part_key, bytes)?.unwrap_or_default());
+                }
+                files.push((query_code, window_id, batches));
+            } else if let Some((query_code, window_id)) = parse_result_path(path) {
+                let bytes = s3::get_object(bucket, &key).await?;
+                if let Some(batches) = decode_file(&key, bytes)? {
+                    files.push((query_code, window_id, batches));
+                }
+            }
+        }
+    } else {
+        let root = Path::new(location);
+        let store = ResultStore::Directory(root.to_path_buf());
+        for (query_code, window_id) in store.windows("").await? {
+            if let Some(batches) = store.get(&query_code, &window_id).await? {
+                files.push((query_code, window_id, batches));
+            }
+        }
+        for query in std::fs::read_dir(root)? {
+            let query = query?;
+            let dir = query.path().join(RESULTS_DIR);
+            if !dir.is_dir() {
+                continue;
+            }
+            for file in std::fs::read_dir(dir)? {
+                let file = file?;
+                if file.path().is_dir() {
+                    continue;
+                }
+                let name = file.file_name().to_string_lossy().to_string();
+                let path = format!(
+                    "{}/{}/{}",
+                    query.file_name().to_string_lossy(),
+                    RESULTS_DIR,
+                    name
+                );
+                if let Some((query_code, window_id)) = parse_result_path(&path) {
+                    if let Some(batches) = decode_file(&name, std::fs::read(file.path())?)? {
+                        files.push((query_code, window_id, batches));
+                    }
+                }
+            }
+        }
+    }
+    if files.is_empty() {
+        return Err(FlockError::Execution(format!(
+            "No results found in {}",
+            location
+        )));
+    }
+    Ok(align(files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::array::{Int64Array, StringArray};
+    use datafusion::arrow::datatypes::{Field, Schema};
+    use flock::datasink::enrich::with_window_columns;
+    use flock::stream::window::tumbling_window;
+
+    fn batch(auctions: Vec<i64>, prices: Vec<f64>, reversed: bool) -> RecordBatch {
+        let auction = Field::new("auction", DataType::Int64, false);
+        let price = Field::new("price", DataType::Float64, false);
+        let (auctions, prices): (ArrayRef, ArrayRef) = (
+            Arc::new(Int64Array::from(auctions)),
+            Arc::new(Float64Array::from(prices)),
+        );
+        if reversed {
+            RecordBatch::try_new(
+                Arc::new(Schema::new(vec![price, auction])),
+                vec![prices, auctions],
+            )
+            .unwrap()
+        } else {
+            RecordBatch::try_new(
+                Arc::new(Schema::new(vec![auction, price])),
+                vec![auctions, prices],
+            )
+            .unwrap()
+        }
+    }
+
+    /// Returns the result files of a run whose windows end at the given times.
+    fn run(windows: Vec<(i64, RecordBatch)>) -> RunResults {
+        align(
+            windows
+                .into_iter()
+                .map(|(end, batch)| {
+                    let window_id = format!("q7-{}-{}-00", end, 1000 - end);
+                    let batches =
+                        with_window_columns(vec![batch], &window_id, Some(&tumbling_window(10)))
+                            .unwrap();
+                    ("q7".to_string(), window_id, batches)
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn align_windows_by_end() {
+        let results = align(vec![
+            (
+                "q1".to_string(),
+                "q1-20-1-01".to_string(),
+                vec![batch(vec![3], vec![3.0], false)],
+            ),
+            (
+                "q1".to_string(),
+                "q1-10-9-00".to_string(),
+                vec![batch(vec![1], vec![1.0], false)],
+            ),
+            (
+                "q1".to_string(),
+                "q1-20-1-00".to_string(),
+                vec![batch(vec![2], vec![2.0], false)],
+            ),
+        ]);
+        let windows = &results["q1"];
+        assert_eq!(windows.len(), 2);
+        assert_eq!(windows[0].window_id, "q1-10-9");
+        assert_eq!(windows[1].window_id, "q1-20-1");
+        assert_eq!(windows[1].batches.len(), 2);
+    }
+
+    #[test]
+    fn diff_float_tolerance() -> Result<()> {
+        let baseline = run(vec![(10, batch(vec![1, 2], vec![0.1 + 0.2, 100.0], false))]);
+        let candidate = run(vec![(
+            50,
+            batch(vec![1, 2], vec![0.3, 100.0 + 1e-8], false),
+        )]);
+
+        // The window columns differ, but they're left out of the comparison.
+        let report = diff_runs(&baseline, &candidate, &DiffOptions::default())?;
+        assert!(report.is_match());
+
+        let options = DiffOptions {
+            float_tol: 1e-12,
+            ..Default::default()
+        };
+        let report = diff_runs(&baseline, &candidate, &options)?;
+        assert!(!report.is_match());
+        let diff = report.differences().next().unwrap();
+        assert_eq!(diff.row_delta(), 0);
+        assert_eq!(diff.missing, vec!["2, 100".to_string()]);
+        assert_eq!(diff.extra.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn diff_missing_windows() -> Result<()> {
+        let baseline = run(vec![
+            (10, batch(vec![1], vec![1.0], false)),
+            (20, batch(vec![2, 3], vec![2.0, 3.0], false)),
+        ]);
+        let candidate = run(vec![(15, batch(vec![1], vec![1.0], false))]);
+        let report = diff_runs(&baseline, &candidate, &DiffOptions::default())?;
+        assert_eq!(report.windows.len(), 2);
+        assert!(report.windows[0].is_match());
+
+        let missing = &report.windows[1];
+        assert!(!missing.is_match());
+        assert_eq!(missing.candidate_window, None);
+        assert_eq!(missing.row_delta(), -2);
+        assert_eq!(missing.missing.len(), 2);
+        assert!(report.render(10).contains("q7: 2 windows, 1 differ"));
+        Ok(())
+    }
+
+    #[test]
+    fn diff_column_and_row_order() -> Result<()> {
+        let baseline = run(vec![(10, batch(vec![1, 2], vec![1.0, 2.0], false))]);
+        let candidate = run(vec![(10, batch(vec![2, 1], vec![2.0, 1.0], true))]);
+        let report = diff_runs(&baseline, &candidate, &DiffOptions::default())?;
+        assert!(report.is_match());
+
+        // The ordered comparison catches the order of the rows.
+        let options = DiffOptions {
+            ordered: true,
+            ..Default::default()
+        };
+        let report = diff_runs(&baseline, &candidate, &options)?;
+        assert!(!report.is_match());
+
+        // A column missing from the candidate run is reported.
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "auction",
+            DataType::Utf8,
+            false,
+        )]));
+        let renamed =
+            RecordBatch::try_new(schema, vec![Arc::new(StringArray::from(vec!["1", "2"]))])?;
+        let candidate = run(vec![(10, renamed)]);
+        let report = diff_runs(&baseline, &candidate, &DiffOptions::default())?;
+        let diff = report.differences().next().unwrap();
+        assert_eq!(
+            diff.columns,
+            Some("[auction, price] -> [auction]".to_string())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn load_committed_windows() -> Result<()> {
+        let root = std::env::temp_dir().join(format!(
+            "flock-diff-{}-{}",
+            std::process::id(),
+            chrono::Utc::now().timestamp_nanos()
+        ));
+        let store = ResultStore::Directory(root.clone());
+        let committed = batch(vec![1, 2], vec![1.0, 2.0], false);
+        store.put("q7", "q7-10-00", &[committed.clone()]).await?;
+        // The run is still writing the second window.
+        store
+            .write_parts("q7", "q7-20-00", &[batch(vec![3], vec![3.0], false)])
+            .await?;
+
+        let results = load_run(root.to_str().unwrap()).await?;
+        assert_eq!(results["q7"].len(), 1);
+        assert_eq!(results["q7"][0].window_id, "q7-10");
+        assert_eq!(results["q7"][0].batches, vec![committed]);
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+}
@@ -11,6 +11,11 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+// The benchmarks share the modules of the library, e.g. the output mode of the
+// process in `rainbow`, as `benchmarks::*` whether they're built into the
+// library or into their own binaries.
+extern crate self as benchmarks;
+
 #[path = "./nexmark/main.rs"]
 pub mod nexmark;
 pub use nexmark::{nexmark_benchmark, NexmarkBenchmarkOpt};
@@ -23,5 +28,19 @@ pub use ysb::{ysb_benchmark, YSBBenchmarkOpt};
 pub mod arch;
 pub use arch::{arch_benchmark, ArchBenchmarkOpt};
 
+pub mod completion;
+pub use completion::{cleanup_state_buckets, wait_for_completion};
+
+pub mod diff;
+pub use diff::{diff_runs, load_run, DiffOptions, DiffReport};
+
+pub mod metrics;
+pub use metrics::{
+    sample_from_sink, samples_from_manifests, samples_from_responses, LatencyReport, LatencySample,
+};
+
 pub mod rainbow;
-pub use rainbow::{rainbow_println, rainbow_string};
+pub use rainbow::{
+    output_mode, output_mode_env, plain_println, rainbow_banner, rainbow_println, rainbow_string,
+    set_output_mode, OutputMode,
+};
@@ -0,0 +1,291 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The benchmark drivers use this module to analyze the end-to-end event-time
+//! latency of a run after it's completed, i.e. the time from the event time of
+//! the results of a window to the time when they're written to the data sink.
+//!
+//! The event time of a window is the end of the window, or the latest event
+//! timestamp of the results of the element-wise queries. The samples are read
+//! from the responses of the synchronous runs, or from the manifests of the
+//! windows committed by the asynchronous runs.
+
+use flock::datasink::results::ResultStore;
+use flock::prelude::*;
+use flock::runtime::response::response_data;
+use serde_json::Value;
+
+/// The number of buckets of the latency histogram.
+const HISTOGRAM_BUCKETS: usize = 10;
+
+/// The width of the longest bar of the latency histogram.
+const HISTOGRAM_WIDTH: usize = 40;
+
+/// The event-time latency of the results of a window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencySample {
+    /// The window of the results, or the function that wrote them.
+    pub window:     String,
+    /// The event time of the results in milliseconds since the epoch.
+    pub event_time: i64,
+    /// The wall-clock time in milliseconds since the epoch when the results
+    /// were written to the data sink.
+    pub written_at: i64,
+}
+
+impl LatencySample {
+    /// Returns the latency in milliseconds.
+    pub fn latency(&self) -> i64 {
+        self.written_at - self.event_time
+    }
+}
+
+/// Returns the latency of the results read from the data sink, or `None` if
+/// the results have no event time or write time.
+pub fn sample_from_sink(sink: &DataSink) -> Option<LatencySample> {
+    Some(LatencySample {
+        window:     sink.function_name.clone(),
+        event_time: sink.event_time?,
+        written_at: sink.written_at?,
+    })
+}
+
+/// Returns the latencies of the results returned by the synchronous
+/// invocations. The responses without the results are skipped.
+pub fn samples_from_responses(responses: &[Value]) -> Vec<LatencySample> {
+    responses
+        .iter()
+        .filter_map(|response| {
+            let data = response_data(response.clone());
+            Some(LatencySample {
+                window:     data["name"].as_str().unwrap_or_default().to_string(),
+                event_time: data["event_time"].as_i64()?,
+                written_at: data["written_at"].as_i64()?,
+            })
+        })
+        .collect()
+}
+
+/// Returns the latencies of the windows of the query committed to the store.
+/// The windows committed before the event time is recorded are skipped.
+pub async fn samples_from_manifests(
+    store: &ResultStore,
+    query_code: &str,
+) -> Result<Vec<LatencySample>> {
+    let mut samples = vec![];
+    for (query_code, window_id) in store.windows(query_code).await? {
+        if let Some(manifest) = store.manifest(&query_code, &window_id).await? {
+            if let Some(event_time) = manifest.event_time {
+                samples.push(LatencySample {
+                    window: window_id,
+                    event_time,
+                    written_at: manifest.committed_at,
+                });
+            }
+        }
+    }
+    Ok(samples)
+}
+
+/// The distribution of the event-time latencies of a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyReport {
+    /// The query code of the run, e.g. `q3` or `ysb`.
+    pub query_code: String,
+    /// The latencies in milliseconds in ascending order.
+    latencies:      Vec<i64>,
+}
+
+impl LatencyReport {
+    /// Creates the report of the latency samples of the query.
+    pub fn new(query_code: impl Into<String>, samples: &[LatencySample]) -> Self {
+        let mut latencies = samples.iter().map(|s| s.latency()).collect::<Vec<_>>();
+        latencies.sort_unstable();
+        Self {
+            query_code: query_code.into(),
+            latencies,
+        }
+    }
+
+    /// Returns the number of samples.
+    pub fn len(&self) -> usize {
+        self.latencies.len()
+    }
+
+    /// Returns true if the report has no samples.
+    pub fn is_empty(&self) -> bool {
+        self.latencies.is_empty()
+    }
+
+    /// Returns the latency at the percentile in `[0, 100]` with the
+    /// nearest-rank method, or `None` if the report has no samples.
+    pub fn percentile(&self, p: f64) -> Option<i64> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        let rank = (p.clamp(0.0, 100.0) / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        Some(self.latencies[rank.max(1) - 1])
+    }
+
+    /// Returns the buckets of the histogram of the latencies with at most the
+    /// given number of buckets of equal width. Each bucket is the range
+    /// `[low, high)` in milliseconds and the number of samples in it.
+    pub fn histogram(&self, buckets: usize) -> Vec<(i64, i64, usize)> {
+        let (min, max) = match (self.latencies.first(), self.latencies.last()) {
+            (Some(&min), Some(&max)) => (min, max),
+            _ => return vec![],
+        };
+        let buckets = buckets.max(1) as i64;
+        let width = ((max - min + 1) + buckets - 1) / buckets;
+        let mut histogram = (0..=(max - min) / width)
+            .map(|i| (min + i * width, min + (i + 1) * width, 0))
+            .collect::<Vec<_>>();
+        for latency in &self.latencies {
+            histogram[((latency - min) / width) as usize].2 += 1;
+        }
+        histogram
+    }
+
+    /// Renders the histogram and the percentiles of the latencies.
+    pub fn render(&self) -> String {
+        if self.latencies.is_empty() {
+            return format!(
+                "No event-time latencies of {} are recorded.",
+                self.query_code
+            );
+        }
+        let ms = |p: f64| self.percentile(p).unwrap_or_default();
+        let mut out = format!(
+            "Event-time latency of {} ({} windows): p50 {} ms, p95 {} ms, p99 {} ms, max {} ms\n",
+            self.query_code,
+            self.len(),
+            ms(50.0),
+            ms(95.0),
+            ms(99.0),
+            ms(100.0)
+        );
+        let histogram = self.histogram(HISTOGRAM_BUCKETS);
+        let highest = histogram.iter().map(|b| b.2).max().unwrap_or(1);
+        for (low, high, count) in histogram {
+            out.push_str(&format!(
+                "  [{:>8}, {:>8}) ms {:>6} {}\n",
+                low,
+                high,
+                count,
+                "#".repeat((count * HISTOGRAM_WIDTH + highest - 1) / highest)
+            ));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn samples(latencies: &[i64]) -> Vec<LatencySample> {
+        latencies
+            .iter()
+            .enumerate()
+            .map(|(i, latency)| LatencySample {
+                window:     format!("w-{:02}", i),
+                event_time: 1_650_000_000_000 + i as i64 * 1000,
+                written_at: 1_650_000_000_000 + i as i64 * 1000 + latency,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn latency_percentiles() {
+        let report = LatencyReport::new("q3", &samples(&(1..=100).rev().collect::<Vec<_>>()));
+        assert_eq!(report.len(), 100);
+        assert_eq!(report.percentile(0.0), Some(1));
+        assert_eq!(report.percentile(50.0), Some(50));
+        assert_eq!(report.percentile(95.0), Some(95));
+        assert_eq!(report.percentile(99.0), Some(99));
+        assert_eq!(report.percentile(100.0), Some(100));
+
+        let report = LatencyReport::new("q3", &samples(&[300, 100, 200]));
+        assert_eq!(report.percentile(50.0), Some(200));
+        assert_eq!(report.percentile(99.0), Some(300));
+
+        let report = LatencyReport::new("q3", &[]);
+        assert!(report.is_empty());
+        assert_eq!(report.percentile(50.0), None);
+        assert!(report.histogram(HISTOGRAM_BUCKETS).is_empty());
+    }
+
+    #[test]
+    fn latency_histogram() {
+        let report = LatencyReport::new("q3", &samples(&[100, 105, 110, 150, 199, 120]));
+        assert_eq!(
+            report.histogram(4),
+            vec![(100, 125, 4), (125, 150, 0), (150, 175, 1), (175, 200, 1)]
+        );
+
+        // The samples of the same latency fall into a single bucket.
+        let report = LatencyReport::new("q3", &samples(&[42, 42]));
+        assert_eq!(report.histogram(4), vec![(42, 43, 2)]);
+
+        let rendered = LatencyReport::new("q3", &samples(&[100, 200])).render();
+        assert!(rendered.contains("(2 windows): p50 100 ms, p95 200 ms, p99 200 ms"));
+    }
+
+    #[test]
+    fn latency_from_responses() {
+        let responses = vec![
+            json!({
+                "name": "q3-00",
+                "sink_type": DataSinkType::Response,
+                "event_time": 1_650_000_000_000i64,
+                "written_at": 1_650_000_002_500i64,
+            }),
+            json!({ "name": "q3-00", "sink_type": DataSinkType::Response }),
+            Value::Null,
+        ];
+        let samples = samples_from_responses(&responses);
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].window, "q3-00");
+        assert_eq!(samples[0].latency(), 2500);
+
+        let sink = DataSink {
+            function_name: "q3-00".to_string(),
+            event_time: Some(1_650_000_000_000),
+            written_at: Some(1_650_000_000_750),
+            ..Default::default()
+        };
+        assert_eq!(sample_from_sink(&sink).map(|s| s.latency()), Some(750));
+        assert_eq!(sample_from_sink(&DataSink::default()), None);
+    }
+
+    #[tokio::test]
+    async fn latency_from_manifests() -> Result<()> {
+        let root = std::env::temp_dir().join(format!(
+            "flock-metrics-{}-{}",
+            std::process::id(),
+            chrono::Utc::now().timestamp_nanos()
+        ));
+        let store = ResultStore::Directory(root.clone());
+        store
+            .commit("q3", "w-01", vec![], 0, Some(1_650_000_000_000))
+            .await?;
+        store.commit("q3", "w-02", vec![], 0, None).await?;
+        let samples = samples_from_manifests(&store, "q3").await?;
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].window, "w-01");
+        assert!(samples[0].written_at >= samples[0].event_time);
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+}
@@ -11,32 +11,44 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-#[path = "../rainbow.rs"]
-mod rainbow;
+use benchmarks::rainbow;
 
 use super::add_extra_metadata;
 use super::create_nexmark_functions;
 use super::create_nexmark_source;
 use super::create_physical_plans;
+use super::print_analyze_report;
+use super::sample_from_sink;
+use super::wait_for_windows;
+use super::LatencyReport;
+use super::QueryResult;
 use crate::NexmarkBenchmarkOpt;
 
 use datafusion::arrow::util::pretty::pretty_format_batches;
+use datafusion::physical_plan::displayable;
 use flock::aws::{cloudwatch, lambda};
+use flock::datasink::inline_response;
 use flock::prelude::*;
+use flock::runtime::arena::UPSTREAM_METADATA_KEY;
+use flock::runtime::metadata::WORKERS_METADATA_KEY;
 use humantime::parse_duration;
 use lazy_static::lazy_static;
 use log::info;
-use nexmark::register_nexmark_tables;
-use rainbow::{rainbow_println, rainbow_string};
+use nexmark::register_nexmark_tables_for_query;
+use rainbow::{plain_println, rainbow_println, rainbow_string};
 use rusoto_lambda::InvocationResponse;
-use std::collections::HashMap;
-use tokio::task::JoinHandle;
+use tokio::task::{JoinError, JoinHandle};
 
 lazy_static! {
     pub static ref NEXMARK_SOURCE_LOG_GROUP: String = "/aws/lambda/flock_datasource".to_string();
 }
 
-pub async fn nexmark_benchmark(opt: &mut NexmarkBenchmarkOpt) -> Result<()> {
+/// Runs the query in a single function, and records the number of result rows
+/// read from the data sink in the result, if the results are collected.
+pub async fn nexmark_benchmark(
+    opt: &mut NexmarkBenchmarkOpt,
+    result: &mut QueryResult,
+) -> Result<()> {
     rainbow_println("================================================================");
     rainbow_println("                    Running the benchmark                       ");
     rainbow_println("================================================================");
@@ -46,28 +58,35 @@ pub async fn nexmark_benchmark(opt: &mut NexmarkBenchmarkOpt) -> Result<()> {
     let query_number = opt.query_number;
     let nexmark_conf = create_nexmark_source(opt).await?;
 
-    let mut ctx = register_nexmark_tables().await?;
+    let mut ctx = register_nexmark_tables_for_query(query_number).await?;
     let plans = create_physical_plans(&mut ctx, query_number).await?;
-    let worker = create_nexmark_functions(
-        opt,
-        nexmark_conf.window.clone(),
-        plans.last().unwrap().clone(),
-    )
-    .await?;
+    let plan_str = format!("{}", displayable(plans.last().unwrap().as_ref()).indent());
+    let worker = create_nexmark_functions(opt, nexmark_conf.window.clone(), &plans).await?;
 
     // The source generator function needs the metadata to determine the type of the
     // workers such as single function or a group. We don't want to keep this info
     // in the environment as part of the source function. Otherwise, we have to
     // *delete* and **recreate** the source function every time we change the query.
-    let mut metadata = HashMap::new();
-    metadata.insert("workers".to_string(), serde_json::to_string(&worker)?);
+    let mut metadata = QueryMetadata::default();
+    metadata.insert(
+        WORKERS_METADATA_KEY.to_string(),
+        serde_json::to_string(&worker)?,
+    );
     add_extra_metadata(opt, &mut metadata).await?;
 
+    let sink_type = DataSinkType::new(&opt.data_sink_type)?;
+    let invocation_type = if opt.analyze || sink_type == DataSinkType::Response {
+        FLOCK_LAMBDA_SYNC_CALL.clone()
+    } else {
+        FLOCK_LAMBDA_ASYNC_CALL.clone()
+    };
     let tasks = (0..opt.generators)
         .into_iter()
         .map(|i| {
             let s = nexmark_conf.clone();
-            let m = metadata.clone();
+            let mut m = metadata.clone();
+            m.insert(UPSTREAM_METADATA_KEY.to_string(), i.to_string());
+            let t = invocation_type.clone();
             tokio::spawn(async move {
                 info!(
                     "[OK] Invoking NEXMark source function: {} by generator {}\n",
@@ -81,31 +100,38 @@ pub async fn nexmark_benchmark(opt: &mut NexmarkBenchmarkOpt) -> Result<()> {
                     ..Default::default()
                 })?
                 .into();
-                lambda::invoke_function(
-                    &FLOCK_DATA_SOURCE_FUNC_NAME,
-                    &FLOCK_LAMBDA_ASYNC_CALL,
-                    Some(p),
-                )
-                .await
+                lambda::invoke_function(&FLOCK_DATA_SOURCE_FUNC_NAME, &t, Some(p)).await
             })
         })
         // this collect *is needed* so that the join below can switch between tasks.
         .collect::<Vec<JoinHandle<Result<InvocationResponse>>>>();
 
-    futures::future::join_all(tasks).await;
+    let responses = futures::future::join_all(tasks).await;
+
+    if opt.analyze {
+        result.stages = print_analyze_report(opt, vec![plan_str]).await?;
+        return Ok(());
+    }
+
+    if opt.async_type {
+        result.windows = Some(wait_for_windows(opt).await?);
+    }
 
     info!("Waiting for the current invocations to be logged.");
     tokio::time::sleep(parse_duration("5s").unwrap()).await;
     cloudwatch::fetch(&NEXMARK_SOURCE_LOG_GROUP, parse_duration("1min").unwrap()).await?;
 
-    let sink_type = DataSinkType::new(&opt.data_sink_type)?;
     if sink_type != DataSinkType::Blackhole {
-        let data_sink = DataSink::read(
-            format!("q{}", opt.query_number),
-            sink_type,
-            DataSinkFormat::default(),
-        )
-        .await?;
+        let data_sink = if sink_type == DataSinkType::Response {
+            inline_results(responses).await?
+        } else {
+            DataSink::read(
+                format!("q{}", opt.query_number),
+                sink_type,
+                DataSinkFormat::default(),
+            )
+            .await?
+        };
         info!(
             "[OK] Received {} batches from the data sink.",
             data_sink.record_batches.len()
@@ -113,8 +139,48 @@ pub async fn nexmark_benchmark(opt: &mut NexmarkBenchmarkOpt) -> Result<()> {
         info!("[OK] Last data sink function: {}", data_sink.function_name);
         let function_log_group = format!("/aws/lambda/{}", data_sink.function_name);
         cloudwatch::fetch(&function_log_group, parse_duration("1min").unwrap()).await?;
-        println!("{}", pretty_format_batches(&data_sink.record_batches)?);
+        plain_println(pretty_format_batches(&data_sink.record_batches)?.to_string());
+        if !opt.async_type {
+            let samples = sample_from_sink(&data_sink).into_iter().collect::<Vec<_>>();
+            plain_println(LatencyReport::new(format!("q{}", query_number), &samples).render());
+        }
+        result.rows = Some(
+            data_sink
+                .record_batches
+                .iter()
+                .map(|b| b.num_rows())
+                .sum::<usize>(),
+        );
     }
 
     Ok(())
 }
+
+/// Returns the results that the synchronous invocations of the data source
+/// generators return inline. The results that are too large to be returned
+/// inline are fetched from the S3 object the responses point to. Fails if no
+/// generator returns any results, since the response data sink doesn't write
+/// them anywhere else.
+async fn inline_results(
+    responses: Vec<std::result::Result<Result<InvocationResponse>, JoinError>>,
+) -> Result<DataSink> {
+    let mut results: Option<DataSink> = None;
+    for response in responses {
+        let response = response.map_err(|e| FlockError::Internal(e.to_string()))??;
+        let inline = response
+            .payload
+            .as_deref()
+            .map(inline_response)
+            .transpose()?;
+        if let Some(value) = inline.flatten() {
+            let data = DataSink::from_response(value).await?;
+            match results.as_mut() {
+                Some(results) => results.record_batches.extend(data.record_batches),
+                None => results = Some(data),
+            }
+        }
+    }
+    results.ok_or_else(|| {
+        FlockError::DataSink("No results are returned inline by the data source.".to_string())
+    })
+}
@@ -13,26 +13,42 @@
 
 extern crate daggy;
 
-#[path = "../rainbow.rs"]
-mod rainbow;
+use benchmarks::rainbow;
 
 use super::add_extra_metadata;
 use super::create_nexmark_source;
 use super::create_physical_plans;
+use super::nexmark_group_size;
 use super::nexmark_query;
+use super::nexmark_running_query;
+use super::nexmark_source_rate;
+use super::print_analyze_report;
+use super::wait_for_windows;
+use super::QueryResult;
 use crate::NexmarkBenchmarkOpt;
 use daggy::NodeIndex;
+use datafusion::arrow::util::pretty::pretty_format_batches;
 use datafusion::execution::context::ExecutionConfig;
+use flock::aws::client::AwsCloudClient;
 use flock::aws::lambda;
 use flock::distributed_plan::QueryDag;
+use flock::driver::funcgen::estimate::MemoryTable;
+use flock::driver::stepfunctions::{
+    collect_results, epoch_payloads, upload_payloads, use_step_functions, Coordinator, StateMachine,
+};
 use flock::prelude::*;
+use flock::runtime::arena::UPSTREAM_METADATA_KEY;
+use flock::runtime::completion::COMPLETION_METADATA_KEY;
+use flock::runtime::lint::LintLevel;
+use flock::runtime::metadata::InvocationType;
+use flock::runtime::multiplex::FunctionRegistry;
 use humantime::parse_duration;
 use lazy_static::lazy_static;
-use log::info;
-use nexmark::register_nexmark_tables_with_config;
-use rainbow::{rainbow_println, rainbow_string};
+use log::{info, warn};
+use nexmark::register_nexmark_tables_for_query_with_config;
+use nexmark::NEXMarkSource;
+use rainbow::{plain_println, rainbow_println, rainbow_string};
 use rusoto_lambda::InvocationResponse;
-use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::task::JoinHandle;
 
@@ -40,22 +56,33 @@ lazy_static! {
     pub static ref NEXMARK_SOURCE_LOG_GROUP: String = "/aws/lambda/flock_datasource".to_string();
 }
 
-pub async fn nexmark_benchmark(opt: &mut NexmarkBenchmarkOpt) -> Result<()> {
+/// Runs the query on the functions of its stages, and records the number of
+/// result rows in the result if the results are collected by the coordinator.
+pub async fn nexmark_benchmark(
+    opt: &mut NexmarkBenchmarkOpt,
+    result: &mut QueryResult,
+) -> Result<()> {
     rainbow_println("================================================================");
     rainbow_println("                    Running the benchmark                       ");
     rainbow_println("================================================================");
     info!("Running the NEXMark benchmark with the following options:\n");
-    println!("{:#?}\n", opt);
+    rainbow_println(format!("{:#?}\n", opt));
 
     let query_number = opt.query_number;
     let query_code = format!("q{}", opt.query_number);
     let nexmark_conf = create_nexmark_source(opt).await?;
 
     let config = ExecutionConfig::new().with_target_partitions(opt.target_partitions);
-    let mut ctx = register_nexmark_tables_with_config(config).await?;
+    let mut ctx = register_nexmark_tables_for_query_with_config(config, query_number).await?;
 
     let plans = create_physical_plans(&mut ctx, query_number).await?;
-    let plan = plans.last().unwrap().clone();
+    let mut plan = plans.last().unwrap().clone();
+    let mut running_aggregate = None;
+    if opt.running_aggregate {
+        let (sql, running) = nexmark_running_query(query_number)?;
+        plan = physical_plan(&ctx, &sql).await?;
+        running_aggregate = Some(running);
+    }
     let sink_type = DataSinkType::new(&opt.data_sink_type)?;
 
     let state_backend: Arc<dyn StateBackend> = match opt.state_backend.as_str() {
@@ -67,7 +94,33 @@ pub async fn nexmark_benchmark(opt: &mut NexmarkBenchmarkOpt) -> Result<()> {
 
     let mut launcher =
         AwsLambdaLauncher::try_new(query_code, plan, sink_type, state_backend).await?;
-    launcher.create_cloud_contexts(*FLOCK_FUNCTION_CONCURRENCY)?;
+    launcher.window = Some(nexmark_conf.window.clone());
+    launcher.reserved_concurrency = opt.reserved_concurrency;
+    launcher.window_columns = opt.window_columns;
+    launcher.result_cache = opt.use_result_cache;
+    launcher.state_persistence = opt.state_persistence;
+    launcher.running_aggregate = running_aggregate;
+    if opt.multiplex {
+        if opt.coordinator == Coordinator::StepFunctions {
+            return Err(FlockError::NotImplemented(
+                "The multiplexed queries are coordinated by the functions directly".to_string(),
+            ));
+        }
+        launcher.multiplex(nexmark_group_size(opt));
+    }
+    launcher.create_cloud_contexts(nexmark_group_size(opt))?;
+    if opt.auto_memory {
+        launcher.size_memory(&nexmark_source_rate(opt), &MemoryTable::from_conf()?)?;
+    }
+    let report = launcher.lint();
+    report
+        .issues_of(LintLevel::Warning)
+        .iter()
+        .for_each(|i| warn!("{}", i));
+    report.check(opt.force)?;
+    if opt.coordinator == Coordinator::StepFunctions {
+        use_step_functions(&mut launcher.dag);
+    }
 
     info!(
         "Streaming: {}",
@@ -92,18 +145,63 @@ pub async fn nexmark_benchmark(opt: &mut NexmarkBenchmarkOpt) -> Result<()> {
         info!("Physical Plan:\n{}", stage.get_plan_str());
     }
 
-    let dag = &mut launcher.dag;
-    create_nexmark_functions(dag, opt, *FLOCK_FUNCTION_CONCURRENCY).await?;
+    let plan_strs = stages
+        .iter()
+        .map(|stage| stage.get_plan_str())
+        .collect::<Vec<_>>();
 
-    let mut metadata = HashMap::new();
+    let mut metadata = QueryMetadata::default();
     add_extra_metadata(opt, &mut metadata).await?;
 
+    if opt.multiplex {
+        let functions = launcher
+            .deploy_multiplexed(
+                &AwsCloudClient,
+                &FunctionRegistry::default(),
+                nexmark_group_size(opt),
+                opt.memory_size,
+                &opt.architecture,
+            )
+            .await?;
+        info!(
+            "Running on the shared functions: {}",
+            rainbow_string(format!("{:?}", functions))
+        );
+        // The functions serve the other queries of the topology as well, so the
+        // query stages travel with the payloads.
+        launcher.query_contexts()?.attach(&mut metadata)?;
+    } else {
+        let dag = &mut launcher.dag;
+        create_nexmark_functions(dag, opt, nexmark_group_size(opt)).await?;
+        if opt.reserved_concurrency.is_some() {
+            launcher
+                .reserve_concurrency(&AwsCloudClient, nexmark_group_size(opt))
+                .await?;
+        }
+    }
+    let function_code = launcher
+        .shared_code
+        .clone()
+        .unwrap_or_else(|| format!("q{}", opt.query_number));
+
+    if opt.coordinator == Coordinator::StepFunctions {
+        result.rows = run_with_step_functions(&launcher.dag, opt, &nexmark_conf, metadata).await?;
+        return Ok(());
+    }
+
+    let invocation_type = if opt.analyze {
+        FLOCK_LAMBDA_SYNC_CALL.clone()
+    } else {
+        FLOCK_LAMBDA_ASYNC_CALL.clone()
+    };
     let tasks = (0..opt.generators)
         .into_iter()
         .map(|i| {
             let s = nexmark_conf.clone();
-            let m = metadata.clone();
-            let f = format!("q{}-{:02}", opt.query_number, 0);
+            let mut m = metadata.clone();
+            m.insert(UPSTREAM_METADATA_KEY.to_string(), i.to_string());
+            let t = invocation_type.clone();
+            let f = format!("{}-{:02}", function_code, 0);
             tokio::spawn(async move {
                 info!(
                     "[OK] Invoking NEXMark source function: {} by generator {}\n",
@@ -117,7 +215,7 @@ pub async fn nexmark_benchmark(opt: &mut NexmarkBenchmarkOpt) -> Result<()> {
                     ..Default::default()
                 })?
                 .into();
-                lambda::invoke_function(&f, &FLOCK_LAMBDA_ASYNC_CALL, Some(p)).await
+                lambda::invoke_function(&f, &t, Some(p)).await
             })
         })
         // this collect *is needed* so that the join below can switch between tasks.
@@ -125,9 +223,62 @@ pub async fn nexmark_benchmark(opt: &mut NexmarkBenchmarkOpt) -> Result<()> {
 
     futures::future::join_all(tasks).await;
 
+    if opt.analyze {
+        result.stages = print_analyze_report(opt, plan_strs).await?;
+    } else if opt.async_type {
+        result.windows = Some(wait_for_windows(opt).await?);
+    }
+
     Ok(())
 }
 
+/// Runs the query stages with the Step Functions state machine as the
+/// coordinator, prints the results of the last stage, and returns the number of
+/// result rows.
+async fn run_with_step_functions(
+    dag: &QueryDag,
+    opt: &NexmarkBenchmarkOpt,
+    source: &NEXMarkSource,
+    mut metadata: QueryMetadata,
+) -> Result<Option<usize>> {
+    let query_code = format!("q{}", opt.query_number);
+    let mut machine = StateMachine::new(dag, &query_code, nexmark_group_size(opt));
+    machine.deploy().await?;
+    info!("Deployed state machine: {}", rainbow_string(machine.name()));
+
+    // The state machine invokes the functions and waits for their responses.
+    metadata.invocation_type = Some(InvocationType::Sync);
+    metadata.remove(COMPLETION_METADATA_KEY);
+
+    let events = source.generate_data()?;
+    let mut payloads = epoch_payloads(
+        &events,
+        &query_code,
+        Some(opt.query_number),
+        opt.generators,
+        opt.seconds,
+    )?;
+    payloads
+        .iter_mut()
+        .for_each(|p| p.metadata = Some(metadata.clone()));
+    let payloads = upload_payloads(&query_code, payloads).await?;
+
+    info!(
+        "[OK] Starting the execution with {} payloads",
+        payloads.len()
+    );
+    let results = machine
+        .execute(payloads, std::time::Duration::from_secs(opt.timeout))
+        .await?;
+    let batches = collect_results(results).await?;
+    if !batches.is_empty() {
+        plain_println(pretty_format_batches(&batches)?.to_string());
+    }
+    info!("[OK] The execution completed");
+
+    Ok(Some(batches.iter().map(|b| b.num_rows()).sum()))
+}
+
 /// Create lambda functions for a given NexMark query.
 async fn create_nexmark_functions(
     dag: &mut QueryDag,
@@ -156,9 +307,20 @@ async fn create_nexmark_functions(
                     let name = group_name.clone();
                     let memory_size = opt.memory_size;
                     let architecture = opt.architecture.clone();
+                    let embedded = opt.embedded_plans;
                     tokio::spawn(async move {
                         ctx.name = format!("{}-{:02}", name, j);
-                        lambda::create_function(&ctx, memory_size, &architecture).await?;
+                        if embedded {
+                            lambda::create_embedded_function(
+                                &ctx,
+                                &name,
+                                memory_size,
+                                &architecture,
+                            )
+                            .await?;
+                        } else {
+                            lambda::create_function(&ctx, memory_size, &architecture).await?;
+                        }
                         info!("Created function member: {}", rainbow_string(&ctx.name));
                         lambda::set_concurrency(&ctx.name, 1).await
                     })
@@ -166,6 +328,10 @@ async fn create_nexmark_functions(
                 .collect::<Vec<JoinHandle<Result<()>>>>();
             futures::future::join_all(tasks).await;
             tokio::time::sleep(parse_duration("2s").unwrap()).await;
+        } else if opt.embedded_plans {
+            let ctx = node.context.as_ref().unwrap();
+            lambda::create_embedded_function(ctx, &ctx.name, opt.memory_size, &opt.architecture)
+                .await?;
         } else {
             lambda::create_function(
                 node.context.as_ref().unwrap(),
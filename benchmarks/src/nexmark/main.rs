@@ -11,8 +11,17 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-#[path = "../rainbow.rs"]
-mod rainbow;
+use benchmarks::rainbow;
+
+#[path = "../completion.rs"]
+mod completion;
+pub use completion::{cleanup_state_buckets, wait_for_completion};
+
+#[path = "../metrics.rs"]
+mod metrics;
+pub use metrics::{
+    sample_from_sink, samples_from_manifests, samples_from_responses, LatencyReport, LatencySample,
+};
 
 #[path = "./centralized.rs"]
 mod centralized;
@@ -20,22 +29,52 @@ mod centralized;
 #[path = "./distributed.rs"]
 mod distributed;
 
+#[path = "./report.rs"]
+mod report;
+pub use report::{estimated_cost, BatchReport, QueryList, QueryResult};
+
+use daggy::NodeIndex;
 use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::datasource::MemTable;
 use datafusion::execution::context::ExecutionContext as DataFusionExecutionContext;
-use datafusion::physical_plan::ExecutionPlan;
+use datafusion::physical_plan::{displayable, ExecutionPlan};
+use flock::aws::chaos::ChaosSpec;
 use flock::aws::{efs, lambda, s3};
+use flock::driver::funcgen::dag::QueryDag;
+use flock::driver::funcgen::estimate::{MemoryTable, Selectivity, SourceRate};
+use flock::driver::lineage::QueryLineage;
+use flock::driver::stepfunctions::Coordinator;
 use flock::prelude::*;
+use flock::runtime::analyze::{AnalyzeReport, StageMetrics, ANALYZE_METADATA_KEY};
+use flock::runtime::arena::{SESSION_TIME_METADATA_KEY, UPSTREAMS_METADATA_KEY};
+use flock::runtime::broadcast::BroadcastRole;
+use flock::datasink::enrich::{split_by_window, WINDOW_END_COLUMN};
+use flock::aws::client::AwsCloudClient;
+use flock::aws::reconcile::{reconcile_functions, FunctionSpec, Polling};
+use flock::datasink::results::{ResultStore, ResultsClient};
+use flock::runtime::completion::{CompletionManifest, COMPLETION_METADATA_KEY};
+use flock::runtime::dictionary::{
+    source_samples, store_dictionary, ZstdDictionary, MAX_DICTIONARY_SIZE,
+};
+use flock::runtime::embedded::export_plans;
+use flock::runtime::function_name::query_key;
+use flock::runtime::metadata::{AddColumn, InvocationType, SessionKeys, SideInput};
+use flock::runtime::plan::{argmax_key, stats_keys};
+use flock::runtime::result_cache::plan_hash;
+use flock::runtime::running_aggregate::{rewrite_cumulative, RunningAggregate};
 use lazy_static::lazy_static;
-use log::info;
+use log::{info, warn};
 use nexmark::event::{side_input_schema, Auction, Bid, Person};
-use nexmark::NEXMarkSource;
-use rainbow::rainbow_string;
-use std::collections::HashMap;
-use std::sync::Arc;
+use nexmark::{
+    get_nexmark_schema, nexmark_tables_for_query, register_nexmark_tables_for_query, NEXMarkSource,
+};
+use rainbow::{output_mode, plain_println, rainbow_println, rainbow_string, OutputMode};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
-use tokio::task::JoinHandle;
 
 static SIDE_INPUT_DOWNLOAD_URL: &str = concat!(
     "https://gist.githubusercontent.com/gangliao/",
@@ -54,6 +93,9 @@ lazy_static! {
     pub static ref NEXMARK_Q6_S3_KEY: String = FLOCK_CONF["nexmark"]["q6_s3_key"].to_string();
     pub static ref NEXMARK_Q9_S3_KEY: String = FLOCK_CONF["nexmark"]["q9_s3_key"].to_string();
     pub static ref NEXMARK_Q13_S3_SIDE_INPUT_KEY: String = FLOCK_CONF["nexmark"]["q13_s3_side_input_key"].to_string();
+    /// The context of the data source function deployed by the previous query,
+    /// which is reused by the next query in the batch mode if it's unchanged.
+    static ref DEPLOYED_SOURCE: Mutex<Option<ExecutionContext>> = Mutex::new(None);
 }
 
 #[derive(Default, Clone, Debug, StructOpt)]
@@ -82,6 +124,11 @@ pub struct NexmarkBenchmarkOpt {
     #[structopt(long = "async")]
     pub async_type: bool,
 
+    /// The maximum number of seconds to wait for all windows to be processed
+    /// in the async mode
+    #[structopt(long = "timeout", default_value = "300")]
+    pub timeout: u64,
+
     /// The worker function's memory size
     #[structopt(short = "m", long = "memory_size", default_value = "128")]
     pub memory_size: i64,
@@ -102,6 +149,121 @@ pub struct NexmarkBenchmarkOpt {
     /// This is only used in distributed mode.
     #[structopt(short = "p", long = "target_partitions", default_value = "8")]
     pub target_partitions: usize,
+
+    /// The AWS region to deploy and run the benchmark. If not specified, the
+    /// default region from the environment is used.
+    #[structopt(long = "region", default_value = "")]
+    pub region: String,
+
+    /// Runs the query once with sync invocations and prints the query stages
+    /// annotated with the observed row counts and timings
+    #[structopt(long = "analyze")]
+    pub analyze: bool,
+
+    /// The window of the query, e.g. `tumbling:10`, `hopping:10:5`,
+    /// `session:10`, `global:10` or `elementwise`. If not specified, the
+    /// default window of the query is used.
+    #[structopt(long = "window")]
+    pub window: Option<Window>,
+
+    /// The coordinator of the query stages in the distributed mode: `direct`
+    /// (the functions invoke the next stages) or `step-functions`
+    #[structopt(long = "coordinator", default_value = "direct")]
+    pub coordinator: Coordinator,
+
+    /// Runs the query on the functions shared by the queries of the same
+    /// topology in the distributed mode, which are deployed only once
+    #[structopt(long = "multiplex")]
+    pub multiplex: bool,
+
+    /// The initial number of functions in each function group. The groups can
+    /// be resized at runtime with `flock-cli lambda --scale`. If not
+    /// specified, the function concurrency of the configuration is used
+    #[structopt(long = "group-size")]
+    pub group_size: Option<usize>,
+
+    /// Deploys the query in the distributed mode despite the warnings of the
+    /// plan lint, e.g. the operators that aren't known to be supported
+    #[structopt(long = "force")]
+    pub force: bool,
+
+    /// The concurrency reserved for each function that isn't a member of a
+    /// function group in the distributed mode. The members of the groups
+    /// always reserve one execution
+    #[structopt(long = "reserved-concurrency")]
+    pub reserved_concurrency: Option<i64>,
+
+    /// Runs the queries one by one with the same options, e.g. `1-8` or
+    /// `1,3,5-7`, and prints a comparison report. It takes precedence over the
+    /// query number
+    #[structopt(long = "queries")]
+    pub queries: Option<QueryList>,
+
+    /// The path of the JSON file of the comparison report in the batch mode
+    #[structopt(long = "report")]
+    pub report: Option<String>,
+
+    /// The endpoint of the results server, e.g. `http://localhost:50051`. If
+    /// specified, the results of the windows are read back from the server
+    /// after the asynchronous run
+    #[structopt(long = "results-server")]
+    pub results_server: Option<String>,
+
+    /// Deletes the state buckets of the query in the background once all
+    /// windows are processed in the async mode
+    #[structopt(long = "cleanup")]
+    pub cleanup: bool,
+
+    /// Appends the window bounds and the query code to the results as the
+    /// columns `_window_start`, `_window_end` and `_query_code`
+    #[structopt(long = "window-columns")]
+    pub window_columns: bool,
+
+    /// Sizes the memory of each query stage in the distributed mode from its
+    /// estimated data volume at the event rate, instead of the memory size
+    #[structopt(long = "auto-memory")]
+    pub auto_memory: bool,
+
+    /// Reuses the output of the windows that the last stage executed with the
+    /// same input before, e.g. when the same seeded run is repeated
+    #[structopt(long = "use-result-cache")]
+    pub use_result_cache: bool,
+
+    /// Compresses the payloads with a Zstd dictionary trained from the first
+    /// epoch of the events, e.g. the small payloads of the element-wise queries
+    #[structopt(long = "zstd-dict")]
+    pub zstd_dict: bool,
+
+    /// When the functions write the payloads of the next stage to the state
+    /// backend: `always`, `on-failure` or `never`. If not specified, the
+    /// payloads of the aggregate stages are always written, and the others never
+    #[structopt(long = "state-persistence")]
+    pub state_persistence: Option<StatePersistence>,
+
+    /// Accumulates the aggregates of the query over all windows of the run,
+    /// and appends them to the results as the `cumulative_*` columns. Only Q4
+    /// is supported, in the distributed mode
+    #[structopt(long = "running-aggregate")]
+    pub running_aggregate: bool,
+
+    /// The JSON file of the faults to inject into the invocations and the S3
+    /// calls of the functions (see `flock::aws::chaos`). The faults are only
+    /// injected if `FLOCK_CHAOS` is set when the functions are deployed
+    #[structopt(long = "chaos")]
+    pub chaos: Option<String>,
+
+    /// Deploys the functions of the stages in the distributed mode to run the
+    /// plans embedded in the function binary, which must be built with the
+    /// plans exported by `flock-cli nexmark plan --export-dir` (see
+    /// `flock::runtime::embedded`)
+    #[structopt(long = "embedded-plans")]
+    pub embedded_plans: bool,
+
+    /// Plans the deployment of the query in the distributed mode, and prints
+    /// the estimated data volumes, the resources that would be created and the
+    /// estimated cost, without creating anything on AWS
+    #[structopt(long = "dry-run")]
+    pub dry_run: bool,
 }
 
 #[allow(dead_code)]
@@ -112,15 +274,24 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-pub async fn create_nexmark_source(opt: &mut NexmarkBenchmarkOpt) -> Result<NEXMarkSource> {
-    let window = match opt.query_number {
+/// Returns the window of the query: the window given on the command line, or
+/// the default window of the query.
+pub fn nexmark_window(opt: &NexmarkBenchmarkOpt) -> Window {
+    if let Some(window) = opt.window.clone() {
+        return window;
+    }
+    match opt.query_number {
         0..=4 | 6 | 9 | 10 | 13 => Window::ElementWise,
         5 => Window::Hopping((10, 5)),
         7..=8 => Window::Tumbling(Schedule::Seconds(10)),
         11 => Window::Session(Schedule::Seconds(10)),
         12 => Window::Global(Schedule::Seconds(10)),
         _ => unreachable!(),
-    };
+    }
+}
+
+pub async fn create_nexmark_source(opt: &mut NexmarkBenchmarkOpt) -> Result<NEXMarkSource> {
+    let window = nexmark_window(opt);
 
     if opt.query_number == 10 {
         opt.data_sink_type = "s3".to_string();
@@ -135,7 +306,7 @@ pub async fn create_nexmark_source(opt: &mut NexmarkBenchmarkOpt) -> Result<NEXM
             .map_err(|_| "Failed to read side input data")?;
         s3::put_object_if_missing(
             &FLOCK_S3_BUCKET,
-            &NEXMARK_Q13_S3_SIDE_INPUT_KEY,
+            &nexmark_s3_key(13, &NEXMARK_Q13_S3_SIDE_INPUT_KEY),
             data.as_bytes().to_vec(),
         )
         .await?;
@@ -149,6 +320,171 @@ pub async fn create_nexmark_source(opt: &mut NexmarkBenchmarkOpt) -> Result<NEXM
     ))
 }
 
+/// Returns the rate of the data source. The windows of the element-wise
+/// queries, and of the queries whose windows depend on the data, are estimated
+/// as the epochs of one second.
+pub fn nexmark_source_rate(opt: &NexmarkBenchmarkOpt) -> SourceRate {
+    SourceRate {
+        events_per_second: opt.events_per_second,
+        window_seconds:    nexmark_window(opt)
+            .length()
+            .map_or(1, |length| length.as_secs() as usize)
+            .max(1),
+    }
+}
+
+/// Returns the subplans of the query annotated with the estimates of their data
+/// volume for each window at the event rate of the benchmark, and the memory
+/// sizes of the functions that the estimates map to.
+pub async fn nexmark_plan(opt: &NexmarkBenchmarkOpt) -> Result<String> {
+    let rate = nexmark_source_rate(opt);
+    let table = MemoryTable::from_conf()?;
+    let mut ctx = register_nexmark_tables_for_query(opt.query_number).await?;
+    let plans = create_physical_plans(&mut ctx, opt.query_number).await?;
+
+    let mut output = String::new();
+    for (i, plan) in plans.iter().enumerate() {
+        let mut dag = QueryDag::from(plan);
+        dag.estimate_volumes(&rate, &Selectivity::default());
+        // The leaves of the DAG read the data source, so they're printed first.
+        for n in (0..dag.node_count()).rev() {
+            let node = dag.get_node(NodeIndex::new(n)).unwrap();
+            let estimate = node.estimate.unwrap_or_default();
+            output.push_str(&format!(
+                "=== Plan {} Subplan {} ===\nEstimate: {}\nMemory size: {} MB\n{}\n",
+                i,
+                dag.node_count() - 1 - n,
+                estimate,
+                table.memory_size(&estimate),
+                displayable(node.plan.as_ref()).indent()
+            ));
+        }
+    }
+    Ok(output)
+}
+
+/// Plans the deployment of the query in the distributed mode without creating
+/// any resource on AWS (see `flock::api::dry_run`), and returns the report: the
+/// sizing of the data generators, the query stages with their estimated data
+/// volumes, the resources that the deployment would create, and the estimated
+/// cost of the run.
+pub async fn nexmark_dry_run(opt: &NexmarkBenchmarkOpt) -> Result<String> {
+    let window = nexmark_window(opt);
+    let rate = nexmark_source_rate(opt);
+    let tables = nexmark_tables_for_query(opt.query_number)
+        .iter()
+        .map(|t| Table::new(*t, Arc::new(get_nexmark_schema(t))))
+        .collect::<Vec<_>>();
+    let query = Query::new(
+        nexmark_query(opt.query_number)[0].clone(),
+        tables,
+        DataSource::NEXMarkEvent(NEXMarkSource::new(
+            opt.seconds,
+            opt.generators,
+            opt.events_per_second,
+            window.clone(),
+        )),
+        DataSinkType::Blackhole,
+        Some(format!("q{}", opt.query_number)),
+        QueryType::Streaming(StreamType::NEXMarkBench),
+        nexmark_state_backend(opt),
+    );
+    let mut opts = DeployOptions::lambda()
+        .with_group_size(nexmark_group_size(opt))
+        .with_memory_size(opt.memory_size)
+        .with_architecture(opt.architecture.clone())
+        .with_force(opt.force)
+        .with_reserved_concurrency(opt.reserved_concurrency)
+        .with_window_columns(opt.window_columns)
+        .with_result_cache(opt.use_result_cache);
+    if opt.auto_memory {
+        opts = opts.with_source_rate(rate);
+    }
+    let report = dry_run(&query, &opts, Some(&rate)).await?;
+
+    // Each generator produces its share of the events of every second.
+    let generators = opt.generators.max(1);
+    let mut output = format!(
+        "=== Data generation ===\n\
+         Events: {} ({} seconds x {} events/s)\n\
+         Generators: {} x {} events/s, {} events each\n\
+         Window: {} ({} events per window)\n\n",
+        opt.seconds * opt.events_per_second,
+        opt.seconds,
+        opt.events_per_second,
+        generators,
+        opt.events_per_second / generators,
+        opt.seconds * opt.events_per_second / generators,
+        window,
+        rate.window_rows() as u64,
+    );
+    output.push_str(&report.to_string());
+
+    // The data source functions have 4 GB of memory (see
+    // `create_source_function`).
+    let duration = Duration::from_secs(opt.seconds as u64);
+    let generators_cost = estimated_cost(duration, &[(4096, generators)], &opt.architecture);
+    output.push_str(&format!(
+        "\nEstimated cost of {} seconds: ${:.6} (functions) + ${:.6} (generators)",
+        opt.seconds,
+        report.estimated_cost(duration),
+        generators_cost
+    ));
+    Ok(output)
+}
+
+/// Writes the plans of the query stages to `<dir>/<stage>.json`, which the
+/// function binary embeds at compile time (see `flock::runtime::embedded`),
+/// and returns the paths of the files.
+pub async fn nexmark_export_plans(opt: &NexmarkBenchmarkOpt, dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut ctx = register_nexmark_tables_for_query(opt.query_number).await?;
+    let plans = create_physical_plans(&mut ctx, opt.query_number).await?;
+    let mut launcher = AwsLambdaLauncher::try_new(
+        format!("q{}", opt.query_number),
+        plans.last().unwrap().clone(),
+        DataSinkType::Blackhole,
+        Arc::new(HashMapStateBackend::new()),
+    )
+    .await?;
+    launcher.create_cloud_contexts(nexmark_group_size(opt))?;
+    let stages = launcher.dag.get_all_stages();
+    export_plans(stages.iter().filter_map(|stage| stage.context.as_ref()), dir)
+}
+
+/// Returns the column-level lineage of each plan of the query, as a JSON array
+/// if `json` is true, or as pretty tables otherwise.
+pub async fn nexmark_lineage(opt: &NexmarkBenchmarkOpt, json: bool) -> Result<String> {
+    let mut ctx = register_nexmark_tables_for_query(opt.query_number).await?;
+    let plans = create_physical_plans(&mut ctx, opt.query_number).await?;
+    let streams = nexmark_tables_for_query(opt.query_number)
+        .iter()
+        .map(|t| Table::new(*t, Arc::new(get_nexmark_schema(t))))
+        .collect::<Vec<_>>();
+    let lineages = plans
+        .iter()
+        .map(|plan| QueryLineage::analyze(plan, &streams))
+        .collect::<Result<Vec<_>>>()?;
+
+    if json {
+        return Ok(serde_json::to_string_pretty(&lineages)?);
+    }
+    let mut output = String::new();
+    for (i, lineage) in lineages.iter().enumerate() {
+        output.push_str(&format!(
+            "=== Plan {} Lineage ({} stages) ===\n{}\n",
+            i,
+            lineage.stages,
+            lineage.to_table()?
+        ));
+    }
+    Ok(output)
+}
+
+/// Returns the S3 key of the object of the query, under its query code.
+pub fn nexmark_s3_key(query_number: usize, key: &str) -> String {
+    query_key(&format!("q{}", query_number), key)
+}
+
 pub async fn plan_placement(
     query_number: usize,
     physcial_plan: Arc<dyn ExecutionPlan>,
@@ -156,9 +492,9 @@ pub async fn plan_placement(
     match query_number {
         4 | 6 | 9 => {
             let (s3_bucket, s3_key) = match query_number {
-                4 => (FLOCK_S3_BUCKET.clone(), NEXMARK_Q4_S3_KEY.clone()),
-                6 => (FLOCK_S3_BUCKET.clone(), NEXMARK_Q6_S3_KEY.clone()),
-                9 => (FLOCK_S3_BUCKET.clone(), NEXMARK_Q9_S3_KEY.clone()),
+                4 => (FLOCK_S3_BUCKET.clone(), nexmark_s3_key(4, &NEXMARK_Q4_S3_KEY)),
+                6 => (FLOCK_S3_BUCKET.clone(), nexmark_s3_key(6, &NEXMARK_Q6_S3_KEY)),
+                9 => (FLOCK_S3_BUCKET.clone(), nexmark_s3_key(9, &NEXMARK_Q9_S3_KEY)),
                 _ => unreachable!(),
             };
             s3::put_object_if_missing(&s3_bucket, &s3_key, serde_json::to_vec(&physcial_plan)?)
@@ -169,89 +505,116 @@ pub async fn plan_placement(
     }
 }
 
+/// Returns the initial number of functions in each function group.
+pub fn nexmark_group_size(opt: &NexmarkBenchmarkOpt) -> usize {
+    opt.group_size.unwrap_or(*FLOCK_FUNCTION_CONCURRENCY)
+}
+
+/// Returns the state backend of the worker functions.
+fn nexmark_state_backend(opt: &NexmarkBenchmarkOpt) -> Arc<dyn StateBackend> {
+    match opt.state_backend.as_str() {
+        "hashmap" => Arc::new(HashMapStateBackend::new()),
+        "s3" => Arc::new(S3StateBackend::new()),
+        "efs" => Arc::new(EfsStateBackend::new()),
+        _ => unreachable!(),
+    }
+}
+
 /// Create lambda functions for a given NexMark query.
 /// The returned function is the worker group as a whole which will be executed
 /// by the NexmarkBenchmark data generator function.
+///
+/// The worker runs the last plan of the query, except for Q7 whose plans are
+/// the stages of the broadcast join (see [`create_broadcast_join_functions`]).
 pub async fn create_nexmark_functions(
     opt: &NexmarkBenchmarkOpt,
     window: Window,
-    physcial_plan: Arc<dyn ExecutionPlan>,
+    plans: &[Arc<dyn ExecutionPlan>],
 ) -> Result<CloudFunction> {
-    let worker_func_name = format!("q{}-00", opt.query_number);
-
-    let state_backend: Arc<dyn StateBackend> = match opt.state_backend.as_str() {
-        "hashmap" => Arc::new(HashMapStateBackend::new()),
-        "s3" => Arc::new(S3StateBackend::new()),
-        "efs" => Arc::new(EfsStateBackend::new()),
-        _ => unreachable!(),
-    };
+    if opt.query_number == 7 {
+        return create_broadcast_join_functions(opt, window, plans).await;
+    }
 
-    let granule_size = if opt.async_type {
-        *FLOCK_ASYNC_GRANULE_SIZE * 2
+    let physcial_plan = plans.last().unwrap().clone();
+    let worker_func_name = format!("q{}-00", opt.query_number);
+    let state_backend = nexmark_state_backend(opt);
+    let zstd_dict = nexmark_dictionary(opt, &window).await?;
+
+    // The element-wise worker is a plain Lambda function, which takes the fast
+    // path without the hash ring of the function group (see
+    // `ExecutionContext::is_pipelined`), and the data source splits the epochs
+    // into as many payloads as its instances run concurrently.
+    let next_func_name = if window != Window::ElementWise {
+        CloudFunction::Group((worker_func_name.clone(), nexmark_group_size(opt)))
     } else {
-        *FLOCK_SYNC_GRANULE_SIZE * 2
+        CloudFunction::Lambda(worker_func_name.clone())
     };
 
-    let next_func_name = if window != Window::ElementWise || opt.events_per_second > granule_size {
-        CloudFunction::Group((worker_func_name.clone(), *FLOCK_FUNCTION_CONCURRENCY))
-    } else {
-        CloudFunction::Lambda(worker_func_name.clone())
+    // The hopping windows of the queries emitting the keys with the maximum count
+    // (e.g. Q5) are evaluated incrementally.
+    let argmax_key = match window {
+        Window::Hopping(_) => argmax_key(&physcial_plan),
+        _ => None,
     };
 
+    let keys = stats_keys(&[physcial_plan.clone()]);
+    let persistence = opt
+        .state_persistence
+        .unwrap_or_else(|| StatePersistence::for_stage(&[physcial_plan.clone()]));
+
     let (plan, s3) = plan_placement(opt.query_number, physcial_plan).await?;
     let nexmark_source_ctx = ExecutionContext {
-        plan:          CloudExecutionPlan::new(vec![FLOCK_EMPTY_PLAN.clone()], s3.clone()),
-        name:          FLOCK_DATA_SOURCE_FUNC_NAME.clone(),
-        next:          next_func_name.clone(),
-        state_backend: state_backend.clone(),
+        plan:              CloudExecutionPlan::new(vec![FLOCK_EMPTY_PLAN.clone()], s3.clone()),
+        name:              FLOCK_DATA_SOURCE_FUNC_NAME.clone(),
+        next:              next_func_name.clone(),
+        state_backend:     state_backend.clone(),
+        region:            flock_region(),
+        argmax_key:        argmax_key.clone(),
+        window:            Some(window.clone()),
+        stats_keys:        keys,
+        state_persistence: persistence,
+        zstd_dict:         zstd_dict.clone(),
+        ..Default::default()
     };
 
     let nexmark_worker_ctx = ExecutionContext {
-        plan:          CloudExecutionPlan::new(vec![plan.clone()], s3.clone()),
-        name:          worker_func_name.clone(),
-        next:          CloudFunction::Sink(DataSinkType::new(&opt.data_sink_type)?),
-        state_backend: state_backend.clone(),
+        plan:           CloudExecutionPlan::new(vec![plan.clone()], s3.clone()),
+        name:           worker_func_name.clone(),
+        next:           CloudFunction::Sink(DataSinkType::new(&opt.data_sink_type)?),
+        state_backend:  state_backend.clone(),
+        region:         flock_region(),
+        argmax_key:     argmax_key.clone(),
+        window:         Some(window.clone()),
+        stats_keys:     vec![],
+        window_columns: opt.window_columns,
+        result_cache:   opt.use_result_cache.then(|| plan_hash(&[plan.clone()])),
+        zstd_dict,
+        ..Default::default()
     };
 
     // Create the function for the nexmark source generator.
-    info!(
-        "Creating lambda function: {}",
-        rainbow_string(FLOCK_DATA_SOURCE_FUNC_NAME.clone())
-    );
-    lambda::create_function(&nexmark_source_ctx, 4096 /* MB */, &opt.architecture).await?;
+    create_source_function(opt, &nexmark_source_ctx).await?;
 
     // Create the function for the nexmark worker.
     match next_func_name.clone() {
         CloudFunction::Lambda(name) => {
             info!("Creating lambda function: {}", rainbow_string(name));
-            lambda::create_function(&nexmark_worker_ctx, opt.memory_size, &opt.architecture)
-                .await?;
+            deploy_functions(
+                vec![FunctionSpec::new(
+                    nexmark_worker_ctx,
+                    opt.memory_size,
+                    &opt.architecture,
+                )],
+                &[],
+            )
+            .await?;
         }
         CloudFunction::Group((name, concurrency)) => {
             info!(
                 "Creating lambda function group: {}",
                 rainbow_string(format!("{:?}", nexmark_source_ctx.next))
             );
-
-            let tasks = (0..concurrency)
-                .into_iter()
-                .map(|i| {
-                    let mut worker_ctx = nexmark_worker_ctx.clone();
-                    let group_name = name.clone();
-                    let memory_size = opt.memory_size;
-                    let architecture = opt.architecture.clone();
-                    tokio::spawn(async move {
-                        worker_ctx.name = format!("{}-{:02}", group_name, i);
-                        info!(
-                            "Creating function member: {}",
-                            rainbow_string(&worker_ctx.name)
-                        );
-                        lambda::create_function(&worker_ctx, memory_size, &architecture).await?;
-                        lambda::set_concurrency(&worker_ctx.name, 1).await
-                    })
-                })
-                .collect::<Vec<JoinHandle<Result<()>>>>();
-            futures::future::join_all(tasks).await;
+            create_function_group(opt, &nexmark_worker_ctx, &name, concurrency).await?;
         }
         CloudFunction::Sink(_) => unreachable!(),
     }
@@ -259,6 +622,187 @@ pub async fn create_nexmark_functions(
     Ok(next_func_name)
 }
 
+/// Trains the Zstd dictionary of the query from the first epoch of the events
+/// if it's enabled, and stores it where the functions of the query read it
+/// from (see [`flock::runtime::dictionary`]).
+async fn nexmark_dictionary(
+    opt: &NexmarkBenchmarkOpt,
+    window: &Window,
+) -> Result<Option<ZstdDictionary>> {
+    if !opt.zstd_dict {
+        return Ok(None);
+    }
+    let source = DataSource::NEXMarkEvent(NEXMarkSource::new(
+        opt.seconds,
+        opt.generators,
+        opt.events_per_second,
+        window.clone(),
+    ));
+    let samples = source_samples(&source, Some(opt.query_number), &[])?;
+    let dict = flock::encoding::train_dictionary(&samples, MAX_DICTIONARY_SIZE)?;
+    let dictionary =
+        ZstdDictionary::new(&dict, &FLOCK_S3_BUCKET, &format!("q{}", opt.query_number))?;
+    store_dictionary(&AwsCloudClient, &dictionary, dict).await?;
+    info!(
+        "[OK] Trained the Zstd dictionary {} from {} samples.",
+        dictionary.dict_id,
+        samples.len()
+    );
+    Ok(Some(dictionary))
+}
+
+/// Creates the function of the nexmark source generator, unless the previous
+/// query has deployed it with the same context.
+async fn create_source_function(opt: &NexmarkBenchmarkOpt, ctx: &ExecutionContext) -> Result<()> {
+    if DEPLOYED_SOURCE.lock().unwrap().as_ref() == Some(ctx) {
+        info!(
+            "Reusing lambda function: {}",
+            rainbow_string(FLOCK_DATA_SOURCE_FUNC_NAME.clone())
+        );
+        return Ok(());
+    }
+    info!(
+        "Creating lambda function: {}",
+        rainbow_string(FLOCK_DATA_SOURCE_FUNC_NAME.clone())
+    );
+    deploy_functions(
+        vec![FunctionSpec::new(ctx.clone(), 4096 /* MB */, &opt.architecture)],
+        &[],
+    )
+    .await?;
+    *DEPLOYED_SOURCE.lock().unwrap() = Some(ctx.clone());
+    Ok(())
+}
+
+/// Creates the members of the function group with the given context.
+async fn create_function_group(
+    opt: &NexmarkBenchmarkOpt,
+    ctx: &ExecutionContext,
+    name: &str,
+    concurrency: usize,
+) -> Result<()> {
+    let specs = (0..concurrency)
+        .map(|i| {
+            let mut worker_ctx = ctx.clone();
+            worker_ctx.name = format!("{}-{:02}", name, i);
+            info!(
+                "Creating function member: {}",
+                rainbow_string(&worker_ctx.name)
+            );
+            FunctionSpec::new(worker_ctx, opt.memory_size, &opt.architecture)
+        })
+        .collect::<Vec<_>>();
+    let members = specs
+        .iter()
+        .map(|spec| spec.name().to_string())
+        .collect::<Vec<_>>();
+    deploy_functions(specs, &members).await
+}
+
+/// Reconciles the functions with their contexts, so that the rerun of a
+/// deployment that died halfway creates the missing functions and updates the
+/// others where they differ (see [`flock::aws::reconcile`]). The members of
+/// the function groups reserve one execution each.
+async fn deploy_functions(specs: Vec<FunctionSpec>, members: &[String]) -> Result<()> {
+    let report = reconcile_functions(&AwsCloudClient, &specs, Polling::default()).await?;
+    info!("{}", report);
+    report.check()?;
+    for member in members {
+        lambda::set_concurrency(member, 1).await?;
+    }
+    Ok(())
+}
+
+/// Creates the functions of the broadcast join of NEXMark Q7 (see
+/// [`flock::runtime::broadcast`]), and returns the first stage:
+///
+/// - `q7-00` computes the maximum price of each partition of the window, and
+///   stashes the bids of the partition in S3.
+/// - `q7-01` is a group whose member combines the maximum prices of the window,
+///   and broadcasts the global maximum as the side input of the window.
+/// - `q7-02` keeps the bids of each partition equal to the global maximum.
+async fn create_broadcast_join_functions(
+    opt: &NexmarkBenchmarkOpt,
+    window: Window,
+    plans: &[Arc<dyn ExecutionPlan>],
+) -> Result<CloudFunction> {
+    assert_eq!(plans.len(), 3);
+    let state_backend = nexmark_state_backend(opt);
+    let stash = CloudFunction::Lambda(format!("q{}-00", opt.query_number));
+    let combiner = format!("q{}-01", opt.query_number);
+    let concurrency = nexmark_group_size(opt);
+    let probe = format!("q{}-02", opt.query_number);
+    let zstd_dict = nexmark_dictionary(opt, &window).await?;
+
+    let nexmark_source_ctx = ExecutionContext {
+        plan:          CloudExecutionPlan::new(vec![FLOCK_EMPTY_PLAN.clone()], None),
+        name:          FLOCK_DATA_SOURCE_FUNC_NAME.clone(),
+        next:          stash.clone(),
+        state_backend: state_backend.clone(),
+        region:        flock_region(),
+        window:        Some(window.clone()),
+        zstd_dict:     zstd_dict.clone(),
+        ..Default::default()
+    };
+
+    let stash_ctx = ExecutionContext {
+        plan:              CloudExecutionPlan::new(vec![plans[0].clone()], None),
+        name:              format!("q{}-00", opt.query_number),
+        next:              CloudFunction::Group((combiner.clone(), concurrency)),
+        state_backend:     state_backend.clone(),
+        region:            flock_region(),
+        window:            Some(window.clone()),
+        broadcast:         Some(BroadcastRole::Stash),
+        state_persistence: opt.state_persistence.unwrap_or_default(),
+        zstd_dict:         zstd_dict.clone(),
+        ..Default::default()
+    };
+
+    let combiner_ctx = ExecutionContext {
+        plan:          CloudExecutionPlan::new(vec![plans[1].clone()], None),
+        name:          combiner.clone(),
+        next:          CloudFunction::Lambda(probe.clone()),
+        state_backend: state_backend.clone(),
+        region:        flock_region(),
+        window:        Some(window.clone()),
+        broadcast:     Some(BroadcastRole::Broadcast),
+        zstd_dict:     zstd_dict.clone(),
+        ..Default::default()
+    };
+
+    let probe_ctx = ExecutionContext {
+        plan:           CloudExecutionPlan::new(vec![plans[2].clone()], None),
+        name:           probe.clone(),
+        next:           CloudFunction::Sink(DataSinkType::new(&opt.data_sink_type)?),
+        state_backend:  state_backend.clone(),
+        region:         flock_region(),
+        window:         Some(window),
+        window_columns: opt.window_columns,
+        result_cache:   opt.use_result_cache.then(|| plan_hash(&[plans[2].clone()])),
+        zstd_dict,
+        ..Default::default()
+    };
+
+    create_source_function(opt, &nexmark_source_ctx).await?;
+
+    let specs = [stash_ctx, probe_ctx]
+        .into_iter()
+        .map(|ctx| {
+            info!("Creating lambda function: {}", rainbow_string(&ctx.name));
+            FunctionSpec::new(ctx, opt.memory_size, &opt.architecture)
+        })
+        .collect();
+    deploy_functions(specs, &[]).await?;
+
+    info!(
+        "Creating lambda function group: {}",
+        rainbow_string(format!("({}, {})", combiner, concurrency))
+    );
+    create_function_group(opt, &combiner_ctx, &combiner, concurrency).await?;
+
+    Ok(stash)
+}
+
 /// Create an Elastic file system access point for Flock.
 #[allow(dead_code)]
 async fn create_file_system() -> Result<String> {
@@ -293,6 +837,14 @@ pub async fn create_physical_plans(
     query_number: usize,
 ) -> Result<Vec<Arc<dyn ExecutionPlan>>> {
     let mut plans = vec![];
+    if query_number == 7 {
+        // The stages of the broadcast join (see `create_nexmark_functions`).
+        for sql in nexmark_q7_stages() {
+            plans.push(physical_plan(ctx, &sql).await?);
+        }
+        return Ok(plans);
+    }
+
     plans.push(physical_plan(ctx, &nexmark_query(query_number)[0]).await?);
 
     if query_number == 12 {
@@ -325,52 +877,330 @@ pub async fn create_physical_plans(
 
 pub async fn add_extra_metadata(
     opt: &NexmarkBenchmarkOpt,
-    metadata: &mut HashMap<String, String>,
+    metadata: &mut QueryMetadata,
 ) -> Result<()> {
-    metadata.insert(
-        "invocation_type".to_string(),
-        if opt.async_type {
-            "async".to_string()
-        } else {
-            "sync".to_string()
-        },
-    );
+    metadata.invocation_type = Some(if opt.async_type {
+        InvocationType::Async
+    } else {
+        InvocationType::Sync
+    });
+
+    if opt.analyze {
+        metadata.insert(ANALYZE_METADATA_KEY.to_string(), "true".to_string());
+    }
+
+    // The analyze report is only complete once the last stage has processed
+    // all windows, which the completion protocol tells.
+    if opt.async_type || opt.analyze {
+        metadata.insert(COMPLETION_METADATA_KEY.to_string(), "true".to_string());
+    }
+
+    if let Some(path) = &opt.chaos {
+        ChaosSpec::from_file(path)?.stamp(metadata)?;
+    }
 
     if opt.query_number == 12 {
-        metadata.insert(
-            "add_process_time_query".to_string(),
-            nexmark_query(opt.query_number)[0].clone(),
-        );
+        metadata.add_column = Some(AddColumn::process_time("p_time"));
     }
 
     if opt.query_number == 11 || opt.query_number == 12 {
-        metadata.insert("session_key".to_string(), "bidder".to_string());
-        metadata.insert("session_name".to_string(), "bid".to_string());
+        metadata.session_keys = Some(SessionKeys {
+            key:  "bidder".to_string(),
+            name: "bid".to_string(),
+        });
     }
 
-    if opt.query_number == 13 {
+    if opt.query_number == 11 {
+        // The session windows are closed by the watermarks of all generators.
         metadata.insert(
-            "side_input_s3_key".to_string(),
-            NEXMARK_Q13_S3_SIDE_INPUT_KEY.clone(),
+            SESSION_TIME_METADATA_KEY.to_string(),
+            "b_date_time".to_string(),
         );
-        metadata.insert("side_input_format".to_string(), "csv".to_string());
-
-        let side_input_schema = Arc::new(side_input_schema());
         metadata.insert(
-            "side_input_schema".to_string(),
-            base64::encode(schema_to_bytes(side_input_schema)),
+            UPSTREAMS_METADATA_KEY.to_string(),
+            opt.generators.to_string(),
         );
     }
 
+    if opt.query_number == 13 {
+        let side_input_schema = Arc::new(side_input_schema());
+        metadata.side_input = Some(SideInput {
+            s3_key:     nexmark_s3_key(13, &NEXMARK_Q13_S3_SIDE_INPUT_KEY),
+            format:     "csv".to_string(),
+            schema:     base64::encode(schema_to_bytes(side_input_schema)),
+            projection: vec![],
+            predicate:  None,
+        });
+    }
+
     Ok(())
 }
 
 pub async fn nexmark_benchmark(opt: &mut NexmarkBenchmarkOpt) -> Result<()> {
-    if opt.distributed {
-        distributed::nexmark_benchmark(opt).await
+    if opt.dry_run {
+        println!("{}", nexmark_dry_run(opt).await?);
+        return Ok(());
+    }
+    if let Some(queries) = opt.queries.clone() {
+        let report = nexmark_batch(opt, &queries).await?;
+        if report.failures() > 0 {
+            return Err(FlockError::Internal(format!(
+                "{} of {} queries failed",
+                report.failures(),
+                report.results.len()
+            )));
+        }
+        return Ok(());
+    }
+    let (result, outcome) = run_and_record(opt).await;
+    let mut report = BatchReport::new();
+    report.push(result);
+    print_summary(opt, &report)?;
+    outcome
+}
+
+/// Runs the queries one by one with the same options, and prints the
+/// comparison report. The failure of a query doesn't abort the others, and
+/// it's recorded in the report instead.
+pub async fn nexmark_batch(opt: &NexmarkBenchmarkOpt, queries: &QueryList) -> Result<BatchReport> {
+    let mut report = BatchReport::new();
+    for query_number in &queries.0 {
+        let mut query_opt = NexmarkBenchmarkOpt {
+            query_number: *query_number,
+            queries: None,
+            report: None,
+            ..opt.clone()
+        };
+        let (result, outcome) = run_and_record(&mut query_opt).await;
+        if let Err(e) = &outcome {
+            warn!("Query q{} failed: {}", query_number, e);
+        }
+        report.push(result);
+    }
+
+    if output_mode() == OutputMode::Pretty {
+        rainbow_println("================================================================");
+        rainbow_println("                     Comparison Report                          ");
+        rainbow_println("================================================================");
+        println!("{}", report.render());
+    }
+    print_summary(opt, &report)?;
+    if let Some(path) = &opt.report {
+        report.write(path)?;
+        info!("[OK] The comparison report is written to {}", path);
+    }
+    Ok(report)
+}
+
+/// Runs a single query, and records its latency, its estimated cost and its
+/// failure in the result. The outcome of the run is returned as well.
+async fn run_and_record(opt: &mut NexmarkBenchmarkOpt) -> (QueryResult, Result<()>) {
+    let mut result = QueryResult {
+        query_number: opt.query_number,
+        ..Default::default()
+    };
+    let start = Instant::now();
+    let outcome = run_nexmark_query(opt, &mut result).await;
+    result.latency = start.elapsed();
+    result.cost = estimated_cost(result.latency, &nexmark_functions(opt), &opt.architecture);
+    result.error = outcome.as_ref().err().map(|e| e.to_string());
+    (result, outcome)
+}
+
+/// Prints the summary of the run in the `json` and the `quiet` output modes:
+/// the JSON document of the options and the report, or its summary line. The
+/// `pretty` mode prints the results as they come instead.
+fn print_summary(opt: &NexmarkBenchmarkOpt, report: &BatchReport) -> Result<()> {
+    match output_mode() {
+        OutputMode::Json => println!(
+            "{}",
+            serde_json::to_string(&report.to_document(nexmark_options(opt)))?
+        ),
+        OutputMode::Quiet => println!("{}", report.summary_line()),
+        OutputMode::Pretty => {}
+    }
+    Ok(())
+}
+
+/// Returns the options of the run as JSON.
+pub fn nexmark_options(opt: &NexmarkBenchmarkOpt) -> Value {
+    json!({
+        "query_number": opt.query_number,
+        "queries": opt.queries.as_ref().map(|q| q.0.clone()),
+        "generators": opt.generators,
+        "seconds": opt.seconds,
+        "events_per_second": opt.events_per_second,
+        "data_sink_type": opt.data_sink_type,
+        "async": opt.async_type,
+        "memory_size": opt.memory_size,
+        "architecture": opt.architecture,
+        "distributed": opt.distributed,
+        "state_backend": opt.state_backend,
+        "region": opt.region,
+        "window": nexmark_window(opt).to_string(),
+        "coordinator": opt.coordinator,
+        "group_size": nexmark_group_size(opt),
+        "analyze": opt.analyze,
+        "auto_memory": opt.auto_memory,
+        "use_result_cache": opt.use_result_cache,
+        "state_persistence": opt.state_persistence.map(|p| format!("{:?}", p)),
+        "running_aggregate": opt.running_aggregate,
+        "chaos": opt.chaos,
+        "embedded_plans": opt.embedded_plans,
+    })
+}
+
+/// Returns the memory size in MB and the number of the functions deployed for
+/// the query, which are used to estimate its cost.
+fn nexmark_functions(opt: &NexmarkBenchmarkOpt) -> Vec<(i64, usize)> {
+    // The data source functions have 4 GB of memory (see
+    // `create_source_function`). The workers are counted as a function group
+    // per stage, and most queries have two stages in the distributed mode.
+    let workers = if opt.distributed {
+        nexmark_group_size(opt) * 2
     } else {
-        centralized::nexmark_benchmark(opt).await
+        nexmark_group_size(opt)
+    };
+    vec![(4096, opt.generators), (opt.memory_size, workers)]
+}
+
+/// Runs a single query, and records the number of result rows read from the
+/// data sink, the stage metrics and the processed windows in the result, if
+/// they're collected.
+async fn run_nexmark_query(opt: &mut NexmarkBenchmarkOpt, result: &mut QueryResult) -> Result<()> {
+    set_flock_region(&opt.region)?;
+    if opt.analyze {
+        // All stages must be finished before the driver collects the telemetry.
+        opt.async_type = false;
+        AnalyzeReport::clear(&format!("q{}", opt.query_number)).await?;
+    }
+    if opt.async_type || opt.analyze {
+        // The markers are keyed on the query code, even if the query runs on
+        // the functions shared by its topology (see `--multiplex`).
+        CompletionManifest::clear(&AwsCloudClient, &format!("q{}", opt.query_number)).await?;
+    }
+    if opt.running_aggregate && !opt.distributed {
+        return Err(FlockError::NotImplemented(
+            "The running aggregates are accumulated in the distributed mode only".to_string(),
+        ));
+    }
+    if opt.distributed && opt.query_number != 7 {
+        // Q7 always runs as a broadcast join over three stages, which is set up
+        // by `create_nexmark_functions`.
+        distributed::nexmark_benchmark(opt, result).await
+    } else {
+        centralized::nexmark_benchmark(opt, result).await
+    }
+}
+
+/// Prints the query stages annotated with the telemetry reported by the cloud
+/// functions in the analyze mode, and returns the metrics of the stages. The
+/// telemetry is fetched once all windows are processed, since the later stages
+/// may still be running when the data source functions return.
+pub async fn print_analyze_report(
+    opt: &NexmarkBenchmarkOpt,
+    stages: Vec<String>,
+) -> Result<Vec<StageMetrics>> {
+    let query_code = format!("q{}", opt.query_number);
+    let manifest = wait_for_completion(
+        &query_code,
+        None,
+        std::time::Duration::from_secs(opt.timeout),
+    )
+    .await?;
+    if !manifest.is_complete(None) {
+        warn!("The analyze report of {} covers the processed windows only.", query_code);
+    }
+    let report = AnalyzeReport::fetch(&query_code).await?;
+    rainbow_println("================================================================");
+    rainbow_println("                      EXPLAIN ANALYZE                           ");
+    rainbow_println("================================================================");
+    plain_println(report.render(&stages));
+    Ok(report.stages().cloned().collect())
+}
+
+/// Waits for all windows of the asynchronous run to be processed, prints the
+/// summary of the run and the event-time latency of the committed windows,
+/// and returns the number of processed windows and the number of expected
+/// windows.
+pub async fn wait_for_windows(opt: &NexmarkBenchmarkOpt) -> Result<(usize, Option<usize>)> {
+    info!("Waiting for all windows to be processed.");
+    let manifest = wait_for_completion(
+        &format!("q{}", opt.query_number),
+        None,
+        std::time::Duration::from_secs(opt.timeout),
+    )
+    .await?;
+    rainbow_println(format!(
+        "[OK] Windows processed: {} / {}",
+        manifest.completed_windows(),
+        manifest
+            .expected_windows()
+            .map(|n| n.to_string())
+            .unwrap_or_else(|| "unknown".to_string())
+    ));
+    // The state buckets are deleted while the results are read back.
+    let cleanup = opt
+        .cleanup
+        .then(|| cleanup_state_buckets(&format!("q{}", opt.query_number)));
+    if let Some(endpoint) = &opt.results_server {
+        read_window_results(endpoint, &format!("q{}", opt.query_number)).await?;
+    }
+    if let Some(store) = ResultStore::for_sink(
+        &DataSinkType::new(&opt.data_sink_type)?,
+        Arc::new(AwsCloudClient),
+    ) {
+        let query_code = format!("q{}", opt.query_number);
+        let samples = samples_from_manifests(&store, &query_code).await?;
+        plain_println(LatencyReport::new(query_code, &samples).render());
+    }
+    if let Some(cleanup) = cleanup {
+        cleanup
+            .await
+            .map_err(|e| FlockError::Internal(e.to_string()))??;
+    }
+    Ok((manifest.completed_windows(), manifest.expected_windows()))
+}
+
+/// Reads the results of the processed windows back from the results server,
+/// and prints the number of rows of them.
+pub async fn read_window_results(endpoint: &str, query_code: &str) -> Result<()> {
+    let mut client = ResultsClient::connect(endpoint).await?;
+    // The completion manifest records the logical windows, so the results are
+    // read by the ids of the windows written to the results server instead.
+    let written = client.list_windows(query_code).await?;
+    let (mut windows, mut rows) = (0, 0);
+    let mut results = vec![];
+    for window_id in &written {
+        let batches = client.get_window(query_code, window_id).await?;
+        rows += batches.iter().map(|b| b.num_rows()).sum::<usize>();
+        windows += 1;
+        results.extend(batches);
     }
+    rainbow_println(format!(
+        "[OK] Results read from {}: {} rows in {} windows",
+        endpoint, rows, windows
+    ));
+    // The results with the window columns are aligned by the window bounds
+    // instead of the window ids, e.g. to compare them with the results of
+    // the same windows computed locally.
+    if results
+        .first()
+        .map_or(false, |b| b.schema().index_of(WINDOW_END_COLUMN).is_ok())
+    {
+        for (bounds, batches) in split_by_window(&results)? {
+            rainbow_println(format!(
+                "  window [{}, {}): {} rows",
+                bounds
+                    .start
+                    .map(|start| start.to_string())
+                    .unwrap_or_else(|| "?".to_string()),
+                bounds.end,
+                batches.iter().map(|b| b.num_rows()).sum::<usize>()
+            ));
+        }
+    }
+    Ok(())
 }
 
 /// Returns Nextmark query strings based on the query number.
@@ -397,12 +1227,41 @@ pub fn nexmark_query(query_number: usize) -> Vec<String> {
     .collect()
 }
 
+/// Returns the SQL queries of the stages of the broadcast join in NEXMark Q7:
+/// the maximum price of each partition, the maximum price of the window, and
+/// the bids at the maximum price.
+pub fn nexmark_q7_stages() -> Vec<String> {
+    include_str!("query/q7_broadcast.sql")
+        .split(';')
+        .map(str::trim)
+        .filter(|sql| !sql.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Returns the SQL query of the stages with the running aggregates of the
+/// query over the whole run, and the running aggregates accumulated by its last
+/// stage (see [`flock::runtime::running_aggregate`]).
+pub fn nexmark_running_query(query_number: usize) -> Result<(String, RunningAggregate)> {
+    let sql = match query_number {
+        4 => include_str!("query/q4_running.sql"),
+        _ => {
+            return Err(FlockError::NotImplemented(format!(
+                "NEXMark Q{} has no running aggregates",
+                query_number
+            )))
+        }
+    };
+    let (sql, running) = rewrite_cumulative(sql)?;
+    Ok((sql, running.unwrap_or_default()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use datafusion::arrow::util::pretty::pretty_format_batches;
     use flock::transmute::event_bytes_to_batch;
-    use nexmark::register_nexmark_tables;
+    use nexmark::{register_nexmark_tables, register_nexmark_tables_for_query};
     use std::fs::File;
     use std::io::Write;
     use std::time::Instant;
@@ -437,7 +1296,7 @@ mod tests {
         let ctx = register_nexmark_tables().await?;
         for sql in sqls {
             let plan = physical_plan(&ctx, &sql[0]).await?;
-            let mut flock_ctx = ExecutionContext {
+            let flock_ctx = ExecutionContext {
                 plan: CloudExecutionPlan::new(vec![plan], None),
                 ..Default::default()
             };
@@ -464,6 +1323,48 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn nexmark_q7_broadcast_join_plans() -> Result<()> {
+        let mut ctx = register_nexmark_tables_for_query(7).await?;
+        let plans = create_physical_plans(&mut ctx, 7).await?;
+        assert_eq!(plans.len(), 3);
+
+        // The first two stages produce the input of the `max_price` table.
+        for plan in &plans[..2] {
+            let schema = plan.schema();
+            assert_eq!(schema.fields().len(), 1);
+            assert_eq!(schema.field(0).name(), "maxprice");
+            assert_eq!(schema.field(0).data_type(), &DataType::Int32);
+        }
+
+        // The last stage has the same output as the query in a single function.
+        let names = |plan: &Arc<dyn ExecutionPlan>| {
+            plan.schema()
+                .fields()
+                .iter()
+                .map(|f| f.name().clone())
+                .collect::<Vec<_>>()
+        };
+        let query = physical_plan(&ctx, &nexmark_query(7)[0]).await?;
+        assert_eq!(names(&plans[2]), names(&query));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nexmark_q4_running_plan() -> Result<()> {
+        let ctx = register_nexmark_tables_for_query(4).await?;
+        let (sql, running) = nexmark_running_query(4)?;
+        let plan = physical_plan(&ctx, &sql).await?;
+        let schema = plan.schema();
+        for column in running.group_by.iter().chain(&running.hidden) {
+            assert!(schema.index_of(column).is_ok());
+        }
+        assert!(running.columns.iter().all(|c| schema.index_of(&c.input).is_ok()));
+        assert_eq!(running.group_by, vec!["category"]);
+        assert!(nexmark_running_query(3).is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn nexmark_display_graphviz() -> Result<()> {
         let sqls = vec![
@@ -0,0 +1,13 @@
+SELECT Max(price) AS maxprice
+FROM   bid;
+
+SELECT Max(maxprice) AS maxprice
+FROM   max_price;
+
+SELECT auction,
+       price,
+       bidder,
+       b_date_time
+FROM   bid
+       JOIN max_price
+         ON price = maxprice;
@@ -0,0 +1,303 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The comparison report of the NEXMark queries that run in the batch mode.
+//!
+//! The report lists the latency, the estimated cost and the number of result
+//! rows of each query, and it's written as a JSON file as well, so that the
+//! reports of two commits can be diffed to catch the performance regressions.
+//!
+//! A single query is reported the same way, which is the JSON document that
+//! the benchmark prints in the `json` output mode (see
+//! [`BatchReport::to_document`]).
+
+pub use flock::driver::funcgen::estimate::estimated_cost;
+use flock::error::{FlockError, Result};
+use flock::runtime::analyze::StageMetrics;
+use serde_json::{json, Value};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The highest NEXMark query number.
+const MAX_QUERY_NUMBER: usize = 13;
+
+/// The query numbers of the batch mode, e.g. `1-8` or `1,3,5-7`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryList(pub Vec<usize>);
+
+impl FromStr for QueryList {
+    type Err = FlockError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || FlockError::Internal(format!("Invalid query list: {}", s));
+        let mut queries = vec![];
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let (start, end) = match item.split_once('-') {
+                Some((start, end)) => (start.trim(), end.trim()),
+                None => (item, item),
+            };
+            let start = start.parse::<usize>().map_err(|_| invalid())?;
+            let end = end.parse::<usize>().map_err(|_| invalid())?;
+            if start > end || end > MAX_QUERY_NUMBER {
+                return Err(invalid());
+            }
+            queries.extend(start..=end);
+        }
+        if queries.is_empty() {
+            return Err(invalid());
+        }
+        let mut seen = std::collections::HashSet::new();
+        queries.retain(|q| seen.insert(*q));
+        Ok(QueryList(queries))
+    }
+}
+
+/// The result of a query in the batch mode.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryResult {
+    /// The query number.
+    pub query_number: usize,
+    /// The end-to-end latency of the query, including the deployment.
+    pub latency:      Duration,
+    /// The estimated cost of the query in USD (see [`estimated_cost`]).
+    pub cost:         f64,
+    /// The number of result rows read from the data sink, if the results are
+    /// collected.
+    pub rows:         Option<usize>,
+    /// The error of the query if it failed.
+    pub error:        Option<String>,
+    /// The metrics of the query stages observed in the analyze mode.
+    pub stages:       Vec<StageMetrics>,
+    /// The number of processed windows and the number of expected windows, if
+    /// the driver waited for the windows in the async mode.
+    pub windows:      Option<(usize, Option<usize>)>,
+}
+
+impl QueryResult {
+    /// Returns true if the query succeeded.
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Returns true if the query succeeded and all its expected windows are
+    /// processed. The windows of an unknown count aren't validated.
+    pub fn is_valid(&self) -> bool {
+        self.is_ok()
+            && match self.windows {
+                Some((completed, Some(expected))) => completed >= expected,
+                _ => true,
+            }
+    }
+}
+
+/// The comparison report of the queries in the batch mode.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BatchReport {
+    /// The results of the queries in the order they ran.
+    pub results: Vec<QueryResult>,
+}
+
+impl BatchReport {
+    /// Creates an empty report.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the result of a query to the report.
+    pub fn push(&mut self, result: QueryResult) {
+        self.results.push(result);
+    }
+
+    /// Returns the number of failed queries.
+    pub fn failures(&self) -> usize {
+        self.results.iter().filter(|r| !r.is_ok()).count()
+    }
+
+    /// Returns the one-line summary of the report, which is the only output of
+    /// the `quiet` mode.
+    pub fn summary_line(&self) -> String {
+        format!(
+            "{} queries, {} failed, {} invalid, {} ms, {:.6} USD",
+            self.results.len(),
+            self.failures(),
+            self.results.iter().filter(|r| !r.is_valid()).count(),
+            self.results
+                .iter()
+                .map(|r| r.latency.as_millis())
+                .sum::<u128>(),
+            self.results.iter().map(|r| r.cost).sum::<f64>()
+        )
+    }
+
+    /// Renders the report as a table.
+    pub fn render(&self) -> String {
+        let mut lines = vec![
+            format!(
+                "| {:<5} | {:<6} | {:>12} | {:>12} | {:>10} | {:<40} |",
+                "query", "status", "latency (ms)", "cost (USD)", "rows", "error"
+            ),
+            format!(
+                "|{}|{}|{}|{}|{}|{}|",
+                "-".repeat(7),
+                "-".repeat(8),
+                "-".repeat(14),
+                "-".repeat(14),
+                "-".repeat(12),
+                "-".repeat(42)
+            ),
+        ];
+        for r in &self.results {
+            let mut error = r.error.clone().unwrap_or_default().replace('\n', " ");
+            if error.chars().count() > 40 {
+                error = format!("{}...", error.chars().take(37).collect::<String>());
+            }
+            lines.push(format!(
+                "| {:<5} | {:<6} | {:>12} | {:>12.6} | {:>10} | {:<40} |",
+                format!("q{}", r.query_number),
+                if r.is_ok() { "ok" } else { "failed" },
+                r.latency.as_millis(),
+                r.cost,
+                r.rows
+                    .map(|n| n.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                error
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// Returns the report as JSON.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "queries": self.results.iter().map(|r| json!({
+                "query": format!("q{}", r.query_number),
+                "status": if r.is_ok() { "ok" } else { "failed" },
+                "latency_ms": r.latency.as_millis() as u64,
+                "cost_usd": r.cost,
+                "rows": r.rows,
+                "error": r.error,
+                "stages": r.stages,
+                "windows": r.windows.map(|(completed, expected)| json!({
+                    "completed": completed,
+                    "expected": expected,
+                })),
+                "valid": r.is_valid(),
+            })).collect::<Vec<_>>(),
+            "failures": self.failures(),
+        })
+    }
+
+    /// Returns the JSON document of the benchmark run: the options of the run
+    /// and the report of its queries.
+    pub fn to_document(&self, options: Value) -> Value {
+        let mut document = self.to_json();
+        document["options"] = options;
+        document["summary"] = json!(self.summary_line());
+        document
+    }
+
+    /// Writes the report as JSON to the given path.
+    pub fn write(&self, path: &str) -> Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(&self.to_json())?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_query_list() -> Result<()> {
+        assert_eq!("1-8".parse::<QueryList>()?.0, (1..=8).collect::<Vec<_>>());
+        assert_eq!("3".parse::<QueryList>()?.0, vec![3]);
+        assert_eq!("1, 3,5-7".parse::<QueryList>()?.0, vec![1, 3, 5, 6, 7]);
+        assert_eq!("2-4,3".parse::<QueryList>()?.0, vec![2, 3, 4]);
+        assert!("8-1".parse::<QueryList>().is_err());
+        assert!("1-14".parse::<QueryList>().is_err());
+        assert!("q1".parse::<QueryList>().is_err());
+        assert!("".parse::<QueryList>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn report_failed_queries() {
+        let mut report = BatchReport::new();
+        report.push(QueryResult {
+            query_number: 1,
+            latency: Duration::from_millis(1500),
+            cost: 0.001,
+            rows: Some(42),
+            error: None,
+            windows: Some((10, Some(10))),
+            ..Default::default()
+        });
+        report.push(QueryResult {
+            query_number: 2,
+            latency: Duration::from_millis(300),
+            cost: 0.0,
+            rows: None,
+            error: Some("Internal error: the function timed out".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(report.failures(), 1);
+
+        let table = report.render();
+        assert_eq!(table.lines().count(), 4);
+        assert!(table.contains("| q1    | ok     |         1500 |"));
+        assert!(table.contains("| q2    | failed |"));
+
+        let json = report.to_json();
+        assert_eq!(json["failures"], 1);
+        assert_eq!(json["queries"][0]["rows"], 42);
+        assert_eq!(json["queries"][1]["status"], "failed");
+        assert_eq!(
+            json["queries"][1]["error"],
+            "Internal error: the function timed out"
+        );
+        assert_eq!(json["queries"][0]["windows"]["completed"], 10);
+        assert_eq!(json["queries"][0]["valid"], true);
+        assert_eq!(json["queries"][1]["valid"], false);
+    }
+
+    #[test]
+    fn report_document() {
+        let mut report = BatchReport::new();
+        report.push(QueryResult {
+            query_number: 3,
+            latency: Duration::from_millis(2000),
+            cost: 0.5,
+            windows: Some((7, Some(10))),
+            stages: vec![StageMetrics {
+                stage: 0,
+                rows_in: 100,
+                rows_out: 10,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        assert!(report.results[0].is_ok());
+        assert!(!report.results[0].is_valid());
+        assert_eq!(
+            report.summary_line(),
+            "1 queries, 0 failed, 1 invalid, 2000 ms, 0.500000 USD"
+        );
+
+        let document = report.to_document(json!({ "query_number": 3 }));
+        assert_eq!(document["options"]["query_number"], 3);
+        assert_eq!(document["queries"][0]["stages"][0]["rows_out"], 10);
+        assert_eq!(document["queries"][0]["windows"]["expected"], 10);
+        assert_eq!(document["summary"], report.summary_line());
+        assert!(serde_json::to_string(&document).unwrap().lines().count() == 1);
+    }
+}
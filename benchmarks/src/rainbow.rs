@@ -12,12 +12,123 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 //! A simple rainbow-colored logger.
+//!
+//! The output respects the [`OutputMode`] of the process, which the command
+//! line sets once with [`set_output_mode`]. The benchmark processes that it
+//! starts inherit it from the environment variable [`FLOCK_OUTPUT`] instead
+//! (see [`output_mode_env`]). In the `json` mode, the lines are printed
+//! without colors to stderr, so that stdout only carries the JSON document of
+//! the benchmark. In the `quiet` mode, they're dropped.
 
+use once_cell::sync::OnceCell;
 use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
 
-/// Prints the text in the rainbow fansion.
+/// The environment variable of the output mode of the child benchmark
+/// processes.
+pub const FLOCK_OUTPUT: &str = "FLOCK_OUTPUT";
+
+/// The output mode of the process.
+static OUTPUT_MODE: OnceCell<OutputMode> = OnceCell::new();
+
+/// How the command line tools and the benchmarks print their output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    /// The banners and the rainbow-colored lines for humans.
+    Pretty,
+    /// A single JSON document of the results on stdout, and the logs on
+    /// stderr.
+    Json,
+    /// The errors and the final summary line only.
+    Quiet,
+}
+
+impl Default for OutputMode {
+    fn default() -> Self {
+        OutputMode::Pretty
+    }
+}
+
+impl FromStr for OutputMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "pretty" => Ok(OutputMode::Pretty),
+            "json" => Ok(OutputMode::Json),
+            "quiet" => Ok(OutputMode::Quiet),
+            _ => Err(format!("Invalid output mode: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for OutputMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputMode::Pretty => write!(f, "pretty"),
+            OutputMode::Json => write!(f, "json"),
+            OutputMode::Quiet => write!(f, "quiet"),
+        }
+    }
+}
+
+/// Returns the output mode of the process. If it isn't set, it's the mode
+/// inherited from the parent in the environment variable [`FLOCK_OUTPUT`], or
+/// `pretty` if the variable isn't set or isn't valid.
+#[allow(dead_code)]
+pub fn output_mode() -> OutputMode {
+    static INHERITED: OnceCell<OutputMode> = OnceCell::new();
+    OUTPUT_MODE.get().copied().unwrap_or_else(|| {
+        *INHERITED.get_or_init(|| {
+            std::env::var(FLOCK_OUTPUT)
+                .ok()
+                .and_then(|mode| mode.parse().ok())
+                .unwrap_or_default()
+        })
+    })
+}
+
+/// Sets the output mode of the process. It's set only once, and the later
+/// calls are ignored.
+#[allow(dead_code)]
+pub fn set_output_mode(mode: OutputMode) {
+    let _ = OUTPUT_MODE.set(mode);
+}
+
+/// Returns the environment variable of the output mode of the process, to
+/// export to a child benchmark process, e.g. with `Command::envs`.
+#[allow(dead_code)]
+pub fn output_mode_env() -> [(&'static str, String); 1] {
+    [(FLOCK_OUTPUT, output_mode().to_string())]
+}
+
+/// Prints the banner, e.g. the logo of Flock, in the `pretty` mode only.
+#[allow(dead_code)]
+pub fn rainbow_banner<S: Into<String>>(text: S) {
+    if output_mode() == OutputMode::Pretty {
+        rainbow_println(text);
+    }
+}
+
+/// Prints the line without colors: to stdout in the `pretty` mode, and to
+/// stderr in the `json` mode. It's used for the tables of the results.
+#[allow(dead_code)]
+pub fn plain_println<S: Into<String>>(line: S) {
+    match output_mode() {
+        OutputMode::Pretty => println!("{}", line.into()),
+        OutputMode::Json => eprintln!("{}", line.into()),
+        OutputMode::Quiet => {}
+    }
+}
+
+/// Prints the text in the rainbow fansion, or as it is in the other modes
+/// (see [`plain_println`]).
 #[allow(dead_code)]
 pub fn rainbow_println<S: Into<String>>(line: S) {
+    if output_mode() != OutputMode::Pretty {
+        return plain_println(line);
+    }
     let frequency: f64 = 0.1;
     let spread: f64 = 3.0;
     for (i, c) in line.into().char_indices() {
@@ -31,9 +142,12 @@ pub fn rainbow_println<S: Into<String>>(line: S) {
     println!();
 }
 
-/// Converts a line to a rainbow-colored string.
+/// Converts a line to a rainbow-colored string in the `pretty` mode.
 #[allow(dead_code)]
 pub fn rainbow_string<S: Into<String>>(line: S) -> String {
+    if output_mode() != OutputMode::Pretty {
+        return line.into();
+    }
     let frequency: f64 = 0.1;
     let spread: f64 = 3.0;
     let mut result = String::new();
@@ -67,4 +181,15 @@ mod tests {
         let text = include_str!("../Cargo.toml");
         rainbow_println(text);
     }
+
+    #[test]
+    fn parse_output_mode() {
+        assert_eq!("json".parse::<OutputMode>(), Ok(OutputMode::Json));
+        assert_eq!(" Quiet ".parse::<OutputMode>(), Ok(OutputMode::Quiet));
+        assert_eq!("pretty".parse::<OutputMode>(), Ok(OutputMode::Pretty));
+        assert!("yaml".parse::<OutputMode>().is_err());
+        for mode in [OutputMode::Pretty, OutputMode::Json, OutputMode::Quiet] {
+            assert_eq!(mode.to_string().parse::<OutputMode>(), Ok(mode));
+        }
+    }
 }
@@ -16,13 +16,15 @@
 
 #[path = "../nexmark/main.rs"]
 mod nexmark_bench;
+use flock::aws::client::AwsCloudClient;
 use flock::aws::lambda;
 use flock::prelude::*;
+use flock::runtime::completion::{CompletionManifest, COMPLETION_METADATA_KEY};
+use flock::runtime::metadata::{InvocationType, S3Pointer, WORKERS_METADATA_KEY};
+use flock::runtime::response::Response;
 use log::info;
-use nexmark::register_nexmark_tables;
+use nexmark::register_nexmark_tables_for_query;
 use nexmark_bench::*;
-use serde_json::Value;
-use std::collections::HashMap;
 use std::time::SystemTime;
 use structopt::StructOpt;
 
@@ -36,39 +38,42 @@ async fn main() -> Result<()> {
 pub fn set_nexmark_config(opt: &mut NexmarkBenchmarkOpt) -> Result<()> {
     opt.async_type = false;
     opt.generators = 1;
-    match opt.query_number {
-        0..=4 | 6 | 9 | 13 => opt.seconds = 1, // ElementWise
-        5 | 7..=8 | 11..=12 => opt.seconds = 10,
-        _ => unreachable!(),
+    // The source function produces a single window in S3.
+    opt.seconds = match nexmark_window(opt) {
+        Window::ElementWise => 1,
+        _ => 10,
     };
     Ok(())
 }
 
 async fn benchmark(opt: &mut NexmarkBenchmarkOpt) -> Result<()> {
     set_nexmark_config(opt)?;
+    set_flock_region(&opt.region)?;
     info!(
         "Running the NEXMark benchmark [S3] with the following options: {:?}",
         opt
     );
     let nexmark_conf = create_nexmark_source(opt).await?;
     let query_number = opt.query_number;
+    let query_code = format!("q{}", query_number);
+    CompletionManifest::clear(&AwsCloudClient, &query_code).await?;
 
-    let mut ctx = register_nexmark_tables().await?;
+    let mut ctx = register_nexmark_tables_for_query(query_number).await?;
     let plans = create_physical_plans(&mut ctx, query_number).await?;
-    let worker = create_nexmark_functions(
-        opt,
-        nexmark_conf.window.clone(),
-        plans.last().unwrap().clone(),
-    )
-    .await?;
+    let worker = create_nexmark_functions(opt, nexmark_conf.window.clone(), &plans).await?;
 
     // The source generator function needs the metadata to determine the type of the
     // workers such as single function or a group. We don't want to keep this info
     // in the environment as part of the source function. Otherwise, we have to
     // *delete* and **recreate** the source function every time we change the query.
-    let mut metadata = HashMap::new();
-    metadata.insert("workers".to_string(), serde_json::to_string(&worker)?);
-    metadata.insert("invocation_type".to_string(), "sync".to_string());
+    let mut metadata = QueryMetadata {
+        invocation_type: Some(InvocationType::Sync),
+        ..Default::default()
+    };
+    metadata.insert(
+        WORKERS_METADATA_KEY.to_string(),
+        serde_json::to_string(&worker)?,
+    );
 
     let start_time = SystemTime::now();
     info!(
@@ -83,7 +88,9 @@ async fn benchmark(opt: &mut NexmarkBenchmarkOpt) -> Result<()> {
     })?
     .into();
 
-    let resp: Value = serde_json::from_slice(
+    // The source function responds with the S3 object of the window and the
+    // worker function to read it (see `flock::runtime::response`).
+    let resp = Response::from_slice(
         &lambda::invoke_function(
             &FLOCK_DATA_SOURCE_FUNC_NAME,
             &FLOCK_LAMBDA_SYNC_CALL,
@@ -92,22 +99,23 @@ async fn benchmark(opt: &mut NexmarkBenchmarkOpt) -> Result<()> {
         .await?
         .payload
         .expect("No response"),
-    )?;
+    )?
+    .into_result()?
+    .into_data();
 
     info!("Recieved response from the source function: {:#?}", resp);
 
     let function_name = resp["function"].as_str().unwrap().to_string();
     let sync = true;
 
-    let mut metadata: HashMap<String, String> = HashMap::new();
-    metadata.insert(
-        "s3_bucket".to_string(),
-        resp["bucket"].as_str().unwrap().to_string(),
-    );
-    metadata.insert(
-        "s3_key".to_string(),
-        resp["key"].as_str().unwrap().to_string(),
-    );
+    let mut metadata = QueryMetadata {
+        s3: Some(S3Pointer {
+            bucket: resp["bucket"].as_str().unwrap().to_string(),
+            key:    resp["key"].as_str().unwrap().to_string(),
+        }),
+        ..Default::default()
+    };
+    metadata.insert(COMPLETION_METADATA_KEY.to_string(), "true".to_string());
 
     let payload = serde_json::to_vec(&Payload {
         query_number: Some(query_number),
@@ -120,13 +128,22 @@ async fn benchmark(opt: &mut NexmarkBenchmarkOpt) -> Result<()> {
     .into();
 
     info!("[OK] Invoking NEXMark worker function: {}", function_name);
-    let resp: Value = serde_json::from_slice(
+    let resp = Response::from_slice(
         &lambda::invoke_function(&function_name, &FLOCK_LAMBDA_SYNC_CALL, Some(payload))
             .await?
             .payload
             .expect("No response"),
-    )?;
+    )?
+    .into_result()?;
     info!("[OK] Received response: {:?}", resp);
+
+    // The source function produces a single window in S3.
+    wait_for_completion(
+        &query_code,
+        Some(1),
+        std::time::Duration::from_secs(opt.timeout),
+    )
+    .await?;
     let end_time = SystemTime::now();
 
     info!(
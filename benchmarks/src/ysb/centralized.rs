@@ -11,22 +11,26 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-#[path = "../rainbow.rs"]
-mod rainbow;
+use benchmarks::rainbow;
 
 use super::create_ysb_source;
+use super::wait_for_windows;
 use super::ysb_query;
+use super::ysb_window;
 use crate::YSBBenchmarkOpt;
 use datafusion::arrow::util::pretty::pretty_format_batches;
 use datafusion::physical_plan::ExecutionPlan;
 use flock::aws::{cloudwatch, lambda};
 use flock::prelude::*;
+use flock::runtime::arena::UPSTREAM_METADATA_KEY;
+use flock::runtime::completion::COMPLETION_METADATA_KEY;
+use flock::runtime::metadata::{InvocationType, WORKERS_METADATA_KEY};
+use flock::runtime::result_cache::plan_hash;
 use humantime::parse_duration;
 use lazy_static::lazy_static;
 use log::info;
-use rainbow::{rainbow_println, rainbow_string};
+use rainbow::{plain_println, rainbow_println, rainbow_string};
 use rusoto_lambda::InvocationResponse;
-use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::task::JoinHandle;
 use ysb::register_ysb_tables;
@@ -51,22 +55,28 @@ pub async fn ysb_benchmark(opt: &mut YSBBenchmarkOpt) -> Result<()> {
     // workers such as single function or a group. We don't want to keep this info
     // in the environment as part of the source function. Otherwise, we have to
     // *delete* and **recreate** the source function every time we change the query.
-    let mut metadata = HashMap::new();
-    metadata.insert("workers".to_string(), serde_json::to_string(&root_actor)?);
-    metadata.insert(
-        "invocation_type".to_string(),
-        if opt.async_type {
-            "async".to_string()
+    let mut metadata = QueryMetadata {
+        invocation_type: Some(if opt.async_type {
+            InvocationType::Async
         } else {
-            "sync".to_string()
-        },
+            InvocationType::Sync
+        }),
+        ..Default::default()
+    };
+    if opt.async_type {
+        metadata.insert(COMPLETION_METADATA_KEY.to_string(), "true".to_string());
+    }
+    metadata.insert(
+        WORKERS_METADATA_KEY.to_string(),
+        serde_json::to_string(&root_actor)?,
     );
 
     let tasks = (0..opt.generators)
         .into_iter()
         .map(|i| {
             let s = ysb_conf.clone();
-            let m = metadata.clone();
+            let mut m = metadata.clone();
+            m.insert(UPSTREAM_METADATA_KEY.to_string(), i.to_string());
             tokio::spawn(async move {
                 info!(
                     "[OK] Invoking YSB source function: {} by generator {}\n",
@@ -92,6 +102,10 @@ pub async fn ysb_benchmark(opt: &mut YSBBenchmarkOpt) -> Result<()> {
 
     futures::future::join_all(tasks).await;
 
+    if opt.async_type {
+        wait_for_windows(opt).await?;
+    }
+
     info!("Waiting for the current invocations to be logged.");
     tokio::time::sleep(parse_duration("5s").unwrap()).await;
     cloudwatch::fetch(&YSB_SOURCE_LOG_GROUP, parse_duration("1min").unwrap()).await?;
@@ -107,7 +121,7 @@ pub async fn ysb_benchmark(opt: &mut YSBBenchmarkOpt) -> Result<()> {
         info!("[OK] Last data sink function: {}", data_sink.function_name);
         let function_log_group = format!("/aws/lambda/{}", data_sink.function_name);
         cloudwatch::fetch(&function_log_group, parse_duration("1min").unwrap()).await?;
-        println!("{}", pretty_format_batches(&data_sink.record_batches)?);
+        plain_println(pretty_format_batches(&data_sink.record_batches)?.to_string());
     }
 
     Ok(())
@@ -128,13 +142,21 @@ async fn create_ysb_functions(
         plan: CloudExecutionPlan::new(vec![FLOCK_EMPTY_PLAN.clone()], None),
         name: FLOCK_DATA_SOURCE_FUNC_NAME.clone(),
         next: next_func_name.clone(),
+        region: flock_region(),
+        window: Some(ysb_window(opt)),
         ..Default::default()
     };
 
+    let result_cache = opt
+        .use_result_cache
+        .then(|| plan_hash(&[physcial_plan.clone()]));
     let ysb_worker_ctx = ExecutionContext {
         plan: CloudExecutionPlan::new(vec![physcial_plan], None),
         name: worker_func_name.clone(),
         next: CloudFunction::Sink(DataSinkType::new(&opt.data_sink_type)?),
+        region: flock_region(),
+        window: Some(ysb_window(opt)),
+        result_cache,
         ..Default::default()
     };
 
@@ -13,27 +13,34 @@
 
 extern crate daggy;
 
-#[path = "../rainbow.rs"]
-mod rainbow;
+use benchmarks::rainbow;
 
 use super::create_ysb_source;
+use super::wait_for_windows;
 use super::ysb_query;
 use crate::YSBBenchmarkOpt;
 
 use daggy::NodeIndex;
+use datafusion::arrow::util::pretty::pretty_format_batches;
 use datafusion::execution::context::ExecutionConfig;
 use flock::aws::lambda;
 use flock::distributed_plan::QueryDag;
+use flock::driver::stepfunctions::{
+    collect_results, epoch_payloads, upload_payloads, use_step_functions, Coordinator, StateMachine,
+};
 use flock::prelude::*;
+use flock::runtime::arena::UPSTREAM_METADATA_KEY;
+use flock::runtime::completion::COMPLETION_METADATA_KEY;
+use flock::runtime::metadata::InvocationType;
 use humantime::parse_duration;
 use lazy_static::lazy_static;
 use log::info;
-use rainbow::{rainbow_println, rainbow_string};
+use rainbow::{plain_println, rainbow_println, rainbow_string};
 use rusoto_lambda::InvocationResponse;
-use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::task::JoinHandle;
 use ysb::register_ysb_tables_with_config;
+use ysb::YSBSource;
 
 lazy_static! {
     pub static ref YSB_SOURCE_LOG_GROUP: String = "/aws/lambda/flock_datasource".to_string();
@@ -64,7 +71,15 @@ pub async fn ysb_benchmark(opt: &mut YSBBenchmarkOpt) -> Result<()> {
 
     let mut launcher =
         AwsLambdaLauncher::try_new(query_code, plan, sink_type, state_backend).await?;
+    launcher.window = Some(ysb_conf.window.clone());
+    launcher.result_cache = opt.use_result_cache;
+    launcher.distribution_keys = opt.distribute_by.clone();
     launcher.create_cloud_contexts(*FLOCK_FUNCTION_CONCURRENCY)?;
+    // The distribution keys must be the hash partitioning columns of the stage.
+    launcher.lint().check(true)?;
+    if opt.coordinator == Coordinator::StepFunctions {
+        use_step_functions(&mut launcher.dag);
+    }
 
     info!(
         "Streaming: {}",
@@ -89,21 +104,28 @@ pub async fn ysb_benchmark(opt: &mut YSBBenchmarkOpt) -> Result<()> {
     let dag = &mut launcher.dag;
     create_ysb_functions(dag, opt, *FLOCK_FUNCTION_CONCURRENCY).await?;
 
-    let mut metadata = HashMap::new();
-    metadata.insert(
-        "invocation_type".to_string(),
-        if opt.async_type {
-            "async".to_string()
+    if opt.coordinator == Coordinator::StepFunctions {
+        return run_with_step_functions(&launcher.dag, opt, &ysb_conf).await;
+    }
+
+    let mut metadata = QueryMetadata {
+        invocation_type: Some(if opt.async_type {
+            InvocationType::Async
         } else {
-            "sync".to_string()
-        },
-    );
+            InvocationType::Sync
+        }),
+        ..Default::default()
+    };
+    if opt.async_type {
+        metadata.insert(COMPLETION_METADATA_KEY.to_string(), "true".to_string());
+    }
 
     let tasks = (0..opt.generators)
         .into_iter()
         .map(|i| {
             let s = ysb_conf.clone();
-            let m = metadata.clone();
+            let mut m = metadata.clone();
+            m.insert(UPSTREAM_METADATA_KEY.to_string(), i.to_string());
             let f = format!("ysb-{:02}", 0);
             tokio::spawn(async move {
                 info!(
@@ -125,6 +147,51 @@ pub async fn ysb_benchmark(opt: &mut YSBBenchmarkOpt) -> Result<()> {
 
     futures::future::join_all(tasks).await;
 
+    if opt.async_type {
+        wait_for_windows(opt).await?;
+    }
+
+    Ok(())
+}
+
+/// Runs the query stages with the Step Functions state machine as the
+/// coordinator, and prints the results of the last stage.
+async fn run_with_step_functions(
+    dag: &QueryDag,
+    opt: &YSBBenchmarkOpt,
+    source: &YSBSource,
+) -> Result<()> {
+    let query_code = "ysb";
+    let mut machine = StateMachine::new(dag, query_code, *FLOCK_FUNCTION_CONCURRENCY);
+    machine.deploy().await?;
+    info!("Deployed state machine: {}", rainbow_string(machine.name()));
+
+    // The state machine invokes the functions and waits for their responses.
+    let metadata = QueryMetadata {
+        invocation_type: Some(InvocationType::Sync),
+        ..Default::default()
+    };
+
+    let events = source.generate_data()?;
+    let mut payloads = epoch_payloads(&events, query_code, None, opt.generators, opt.seconds)?;
+    payloads
+        .iter_mut()
+        .for_each(|p| p.metadata = Some(metadata.clone()));
+    let payloads = upload_payloads(query_code, payloads).await?;
+
+    info!(
+        "[OK] Starting the execution with {} payloads",
+        payloads.len()
+    );
+    let results = machine
+        .execute(payloads, std::time::Duration::from_secs(opt.timeout))
+        .await?;
+    let batches = collect_results(results).await?;
+    if !batches.is_empty() {
+        plain_println(pretty_format_batches(&batches)?.to_string());
+    }
+    info!("[OK] The execution completed");
+
     Ok(())
 }
 
@@ -11,8 +11,11 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-#[path = "../rainbow.rs"]
-mod rainbow;
+use benchmarks::rainbow;
+
+#[path = "../completion.rs"]
+mod completion;
+pub use completion::{cleanup_state_buckets, wait_for_completion};
 
 #[path = "./centralized.rs"]
 mod centralized;
@@ -21,8 +24,13 @@ mod centralized;
 mod distributed;
 
 use datafusion::arrow::datatypes::SchemaRef;
+use flock::aws::client::AwsCloudClient;
+use flock::driver::stepfunctions::Coordinator;
 use flock::prelude::*;
+use flock::runtime::completion::CompletionManifest;
 use lazy_static::lazy_static;
+use log::info;
+use rainbow::rainbow_println;
 use std::sync::Arc;
 use structopt::StructOpt;
 use ysb::event::{AdEvent, Campaign};
@@ -57,6 +65,11 @@ pub struct YSBBenchmarkOpt {
     #[structopt(long = "async")]
     pub async_type: bool,
 
+    /// The maximum number of seconds to wait for all windows to be processed
+    /// in the async mode
+    #[structopt(long = "timeout", default_value = "300")]
+    pub timeout: u64,
+
     /// The worker function's memory size
     #[structopt(short = "m", long = "memory_size", default_value = "128")]
     pub memory_size: i64,
@@ -77,6 +90,36 @@ pub struct YSBBenchmarkOpt {
     /// This is only used in distributed mode.
     #[structopt(short = "p", long = "target_partitions", default_value = "8")]
     pub target_partitions: usize,
+
+    /// The AWS region to deploy and run the benchmark. If not specified, the
+    /// default region from the environment is used.
+    #[structopt(long = "region", default_value = "")]
+    pub region: String,
+
+    /// The tumbling window of the query, e.g. `tumbling:10`. If not
+    /// specified, a 10-second tumbling window is used.
+    #[structopt(long = "window")]
+    pub window: Option<Window>,
+
+    /// The coordinator of the query stages in the distributed mode: `direct`
+    /// (the functions invoke the next stages) or `step-functions`
+    #[structopt(long = "coordinator", default_value = "direct")]
+    pub coordinator: Coordinator,
+
+    /// Deletes the state buckets of the query in the background once all
+    /// windows are processed in the async mode
+    #[structopt(long = "cleanup")]
+    pub cleanup: bool,
+
+    /// Reuses the output of the windows that the last stage executed with the
+    /// same input before, e.g. when the same seeded run is repeated
+    #[structopt(long = "use-result-cache")]
+    pub use_result_cache: bool,
+
+    /// Distributes the partial counts to the function group by the hash of
+    /// the given key columns, e.g. `campaign_id`, in the distributed mode
+    #[structopt(long = "distribute-by")]
+    pub distribute_by: Vec<String>,
 }
 
 #[tokio::main]
@@ -87,12 +130,35 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Returns the window of the query: the window given on the command line, or
+/// a 10-second tumbling window.
+fn ysb_window(opt: &YSBBenchmarkOpt) -> Window {
+    opt.window
+        .clone()
+        .unwrap_or(Window::Tumbling(Schedule::Seconds(10)))
+}
+
 fn create_ysb_source(opt: &YSBBenchmarkOpt) -> YSBSource {
-    let window = Window::Tumbling(Schedule::Seconds(10));
-    YSBSource::new(opt.seconds, opt.generators, opt.events_per_second, window)
+    YSBSource::new(
+        opt.seconds,
+        opt.generators,
+        opt.events_per_second,
+        ysb_window(opt),
+    )
 }
 
 pub async fn ysb_benchmark(opt: &mut YSBBenchmarkOpt) -> Result<()> {
+    set_flock_region(&opt.region)?;
+    let window = ysb_window(opt);
+    if !matches!(window, Window::Tumbling(Schedule::Seconds(_))) {
+        return Err(FlockError::Execution(format!(
+            "YSB only supports tumbling windows, but got {}",
+            window
+        )));
+    }
+    if opt.async_type {
+        CompletionManifest::clear(&AwsCloudClient, "ysb").await?;
+    }
     if opt.distributed {
         distributed::ysb_benchmark(opt).await
     } else {
@@ -100,6 +166,28 @@ pub async fn ysb_benchmark(opt: &mut YSBBenchmarkOpt) -> Result<()> {
     }
 }
 
+/// Waits for all windows of the asynchronous run to be processed, and prints
+/// the summary of the run.
+async fn wait_for_windows(opt: &YSBBenchmarkOpt) -> Result<()> {
+    info!("Waiting for all windows to be processed.");
+    let manifest =
+        wait_for_completion("ysb", None, std::time::Duration::from_secs(opt.timeout)).await?;
+    rainbow_println(format!(
+        "[OK] Windows processed: {} / {}",
+        manifest.completed_windows(),
+        manifest
+            .expected_windows()
+            .map(|n| n.to_string())
+            .unwrap_or_else(|| "unknown".to_string())
+    ));
+    if opt.cleanup {
+        cleanup_state_buckets("ysb")
+            .await
+            .map_err(|e| FlockError::Internal(e.to_string()))??;
+    }
+    Ok(())
+}
+
 /// Returns YSB query string.
 fn ysb_query() -> String {
     include_str!("ysb.sql").to_string()
@@ -129,7 +217,7 @@ mod tests {
         let sql = ysb_query();
         let ctx = register_ysb_tables().await?;
         let plan = physical_plan(&ctx, &sql).await?;
-        let mut flock_ctx = ExecutionContext {
+        let flock_ctx = ExecutionContext {
             plan: CloudExecutionPlan::new(vec![plan], None),
             ..Default::default()
         };
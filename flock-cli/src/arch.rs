@@ -15,7 +15,7 @@
 //! architectures such as x86, and ARM.
 
 use anyhow::{anyhow, Context as _, Ok, Result};
-use benchmarks::{arch_benchmark, rainbow_println, ArchBenchmarkOpt};
+use benchmarks::{arch_benchmark, rainbow_banner, ArchBenchmarkOpt};
 use clap::{App, AppSettings, Arg, ArgMatches};
 use log::warn;
 
@@ -72,6 +72,28 @@ fn run_args() -> App<'static> {
                 .possible_values(&["x86_64", "arm64"])
                 .default_value("x86_64"),
         )
+        .arg(
+            Arg::new("region")
+                .long("region")
+                .value_name("region")
+                .help("Sets the AWS region to deploy and run the benchmark")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("embedded stage")
+                .long("embedded-stage")
+                .value_name("STAGE")
+                .help("Compares the cold starts with the plans of the stage embedded in the binary")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("export dir")
+                .long("export-dir")
+                .value_name("DIR")
+                .help("Sets the directory of the plans exported by `nexmark plan --export-dir`")
+                .takes_value(true)
+                .default_value("/tmp/flock-plans"),
+        )
 }
 
 pub fn run(matches: &ArgMatches) -> Result<()> {
@@ -101,7 +123,23 @@ pub fn run(matches: &ArgMatches) -> Result<()> {
             .with_context(|| anyhow!("Invalid architecture"))?;
     }
 
-    rainbow_println(include_str!("./flock"));
+    if matches.is_present("region") {
+        opt.region = matches
+            .value_of("region")
+            .unwrap()
+            .parse::<String>()
+            .with_context(|| anyhow!("Invalid region"))?;
+    }
+
+    if matches.is_present("embedded stage") {
+        opt.embedded_stage = Some(matches.value_of("embedded stage").unwrap().to_string());
+    }
+
+    if matches.is_present("export dir") {
+        opt.export_dir = matches.value_of("export dir").unwrap().into();
+    }
+
+    rainbow_banner(include_str!("./flock"));
 
     futures::executor::block_on(arch_benchmark(&mut opt)).map_err(|e| e.into())
 }
@@ -11,8 +11,8 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use anyhow::Result;
-use benchmarks::rainbow_string;
+use anyhow::{anyhow, Result};
+use benchmarks::{rainbow_string, set_output_mode, OutputMode};
 use clap::{Arg, ArgMatches};
 use std::io::Write;
 
@@ -44,16 +44,45 @@ fn get_logging_args() -> Vec<Arg<'static>> {
             .help("Suppress all output")
             .global(true)
             .takes_value(false),
+        Arg::new("output")
+            .long("output")
+            .value_name("MODE")
+            .possible_values(&["pretty", "json", "quiet"])
+            .help(
+                "Output mode: `json` prints a single JSON document of the results to stdout \
+                 and the logs to stderr, `quiet` prints only the errors and the final summary \
+                 line [default: pretty]",
+            )
+            .global(true)
+            .takes_value(true),
     ]
     .to_vec()
 }
 
+/// Returns the output mode of the command, and sets it once as the output mode
+/// of the process, which the benchmarks and the rainbow output respect.
+pub fn get_output(global_matches: &ArgMatches, matches: &ArgMatches) -> Result<OutputMode> {
+    let mode = match matches
+        .value_of("output")
+        .or_else(|| global_matches.value_of("output"))
+    {
+        Some(mode) => mode.parse::<OutputMode>().map_err(|e| anyhow!(e))?,
+        None => OutputMode::Pretty,
+    };
+    set_output_mode(mode);
+    Ok(mode)
+}
+
 pub fn get_logging(
     global_matches: &ArgMatches,
     matches: &ArgMatches,
 ) -> Result<env_logger::Builder> {
     let mut builder = env_logger::Builder::new();
+    // The logs never go to stdout, which carries the JSON document in the json
+    // output mode.
+    builder.target(env_logger::Target::Stderr);
 
+    let output = get_output(global_matches, matches)?;
     let level = if matches.is_present("trace") {
         log::LevelFilter::Trace
     } else if matches.is_present("silent") {
@@ -68,6 +97,7 @@ pub fn get_logging(
             Some("debug") => log::LevelFilter::Debug,
             Some("trace") => log::LevelFilter::Trace,
             Some("off") => log::LevelFilter::Off,
+            _ if output == OutputMode::Quiet => log::LevelFilter::Error,
             _ => log::LevelFilter::Info,
         }
     };
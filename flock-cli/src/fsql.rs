@@ -13,21 +13,460 @@
 
 //! fsql is a terminal-based front-end to Flock.
 
-use anyhow::{anyhow, Result};
-use benchmarks::rainbow_println;
-use clap::{App, ArgMatches};
+use crate::websocket::ResultStream;
+use anyhow::{anyhow, Context as _, Result};
+use clap::{App, Arg, ArgMatches};
+use datafusion::arrow::array::{ArrayRef, BooleanArray, StringArray};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::arrow::util::pretty::pretty_format_batches;
+use flock::aws::client::AwsCloudClient;
+use flock::datasink::websocket::api_address;
+use flock::datasource::nexmark::{
+    get_nexmark_schema, register_nexmark_tables, Auction, Bid, NEXMarkSource, Person,
+    NEXMARK_TABLES,
+};
+use flock::datasource::s3::{S3ObjectCompression, S3ObjectFormat, S3ObjectsSource};
+use flock::distributed_plan::QueryDag;
+use flock::driver::funcgen::estimate::SourceRate;
+use flock::driver::lineage::QueryLineage;
+use flock::prelude::*;
+use flock::runtime::analyze::analyze_locally;
+use flock::runtime::plan::{stream_name, PlanProperties};
+use flock::runtime::running_aggregate::rewrite_cumulative;
 use rustyline::Editor;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
 
-pub fn command(_: &ArgMatches) -> Result<()> {
-    futures::executor::block_on(fsql())
+/// The options of the queries that stream their results to fsql.
+#[derive(Debug, Clone)]
+pub struct StreamOptions {
+    /// The id of the API Gateway WebSocket API. Empty if the results can't be
+    /// streamed.
+    pub api_id:               String,
+    /// The stage of the WebSocket API.
+    pub stage:                String,
+    /// The number of seconds to generate the NEXMark events.
+    pub seconds:              usize,
+    /// The number of NEXMark events per second.
+    pub events_per_second:    usize,
+    /// Whether to deploy the queries despite the warnings of the plan lint.
+    pub force:                bool,
+    /// The concurrency reserved for each function that isn't a member of a
+    /// function group.
+    pub reserved_concurrency: Option<i64>,
+    /// The key columns that the shuffled output of the queries is distributed
+    /// by to the function groups. Empty if the output partitions are sent by
+    /// their positions.
+    pub distribute_by:        Vec<String>,
+    /// Whether to print the deployment of the queries instead of running them
+    /// (see [`flock::api::dry_run`]).
+    pub dry_run:              bool,
+}
+
+pub fn command(matches: &ArgMatches) -> Result<()> {
+    let window = match matches.value_of("window") {
+        Some(window) => window
+            .parse::<Window>()
+            .with_context(|| anyhow!("Invalid window"))?,
+        None => Window::ElementWise,
+    };
+    let opts = StreamOptions {
+        api_id:               matches
+            .value_of("websocket api id")
+            .unwrap_or(&FLOCK_WEBSOCKET_API_ID)
+            .to_string(),
+        stage:                matches
+            .value_of("websocket stage")
+            .unwrap_or(&FLOCK_WEBSOCKET_STAGE)
+            .to_string(),
+        seconds:              matches
+            .value_of("seconds")
+            .unwrap_or("10")
+            .parse::<usize>()
+            .with_context(|| anyhow!("Invalid seconds"))?,
+        events_per_second:    matches
+            .value_of("events per second")
+            .unwrap_or("1000")
+            .parse::<usize>()
+            .with_context(|| anyhow!("Invalid events per second"))?,
+        force:                matches.is_present("force"),
+        reserved_concurrency: matches
+            .value_of("reserved concurrency")
+            .map(|c| c.parse::<i64>())
+            .transpose()
+            .with_context(|| anyhow!("Invalid reserved concurrency"))?,
+        distribute_by:        matches
+            .value_of("distribute by")
+            .map(|keys| keys.split(',').map(|k| k.trim().to_string()).collect())
+            .unwrap_or_default(),
+        dry_run:              matches.is_present("dry run"),
+    };
+    futures::executor::block_on(fsql(window, opts))
 }
 
 pub fn command_args() -> App<'static> {
-    App::new("fsql").about("The terminal-based front-end to Flock")
+    App::new("fsql")
+        .about("The terminal-based front-end to Flock")
+        .arg(
+            Arg::new("window")
+                .long("window")
+                .value_name("window")
+                .help("Sets the window of the NEXMark events, e.g. tumbling:10 or hopping:10:5")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("websocket api id")
+                .long("websocket-api-id")
+                .value_name("API_ID")
+                .help("Sets the id of the API Gateway WebSocket API that streams the results")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("websocket stage")
+                .long("websocket-stage")
+                .value_name("STAGE")
+                .help("Sets the stage of the WebSocket API")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("seconds")
+                .long("seconds")
+                .value_name("SECONDS")
+                .help("Sets the number of seconds to generate the NEXMark events")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("events per second")
+                .long("events-per-second")
+                .value_name("EVENTS")
+                .help("Sets the number of NEXMark events per second")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("force")
+                .long("force")
+                .help("Deploys the queries despite the warnings of the plan lint"),
+        )
+        .arg(
+            Arg::new("reserved concurrency")
+                .long("reserved-concurrency")
+                .value_name("N")
+                .help("Reserves the concurrency of each function that isn't a member of a function group")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("distribute by")
+                .long("distribute-by")
+                .value_name("KEYS")
+                .help("Distributes the shuffled output to the function groups by the comma-separated key columns, e.g. campaign_id")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("dry run")
+                .long("dry-run")
+                .help("Prints the resources and the cost of the deployment of the queries without creating them"),
+        )
+}
+
+/// A stream registered in the fsql session.
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    /// The type of the data source of the stream, e.g. `NEXMarkEvent`.
+    pub datasource: String,
+    /// The window of the queries on the stream.
+    pub window:     Window,
+    /// The schema of the stream given at registration.
+    pub schema:     SchemaRef,
 }
 
-/// The main entry point for fsql.
-pub async fn fsql() -> Result<()> {
+/// The streams registered in the fsql session by name, which the queries are
+/// planned against.
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    streams: BTreeMap<String, StreamInfo>,
+    /// The S3 objects of the streams created with `CREATE STREAM`.
+    objects: BTreeMap<String, S3ObjectsSource>,
+}
+
+impl Session {
+    /// Returns the session with the NEXMark streams over the given window.
+    pub fn nexmark(window: &Window) -> Self {
+        let mut session = Session::default();
+        for table in NEXMARK_TABLES {
+            session
+                .register(
+                    table,
+                    "NEXMarkEvent",
+                    window.clone(),
+                    Arc::new(get_nexmark_schema(table)),
+                )
+                .expect("The NEXMark tables have unique names");
+        }
+        session
+    }
+
+    /// Registers a stream with its schema, e.g. the schema inferred from the
+    /// data or given by the user. The stream names are unique in the session,
+    /// since the queries route the data sources to the streams by name.
+    pub fn register(
+        &mut self,
+        name: &str,
+        datasource: &str,
+        window: Window,
+        schema: SchemaRef,
+    ) -> Result<()> {
+        if self.streams.contains_key(name) {
+            return Err(anyhow!("Stream {} is already registered", name));
+        }
+        self.streams.insert(
+            name.to_string(),
+            StreamInfo {
+                datasource: datasource.to_string(),
+                window,
+                schema,
+            },
+        );
+        Ok(())
+    }
+
+    /// Registers the S3 objects as a stream. The schema of the objects must
+    /// be set, so that every object is decoded with the schema of the stream.
+    pub fn register_objects(&mut self, name: &str, source: S3ObjectsSource) -> Result<()> {
+        let schema = source
+            .schema()?
+            .ok_or_else(|| anyhow!("The schema of the stream {} is not set", name))?;
+        self.register(name, "S3Objects", Window::ElementWise, schema)?;
+        self.objects.insert(name.to_string(), source);
+        Ok(())
+    }
+
+    /// Returns the S3 objects that the query on the given streams scans, or
+    /// `None` if the query doesn't read a stream created with `CREATE STREAM`.
+    /// Such a stream is scanned by a batch query, which reads no other stream.
+    pub fn objects_source(&self, streams: &[&str]) -> Result<Option<S3ObjectsSource>> {
+        match streams
+            .iter()
+            .filter_map(|s| self.objects.get(*s))
+            .collect::<Vec<_>>()
+            .as_slice()
+        {
+            [] => Ok(None),
+            [source] if streams.len() == 1 => Ok(Some((*source).clone())),
+            _ => Err(anyhow!(
+                "The streams {:?} can't be queried together, since a stream of S3 objects \
+                 is scanned alone",
+                streams
+            )),
+        }
+    }
+
+    /// Returns the tables of the registered streams.
+    pub fn tables(&self) -> Vec<Table> {
+        self.streams
+            .iter()
+            .map(|(name, stream)| Table::new(name, stream.schema.clone()))
+            .collect()
+    }
+
+    /// Lists the registered streams with their data source and window.
+    pub fn show_streams(&self) -> Result<RecordBatch> {
+        let column = |f: fn(&String, &StreamInfo) -> String| -> ArrayRef {
+            Arc::new(StringArray::from(
+                self.streams
+                    .iter()
+                    .map(|(name, stream)| f(name, stream))
+                    .collect::<Vec<_>>(),
+            ))
+        };
+        Ok(RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("stream", DataType::Utf8, false),
+                Field::new("datasource", DataType::Utf8, false),
+                Field::new("window", DataType::Utf8, false),
+            ])),
+            vec![
+                column(|name, _| name.clone()),
+                column(|_, stream| stream.datasource.clone()),
+                column(|_, stream| stream.window.to_string()),
+            ],
+        )?)
+    }
+
+    /// Lists the fields of the stream's schema.
+    pub fn describe(&self, name: &str) -> Result<RecordBatch> {
+        let schema = &self
+            .streams
+            .get(name)
+            .ok_or_else(|| anyhow!("Stream {} is not registered", name))?
+            .schema;
+        let fields = schema.fields();
+        let metadata = fields
+            .iter()
+            .map(|f| {
+                f.metadata()
+                    .iter()
+                    .flatten()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+            .collect::<Vec<_>>();
+        Ok(RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("field", DataType::Utf8, false),
+                Field::new("type", DataType::Utf8, false),
+                Field::new("nullable", DataType::Boolean, false),
+                Field::new("metadata", DataType::Utf8, false),
+            ])),
+            vec![
+                Arc::new(StringArray::from(
+                    fields.iter().map(|f| f.name().as_str()).collect::<Vec<_>>(),
+                )),
+                Arc::new(StringArray::from(
+                    fields
+                        .iter()
+                        .map(|f| format!("{:?}", f.data_type()))
+                        .collect::<Vec<_>>(),
+                )),
+                Arc::new(BooleanArray::from(
+                    fields.iter().map(|f| f.is_nullable()).collect::<Vec<_>>(),
+                )),
+                Arc::new(StringArray::from(metadata)),
+            ],
+        )?)
+    }
+
+    /// Answers the introspection statement as a formatted table.
+    fn introspect(&self, statement: &Introspection) -> Result<String> {
+        let batch = match statement {
+            Introspection::ShowStreams => self.show_streams()?,
+            Introspection::Describe(name) => self.describe(name)?,
+        };
+        Ok(pretty_format_batches(&[batch])?.to_string())
+    }
+}
+
+/// The statements of fsql that are answered from the session instead of being
+/// planned by DataFusion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Introspection {
+    /// `SHOW STREAMS` lists the registered streams.
+    ShowStreams,
+    /// `DESCRIBE <stream>` (or `DESC <stream>`) lists the fields of a stream.
+    Describe(String),
+}
+
+impl Introspection {
+    /// Parses the statement, or returns `None` if it isn't an introspection
+    /// statement. The keywords are case-insensitive.
+    fn parse(query: &str) -> Result<Option<Self>> {
+        let query = query.trim();
+        let (keyword, rest) = query.split_once(char::is_whitespace).unwrap_or((query, ""));
+        let rest = rest.trim();
+        if keyword.eq_ignore_ascii_case("show") && rest.eq_ignore_ascii_case("streams") {
+            Ok(Some(Introspection::ShowStreams))
+        } else if keyword.eq_ignore_ascii_case("describe") || keyword.eq_ignore_ascii_case("desc") {
+            Ok(Some(Introspection::Describe(parse_identifier(rest)?)))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// `CREATE STREAM <name> FROM 's3://<bucket>/<prefix>' [FORMAT <format>]
+/// [COMPRESSION <compression>]` registers the S3 objects under the prefix as a
+/// stream. The format defaults to `ndjson`, and the compression to `none`.
+#[derive(Debug, Clone, PartialEq)]
+struct CreateStream {
+    /// The name of the stream.
+    name:   String,
+    /// The S3 objects of the stream, whose schema is inferred at registration.
+    source: S3ObjectsSource,
+}
+
+impl CreateStream {
+    /// Parses the statement, or returns `None` if it isn't a `CREATE STREAM`
+    /// statement. The keywords are case-insensitive.
+    fn parse(query: &str) -> Result<Option<Self>> {
+        let tokens = query.split_whitespace().collect::<Vec<_>>();
+        match tokens.as_slice() {
+            [create, stream, ..]
+                if create.eq_ignore_ascii_case("create")
+                    && stream.eq_ignore_ascii_case("stream") => {}
+            _ => return Ok(None),
+        }
+        let usage = || {
+            anyhow!(
+                "Usage: CREATE STREAM <name> FROM 's3://<bucket>/<prefix>' \
+                 [FORMAT ndjson|csv|parquet] [COMPRESSION gzip|zstd|none]"
+            )
+        };
+        let (name, url, options) = match &tokens[2..] {
+            [name, from, url, options @ ..] if from.eq_ignore_ascii_case("from") => {
+                (parse_identifier(name)?, *url, options)
+            }
+            _ => return Err(usage()),
+        };
+        let (bucket, prefix) = url
+            .strip_prefix('\'')
+            .and_then(|url| url.strip_suffix('\''))
+            .and_then(|url| url.strip_prefix("s3://"))
+            .map(|path| path.split_once('/').unwrap_or((path, "")))
+            .filter(|(bucket, _)| !bucket.is_empty())
+            .ok_or_else(|| anyhow!("Invalid S3 location: {}", url))?;
+
+        let mut format = S3ObjectFormat::default();
+        let mut compression = S3ObjectCompression::default();
+        for option in options.chunks(2) {
+            match option {
+                [key, value] if key.eq_ignore_ascii_case("format") => {
+                    format = S3ObjectFormat::new(value)?;
+                }
+                [key, value] if key.eq_ignore_ascii_case("compression") => {
+                    compression = S3ObjectCompression::new(value)?;
+                }
+                _ => return Err(usage()),
+            }
+        }
+        Ok(Some(CreateStream {
+            name,
+            source: S3ObjectsSource::new(bucket, prefix, format, compression),
+        }))
+    }
+}
+
+/// Parses the name of a stream. The unquoted names are folded to lowercase
+/// like the other identifiers in SQL, and the double-quoted names are taken as
+/// they are, with `""` escaping a quote.
+fn parse_identifier(name: &str) -> Result<String> {
+    if name.is_empty() {
+        return Err(anyhow!("Usage: DESCRIBE <stream>"));
+    }
+    if let Some(quoted) = name.strip_prefix('"') {
+        return match quoted.strip_suffix('"') {
+            Some(inner) if !inner.is_empty() && !inner.replace("\"\"", "").contains('"') => {
+                Ok(inner.replace("\"\"", "\""))
+            }
+            _ => Err(anyhow!("Invalid stream name: {}", name)),
+        };
+    }
+    if name.contains(|c: char| c.is_whitespace() || c == '"') {
+        return Err(anyhow!("Invalid stream name: {}", name));
+    }
+    Ok(name.to_lowercase())
+}
+
+/// The main entry point for fsql. The `SHOW STREAMS` and `DESCRIBE` statements
+/// are answered from the session, the `CREATE STREAM` statements register the
+/// S3 objects as a stream, the `EXPLAIN LINEAGE` statements print the
+/// lineage of the query's columns, and the `EXPLAIN ANALYZE` statements run on
+/// the NEXMark events generated with the given window. The other queries run
+/// on AWS Lambda, and their results are streamed back over the WebSocket API,
+/// or returned inline by the data source if the WebSocket API isn't set.
+pub async fn fsql(window: Window, opts: StreamOptions) -> Result<()> {
+    let mut session = Session::nexmark(&window);
     let mut rl = Editor::<()>::new();
     rl.load_history(".history").ok();
 
@@ -41,7 +480,7 @@ pub async fn fsql() -> Result<()> {
             Ok(ref line) if line.trim_end().ends_with(';') => {
                 query.push_str(line.trim_end());
                 rl.add_history_entry(query.clone());
-                match exec_and_print(query).await {
+                match exec_and_print(query, &mut session, &window, &opts).await {
                     Ok(_) => {}
                     Err(err) => println!("{:?}", err),
                 }
@@ -65,7 +504,389 @@ fn is_exit_command(line: &str) -> bool {
     line == "quit" || line == "exit"
 }
 
-async fn exec_and_print(_: String) -> Result<()> {
-    rainbow_println("CLI is under construction. Please try Flock API directly.");
+async fn exec_and_print(
+    query: String,
+    session: &mut Session,
+    window: &Window,
+    opts: &StreamOptions,
+) -> Result<()> {
+    let query = query.trim().trim_end_matches(';');
+    if let Some(statement) = Introspection::parse(query)? {
+        println!("{}", session.introspect(&statement)?);
+        return Ok(());
+    }
+    if let Some(statement) = CreateStream::parse(query)? {
+        return create_stream(statement, session).await;
+    }
+    if let Some(sql) = strip_keywords(query, "EXPLAIN ANALYZE ") {
+        return explain_analyze(sql, window).await;
+    }
+    if let Some(sql) = strip_keywords(query, "EXPLAIN LINEAGE ") {
+        return explain_lineage(sql, session).await;
+    }
+    if opts.dry_run {
+        return dry_run_query(query, session, window, opts).await;
+    }
+    if opts.api_id.is_empty() {
+        return inline_query(query, session, window, opts).await;
+    }
+    stream_query(query, session, window, opts).await
+}
+
+/// Registers the S3 objects of the statement as a stream of the session, with
+/// the schema inferred from the first object under the prefix.
+async fn create_stream(statement: CreateStream, session: &mut Session) -> Result<()> {
+    let CreateStream { name, source } = statement;
+    let schema = source.infer_schema(&AwsCloudClient).await?;
+    session.register_objects(&name, source.with_schema(schema))?;
+    println!("{}", pretty_format_batches(&[session.describe(&name)?])?);
+    Ok(())
+}
+
+/// Runs the query on the session streams on AWS Lambda, and prints the results
+/// returned inline by the synchronous invocation of the data source once all
+/// windows are processed (see [`DataSinkType::Response`]).
+async fn inline_query(
+    sql: &str,
+    session: &Session,
+    window: &Window,
+    opts: &StreamOptions,
+) -> Result<()> {
+    let (query, deploy_opts) = lambda_query(sql, session, window, opts, DataSinkType::Response)?;
+    let mut handle = run_query(query, deploy_opts).await?;
+    let batches = handle
+        .await_window_results(Duration::from_secs(opts.seconds as u64))
+        .await?;
+    println!("{}", pretty_format_batches(&batches)?);
+    handle.teardown().await?;
+    Ok(())
+}
+
+/// Runs the query on the session streams on AWS Lambda, and prints the results
+/// of each window as they are pushed over the WebSocket API.
+async fn stream_query(
+    sql: &str,
+    session: &Session,
+    window: &Window,
+    opts: &StreamOptions,
+) -> Result<()> {
+    let address = api_address(&opts.api_id, &opts.stage)?;
+    let mut stream = ResultStream::connect(&format!("wss://{}", address)).await?;
+
+    // The connection id is registered into the query at submit time.
+    let datasink = DataSinkType::WebSocket {
+        api_endpoint:  format!("https://{}", address),
+        connection_id: stream.connection_id().to_string(),
+    };
+    let (query, deploy_opts) = lambda_query(sql, session, window, opts, datasink)?;
+    let handle = run_query(query, deploy_opts).await?;
+    stream.follow(handle.query_code());
+
+    let idle_timeout = Duration::from_secs(*FLOCK_WEBSOCKET_IDLE_TIMEOUT);
+    while let Some(batches) = stream.next_batches(idle_timeout).await? {
+        if !batches.is_empty() {
+            println!("{}", pretty_format_batches(&batches)?);
+        }
+    }
+    handle.teardown().await?;
+    Ok(())
+}
+
+/// Returns the query on the streams of the session, and the options to deploy
+/// it on AWS Lambda. The query on a stream created with `CREATE STREAM` scans
+/// its S3 objects once, and the other queries run on the NEXMark events.
+fn lambda_query(
+    sql: &str,
+    session: &Session,
+    window: &Window,
+    opts: &StreamOptions,
+    datasink: DataSinkType,
+) -> Result<(Query, DeployOptions)> {
+    // The running aggregates `CUMULATIVE(...)` are accumulated over all windows
+    // in S3, where every instance of the last function reads them.
+    let (sql, running_aggregate) = rewrite_cumulative(sql)?;
+    let state_backend: Arc<dyn StateBackend> = if running_aggregate.is_some() {
+        Arc::new(S3StateBackend::new())
+    } else {
+        Arc::new(HashMapStateBackend::new())
+    };
+
+    let tables = session.tables();
+    let source = NEXMarkSource::new(opts.seconds, 1, opts.events_per_second, window.clone());
+    let query = Query::new(
+        &sql,
+        tables.clone(),
+        DataSource::NEXMarkEvent(source),
+        datasink.clone(),
+        None,
+        QueryType::Streaming(StreamType::NEXMarkBench),
+        state_backend.clone(),
+    );
+    let leaves = PlanProperties::analyze(&query.plan()?).leaf_schemas;
+    let streams = leaves
+        .iter()
+        .filter_map(|schema| stream_name(schema))
+        .collect::<Vec<_>>();
+    let query = match session.objects_source(&streams)? {
+        Some(source) => Query::new(
+            &sql,
+            tables,
+            DataSource::S3Objects(source),
+            datasink,
+            None,
+            QueryType::OLAP,
+            state_backend,
+        ),
+        None => query,
+    }
+    .distribute_by(opts.distribute_by.clone());
+    let deploy_opts = DeployOptions::lambda()
+        .with_force(opts.force)
+        .with_reserved_concurrency(opts.reserved_concurrency)
+        .with_running_aggregate(running_aggregate);
+    Ok((query, deploy_opts))
+}
+
+/// Prints the deployment of the query on AWS Lambda without creating anything:
+/// the query stages with their estimated data volumes at the event rate, the
+/// resources that would be created, and the estimated cost of the run.
+async fn dry_run_query(
+    sql: &str,
+    session: &Session,
+    window: &Window,
+    opts: &StreamOptions,
+) -> Result<()> {
+    let (query, deploy_opts) = lambda_query(sql, session, window, opts, DataSinkType::Blackhole)?;
+    let rate = SourceRate {
+        events_per_second: opts.events_per_second,
+        window_seconds:    window
+            .length()
+            .map_or(1, |length| length.as_secs() as usize)
+            .max(1),
+    };
+    let report = dry_run(&query, &deploy_opts, Some(&rate)).await?;
+    println!("{}", report);
+    println!(
+        "Estimated cost of {} seconds: ${:.6}",
+        opts.seconds,
+        report.estimated_cost(Duration::from_secs(opts.seconds as u64))
+    );
+    Ok(())
+}
+
+/// Returns the rest of the query if it starts with the keywords, which are
+/// case-insensitive.
+fn strip_keywords<'a>(query: &'a str, keywords: &str) -> Option<&'a str> {
+    query
+        .get(..keywords.len())
+        .filter(|p| p.eq_ignore_ascii_case(keywords))
+        .map(|_| &query[keywords.len()..])
+}
+
+/// Prints the column-level lineage of the query over the session streams.
+async fn explain_lineage(sql: &str, session: &Session) -> Result<()> {
+    let ctx = register_nexmark_tables().await?;
+    let plan = physical_plan(&ctx, sql).await?;
+    let lineage = QueryLineage::analyze(&plan, &session.tables())?;
+    println!("{}", lineage.to_table()?);
     Ok(())
 }
+
+/// Runs the query on the NEXMark tables for one epoch in the current process,
+/// and prints the query stages annotated with the observed row counts and
+/// timings.
+async fn explain_analyze(sql: &str, window: &Window) -> Result<()> {
+    let ctx = register_nexmark_tables().await?;
+    let plan = physical_plan(&ctx, sql).await?;
+    let dag = QueryDag::from(plan)?;
+    let stages = dag.get_all_stages();
+
+    let events = NEXMarkSource::new(1, 1, 1000, window.clone()).generate_data()?;
+    let (event, _) = events
+        .select(0, 0)
+        .ok_or_else(|| anyhow!("No NEXMark events generated"))?;
+    let sources = vec![
+        vec![event_bytes_to_batch(
+            &event.persons,
+            Arc::new(Person::schema()),
+            1024,
+        )],
+        vec![event_bytes_to_batch(
+            &event.auctions,
+            Arc::new(Auction::schema()),
+            1024,
+        )],
+        vec![event_bytes_to_batch(
+            &event.bids,
+            Arc::new(Bid::schema()),
+            1024,
+        )],
+    ];
+
+    let report = analyze_locally(stages.iter().map(|s| s.stage.clone()).collect(), sources).await?;
+    println!(
+        "{}",
+        report.render(&stages.iter().map(|s| s.get_plan_str()).collect::<Vec<_>>())
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_introspection() -> Result<()> {
+        let show = Some(Introspection::ShowStreams);
+        assert_eq!(Introspection::parse("SHOW STREAMS")?, show);
+        assert_eq!(Introspection::parse("  show \t Streams ")?, show);
+        assert_eq!(Introspection::parse("SHOW TABLES")?, None);
+        assert_eq!(Introspection::parse("SELECT * FROM bid")?, None);
+
+        let describe = |name: &str| Some(Introspection::Describe(name.to_string()));
+        assert_eq!(Introspection::parse("DESCRIBE bid")?, describe("bid"));
+        assert_eq!(Introspection::parse("desc Bid")?, describe("bid"));
+        assert_eq!(Introspection::parse("Describe \"Bid\"")?, describe("Bid"));
+        assert_eq!(
+            Introspection::parse("DESCRIBE \"my \"\"odd\"\" stream\"")?,
+            describe("my \"odd\" stream")
+        );
+
+        assert!(Introspection::parse("DESCRIBE").is_err());
+        assert!(Introspection::parse("DESCRIBE bid person").is_err());
+        assert!(Introspection::parse("DESCRIBE \"bid").is_err());
+        assert!(Introspection::parse("DESCRIBE \"\"").is_err());
+        assert!(Introspection::parse("DESCRIBE \"a\"b\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_explain() {
+        let sql = "SELECT * FROM bid";
+        let lineage = format!("explain Lineage {}", sql);
+        assert_eq!(strip_keywords(&lineage, "EXPLAIN LINEAGE "), Some(sql));
+        assert_eq!(strip_keywords(&lineage, "EXPLAIN ANALYZE "), None);
+        assert_eq!(strip_keywords("EXPLAIN", "EXPLAIN LINEAGE "), None);
+        assert_eq!(strip_keywords(sql, "EXPLAIN LINEAGE "), None);
+    }
+
+    /// Returns the cells of the rows of a formatted table.
+    fn rows(table: &str) -> Vec<Vec<String>> {
+        table
+            .lines()
+            .filter(|l| l.starts_with('|'))
+            .map(|l| {
+                l.trim_matches('|')
+                    .split('|')
+                    .map(|c| c.trim().to_string())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn format_introspection() -> Result<()> {
+        let mut session = Session::nexmark(&Window::Tumbling(Schedule::Seconds(10)));
+        let streams = rows(&session.introspect(&Introspection::ShowStreams)?);
+        assert_eq!(streams[0], vec!["stream", "datasource", "window"]);
+        assert_eq!(streams.len(), NEXMARK_TABLES.len() + 1);
+        assert!(streams.contains(&vec![
+            "bid".to_string(),
+            "NEXMarkEvent".to_string(),
+            "tumbling:10".to_string()
+        ]));
+
+        let bid = rows(&session.introspect(&Introspection::Describe("bid".to_string()))?);
+        assert_eq!(bid[0], vec!["field", "type", "nullable", "metadata"]);
+        assert_eq!(bid[1], vec!["auction", "Int32", "false", ""]);
+        assert_eq!(
+            bid[4],
+            vec!["b_date_time", "Timestamp(Millisecond, None)", "false", ""]
+        );
+
+        // The schemas of the other streams are the ones given at registration.
+        let mut metadata = BTreeMap::new();
+        metadata.insert("unit".to_string(), "ms".to_string());
+        let mut latency = Field::new("latency", DataType::Int64, true);
+        latency.set_metadata(Some(metadata));
+        session.register(
+            "Metrics",
+            "KinesisEvent",
+            Window::ElementWise,
+            Arc::new(Schema::new(vec![latency])),
+        )?;
+        // The stream names are unique, even if the schema is the same.
+        assert!(session
+            .register(
+                "bid",
+                "KinesisEvent",
+                Window::ElementWise,
+                Arc::new(get_nexmark_schema("bid")),
+            )
+            .is_err());
+        let metrics = rows(&session.introspect(&Introspection::Describe("Metrics".to_string()))?);
+        assert_eq!(metrics[1], vec!["latency", "Int64", "true", "unit=ms"]);
+        assert!(session.describe("metrics").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_create_stream() -> Result<()> {
+        let create = CreateStream::parse(
+            "create stream Clicks FROM 's3://flock-clicks/2021/10/' FORMAT csv COMPRESSION gzip",
+        )?
+        .unwrap();
+        assert_eq!(create.name, "clicks");
+        assert_eq!(
+            create.source,
+            S3ObjectsSource::new(
+                "flock-clicks",
+                "2021/10/",
+                S3ObjectFormat::Csv,
+                S3ObjectCompression::Gzip
+            )
+        );
+
+        let create = CreateStream::parse("CREATE STREAM clicks FROM 's3://flock-clicks'")?.unwrap();
+        assert_eq!(
+            create.source,
+            S3ObjectsSource::new(
+                "flock-clicks",
+                "",
+                S3ObjectFormat::Ndjson,
+                S3ObjectCompression::None
+            )
+        );
+
+        assert_eq!(CreateStream::parse("CREATE TABLE clicks")?, None);
+        assert_eq!(CreateStream::parse("SELECT * FROM bid")?, None);
+        assert!(CreateStream::parse("CREATE STREAM clicks").is_err());
+        assert!(CreateStream::parse("CREATE STREAM clicks FROM 'flock-clicks/2021'").is_err());
+        assert!(CreateStream::parse("CREATE STREAM clicks FROM 's3:///2021'").is_err());
+        assert!(CreateStream::parse("CREATE STREAM c FROM 's3://b/p' FORMAT avro").is_err());
+        assert!(CreateStream::parse("CREATE STREAM c FROM 's3://b/p' FORMAT").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn query_objects_source() -> Result<()> {
+        let mut session = Session::nexmark(&Window::Tumbling(Schedule::Seconds(10)));
+        let source = S3ObjectsSource::new(
+            "flock-clicks",
+            "2021/10/",
+            S3ObjectFormat::Ndjson,
+            S3ObjectCompression::Gzip,
+        );
+        // The schema of the objects is inferred before they're registered.
+        assert!(session.register_objects("clicks", source.clone()).is_err());
+        let schema = Arc::new(Schema::new(vec![Field::new("page", DataType::Utf8, false)]));
+        let source = source.with_schema(schema);
+        session.register_objects("clicks", source.clone())?;
+
+        assert_eq!(session.objects_source(&["clicks"])?, Some(source));
+        assert_eq!(session.objects_source(&["bid", "person"])?, None);
+        assert!(session.objects_source(&["clicks", "bid"]).is_err());
+        assert!(session.objects_source(&["clicks", "clicks"]).is_err());
+        Ok(())
+    }
+}
@@ -11,16 +11,74 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-//! Flock CLI creates/lists/deletes AWS Lambda functions.
+//! Flock CLI creates/lists/deletes/updates AWS Lambda functions.
 
-use anyhow::{Ok, Result};
+use anyhow::{anyhow, bail, Context as _, Ok, Result};
 use benchmarks::rainbow_println;
 use clap::{App, Arg, ArgMatches};
-use rusoto_core::Region;
-use rusoto_lambda::{DeleteFunctionRequest, Lambda, LambdaClient, ListFunctionsRequest};
+use datafusion::arrow::util::pretty::pretty_format_batches;
+use flock::configs::{lambda_client, set_flock_region, FLOCK_SWITCHOVER_OVERLAP};
+use flock::datasource::ysb::event::{AdEvent, Campaign};
+use flock::prelude::*;
+use rusoto_lambda::{DeleteFunctionRequest, Lambda, ListFunctionsRequest};
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The data sources that can be compiled into the function binary.
+const DATA_SOURCES: &[&str] = &["nexmark", "ysb", "tpch", "kinesis", "kafka"];
 
 pub fn command(matches: &ArgMatches) -> Result<()> {
-    if matches.is_present("delete function") {
+    if let Some(region) = matches.value_of("region") {
+        set_flock_region(region)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("peek") {
+        let stage = matches
+            .value_of("stage")
+            .unwrap()
+            .parse::<usize>()
+            .with_context(|| anyhow!("Invalid stage"))?;
+        let rows = matches
+            .value_of("rows")
+            .unwrap()
+            .parse::<usize>()
+            .with_context(|| anyhow!("Invalid rows"))?;
+        let timeout = matches
+            .value_of("timeout")
+            .unwrap()
+            .parse::<u64>()
+            .with_context(|| anyhow!("Invalid timeout"))?;
+        futures::executor::block_on(peek(
+            matches.value_of("query code").unwrap(),
+            stage,
+            rows,
+            Duration::from_secs(timeout),
+        ))?;
+    } else if let Some(matches) = matches.subcommand_matches("deploy") {
+        let memory_size = matches
+            .value_of("memory size")
+            .unwrap()
+            .parse::<i64>()
+            .with_context(|| anyhow!("Invalid memory size"))?;
+        futures::executor::block_on(deploy_query(
+            matches.value_of("sql").unwrap(),
+            matches.value_of("data source").unwrap(),
+            matches.value_of("data sink").unwrap(),
+            memory_size,
+            matches.value_of("architecture").unwrap(),
+        ))?;
+    } else if let Some(matches) = matches.subcommand_matches("quota") {
+        let period = matches
+            .value_of("period")
+            .unwrap()
+            .parse::<u64>()
+            .with_context(|| anyhow!("Invalid period"))?;
+        futures::executor::block_on(quota(
+            matches.value_of("query code").unwrap(),
+            Duration::from_secs(period),
+        ))?;
+    } else if matches.is_present("delete function") {
         futures::executor::block_on(delete_function(matches.value_of("delete function")))?;
     } else if matches.is_present("list functions") {
         futures::executor::block_on(list_functions(matches.value_of("list functions")))?;
@@ -28,6 +86,44 @@ pub fn command(matches: &ArgMatches) -> Result<()> {
         futures::executor::block_on(delete_all_functions())?;
     } else if matches.is_present("list all functions") {
         futures::executor::block_on(list_all_functions())?;
+    } else if matches.is_present("update query") {
+        let overlap = match matches.value_of("overlap") {
+            Some(seconds) => seconds
+                .parse::<u64>()
+                .with_context(|| anyhow!("Invalid overlap"))?,
+            None => *FLOCK_SWITCHOVER_OVERLAP,
+        };
+        futures::executor::block_on(update_query(
+            matches.value_of("update query").unwrap(),
+            matches.value_of("sql").unwrap(),
+            matches.value_of("data source").unwrap(),
+            matches.value_of("data sink").unwrap(),
+            Duration::from_secs(overlap),
+        ))?;
+    } else if matches.is_present("upgrade query") {
+        futures::executor::block_on(upgrade_query(matches.value_of("upgrade query").unwrap()))?;
+    } else if matches.is_present("scale group") {
+        let stage = matches
+            .value_of("stage")
+            .unwrap()
+            .parse::<usize>()
+            .with_context(|| anyhow!("Invalid stage"))?;
+        let size = matches
+            .value_of("size")
+            .map(|size| size.parse::<usize>())
+            .transpose()
+            .with_context(|| anyhow!("Invalid size"))?;
+        futures::executor::block_on(scale_group(
+            matches.value_of("scale group").unwrap(),
+            stage,
+            size,
+        ))?;
+    } else if matches.is_present("package function") {
+        let features = match matches.value_of("features") {
+            Some(features) => features.split(',').map(|f| f.trim().to_owned()).collect(),
+            None => default_features(matches.value_of("data source").unwrap()),
+        };
+        package_function(&features)?;
     }
 
     Ok(())
@@ -36,6 +132,9 @@ pub fn command(matches: &ArgMatches) -> Result<()> {
 pub fn command_args() -> App<'static> {
     App::new("lambda")
         .about("The AWS Lambda Tool for Flock")
+        .subcommand(peek_args())
+        .subcommand(quota_args())
+        .subcommand(deploy_args())
         .arg(
             Arg::new("delete function")
                 .short('d')
@@ -64,6 +163,431 @@ pub fn command_args() -> App<'static> {
                 .long("list-all")
                 .help("Lists all lambda functions"),
         )
+        .arg(
+            Arg::new("update query")
+                .long("update")
+                .value_name("query code")
+                .help(
+                    "Replaces the running query with a new query without stopping its data source",
+                )
+                .requires("sql")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("sql")
+                .long("sql")
+                .value_name("SQL")
+                .help("Sets the SQL of the new query")
+                .requires("update query")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("overlap")
+                .long("overlap")
+                .value_name("SECONDS")
+                .help("Sets the number of seconds that the windows are written to both queries")
+                .requires("update query")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("upgrade query")
+                .long("upgrade")
+                .value_name("query code")
+                .help("Upgrades the running query to the function binary in S3, keeping its windows")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("scale group")
+                .long("scale")
+                .value_name("query code")
+                .help("Resizes the function group fed by the data source of the running query")
+                .requires("stage")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("stage")
+                .long("stage")
+                .value_name("plan index")
+                .help("Sets the plan index of the function group to resize")
+                .requires("scale group")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("size")
+                .long("size")
+                .value_name("N")
+                .help("Sets the new size of the function group, or applies its scaling hint if not set")
+                .requires("scale group")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("data sink")
+                .long("sink")
+                .value_name("data sink")
+                .help("Sets the data sink of the new query")
+                .possible_values(&["blackhole", "s3", "dynamodb", "sqs", "efs"])
+                .default_value("blackhole")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("package function")
+                .short('p')
+                .long("package")
+                .help("Builds the lambda function binary with the given features"),
+        )
+        .arg(
+            Arg::new("features")
+                .long("features")
+                .value_name("feature list")
+                .help("Sets the comma-separated cargo features of the function binary")
+                .requires("package function")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("data source")
+                .long("datasource")
+                .value_name("data source")
+                .help("Picks the default features for the data source of the query")
+                .possible_values(DATA_SOURCES)
+                .default_value("nexmark")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("region")
+                .long("region")
+                .value_name("region")
+                .help("Sets the AWS region of lambda functions")
+                .takes_value(true),
+        )
+}
+
+fn peek_args() -> App<'static> {
+    App::new("peek")
+        .about("Prints the first rows of the output of a stage of the running query")
+        .arg(
+            Arg::new("query code")
+                .long("query")
+                .value_name("query code")
+                .help("Sets the query code that the running query was started with")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("stage")
+                .long("stage")
+                .value_name("plan index")
+                .help("Sets the plan index of the stage to sample")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("rows")
+                .long("rows")
+                .value_name("N")
+                .help("Sets the number of rows to sample")
+                .takes_value(true)
+                .default_value("20"),
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("Sets the maximum seconds to wait for the next window")
+                .takes_value(true)
+                .default_value("60"),
+        )
+}
+
+fn deploy_args() -> App<'static> {
+    App::new("deploy")
+        .about("Deploys the functions of a query, and reconciles those left by a former deployment")
+        .arg(
+            Arg::new("sql")
+                .long("sql")
+                .value_name("SQL")
+                .help("Sets the SQL of the query")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("data source")
+                .long("datasource")
+                .value_name("data source")
+                .help("Sets the data source whose tables the SQL refers to")
+                .possible_values(&["nexmark", "ysb"])
+                .default_value("nexmark")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("data sink")
+                .long("sink")
+                .value_name("data sink")
+                .help("Sets the data sink of the query")
+                .possible_values(&["blackhole", "s3", "dynamodb", "sqs", "efs"])
+                .default_value("blackhole")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("memory size")
+                .long("memory-size")
+                .value_name("MB")
+                .help("Sets the memory size of the functions")
+                .takes_value(true)
+                .default_value("128"),
+        )
+        .arg(
+            Arg::new("architecture")
+                .long("arch")
+                .value_name("architecture")
+                .help("Sets the architecture of the functions")
+                .possible_values(&["x86_64", "arm64"])
+                .default_value("x86_64")
+                .takes_value(true),
+        )
+}
+
+fn quota_args() -> App<'static> {
+    App::new("quota")
+        .about("Prints the concurrency settings and the current usage of the query's functions")
+        .arg(
+            Arg::new("query code")
+                .long("query")
+                .value_name("query code")
+                .help("Sets the query code that the query was started with")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("period")
+                .long("period")
+                .value_name("SECONDS")
+                .help("Sets the period over which the concurrent executions are measured")
+                .takes_value(true)
+                .default_value("300"),
+        )
+}
+
+/// Returns the query of the SQL on the tables of the data source.
+fn query_of(sql: &str, datasource: &str, datasink: &str) -> Result<Query> {
+    let (tables, stream_type) = match datasource {
+        "nexmark" => (
+            nexmark::NEXMARK_TABLES
+                .iter()
+                .map(|t| Table::new(*t, Arc::new(nexmark::get_nexmark_schema(t))))
+                .collect(),
+            StreamType::NEXMarkBench,
+        ),
+        "ysb" => (
+            vec![
+                Table::new("ad_event", Arc::new(AdEvent::schema())),
+                Table::new("campaign", Arc::new(Campaign::schema())),
+            ],
+            StreamType::YSBBench,
+        ),
+        _ => bail!("Only the queries on the NEXMark or YSB tables are supported"),
+    };
+    Ok(Query::new(
+        sql,
+        tables,
+        DataSource::default(),
+        DataSinkType::new(datasink)?,
+        None,
+        QueryType::Streaming(stream_type),
+        Arc::new(HashMapStateBackend::new()),
+    ))
+}
+
+/// Deploys the functions of the query without starting its data source. A
+/// rerun after a deployment that died halfway creates the missing functions,
+/// and updates the others where they differ (see
+/// [`flock::api::deploy_query`]).
+///
+/// # Arguments
+/// * `sql` - The SQL of the query.
+/// * `datasource` - The data source whose tables the SQL refers to.
+/// * `datasink` - The data sink of the query.
+/// * `memory_size` - The memory size of the functions in MB.
+/// * `architecture` - The architecture of the functions.
+async fn deploy_query(
+    sql: &str,
+    datasource: &str,
+    datasink: &str,
+    memory_size: i64,
+    architecture: &str,
+) -> Result<()> {
+    let query = query_of(sql, datasource, datasink)?;
+    let opts = DeployOptions::lambda()
+        .with_memory_size(memory_size)
+        .with_architecture(architecture);
+    let report = flock::api::deploy_query(&query, &opts).await?;
+    print!("{}", report);
+    report.check()?;
+    rainbow_println(format!("[OK] deployed {} functions", report.actions.len()));
+
+    Ok(())
+}
+
+/// Replaces the running query with the query of the new SQL on the tables of
+/// the data source. The data source keeps running, and the windows are handed
+/// over to the new query (see [`flock::api::update_query`]).
+///
+/// # Arguments
+/// * `query_code` - The query code that the running query was started with.
+/// * `sql` - The SQL of the new query.
+/// * `datasource` - The data source whose tables the SQL refers to.
+/// * `datasink` - The data sink of the new query.
+/// * `overlap` - The period that the windows are written to both queries.
+async fn update_query(
+    query_code: &str,
+    sql: &str,
+    datasource: &str,
+    datasink: &str,
+    overlap: Duration,
+) -> Result<()> {
+    let query = query_of(sql, datasource, datasink)?;
+
+    rainbow_println(format!(
+        "[OK] updating {} with an overlap of {:?}",
+        query_code, overlap
+    ));
+    let handle =
+        flock::api::update_query(query_code, query, DeployOptions::lambda(), overlap).await?;
+    rainbow_println(format!(
+        "[OK] {} is running as {}",
+        query_code,
+        handle.query_code()
+    ));
+
+    Ok(())
+}
+
+/// Upgrades the functions of the running query to the function binary in S3.
+/// The open windows of the function groups are kept across the upgrade (see
+/// [`flock::api::upgrade_query`]).
+///
+/// # Arguments
+/// * `query_code` - The query code that the running query was started with.
+async fn upgrade_query(query_code: &str) -> Result<()> {
+    let functions = flock::api::upgrade_query(query_code).await?;
+    rainbow_println(format!(
+        "[OK] upgraded {} functions of {}",
+        functions.len(),
+        query_code
+    ));
+
+    Ok(())
+}
+
+/// Resizes the function group of the running query. The new windows are
+/// hashed to the resized group, while the windows in flight complete on the
+/// old one (see [`flock::api::resize_group`]).
+///
+/// # Arguments
+/// * `query_code` - The query code that the running query was started with.
+/// * `stage` - The plan index of the function group.
+/// * `size` - The new size of the group, or `None` to apply its scaling hint.
+async fn scale_group(query_code: &str, stage: usize, size: Option<usize>) -> Result<()> {
+    let route = flock::api::resize_group(query_code, stage, size).await?;
+    if let Some(CloudFunction::Group((group, size))) = &route.active.workers {
+        rainbow_println(format!(
+            "[OK] {} has {} members from ring version {}",
+            group, size, route.generation
+        ));
+    }
+
+    Ok(())
+}
+
+/// Prints the sample of the next window of the stage of the running query
+/// (see [`flock::api::peek`]).
+///
+/// # Arguments
+/// * `query_code` - The query code that the running query was started with.
+/// * `stage` - The plan index of the stage.
+/// * `rows` - The number of rows to sample.
+/// * `timeout` - The maximum time to wait for the next window.
+async fn peek(query_code: &str, stage: usize, rows: usize, timeout: Duration) -> Result<()> {
+    match flock::api::peek(query_code, stage, rows, timeout).await? {
+        Some((window, batches)) => {
+            rainbow_println(format!("[OK] window {} of stage {:02}", window, stage));
+            println!("{}", pretty_format_batches(&batches)?);
+        }
+        None => bail!(
+            "No window of stage {:02} was sampled in {:?}",
+            stage,
+            timeout
+        ),
+    }
+
+    Ok(())
+}
+
+/// Prints the reserved concurrency and the peak concurrent executions of each
+/// function of the query (see [`flock::api::query_quota`]).
+///
+/// # Arguments
+/// * `query_code` - The query code that the query was started with.
+/// * `period` - The period over which the concurrent executions are measured.
+async fn quota(query_code: &str, period: Duration) -> Result<()> {
+    let quota = flock::api::query_quota(query_code, period).await?;
+    rainbow_println(format!(
+        "[OK] account concurrency: {} ({} unreserved)",
+        quota.account_limit, quota.unreserved
+    ));
+    println!("{:<40} {:>10} {:>10}", "function", "reserved", "peak");
+    for function in &quota.functions {
+        println!(
+            "{:<40} {:>10} {:>10}",
+            function.function,
+            function
+                .reserved
+                .map_or_else(|| "-".to_owned(), |c| c.to_string()),
+            function
+                .peak
+                .map_or_else(|| "-".to_owned(), |c| c.to_string())
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns the default cargo features of the function binary for the query
+/// reading from the given data source.
+fn default_features(datasource: &str) -> Vec<String> {
+    vec![datasource.to_owned(), "zstd".to_owned()]
+}
+
+/// Builds the function binary with only the given cargo features, which keeps
+/// the binary small and the cold starts fast.
+///
+/// # Arguments
+/// * `features` - The cargo features of the function binary.
+fn package_function(features: &[String]) -> Result<()> {
+    rainbow_println(format!(
+        "[OK] building the function binary with features: {:?}",
+        features
+    ));
+
+    let status = Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned()))
+        .args(&[
+            "build",
+            "--release",
+            "--package",
+            "flock-function",
+            "--no-default-features",
+            "--features",
+        ])
+        .arg(features.join(","))
+        .status()?;
+    if !status.success() {
+        bail!("failed to build the function binary: {}", status);
+    }
+
+    rainbow_println(
+        "[OK] the function binary is at target/release/flock, use `flock-cli s3 put` to upload it",
+    );
+
+    Ok(())
 }
 
 /// Delete Lambda functions matching the given pattern.
@@ -81,9 +605,7 @@ async fn delete_function(pattern: Option<&str>) -> Result<()> {
                     function_name: name,
                     ..Default::default()
                 };
-                LambdaClient::new(Region::default())
-                    .delete_function(request)
-                    .await
+                lambda_client("").delete_function(request).await
             })
         })
         .collect::<Vec<_>>();
@@ -118,7 +640,7 @@ async fn delete_all_functions() -> Result<()> {
 /// # Returns
 /// A vector of function names.
 async fn list_functions(pattern: Option<&str>) -> Result<Vec<String>> {
-    let client = LambdaClient::new(Region::default());
+    let client = lambda_client("");
     let mut request = ListFunctionsRequest {
         ..Default::default()
     };
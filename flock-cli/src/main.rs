@@ -19,6 +19,8 @@ mod nexmark;
 #[cfg(feature = "cli")]
 mod repl;
 mod s3;
+mod schedule;
+mod websocket;
 mod ysb;
 
 use anyhow::Result;
@@ -14,8 +14,12 @@
 //! This crate runs the NexMark Benchmark on cloud function services.
 
 use anyhow::{anyhow, Context as _, Ok, Result};
-use benchmarks::{nexmark_benchmark, rainbow_println, NexmarkBenchmarkOpt};
+use benchmarks::nexmark::{nexmark_export_plans, nexmark_lineage, nexmark_plan, QueryList};
+use benchmarks::{diff_runs, load_run, DiffOptions};
+use benchmarks::{nexmark_benchmark, rainbow_banner, NexmarkBenchmarkOpt};
 use clap::{App, AppSettings, Arg, ArgMatches};
+use flock::driver::stepfunctions::Coordinator;
+use flock::stream::Window;
 use log::warn;
 
 pub fn command(matches: &ArgMatches) -> Result<()> {
@@ -26,6 +30,8 @@ pub fn command(matches: &ArgMatches) -> Result<()> {
 
     match command {
         "run" => run(matches),
+        "plan" => plan(matches),
+        "diff" => diff(matches),
         _ => {
             warn!("{} command is not implemented", command);
             Ok(())
@@ -41,6 +47,8 @@ pub fn command_args() -> App<'static> {
         .about("The NEXMark Benchmark Tool")
         .setting(AppSettings::SubcommandRequired)
         .subcommand(run_args())
+        .subcommand(plan_args())
+        .subcommand(diff_args())
 }
 
 fn run_args() -> App<'static> {
@@ -87,7 +95,7 @@ fn run_args() -> App<'static> {
                 .long("data-sink-type")
                 .help("Runs the NEXMark benchmark with a data sink type")
                 .takes_value(true)
-                .possible_values(&["sqs", "s3", "dynamodb", "efs", "blackhole"])
+                .possible_values(&["sqs", "s3", "dynamodb", "efs", "response", "blackhole"])
                 .default_value("blackhole"),
         )
         .arg(
@@ -96,6 +104,14 @@ fn run_args() -> App<'static> {
                 .long("async-type")
                 .help("Runs the NEXMark benchmark with async function invocations"),
         )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .value_name("timeout")
+                .help("Sets the maximum seconds to wait for all windows in the async mode")
+                .takes_value(true)
+                .default_value("300"),
+        )
         .arg(
             Arg::new("memory size")
                 .short('m')
@@ -137,6 +153,193 @@ fn run_args() -> App<'static> {
                 .possible_values(&["1", "2", "4", "8", "16", "24", "32"])
                 .default_value("8"),
         )
+        .arg(
+            Arg::new("analyze")
+                .long("analyze")
+                .help("Runs the query once and prints the stages annotated with observed metrics"),
+        )
+        .arg(
+            Arg::new("region")
+                .long("region")
+                .value_name("region")
+                .help("Sets the AWS region to deploy and run the benchmark")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("window")
+                .long("window")
+                .value_name("window")
+                .help("Sets the window of the query, e.g. tumbling:10, hopping:10:5 or session:10")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("coordinator")
+                .long("coordinator")
+                .value_name("coordinator")
+                .help("Sets the coordinator of the query stages")
+                .takes_value(true)
+                .possible_values(&["direct", "step-functions"])
+                .default_value("direct"),
+        )
+        .arg(
+            Arg::new("multiplex")
+                .long("multiplex")
+                .help("Runs the query on the functions shared by the queries of the same topology"),
+        )
+        .arg(
+            Arg::new("force")
+                .long("force")
+                .help("Deploys the query despite the warnings of the plan lint"),
+        )
+        .arg(
+            Arg::new("reserved concurrency")
+                .long("reserved-concurrency")
+                .value_name("N")
+                .help("Reserves the concurrency of each function that isn't a member of a function group")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("results server")
+                .long("results-server")
+                .value_name("endpoint")
+                .help("Reads the window results back from the results server, e.g. http://localhost:50051")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("queries")
+                .long("queries")
+                .value_name("queries")
+                .help("Runs the queries one by one and prints a comparison report, e.g. 1-8 or 1,3,5-7")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("report")
+                .long("report")
+                .value_name("report")
+                .help("Writes the comparison report of the queries as JSON to the given path")
+                .takes_value(true)
+                .requires("queries"),
+        )
+        .arg(
+            Arg::new("auto memory")
+                .long("auto-memory")
+                .help("Sizes the memory of each query stage from its estimated data volume"),
+        )
+        .arg(
+            Arg::new("use result cache")
+                .long("use-result-cache")
+                .help("Reuses the output of the windows executed with the same input before"),
+        )
+        .arg(
+            Arg::new("zstd dict")
+                .long("zstd-dict")
+                .help("Compresses the payloads with a Zstd dictionary trained from the events"),
+        )
+        .arg(
+            Arg::new("embedded plans")
+                .long("embedded-plans")
+                .help("Runs the plans embedded in the function binary in the distributed mode"),
+        )
+        .arg(
+            Arg::new("dry run")
+                .long("dry-run")
+                .help("Prints the resources and the cost of the deployment without creating them"),
+        )
+}
+
+fn plan_args() -> App<'static> {
+    App::new("plan")
+        .about("Prints the subplans of a NEXMark query with their estimated data volume")
+        .arg(
+            Arg::new("query number")
+                .short('q')
+                .long("query")
+                .help("Sets the NEXMark benchmark query number")
+                .takes_value(true)
+                .possible_values(&[
+                    "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11", "12", "13",
+                ])
+                .default_value("3"),
+        )
+        .arg(
+            Arg::new("events per second")
+                .short('e')
+                .long("events-per-second")
+                .help("Estimates the data volume with a number of events per second")
+                .takes_value(true)
+                .default_value("1000"),
+        )
+        .arg(
+            Arg::new("window")
+                .long("window")
+                .value_name("window")
+                .help("Sets the window of the query, e.g. tumbling:10, hopping:10:5 or session:10")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("lineage")
+                .long("lineage")
+                .help("Prints the column-level lineage of the query instead of its subplans"),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .help("Sets the output format of the lineage")
+                .takes_value(true)
+                .possible_values(&["table", "json"])
+                .default_value("table"),
+        )
+        .arg(
+            Arg::new("export dir")
+                .long("export-dir")
+                .value_name("DIR")
+                .help("Writes the plans of the query stages to embed into the function binary")
+                .takes_value(true),
+        )
+}
+
+fn diff_args() -> App<'static> {
+    App::new("diff")
+        .about("Compares the query results of two runs, e.g. before and after a change")
+        .arg(
+            Arg::new("baseline")
+                .long("baseline")
+                .value_name("S3 PREFIX OR DIR")
+                .help(
+                    "Sets the results of the baseline run, e.g. s3://bucket/prefix or a directory",
+                )
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::new("candidate")
+                .long("candidate")
+                .value_name("S3 PREFIX OR DIR")
+                .help(
+                    "Sets the results of the candidate run, e.g. s3://bucket/prefix or a directory",
+                )
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::new("float tolerance")
+                .long("float-tol")
+                .help("Sets the tolerance of the floating-point values")
+                .takes_value(true)
+                .default_value("1e-9"),
+        )
+        .arg(
+            Arg::new("ordered")
+                .long("ordered")
+                .help("Compares the rows of each window in order"),
+        )
+        .arg(
+            Arg::new("max rows")
+                .long("max-rows")
+                .help("Sets the maximum number of differing rows printed per window")
+                .takes_value(true)
+                .default_value("10"),
+        )
 }
 
 pub fn run(matches: &ArgMatches) -> Result<()> {
@@ -186,6 +389,14 @@ pub fn run(matches: &ArgMatches) -> Result<()> {
         opt.async_type = true;
     }
 
+    if matches.is_present("timeout") {
+        opt.timeout = matches
+            .value_of("timeout")
+            .unwrap()
+            .parse::<u64>()
+            .with_context(|| anyhow!("Invalid timeout"))?;
+    }
+
     if matches.is_present("memory size") {
         opt.memory_size = matches
             .value_of("memory size")
@@ -222,7 +433,164 @@ pub fn run(matches: &ArgMatches) -> Result<()> {
             .with_context(|| anyhow!("Invalid Arrow Datafusion target partitions"))?;
     }
 
-    rainbow_println(include_str!("./flock"));
+    if matches.is_present("analyze") {
+        opt.analyze = true;
+    }
+
+    if matches.is_present("region") {
+        opt.region = matches
+            .value_of("region")
+            .unwrap()
+            .parse::<String>()
+            .with_context(|| anyhow!("Invalid region"))?;
+    }
+
+    if matches.is_present("window") {
+        opt.window = Some(
+            matches
+                .value_of("window")
+                .unwrap()
+                .parse::<Window>()
+                .with_context(|| anyhow!("Invalid window"))?,
+        );
+    }
+
+    if matches.is_present("coordinator") {
+        opt.coordinator = matches
+            .value_of("coordinator")
+            .unwrap()
+            .parse::<Coordinator>()
+            .with_context(|| anyhow!("Invalid coordinator"))?;
+    }
+
+    if matches.is_present("multiplex") {
+        opt.multiplex = true;
+    }
+
+    if matches.is_present("force") {
+        opt.force = true;
+    }
+
+    if matches.is_present("reserved concurrency") {
+        opt.reserved_concurrency = Some(
+            matches
+                .value_of("reserved concurrency")
+                .unwrap()
+                .parse::<i64>()
+                .with_context(|| anyhow!("Invalid reserved concurrency"))?,
+        );
+    }
+
+    if matches.is_present("results server") {
+        opt.results_server = Some(matches.value_of("results server").unwrap().to_string());
+    }
+
+    if matches.is_present("queries") {
+        opt.queries = Some(
+            matches
+                .value_of("queries")
+                .unwrap()
+                .parse::<QueryList>()
+                .with_context(|| anyhow!("Invalid queries"))?,
+        );
+    }
+
+    if matches.is_present("report") {
+        opt.report = Some(matches.value_of("report").unwrap().to_string());
+    }
+
+    if matches.is_present("auto memory") {
+        opt.auto_memory = true;
+    }
+
+    if matches.is_present("use result cache") {
+        opt.use_result_cache = true;
+    }
+
+    if matches.is_present("zstd dict") {
+        opt.zstd_dict = true;
+    }
+
+    if matches.is_present("embedded plans") {
+        opt.embedded_plans = true;
+    }
+
+    if matches.is_present("dry run") {
+        opt.dry_run = true;
+    }
+
+    rainbow_banner(include_str!("./flock"));
 
     futures::executor::block_on(nexmark_benchmark(&mut opt)).map_err(|e| e.into())
 }
+
+pub fn plan(matches: &ArgMatches) -> Result<()> {
+    let mut opt = NexmarkBenchmarkOpt {
+        query_number: matches
+            .value_of("query number")
+            .unwrap()
+            .parse::<usize>()
+            .with_context(|| anyhow!("invalid query number"))?,
+        events_per_second: matches
+            .value_of("events per second")
+            .unwrap()
+            .parse::<usize>()
+            .with_context(|| anyhow!("Invalid events per second"))?,
+        ..Default::default()
+    };
+
+    if matches.is_present("window") {
+        opt.window = Some(
+            matches
+                .value_of("window")
+                .unwrap()
+                .parse::<Window>()
+                .with_context(|| anyhow!("Invalid window"))?,
+        );
+    }
+
+    if matches.is_present("lineage") {
+        let json = matches.value_of("format") == Some("json");
+        let lineage = futures::executor::block_on(nexmark_lineage(&opt, json))?;
+        println!("{}", lineage);
+        return Ok(());
+    }
+
+    if let Some(dir) = matches.value_of("export dir") {
+        let paths =
+            futures::executor::block_on(nexmark_export_plans(&opt, std::path::Path::new(dir)))?;
+        paths.iter().for_each(|path| println!("{}", path.display()));
+        return Ok(());
+    }
+
+    let plan = futures::executor::block_on(nexmark_plan(&opt))?;
+    println!("{}", plan);
+    Ok(())
+}
+
+pub fn diff(matches: &ArgMatches) -> Result<()> {
+    let options = DiffOptions {
+        float_tol: matches
+            .value_of("float tolerance")
+            .unwrap()
+            .parse::<f64>()
+            .with_context(|| anyhow!("Invalid float tolerance"))?,
+        ordered:   matches.is_present("ordered"),
+    };
+    let max_rows = matches
+        .value_of("max rows")
+        .unwrap()
+        .parse::<usize>()
+        .with_context(|| anyhow!("Invalid max rows"))?;
+
+    let baseline = futures::executor::block_on(load_run(matches.value_of("baseline").unwrap()))?;
+    let candidate = futures::executor::block_on(load_run(matches.value_of("candidate").unwrap()))?;
+    let report = diff_runs(&baseline, &candidate, &options)?;
+    print!("{}", report.render(max_rows));
+
+    let differences = report.differences().count();
+    if differences > 0 {
+        return Err(anyhow!("{} windows differ", differences));
+    }
+    Ok(())
+}
@@ -17,6 +17,7 @@ use crate::fsql;
 use crate::lambda;
 use crate::nexmark;
 use crate::s3;
+use crate::schedule;
 use crate::ysb;
 use anyhow::Context as _;
 use anyhow::{anyhow, Result};
@@ -37,6 +38,7 @@ pub async fn main() -> Result<()> {
         .subcommand(nexmark::command_args())
         .subcommand(ysb::command_args())
         .subcommand(s3::command_args())
+        .subcommand(schedule::command_args())
         .subcommand(lambda::command_args())
         .subcommand(arch::command_args())
         .subcommand(fsql::command_args());
@@ -53,6 +55,7 @@ pub async fn main() -> Result<()> {
         "nexmark" => nexmark::command(matches),
         "ysb" => ysb::command(matches),
         "s3" => s3::command(matches),
+        "schedule" => schedule::command(matches),
         "lambda" => lambda::command(matches),
         "fsql" => fsql::command(matches),
         "arch" => arch::command(matches),
@@ -17,10 +17,12 @@ use anyhow::{anyhow, bail, Context as _, Ok, Result};
 use benchmarks::rainbow_println;
 use clap::{App, AppSettings, Arg, ArgMatches};
 use flock::aws::s3;
+use flock::configs::new_client;
+use flock::runtime::result_cache::{is_result_cache_key, result_cache_prefix};
+use flock::state::StateCleanup;
 use ini::Ini;
 use lazy_static::lazy_static;
 use log::warn;
-use rusoto_core::Region;
 use rusoto_s3::PutObjectRequest;
 use rusoto_s3::{S3Client, S3};
 use std::fs;
@@ -43,6 +45,7 @@ pub fn command(matches: &ArgMatches) -> Result<()> {
         "put" => futures::executor::block_on(put_function_object(matches)),
         "list" => futures::executor::block_on(list_buckets(matches)),
         "delete" => futures::executor::block_on(delete_buckets(matches)),
+        "gc" => futures::executor::block_on(gc_buckets(matches)),
         _ => {
             warn!("{} command is not implemented", command);
             Ok(())
@@ -60,6 +63,34 @@ pub fn command_args() -> App<'static> {
         .subcommand(put_args())
         .subcommand(list_args())
         .subcommand(delete_args())
+        .subcommand(gc_args())
+}
+
+fn gc_args() -> App<'static> {
+    App::new("gc")
+        .about("Deletes the state buckets of the queries that started long ago")
+        .arg(
+            Arg::new("older than")
+                .long("older-than")
+                .value_name("DURATION")
+                .help("Sets the age of the queries to delete the state buckets of, e.g. 7d")
+                .takes_value(true)
+                .default_value("7d"),
+        )
+        .arg(
+            Arg::new("result cache")
+                .long("result-cache")
+                .help("Deletes the cached results of the last stages instead of the state buckets"),
+        )
+        .arg(
+            Arg::new("query code")
+                .short('q')
+                .long("query")
+                .value_name("QUERY_CODE")
+                .help("Only deletes the cached results of the query")
+                .takes_value(true)
+                .requires("result cache"),
+        )
 }
 
 fn delete_args() -> App<'static> {
@@ -149,7 +180,7 @@ pub async fn put_function_object(matches: &ArgMatches) -> Result<()> {
         ..Default::default()
     };
 
-    S3Client::new(Region::default()).put_object(request).await?;
+    new_client::<S3Client>("").put_object(request).await?;
     rainbow_println("[OK] Upload Succeed.");
 
     Ok(())
@@ -222,3 +253,47 @@ async fn delete_buckets(matches: &ArgMatches) -> Result<()> {
 
     Ok(())
 }
+
+/// Deletes the state buckets of the queries that started before the given
+/// age, e.g. the ones left behind by the runs that skipped the cleanup.
+async fn gc_buckets(matches: &ArgMatches) -> Result<()> {
+    if matches.is_present("result cache") {
+        return gc_result_cache(matches.value_of("query code")).await;
+    }
+    let older_than = humantime::parse_duration(
+        matches
+            .value_of("older than")
+            .expect("No duration provided"),
+    )?;
+    let buckets = StateCleanup::default().run_orphaned(older_than).await?;
+    if buckets.is_empty() {
+        rainbow_println("No orphaned state buckets found.");
+    } else {
+        for bucket in &buckets {
+            rainbow_println(bucket);
+        }
+        rainbow_println(format!("Deleted {} state buckets.", buckets.len()));
+    }
+    Ok(())
+}
+
+/// Deletes the cached results of the last stages (see
+/// `flock::runtime::result_cache`) of the query, or of all queries if no query
+/// code is given.
+async fn gc_result_cache(query_code: Option<&str>) -> Result<()> {
+    let keys = match query_code {
+        Some(code) => s3::get_matched_keys(&FLOCK_S3_BUCKET, &result_cache_prefix(code)).await?,
+        None => s3::get_all_keys(&FLOCK_S3_BUCKET)
+            .await?
+            .into_iter()
+            .filter(|key| is_result_cache_key(key))
+            .collect(),
+    };
+    if keys.is_empty() {
+        rainbow_println("No cached results found.");
+    } else {
+        s3::delete_objects(&FLOCK_S3_BUCKET, &keys).await?;
+        rainbow_println(format!("Deleted {} cached results.", keys.len()));
+    }
+    Ok(())
+}
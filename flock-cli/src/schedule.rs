@@ -0,0 +1,207 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Flock CLI runs the batch queries over the S3 objects periodically.
+
+use anyhow::{anyhow, Context as _, Ok, Result};
+use benchmarks::rainbow_println;
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches};
+use flock::datasource::s3::{S3ObjectCompression, S3ObjectFormat, S3ObjectsSource};
+use flock::stream::Schedule;
+use log::warn;
+use std::time::Duration;
+
+pub fn command(matches: &ArgMatches) -> Result<()> {
+    let (command, matches) = match matches.subcommand() {
+        Some((command, matches)) => (command, matches),
+        None => unreachable!(),
+    };
+
+    match command {
+        "create" => futures::executor::block_on(create_schedule(matches)),
+        "list" => futures::executor::block_on(list_schedules(matches)),
+        "delete" => futures::executor::block_on(delete_schedule(matches)),
+        _ => {
+            warn!("{} command is not implemented", command);
+            Ok(())
+        }
+    }
+    .with_context(|| anyhow!("{} command failed", command))?;
+
+    Ok(())
+}
+
+pub fn command_args() -> App<'static> {
+    App::new("schedule")
+        .about("Runs the batch queries over AWS S3 periodically")
+        .setting(AppSettings::SubcommandRequired)
+        .subcommand(create_args())
+        .subcommand(list_args())
+        .subcommand(delete_args())
+}
+
+fn query_arg() -> Arg<'static> {
+    Arg::new("query code")
+        .short('q')
+        .long("query")
+        .value_name("query code")
+        .help("Sets the query code of the deployed batch query")
+        .required(true)
+        .takes_value(true)
+}
+
+fn name_arg() -> Arg<'static> {
+    Arg::new("name")
+        .short('n')
+        .long("name")
+        .value_name("name")
+        .help("Sets the name of the schedule")
+        .required(true)
+        .takes_value(true)
+}
+
+fn create_args() -> App<'static> {
+    App::new("create")
+        .about("Creates the EventBridge rule that runs the query on a schedule")
+        .arg(query_arg())
+        .arg(name_arg())
+        .arg(
+            Arg::new("rate")
+                .long("rate")
+                .value_name("rate")
+                .help("Sets the rate of the schedule, e.g. \"1 hour\"")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("cron")
+                .long("cron")
+                .value_name("cron")
+                .help("Sets the cron expression of the schedule, e.g. \"0 * * * ? *\"")
+                .takes_value(true),
+        )
+        .group(
+            ArgGroup::new("schedule")
+                .args(&["rate", "cron"])
+                .required(true),
+        )
+        .arg(
+            Arg::new("period")
+                .long("period")
+                .value_name("seconds")
+                .help("Sets the time range scanned by each run, which defaults to the rate")
+                .required_unless_present("rate")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("bucket")
+                .short('b')
+                .long("bucket")
+                .value_name("bucket")
+                .help("Sets the S3 bucket of the objects to scan")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("prefix")
+                .short('p')
+                .long("prefix")
+                .value_name("prefix")
+                .help("Sets the key prefix of the objects to scan")
+                .takes_value(true)
+                .default_value(""),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("format")
+                .help("Sets the format of the objects")
+                .possible_values(&["ndjson", "csv", "parquet"])
+                .takes_value(true)
+                .default_value("ndjson"),
+        )
+        .arg(
+            Arg::new("compression")
+                .long("compression")
+                .value_name("compression")
+                .help("Sets the compression of the objects")
+                .possible_values(&["none", "gzip", "zstd"])
+                .takes_value(true)
+                .default_value("none"),
+        )
+}
+
+fn list_args() -> App<'static> {
+    App::new("list")
+        .about("Lists the schedules of the query")
+        .arg(query_arg())
+}
+
+fn delete_args() -> App<'static> {
+    App::new("delete")
+        .about("Deletes the schedule of the query")
+        .arg(query_arg())
+        .arg(name_arg())
+}
+
+/// Creates the schedule of the batch query.
+async fn create_schedule(matches: &ArgMatches) -> Result<()> {
+    let schedule = match (matches.value_of("rate"), matches.value_of("cron")) {
+        (Some(rate), _) => Schedule::Rate(rate.to_string()),
+        (_, Some(cron)) => Schedule::Cron(cron.to_string()),
+        _ => unreachable!(),
+    };
+    let period = matches
+        .value_of("period")
+        .map(|p| p.parse::<u64>().map(Duration::from_secs))
+        .transpose()
+        .with_context(|| anyhow!("Invalid period"))?;
+    let source = S3ObjectsSource::new(
+        matches.value_of("bucket").unwrap(),
+        matches.value_of("prefix").unwrap(),
+        S3ObjectFormat::new(matches.value_of("format").unwrap())?,
+        S3ObjectCompression::new(matches.value_of("compression").unwrap())?,
+    );
+    let arn = flock::api::schedule_query(
+        matches.value_of("query code").unwrap(),
+        matches.value_of("name").unwrap(),
+        &source,
+        &schedule,
+        period,
+    )
+    .await?;
+    rainbow_println(format!("[OK] Created the schedule {}", arn));
+    Ok(())
+}
+
+/// Lists the schedules of the batch query.
+async fn list_schedules(matches: &ArgMatches) -> Result<()> {
+    let schedules = flock::api::list_schedules(matches.value_of("query code").unwrap()).await?;
+    if schedules.is_empty() {
+        rainbow_println("No schedule found.");
+    }
+    for (name, expression) in schedules {
+        rainbow_println(format!("{}: {}", name, expression));
+    }
+    Ok(())
+}
+
+/// Deletes the schedule of the batch query.
+async fn delete_schedule(matches: &ArgMatches) -> Result<()> {
+    flock::api::unschedule_query(
+        matches.value_of("query code").unwrap(),
+        matches.value_of("name").unwrap(),
+    )
+    .await?;
+    rainbow_println("[OK] Deleted the schedule.");
+    Ok(())
+}
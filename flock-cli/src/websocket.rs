@@ -0,0 +1,208 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The WebSocket client that receives the results of the interactive queries
+//! pushed by the WebSocket data sink.
+//!
+//! If the connection drops, the client reconnects with exponential backoff,
+//! registers its new connection id for the query, and collects the frames that
+//! the data sink stored in S3 in the meantime.
+
+use anyhow::{anyhow, Result};
+use datafusion::arrow::record_batch::RecordBatch;
+use flock::aws::s3;
+use flock::configs::FLOCK_S3_BUCKET;
+use flock::datasink::websocket::{connection_key, frames_key_prefix, Frame};
+use futures::{SinkExt, StreamExt};
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+/// The maximum number of attempts to reconnect.
+const MAX_RECONNECT_ATTEMPTS: u32 = 5;
+
+/// The initial delay between two attempts to reconnect.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The timeout to receive the connection id after the connection is opened.
+const CONNECTION_ID_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// The stream of the results pushed to the client.
+pub struct ResultStream {
+    url:           String,
+    socket:        Socket,
+    connection_id: String,
+    /// The query whose results are received, once it's submitted.
+    query_code:    Option<String>,
+    /// The ids of the frames received so far.
+    seen:          HashSet<String>,
+    /// The frames collected from S3 but not yet returned.
+    pending:       VecDeque<Frame>,
+}
+
+impl ResultStream {
+    /// Connects to the WebSocket API, i.e. `wss://{api-id}.execute-api.
+    /// {region}.amazonaws.com/{stage}`.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let (socket, connection_id) = open(url).await?;
+        Ok(Self {
+            url: url.to_string(),
+            socket,
+            connection_id,
+            query_code: None,
+            seen: HashSet::new(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Returns the connection id that the results are pushed to.
+    pub fn connection_id(&self) -> &str {
+        &self.connection_id
+    }
+
+    /// Receives the results of the query.
+    pub fn follow(&mut self, query_code: &str) {
+        self.query_code = Some(query_code.to_string());
+    }
+
+    /// Returns the record batches of the next window, or `None` if no
+    /// results arrive within the idle timeout.
+    pub async fn next_batches(
+        &mut self,
+        idle_timeout: Duration,
+    ) -> Result<Option<Vec<RecordBatch>>> {
+        loop {
+            if let Some(frame) = self.pending.pop_front() {
+                return Ok(Some(frame.into_record_batches().await?));
+            }
+
+            match tokio::time::timeout(idle_timeout, self.socket.next()).await {
+                Err(_) => {
+                    // The frames stored while the sink was switching over to
+                    // the new connection only show up in S3.
+                    self.collect_stored_frames().await?;
+                    if self.pending.is_empty() {
+                        return Ok(None);
+                    }
+                }
+                Ok(Some(Ok(Message::Text(text)))) => self.receive(text.as_bytes()),
+                Ok(Some(Ok(Message::Binary(data)))) => self.receive(&data),
+                Ok(Some(Ok(Message::Close(_)))) | Ok(None) => self.reconnect().await?,
+                Ok(Some(Ok(_))) => {}
+                Ok(Some(Err(e))) => {
+                    warn!("WebSocket error: {}", e);
+                    self.reconnect().await?;
+                }
+            }
+        }
+    }
+
+    /// Queues the frame unless it is received already.
+    fn receive(&mut self, data: &[u8]) {
+        match serde_json::from_slice::<Frame>(data) {
+            Ok(frame) => {
+                if self.seen.insert(frame.id().to_string()) {
+                    self.pending.push_back(frame);
+                }
+            }
+            Err(e) => warn!("Ignore the unknown message: {}", e),
+        }
+    }
+
+    /// Opens a new connection, registers it for the query, and collects the
+    /// frames that were stored while the client was disconnected.
+    async fn reconnect(&mut self) -> Result<()> {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 1;
+        let (socket, connection_id) = loop {
+            match open(&self.url).await {
+                Ok(connection) => break connection,
+                Err(e) if attempt < MAX_RECONNECT_ATTEMPTS => {
+                    warn!("Failed to reconnect (attempt {}): {}", attempt, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        info!("Reconnected as {}", connection_id);
+        self.socket = socket;
+        self.connection_id = connection_id;
+
+        if let Some(query_code) = &self.query_code {
+            s3::put_object(
+                &FLOCK_S3_BUCKET,
+                &connection_key(query_code),
+                self.connection_id.as_bytes().to_vec(),
+            )
+            .await?;
+        }
+        self.collect_stored_frames().await
+    }
+
+    /// Queues the frames of the query stored in S3 that are not received yet.
+    async fn collect_stored_frames(&mut self) -> Result<()> {
+        let query_code = match &self.query_code {
+            Some(query_code) => query_code.clone(),
+            None => return Ok(()),
+        };
+        let prefix = frames_key_prefix(&query_code);
+        for key in s3::get_matched_keys(&FLOCK_S3_BUCKET, &prefix).await? {
+            let id = key.trim_start_matches(&prefix);
+            if self.seen.contains(id) {
+                continue;
+            }
+            let data = s3::get_object(&FLOCK_S3_BUCKET, &key).await?;
+            self.receive(&data);
+        }
+        Ok(())
+    }
+}
+
+/// Opens a connection to the WebSocket API, and asks for its connection id.
+async fn open(url: &str) -> Result<(Socket, String)> {
+    let (mut socket, _) = connect_async(url).await?;
+    socket
+        .send(Message::Text(
+            json!({"action": "connection_id"}).to_string(),
+        ))
+        .await?;
+
+    let connection_id =
+        tokio::time::timeout(CONNECTION_ID_TIMEOUT, receive_connection_id(&mut socket))
+            .await
+            .map_err(|_| anyhow!("Timed out waiting for the connection id of {}", url))??;
+    Ok((socket, connection_id))
+}
+
+/// Waits for the reply of the `connection_id` route.
+async fn receive_connection_id(socket: &mut Socket) -> Result<String> {
+    while let Some(message) = socket.next().await {
+        if let Message::Text(text) = message? {
+            let value: Value = serde_json::from_str(&text)?;
+            if let Some(id) = value["connection_id"].as_str() {
+                return Ok(id.to_string());
+            }
+        }
+    }
+    Err(anyhow!(
+        "The connection is closed before its id is received"
+    ))
+}
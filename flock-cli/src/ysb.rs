@@ -14,8 +14,10 @@
 //! This crate runs the Yahoo! Streaming Benchmarks on cloud function services.
 
 use anyhow::{anyhow, Context as _, Result};
-use benchmarks::{rainbow_println, ysb_benchmark, YSBBenchmarkOpt};
+use benchmarks::{rainbow_banner, ysb_benchmark, YSBBenchmarkOpt};
 use clap::{App, AppSettings, Arg, ArgMatches};
+use flock::driver::stepfunctions::Coordinator;
+use flock::stream::Window;
 use log::warn;
 
 pub fn command(matches: &ArgMatches) -> Result<()> {
@@ -76,7 +78,7 @@ fn run_args() -> App<'static> {
                 .long("data-sink-type")
                 .help("Runs the YSB benchmark with a data sink type")
                 .takes_value(true)
-                .possible_values(&["sqs", "s3", "dynamodb", "efs", "blackhole"])
+                .possible_values(&["sqs", "s3", "dynamodb", "efs", "response", "blackhole"])
                 .default_value("blackhole"),
         )
         .arg(
@@ -85,6 +87,14 @@ fn run_args() -> App<'static> {
                 .long("async-type")
                 .help("Runs the YSB benchmark with async function invocations"),
         )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .value_name("timeout")
+                .help("Sets the maximum seconds to wait for all windows in the async mode")
+                .takes_value(true)
+                .default_value("300"),
+        )
         .arg(
             Arg::new("memory size")
                 .short('m')
@@ -126,6 +136,34 @@ fn run_args() -> App<'static> {
                 .possible_values(&["1", "2", "4", "8", "16", "24", "32"])
                 .default_value("8"),
         )
+        .arg(
+            Arg::new("region")
+                .long("region")
+                .value_name("region")
+                .help("Sets the AWS region to deploy and run the benchmark")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("window")
+                .long("window")
+                .value_name("window")
+                .help("Sets the tumbling window of the query, e.g. tumbling:10")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("coordinator")
+                .long("coordinator")
+                .value_name("coordinator")
+                .help("Sets the coordinator of the query stages")
+                .takes_value(true)
+                .possible_values(&["direct", "step-functions"])
+                .default_value("direct"),
+        )
+        .arg(
+            Arg::new("use result cache")
+                .long("use-result-cache")
+                .help("Reuses the output of the windows executed with the same input before"),
+        )
 }
 
 pub fn run(matches: &ArgMatches) -> Result<()> {
@@ -171,6 +209,14 @@ pub fn run(matches: &ArgMatches) -> Result<()> {
             .with_context(|| anyhow!("Invalid async type"))?;
     }
 
+    if matches.is_present("timeout") {
+        opt.timeout = matches
+            .value_of("timeout")
+            .unwrap()
+            .parse::<u64>()
+            .with_context(|| anyhow!("Invalid timeout"))?;
+    }
+
     if matches.is_present("memory size") {
         opt.memory_size = matches
             .value_of("memory size")
@@ -207,7 +253,37 @@ pub fn run(matches: &ArgMatches) -> Result<()> {
             .with_context(|| anyhow!("Invalid Arrow Datafusion target partitions"))?;
     }
 
-    rainbow_println(include_str!("./flock"));
+    if matches.is_present("region") {
+        opt.region = matches
+            .value_of("region")
+            .unwrap()
+            .parse::<String>()
+            .with_context(|| anyhow!("Invalid region"))?;
+    }
+
+    if matches.is_present("window") {
+        opt.window = Some(
+            matches
+                .value_of("window")
+                .unwrap()
+                .parse::<Window>()
+                .with_context(|| anyhow!("Invalid window"))?,
+        );
+    }
+
+    if matches.is_present("coordinator") {
+        opt.coordinator = matches
+            .value_of("coordinator")
+            .unwrap()
+            .parse::<Coordinator>()
+            .with_context(|| anyhow!("Invalid coordinator"))?;
+    }
+
+    if matches.is_present("use result cache") {
+        opt.use_result_cache = true;
+    }
+
+    rainbow_banner(include_str!("./flock"));
 
     futures::executor::block_on(ysb_benchmark(&mut opt)).map_err(|e| e.into())
 }
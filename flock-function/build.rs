@@ -0,0 +1,76 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Embeds the plans of the query stages into the function binary (see
+//! `flock::runtime::embedded`).
+//!
+//! The plans exported by `flock-cli nexmark plan --export-dir <dir>` are read
+//! from the directory in the `FLOCK_EMBEDDED_PLANS` environment variable of the
+//! build, e.g.
+//!
+//! ```bash
+//! FLOCK_EMBEDDED_PLANS=/tmp/plans cargo build --release --bin flock
+//! ```
+//!
+//! Without the variable, no plan is embedded and the functions load their
+//! plans from the environment.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The environment variable of the directory of the exported plans, which is
+/// `flock::runtime::embedded::FLOCK_EMBEDDED_PLANS`.
+const FLOCK_EMBEDDED_PLANS: &str = "FLOCK_EMBEDDED_PLANS";
+
+fn main() {
+    println!("cargo:rerun-if-env-changed={}", FLOCK_EMBEDDED_PLANS);
+
+    let mut stages: Vec<(String, PathBuf)> = vec![];
+    if let Ok(dir) = env::var(FLOCK_EMBEDDED_PLANS) {
+        println!("cargo:rerun-if-changed={}", dir);
+        let entries = fs::read_dir(&dir)
+            .unwrap_or_else(|e| panic!("Failed to read the plans in {}: {}", dir, e));
+        for entry in entries {
+            let path = entry.expect("Failed to read the plans").path();
+            if path.extension().map_or(true, |ext| ext != "json") {
+                continue;
+            }
+            let stage = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .expect("The name of the plan isn't a stage name")
+                .to_owned();
+            let path = fs::canonicalize(&path).expect("Failed to resolve the plan");
+            println!("cargo:rerun-if-changed={}", path.display());
+            stages.push((stage, path));
+        }
+    }
+    stages.sort();
+
+    let mut code = String::from(
+        "/// The serialized plans embedded into the function binary, by stage name.\n\
+         pub static EMBEDDED_PLANS: &[(&str, &str)] = &[\n",
+    );
+    for (stage, path) in &stages {
+        code.push_str(&format!(
+            "    ({:?}, include_str!({:?})),\n",
+            stage,
+            path.display().to_string()
+        ));
+    }
+    code.push_str("];\n");
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("embedded_plans.rs");
+    fs::write(out, code).expect("Failed to write the embedded plans");
+}
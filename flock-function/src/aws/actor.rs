@@ -11,32 +11,95 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use crate::{consistent_hash_context, ConsistentHashContext, CONSISTENT_HASH_CONTEXT};
+use crate::{consistent_hash_context, next_function, ConsistentHashContext};
 use chrono::Utc;
-use datafusion::arrow::csv::reader::ReaderBuilder;
 use datafusion::arrow::record_batch::RecordBatch;
-use flock::aws::lambda;
-use flock::aws::s3;
+use flock::aws::chaos::with_chaos;
+use flock::aws::client::CloudClient;
+use flock::datasink::enrich::with_window_columns;
+use flock::datasink::results::{window_id, ResultStore};
+use flock::datasink::{inline_response, sink_key};
+use flock::driver::stepfunctions::{sfn_step, spill_key, SFN_MAX_RESPONSE_SIZE};
+use flock::encryption;
 use flock::prelude::*;
-use flock::runtime::arena::WindowId;
+use flock::runtime::analyze::{is_analyze, StageMetrics};
+use flock::runtime::arena::{
+    flush_len, snapshot, spill_threshold, ArenaSnapshot, DoneMarker, ProcessedWindows,
+    SessionMetadata, SessionState, WindowId, WindowState, FLUSHED_METADATA_KEY, FLUSH_METADATA_KEY,
+    PANE_METADATA_KEY, SESSION_GAP_METADATA_KEY, WINDOW_METADATA_KEY,
+};
+use flock::runtime::broadcast::{
+    self, build_side_bytes, is_small_side, probe_metadata, side_input_key, side_input_to_csv,
+    stash_key, BroadcastRole, BUILD_SIDE_CACHE,
+};
+use flock::runtime::completion::{completion_window, is_completion, report_window};
+use flock::runtime::deadline::{self, SystemClock};
+use flock::runtime::distribution::distribute;
+use flock::runtime::external_sort::{ExternalSortSpec, ExternalSorter};
+use flock::runtime::function_name::FunctionName;
+use flock::runtime::logging::spawn_in_span;
+#[cfg(feature = "nexmark")]
+use flock::runtime::metadata::AddColumn;
+use flock::runtime::metadata::InvocationType;
+use flock::runtime::metrics::{self, Metric};
+use flock::runtime::peek::{PeekMarker, Peeks};
+use flock::runtime::response::{Response, Status};
+use flock::runtime::result_cache;
+use flock::runtime::running_aggregate;
+use flock::runtime::scaling::{ScalingHints, ScalingMonitor, ScalingPolicy};
+use flock::runtime::side_input::SIDE_INPUT_CACHE;
+use flock::runtime::skew::{
+    combine_salts, combine_sender, combiners, remove_salts, salt_partitions, salted_keys,
+    set_salted_keys, split_salted, SaltState, SaltedKey, SALT_COMBINE_METADATA_KEY,
+};
+use flock::runtime::stats::PayloadStats;
+use flock::runtime::topology;
+use flock::runtime::trace;
+use flock::runtime::transport;
+use futures::stream::StreamExt;
 use lazy_static::lazy_static;
-use log::info;
 use rand::{rngs::StdRng, Rng, SeedableRng};
 use rayon::prelude::*;
 use serde_json::Value;
-use std::collections::HashMap;
-use std::collections::HashSet;
-use std::io::Cursor;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Instant;
+use tracing::{info, warn};
 
 lazy_static! {
     static ref CONCURRENCY: usize = FLOCK_CONF["lambda"]["concurrency"]
         .parse::<usize>()
         .unwrap();
-    static ref PROCESSED_WINDOWS: Mutex<HashSet<WindowId>> = Mutex::new(HashSet::new());
+    static ref PROCESSED_WINDOWS: Mutex<ProcessedWindows> =
+        Mutex::new(ProcessedWindows::new(*FLOCK_PROCESSED_WINDOWS_CAPACITY));
+    static ref WINDOW_STATE: Mutex<WindowState> = Mutex::new(WindowState::new());
+    static ref SESSION_STATE: Mutex<SessionState> = Mutex::new(SessionState::new());
+    static ref SALT_STATE: Mutex<SaltState> = Mutex::new(SaltState::new());
+    /// The external sorts of the open windows (see `collect_sorted`).
+    static ref SORTED_RUNS: Mutex<HashMap<WindowId, ExternalSorter>> = Mutex::new(HashMap::new());
+    static ref SCALING_MONITORS: Mutex<HashMap<String, ScalingMonitor>> =
+        Mutex::new(HashMap::new());
+    /// The peek markers of the functions, and when they were last checked.
+    static ref PEEK_MARKERS: Mutex<HashMap<String, (i64, Option<PeekMarker>)>> =
+        Mutex::new(HashMap::new());
+    /// The functions that are draining before the upgrade of their binary.
+    static ref DRAINING: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    /// The functions that have claimed the snapshot of their previous binary.
+    static ref RESTORED: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
 }
 
+/// The number of payloads that took the fast path of the element-wise windows.
+static PIPELINED_PAYLOADS: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of windows whose output was read from the result cache instead
+/// of executing the plan.
+static CACHED_WINDOWS: AtomicUsize = AtomicUsize::new(0);
+
 /// The generic function executor.
 ///
 /// This function is invoked by the datafusion runtime. It is responsible for
@@ -52,11 +115,29 @@ lazy_static! {
 /// ## Returns
 /// The output stream of the function.
 pub async fn collect(
-    ctx: &mut ExecutionContext,
+    ctx: &ExecutionContext,
+    streams: Vec<Vec<Vec<RecordBatch>>>,
+) -> Result<Vec<Vec<RecordBatch>>> {
+    collect_named(ctx, streams, &[]).await
+}
+
+/// Executes the plan like [`collect`], where the input streams are routed to
+/// the leaves of the plan by the given stream names, e.g. the ones carried by
+/// the payload (see [`Payload::stream_names`]).
+pub async fn collect_named(
+    ctx: &ExecutionContext,
     streams: Vec<Vec<Vec<RecordBatch>>>,
+    names: &[Option<String>],
 ) -> Result<Vec<Vec<RecordBatch>>> {
     info!("Executing the physical plan.");
-    ctx.feed_data_sources(streams).await?;
+    let start = Instant::now();
+    let rows = streams
+        .iter()
+        .flatten()
+        .flatten()
+        .map(|b| b.num_rows())
+        .sum::<usize>();
+    ctx.feed_named_data_sources(streams, names).await?;
     let output = if ctx.is_shuffling().await? {
         let output = ctx.execute_partitioned().await?;
         assert!(output.len() == 1);
@@ -67,6 +148,9 @@ pub async fn collect(
     ctx.clean_data_sources().await?;
     info!("[OK] The execution is finished.");
 
+    metrics::scope().add(Metric::RowsProcessed, rows as f64);
+    metrics::scope().add(Metric::ExecuteDuration, start.elapsed().as_millis() as f64);
+
     info!(
         "[INFO] The number of rows in the output is {}.",
         output
@@ -78,10 +162,54 @@ pub async fn collect(
     Ok(output)
 }
 
+/// Executes the plan like [`collect`], unless the function is the last stage
+/// of a query with the result cache enabled, and the window was executed with
+/// the same input before (see [`flock::runtime::result_cache`]).
+async fn collect_cached(
+    ctx: &ExecutionContext,
+    window_id: &WindowId,
+    streams: Vec<Vec<Vec<RecordBatch>>>,
+) -> Result<Vec<Vec<RecordBatch>>> {
+    let plan_hash = match (&ctx.result_cache, &ctx.next) {
+        (Some(plan_hash), CloudFunction::Sink(_)) => plan_hash.clone(),
+        _ => return collect(ctx, streams).await,
+    };
+
+    let key = result_cache::cache_key(ctx.query_code(), window_id, &streams)?;
+    let state_backend = ctx.state_backend.clone();
+    if let Some(output) =
+        result_cache::lookup(state_backend.as_ref(), &FLOCK_S3_BUCKET, &key, &plan_hash).await?
+    {
+        info!(
+            "[OK] Read the output of the window from the result cache: {}",
+            key
+        );
+        CACHED_WINDOWS.fetch_add(1, Ordering::Relaxed);
+        metrics::scope().incr(Metric::ResultCacheHits);
+        return Ok(output);
+    }
+
+    let output = collect(ctx, streams).await?;
+    result_cache::store(
+        state_backend.as_ref(),
+        &FLOCK_S3_BUCKET,
+        &key,
+        &plan_hash,
+        &output,
+    )
+    .await?;
+    Ok(output)
+}
+
 /// Read the payload from S3 via the S3 bucket and the key.
-async fn read_payload_from_s3(bucket: String, key: String) -> Result<Payload> {
-    let body = s3::get_object(&bucket, &key).await?;
-    let payload: Payload = serde_json::from_slice(&body)?;
+async fn read_payload_from_s3(
+    client: &dyn CloudClient,
+    bucket: String,
+    key: String,
+) -> Result<Payload> {
+    let body = encryption::open_bytes(client.s3_get(&bucket, &key).await?).await?;
+    let mut payload = Payload::from_slice(&body)?;
+    encryption::open_payload(&mut payload).await?;
     Ok(payload)
 }
 
@@ -95,40 +223,499 @@ async fn read_payload_from_s3(bucket: String, key: String) -> Result<Payload> {
 /// * `payload` - The payload of the function invocation.
 ///
 /// # Returns
-/// A JSON object that contains the return value of the function invocation.
+/// The response envelope of the invocation: `not_ready` if the window of the
+/// payload isn't complete yet, `duplicate` if it's already processed, and `ok`
+/// with the response of the next functions otherwise.
 pub async fn handler(
-    ctx: &mut ExecutionContext,
+    ctx: &ExecutionContext,
     arena: &mut Arena,
     event: Payload,
-) -> Result<Value> {
+) -> Result<Response> {
+    // The faults of the chaos layer are injected into the AWS calls of this
+    // invocation only (see `flock::aws::chaos`).
+    match with_chaos(ctx.cloud_client.clone(), &event.metadata)? {
+        Some(client) => {
+            let ctx = ExecutionContext {
+                cloud_client: client,
+                ..ctx.clone()
+            };
+            handle_payload(&ctx, arena, event).await
+        }
+        None => handle_payload(ctx, arena, event).await,
+    }
+}
+
+/// Handles the payload of the invocation (see [`handler`]).
+async fn handle_payload(
+    ctx: &ExecutionContext,
+    arena: &mut Arena,
+    event: Payload,
+) -> Result<Response> {
     info!("Receiving a data packet: {:?}", event.uuid);
 
+    if snapshot::is_drain(&event.metadata) {
+        return Ok(Response::ok(ctx.name.clone(), drain(ctx, arena).await?));
+    }
+    if ctx.is_aggregate() {
+        restore_snapshot(ctx, arena).await?;
+        if DRAINING.lock().unwrap().contains(&ctx.name) {
+            let value = redirect_to_snapshot(ctx, event).await?;
+            return Ok(Response::ok(ctx.name.clone(), value));
+        }
+    }
+
     let query_number = event.query_number;
-    let metadata = event.metadata.clone();
+    let mut metadata = event.metadata.clone();
     let uuid = event.uuid.clone();
     let shuffle_id = event.shuffle_id;
+    let envelope = Response::new(Status::Ok, ctx.name.clone())
+        .with_window(window_id(&uuid, shuffle_id))
+        .with_uuid(uuid.clone());
+    let window_id = event.get_window_id();
+
+    // The salted keys only concern the current stage (see `flock::runtime::skew`).
+    let salted = salted_keys(&metadata);
+    let combine = combine_sender(&metadata);
+    remove_salts(&mut metadata);
+    // The window is partial if the data source has flushed it, either with
+    // this payload or before (see `Arena::flush`).
+    let flushed = flush_len(&metadata).is_some() || arena.is_flushed(&window_id);
+    if let Some(metadata) = metadata.as_mut() {
+        metadata.remove(FLUSH_METADATA_KEY);
+    }
+    if ctx.is_pipelined() {
+        return Ok(envelope.with_data(pipeline(ctx, event, metadata).await?));
+    }
+    if let Some(partition) = combine {
+        let (batches, _) = event.to_record_batch()?;
+        SALT_STATE
+            .lock()
+            .unwrap()
+            .receive(&window_id, partition, batches);
+        return match finalize_salted_window(ctx, &window_id).await? {
+            Some(output) => {
+                let hash_context = consistent_hash_context(&next_function(ctx, &metadata)?);
+                let value = invoke_next_functions(
+                    ctx,
+                    &hash_context,
+                    query_number,
+                    uuid,
+                    metadata,
+                    shuffle_id,
+                    output,
+                )
+                .await?;
+                Ok(envelope.with_data(value))
+            }
+            None => Ok(Response {
+                status: Status::NotReady,
+                ..envelope
+            }),
+        };
+    }
+    if !salted.is_empty() {
+        SALT_STATE.lock().unwrap().mark(&window_id, salted);
+    }
+
+    if ctx.broadcast == Some(BroadcastRole::Stash) {
+        stash_payload(ctx.cloud_client.as_ref(), &event).await?;
+    }
+
+    let mut metrics = if is_analyze(&metadata) {
+        let mut metrics = StageMetrics::new(&ctx.name);
+        metrics.record_payload(&event);
+        Some(metrics)
+    } else {
+        None
+    };
 
-    let (input, status) = prepare_data_sources(ctx, arena, event).await?;
+    let session = infer_session_window(&metadata, ctx.window.as_ref()).zip(event.watermark);
+    let pane = ctx.argmax_key.clone().zip(infer_pane(&metadata));
+    let (output, input, status) = match (session, pane) {
+        (Some((session, watermark)), _) => {
+            collect_session(ctx, arena, event, session, watermark).await?
+        }
+        (None, Some((key, (pane, window)))) => {
+            collect_pane(ctx, arena, event, &key, pane, window).await?
+        }
+        (None, None) => match external_sorter(ctx, arena, &event).await? {
+            Some(sorter) => collect_sorted(ctx, arena, event, sorter).await?,
+            None => {
+                let (input, status) = prepare_data_sources(ctx, arena, event).await?;
+                (None, input, status)
+            }
+        },
+    };
 
     if status == HashAggregateStatus::Processed {
         info!("[Ok] Function {}: data is already processed.", ctx.name);
-        return Ok(Value::Null);
+        report_stage_metrics(&uuid, shuffle_id, metrics).await?;
+        return Ok(Response {
+            status: Status::from(&status),
+            ..envelope
+        });
     } else if status == HashAggregateStatus::NotReady {
         info!("[Ok] Function {}: data aggregation is not ready.", ctx.name);
-        return Ok(Value::Null);
+        report_stage_metrics(&uuid, shuffle_id, metrics).await?;
+        let response = Response {
+            status: Status::from(&status),
+            ..envelope
+        };
+        // The group functions report the window state held by the arena.
+        return Ok(if ctx.is_aggregate() {
+            response.with_data(serde_json::json!({ "arena": arena.stats() }))
+        } else {
+            response
+        });
+    }
+
+    if flushed {
+        metadata
+            .get_or_insert_with(QueryMetadata::default)
+            .insert(FLUSHED_METADATA_KEY.to_string(), "true".to_string());
+    }
+
+    let output = match output {
+        Some(output) => output,
+        None => {
+            if let Some(m) = metrics.as_mut() {
+                m.record_input(&input);
+            }
+            let start = Instant::now();
+            let output = collect_cached(ctx, &window_id, input).await?;
+            if let Some(m) = metrics.as_mut() {
+                m.execute_ms = start.elapsed().as_millis() as u64;
+                m.record_output(&output);
+            }
+            output
+        }
+    };
+
+    let output = match combine_salted_keys(ctx, &window_id, &uuid, &metadata, output).await? {
+        Some(output) => output,
+        None => {
+            info!("[Ok] Function {}: waiting for the salted keys.", ctx.name);
+            report_stage_metrics(&uuid, shuffle_id, metrics).await?;
+            return Ok(Response {
+                status: Status::NotReady,
+                ..envelope
+            });
+        }
+    };
+
+    let value = if ctx.broadcast == Some(BroadcastRole::Broadcast) {
+        broadcast_side_input(ctx, query_number, &uuid, &window_id, metadata, output).await?
+    } else {
+        let hash_context = consistent_hash_context(&next_function(ctx, &metadata)?);
+        invoke_next_functions(
+            ctx,
+            &hash_context,
+            query_number,
+            uuid.clone(),
+            metadata,
+            shuffle_id,
+            output,
+        )
+        .await?
+    };
+    report_stage_metrics(&uuid, shuffle_id, metrics).await?;
+    Ok(envelope.with_data(value))
+}
+
+/// Drains the function before the upgrade of its binary: the open windows of
+/// the arena and the open session windows are moved to the snapshot of the
+/// function in the state backend (see [`flock::runtime::arena::snapshot`]).
+/// The window state of the incremental queries isn't kept, and their windows
+/// spanning the upgrade are recomputed from the panes.
+///
+/// # Returns
+/// The number of windows and sessions in the snapshot.
+async fn drain(ctx: &ExecutionContext, arena: &mut Arena) -> Result<Value> {
+    let sessions = SESSION_STATE.lock().unwrap().snapshot()?;
+    let snapshot = ArenaSnapshot {
+        windows: arena.take_snapshot(),
+        sessions,
+        pending: vec![],
+    };
+    let ack = serde_json::json!({
+        "drained": {
+            "windows": snapshot.windows.len(),
+            "sessions": snapshot.sessions.len(),
+        }
+    });
+    if !snapshot.is_empty() {
+        let key = snapshot::snapshot_key(ctx.query_code(), &ctx.name);
+        if let Err(e) = snapshot::merge(
+            ctx.state_backend.as_ref(),
+            &FLOCK_S3_BUCKET,
+            &key,
+            &snapshot,
+        )
+        .await
+        {
+            // The function keeps running with its state if it can't drain.
+            arena.restore_snapshot(snapshot.windows);
+            return Err(e);
+        }
+        *SESSION_STATE.lock().unwrap() = SessionState::new();
+        info!("[OK] Wrote the snapshot of the function: {}", key);
+    }
+    if ctx.is_aggregate() {
+        DRAINING.lock().unwrap().insert(ctx.name.clone());
+    }
+    Ok(ack)
+}
+
+/// Appends the payload received by the draining function to its snapshot
+/// instead of its arena, so that the new binary collects it.
+async fn redirect_to_snapshot(ctx: &ExecutionContext, event: Payload) -> Result<Value> {
+    let key = snapshot::snapshot_key(ctx.query_code(), &ctx.name);
+    snapshot::append(ctx.state_backend.as_ref(), &FLOCK_S3_BUCKET, &key, event).await?;
+    info!(
+        "[Ok] Function {} is draining: appended the payload to {}.",
+        ctx.name, key
+    );
+    Ok(serde_json::json!({ "draining": true }))
+}
+
+/// Restores the snapshot written by the previous binary of the function on
+/// its first invocation, and replays the payloads that the previous binary
+/// received while it was draining.
+async fn restore_snapshot(ctx: &ExecutionContext, arena: &mut Arena) -> Result<()> {
+    if RESTORED.lock().unwrap().contains(&ctx.name) {
+        return Ok(());
+    }
+    let key = snapshot::snapshot_key(ctx.query_code(), &ctx.name);
+    let snapshot = snapshot::claim(ctx.state_backend.as_ref(), &FLOCK_S3_BUCKET, &key).await?;
+    RESTORED.lock().unwrap().insert(ctx.name.clone());
+    let snapshot = match snapshot {
+        Some(snapshot) => snapshot,
+        None => return Ok(()),
+    };
+    info!(
+        "[OK] Restored {} windows and {} sessions from the snapshot: {}",
+        snapshot.windows.len(),
+        snapshot.sessions.len(),
+        key
+    );
+    arena.restore_snapshot(snapshot.windows);
+    SESSION_STATE.lock().unwrap().restore(snapshot.sessions)?;
+    for payload in snapshot.pending {
+        let replay: Pin<Box<dyn Future<Output = Result<Response>> + Send + '_>> =
+            Box::pin(handle_payload(ctx, arena, payload));
+        replay.await?;
+    }
+    Ok(())
+}
+
+/// The fast path of the element-wise windows (see
+/// [`ExecutionContext::is_pipelined`]). The payload is executed on its own and
+/// its output is forwarded with a fresh uuid, since no function downstream
+/// collects the payloads of the window. Like [`prepare_data_sources`], it reads
+/// the payload of the S3 communication mode and the side input of the query.
+///
+/// # Arguments
+/// * `ctx` - The runtime context of the function.
+/// * `event` - The payload of the function invocation.
+/// * `metadata` - The metadata of the payload without the salted keys.
+async fn pipeline(
+    ctx: &ExecutionContext,
+    event: Payload,
+    metadata: Option<QueryMetadata>,
+) -> Result<Value> {
+    PIPELINED_PAYLOADS.fetch_add(1, Ordering::Relaxed);
+    metrics::scope().incr(Metric::PipelinedPayloads);
+
+    let query_number = event.query_number;
+    let uuid = event.uuid.clone();
+    let shuffle_id = event.shuffle_id;
+    let event = match infer_s3_mode(&metadata) {
+        Some((bucket, key)) => read_payload_from_s3(ctx.cloud_client.as_ref(), bucket, key).await?,
+        None => event,
+    };
+    let mut stage_metrics = is_analyze(&metadata).then(|| {
+        let mut m = StageMetrics::new(&ctx.name);
+        m.record_payload(&event);
+        m
+    });
+
+    report_input_stats(event.stats.as_ref());
+    let names = event.stream_names();
+    let pointer = event.broadcast.clone();
+    let (r1, mut r2) = event.to_record_batch()?;
+    if let Some(pointer) = pointer {
+        r2 = BUILD_SIDE_CACHE
+            .get_or_read(ctx.cloud_client.as_ref(), &pointer)
+            .await?;
+    }
+    let mut input = vec![vec![r1], vec![r2]];
+    if let Ok(batch) = infer_side_input(ctx.cloud_client.as_ref(), &metadata).await {
+        input.push(vec![batch]);
+    }
+    if let Some(m) = stage_metrics.as_mut() {
+        m.record_input(&input);
+    }
+    let start = Instant::now();
+    let output = collect_named(ctx, input, &names).await?;
+    if let Some(m) = stage_metrics.as_mut() {
+        m.execute_ms = start.elapsed().as_millis() as u64;
+        m.record_output(&output);
     }
 
-    let output = collect(ctx, input).await?;
-    invoke_next_functions(ctx, query_number, uuid, metadata, shuffle_id, output).await
+    // The data sink reports the window of the source to the completion
+    // tracker, so the uuid of the window is kept for it.
+    let next_uuid = match &ctx.next {
+        CloudFunction::Lambda(name) if !is_completion(&metadata) => UuidBuilder::new_with_ts(
+            ctx.query_code.as_deref().unwrap_or(name),
+            Utc::now().timestamp(),
+            1,
+        )
+        .next_uuid(),
+        _ => uuid.clone(),
+    };
+    // The next function is never a function group, so its ring is trivial.
+    let hash_context = ConsistentHashContext::new(&ctx.next);
+    let value = invoke_next_functions(
+        ctx,
+        &hash_context,
+        query_number,
+        next_uuid,
+        metadata,
+        shuffle_id,
+        output,
+    )
+    .await?;
+    report_stage_metrics(&uuid, shuffle_id, stage_metrics).await?;
+    Ok(value)
+}
+
+/// Runs the combine step of the salted keys of the window (see
+/// [`flock::runtime::skew`]). The function of a salt sends its output of the
+/// keys to their home functions, and the home function holds its output until
+/// the outputs of all salts arrive.
+///
+/// # Returns
+/// The output of the window, or `None` if it waits for the outputs of the
+/// salts.
+async fn combine_salted_keys(
+    ctx: &ExecutionContext,
+    window_id: &WindowId,
+    uuid: &Uuid,
+    metadata: &Option<QueryMetadata>,
+    mut output: Vec<Vec<RecordBatch>>,
+) -> Result<Option<Vec<Vec<RecordBatch>>>> {
+    let keys = SALT_STATE.lock().unwrap().take_marks(window_id);
+    if keys.is_empty() {
+        return Ok(Some(output));
+    }
+
+    // The shuffle id starts from 1.
+    let partition = window_id.1 - 1;
+    let (homed, salts): (Vec<_>, Vec<_>) = keys.into_iter().partition(|k| k.home() == partition);
+
+    let mut homes = BTreeMap::<usize, Vec<SaltedKey>>::new();
+    salts
+        .into_iter()
+        .for_each(|k| homes.entry(k.home()).or_default().push(k));
+    let sync = infer_invocation_type(metadata)?;
+    let invocation_type = if sync {
+        FLOCK_LAMBDA_SYNC_CALL.to_string()
+    } else {
+        FLOCK_LAMBDA_ASYNC_CALL.to_string()
+    };
+    for (home, keys) in homes {
+        // The home function expects an output from every salt, even if it's empty.
+        let (rest, rows) = split_salted(output, &keys)?;
+        output = rest;
+        let uuid = Uuid {
+            qid:     uuid.qid.clone(),
+            seq_num: partition + 1,
+            seq_len: uuid.seq_len,
+        };
+        let mut payload = to_payload(&rows, &[], uuid, sync)?;
+        let mut meta = metadata.clone().unwrap_or_default();
+        meta.insert(SALT_COMBINE_METADATA_KEY.to_string(), partition.to_string());
+        payload.metadata = Some(meta);
+        payload.set_shuffle_id(home + 1);
+
+        let home_function = &keys[0].home_function;
+        info!(
+            "[OK] Send the output of {} salted keys to the home function: {}.",
+            keys.len(),
+            home_function
+        );
+        ctx.cloud_client
+            .invoke(
+                home_function,
+                &invocation_type,
+                serde_json::to_vec(&payload)?,
+            )
+            .await?;
+    }
+
+    if homed.is_empty() {
+        return Ok(Some(output));
+    }
+    SALT_STATE.lock().unwrap().wait(window_id, homed, output);
+    finalize_salted_window(ctx, window_id).await
+}
+
+/// Combines the outputs of the salts with the output of the home function once
+/// all of them have arrived.
+///
+/// # Returns
+/// The output of the window, or `None` if it waits for the outputs of the
+/// salts.
+async fn finalize_salted_window(
+    ctx: &ExecutionContext,
+    window_id: &WindowId,
+) -> Result<Option<Vec<Vec<RecordBatch>>>> {
+    let ready = SALT_STATE.lock().unwrap().take_ready(window_id);
+    let (keys, output, received) = match ready {
+        Some(ready) => ready,
+        None => return Ok(None),
+    };
+
+    let (mut output, mut rows) = split_salted(output, &keys)?;
+    rows.extend(received);
+    let plan = ctx.plan().await?[0].clone();
+    let combined = combine_salts(rows, &keys[0].columns, &combiners(&plan)).await?;
+    info!(
+        "[OK] Combined the salts of {} keys into {} rows.",
+        keys.len(),
+        combined.iter().map(|b| b.num_rows()).sum::<usize>()
+    );
+    match output.first_mut() {
+        Some(partition) => partition.extend(combined),
+        None => output.push(combined),
+    }
+    Ok(Some(output))
+}
+
+/// Reports the stage metrics of the current invocation in the analyze mode.
+async fn report_stage_metrics(
+    uuid: &Uuid,
+    shuffle_id: Option<usize>,
+    metrics: Option<StageMetrics>,
+) -> Result<()> {
+    if let Some(metrics) = metrics {
+        // qid: <query code>-<timestamp>-<random string>
+        let query_code = uuid.qid.split('-').next().unwrap_or_default();
+        let invocation_id = format!(
+            "{}-{:02}-{:02}",
+            uuid.qid,
+            shuffle_id.unwrap_or_default(),
+            uuid.seq_num
+        );
+        metrics.report(query_code, &invocation_id).await?;
+    }
+    Ok(())
 }
 
 /// Get the S3 key's prefix for the current query stage
-fn s3_key_prefix(ctx: &ExecutionContext, event: &Payload) -> String {
-    // function name format: <query code>-<plan index>-<group index>
-    let mut name_parts = ctx.name.split('-');
-    name_parts.next(); // skip the query code
-    let plan_index = name_parts.next().unwrap();
-    format!("{:02}/{:02}", plan_index, event.get_shuffle_id())
+fn s3_key_prefix(ctx: &ExecutionContext, event: &Payload) -> Result<String> {
+    let plan_index = FunctionName::parse(&ctx.name)?.plan_index;
+    Ok(state_key_prefix(plan_index, event.get_window_id().1))
 }
 
 /// Prepare the data sources to the executor in the current function.
@@ -141,16 +728,43 @@ fn s3_key_prefix(ctx: &ExecutionContext, event: &Payload) -> String {
 /// # Returns
 /// The input data for the executor in the current function.
 async fn prepare_data_sources(
-    ctx: &mut ExecutionContext,
+    ctx: &ExecutionContext,
     arena: &mut Arena,
     event: Payload,
 ) -> Result<(Vec<Vec<Vec<RecordBatch>>>, HashAggregateStatus)> {
+    check_topology(ctx, &event)?;
     let uuid = event.uuid.clone();
     let metadata = event.metadata.clone();
-    let s3_key_prefix = s3_key_prefix(ctx, &event);
+    let s3_key_prefix = s3_key_prefix(ctx, &event)?;
     let window_id = event.get_window_id();
+    // The build side of the window is read once the window is ready.
+    if let Some(pointer) = &event.broadcast {
+        BUILD_SIDE_CACHE.remember(&window_id, pointer);
+    }
+
+    // The done markers are only checked for the aggregate stages, because
+    // reprocessing a window is harmless for the other stages.
+    let state_bucket = ctx.state_bucket(&uuid.qid);
+    let marker = ctx.is_aggregate().then(|| {
+        DoneMarker::new(
+            ctx.state_backend.as_ref(),
+            &state_bucket,
+            &ctx.name,
+            &window_id,
+        )
+    });
 
-    if PROCESSED_WINDOWS.lock().unwrap().contains(&window_id) {
+    // A window open in the arena hasn't been processed, since it's taken out
+    // of the arena once processed. So the done marker is only read for the
+    // first payload of the window that reaches this instance.
+    let fresh = arena.get(&window_id).is_none();
+    if ProcessedWindows::is_processed(
+        &PROCESSED_WINDOWS,
+        &window_id,
+        marker.as_ref().filter(|_| fresh),
+    )
+    .await?
+    {
         return Ok((vec![], HashAggregateStatus::Processed));
     }
 
@@ -162,11 +776,15 @@ async fn prepare_data_sources(
     // Read payload from S3 is a baseline for our system.
     if let Some((bucket, key)) = infer_s3_mode(&metadata) {
         info!("Reading payload from S3...");
-        let payload = read_payload_from_s3(bucket, key).await?;
+        let payload = read_payload_from_s3(ctx.cloud_client.as_ref(), bucket, key).await?;
         info!("[OK] Received payload from S3.");
 
         info!("Parsing payload to input partitions...");
-        let (r1, r2) = payload.to_record_batch();
+        report_input_stats(payload.stats.as_ref());
+        if let Some(pointer) = &payload.broadcast {
+            BUILD_SIDE_CACHE.remember(&window_id, pointer);
+        }
+        let (r1, r2) = payload.to_record_batch()?;
         info!("[OK] Parsed payload.");
 
         input.push(vec![r1]);
@@ -174,15 +792,21 @@ async fn prepare_data_sources(
         status = HashAggregateStatus::Ready;
     } else if ctx.is_aggregate() {
         // aggregate incoming data to its specific destination
-        status = arena.collect(event);
+        status = arena.collect(event)?;
+        metrics::scope().add(Metric::ArenaBytes, arena.total_bytes() as f64);
+        metrics::scope().add(Metric::ArenaWindows, arena.len() as f64);
+        if status == HashAggregateStatus::NotReady {
+            spill_arena(ctx, arena, function_spill_threshold()).await;
+        }
         if status == HashAggregateStatus::Ready {
             info!("Received all data packets for the window: {:?}", window_id);
-            arena
-                .take(&window_id)
+            report_input_stats(arena.get(&window_id).and_then(|w| w.r1_stats.as_ref()));
+            take_window(ctx, arena, &window_id)
                 .await?
                 .into_iter()
                 .for_each(|b| input.push(b));
-            PROCESSED_WINDOWS.lock().unwrap().insert(window_id);
+            mark_processed(window_id.clone(), marker.as_ref()).await;
+            compact_window(ctx, &state_bucket, &s3_key_prefix, &window_id, &input).await;
         } else if status == HashAggregateStatus::NotReady {
             // Aggregation has not yet been completed. We can also check the query states in
             // the corresponding S3 buckets. If some states exist in S3, Flock can bring the
@@ -191,42 +815,64 @@ async fn prepare_data_sources(
             // the former stage of the dataflow pipeline. Since aggregator's ancestors are
             // default Lambda functions with much higher concurrency, all of them can write
             // the partial aggregation states to the S3 buckets in parallel.
-            if let Some(bitmap) = arena.get_bitmap(&window_id) {
-                if ctx
-                    .state_backend
-                    .as_any()
-                    .downcast_ref::<S3StateBackend>()
-                    .is_some()
-                {
-                    let state_backend: &S3StateBackend = ctx
-                        .state_backend
-                        .as_any()
-                        .downcast_ref::<S3StateBackend>()
-                        .unwrap();
-                    let keys = state_backend
-                        .new_s3_keys(&uuid.qid, &s3_key_prefix, bitmap)
-                        .await?;
+            if let Some(state_backend) = ctx.state_backend.as_any().downcast_ref::<S3StateBackend>()
+            {
+                let bucket = state_bucket.clone();
+                let keys = state_backend.list_keys(&bucket, &s3_key_prefix).await?;
+                // The compacted object has the whole window, but it's only read if this
+                // instance had no state of the window, i.e. a restarted instance picking up
+                // a window reported complete. Otherwise the arena already holds part of the
+                // window and only the new data partitions are read.
+                let compacted = if fresh {
+                    state_backend
+                        .read_compacted(&bucket, &s3_key_prefix, &window_id, &keys)
+                        .await?
+                } else {
+                    None
+                };
+                if let Some(compacted) = compacted {
+                    info!(
+                        "Recovered the window from the compacted state: {:?}",
+                        window_id
+                    );
+                    arena.remove(&window_id);
+                    input.extend(compacted);
+                    status = HashAggregateStatus::Ready;
+                    mark_processed(window_id.clone(), marker.as_ref()).await;
+                } else if let Some(bitmap) = arena.get_bitmap(&window_id) {
+                    let keys = state_backend.partition_keys(keys, bitmap);
 
                     if !keys.is_empty() {
-                        // TODO: optimize the performance of this part.
-                        // Because the S3 key include a negative sequence number, we don't need
-                        // to read its object from S3.
-                        state_backend
-                            .read(uuid.qid.clone(), keys)
-                            .await?
-                            .into_iter()
-                            .for_each(|payload| {
-                                arena.collect(payload);
-                            });
+                        // The reads are bounded, so that the members of a function group
+                        // recovering at once don't get throttled by S3, and the partitions
+                        // left over are read by a later invocation of the window.
+                        let report = recover_partitions(
+                            state_backend,
+                            &bucket,
+                            keys,
+                            &RecoveryBudget::default(),
+                            |payload| {
+                                arena.collect(payload)?;
+                                Ok(arena.is_complete(&window_id))
+                            },
+                        )
+                        .await?;
+                        if report.remaining > 0 {
+                            info!(
+                                "Recovered {} data partitions of the window {:?}, {} left to a \
+                                 later invocation.",
+                                report.read, window_id, report.remaining
+                            );
+                        }
                         if arena.is_complete(&window_id) {
                             info!("Received all data packets for the window: {:?}", window_id);
-                            arena
-                                .take(&window_id)
+                            take_window(ctx, arena, &window_id)
                                 .await?
                                 .into_iter()
                                 .for_each(|b| input.push(b));
                             status = HashAggregateStatus::Ready;
-                            PROCESSED_WINDOWS.lock().unwrap().insert(window_id);
+                            mark_processed(window_id.clone(), marker.as_ref()).await;
+                            compact_window(ctx, &bucket, &s3_key_prefix, &window_id, &input).await;
                         }
                     }
                 }
@@ -234,15 +880,26 @@ async fn prepare_data_sources(
         }
     } else {
         // data packet is an individual event for the current function.
-        let (r1, r2) = event.to_record_batch();
+        report_input_stats(event.stats.as_ref());
+        let (r1, r2) = event.to_record_batch()?;
         input.push(vec![r1]);
         input.push(vec![r2]);
         status = HashAggregateStatus::Ready;
     }
 
     if status == HashAggregateStatus::Ready {
+        // The build side broadcast through S3 replaces the second relation.
+        if let Some(pointer) = BUILD_SIDE_CACHE.take_pointer(&window_id) {
+            let build_side = BUILD_SIDE_CACHE
+                .get_or_read(ctx.cloud_client.as_ref(), &pointer)
+                .await?;
+            match input.get_mut(1) {
+                Some(r2) => *r2 = vec![build_side],
+                None => input.push(vec![build_side]),
+            }
+        }
         // If the data sources are ready, then we can read the side inputs from S3.
-        if let Ok(batch) = infer_side_input(&metadata).await {
+        if let Ok(batch) = infer_side_input(ctx.cloud_client.as_ref(), &metadata).await {
             input.push(vec![batch]);
         }
     }
@@ -250,6 +907,419 @@ async fn prepare_data_sources(
     Ok((input, status))
 }
 
+/// Rejects the payload if it's from another stage than the upstream stages of
+/// the function, or of another schema than its inputs (see
+/// [`flock::runtime::topology`]).
+fn check_topology(ctx: &ExecutionContext, event: &Payload) -> Result<()> {
+    match &ctx.topology {
+        Some(topology) => topology.check(&ctx.name, event),
+        None => Ok(()),
+    }
+}
+
+/// Returns the size in bytes of the arena above which the windows are spilled
+/// (see `arena_spill_fraction`), or `None` outside AWS Lambda.
+fn function_spill_threshold() -> Option<usize> {
+    let memory = std::env::var("AWS_LAMBDA_FUNCTION_MEMORY_SIZE")
+        .ok()?
+        .parse::<usize>()
+        .ok()?;
+    spill_threshold(memory, *FLOCK_ARENA_SPILL_FRACTION)
+}
+
+/// Spills the largest incomplete windows to the state backend until the arena
+/// holds less than the threshold. A failed spill leaves the window in memory.
+async fn spill_arena(ctx: &ExecutionContext, arena: &mut Arena, threshold: Option<usize>) {
+    let threshold = match threshold {
+        Some(threshold) => threshold,
+        None => return,
+    };
+    if ctx.state_backend.as_any().is::<HashMapStateBackend>() {
+        if arena.spill_candidate(threshold).is_some() {
+            warn!(
+                "The arena holds {} bytes, but the windows can't be spilled to {}.",
+                arena.total_bytes(),
+                ctx.state_backend.name()
+            );
+        }
+        return;
+    }
+    while let Some(window_id) = arena.spill_candidate(threshold) {
+        let bucket = ctx.state_bucket(&window_id.0);
+        match arena
+            .spill(&window_id, ctx.state_backend.as_ref(), &bucket)
+            .await
+        {
+            Ok(bytes) => {
+                metrics::scope().incr(Metric::ArenaSpills);
+                info!("Spilled {} bytes of the window {:?}.", bytes, window_id);
+            }
+            Err(e) => {
+                warn!("Failed to spill the window {:?}: {:?}", window_id, e);
+                break;
+            }
+        }
+    }
+}
+
+/// Takes the window from the arena. The data fragments of the window spilled
+/// to the state backend are read back first.
+async fn take_window(
+    ctx: &ExecutionContext,
+    arena: &mut Arena,
+    window_id: &WindowId,
+) -> Result<Vec<Vec<Vec<RecordBatch>>>> {
+    let bucket = ctx.state_bucket(&window_id.0);
+    arena
+        .restore(window_id, ctx.state_backend.as_ref(), &bucket)
+        .await?;
+    arena.take(window_id).await
+}
+
+/// Reports the estimated number of groups of the input if the payloads carry
+/// the statistics (see [`flock::runtime::stats`]).
+fn report_input_stats(stats: Option<&PayloadStats>) {
+    if let Some((stats, groups)) = stats.and_then(|s| s.estimated_groups().map(|g| (s, g))) {
+        metrics::scope().add(Metric::EstimatedGroups, groups as f64);
+        info!(
+            "[INFO] The input has {} rows and about {} groups (distributed: {}).",
+            stats.num_rows,
+            groups,
+            stats.exceeds_group_threshold(*FLOCK_GROUP_THRESHOLD)
+        );
+    }
+}
+
+/// Collects a pane of the hopping window for the queries evaluated
+/// incrementally (see `ExecutionContext::argmax_key`).
+///
+/// The payloads of the panes counted by the previous windows are not decoded
+/// again. Once the window is complete, its output is emitted from the window
+/// state directly, unless the state can't answer the window (e.g. the function
+/// instance is recycled), in which case the whole window is recomputed.
+///
+/// # Arguments
+/// * `ctx` - The runtime context of the current function.
+/// * `arena` - The global memory arena for the function across invocations.
+/// * `event` - The payload of the current function invocation.
+/// * `key` - The group-by column of the query.
+/// * `pane` - The pane of the payload.
+/// * `window` - The panes of the window.
+///
+/// # Returns
+/// The output of the window if it's evaluated incrementally, the input data
+/// for the executor if it falls back to the full recomputation, and the status
+/// of the window.
+async fn collect_pane(
+    ctx: &ExecutionContext,
+    arena: &mut Arena,
+    event: Payload,
+    key: &str,
+    pane: usize,
+    window: Range<usize>,
+) -> Result<(
+    Option<Vec<Vec<RecordBatch>>>,
+    Vec<Vec<Vec<RecordBatch>>>,
+    HashAggregateStatus,
+)> {
+    let qid = event.uuid.qid.clone();
+    let window_id = event.get_window_id();
+
+    if ProcessedWindows::is_processed(&PROCESSED_WINDOWS, &window_id, None).await? {
+        return Ok((None, vec![], HashAggregateStatus::Processed));
+    }
+
+    let sealed = WINDOW_STATE.lock().unwrap().is_sealed(pane);
+    let batches = if sealed {
+        None
+    } else {
+        Some(event.clone().to_record_batch()?.0)
+    };
+
+    // The arena keeps the encoded payloads in case of the full recomputation.
+    let status = arena.collect(event)?;
+    if status == HashAggregateStatus::Processed {
+        return Ok((None, vec![], status));
+    }
+    match batches {
+        Some(batches) => WINDOW_STATE
+            .lock()
+            .unwrap()
+            .accumulate(&qid, pane, key, &batches)?,
+        None => WINDOW_STATE.lock().unwrap().reuse(&qid, pane),
+    }
+    if status == HashAggregateStatus::NotReady {
+        return Ok((None, vec![], status));
+    }
+
+    info!("Received all data packets for the window: {:?}", window_id);
+    ProcessedWindows::mark_processed(&PROCESSED_WINDOWS, window_id.clone(), None).await?;
+    metrics::scope().incr(Metric::WindowsCompleted);
+    let schema = ctx.plan().await?[0].schema();
+    let output = WINDOW_STATE
+        .lock()
+        .unwrap()
+        .seal(&qid, window.clone(), schema)?;
+    match output {
+        Some(batch) => {
+            arena.remove(&window_id);
+            Ok((Some(vec![vec![batch]]), vec![], status))
+        }
+        None => {
+            info!("Recomputing the window {:?} from scratch.", window);
+            Ok((None, take_window(ctx, arena, &window_id).await?, status))
+        }
+    }
+}
+
+/// Returns the external sort of the window of the payload if the stage is a
+/// limited sort, and the window is estimated to outgrow the memory budget of
+/// the external sort (see [`flock::runtime::external_sort`]). The windows
+/// opened in the arena stay there.
+async fn external_sorter(
+    ctx: &ExecutionContext,
+    arena: &Arena,
+    event: &Payload,
+) -> Result<Option<ExternalSorter>> {
+    if !*FLOCK_EXTERNAL_SORT || !ctx.is_aggregate() || infer_s3_mode(&event.metadata).is_some() {
+        return Ok(None);
+    }
+    let window_id = event.get_window_id();
+    if let Some(sorter) = SORTED_RUNS.lock().unwrap().remove(&window_id) {
+        return Ok(Some(sorter));
+    }
+    if arena.contains_key(&window_id) || flush_len(&event.metadata).is_some() {
+        return Ok(None);
+    }
+
+    let properties = ctx.plan_properties().await?;
+    let spec = match ExternalSortSpec::from_plans(&ctx.plan().await?, &properties) {
+        Some(spec) => spec,
+        None => return Ok(None),
+    };
+    let (batches, _) = event.clone().to_record_batch()?;
+    if !ExternalSortSpec::exceeds_budget(&batches, event.uuid.seq_len, *FLOCK_EXTERNAL_SORT_BUDGET)
+    {
+        return Ok(None);
+    }
+    info!("Sorting the window {:?} externally.", window_id);
+    Ok(Some(ExternalSorter::from_conf(spec)))
+}
+
+/// Collects a payload of the window sorted externally.
+///
+/// The rows of the payload are sorted into a run on arrival, and the arena
+/// only keeps track of the payloads received, so that the duplicates and the
+/// flushes of the window are handled as usual. Once the window is complete,
+/// the output of the stage is merged from the sorted runs.
+///
+/// # Returns
+/// The output of the window once it's complete, no input for the executor,
+/// and the status of the window.
+async fn collect_sorted(
+    ctx: &ExecutionContext,
+    arena: &mut Arena,
+    mut event: Payload,
+    mut sorter: ExternalSorter,
+) -> Result<(
+    Option<Vec<Vec<RecordBatch>>>,
+    Vec<Vec<Vec<RecordBatch>>>,
+    HashAggregateStatus,
+)> {
+    check_topology(ctx, &event)?;
+    let window_id = event.get_window_id();
+    let state_bucket = ctx.state_bucket(&window_id.0);
+    let marker = DoneMarker::new(
+        ctx.state_backend.as_ref(),
+        &state_bucket,
+        &ctx.name,
+        &window_id,
+    );
+    // The done marker is only read for the windows that aren't open yet (see
+    // `prepare_data_sources`).
+    let fresh = arena.get(&window_id).is_none();
+    if ProcessedWindows::is_processed(
+        &PROCESSED_WINDOWS,
+        &window_id,
+        Some(&marker).filter(|_| fresh),
+    )
+    .await?
+    {
+        return Ok((None, vec![], HashAggregateStatus::Processed));
+    }
+
+    let duplicate = arena
+        .get_bitmap(&window_id)
+        .map_or(false, |bitmap| bitmap.is_set(event.uuid.seq_num));
+    if flush_len(&event.metadata).is_none() && !duplicate {
+        let (batches, _) = event.clone().to_record_batch()?;
+        sorter.push(batches).await?;
+        event.data = vec![];
+        event.data2 = vec![];
+        event.sealed = None;
+    }
+
+    let status = arena.collect(event)?;
+    if status != HashAggregateStatus::Ready {
+        SORTED_RUNS.lock().unwrap().insert(window_id, sorter);
+        return Ok((None, vec![], status));
+    }
+
+    info!("Received all data packets for the window: {:?}", window_id);
+    arena.remove(&window_id);
+    mark_processed(window_id.clone(), Some(&marker)).await;
+    let start = Instant::now();
+    let output = sorter.finish()?;
+    metrics::scope().add(Metric::ExecuteDuration, start.elapsed().as_millis() as f64);
+    Ok((Some(vec![output]), vec![], status))
+}
+
+/// Collects the rows of the session windows (NEXMark Q11), and emits the
+/// sessions closed by the stage-wide watermark.
+///
+/// Each upstream function sends the rows of an epoch in a window of payloads,
+/// which carry the same watermark. Once all payloads of the window have been
+/// received, the rows are merged into the open sessions, and the watermark of
+/// the upstream is advanced. The closed sessions are executed one by one, so
+/// that the sessions of the same key are not grouped together.
+///
+/// # Arguments
+/// * `ctx` - The runtime context of the current function.
+/// * `arena` - The global memory arena for the function across invocations.
+/// * `event` - The payload of the current function invocation.
+/// * `session` - The session window settings of the payload.
+/// * `watermark` - The watermark of the upstream function.
+///
+/// # Returns
+/// The output of the closed sessions, and the status of the window.
+async fn collect_session(
+    ctx: &ExecutionContext,
+    arena: &mut Arena,
+    event: Payload,
+    session: SessionMetadata,
+    watermark: i64,
+) -> Result<(
+    Option<Vec<Vec<RecordBatch>>>,
+    Vec<Vec<Vec<RecordBatch>>>,
+    HashAggregateStatus,
+)> {
+    let window_id = event.get_window_id();
+
+    if ProcessedWindows::is_processed(&PROCESSED_WINDOWS, &window_id, None).await? {
+        return Ok((None, vec![], HashAggregateStatus::Processed));
+    }
+
+    let status = arena.collect(event)?;
+    if status != HashAggregateStatus::Ready {
+        return Ok((None, vec![], status));
+    }
+
+    info!("Received all data packets for the window: {:?}", window_id);
+    ProcessedWindows::mark_processed(&PROCESSED_WINDOWS, window_id.clone(), None).await?;
+    let batches = take_window(ctx, arena, &window_id)
+        .await?
+        .into_iter()
+        .next()
+        .unwrap_or_default()
+        .into_iter()
+        .flatten()
+        .collect::<Vec<RecordBatch>>();
+
+    let sessions = {
+        let mut state = SESSION_STATE.lock().unwrap();
+        state.accumulate(&session.key, &session.time, session.gap, &batches)?;
+        state.advance_watermark(&session.upstream, watermark);
+        match state.watermark(session.upstreams) {
+            Some(watermark) => state.emit(watermark, session.gap),
+            None => vec![],
+        }
+    };
+
+    if sessions.is_empty() {
+        info!("No session window is closed by the watermark.");
+        return Ok((None, vec![], HashAggregateStatus::NotReady));
+    }
+
+    info!(
+        "{} session windows are closed by the watermark.",
+        sessions.len()
+    );
+    metrics::scope().add(Metric::WindowsCompleted, sessions.len() as f64);
+    let mut output = vec![];
+    for (_, session) in sessions {
+        output.extend(
+            collect(ctx, vec![vec![session.batches]])
+                .await?
+                .into_iter()
+                .flatten(),
+        );
+    }
+    Ok((Some(vec![output]), vec![], HashAggregateStatus::Ready))
+}
+
+/// Writes the window assembled by the arena to the S3 state backend as a single
+/// compacted object, so that the recovery of the window doesn't have to read
+/// all data partitions again. A failure doesn't fail the invocation, because
+/// the recovery falls back to the data partitions.
+async fn compact_window(
+    ctx: &ExecutionContext,
+    bucket: &str,
+    prefix: &str,
+    window_id: &WindowId,
+    input: &[Vec<Vec<RecordBatch>>],
+) {
+    if let Some(state_backend) = ctx.state_backend.as_any().downcast_ref::<S3StateBackend>() {
+        if let Err(e) = state_backend
+            .write_compacted(bucket, prefix, window_id, input)
+            .await
+        {
+            warn!("Failed to write the compacted state: {:?}", e);
+        }
+    }
+}
+
+/// Records that the window has been processed by the current function.
+///
+/// The window has been taken from the arena, so a failure to write the done
+/// marker doesn't fail the invocation; the duplicates of the window are still
+/// suppressed as long as it stays in the processed windows of this instance.
+async fn mark_processed(window_id: WindowId, marker: Option<&DoneMarker<'_>>) {
+    if let Err(e) = ProcessedWindows::mark_processed(&PROCESSED_WINDOWS, window_id, marker).await {
+        warn!("Failed to write the done marker: {:?}", e);
+    }
+    metrics::scope().incr(Metric::WindowsCompleted);
+}
+
+/// Moves the second relation of the payloads of a window to S3 if it's the
+/// build side of a broadcast join, i.e. if the function has the hint or the
+/// relation is at most `broadcast_threshold` bytes (see
+/// [`flock::runtime::broadcast`]).
+///
+/// # Arguments
+/// * `ctx` - The runtime context of the current function.
+/// * `payloads` - The payloads of the same window to the next function.
+pub async fn broadcast_build_side(ctx: &ExecutionContext, payloads: &mut [Payload]) -> Result<()> {
+    let bytes = build_side_bytes(payloads);
+    if !is_small_side(ctx.broadcast, bytes, *FLOCK_BROADCAST_THRESHOLD) {
+        return Ok(());
+    }
+    if broadcast::broadcast_build_side(
+        ctx.cloud_client.as_ref(),
+        &FLOCK_S3_BUCKET,
+        &topology::stage_name(&ctx.name),
+        payloads,
+    )
+    .await?
+    {
+        info!(
+            "[OK] Broadcast the build side of {} bytes for the window: {:?}",
+            bytes,
+            payloads[0].get_window_id()
+        );
+    }
+    Ok(())
+}
+
 /// Invoke the next functions in the dataflow pipeline.
 ///
 /// # Arguments
@@ -262,33 +1332,132 @@ async fn prepare_data_sources(
 /// # Returns
 /// A JSON object that contains the return value of the current function.
 async fn invoke_next_functions(
-    ctx: &mut ExecutionContext,
+    ctx: &ExecutionContext,
+    hash_context: &ConsistentHashContext,
     query_number: Option<usize>,
     uuid: Uuid,
-    metadata: Option<HashMap<String, String>>,
+    metadata: Option<QueryMetadata>,
     shuffle_id: Option<usize>,
     output: Vec<Vec<RecordBatch>>,
 ) -> Result<Value> {
-    let (ring, _) = consistent_hash_context!();
+    let ring = &hash_context.ring;
     let sync = infer_invocation_type(&metadata)?;
     let invocation_type = if sync {
         FLOCK_LAMBDA_SYNC_CALL.to_string()
     } else {
         FLOCK_LAMBDA_ASYNC_CALL.to_string()
     };
-    let schema = schema_to_bytes(ctx.schema(0).await?);
 
-    match &ctx.next {
+    // The output of an expired invocation is not fanned out any further, and
+    // the synchronous invocations of the next stage inherit the reduced
+    // deadline, since this function waits for them.
+    let deadline = deadline::effective(&metadata)?;
+    if let Some(deadline) = deadline {
+        if let Err(e) = deadline.check(&SystemClock, &ctx.name) {
+            metrics::scope().incr(Metric::Timeouts);
+            return Err(e);
+        }
+    }
+    let metadata = deadline::downstream_metadata(metadata, deadline, sync);
+    // The next stage is a child of this invocation in the trace of the window.
+    let mut metadata = trace::downstream_metadata(metadata);
+    topology::stamp_sender(&mut metadata, &ctx.name);
+    let schema = schema_to_bytes(ctx.schema(0).await?);
+    peek_output(ctx, &uuid, &output).await;
+
+    match &ctx.next {
         CloudFunction::Sink(sink_type) => {
             info!("[Ok] Sinking data to {:?}", sink_type);
-            let output = output.into_iter().flatten().collect::<Vec<_>>();
-            if !output.is_empty() && DataSinkType::Blackhole != *sink_type {
-                DataSink::new(ctx.name.clone(), output, Encoding::default())
-                    .write(sink_type.clone(), DataSinkFormat::SerdeBinary)
-                    .await
+            trace::record("strategy", "sink");
+            let mut output = output.into_iter().flatten().collect::<Vec<_>>();
+            if is_small_batches(std::slice::from_ref(&output)) {
+                // Merge the tiny batches of the upstream payloads before writing them.
+                let schema = output[0].schema();
+                output = concat_small_batches(schema, output, *FLOCK_TARGET_BATCH_SIZE).await?;
+            }
+            if let Some(running) = ctx
+                .running_aggregate
+                .as_ref()
+                .filter(|_| !output.is_empty())
+            {
+                // The window is merged into the running aggregates at most once, even if
+                // the invocation is retried.
+                output = running_aggregate::accumulate(
+                    ctx.state_backend.as_ref(),
+                    &FLOCK_S3_BUCKET,
+                    ctx.query_code(),
+                    &window_id(&uuid, shuffle_id),
+                    running,
+                    &output,
+                )
+                .await?;
+            }
+            if ctx.window_columns {
+                // The plan of the stage is already executed, so the columns
+                // don't change the semantics of the query.
+                output = with_window_columns(
+                    output,
+                    &window_id(&uuid, shuffle_id),
+                    ctx.window.as_ref(),
+                )?;
+            }
+            metrics::scope().add(
+                Metric::SinkRows,
+                output.iter().map(|b| b.num_rows()).sum::<usize>() as f64,
+            );
+            let value = if !output.is_empty() && DataSinkType::Blackhole != *sink_type {
+                let mut sink = DataSink::new(ctx.logical_name(), output, Encoding::default());
+                if sync && DataSinkType::Response == *sink_type {
+                    // Return the results inline to the synchronous caller, or write them
+                    // to S3 if they exceed the response limit of AWS Lambda, or the
+                    // smaller limit of the state output under the Step Functions driver.
+                    let (limit, key) = match sfn_step(&metadata) {
+                        Some(step) => (
+                            SFN_MAX_RESPONSE_SIZE,
+                            spill_key(&ctx.logical_name(), step, &window_id(&uuid, shuffle_id)),
+                        ),
+                        None => (FLOCK_MAX_RESPONSE_SIZE, sink_key(ctx.query_code())),
+                    };
+                    sink.write_to_response(limit, &key, ctx.cloud_client.as_ref())
+                        .await?
+                } else {
+                    // The results of the window are served by the results server too.
+                    // The window is committed before the sink object is written, so the
+                    // sink object is always the output of a committed window.
+                    if let Some(store) = ResultStore::for_sink(sink_type, ctx.cloud_client.clone())
+                    {
+                        store
+                            .put(
+                                ctx.query_code(),
+                                &window_id(&uuid, shuffle_id),
+                                &sink.record_batches,
+                            )
+                            .await?;
+                    }
+                    sink.write(
+                        sink_type.clone(),
+                        DataSinkFormat::SerdeBinary,
+                        ctx.cloud_client.as_ref(),
+                    )
+                    .await?
+                }
             } else {
-                Ok(Value::Null)
+                Value::Null
+            };
+            if is_completion(&metadata) {
+                // The window is recorded after its results are written to the data sink,
+                // so the driver can collect them as soon as all windows are accounted for.
+                // The payloads of the same logical window, e.g. the partitions of a
+                // shuffle, record the same window.
+                let query_code = ctx.query_code();
+                let window = completion_window(&metadata)
+                    .map(|w| w.to_string())
+                    .unwrap_or_else(|| window_id(&uuid, None));
+                if let Err(e) = report_window(query_code, &window).await {
+                    warn!("Failed to report the completed window: {:?}", e);
+                }
             }
+            Ok(value)
         }
         CloudFunction::Lambda(group_name) => {
             if ctx.is_aggregate() {
@@ -296,53 +1465,57 @@ async fn invoke_next_functions(
                 // can be repartitioned to multiple partitions, and each partition
                 // can be executed by a single lambda function for the next stage of the
                 // dataflow pipeline.
-                let output = Arc::new(output);
+                trace::record("strategy", "repartition");
                 let size = output.len();
+                // The qid of the next stage is derived from the window, so the
+                // window is the same if it's emitted again, e.g. by a retry.
                 let mut uuid_builder =
-                    UuidBuilder::new_with_ts(group_name, Utc::now().timestamp(), size);
-                let tasks = (0..size)
-                    .map(|i| {
-                        let data = output.clone();
-                        let function_name = group_name.clone();
-                        let meta = metadata.clone();
-                        let invoke_type = invocation_type.clone();
-                        let uuid = uuid_builder.next_uuid();
-                        let schema_bytes = schema.clone();
-                        tokio::spawn(async move {
-                            let mut payload = to_payload(&data[i], &[], uuid, sync);
-                            payload.query_number = query_number;
-                            payload.metadata = meta;
-                            payload.schema = schema_bytes;
-                            let bytes = serde_json::to_vec(&payload)?;
-
-                            info!(
-                                "[OK] {} function's payload bytes: {}",
-                                function_name,
-                                bytes.len()
-                            );
-                            lambda::invoke_function(
-                                &function_name,
-                                &invoke_type,
-                                Some(bytes.into()),
-                            )
-                            .await
-                            .map(|_| ())
-                        })
-                    })
-                    .collect::<Vec<tokio::task::JoinHandle<Result<()>>>>();
-                futures::future::join_all(tasks).await;
+                    UuidBuilder::for_window(ctx.query_code(), &window_id(&uuid, shuffle_id), size);
+                let uuids = (0..size)
+                    .map(|_| uuid_builder.next_uuid())
+                    .collect::<Vec<_>>();
+                let function_name = group_name.clone();
+                let keys = ctx.stats_keys.clone();
+                let encoding = ctx.encoding.clone();
+                let prepared = prepare_payloads(size, move |i| {
+                    let mut payload = to_stage_payload(
+                        &output[i],
+                        &[],
+                        uuids[i].clone(),
+                        sync,
+                        &keys,
+                        &encoding,
+                    )?;
+                    payload.query_number = query_number;
+                    payload.metadata = metadata.clone();
+                    payload.schema = schema.clone();
+                    Ok((function_name.clone(), payload))
+                })
+                .await?;
+                send_payloads(
+                    ctx.cloud_client.clone(),
+                    ctx.via_queue,
+                    StatePersistence::Never,
+                    0,
+                    &invocation_type,
+                    prepared,
+                )
+                .await;
             } else {
                 // If the current function is not an aggregator, which means its
                 // output CANNOT be repartitioned to multiple partitions,
                 // otherwise the future aggregator CANNOT ganuantee the
                 // correctness of the result. Therefore, we have to reuse the
                 // uuid of the current payload to the next function.
-                let mut payload = to_payload(
+                trace::record("strategy", "forward");
+                let mut payload = to_stage_payload(
                     &output.into_iter().flatten().collect::<Vec<_>>(),
                     &[],
                     uuid,
                     sync,
-                );
+                    &ctx.stats_keys,
+                    &ctx.encoding,
+                )?;
                 payload.schema = schema;
                 payload.query_number = query_number;
                 payload.metadata = metadata;
@@ -353,19 +1526,84 @@ async fn invoke_next_functions(
                     group_name,
                     bytes.len()
                 );
-                lambda::invoke_function(group_name, &invocation_type, Some(bytes.into())).await?;
+                let response = transport::deliver(
+                    ctx.cloud_client.as_ref(),
+                    ctx.via_queue,
+                    group_name,
+                    &invocation_type,
+                    bytes,
+                )
+                .await?;
+                if sync {
+                    // Pass the inline results of the downstream function back to the
+                    // synchronous caller.
+                    let inline = response.as_deref().map(inline_response).transpose()?;
+                    if let Some(value) = inline.flatten() {
+                        return Ok(value);
+                    }
+                }
             }
             Ok(Value::Null)
         }
         CloudFunction::Group(..) => {
-            if !ctx.is_shuffling().await? {
+            observe_window_volume(ctx, hash_context, &uuid, &output).await;
+            if let Some(affinity) = ctx.session_affinity.clone() {
+                // The rows are routed by the buckets of their session keys, so that the
+                // events of a session land on the same member of the group whichever
+                // upstream function they come from. Every member gets the fragment of the
+                // window, even if it's empty, so that the window completes everywhere.
+                trace::record("strategy", "session");
+                let mut routed = hash_context
+                    .members()
+                    .into_iter()
+                    .map(|member| (member, vec![]))
+                    .collect::<BTreeMap<String, Vec<RecordBatch>>>();
+                for (bucket, batches) in affinity.partition(output)?.into_iter().enumerate() {
+                    if batches.is_empty() {
+                        continue;
+                    }
+                    routed
+                        .entry(hash_context.session_member(bucket))
+                        .or_default()
+                        .extend(batches);
+                }
+                let routed = routed.into_iter().collect::<Vec<_>>();
+
+                let plan_index = FunctionName::parse(&ctx.name)?.plan_index;
+                let keys = ctx.stats_keys.clone();
+                let encoding = ctx.encoding.clone();
+                let prepared = prepare_payloads(routed.len(), move |i| {
+                    let (member, batches) = &routed[i];
+                    let mut payload =
+                        to_stage_payload(batches, &[], uuid.clone(), sync, &keys, &encoding)?;
+                    payload.query_number = query_number;
+                    payload.metadata = metadata.clone();
+                    payload.schema = schema.clone();
+                    Ok((member.clone(), payload))
+                })
+                .await?;
+                send_payloads(
+                    ctx.cloud_client.clone(),
+                    ctx.via_queue,
+                    state_persistence(ctx),
+                    plan_index,
+                    &invocation_type,
+                    prepared,
+                )
+                .await;
+
+                Ok(Value::Null)
+            } else if !ctx.is_shuffling().await? {
+                trace::record("strategy", "forward");
                 let next_function = ring.get(&uuid.qid).expect("hash ring failure.").to_string();
-                let mut payload = to_payload(
+                let mut payload = to_stage_payload(
                     &output.into_iter().flatten().collect::<Vec<_>>(),
                     &[],
                     uuid,
                     sync,
-                );
+                    &ctx.stats_keys,
+                    &ctx.encoding,
+                )?;
                 payload.schema = schema;
                 payload.query_number = query_number;
                 payload.metadata = metadata;
@@ -377,167 +1615,131 @@ async fn invoke_next_functions(
                     bytes.len()
                 );
 
-                let state_backend = ctx.state_backend.clone();
-                let mut tasks: Vec<tokio::task::JoinHandle<Result<()>>> = vec![];
-
-                if state_backend
-                    .as_any()
-                    .downcast_ref::<S3StateBackend>()
-                    .is_some()
-                {
-                    let bytes_copy = bytes.clone();
-                    let current_function = ctx.name.clone();
-                    tasks.push(tokio::spawn(async move {
-                        // function name format: <query code>-<plan index>-<group index>
-                        let plan_index = current_function.split('-').collect::<Vec<_>>()[1]
-                            .parse::<usize>()
-                            .expect("parse the plan index error.");
-                        let next_plan_index = plan_index + 1;
-                        let shuffle_id = 1; // since the current function is not shuffling
-                        let seq_num = if payload.is_empty_data() {
-                            -(payload.get_seq_num() as i32)
-                        } else {
-                            payload.get_seq_num() as i32
-                        };
-                        let key =
-                            format!("{:02}/{:02}/{:02}", next_plan_index, shuffle_id, seq_num);
-                        let bucket = payload.get_query_id();
-
-                        // S3 state backend:
-                        // - bucket equals to qid: <query code>-<timestamp>-<random string>
-                        // - key: <plan index>-<shuffle id>-<sequence id>
-                        state_backend
-                            .write(bucket, key, bytes_copy)
-                            .await
-                            .map(|_| ())
-                    }));
-                }
-
-                tasks.push(tokio::spawn(async move {
-                    lambda::invoke_function(&next_function, &invocation_type, Some(bytes.into()))
-                        .await
-                        .map(|_| ())
-                }));
-
-                futures::future::join_all(tasks).await;
+                let plan_index = FunctionName::parse(&ctx.name)?.plan_index;
+                let _ = send_payload(
+                    ctx.cloud_client.clone(),
+                    ctx.via_queue,
+                    state_persistence(ctx),
+                    plan_index,
+                    next_function,
+                    invocation_type,
+                    payload,
+                    bytes,
+                )
+                .await;
 
                 Ok(Value::Null)
             } else {
-                let output = Arc::new(output);
+                trace::record("strategy", "shuffle");
                 let mut rng = StdRng::seed_from_u64(0xDEAD); // Predictable RNG clutch
                 let mut arr = [0u8; 64];
                 rng.fill(&mut arr);
                 let func_idx = ring.get_index(&arr).expect("hash ring failure.");
-                let tasks = (0..output.len())
-                    .map(|i| {
-                        let my_output = output.clone();
-                        let my_metadata = metadata.clone();
-                        let state_backend = ctx.state_backend.clone();
-                        let current_function = ctx.name.clone();
-                        let invoke_type = invocation_type.clone();
-                        let schema_bytes = schema.clone();
-                        let mut my_uuid = uuid.clone();
-                        if let Some(new_seq_num) = shuffle_id {
-                            // This is REALLY important and tricky.
-                            // The shuffle id must be assigned to the new payload's sequence number.
-                            // Otherwise, the next function will not be able to distinguish the
-                            // payloads for aggregation.
-                            my_uuid.seq_num = new_seq_num;
-                        }
 
-                        // Partitions at the same index position in different functions can get the
-                        // same hash key. Therefore, they can be forwarded to the same lambda
-                        // function.
-                        //
-                        // Function 0: data[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
-                        // Function 1: data[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
-                        // Function 2: data[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
-                        // ..
-                        // Function n: data[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
-                        //
-                        // F0[0], F1[0], F2[0] .. Fn[0] ---> lambda function x
-                        // F0[1], F1[1], F2[1] .. Fn[1] ---> lambda function y
-                        // F0[2], F1[2], F2[2] .. Fn[2] ---> lambda function z
-                        // ..
-                        // F0[n], F1[n], F2[n] .. Fn[n] ---> lambda function v
-
-                        let next_function = ring
-                            .get_by_index((func_idx + i) % ring.len())
-                            .expect("hash ring failure.")
-                            .to_string();
-
-                        tokio::spawn(async move {
-                            let mut payload = to_payload(&my_output[i], &[], my_uuid, sync);
-                            payload.query_number = query_number;
-                            payload.metadata = my_metadata;
-                            payload.schema = schema_bytes;
-                            // set shuffle id to each data partition since they will be aggregated
-                            // at different functions.
-                            payload.shuffle_id = Some(i + 1); // Starts from 1.
-                            let bytes = serde_json::to_vec(&payload)?;
+                // If the query declares its distribution keys, the rows are placed by the
+                // hash of their keys, so that a key lands on the same member of the group
+                // whatever the number of the output partitions of the upstream functions.
+                let output = if ctx.distribution_keys.is_empty() {
+                    output
+                } else {
+                    distribute(output, &ctx.distribution_keys, ring.len())?
+                };
 
-                            info!(
-                                "[OK] {} function's payload bytes: {}",
-                                next_function,
-                                bytes.len()
-                            );
+                // The rows of the hot keys in the skewed partitions are split across the
+                // salted sub-partitions, and the payloads are marked with the salted keys.
+                let columns = match output.iter().flatten().next() {
+                    Some(batch) => ctx
+                        .stats_keys
+                        .iter()
+                        .filter(|k| batch.schema().index_of(k).is_ok())
+                        .cloned()
+                        .collect::<Vec<_>>(),
+                    None => vec![],
+                };
+                let (output, mut salted) =
+                    salt_partitions(output, &columns, *FLOCK_SKEW_THRESHOLD, *FLOCK_SKEW_SALTS)?;
+                salted.iter_mut().for_each(|k| {
+                    k.home_function = ring
+                        .get_by_index((func_idx + k.home()) % ring.len())
+                        .expect("hash ring failure.")
+                        .to_string();
+                    info!(
+                        "[INFO] Salted the hot key {:?} of partition {} by a factor of {}: {:?}",
+                        k.key,
+                        k.home(),
+                        k.salts(),
+                        k.partitions
+                    );
+                });
+                if !salted.is_empty() {
+                    metrics::scope().add(Metric::SaltedKeys, salted.len() as f64);
+                }
 
-                            let mut tasks: Vec<tokio::task::JoinHandle<Result<()>>> = vec![];
-
-                            if state_backend
-                                .as_any()
-                                .downcast_ref::<S3StateBackend>()
-                                .is_some()
-                            {
-                                let bytes_copy = bytes.clone();
-                                tasks.push(tokio::spawn(async move {
-                                    // function name format: <query code>-<plan index>-<group
-                                    // index>
-                                    let plan_index =
-                                        current_function.split('-').collect::<Vec<_>>()[1]
-                                            .parse::<usize>()
-                                            .expect("parse the plan index error.");
-                                    let next_plan_index = plan_index + 1;
-                                    let shuffle_id = i + 1; // since the current function is shuffling
-                                    let seq_num = if payload.is_empty_data() {
-                                        -(payload.get_seq_num() as i32)
-                                    } else {
-                                        payload.get_seq_num() as i32
-                                    };
-                                    let key = format!(
-                                        "{:02}/{:02}/{:02}",
-                                        next_plan_index, shuffle_id, seq_num
-                                    );
-                                    let bucket = payload.get_query_id();
-
-                                    // S3 state backend:
-                                    // - bucket equals to qid: <query code>-<timestamp>-<random
-                                    //   string>
-                                    // - key: <plan index>-<shuffle id>-<sequence id>
-                                    state_backend
-                                        .write(bucket, key, bytes_copy)
-                                        .await
-                                        .map(|_| ())
-                                }));
-                            }
-
-                            tasks.push(tokio::spawn(async move {
-                                lambda::invoke_function(
-                                    &next_function,
-                                    &invoke_type,
-                                    Some(bytes.into()),
-                                )
-                                .await
-                                .map(|_| ())
-                            }));
-
-                            futures::future::join_all(tasks).await;
-
-                            Ok(())
-                        })
+                // Partitions at the same index position in different functions can get the
+                // same hash key. Therefore, they can be forwarded to the same lambda
+                // function.
+                //
+                // Function 0: data[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
+                // Function 1: data[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
+                // Function 2: data[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
+                // ..
+                // Function n: data[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
+                //
+                // F0[0], F1[0], F2[0] .. Fn[0] ---> lambda function x
+                // F0[1], F1[1], F2[1] .. Fn[1] ---> lambda function y
+                // F0[2], F1[2], F2[2] .. Fn[2] ---> lambda function z
+                // ..
+                // F0[n], F1[n], F2[n] .. Fn[n] ---> lambda function v
+                let functions = (0..output.len())
+                    .map(|i| {
+                        ring.get_by_index((func_idx + i) % ring.len())
+                            .expect("hash ring failure.")
+                            .to_string()
                     })
-                    .collect::<Vec<tokio::task::JoinHandle<Result<()>>>>();
-                futures::future::join_all(tasks).await;
+                    .collect::<Vec<_>>();
+                // If the current function aggregates a shuffled partition, its output
+                // is the fragment of the next window at the position of the partition,
+                // so that the next function can distinguish the payloads from different
+                // upstream functions.
+                let my_uuid = match shuffle_id {
+                    Some(seq_num) => Uuid {
+                        qid: uuid.qid.clone(),
+                        seq_num,
+                        seq_len: uuid.seq_len,
+                    },
+                    None => uuid.clone(),
+                };
+
+                let plan_index = FunctionName::parse(&ctx.name)?.plan_index;
+                let keys = ctx.stats_keys.clone();
+                let encoding = ctx.encoding.clone();
+                let prepared = prepare_payloads(output.len(), move |i| {
+                    let mut payload =
+                        to_stage_payload(&output[i], &[], my_uuid.clone(), sync, &keys, &encoding)?;
+                    payload.query_number = query_number;
+                    payload.metadata = metadata.clone();
+                    let my_salted = salted
+                        .iter()
+                        .filter(|k| k.partitions.contains(&i))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    set_salted_keys(&mut payload.metadata, &my_salted)?;
+                    payload.schema = schema.clone();
+                    // set shuffle id to each data partition since they will be aggregated
+                    // at different functions.
+                    payload.set_shuffle_id(i + 1); // Starts from 1.
+                    Ok((functions[i].clone(), payload))
+                })
+                .await?;
+                send_payloads(
+                    ctx.cloud_client.clone(),
+                    ctx.via_queue,
+                    state_persistence(ctx),
+                    plan_index,
+                    &invocation_type,
+                    prepared,
+                )
+                .await;
 
                 Ok(Value::Null)
             }
@@ -545,99 +1747,505 @@ async fn invoke_next_functions(
     }
 }
 
-/// Infer the invocation mode of the function.
-pub fn infer_invocation_type(metadata: &Option<HashMap<String, String>>) -> Result<bool> {
-    let mut sync = true;
-    if let Some(metadata) = metadata {
-        if let Some(invocation_type) = metadata.get("invocation_type") {
-            if invocation_type.parse::<String>().unwrap() == "async" {
-                sync = false;
+/// Returns when the function writes the payloads of the next stage to the
+/// state backend. Only the S3 state backend is written by the sender.
+fn state_persistence(ctx: &ExecutionContext) -> StatePersistence {
+    if ctx
+        .state_backend
+        .as_any()
+        .downcast_ref::<S3StateBackend>()
+        .is_some()
+    {
+        ctx.state_persistence
+    } else {
+        StatePersistence::Never
+    }
+}
+
+/// Writes the payload of the next stage to the S3 state backend, from which
+/// the next function reads the payloads of the window if some of them are lost.
+async fn persist_payload(
+    client: &dyn CloudClient,
+    plan_index: usize,
+    payload: &Payload,
+    bytes: Vec<u8>,
+) -> Result<()> {
+    let next_plan_index = plan_index + 1;
+    let shuffle_id = payload.get_window_id().1;
+    let seq_num = if payload.is_empty_data() {
+        -(payload.get_seq_num() as i32)
+    } else {
+        payload.get_seq_num() as i32
+    };
+    let key = state_key(next_plan_index, shuffle_id, seq_num);
+    let bucket = state_bucket_name(&payload.get_query_id(), &flock_region());
+
+    // S3 state backend:
+    // - bucket equals to qid: <query code>-<timestamp>-<random string> with the
+    //   region suffix if the region is specified
+    // - key: state/<plan index>/<shuffle id>/<sequence id>
+    client
+        .s3_put(&bucket, &key, encryption::seal_bytes(bytes)?)
+        .await
+        .map(|_| metrics::scope().incr(Metric::Spills))
+}
+
+/// A payload of the fan-out to the next stage, serialized before any payload
+/// of the fan-out is sent.
+struct Outgoing {
+    /// The partition of the output that the payload carries.
+    partition: usize,
+    /// The name of the next function.
+    function:  String,
+    /// The payload, which is written to the state backend.
+    payload:   Payload,
+    /// The serialized payload.
+    bytes:     Vec<u8>,
+}
+
+/// Builds and serializes the payloads of the output partitions on the rayon
+/// pool, so that the Arrow serialization doesn't starve the network I/O of the
+/// async executor threads.
+///
+/// # Arguments
+/// * `partitions` - The number of the output partitions.
+/// * `build` - Returns the next function and the payload of a partition.
+///
+/// # Returns
+/// The payload of each partition in the order of the partitions, or the error
+/// of the partition that failed to build.
+async fn prepare_payloads<F>(partitions: usize, build: F) -> Result<Vec<Result<Outgoing>>>
+where
+    F: Fn(usize) -> Result<(String, Payload)> + Send + Sync + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        (0..partitions)
+            .into_par_iter()
+            .map(|partition| {
+                let outgoing = || -> Result<Outgoing> {
+                    let (function, payload) = build(partition)?;
+                    let bytes = serde_json::to_vec(&payload)?;
+                    Ok(Outgoing {
+                        partition,
+                        function,
+                        payload,
+                        bytes,
+                    })
+                };
+                outgoing().map_err(|e| {
+                    FlockError::Execution(format!(
+                        "Failed to build the payload of partition {}: {}",
+                        partition, e
+                    ))
+                })
+            })
+            .collect()
+    })
+    .await
+    .map_err(|e| FlockError::Internal(e.to_string()))
+}
+
+/// Sends the prepared payloads to the next functions, with at most
+/// `FLOCK_FANOUT_CONCURRENCY` calls in flight.
+///
+/// # Returns
+/// The result of each partition in the order of the partitions. The failures
+/// are logged with their partitions and functions.
+async fn send_payloads(
+    client: Arc<dyn CloudClient>,
+    via_queue: bool,
+    persistence: StatePersistence,
+    plan_index: usize,
+    invocation_type: &str,
+    prepared: Vec<Result<Outgoing>>,
+) -> Vec<Result<()>> {
+    let mut results = futures::stream::iter(prepared.into_iter().enumerate())
+        .map(|(i, outgoing)| {
+            let client = client.clone();
+            let invocation_type = invocation_type.to_string();
+            async move {
+                let result = match outgoing {
+                    Ok(outgoing) => {
+                        info!(
+                            "[OK] {} function's payload bytes: {}",
+                            outgoing.function,
+                            outgoing.bytes.len()
+                        );
+                        let (partition, function) = (outgoing.partition, outgoing.function);
+                        send_payload(
+                            client,
+                            via_queue,
+                            persistence,
+                            plan_index,
+                            function.clone(),
+                            invocation_type,
+                            outgoing.payload,
+                            outgoing.bytes,
+                        )
+                        .await
+                        .map_err(|e| {
+                            FlockError::Execution(format!(
+                                "Failed to send partition {} to {}: {}",
+                                partition, function, e
+                            ))
+                        })
+                    }
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = &result {
+                    warn!("{}", e);
+                }
+                (i, result)
             }
+        })
+        .buffer_unordered((*FLOCK_FANOUT_CONCURRENCY).max(1))
+        .collect::<Vec<_>>()
+        .await;
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Sends the payload to the next function (see [`transport::deliver`]), and
+/// writes the payload to the state backend according to the policy of the
+/// next stage (see [`StatePersistence`]).
+async fn send_payload(
+    client: Arc<dyn CloudClient>,
+    via_queue: bool,
+    persistence: StatePersistence,
+    plan_index: usize,
+    next_function: String,
+    invocation_type: String,
+    payload: Payload,
+    bytes: Vec<u8>,
+) -> Result<()> {
+    match persistence {
+        StatePersistence::Always => {
+            let writer = client.clone();
+            let bytes_copy = bytes.clone();
+            let tasks = vec![
+                spawn_in_span(async move {
+                    persist_payload(&*writer, plan_index, &payload, bytes_copy).await
+                }),
+                spawn_in_span(async move {
+                    transport::deliver(&*client, via_queue, &next_function, &invocation_type, bytes)
+                        .await
+                        .map(|_| ())
+                }),
+            ];
+            futures::future::join_all(tasks).await;
+            Ok(())
+        }
+        StatePersistence::OnFailureOnly => {
+            // The invocation retries on its own, so the payload is written only
+            // if it's lost for good.
+            match transport::deliver(
+                &*client,
+                via_queue,
+                &next_function,
+                &invocation_type,
+                bytes.clone(),
+            )
+            .await
+            {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    warn!(
+                        "Failed to invoke {}: {}. The payload is written to the state backend.",
+                        next_function, e
+                    );
+                    persist_payload(&*client, plan_index, &payload, bytes).await?;
+                    Err(e)
+                }
+            }
+        }
+        StatePersistence::Never => {
+            transport::deliver(&*client, via_queue, &next_function, &invocation_type, bytes)
+                .await
+                .map(|_| ())
         }
     }
-    Ok(sync)
 }
 
-/// Infer the S3 communucation mode of the function.
-pub fn infer_s3_mode(metadata: &Option<HashMap<String, String>>) -> Option<(String, String)> {
-    if let Some(metadata) = metadata {
-        if let (Some(bucket), Some(key)) = (metadata.get("s3_bucket"), metadata.get("s3_key")) {
-            let bucket = bucket.parse::<String>().unwrap();
-            let key = key.parse::<String>().unwrap();
-            if !bucket.is_empty() && !key.is_empty() {
-                return Some((bucket, key));
+/// Observes the volume of the window sent to the function group, and writes
+/// the scaling hint of the group if it needs another size (see
+/// [`flock::runtime::scaling`]). The hash ring is the ring version that the
+/// window is sent to.
+async fn observe_window_volume(
+    ctx: &ExecutionContext,
+    hash_context: &ConsistentHashContext,
+    uuid: &Uuid,
+    output: &[Vec<RecordBatch>],
+) {
+    let policy = ScalingPolicy::from_conf();
+    if !policy.is_enabled() {
+        return;
+    }
+    let bytes = output
+        .iter()
+        .flatten()
+        .map(|b| {
+            b.columns()
+                .iter()
+                .map(|a| a.get_array_memory_size())
+                .sum::<usize>()
+        })
+        .sum::<usize>();
+    let group = CloudFunction::Group((hash_context.group_name.clone(), hash_context.ring.len()));
+    let hint = {
+        let mut monitors = SCALING_MONITORS.lock().unwrap();
+        if monitors
+            .get(&hash_context.group_name)
+            .map_or(true, |m| m.group_size() != hash_context.ring.len())
+        {
+            match ScalingMonitor::new(&group, policy) {
+                Ok(monitor) => {
+                    monitors.insert(hash_context.group_name.clone(), monitor);
+                }
+                Err(e) => {
+                    warn!("Failed to monitor the function group: {:?}", e);
+                    return;
+                }
+            }
+        }
+        monitors
+            .get_mut(&hash_context.group_name)
+            .and_then(|m| m.observe(&uuid.qid, bytes, Utc::now().timestamp_millis()))
+    };
+    if let Some(hint) = hint {
+        match ScalingHints::new(ctx.cloud_client.clone(), &FLOCK_S3_BUCKET)
+            .write(&hint)
+            .await
+        {
+            Ok(()) => info!(
+                "[OK] Requested {} members of {} for {} bytes per window.",
+                hint.target_size, hash_context.group_name, hint.window_bytes
+            ),
+            Err(e) => warn!("Failed to write the scaling hint: {:?}", e),
+        }
+    }
+}
+
+/// Writes the first rows of the output of the window to S3 if the peek marker
+/// of the stage exists (see [`flock::runtime::peek`]). The output itself is
+/// forwarded as it is, so a failed sample is only logged.
+async fn peek_output(ctx: &ExecutionContext, uuid: &Uuid, output: &[Vec<RecordBatch>]) {
+    // The client can't read the samples of an encrypted query.
+    if *FLOCK_PEEK_INTERVAL == 0 || encryption::cipher().is_some() {
+        return;
+    }
+    let name = match FunctionName::parse(&ctx.name) {
+        Ok(name) => name,
+        Err(_) => return,
+    };
+    let peeks = Peeks::new(ctx.cloud_client.clone(), &FLOCK_S3_BUCKET);
+    let now = Utc::now().timestamp_millis();
+    let cached = PEEK_MARKERS
+        .lock()
+        .unwrap()
+        .get(&ctx.name)
+        .filter(|(checked, _)| now - checked < *FLOCK_PEEK_INTERVAL as i64 * 1000)
+        .map(|(_, marker)| marker.clone());
+    let marker = match cached {
+        Some(marker) => marker,
+        None => match peeks.marker(&name.query_code, name.plan_index).await {
+            Ok(marker) => {
+                PEEK_MARKERS
+                    .lock()
+                    .unwrap()
+                    .insert(ctx.name.clone(), (now, marker.clone()));
+                marker
+            }
+            Err(e) => {
+                warn!("Failed to read the peek marker: {:?}", e);
+                return;
             }
+        },
+    };
+    if let Some(marker) = marker {
+        match peeks
+            .write_sample(
+                &name.query_code,
+                name.plan_index,
+                uuid,
+                output,
+                marker.rows,
+                *FLOCK_PEEK_MAX_BYTES,
+            )
+            .await
+        {
+            Ok(Some(rows)) => info!("[OK] Sampled {} rows of the window {}.", rows, uuid.qid),
+            Ok(None) => warn!(
+                "No row of the window {} fits in a sample of {} bytes.",
+                uuid.qid, *FLOCK_PEEK_MAX_BYTES
+            ),
+            Err(e) => warn!("Failed to write the sample of the output: {:?}", e),
+        }
+    }
+}
+
+/// Writes the input payload of the stash stage to S3, where the probe stage
+/// reads it once the side input of the window is broadcast (see
+/// [`flock::runtime::broadcast`]).
+async fn stash_payload(client: &dyn CloudClient, event: &Payload) -> Result<()> {
+    let key = stash_key(&event.get_window_id(), event.uuid.seq_num);
+    client
+        .s3_put(
+            &FLOCK_S3_BUCKET,
+            &key,
+            encryption::seal_bytes(serde_json::to_vec(event)?)?,
+        )
+        .await
+}
+
+/// Writes the output of the broadcast stage to S3 as the side input of the
+/// window, and invokes the probe function once for each payload stashed by the
+/// stash stage (see [`flock::runtime::broadcast`]).
+///
+/// # Arguments
+/// * `ctx` - The runtime context of the current function.
+/// * `query_number` - The query number of the NEXMark benchmark.
+/// * `uuid` - The uuid of the current payload, whose `seq_len` is the number of
+///   the stashed payloads of the window.
+/// * `window_id` - The window of the broadcast join.
+/// * `metadata` - The metadata of the current payload.
+/// * `output` - The output of the current function.
+async fn broadcast_side_input(
+    ctx: &ExecutionContext,
+    query_number: Option<usize>,
+    uuid: &Uuid,
+    window_id: &WindowId,
+    metadata: Option<QueryMetadata>,
+    output: Vec<Vec<RecordBatch>>,
+) -> Result<Value> {
+    let probe = match &ctx.next {
+        CloudFunction::Lambda(name) => name.clone(),
+        next => {
+            return Err(FlockError::Execution(format!(
+                "The broadcast stage must invoke a single probe function, not {:?}",
+                next
+            )));
         }
+    };
+    let invocation_type = if infer_invocation_type(&metadata)? {
+        FLOCK_LAMBDA_SYNC_CALL.to_string()
+    } else {
+        FLOCK_LAMBDA_ASYNC_CALL.to_string()
+    };
+
+    let output = output.into_iter().flatten().collect::<Vec<_>>();
+    ctx.cloud_client
+        .s3_put(
+            &FLOCK_S3_BUCKET,
+            &side_input_key(window_id),
+            encryption::seal_bytes(side_input_to_csv(&output)?)?,
+        )
+        .await?;
+    info!(
+        "[OK] Broadcast the side input of the window: {:?}",
+        window_id
+    );
+
+    let schema = ctx.schema(0).await?;
+    let tasks = (1..=uuid.seq_len)
+        .map(|seq_num| {
+            let payload = Payload {
+                uuid: Uuid {
+                    qid: uuid.qid.clone(),
+                    seq_num,
+                    seq_len: uuid.seq_len,
+                },
+                query_number,
+                metadata: Some(probe_metadata(
+                    &metadata,
+                    window_id,
+                    seq_num,
+                    schema.clone(),
+                )),
+                ..Default::default()
+            };
+            let function_name = probe.clone();
+            let invoke_type = invocation_type.clone();
+            let client = ctx.cloud_client.clone();
+            spawn_in_span(async move {
+                let bytes = serde_json::to_vec(&payload)?;
+                client
+                    .invoke(&function_name, &invoke_type, bytes)
+                    .await
+                    .map(|_| ())
+            })
+        })
+        .collect::<Vec<tokio::task::JoinHandle<Result<()>>>>();
+    futures::future::join_all(tasks).await;
+
+    Ok(Value::Null)
+}
+
+/// Infer the pane and the window of the payload for the hopping windows
+/// evaluated incrementally.
+pub fn infer_pane(metadata: &Option<QueryMetadata>) -> Option<(usize, Range<usize>)> {
+    let metadata = metadata.as_ref()?;
+    let pane = metadata.get(PANE_METADATA_KEY)?.parse::<usize>().ok()?;
+    let (start, end) = metadata.get(WINDOW_METADATA_KEY)?.split_once('-')?;
+    Some((pane, start.parse().ok()?..end.parse().ok()?))
+}
+
+/// Infer the session window settings of the payload (used in NEXMark Q11).
+/// The session gap of the window in the execution context takes precedence
+/// over the gap carried in the payload.
+pub fn infer_session_window(
+    metadata: &Option<QueryMetadata>,
+    window: Option<&Window>,
+) -> Option<SessionMetadata> {
+    // The session group key shares the metadata key with the session state.
+    let mut metadata = metadata.as_ref()?.to_legacy();
+    if let Some(Window::Session(Schedule::Seconds(gap))) = window {
+        metadata.insert(
+            SESSION_GAP_METADATA_KEY.to_string(),
+            (*gap as i64 * 1000).to_string(),
+        );
+    }
+    SessionMetadata::from_metadata(&metadata)
+}
+
+/// Infer the invocation mode of the function.
+pub fn infer_invocation_type(metadata: &Option<QueryMetadata>) -> Result<bool> {
+    let invocation_type = metadata.as_ref().and_then(|m| m.invocation_type);
+    Ok(invocation_type != Some(InvocationType::Async))
+}
+
+/// Infer the S3 communucation mode of the function.
+pub fn infer_s3_mode(metadata: &Option<QueryMetadata>) -> Option<(String, String)> {
+    let s3 = metadata.as_ref()?.s3.as_ref()?;
+    if !s3.bucket.is_empty() && !s3.key.is_empty() {
+        return Some((s3.bucket.clone(), s3.key.clone()));
     }
     None
 }
 
+/// Infer the side input of the query, i.e. its filtered and projected rows
+/// (see [`flock::runtime::side_input`]).
 pub async fn infer_side_input(
-    metadata: &Option<HashMap<String, String>>,
+    client: &dyn CloudClient,
+    metadata: &Option<QueryMetadata>,
 ) -> Result<Vec<RecordBatch>> {
-    if let Some(metadata) = metadata {
-        if let Some(key) = metadata.get("side_input_s3_key") {
-            let key = key.parse::<String>().unwrap();
-            let bytes = s3::get_object(&FLOCK_S3_BUCKET, &key).await?;
-
-            let format = metadata
-                .get("side_input_format")
-                .expect("side_input_format is missing")
-                .as_str();
-
-            let schema = schema_from_bytes(&base64::decode(
-                metadata
-                    .get("side_input_schema")
-                    .expect("side_input_schema is missing")
-                    .as_str(),
-            )?)?;
-
-            let mut batches = vec![];
-            match format {
-                "csv" => {
-                    let mut batch_reader = ReaderBuilder::new()
-                        .with_schema(schema)
-                        .has_header(true)
-                        .with_delimiter(b',')
-                        .with_batch_size(1024)
-                        .build(Cursor::new(bytes))?;
-                    loop {
-                        match batch_reader.next() {
-                            Some(Ok(batch)) => {
-                                batches.push(batch);
-                            }
-                            None => {
-                                break;
-                            }
-                            Some(Err(e)) => {
-                                return Err(FlockError::Execution(format!(
-                                    "Error reading batch from side input: {}",
-                                    e
-                                )));
-                            }
-                        }
-                    }
-                }
-                _ => unimplemented!(),
-            }
-            return Ok(batches);
+    match metadata.as_ref().and_then(|m| m.side_input.as_ref()) {
+        Some(side_input) => {
+            SIDE_INPUT_CACHE
+                .get_or_read(client, &FLOCK_S3_BUCKET, side_input)
+                .await
         }
+        None => Err(FlockError::AWS(
+            "Side Input's S3 key is not specified".to_string(),
+        )),
     }
-    Err(FlockError::AWS(
-        "Side Input's S3 key is not specified".to_string(),
-    ))
 }
 
 /// Infer group keys for session windows (used in NEXMark Q11 and Q12).
-pub fn infer_session_keys(metadata: &Option<HashMap<String, String>>) -> Result<(String, String)> {
-    if let Some(metadata) = metadata {
-        if let (Some(key), Some(name)) = (metadata.get("session_key"), metadata.get("session_name"))
-        {
-            let key = key.parse::<String>().unwrap();
-            let name = name.parse::<String>().unwrap();
-            if !key.is_empty() && !name.is_empty() {
-                return Ok((key, name));
-            }
+#[cfg(feature = "nexmark")]
+pub fn infer_session_keys(metadata: &Option<QueryMetadata>) -> Result<(String, String)> {
+    if let Some(keys) = metadata.as_ref().and_then(|m| m.session_keys.as_ref()) {
+        if !keys.key.is_empty() && !keys.name.is_empty() {
+            return Ok((keys.key.clone(), keys.name.clone()));
         }
     }
     Err(FlockError::Internal(
@@ -645,16 +2253,1720 @@ pub fn infer_session_keys(metadata: &Option<HashMap<String, String>>) -> Result<
     ))
 }
 
-/// This function is only used for NEXMark Q12 to add the process time field to
-/// the input data.
-pub fn infer_add_process_time_query(metadata: &Option<HashMap<String, String>>) -> Result<String> {
-    if let Some(metadata) = metadata {
-        if let Some(plan) = metadata.get("add_process_time_query") {
-            let plan = plan.parse::<String>().unwrap();
-            return Ok(plan);
-        }
+/// This function is only used for NEXMark Q12 to infer the column, i.e. the
+/// process time field, to add to the input data.
+#[cfg(feature = "nexmark")]
+pub fn infer_add_column(metadata: &Option<QueryMetadata>) -> Result<AddColumn> {
+    if let Some(column) = metadata.as_ref().and_then(|m| m.add_column.as_ref()) {
+        column.validate()?;
+        return Ok(column.clone());
     }
     Err(FlockError::Execution(
-        "Failed to infer plan for adding process time field to the input data.".to_string(),
+        "Failed to infer the column to add to the input data.".to_string(),
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolve_exec_context;
+    use crate::window::tumbling::{expected_len, flush_payloads, window_len, window_payloads};
+    use datafusion::arrow::array::{Int32Array, Int64Array, TimestampMillisecondArray};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+    use datafusion::physical_plan::coalesce_batches::CoalesceBatchesExec;
+    use datafusion::physical_plan::cross_join::CrossJoinExec;
+    use datafusion::physical_plan::expressions::Column;
+    use datafusion::physical_plan::limit::LocalLimitExec;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::physical_plan::repartition::RepartitionExec;
+    use datafusion::physical_plan::{ExecutionPlan, Partitioning};
+    use flock::aws::chaos::{ChaosSpec, Latency, FLOCK_CHAOS};
+    use flock::aws::client::FakeCloudClient;
+    use flock::datasink::results::ResultStore;
+    use flock::datasink::sink_key;
+    use flock::runtime::deadline::{Clock, Deadline};
+    use flock::runtime::distribution::SessionAffinity;
+    use flock::runtime::metadata::S3Pointer;
+    use flock::runtime::multiplex::QueryContexts;
+    use flock::tests::{int64_batch, int64_schema};
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::time::Duration;
+
+    fn num_rows(batches: &[RecordBatch]) -> usize {
+        batches.iter().map(|b| b.num_rows()).sum()
+    }
+
+    /// Returns the committed results of the window in the S3 data sink.
+    async fn stored_window(
+        client: Arc<FakeCloudClient>,
+        query_code: &str,
+        window_id: &str,
+    ) -> Result<Vec<RecordBatch>> {
+        let store = ResultStore::S3 {
+            bucket: FLOCK_S3_BUCKET.clone(),
+            client,
+        };
+        Ok(store.get(query_code, window_id).await?.unwrap())
+    }
+
+    fn memory_plan() -> Arc<dyn ExecutionPlan> {
+        Arc::new(MemoryExec::try_new(&[vec![]], int64_schema(), None).unwrap())
+    }
+
+    /// The plan of the stage that shuffles its output to the next function
+    /// group (see `ExecutionContext::is_shuffling`).
+    fn shuffle_plan(partitions: usize) -> Arc<dyn ExecutionPlan> {
+        let repartition = RepartitionExec::try_new(
+            memory_plan(),
+            Partitioning::Hash(vec![Arc::new(Column::new("c1", 0))], partitions),
+        )
+        .unwrap();
+        Arc::new(CoalesceBatchesExec::new(Arc::new(repartition), 4096))
+    }
+
+    fn context(
+        name: &str,
+        next: CloudFunction,
+        plan: Arc<dyn ExecutionPlan>,
+        client: Arc<FakeCloudClient>,
+    ) -> ExecutionContext {
+        ExecutionContext {
+            plan: CloudExecutionPlan::new(vec![plan], None),
+            name: name.to_string(),
+            next,
+            cloud_client: client,
+            ..Default::default()
+        }
+    }
+
+    fn async_metadata() -> Option<QueryMetadata> {
+        Some(QueryMetadata {
+            invocation_type: Some(InvocationType::Async),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn read_payload_in_s3_mode() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let ctx = context(
+            "q1-01",
+            CloudFunction::Sink(DataSinkType::Blackhole),
+            memory_plan(),
+            client.clone(),
+        );
+
+        let uuid = UuidBuilder::new_with_ts("q1-00", 1, 1).next_uuid();
+        let payload = to_payload(
+            &[int64_batch(vec![1, 2, 3])],
+            &[int64_batch(vec![4])],
+            uuid.clone(),
+            false,
+        )?;
+        client.put_object("inputs", "q1/0", serde_json::to_vec(&payload)?);
+
+        // The payload of the invocation only points to the S3 object.
+        let event = Payload {
+            uuid,
+            metadata: Some(QueryMetadata {
+                s3: Some(S3Pointer {
+                    bucket: "inputs".to_string(),
+                    key:    "q1/0".to_string(),
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let mut arena = Arena::new();
+        let (input, status) = prepare_data_sources(&ctx, &mut arena, event.clone()).await?;
+        assert!(status == HashAggregateStatus::Ready);
+        assert_eq!(input.len(), 2);
+        assert_eq!(num_rows(&input[0][0]), 3);
+        assert_eq!(num_rows(&input[1][0]), 1);
+
+        client.fail_next("inputs", 1);
+        assert!(prepare_data_sources(&ctx, &mut arena, event).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_broadcast_build_side() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let mut source = context(
+            "q1-00",
+            CloudFunction::Lambda("q1-01".to_string()),
+            memory_plan(),
+            client.clone(),
+        );
+        source.broadcast = Some(BroadcastRole::Build);
+        let mut uuid_builder = UuidBuilder::new_with_ts("q1-00", 1, 2);
+        let mut payloads = vec![
+            to_payload(
+                &[int64_batch(vec![1, 2])],
+                &[int64_batch(vec![10])],
+                uuid_builder.next_uuid(),
+                false,
+            )?,
+            to_payload(
+                &[int64_batch(vec![3])],
+                &[int64_batch(vec![20, 30])],
+                uuid_builder.next_uuid(),
+                false,
+            )?,
+        ];
+        broadcast_build_side(&source, &mut payloads).await?;
+        assert!(payloads.iter().all(|p| p.data2.is_empty()));
+        let pointer = payloads[0].broadcast.clone().unwrap();
+        assert!(client.object(&pointer.bucket, &pointer.key).is_some());
+
+        // Each payload is joined with the whole build side of the window.
+        let ctx = context(
+            "q1-01",
+            CloudFunction::Sink(DataSinkType::Blackhole),
+            memory_plan(),
+            client.clone(),
+        );
+        let mut arena = Arena::new();
+        for (payload, rows) in payloads.into_iter().zip([2, 1]) {
+            let (input, status) = prepare_data_sources(&ctx, &mut arena, payload).await?;
+            assert!(status == HashAggregateStatus::Ready);
+            assert_eq!(num_rows(&input[0][0]), rows);
+            assert_eq!(num_rows(&input[1][0]), 3);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fan_out_aggregate_output() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let next = CloudFunction::Lambda("q1-02".to_string());
+        let hash_context = ConsistentHashContext::new(&next);
+        let ctx = context("q1-01-00", next, memory_plan(), client.clone());
+
+        let uuid = UuidBuilder::new_with_ts("q1-00", 1, 1).next_uuid();
+        let output = vec![
+            vec![int64_batch(vec![1])],
+            vec![int64_batch(vec![2, 2])],
+            vec![int64_batch(vec![3, 3, 3])],
+        ];
+        invoke_next_functions(
+            &ctx,
+            &hash_context,
+            None,
+            uuid.clone(),
+            async_metadata(),
+            None,
+            output,
+        )
+        .await?;
+
+        // Each partition of the aggregator's output is a fragment of a new
+        // window, which is processed by a different function instance.
+        let invocations = client.invocations();
+        assert_eq!(invocations.len(), 3);
+        let mut qids = HashSet::new();
+        let mut rows = HashMap::new();
+        for invocation in &invocations {
+            assert_eq!(invocation.function, "q1-02");
+            assert_eq!(invocation.invocation_type, *FLOCK_LAMBDA_ASYNC_CALL);
+            let payload = invocation.payload()?;
+            assert_eq!(payload.uuid.seq_len, 3);
+            assert!(payload.uuid.qid.starts_with("q1-"));
+            assert_ne!(payload.uuid.qid, uuid.qid);
+            qids.insert(payload.uuid.qid.clone());
+            let seq_num = payload.uuid.seq_num;
+            rows.insert(seq_num, num_rows(&payload.to_record_batch()?.0));
+        }
+        assert_eq!(qids.len(), 1);
+        assert_eq!(rows, HashMap::from([(1, 1), (2, 2), (3, 3)]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_payloads_of_wrong_successor() -> Result<()> {
+        use flock::runtime::topology::StageTopology;
+
+        // The output of each stage is sent to `q4-01`, whose upstream is `q4-00`.
+        let send = |name: &str, plan: Arc<dyn ExecutionPlan>, output: RecordBatch| {
+            let name = name.to_string();
+            async move {
+                let client = Arc::new(FakeCloudClient::new());
+                let next = CloudFunction::Lambda("q4-01".to_string());
+                let hash_context = ConsistentHashContext::new(&next);
+                let ctx = context(&name, next, plan, client.clone());
+                let uuid = UuidBuilder::new_with_ts("q4-00", 1, 1).next_uuid();
+                invoke_next_functions(
+                    &ctx,
+                    &hash_context,
+                    None,
+                    uuid,
+                    async_metadata(),
+                    None,
+                    vec![vec![output]],
+                )
+                .await?;
+                client.invocations()[0].payload()
+            }
+        };
+        let mut receiver = context(
+            "q4-01",
+            CloudFunction::Sink(DataSinkType::Blackhole),
+            memory_plan(),
+            Arc::new(FakeCloudClient::new()),
+        );
+        receiver.topology = Some(StageTopology::for_stage(
+            &[memory_plan()],
+            vec!["q4-00".to_string()],
+        ));
+        let mut arena = Arena::new();
+
+        let payload = send("q4-00", memory_plan(), int64_batch(vec![1, 2])).await?;
+        assert_eq!(topology::sender(&payload.metadata), Some("q4-00"));
+        let (input, status) = prepare_data_sources(&receiver, &mut arena, payload).await?;
+        assert!(status == HashAggregateStatus::Ready);
+        assert_eq!(num_rows(&input[0][0]), 2);
+
+        // A q3 stage with the stale metadata of q4, whose output has as many
+        // fields as the input of q4.
+        let q3_schema = Arc::new(Schema::new(vec![Field::new("c2", DataType::Int64, false)]));
+        let q3_plan = Arc::new(MemoryExec::try_new(&[vec![]], q3_schema.clone(), None)?);
+        let q3_batch = RecordBatch::try_new(q3_schema, vec![Arc::new(Int64Array::from(vec![3]))])?;
+        let payload = send("q3-00", q3_plan, q3_batch).await?;
+        let error = prepare_data_sources(&receiver, &mut arena, payload)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), "Payload.Topology");
+        let message = error.to_string();
+        assert!(message.contains("q3-00") && message.contains("q4-01"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pipeline_element_wise_payloads() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let next = CloudFunction::Lambda("q1-02".to_string());
+        let mut ctx = context("q1-01", next.clone(), memory_plan(), client.clone());
+        ctx.window = Some(Window::ElementWise);
+        assert!(ctx.is_pipelined());
+
+        let uuid = UuidBuilder::new_with_ts("q1-00", 1, 2).next_uuid();
+        let mut payload = to_payload(&[int64_batch(vec![1, 2, 3])], &[], uuid.clone(), false)?;
+        payload.metadata = async_metadata();
+
+        // The payload takes the fast path, which bypasses the arena and
+        // forwards the output as a window by itself.
+        let mut arena = Arena::new();
+        let pipelined = PIPELINED_PAYLOADS.load(Ordering::Relaxed);
+        handler(&ctx, &mut arena, payload.clone()).await?;
+        assert_eq!(PIPELINED_PAYLOADS.load(Ordering::Relaxed), pipelined + 1);
+        assert!(arena.is_empty());
+        let invocations = client.invocations();
+        assert_eq!(invocations.len(), 1);
+        assert_eq!(invocations[0].function, "q1-02");
+        let fast = invocations[0].payload()?;
+        assert!(fast.uuid.qid.starts_with("q1-"));
+        assert_ne!(fast.uuid.qid, uuid.qid);
+        assert_eq!((fast.uuid.seq_num, fast.uuid.seq_len), (1, 1));
+        let mut metadata = payload.metadata.clone();
+        topology::stamp_sender(&mut metadata, "q1-01");
+        assert_eq!(fast.metadata, metadata);
+
+        // The stages of the other windows and the aggregators take the old path.
+        ctx.window = Some(Window::Tumbling(Schedule::Seconds(10)));
+        assert!(!ctx.is_pipelined());
+        let aggregator = context("q1-01-00", next.clone(), memory_plan(), client.clone());
+        assert!(!ExecutionContext {
+            window: Some(Window::ElementWise),
+            ..aggregator
+        }
+        .is_pipelined());
+
+        // The results of the old path are the same.
+        let old_client = Arc::new(FakeCloudClient::new());
+        ctx.cloud_client = old_client.clone();
+        let (input, status) = prepare_data_sources(&ctx, &mut arena, payload.clone()).await?;
+        assert!(status == HashAggregateStatus::Ready);
+        let output = collect(&ctx, input).await?;
+        let hash_context = ConsistentHashContext::new(&next);
+        invoke_next_functions(
+            &ctx,
+            &hash_context,
+            None,
+            uuid.clone(),
+            payload.metadata.clone(),
+            None,
+            output,
+        )
+        .await?;
+        assert_eq!(PIPELINED_PAYLOADS.load(Ordering::Relaxed), pipelined + 1);
+        let old = old_client.invocations()[0].payload()?;
+        assert_eq!(old.uuid, uuid);
+        let (old, fast) = (old.to_record_batch()?.0, fast.to_record_batch()?.0);
+        assert_eq!(num_rows(&old), 3);
+        assert_eq!(old[0].columns(), fast[0].columns());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pipeline_payloads_with_side_input() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let uuid = UuidBuilder::new_with_ts("q13-00", 1, 1).next_uuid();
+
+        // The partition of the window is stashed in S3, and the side input of
+        // the window is written next to it.
+        let event = to_payload(&[int64_batch(vec![1, 2, 3])], &[], uuid.clone(), false)?;
+        stash_payload(client.as_ref(), &event).await?;
+        let window_id = event.get_window_id();
+        let side_schema = Arc::new(Schema::new(vec![Field::new("m", DataType::Int64, true)]));
+        let side = RecordBatch::try_new(
+            side_schema.clone(),
+            vec![Arc::new(Int64Array::from(vec![5]))],
+        )?;
+        client.put_object(
+            &FLOCK_S3_BUCKET,
+            &side_input_key(&window_id),
+            side_input_to_csv(&[side])?,
+        );
+
+        // The element-wise stage joins its input with the side input.
+        let side_plan = Arc::new(MemoryExec::try_new(&[vec![]], side_schema.clone(), None)?);
+        let plan = Arc::new(CrossJoinExec::try_new(memory_plan(), side_plan)?);
+        let next = CloudFunction::Lambda("q13-02".to_string());
+        let mut ctx = context("q13-01", next, plan, client.clone());
+        ctx.window = Some(Window::ElementWise);
+        assert!(ctx.is_pipelined());
+
+        // The payload only points to the stashed partition and the side input.
+        let payload = Payload {
+            uuid,
+            metadata: Some(probe_metadata(
+                &async_metadata(),
+                &window_id,
+                1,
+                side_schema,
+            )),
+            ..Default::default()
+        };
+        let pipelined = PIPELINED_PAYLOADS.load(Ordering::Relaxed);
+        handler(&ctx, &mut Arena::new(), payload).await?;
+        assert_eq!(PIPELINED_PAYLOADS.load(Ordering::Relaxed), pipelined + 1);
+
+        let invocations = client.invocations();
+        assert_eq!(invocations.len(), 1);
+        let (output, _) = invocations[0].payload()?.to_record_batch()?;
+        assert_eq!(num_rows(&output), 3);
+        let m = output[0]
+            .column(1)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(m.values(), &[5, 5, 5]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stop_fan_out_after_deadline() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let next = CloudFunction::Lambda("q1-02".to_string());
+        let hash_context = ConsistentHashContext::new(&next);
+        let ctx = context("q1-01-00", next, memory_plan(), client.clone());
+        let uuid = UuidBuilder::new_with_ts("q1-00", 1, 1).next_uuid();
+        let output = vec![vec![int64_batch(vec![1])], vec![int64_batch(vec![2, 2])]];
+
+        // The deadline of the window has passed.
+        let mut metadata = async_metadata();
+        Deadline::at(SystemClock.now() - 1).stamp(&mut metadata);
+        let result = invoke_next_functions(
+            &ctx,
+            &hash_context,
+            None,
+            uuid.clone(),
+            metadata,
+            None,
+            output.clone(),
+        )
+        .await;
+        assert!(matches!(result, Err(FlockError::Timeout(_))));
+        assert!(client.invocations().is_empty());
+
+        // Before the deadline, the output is fanned out with the propagated
+        // deadline of the asynchronous invocations.
+        let deadline = Deadline::after(&SystemClock, Duration::from_secs(60));
+        let mut metadata = async_metadata();
+        deadline.stamp(&mut metadata);
+        invoke_next_functions(&ctx, &hash_context, None, uuid, metadata, None, output).await?;
+        let invocations = client.invocations();
+        assert_eq!(invocations.len(), 2);
+        for invocation in &invocations {
+            let payload = invocation.payload()?;
+            assert_eq!(Deadline::from_metadata(&payload.metadata)?, Some(deadline));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn route_shuffled_partitions_to_ring_members() -> Result<()> {
+        let next = CloudFunction::Group(("q1-02".to_string(), 4));
+        let hash_context = ConsistentHashContext::new(&next);
+        let members = (0..4)
+            .map(|i| format!("q1-02-{:02}", i))
+            .collect::<HashSet<_>>();
+
+        // Two upstream functions shuffle their outputs of the same window.
+        let mut routes = vec![];
+        for upstream in ["q1-01-00", "q1-01-01"] {
+            let client = Arc::new(FakeCloudClient::new());
+            let ctx = context(upstream, next.clone(), shuffle_plan(4), client.clone());
+            let uuid = UuidBuilder::new_with_ts("q1-00", 1, 1).next_uuid();
+            let output = (0..4)
+                .map(|i| vec![int64_batch(vec![i])])
+                .collect::<Vec<_>>();
+            invoke_next_functions(
+                &ctx,
+                &hash_context,
+                None,
+                uuid,
+                async_metadata(),
+                None,
+                output,
+            )
+            .await?;
+
+            let invocations = client.invocations();
+            assert_eq!(invocations.len(), 4);
+            let route = invocations
+                .iter()
+                .map(|invocation| {
+                    let payload = invocation.payload().unwrap();
+                    (payload.shuffle_id.unwrap(), invocation.function.clone())
+                })
+                .collect::<HashMap<_, _>>();
+            assert_eq!(
+                route.keys().copied().collect::<HashSet<_>>(),
+                (1..=4).collect()
+            );
+            assert_eq!(route.values().cloned().collect::<HashSet<_>>(), members);
+            routes.push(route);
+        }
+        // The partitions at the same position meet at the same function.
+        assert_eq!(routes[0], routes[1]);
+        Ok(())
+    }
+
+    /// Returns the serialized payload without its id, which is new for every
+    /// payload built.
+    fn without_payload_id(bytes: &[u8]) -> Value {
+        let mut value: Value = serde_json::from_slice(bytes).unwrap();
+        value.as_object_mut().unwrap().remove("payload_id");
+        value
+    }
+
+    /// Serializes the payloads of the shuffled partitions inline, as the
+    /// spawned task of each partition used to, by their shuffle ids.
+    async fn inline_shuffle_payloads(
+        ctx: &ExecutionContext,
+        uuid: &Uuid,
+        output: &[Vec<RecordBatch>],
+    ) -> Result<HashMap<usize, Value>> {
+        let schema = schema_to_bytes(ctx.schema(0).await?);
+        let mut bodies = HashMap::new();
+        for (i, partition) in output.iter().enumerate() {
+            let mut payload = to_stage_payload(
+                partition,
+                &[],
+                uuid.clone(),
+                false,
+                &ctx.stats_keys,
+                &ctx.encoding,
+            )?;
+            payload.metadata = async_metadata();
+            payload.schema = schema.clone();
+            payload.set_shuffle_id(i + 1);
+            bodies.insert(i + 1, without_payload_id(&serde_json::to_vec(&payload)?));
+        }
+        Ok(bodies)
+    }
+
+    /// Returns the output of a busy shuffle stage.
+    fn large_output(partitions: usize, rows: usize) -> Vec<Vec<RecordBatch>> {
+        (0..partitions)
+            .map(|i| {
+                (0..4)
+                    .map(|j| int64_batch((0..rows as i64).map(|r| r * (i + j) as i64).collect()))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn send_identical_payload_bytes() -> Result<()> {
+        let uuid = UuidBuilder::new_with_ts("q1-00", 1, 1).next_uuid();
+        let output = large_output(8, 1024);
+
+        // The shuffled partitions.
+        let client = Arc::new(FakeCloudClient::new());
+        let next = CloudFunction::Group(("q1-02".to_string(), 4));
+        let hash_context = ConsistentHashContext::new(&next);
+        let ctx = context("q1-01-00", next, shuffle_plan(8), client.clone());
+        let expected = inline_shuffle_payloads(&ctx, &uuid, &output).await?;
+        invoke_next_functions(
+            &ctx,
+            &hash_context,
+            None,
+            uuid.clone(),
+            async_metadata(),
+            None,
+            output.clone(),
+        )
+        .await?;
+        let sent = client
+            .invocations()
+            .into_iter()
+            .map(|invocation| {
+                (
+                    invocation.payload().unwrap().shuffle_id.unwrap(),
+                    without_payload_id(&invocation.payload),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        assert_eq!(sent.len(), 8);
+        assert_eq!(sent, expected);
+
+        // The fragments of the aggregator's output.
+        let client = Arc::new(FakeCloudClient::new());
+        let next = CloudFunction::Lambda("q1-02".to_string());
+        let hash_context = ConsistentHashContext::new(&next);
+        let ctx = context("q1-01-00", next, memory_plan(), client.clone());
+        let schema = schema_to_bytes(ctx.schema(0).await?);
+        let mut uuid_builder = UuidBuilder::for_window("q1-02", &window_id(&uuid, None), 8);
+        let mut expected = output
+            .iter()
+            .map(|partition| {
+                let mut payload = to_stage_payload(
+                    partition,
+                    &[],
+                    uuid_builder.next_uuid(),
+                    false,
+                    &ctx.stats_keys,
+                    &ctx.encoding,
+                )
+                .unwrap();
+                payload.metadata = async_metadata();
+                payload.schema = schema.clone();
+                without_payload_id(&serde_json::to_vec(&payload).unwrap()).to_string()
+            })
+            .collect::<Vec<_>>();
+        invoke_next_functions(
+            &ctx,
+            &hash_context,
+            None,
+            uuid,
+            async_metadata(),
+            None,
+            output,
+        )
+        .await?;
+        let mut sent = client
+            .invocations()
+            .into_iter()
+            .map(|invocation| without_payload_id(&invocation.payload).to_string())
+            .collect::<Vec<_>>();
+        expected.sort();
+        sent.sort();
+        assert_eq!(sent, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn attribute_fan_out_failures() -> Result<()> {
+        let prepared = prepare_payloads(4, |i| {
+            if i == 2 {
+                return Err(FlockError::Execution("broken batch".to_string()));
+            }
+            let uuid = UuidBuilder::new_with_ts("q1-00", 1, 1).next_uuid();
+            let payload = to_payload(&[int64_batch(vec![i as i64])], &[], uuid, false)?;
+            Ok((format!("q1-02-{:02}", i), payload))
+        })
+        .await?;
+        assert_eq!(prepared.len(), 4);
+        let err = prepared[2].as_ref().err().unwrap().to_string();
+        assert!(err.contains("partition 2") && err.contains("broken batch"));
+
+        let client = Arc::new(FakeCloudClient::new());
+        client.fail_next("q1-02-01", 1);
+        let results = send_payloads(
+            client.clone(),
+            false,
+            StatePersistence::Never,
+            1,
+            &FLOCK_LAMBDA_ASYNC_CALL,
+            prepared,
+        )
+        .await;
+        assert!(results[0].is_ok() && results[3].is_ok());
+        let err = results[1].as_ref().unwrap_err().to_string();
+        assert!(err.contains("partition 1") && err.contains("q1-02-01"));
+        assert!(results[2]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("partition 2"));
+        let mut functions = client
+            .invocations()
+            .into_iter()
+            .map(|invocation| invocation.function)
+            .collect::<Vec<_>>();
+        functions.sort();
+        assert_eq!(functions, vec!["q1-02-00", "q1-02-03"]);
+        Ok(())
+    }
+
+    /// Compares the fan-out of a busy shuffle stage with the former code path,
+    /// which serialized each partition in its own async task. Run it with
+    /// `cargo test --release -- --ignored fan_out_speedup --nocapture`.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[ignore]
+    async fn fan_out_speedup() -> Result<()> {
+        let uuid = UuidBuilder::new_with_ts("q1-00", 1, 1).next_uuid();
+        let output = large_output(64, 16 * 1024);
+        let next = CloudFunction::Group(("q1-02".to_string(), 64));
+        let hash_context = ConsistentHashContext::new(&next);
+        let latency = Duration::from_millis(20);
+        let rounds = 5;
+
+        let client = Arc::new(FakeCloudClient::new().with_latency(latency));
+        let ctx = context("q1-01-00", next.clone(), shuffle_plan(64), client.clone());
+        let schema = schema_to_bytes(ctx.schema(0).await?);
+        let output_ref = Arc::new(output.clone());
+        let now = Instant::now();
+        for _ in 0..rounds {
+            let tasks = (0..output_ref.len())
+                .map(|i| {
+                    let (output, uuid, schema) = (output_ref.clone(), uuid.clone(), schema.clone());
+                    let (keys, encoding) = (ctx.stats_keys.clone(), ctx.encoding.clone());
+                    let client = client.clone();
+                    tokio::spawn(async move {
+                        let mut payload =
+                            to_stage_payload(&output[i], &[], uuid, false, &keys, &encoding)?;
+                        payload.metadata = async_metadata();
+                        payload.schema = schema;
+                        payload.set_shuffle_id(i + 1);
+                        let bytes = serde_json::to_vec(&payload)?;
+                        client
+                            .invoke(&format!("q1-02-{:02}", i), &FLOCK_LAMBDA_ASYNC_CALL, bytes)
+                            .await
+                            .map(|_| ())
+                    })
+                })
+                .collect::<Vec<tokio::task::JoinHandle<Result<()>>>>();
+            futures::future::join_all(tasks).await;
+        }
+        let inline_time = now.elapsed();
+
+        let client = Arc::new(FakeCloudClient::new().with_latency(latency));
+        let ctx = context("q1-01-00", next, shuffle_plan(64), client.clone());
+        let now = Instant::now();
+        for _ in 0..rounds {
+            invoke_next_functions(
+                &ctx,
+                &hash_context,
+                None,
+                uuid.clone(),
+                async_metadata(),
+                None,
+                output.clone(),
+            )
+            .await?;
+        }
+        let prepared_time = now.elapsed();
+
+        println!(
+            "Fan out {} partitions: inline {:?}, prepared on rayon {:?}, speedup {:.2}x",
+            output.len(),
+            inline_time / rounds,
+            prepared_time / rounds,
+            inline_time.as_secs_f64() / prepared_time.as_secs_f64()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn route_window_to_ring_member() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let next = CloudFunction::Group(("q1-02".to_string(), 4));
+        let hash_context = ConsistentHashContext::new(&next);
+        let ctx = context("q1-01", next, memory_plan(), client.clone());
+
+        let uuid = UuidBuilder::new_with_ts("q1-00", 1, 2).next_uuid();
+        let output = vec![vec![int64_batch(vec![1, 2])]];
+        invoke_next_functions(
+            &ctx,
+            &hash_context,
+            None,
+            uuid.clone(),
+            async_metadata(),
+            None,
+            output,
+        )
+        .await?;
+
+        // The payload keeps its uuid, so the fragments of the window are
+        // aggregated by the same function.
+        let invocations = client.invocations();
+        assert_eq!(invocations.len(), 1);
+        assert_eq!(
+            invocations[0].function,
+            *hash_context.ring.get(&uuid.qid).unwrap()
+        );
+        assert_eq!(invocations[0].payload()?.uuid, uuid);
+        Ok(())
+    }
+
+    fn bid_schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("bidder", DataType::Int32, false),
+            Field::new(
+                "b_date_time",
+                DataType::Timestamp(TimeUnit::Millisecond, None),
+                false,
+            ),
+        ]))
+    }
+
+    fn bids(bids: Vec<(i32, i64)>) -> RecordBatch {
+        let (bidders, times): (Vec<_>, Vec<_>) = bids.into_iter().unzip();
+        RecordBatch::try_new(
+            bid_schema(),
+            vec![
+                Arc::new(Int32Array::from(bidders)),
+                Arc::new(TimestampMillisecondArray::from(times)),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn route_sessions_by_key() -> Result<()> {
+        let next = CloudFunction::Group(("q11-02".to_string(), 4));
+        let hash_context = ConsistentHashContext::new(&next);
+        let plan: Arc<dyn ExecutionPlan> =
+            Arc::new(MemoryExec::try_new(&[vec![]], bid_schema(), None).unwrap());
+
+        // Two upstream functions emit the events of the same bidders in their own
+        // windows, which used to be forwarded to the members of their query ids.
+        let mut received: HashMap<String, Vec<RecordBatch>> = HashMap::new();
+        for (i, upstream) in ["q11-01-00", "q11-01-01"].iter().enumerate() {
+            let client = Arc::new(FakeCloudClient::new());
+            let mut ctx = context(upstream, next.clone(), plan.clone(), client.clone());
+            ctx.session_affinity = Some(SessionAffinity::new("bidder", 16));
+            let uuid = UuidBuilder::new_with_ts(upstream, i as i64, 1).next_uuid();
+            let output = vec![
+                vec![bids(
+                    (0..10).map(|b| (b, 1000 * i as i64 + b as i64)).collect(),
+                )],
+                vec![bids(
+                    (0..10).map(|b| (b, 1500 * i as i64 + b as i64)).collect(),
+                )],
+            ];
+            invoke_next_functions(
+                &ctx,
+                &hash_context,
+                None,
+                uuid.clone(),
+                async_metadata(),
+                None,
+                output,
+            )
+            .await?;
+
+            // Every member gets the fragment of the window.
+            let invocations = client.invocations();
+            assert_eq!(invocations.len(), 4);
+            for invocation in invocations {
+                let payload = invocation.payload()?;
+                assert_eq!(payload.uuid, uuid);
+                received
+                    .entry(invocation.function.clone())
+                    .or_default()
+                    .extend(payload.to_record_batch()?.0);
+            }
+        }
+        assert_eq!(received.len(), 4);
+
+        // The sessions of the members are disjoint, and each bidder has a single
+        // session merged from the events of both upstream functions.
+        let mut sessions: HashMap<i32, Vec<String>> = HashMap::new();
+        for (member, batches) in received {
+            let mut state = SessionState::new();
+            state.accumulate("bidder", "b_date_time", 10_000, &batches)?;
+            for (bidder, session) in state.emit(1_000_000, 10_000) {
+                assert_eq!(num_rows(&session.batches), 4);
+                sessions.entry(bidder).or_default().push(member.clone());
+            }
+        }
+        assert_eq!(sessions.len(), 10);
+        assert!(sessions.values().all(|members| members.len() == 1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hand_off_payloads_through_queue() -> Result<()> {
+        use flock::runtime::transport::{open_message, pointer_prefix, queue_name};
+
+        let client = Arc::new(FakeCloudClient::new());
+        let next = CloudFunction::Group(("q1-02".to_string(), 1));
+        let hash_context = ConsistentHashContext::new(&next);
+        let mut uuid_builder = UuidBuilder::new_with_ts("q1-00", 1, 3);
+
+        // The last fragment is too large for a queue message.
+        let large = (0..100_000i64)
+            .map(|r| r.wrapping_mul(0x9E37_79B9_7F4A_7C15))
+            .collect::<Vec<_>>();
+        let fragments = vec![
+            int64_batch(vec![1]),
+            int64_batch(vec![2, 2]),
+            int64_batch(large),
+        ];
+        let rows = num_rows(&fragments);
+        let mut uuids = vec![];
+        for fragment in fragments {
+            let mut ctx = context("q1-01", next.clone(), memory_plan(), client.clone());
+            ctx.via_queue = true;
+            let uuid = uuid_builder.next_uuid();
+            invoke_next_functions(
+                &ctx,
+                &hash_context,
+                None,
+                uuid.clone(),
+                async_metadata(),
+                None,
+                vec![vec![fragment]],
+            )
+            .await?;
+            uuids.push(uuid);
+        }
+
+        // The fragments are sent to the queue of the ring member instead of
+        // invoking it, and the oversized one is written to S3.
+        assert!(client.invocations().is_empty());
+        let member = hash_context.ring.get(&uuids[0].qid).unwrap().clone();
+        let messages = client.messages();
+        assert_eq!(messages.len(), 3);
+        assert!(messages.iter().all(|m| m.queue == queue_name(&member)));
+        let keys = client.keys(&FLOCK_S3_BUCKET);
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0].starts_with(&pointer_prefix("q1")));
+
+        // The queue doesn't keep the order of the messages, and delivers some
+        // of them again.
+        let receiver = context(
+            &member,
+            CloudFunction::Sink(DataSinkType::Blackhole),
+            memory_plan(),
+            client.clone(),
+        );
+        let mut arena = Arena::new();
+        let mut statuses = vec![];
+        for message in messages.iter().rev().chain(messages.iter().take(1)) {
+            let payload: Payload =
+                serde_json::from_value(open_message(client.as_ref(), &message.body).await?)?;
+            let (input, status) = prepare_data_sources(&receiver, &mut arena, payload).await?;
+            if status == HashAggregateStatus::Ready {
+                assert_eq!(input.iter().map(|p| num_rows(p)).sum::<usize>(), rows);
+            }
+            statuses.push(status);
+        }
+        assert!(
+            statuses
+                == vec![
+                    HashAggregateStatus::NotReady,
+                    HashAggregateStatus::NotReady,
+                    HashAggregateStatus::Ready,
+                    HashAggregateStatus::Processed,
+                ]
+        );
+        assert!(arena.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn persist_payloads_by_policy() -> Result<()> {
+        let uuid = UuidBuilder::new_with_ts("q1-00", 1, 2).next_uuid();
+        let bucket = state_bucket_name(&uuid.qid, &flock_region());
+        let send = |persistence: StatePersistence, failures: usize| {
+            let uuid = uuid.clone();
+            async move {
+                let client = Arc::new(FakeCloudClient::new());
+                let next = CloudFunction::Group(("q1-02".to_string(), 4));
+                let hash_context = ConsistentHashContext::new(&next);
+                let mut ctx = context("q1-01", next, memory_plan(), client.clone());
+                ctx.state_backend = Arc::new(S3StateBackend::new());
+                ctx.state_persistence = persistence;
+                let next_function = hash_context.ring.get(&uuid.qid).unwrap().to_string();
+                client.fail_next(&next_function, failures);
+                invoke_next_functions(
+                    &ctx,
+                    &hash_context,
+                    None,
+                    uuid,
+                    async_metadata(),
+                    None,
+                    vec![vec![int64_batch(vec![1, 2])]],
+                )
+                .await
+                .map(|_| client)
+            }
+        };
+
+        let client = send(StatePersistence::Always, 0).await?;
+        assert_eq!(client.invocations().len(), 1);
+        assert_eq!(client.keys(&bucket).len(), 1);
+
+        let client = send(StatePersistence::Never, 0).await?;
+        assert_eq!(client.invocations().len(), 1);
+        assert!(client.keys(&bucket).is_empty());
+
+        let client = send(StatePersistence::OnFailureOnly, 0).await?;
+        assert_eq!(client.invocations().len(), 1);
+        assert!(client.keys(&bucket).is_empty());
+
+        // The payload is written only if the invocation of the next function fails.
+        let client = send(StatePersistence::OnFailureOnly, 1).await?;
+        assert!(client.invocations().is_empty());
+        let keys = client.keys(&bucket);
+        assert_eq!(keys.len(), 1);
+        let payload: Payload = serde_json::from_slice(&client.object(&bucket, &keys[0]).unwrap())?;
+        assert_eq!(payload.uuid, uuid);
+
+        // The other state backends are written by their own functions.
+        let client = Arc::new(FakeCloudClient::new());
+        let mut ctx = context(
+            "q1-01",
+            CloudFunction::Group(("q1-02".to_string(), 4)),
+            memory_plan(),
+            client.clone(),
+        );
+        ctx.state_persistence = StatePersistence::Always;
+        assert_eq!(state_persistence(&ctx), StatePersistence::Never);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn peek_output_of_stage() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let next = CloudFunction::Lambda("q7-02".to_string());
+        let hash_context = ConsistentHashContext::new(&next);
+        let ctx = context("q7-01", next, memory_plan(), client.clone());
+        let peeks = Peeks::new(client.clone(), &FLOCK_S3_BUCKET);
+        let marker = PeekMarker {
+            rows:      2,
+            timestamp: 1,
+        };
+        peeks.request("q7", 1, &marker).await?;
+
+        let uuid = UuidBuilder::new_with_ts("q7-00", 1, 1).next_uuid();
+        let output = vec![
+            vec![int64_batch(vec![1, 2, 3])],
+            vec![int64_batch(vec![4, 5])],
+        ];
+        invoke_next_functions(
+            &ctx,
+            &hash_context,
+            None,
+            uuid.clone(),
+            async_metadata(),
+            None,
+            output,
+        )
+        .await?;
+
+        // The sample holds the first rows of the window.
+        assert_eq!(peeks.windows("q7", 1).await?, vec![uuid.qid.clone()]);
+        assert_eq!(
+            peeks.read_sample("q7", 1, &uuid.qid).await?,
+            vec![int64_batch(vec![1, 2])]
+        );
+
+        // The next function still receives the whole output.
+        let invocations = client.invocations();
+        assert_eq!(invocations.len(), 1);
+        let payload = invocations[0].payload()?;
+        assert_eq!(payload.uuid, uuid);
+        assert_eq!(
+            payload.to_record_batch()?.0,
+            vec![int64_batch(vec![1, 2, 3]), int64_batch(vec![4, 5])]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn broadcast_side_input_to_probes() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let uuids = UuidBuilder::new_with_ts("q7-00", 1, 2);
+        let uuid = uuids.get(2);
+
+        // The stash stage keeps the partitions of the window in S3.
+        let partitions = [vec![1, 5, 3], vec![4, 2]];
+        let mut events = vec![];
+        for (i, values) in partitions.iter().enumerate() {
+            let event = to_payload(&[int64_batch(values.clone())], &[], uuids.get(i + 1), false)?;
+            stash_payload(client.as_ref(), &event).await?;
+            events.push(event);
+        }
+        let window_id = events[0].get_window_id();
+        assert!(client
+            .object(&FLOCK_S3_BUCKET, &stash_key(&window_id, 2))
+            .is_some());
+
+        // The broadcast stage writes the global maximum as the side input of
+        // the window, and invokes the probe function for each partition.
+        let max_schema = Arc::new(Schema::new(vec![Field::new("m", DataType::Int64, true)]));
+        let max_plan = Arc::new(MemoryExec::try_new(&[vec![]], max_schema.clone(), None)?);
+        let next = CloudFunction::Lambda("q7-02".to_string());
+        let ctx = context("q7-01-00", next, max_plan, client.clone());
+        let max = RecordBatch::try_new(max_schema, vec![Arc::new(Int64Array::from(vec![5]))])?;
+        broadcast_side_input(
+            &ctx,
+            Some(7),
+            &uuid,
+            &window_id,
+            async_metadata(),
+            vec![vec![max]],
+        )
+        .await?;
+        assert_eq!(
+            client.object(&FLOCK_S3_BUCKET, &side_input_key(&window_id)),
+            Some(b"m\n5\n".to_vec())
+        );
+
+        let invocations = client.invocations();
+        assert_eq!(invocations.len(), 2);
+        let mut ctx = context(
+            "q7-02",
+            CloudFunction::Sink(DataSinkType::Blackhole),
+            memory_plan(),
+            client.clone(),
+        );
+        let mut rows = HashMap::new();
+        for invocation in &invocations {
+            assert_eq!(invocation.function, "q7-02");
+            let payload = invocation.payload()?;
+            assert_eq!(payload.query_number, Some(7));
+            assert_eq!(payload.uuid.qid, uuid.qid);
+            let seq_num = payload.uuid.seq_num;
+
+            // The probe function reads its partition and the side input.
+            let mut arena = Arena::new();
+            let (input, status) = prepare_data_sources(&ctx, &mut arena, payload).await?;
+            assert!(status == HashAggregateStatus::Ready);
+            rows.insert(seq_num, num_rows(&input[0][0]));
+            let side_input = &input.last().unwrap()[0];
+            let max = side_input[0]
+                .column(0)
+                .as_any()
+                .downcast_ref::<Int64Array>()
+                .unwrap();
+            assert_eq!(max.values(), &[5]);
+        }
+        assert_eq!(rows, HashMap::from([(1, 3), (2, 2)]));
+
+        // The broadcast stage can't fan out to a group of functions.
+        ctx.next = CloudFunction::Group(("q7-02".to_string(), 2));
+        assert!(
+            broadcast_side_input(&ctx, None, &uuid, &window_id, None, vec![])
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_to_data_sinks() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let next = CloudFunction::Sink(DataSinkType::S3);
+        let hash_context = ConsistentHashContext::new(&next);
+        let ctx = context("q1-02", next, memory_plan(), client.clone());
+        let uuid = UuidBuilder::new_with_ts("q1-00", 1, 1).next_uuid();
+        let output = vec![vec![int64_batch(vec![1, 2])], vec![int64_batch(vec![3])]];
+
+        let value = invoke_next_functions(
+            &ctx,
+            &hash_context,
+            None,
+            uuid.clone(),
+            async_metadata(),
+            None,
+            output.clone(),
+        )
+        .await?;
+        assert_eq!(value["status"], "success");
+        assert!(client.invocations().is_empty());
+        let body = client.object(&FLOCK_S3_BUCKET, &sink_key("q1")).unwrap();
+        let sink: DataSink = serde_json::from_slice(&body)?;
+        assert_eq!(sink.function_name, "q1-02");
+        assert!(!sink.encoded_data.is_empty());
+
+        // The results of the window are kept for the results server.
+        let batches = stored_window(client.clone(), "q1", &window_id(&uuid, None)).await?;
+        assert_eq!(num_rows(&batches), 3);
+
+        // The results are returned inline to the synchronous caller.
+        let client = Arc::new(FakeCloudClient::new());
+        let next = CloudFunction::Sink(DataSinkType::Response);
+        let ctx = context("q1-02", next, memory_plan(), client.clone());
+        let value =
+            invoke_next_functions(&ctx, &hash_context, None, uuid, None, None, output).await?;
+        assert_eq!(value["truncated"], false);
+        assert!(client.keys(&FLOCK_S3_BUCKET).is_empty());
+        let sink = DataSink::from_response(value).await?;
+        assert_eq!(num_rows(&sink.record_batches), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reuse_cached_results() -> Result<()> {
+        let uuid = UuidBuilder::new_with_ts("qrc-00", 1, 1).next_uuid();
+        let mut payload = to_payload(&[int64_batch(vec![1, 2, 3])], &[], uuid.clone(), false)?;
+        payload.metadata = async_metadata();
+
+        // Runs the window with a fresh function, and returns its sink output.
+        let run = |plan_hash: &str| {
+            let client = Arc::new(FakeCloudClient::new());
+            let next = CloudFunction::Sink(DataSinkType::S3);
+            let mut ctx = context("qrc-01", next, memory_plan(), client.clone());
+            ctx.result_cache = Some(plan_hash.to_string());
+            let payload = payload.clone();
+            let uuid = uuid.clone();
+            async move {
+                handler(&ctx, &mut Arena::new(), payload).await?;
+                stored_window(client, "qrc", &window_id(&uuid, None)).await
+            }
+        };
+        let plan_hash = result_cache::plan_hash(&[memory_plan()]);
+
+        let cached = CACHED_WINDOWS.load(Ordering::Relaxed);
+        let first = run(&plan_hash).await?;
+        assert_eq!(CACHED_WINDOWS.load(Ordering::Relaxed), cached);
+        assert_eq!(num_rows(&first), 3);
+
+        // The second run of the same window skips the execution.
+        let second = run(&plan_hash).await?;
+        assert_eq!(CACHED_WINDOWS.load(Ordering::Relaxed), cached + 1);
+        assert_eq!(first[0].columns(), second[0].columns());
+
+        // The output cached by another plan isn't reused.
+        let third = run("another plan").await?;
+        assert_eq!(CACHED_WINDOWS.load(Ordering::Relaxed), cached + 1);
+        assert_eq!(first[0].columns(), third[0].columns());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_window_columns() -> Result<()> {
+        use flock::datasink::enrich::{split_by_window, WindowBounds, QUERY_CODE_COLUMN};
+
+        let client = Arc::new(FakeCloudClient::new());
+        let next = CloudFunction::Sink(DataSinkType::S3);
+        let hash_context = ConsistentHashContext::new(&next);
+        let mut ctx = context("q1-02", next, memory_plan(), client.clone());
+        ctx.window = Some(Window::Tumbling(Schedule::Seconds(10)));
+        ctx.window_columns = true;
+        let uuid = UuidBuilder::new_with_ts("q1-00", 1650000000, 1).next_uuid();
+        let output = vec![vec![int64_batch(vec![1, 2])], vec![int64_batch(vec![3])]];
+
+        invoke_next_functions(
+            &ctx,
+            &hash_context,
+            None,
+            uuid.clone(),
+            async_metadata(),
+            None,
+            output,
+        )
+        .await?;
+        let batches = stored_window(client, "q1", &window_id(&uuid, None)).await?;
+        assert!(batches[0].schema().index_of(QUERY_CODE_COLUMN).is_ok());
+        let windows = split_by_window(&batches)?;
+        let bounds = WindowBounds {
+            start: Some(1_649_999_990_000),
+            end:   1_650_000_000_000,
+        };
+        assert_eq!(windows.keys().collect::<Vec<_>>(), vec![&bounds]);
+        assert_eq!(num_rows(&windows[&bounds]), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn multiplex_queries_on_shared_functions() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let next = CloudFunction::Lambda("mux_test-02".to_string());
+        let hash_context = ConsistentHashContext::new(&next);
+
+        // Two queries of the same topology with different plans of the stage.
+        let limit_plan: Arc<dyn ExecutionPlan> = Arc::new(LocalLimitExec::new(memory_plan(), 1));
+        let queries = [("mux_q1", memory_plan()), ("mux_q2", limit_plan)];
+        let mut fragments = HashMap::new();
+        for (query_code, plan) in &queries {
+            let stages = [
+                ("mux_test-00", memory_plan()),
+                ("mux_test-01", plan.clone()),
+            ]
+            .into_iter()
+            .map(|(name, plan)| {
+                let ctx = context(name, next.clone(), plan, client.clone());
+                context::marshal(&ctx, Encoding::default())
+            })
+            .collect::<Result<Vec<_>>>()?;
+            let mut metadata = async_metadata().unwrap();
+            QueryContexts {
+                query_code: query_code.to_string(),
+                stages,
+                first: 0,
+            }
+            .attach(&mut metadata)?;
+
+            // The windows of both queries are emitted by the same source function.
+            let mut uuids = UuidBuilder::new_with_ts("mux_test-00", 1, 2);
+            let payloads = [vec![1, 2], vec![3, 4]]
+                .into_iter()
+                .map(|values| {
+                    let mut payload =
+                        to_payload(&[int64_batch(values)], &[], uuids.next_uuid(), false)?;
+                    payload.metadata = Some(metadata.clone());
+                    payload
+                })
+                .collect::<Vec<_>>();
+            fragments.insert(*query_code, payloads);
+        }
+
+        // The shared aggregator was deployed with the context of the first query.
+        let env_ctx = Arc::new(context(
+            "mux_test-01-00",
+            next.clone(),
+            memory_plan(),
+            client.clone(),
+        ));
+
+        // The fragments of the windows of both queries arrive interleaved.
+        let mut arena = Arena::new();
+        for seq_num in 0..2 {
+            for (query_code, _) in &queries {
+                let mut event = fragments[query_code][seq_num].clone();
+                let mut ctx =
+                    (*resolve_exec_context(env_ctx.clone(), &mut event.metadata).await?).clone();
+                assert_eq!(ctx.name, "mux_test-01-00");
+                assert_eq!(ctx.query_code(), *query_code);
+                ctx.cloud_client = client.clone();
+
+                let uuid = event.uuid.clone();
+                let metadata = event.metadata.clone();
+                let (input, status) = prepare_data_sources(&ctx, &mut arena, event).await?;
+                if seq_num == 0 {
+                    assert!(status == HashAggregateStatus::NotReady);
+                    continue;
+                }
+                assert!(status == HashAggregateStatus::Ready);
+                let output = collect(&ctx, input).await?;
+                invoke_next_functions(&ctx, &hash_context, None, uuid, metadata, None, output)
+                    .await?;
+            }
+        }
+        assert!(arena.is_empty());
+
+        // Each query got the results of its own plan over its own window, and
+        // the next stage receives the contexts of the query after its own stage.
+        let mut rows = HashMap::new();
+        for invocation in client.invocations() {
+            assert_eq!(invocation.function, "mux_test-02");
+            let payload = invocation.payload()?;
+            let contexts = QueryContexts::from_metadata(&payload.metadata)?.unwrap();
+            assert_eq!(contexts.first, 2);
+            assert!(contexts.stages.is_empty());
+            *rows.entry(contexts.query_code).or_insert(0) +=
+                num_rows(&payload.to_record_batch()?.0);
+        }
+        assert_eq!(
+            rows,
+            HashMap::from([("mux_q1".to_string(), 4), ("mux_q2".to_string(), 2)])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flush_partial_window() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let next = CloudFunction::Lambda("flush-02".to_string());
+        let ring =
+            ConsistentHashContext::new(&CloudFunction::Group(("flush-01".to_string(), 2))).ring;
+        let mut contexts = HashMap::new();
+        let mut arenas = HashMap::new();
+        for name in ["flush-01-00", "flush-01-01"] {
+            let ctx = context(name, next.clone(), memory_plan(), client.clone());
+            contexts.insert(name.to_string(), ctx);
+            arenas.insert(name.to_string(), Arena::new());
+        }
+
+        // The data source stops in the middle of the third window: 2.5 windows
+        // of 4 seconds, with an event per second.
+        let (seconds, window_size) = (10, 4);
+        let mut sent = vec![];
+        for time in 0..(seconds + window_size - 1) / window_size {
+            let start = time * window_size;
+            let end = std::cmp::min(start + window_size, seconds);
+            let window = (start..end)
+                .map(|t| (vec![vec![int64_batch(vec![t as i64])]], vec![]))
+                .collect::<Vec<(RelationPartitions, RelationPartitions)>>();
+            let size = window_len(&window);
+            let seq_len = expected_len(size, end - start, window_size);
+            let mut uuid_builder = UuidBuilder::new_with_ts("flush-01", time as i64, seq_len);
+            let function_name = ring.get(&uuid_builder.qid).unwrap().to_string();
+            for payload in window_payloads(&window, &mut uuid_builder, false)? {
+                sent.push((function_name.clone(), payload));
+            }
+            if seq_len > size {
+                assert_eq!((size, seq_len), (2, 4));
+                sent.extend(flush_payloads(&ring, &uuid_builder.qid, size, false));
+            }
+        }
+        assert_eq!(sent.len(), 4 + 4 + 2 + 2);
+
+        for (function_name, payload) in sent {
+            let ctx = contexts.get_mut(&function_name).unwrap();
+            let arena = arenas.get_mut(&function_name).unwrap();
+            handler(ctx, arena, payload).await?;
+        }
+        assert!(arenas.values().all(|arena| arena.is_empty()));
+
+        // All three windows are emitted, and the last one is marked partial.
+        let mut windows = client
+            .invocations()
+            .iter()
+            .map(|invocation| {
+                assert_eq!(invocation.function, "flush-02");
+                let payload = invocation.payload()?;
+                let flushed = payload
+                    .metadata
+                    .as_ref()
+                    .and_then(|m| m.get(FLUSHED_METADATA_KEY).cloned());
+                assert!(payload
+                    .metadata
+                    .as_ref()
+                    .map_or(true, |m| !m.contains_key(FLUSH_METADATA_KEY)));
+                Ok((num_rows(&payload.to_record_batch()?.0), flushed))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        windows.sort();
+        assert_eq!(
+            windows,
+            vec![(2, Some("true".to_string())), (4, None), (4, None)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upgrade_function_mid_window() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let next = CloudFunction::Lambda("qdrain-02".to_string());
+        let name = "qdrain-01-00";
+        let mut uuids = UuidBuilder::new_with_ts("qdrain-00", 1, 4);
+        let payloads = (1..=4)
+            .map(|i| {
+                let mut payload =
+                    to_payload(&[int64_batch(vec![i])], &[], uuids.next_uuid(), false)?;
+                payload.metadata = async_metadata();
+                payload
+            })
+            .collect::<Vec<_>>();
+
+        // The old binary receives half of the window, and drains.
+        let ctx = context(name, next.clone(), memory_plan(), client.clone());
+        let mut arena = Arena::new();
+        for payload in &payloads[..2] {
+            handler(&ctx, &mut arena, payload.clone()).await?;
+        }
+        let ack = handler(&ctx, &mut arena, snapshot::drain_payload("qdrain")).await?;
+        assert_eq!(ack.into_data()["drained"]["windows"], 1);
+        assert!(arena.is_empty());
+
+        // The payloads that still reach the old binary go to the snapshot.
+        let response = handler(&ctx, &mut arena, payloads[2].clone()).await?;
+        assert_eq!(response.into_data()["draining"], true);
+        assert!(arena.is_empty());
+
+        // The new binary starts with an empty arena, and restores the snapshot
+        // on its first invocation.
+        DRAINING.lock().unwrap().remove(name);
+        RESTORED.lock().unwrap().remove(name);
+        let ctx = context(name, next, memory_plan(), client.clone());
+        let mut arena = Arena::new();
+        assert!(client.invocations().is_empty());
+        handler(&ctx, &mut arena, payloads[3].clone()).await?;
+        assert!(arena.is_empty());
+
+        // The window is completed across the upgrade.
+        let invocations = client.invocations();
+        assert_eq!(invocations.len(), 1);
+        assert_eq!(invocations[0].function, "qdrain-02");
+        assert_eq!(num_rows(&invocations[0].payload()?.to_record_batch()?.0), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn respond_with_window_status() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let next = CloudFunction::Lambda("qst-02".to_string());
+        let ctx = context("qst-01-00", next, memory_plan(), client.clone());
+        let mut arena = Arena::new();
+        let mut uuids = UuidBuilder::new_with_ts("qst-00", 1, 2);
+        let payloads = (1..=2)
+            .map(|i| {
+                let mut payload =
+                    to_payload(&[int64_batch(vec![i])], &[], uuids.next_uuid(), false)?;
+                payload.metadata = async_metadata();
+                payload
+            })
+            .collect::<Vec<_>>();
+        let window = window_id(&payloads[0].uuid, None);
+
+        let response = handler(&ctx, &mut arena, payloads[0].clone()).await?;
+        assert_eq!(response.status, Status::NotReady);
+        assert_eq!(response.function, "qst-01-00");
+        assert_eq!(response.window.as_ref(), Some(&window));
+        assert_eq!(response.uuid.as_ref(), Some(&payloads[0].uuid));
+        let data = response.into_data();
+        assert_eq!(data["arena"]["windows"].as_array().unwrap().len(), 1);
+
+        // The payload delivered again is a duplicate.
+        let response = handler(&ctx, &mut arena, payloads[0].clone()).await?;
+        assert_eq!(response.status, Status::Duplicate);
+        assert!(response.data.is_none());
+
+        let response = handler(&ctx, &mut arena, payloads[1].clone()).await?;
+        assert!(response.is_ok());
+        assert_eq!(response.window, Some(window));
+        assert_eq!(client.invocations().len(), 1);
+        Ok(())
+    }
+
+    /// The functions of a two-stage query in the simulator: a function that
+    /// forwards each payload to an aggregate function, which writes the windows
+    /// to the S3 data sink.
+    fn chaos_pipeline(
+        query_code: &str,
+        client: Arc<FakeCloudClient>,
+    ) -> HashMap<String, (ExecutionContext, Arena)> {
+        let source = format!("{}-00", query_code);
+        let aggregate = format!("{}-01-00", query_code);
+        let next = CloudFunction::Lambda(aggregate.clone());
+        vec![
+            context(&source, next, memory_plan(), client.clone()),
+            context(
+                &aggregate,
+                CloudFunction::Sink(DataSinkType::S3),
+                memory_plan(),
+                client,
+            ),
+        ]
+        .into_iter()
+        .map(|ctx| (ctx.name.clone(), (ctx, Arena::new())))
+        .collect()
+    }
+
+    /// Returns the metadata of the asynchronous invocations with the chaos spec
+    /// and the deadline.
+    fn chaos_metadata(spec: &ChaosSpec, deadline: Deadline) -> Result<Option<QueryMetadata>> {
+        let mut metadata = async_metadata();
+        spec.stamp(metadata.as_mut().unwrap())?;
+        deadline.stamp(&mut metadata);
+        Ok(metadata)
+    }
+
+    /// Simulates the asynchronous invocations of the functions. The payloads
+    /// are delivered in the order of the invocations, and the invocations of
+    /// the functions are delivered in turn. A failed invocation is retried, as
+    /// AWS Lambda does, up to `max_attempts` times in all. Returns the
+    /// responses of the invocations and the number of the failed ones.
+    async fn simulate(
+        functions: &mut HashMap<String, (ExecutionContext, Arena)>,
+        client: &FakeCloudClient,
+        payloads: Vec<(String, Payload)>,
+        max_attempts: usize,
+    ) -> Result<(Vec<Response>, usize)> {
+        let mut queue = payloads
+            .into_iter()
+            .map(|(function, payload)| (function, payload, 1))
+            .collect::<VecDeque<_>>();
+        let mut responses = vec![];
+        let mut failures = 0;
+        let mut delivered = client.invocations().len();
+        while let Some((function, payload, attempt)) = queue.pop_front() {
+            let (ctx, arena) = functions.get_mut(&function).unwrap();
+            match handler(ctx, arena, payload.clone()).await {
+                Ok(response) => responses.push(response),
+                Err(e) => {
+                    assert!(attempt < max_attempts, "{} failed: {:?}", function, e);
+                    failures += 1;
+                    queue.push_back((function, payload, attempt + 1));
+                }
+            }
+            let invocations = client.invocations();
+            for invocation in &invocations[delivered..] {
+                queue.push_back((invocation.function.clone(), invocation.payload()?, 1));
+            }
+            delivered = invocations.len();
+        }
+        Ok((responses, failures))
+    }
+
+    /// Returns the number of the responses of the function with the status.
+    fn count_status(responses: &[Response], function: &str, status: Status) -> usize {
+        responses
+            .iter()
+            .filter(|r| r.function == function && r.status == status)
+            .count()
+    }
+
+    #[tokio::test]
+    async fn chaos_duplicate_aggregate_payloads() -> Result<()> {
+        std::env::set_var(FLOCK_CHAOS, "1");
+        let client = Arc::new(FakeCloudClient::new());
+        let mut functions = chaos_pipeline("qcdup", client.clone());
+        let spec = ChaosSpec {
+            duplicate: 1.0,
+            ..Default::default()
+        };
+        let deadline = Deadline::after(&SystemClock, Duration::from_secs(60));
+        let metadata = chaos_metadata(&spec, deadline)?;
+
+        let mut payloads = vec![];
+        let mut windows = vec![];
+        for time in 0..4 {
+            let mut uuids = UuidBuilder::new_with_ts("qcdup-00", time, 4);
+            for i in 0..4 {
+                let uuid = uuids.next_uuid();
+                let mut payload = to_payload(&[int64_batch(vec![time * 4 + i])], &[], uuid, false)?;
+                payload.metadata = metadata.clone();
+                payloads.push(("qcdup-00".to_string(), payload));
+            }
+            windows.push(window_id(&payloads.last().unwrap().1.uuid, None));
+        }
+        let (responses, failures) = simulate(&mut functions, &client, payloads, 1).await?;
+        assert_eq!(failures, 0);
+
+        // Every payload reaches the aggregate function twice, and the copies
+        // are dropped, so each window is emitted exactly once.
+        assert_eq!(client.invocations().len(), 32);
+        assert_eq!(count_status(&responses, "qcdup-01-00", Status::Ok), 4);
+        assert_eq!(
+            count_status(&responses, "qcdup-01-00", Status::NotReady),
+            12
+        );
+        assert_eq!(
+            count_status(&responses, "qcdup-01-00", Status::Duplicate),
+            16
+        );
+        for window in &windows {
+            let output = stored_window(client.clone(), "qcdup", window).await?;
+            assert_eq!(num_rows(&output), 4);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chaos_invoke_failures() -> Result<()> {
+        std::env::set_var(FLOCK_CHAOS, "1");
+        let client = Arc::new(FakeCloudClient::new());
+        let mut functions = chaos_pipeline("qcfail", client.clone());
+        let spec = ChaosSpec {
+            invoke_failure: 0.1,
+            ..Default::default()
+        };
+        let deadline = Deadline::after(&SystemClock, Duration::from_secs(60));
+        let metadata = chaos_metadata(&spec, deadline)?;
+
+        let mut payloads = vec![];
+        let mut windows = vec![];
+        for time in 0..64 {
+            let mut uuids = UuidBuilder::new_with_ts("qcfail-00", time, 2);
+            for i in 0..2 {
+                let uuid = uuids.next_uuid();
+                let mut payload = to_payload(&[int64_batch(vec![time * 2 + i])], &[], uuid, false)?;
+                payload.metadata = metadata.clone();
+                payloads.push(("qcfail-00".to_string(), payload));
+            }
+            windows.push(window_id(&payloads.last().unwrap().1.uuid, None));
+        }
+        let (responses, failures) = simulate(&mut functions, &client, payloads, 10).await?;
+
+        // The failed invocations are retried, and all windows complete once
+        // before the deadline.
+        assert!(failures > 0);
+        assert_eq!(count_status(&responses, "qcfail-01-00", Status::Ok), 64);
+        assert!(deadline.allows(&SystemClock, Duration::from_secs(0)));
+        for window in &windows {
+            let output = stored_window(client.clone(), "qcfail", window).await?;
+            assert_eq!(num_rows(&output), 2);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chaos_delayed_state_reads() -> Result<()> {
+        std::env::set_var(FLOCK_CHAOS, "1");
+        let client = Arc::new(FakeCloudClient::new());
+        let ctx = context(
+            "qcread-00",
+            CloudFunction::Sink(DataSinkType::S3),
+            memory_plan(),
+            client.clone(),
+        );
+        let mut functions = HashMap::new();
+        functions.insert(ctx.name.clone(), (ctx, Arena::new()));
+        let spec = ChaosSpec {
+            s3_not_found: 0.3,
+            latency: Some(Latency {
+                min_ms: 1,
+                max_ms: 3,
+            }),
+            ..Default::default()
+        };
+        let deadline = Deadline::after(&SystemClock, Duration::from_secs(60));
+
+        // The payloads of the windows are in S3, and the invocations only
+        // point to them.
+        let mut payloads = vec![];
+        for time in 0..40 {
+            let uuid = UuidBuilder::new_with_ts("qcread-00", time, 1).next_uuid();
+            let key = format!("qcread/{}", time);
+            let payload = to_payload(&[int64_batch(vec![time, time])], &[], uuid.clone(), false)?;
+            client.put_object("inputs", &key, serde_json::to_vec(&payload)?);
+            let mut metadata = chaos_metadata(&spec, deadline)?;
+            metadata.as_mut().unwrap().s3 = Some(S3Pointer {
+                bucket: "inputs".to_string(),
+                key,
+            });
+            let event = Payload {
+                uuid,
+                metadata,
+                ..Default::default()
+            };
+            payloads.push(("qcread-00".to_string(), event));
+        }
+        let start = Instant::now();
+        let (responses, failures) = simulate(&mut functions, &client, payloads.clone(), 20).await?;
+
+        // The reads that are delayed or not found yet fail the invocations,
+        // which recover on their retries.
+        assert!(failures > 0);
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert_eq!(count_status(&responses, "qcread-00", Status::Ok), 40);
+        assert!(deadline.allows(&SystemClock, Duration::from_secs(0)));
+        for (_, payload) in &payloads {
+            let window = window_id(&payload.uuid, None);
+            let output = stored_window(client.clone(), "qcread", &window).await?;
+            assert_eq!(num_rows(&output), 2);
+        }
+        Ok(())
+    }
+}
@@ -17,10 +17,10 @@ use flock::datasource::nexmark::event::{Auction, Bid};
 use flock::datasource::nexmark::register_nexmark_tables;
 use flock::datasource::nexmark::NEXMarkSource;
 use flock::prelude::*;
-use log::info;
 use serde_json::Value;
 use std::sync::Arc;
 use std::time::Instant;
+use tracing::info;
 
 macro_rules! eval_operator {
     ($CTX: ident, $EVENTS: ident, $OPERATOR:literal, $PATH:literal) => {
@@ -74,7 +74,7 @@ macro_rules! eval_operator {
 ///
 /// # Returns
 /// A JSON object that contains the return value of the function invocation.
-pub async fn handler(ctx: &mut ExecutionContext, payload: Payload) -> Result<Value> {
+pub async fn handler(ctx: &ExecutionContext, payload: Payload) -> Result<Value> {
     let events_per_second = match payload.datasource.clone() {
         DataSource::Arch(events) => events,
         _ => unreachable!(),
@@ -95,6 +95,10 @@ pub async fn handler(ctx: &mut ExecutionContext, payload: Payload) -> Result<Val
 
     info!("[OK] Generated nexmark events.");
 
+    // The operators are evaluated with their own plans in a copy of the context
+    // shared by the invocations.
+    let mut ctx = ctx.clone();
+
     // 1. filter operators
     eval_operator!(ctx, events, "filter", "./ops/filter.sql");
 
@@ -116,7 +120,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_arch_benchmark() -> Result<()> {
-        let mut ctx = ExecutionContext {
+        let ctx = ExecutionContext {
             plan: CloudExecutionPlan::new(vec![FLOCK_EMPTY_PLAN.clone()], None),
             name: FLOCK_DATA_SOURCE_FUNC_NAME.clone(),
             next: CloudFunction::Sink(DataSinkType::Blackhole),
@@ -128,7 +132,7 @@ mod tests {
             ..Default::default()
         };
 
-        handler(&mut ctx, payload).await?;
+        handler(&ctx, payload).await?;
 
         Ok(())
     }
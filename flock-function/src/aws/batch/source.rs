@@ -0,0 +1,169 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The entry point for the batch queries over the S3 objects, which are run
+//! once by `run_query` or periodically by an EventBridge schedule (see
+//! [`flock::runtime::schedule`]).
+
+use crate::actor::infer_invocation_type;
+use crate::ConsistentHashContext;
+use chrono::Utc;
+use flock::prelude::*;
+use flock::runtime::completion::{generator_index, is_completion, SourceReport};
+use flock::runtime::schedule::{prune_keys, ScanRange};
+use serde_json::{json, Value};
+use tracing::info;
+
+/// The endpoint of the source function of a batch query. The objects whose
+/// time partitions overlap the scanned range of the payload are read, and
+/// each object is forwarded to the next function as a fragment of the same
+/// window.
+///
+/// # Arguments
+/// * `ctx` - The runtime context of the function.
+/// * `payload` - The payload of the function.
+///
+/// # Returns
+/// A JSON object with the number of objects and rows that were scanned.
+pub async fn handler(ctx: &ExecutionContext, payload: Payload) -> Result<Value> {
+    let source = match &payload.datasource {
+        DataSource::S3Objects(source) => source.clone(),
+        _ => unreachable!(),
+    };
+
+    let range = ScanRange::from_metadata(&payload.metadata, Utc::now())?;
+    let mut keys = source.list_keys(ctx.cloud_client.as_ref()).await?;
+    if let Some(range) = &range {
+        keys = prune_keys(keys, range);
+        info!(
+            "Scanning s3://{}/{} from {} to {}",
+            source.bucket, source.prefix, range.start, range.end
+        );
+    }
+    let partitions = source.fetch_keys(ctx.cloud_client.as_ref(), &keys).await?;
+    let rows = partitions
+        .iter()
+        .flatten()
+        .map(|b| b.num_rows())
+        .sum::<usize>();
+    info!("[OK] Read {} rows from {} objects.", rows, keys.len());
+
+    if is_completion(&payload.metadata) {
+        let report = SourceReport {
+            generators: 1,
+            windows:    Some(1),
+        };
+        report
+            .report(ctx.query_code(), generator_index(&payload.metadata))
+            .await?;
+    }
+
+    let hash_context = ConsistentHashContext::new(&ctx.next);
+    let sync = infer_invocation_type(&payload.metadata)?;
+    let invocation_type = if sync {
+        FLOCK_LAMBDA_SYNC_CALL.to_string()
+    } else {
+        FLOCK_LAMBDA_ASYNC_CALL.to_string()
+    };
+
+    // The window of a scheduled run is named after the end of its range, so
+    // that a retried run is aggregated into the same window.
+    let timestamp = range.map(|r| r.end).unwrap_or_else(Utc::now).timestamp();
+    let mut uuid_builder =
+        UuidBuilder::new_with_ts(&hash_context.group_name, timestamp, partitions.len());
+    for batches in &partitions {
+        let uuid = uuid_builder.next_uuid();
+        let function_name = hash_context
+            .ring
+            .get(&uuid.qid)
+            .ok_or_else(|| FlockError::Execution(format!("{} has no next function", ctx.name)))?
+            .to_string();
+        let mut payload_out = to_payload(batches, &[], uuid, sync)?;
+        payload_out.query_number = payload.query_number;
+        payload_out.metadata = payload.metadata.clone();
+        let bytes = serde_json::to_vec(&payload_out)?;
+        info!(
+            "[OK] {} function's payload bytes: {}",
+            function_name,
+            bytes.len()
+        );
+        ctx.cloud_client
+            .invoke(&function_name, &invocation_type, bytes)
+            .await?;
+    }
+
+    Ok(json!({
+        "objects": keys.len(),
+        "rows": rows,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flock::aws::client::FakeCloudClient;
+    use flock::datasource::s3::{S3ObjectCompression, S3ObjectFormat, S3ObjectsSource};
+    use flock::runtime::schedule::scheduled_input;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn scheduled_run() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        for hour in 0..3 {
+            let rows = (0..hour + 1)
+                .map(|i| format!("{{\"hour\": {}, \"seq\": {}}}", hour, i))
+                .collect::<Vec<_>>()
+                .join("\n");
+            client.put_object(
+                "clicks",
+                &format!("logs/2021/10/01/{:02}/part-0.json", hour),
+                rows.into_bytes(),
+            );
+        }
+
+        // The EventBridge rule fires at 02:00 and scans the last hour.
+        let source = S3ObjectsSource::new(
+            "clicks",
+            "logs/",
+            S3ObjectFormat::Ndjson,
+            S3ObjectCompression::None,
+        );
+        let (_, template) = scheduled_input(&source, Duration::from_secs(3600))?;
+        let event = template.replace("<time>", "2021-10-01T02:00:00Z");
+        let mut payload: Payload = serde_json::from_str(&event)?;
+        payload.metadata.as_mut().unwrap().invocation_type =
+            Some(flock::runtime::metadata::InvocationType::Async);
+
+        let ctx = ExecutionContext {
+            name: "q1-00".to_string(),
+            next: CloudFunction::Lambda("q1-01".to_string()),
+            cloud_client: client.clone(),
+            ..Default::default()
+        };
+        let value = handler(&ctx, payload).await?;
+        assert_eq!(value["objects"], 1);
+        assert_eq!(value["rows"], 2);
+
+        // Only the rows of hour 01 reach the next function.
+        let invocations = client.invocations();
+        assert_eq!(invocations.len(), 1);
+        assert_eq!(invocations[0].function, "q1-01");
+        assert_eq!(invocations[0].invocation_type, *FLOCK_LAMBDA_ASYNC_CALL);
+        let payload = invocations[0].payload()?;
+        assert_eq!(payload.uuid.seq_len, 1);
+        let batches = payload.to_record_batch()?.0;
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 2);
+        Ok(())
+    }
+}
@@ -11,157 +11,315 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+//! The execution contexts of the cloud function instance.
+//!
+//! The execution context is deserialized from the cloud environment only once
+//! per container, and shared by all invocations of the function instance. The
+//! contexts are keyed by the hash of the serialized context, so that the same
+//! function binary can serve different query stages after the environment is
+//! updated.
+//!
+//! The functions shared by the multiplexed queries receive the contexts of
+//! their query stages in the payloads instead (see
+//! [`flock::runtime::multiplex`]), which are cached by query code.
+//!
+//! The function that runs a stage embedded in the binary takes the plans of
+//! the stage from the binary, and the context in the environment carries no
+//! plans (see [`flock::runtime::embedded`]).
+
 use flock::prelude::*;
+use flock::runtime::distribution::SessionAffinity;
+use flock::runtime::embedded::{embed, embedded_stage};
+use flock::runtime::function_name::FunctionName;
+use flock::runtime::metadata::WORKERS_METADATA_KEY;
+use flock::runtime::multiplex::{ContextCache, QueryContexts};
 use hashring::HashRing;
 use lazy_static::lazy_static;
-use std::cell::Cell;
+use once_cell::sync::Lazy;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-use std::sync::Once;
-
-/// Initializes the lambda function once and only once.
-pub static INIT: Once = Once::new();
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{Mutex, RwLock};
+use tracing::info;
 
-thread_local! {
-    /// Is in the testing environment.
-    pub static IS_TESTING: Cell<bool> = Cell::new(false);
-}
+// The plans of the stages embedded at compile time by the build script.
+include!(concat!(env!("OUT_DIR"), "/embedded_plans.rs"));
 
 lazy_static! {
     pub static ref CONTEXT_NAME: String = FLOCK_CONF["lambda"]["environment"].to_string();
 }
 
-/// A wrapper to allow the declaration of the execution context of the lambda
-/// function.
-pub enum CloudFunctionContext {
-    Lambda((Box<ExecutionContext>, Arena)),
-    Uninitialized,
-}
-/// Lambda execution context.
-pub static mut EXECUTION_CONTEXT: CloudFunctionContext = CloudFunctionContext::Uninitialized;
+/// The execution contexts of the function instance, keyed by the hash of the
+/// serialized context in the cloud environment.
+static EXECUTION_CONTEXTS: Lazy<RwLock<HashMap<String, Arc<ExecutionContext>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// The execution contexts of the multiplexed queries served by the function
+/// instance, keyed by query code.
+static QUERY_CONTEXTS: Lazy<Mutex<ContextCache>> =
+    Lazy::new(|| Mutex::new(ContextCache::new(*FLOCK_QUERY_CONTEXTS_CAPACITY)));
+
+/// The window and session states of the function instance.
+pub static ARENA: Lazy<Mutex<Arena>> = Lazy::new(|| Mutex::new(Arena::new()));
+
+/// The consistent hashing contexts of the function instance, keyed by the
+/// next function that their rings route to, so that each context, e.g. of a
+/// multiplexed query, routes to its own next function group.
+static CONSISTENT_HASH_CONTEXTS: Lazy<
+    std::sync::RwLock<HashMap<String, Arc<ConsistentHashContext>>>,
+> = Lazy::new(|| std::sync::RwLock::new(HashMap::new()));
 
-/// A wrapper to allow the declaration of consistent hashing.
-pub enum ConsistentHashContext {
-    Lambda((HashRing<String>, String /* group name */)),
-    Uninitialized,
+/// The consistent hashing ring of the next function (group).
+pub struct ConsistentHashContext {
+    /// The consistent hashing ring to forward the windowed events to the same
+    /// function execution environment.
+    pub ring:       HashRing<String>,
+    /// The function group name.
+    pub group_name: String,
 }
-/// Consistent hashing context.
-pub static mut CONSISTENT_HASH_CONTEXT: ConsistentHashContext =
-    ConsistentHashContext::Uninitialized;
-
-/// Performs an initialization routine once and only once.
-#[macro_export]
-macro_rules! init_exec_context {
-    () => {{
-        unsafe {
-            // Init query executor from the cloud evironment.
-            let init_context = || match std::env::var(&**CONTEXT_NAME) {
-                Ok(s) => {
-                    let ctx = context::unmarshal(&s).unwrap();
-                    let next_function = match &ctx.next {
-                        CloudFunction::Lambda(name) => (name.clone(), 1),
-                        CloudFunction::Group((name, group_size)) => {
-                            (name.clone(), group_size.clone())
-                        }
-                        CloudFunction::Sink(..) => (String::new(), 0),
-                    };
-
-                    // The *consistent hash* technique distributes the data packets in a time window
-                    // to the same function name in the function group. Because each function in the
-                    // function group has a concurrency of *1*, all data packets from the same query
-                    // can be routed to the same function execution environment.
-                    let mut ring: HashRing<String> = HashRing::new();
-                    if next_function.1 == 1 {
-                        ring.add(next_function.0.clone());
-                    } else if next_function.1 > 1 {
-                        (0..next_function.1).for_each(|i| {
-                            ring.add(format!("{}-{:02}", next_function.0, i));
-                        });
-                    }
-                    CONSISTENT_HASH_CONTEXT =
-                        ConsistentHashContext::Lambda((ring, next_function.0));
-                    EXECUTION_CONTEXT = CloudFunctionContext::Lambda((Box::new(ctx), Arena::new()));
-                }
-                Err(_) => {
-                    panic!("No execution context in the cloud environment.");
-                }
-            };
-            if IS_TESTING.with(|t| t.get()) {
-                init_context();
-            } else {
-                INIT.call_once(init_context);
+
+impl ConsistentHashContext {
+    /// Creates the consistent hashing context of the given next function.
+    pub fn new(next_function: &CloudFunction) -> Self {
+        let (group_name, group_size) = match next_function {
+            CloudFunction::Lambda(name) => (name.clone(), 1),
+            CloudFunction::Group((name, size)) => (name.clone(), *size),
+            CloudFunction::Sink(..) => (String::new(), 0),
+        };
+
+        // The *consistent hash* technique distributes the data packets in a time window
+        // to the same function name in the function group. Because each function in the
+        // function group has a concurrency of *1*, all data packets from the same query
+        // can be routed to the same function execution environment.
+        let mut ring: HashRing<String> = HashRing::new();
+        match group_size {
+            0 => {}
+            1 => {
+                // only one function in the function group, the data packets are routed to the
+                // next function.
+                ring.add(group_name.clone());
             }
-            match &mut EXECUTION_CONTEXT {
-                CloudFunctionContext::Lambda((ctx, arena)) => (ctx, arena),
-                CloudFunctionContext::Uninitialized => panic!("Uninitialized execution context!"),
+            _ => {
+                // multiple functions in the function group, the data packets are routed to the
+                // function with the same hash value.
+                (0..group_size).for_each(|i| {
+                    ring.add(format!("{}-{:02}", group_name, i));
+                });
             }
         }
-    }};
+
+        Self { ring, group_name }
+    }
+
+    /// Returns the member of the function group that the bucket of the
+    /// session keys maps to (see [`SessionAffinity`]).
+    pub fn session_member(&self, bucket: usize) -> String {
+        self.ring
+            .get(&SessionAffinity::ring_key(bucket))
+            .expect("hash ring failure.")
+            .to_string()
+    }
+
+    /// Returns the members of the function group.
+    pub fn members(&self) -> Vec<String> {
+        (0..self.ring.len())
+            .filter_map(|i| self.ring.get_by_index(i))
+            .cloned()
+            .collect()
+    }
 }
 
-/// Returns the consistent hash context.
-#[macro_export]
-macro_rules! consistent_hash_context {
-    () => {{
-        unsafe {
-            match &mut CONSISTENT_HASH_CONTEXT {
-                ConsistentHashContext::Uninitialized => {
-                    panic!("Uninitialized consistent hash context.")
-                }
-                ConsistentHashContext::Lambda((ring, group_name)) => (ring, group_name),
-            }
-        }
-    }};
+/// Returns the key of the serialized execution context.
+fn context_key(encoded_ctx: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    encoded_ctx.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Returns the execution context of the given serialized context, which is
+/// deserialized once and only once per function instance.
+///
+/// If the plan is stored in S3, it is loaded during the initialization, and if
+/// the function runs an embedded stage, its plans are taken from the binary.
+pub async fn get_or_init_context(encoded_ctx: &str) -> Result<Arc<ExecutionContext>> {
+    let key = context_key(encoded_ctx);
+    if let Some(ctx) = EXECUTION_CONTEXTS.read().await.get(&key) {
+        return Ok(ctx.clone());
+    }
+
+    // Double-checked locking: another task may have initialized the context
+    // while waiting for the write lock.
+    let mut contexts = EXECUTION_CONTEXTS.write().await;
+    if let Some(ctx) = contexts.get(&key) {
+        return Ok(ctx.clone());
+    }
+
+    let start = Instant::now();
+    let mut ctx = context::unmarshal(encoded_ctx)?;
+    set_flock_region(&ctx.region)?;
+    if let Some(stage) = embedded_stage() {
+        embed(&mut ctx, EMBEDDED_PLANS, &stage).await?;
+    }
+    // Loads the plans and computes their properties once per context.
+    if ctx.plan.object_storage.is_some() || !ctx.plan.execution_plans.is_empty() {
+        ctx.properties().await?;
+    }
+    info!(
+        "Loaded the execution context of {} in {:?}",
+        ctx.name,
+        start.elapsed()
+    );
+
+    let ctx = Arc::new(ctx);
+    contexts.insert(key, ctx.clone());
+    Ok(ctx)
 }
 
-/// Updates consistent hash context.
+/// Returns the execution context of the function from the cloud environment.
+pub async fn init_exec_context() -> Result<Arc<ExecutionContext>> {
+    match std::env::var(&**CONTEXT_NAME) {
+        Ok(s) => get_or_init_context(&s).await,
+        Err(_) => Err(FlockError::Internal(
+            "No execution context in the cloud environment.".to_string(),
+        )),
+    }
+}
+
+/// Returns the execution context of the invocation.
+///
+/// If the payload carries the contexts of a multiplexed query, the context of
+/// the query stage served by the function is unmarshalled once and cached by
+/// query code, and only the contexts of the next stages are left in the
+/// metadata to forward. Otherwise, the context from the cloud environment is
+/// returned.
+///
+/// # Arguments
+/// * `env_ctx` - The execution context from the cloud environment.
+/// * `metadata` - The metadata of the payload.
+pub async fn resolve_exec_context(
+    env_ctx: Arc<ExecutionContext>,
+    metadata: &mut Option<QueryMetadata>,
+) -> Result<Arc<ExecutionContext>> {
+    let contexts = match QueryContexts::from_metadata(metadata)? {
+        Some(contexts) => contexts,
+        None => return Ok(env_ctx),
+    };
+    let plan_index = FunctionName::parse(&env_ctx.name)?.plan_index;
+    if let Some(metadata) = metadata.as_mut() {
+        contexts.downstream(plan_index).attach(metadata)?;
+    }
+    let encoded_ctx = contexts.stage(plan_index)?;
+
+    let mut cache = QUERY_CONTEXTS.lock().await;
+    if let Some(ctx) = cache.get(&contexts.query_code, encoded_ctx) {
+        return Ok(ctx);
+    }
+
+    let mut ctx = context::unmarshal(encoded_ctx)?;
+    // The context of the query stage doesn't know which member of the
+    // function group serves it, and the function is named after the shared
+    // functions rather than the query.
+    ctx.name = env_ctx.name.clone();
+    ctx.query_code = Some(contexts.query_code.clone());
+    // Loads the plans and computes their properties once per context.
+    if ctx.plan.object_storage.is_some() || !ctx.plan.execution_plans.is_empty() {
+        ctx.properties().await?;
+    }
+    let ctx = Arc::new(ctx);
+    cache.insert(&contexts.query_code, encoded_ctx, ctx.clone());
+    Ok(ctx)
+}
+
+/// Returns the consistent hash context of the given next function, which is
+/// built once per function instance.
+pub fn consistent_hash_context(next: &CloudFunction) -> Arc<ConsistentHashContext> {
+    let key = serde_json::to_string(next).expect("The next function is serializable.");
+    if let Some(hash_context) = CONSISTENT_HASH_CONTEXTS.read().unwrap().get(&key) {
+        return hash_context.clone();
+    }
+    CONSISTENT_HASH_CONTEXTS
+        .write()
+        .unwrap()
+        .entry(key)
+        .or_insert_with(|| Arc::new(ConsistentHashContext::new(next)))
+        .clone()
+}
+
+/// Returns the next function of the invocation, which is either given by the
+/// metadata of the payload, or by the execution context.
 ///
 /// To make data source generator function work generally, we *cannot*
-/// use `CONSISTENT_HASH_CONTEXT` from cloud environment directly. The cloud
+/// use the next function from cloud environment directly. The cloud
 /// environment is used to specialize the plan for each function (stage
 /// of the query). We WANT to use the same data source function to handle
 /// all benchamrk queries.
-pub fn update_consistent_hash_context(metadata: &Option<HashMap<String, String>>) -> Result<()> {
-    if let Some(metadata) = metadata {
-        if let Some(workers) = metadata.get("workers") {
-            let next_function = serde_json::from_str(workers)?;
-
-            let (group_name, group_size) = match &next_function {
-                CloudFunction::Lambda(name) => (name.clone(), 1),
-                CloudFunction::Group((name, size)) => (name.clone(), *size),
-                CloudFunction::Sink(..) => (String::new(), 0),
-            };
-
-            // The *consistent hash* technique distributes the data packets in a time window
-            // to the same function name in the function group. Because each function in the
-            // function group has a concurrency of *1*, all data packets from the same query
-            // can be routed to the same function execution environment.
-            let mut ring: HashRing<String> = HashRing::new();
-            match group_size {
-                0 => {
-                    unreachable!("group_size should not be 0.");
-                }
-                1 => {
-                    // only one function in the function group, the data packets are routed to the
-                    // next function.
-                    ring.add(group_name.clone());
-                }
-                _ => {
-                    // multiple functions in the function group, the data packets are routed to the
-                    // function with the same hash value.
-                    (0..group_size).for_each(|i| {
-                        ring.add(format!("{}-{:02}", group_name, i));
-                    });
-                }
-            }
+pub fn next_function(
+    ctx: &ExecutionContext,
+    metadata: &Option<QueryMetadata>,
+) -> Result<CloudFunction> {
+    match metadata.as_ref().and_then(|m| m.get(WORKERS_METADATA_KEY)) {
+        Some(workers) => Ok(serde_json::from_str(workers)?),
+        None => Ok(ctx.next.clone()),
+    }
+}
 
-            unsafe {
-                // `ring`: the consistent hashing ring to forward the windowed events to the
-                // same function execution environment.
-                // `group_name`: function group name.
-                CONSISTENT_HASH_CONTEXT = ConsistentHashContext::Lambda((ring, group_name));
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn init_contexts_concurrently() -> Result<()> {
+        let encoded_ctxs = (0..4)
+            .map(|i| {
+                let ctx = ExecutionContext {
+                    name: format!("q{}-00", i),
+                    next: CloudFunction::Group((format!("q{}-01", i), 8)),
+                    ..Default::default()
+                };
+                context::marshal(&ctx, Encoding::default())
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let tasks = (0..64)
+            .map(|i| {
+                let encoded_ctx = encoded_ctxs[i % encoded_ctxs.len()].clone();
+                tokio::spawn(async move { get_or_init_context(&encoded_ctx).await })
+            })
+            .collect::<Vec<_>>();
+        let ctxs = futures::future::join_all(tasks)
+            .await
+            .into_iter()
+            .map(|r| r.unwrap())
+            .collect::<Result<Vec<_>>>()?;
+
+        // Each distinct context is deserialized once, and all invocations share
+        // the same instance.
+        for (i, ctx) in ctxs.iter().enumerate() {
+            let n = i % encoded_ctxs.len();
+            assert!(Arc::ptr_eq(ctx, &ctxs[n]));
+            assert_eq!(ctx.name, format!("q{}-00", n));
+        }
+        for encoded_ctx in &encoded_ctxs {
+            assert!(EXECUTION_CONTEXTS
+                .read()
+                .await
+                .contains_key(&context_key(encoded_ctx)));
         }
-    }
 
-    Ok(())
+        // Each context routes to its own next function group, and shares the
+        // consistent hash context with the other contexts of the group.
+        for (i, ctx) in ctxs.iter().take(encoded_ctxs.len()).enumerate() {
+            let hash_context = consistent_hash_context(&ctx.next);
+            assert_eq!(hash_context.ring.len(), 8);
+            assert_eq!(hash_context.group_name, format!("q{}-01", i));
+            assert!(Arc::ptr_eq(
+                &hash_context,
+                &consistent_hash_context(&ctx.next)
+            ));
+        }
+
+        Ok(())
+    }
 }
@@ -17,6 +17,8 @@ use aws_lambda_events::event::kafka::KafkaEvent;
 use aws_lambda_events::event::kinesis::KinesisEvent;
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::physical_plan::Partitioning;
+use flock::runtime::plan::stats_keys;
+use flock::runtime::stats::payload_stats;
 use futures::executor::block_on;
 use lambda_runtime::{service_fn, LambdaEvent};
 use log::warn;
@@ -145,7 +147,8 @@ async fn source_handler(ctx: &mut ExecutionContext, event: Value) -> Result<Valu
         _ => unimplemented!(),
     };
 
-    match LambdaExecutor::choose_strategy(&ctx, &batch) {
+    let stats = payload_stats(&batch, &stats_keys(&[ctx.plan.clone()]));
+    match LambdaExecutor::choose_strategy(&ctx, &batch, stats.as_ref()) {
         ExecutionStrategy::Centralized => {
             // feed data into the physical plan
             let output_partitions = coalesce_batches(
@@ -0,0 +1,180 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The entry point for the Kinesis event source mapping, which invokes the
+//! source function with the records of a shard instead of a payload.
+
+use crate::ConsistentHashContext;
+use aws_lambda_events::event::kinesis::KinesisEvent;
+use chrono::Utc;
+use flock::datasource::kinesis;
+use flock::prelude::*;
+use flock::runtime::dedup::deduplicate;
+use flock::runtime::metrics::{self, Metric};
+use flock::runtime::source_filter::filter_batches;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tracing::info;
+
+/// Returns the Kinesis event of the invocation, or `None` if the event isn't
+/// from a Kinesis event source mapping.
+pub fn kinesis_event(event: &Value) -> Result<Option<KinesisEvent>> {
+    match event["Records"][0]["eventSource"].as_str() {
+        Some("aws:kinesis") => Ok(Some(serde_json::from_value(event.clone())?)),
+        _ => Ok(None),
+    }
+}
+
+/// The endpoint of the source function of a Kinesis stream. The records that
+/// are delivered again are dropped (see [`flock::runtime::dedup`]), and the
+/// rest are forwarded to the next function as a window, without the rows
+/// rejected by the source filters of the context (see
+/// [`flock::runtime::source_filter`]).
+///
+/// # Arguments
+/// * `ctx` - The runtime context of the function.
+/// * `event` - The Kinesis event of the invocation.
+///
+/// # Returns
+/// A JSON object with the number of records received, the number of
+/// duplicates dropped, and the number of rows forwarded.
+pub async fn handler(ctx: &ExecutionContext, mut event: KinesisEvent) -> Result<Value> {
+    let records = event.records.len();
+    let dedup = deduplicate(
+        ctx.state_backend.as_ref(),
+        &FLOCK_S3_BUCKET,
+        ctx.query_code(),
+        &mut event,
+        Duration::from_millis(*FLOCK_KINESIS_DEDUP_LATENESS),
+    )
+    .await?;
+    let duplicates = dedup.duplicates;
+    metrics::scope().add(Metric::DuplicateRecords, duplicates as f64);
+    info!(
+        "[OK] Received {} records, {} of them are duplicates.",
+        records, duplicates
+    );
+
+    let batches = if event.records.is_empty() {
+        vec![]
+    } else {
+        let batches = kinesis::to_batch(event, ctx.metadata_columns);
+        filter_batches(&ctx.source_filters, batches).await?
+    };
+    let rows = batches.iter().map(|b| b.num_rows()).sum::<usize>();
+    if rows > 0 {
+        let hash_context = ConsistentHashContext::new(&ctx.next);
+        let uuid = UuidBuilder::new_with_ts(&hash_context.group_name, Utc::now().timestamp(), 1)
+            .next_uuid();
+        let function_name = hash_context
+            .ring
+            .get(&uuid.qid)
+            .ok_or_else(|| FlockError::Execution(format!("{} has no next function", ctx.name)))?
+            .to_string();
+        let bytes = serde_json::to_vec(&to_payload(&batches, &[], uuid, false)?)?;
+        info!(
+            "[OK] {} function's payload bytes: {}",
+            function_name,
+            bytes.len()
+        );
+        ctx.cloud_client
+            .invoke(&function_name, &FLOCK_LAMBDA_ASYNC_CALL, bytes)
+            .await?;
+    }
+
+    // The shards are checkpointed only after their records are sent, so that
+    // the records of a failed invocation are accepted again on the retry.
+    dedup
+        .commit(ctx.state_backend.as_ref(), &FLOCK_S3_BUCKET)
+        .await?;
+
+    Ok(json!({
+        "records": records,
+        "duplicates": duplicates,
+        "rows": rows,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flock::aws::client::FakeCloudClient;
+    use std::sync::Arc;
+
+    /// Returns a Kinesis event with the records `[first, last]` of the shard.
+    fn kinesis_event_of(shard: &str, first: u64, last: u64) -> Value {
+        let records = (first..=last)
+            .map(|seq| {
+                let sequence_number = format!("4956816737{:046}", seq);
+                json!({
+                    "eventID": format!("{}:{}", shard, sequence_number),
+                    "eventSource": "aws:kinesis",
+                    "kinesis": {
+                        "approximateArrivalTimestamp": 1_600_000_000 + seq,
+                        "data": base64::encode(format!("{{\"seq\": {}}}", seq)),
+                        "partitionKey": "p",
+                        "sequenceNumber": sequence_number,
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({ "Records": records })
+    }
+
+    /// Returns the number of rows sent to the next functions.
+    fn rows_downstream(client: &FakeCloudClient) -> Result<usize> {
+        let mut rows = 0;
+        for invocation in client.invocations() {
+            let (batches, _) = invocation.payload()?.to_record_batch()?;
+            rows += batches.iter().map(|b| b.num_rows()).sum::<usize>();
+        }
+        Ok(rows)
+    }
+
+    #[tokio::test]
+    async fn deduplicate_redelivered_records() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let ctx = ExecutionContext {
+            name: "kdedup-00".to_string(),
+            next: CloudFunction::Lambda("kdedup-01".to_string()),
+            cloud_client: client.clone(),
+            ..Default::default()
+        };
+        assert!(kinesis_event(&json!({"Records": []}))?.is_none());
+
+        // The second invocation delivers the records 6-10 again after the
+        // shard is rebalanced.
+        let first = kinesis_event(&kinesis_event_of("shardId-000000000000", 1, 10))?.unwrap();
+        let value = handler(&ctx, first).await?;
+        assert_eq!(value["duplicates"], 0);
+        let second = kinesis_event(&kinesis_event_of("shardId-000000000000", 6, 15))?.unwrap();
+        let value = handler(&ctx, second).await?;
+        assert_eq!(value["records"], 10);
+        assert_eq!(value["duplicates"], 5);
+        assert_eq!(value["rows"], 5);
+
+        // The records of another shard are independent.
+        let other = kinesis_event(&kinesis_event_of("shardId-000000000001", 1, 10))?.unwrap();
+        assert_eq!(handler(&ctx, other).await?["rows"], 10);
+
+        // Each record reaches the next function exactly once.
+        assert_eq!(client.invocations().len(), 3);
+        assert_eq!(rows_downstream(&client)?, 25);
+
+        // A fully redelivered batch invokes no function.
+        let again = kinesis_event(&kinesis_event_of("shardId-000000000000", 1, 15))?.unwrap();
+        assert_eq!(handler(&ctx, again).await?["duplicates"], 15);
+        assert_eq!(client.invocations().len(), 3);
+        Ok(())
+    }
+}
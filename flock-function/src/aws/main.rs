@@ -16,19 +16,37 @@
 #![feature(get_mut_unchecked)]
 
 mod actor;
+#[cfg(feature = "nexmark")]
 mod arch;
+mod batch;
 mod cloud_context;
+#[cfg(feature = "kinesis")]
+mod kinesis;
+#[cfg(feature = "nexmark")]
 mod nexmark;
+#[cfg(feature = "nexmark")]
 mod s3;
+#[cfg(any(feature = "nexmark", feature = "ysb"))]
 mod window;
+#[cfg(feature = "ysb")]
 mod ysb;
 
 use cloud_context::*;
+use flock::driver::stepfunctions::unwrap_payload;
+use flock::encryption;
 use flock::prelude::*;
-use hashring::HashRing;
+use flock::runtime::capability::{self, Capabilities};
+use flock::runtime::deadline::{self, Deadline, SystemClock};
+use flock::runtime::dictionary;
+use flock::runtime::logging::{init_function_logging, invocation_span};
+use flock::runtime::metrics::{self, Metric};
+use flock::runtime::response::Response;
+use flock::runtime::trace;
+use flock::runtime::transport;
 use lambda_runtime::{service_fn, LambdaEvent};
-use log::info;
-use serde_json::Value;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tracing::{info, warn, Instrument};
 
 // #[cfg(feature = "snmalloc")]
 // #[global_allocator]
@@ -38,29 +56,247 @@ use serde_json::Value;
 #[global_allocator]
 static ALLOC: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
-async fn handler(event: LambdaEvent<Payload>) -> Result<Value> {
-    let payload = event.payload;
-    let (ctx, arena) = init_exec_context!();
-    update_consistent_hash_context(&payload.metadata)?;
+async fn handler(event: LambdaEvent<Value>) -> Result<Value> {
+    // The event source mapping of the queue transport invokes the function with
+    // a batch of the payloads sent to its queue. A retryable error fails the
+    // whole batch, and the payloads handled before it are dropped by the arena
+    // when the batch is delivered again.
+    if let Some(bodies) = transport::queue_messages(&event.payload) {
+        let client = init_exec_context().await?.cloud_client.clone();
+        let mut responses = vec![];
+        for body in bodies {
+            let payload = transport::open_message(client.as_ref(), &body).await?;
+            let event = LambdaEvent {
+                payload,
+                context: event.context.clone(),
+            };
+            responses.push(handle_or_reply(event).await?);
+        }
+        return Ok(json!({ "records": responses }));
+    }
+    handle_or_reply(event).await
+}
+
+/// Handles the payload of the invocation, and replies to the errors that can't
+/// be retried.
+async fn handle_or_reply(event: LambdaEvent<Value>) -> Result<Value> {
+    let function = function_name(&event.context.invoked_function_arn);
+    match handle(event).await {
+        // An error that fails the same way on every retry, e.g. a malformed
+        // payload, is returned in the response envelope instead of failing the
+        // invocation. The other errors fail the invocation, so that Lambda
+        // retries the asynchronous invocations.
+        Err(e) if !e.is_retryable() => {
+            warn!("{}", e);
+            Ok(Response::error(function, &e).to_value())
+        }
+        result => result,
+    }
+}
+
+/// Returns the name of the function from the ARN of the invocation, e.g.
+/// `arn:aws:lambda:us-east-1:123456789012:function:q3-01-00`.
+fn function_name(arn: &str) -> String {
+    arn.split(':').nth(6).unwrap_or(arn).to_string()
+}
+
+/// Handles the payload of the invocation.
+async fn handle(event: LambdaEvent<Value>) -> Result<Value> {
+    let context_deadline = event.context.deadline as i64;
+    let function = function_name(&event.context.invoked_function_arn);
+    // The debug request returns the window state held by the arena of the
+    // function instance, e.g. `{"debug": "arena"}`.
+    if event.payload.get("debug") == Some(&json!("arena")) {
+        let arena = json!({ "arena": ARENA.lock().await.stats() });
+        return Ok(Response::ok(function, arena).to_value());
+    }
+    // The deployment probes the capabilities of the function binary after it
+    // creates or updates the function, e.g. `{"probe": "capabilities"}`.
+    if capability::is_probe(&event.payload) {
+        let capabilities = json!({ "capabilities": Capabilities::detect().await });
+        return Ok(Response::ok(function, capabilities).to_value());
+    }
+    // The Kinesis event source mapping invokes the function with the records
+    // of a shard instead of a payload.
+    #[cfg(feature = "kinesis")]
+    if let Some(event) = kinesis::kinesis_event(&event.payload)? {
+        let ctx = init_exec_context().await?;
+        dictionary::load_dictionary(&ctx).await?;
+        metrics::scope().begin(&ctx.name);
+        let result = kinesis::handler(&ctx, event).await;
+        metrics::scope().flush();
+        return result.map(|value| Response::ok(ctx.name.clone(), value).to_value());
+    }
+    // The Step Functions state machine wraps the payload in an envelope.
+    let mut payload = match unwrap_payload(event.payload).await? {
+        Some(payload) => payload,
+        None => {
+            info!("[Ok] The former stage produced no results.");
+            return Ok(Response::ok(function, Value::Null).to_value());
+        }
+    };
+    // The handlers share the cached context of the function instance.
+    let env_ctx = init_exec_context().await?;
+    let ctx = resolve_exec_context(env_ctx, &mut payload.metadata).await?;
+    let warnings = match &payload.metadata {
+        Some(metadata) => metadata.validate(*FLOCK_STRICT_METADATA)?,
+        None => vec![],
+    };
+    if let DataSource::Payload(_) = payload.datasource {
+        // The receiver doesn't know the size of the ring that the upstream
+        // function shuffled its partitions to, so the shuffle id is only
+        // checked against its lower bound.
+        payload.validate(ctx.is_aggregate(), None)?;
+    }
+
+    // The invocation is bounded by the earliest of its Lambda timeout and the
+    // propagated deadline, so that it fails with a timeout error before the
+    // runtime kills it.
+    let deadline = Deadline::invocation(
+        context_deadline,
+        Deadline::from_metadata(&payload.metadata)?,
+        Duration::from_millis(*FLOCK_DEADLINE_MARGIN),
+    );
+    deadline::begin(Some(deadline));
+    let budget = deadline.check(&SystemClock, &ctx.name)?;
+
+    // The invocation generates its data key once, and decrypts the incoming
+    // payload before any handler reads its record batches.
+    encryption::begin(&ctx.encryption).await?;
+    encryption::open_payload(&mut payload).await?;
+    // The Zstd dictionary of the query is registered before the payloads are
+    // encoded or decoded with it.
+    dictionary::load_dictionary(&ctx).await?;
+
+    // All events of the invocation carry the fields of its query stage and window,
+    // and the invocation is a span of the trace of its window, if it's enabled.
+    trace::begin(&ctx.name, &payload);
+    let span = invocation_span(&ctx.name, &payload);
+    let result = match tokio::time::timeout(budget, invoke(&ctx, payload).instrument(span)).await {
+        Ok(result) => result.map(|response| with_warnings(response.to_value(), warnings)),
+        Err(_) => {
+            metrics::scope().incr(Metric::Timeouts);
+            metrics::scope().flush();
+            Err(deadline.exceeded(&SystemClock, &ctx.name))
+        }
+    };
+    trace::end().await;
+    result
+}
 
+/// Dispatches the payload to the handler of its data source. The responses of
+/// the data source functions are wrapped in the response envelope, which also
+/// carries the id and the delivery attempt of the payload.
+async fn invoke(ctx: &ExecutionContext, payload: Payload) -> Result<Response> {
     info!(
         "AWS Lambda function architecture: {}",
         std::env::consts::ARCH
     );
 
-    match &payload.datasource {
-        DataSource::Payload(_) => actor::handler(ctx, arena, payload).await,
-        DataSource::NEXMarkEvent(_) => nexmark::handler(ctx, payload).await,
-        DataSource::YSBEvent(_) => ysb::handler(ctx, payload).await,
-        DataSource::S3(_) => s3::handler(ctx, payload).await,
-        DataSource::Arch(_) => arch::handler(ctx, payload).await,
-        _ => unimplemented!(),
+    // The metrics are buffered during the invocation and flushed once at the end.
+    metrics::scope().begin(&ctx.name);
+    metrics::scope().add(Metric::PayloadBytes, payload.get_data_size() as f64);
+
+    let (name, uuid) = (ctx.name.clone(), payload.uuid.clone());
+    let (payload_id, attempt) = (payload.payload_id.clone(), payload.delivery_attempt);
+    let ok = move |value: Value| Response::ok(name, value).with_uuid(uuid);
+    let result = match &payload.datasource {
+        DataSource::Payload(_) => {
+            let mut arena = ARENA.lock().await;
+            actor::handler(ctx, &mut arena, payload).await
+        }
+        #[cfg(feature = "nexmark")]
+        DataSource::NEXMarkEvent(_) => nexmark::handler(ctx, payload).await.map(ok),
+        #[cfg(feature = "ysb")]
+        DataSource::YSBEvent(_) => ysb::handler(ctx, payload).await.map(ok),
+        #[cfg(feature = "nexmark")]
+        DataSource::S3(_) => s3::handler(ctx, payload).await.map(ok),
+        #[cfg(feature = "nexmark")]
+        DataSource::Arch(_) => arch::handler(ctx, payload).await.map(ok),
+        DataSource::S3Objects(_) => batch::handler(ctx, payload).await.map(ok),
+        datasource => Err(FlockError::NotImplemented(format!(
+            "{:?} is not supported by this function binary",
+            datasource
+        ))),
+    };
+
+    metrics::scope().flush();
+    result.map(|response| response.with_delivery(payload_id, attempt))
+}
+
+/// Attaches the metadata warnings of the strict mode to the function response.
+fn with_warnings(value: Value, warnings: Vec<String>) -> Value {
+    if warnings.is_empty() {
+        return value;
+    }
+    warnings.iter().for_each(|w| warn!("{}", w));
+    match value {
+        Value::Object(mut map) => {
+            map.insert("warnings".to_string(), json!(warnings));
+            Value::Object(map)
+        }
+        Value::Null => json!({ "warnings": warnings }),
+        value => json!({ "response": value, "warnings": warnings }),
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    init_function_logging()?;
     lambda_runtime::run(service_fn(handler)).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::function_name;
+    use std::path::Path;
+    use std::process::Command;
+
+    /// Runs `cargo check` on the function binary with the given feature flags,
+    /// so that every supported feature combination keeps building.
+    fn cargo_check(features: &[&str]) {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let status = Command::new(env!("CARGO"))
+            .args(&[
+                "check",
+                "--quiet",
+                "--package",
+                "flock-function",
+                "--target-dir",
+            ])
+            .arg(manifest_dir.join("../target/feature-check"))
+            .args(features)
+            .current_dir(manifest_dir)
+            .status()
+            .expect("failed to run cargo check");
+        assert!(status.success(), "cargo check {:?} failed", features);
+    }
+
+    #[test]
+    fn parse_function_name() {
+        assert_eq!(
+            function_name("arn:aws:lambda:us-east-1:123456789012:function:q3-01-00"),
+            "q3-01-00"
+        );
+        assert_eq!(
+            function_name("arn:aws:lambda:us-east-1:123456789012:function:q3-00:live"),
+            "q3-00"
+        );
+        assert_eq!(function_name("q3-00"), "q3-00");
+    }
+
+    #[test]
+    fn check_minimal_features() {
+        cargo_check(&["--no-default-features"]);
+    }
+
+    #[test]
+    fn check_full_features() {
+        cargo_check(&[
+            "--no-default-features",
+            "--features",
+            "kinesis,kafka,nexmark,ysb,tpch,snappy,lz4,zstd",
+        ]);
+    }
+}
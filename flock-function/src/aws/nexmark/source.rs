@@ -14,10 +14,14 @@
 //! The entry point for the NEXMark benchmark on cloud functions.
 
 use crate::window::*;
+use flock::datasource::nexmark::NEXMarkLazyStream;
 use flock::prelude::*;
-use log::info;
+use flock::runtime::backpressure::resume_window;
+use flock::runtime::completion::{generator_index, is_completion, SourceReport};
+use flock::runtime::continuation::Continuation;
 use serde_json::Value;
 use std::sync::Arc;
+use tracing::info;
 
 /// The endpoint of the data source generator function invocation. The data
 /// source generator function is responsible for generating the data packets for
@@ -29,13 +33,19 @@ use std::sync::Arc;
 ///
 /// # Returns
 /// A JSON object that contains the return value of the function invocation.
-pub async fn handler(ctx: &mut ExecutionContext, payload: Payload) -> Result<Value> {
+pub async fn handler(ctx: &ExecutionContext, payload: Payload) -> Result<Value> {
     // Copy data source from the payload.
     let mut source = match payload.datasource.clone() {
         DataSource::NEXMarkEvent(source) => source,
         _ => unreachable!(),
     };
 
+    // The window in the execution context takes precedence over the window
+    // compiled into the data source, so it can be changed without rebuilding.
+    if let Some(window) = ctx.window.clone() {
+        source.window = window;
+    }
+
     // Each source function is a data generator.
     let gen = source.config.get_as_or("threads", 1);
     let sec = source.config.get_as_or("seconds", 10);
@@ -47,31 +57,60 @@ pub async fn handler(ctx: &mut ExecutionContext, payload: Payload) -> Result<Val
         .insert("events-per-second", format!("{}", eps / gen));
     assert!(eps / gen > 0);
 
-    let events = Arc::new(source.generate_data()?);
     let query_number = payload.query_number.expect("Query number is missing.");
+    source.select_tables_for_query(query_number);
+    // The window launchers walk the epochs in ascending order, so the events are
+    // generated on demand and only the latest epochs are kept in memory. A
+    // resumed data source starts at the first epoch of its window.
+    let seed = source.config.get_as_or("seed", 0);
+    let epoch = resume_window(&payload.metadata)
+        .and_then(|window| source.window.window_start(window))
+        .unwrap_or(0);
+    let events = Arc::new(NEXMarkLazyStream::new(
+        source.stream_epochs(seed).starting_at(epoch),
+        2,
+    ));
 
     info!("Nexmark Benchmark: Query {:?}", query_number);
     info!("{:?}", source);
     info!("[OK] Generate nexmark events.");
 
+    if is_completion(&payload.metadata) {
+        let report = SourceReport {
+            generators: gen,
+            windows:    Continuation::from_conf(&payload.metadata)
+                .total_windows(source.window.num_windows(sec)),
+        };
+        report
+            .report(
+                &format!("q{}", query_number),
+                generator_index(&payload.metadata),
+            )
+            .await?;
+    }
+
+    let results = InlineResults::default();
     match source.window {
         Window::Tumbling(Schedule::Seconds(window_size)) => {
-            tumbling::launch_tasks(ctx, payload, events, sec, window_size).await?;
+            tumbling::launch_tasks(ctx, payload, events, sec, window_size, &results).await?;
         }
         Window::Hopping((window_size, hop_size)) => {
-            hopping::launch_tasks(payload, events, sec, window_size, hop_size).await?;
+            hopping::launch_tasks(ctx, payload, events, sec, window_size, hop_size, &results)
+                .await?;
         }
         Window::ElementWise => {
-            elementwise::launch_tasks(ctx, payload, events, sec).await?;
+            elementwise::launch_tasks(ctx, payload, events, sec, &results).await?;
         }
         Window::Session(Schedule::Seconds(timeout)) => {
-            session::launch_tasks(payload, events, sec, timeout).await?;
+            session::launch_tasks(ctx, payload, events, sec, timeout, &results).await?;
         }
         Window::Global(Schedule::Seconds(window_size)) => {
-            global::launch_tasks(payload, events, sec, window_size).await?;
+            global::launch_tasks(ctx, payload, events, sec, window_size, &results).await?;
         }
         _ => unimplemented!(),
     };
 
-    Ok(Value::Null)
+    // The results of the query are returned inline to the synchronous caller if
+    // the query ends in a response data sink.
+    results.into_response(ctx).await
 }
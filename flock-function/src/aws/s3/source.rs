@@ -13,16 +13,16 @@
 
 //! The entry point for the NEXMark benchmark on cloud functions.
 
-use crate::{consistent_hash_context, ConsistentHashContext, CONSISTENT_HASH_CONTEXT};
+use crate::{consistent_hash_context, next_function};
 use chrono::Utc;
 use datafusion::physical_plan::Partitioning;
 use flock::prelude::*;
-use log::info;
 use rusoto_core::ByteStream;
 use rusoto_s3::{PutObjectRequest, S3};
 use serde_json::json;
 use serde_json::Value;
 use std::sync::Arc;
+use tracing::info;
 
 /// The endpoint of the data source generator function invocation. The data
 /// source generator function is responsible for generating the data packets for
@@ -34,7 +34,7 @@ use std::sync::Arc;
 ///
 /// # Returns
 /// A JSON object that contains the return value of the function invocation.
-pub async fn handler(_ctx: &ExecutionContext, payload: Payload) -> Result<Value> {
+pub async fn handler(ctx: &ExecutionContext, payload: Payload) -> Result<Value> {
     // Copy data source from the payload.
     let mut source = match payload.datasource.clone() {
         DataSource::S3(source) => source,
@@ -52,14 +52,16 @@ pub async fn handler(_ctx: &ExecutionContext, payload: Payload) -> Result<Value>
         .insert("events-per-second", format!("{}", eps / gen));
     assert!(eps / gen > 0);
 
-    let events = Arc::new(source.generate_data()?);
     let query_number = payload.query_number.expect("Query number is missing.");
+    source.select_tables_for_query(query_number);
+    let events = Arc::new(source.generate_data()?);
 
     info!("Nexmark Benchmark [S3]: Query {:?}", query_number);
     info!("{:?}", source);
     info!("[OK] Generate nexmark events.");
 
-    let (ring, group_name) = consistent_hash_context!();
+    let hash_context = consistent_hash_context(&next_function(ctx, &payload.metadata)?);
+    let (ring, group_name) = (&hash_context.ring, &hash_context.group_name);
     let uuid = UuidBuilder::new_with_ts(group_name, Utc::now().timestamp(), 1).next_uuid();
     let sync = true;
 
@@ -102,7 +104,7 @@ pub async fn handler(_ctx: &ExecutionContext, payload: Payload) -> Result<Value>
                 if r2.len() == 1 { &r2[0] } else { &[] },
                 uuid.clone(),
                 sync,
-            ))?
+            )?)?
         }
         Window::ElementWise => {
             assert!(sec == 1);
@@ -125,7 +127,7 @@ pub async fn handler(_ctx: &ExecutionContext, payload: Payload) -> Result<Value>
     );
     info!("Writing {} function payload to S3...", function_name);
     let s3_key = format!("{}_payload", function_name);
-    FLOCK_S3_CLIENT
+    s3_client("")
         .put_object(PutObjectRequest {
             bucket: FLOCK_S3_BUCKET.clone(),
             key: s3_key.clone(),
@@ -11,14 +11,17 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use super::{InlineResults, WindowGate};
 use crate::actor::*;
-use crate::{consistent_hash_context, ConsistentHashContext, CONSISTENT_HASH_CONTEXT};
-use chrono::Utc;
+use crate::{consistent_hash_context, next_function};
 use datafusion::physical_plan::empty::EmptyExec;
-use flock::aws::{lambda, s3};
+use flock::aws::lambda;
 use flock::prelude::*;
-use log::info;
+use flock::runtime::analyze::{is_analyze, StageMetrics};
+use flock::runtime::logging::spawn_in_span;
 use std::sync::Arc;
+use std::time::Instant;
+use tracing::info;
 
 /// Generate normal elementwose workloads for the benchmark on cloud
 /// function services.
@@ -27,124 +30,146 @@ use std::sync::Arc;
 /// * `payload` - The payload of the function.
 /// * `stream` - the source stream of events.
 /// * `seconds` - the total number of seconds to generate workloads.
+/// * `results` - the inline results returned by the next stage.
 pub async fn launch_tasks(
-    ctx: &mut ExecutionContext,
+    ctx: &ExecutionContext,
     payload: Payload,
     stream: Arc<dyn DataStream + Send + Sync>,
     seconds: usize,
+    results: &InlineResults,
 ) -> Result<()> {
-    let query_number = payload.query_number;
-    let metadata = payload.metadata;
-    let (ring, group_name) = consistent_hash_context!();
-    let sync = infer_invocation_type(&metadata)?;
+    let hash_context = consistent_hash_context(&next_function(ctx, &payload.metadata)?);
+    let (ring, group_name) = (&hash_context.ring, &hash_context.group_name);
+    let sync = infer_invocation_type(&payload.metadata)?;
     let invocation_type = if sync {
         FLOCK_LAMBDA_SYNC_CALL.to_string()
     } else {
         FLOCK_LAMBDA_ASYNC_CALL.to_string()
     };
+    let mut gate = WindowGate::new(ctx, &payload, group_name, sync);
+    let query_number = payload.query_number;
+    let metadata = payload.metadata;
 
-    for epoch in 0..seconds {
+    for epoch in gate.first_window()..seconds {
+        if !gate.admit(ctx, epoch).await? {
+            break;
+        }
+        let metadata = gate.window_metadata(ctx, &metadata, epoch, 1)?;
         info!("[OK] Send events (epoch: {}).", epoch);
         let events = stream.clone();
-        if ring.len() == 1 {
-            // lambda default concurrency is 1000.
-            assert!(!ctx.plan.execution_plans.is_empty());
-            let exec_plans = &ctx.plan.execution_plans;
-            if exec_plans[0].as_any().downcast_ref::<EmptyExec>().is_some() {
-                // centralized mode
-                let function_name = group_name.clone();
-                let uuid =
-                    UuidBuilder::new_with_ts(&function_name, Utc::now().timestamp(), 1).next_uuid();
-                let mut payload =
-                    events.select_event_to_payload(epoch, 0, query_number, uuid, sync)?;
-                payload.metadata = metadata.clone();
-                let bytes = serde_json::to_vec(&payload)?;
-                info!(
-                    "[OK] {} function's payload bytes: {}",
-                    function_name,
-                    bytes.len()
-                );
-                lambda::invoke_function(&function_name, &invocation_type, Some(bytes.into()))
-                    .await?;
-            } else {
-                // distributed mode
-                let partitions = events.select_event_to_batches(
+        // lambda default concurrency is 1000.
+        assert!(!ctx.plan.execution_plans.is_empty());
+        let centralized = ctx.plan.execution_plans[0]
+            .as_any()
+            .downcast_ref::<EmptyExec>()
+            .is_some();
+        if ring.len() == 1 && !centralized {
+            // distributed mode
+            let partitions = gate
+                .filter_events(events.select_event_to_batches(
                     epoch,
                     0, // generator id
                     payload.query_number,
                     sync,
-                )?;
-                let mut input = vec![];
-                for b in vec![partitions.0, partitions.1] {
-                    if !b.is_empty() {
-                        input.push(b);
-                    }
+                )?)
+                .await?;
+            let mut input = vec![];
+            for b in vec![partitions.0, partitions.1] {
+                if !b.is_empty() {
+                    input.push(b);
                 }
+            }
 
-                ctx.feed_data_sources(input).await?;
-                let output = Arc::new(ctx.execute_partitioned().await?);
-                let size = output[0].len();
-                let mut uuid_builder =
-                    UuidBuilder::new_with_ts(group_name, Utc::now().timestamp(), size);
+            let mut metrics = is_analyze(&metadata).then(|| StageMetrics::new(&ctx.name));
+            if let Some(m) = metrics.as_mut() {
+                m.record_input(&input);
+            }
+            let start = Instant::now();
+            ctx.feed_data_sources(input).await?;
+            let output = ctx.execute_partitioned().await?;
+            if let Some(m) = metrics.as_mut() {
+                m.execute_ms = start.elapsed().as_millis() as u64;
+                output.iter().for_each(|o| m.record_output(o));
+            }
+            let size = output[0].len();
+            let mut uuid_builder =
+                UuidBuilder::for_window(&gate.query_code, &gate.window_id(epoch), size);
 
-                // Creates the S3 bucket for the current query if state backend is S3.
-                if ctx
-                    .state_backend
-                    .as_any()
-                    .downcast_ref::<S3StateBackend>()
-                    .is_some()
-                {
-                    s3::create_bucket(&uuid_builder.qid).await?;
-                }
+            // Creates the S3 bucket for the current query if state backend is S3.
+            if ctx
+                .state_backend
+                .as_any()
+                .downcast_ref::<S3StateBackend>()
+                .is_some()
+            {
+                create_state_bucket(&ctx.state_bucket(&uuid_builder.qid)).await?;
+            }
 
-                let tasks = (0..size)
-                    .map(|i| {
-                        let data = output.clone();
-                        let function_name = group_name.clone();
-                        let meta = metadata.clone();
-                        let invoke_type = invocation_type.clone();
-                        let uuid = uuid_builder.next_uuid();
-                        tokio::spawn(async move {
-                            let mut payload = to_payload(
-                                &data[0][i],
-                                if data.len() == 1 { &[] } else { &data[1][i] },
-                                uuid,
-                                sync,
-                            );
-                            payload.query_number = query_number;
-                            payload.metadata = meta;
+            let mut payloads = (0..size)
+                .map(|i| {
+                    let mut payload = to_payload(
+                        &output[0][i],
+                        if output.len() == 1 {
+                            &[]
+                        } else {
+                            &output[1][i]
+                        },
+                        uuid_builder.next_uuid(),
+                        sync,
+                    )?;
+                    payload.query_number = query_number;
+                    payload.metadata = metadata.clone();
+                    Ok(payload)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            broadcast_build_side(ctx, &mut payloads).await?;
 
-                            let bytes = serde_json::to_vec(&payload)?;
-                            info!(
-                                "[OK] {} function's payload bytes: {}",
-                                function_name,
-                                bytes.len()
-                            );
-                            lambda::invoke_function(
-                                &function_name,
-                                &invoke_type,
-                                Some(bytes.into()),
-                            )
-                            .await
-                            .map(|_| ())
-                        })
+            let tasks = payloads
+                .into_iter()
+                .map(|payload| {
+                    let function_name = group_name.clone();
+                    let invoke_type = invocation_type.clone();
+                    let results = results.clone();
+                    spawn_in_span(async move {
+                        let bytes = serde_json::to_vec(&payload)?;
+                        info!(
+                            "[OK] {} function's payload bytes: {}",
+                            function_name,
+                            bytes.len()
+                        );
+                        let response = lambda::invoke_function(
+                            &function_name,
+                            &invoke_type,
+                            Some(bytes.into()),
+                        )
+                        .await?;
+                        results.collect(&response)
                     })
-                    .collect::<Vec<tokio::task::JoinHandle<Result<()>>>>();
-                futures::future::join_all(tasks).await;
-                ctx.clean_data_sources().await?;
+                })
+                .collect::<Vec<tokio::task::JoinHandle<Result<()>>>>();
+            futures::future::join_all(tasks).await;
+            ctx.clean_data_sources().await?;
+            if let Some(m) = metrics {
+                let query_code = ctx.query_code();
+                m.report(query_code, &uuid_builder.qid).await?;
             }
         } else {
+            // The payloads of the epoch are sent to a member of the function group, or
+            // to the pipelined Lambda function in the centralized mode, whose instances
+            // run them concurrently (see `ExecutionContext::is_pipelined`).
             // Calculate the total data packets to be sent.
-            let (a, b) = events.select_event_to_batches(
-                epoch,
-                0, // generator id
-                payload.query_number,
-                sync,
-            )?;
+            let (a, b) = gate
+                .filter_events(events.select_event_to_batches(
+                    epoch,
+                    0, // generator id
+                    payload.query_number,
+                    sync,
+                )?)
+                .await?;
             let size = if a.len() > b.len() { a.len() } else { b.len() };
 
             let mut uuid_builder =
-                UuidBuilder::new_with_ts(group_name, Utc::now().timestamp(), size);
+                UuidBuilder::for_window(&gate.query_code, &gate.window_id(epoch), size);
 
             // Distribute the epoch data to a single function execution environment.
             let function_name = ring
@@ -159,16 +184,22 @@ pub async fn launch_tasks(
             );
 
             let empty = vec![];
-            for i in 0..size {
-                let mut payload = to_payload(
-                    if i < a.len() { &a[i] } else { &empty },
-                    if i < b.len() { &b[i] } else { &empty },
-                    uuid_builder.next_uuid(),
-                    sync,
-                );
-                payload.query_number = query_number;
-                payload.metadata = metadata.clone();
+            let mut payloads = (0..size)
+                .map(|i| {
+                    let mut payload = to_payload(
+                        if i < a.len() { &a[i] } else { &empty },
+                        if i < b.len() { &b[i] } else { &empty },
+                        uuid_builder.next_uuid(),
+                        sync,
+                    )?;
+                    payload.query_number = query_number;
+                    payload.metadata = metadata.clone();
+                    Ok(payload)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            broadcast_build_side(ctx, &mut payloads).await?;
 
+            for (i, payload) in payloads.into_iter().enumerate() {
                 let bytes = serde_json::to_vec(&payload)?;
                 info!(
                     "[OK] Event {} - {} function's payload bytes: {}",
@@ -176,8 +207,10 @@ pub async fn launch_tasks(
                     function_name,
                     bytes.len()
                 );
-                lambda::invoke_function(&function_name, &invocation_type, Some(bytes.into()))
-                    .await?;
+                let response =
+                    lambda::invoke_function(&function_name, &invocation_type, Some(bytes.into()))
+                        .await?;
+                results.collect(&response)?;
             }
         }
     }
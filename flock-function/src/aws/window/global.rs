@@ -11,24 +11,25 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use super::coalesce_windows;
+use super::{coalesce_windows, InlineResults};
 use crate::actor::*;
-use crate::{consistent_hash_context, ConsistentHashContext, CONSISTENT_HASH_CONTEXT};
+use crate::{consistent_hash_context, next_function};
 use chrono::{DateTime, NaiveDateTime, Utc};
 use datafusion::arrow::array::{Int32Array, TimestampNanosecondArray, UInt64Array};
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::datasource::MemTable;
 use datafusion::execution::context::ExecutionContext as DataFusionExecutionContext;
 use datafusion::logical_plan::{col, count_distinct};
-use datafusion::physical_plan::collect_partitioned;
 use datafusion::physical_plan::expressions::col as expr_col;
 use datafusion::physical_plan::Partitioning::{HashDiff, RoundRobinBatch};
 use flock::aws::lambda;
 use flock::prelude::*;
-use log::{info, warn};
+use flock::runtime::function_name::query_code_of;
+use flock::runtime::logging::spawn_in_span;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Instant;
+use tracing::{info, warn};
 
 /// Add each unique partition to a distinct tumbling window.
 ///
@@ -154,15 +155,19 @@ fn find_timeout_tumbling_windows(
 /// aggregated elements.
 ///
 /// # Arguments
+/// * `ctx` - The runtime context of the function.
 /// * `payload` - The payload of the function invocation.
 /// * `stream` - The data stream.
 /// * `seconds` - The number of seconds to group events into.
 /// * `window_size` - The size of the window.
+/// * `results` - The inline results returned by the next stage.
 pub async fn launch_tasks(
+    ctx: &ExecutionContext,
     payload: Payload,
     stream: Arc<dyn DataStream>,
     seconds: usize,
     window_size: usize,
+    results: &InlineResults,
 ) -> Result<()> {
     if seconds < window_size {
         warn!(
@@ -172,8 +177,9 @@ pub async fn launch_tasks(
     }
     let sync = infer_invocation_type(&payload.metadata)?;
     let (group_key, table_name) = infer_session_keys(&payload.metadata)?;
-    let add_process_time_sql = infer_add_process_time_query(&payload.metadata)?;
-    let (ring, group_name) = consistent_hash_context!();
+    let add_column = infer_add_column(&payload.metadata)?;
+    let hash_context = consistent_hash_context(&next_function(ctx, &payload.metadata)?);
+    let (ring, group_name) = (&hash_context.ring, &hash_context.group_name);
 
     let (invocation_type, granule_size) = if sync {
         (FLOCK_LAMBDA_SYNC_CALL.to_string(), *FLOCK_SYNC_GRANULE_SIZE)
@@ -202,6 +208,7 @@ pub async fn launch_tasks(
         .collect::<Vec<Vec<Vec<RecordBatch>>>>();
 
     let schema = events[0][0][0].schema();
+    let projection = add_column.projection(&schema)?;
 
     for (time, batches) in events.into_iter().enumerate() {
         info!("Processing events in epoch: {}", time);
@@ -225,7 +232,11 @@ pub async fn launch_tasks(
             .value(0);
 
         // Equivalent to `SELECT *, now() as p_time FROM table_name;`
-        let output = collect_partitioned(physical_plan(&ctx, &add_process_time_sql).await?).await?;
+        let output = ctx
+            .table(&*table_name)?
+            .select(projection.clone())?
+            .collect_partitioned()
+            .await?;
         let schema_with_ptime = output[0][0].schema();
 
         // Each partition has a unique key after `repartition` execution.
@@ -252,8 +263,9 @@ pub async fn launch_tasks(
             .map(|window| {
                 let function_group = group_name.clone();
                 let invoke_type = invocation_type.clone();
+                let results = results.clone();
 
-                let query_code = group_name.split('-').next().unwrap();
+                let query_code = query_code_of(&group_name);
                 let timestamp = Utc::now().timestamp();
                 let rand_id = uuid::Uuid::new_v4().as_u128();
                 let qid = format!("{}-{}-{}", query_code, timestamp, rand_id);
@@ -262,7 +274,7 @@ pub async fn launch_tasks(
                 let function_name = ring.get(&qid).expect("hash ring failure.").to_string();
                 info!("Tumbling window -> function name: {}", function_name);
 
-                tokio::spawn(async move {
+                spawn_in_span(async move {
                     let window = repartition(window, RoundRobinBatch(1)).await?;
                     let window = coalesce_batches(window, granule_size * 2).await?;
                     let size = window[0].len();
@@ -281,15 +293,20 @@ pub async fn launch_tasks(
                             &[],
                             uuid_builder.next_uuid(),
                             sync,
-                        ))?;
+                        )?)?;
                         info!(
                             "[OK] Event {} - {} function's payload bytes: {}",
                             eid,
                             function_name,
                             payload.len()
                         );
-                        lambda::invoke_function(&function_name, &invoke_type, Some(payload.into()))
-                            .await?;
+                        let response = lambda::invoke_function(
+                            &function_name,
+                            &invoke_type,
+                            Some(payload.into()),
+                        )
+                        .await?;
+                        results.collect(&response)?;
                     }
                     Ok(())
                 })
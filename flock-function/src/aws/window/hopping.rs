@@ -11,29 +11,39 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use super::{InlineResults, WindowGate};
 use crate::actor::*;
-use crate::{consistent_hash_context, ConsistentHashContext, CONSISTENT_HASH_CONTEXT};
-use chrono::Utc;
+use crate::{consistent_hash_context, next_function};
 use flock::aws::lambda;
 use flock::prelude::*;
-use log::{info, warn};
+use flock::runtime::arena::{PANE_METADATA_KEY, WINDOW_METADATA_KEY};
 use std::sync::Arc;
+use tracing::{info, warn};
 
 /// Generate hopping windows workloads for the benchmark on cloud
 /// function services.
 ///
+/// If the query is evaluated incrementally (see
+/// `ExecutionContext::argmax_key`), all windows are sent to the same function,
+/// and each payload is tagged with its pane and window, so that the function
+/// only counts the new panes.
+///
 /// # Arguments
+/// * `ctx` - The runtime context of the function.
 /// * `payload` - The payload of the function.
 /// * `stream` - the source stream of events.
 /// * `seconds` - the total number of seconds to generate workloads.
 /// * `window_size` - the size of the window in seconds.
 /// * `hop_size` - the size of the hop in seconds.
+/// * `results` - the inline results returned by the next stage.
 pub async fn launch_tasks(
+    ctx: &ExecutionContext,
     payload: Payload,
     stream: Arc<dyn DataStream>,
     seconds: usize,
     window_size: usize,
     hop_size: usize,
+    results: &InlineResults,
 ) -> Result<()> {
     if seconds < window_size {
         warn!(
@@ -48,11 +58,16 @@ pub async fn launch_tasks(
         FLOCK_LAMBDA_ASYNC_CALL.to_string()
     };
 
-    let (ring, group_name) = consistent_hash_context!();
+    let incremental = ctx.argmax_key.is_some();
+    let hash_context = consistent_hash_context(&next_function(ctx, &payload.metadata)?);
+    let (ring, group_name) = (&hash_context.ring, &hash_context.group_name);
     let mut window: Box<Vec<(RelationPartitions, RelationPartitions)>> = Box::new(vec![]);
+    let mut gate = WindowGate::new(ctx, &payload, group_name, sync);
 
-    for time in (0..seconds).step_by(hop_size) {
-        if time + window_size > seconds {
+    // A rescheduled data source starts with an empty window, so the first window
+    // is generated in full.
+    for time in (0..seconds).step_by(hop_size).skip(gate.first_window()) {
+        if time + window_size > seconds || !gate.admit(ctx, time / hop_size).await? {
             break;
         }
 
@@ -65,12 +80,13 @@ pub async fn launch_tasks(
 
         // Update the hopping window, and generate the next batch of data.
         for t in time + start_pos..time + window_size {
-            window.push(stream.select_event_to_batches(
+            let events = stream.select_event_to_batches(
                 t,
                 0, // generator id
                 payload.query_number,
                 sync,
-            )?);
+            )?;
+            window.push(gate.filter_events(events).await?);
         }
 
         // Calculate the total data packets to be sent.
@@ -79,11 +95,18 @@ pub async fn launch_tasks(
             .map(|(a, b)| if a.len() > b.len() { a.len() } else { b.len() })
             .sum::<usize>();
 
-        let mut uuid_builder = UuidBuilder::new_with_ts(group_name, Utc::now().timestamp(), size);
+        let mut uuid_builder =
+            UuidBuilder::for_window(&gate.query_code, &gate.window_id(time / hop_size), size);
 
         // Distribute the window data to a single function execution environment.
+        // The incremental state lives in the function, so all windows go to the
+        // same function.
         let function_name = ring
-            .get(&uuid_builder.qid)
+            .get(if incremental {
+                &*group_name
+            } else {
+                &uuid_builder.qid
+            })
             .expect("hash ring failure.")
             .to_string();
 
@@ -98,23 +121,35 @@ pub async fn launch_tasks(
 
         let mut eid = 0;
         let empty = vec![];
-        for (a, b) in window.iter() {
+        for (pane, (a, b)) in (time..time + window_size).zip(window.iter()) {
             let num = if a.len() > b.len() { a.len() } else { b.len() };
             for i in 0..num {
-                let payload = serde_json::to_vec(&to_payload(
+                let mut payload = to_payload(
                     if i < a.len() { &a[i] } else { &empty },
                     if i < b.len() { &b[i] } else { &empty },
                     uuid_builder.next_uuid(),
                     sync,
-                ))?;
+                )?;
+                if incremental {
+                    let mut metadata = payload.metadata.take().unwrap_or_default();
+                    metadata.insert(PANE_METADATA_KEY.to_string(), pane.to_string());
+                    metadata.insert(
+                        WINDOW_METADATA_KEY.to_string(),
+                        format!("{}-{}", time, time + window_size),
+                    );
+                    payload.metadata = Some(metadata);
+                }
+                let payload = serde_json::to_vec(&payload)?;
                 info!(
                     "[OK] Event {} - {} function's payload bytes: {}",
                     eid,
                     function_name,
                     payload.len()
                 );
-                lambda::invoke_function(&function_name, &invocation_type, Some(payload.into()))
-                    .await?;
+                let response =
+                    lambda::invoke_function(&function_name, &invocation_type, Some(payload.into()))
+                        .await?;
+                results.collect(&response)?;
                 eid += 1;
             }
         }
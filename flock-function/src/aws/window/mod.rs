@@ -13,19 +13,45 @@
 
 //! The time window types for the stream queries.
 
+#[cfg(feature = "nexmark")]
 pub mod elementwise;
+#[cfg(feature = "nexmark")]
 pub mod global;
+#[cfg(feature = "nexmark")]
 pub mod hopping;
+#[cfg(feature = "nexmark")]
 pub mod session;
 pub mod tumbling;
 
+use chrono::Utc;
+#[cfg(feature = "nexmark")]
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::physical_plan::empty::EmptyExec;
+use flock::aws::lambda;
+use flock::datasink::inline_response;
+use flock::datasink::results::window_id;
 use flock::prelude::*;
+use flock::runtime::backpressure::{
+    resume_window, Admission, Backpressure, CompletionTracker, WindowTracker,
+};
+use flock::runtime::completion::{self, generator_index, is_completion};
+use flock::runtime::continuation::{Continuation, Step};
+use flock::runtime::deadline::{self, SystemClock};
+use flock::runtime::function_name::query_code_of;
+use flock::runtime::source_filter::{self, SourceFilter, SOURCE_FILTER_METADATA_KEY};
+use flock::runtime::switchover::RouteFollower;
+use flock::runtime::topology;
+use flock::runtime::trace;
+use rusoto_lambda::InvocationResponse;
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::info;
 
-/// This function is used to coalesce smaller session windows or global windows
-/// to bigger ones so that the number of events in each payload is greater than
-/// the granule size, and close to the payload limit.
+/// This function is used to coalesce smaller global windows to bigger ones so
+/// that the number of events in each payload is greater than the granule size,
+/// and close to the payload limit.
+#[cfg(feature = "nexmark")]
 fn coalesce_windows(
     windows: Vec<Vec<Vec<RecordBatch>>>,
     granule_size: usize,
@@ -62,3 +88,241 @@ fn is_distributed(ctx: &ExecutionContext) -> bool {
         .downcast_ref::<EmptyExec>()
         .is_none()
 }
+
+/// The inline results that the next stage returns to the synchronous
+/// invocations of the data source function. The data source returns them to
+/// its own caller, since the [`DataSinkType::Response`] data sink writes no
+/// results elsewhere.
+#[derive(Debug, Clone, Default)]
+pub struct InlineResults(Arc<Mutex<Vec<Value>>>);
+
+impl InlineResults {
+    /// Keeps the inline results in the response of the invocation, if any.
+    pub fn collect(&self, response: &InvocationResponse) -> Result<()> {
+        if let Some(body) = response.payload.as_ref() {
+            if let Some(value) = inline_response(body)? {
+                self.0.lock().unwrap().push(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the inline results of all windows in a single response (see
+    /// [`DataSink::merge_responses`]), or `null` if there are none.
+    pub async fn into_response(self, ctx: &ExecutionContext) -> Result<Value> {
+        let responses = std::mem::take(&mut *self.0.lock().unwrap());
+        DataSink::merge_responses(
+            responses,
+            FLOCK_MAX_RESPONSE_SIZE,
+            ctx.cloud_client.as_ref(),
+        )
+        .await
+    }
+}
+
+/// The backpressure on the windows emitted by the data source function (see
+/// [`flock::runtime::backpressure`]).
+///
+/// The windows in flight are only tracked if the completion protocol is
+/// enabled in the payload metadata. If the data source follows the route of
+/// the query, the gate hands the data source over to the switched function set
+/// at the window boundary (see [`flock::runtime::switchover`]). Once the
+/// invocation has emitted its share of the windows, the gate continues the
+/// data source in a new invocation (see [`flock::runtime::continuation`]).
+/// The events of each window are filtered by the filters pushed down from the
+/// first stage (see [`flock::runtime::source_filter`]).
+struct WindowGate {
+    backpressure: Backpressure,
+    tracker:      Option<CompletionTracker>,
+    route:        Option<RouteFollower>,
+    continuation: Continuation,
+    payload:      Payload,
+    sync:         bool,
+    query_code:   String,
+    filters:      Vec<SourceFilter>,
+}
+
+impl WindowGate {
+    /// Creates the gate for the data source function.
+    ///
+    /// # Arguments
+    /// * `ctx` - The execution context of the data source function.
+    /// * `payload` - The payload of the data source function.
+    /// * `group_name` - The function group of the next stage.
+    /// * `sync` - Whether the next stage is invoked synchronously.
+    fn new(ctx: &ExecutionContext, payload: &Payload, group_name: &str, sync: bool) -> Self {
+        // The windows are tracked under the query of the next stage, unless the
+        // function serves a multiplexed query, whose shared functions are named
+        // after its topology (see [`ExecutionContext::query_code`]).
+        let query_code = ctx
+            .query_code
+            .clone()
+            .unwrap_or_else(|| query_code_of(group_name).to_string());
+        // A rescheduled data source is still throttled until the low watermark,
+        // unlike the continuation of the previous invocation.
+        let continuation = Continuation::from_conf(&payload.metadata);
+        let backpressure = Backpressure::from_conf().with_throttled(
+            resume_window(&payload.metadata).is_some() && !continuation.is_handover(),
+        );
+        let route = RouteFollower::from_metadata(&payload.metadata);
+        // The switchover drains the replaced function set by its in-flight
+        // windows, so they are tracked even without the backpressure.
+        let tracker = ((backpressure.is_enabled() || route.is_some())
+            && is_completion(&payload.metadata))
+        .then(|| CompletionTracker::new(&query_code, generator_index(&payload.metadata)));
+        // The windows are named after the first invocation of the data source,
+        // which is carried over to the rescheduled invocations, so the payloads
+        // of a window emitted by different invocations have the same qid.
+        let mut payload = payload.clone();
+        if payload.uuid.qid.is_empty() {
+            payload.uuid =
+                UuidBuilder::new_with_ts(&query_code, Utc::now().timestamp(), 1).next_uuid();
+        }
+        continuation.stamp(&mut payload.metadata);
+        Self {
+            backpressure,
+            tracker,
+            route,
+            continuation,
+            payload,
+            sync,
+            query_code,
+            filters: source_filter::from_metadata(&payload.metadata),
+        }
+    }
+
+    /// Returns the id of the window, from which the qid of its payloads is
+    /// derived (see [`UuidBuilder::for_window`]).
+    fn window_id(&self, window: usize) -> String {
+        window_id(&self.payload.uuid, Some(window))
+    }
+
+    /// Returns the index of the first window to emit, which is not 0 if the
+    /// data source was rescheduled or continued.
+    fn first_window(&self) -> usize {
+        resume_window(&self.payload.metadata).unwrap_or(0)
+    }
+
+    /// Waits until the window can be emitted, and records its start. Returns
+    /// false if the data source has been rescheduled or continued to resume
+    /// from the window instead, or has reached the end of its continuations,
+    /// and must return.
+    async fn admit(&mut self, ctx: &ExecutionContext, window: usize) -> Result<bool> {
+        if !self.follow_route(window).await? {
+            return Ok(false);
+        }
+        match self.continuation.step(window) {
+            Step::Emit => {}
+            Step::Continue => {
+                self.continuation
+                    .invoke(&ctx.name, &self.payload, window)
+                    .await?;
+                info!(
+                    "[OK] Continued the data source in invocation {} from window {}.",
+                    self.continuation.invocation() + 1,
+                    window
+                );
+                return Ok(false);
+            }
+            Step::Stop => {
+                info!(
+                    "[OK] The data source stops at window {} after {} invocations.",
+                    window,
+                    self.continuation.invocation() + 1
+                );
+                return Ok(false);
+            }
+        }
+        let tracker = match &self.tracker {
+            Some(tracker) => tracker,
+            None => return Ok(true),
+        };
+        match self.backpressure.acquire(tracker, self.sync).await? {
+            Admission::Proceed => {
+                tracker.record_start(window).await?;
+                Ok(true)
+            }
+            Admission::Reschedule => {
+                self.backpressure
+                    .reschedule(&ctx.name, &self.payload, window)
+                    .await?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Returns the metadata of the window's payloads, stamped with the deadline
+    /// of the window if the deadline factor is set (see
+    /// [`flock::runtime::deadline`]), with the root span of the window's
+    /// trace if the tracing is enabled (see [`flock::runtime::trace`]), and
+    /// with the logical window if the completion protocol is enabled (see
+    /// [`flock::runtime::completion`]).
+    fn window_metadata(
+        &self,
+        ctx: &ExecutionContext,
+        metadata: &Option<QueryMetadata>,
+        window: usize,
+        window_size: usize,
+    ) -> Result<Option<QueryMetadata>> {
+        let mut metadata = deadline::window_metadata(
+            metadata,
+            &SystemClock,
+            Duration::from_secs(window_size as u64),
+            *FLOCK_DEADLINE_WINDOW_FACTOR,
+        )?;
+        if let Some(context) =
+            trace::begin_window(&ctx.name, &self.query_code, &self.window_id(window))
+        {
+            context.stamp(&mut metadata);
+        }
+        topology::stamp_sender(&mut metadata, &ctx.name);
+        completion::stamp_window(
+            &mut metadata,
+            generator_index(&self.payload.metadata),
+            window,
+        );
+        // The filters only concern the data source.
+        if let Some(metadata) = metadata.as_mut() {
+            metadata.remove(SOURCE_FILTER_METADATA_KEY);
+        }
+        Ok(metadata)
+    }
+
+    /// Returns the events of the window that pass the source filters.
+    async fn filter_events(
+        &self,
+        (r1, r2): (RelationPartitions, RelationPartitions),
+    ) -> Result<(RelationPartitions, RelationPartitions)> {
+        Ok((
+            source_filter::filter_partitions(&self.filters, r1).await?,
+            source_filter::filter_partitions(&self.filters, r2).await?,
+        ))
+    }
+
+    /// Hands the data source over to the source function of the switched
+    /// route, resuming from the window. The replaced data source keeps
+    /// emitting to its own function set until the overlap of the route
+    /// expires. Returns false once it must stop.
+    async fn follow_route(&mut self, window: usize) -> Result<bool> {
+        let follower = match &mut self.route {
+            Some(follower) => follower,
+            None => return Ok(true),
+        };
+        if let Some(route) = follower.poll().await? {
+            let payload = route.hand_off(&self.payload, window)?;
+            lambda::invoke_function(
+                &route.active.source,
+                &FLOCK_LAMBDA_ASYNC_CALL,
+                Some(serde_json::to_vec(&payload)?.into()),
+            )
+            .await?;
+            info!(
+                "[OK] Handed the data source over to {} from window {}.",
+                route.active.source, window
+            );
+            route.retire(&mut self.payload.metadata);
+            follower.retire(&route);
+        }
+        Ok(!follower.is_expired(Utc::now().timestamp_millis()))
+    }
+}
@@ -11,171 +11,58 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use super::coalesce_windows;
+use super::InlineResults;
 use crate::actor::*;
-use crate::{consistent_hash_context, ConsistentHashContext, CONSISTENT_HASH_CONTEXT};
-use chrono::{DateTime, NaiveDateTime, Utc};
-use datafusion::arrow::array::{Int32Array, TimestampMillisecondArray, UInt64Array};
+use crate::{consistent_hash_context, next_function};
+use chrono::Utc;
 use datafusion::arrow::record_batch::RecordBatch;
-use datafusion::datasource::MemTable;
-use datafusion::execution::context::ExecutionContext as DataFusionExecutionContext;
-use datafusion::logical_plan::{col, count_distinct};
-use datafusion::physical_plan::expressions::col as expr_col;
-use datafusion::physical_plan::Partitioning::{HashDiff, RoundRobinBatch};
+use datafusion::physical_plan::Partitioning::RoundRobinBatch;
 use flock::aws::lambda;
 use flock::datasource::nexmark::config::BASE_TIME;
 use flock::prelude::*;
-use log::{info, warn};
+use flock::runtime::arena::{
+    SessionMetadata, END_OF_INPUT_WATERMARK, SESSION_TIME_METADATA_KEY, UPSTREAMS_METADATA_KEY,
+    UPSTREAM_METADATA_KEY,
+};
+use flock::runtime::distribution::SessionAffinity;
+use flock::runtime::logging::spawn_in_span;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Instant;
+use tracing::{info, warn};
 
-/// Get back the input data from the registered table after the query is
-/// executed to avoid copying the input data.
-fn get_input_from_registered_table(
-    ctx: &mut DataFusionExecutionContext,
-    table_name: &str,
-) -> Result<Vec<Vec<RecordBatch>>> {
-    Ok(unsafe {
-        Arc::get_mut_unchecked(
-            &mut ctx
-                .deregister_table(table_name)
-                .map_err(FlockError::DataFusion)?
-                .ok_or_else(|| {
-                    FlockError::Internal(format!("Failed to deregister table `{}`", table_name))
-                })?,
-        )
-        .as_mut_any()
-        .downcast_mut::<MemTable>()
-        .unwrap()
-        .batches()
-    })
-}
-
-/// Add each unique partition to a distinct session window.
-///
-/// # Arguments
-/// * `partitions` - the partitions to be added.
-/// * `windows` - the session windows.
-/// * `timeouts` - the session timeouts.
+/// Returns the names of all functions in the next function group, so that
+/// each of them receives the watermark of the source.
 ///
-/// # Return
-/// The updated session windows.
-fn add_partitions_to_session_windows(
-    partitions: Vec<Vec<RecordBatch>>,
-    windows: &mut HashMap<usize, Vec<Vec<RecordBatch>>>,
-    timeout: usize,
-) -> Result<Vec<Vec<Vec<RecordBatch>>>> {
-    Ok(partitions
-        .into_iter()
-        .filter(|p| !p.is_empty())
-        .map(|p| {
-            let mut session = vec![];
-            let bidder = p[0]
-                .column(1 /* bidder field */)
-                .as_any()
-                .downcast_ref::<Int32Array>()
-                .unwrap()
-                .value(0);
-            let current_timestamp = p[0]
-                .column(3 /* b_date_time field */)
-                .as_any()
-                .downcast_ref::<TimestampMillisecondArray>()
-                .unwrap()
-                .value(0);
-            let curr_date_time = DateTime::<Utc>::from_utc(
-                NaiveDateTime::from_timestamp(current_timestamp / 1000, 0),
-                Utc,
-            );
-
-            if !windows.contains_key(&(bidder as usize)) {
-                windows
-                    .entry(bidder as usize)
-                    .or_insert_with(Vec::new)
-                    .push(p);
-            } else {
-                // get the last batch in the window.
-                let batch = windows
-                    .get(&(bidder as usize))
-                    .unwrap()
-                    .last()
-                    .unwrap()
-                    .last()
-                    .unwrap();
-                // get the last bid record's timestamp.
-                let last_timestamp = batch
-                    .column(3 /* b_date_time field */)
-                    .as_any()
-                    .downcast_ref::<TimestampMillisecondArray>()
-                    .unwrap()
-                    .value(batch.num_rows() - 1);
-                let last_date_time = DateTime::<Utc>::from_utc(
-                    NaiveDateTime::from_timestamp(last_timestamp / 1000, 0),
-                    Utc,
-                );
-
-                // If the current date time isn't 10 seconds later than the last date time,
-                // then we can add the current batch to the window . Otherwise, we have a new
-                // session window.
-                if curr_date_time.signed_duration_since(last_date_time)
-                    > chrono::Duration::seconds(timeout as i64)
-                {
-                    session = windows.remove(&(bidder as usize)).unwrap();
-                }
-                windows
-                    .entry(bidder as usize)
-                    .or_insert_with(Vec::new)
-                    .push(p);
-            }
-            session
-        })
-        .collect::<Vec<Vec<Vec<RecordBatch>>>>())
+/// The next functions are either given by the metadata, or by the execution
+/// context (see [`next_function`]).
+fn function_group(ctx: &ExecutionContext, metadata: &Option<QueryMetadata>) -> Result<Vec<String>> {
+    Ok(match next_function(ctx, metadata)? {
+        CloudFunction::Lambda(name) => vec![name],
+        CloudFunction::Group((name, 1)) => vec![name],
+        CloudFunction::Group((name, size)) => {
+            (0..size).map(|i| format!("{}-{:02}", name, i)).collect()
+        }
+        CloudFunction::Sink(..) => vec![],
+    })
 }
 
-/// Find new session windows after timeout.
-///
-/// # Arguments
-/// * `windows` - the session windows.
-/// * `timeout` - the session timeout.
-/// * `epoch_count` - the number of epochs have been processed.
-///
-/// # Return
-/// The keys of the new session windows.
-fn find_timeout_session_windows(
-    windows: &HashMap<usize, Vec<Vec<RecordBatch>>>,
+/// Returns the session window settings of the current source function.
+fn session_metadata(
+    metadata: &Option<QueryMetadata>,
+    group_key: &str,
     timeout: usize,
-    epoch_count: usize,
-) -> Result<Vec<usize>> {
-    let mut to_remove = vec![];
-
-    windows.iter().for_each(|(bidder, batches)| {
-        // Get the last batch
-        let batch = batches.last().unwrap().last().unwrap();
-
-        // Get the last bid's date time
-        let last_timestamp = batch
-            .column(3 /* b_date_time field */)
-            .as_any()
-            .downcast_ref::<TimestampMillisecondArray>()
-            .unwrap()
-            .value(batch.num_rows() - 1);
-
-        let last_date_time =
-            DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(last_timestamp / 1000, 0), Utc);
-
-        let epoch_gap_time = DateTime::<Utc>::from_utc(
-            NaiveDateTime::from_timestamp(BASE_TIME as i64 / 1000 + epoch_count as i64, 0),
-            Utc,
-        );
-
-        if epoch_gap_time.signed_duration_since(last_date_time)
-            > chrono::Duration::seconds(timeout as i64)
-        {
-            to_remove.push(bidder.to_owned());
-        }
-    });
-
-    Ok(to_remove)
+) -> SessionMetadata {
+    let get = |key: &str| metadata.as_ref().and_then(|m| m.get(key)).cloned();
+    SessionMetadata {
+        key:       group_key.to_owned(),
+        time:      get(SESSION_TIME_METADATA_KEY).unwrap_or_else(|| "b_date_time".to_string()),
+        gap:       timeout as i64 * 1000,
+        upstream:  get(UPSTREAM_METADATA_KEY).unwrap_or_else(|| "0".to_string()),
+        upstreams: get(UPSTREAMS_METADATA_KEY)
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(1),
+    }
 }
 
 /// Session windows group events that arrive at similar times, filtering out
@@ -184,18 +71,34 @@ fn find_timeout_session_windows(
 /// from the last ingested event, then the window extends to include the new
 /// event. Otherwise if no events occur within the timeout, then the window is
 /// closed at the timeout.
+///
+/// The source function doesn't close the session windows, since the events of
+/// a session can be generated by different source functions. Instead, the
+/// events of each key are routed to the same function of the next stage, and
+/// the payloads carry the watermark of the source. The next stage merges the
+/// sessions, and emits them once the stage-wide watermark passes the timeout.
+/// After the last epoch, the source sends [`END_OF_INPUT_WATERMARK`] to every
+/// function, so that the sessions still open at the end of the input are
+/// emitted as well.
 pub async fn launch_tasks(
+    ctx: &ExecutionContext,
     payload: Payload,
     stream: Arc<dyn DataStream>,
     seconds: usize,
     timeout: usize,
+    results: &InlineResults,
 ) -> Result<()> {
     if seconds < timeout {
         warn!("seconds: {} is less than timeout: {}", seconds, timeout);
     }
     let sync = infer_invocation_type(&payload.metadata)?;
-    let (group_key, table_name) = infer_session_keys(&payload.metadata)?;
-    let (ring, group_name) = consistent_hash_context!();
+    let (group_key, _) = infer_session_keys(&payload.metadata)?;
+    let session = session_metadata(&payload.metadata, &group_key, timeout);
+    let affinity = SessionAffinity::infer(ctx.session_affinity.as_ref(), &payload.metadata)
+        .unwrap_or_else(|| SessionAffinity::new(&group_key, *FLOCK_SESSION_BUCKETS));
+    let functions = function_group(ctx, &payload.metadata)?;
+    let hash_context = consistent_hash_context(&next_function(ctx, &payload.metadata)?);
+    let (ring, group_name) = (&hash_context.ring, &hash_context.group_name);
 
     let (invocation_type, granule_size) = if sync {
         (FLOCK_LAMBDA_SYNC_CALL.to_string(), *FLOCK_SYNC_GRANULE_SIZE)
@@ -206,9 +109,6 @@ pub async fn launch_tasks(
         )
     };
 
-    let mut ctx = DataFusionExecutionContext::new();
-    let mut windows: HashMap<usize, Vec<Vec<RecordBatch>>> = HashMap::new();
-
     let events = (0..seconds)
         .map(|t| {
             let (r1, _) = stream
@@ -225,85 +125,96 @@ pub async fn launch_tasks(
 
     let schema = events[0][0][0].schema();
 
-    for (time, batches) in events.into_iter().enumerate() {
+    // The epoch after the last one carries no events, and flushes the sessions.
+    let epochs = events.into_iter().map(Some).chain(std::iter::once(None));
+    for (time, batches) in epochs.enumerate() {
         info!("Processing events in epoch: {}", time);
         let now = Instant::now();
-        let table = MemTable::try_new(schema.clone(), batches)?;
-        ctx.deregister_table(&*table_name)?;
-        ctx.register_table(&*table_name, Arc::new(table))?;
-
-        // Equivalent to `SELECT COUNT(DISTINCT group_key) FROM table_name;`
-        let output = ctx
-            .table(&*table_name)?
-            .aggregate(vec![], vec![count_distinct(col(&group_key))])?
-            .collect()
-            .await?;
-
-        let distinct_keys = output[0]
-            .column(0)
-            .as_any()
-            .downcast_ref::<UInt64Array>()
-            .unwrap()
-            .value(0);
-
-        // Each partition has a unique key after `repartition` execution.
-        let partitions = repartition(
-            get_input_from_registered_table(&mut ctx, &table_name)?,
-            HashDiff(vec![expr_col(&group_key, &schema)?], distinct_keys as usize),
-        )
-        .await?;
+        let end_of_input = batches.is_none();
+        let batches = batches.unwrap_or_default();
+
+        // The events of the same key are always routed to the same function by the
+        // bucket of the key, as are the outputs of the upstream functions of the next
+        // stage, and every function receives the watermark even if it has no events.
+        let mut routes: HashMap<String, Vec<RecordBatch>> =
+            functions.iter().map(|f| (f.clone(), vec![])).collect();
+        let partitions = if end_of_input {
+            vec![]
+        } else {
+            affinity.partition(batches)?
+        };
+        for (bucket, partition) in partitions.into_iter().enumerate() {
+            if partition.is_empty() {
+                continue;
+            }
+            let function_name = ring
+                .get(&SessionAffinity::ring_key(bucket))
+                .expect("hash ring failure.")
+                .to_string();
+            routes.entry(function_name).or_default().extend(partition);
+        }
 
-        // Update the window.
-        let mut sessions = add_partitions_to_session_windows(partitions, &mut windows, timeout)?;
-        let to_remove = find_timeout_session_windows(&windows, timeout, time)?;
-        to_remove.iter().for_each(|bidder| {
-            sessions.push(windows.remove(bidder).unwrap());
-        });
+        // All events before the current epoch have been sent.
+        let watermark = if end_of_input {
+            END_OF_INPUT_WATERMARK
+        } else {
+            BASE_TIME as i64 + time as i64 * 1000
+        };
 
-        let tasks = coalesce_windows(sessions, granule_size)?
+        let tasks = routes
             .into_iter()
-            .filter(|session| !session.is_empty())
-            .map(|session| {
+            .map(|(function_name, batches)| {
                 let function_group = group_name.clone();
                 let invoke_type = invocation_type.clone();
-
-                let query_code = group_name.split('-').next().unwrap();
-                let timestamp = Utc::now().timestamp();
-                let rand_id = uuid::Uuid::new_v4().as_u128();
-                let qid = format!("{}-{}-{}", query_code, timestamp, rand_id);
-
-                // Distribute the window data to a single function execution environment.
-                let function_name = ring.get(&qid).expect("hash ring failure.").to_string();
-                info!("Session window -> function name: {}", function_name);
-
-                tokio::spawn(async move {
-                    let output = repartition(session, RoundRobinBatch(1)).await?;
-                    let window = coalesce_batches(output, granule_size * 2).await?;
-                    let size = window[0].len();
+                let results = results.clone();
+                let schema = schema.clone();
+                let mut metadata = QueryMetadata::default();
+                session.to_metadata(&mut metadata);
+
+                spawn_in_span(async move {
+                    let window = if batches.is_empty() {
+                        vec![]
+                    } else {
+                        let output = repartition(vec![batches], RoundRobinBatch(1)).await?;
+                        coalesce_batches(output, granule_size * 2).await?.remove(0)
+                    };
+                    let size = window.len().max(1);
                     let mut uuid_builder =
-                        UuidBuilder::new_with_ts_uuid(&function_group, timestamp, rand_id, size);
+                        UuidBuilder::new_with_ts(&function_group, Utc::now().timestamp(), size);
 
                     // Call the next stage of the dataflow graph.
                     info!(
-                        "[OK] Send {} events from a session window to function: {}.",
-                        size, function_name
+                        "[OK] Send {} batches of session events (watermark: {}) to function: {}.",
+                        window.len(),
+                        watermark,
+                        function_name
                     );
 
-                    for (eid, partition) in window.iter().enumerate() {
-                        let payload = serde_json::to_vec(&to_payload(
-                            partition,
-                            &[],
-                            uuid_builder.next_uuid(),
-                            sync,
-                        ))?;
+                    for eid in 0..size {
+                        let batches = match window.get(eid) {
+                            Some(batch) => std::slice::from_ref(batch),
+                            None => &[],
+                        };
+                        let mut payload = to_payload(batches, &[], uuid_builder.next_uuid(), sync)?;
+                        if payload.schema.is_empty() {
+                            payload.schema = schema_to_bytes(schema.clone());
+                        }
+                        payload.watermark = Some(watermark);
+                        payload.metadata = Some(metadata.clone());
+                        let payload = serde_json::to_vec(&payload)?;
                         info!(
                             "[OK] Event {} - {} function's payload bytes: {}",
                             eid,
                             function_name,
                             payload.len()
                         );
-                        lambda::invoke_function(&function_name, &invoke_type, Some(payload.into()))
-                            .await?;
+                        let response = lambda::invoke_function(
+                            &function_name,
+                            &invoke_type,
+                            Some(payload.into()),
+                        )
+                        .await?;
+                        results.collect(&response)?;
                     }
                     Ok(())
                 })
@@ -312,7 +223,7 @@ pub async fn launch_tasks(
         futures::future::join_all(tasks).await;
 
         let elapsed = now.elapsed().as_millis() as u64;
-        if elapsed < 1000 {
+        if !end_of_input && elapsed < 1000 {
             std::thread::sleep(std::time::Duration::from_millis(1000 - elapsed));
         }
     }
@@ -11,14 +11,94 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use super::is_distributed;
+use super::{is_distributed, InlineResults, WindowGate};
 use crate::actor::*;
-use crate::{consistent_hash_context, ConsistentHashContext, CONSISTENT_HASH_CONTEXT};
-use chrono::Utc;
-use flock::aws::{lambda, s3};
+use crate::{consistent_hash_context, next_function};
+use flock::aws::lambda;
 use flock::prelude::*;
-use log::{info, warn};
+use flock::runtime::analyze::{is_analyze, StageMetrics};
+use flock::runtime::arena::flush_payload;
+use flock::runtime::logging::spawn_in_span;
+use hashring::HashRing;
 use std::sync::Arc;
+use std::time::Instant;
+use tracing::{info, warn};
+
+/// Returns the number of payloads of the window: the partitions of both
+/// relations of each second are paired up into payloads.
+pub fn window_len(window: &[(RelationPartitions, RelationPartitions)]) -> usize {
+    window
+        .iter()
+        .map(|(a, b)| if a.len() > b.len() { a.len() } else { b.len() })
+        .sum::<usize>()
+}
+
+/// Returns the number of payloads of a full window, extrapolated from the
+/// payloads of the first `seconds` seconds of the window. The payloads of a
+/// window are numbered before the data source knows whether it stops in the
+/// middle of the window.
+///
+/// # Arguments
+/// * `size` - The number of payloads produced for the window.
+/// * `seconds` - The number of seconds produced for the window.
+/// * `window_size` - The size of the window in seconds.
+pub fn expected_len(size: usize, seconds: usize, window_size: usize) -> usize {
+    if seconds == 0 || seconds >= window_size {
+        size
+    } else {
+        (size * window_size + seconds - 1) / seconds
+    }
+}
+
+/// Returns the payloads of the window, numbered by the uuid builder.
+pub fn window_payloads(
+    window: &[(RelationPartitions, RelationPartitions)],
+    uuid_builder: &mut UuidBuilder,
+    sync: bool,
+) -> Result<Vec<Payload>> {
+    let empty = vec![];
+    let mut payloads = vec![];
+    for (a, b) in window.iter() {
+        let num = if a.len() > b.len() { a.len() } else { b.len() };
+        for i in 0..num {
+            payloads.push(to_payload(
+                if i < a.len() { &a[i] } else { &empty },
+                if i < b.len() { &b[i] } else { &empty },
+                uuid_builder.next_uuid(),
+                sync,
+            )?);
+        }
+    }
+    Ok(payloads)
+}
+
+/// Returns the flush payloads of the window for all functions of the ring,
+/// each of which carries the number of payloads the function received for the
+/// window (see [`flock::runtime::arena::Arena::flush`]).
+///
+/// # Arguments
+/// * `ring` - The consistent hashing ring of the next function group.
+/// * `qid` - The query id of the window.
+/// * `size` - The number of payloads produced for the window.
+/// * `sync` - Whether the functions are invoked synchronously.
+pub fn flush_payloads(
+    ring: &HashRing<String>,
+    qid: &str,
+    size: usize,
+    sync: bool,
+) -> Vec<(String, Payload)> {
+    let owner = ring.get(&qid.to_string()).expect("hash ring failure.");
+    (0..ring.len())
+        .filter_map(|i| ring.get_by_index(i))
+        .map(|function_name| {
+            let seq_len = if function_name == owner { size } else { 0 };
+            (
+                function_name.to_string(),
+                flush_payload(qid, None, seq_len, sync),
+            )
+        })
+        .collect()
+}
 
 /// Generate tumble windows workloads for the benchmark on cloud
 /// function services.
@@ -28,12 +108,14 @@ use std::sync::Arc;
 /// * `stream` - the source stream of events.
 /// * `seconds` - the total number of seconds to generate workloads.
 /// * `window_size` - the size of the window in seconds.
+/// * `results` - the inline results returned by the next stage.
 pub async fn launch_tasks(
-    ctx: &mut ExecutionContext,
+    ctx: &ExecutionContext,
     payload: Payload,
     stream: Arc<dyn DataStream + Send + Sync>,
     seconds: usize,
     window_size: usize,
+    results: &InlineResults,
 ) -> Result<()> {
     if seconds < window_size {
         warn!(
@@ -41,27 +123,36 @@ pub async fn launch_tasks(
             seconds, window_size
         );
     }
-    let metadata = payload.metadata;
-    let (ring, group_name) = consistent_hash_context!();
-    let sync = infer_invocation_type(&metadata)?;
+    let hash_context = consistent_hash_context(&next_function(ctx, &payload.metadata)?);
+    let (ring, group_name) = (&hash_context.ring, &hash_context.group_name);
+    let sync = infer_invocation_type(&payload.metadata)?;
     let invocation_type = if sync {
         FLOCK_LAMBDA_SYNC_CALL.to_string()
     } else {
         FLOCK_LAMBDA_ASYNC_CALL.to_string()
     };
+    let mut gate = WindowGate::new(ctx, &payload, group_name, sync);
+    let metadata = payload.metadata;
 
     let mut window: Box<Vec<(RelationPartitions, RelationPartitions)>> = Box::new(vec![]);
 
-    for time in 0..seconds / window_size {
+    // The last window is partial if the data source stops in the middle of it.
+    for time in gate.first_window()..(seconds + window_size - 1) / window_size {
+        if !gate.admit(ctx, time).await? {
+            break;
+        }
+        let metadata = gate.window_metadata(ctx, &metadata, time, window_size)?;
         let start = time * window_size;
-        let end = start + window_size;
+        let end = std::cmp::min(start + window_size, seconds);
 
         if is_distributed(ctx) {
             // Distribute the workloads to the cloud function services.
             let mut input1 = vec![];
             let mut input2 = vec![];
             for t in start..end {
-                let (r1, r2) = stream.select_event_to_batches(t, 0, None, sync)?;
+                let (r1, r2) = gate
+                    .filter_events(stream.select_event_to_batches(t, 0, None, sync)?)
+                    .await?;
                 if !r1.is_empty() {
                     input1.push(r1);
                 }
@@ -77,11 +168,20 @@ pub async fn launch_tasks(
                 input.push(input2.into_iter().flatten().collect());
             }
 
+            let mut metrics = is_analyze(&metadata).then(|| StageMetrics::new(&ctx.name));
+            if let Some(m) = metrics.as_mut() {
+                m.record_input(&input);
+            }
+            let start = Instant::now();
             ctx.feed_data_sources(input).await?;
-            let output = Arc::new(ctx.execute_partitioned().await?);
+            let output = ctx.execute_partitioned().await?;
+            if let Some(m) = metrics.as_mut() {
+                m.execute_ms = start.elapsed().as_millis() as u64;
+                output.iter().for_each(|o| m.record_output(o));
+            }
             let size = output[0].len();
             let mut uuid_builder =
-                UuidBuilder::new_with_ts(group_name, Utc::now().timestamp(), size);
+                UuidBuilder::for_window(&gate.query_code, &gate.window_id(time), size);
 
             // Creates the S3 bucket for the current query if state backend is S3.
             if ctx
@@ -90,59 +190,75 @@ pub async fn launch_tasks(
                 .downcast_ref::<S3StateBackend>()
                 .is_some()
             {
-                s3::create_bucket(&uuid_builder.qid).await?;
+                create_state_bucket(&ctx.state_bucket(&uuid_builder.qid)).await?;
             }
 
-            let tasks = (0..size)
+            let mut payloads = (0..size)
                 .map(|i| {
-                    let data = output.clone();
+                    let mut payload = to_payload(
+                        &output[0][i],
+                        if output.len() == 1 {
+                            &[]
+                        } else {
+                            &output[1][i]
+                        },
+                        uuid_builder.next_uuid(),
+                        sync,
+                    )?;
+                    payload.metadata = metadata.clone();
+                    Ok(payload)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            broadcast_build_side(ctx, &mut payloads).await?;
+
+            let tasks = payloads
+                .into_iter()
+                .map(|payload| {
                     let function_name = group_name.clone();
-                    let meta = metadata.clone();
                     let invoke_type = invocation_type.clone();
-                    let uuid = uuid_builder.next_uuid();
-                    tokio::spawn(async move {
-                        let mut payload = to_payload(
-                            &data[0][i],
-                            if data.len() == 1 { &[] } else { &data[1][i] },
-                            uuid,
-                            sync,
-                        );
-                        payload.metadata = meta;
-
+                    let results = results.clone();
+                    spawn_in_span(async move {
                         let bytes = serde_json::to_vec(&payload)?;
                         info!(
                             "[OK] {} function's payload bytes: {}",
                             function_name,
                             bytes.len()
                         );
-                        lambda::invoke_function(&function_name, &invoke_type, Some(bytes.into()))
-                            .await
-                            .map(|_| ())
+                        let response = lambda::invoke_function(
+                            &function_name,
+                            &invoke_type,
+                            Some(bytes.into()),
+                        )
+                        .await?;
+                        results.collect(&response)
                     })
                 })
                 .collect::<Vec<tokio::task::JoinHandle<Result<()>>>>();
             futures::future::join_all(tasks).await;
             ctx.clean_data_sources().await?;
+            if let Some(m) = metrics {
+                let query_code = ctx.query_code();
+                m.report(query_code, &uuid_builder.qid).await?;
+            }
         } else {
             // Update the tumbling window, and generate the next batch of data.
             window.drain(..);
             for t in start..end {
-                window.push(stream.select_event_to_batches(
+                let events = stream.select_event_to_batches(
                     t,
                     0, // generator id
                     payload.query_number,
                     sync,
-                )?);
+                )?;
+                window.push(gate.filter_events(events).await?);
             }
 
             // Calculate the total data packets to be sent.
-            let size = window
-                .iter()
-                .map(|(a, b)| if a.len() > b.len() { a.len() } else { b.len() })
-                .sum::<usize>();
+            let size = window_len(&window);
+            let seq_len = expected_len(size, end - start, window_size);
 
             let mut uuid_builder =
-                UuidBuilder::new_with_ts(group_name, Utc::now().timestamp(), size);
+                UuidBuilder::for_window(&gate.query_code, &gate.window_id(time), seq_len);
 
             // Distribute the window data to a single function execution environment.
             let function_name = ring
@@ -159,26 +275,38 @@ pub async fn launch_tasks(
                 function_name
             );
 
-            let mut eid = 0;
-            let empty = vec![];
-            for (a, b) in window.iter() {
-                let num = if a.len() > b.len() { a.len() } else { b.len() };
-                for i in 0..num {
-                    let payload = serde_json::to_vec(&to_payload(
-                        if i < a.len() { &a[i] } else { &empty },
-                        if i < b.len() { &b[i] } else { &empty },
-                        uuid_builder.next_uuid(),
-                        sync,
-                    ))?;
-                    info!(
-                        "[OK] Event {} - {} function payload bytes: {}",
-                        eid,
-                        function_name,
-                        payload.len()
-                    );
+            let mut payloads = window_payloads(&window, &mut uuid_builder, sync)?;
+            broadcast_build_side(ctx, &mut payloads).await?;
+            for (eid, payload) in payloads.iter().enumerate() {
+                let payload = serde_json::to_vec(payload)?;
+                info!(
+                    "[OK] Event {} - {} function payload bytes: {}",
+                    eid,
+                    function_name,
+                    payload.len()
+                );
+                let response =
                     lambda::invoke_function(&function_name, &invocation_type, Some(payload.into()))
                         .await?;
-                    eid += 1;
+                results.collect(&response)?;
+            }
+
+            if seq_len > size {
+                // The data source stopped in the middle of the window, so the
+                // functions are told how many payloads were actually produced.
+                info!(
+                    "[OK] Flush the window (epoch: {}-{}) with {} of {} payloads.",
+                    start, end, size, seq_len
+                );
+                for (function_name, payload) in flush_payloads(ring, &uuid_builder.qid, size, sync)
+                {
+                    let response = lambda::invoke_function(
+                        &function_name,
+                        &invocation_type,
+                        Some(serde_json::to_vec(&payload)?.into()),
+                    )
+                    .await?;
+                    results.collect(&response)?;
                 }
             }
         }
@@ -15,10 +15,12 @@
 
 use crate::window::*;
 use flock::prelude::*;
-use log::info;
+use flock::runtime::completion::{generator_index, is_completion, SourceReport};
+use flock::runtime::continuation::Continuation;
 use serde_json::json;
 use serde_json::Value;
 use std::sync::Arc;
+use tracing::info;
 
 /// The endpoint of the data source generator function invocation. The data
 /// source generator function is responsible for generating the data packets for
@@ -30,13 +32,19 @@ use std::sync::Arc;
 ///
 /// # Returns
 /// A JSON object that contains the return value of the function invocation.
-pub async fn handler(ctx: &mut ExecutionContext, payload: Payload) -> Result<Value> {
+pub async fn handler(ctx: &ExecutionContext, payload: Payload) -> Result<Value> {
     // Copy data source from the payload.
     let mut source = match payload.datasource.clone() {
         DataSource::YSBEvent(source) => source,
         _ => unreachable!(),
     };
 
+    // The window in the execution context takes precedence over the window
+    // compiled into the data source, so it can be changed without rebuilding.
+    if let Some(window) = ctx.window.clone() {
+        source.window = window;
+    }
+
     // Each source function is a data generator.
     let gen = source.config.get_as_or("threads", 1);
     let sec = source.config.get_as_or("seconds", 10);
@@ -54,11 +62,31 @@ pub async fn handler(ctx: &mut ExecutionContext, payload: Payload) -> Result<Val
     info!("{:?}", source);
     info!("[OK] Generate YSB events.");
 
+    if is_completion(&payload.metadata) {
+        let report = SourceReport {
+            generators: gen,
+            windows:    Continuation::from_conf(&payload.metadata)
+                .total_windows(source.window.num_windows(sec)),
+        };
+        report
+            .report("ysb", generator_index(&payload.metadata))
+            .await?;
+    }
+
+    let results = InlineResults::default();
     if let Window::Tumbling(Schedule::Seconds(window_size)) = source.window {
-        tumbling::launch_tasks(ctx, payload, events, sec, window_size).await?;
+        tumbling::launch_tasks(ctx, payload, events, sec, window_size, &results).await?;
     } else {
-        unreachable!();
+        return Err(FlockError::Execution(format!(
+            "YSB only supports tumbling windows, but got {}",
+            source.window
+        )));
     }
 
-    Ok(json!({"name": &ctx.name, "type": "ysb_bench".to_string()}))
+    // The results of the query are returned inline if it ends in a response
+    // data sink.
+    match results.into_response(ctx).await? {
+        Value::Null => Ok(json!({"name": &ctx.name, "type": "ysb_bench".to_string()})),
+        response => Ok(response),
+    }
 }
@@ -0,0 +1,214 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The Arrow Flight server of the window results.
+//!
+//! The cloud functions write the results of each window to the data sink with
+//! [`ResultStore`]. [`ResultsService`] serves them over Arrow Flight, so the
+//! clients can read the windows of a query without access to the sink:
+//!
+//! * `ListFlights` lists the windows of the query code in the criteria, or the
+//!   windows of all queries if the criteria is empty. The descriptor of each
+//!   flight is the path `[query code, window id]`.
+//! * `DoGet` streams the record batches of the window in the ticket.
+//!
+//! [`flock::datasink::results::ResultsClient`] is the client of the service.
+
+use datafusion::arrow::ipc::writer::IpcWriteOptions;
+use datafusion::arrow_flight::flight_descriptor::DescriptorType;
+use datafusion::arrow_flight::flight_service_server::{FlightService, FlightServiceServer};
+use datafusion::arrow_flight::{
+    Action, ActionType, Criteria, Empty, FlightData, FlightDescriptor, FlightEndpoint, FlightInfo,
+    HandshakeRequest, HandshakeResponse, IpcMessage, PutResult, SchemaAsIpc, SchemaResult, Ticket,
+};
+use flock::datasink::results::{
+    parse_window_ticket, window_flight_data, window_ticket, ResultStore,
+};
+use flock::error::FlockError;
+use futures::Stream;
+use std::convert::TryInto;
+use std::pin::Pin;
+use tonic::{Request, Response, Status, Streaming};
+
+type BoxedFlightStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send + Sync + 'static>>;
+
+/// Returns the Arrow Flight service of the window results in the store.
+pub fn service(store: ResultStore) -> FlightServiceServer<ResultsService> {
+    FlightServiceServer::new(ResultsService::new(store))
+}
+
+/// The Arrow Flight service that serves the window results in the store.
+#[derive(Debug, Clone)]
+pub struct ResultsService {
+    store: ResultStore,
+}
+
+impl ResultsService {
+    /// Creates a new service of the window results in the store.
+    pub fn new(store: ResultStore) -> Self {
+        Self { store }
+    }
+}
+
+fn to_status(e: FlockError) -> Status {
+    Status::internal(e.to_string())
+}
+
+fn window_descriptor(query_code: &str, window_id: &str) -> FlightDescriptor {
+    FlightDescriptor {
+        r#type: DescriptorType::Path as i32,
+        cmd:    vec![],
+        path:   vec![query_code.to_string(), window_id.to_string()],
+    }
+}
+
+fn window_endpoint(query_code: &str, window_id: &str) -> FlightEndpoint {
+    FlightEndpoint {
+        ticket:   Some(window_ticket(query_code, window_id)),
+        location: vec![],
+    }
+}
+
+#[tonic::async_trait]
+impl FlightService for ResultsService {
+    type HandshakeStream = BoxedFlightStream<HandshakeResponse>;
+    type ListFlightsStream = BoxedFlightStream<FlightInfo>;
+    type DoGetStream = BoxedFlightStream<FlightData>;
+    type DoPutStream = BoxedFlightStream<PutResult>;
+    type DoActionStream = BoxedFlightStream<datafusion::arrow_flight::Result>;
+    type ListActionsStream = BoxedFlightStream<ActionType>;
+    type DoExchangeStream = BoxedFlightStream<FlightData>;
+
+    async fn handshake(
+        &self,
+        _request: Request<Streaming<HandshakeRequest>>,
+    ) -> Result<Response<Self::HandshakeStream>, Status> {
+        Err(Status::unimplemented("Handshake is not supported"))
+    }
+
+    async fn list_flights(
+        &self,
+        request: Request<Criteria>,
+    ) -> Result<Response<Self::ListFlightsStream>, Status> {
+        let query_code = String::from_utf8(request.into_inner().expression)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let flights = self
+            .store
+            .windows(&query_code)
+            .await
+            .map_err(to_status)?
+            .into_iter()
+            .map(|(query, window)| {
+                Ok(FlightInfo {
+                    schema:            vec![],
+                    flight_descriptor: Some(window_descriptor(&query, &window)),
+                    endpoint:          vec![window_endpoint(&query, &window)],
+                    total_records:     -1,
+                    total_bytes:       -1,
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(Response::new(Box::pin(futures::stream::iter(flights))))
+    }
+
+    async fn get_flight_info(
+        &self,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        let descriptor = request.into_inner();
+        let (query, window) = match descriptor.path.as_slice() {
+            [query, window] => (query.clone(), window.clone()),
+            _ => {
+                return Err(Status::invalid_argument(
+                    "The descriptor must be the path [query code, window id]",
+                ))
+            }
+        };
+        let batches = self
+            .store
+            .get(&query, &window)
+            .await
+            .map_err(to_status)?
+            .ok_or_else(|| Status::not_found(format!("No results of the window {}", window)))?;
+        let schema = match batches.first() {
+            Some(batch) => {
+                let options = IpcWriteOptions::default();
+                let IpcMessage(schema) = SchemaAsIpc::new(&batch.schema(), &options)
+                    .try_into()
+                    .map_err(|e: datafusion::arrow::error::ArrowError| {
+                        Status::internal(e.to_string())
+                    })?;
+                schema
+            }
+            None => vec![],
+        };
+        Ok(Response::new(FlightInfo {
+            schema,
+            flight_descriptor: Some(descriptor),
+            endpoint: vec![window_endpoint(&query, &window)],
+            total_records: batches.iter().map(|b| b.num_rows() as i64).sum(),
+            total_bytes: -1,
+        }))
+    }
+
+    async fn get_schema(
+        &self,
+        _request: Request<FlightDescriptor>,
+    ) -> Result<Response<SchemaResult>, Status> {
+        Err(Status::unimplemented("GetSchema is not supported"))
+    }
+
+    async fn do_get(
+        &self,
+        request: Request<Ticket>,
+    ) -> Result<Response<Self::DoGetStream>, Status> {
+        let (query, window) = parse_window_ticket(&request.into_inner())
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let batches = self
+            .store
+            .get(&query, &window)
+            .await
+            .map_err(to_status)?
+            .ok_or_else(|| Status::not_found(format!("No results of the window {}", window)))?;
+        let messages = window_flight_data(&batches).into_iter().map(Ok);
+        Ok(Response::new(Box::pin(futures::stream::iter(messages))))
+    }
+
+    async fn do_put(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoPutStream>, Status> {
+        Err(Status::unimplemented("DoPut is not supported"))
+    }
+
+    async fn do_action(
+        &self,
+        _request: Request<Action>,
+    ) -> Result<Response<Self::DoActionStream>, Status> {
+        Err(Status::unimplemented("DoAction is not supported"))
+    }
+
+    async fn list_actions(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::ListActionsStream>, Status> {
+        Err(Status::unimplemented("ListActions is not supported"))
+    }
+
+    async fn do_exchange(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoExchangeStream>, Status> {
+        Err(Status::unimplemented("DoExchange is not supported"))
+    }
+}
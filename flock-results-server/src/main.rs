@@ -0,0 +1,63 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The command line of the Arrow Flight server of the window results.
+
+use flock::aws::client::AwsCloudClient;
+use flock::configs::{FLOCK_EFS_MOUNT_PATH, FLOCK_S3_BUCKET};
+use flock::datasink::results::ResultStore;
+use flock::error::{FlockError, Result};
+use log::info;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "flock-results-server")]
+struct ResultsServerOpt {
+    /// The port of the server.
+    #[structopt(short = "p", long = "port", default_value = "50051")]
+    port: u16,
+
+    /// Serves the results in the local directory instead of the S3 bucket.
+    #[structopt(long = "root", parse(from_os_str))]
+    root: Option<PathBuf>,
+
+    /// Serves the results in the EFS mount path instead of the S3 bucket.
+    #[structopt(long = "efs")]
+    efs: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+    let opt = ResultsServerOpt::from_args();
+    let store = match (opt.root, opt.efs) {
+        (Some(root), _) => ResultStore::Directory(root),
+        (None, true) => ResultStore::Directory(PathBuf::from(&*FLOCK_EFS_MOUNT_PATH)),
+        (None, false) => ResultStore::S3 {
+            bucket: FLOCK_S3_BUCKET.clone(),
+            client: Arc::new(AwsCloudClient),
+        },
+    };
+
+    let addr = format!("0.0.0.0:{}", opt.port)
+        .parse()
+        .map_err(|e| FlockError::Internal(format!("Invalid address: {}", e)))?;
+    info!("Serving the window results of {:?} on {}", store, addr);
+    tonic::transport::Server::builder()
+        .add_service(flock_results_server::service(store))
+        .serve(addr)
+        .await
+        .map_err(|e| FlockError::Internal(e.to_string()))
+}
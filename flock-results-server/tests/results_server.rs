@@ -0,0 +1,53 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Serves the window results written to a local directory over Arrow Flight
+//! on localhost, and reads them back with the results client.
+
+use flock::datasink::results::{ResultStore, ResultsClient};
+use flock::error::Result;
+use flock::testing::int64_string_batch;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+
+#[tokio::test]
+async fn serve_window_results() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("flock-results-{}", uuid::Uuid::new_v4()));
+    let store = ResultStore::Directory(root.clone());
+    let batches = vec![
+        int64_string_batch(vec![1, 2, 3]),
+        int64_string_batch(vec![4, 5]),
+    ];
+    store.put("q1", "w-00", &batches).await?;
+    store.put("q1", "w-01", &batches[1..]).await?;
+    store.put("q2", "w-00", &batches[..1]).await?;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(flock_results_server::service(store))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+
+    let mut client = ResultsClient::connect(format!("http://{}", addr)).await?;
+    assert_eq!(client.list_windows("q1").await?, vec!["w-00", "w-01"]);
+    assert_eq!(client.list_windows("q3").await?, Vec::<String>::new());
+    assert_eq!(client.get_window("q1", "w-00").await?, batches);
+    assert_eq!(client.get_window("q1", "w-01").await?, batches[1..]);
+    assert_eq!(client.get_window("q2", "w-00").await?, batches[..1]);
+    assert!(client.get_window("q1", "w-02").await.is_err());
+
+    std::fs::remove_dir_all(root)?;
+    Ok(())
+}
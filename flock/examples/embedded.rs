@@ -0,0 +1,71 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! This example embeds Flock into an application: it defines a stream over a
+//! memory data source, submits a filter-aggregate query, and awaits the
+//! results.
+//!
+//! ```shell
+//! cargo run --example embedded
+//! ```
+//!
+//! Replace `DeployOptions::local()` with `DeployOptions::lambda()` and the
+//! memory data source with a Kinesis, Kafka or NEXMark source to run the same
+//! query on AWS Lambda.
+
+use datafusion::arrow::array::{Float64Array, StringArray};
+use datafusion::arrow::datatypes::{DataType, Field, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::arrow::util::pretty::pretty_format_batches;
+use flock::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Define the stream.
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("symbol", DataType::Utf8, false),
+        Field::new("price", DataType::Float64, false),
+    ]));
+    let batch = RecordBatch::try_new(
+        schema.clone(),
+        vec![
+            Arc::new(StringArray::from(vec![
+                "AMZN", "GOOG", "AMZN", "MSFT", "GOOG", "MSFT",
+            ])),
+            Arc::new(Float64Array::from(vec![
+                3370.0, 2830.5, 3389.5, 301.2, 2845.0, 12.5,
+            ])),
+        ],
+    )?;
+
+    // Submit the query.
+    let query = Query::new(
+        "SELECT symbol, COUNT(*), MAX(price) FROM ticker WHERE price > 100 GROUP BY symbol",
+        vec![Table::new("ticker", schema)],
+        DataSource::Memory,
+        DataSinkType::Blackhole,
+        Some("ticker"),
+        QueryType::Streaming(StreamType::Regular),
+        Arc::new(HashMapStateBackend::new()),
+    );
+    let opts = DeployOptions::local().with_sources(vec![vec![vec![batch]]]);
+    let mut handle = run_query(query, opts).await?;
+
+    // Await the results.
+    let results = handle.await_window_results(Duration::from_secs(60)).await?;
+    println!("{}", pretty_format_batches(&results)?);
+
+    handle.teardown().await
+}
@@ -0,0 +1,1708 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The high-level API to embed Flock into an application. [`run_query`]
+//! deploys a [`Query`] to the cloud function services (or runs it on the
+//! local machine), starts its data source, and returns a [`QueryHandle`] to
+//! collect the results and release the resources of the query. The functions
+//! of a query are deployed without starting it with [`deploy_query`]. A running
+//! query is replaced by a new one without stopping its data source with
+//! [`update_query`], the function group fed by its data source is resized
+//! with [`resize_group`], and the output of its stages is sampled with
+//! [`peek`]. A batch query over the S3 objects is run periodically with
+//! [`schedule_query`]. The concurrency of the functions of a query is reported
+//! by [`query_quota`].
+
+use crate::aws::client::{
+    AwsCloudClient, CloudClient, EventSource, RecordingCloudClient, Resource,
+};
+use crate::aws::reconcile::ReconcileReport;
+use crate::aws::{cloudwatch, events, lambda, s3, sqs};
+use crate::configs::*;
+use crate::datasink::{inline_response, DataSink, DataSinkFormat, DataSinkType};
+use crate::datasource::s3::S3ObjectsSource;
+use crate::datasource::{DataSource, RelationPartitions};
+use crate::driver::funcgen::estimate::{
+    estimated_cost, MemoryTable, Selectivity, SourceRate, VolumeEstimate,
+};
+use crate::encoding::{train_dictionary, StageEncoding};
+use crate::error::{FlockError, Result};
+use crate::launcher::{AwsLambdaLauncher, Launcher, LocalLauncher};
+use crate::query::Query;
+use crate::runtime::arena::{snapshot, UPSTREAM_METADATA_KEY};
+use crate::runtime::capability;
+use crate::runtime::completion::{
+    completion_key_prefix, in_flight_windows, CompletionManifest, COMPLETION_METADATA_KEY,
+};
+use crate::runtime::deadline::{Deadline, SystemClock};
+use crate::runtime::dictionary::{source_samples, MAX_DICTIONARY_SIZE};
+use crate::runtime::function_name::{query_code_of, FunctionName};
+use crate::runtime::lint::{LintIssue, LintLevel};
+use crate::runtime::metadata::QueryMetadata;
+use crate::runtime::payload::Payload;
+use crate::runtime::peek::{PeekMarker, Peeks};
+use crate::runtime::response::Response;
+use crate::runtime::running_aggregate::RunningAggregate;
+use crate::runtime::scaling::{scalable_group, ScalingHints, MAX_GROUP_SIZE, MIN_GROUP_SIZE};
+use crate::runtime::schedule::{rule_name, rule_prefix, scheduled_input, SCHEDULE_TARGET_ID};
+use crate::runtime::source_filter::{self, SourceFilter};
+use crate::runtime::switchover::{Route, RouteTable, RouteTarget, ROUTE_METADATA_KEY};
+use crate::runtime::transport::{pointer_prefix, queue_name};
+use crate::state::{CleanupReport, S3StateBackend, StateBackend, StateCleanup};
+use crate::stream::{Schedule, Window};
+use chrono::Utc;
+use datafusion::arrow::record_batch::RecordBatch;
+use log::{info, warn};
+use rusoto_lambda::{CreateEventSourceMappingRequest, EventSourceMappingConfiguration};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// The interval between two polls of the completion manifest.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Where the query is deployed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeployTarget {
+    /// The query is executed on the local machine by [`LocalLauncher`].
+    Local,
+    /// The query is deployed to AWS Lambda by [`AwsLambdaLauncher`].
+    AwsLambda,
+}
+
+/// The options to deploy a query with [`run_query`].
+#[derive(Debug, Clone)]
+pub struct DeployOptions {
+    /// Where the query is deployed to.
+    pub target:               DeployTarget,
+    /// The number of functions in each function group.
+    pub group_size:           usize,
+    /// The memory size of the lambda functions in MB.
+    pub memory_size:          i64,
+    /// The architecture of the lambda functions: `x86_64` or `arm64`.
+    pub architecture:         String,
+    /// Whether to reuse the existing functions of the query instead of
+    /// updating their code.
+    pub reuse_functions:      bool,
+    /// The data of the relations of a memory data source.
+    pub sources:              Vec<RelationPartitions>,
+    /// Whether to deploy the query despite the warnings of the plan lint (see
+    /// [`crate::runtime::lint`]).
+    pub force:                bool,
+    /// The concurrency reserved for each function that isn't a member of a
+    /// function group, or `None` to share the unreserved concurrency.
+    pub reserved_concurrency: Option<i64>,
+    /// Whether the last stage appends the window bounds and the query code to
+    /// its output as columns (see [`crate::datasink::enrich`]).
+    pub window_columns:       bool,
+    /// The input rate of the data source, which sizes the memory of each
+    /// stage from its estimated data volume (see
+    /// [`crate::driver::funcgen::estimate`]) instead of `memory_size`.
+    pub source_rate:          Option<SourceRate>,
+    /// The encoding of the payloads between all query stages, or `None` to
+    /// choose it per stage (see [`StageEncoding::for_stage`]).
+    pub encoding:             Option<StageEncoding>,
+    /// Whether the last stage caches the output of the windows in the state
+    /// backend, and reuses it for the same input (see
+    /// [`crate::runtime::result_cache`]).
+    pub result_cache:         bool,
+    /// The running aggregates that the last stage accumulates over the
+    /// windows of the whole run on AWS Lambda (see
+    /// [`crate::runtime::running_aggregate`]).
+    pub running_aggregate:    Option<RunningAggregate>,
+    /// Whether the data source filters the events by the filters of the first
+    /// stage before it sends them (see [`crate::runtime::source_filter`]).
+    pub source_filter:        bool,
+    /// Whether the stages send their payloads to the queues of the next stages
+    /// instead of invoking them (see [`crate::runtime::transport`]).
+    pub via_queue:            bool,
+    /// The sample payload bytes to train the Zstd dictionary of the query
+    /// from (see [`crate::runtime::dictionary`]). The query has no dictionary
+    /// if it's empty, unless it samples the payloads of its data source.
+    pub dictionary_samples:   Vec<Vec<u8>>,
+    /// Whether the driver trains the Zstd dictionary of the query from the
+    /// payloads of its data source if no samples are given (see
+    /// [`source_samples`]).
+    pub zstd_dictionary:      bool,
+    /// The NEXMark query whose events the data source generates, or `None` to
+    /// infer it from the streams that the query reads (see
+    /// [`nexmark_query_for_tables`](crate::datasource::nexmark::nexmark_query_for_tables)).
+    pub query_number:         Option<usize>,
+}
+
+impl Default for DeployOptions {
+    fn default() -> Self {
+        DeployOptions {
+            target:               DeployTarget::AwsLambda,
+            group_size:           *FLOCK_FUNCTION_CONCURRENCY,
+            memory_size:          128,
+            architecture:         "x86_64".to_string(),
+            reuse_functions:      false,
+            sources:              vec![],
+            force:                false,
+            reserved_concurrency: None,
+            window_columns:       false,
+            source_rate:          None,
+            encoding:             None,
+            result_cache:         false,
+            running_aggregate:    None,
+            source_filter:        false,
+            via_queue:            false,
+            dictionary_samples:   vec![],
+            zstd_dictionary:      false,
+            query_number:         None,
+        }
+    }
+}
+
+impl DeployOptions {
+    /// Returns the options to deploy the query to AWS Lambda.
+    pub fn lambda() -> Self {
+        DeployOptions::default()
+    }
+
+    /// Returns the options to run the query on the local machine.
+    pub fn local() -> Self {
+        DeployOptions {
+            target: DeployTarget::Local,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the number of functions in each function group.
+    pub fn with_group_size(mut self, group_size: usize) -> Self {
+        self.group_size = group_size;
+        self
+    }
+
+    /// Sets the memory size of the lambda functions in MB.
+    pub fn with_memory_size(mut self, memory_size: i64) -> Self {
+        self.memory_size = memory_size;
+        self
+    }
+
+    /// Sets the architecture of the lambda functions.
+    pub fn with_architecture<T: Into<String>>(mut self, architecture: T) -> Self {
+        self.architecture = architecture.into();
+        self
+    }
+
+    /// Reuses the existing functions of the query if they are deployed.
+    pub fn with_reuse_functions(mut self, reuse_functions: bool) -> Self {
+        self.reuse_functions = reuse_functions;
+        self
+    }
+
+    /// Sets the data of the relations of a memory data source.
+    pub fn with_sources(mut self, sources: Vec<RelationPartitions>) -> Self {
+        self.sources = sources;
+        self
+    }
+
+    /// Deploys the query despite the warnings of the plan lint.
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Reserves the concurrency of each function that isn't a member of a
+    /// function group.
+    pub fn with_reserved_concurrency(mut self, concurrency: Option<i64>) -> Self {
+        self.reserved_concurrency = concurrency;
+        self
+    }
+
+    /// Appends the window columns `_window_start`, `_window_end` and
+    /// `_query_code` to the results of the query on AWS Lambda.
+    pub fn with_window_columns(mut self, window_columns: bool) -> Self {
+        self.window_columns = window_columns;
+        self
+    }
+
+    /// Sizes the memory of each stage from the data volume that it's estimated
+    /// to process at the given input rate.
+    pub fn with_source_rate(mut self, rate: SourceRate) -> Self {
+        self.source_rate = Some(rate);
+        self
+    }
+
+    /// Compresses the payloads between all query stages with the given
+    /// encoding instead of the encoding chosen per stage.
+    pub fn with_encoding(mut self, encoding: StageEncoding) -> Self {
+        self.encoding = Some(encoding);
+        self
+    }
+
+    /// Reuses the output of the windows executed with the same input before,
+    /// e.g. when the same seeded benchmark is run again during development.
+    pub fn with_result_cache(mut self, result_cache: bool) -> Self {
+        self.result_cache = result_cache;
+        self
+    }
+
+    /// Accumulates the running aggregates over the windows of the whole run,
+    /// e.g. those returned by
+    /// [`crate::runtime::running_aggregate::rewrite_cumulative`] for the
+    /// query.
+    pub fn with_running_aggregate(mut self, running_aggregate: Option<RunningAggregate>) -> Self {
+        self.running_aggregate = running_aggregate;
+        self
+    }
+
+    /// Filters the events at the data source by the predicates of the first
+    /// stage, e.g. to shrink the payloads of a selective query like NEXMark
+    /// q2.
+    pub fn with_source_filter(mut self, source_filter: bool) -> Self {
+        self.source_filter = source_filter;
+        self
+    }
+
+    /// Hands the payloads off between the stages through SQS queues, so that
+    /// a slow stage doesn't throttle the stages before it.
+    pub fn with_via_queue(mut self, via_queue: bool) -> Self {
+        self.via_queue = via_queue;
+        self
+    }
+
+    /// Compresses the payloads that the stages compress with Zstd with a
+    /// dictionary trained from the samples instead, e.g. the Arrow Flight data
+    /// of sample batches (see
+    /// [`payload_samples`](crate::runtime::dictionary::payload_samples)). It
+    /// pays off for the small payloads, e.g. those of the elementwise queries.
+    pub fn with_dictionary_samples(mut self, samples: Vec<Vec<u8>>) -> Self {
+        self.dictionary_samples = samples;
+        self
+    }
+
+    /// Trains the Zstd dictionary of the query from the sample payloads of its
+    /// data source at deployment, e.g. the first epoch of the NEXMark events.
+    pub fn with_zstd_dictionary(mut self, zstd_dictionary: bool) -> Self {
+        self.zstd_dictionary = zstd_dictionary;
+        self
+    }
+
+    /// Sets the NEXMark query whose events the data source generates.
+    pub fn with_query_number(mut self, query_number: usize) -> Self {
+        self.query_number = Some(query_number);
+        self
+    }
+}
+
+/// The deployed resources of a query.
+enum Deployment {
+    /// The local execution of the query, which is `None` once its results are
+    /// consumed or it is cancelled.
+    Local(Option<JoinHandle<Result<Vec<RecordBatch>>>>),
+    /// The lambda functions and the event source mappings of the query, and
+    /// the response of the data source if it's invoked synchronously.
+    AwsLambda {
+        functions: Vec<String>,
+        mappings:  Vec<String>,
+        response:  Option<Vec<u8>>,
+    },
+}
+
+/// The handle of a running query returned by [`run_query`].
+pub struct QueryHandle {
+    query_code:    String,
+    sink_type:     DataSinkType,
+    state_backend: Arc<dyn StateBackend>,
+    deployment:    Deployment,
+}
+
+impl QueryHandle {
+    /// Returns the query code, which is the first component of the names of
+    /// the query's functions.
+    pub fn query_code(&self) -> &str {
+        &self.query_code
+    }
+
+    /// Waits until all windows of the query are processed, or the timeout
+    /// expires, and returns the results from the data sink.
+    ///
+    /// The local execution returns the results of the query directly, as does
+    /// the data source on AWS Lambda if the query ends in the response data
+    /// sink. Otherwise, the windows are tracked by the completion protocol, and
+    /// the results are read from the data sink once all windows are written
+    /// or the timeout expires. The streams read by the event source
+    /// mappings are unbounded, so the results of those queries are read
+    /// after the timeout.
+    pub async fn await_window_results(&mut self, timeout: Duration) -> Result<Vec<RecordBatch>> {
+        match &mut self.deployment {
+            Deployment::Local(execution) => {
+                let handle = execution.as_mut().ok_or_else(|| {
+                    FlockError::Execution(format!(
+                        "The results of {} are consumed or the query is cancelled.",
+                        self.query_code
+                    ))
+                })?;
+                let results = tokio::time::timeout(timeout, handle)
+                    .await
+                    .map_err(|_| {
+                        FlockError::Execution(format!(
+                            "Timed out after {:?} waiting for {}.",
+                            timeout, self.query_code
+                        ))
+                    })?
+                    .map_err(|e| FlockError::Execution(e.to_string()))?;
+                *execution = None;
+                results
+            }
+            Deployment::AwsLambda { response, .. } => {
+                if self.sink_type == DataSinkType::Response {
+                    // The data source is invoked synchronously, and returns the
+                    // results once all windows are processed.
+                    let inline = response.as_deref().map(inline_response).transpose()?;
+                    let inline = inline.flatten().ok_or_else(|| {
+                        FlockError::DataSink(format!(
+                            "No results of {} are returned inline.",
+                            self.query_code
+                        ))
+                    })?;
+                    return Ok(DataSink::from_response(inline).await?.record_batches);
+                }
+                let start = Instant::now();
+                loop {
+                    let manifest = CompletionManifest::fetch(&self.query_code).await?;
+                    if manifest.is_complete(None) {
+                        info!(
+                            "[OK] All {} windows of {} are processed in {:?}.",
+                            manifest.completed_windows(),
+                            self.query_code,
+                            start.elapsed()
+                        );
+                        break;
+                    }
+                    if start.elapsed() >= timeout {
+                        warn!(
+                            "Timed out after {:?}: {} windows of {} are processed.",
+                            timeout,
+                            manifest.completed_windows(),
+                            self.query_code
+                        );
+                        break;
+                    }
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+
+                if self.sink_type == DataSinkType::Blackhole {
+                    return Ok(vec![]);
+                }
+                Ok(DataSink::read(
+                    self.query_code.clone(),
+                    self.sink_type.clone(),
+                    DataSinkFormat::default(),
+                )
+                .await?
+                .record_batches)
+            }
+        }
+    }
+
+    /// Stops the query. The local execution is aborted. On AWS Lambda, the
+    /// event source mappings are deleted and the functions are throttled, so
+    /// that no more events are processed.
+    pub async fn cancel(&mut self) -> Result<()> {
+        match &mut self.deployment {
+            Deployment::Local(execution) => {
+                if let Some(handle) = execution.take() {
+                    handle.abort();
+                }
+            }
+            Deployment::AwsLambda {
+                functions,
+                mappings,
+                ..
+            } => {
+                for uuid in mappings.drain(..) {
+                    lambda::delete_event_source_mapping(&uuid).await?;
+                }
+                for function in functions.iter() {
+                    lambda::throttle_function(function).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the state buckets of the S3 state backend in the background, and
+    /// returns the handle of the cleanup task. It's meant to be called once
+    /// [`QueryHandle::await_window_results`] returns, so that the query no
+    /// longer writes to the buckets. The objects are deleted page by page at
+    /// the rate limit of [`StateCleanup`], and an interrupted cleanup resumes
+    /// from its checkpoint the next time.
+    pub fn cleanup(&self) -> JoinHandle<Result<Vec<CleanupReport>>> {
+        let query_code = self.query_code.clone();
+        let state_buckets = matches!(self.deployment, Deployment::AwsLambda { .. })
+            && self
+                .state_backend
+                .as_any()
+                .downcast_ref::<S3StateBackend>()
+                .is_some();
+        tokio::spawn(async move {
+            if !state_buckets {
+                return Ok(vec![]);
+            }
+            StateCleanup::default().run_query(&query_code).await
+        })
+    }
+
+    /// Stops the query and releases all its resources: the lambda functions,
+    /// the queues of the queue transport, the completion manifest, and the
+    /// state buckets of the S3 state backend.
+    pub async fn teardown(mut self) -> Result<()> {
+        self.cancel().await?;
+        if let Deployment::AwsLambda { functions, .. } = &self.deployment {
+            let state_buckets = self
+                .state_backend
+                .as_any()
+                .downcast_ref::<S3StateBackend>()
+                .is_some();
+            release_functions(&self.query_code, functions, state_buckets).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Creates the queues of the functions that receive their payloads from the
+/// former stages, and the event source mappings that invoke the functions with
+/// the payloads of their queues (see [`crate::runtime::transport`]).
+///
+/// # Returns
+/// The identifiers of the event source mappings.
+async fn create_queues(client: &dyn CloudClient, functions: &[String]) -> Result<Vec<String>> {
+    let mut mappings = vec![];
+    for function in functions {
+        // The first stage is invoked by the data source.
+        if FunctionName::parse(function)?.plan_index == 0 {
+            continue;
+        }
+        let queue = queue_name(function);
+        // A message is hidden from the other invocations until the function
+        // times out, and some more for the retries of the batch.
+        client
+            .create_queue(&queue, 6 * *FLOCK_LAMBDA_TIMEOUT)
+            .await?;
+        mappings.push(
+            client
+                .create_event_source_mapping(&EventSource::Queue(queue), function)
+                .await?,
+        );
+    }
+    info!("[OK] Created {} queues of the functions.", mappings.len());
+    Ok(mappings)
+}
+
+/// Deletes the queues of the query, the event source mappings left on them,
+/// and the oversized payloads in S3 that their messages point to.
+async fn release_queues(query_code: &str) -> Result<()> {
+    let queues = sqs::list_queues(&format!("{}-", query_code)).await?;
+    for queue in &queues {
+        let function = queue.trim_end_matches("-queue");
+        for mapping in lambda::list_event_source_mappings(function).await? {
+            lambda::delete_event_source_mapping(&mapping.uuid.unwrap_or_default()).await?;
+        }
+        sqs::delete_queue(queue).await?;
+    }
+    if !queues.is_empty() {
+        s3::delete_matched_objects(&FLOCK_S3_BUCKET, &pointer_prefix(query_code)).await?;
+    }
+    Ok(())
+}
+
+/// Deletes the lambda functions, the queues and the completion manifest of the
+/// query, and the state buckets that the query created for the S3 state
+/// backend if `state_buckets` is true.
+async fn release_functions(
+    query_code: &str,
+    functions: &[String],
+    state_buckets: bool,
+) -> Result<()> {
+    release_queues(query_code).await?;
+    for function in functions {
+        lambda::delete_function(function).await?;
+    }
+    CompletionManifest::clear(&AwsCloudClient, query_code).await?;
+    if state_buckets {
+        StateCleanup::default().run_query(query_code).await?;
+    }
+    Ok(())
+}
+
+/// Returns the interval in seconds that the event source mapping gathers the
+/// records of the stream before invoking the function.
+fn window_in_seconds(window: &Window) -> i64 {
+    let seconds = match window {
+        Window::Tumbling(Schedule::Seconds(size))
+        | Window::Session(Schedule::Seconds(size))
+        | Window::Global(Schedule::Seconds(size)) => *size,
+        Window::Hopping((_, hop)) | Window::Sliding((_, hop)) => *hop,
+        _ => 1,
+    };
+    // The tumbling window of the event source mapping ranges from 1 second
+    // up to 15 minutes.
+    (seconds as i64).clamp(1, 900)
+}
+
+/// Starts the data source of the query deployed to AWS Lambda. The generated
+/// events are filtered by the given source filters, and are those of the
+/// NEXMark query `query_number`. If `sync` is true, the data source is invoked
+/// synchronously, so that it returns the results of
+/// the query inline (see [`DataSinkType::Response`]).
+///
+/// # Returns
+/// The identifiers of the event source mappings created for the streams, and
+/// the response of the synchronous invocation of the data source.
+async fn start_source(
+    client: &dyn CloudClient,
+    datasource: DataSource,
+    function_name: &str,
+    filters: &[SourceFilter],
+    query_number: Option<usize>,
+    sync: bool,
+) -> Result<(Vec<String>, Option<Vec<u8>>)> {
+    match datasource {
+        #[cfg(feature = "kinesis")]
+        DataSource::KinesisEvent(source) => {
+            let stream = EventSource::Kinesis {
+                stream: source.stream_name.clone(),
+                window: window_in_seconds(&source.window),
+            };
+            Ok((
+                vec![
+                    client
+                        .create_event_source_mapping(&stream, function_name)
+                        .await?,
+                ],
+                None,
+            ))
+        }
+        #[cfg(feature = "kafka")]
+        DataSource::KafkaEvent(source) => {
+            let topics = EventSource::Kafka {
+                cluster_arn: source.cluster_arn.clone(),
+                topics:      source.topics.clone(),
+                window:      window_in_seconds(&source.window),
+            };
+            Ok((
+                vec![
+                    client
+                        .create_event_source_mapping(&topics, function_name)
+                        .await?,
+                ],
+                None,
+            ))
+        }
+        DataSource::Memory => Err(FlockError::NotImplemented(
+            "The memory data source only runs locally, use `DeployOptions::local()`.".to_string(),
+        )),
+        datasource => {
+            let mut metadata = QueryMetadata::default();
+            metadata.insert(COMPLETION_METADATA_KEY.to_string(), "true".to_string());
+            metadata.insert(UPSTREAM_METADATA_KEY.to_string(), "0".to_string());
+            // The data source follows the route of the query, so that the
+            // query can be updated with `update_query`.
+            metadata.insert(
+                ROUTE_METADATA_KEY.to_string(),
+                query_code_of(function_name).to_string(),
+            );
+            let mut metadata = Some(metadata);
+            source_filter::stamp(filters, &mut metadata)?;
+            if *FLOCK_QUERY_TIMEOUT > 0 {
+                Deadline::after(&SystemClock, Duration::from_secs(*FLOCK_QUERY_TIMEOUT))
+                    .stamp(&mut metadata);
+            }
+            let payload = serde_json::to_vec(&Payload {
+                datasource,
+                metadata,
+                query_number,
+                ..Default::default()
+            })?;
+            let invocation_type = if sync {
+                FLOCK_LAMBDA_SYNC_CALL.as_str()
+            } else {
+                FLOCK_LAMBDA_ASYNC_CALL.as_str()
+            };
+            let response = client
+                .invoke(function_name, invocation_type, payload)
+                .await?;
+            Ok((vec![], response))
+        }
+    }
+}
+
+/// Deploys the query and starts its data source.
+///
+/// # Arguments
+/// * `query` - The query to run.
+/// * `opts` - The options to deploy the query.
+///
+/// # Returns
+/// The handle to collect the results and release the resources of the query.
+pub async fn run_query(query: Query, opts: DeployOptions) -> Result<QueryHandle> {
+    match opts.target {
+        DeployTarget::Local => {
+            let mut launcher = LocalLauncher::new(&query).await?;
+            launcher.feed_data_sources(opts.sources)?;
+            Ok(QueryHandle {
+                query_code:    query.query_code().unwrap_or_default(),
+                sink_type:     query.datasink(),
+                state_backend: query.state_backend(),
+                deployment:    Deployment::Local(Some(tokio::spawn(async move {
+                    launcher.collect().await
+                }))),
+            })
+        }
+        DeployTarget::AwsLambda => {
+            let launcher = plan_functions(&query, &opts).await?;
+            let (functions, mappings, response) =
+                deploy(&AwsCloudClient, &launcher, &query, &opts).await?;
+            Ok(QueryHandle {
+                query_code:    launcher.query_code.clone().unwrap_or_default(),
+                sink_type:     query.datasink(),
+                state_backend: query.state_backend(),
+                deployment:    Deployment::AwsLambda {
+                    functions,
+                    mappings,
+                    response,
+                },
+            })
+        }
+    }
+}
+
+/// Deploys the functions of the query on AWS Lambda without starting its data
+/// source. The functions left by a former deployment of the query, e.g. one
+/// that died halfway, are reconciled with the query instead of being created
+/// again (see [`crate::aws::reconcile`]).
+///
+/// # Arguments
+/// * `query` - The query to deploy.
+/// * `opts` - The options to deploy the query.
+///
+/// # Returns
+/// The action taken on each function of the query. The capabilities and the
+/// concurrency of the functions are only set up if none of them failed.
+pub async fn deploy_query(query: &Query, opts: &DeployOptions) -> Result<ReconcileReport> {
+    let launcher = plan_functions(query, opts).await?;
+    let report = launcher
+        .reconcile_cloud_functions(
+            &AwsCloudClient,
+            opts.group_size,
+            opts.memory_size,
+            &opts.architecture,
+            opts.reuse_functions,
+        )
+        .await?;
+    if report.check().is_ok() {
+        launcher
+            .verify_capabilities(&AwsCloudClient, opts.group_size)
+            .await?;
+        launcher
+            .reserve_concurrency(&AwsCloudClient, opts.group_size)
+            .await?;
+    }
+    Ok(report)
+}
+
+/// Plans the functions of the query on AWS Lambda: the cloud contexts of the
+/// query stages, and the memory sizes of the stages if the source rate is set.
+/// The query stages are linted before any function is deployed.
+async fn plan_functions(query: &Query, opts: &DeployOptions) -> Result<AwsLambdaLauncher> {
+    let mut launcher = AwsLambdaLauncher::new(query).await?;
+    launcher.reserved_concurrency = opts.reserved_concurrency;
+    launcher.window_columns = opts.window_columns;
+    launcher.encoding = opts.encoding.clone();
+    launcher.result_cache = opts.result_cache;
+    launcher.running_aggregate = opts.running_aggregate.clone();
+    launcher.source_filter = opts.source_filter;
+    launcher.via_queue = opts.via_queue;
+    let samples = if opts.dictionary_samples.is_empty() && opts.zstd_dictionary {
+        let samples = source_samples(
+            &query.datasource(),
+            source_query_number(query, opts)?,
+            &opts.sources,
+        )?;
+        if samples.is_empty() {
+            warn!("The data source of the query has no payloads to train the Zstd dictionary.");
+        }
+        samples
+    } else {
+        opts.dictionary_samples.clone()
+    };
+    if !samples.is_empty() {
+        launcher.zstd_dict = Some(train_dictionary(&samples, MAX_DICTIONARY_SIZE)?);
+    }
+    launcher.create_cloud_contexts(opts.group_size)?;
+    if let Some(rate) = &opts.source_rate {
+        launcher.size_memory(rate, &MemoryTable::from_conf()?)?;
+    }
+    let report = launcher.lint();
+    report
+        .issues_of(LintLevel::Warning)
+        .iter()
+        .for_each(|i| warn!("{}", i));
+    report.check(opts.force)?;
+    Ok(launcher)
+}
+
+/// Deploys the planned functions of the query with the client, and clears the
+/// completion manifest of a former run of the query.
+///
+/// # Returns
+/// The names of all functions of the query.
+async fn deploy_functions(
+    client: &dyn CloudClient,
+    launcher: &AwsLambdaLauncher,
+    opts: &DeployOptions,
+) -> Result<Vec<String>> {
+    let functions = launcher
+        .create_cloud_functions(
+            client,
+            opts.group_size,
+            opts.memory_size,
+            &opts.architecture,
+            opts.reuse_functions,
+        )
+        .await?;
+    let query_code = launcher.query_code.clone().unwrap_or_default();
+    CompletionManifest::clear(client, &query_code).await?;
+    Ok(functions)
+}
+
+/// Deploys the planned functions of the query with the client, creates their
+/// queues if the payloads are handed off through the queues, and starts the
+/// data source.
+///
+/// # Returns
+/// The names of all functions of the query, the identifiers of the event
+/// source mappings, and the response of the data source if the query returns
+/// its results inline.
+async fn deploy(
+    client: &dyn CloudClient,
+    launcher: &AwsLambdaLauncher,
+    query: &Query,
+    opts: &DeployOptions,
+) -> Result<(Vec<String>, Vec<String>, Option<Vec<u8>>)> {
+    let functions = deploy_functions(client, launcher, opts).await?;
+    let filters = if opts.source_filter {
+        SourceFilter::detect(&[query.plan()?])?
+    } else {
+        vec![]
+    };
+    let mut mappings = if opts.via_queue {
+        create_queues(client, &functions).await?
+    } else {
+        vec![]
+    };
+    let query_code = launcher.query_code.clone().unwrap_or_default();
+    let (streams, response) = start_source(
+        client,
+        query.datasource(),
+        &format!("{}-{:02}", query_code, 0),
+        &filters,
+        source_query_number(query, opts)?,
+        query.datasink() == DataSinkType::Response,
+    )
+    .await?;
+    mappings.extend(streams);
+    Ok((functions, mappings, response))
+}
+
+/// Returns the NEXMark query whose events the data source of the query
+/// generates. Unless it's set in the options, it's the NEXMark query that
+/// reads the same streams as the query.
+fn source_query_number(query: &Query, opts: &DeployOptions) -> Result<Option<usize>> {
+    if opts.query_number.is_some() {
+        return Ok(opts.query_number);
+    }
+    match query.datasource() {
+        #[cfg(feature = "nexmark")]
+        DataSource::NEXMarkEvent(_) | DataSource::S3(_) => {
+            use crate::datasource::nexmark::nexmark_query_for_tables;
+            use crate::runtime::plan::{stream_name, PlanProperties};
+
+            let leaves = PlanProperties::analyze(&query.plan()?).leaf_schemas;
+            let streams = leaves
+                .iter()
+                .filter_map(|schema| stream_name(schema))
+                .collect::<Vec<_>>();
+            nexmark_query_for_tables(&streams).map(Some).ok_or_else(|| {
+                FlockError::Execution(format!(
+                    "No NEXMark query reads the streams {:?}, set it with \
+                     `DeployOptions::with_query_number`.",
+                    streams
+                ))
+            })
+        }
+        _ => Ok(None),
+    }
+}
+
+/// A query stage planned by [`dry_run`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct StagePlan {
+    /// The name of the function of the stage, or of its function group.
+    pub name:        String,
+    /// The number of the functions of the stage.
+    pub functions:   usize,
+    /// The memory size of the functions in MB.
+    pub memory_size: i64,
+    /// The estimated data volume of the stage for each window, or `None` if
+    /// the source rate isn't set.
+    pub estimate:    Option<VolumeEstimate>,
+}
+
+/// The deployment of a query planned by [`dry_run`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DryRunReport {
+    /// The query code of the query.
+    pub query_code:   String,
+    /// The architecture of the functions.
+    pub architecture: String,
+    /// The query stages from the data source to the last stage.
+    pub stages:       Vec<StagePlan>,
+    /// The issues found by the plan lint.
+    pub lint:         Vec<LintIssue>,
+    /// The cloud resources that the deployment would create, in sorted order.
+    pub resources:    Vec<Resource>,
+    /// The functions that would be invoked to start the data source.
+    pub invocations:  Vec<String>,
+}
+
+impl DryRunReport {
+    /// Returns the estimated cost in USD of running the functions of the query
+    /// for the duration (see [`estimated_cost`]).
+    pub fn estimated_cost(&self, duration: Duration) -> f64 {
+        let functions = self
+            .stages
+            .iter()
+            .map(|stage| (stage.memory_size, stage.functions))
+            .collect::<Vec<_>>();
+        estimated_cost(duration, &functions, &self.architecture)
+    }
+}
+
+impl fmt::Display for DryRunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "=== Dry run of {} ===", self.query_code)?;
+        writeln!(f, "Stages:")?;
+        for stage in &self.stages {
+            write!(
+                f,
+                "  {}: {} x {} MB",
+                stage.name, stage.functions, stage.memory_size
+            )?;
+            match &stage.estimate {
+                Some(estimate) => writeln!(f, ", {}", estimate)?,
+                None => writeln!(f)?,
+            }
+        }
+        writeln!(f, "Lint issues: {}", self.lint.len())?;
+        for issue in &self.lint {
+            writeln!(f, "  {}", issue)?;
+        }
+        writeln!(f, "Resources: {}", self.resources.len())?;
+        for resource in &self.resources {
+            writeln!(f, "  {}", resource)?;
+        }
+        for function in &self.invocations {
+            writeln!(f, "Start: invoke {}", function)?;
+        }
+        // The state buckets are named after the windows, so they're only known
+        // at runtime.
+        write!(
+            f,
+            "The state buckets of the query are created by its functions at runtime."
+        )
+    }
+}
+
+/// Plans the deployment of the query to AWS Lambda without creating any cloud
+/// resource. The query goes through the same pipeline as [`run_query`]: the
+/// query stages are planned, linted and sized, and every call to AWS is made
+/// to a [`RecordingCloudClient`] instead, which records the resources that the
+/// deployment would create.
+///
+/// # Arguments
+/// * `query` - The query to plan.
+/// * `opts` - The options to deploy the query.
+/// * `rate` - The input rate that the data volumes of the stages are estimated
+///   at. It defaults to the source rate of the options.
+///
+/// # Returns
+/// The report of the stages, their estimated data volumes and the resources of
+/// the deployment.
+pub async fn dry_run(
+    query: &Query,
+    opts: &DeployOptions,
+    rate: Option<&SourceRate>,
+) -> Result<DryRunReport> {
+    let launcher = plan_functions(query, opts).await?;
+    let client = RecordingCloudClient::new();
+    let (functions, ..) = deploy(&client, &launcher, query, opts).await?;
+
+    let estimates = match rate.or(opts.source_rate.as_ref()) {
+        Some(rate) => launcher.estimate_volumes(rate, &Selectivity::default())?,
+        None => vec![],
+    };
+    let stages = launcher
+        .dag
+        .get_all_stages()
+        .iter()
+        .filter_map(|stage| stage.context.as_ref())
+        .map(|ctx| StagePlan {
+            name:        ctx.name.clone(),
+            functions:   functions
+                .iter()
+                .filter(|f| **f == ctx.name || f.starts_with(&format!("{}-", ctx.name)))
+                .count(),
+            memory_size: launcher
+                .memory_sizes
+                .get(&ctx.name)
+                .copied()
+                .unwrap_or(opts.memory_size),
+            estimate:    estimates
+                .iter()
+                .find(|(name, _)| *name == ctx.name)
+                .map(|(_, estimate)| *estimate),
+        })
+        .collect();
+    Ok(DryRunReport {
+        query_code: launcher.query_code.clone().unwrap_or_default(),
+        architecture: opts.architecture.clone(),
+        stages,
+        lint: launcher.lint().issues,
+        resources: client.resources(),
+        invocations: client
+            .invocations()
+            .into_iter()
+            .map(|invocation| invocation.function)
+            .collect(),
+    })
+}
+
+/// Replaces a query started by [`run_query`] with a new query on AWS Lambda,
+/// without stopping its data source (see [`crate::runtime::switchover`]).
+///
+/// The new query is deployed under the next query code of the route, e.g.
+/// `q1_v1`. If the data source is a stream, the event source mappings are
+/// pointed to the entry stage of the new query, or duplicated for the overlap
+/// period. Otherwise, the switched route is published, and the data source
+/// hands itself over to the new query at the next window boundary. Once the
+/// overlap expires and the in-flight windows of the old query are drained, the
+/// functions of the old query are deleted.
+///
+/// # Arguments
+/// * `name` - The query code that the query was started with.
+/// * `query` - The new query. Its data source is ignored, since the running
+///   data source is kept.
+/// * `opts` - The options to deploy the new query.
+/// * `overlap` - The period that the windows are written to both queries.
+///
+/// # Returns
+/// The handle of the new query.
+pub async fn update_query(
+    name: &str,
+    mut query: Query,
+    opts: DeployOptions,
+    overlap: Duration,
+) -> Result<QueryHandle> {
+    if opts.target != DeployTarget::AwsLambda {
+        return Err(FlockError::NotImplemented(
+            "Only the queries on AWS Lambda can be updated.".to_string(),
+        ));
+    }
+    let table = RouteTable::default();
+    let route = table
+        .lookup(name)
+        .await?
+        .unwrap_or_else(|| Route::new(RouteTarget::entry(name)));
+    let old = route.active.clone();
+
+    query.query_code = Some(route.next_query_code(name));
+    let launcher = plan_functions(&query, &opts).await?;
+    let query_code = launcher.query_code.clone().unwrap_or_default();
+    let functions = deploy_functions(&AwsCloudClient, &launcher, &opts).await?;
+    let queue_mappings = if opts.via_queue {
+        create_queues(&AwsCloudClient, &functions).await?
+    } else {
+        vec![]
+    };
+    let route = route.switch(
+        RouteTarget::entry(&query_code),
+        overlap,
+        Utc::now().timestamp_millis(),
+    );
+
+    let streams = lambda::list_event_source_mappings(&old.source).await?;
+    let mut mappings = queue_mappings;
+    if overlap.is_zero() {
+        for mapping in &streams {
+            let uuid = mapping.uuid.clone().unwrap_or_default();
+            lambda::update_event_source_mapping(&uuid, &route.active.source).await?;
+            mappings.push(uuid);
+        }
+    } else {
+        for mapping in &streams {
+            let request = duplicate_mapping(mapping, &route.active.source);
+            mappings.push(lambda::create_event_source_mapping(request).await?);
+        }
+    }
+    table.publish(name, &route).await?;
+    info!(
+        "[OK] Switched {} from {} to {} (generation {}).",
+        name, old.query_code, query_code, route.generation
+    );
+
+    if streams.is_empty() {
+        wait_for_handover(&query_code).await?;
+    }
+    // The old data source keeps writing to the old query until the overlap
+    // expires.
+    tokio::time::sleep(overlap).await;
+    if !overlap.is_zero() {
+        for mapping in &streams {
+            lambda::delete_event_source_mapping(&mapping.uuid.clone().unwrap_or_default()).await?;
+        }
+    }
+
+    drain_windows(
+        &AwsCloudClient,
+        &old.query_code,
+        Duration::from_secs(*FLOCK_SWITCHOVER_DRAIN_TIMEOUT),
+    )
+    .await?;
+    // Only the resources of the old query are released: its functions, queues
+    // and completion markers are under its own query code, and the state
+    // buckets are the ones it registered at creation (see
+    // [`StateCleanup::run_query`]), not the buckets of the other queries that
+    // share its name as a prefix.
+    let old_functions = lambda::list_functions(&format!("{}-", old.query_code)).await?;
+    release_functions(&old.query_code, &old_functions, true).await?;
+    info!(
+        "[OK] Deleted {} functions of {}.",
+        old_functions.len(),
+        old.query_code
+    );
+
+    Ok(QueryHandle {
+        query_code,
+        sink_type: query.datasink(),
+        state_backend: query.state_backend(),
+        deployment: Deployment::AwsLambda {
+            functions,
+            mappings,
+            response: None,
+        },
+    })
+}
+
+/// Upgrades the function binary of a query started by [`run_query`] without
+/// losing the windows in flight (see [`crate::runtime::arena::snapshot`]).
+///
+/// Each member of the function groups is drained first: it writes the open
+/// windows of its arena to its snapshot, and appends the payloads received
+/// afterwards to the snapshot. Then the code of all functions of the query is
+/// replaced with the function binary in the Flock bucket, and the first
+/// invocation of each member restores its snapshot.
+///
+/// # Arguments
+/// * `name` - The query code that the query was started with.
+///
+/// # Returns
+/// The names of the upgraded functions.
+pub async fn upgrade_query(name: &str) -> Result<Vec<String>> {
+    // The functions of an updated query run under the query code of the route.
+    let query_code = match RouteTable::default().lookup(name).await? {
+        Some(route) => route.active.query_code,
+        None => name.to_string(),
+    };
+    let functions = lambda::list_functions(&format!("{}-", query_code)).await?;
+    if functions.is_empty() {
+        return Err(FlockError::Internal(format!(
+            "The query {} isn't deployed",
+            query_code
+        )));
+    }
+
+    let payload = serde_json::to_vec(&snapshot::drain_payload(&query_code))?;
+    for function in &functions {
+        if !FunctionName::parse(function)?.is_group_member() {
+            continue;
+        }
+        let response = lambda::invoke_function(
+            function,
+            &FLOCK_LAMBDA_SYNC_CALL,
+            Some(payload.clone().into()),
+        )
+        .await?;
+        let ack = Response::from_slice(&response.payload.unwrap_or_default())?
+            .into_result()?
+            .into_data();
+        info!("[OK] Drained {}: {}", function, ack["drained"]);
+    }
+
+    for function in &functions {
+        lambda::update_function_code(function).await?;
+        // The new binary must still have the scalar functions of the plan.
+        let mut ctx = lambda::function_context(function).await?;
+        let plans = ctx.plan.get_execution_plans().await?;
+        capability::handshake(&AwsCloudClient, function, &plans).await?;
+    }
+    info!(
+        "[OK] Upgraded {} functions of {}.",
+        functions.len(),
+        query_code
+    );
+    Ok(functions)
+}
+
+/// Resizes the function group fed by the data source of a query started by
+/// [`run_query`] (see [`crate::runtime::scaling`]).
+///
+/// The additional members are copies of the first member of the group. The
+/// route of the query is published with the resized hash ring, so that the
+/// data source emits the next windows to the new ring while the windows in
+/// flight complete on the old one. The surplus members of a shrunk group are
+/// kept for the windows in flight.
+///
+/// # Arguments
+/// * `name` - The query code that the query was started with.
+/// * `stage` - The plan index of the function group.
+/// * `size` - The new size of the group, or `None` to apply the scaling hint
+///   written by the function feeding the group.
+///
+/// # Returns
+/// The resized route of the query.
+pub async fn resize_group(name: &str, stage: usize, size: Option<usize>) -> Result<Route> {
+    let table = RouteTable::default();
+    let route = table
+        .lookup(name)
+        .await?
+        .unwrap_or_else(|| Route::new(RouteTarget::entry(name)));
+    let (group, current) = scalable_group(&route, stage)?;
+    let size = match size {
+        Some(size) => size,
+        None => ScalingHints::default()
+            .read(&route.active.query_code, stage)
+            .await?
+            .map(|hint| hint.target_size)
+            .ok_or_else(|| {
+                FlockError::Internal(format!("No scaling hint of the function group {}", group))
+            })?,
+    };
+    if !(MIN_GROUP_SIZE..=MAX_GROUP_SIZE).contains(&size) {
+        return Err(FlockError::Internal(format!(
+            "The size of a function group ranges from {} to {}, not {}",
+            MIN_GROUP_SIZE, MAX_GROUP_SIZE, size
+        )));
+    }
+    let members = lambda::list_functions(&format!("{}-", group)).await?;
+    if members.is_empty() {
+        return Err(FlockError::Internal(format!(
+            "The function group {} isn't deployed",
+            group
+        )));
+    }
+    let current = current.unwrap_or(members.len());
+
+    let first = format!("{}-{:02}", group, 0);
+    for i in members.len()..size {
+        let member = lambda::copy_function(&first, &format!("{}-{:02}", group, i)).await?;
+        // Each member of a function group aggregates its own windows.
+        lambda::set_concurrency(&member, 1).await?;
+    }
+    let route = route.resize(&group, size, Utc::now().timestamp_millis());
+    table.publish(name, &route).await?;
+    info!(
+        "[OK] Resized {} from {} to {} members (generation {}).",
+        group, current, size, route.generation
+    );
+    Ok(route)
+}
+
+/// Samples the output of a stage of a running query (see
+/// [`crate::runtime::peek`]). The peek marker of the stage is written, and
+/// removed with the samples once a window is sampled or the timeout expires.
+///
+/// # Arguments
+/// * `name` - The query code that the query was started with.
+/// * `stage` - The plan index of the stage.
+/// * `rows` - The number of rows to sample.
+/// * `timeout` - The maximum time to wait for a window.
+///
+/// # Returns
+/// The first sampled window and its rows, or `None` if no window was sampled
+/// before the timeout.
+pub async fn peek(
+    name: &str,
+    stage: usize,
+    rows: usize,
+    timeout: Duration,
+) -> Result<Option<(String, Vec<RecordBatch>)>> {
+    if rows == 0 {
+        return Err(FlockError::Internal(
+            "The sample needs at least one row".to_string(),
+        ));
+    }
+    // The functions of an updated query run under the query code of the route.
+    let query_code = match RouteTable::default().lookup(name).await? {
+        Some(route) => route.active.query_code,
+        None => name.to_string(),
+    };
+    let peeks = Peeks::default();
+    let marker = PeekMarker {
+        rows,
+        timestamp: Utc::now().timestamp_millis(),
+    };
+    peeks.request(&query_code, stage, &marker).await?;
+
+    let start = Instant::now();
+    let sample: Result<Option<(String, Vec<RecordBatch>)>> = async {
+        loop {
+            if let Some(window) = peeks.windows(&query_code, stage).await?.into_iter().next() {
+                let batches = peeks.read_sample(&query_code, stage, &window).await?;
+                return Ok(Some((window, batches)));
+            }
+            if start.elapsed() > timeout {
+                return Ok(None);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+    .await;
+    peeks.remove(&query_code, stage).await?;
+    sample
+}
+
+/// The concurrency of a function of the query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionQuota {
+    /// The name of the function.
+    pub function: String,
+    /// The reserved concurrency of the function, or `None` if it shares the
+    /// unreserved concurrency of the account.
+    pub reserved: Option<i64>,
+    /// The maximum number of the concurrent executions of the function over
+    /// the last period, or `None` if it wasn't invoked.
+    pub peak:     Option<i64>,
+}
+
+/// The concurrency of the functions of a query returned by [`query_quota`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryQuota {
+    /// The concurrency limit of the account.
+    pub account_limit: i64,
+    /// The concurrency of the account that isn't reserved by any function.
+    pub unreserved:    i64,
+    /// The functions of the query in the order of their names.
+    pub functions:     Vec<FunctionQuota>,
+}
+
+/// Returns the concurrency settings and the current usage of the functions of
+/// a query deployed to AWS Lambda.
+///
+/// # Arguments
+/// * `name` - The query code that the query was started with.
+/// * `period` - The period over which the concurrent executions are measured.
+pub async fn query_quota(name: &str, period: Duration) -> Result<QueryQuota> {
+    // The functions of an updated query run under the query code of the route.
+    let query_code = match RouteTable::default().lookup(name).await? {
+        Some(route) => route.active.query_code,
+        None => name.to_string(),
+    };
+    let mut names = lambda::list_functions(&format!("{}-", query_code)).await?;
+    if names.is_empty() {
+        return Err(FlockError::Internal(format!(
+            "The query {} isn't deployed",
+            query_code
+        )));
+    }
+    names.sort();
+
+    let mut functions = vec![];
+    for function in names {
+        functions.push(FunctionQuota {
+            reserved: lambda::get_concurrency(&function).await?,
+            peak: cloudwatch::concurrent_executions(&function, period).await?,
+            function,
+        });
+    }
+    let (account_limit, unreserved) = lambda::account_concurrency().await?;
+    Ok(QueryQuota {
+        account_limit,
+        unreserved,
+        functions,
+    })
+}
+
+/// Runs the batch query over the S3 objects periodically (see
+/// [`crate::runtime::schedule`]). The query must be deployed with
+/// [`run_query`] first. An EventBridge rule invokes the source function of the
+/// query on the schedule, and each run scans the objects of the last period.
+///
+/// # Arguments
+/// * `query_code` - The query code of the deployed query.
+/// * `name` - The name of the schedule.
+/// * `source` - The S3 objects to scan.
+/// * `schedule` - The rate or cron expression of the schedule.
+/// * `period` - The time range scanned by each run. It defaults to the rate of
+///   the schedule, and it's required for a cron expression.
+///
+/// # Returns
+/// The ARN of the EventBridge rule.
+pub async fn schedule_query(
+    query_code: &str,
+    name: &str,
+    source: &S3ObjectsSource,
+    schedule: &Schedule,
+    period: Option<Duration>,
+) -> Result<String> {
+    let expression = schedule.expression()?;
+    let period = period.or_else(|| schedule.period()).ok_or_else(|| {
+        FlockError::Execution(format!(
+            "The scan period of the schedule {} must be given explicitly",
+            expression
+        ))
+    })?;
+    let (paths, template) = scheduled_input(source, period)?;
+
+    let rule = rule_name(query_code, name);
+    let function_arn = lambda::function_arn(&format!("{}-{:02}", query_code, 0)).await?;
+    let rule_arn = events::put_rule(
+        &rule,
+        &expression,
+        &format!("Scans s3://{}/{}", source.bucket, source.prefix),
+    )
+    .await?;
+    lambda::add_permission(&function_arn, &rule, "events.amazonaws.com", &rule_arn).await?;
+    events::put_lambda_target(&rule, SCHEDULE_TARGET_ID, &function_arn, paths, &template).await?;
+    info!(
+        "[OK] Scheduled {} on {}, scanning the last {:?}.",
+        query_code, expression, period
+    );
+    Ok(rule_arn)
+}
+
+/// Returns the names and the expressions of the schedules of the query.
+pub async fn list_schedules(query_code: &str) -> Result<Vec<(String, String)>> {
+    let prefix = rule_prefix(query_code);
+    Ok(events::list_rules(&prefix)
+        .await?
+        .into_iter()
+        .filter_map(|rule| {
+            let name = rule.name?.strip_prefix(&prefix)?.to_string();
+            Some((name, rule.schedule_expression.unwrap_or_default()))
+        })
+        .collect())
+}
+
+/// Deletes the schedule of the query created by [`schedule_query`].
+pub async fn unschedule_query(query_code: &str, name: &str) -> Result<()> {
+    let rule = rule_name(query_code, name);
+    events::delete_rule(&rule, &[SCHEDULE_TARGET_ID]).await?;
+    lambda::remove_permission(&format!("{}-{:02}", query_code, 0), &rule).await?;
+    info!("[OK] Deleted the schedule {} of {}.", name, query_code);
+    Ok(())
+}
+
+/// Returns the request to create a copy of the event source mapping that
+/// invokes the given function, starting from the latest records.
+fn duplicate_mapping(
+    mapping: &EventSourceMappingConfiguration,
+    function_name: &str,
+) -> CreateEventSourceMappingRequest {
+    CreateEventSourceMappingRequest {
+        batch_size: mapping.batch_size,
+        enabled: Some(true),
+        event_source_arn: mapping.event_source_arn.clone(),
+        function_name: function_name.to_owned(),
+        maximum_batching_window_in_seconds: mapping.maximum_batching_window_in_seconds,
+        parallelization_factor: mapping.parallelization_factor,
+        self_managed_event_source: mapping.self_managed_event_source.clone(),
+        source_access_configurations: mapping.source_access_configurations.clone(),
+        starting_position: Some("LATEST".to_owned()),
+        topics: mapping.topics.clone(),
+        tumbling_window_in_seconds: mapping.tumbling_window_in_seconds,
+        ..Default::default()
+    }
+}
+
+/// Waits until the data source is handed over to the new query, i.e. the new
+/// query has started a window, or the drain timeout expires.
+async fn wait_for_handover(query_code: &str) -> Result<()> {
+    let timeout = Duration::from_secs(*FLOCK_SWITCHOVER_DRAIN_TIMEOUT);
+    let prefix = completion_key_prefix(query_code);
+    let start = Instant::now();
+    while s3::get_matched_keys(&FLOCK_S3_BUCKET, &prefix)
+        .await?
+        .is_empty()
+    {
+        if start.elapsed() >= timeout {
+            warn!(
+                "Timed out after {:?}: the data source isn't handed over to {}, it may have finished.",
+                timeout, query_code
+            );
+            break;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    Ok(())
+}
+
+/// Waits until the in-flight windows of the query are written to the data
+/// sink, or the timeout expires. The windows are counted by their logical ids,
+/// i.e. a window started by the data source is drained once it's recorded as
+/// completed, however many payloads of the window write to the data sink.
+///
+/// # Returns
+/// The number of the windows still in flight.
+async fn drain_windows(
+    client: &dyn CloudClient,
+    query_code: &str,
+    timeout: Duration,
+) -> Result<usize> {
+    let start = Instant::now();
+    loop {
+        let in_flight = in_flight_windows(client, query_code).await?.len();
+        if in_flight == 0 {
+            info!("[OK] Drained {} in {:?}.", query_code, start.elapsed());
+            return Ok(0);
+        }
+        if start.elapsed() >= timeout {
+            warn!(
+                "Timed out after {:?}: {} windows of {} are still in flight.",
+                timeout, in_flight, query_code
+            );
+            return Ok(in_flight);
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_batches_eq;
+    use crate::aws::client::FakeCloudClient;
+    use crate::query::{QueryType, Table};
+    use crate::state::HashMapStateBackend;
+    use datafusion::arrow::array::{Int64Array, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+
+    #[tokio::test]
+    async fn drain_windows_by_logical_id() -> Result<()> {
+        let client = FakeCloudClient::new();
+        let prefix = completion_key_prefix("q1");
+        for marker in ["started/0-0", "started/0-1", "windows/0-0", "sources/0"] {
+            client.put_object(&FLOCK_S3_BUCKET, &format!("{}{}", prefix, marker), vec![]);
+        }
+        // The windows of the next version of the query are not drained.
+        client.put_object(
+            &FLOCK_S3_BUCKET,
+            &format!("{}started/0-2", completion_key_prefix("q1_v1")),
+            vec![],
+        );
+        assert_eq!(drain_windows(&client, "q1", Duration::ZERO).await?, 1);
+
+        client.put_object(&FLOCK_S3_BUCKET, &format!("{}windows/0-1", prefix), vec![]);
+        assert_eq!(drain_windows(&client, "q1", Duration::ZERO).await?, 0);
+        Ok(())
+    }
+
+    fn init_query() -> Result<(Query, Vec<RelationPartitions>)> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("c1", DataType::Utf8, false),
+            Field::new("c2", DataType::Int64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(vec!["a", "b", "a", "c", "b", "a"])),
+                Arc::new(Int64Array::from(vec![1, 2, 3, 4, 5, 6])),
+            ],
+        )?;
+        let query = Query::new(
+            "SELECT c1, SUM(c2) FROM t WHERE c2 > 1 GROUP BY c1 ORDER BY c1",
+            vec![Table::new("t", schema)],
+            DataSource::Memory,
+            DataSinkType::Blackhole,
+            None,
+            QueryType::OLAP,
+            Arc::new(HashMapStateBackend::new()),
+        );
+        Ok((query, vec![vec![vec![batch]]]))
+    }
+
+    #[tokio::test]
+    async fn dry_run_query() -> Result<()> {
+        let (query, _) = init_query()?;
+        let query = Query::new(
+            query.sql(),
+            query.tables().clone(),
+            DataSource::SqsEvent,
+            DataSinkType::Blackhole,
+            None,
+            QueryType::OLAP,
+            Arc::new(HashMapStateBackend::new()),
+        );
+        let opts = DeployOptions::lambda()
+            .with_group_size(2)
+            .with_reserved_concurrency(Some(4))
+            .with_via_queue(true)
+            .with_source_rate(SourceRate {
+                events_per_second: 1000,
+                window_seconds:    10,
+            });
+
+        // The dry run only goes through the recording client, so the client of
+        // the deployment isn't called at all.
+        let client = FakeCloudClient::new();
+        let report = dry_run(&query, &opts, None).await?;
+        assert_eq!(0, client.calls());
+        assert!(report.stages.iter().all(|stage| stage.estimate.is_some()));
+        assert_eq!(
+            report.stages.iter().map(|s| s.functions).sum::<usize>(),
+            report
+                .resources
+                .iter()
+                .filter(|r| matches!(r, Resource::Function { .. }))
+                .count()
+        );
+        assert_eq!(
+            vec![format!("{}-00", report.query_code)],
+            report.invocations
+        );
+
+        // The same deployment on the simulator creates the recorded resources.
+        for resource in &report.resources {
+            if let Resource::Function { name, .. } = resource {
+                let capabilities = capability::Capabilities::detect().await;
+                let response =
+                    Response::ok(name, serde_json::json!({ "capabilities": capabilities }));
+                client.set_response(name, serde_json::to_vec(&response.to_value())?);
+            }
+        }
+        let launcher = plan_functions(&query, &opts).await?;
+        deploy(&client, &launcher, &query, &opts).await?;
+        assert_eq!(report.resources, client.resources());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "zstd")]
+    async fn train_dictionary_from_sources() -> Result<()> {
+        let (query, sources) = init_query()?;
+        let schema = sources[0][0][0].schema();
+        // The single-row payloads of the memory data source.
+        let batches = (0..600)
+            .map(|i| {
+                RecordBatch::try_new(
+                    schema.clone(),
+                    vec![
+                        Arc::new(StringArray::from(vec![["a", "b", "c"][i % 3]])),
+                        Arc::new(Int64Array::from(vec![i as i64])),
+                    ],
+                )
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let opts = DeployOptions::lambda().with_sources(vec![vec![batches]]);
+        assert!(plan_functions(&query, &opts).await?.zstd_dict.is_none());
+
+        let launcher = plan_functions(&query, &opts.with_zstd_dictionary(true)).await?;
+        let dict = launcher.zstd_dict.expect("the dictionary is trained");
+        assert!(crate::encoding::dictionary_id(&dict).is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn start_source_synchronously() -> Result<()> {
+        // The query that ends in the response data sink gets its results from
+        // the response of the data source.
+        let client = FakeCloudClient::new();
+        let sink = serde_json::json!({ "sink_type": DataSinkType::Response });
+        let response = serde_json::to_vec(&Response::ok("q1-00", sink).to_value())?;
+        client.set_response("q1-00", response.clone());
+
+        let (mappings, body) =
+            start_source(&client, DataSource::SqsEvent, "q1-00", &[], None, true).await?;
+        assert!(mappings.is_empty());
+        assert_eq!(body, Some(response));
+
+        start_source(&client, DataSource::SqsEvent, "q1-00", &[], Some(1), false).await?;
+        let invocations = client.invocations();
+        assert_eq!(invocations[0].invocation_type, *FLOCK_LAMBDA_SYNC_CALL);
+        assert_eq!(invocations[1].invocation_type, *FLOCK_LAMBDA_ASYNC_CALL);
+        // The data source generates the events of the query's streams.
+        let payload: Payload = serde_json::from_slice(&invocations[1].payload)?;
+        assert_eq!(payload.query_number, Some(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_local_query() -> Result<()> {
+        let (query, sources) = init_query()?;
+        let mut handle = run_query(query, DeployOptions::local().with_sources(sources)).await?;
+        let batches = handle.await_window_results(Duration::from_secs(10)).await?;
+
+        let expected = vec![
+            "+----+-----------+",
+            "| c1 | SUM(t.c2) |",
+            "+----+-----------+",
+            "| a  | 9         |",
+            "| b  | 7         |",
+            "| c  | 4         |",
+            "+----+-----------+",
+        ];
+        assert_batches_eq!(&expected, &batches);
+
+        // The results can only be consumed once.
+        assert!(handle
+            .await_window_results(Duration::from_secs(1))
+            .await
+            .is_err());
+        handle.teardown().await
+    }
+
+    #[tokio::test]
+    async fn cancel_local_query() -> Result<()> {
+        let (query, sources) = init_query()?;
+        let mut handle = run_query(query, DeployOptions::local().with_sources(sources)).await?;
+        handle.cancel().await?;
+        assert!(handle
+            .await_window_results(Duration::from_secs(1))
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_local_query() -> Result<()> {
+        let (query, _) = init_query()?;
+        let result = update_query("q1", query, DeployOptions::local(), Duration::ZERO).await;
+        assert!(matches!(result, Err(FlockError::NotImplemented(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn event_source_window() {
+        assert_eq!(window_in_seconds(&Window::ElementWise), 1);
+        assert_eq!(window_in_seconds(&Window::Hopping((10, 5))), 5);
+        assert_eq!(
+            window_in_seconds(&Window::Tumbling(Schedule::Seconds(3600))),
+            900
+        );
+    }
+}
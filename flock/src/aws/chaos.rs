@@ -0,0 +1,429 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The chaos layer injects failures, delays and duplicates into the AWS calls
+//! of the cloud functions, so that the retries, the deduplication and the
+//! deadlines of the runtime can be tested under controlled faults rather than
+//! whenever AWS misbehaves.
+//!
+//! [`ChaosCloudClient`] wraps another [`CloudClient`] and follows a
+//! [`ChaosSpec`]: the probability that an invocation fails, the probability
+//! that an invocation is delivered twice, the probability that an S3 read
+//! returns not found, and the latency added to each call. The spec is carried
+//! in the query metadata under [`CHAOS_METADATA_KEY`], so every stage of a test
+//! deployment injects the same faults.
+//!
+//! The faults are injected only if the environment variable [`FLOCK_CHAOS`] is
+//! set in the function, which the driver passes on to the functions it deploys.
+//! Without it, the spec in the metadata is ignored, so the production
+//! functions are unaffected. If the variable holds a spec itself, the spec
+//! applies to the invocations without one in their metadata.
+
+use crate::aws::client::{CloudClient, EventSource, FunctionState, ObjectMeta};
+use crate::error::{FlockError, Result};
+use crate::runtime::context::ExecutionContext;
+use crate::runtime::metadata::QueryMetadata;
+use async_trait::async_trait;
+use log::warn;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The environment variable that turns on the chaos layer.
+pub const FLOCK_CHAOS: &str = "FLOCK_CHAOS";
+
+/// The metadata key of the chaos spec of the query.
+pub const CHAOS_METADATA_KEY: &str = "chaos";
+
+/// The latency added to the calls, uniformly distributed between the bounds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Latency {
+    /// The minimum latency in milliseconds.
+    pub min_ms: u64,
+    /// The maximum latency in milliseconds.
+    pub max_ms: u64,
+}
+
+/// The faults to inject into the AWS calls, e.g.
+///
+/// ```json
+/// { "invoke_failure": 0.1, "duplicate": 0.05, "s3_not_found": 0.01,
+///   "latency": { "min_ms": 10, "max_ms": 200 } }
+/// ```
+///
+/// The missing fields inject nothing.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChaosSpec {
+    /// The probability that an invocation fails before it reaches the
+    /// function.
+    pub invoke_failure: f64,
+    /// The probability that an invocation is delivered twice.
+    pub duplicate:      f64,
+    /// The probability that an S3 read returns not found, e.g. as a read
+    /// before the write is visible.
+    pub s3_not_found:   f64,
+    /// The latency added to the invocations and the S3 calls.
+    pub latency:        Option<Latency>,
+}
+
+impl ChaosSpec {
+    /// Parses the spec from JSON, and checks that the probabilities are in
+    /// `[0, 1]` and the latency bounds are ordered.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let spec: ChaosSpec = serde_json::from_str(json)?;
+        for (name, p) in [
+            ("invoke_failure", spec.invoke_failure),
+            ("duplicate", spec.duplicate),
+            ("s3_not_found", spec.s3_not_found),
+        ] {
+            if !(0.0..=1.0).contains(&p) {
+                return Err(FlockError::Internal(format!(
+                    "The chaos probability {} must be in [0, 1], got {}",
+                    name, p
+                )));
+            }
+        }
+        if let Some(latency) = spec.latency {
+            if latency.min_ms > latency.max_ms {
+                return Err(FlockError::Internal(format!(
+                    "The chaos latency must have min_ms <= max_ms, got {:?}",
+                    latency
+                )));
+            }
+        }
+        Ok(spec)
+    }
+
+    /// Reads the spec from the JSON file.
+    pub fn from_file(path: &str) -> Result<Self> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    /// Adds the spec to the query metadata.
+    pub fn stamp(&self, metadata: &mut QueryMetadata) -> Result<()> {
+        metadata.insert(CHAOS_METADATA_KEY.to_string(), serde_json::to_string(self)?);
+        Ok(())
+    }
+
+    /// Returns the spec of the invocation if [`FLOCK_CHAOS`] is set: the spec
+    /// in the metadata, or else the spec in the environment variable, if
+    /// any.
+    pub fn active(metadata: &Option<QueryMetadata>) -> Result<Option<Self>> {
+        let env = match std::env::var(FLOCK_CHAOS) {
+            Ok(env) if !env.is_empty() => env,
+            _ => return Ok(None),
+        };
+        match metadata.as_ref().and_then(|m| m.get(CHAOS_METADATA_KEY)) {
+            Some(json) => Ok(Some(Self::from_json(json)?)),
+            None if env.trim_start().starts_with('{') => Ok(Some(Self::from_json(&env)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Returns the client of the invocation with the faults of the active spec, or
+/// `None` if no fault is injected (see [`ChaosSpec::active`]).
+pub fn with_chaos(
+    client: Arc<dyn CloudClient>,
+    metadata: &Option<QueryMetadata>,
+) -> Result<Option<Arc<dyn CloudClient>>> {
+    Ok(ChaosSpec::active(metadata)?
+        .map(|spec| Arc::new(ChaosCloudClient::new(client, spec)) as Arc<dyn CloudClient>))
+}
+
+/// A client that injects the faults of the spec into the calls of the wrapped
+/// client.
+#[derive(Debug)]
+pub struct ChaosCloudClient {
+    inner: Arc<dyn CloudClient>,
+    spec:  ChaosSpec,
+}
+
+impl ChaosCloudClient {
+    /// Wraps the client.
+    pub fn new(inner: Arc<dyn CloudClient>, spec: ChaosSpec) -> Self {
+        Self { inner, spec }
+    }
+
+    /// Returns true with the probability.
+    fn roll(probability: f64) -> bool {
+        probability > 0.0 && rand::thread_rng().gen::<f64>() < probability
+    }
+
+    /// Waits for the latency of the spec, if any.
+    async fn delay(&self) {
+        if let Some(Latency { min_ms, max_ms }) = self.spec.latency {
+            let ms = rand::thread_rng().gen_range(min_ms..=max_ms);
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+        }
+    }
+
+    /// Waits for the latency, and returns not found for the S3 read with the
+    /// probability of the spec.
+    async fn read(&self, bucket: &str, key: &str) -> Result<()> {
+        self.delay().await;
+        if Self::roll(self.spec.s3_not_found) {
+            warn!("[Chaos] Injected not found of s3://{}/{}", bucket, key);
+            return Err(FlockError::AWS(format!(
+                "NoSuchKey: s3://{}/{} (injected)",
+                bucket, key
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CloudClient for ChaosCloudClient {
+    async fn invoke(
+        &self,
+        function: &str,
+        invocation_type: &str,
+        payload: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        self.delay().await;
+        if Self::roll(self.spec.invoke_failure) {
+            warn!("[Chaos] Injected failure of invoking {}", function);
+            return Err(FlockError::AWS(format!(
+                "Injected failure of invoking {}",
+                function
+            )));
+        }
+        if Self::roll(self.spec.duplicate) {
+            warn!("[Chaos] Injected duplicate invocation of {}", function);
+            self.inner
+                .invoke(function, invocation_type, payload.clone())
+                .await?;
+        }
+        self.inner.invoke(function, invocation_type, payload).await
+    }
+
+    async fn s3_get(&self, bucket: &str, key: &str) -> Result<Vec<u8>> {
+        self.read(bucket, key).await?;
+        self.inner.s3_get(bucket, key).await
+    }
+
+    async fn s3_head(&self, bucket: &str, key: &str) -> Result<ObjectMeta> {
+        self.read(bucket, key).await?;
+        self.inner.s3_head(bucket, key).await
+    }
+
+    async fn s3_get_range(&self, bucket: &str, key: &str, range: Range<u64>) -> Result<Vec<u8>> {
+        self.read(bucket, key).await?;
+        self.inner.s3_get_range(bucket, key, range).await
+    }
+
+    async fn s3_put(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<()> {
+        self.delay().await;
+        self.inner.s3_put(bucket, key, body).await
+    }
+
+    async fn s3_put_if_missing(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<bool> {
+        self.delay().await;
+        self.inner.s3_put_if_missing(bucket, key, body).await
+    }
+
+    async fn s3_list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>> {
+        self.delay().await;
+        self.inner.s3_list(bucket, prefix).await
+    }
+
+    async fn s3_delete(&self, bucket: &str, prefix: &str) -> Result<()> {
+        self.delay().await;
+        self.inner.s3_delete(bucket, prefix).await
+    }
+
+    async fn s3_list_page(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        start_after: Option<String>,
+    ) -> Result<(Vec<String>, bool)> {
+        self.delay().await;
+        self.inner.s3_list_page(bucket, prefix, start_after).await
+    }
+
+    async fn s3_delete_objects(&self, bucket: &str, keys: &[String]) -> Result<()> {
+        self.delay().await;
+        self.inner.s3_delete_objects(bucket, keys).await
+    }
+
+    async fn s3_list_buckets(&self) -> Result<Vec<String>> {
+        self.inner.s3_list_buckets().await
+    }
+
+    async fn s3_delete_bucket(&self, bucket: &str) -> Result<()> {
+        self.inner.s3_delete_bucket(bucket).await
+    }
+
+    async fn put_concurrency(&self, function: &str, concurrency: i64) -> Result<()> {
+        self.inner.put_concurrency(function, concurrency).await
+    }
+
+    async fn send_message(&self, queue: &str, body: String) -> Result<()> {
+        self.delay().await;
+        if Self::roll(self.spec.invoke_failure) {
+            warn!("[Chaos] Injected failure of sending to {}", queue);
+            return Err(FlockError::AWS(format!(
+                "Injected failure of sending to {}",
+                queue
+            )));
+        }
+        if Self::roll(self.spec.duplicate) {
+            warn!("[Chaos] Injected duplicate message to {}", queue);
+            self.inner.send_message(queue, body.clone()).await?;
+        }
+        self.inner.send_message(queue, body).await
+    }
+
+    async fn create_function(
+        &self,
+        ctx: &ExecutionContext,
+        embedded_stage: Option<&str>,
+        memory_size: i64,
+        architecture: &str,
+    ) -> Result<String> {
+        self.inner
+            .create_function(ctx, embedded_stage, memory_size, architecture)
+            .await
+    }
+
+    async fn function_exists(&self, function: &str) -> bool {
+        self.inner.function_exists(function).await
+    }
+
+    async fn get_function(&self, function: &str) -> Result<Option<FunctionState>> {
+        self.inner.get_function(function).await
+    }
+
+    async fn code_sha256(&self, architecture: &str) -> Result<String> {
+        self.inner.code_sha256(architecture).await
+    }
+
+    async fn update_function_code(&self, function: &str, architecture: &str) -> Result<()> {
+        self.inner
+            .update_function_code(function, architecture)
+            .await
+    }
+
+    async fn update_function_configuration(
+        &self,
+        ctx: &ExecutionContext,
+        embedded_stage: Option<&str>,
+        memory_size: i64,
+    ) -> Result<()> {
+        self.inner
+            .update_function_configuration(ctx, embedded_stage, memory_size)
+            .await
+    }
+
+    async fn create_queue(&self, queue: &str, visibility_timeout: i64) -> Result<()> {
+        self.inner.create_queue(queue, visibility_timeout).await
+    }
+
+    async fn create_event_source_mapping(
+        &self,
+        source: &EventSource,
+        function: &str,
+    ) -> Result<String> {
+        self.inner
+            .create_event_source_mapping(source, function)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aws::client::FakeCloudClient;
+    use std::time::Instant;
+
+    #[test]
+    fn parse_chaos_spec() -> Result<()> {
+        let spec = ChaosSpec::from_json(
+            r#"{"invoke_failure": 0.1, "latency": {"min_ms": 5, "max_ms": 10}}"#,
+        )?;
+        assert_eq!(spec.invoke_failure, 0.1);
+        assert_eq!(spec.duplicate, 0.0);
+        assert_eq!(
+            spec.latency,
+            Some(Latency {
+                min_ms: 5,
+                max_ms: 10,
+            })
+        );
+
+        assert!(ChaosSpec::from_json(r#"{"duplicate": 1.5}"#).is_err());
+        assert!(ChaosSpec::from_json(r#"{"latency": {"min_ms": 10, "max_ms": 5}}"#).is_err());
+
+        // The spec is enabled by the environment variable only.
+        let mut metadata = QueryMetadata::default();
+        ChaosSpec {
+            duplicate: 1.0,
+            ..Default::default()
+        }
+        .stamp(&mut metadata)?;
+        let metadata = Some(metadata);
+        std::env::remove_var(FLOCK_CHAOS);
+        assert_eq!(ChaosSpec::active(&metadata)?, None);
+        std::env::set_var(FLOCK_CHAOS, "1");
+        assert_eq!(ChaosSpec::active(&metadata)?.unwrap().duplicate, 1.0);
+        assert_eq!(ChaosSpec::active(&None)?, None);
+        std::env::set_var(FLOCK_CHAOS, r#"{"s3_not_found": 0.5}"#);
+        assert_eq!(ChaosSpec::active(&None)?.unwrap().s3_not_found, 0.5);
+        assert_eq!(ChaosSpec::active(&metadata)?.unwrap().s3_not_found, 0.0);
+        std::env::remove_var(FLOCK_CHAOS);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn inject_faults() -> Result<()> {
+        let fake = Arc::new(FakeCloudClient::new());
+        fake.put_object("bucket", "key", vec![1]);
+        let client = |spec: ChaosSpec| ChaosCloudClient::new(fake.clone(), spec);
+
+        let duplicate = client(ChaosSpec {
+            duplicate: 1.0,
+            ..Default::default()
+        });
+        duplicate.invoke("q1-00", "Event", vec![1]).await?;
+        assert_eq!(fake.invocations().len(), 2);
+
+        let failure = client(ChaosSpec {
+            invoke_failure: 1.0,
+            s3_not_found: 1.0,
+            ..Default::default()
+        });
+        assert!(failure.invoke("q1-00", "Event", vec![2]).await.is_err());
+        assert_eq!(fake.invocations().len(), 2);
+        assert!(failure.s3_get("bucket", "key").await.is_err());
+        assert!(failure.s3_head("bucket", "key").await.is_err());
+        failure.s3_put("bucket", "other", vec![2]).await?;
+        assert_eq!(fake.object("bucket", "other"), Some(vec![2]));
+
+        let delayed = client(ChaosSpec {
+            latency: Some(Latency {
+                min_ms: 20,
+                max_ms: 30,
+            }),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        assert_eq!(delayed.s3_get("bucket", "key").await?, vec![1]);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        Ok(())
+    }
+}
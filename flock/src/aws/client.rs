@@ -0,0 +1,1332 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The [`CloudClient`] trait abstracts the AWS calls on the hot path of the
+//! cloud functions, i.e. invoking the next functions and reading/writing the
+//! S3 objects, or sending the payloads to the queues of the queue transport,
+//! so that the function runtime can be tested without AWS. The
+//! reserved concurrency of the functions, which is set on the deployment, goes
+//! through it too, and so does the cleanup of the state buckets after the
+//! query is completed. The deployment creates the functions, the queues and
+//! the event source mappings of the query with it as well, and reconciles the
+//! functions left by a former deployment (see [`crate::aws::reconcile`]).
+//!
+//! [`AwsCloudClient`] calls the AWS services with the wrapped functions of
+//! [`crate::aws`], and [`FakeCloudClient`] keeps everything in memory.
+//! [`RecordingCloudClient`] makes no call at all, and only records the
+//! [`Resource`]s that a deployment would create (see [`crate::api::dry_run`]).
+
+use crate::aws::{lambda, s3, sqs};
+use crate::error::{FlockError, Result};
+use crate::runtime::capability::{is_probe, Capabilities};
+use crate::runtime::context::ExecutionContext;
+use crate::runtime::embedded::without_plans;
+use crate::runtime::payload::Payload;
+use crate::runtime::response::Response;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The AWS calls of the cloud functions.
+#[async_trait]
+pub trait CloudClient: Debug + Send + Sync {
+    /// Invokes the function with the payload, and returns the payload of the
+    /// response if any.
+    ///
+    /// # Arguments
+    /// * `function` - The name of the function.
+    /// * `invocation_type` - `Event` or `RequestResponse`.
+    /// * `payload` - The serialized payload of the invocation.
+    async fn invoke(
+        &self,
+        function: &str,
+        invocation_type: &str,
+        payload: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>>;
+
+    /// Returns the body of the S3 object.
+    async fn s3_get(&self, bucket: &str, key: &str) -> Result<Vec<u8>>;
+
+    /// Returns the size and the entity tag of the S3 object.
+    async fn s3_head(&self, bucket: &str, key: &str) -> Result<ObjectMeta>;
+
+    /// Returns the bytes of the S3 object in the range. The range is cut at
+    /// the end of the object.
+    async fn s3_get_range(&self, bucket: &str, key: &str, range: Range<u64>) -> Result<Vec<u8>>;
+
+    /// Writes the body to the S3 object.
+    async fn s3_put(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<()>;
+
+    /// Writes the body to the S3 object unless the object exists, and returns
+    /// true if the object is written.
+    async fn s3_put_if_missing(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<bool>;
+
+    /// Returns the keys of the S3 objects in the bucket that begin with the
+    /// prefix.
+    async fn s3_list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>>;
+
+    /// Deletes the S3 objects in the bucket that begin with the prefix.
+    async fn s3_delete(&self, bucket: &str, prefix: &str) -> Result<()>;
+
+    /// Returns one page of at most 1000 keys in the bucket that begin with the
+    /// prefix and come after `start_after`, and true if there are more keys.
+    async fn s3_list_page(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        start_after: Option<String>,
+    ) -> Result<(Vec<String>, bool)>;
+
+    /// Deletes the S3 objects of the keys in the bucket.
+    async fn s3_delete_objects(&self, bucket: &str, keys: &[String]) -> Result<()>;
+
+    /// Returns the names of all S3 buckets.
+    async fn s3_list_buckets(&self) -> Result<Vec<String>>;
+
+    /// Deletes the S3 bucket. The bucket must be empty.
+    async fn s3_delete_bucket(&self, bucket: &str) -> Result<()>;
+
+    /// Reserves the concurrency of the function.
+    ///
+    /// # Arguments
+    /// * `function` - The name of the function.
+    /// * `concurrency` - The number of the concurrent executions reserved.
+    async fn put_concurrency(&self, function: &str, concurrency: i64) -> Result<()>;
+
+    /// Sends the message to the SQS queue.
+    ///
+    /// # Arguments
+    /// * `queue` - The name of the queue.
+    /// * `body` - The body of the message.
+    async fn send_message(&self, queue: &str, body: String) -> Result<()>;
+
+    /// Creates the function of the context. The call fails with a
+    /// [`lambda::RESOURCE_CONFLICT`] if the function exists.
+    ///
+    /// # Arguments
+    /// * `ctx` - The execution context of the function.
+    /// * `embedded_stage` - The stage whose plans are embedded in the binary,
+    ///   if any (see [`crate::runtime::embedded`]).
+    /// * `memory_size` - The memory size of the function in MB.
+    /// * `architecture` - The architecture of the function.
+    ///
+    /// # Returns
+    /// The name of the function.
+    async fn create_function(
+        &self,
+        ctx: &ExecutionContext,
+        embedded_stage: Option<&str>,
+        memory_size: i64,
+        architecture: &str,
+    ) -> Result<String>;
+
+    /// Returns true if the function exists.
+    async fn function_exists(&self, function: &str) -> bool;
+
+    /// Returns the deployed configuration of the function, or `None` if the
+    /// function doesn't exist.
+    async fn get_function(&self, function: &str) -> Result<Option<FunctionState>>;
+
+    /// Returns the code hash of the function binary of the architecture, as
+    /// reported in the [`FunctionState`] of the functions running it.
+    async fn code_sha256(&self, architecture: &str) -> Result<String>;
+
+    /// Replaces the code of the function with the function binary of the
+    /// architecture.
+    async fn update_function_code(&self, function: &str, architecture: &str) -> Result<()>;
+
+    /// Replaces the execution context and the memory size of the function.
+    ///
+    /// # Arguments
+    /// * `ctx` - The execution context of the function.
+    /// * `embedded_stage` - The stage whose plans are embedded in the binary,
+    ///   if any.
+    /// * `memory_size` - The memory size of the function in MB.
+    async fn update_function_configuration(
+        &self,
+        ctx: &ExecutionContext,
+        embedded_stage: Option<&str>,
+        memory_size: i64,
+    ) -> Result<()>;
+
+    /// Creates the SQS queue.
+    ///
+    /// # Arguments
+    /// * `queue` - The name of the queue.
+    /// * `visibility_timeout` - The seconds that a received message is hidden
+    ///   from the other consumers.
+    async fn create_queue(&self, queue: &str, visibility_timeout: i64) -> Result<()>;
+
+    /// Creates the event source mapping that invokes the function with the
+    /// records of the source, and returns its identifier.
+    async fn create_event_source_mapping(
+        &self,
+        source: &EventSource,
+        function: &str,
+    ) -> Result<String>;
+}
+
+/// The source of the records of an event source mapping.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EventSource {
+    /// The SQS queue of a function (see [`crate::runtime::transport`]).
+    Queue(String),
+    /// The Kinesis data stream, whose records are gathered over the window in
+    /// seconds.
+    Kinesis {
+        /// The name of the stream.
+        stream: String,
+        /// The batching window in seconds.
+        window: i64,
+    },
+    /// The topics of the Kafka cluster, whose records are gathered over the
+    /// window in seconds.
+    Kafka {
+        /// The ARN of the cluster.
+        cluster_arn: Option<String>,
+        /// The topics of the cluster.
+        topics:      Option<Vec<String>>,
+        /// The batching window in seconds.
+        window:      i64,
+    },
+}
+
+impl fmt::Display for EventSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventSource::Queue(queue) => write!(f, "sqs:{}", queue),
+            EventSource::Kinesis { stream, .. } => write!(f, "kinesis:{}", stream),
+            EventSource::Kafka { cluster_arn, .. } => {
+                write!(f, "kafka:{}", cluster_arn.as_deref().unwrap_or_default())
+            }
+        }
+    }
+}
+
+/// A cloud resource created by the deployment of a query, as recorded by
+/// [`FakeCloudClient`] and [`RecordingCloudClient`].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Resource {
+    /// A function.
+    Function {
+        /// The name of the function.
+        name:           String,
+        /// The memory size of the function in MB.
+        memory_size:    i64,
+        /// The architecture of the function.
+        architecture:   String,
+        /// The stage whose plans are embedded in the binary, if any.
+        embedded_stage: Option<String>,
+    },
+    /// The reserved concurrency of a function.
+    Concurrency {
+        /// The name of the function.
+        function:    String,
+        /// The number of the concurrent executions reserved.
+        concurrency: i64,
+    },
+    /// An SQS queue.
+    Queue {
+        /// The name of the queue.
+        name:               String,
+        /// The visibility timeout of the messages in seconds.
+        visibility_timeout: i64,
+    },
+    /// An event source mapping.
+    EventSourceMapping {
+        /// The source of the records.
+        source:   EventSource,
+        /// The name of the invoked function.
+        function: String,
+    },
+}
+
+impl Resource {
+    /// Returns the function of the context as a resource.
+    fn function(
+        ctx: &ExecutionContext,
+        embedded_stage: Option<&str>,
+        memory_size: i64,
+        architecture: &str,
+    ) -> Self {
+        Resource::Function {
+            name: ctx.name.clone(),
+            memory_size,
+            architecture: architecture.to_string(),
+            embedded_stage: embedded_stage.map(str::to_string),
+        }
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resource::Function {
+                name,
+                memory_size,
+                architecture,
+                embedded_stage,
+            } => {
+                write!(f, "function {} ({} MB, {}", name, memory_size, architecture)?;
+                if let Some(stage) = embedded_stage {
+                    write!(f, ", embedded stage {}", stage)?;
+                }
+                write!(f, ")")
+            }
+            Resource::Concurrency {
+                function,
+                concurrency,
+            } => write!(
+                f,
+                "reserved concurrency {} of function {}",
+                concurrency, function
+            ),
+            Resource::Queue {
+                name,
+                visibility_timeout,
+            } => write!(
+                f,
+                "queue {} (visibility timeout: {}s)",
+                name, visibility_timeout
+            ),
+            Resource::EventSourceMapping { source, function } => {
+                write!(f, "event source mapping {} -> {}", source, function)
+            }
+        }
+    }
+}
+
+/// The status of the last update of a function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateStatus {
+    /// The function can be invoked and updated.
+    Successful,
+    /// The function is being created or updated.
+    InProgress,
+    /// The last update of the function failed for the reason.
+    Failed(String),
+}
+
+/// The deployed configuration of a function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionState {
+    /// The base64-encoded SHA-256 hash of the code of the function.
+    pub code_sha256:    String,
+    /// The memory size of the function in MB.
+    pub memory_size:    i64,
+    /// The architecture of the function.
+    pub architecture:   String,
+    /// The execution context in the environment of the function, if any.
+    pub context:        Option<ExecutionContext>,
+    /// The stage whose plans are embedded in the binary, if any.
+    pub embedded_stage: Option<String>,
+    /// The status of the last update of the function.
+    pub status:         UpdateStatus,
+}
+
+/// The metadata of an S3 object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectMeta {
+    /// The size of the object in bytes.
+    pub size: u64,
+    /// The entity tag of the object, which changes when the object is
+    /// overwritten.
+    pub etag: String,
+}
+
+/// The client that calls the AWS services.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AwsCloudClient;
+
+#[async_trait]
+impl CloudClient for AwsCloudClient {
+    async fn invoke(
+        &self,
+        function: &str,
+        invocation_type: &str,
+        payload: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        let response =
+            lambda::invoke_function(function, invocation_type, Some(payload.into())).await?;
+        Ok(response.payload.map(|p| p.to_vec()))
+    }
+
+    async fn s3_get(&self, bucket: &str, key: &str) -> Result<Vec<u8>> {
+        s3::get_object(bucket, key).await
+    }
+
+    async fn s3_head(&self, bucket: &str, key: &str) -> Result<ObjectMeta> {
+        let (size, etag) = s3::head_object(bucket, key).await?;
+        Ok(ObjectMeta { size, etag })
+    }
+
+    async fn s3_get_range(&self, bucket: &str, key: &str, range: Range<u64>) -> Result<Vec<u8>> {
+        s3::get_object_range(bucket, key, range).await
+    }
+
+    async fn s3_put(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<()> {
+        s3::put_object(bucket, key, body).await
+    }
+
+    async fn s3_put_if_missing(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<bool> {
+        s3::put_object_if_missing(bucket, key, body).await
+    }
+
+    async fn s3_list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>> {
+        s3::get_matched_keys(bucket, prefix).await
+    }
+
+    async fn s3_delete(&self, bucket: &str, prefix: &str) -> Result<()> {
+        s3::delete_matched_objects(bucket, prefix).await
+    }
+
+    async fn s3_list_page(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        start_after: Option<String>,
+    ) -> Result<(Vec<String>, bool)> {
+        s3::get_matched_keys_page(bucket, prefix, start_after).await
+    }
+
+    async fn s3_delete_objects(&self, bucket: &str, keys: &[String]) -> Result<()> {
+        s3::delete_objects(bucket, keys).await
+    }
+
+    async fn s3_list_buckets(&self) -> Result<Vec<String>> {
+        s3::list_buckets().await
+    }
+
+    async fn s3_delete_bucket(&self, bucket: &str) -> Result<()> {
+        s3::delete_bucket(bucket).await
+    }
+
+    async fn put_concurrency(&self, function: &str, concurrency: i64) -> Result<()> {
+        lambda::set_concurrency(function, concurrency).await
+    }
+
+    async fn send_message(&self, queue: &str, body: String) -> Result<()> {
+        sqs::send_message(queue, body).await
+    }
+
+    async fn create_function(
+        &self,
+        ctx: &ExecutionContext,
+        embedded_stage: Option<&str>,
+        memory_size: i64,
+        architecture: &str,
+    ) -> Result<String> {
+        lambda::create_new_function(ctx, embedded_stage, memory_size, architecture).await
+    }
+
+    async fn function_exists(&self, function: &str) -> bool {
+        lambda::function_exists(function).await
+    }
+
+    async fn get_function(&self, function: &str) -> Result<Option<FunctionState>> {
+        lambda::get_function(function).await
+    }
+
+    async fn code_sha256(&self, architecture: &str) -> Result<String> {
+        lambda::code_sha256(architecture).await
+    }
+
+    async fn update_function_code(&self, function: &str, architecture: &str) -> Result<()> {
+        lambda::update_code(function, architecture).await
+    }
+
+    async fn update_function_configuration(
+        &self,
+        ctx: &ExecutionContext,
+        embedded_stage: Option<&str>,
+        memory_size: i64,
+    ) -> Result<()> {
+        lambda::update_function_configuration(ctx, embedded_stage, memory_size).await
+    }
+
+    async fn create_queue(&self, queue: &str, visibility_timeout: i64) -> Result<()> {
+        sqs::create_queue(queue, visibility_timeout).await?;
+        Ok(())
+    }
+
+    async fn create_event_source_mapping(
+        &self,
+        source: &EventSource,
+        function: &str,
+    ) -> Result<String> {
+        let request = match source {
+            EventSource::Queue(queue) => {
+                sqs::create_event_source_mapping_request(queue, function).await?
+            }
+            #[cfg(feature = "kinesis")]
+            EventSource::Kinesis { stream, window } => {
+                crate::datasource::kinesis::create_event_source_mapping_request(
+                    stream, function, *window,
+                )
+                .await?
+            }
+            #[cfg(feature = "kafka")]
+            EventSource::Kafka {
+                cluster_arn,
+                topics,
+                window,
+            } => {
+                crate::datasource::kafka::create_event_source_mapping_request(
+                    function,
+                    *window,
+                    cluster_arn,
+                    topics,
+                )
+                .await?
+            }
+            #[allow(unreachable_patterns)]
+            source => {
+                return Err(FlockError::NotImplemented(format!(
+                    "The event source {} isn't compiled",
+                    source
+                )))
+            }
+        };
+        lambda::create_event_source_mapping(request).await
+    }
+}
+
+/// An invocation recorded by [`FakeCloudClient`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invocation {
+    /// The name of the invoked function.
+    pub function:        String,
+    /// The invocation type.
+    pub invocation_type: String,
+    /// The serialized payload.
+    pub payload:         Vec<u8>,
+}
+
+impl Invocation {
+    /// Deserializes the payload of the invocation.
+    pub fn payload(&self) -> Result<Payload> {
+        Ok(serde_json::from_slice(&self.payload)?)
+    }
+}
+
+/// A message sent to a queue, recorded by [`FakeCloudClient`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueMessage {
+    /// The name of the queue.
+    pub queue: String,
+    /// The body of the message.
+    pub body:  String,
+}
+
+/// An in-memory client for tests. It records the invocations, the queue
+/// messages and the created resources, keeps the S3 objects in memory, and can
+/// inject failures and latency into the calls.
+#[derive(Debug, Default)]
+pub struct FakeCloudClient {
+    invocations: Mutex<Vec<Invocation>>,
+    resources:   Mutex<Vec<Resource>>,
+    calls:       AtomicUsize,
+    objects:     Mutex<HashMap<(String, String), Vec<u8>>>,
+    ranges:      Mutex<Vec<Range<u64>>>,
+    responses:   Mutex<HashMap<String, Vec<u8>>>,
+    concurrency: Mutex<HashMap<String, i64>>,
+    deleted:     Mutex<Vec<String>>,
+    puts:        Mutex<Vec<String>>,
+    messages:    Mutex<Vec<QueueMessage>>,
+    functions:   Mutex<HashMap<String, FunctionState>>,
+    /// The code hashes of the function binaries by architecture.
+    code:        Mutex<HashMap<String, String>>,
+    /// The number of the next lookups that miss the function, by function.
+    hidden:      Mutex<HashMap<String, usize>>,
+    /// The number of the next lookups that see the update of the function in
+    /// progress, by function.
+    updating:    Mutex<HashMap<String, usize>>,
+    /// The number of the next calls to fail, by function name, bucket or
+    /// object.
+    failures:    Mutex<HashMap<String, usize>>,
+    latency:     Option<Duration>,
+}
+
+impl FakeCloudClient {
+    /// Creates an empty client.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Delays every call by the latency.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    /// Fails the next `times` calls to the function or the bucket, or the next
+    /// `times` writes of the object `<bucket>/<key>`.
+    pub fn fail_next(&self, target: &str, times: usize) {
+        self.failures
+            .lock()
+            .unwrap()
+            .insert(target.to_string(), times);
+    }
+
+    /// Sets the response payload of the invocations of the function.
+    pub fn set_response(&self, function: &str, response: Vec<u8>) {
+        self.responses
+            .lock()
+            .unwrap()
+            .insert(function.to_string(), response);
+    }
+
+    /// Writes the S3 object directly, e.g. to seed the inputs of a test.
+    pub fn put_object(&self, bucket: &str, key: &str, body: Vec<u8>) {
+        self.objects
+            .lock()
+            .unwrap()
+            .insert((bucket.to_string(), key.to_string()), body);
+    }
+
+    /// Returns the body of the S3 object if it exists.
+    pub fn object(&self, bucket: &str, key: &str) -> Option<Vec<u8>> {
+        self.objects
+            .lock()
+            .unwrap()
+            .get(&(bucket.to_string(), key.to_string()))
+            .cloned()
+    }
+
+    /// Returns the keys of the S3 objects in the bucket, in sorted order.
+    pub fn keys(&self, bucket: &str) -> Vec<String> {
+        let mut keys = self
+            .objects
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(b, _)| b == bucket)
+            .map(|(_, k)| k.clone())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    /// Returns the invocations recorded so far, in the order of the calls.
+    pub fn invocations(&self) -> Vec<Invocation> {
+        self.invocations.lock().unwrap().clone()
+    }
+
+    /// Returns the ranges read with [`CloudClient::s3_get_range`] so far, in
+    /// the order of the calls.
+    pub fn ranges(&self) -> Vec<Range<u64>> {
+        self.ranges.lock().unwrap().clone()
+    }
+
+    /// Returns the concurrency reserved for the function, if any.
+    pub fn concurrency(&self, function: &str) -> Option<i64> {
+        self.concurrency.lock().unwrap().get(function).copied()
+    }
+
+    /// Returns the keys written with [`CloudClient::s3_put`] so far, in the
+    /// order of the calls.
+    pub fn puts(&self) -> Vec<String> {
+        self.puts.lock().unwrap().clone()
+    }
+
+    /// Returns the messages sent to the queues so far, in the order of the
+    /// calls.
+    pub fn messages(&self) -> Vec<QueueMessage> {
+        self.messages.lock().unwrap().clone()
+    }
+
+    /// Returns the buckets deleted so far, in the order of the calls.
+    pub fn deleted_buckets(&self) -> Vec<String> {
+        self.deleted.lock().unwrap().clone()
+    }
+
+    /// Returns the resources created so far, in sorted order.
+    pub fn resources(&self) -> Vec<Resource> {
+        let mut resources = self.resources.lock().unwrap().clone();
+        resources.sort();
+        resources
+    }
+
+    /// Returns the number of the calls made so far.
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+
+    /// Returns the deployed configuration of the function if it exists.
+    pub fn function(&self, function: &str) -> Option<FunctionState> {
+        self.functions.lock().unwrap().get(function).cloned()
+    }
+
+    /// Sets the code hash of the function binary of the architecture, e.g. to
+    /// upload a new binary.
+    pub fn set_code_sha256(&self, architecture: &str, code_sha256: &str) {
+        self.code
+            .lock()
+            .unwrap()
+            .insert(architecture.to_string(), code_sha256.to_string());
+    }
+
+    /// Hides the function from the next `times` lookups, as AWS Lambda may do
+    /// right after the function is created.
+    pub fn hide_function(&self, function: &str, times: usize) {
+        self.hidden
+            .lock()
+            .unwrap()
+            .insert(function.to_string(), times);
+    }
+
+    /// Reports the update of the function in progress to the next `times`
+    /// lookups.
+    pub fn delay_updates(&self, function: &str, times: usize) {
+        self.updating
+            .lock()
+            .unwrap()
+            .insert(function.to_string(), times);
+    }
+
+    /// Returns the code hash of the function binary of the architecture.
+    fn code_of(&self, architecture: &str) -> String {
+        self.code
+            .lock()
+            .unwrap()
+            .get(architecture)
+            .cloned()
+            .unwrap_or_else(|| format!("{}-code", architecture))
+    }
+
+    /// Decrements the count of the function, and returns true if it was set.
+    fn take(counts: &Mutex<HashMap<String, usize>>, function: &str) -> bool {
+        match counts.lock().unwrap().get_mut(function) {
+            Some(times) if *times > 0 => {
+                *times -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Applies the update to the deployed function, and to its recorded
+    /// resource.
+    fn update(&self, function: &str, update: impl FnOnce(&mut FunctionState)) -> Result<()> {
+        let mut functions = self.functions.lock().unwrap();
+        let state = functions.get_mut(function).ok_or_else(|| {
+            FlockError::AWS(format!(
+                "ResourceNotFoundException: Function not found: {}",
+                function
+            ))
+        })?;
+        update(state);
+        for resource in self.resources.lock().unwrap().iter_mut() {
+            if let Resource::Function {
+                name,
+                memory_size,
+                architecture,
+                embedded_stage,
+            } = resource
+            {
+                if name == function {
+                    *memory_size = state.memory_size;
+                    *architecture = state.architecture.clone();
+                    *embedded_stage = state.embedded_stage.clone();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the latency, and returns an error if the call to the target
+    /// is set to fail.
+    async fn call(&self, target: &str) -> Result<()> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        if let Some(latency) = self.latency {
+            tokio::time::sleep(latency).await;
+        }
+        let mut failures = self.failures.lock().unwrap();
+        match failures.get_mut(target) {
+            Some(times) if *times > 0 => {
+                *times -= 1;
+                Err(FlockError::AWS(format!("Injected failure of {}", target)))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl CloudClient for FakeCloudClient {
+    async fn invoke(
+        &self,
+        function: &str,
+        invocation_type: &str,
+        payload: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        self.call(function).await?;
+        self.invocations.lock().unwrap().push(Invocation {
+            function: function.to_string(),
+            invocation_type: invocation_type.to_string(),
+            payload,
+        });
+        Ok(self.responses.lock().unwrap().get(function).cloned())
+    }
+
+    async fn s3_get(&self, bucket: &str, key: &str) -> Result<Vec<u8>> {
+        self.call(bucket).await?;
+        self.object(bucket, key)
+            .ok_or_else(|| FlockError::AWS(format!("NoSuchKey: s3://{}/{}", bucket, key)))
+    }
+
+    async fn s3_head(&self, bucket: &str, key: &str) -> Result<ObjectMeta> {
+        let body = self.s3_get(bucket, key).await?;
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        Ok(ObjectMeta {
+            size: body.len() as u64,
+            etag: format!("{:016x}", hasher.finish()),
+        })
+    }
+
+    async fn s3_get_range(&self, bucket: &str, key: &str, range: Range<u64>) -> Result<Vec<u8>> {
+        let body = self.s3_get(bucket, key).await?;
+        self.ranges.lock().unwrap().push(range.clone());
+        let end = (range.end as usize).min(body.len());
+        let start = (range.start as usize).min(end);
+        Ok(body[start..end].to_vec())
+    }
+
+    async fn s3_put(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<()> {
+        self.call(bucket).await?;
+        self.call(&format!("{}/{}", bucket, key)).await?;
+        self.puts.lock().unwrap().push(key.to_string());
+        self.put_object(bucket, key, body);
+        Ok(())
+    }
+
+    async fn s3_put_if_missing(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<bool> {
+        self.call(bucket).await?;
+        self.call(&format!("{}/{}", bucket, key)).await?;
+        if self.object(bucket, key).is_some() {
+            return Ok(false);
+        }
+        self.puts.lock().unwrap().push(key.to_string());
+        self.put_object(bucket, key, body);
+        Ok(true)
+    }
+
+    async fn s3_list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>> {
+        self.call(bucket).await?;
+        Ok(self
+            .keys(bucket)
+            .into_iter()
+            .filter(|k| k.starts_with(prefix))
+            .collect())
+    }
+
+    async fn s3_delete(&self, bucket: &str, prefix: &str) -> Result<()> {
+        self.call(bucket).await?;
+        self.objects
+            .lock()
+            .unwrap()
+            .retain(|(b, k), _| b != bucket || !k.starts_with(prefix));
+        Ok(())
+    }
+
+    async fn s3_list_page(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        start_after: Option<String>,
+    ) -> Result<(Vec<String>, bool)> {
+        let mut keys = self
+            .s3_list(bucket, prefix)
+            .await?
+            .into_iter()
+            .filter(|k| start_after.as_ref().map_or(true, |s| k > s))
+            .collect::<Vec<_>>();
+        let truncated = keys.len() > 1000;
+        keys.truncate(1000);
+        Ok((keys, truncated))
+    }
+
+    async fn s3_delete_objects(&self, bucket: &str, keys: &[String]) -> Result<()> {
+        self.call(bucket).await?;
+        let mut objects = self.objects.lock().unwrap();
+        for key in keys {
+            objects.remove(&(bucket.to_string(), key.clone()));
+        }
+        Ok(())
+    }
+
+    async fn s3_list_buckets(&self) -> Result<Vec<String>> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        let mut buckets = self
+            .objects
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|(b, _)| b.clone())
+            .collect::<Vec<_>>();
+        buckets.sort();
+        buckets.dedup();
+        Ok(buckets)
+    }
+
+    async fn s3_delete_bucket(&self, bucket: &str) -> Result<()> {
+        self.call(bucket).await?;
+        if !self.keys(bucket).is_empty() {
+            return Err(FlockError::AWS(format!("BucketNotEmpty: {}", bucket)));
+        }
+        self.deleted.lock().unwrap().push(bucket.to_string());
+        Ok(())
+    }
+
+    async fn put_concurrency(&self, function: &str, concurrency: i64) -> Result<()> {
+        self.call(function).await?;
+        self.concurrency
+            .lock()
+            .unwrap()
+            .insert(function.to_string(), concurrency);
+        self.resources.lock().unwrap().push(Resource::Concurrency {
+            function: function.to_string(),
+            concurrency,
+        });
+        Ok(())
+    }
+
+    async fn send_message(&self, queue: &str, body: String) -> Result<()> {
+        self.call(queue).await?;
+        self.messages.lock().unwrap().push(QueueMessage {
+            queue: queue.to_string(),
+            body,
+        });
+        Ok(())
+    }
+
+    async fn create_function(
+        &self,
+        ctx: &ExecutionContext,
+        embedded_stage: Option<&str>,
+        memory_size: i64,
+        architecture: &str,
+    ) -> Result<String> {
+        self.call(&ctx.name).await?;
+        let state = FunctionState {
+            code_sha256: self.code_of(architecture),
+            memory_size,
+            architecture: architecture.to_string(),
+            context: Some(match embedded_stage {
+                Some(_) => without_plans(ctx),
+                None => ctx.clone(),
+            }),
+            embedded_stage: embedded_stage.map(str::to_string),
+            status: UpdateStatus::Successful,
+        };
+        {
+            let mut functions = self.functions.lock().unwrap();
+            if functions.contains_key(&ctx.name) {
+                return Err(FlockError::AWS(format!(
+                    "{}: Function already exist: {}",
+                    lambda::RESOURCE_CONFLICT,
+                    ctx.name
+                )));
+            }
+            functions.insert(ctx.name.clone(), state);
+        }
+        self.resources.lock().unwrap().push(Resource::function(
+            ctx,
+            embedded_stage,
+            memory_size,
+            architecture,
+        ));
+        Ok(ctx.name.clone())
+    }
+
+    async fn function_exists(&self, function: &str) -> bool {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        self.functions.lock().unwrap().contains_key(function)
+    }
+
+    async fn get_function(&self, function: &str) -> Result<Option<FunctionState>> {
+        self.call(function).await?;
+        if Self::take(&self.hidden, function) {
+            return Ok(None);
+        }
+        let updating = Self::take(&self.updating, function);
+        Ok(self.function(function).map(|mut state| {
+            if updating {
+                state.status = UpdateStatus::InProgress;
+            }
+            state
+        }))
+    }
+
+    async fn code_sha256(&self, architecture: &str) -> Result<String> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        Ok(self.code_of(architecture))
+    }
+
+    async fn update_function_code(&self, function: &str, architecture: &str) -> Result<()> {
+        self.call(function).await?;
+        let code_sha256 = self.code_of(architecture);
+        self.update(function, |state| {
+            state.code_sha256 = code_sha256;
+            state.architecture = architecture.to_string();
+        })
+    }
+
+    async fn update_function_configuration(
+        &self,
+        ctx: &ExecutionContext,
+        embedded_stage: Option<&str>,
+        memory_size: i64,
+    ) -> Result<()> {
+        self.call(&ctx.name).await?;
+        self.update(&ctx.name, |state| {
+            state.context = Some(match embedded_stage {
+                Some(_) => without_plans(ctx),
+                None => ctx.clone(),
+            });
+            state.embedded_stage = embedded_stage.map(str::to_string);
+            state.memory_size = memory_size;
+        })
+    }
+
+    async fn create_queue(&self, queue: &str, visibility_timeout: i64) -> Result<()> {
+        self.call(queue).await?;
+        self.resources.lock().unwrap().push(Resource::Queue {
+            name: queue.to_string(),
+            visibility_timeout,
+        });
+        Ok(())
+    }
+
+    async fn create_event_source_mapping(
+        &self,
+        source: &EventSource,
+        function: &str,
+    ) -> Result<String> {
+        self.call(function).await?;
+        let mut resources = self.resources.lock().unwrap();
+        resources.push(Resource::EventSourceMapping {
+            source:   source.clone(),
+            function: function.to_string(),
+        });
+        Ok(format!("mapping-{}", resources.len()))
+    }
+}
+
+/// The client of the dry runs (see [`crate::api::dry_run`]). It makes no call
+/// to the cloud. The calls that would create the resources and invoke the
+/// functions are recorded, the S3 objects are never found, and the capability
+/// probes are answered with the capabilities of the running binary, which the
+/// functions are assumed to be built from.
+#[derive(Debug, Default)]
+pub struct RecordingCloudClient {
+    resources:   Mutex<Vec<Resource>>,
+    invocations: Mutex<Vec<Invocation>>,
+    puts:        Mutex<Vec<String>>,
+}
+
+impl RecordingCloudClient {
+    /// Creates an empty client.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the resources that the calls would have created, in sorted
+    /// order.
+    pub fn resources(&self) -> Vec<Resource> {
+        let mut resources = self.resources.lock().unwrap().clone();
+        resources.sort();
+        resources
+    }
+
+    /// Returns the invocations that would have been made, except for the
+    /// capability probes, in the order of the calls.
+    pub fn invocations(&self) -> Vec<Invocation> {
+        self.invocations.lock().unwrap().clone()
+    }
+
+    /// Returns the S3 objects `<bucket>/<key>` that would have been written,
+    /// in the order of the calls.
+    pub fn puts(&self) -> Vec<String> {
+        self.puts.lock().unwrap().clone()
+    }
+
+    fn not_found(bucket: &str, key: &str) -> FlockError {
+        FlockError::AWS(format!("NoSuchKey: s3://{}/{} (dry run)", bucket, key))
+    }
+}
+
+#[async_trait]
+impl CloudClient for RecordingCloudClient {
+    async fn invoke(
+        &self,
+        function: &str,
+        invocation_type: &str,
+        payload: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        if serde_json::from_slice::<Value>(&payload).map_or(false, |p| is_probe(&p)) {
+            let capabilities = Capabilities::detect().await;
+            let response = Response::ok(function, json!({ "capabilities": capabilities }));
+            return Ok(Some(serde_json::to_vec(&response.to_value())?));
+        }
+        self.invocations.lock().unwrap().push(Invocation {
+            function: function.to_string(),
+            invocation_type: invocation_type.to_string(),
+            payload,
+        });
+        Ok(None)
+    }
+
+    async fn s3_get(&self, bucket: &str, key: &str) -> Result<Vec<u8>> {
+        Err(Self::not_found(bucket, key))
+    }
+
+    async fn s3_head(&self, bucket: &str, key: &str) -> Result<ObjectMeta> {
+        Err(Self::not_found(bucket, key))
+    }
+
+    async fn s3_get_range(&self, bucket: &str, key: &str, _: Range<u64>) -> Result<Vec<u8>> {
+        Err(Self::not_found(bucket, key))
+    }
+
+    async fn s3_put(&self, bucket: &str, key: &str, _: Vec<u8>) -> Result<()> {
+        self.puts
+            .lock()
+            .unwrap()
+            .push(format!("{}/{}", bucket, key));
+        Ok(())
+    }
+
+    async fn s3_put_if_missing(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<bool> {
+        self.s3_put(bucket, key, body).await?;
+        Ok(true)
+    }
+
+    async fn s3_list(&self, _: &str, _: &str) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    async fn s3_delete(&self, _: &str, _: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn s3_list_page(
+        &self,
+        _: &str,
+        _: &str,
+        _: Option<String>,
+    ) -> Result<(Vec<String>, bool)> {
+        Ok((vec![], false))
+    }
+
+    async fn s3_delete_objects(&self, _: &str, _: &[String]) -> Result<()> {
+        Ok(())
+    }
+
+    async fn s3_list_buckets(&self) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    async fn s3_delete_bucket(&self, _: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn put_concurrency(&self, function: &str, concurrency: i64) -> Result<()> {
+        self.resources.lock().unwrap().push(Resource::Concurrency {
+            function: function.to_string(),
+            concurrency,
+        });
+        Ok(())
+    }
+
+    async fn send_message(&self, _: &str, _: String) -> Result<()> {
+        Ok(())
+    }
+
+    async fn create_function(
+        &self,
+        ctx: &ExecutionContext,
+        embedded_stage: Option<&str>,
+        memory_size: i64,
+        architecture: &str,
+    ) -> Result<String> {
+        self.resources.lock().unwrap().push(Resource::function(
+            ctx,
+            embedded_stage,
+            memory_size,
+            architecture,
+        ));
+        Ok(ctx.name.clone())
+    }
+
+    async fn function_exists(&self, _: &str) -> bool {
+        false
+    }
+
+    async fn get_function(&self, _: &str) -> Result<Option<FunctionState>> {
+        Ok(None)
+    }
+
+    async fn code_sha256(&self, _: &str) -> Result<String> {
+        Ok(String::new())
+    }
+
+    async fn update_function_code(&self, _: &str, _: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn update_function_configuration(
+        &self,
+        _: &ExecutionContext,
+        _: Option<&str>,
+        _: i64,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    async fn create_queue(&self, queue: &str, visibility_timeout: i64) -> Result<()> {
+        self.resources.lock().unwrap().push(Resource::Queue {
+            name: queue.to_string(),
+            visibility_timeout,
+        });
+        Ok(())
+    }
+
+    async fn create_event_source_mapping(
+        &self,
+        source: &EventSource,
+        function: &str,
+    ) -> Result<String> {
+        let mut resources = self.resources.lock().unwrap();
+        resources.push(Resource::EventSourceMapping {
+            source:   source.clone(),
+            function: function.to_string(),
+        });
+        Ok(format!("dry-run-{}", resources.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn fake_client_records_calls() -> Result<()> {
+        let client = FakeCloudClient::new();
+        client.set_response("q1-01", b"{}".to_vec());
+
+        assert_eq!(client.invoke("q1-00", "Event", vec![1]).await?, None);
+        assert_eq!(
+            client.invoke("q1-01", "RequestResponse", vec![2]).await?,
+            Some(b"{}".to_vec())
+        );
+        let invocations = client.invocations();
+        assert_eq!(invocations.len(), 2);
+        assert_eq!(invocations[0].function, "q1-00");
+        assert_eq!(invocations[1].invocation_type, "RequestResponse");
+        assert_eq!(invocations[1].payload, vec![2]);
+
+        client.s3_put("bucket", "b", vec![2]).await?;
+        client.s3_put("bucket", "a", vec![1]).await?;
+        assert_eq!(client.s3_get("bucket", "a").await?, vec![1]);
+        assert_eq!(client.keys("bucket"), vec!["a", "b"]);
+        assert_eq!(client.s3_list("bucket", "a").await?, vec!["a"]);
+        assert!(client.s3_get("bucket", "c").await.is_err());
+        assert!(client.s3_get("other", "a").await.is_err());
+
+        client.s3_put("bucket", "c", vec![1, 2, 3, 4]).await?;
+        let meta = client.s3_head("bucket", "c").await?;
+        assert_eq!(meta.size, 4);
+        assert_ne!(meta.etag, client.s3_head("bucket", "a").await?.etag);
+        assert_eq!(client.s3_get_range("bucket", "c", 1..3).await?, vec![2, 3]);
+        assert_eq!(client.s3_get_range("bucket", "c", 3..8).await?, vec![4]);
+        assert_eq!(client.ranges(), vec![1..3, 3..8]);
+        client.s3_delete("bucket", "c").await?;
+
+        client.s3_put("other", "a", vec![3]).await?;
+        client.s3_delete("bucket", "a").await?;
+        assert_eq!(client.keys("bucket"), vec!["b"]);
+        assert_eq!(client.keys("other"), vec!["a"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fake_client_injects_failures_and_latency() -> Result<()> {
+        let client = FakeCloudClient::new().with_latency(Duration::from_millis(20));
+        client.fail_next("q1-00", 2);
+        client.fail_next("bucket", 1);
+
+        let start = Instant::now();
+        assert!(client.invoke("q1-00", "Event", vec![]).await.is_err());
+        assert!(client.invoke("q1-00", "Event", vec![]).await.is_err());
+        assert!(client.invoke("q1-00", "Event", vec![]).await.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        assert_eq!(client.invocations().len(), 1);
+
+        assert!(client.s3_put("bucket", "a", vec![]).await.is_err());
+        assert!(client.object("bucket", "a").is_none());
+        client.s3_put("bucket", "a", vec![]).await?;
+        assert!(client.object("bucket", "a").is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recording_client_records_resources() -> Result<()> {
+        let client = RecordingCloudClient::new();
+        let probe = serde_json::to_vec(&crate::runtime::capability::probe_payload())?;
+        let answer = client.invoke("q1-00", "RequestResponse", probe).await?;
+        assert!(Response::from_slice(&answer.unwrap())?
+            .into_result()
+            .is_ok());
+        assert_eq!(client.invoke("q1-00", "Event", vec![1]).await?, None);
+        assert_eq!(client.invocations().len(), 1);
+
+        assert!(client.s3_get("bucket", "a").await.is_err());
+        client.s3_put("bucket", "a", vec![1]).await?;
+        assert!(client.s3_get("bucket", "a").await.is_err());
+        assert_eq!(client.puts(), vec!["bucket/a"]);
+
+        let queue = EventSource::Queue("q1-01-queue".to_string());
+        client.create_queue("q1-01-queue", 60).await?;
+        client.put_concurrency("q1-01", 1).await?;
+        client.create_event_source_mapping(&queue, "q1-01").await?;
+        assert!(!client.function_exists("q1-01").await);
+        assert_eq!(
+            client.resources(),
+            vec![
+                Resource::Concurrency {
+                    function:    "q1-01".to_string(),
+                    concurrency: 1,
+                },
+                Resource::Queue {
+                    name:               "q1-01-queue".to_string(),
+                    visibility_timeout: 60,
+                },
+                Resource::EventSourceMapping {
+                    source:   queue,
+                    function: "q1-01".to_string(),
+                },
+            ]
+        );
+        Ok(())
+    }
+}
@@ -19,11 +19,46 @@ use chrono::Duration as Delta;
 use chrono::{DateTime, Local, NaiveDateTime, Utc};
 use humantime::parse_duration;
 use log::info;
+use rusoto_cloudwatch::{CloudWatch, Dimension, GetMetricStatisticsInput};
 use rusoto_logs::{
     CloudWatchLogs, CloudWatchLogsClient, DescribeLogGroupsRequest, FilterLogEventsRequest,
 };
 use std::time::Duration;
 
+/// Returns the maximum number of the concurrent executions of the lambda
+/// function over the last period, or `None` if the function wasn't invoked.
+///
+/// # Arguments
+/// * `function_name` - The name of the lambda function.
+/// * `period` - The period of the metric, rounded up to a minute.
+pub async fn concurrent_executions(function_name: &str, period: Duration) -> Result<Option<i64>> {
+    let end = Utc::now();
+    let period = Delta::minutes(((period.as_secs() + 59) / 60).max(1) as i64);
+    let datapoints = metrics_client("")
+        .get_metric_statistics(GetMetricStatisticsInput {
+            namespace: "AWS/Lambda".to_owned(),
+            metric_name: "ConcurrentExecutions".to_owned(),
+            dimensions: Some(vec![Dimension {
+                name:  "FunctionName".to_owned(),
+                value: function_name.to_owned(),
+            }]),
+            start_time: (end - period).to_rfc3339(),
+            end_time: end.to_rfc3339(),
+            period: period.num_seconds(),
+            statistics: Some(vec!["Maximum".to_owned()]),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?
+        .datapoints
+        .unwrap_or_default();
+    Ok(datapoints
+        .iter()
+        .filter_map(|d| d.maximum)
+        .map(|m| m as i64)
+        .max())
+}
+
 /// AWS Response for CloudWatch logs.
 pub enum AWSResponse {
     /// The token to use when requesting the next set of items. The token
@@ -49,7 +84,7 @@ pub async fn fetch(group: &str, mtime: Duration) -> Result<()> {
             break;
         }
 
-        match fetch_logs(&FLOCK_WATCHLOGS_CLIENT, req, timeout)
+        match fetch_logs(&watchlogs_client(""), req, timeout)
             .await
             .map_err(|e| FlockError::Internal(e.to_string()))?
         {
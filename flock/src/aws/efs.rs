@@ -57,7 +57,7 @@ pub async fn create() -> Result<String> {
         ..Default::default()
     };
 
-    match FLOCK_EFS_CLIENT.create_file_system(req).await {
+    match efs_client("").create_file_system(req).await {
         Ok(resp) => Ok(resp.file_system_id),
         Err(RusotoError::Service(CreateFileSystemError::FileSystemAlreadyExists(_))) => {
             Ok(String::new())
@@ -79,7 +79,7 @@ pub async fn discribe() -> Result<String> {
         creation_token: Some(FLOCK_EFS_CREATION_TOKEN.to_string()),
         ..Default::default()
     };
-    match FLOCK_EFS_CLIENT.describe_file_systems(req).await {
+    match efs_client("").describe_file_systems(req).await {
         Ok(resp) => Ok(resp.file_systems.unwrap()[0].file_system_id.clone()),
         Err(e) => Err(FlockError::AWS(e.to_string())),
     }
@@ -118,7 +118,7 @@ pub async fn create_access_point(file_system_id: &str) -> Result<String> {
         ..Default::default()
     };
 
-    match FLOCK_EFS_CLIENT.create_access_point(req).await {
+    match efs_client("").create_access_point(req).await {
         Ok(resp) => resp
             .access_point_id
             .ok_or_else(|| FlockError::AWS("No access point ID!".to_string())),
@@ -150,7 +150,7 @@ pub async fn describe_access_point(
         ..Default::default()
     };
 
-    match FLOCK_EFS_CLIENT
+    match efs_client("")
         .describe_access_points(req)
         .await
         .map_err(|e| FlockError::AWS(e.to_string()))
@@ -188,7 +188,7 @@ pub async fn create_mount_target(file_system_id: &str) -> Result<String> {
         ..Default::default()
     };
 
-    match FLOCK_EFS_CLIENT.create_mount_target(req).await {
+    match efs_client("").create_mount_target(req).await {
         Ok(resp) => Ok(resp.mount_target_id),
         Err(RusotoError::Service(CreateMountTargetError::MountTargetConflict(_))) => {
             Ok(String::new())
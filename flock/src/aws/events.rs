@@ -0,0 +1,132 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! This crate contains all wrapped functions of the Amazon EventBridge
+//! (CloudWatch Events) service, which invokes the scheduled batch queries.
+
+use crate::configs::*;
+use crate::error::{FlockError, Result};
+use rusoto_events::{
+    CloudWatchEvents, DeleteRuleRequest, InputTransformer, ListRulesRequest, PutRuleRequest,
+    PutTargetsRequest, RemoveTargetsRequest, Rule, Target,
+};
+use std::collections::HashMap;
+
+/// Creates or updates the rule that triggers on the schedule expression.
+///
+/// # Arguments
+/// * `name` - The name of the rule.
+/// * `expression` - The schedule expression, e.g. `rate(5 minutes)`.
+/// * `description` - The description of the rule.
+///
+/// # Returns
+/// The ARN of the rule.
+pub async fn put_rule(name: &str, expression: &str, description: &str) -> Result<String> {
+    events_client("")
+        .put_rule(PutRuleRequest {
+            name: name.to_owned(),
+            schedule_expression: Some(expression.to_owned()),
+            description: Some(description.to_owned()),
+            state: Some("ENABLED".to_owned()),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?
+        .rule_arn
+        .ok_or_else(|| FlockError::AWS(format!("No ARN of the rule {}!", name)))
+}
+
+/// Sets the target of the rule to the lambda function. The input of the
+/// function is rendered from the event that triggers the rule.
+///
+/// # Arguments
+/// * `rule` - The name of the rule.
+/// * `id` - The identifier of the target in the rule.
+/// * `function_arn` - The ARN of the lambda function.
+/// * `input_paths` - The JSON paths of the event to substitute in the input.
+/// * `input_template` - The template of the input.
+pub async fn put_lambda_target(
+    rule: &str,
+    id: &str,
+    function_arn: &str,
+    input_paths: HashMap<String, String>,
+    input_template: &str,
+) -> Result<()> {
+    let response = events_client("")
+        .put_targets(PutTargetsRequest {
+            rule: rule.to_owned(),
+            targets: vec![Target {
+                id: id.to_owned(),
+                arn: function_arn.to_owned(),
+                input_transformer: Some(InputTransformer {
+                    input_paths_map: Some(input_paths),
+                    input_template:  input_template.to_owned(),
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    match response.failed_entry_count {
+        Some(count) if count > 0 => Err(FlockError::AWS(format!(
+            "Failed to set the target of the rule {}: {:?}",
+            rule, response.failed_entries
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the rules whose names begin with the prefix.
+pub async fn list_rules(prefix: &str) -> Result<Vec<Rule>> {
+    let mut request = ListRulesRequest {
+        name_prefix: Some(prefix.to_owned()),
+        ..Default::default()
+    };
+    let mut rules = vec![];
+    loop {
+        let response = events_client("")
+            .list_rules(request.clone())
+            .await
+            .map_err(|e| FlockError::AWS(e.to_string()))?;
+        rules.extend(response.rules.unwrap_or_default());
+        if response.next_token.is_none() {
+            break;
+        }
+        request.next_token = response.next_token;
+    }
+    Ok(rules)
+}
+
+/// Removes the targets from the rule, and deletes the rule.
+///
+/// # Arguments
+/// * `name` - The name of the rule.
+/// * `ids` - The identifiers of the targets of the rule.
+pub async fn delete_rule(name: &str, ids: &[&str]) -> Result<()> {
+    events_client("")
+        .remove_targets(RemoveTargetsRequest {
+            rule: name.to_owned(),
+            ids: ids.iter().map(|id| id.to_string()).collect(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    events_client("")
+        .delete_rule(DeleteRuleRequest {
+            name: name.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))
+}
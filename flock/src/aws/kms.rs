@@ -0,0 +1,166 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The [`KeyManagementClient`] trait abstracts the AWS KMS calls of the
+//! envelope encryption (see [`crate::encryption`]), so that the encryption can
+//! be tested without AWS.
+//!
+//! [`AwsKmsClient`] calls AWS KMS, and [`FakeKmsClient`] keeps the master keys
+//! in memory.
+
+use crate::configs::kms_client;
+use crate::encryption::{aes_gcm_open, aes_gcm_seal, DATA_KEY_LEN, NONCE_LEN};
+use crate::error::{FlockError, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use rand::RngCore;
+use rusoto_kms::{DecryptRequest, GenerateDataKeyRequest, Kms};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// The data key generated under a KMS key.
+#[derive(Clone, PartialEq, Eq)]
+pub struct DataKey {
+    /// The plaintext data key, which never leaves the function.
+    pub plaintext: Vec<u8>,
+    /// The data key encrypted by the KMS key, which is stored alongside the
+    /// encrypted data.
+    pub wrapped:   Vec<u8>,
+}
+
+impl Debug for DataKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DataKey")
+            .field("plaintext", &"<redacted>")
+            .field("wrapped", &self.wrapped.len())
+            .finish()
+    }
+}
+
+/// The KMS calls of the envelope encryption.
+#[async_trait]
+pub trait KeyManagementClient: Debug + Send + Sync {
+    /// Generates a 256-bit data key under the KMS key.
+    async fn generate_data_key(&self, key_id: &str) -> Result<DataKey>;
+
+    /// Returns the plaintext of the data key wrapped by the KMS key.
+    async fn decrypt(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// The client that calls AWS KMS.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AwsKmsClient;
+
+#[async_trait]
+impl KeyManagementClient for AwsKmsClient {
+    async fn generate_data_key(&self, key_id: &str) -> Result<DataKey> {
+        let response = kms_client("")
+            .generate_data_key(GenerateDataKeyRequest {
+                key_id: key_id.to_string(),
+                key_spec: Some("AES_256".to_string()),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| FlockError::AWS(e.to_string()))?;
+        match (response.plaintext, response.ciphertext_blob) {
+            (Some(plaintext), Some(wrapped)) => Ok(DataKey {
+                plaintext: plaintext.to_vec(),
+                wrapped:   wrapped.to_vec(),
+            }),
+            _ => Err(FlockError::AWS(format!(
+                "KMS returned no data key for {}",
+                key_id
+            ))),
+        }
+    }
+
+    async fn decrypt(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>> {
+        let response = kms_client("")
+            .decrypt(DecryptRequest {
+                ciphertext_blob: Bytes::from(wrapped.to_vec()),
+                key_id: Some(key_id.to_string()),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| FlockError::AWS(e.to_string()))?;
+        response
+            .plaintext
+            .map(|p| p.to_vec())
+            .ok_or_else(|| FlockError::AWS(format!("KMS returned no plaintext for {}", key_id)))
+    }
+}
+
+/// An in-memory KMS for tests. Each key id gets a random master key on first
+/// use, and the data keys are wrapped with AES-GCM under the master key, so
+/// that unwrapping with another key id fails like KMS does. It counts the
+/// calls to verify the caching of the data keys.
+#[derive(Debug, Default)]
+pub struct FakeKmsClient {
+    master_keys:    Mutex<HashMap<String, Vec<u8>>>,
+    generate_calls: AtomicUsize,
+    decrypt_calls:  AtomicUsize,
+}
+
+impl FakeKmsClient {
+    /// Creates an empty client.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of `GenerateDataKey` calls.
+    pub fn generate_calls(&self) -> usize {
+        self.generate_calls.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of `Decrypt` calls.
+    pub fn decrypt_calls(&self) -> usize {
+        self.decrypt_calls.load(Ordering::SeqCst)
+    }
+
+    fn master_key(&self, key_id: &str) -> Vec<u8> {
+        self.master_keys
+            .lock()
+            .unwrap()
+            .entry(key_id.to_string())
+            .or_insert_with(|| {
+                let mut key = vec![0u8; DATA_KEY_LEN];
+                rand::thread_rng().fill_bytes(&mut key);
+                key
+            })
+            .clone()
+    }
+}
+
+#[async_trait]
+impl KeyManagementClient for FakeKmsClient {
+    async fn generate_data_key(&self, key_id: &str) -> Result<DataKey> {
+        self.generate_calls.fetch_add(1, Ordering::SeqCst);
+        let mut plaintext = vec![0u8; DATA_KEY_LEN];
+        rand::thread_rng().fill_bytes(&mut plaintext);
+        let (mut wrapped, ciphertext) = aes_gcm_seal(&self.master_key(key_id), &plaintext)?;
+        wrapped.extend(ciphertext);
+        Ok(DataKey { plaintext, wrapped })
+    }
+
+    async fn decrypt(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>> {
+        self.decrypt_calls.fetch_add(1, Ordering::SeqCst);
+        if wrapped.len() < NONCE_LEN {
+            return Err(FlockError::AWS("InvalidCiphertextException".to_string()));
+        }
+        let (nonce, ciphertext) = wrapped.split_at(NONCE_LEN);
+        aes_gcm_open(&self.master_key(key_id), nonce, ciphertext)
+            .map_err(|_| FlockError::AWS("IncorrectKeyException".to_string()))
+    }
+}
@@ -13,18 +13,47 @@
 
 //! This crate contains all wrapped functions of the AWS Lambda services.
 
+use crate::aws::client::{FunctionState, UpdateStatus};
+use crate::aws::s3;
 use crate::configs::*;
 use crate::error::{FlockError, Result};
-use crate::runtime::context::ExecutionContext;
+use crate::runtime::context::{self, ExecutionContext};
+use crate::runtime::deadline::{self, SystemClock};
+use crate::runtime::embedded::{without_plans, FLOCK_EMBEDDED_STAGE};
+use crate::runtime::metrics::{self, Metric};
+use crate::runtime::payload::next_attempt;
 use bytes::Bytes;
+use lazy_static::lazy_static;
 use log::{debug, info};
 use rand::Rng;
+use rusoto_core::RusotoError;
 use rusoto_lambda::{
-    CreateFunctionRequest, GetFunctionRequest, InvocationRequest, InvocationResponse, Lambda,
-    PutFunctionConcurrencyRequest, UpdateFunctionCodeRequest,
+    AddPermissionRequest, CreateEventSourceMappingRequest, CreateFunctionError,
+    CreateFunctionRequest, DeleteEventSourceMappingRequest, DeleteFunctionRequest,
+    EventSourceMappingConfiguration, FunctionConfiguration, GetFunctionConcurrencyRequest,
+    GetFunctionConfigurationRequest, GetFunctionError, GetFunctionRequest, InvocationRequest,
+    InvocationResponse, Lambda, ListEventSourceMappingsRequest, ListFunctionsRequest,
+    PutFunctionConcurrencyRequest, RemovePermissionRequest, UpdateEventSourceMappingRequest,
+    UpdateFunctionCodeRequest, UpdateFunctionConfigurationRequest,
 };
+use std::collections::HashMap;
+use std::sync::Mutex;
 use std::time::Duration;
 
+/// The error code of AWS Lambda for creating a function that exists, or for
+/// updating a function whose previous update is in progress.
+pub const RESOURCE_CONFLICT: &str = "ResourceConflictException";
+
+lazy_static! {
+    /// The code hashes of the function binaries by their S3 keys.
+    static ref CODE_SHA256: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// Returns true if the error is a [`RESOURCE_CONFLICT`].
+pub fn is_resource_conflict(e: &FlockError) -> bool {
+    matches!(e, FlockError::AWS(message) if message.contains(RESOURCE_CONFLICT))
+}
+
 /// Sets the lambda function's concurrency.
 ///
 /// # Arguments
@@ -37,7 +66,7 @@ pub async fn set_concurrency(function_name: &str, concurrency: i64) -> Result<()
         function_name:                  function_name.to_owned(),
         reserved_concurrent_executions: concurrency,
     };
-    let concurrency = FLOCK_LAMBDA_CLIENT
+    let concurrency = lambda_client("")
         .put_function_concurrency(request)
         .await
         .map_err(|e| FlockError::Internal(e.to_string()))?;
@@ -45,6 +74,217 @@ pub async fn set_concurrency(function_name: &str, concurrency: i64) -> Result<()
     Ok(())
 }
 
+/// Returns the reserved concurrency of the lambda function, or `None` if the
+/// function shares the unreserved concurrency of the account.
+pub async fn get_concurrency(function_name: &str) -> Result<Option<i64>> {
+    Ok(lambda_client("")
+        .get_function_concurrency(GetFunctionConcurrencyRequest {
+            function_name: function_name.to_owned(),
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?
+        .reserved_concurrent_executions)
+}
+
+/// Returns the concurrency limit of the account, and the part of it that
+/// isn't reserved by any function.
+pub async fn account_concurrency() -> Result<(i64, i64)> {
+    let limit = lambda_client("")
+        .get_account_settings()
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?
+        .account_limit
+        .ok_or_else(|| FlockError::AWS("No account limit of AWS Lambda!".to_string()))?;
+    Ok((
+        limit.concurrent_executions.unwrap_or_default(),
+        limit.unreserved_concurrent_executions.unwrap_or_default(),
+    ))
+}
+
+/// Throttles the lambda function by setting its concurrency to zero, so that
+/// no more events are processed by the function.
+///
+/// # Arguments
+/// * `function_name` - The name of the lambda function.
+pub async fn throttle_function(function_name: &str) -> Result<()> {
+    let request = PutFunctionConcurrencyRequest {
+        function_name:                  function_name.to_owned(),
+        reserved_concurrent_executions: 0,
+    };
+    lambda_client("")
+        .put_function_concurrency(request)
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    Ok(())
+}
+
+/// Returns true if the lambda function exists.
+pub async fn function_exists(function_name: &str) -> bool {
+    lambda_client("")
+        .get_function(GetFunctionRequest {
+            function_name: function_name.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .is_ok()
+}
+
+/// Deletes the lambda function.
+pub async fn delete_function(function_name: &str) -> Result<()> {
+    lambda_client("")
+        .delete_function(DeleteFunctionRequest {
+            function_name: function_name.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))
+}
+
+/// Creates the event source mapping that invokes the lambda function with the
+/// records of a stream, e.g. Kinesis Data Streams or Kafka.
+///
+/// # Returns
+/// The identifier of the event source mapping.
+pub async fn create_event_source_mapping(
+    request: CreateEventSourceMappingRequest,
+) -> Result<String> {
+    lambda_client("")
+        .create_event_source_mapping(request)
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?
+        .uuid
+        .ok_or_else(|| FlockError::AWS("No event source mapping id!".to_string()))
+}
+
+/// Deletes the event source mapping, which stops reading from the stream.
+pub async fn delete_event_source_mapping(uuid: &str) -> Result<()> {
+    lambda_client("")
+        .delete_event_source_mapping(DeleteEventSourceMappingRequest {
+            uuid: uuid.to_owned(),
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    Ok(())
+}
+
+/// Returns the event source mappings that invoke the lambda function.
+pub async fn list_event_source_mappings(
+    function_name: &str,
+) -> Result<Vec<EventSourceMappingConfiguration>> {
+    let mut request = ListEventSourceMappingsRequest {
+        function_name: Some(function_name.to_owned()),
+        ..Default::default()
+    };
+    let mut mappings = vec![];
+    loop {
+        let response = lambda_client("")
+            .list_event_source_mappings(request.clone())
+            .await
+            .map_err(|e| FlockError::AWS(e.to_string()))?;
+        mappings.extend(response.event_source_mappings.unwrap_or_default());
+        if response.next_marker.is_none() {
+            break;
+        }
+        request.marker = response.next_marker;
+    }
+    Ok(mappings)
+}
+
+/// Points the event source mapping to another lambda function. The mapping
+/// keeps its position in the stream, so no records are skipped or replayed.
+pub async fn update_event_source_mapping(uuid: &str, function_name: &str) -> Result<()> {
+    lambda_client("")
+        .update_event_source_mapping(UpdateEventSourceMappingRequest {
+            uuid: uuid.to_owned(),
+            function_name: Some(function_name.to_owned()),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    Ok(())
+}
+
+/// Returns the ARN of the lambda function.
+pub async fn function_arn(function_name: &str) -> Result<String> {
+    lambda_client("")
+        .get_function_configuration(GetFunctionConfigurationRequest {
+            function_name: function_name.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?
+        .function_arn
+        .ok_or_else(|| FlockError::AWS(format!("No ARN of {}!", function_name)))
+}
+
+/// Allows the AWS service to invoke the lambda function. The permission is
+/// left as it is if the statement already exists.
+///
+/// # Arguments
+/// * `function_name` - The name of the lambda function.
+/// * `statement_id` - The identifier of the permission statement.
+/// * `principal` - The AWS service, e.g. `events.amazonaws.com`.
+/// * `source_arn` - The ARN of the resource that invokes the function.
+pub async fn add_permission(
+    function_name: &str,
+    statement_id: &str,
+    principal: &str,
+    source_arn: &str,
+) -> Result<()> {
+    match lambda_client("")
+        .add_permission(AddPermissionRequest {
+            action: "lambda:InvokeFunction".to_owned(),
+            function_name: function_name.to_owned(),
+            principal: principal.to_owned(),
+            source_arn: Some(source_arn.to_owned()),
+            statement_id: statement_id.to_owned(),
+            ..Default::default()
+        })
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) if e.to_string().contains("already exists") => Ok(()),
+        Err(e) => Err(FlockError::AWS(e.to_string())),
+    }
+}
+
+/// Removes the permission statement from the lambda function.
+pub async fn remove_permission(function_name: &str, statement_id: &str) -> Result<()> {
+    lambda_client("")
+        .remove_permission(RemovePermissionRequest {
+            function_name: function_name.to_owned(),
+            statement_id: statement_id.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))
+}
+
+/// Returns the names of the lambda functions that begin with the prefix.
+pub async fn list_functions(prefix: &str) -> Result<Vec<String>> {
+    let mut request = ListFunctionsRequest::default();
+    let mut functions = vec![];
+    loop {
+        let response = lambda_client("")
+            .list_functions(request.clone())
+            .await
+            .map_err(|e| FlockError::AWS(e.to_string()))?;
+        functions.extend(
+            response
+                .functions
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|f| f.function_name)
+                .filter(|name| name.starts_with(prefix)),
+        );
+        if response.next_marker.is_none() {
+            break;
+        }
+        request.marker = response.next_marker;
+    }
+    Ok(functions)
+}
+
 /// Invokes the lambda function with the given payload.
 ///
 /// # Arguments
@@ -55,13 +295,15 @@ pub async fn set_concurrency(function_name: &str, concurrency: i64) -> Result<()
 ///   - `RequestResponse`: Synchronous invocation.
 ///
 /// # Returns
-/// The result of the invocation.
+/// The result of the invocation. The synchronous invocation is retried on
+/// failures, and each retry increments the delivery attempt of the payload
+/// (see [`next_attempt`]).
 pub async fn invoke_function(
     function_name: &str,
     invocation_type: &str,
     payload: Option<Bytes>,
 ) -> Result<InvocationResponse> {
-    let request = InvocationRequest {
+    let mut request = InvocationRequest {
         function_name: function_name.to_owned(),
         invocation_type: Some(invocation_type.to_owned()),
         payload,
@@ -69,7 +311,7 @@ pub async fn invoke_function(
     };
 
     if invocation_type == *FLOCK_LAMBDA_ASYNC_CALL {
-        let response = FLOCK_LAMBDA_CLIENT
+        let response = lambda_client("")
             .invoke(request)
             .await
             .map_err(|e| FlockError::AWS(e.to_string()))?;
@@ -80,7 +322,7 @@ pub async fn invoke_function(
         let mut retries = 0;
         let mut increase_factor = 0;
         loop {
-            match FLOCK_LAMBDA_CLIENT
+            match lambda_client("")
                 .invoke(request.clone())
                 .await
                 .map_err(|e| FlockError::AWS(e.to_string()))
@@ -106,6 +348,7 @@ pub async fn invoke_function(
                 retries, function_name
             );
             retries += 1;
+            metrics::scope().incr(Metric::Retries);
 
             increase_factor = std::cmp::min(increase_factor, 9) + 1;
 
@@ -115,6 +358,15 @@ pub async fn invoke_function(
                 *FLOCK_LAMBDA_MAX_BACKOFF,
             ));
 
+            // The retries stop early if the backoff would exceed the deadline of
+            // the invocation, so that the caller still has time to fail gracefully.
+            if let Some(deadline) = deadline::current() {
+                if !deadline.allows(&SystemClock, backoff) {
+                    metrics::scope().incr(Metric::Timeouts);
+                    return Err(deadline.exceeded(&SystemClock, function_name));
+                }
+            }
+
             tokio::time::sleep(backoff).await;
 
             if retries as usize > *FLOCK_LAMBDA_MAX_RETRIES {
@@ -123,6 +375,11 @@ pub async fn invoke_function(
                     *FLOCK_LAMBDA_MAX_RETRIES
                 )));
             }
+
+            // The retry resends the same payload as its next delivery attempt.
+            if let Some(payload) = request.payload.as_ref().and_then(|p| next_attempt(p)) {
+                request.payload = Some(payload.into());
+            }
         }
     }
 }
@@ -141,58 +398,324 @@ pub async fn create_function(
     memory_size: i64,
     architecture: &str,
 ) -> Result<String> {
-    let func_name = ctx.name.clone();
-    let flock_s3_key = if architecture == "x86_64" {
+    deploy_function(ctx, None, memory_size, architecture).await
+}
+
+/// Creates a single lambda function that runs the plans of the stage embedded
+/// in the function binary (see [`crate::runtime::embedded`]). The execution
+/// context in its environment carries no plans.
+///
+/// # Arguments
+/// * `ctx` - The execution context.
+/// * `stage` - The name of the embedded stage, e.g. `q3-00`.
+/// * `memory_size` - The memory size of the lambda function.
+/// * `architecture` - The architecture of the lambda function.
+///
+/// # Returns
+/// The name of the created lambda function.
+pub async fn create_embedded_function(
+    ctx: &ExecutionContext,
+    stage: &str,
+    memory_size: i64,
+    architecture: &str,
+) -> Result<String> {
+    deploy_function(&without_plans(ctx), Some(stage), memory_size, architecture).await
+}
+
+/// Creates the lambda function, or updates its code if it already exists.
+async fn deploy_function(
+    ctx: &ExecutionContext,
+    embedded_stage: Option<&str>,
+    memory_size: i64,
+    architecture: &str,
+) -> Result<String> {
+    if function_exists(&ctx.name).await {
+        update_code(&ctx.name, architecture).await?;
+        Ok(ctx.name.clone())
+    } else {
+        create_new_function(ctx, embedded_stage, memory_size, architecture).await
+    }
+}
+
+/// Returns the S3 key of the function binary of the architecture.
+fn code_key(architecture: &str) -> String {
+    if architecture == "x86_64" {
         FLOCK_S3_X86_64_KEY.clone()
     } else {
         FLOCK_S3_ARM_64_KEY.clone()
-    };
+    }
+}
 
+/// Returns the configuration of the lambda function of the context.
+async fn function_config(
+    ctx: &ExecutionContext,
+    embedded_stage: Option<&str>,
+    memory_size: i64,
+    architecture: &str,
+) -> Result<AwsLambdaConfig> {
     let mut conf = AwsLambdaConfig::try_new().await?;
     conf.set_memory_size(memory_size);
     conf.set_function_spec(ctx);
+    if let Some(stage) = embedded_stage {
+        conf.set_embedded_stage(stage);
+    }
     conf.set_architectures(vec![architecture.to_string()]);
-    conf.set_code(&flock_s3_key);
+    conf.set_code(&code_key(architecture));
+    Ok(conf)
+}
+
+/// Creates the lambda function, which must not exist. The functions of an
+/// embedded stage are created without the plans of the context.
+///
+/// # Arguments
+/// * `ctx` - The execution context.
+/// * `embedded_stage` - The name of the embedded stage, if any.
+/// * `memory_size` - The memory size of the lambda function.
+/// * `architecture` - The architecture of the lambda function.
+///
+/// # Returns
+/// The name of the created lambda function, or a [`RESOURCE_CONFLICT`] if
+/// the function exists.
+pub async fn create_new_function(
+    ctx: &ExecutionContext,
+    embedded_stage: Option<&str>,
+    memory_size: i64,
+    architecture: &str,
+) -> Result<String> {
+    let ctx = match embedded_stage {
+        Some(_) => without_plans(ctx),
+        None => ctx.clone(),
+    };
+    let conf = function_config(&ctx, embedded_stage, memory_size, architecture).await?;
+    let resp = lambda_client("")
+        .create_function(CreateFunctionRequest {
+            architectures: conf.architectures,
+            function_name: conf.function_name,
+            code: conf.code,
+            handler: conf.handler,
+            runtime: conf.runtime,
+            role: conf.role,
+            vpc_config: conf.vpc_config,
+            environment: conf.environment,
+            timeout: conf.timeout,
+            memory_size: conf.memory_size,
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| match e {
+            RusotoError::Service(CreateFunctionError::ResourceConflict(message)) => {
+                FlockError::AWS(format!("{}: {}", RESOURCE_CONFLICT, message))
+            }
+            e => FlockError::AWS(e.to_string()),
+        })?;
 
-    if FLOCK_LAMBDA_CLIENT
+    resp.function_name
+        .ok_or_else(|| FlockError::AWS("No function name!".to_string()))
+}
+
+/// Returns the deployed configuration of the lambda function, or `None` if
+/// the function doesn't exist.
+pub async fn get_function(function_name: &str) -> Result<Option<FunctionState>> {
+    let conf = match lambda_client("")
         .get_function(GetFunctionRequest {
-            function_name: ctx.name.clone(),
+            function_name: function_name.to_owned(),
             ..Default::default()
         })
         .await
-        .is_ok()
     {
-        let conf = FLOCK_LAMBDA_CLIENT
-            .update_function_code(UpdateFunctionCodeRequest {
-                architectures: conf.architectures,
-                function_name: func_name.clone(),
-                s3_bucket: Some(FLOCK_S3_BUCKET.clone()),
-                s3_key: Some(flock_s3_key),
-                ..Default::default()
-            })
-            .await
-            .map_err(|e| FlockError::AWS(e.to_string()))?;
-        conf.function_name
-            .ok_or_else(|| FlockError::AWS("No function name!".to_string()))
-    } else {
-        let resp = FLOCK_LAMBDA_CLIENT
-            .create_function(CreateFunctionRequest {
-                architectures: conf.architectures,
-                function_name: conf.function_name,
-                code: conf.code,
-                handler: conf.handler,
-                runtime: conf.runtime,
-                role: conf.role,
-                vpc_config: conf.vpc_config,
-                environment: conf.environment,
-                timeout: conf.timeout,
-                memory_size: conf.memory_size,
-                ..Default::default()
-            })
-            .await
-            .map_err(|e| FlockError::AWS(e.to_string()))?;
+        Ok(resp) => resp.configuration.unwrap_or_default(),
+        Err(RusotoError::Service(GetFunctionError::ResourceNotFound(_))) => return Ok(None),
+        Err(e) => return Err(FlockError::AWS(e.to_string())),
+    };
+    // A function just created is pending until it can be invoked or updated.
+    let status = match (conf.state.as_deref(), conf.last_update_status.as_deref()) {
+        (Some("Pending"), _) | (_, Some("InProgress")) => UpdateStatus::InProgress,
+        (Some("Failed"), _) => UpdateStatus::Failed(conf.state_reason.clone().unwrap_or_default()),
+        (_, Some("Failed")) => {
+            UpdateStatus::Failed(conf.last_update_status_reason.clone().unwrap_or_default())
+        }
+        _ => UpdateStatus::Successful,
+    };
+    Ok(Some(FunctionState {
+        code_sha256: conf.code_sha256.clone().unwrap_or_default(),
+        memory_size: conf.memory_size.unwrap_or_default(),
+        architecture: conf
+            .architectures
+            .as_ref()
+            .and_then(|archs| archs.first().cloned())
+            .unwrap_or_else(|| "x86_64".to_owned()),
+        context: environment_context(function_name, &conf).ok(),
+        embedded_stage: conf
+            .environment
+            .as_ref()
+            .and_then(|env| env.variables.as_ref())
+            .and_then(|vars| vars.get(FLOCK_EMBEDDED_STAGE))
+            .cloned(),
+        status,
+    }))
+}
 
-        resp.function_name
-            .ok_or_else(|| FlockError::AWS("No function name!".to_string()))
+/// Returns the code hash of the function binary of the architecture in the
+/// Flock bucket, as AWS Lambda reports it for the functions running it. The
+/// binary is read once per driver, so it must not be replaced while the
+/// driver runs.
+pub async fn code_sha256(architecture: &str) -> Result<String> {
+    let key = code_key(architecture);
+    if let Some(hash) = CODE_SHA256.lock().unwrap().get(&key) {
+        return Ok(hash.clone());
     }
+    let code = s3::get_object(&FLOCK_S3_BUCKET, &key).await?;
+    let hash = base64::encode(openssl::sha::sha256(&code));
+    CODE_SHA256.lock().unwrap().insert(key, hash.clone());
+    Ok(hash)
+}
+
+/// Replaces the code of the lambda function with the function binary of the
+/// architecture in the Flock bucket.
+///
+/// # Arguments
+/// * `function_name` - The name of the lambda function to update.
+/// * `architecture` - The architecture of the function binary.
+pub async fn update_code(function_name: &str, architecture: &str) -> Result<()> {
+    lambda_client("")
+        .update_function_code(UpdateFunctionCodeRequest {
+            architectures: Some(vec![architecture.to_owned()]),
+            function_name: function_name.to_owned(),
+            s3_bucket: Some(FLOCK_S3_BUCKET.clone()),
+            s3_key: Some(code_key(architecture)),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    Ok(())
+}
+
+/// Replaces the environment and the memory size of the lambda function with
+/// those of the context.
+///
+/// # Arguments
+/// * `ctx` - The execution context.
+/// * `embedded_stage` - The name of the embedded stage, if any.
+/// * `memory_size` - The memory size of the lambda function.
+pub async fn update_function_configuration(
+    ctx: &ExecutionContext,
+    embedded_stage: Option<&str>,
+    memory_size: i64,
+) -> Result<()> {
+    let ctx = match embedded_stage {
+        Some(_) => without_plans(ctx),
+        None => ctx.clone(),
+    };
+    // The architecture belongs to the code of the function.
+    let conf = function_config(&ctx, embedded_stage, memory_size, "x86_64").await?;
+    lambda_client("")
+        .update_function_configuration(UpdateFunctionConfigurationRequest {
+            function_name: ctx.name.clone(),
+            environment: conf.environment,
+            memory_size: conf.memory_size,
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    Ok(())
+}
+
+/// Creates a copy of the lambda function under another name, e.g. a new
+/// member of a function group. The copy runs the same code with the same
+/// memory size and architecture, and its execution context is the context of
+/// the function renamed to the copy.
+///
+/// # Arguments
+/// * `function_name` - The name of the lambda function to copy.
+/// * `copy_name` - The name of the copy.
+///
+/// # Returns
+/// The name of the created lambda function.
+pub async fn copy_function(function_name: &str, copy_name: &str) -> Result<String> {
+    let conf = lambda_client("")
+        .get_function_configuration(GetFunctionConfigurationRequest {
+            function_name: function_name.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    let mut ctx = environment_context(function_name, &conf)?;
+    ctx.name = copy_name.to_owned();
+    // The copy runs the same embedded stage, if any.
+    let stage = conf
+        .environment
+        .as_ref()
+        .and_then(|env| env.variables.as_ref())
+        .and_then(|vars| vars.get(FLOCK_EMBEDDED_STAGE))
+        .cloned();
+    let architecture = conf
+        .architectures
+        .and_then(|archs| archs.into_iter().next())
+        .unwrap_or_else(|| "x86_64".to_owned());
+    deploy_function(
+        &ctx,
+        stage.as_deref(),
+        conf.memory_size.unwrap_or(128),
+        &architecture,
+    )
+    .await
+}
+
+/// Returns the execution context stored in the environment of the lambda
+/// function.
+///
+/// # Arguments
+/// * `function_name` - The name of the lambda function.
+pub async fn function_context(function_name: &str) -> Result<ExecutionContext> {
+    let conf = lambda_client("")
+        .get_function_configuration(GetFunctionConfigurationRequest {
+            function_name: function_name.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    environment_context(function_name, &conf)
+}
+
+/// Unmarshals the execution context from the environment variables of the
+/// function configuration.
+fn environment_context(
+    function_name: &str,
+    conf: &FunctionConfiguration,
+) -> Result<ExecutionContext> {
+    let encoded_ctx = conf
+        .environment
+        .as_ref()
+        .and_then(|env| env.variables.as_ref())
+        .and_then(|vars| vars.get(&FLOCK_CONF["lambda"]["environment"]))
+        .ok_or_else(|| {
+            FlockError::AWS(format!(
+                "No execution context in function {}",
+                function_name
+            ))
+        })?;
+    context::unmarshal(encoded_ctx)
+}
+
+/// Replaces the code of the lambda function with the function binary in the
+/// Flock bucket, e.g. to upgrade the binary of a running query. The function
+/// keeps its architecture and configuration.
+///
+/// # Arguments
+/// * `function_name` - The name of the lambda function to update.
+pub async fn update_function_code(function_name: &str) -> Result<()> {
+    let conf = lambda_client("")
+        .get_function_configuration(GetFunctionConfigurationRequest {
+            function_name: function_name.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    let architecture = conf
+        .architectures
+        .and_then(|archs| archs.into_iter().next())
+        .unwrap_or_else(|| "x86_64".to_owned());
+    update_code(function_name, &architecture).await?;
+    info!("[OK] Updated the code of the function: {}", function_name);
+    Ok(())
 }
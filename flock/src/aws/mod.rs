@@ -15,9 +15,15 @@
 //! Lambda, DynamoDB, S3, etc. Flock uses the AWS services to build the
 //! distributed query engine.
 
+pub mod chaos;
+pub mod client;
 pub mod cloudwatch;
 pub mod dynamodb;
 pub mod efs;
+pub mod events;
+pub mod kms;
 pub mod lambda;
+pub mod reconcile;
 pub mod s3;
 pub mod sqs;
+pub mod stepfunctions;
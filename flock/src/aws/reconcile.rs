@@ -0,0 +1,544 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The deployment reconciles the functions of a query with their desired
+//! state instead of creating them one by one, so that it can be rerun after
+//! it died halfway, e.g. on expired credentials or on throttling. Otherwise
+//! the rerun fails on the functions created by the first run.
+//!
+//! Each function is compared with its [`FunctionSpec`]. It's created if it
+//! doesn't exist, its code is updated if the code hash or the architecture
+//! differ, and its configuration is updated if the execution context, the
+//! embedded stage or the memory size differ. AWS Lambda rejects the update of
+//! a function whose previous update is in progress, so each update waits for
+//! the function to settle. The outcome of every function is collected into a
+//! [`ReconcileReport`], and a failed function doesn't stop the others.
+
+use crate::aws::client::{CloudClient, FunctionState, UpdateStatus};
+use crate::aws::lambda::is_resource_conflict;
+use crate::configs::FLOCK_RECONCILE_CONCURRENCY;
+use crate::error::{FlockError, Result};
+use crate::runtime::context::ExecutionContext;
+use crate::runtime::embedded::without_plans;
+use futures::stream::{self, StreamExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+/// The desired state of a function.
+#[derive(Debug, Clone)]
+pub struct FunctionSpec {
+    /// The execution context of the function.
+    pub ctx:            ExecutionContext,
+    /// The stage whose plans are embedded in the binary, if any (see
+    /// [`crate::runtime::embedded`]).
+    pub embedded_stage: Option<String>,
+    /// The memory size of the function in MB.
+    pub memory_size:    i64,
+    /// The architecture of the function.
+    pub architecture:   String,
+}
+
+impl FunctionSpec {
+    /// Creates the spec of the function of the context.
+    pub fn new(ctx: ExecutionContext, memory_size: i64, architecture: &str) -> Self {
+        Self {
+            ctx,
+            embedded_stage: None,
+            memory_size,
+            architecture: architecture.to_string(),
+        }
+    }
+
+    /// Sets the stage whose plans are embedded in the binary.
+    pub fn with_embedded_stage(mut self, embedded_stage: Option<String>) -> Self {
+        self.embedded_stage = embedded_stage;
+        self
+    }
+
+    /// Returns the name of the function.
+    pub fn name(&self) -> &str {
+        &self.ctx.name
+    }
+
+    /// Returns the execution context in the environment of the function. The
+    /// functions of the embedded stages are deployed without the plans.
+    fn deployed_context(&self) -> ExecutionContext {
+        match self.embedded_stage {
+            Some(_) => without_plans(&self.ctx),
+            None => self.ctx.clone(),
+        }
+    }
+}
+
+/// The differences between a deployed function and its spec.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FunctionDiff {
+    /// The code hash differs from the hash of the function binary.
+    pub code:         bool,
+    /// The architecture differs.
+    pub architecture: bool,
+    /// The execution context or the embedded stage differ. The rest of the
+    /// environment, e.g. the log levels, follows the driver and isn't compared.
+    pub environment:  bool,
+    /// The memory size differs.
+    pub memory_size:  bool,
+}
+
+impl FunctionDiff {
+    /// Compares the deployed function with the spec.
+    ///
+    /// # Arguments
+    /// * `state` - The deployed configuration of the function.
+    /// * `spec` - The desired state of the function.
+    /// * `code_sha256` - The code hash of the function binary of the spec.
+    pub fn between(state: &FunctionState, spec: &FunctionSpec, code_sha256: &str) -> Self {
+        Self {
+            code:         state.code_sha256 != code_sha256,
+            architecture: state.architecture != spec.architecture,
+            environment:  state.context.as_ref() != Some(&spec.deployed_context())
+                || state.embedded_stage != spec.embedded_stage,
+            memory_size:  state.memory_size != spec.memory_size,
+        }
+    }
+
+    /// Returns true if the function is in its desired state.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Returns true if the code of the function is updated.
+    fn code_changed(&self) -> bool {
+        self.code || self.architecture
+    }
+
+    /// Returns true if the configuration of the function is updated.
+    fn configuration_changed(&self) -> bool {
+        self.environment || self.memory_size
+    }
+}
+
+impl fmt::Display for FunctionDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = [
+            (self.code, "code"),
+            (self.architecture, "architecture"),
+            (self.environment, "environment"),
+            (self.memory_size, "memory size"),
+        ];
+        let changed = fields
+            .iter()
+            .filter(|(changed, _)| *changed)
+            .map(|(_, field)| *field)
+            .collect::<Vec<_>>();
+        write!(f, "{}", changed.join(", "))
+    }
+}
+
+/// The action taken on a function by the reconciliation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FunctionAction {
+    /// The function didn't exist, and it was created.
+    Created,
+    /// The function existed with the differences, and it was updated.
+    Updated(FunctionDiff),
+    /// The function was already in its desired state, or it was reused.
+    Unchanged,
+    /// The function couldn't be reconciled for the reason.
+    Failed(String),
+}
+
+impl fmt::Display for FunctionAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FunctionAction::Created => write!(f, "created"),
+            FunctionAction::Updated(diff) => write!(f, "updated ({})", diff),
+            FunctionAction::Unchanged => write!(f, "unchanged"),
+            FunctionAction::Failed(reason) => write!(f, "failed: {}", reason),
+        }
+    }
+}
+
+/// The actions taken on the functions of a deployment.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReconcileReport {
+    /// The functions and their actions, in the order of the specs.
+    pub actions: Vec<(String, FunctionAction)>,
+}
+
+impl ReconcileReport {
+    /// Returns the names of all functions.
+    pub fn functions(&self) -> Vec<String> {
+        self.actions.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// Returns the names of the functions that couldn't be reconciled.
+    pub fn failed(&self) -> Vec<&str> {
+        self.actions
+            .iter()
+            .filter(|(_, action)| matches!(action, FunctionAction::Failed(_)))
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// Returns an error with the failed functions, if any. Rerunning the
+    /// deployment reconciles them again.
+    pub fn check(&self) -> Result<()> {
+        let errors = self
+            .actions
+            .iter()
+            .filter_map(|(name, action)| match action {
+                FunctionAction::Failed(reason) => Some(format!("{}: {}", name, reason)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(FlockError::FunctionGeneration(errors.join("\n")))
+        }
+    }
+}
+
+impl fmt::Display for ReconcileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = |kind: fn(&FunctionAction) -> bool| {
+            self.actions.iter().filter(|(_, a)| kind(a)).count()
+        };
+        writeln!(
+            f,
+            "Reconciled {} functions: {} created, {} updated, {} unchanged, {} failed",
+            self.actions.len(),
+            count(|a| matches!(a, FunctionAction::Created)),
+            count(|a| matches!(a, FunctionAction::Updated(_))),
+            count(|a| matches!(a, FunctionAction::Unchanged)),
+            count(|a| matches!(a, FunctionAction::Failed(_))),
+        )?;
+        for (name, action) in &self.actions {
+            writeln!(f, "  {}: {}", name, action)?;
+        }
+        Ok(())
+    }
+}
+
+/// How the reconciliation waits for a function to settle, i.e. for its update
+/// to complete, or for a function just created to be visible, and how many
+/// functions it reconciles at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Polling {
+    /// The time between the lookups of the function.
+    pub interval:    Duration,
+    /// The maximum number of the lookups.
+    pub attempts:    usize,
+    /// The number of functions reconciled at once, which bounds the rate of
+    /// the AWS Lambda calls.
+    pub concurrency: usize,
+}
+
+impl Default for Polling {
+    fn default() -> Self {
+        Self {
+            interval:    Duration::from_secs(1),
+            attempts:    60,
+            concurrency: *FLOCK_RECONCILE_CONCURRENCY,
+        }
+    }
+}
+
+/// Reconciles the functions with their specs, at most `polling.concurrency`
+/// of them at once.
+///
+/// # Arguments
+/// * `client` - The client of the AWS Lambda calls.
+/// * `specs` - The desired states of the functions.
+/// * `polling` - How to wait for the functions to settle.
+///
+/// # Returns
+/// The action taken on each function. The error is only returned if the code
+/// hash of the function binaries can't be read.
+pub async fn reconcile_functions(
+    client: &dyn CloudClient,
+    specs: &[FunctionSpec],
+    polling: Polling,
+) -> Result<ReconcileReport> {
+    let mut hashes = HashMap::new();
+    for spec in specs {
+        if !hashes.contains_key(&spec.architecture) {
+            let hash = client.code_sha256(&spec.architecture).await?;
+            hashes.insert(spec.architecture.clone(), hash);
+        }
+    }
+    let mut actions = stream::iter(specs.iter().enumerate())
+        .map(|(i, spec)| {
+            let code_sha256 = &hashes[&spec.architecture];
+            async move {
+                let action = reconcile_function(client, spec, code_sha256, polling).await;
+                (i, (spec.name().to_string(), action))
+            }
+        })
+        .buffer_unordered(polling.concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    // The report follows the order of the specs.
+    actions.sort_by_key(|(i, _)| *i);
+    Ok(ReconcileReport {
+        actions: actions.into_iter().map(|(_, action)| action).collect(),
+    })
+}
+
+/// Reconciles the function with its spec.
+///
+/// # Arguments
+/// * `client` - The client of the AWS Lambda calls.
+/// * `spec` - The desired state of the function.
+/// * `code_sha256` - The code hash of the function binary of the spec.
+/// * `polling` - How to wait for the function to settle.
+pub async fn reconcile_function(
+    client: &dyn CloudClient,
+    spec: &FunctionSpec,
+    code_sha256: &str,
+    polling: Polling,
+) -> FunctionAction {
+    match try_reconcile(client, spec, code_sha256, polling).await {
+        Ok(action) => action,
+        Err(e) => FunctionAction::Failed(e.to_string()),
+    }
+}
+
+async fn try_reconcile(
+    client: &dyn CloudClient,
+    spec: &FunctionSpec,
+    code_sha256: &str,
+    polling: Polling,
+) -> Result<FunctionAction> {
+    let name = spec.name();
+    let state = match client.get_function(name).await? {
+        Some(state) if state.status != UpdateStatus::InProgress => state,
+        Some(_) => settle(client, name, polling).await?,
+        None => match client
+            .create_function(
+                &spec.ctx,
+                spec.embedded_stage.as_deref(),
+                spec.memory_size,
+                &spec.architecture,
+            )
+            .await
+        {
+            Ok(_) => return Ok(FunctionAction::Created),
+            // The function was created by a former run whose lookup missed it,
+            // so it's updated instead once it's visible.
+            Err(e) if is_resource_conflict(&e) => settle(client, name, polling).await?,
+            Err(e) => return Err(e),
+        },
+    };
+
+    // A failed update of a former run is applied again.
+    let diff = FunctionDiff::between(&state, spec, code_sha256);
+    if diff.code_changed() {
+        client
+            .update_function_code(name, &spec.architecture)
+            .await?;
+        check_update(name, settle(client, name, polling).await?)?;
+    }
+    if diff.configuration_changed() {
+        client
+            .update_function_configuration(
+                &spec.ctx,
+                spec.embedded_stage.as_deref(),
+                spec.memory_size,
+            )
+            .await?;
+        check_update(name, settle(client, name, polling).await?)?;
+    }
+    Ok(if diff.is_empty() {
+        FunctionAction::Unchanged
+    } else {
+        FunctionAction::Updated(diff)
+    })
+}
+
+/// Waits until the function is visible and its update isn't in progress.
+async fn settle(client: &dyn CloudClient, name: &str, polling: Polling) -> Result<FunctionState> {
+    for attempt in 0..polling.attempts {
+        if attempt > 0 {
+            tokio::time::sleep(polling.interval).await;
+        }
+        match client.get_function(name).await? {
+            Some(state) if state.status != UpdateStatus::InProgress => return Ok(state),
+            _ => continue,
+        }
+    }
+    Err(FlockError::AWS(format!(
+        "The function {} didn't settle after {} lookups",
+        name, polling.attempts
+    )))
+}
+
+/// Returns an error if the update of the function failed.
+fn check_update(name: &str, state: FunctionState) -> Result<()> {
+    match state.status {
+        UpdateStatus::Failed(reason) => Err(FlockError::AWS(format!(
+            "The update of the function {} failed: {}",
+            name, reason
+        ))),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aws::client::FakeCloudClient;
+
+    const POLLING: Polling = Polling {
+        interval:    Duration::from_millis(0),
+        attempts:    3,
+        concurrency: 4,
+    };
+
+    fn spec(name: &str, memory_size: i64) -> FunctionSpec {
+        let ctx = ExecutionContext {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        FunctionSpec::new(ctx, memory_size, "x86_64")
+    }
+
+    #[tokio::test]
+    async fn reconcile_all_outcomes() -> Result<()> {
+        let client = FakeCloudClient::new();
+        // The former run created q1-00 and q1-01, and died before q1-02.
+        for spec in [spec("q1-00", 128), spec("q1-01", 128)] {
+            client
+                .create_function(&spec.ctx, None, spec.memory_size, &spec.architecture)
+                .await?;
+        }
+        client.fail_next("q1-03", 1);
+
+        let specs = vec![
+            spec("q1-00", 128),
+            spec("q1-01", 256),
+            spec("q1-02", 128),
+            spec("q1-03", 128),
+        ];
+        let report = reconcile_functions(&client, &specs, POLLING).await?;
+        assert_eq!(report.actions[0].1, FunctionAction::Unchanged);
+        assert_eq!(
+            report.actions[1].1,
+            FunctionAction::Updated(FunctionDiff {
+                memory_size: true,
+                ..Default::default()
+            })
+        );
+        assert_eq!(report.actions[2].1, FunctionAction::Created);
+        assert!(matches!(report.actions[3].1, FunctionAction::Failed(_)));
+        assert_eq!(report.failed(), vec!["q1-03"]);
+        assert!(report.check().is_err());
+        assert_eq!(client.function("q1-01").unwrap().memory_size, 256);
+
+        // The rerun picks up the failed function.
+        let report = reconcile_functions(&client, &specs, POLLING).await?;
+        assert_eq!(report.actions[3].1, FunctionAction::Created);
+        assert!(report.check().is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reconcile_within_concurrency() -> Result<()> {
+        // Each function takes at least one call of the latency, and only two
+        // functions are reconciled at once.
+        let latency = Duration::from_millis(20);
+        let client = FakeCloudClient::new().with_latency(latency);
+        let specs = (0..8)
+            .map(|i| spec(&format!("q1-{:02}", i), 128))
+            .collect::<Vec<_>>();
+        let now = std::time::Instant::now();
+        let report = reconcile_functions(
+            &client,
+            &specs,
+            Polling {
+                concurrency: 2,
+                ..POLLING
+            },
+        )
+        .await?;
+        assert!(now.elapsed() >= latency * 4);
+        assert!(report.check().is_ok());
+        assert_eq!(
+            report.functions(),
+            specs
+                .iter()
+                .map(|s| s.name().to_string())
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_code_and_environment() -> Result<()> {
+        let client = FakeCloudClient::new();
+        let old = spec("q1-00", 128);
+        client
+            .create_function(&old.ctx, None, 128, "x86_64")
+            .await?;
+
+        // The new binary and the new context are deployed, one update after
+        // the other is settled.
+        client.set_code_sha256("x86_64", "new-code");
+        client.delay_updates("q1-00", 2);
+        let mut new = spec("q1-00", 128);
+        new.ctx.window_columns = true;
+        let action = reconcile_function(&client, &new, "new-code", POLLING).await;
+        assert_eq!(
+            action,
+            FunctionAction::Updated(FunctionDiff {
+                code: true,
+                environment: true,
+                ..Default::default()
+            })
+        );
+        let state = client.function("q1-00").unwrap();
+        assert_eq!(state.code_sha256, "new-code");
+        assert_eq!(state.context, Some(new.ctx.clone()));
+
+        // The update never settles.
+        client.delay_updates("q1-00", 10);
+        let action = reconcile_function(&client, &new, "newer-code", POLLING).await;
+        assert!(matches!(action, FunctionAction::Failed(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_on_conflict() -> Result<()> {
+        let client = FakeCloudClient::new();
+        let old = spec("q1-00", 128);
+        client
+            .create_function(&old.ctx, None, 128, "x86_64")
+            .await?;
+
+        // The lookups miss the function created by the former run, so its
+        // creation conflicts, and the function is updated once it's visible.
+        client.hide_function("q1-00", 2);
+        let action = reconcile_function(&client, &spec("q1-00", 512), "x86_64-code", POLLING).await;
+        assert_eq!(
+            action,
+            FunctionAction::Updated(FunctionDiff {
+                memory_size: true,
+                ..Default::default()
+            })
+        );
+        assert_eq!(client.function("q1-00").unwrap().memory_size, 512);
+        assert_eq!(client.resources().len(), 1);
+        Ok(())
+    }
+}
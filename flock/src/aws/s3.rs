@@ -18,20 +18,23 @@ use crate::error::{FlockError, Result};
 use rayon::prelude::*;
 use rusoto_core::ByteStream;
 use rusoto_s3::{
-    CreateBucketRequest, Delete, DeleteBucketRequest, DeleteObjectsRequest, GetObjectRequest,
-    HeadBucketRequest, ListObjectsV2Request, ObjectIdentifier, PutObjectRequest, S3,
+    CreateBucketConfiguration, CreateBucketRequest, Delete, DeleteBucketRequest,
+    DeleteObjectsRequest, GetObjectRequest, HeadBucketRequest, HeadObjectRequest,
+    ListObjectsV2Output, ListObjectsV2Request, ObjectIdentifier, PutObjectRequest, S3,
 };
+use std::future::Future;
 use std::io::Read;
+use std::ops::Range;
 
 /// Puts an object to AWS S3 if the object does not exist. If the object exists,
-/// it isn't modified.
+/// it isn't modified. Returns true if the object is written.
 ///
 /// # Arguments
 /// * `bucket` - The name of the bucket to put the object in.
 /// * `key` - The key of the object to put.
 /// * `body` - The body of the object to put.
-pub async fn put_object_if_missing(bucket: &str, key: &str, body: Vec<u8>) -> Result<()> {
-    if let Some(0) = FLOCK_S3_CLIENT
+pub async fn put_object_if_missing(bucket: &str, key: &str, body: Vec<u8>) -> Result<bool> {
+    if let Some(0) = s3_client("")
         .list_objects_v2(ListObjectsV2Request {
             bucket: bucket.to_owned(),
             prefix: Some(key.to_owned()),
@@ -42,7 +45,7 @@ pub async fn put_object_if_missing(bucket: &str, key: &str, body: Vec<u8>) -> Re
         .map_err(|e| FlockError::Internal(e.to_string()))?
         .key_count
     {
-        FLOCK_S3_CLIENT
+        s3_client("")
             .put_object(PutObjectRequest {
                 bucket: bucket.to_owned(),
                 key: key.to_owned(),
@@ -51,8 +54,9 @@ pub async fn put_object_if_missing(bucket: &str, key: &str, body: Vec<u8>) -> Re
             })
             .await
             .map_err(|e| FlockError::AWS(e.to_string()))?;
+        return Ok(true);
     }
-    Ok(())
+    Ok(false)
 }
 
 /// Puts an object to AWS S3. If the object exists, it is overwritten.
@@ -62,7 +66,7 @@ pub async fn put_object_if_missing(bucket: &str, key: &str, body: Vec<u8>) -> Re
 /// * `key` - The key of the object to put.
 /// * `body` - The body of the object to put.
 pub async fn put_object(bucket: &str, key: &str, body: Vec<u8>) -> Result<()> {
-    FLOCK_S3_CLIENT
+    s3_client("")
         .put_object(PutObjectRequest {
             bucket: bucket.to_owned(),
             key: key.to_owned(),
@@ -95,7 +99,7 @@ pub async fn put_object_with_content_type(
     body: Vec<u8>,
     content_type: &str,
 ) -> Result<()> {
-    FLOCK_S3_CLIENT
+    s3_client("")
         .put_object(PutObjectRequest {
             bucket: bucket.to_owned(),
             key: key.to_owned(),
@@ -118,25 +122,93 @@ pub async fn put_object_with_content_type(
 /// # Returns
 /// The body of the object.
 pub async fn get_object(bucket: &str, key: &str) -> Result<Vec<u8>> {
-    let body = FLOCK_S3_CLIENT
+    let body = s3_client("")
+        .get_object(GetObjectRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?
+        .body
+        .take()
+        .ok_or_else(|| FlockError::AWS(format!("The body of s3://{}/{} is empty", bucket, key)))?;
+
+    tokio::task::spawn_blocking(move || {
+        let mut buf = Vec::new();
+        body.into_blocking_read()
+            .read_to_end(&mut buf)
+            .map_err(|e| FlockError::AWS(e.to_string()))?;
+        Ok(buf)
+    })
+    .await
+    .map_err(|e| FlockError::Internal(e.to_string()))?
+}
+
+/// Gets the size and the entity tag of an object in AWS S3.
+///
+/// # Arguments
+/// * `bucket` - The name of the bucket of the object.
+/// * `key` - The key of the object.
+pub async fn head_object(bucket: &str, key: &str) -> Result<(u64, String)> {
+    let output = s3_client("")
+        .head_object(HeadObjectRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    Ok((
+        output.content_length.unwrap_or_default() as u64,
+        output.e_tag.unwrap_or_default(),
+    ))
+}
+
+/// Checks whether the object exists in AWS S3 and you have permission to
+/// access it.
+///
+/// # Arguments
+/// * `bucket` - The name of the bucket of the object.
+/// * `key` - The key of the object.
+pub async fn object_exists(bucket: &str, key: &str) -> Result<bool> {
+    // Like the HEAD request of a bucket, the HEAD request of an object returns a
+    // 404 Not Found or 403 Forbidden code without a message body.
+    Ok(head_object(bucket, key).await.is_ok())
+}
+
+/// Gets a byte range of an object from AWS S3.
+///
+/// # Arguments
+/// * `bucket` - The name of the bucket to get the object from.
+/// * `key` - The key of the object to get.
+/// * `range` - The byte range to get, which is cut at the end of the object.
+pub async fn get_object_range(bucket: &str, key: &str, range: Range<u64>) -> Result<Vec<u8>> {
+    if range.is_empty() {
+        return Ok(vec![]);
+    }
+    let body = s3_client("")
         .get_object(GetObjectRequest {
             bucket: bucket.to_owned(),
             key: key.to_owned(),
+            range: Some(format!("bytes={}-{}", range.start, range.end - 1)),
             ..Default::default()
         })
         .await
-        .unwrap()
+        .map_err(|e| FlockError::AWS(e.to_string()))?
         .body
         .take()
-        .expect("body is empty");
+        .ok_or_else(|| FlockError::AWS(format!("The body of s3://{}/{} is empty", bucket, key)))?;
 
-    Ok(tokio::task::spawn_blocking(move || {
+    tokio::task::spawn_blocking(move || {
         let mut buf = Vec::new();
-        body.into_blocking_read().read_to_end(&mut buf).unwrap();
-        buf
+        body.into_blocking_read()
+            .read_to_end(&mut buf)
+            .map_err(|e| FlockError::AWS(e.to_string()))?;
+        Ok(buf)
     })
     .await
-    .expect("failed to load object from S3"))
+    .map_err(|e| FlockError::Internal(e.to_string()))?
 }
 
 /// Checks if a bucket exists in AWS S3.
@@ -146,7 +218,7 @@ pub async fn get_object(bucket: &str, key: &str) -> Result<Vec<u8>> {
 pub async fn bucket_exists(bucket: &str) -> Result<bool> {
     // Returns a list of all buckets owned by the authenticated sender of the
     // request.
-    let resp = FLOCK_S3_CLIENT
+    let resp = s3_client("")
         .list_buckets()
         .await
         .map_err(|e| FlockError::AWS(e.to_string()))?;
@@ -172,7 +244,7 @@ pub async fn bucket_exists_and_accessible(bucket: &str) -> Result<bool> {
     // HEAD request returns a generic 404 Not Found or 403 Forbidden code. A message
     // body is not included, so you cannot determine the exception beyond these
     // error codes.
-    match FLOCK_S3_CLIENT
+    match s3_client("")
         .head_bucket(HeadBucketRequest {
             bucket: bucket.to_owned(),
             ..Default::default()
@@ -185,12 +257,26 @@ pub async fn bucket_exists_and_accessible(bucket: &str) -> Result<bool> {
     }
 }
 
+/// Returns the bucket configuration for the region set by `set_flock_region`.
+///
+/// Buckets outside of US East (N. Virginia) must specify the location
+/// constraint explicitly.
+fn bucket_configuration() -> Option<CreateBucketConfiguration> {
+    match flock_region().as_str() {
+        "" | "us-east-1" => None,
+        region => Some(CreateBucketConfiguration {
+            location_constraint: Some(region.to_owned()),
+        }),
+    }
+}
+
 /// Creates a new S3 bucket if it does not exist.
 pub async fn create_bucket_if_missing(bucket: &str) -> Result<()> {
     if !bucket_exists(bucket).await? {
-        FLOCK_S3_CLIENT
+        s3_client("")
             .create_bucket(CreateBucketRequest {
                 bucket: bucket.to_owned(),
+                create_bucket_configuration: bucket_configuration(),
                 ..Default::default()
             })
             .await
@@ -213,9 +299,10 @@ pub async fn create_bucket_if_missing(bucket: &str) -> Result<()> {
 /// requirements. For example, if you reside in Europe, you will probably find
 /// it advantageous to create buckets in the Europe (Ireland) Region.
 pub async fn create_bucket(bucket: &str) -> Result<()> {
-    FLOCK_S3_CLIENT
+    s3_client("")
         .create_bucket(CreateBucketRequest {
             bucket: bucket.to_owned(),
+            create_bucket_configuration: bucket_configuration(),
             ..Default::default()
         })
         .await
@@ -223,6 +310,42 @@ pub async fn create_bucket(bucket: &str) -> Result<()> {
         .map(|_| ())
 }
 
+/// Returns the keys of all pages of the `ListObjectsV2` API. Each response
+/// has at most 1000 keys, so the continuation tokens are followed until the
+/// listing is no longer truncated.
+///
+/// # Arguments
+/// * `list_page` - Lists one page of the keys from the given continuation
+///   token.
+///
+/// # Returns
+/// A list of keys of all pages.
+pub async fn list_all_keys<F, Fut>(mut list_page: F) -> Result<Vec<String>>
+where
+    F: FnMut(Option<String>) -> Fut,
+    Fut: Future<Output = Result<ListObjectsV2Output>>,
+{
+    let mut keys = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let resp = list_page(continuation_token).await?;
+        keys.extend(
+            resp.contents
+                .into_iter()
+                .flatten()
+                .filter_map(|obj| obj.key),
+        );
+
+        match resp.next_continuation_token {
+            Some(token) if resp.is_truncated.unwrap_or_default() => {
+                continuation_token = Some(token);
+            }
+            _ => break,
+        }
+    }
+    Ok(keys)
+}
+
 /// Returns all S3 keys in a bucket.
 ///
 /// # Arguments
@@ -231,31 +354,17 @@ pub async fn create_bucket(bucket: &str) -> Result<()> {
 /// # Returns
 /// A list of keys in the bucket.
 pub async fn get_all_keys(bucket: &str) -> Result<Vec<String>> {
-    let mut keys = Vec::new();
-    let mut continuation_token = None;
-    loop {
-        let resp = FLOCK_S3_CLIENT
+    list_all_keys(|continuation_token| async move {
+        s3_client("")
             .list_objects_v2(ListObjectsV2Request {
                 bucket: bucket.to_owned(),
                 continuation_token,
                 ..Default::default()
             })
             .await
-            .map_err(|e| FlockError::AWS(e.to_string()))?;
-
-        resp.contents.iter().flatten().for_each(|obj| {
-            if obj.key.is_some() {
-                keys.push(obj.key.as_ref().unwrap().to_owned());
-            }
-        });
-
-        if resp.is_truncated.unwrap_or_default() {
-            continuation_token = Some(resp.next_continuation_token.unwrap_or_default());
-        } else {
-            break;
-        }
-    }
-    Ok(keys)
+            .map_err(|e| FlockError::AWS(e.to_string()))
+    })
+    .await
 }
 
 /// Returns S3 keys in a bucket that match the prefix.
@@ -268,10 +377,8 @@ pub async fn get_all_keys(bucket: &str) -> Result<Vec<String>> {
 /// # Returns
 /// A list of keys in the bucket.
 pub async fn get_matched_keys(bucket: &str, prefix: &str) -> Result<Vec<String>> {
-    let mut keys = Vec::new();
-    let mut continuation_token = None;
-    loop {
-        let resp = FLOCK_S3_CLIENT
+    list_all_keys(|continuation_token| async move {
+        s3_client("")
             .list_objects_v2(ListObjectsV2Request {
                 bucket: bucket.to_owned(),
                 prefix: Some(prefix.to_owned()),
@@ -279,32 +386,55 @@ pub async fn get_matched_keys(bucket: &str, prefix: &str) -> Result<Vec<String>>
                 ..Default::default()
             })
             .await
-            .map_err(|e| FlockError::AWS(e.to_string()))?;
-
-        resp.contents.iter().flatten().for_each(|obj| {
-            if obj.key.is_some() {
-                keys.push(obj.key.as_ref().unwrap().to_owned());
-            }
-        });
+            .map_err(|e| FlockError::AWS(e.to_string()))
+    })
+    .await
+}
 
-        if resp.is_truncated.unwrap_or_default() {
-            continuation_token = Some(resp.next_continuation_token.unwrap_or_default());
-        } else {
-            break;
-        }
-    }
-    Ok(keys)
+/// Returns one page of the S3 keys in a bucket that match the prefix.
+///
+/// # Arguments
+/// * `bucket` - The name of the bucket to get the keys from.
+/// * `prefix` - Limits the response to keys that begin with the specified
+///   prefix.
+/// * `start_after` - Limits the response to keys that come after the key in the
+///   lexicographical order.
+///
+/// # Returns
+/// At most 1000 keys, and true if there are more keys to list.
+pub async fn get_matched_keys_page(
+    bucket: &str,
+    prefix: &str,
+    start_after: Option<String>,
+) -> Result<(Vec<String>, bool)> {
+    let resp = s3_client("")
+        .list_objects_v2(ListObjectsV2Request {
+            bucket: bucket.to_owned(),
+            prefix: Some(prefix.to_owned()),
+            start_after,
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    Ok((
+        resp.contents
+            .into_iter()
+            .flatten()
+            .filter_map(|obj| obj.key)
+            .collect(),
+        resp.is_truncated.unwrap_or_default(),
+    ))
 }
 
-/// Deletes all objects in a bucket.
-pub async fn delete_all_objects(bucket: &str) -> Result<()> {
-    if bucket_exists(bucket).await? {
-        FLOCK_S3_CLIENT
+/// Deletes the objects of the keys in a bucket. Each request can contain a
+/// list of up to 1000 keys.
+pub async fn delete_objects(bucket: &str, keys: &[String]) -> Result<()> {
+    for chunk in keys.chunks(1000) {
+        s3_client("")
             .delete_objects(DeleteObjectsRequest {
                 bucket: bucket.to_owned(),
                 delete: Delete {
-                    objects: get_all_keys(bucket)
-                        .await?
+                    objects: chunk
                         .iter()
                         .map(|key| ObjectIdentifier {
                             key:        key.to_owned(),
@@ -321,6 +451,23 @@ pub async fn delete_all_objects(bucket: &str) -> Result<()> {
     Ok(())
 }
 
+/// Deletes all objects in a bucket.
+pub async fn delete_all_objects(bucket: &str) -> Result<()> {
+    if bucket_exists(bucket).await? {
+        delete_objects(bucket, &get_all_keys(bucket).await?).await?;
+    }
+    Ok(())
+}
+
+/// Deletes all objects in a bucket that match the prefix.
+///
+/// # Arguments
+/// * `bucket` - The name of the bucket to delete the objects from.
+/// * `prefix` - The prefix of the keys to delete.
+pub async fn delete_matched_objects(bucket: &str, prefix: &str) -> Result<()> {
+    delete_objects(bucket, &get_matched_keys(bucket, prefix).await?).await
+}
+
 /// Deletes an S3 bucket.
 ///
 /// All objects (including all object versions and delete markers) in the bucket
@@ -328,7 +475,7 @@ pub async fn delete_all_objects(bucket: &str) -> Result<()> {
 pub async fn delete_bucket(bucket: &str) -> Result<()> {
     if bucket_exists(bucket).await? {
         delete_all_objects(bucket).await?;
-        FLOCK_S3_CLIENT
+        s3_client("")
             .delete_bucket(DeleteBucketRequest {
                 bucket: bucket.to_owned(),
                 ..Default::default()
@@ -341,7 +488,7 @@ pub async fn delete_bucket(bucket: &str) -> Result<()> {
 
 /// Lists all buckets owned by the authenticated user.
 pub async fn list_buckets() -> Result<Vec<String>> {
-    Ok(FLOCK_S3_CLIENT
+    Ok(s3_client("")
         .list_buckets()
         .await
         .map_err(|e| FlockError::AWS(e.to_string()))?
@@ -354,7 +501,7 @@ pub async fn list_buckets() -> Result<Vec<String>> {
 
 /// Returns the S3 buckets with the specified bucket parttern.
 pub async fn get_matched_buckets(bucket_parttern: &str) -> Result<Vec<String>> {
-    Ok(FLOCK_S3_CLIENT
+    Ok(s3_client("")
         .list_buckets()
         .await
         .map_err(|e| FlockError::AWS(e.to_string()))?
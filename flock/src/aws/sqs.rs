@@ -12,3 +12,160 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 //! This crate contains all wrapped functions of the AWS SQS service.
+//!
+//! The queues carry the payloads between the query stages of the queue
+//! transport (see [`crate::runtime::transport`]).
+
+use crate::configs::*;
+use crate::error::{FlockError, Result};
+use lazy_static::lazy_static;
+use rusoto_lambda::CreateEventSourceMappingRequest;
+use rusoto_sqs::{
+    CreateQueueRequest, DeleteQueueRequest, GetQueueAttributesRequest, GetQueueUrlRequest,
+    ListQueuesRequest, SendMessageRequest, Sqs,
+};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    /// The URLs of the queues by their names, which don't change while the
+    /// queues exist.
+    static ref QUEUE_URLS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// Creates the queue, or returns the existing queue of the same attributes.
+///
+/// # Arguments
+/// * `queue_name` - The name of the queue.
+/// * `visibility_timeout` - The seconds that a received message is hidden from
+///   the other consumers, which must cover the timeout of the function.
+///
+/// # Returns
+/// The URL of the queue.
+pub async fn create_queue(queue_name: &str, visibility_timeout: i64) -> Result<String> {
+    let mut attributes = HashMap::new();
+    attributes.insert(
+        "VisibilityTimeout".to_string(),
+        visibility_timeout.to_string(),
+    );
+    let url = sqs_client("")
+        .create_queue(CreateQueueRequest {
+            queue_name: queue_name.to_owned(),
+            attributes: Some(attributes),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?
+        .queue_url
+        .ok_or_else(|| FlockError::AWS(format!("No URL of the queue {}!", queue_name)))?;
+    QUEUE_URLS
+        .lock()
+        .unwrap()
+        .insert(queue_name.to_owned(), url.clone());
+    Ok(url)
+}
+
+/// Returns the URL of the queue.
+pub async fn queue_url(queue_name: &str) -> Result<String> {
+    if let Some(url) = QUEUE_URLS.lock().unwrap().get(queue_name) {
+        return Ok(url.clone());
+    }
+    let url = sqs_client("")
+        .get_queue_url(GetQueueUrlRequest {
+            queue_name: queue_name.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?
+        .queue_url
+        .ok_or_else(|| FlockError::AWS(format!("No URL of the queue {}!", queue_name)))?;
+    QUEUE_URLS
+        .lock()
+        .unwrap()
+        .insert(queue_name.to_owned(), url.clone());
+    Ok(url)
+}
+
+/// Returns the ARN of the queue.
+pub async fn queue_arn(queue_name: &str) -> Result<String> {
+    sqs_client("")
+        .get_queue_attributes(GetQueueAttributesRequest {
+            queue_url:       queue_url(queue_name).await?,
+            attribute_names: Some(vec!["QueueArn".to_string()]),
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?
+        .attributes
+        .and_then(|mut attributes| attributes.remove("QueueArn"))
+        .ok_or_else(|| FlockError::AWS(format!("No ARN of the queue {}!", queue_name)))
+}
+
+/// Sends the message to the queue.
+pub async fn send_message(queue_name: &str, body: String) -> Result<()> {
+    sqs_client("")
+        .send_message(SendMessageRequest {
+            queue_url: queue_url(queue_name).await?,
+            message_body: body,
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    Ok(())
+}
+
+/// Returns the names of the queues whose names start with the prefix.
+pub async fn list_queues(prefix: &str) -> Result<Vec<String>> {
+    let mut request = ListQueuesRequest {
+        queue_name_prefix: Some(prefix.to_owned()),
+        ..Default::default()
+    };
+    let mut queues = vec![];
+    loop {
+        let response = sqs_client("")
+            .list_queues(request.clone())
+            .await
+            .map_err(|e| FlockError::AWS(e.to_string()))?;
+        // The queue name is the last segment of its URL.
+        queues.extend(
+            response
+                .queue_urls
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|url| url.rsplit('/').next().map(|name| name.to_string())),
+        );
+        if response.next_token.is_none() {
+            break;
+        }
+        request.next_token = response.next_token;
+    }
+    Ok(queues)
+}
+
+/// Deletes the queue and its messages.
+pub async fn delete_queue(queue_name: &str) -> Result<()> {
+    sqs_client("")
+        .delete_queue(DeleteQueueRequest {
+            queue_url: queue_url(queue_name).await?,
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    QUEUE_URLS.lock().unwrap().remove(queue_name);
+    Ok(())
+}
+
+/// Creates the request of the event source mapping that invokes the function
+/// with the messages of the queue.
+pub async fn create_event_source_mapping_request(
+    queue_name: &str,
+    function_name: &str,
+) -> Result<CreateEventSourceMappingRequest> {
+    Ok(CreateEventSourceMappingRequest {
+        // The maximum number of messages to retrieve in a single batch.
+        // Amazon SQS - Default 10. Max 10 without a batching window.
+        batch_size: Some(*FLOCK_SQS_BATCH_SIZE),
+        enabled: Some(true),
+        event_source_arn: Some(queue_arn(queue_name).await?),
+        function_name: function_name.to_owned(),
+        ..CreateEventSourceMappingRequest::default()
+    })
+}
@@ -0,0 +1,189 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! This crate contains all wrapped functions of the AWS Step Functions
+//! service.
+
+use crate::configs::*;
+use crate::error::{FlockError, Result};
+use log::info;
+use rusoto_iam::{GetRoleRequest, Iam, IamClient};
+use rusoto_stepfunctions::{
+    CreateStateMachineInput, DeleteStateMachineInput, DescribeExecutionInput,
+    ListStateMachinesInput, StartExecutionInput, StepFunctions, StopExecutionInput,
+    UpdateStateMachineInput,
+};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+/// Returns the ARN of the IAM role that the state machines assume to invoke
+/// the cloud functions.
+async fn state_machine_role() -> Result<String> {
+    let iam: IamClient = new_client("");
+    let resp = iam
+        .get_role(GetRoleRequest {
+            role_name: FLOCK_SFN_ROLE.to_string(),
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    Ok(resp.role.arn)
+}
+
+/// Returns the ARN of the state machine with the given name, or `None` if it
+/// doesn't exist.
+pub async fn find_state_machine(name: &str) -> Result<Option<String>> {
+    let mut next_token = None;
+    loop {
+        let resp = sfn_client("")
+            .list_state_machines(ListStateMachinesInput {
+                next_token,
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| FlockError::AWS(e.to_string()))?;
+        if let Some(machine) = resp.state_machines.into_iter().find(|m| m.name == name) {
+            return Ok(Some(machine.state_machine_arn));
+        }
+        next_token = resp.next_token;
+        if next_token.is_none() {
+            return Ok(None);
+        }
+    }
+}
+
+/// Creates the state machine, or updates its definition if a state machine
+/// with the same name already exists.
+///
+/// # Arguments
+/// * `name` - The name of the state machine.
+/// * `definition` - The Amazon States Language definition of the state machine.
+///
+/// # Returns
+/// The ARN of the state machine.
+pub async fn create_or_update_state_machine(name: &str, definition: &str) -> Result<String> {
+    let role_arn = state_machine_role().await?;
+    match find_state_machine(name).await? {
+        Some(arn) => {
+            info!("Updating state machine: {}", name);
+            sfn_client("")
+                .update_state_machine(UpdateStateMachineInput {
+                    state_machine_arn: arn.clone(),
+                    definition: Some(definition.to_owned()),
+                    role_arn: Some(role_arn),
+                    ..Default::default()
+                })
+                .await
+                .map_err(|e| FlockError::AWS(e.to_string()))?;
+            Ok(arn)
+        }
+        None => {
+            info!("Creating state machine: {}", name);
+            Ok(sfn_client("")
+                .create_state_machine(CreateStateMachineInput {
+                    name: name.to_owned(),
+                    definition: definition.to_owned(),
+                    role_arn,
+                    ..Default::default()
+                })
+                .await
+                .map_err(|e| FlockError::AWS(e.to_string()))?
+                .state_machine_arn)
+        }
+    }
+}
+
+/// Deletes the state machine.
+pub async fn delete_state_machine(arn: &str) -> Result<()> {
+    sfn_client("")
+        .delete_state_machine(DeleteStateMachineInput {
+            state_machine_arn: arn.to_owned(),
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    Ok(())
+}
+
+/// Starts an execution of the state machine.
+///
+/// # Arguments
+/// * `arn` - The ARN of the state machine.
+/// * `name` - The name of the execution, which must be unique for the state
+///   machine.
+/// * `input` - The JSON input of the execution.
+///
+/// # Returns
+/// The ARN of the execution.
+pub async fn start_execution(arn: &str, name: &str, input: &Value) -> Result<String> {
+    Ok(sfn_client("")
+        .start_execution(StartExecutionInput {
+            state_machine_arn: arn.to_owned(),
+            name: Some(name.to_owned()),
+            input: Some(serde_json::to_string(input)?),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?
+        .execution_arn)
+}
+
+/// Stops the execution of the state machine.
+pub async fn stop_execution(execution_arn: &str, cause: &str) -> Result<()> {
+    sfn_client("")
+        .stop_execution(StopExecutionInput {
+            execution_arn: execution_arn.to_owned(),
+            cause: Some(cause.to_owned()),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| FlockError::AWS(e.to_string()))?;
+    Ok(())
+}
+
+/// Polls the execution until it completes, and returns its JSON output. The
+/// execution is stopped if it doesn't complete within the timeout.
+pub async fn wait_for_execution(execution_arn: &str, timeout: Duration) -> Result<Value> {
+    let start = Instant::now();
+    let interval = Duration::from_millis(*FLOCK_SFN_POLL_INTERVAL);
+    loop {
+        let resp = sfn_client("")
+            .describe_execution(DescribeExecutionInput {
+                execution_arn: execution_arn.to_owned(),
+            })
+            .await
+            .map_err(|e| FlockError::AWS(e.to_string()))?;
+        match resp.status.as_str() {
+            "RUNNING" => {}
+            "SUCCEEDED" => {
+                return match resp.output {
+                    Some(output) => Ok(serde_json::from_str(&output)?),
+                    None => Ok(Value::Null),
+                };
+            }
+            status => {
+                return Err(FlockError::AWS(format!(
+                    "Execution {} {}",
+                    execution_arn,
+                    status.to_lowercase()
+                )));
+            }
+        }
+        if start.elapsed() > timeout {
+            stop_execution(execution_arn, "Timed out on the client side").await?;
+            return Err(FlockError::Execution(format!(
+                "Execution {} didn't complete in {:?}",
+                execution_arn, timeout
+            )));
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
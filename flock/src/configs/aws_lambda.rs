@@ -13,11 +13,13 @@
 
 //! Helper functions to create a Lambda function.
 
-use crate::configs::FLOCK_CONF;
+use crate::aws::chaos::FLOCK_CHAOS;
+use crate::configs::{new_client, FLOCK_CONF};
 use crate::encoding::Encoding;
 use crate::error::{FlockError, Result};
 use crate::runtime::context::{self, ExecutionContext};
-use rusoto_core::Region;
+use crate::runtime::embedded::FLOCK_EMBEDDED_STAGE;
+use crate::runtime::logging::FLOCK_LOG_ENV;
 use rusoto_iam::{GetRoleRequest, Iam, IamClient};
 use rusoto_lambda::{Environment, FunctionCode};
 use std::collections::hash_map::HashMap;
@@ -279,8 +281,17 @@ impl AwsLambdaConfig {
             (&FLOCK_CONF["lambda"]["environment"]).to_owned(),
             context::marshal(ctx, Encoding::default()).unwrap(),
         );
-        map.insert("RUST_LOG".to_owned(), "info".to_owned());
+        // The functions take the log levels of the driver, if any.
+        map.insert(
+            FLOCK_LOG_ENV.to_owned(),
+            std::env::var(FLOCK_LOG_ENV).unwrap_or_else(|_| "info".to_owned()),
+        );
         map.insert("RUST_BACKTRACE".to_owned(), "full".to_owned());
+        // The test deployments inject the faults of the chaos layer only if the
+        // driver turns it on (see `crate::aws::chaos`).
+        if let Ok(chaos) = std::env::var(FLOCK_CHAOS) {
+            map.insert(FLOCK_CHAOS.to_owned(), chaos);
+        }
 
         self.environment = Some(Environment {
             variables: Some(map),
@@ -291,6 +302,20 @@ impl AwsLambdaConfig {
         self
     }
 
+    /// Creates a new AWS Lambda function that runs the plans of the stage
+    /// embedded in the function binary (see [`crate::runtime::embedded`]). It
+    /// must be set after the function spec.
+    pub fn set_embedded_stage(&mut self, stage: &str) -> &mut Self {
+        if let Some(variables) = self
+            .environment
+            .as_mut()
+            .and_then(|env| env.variables.as_mut())
+        {
+            variables.insert(FLOCK_EMBEDDED_STAGE.to_owned(), stage.to_owned());
+        }
+        self
+    }
+
     /// Creates a new AWS Lambda function with the specified system
     /// architecture.
     pub fn set_architectures(&mut self, architectures: Vec<String>) -> &mut Self {
@@ -300,7 +325,7 @@ impl AwsLambdaConfig {
 
     /// Creates a new AWS Lambda function with a default role.
     async fn default_role() -> Result<String> {
-        let iam = IamClient::new(Region::default());
+        let iam: IamClient = new_client("");
         let resp = iam
             .get_role(GetRoleRequest {
                 role_name: FLOCK_CONF["aws"]["role"].to_string(),
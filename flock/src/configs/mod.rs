@@ -18,11 +18,17 @@ pub use aws_lambda::AwsLambdaConfig;
 
 mod flock;
 pub use self::flock::FLOCK_CONF;
+
+pub mod region;
 use datafusion::arrow::datatypes::Schema;
 use datafusion::physical_plan::empty::EmptyExec;
 use datafusion::physical_plan::ExecutionPlan;
 use lazy_static::lazy_static;
-use rusoto_core::Region;
+pub use region::{
+    aws_endpoint, efs_client, events_client, flock_region, kms_client, lambda_client,
+    metrics_client, new_client, parse_region, s3_client, set_flock_region, sfn_client, sqs_client,
+    state_bucket_name, watchlogs_client,
+};
 use rusoto_efs::EfsClient;
 use rusoto_lambda::LambdaClient;
 use rusoto_logs::CloudWatchLogsClient;
@@ -41,8 +47,91 @@ lazy_static! {
     pub static ref FLOCK_LAMBDA_MAX_BACKOFF: u64 = FLOCK_CONF["lambda"]["max_backoff"].parse::<u64>().unwrap();
     /// AWS Lambda function timeout.
     pub static ref FLOCK_LAMBDA_TIMEOUT: i64 = FLOCK_CONF["lambda"]["timeout"].parse::<i64>().unwrap();
+    /// The target number of rows of the coalesced record batches.
+    pub static ref FLOCK_TARGET_BATCH_SIZE: usize = FLOCK_CONF["lambda"]["target_batch_size"].parse::<usize>().unwrap();
     /// AWS Lambda function concurrency.
     pub static ref FLOCK_FUNCTION_CONCURRENCY: usize = FLOCK_CONF["lambda"]["concurrency"].parse::<usize>().unwrap();
+    /// The maximum number of processed windows remembered by a function instance.
+    pub static ref FLOCK_PROCESSED_WINDOWS_CAPACITY: usize = FLOCK_CONF["lambda"]["processed_windows_capacity"].parse::<usize>().unwrap();
+    /// The maximum number of execution contexts of the multiplexed queries cached by a function instance.
+    pub static ref FLOCK_QUERY_CONTEXTS_CAPACITY: usize = FLOCK_CONF["lambda"]["query_contexts_capacity"].parse::<usize>().unwrap();
+    /// Whether the query metadata in the payload is validated strictly.
+    pub static ref FLOCK_STRICT_METADATA: bool = FLOCK_CONF["lambda"]["strict_metadata"].parse::<bool>().unwrap();
+    /// True if the payloads mismatching the topology of the stage are accepted with a warning.
+    pub static ref FLOCK_TOPOLOGY_WARN_ONLY: bool = FLOCK_CONF["lambda"]["topology_warn_only"].parse::<bool>().unwrap();
+
+    /// Whether the payloads carry the statistics of their record batches.
+    pub static ref FLOCK_PAYLOAD_STATS: bool = FLOCK_CONF["lambda"]["payload_stats"].parse::<bool>().unwrap();
+    /// The maximum number of rows sampled from each record batch for the payload statistics.
+    pub static ref FLOCK_STATS_SAMPLE_ROWS: usize = FLOCK_CONF["lambda"]["stats_sample_rows"].parse::<usize>().unwrap();
+    /// The estimated number of groups above which the input is better processed by the distributed execution.
+    pub static ref FLOCK_GROUP_THRESHOLD: usize = FLOCK_CONF["lambda"]["group_threshold"].parse::<usize>().unwrap();
+    /// The multiple of the mean partition size above which a partition of the hash shuffle is salted.
+    pub static ref FLOCK_SKEW_THRESHOLD: f64 = FLOCK_CONF["lambda"]["skew_threshold"].parse::<f64>().unwrap();
+    /// The number of salted sub-partitions of a hot key.
+    pub static ref FLOCK_SKEW_SALTS: usize = FLOCK_CONF["lambda"]["skew_salts"].parse::<usize>().unwrap();
+    /// The number of buckets of the session keys routed to the session windows.
+    pub static ref FLOCK_SESSION_BUCKETS: usize = FLOCK_CONF["lambda"]["session_buckets"].parse::<usize>().unwrap();
+    /// The number of in-flight windows of a query at which the data source functions stop emitting windows.
+    pub static ref FLOCK_INFLIGHT_HIGH_WATERMARK: usize = FLOCK_CONF["lambda"]["inflight_high_watermark"].parse::<usize>().unwrap();
+    /// The number of in-flight windows of a query below which the throttled data source functions resume.
+    pub static ref FLOCK_INFLIGHT_LOW_WATERMARK: usize = FLOCK_CONF["lambda"]["inflight_low_watermark"].parse::<usize>().unwrap();
+    /// The interval in milliseconds to poll the in-flight windows while the data source is throttled.
+    pub static ref FLOCK_BACKPRESSURE_POLL_INTERVAL: u64 = FLOCK_CONF["lambda"]["backpressure_poll_interval"].parse::<u64>().unwrap();
+    /// The number of windows that a data source function emits before it continues in a new invocation, or 0 if unbounded.
+    pub static ref FLOCK_SOURCE_WINDOWS_PER_INVOCATION: usize = FLOCK_CONF["lambda"]["source_windows_per_invocation"].parse::<usize>().unwrap();
+    /// The maximum number of invocations of a data source function that continues itself.
+    pub static ref FLOCK_SOURCE_MAX_INVOCATIONS: usize = FLOCK_CONF["lambda"]["source_max_invocations"].parse::<usize>().unwrap();
+    /// The number of seconds that the windows are written to both function sets during a query update.
+    pub static ref FLOCK_SWITCHOVER_OVERLAP: u64 = FLOCK_CONF["lambda"]["switchover_overlap"].parse::<u64>().unwrap();
+    /// The number of seconds to wait for the in-flight windows of the replaced function set to drain.
+    pub static ref FLOCK_SWITCHOVER_DRAIN_TIMEOUT: u64 = FLOCK_CONF["lambda"]["switchover_drain_timeout"].parse::<u64>().unwrap();
+    /// The number of seconds from the start by which the query must finish, or 0 if unbounded.
+    pub static ref FLOCK_QUERY_TIMEOUT: u64 = FLOCK_CONF["lambda"]["query_timeout"].parse::<u64>().unwrap();
+    /// The deadline of a window in multiples of the window length, or 0 if unbounded.
+    pub static ref FLOCK_DEADLINE_WINDOW_FACTOR: f64 = FLOCK_CONF["lambda"]["deadline_window_factor"].parse::<f64>().unwrap();
+    /// The milliseconds reserved to fail gracefully before the deadline.
+    pub static ref FLOCK_DEADLINE_MARGIN: u64 = FLOCK_CONF["lambda"]["deadline_margin"].parse::<u64>().unwrap();
+    /// The number of windows observed before the size of a function group is decided, or 0 if disabled.
+    pub static ref FLOCK_SCALING_WINDOWS: usize = FLOCK_CONF["lambda"]["scaling_windows"].parse::<usize>().unwrap();
+    /// The window volume in bytes that each member of a function group is sized for.
+    pub static ref FLOCK_SCALING_BYTES_PER_MEMBER: usize = FLOCK_CONF["lambda"]["scaling_bytes_per_member"].parse::<usize>().unwrap();
+    /// The maximum size of a function group resized at runtime.
+    pub static ref FLOCK_SCALING_MAX_GROUP_SIZE: usize = FLOCK_CONF["lambda"]["scaling_max_group_size"].parse::<usize>().unwrap();
+    /// The seconds between two checks of the peek marker of a stage, or 0 if the sampling is disabled.
+    pub static ref FLOCK_PEEK_INTERVAL: u64 = FLOCK_CONF["lambda"]["peek_interval"].parse::<u64>().unwrap();
+    /// The maximum size in bytes of the sampled output of a stage.
+    pub static ref FLOCK_PEEK_MAX_BYTES: usize = FLOCK_CONF["lambda"]["peek_max_bytes"].parse::<usize>().unwrap();
+    /// The size in bytes of the ranged GETs of the side input.
+    pub static ref FLOCK_SIDE_INPUT_CHUNK_SIZE: u64 = FLOCK_CONF["lambda"]["side_input_chunk_size"].parse::<u64>().unwrap();
+    /// The maximum number of the filtered side inputs cached by a function.
+    pub static ref FLOCK_SIDE_INPUT_CACHE_SIZE: usize = FLOCK_CONF["lambda"]["side_input_cache_size"].parse::<usize>().unwrap();
+    /// The size in bytes of the second relation of a window below which it's broadcast through S3, or 0 if only the stages with the hint broadcast it.
+    pub static ref FLOCK_BROADCAST_THRESHOLD: usize = FLOCK_CONF["lambda"]["broadcast_threshold"].parse::<usize>().unwrap();
+    /// The maximum number of the broadcast build sides cached by a function.
+    pub static ref FLOCK_BROADCAST_CACHE_SIZE: usize = FLOCK_CONF["lambda"]["broadcast_cache_size"].parse::<usize>().unwrap();
+    /// The fraction of the function memory held by the arena before the windows are spilled, or 0 if the spilling is disabled.
+    pub static ref FLOCK_ARENA_SPILL_FRACTION: f64 = FLOCK_CONF["lambda"]["arena_spill_fraction"].parse::<f64>().unwrap();
+    /// True if the group functions of the sort stages sort the large windows externally.
+    pub static ref FLOCK_EXTERNAL_SORT: bool = FLOCK_CONF["lambda"]["external_sort"].parse::<bool>().unwrap();
+    /// The estimated size in bytes of a window above which it's sorted externally, and of the sorted runs kept in memory.
+    pub static ref FLOCK_EXTERNAL_SORT_BUDGET: usize = FLOCK_CONF["lambda"]["external_sort_budget"].parse::<usize>().unwrap();
+    /// The local directory of the sorted runs spilled by the external sort.
+    pub static ref FLOCK_EXTERNAL_SORT_DIR: String = FLOCK_CONF["lambda"]["external_sort_dir"].to_string();
+    /// The maximum size in bytes of the sorted runs spilled to the local disk by a function.
+    pub static ref FLOCK_EXTERNAL_SORT_DISK_LIMIT: usize = FLOCK_CONF["lambda"]["external_sort_disk_limit"].parse::<usize>().unwrap();
+    /// The lateness window in milliseconds of the deduplication of the Kinesis records.
+    pub static ref FLOCK_KINESIS_DEDUP_LATENESS: u64 = FLOCK_CONF["lambda"]["kinesis_dedup_lateness"].parse::<u64>().unwrap();
+    /// The size in bytes below which the payloads are sent uncompressed.
+    pub static ref FLOCK_ENCODING_THRESHOLD: usize = FLOCK_CONF["lambda"]["encoding_threshold"].parse::<usize>().unwrap();
+    /// The Zstd level of the payloads handed off to the aggregate stages.
+    pub static ref FLOCK_AGGREGATE_ZSTD_LEVEL: i32 = FLOCK_CONF["lambda"]["aggregate_zstd_level"].parse::<i32>().unwrap();
+    /// The maximum number of the payloads of a fan-out that are sent at once.
+    pub static ref FLOCK_FANOUT_CONCURRENCY: usize = FLOCK_CONF["lambda"]["fanout_concurrency"].parse::<usize>().unwrap();
+    /// The maximum number of the functions that the deployment reconciles at once.
+    pub static ref FLOCK_RECONCILE_CONCURRENCY: usize = FLOCK_CONF["lambda"]["reconcile_concurrency"].parse::<usize>().unwrap();
+    /// The memory sizes of the functions by the estimated data volume of their stages.
+    pub static ref FLOCK_MEMORY_TABLE: String = FLOCK_CONF["lambda"]["memory_table"].to_string();
 
     /// Flock sync invocation granularity.
     pub static ref FLOCK_SYNC_GRANULE_SIZE: usize = FLOCK_CONF["lambda"]["sync_granule"].parse::<usize>().unwrap();
@@ -55,12 +144,34 @@ lazy_static! {
     pub static ref FLOCK_S3_ARM_64_KEY: String = FLOCK_CONF["s3"]["arm_64_key"].to_string();
     /// Flock S3 bucket name.
     pub static ref FLOCK_S3_BUCKET: String = FLOCK_CONF["s3"]["bucket"].to_string();
+    /// The number of objects per second that the cleanup of the state buckets deletes at most.
+    pub static ref FLOCK_S3_CLEANUP_DELETES_PER_SECOND: usize = FLOCK_CONF["s3"]["cleanup_deletes_per_second"].parse::<usize>().unwrap();
+    /// The number of the data partitions that the straggler recovery reads at once.
+    pub static ref FLOCK_S3_RECOVERY_CONCURRENCY: usize = FLOCK_CONF["s3"]["recovery_concurrency"].parse::<usize>().unwrap();
+    /// The number of the data partitions that the straggler recovery reads at most per invocation.
+    pub static ref FLOCK_S3_RECOVERY_MAX_KEYS: usize = FLOCK_CONF["s3"]["recovery_max_keys"].parse::<usize>().unwrap();
+    /// The time in milliseconds that the straggler recovery spends at most per invocation.
+    pub static ref FLOCK_S3_RECOVERY_MAX_MILLIS: u64 = FLOCK_CONF["s3"]["recovery_max_millis"].parse::<u64>().unwrap();
     /// Flock availablity zone.
     pub static ref FLOCK_AVAILABILITY_ZONE: String = FLOCK_CONF["aws"]["availability_zone"].to_string();
     /// Flock subnet id.
     pub static ref FLOCK_SUBNET_ID: String = FLOCK_CONF["aws"]["subnet_id"].to_string();
     /// Flock security group id.
     pub static ref FLOCK_SECURITY_GROUP_ID: String = FLOCK_CONF["aws"]["security_group_id"].to_string();
+    /// The KMS key that encrypts the payloads and the state. Empty means no encryption.
+    pub static ref FLOCK_KMS_KEY_ID: String = FLOCK_CONF["aws"]["kms_key_id"].to_string();
+
+    /// The IAM role that the Step Functions state machines assume.
+    pub static ref FLOCK_SFN_ROLE: String = FLOCK_CONF["stepfunctions"]["role"].to_string();
+    /// The interval in milliseconds to poll the status of a Step Functions execution.
+    pub static ref FLOCK_SFN_POLL_INTERVAL: u64 = FLOCK_CONF["stepfunctions"]["poll_interval"].parse::<u64>().unwrap();
+
+    /// The id of the API Gateway WebSocket API that pushes the results to the client.
+    pub static ref FLOCK_WEBSOCKET_API_ID: String = FLOCK_CONF["websocket"]["api_id"].to_string();
+    /// The stage of the API Gateway WebSocket API.
+    pub static ref FLOCK_WEBSOCKET_STAGE: String = FLOCK_CONF["websocket"]["stage"].to_string();
+    /// The number of seconds without any results after which the client stops waiting.
+    pub static ref FLOCK_WEBSOCKET_IDLE_TIMEOUT: u64 = FLOCK_CONF["websocket"]["idle_timeout"].parse::<u64>().unwrap();
 
     /// Flock EFS creation token.
     pub static ref FLOCK_EFS_CREATION_TOKEN: String = FLOCK_CONF["efs"]["creation_token"].to_string();
@@ -75,17 +186,18 @@ lazy_static! {
     /// Flocl EFS local mount point.
     pub static ref FLOCK_EFS_MOUNT_PATH: String = FLOCK_CONF["efs"]["mount_path"].to_string();
 
-    /// Flock associated services.
+    /// Flock associated services in the default region. Use the region-aware
+    /// constructors in [`region`] to work on a specific region.
     /// Flock S3 Client.
-    pub static ref FLOCK_S3_CLIENT: S3Client = S3Client::new(Region::default());
+    pub static ref FLOCK_S3_CLIENT: S3Client = new_client("");
     /// Flock LAMBDA Client.
-    pub static ref FLOCK_LAMBDA_CLIENT: LambdaClient = LambdaClient::new(Region::default());
+    pub static ref FLOCK_LAMBDA_CLIENT: LambdaClient = new_client("");
     /// Flock EFS Client.
-    pub static ref FLOCK_EFS_CLIENT: EfsClient = EfsClient::new(Region::default());
+    pub static ref FLOCK_EFS_CLIENT: EfsClient = new_client("");
     /// Flock SQS Client.
-    pub static ref FLOCK_SQS_CLIENT: SqsClient = SqsClient::new(Region::default());
+    pub static ref FLOCK_SQS_CLIENT: SqsClient = new_client("");
     /// Flock CloudWatch Logs Client.
-    pub static ref FLOCK_WATCHLOGS_CLIENT: CloudWatchLogsClient = CloudWatchLogsClient::new(Region::default());
+    pub static ref FLOCK_WATCHLOGS_CLIENT: CloudWatchLogsClient = new_client("");
 
     /// Flock Empty query plan
     pub static ref FLOCK_EMPTY_PLAN: Arc<dyn ExecutionPlan> = Arc::new(EmptyExec::new(false, Arc::new(Schema::empty())));
@@ -94,4 +206,16 @@ lazy_static! {
 
     /// Flock target partitions.
     pub static ref FLOCK_TARGET_PARTITIONS: usize = FLOCK_CONF["datafusion"]["target_partitions"].parse::<usize>().unwrap();
+
+    /// The target size of the batches read from the sources in bytes.
+    pub static ref FLOCK_SOURCE_BATCH_BYTES: usize = FLOCK_CONF["source"]["batch_bytes"].parse::<usize>().unwrap();
+
+    /// The endpoint the spans of the window traces are exported to, `stdout`, or empty if the tracing is disabled.
+    pub static ref FLOCK_OTEL_ENDPOINT: String = FLOCK_CONF["otel"]["endpoint"].to_string();
+
+    /// The number of messages that the event source mapping of a queue delivers per invocation.
+    pub static ref FLOCK_SQS_BATCH_SIZE: i64 = FLOCK_CONF["sqs"]["batch_size"].parse::<i64>().unwrap();
+
+    /// The largest message of the queue transport in bytes.
+    pub static ref FLOCK_SQS_MAX_MESSAGE_SIZE: usize = FLOCK_CONF["sqs"]["max_message_size"].parse::<usize>().unwrap();
 }
@@ -0,0 +1,317 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Region-aware AWS service clients.
+//!
+//! By default, all clients are created with `Region::default()`, which reads
+//! the region from the environment. To run the same query in multiple regions,
+//! the region is set explicitly on the client side (e.g. `--region`) and
+//! carried to the cloud functions in the `ExecutionContext`. Clients are cached
+//! per region, so each region only pays the cost of construction once.
+//!
+//! If [`FLOCK_AWS_ENDPOINT_URL`] is set, e.g. `http://localhost:4566`, all
+//! clients send their requests to that endpoint instead, with dummy
+//! credentials, which runs the AWS code paths against LocalStack.
+
+use crate::error::{FlockError, Result};
+use lazy_static::lazy_static;
+use rusoto_cloudwatch::CloudWatchClient;
+use rusoto_core::credential::StaticProvider;
+use rusoto_core::{Client, HttpClient, Region};
+use rusoto_efs::EfsClient;
+use rusoto_events::CloudWatchEventsClient;
+use rusoto_iam::IamClient;
+#[cfg(feature = "kinesis")]
+use rusoto_kinesis::KinesisClient;
+use rusoto_kms::KmsClient;
+use rusoto_lambda::LambdaClient;
+use rusoto_logs::CloudWatchLogsClient;
+use rusoto_s3::S3Client;
+use rusoto_sqs::SqsClient;
+use rusoto_stepfunctions::StepFunctionsClient;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+
+/// The maximum length of a S3 bucket name.
+const S3_BUCKET_NAME_MAX_LEN: usize = 63;
+
+/// The environment variable of the endpoint that overrides the endpoints of
+/// all AWS services, e.g. `http://localhost:4566` for LocalStack.
+pub const FLOCK_AWS_ENDPOINT_URL: &str = "FLOCK_AWS_ENDPOINT_URL";
+
+/// The access key of the dummy credentials sent to the overridden endpoint.
+const DUMMY_ACCESS_KEY: &str = "test";
+
+lazy_static! {
+    /// The region that Flock is currently working on. An empty string means
+    /// the default region from the environment.
+    static ref FLOCK_REGION: RwLock<String> = RwLock::new(String::new());
+
+    static ref FLOCK_S3_CLIENTS: Mutex<HashMap<String, S3Client>> = Mutex::new(HashMap::new());
+    static ref FLOCK_LAMBDA_CLIENTS: Mutex<HashMap<String, LambdaClient>> = Mutex::new(HashMap::new());
+    static ref FLOCK_EFS_CLIENTS: Mutex<HashMap<String, EfsClient>> = Mutex::new(HashMap::new());
+    static ref FLOCK_SQS_CLIENTS: Mutex<HashMap<String, SqsClient>> = Mutex::new(HashMap::new());
+    static ref FLOCK_WATCHLOGS_CLIENTS: Mutex<HashMap<String, CloudWatchLogsClient>> = Mutex::new(HashMap::new());
+    static ref FLOCK_SFN_CLIENTS: Mutex<HashMap<String, StepFunctionsClient>> = Mutex::new(HashMap::new());
+    static ref FLOCK_KMS_CLIENTS: Mutex<HashMap<String, KmsClient>> = Mutex::new(HashMap::new());
+    static ref FLOCK_EVENTS_CLIENTS: Mutex<HashMap<String, CloudWatchEventsClient>> = Mutex::new(HashMap::new());
+    static ref FLOCK_METRICS_CLIENTS: Mutex<HashMap<String, CloudWatchClient>> = Mutex::new(HashMap::new());
+}
+
+/// Parses the region name. An empty name returns the default region.
+pub fn parse_region(name: &str) -> Result<Region> {
+    if name.is_empty() {
+        Ok(Region::default())
+    } else {
+        Region::from_str(name).map_err(|e| FlockError::AWS(format!("{}: {}", name, e)))
+    }
+}
+
+/// Sets the region that all region-aware clients use by default.
+pub fn set_flock_region(name: &str) -> Result<()> {
+    let region = parse_region(name)?;
+    *FLOCK_REGION.write().unwrap() = if name.is_empty() {
+        String::new()
+    } else {
+        region.name().to_string()
+    };
+    Ok(())
+}
+
+/// Returns the region name set by `set_flock_region`. An empty string means
+/// the default region from the environment.
+pub fn flock_region() -> String {
+    FLOCK_REGION.read().unwrap().clone()
+}
+
+/// Returns the endpoint that overrides the endpoints of all AWS services, if
+/// [`FLOCK_AWS_ENDPOINT_URL`] is set.
+pub fn aws_endpoint() -> Option<String> {
+    std::env::var(FLOCK_AWS_ENDPOINT_URL)
+        .ok()
+        .filter(|endpoint| !endpoint.is_empty())
+}
+
+/// Returns the region of the clients with the given region name. If the
+/// endpoint is overridden, the region keeps its name, so that the requests are
+/// still signed for it, but the requests are sent to the endpoint.
+pub fn client_region(name: &str) -> Region {
+    region_at(name, aws_endpoint())
+}
+
+/// Returns the region with the given name at the endpoint, if any.
+fn region_at(name: &str, endpoint: Option<String>) -> Region {
+    let region = parse_region(name).unwrap_or_else(|_| Region::default());
+    match endpoint {
+        Some(endpoint) => Region::Custom {
+            name: region.name().to_string(),
+            endpoint,
+        },
+        None => region,
+    }
+}
+
+/// An AWS service client that is created from a shared rusoto client.
+pub trait AwsServiceClient {
+    /// Creates the service client of the region with the rusoto client.
+    fn with_client(client: Client, region: Region) -> Self;
+}
+
+macro_rules! aws_service_client {
+    ($($client:ty),*) => {
+        $(
+            impl AwsServiceClient for $client {
+                fn with_client(client: Client, region: Region) -> Self {
+                    <$client>::new_with_client(client, region)
+                }
+            }
+        )*
+    };
+}
+
+aws_service_client!(
+    S3Client,
+    LambdaClient,
+    EfsClient,
+    SqsClient,
+    CloudWatchLogsClient,
+    StepFunctionsClient,
+    KmsClient,
+    CloudWatchEventsClient,
+    CloudWatchClient,
+    IamClient
+);
+#[cfg(feature = "kinesis")]
+aws_service_client!(KinesisClient);
+
+/// Creates a client of the AWS service in the given region, or in the default
+/// region if the name is empty or invalid.
+///
+/// If the endpoint is overridden (see [`aws_endpoint`]), the client signs the
+/// requests with dummy credentials instead of the credentials of the
+/// environment. The S3 clients address the buckets in the path style
+/// (`<endpoint>/<bucket>/<key>`), which needs no DNS name for each bucket.
+pub fn new_client<C: AwsServiceClient>(region: &str) -> C {
+    let client = match aws_endpoint() {
+        Some(_) => Client::new_with(
+            StaticProvider::new_minimal(DUMMY_ACCESS_KEY.to_string(), DUMMY_ACCESS_KEY.to_string()),
+            HttpClient::new().expect("Failed to create the HTTP client"),
+        ),
+        None => Client::shared(),
+    };
+    C::with_client(client, client_region(region))
+}
+
+macro_rules! region_client {
+    ($func:ident, $client:ty, $cache:ident, $doc:expr) => {
+        #[doc = $doc]
+        /// If `region` is empty, the region set by `set_flock_region` is used.
+        pub fn $func(region: &str) -> $client {
+            let name = if region.is_empty() {
+                FLOCK_REGION.read().unwrap().clone()
+            } else {
+                region.to_string()
+            };
+            $cache
+                .lock()
+                .unwrap()
+                .entry(name.clone())
+                .or_insert_with(|| new_client(&name))
+                .clone()
+        }
+    };
+}
+
+region_client!(
+    s3_client,
+    S3Client,
+    FLOCK_S3_CLIENTS,
+    "Returns the cached S3 client of the given region."
+);
+region_client!(
+    lambda_client,
+    LambdaClient,
+    FLOCK_LAMBDA_CLIENTS,
+    "Returns the cached Lambda client of the given region."
+);
+region_client!(
+    efs_client,
+    EfsClient,
+    FLOCK_EFS_CLIENTS,
+    "Returns the cached EFS client of the given region."
+);
+region_client!(
+    sqs_client,
+    SqsClient,
+    FLOCK_SQS_CLIENTS,
+    "Returns the cached SQS client of the given region."
+);
+region_client!(
+    watchlogs_client,
+    CloudWatchLogsClient,
+    FLOCK_WATCHLOGS_CLIENTS,
+    "Returns the cached CloudWatch Logs client of the given region."
+);
+region_client!(
+    sfn_client,
+    StepFunctionsClient,
+    FLOCK_SFN_CLIENTS,
+    "Returns the cached Step Functions client of the given region."
+);
+region_client!(
+    kms_client,
+    KmsClient,
+    FLOCK_KMS_CLIENTS,
+    "Returns the cached KMS client of the given region."
+);
+region_client!(
+    events_client,
+    CloudWatchEventsClient,
+    FLOCK_EVENTS_CLIENTS,
+    "Returns the cached EventBridge (CloudWatch Events) client of the given region."
+);
+region_client!(
+    metrics_client,
+    CloudWatchClient,
+    FLOCK_METRICS_CLIENTS,
+    "Returns the cached CloudWatch (metrics) client of the given region."
+);
+
+/// Returns the S3 bucket name of the state backend for the given query id.
+///
+/// The bucket name is `<query id>-<region>`, so that the same query running in
+/// different regions doesn't collide. Since S3 bucket names are limited to 63
+/// characters, the query id is truncated if necessary. If the region is empty,
+/// the query id is used as it is.
+pub fn state_bucket_name(qid: &str, region: &str) -> String {
+    if region.is_empty() {
+        return qid.to_string();
+    }
+    let max_len = S3_BUCKET_NAME_MAX_LEN - region.len() - 1;
+    let qid = if qid.len() > max_len {
+        qid[..max_len].trim_end_matches('-')
+    } else {
+        qid
+    };
+    format!("{}-{}", qid, region)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_bucket_name_with_region() {
+        let qid = "q4-1642991536-2187351285";
+        assert_eq!(state_bucket_name(qid, ""), qid);
+        assert_eq!(
+            state_bucket_name(qid, "us-east-1"),
+            "q4-1642991536-2187351285-us-east-1"
+        );
+        assert_ne!(
+            state_bucket_name(qid, "us-east-1"),
+            state_bucket_name(qid, "eu-central-1")
+        );
+
+        let qid = "q4-1642991536-218735128523183619391499820347984139655";
+        let bucket = state_bucket_name(qid, "eu-central-1");
+        assert_eq!(bucket.len(), S3_BUCKET_NAME_MAX_LEN);
+        assert!(bucket.ends_with("-eu-central-1"));
+        assert!(bucket.starts_with("q4-1642991536-"));
+    }
+
+    #[test]
+    fn region_at_endpoint() {
+        assert_eq!(region_at("eu-central-1", None), Region::EuCentral1);
+        assert_eq!(region_at("mars-north-1", None), Region::default());
+
+        let endpoint = Some("http://localhost:4566".to_string());
+        assert_eq!(
+            region_at("eu-central-1", endpoint.clone()),
+            Region::Custom {
+                name:     "eu-central-1".to_string(),
+                endpoint: "http://localhost:4566".to_string(),
+            }
+        );
+        assert_eq!(region_at("", endpoint).name(), Region::default().name());
+    }
+
+    #[test]
+    fn parse_region_name() -> Result<()> {
+        assert_eq!(parse_region("eu-central-1")?, Region::EuCentral1);
+        assert_eq!(parse_region("us-east-1")?, Region::UsEast1);
+        assert!(parse_region("mars-north-1").is_err());
+        Ok(())
+    }
+}
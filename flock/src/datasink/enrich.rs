@@ -0,0 +1,377 @@
+// // Copyright (c) 2020-present, UMD Database Group.
+// //
+// // This program is free software: you can use, redistribute, and/or modify
+// // it under the terms of the GNU Affero General Public License, version 3
+// // or later ("AGPL"), as published by the Free Software Foundation.
+// //
+// // This program is distributed in the hope that it will be useful, but
+// WITHOUT // ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+// or // FITNESS FOR A PARTICULAR PURPOSE.
+// //
+// // You should have received a copy of the GNU Affero General Public License
+// // along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The window columns of the query results.
+//!
+//! If the query is deployed with the window columns on (see
+//! [`crate::api::DeployOptions::with_window_columns`]), the last stage appends
+//! the columns `_window_start`, `_window_end` and `_query_code` to its output
+//! before writing it to the data sink. The columns are appended after the
+//! plan of the stage is executed, so the SQL semantics of the query are
+//! untouched.
+//!
+//! The window bounds are derived from the window id and the window of the
+//! context. The window id is `<query code>-<timestamp>-<random id>-<shuffle>`,
+//! whose timestamp is the time in seconds at which the data source emitted
+//! the window, i.e. the end of the window. The start is the end minus the
+//! length of the window, and is null if the length depends on the data, e.g.
+//! for the session windows.
+//!
+//! The event time of the results of a window (see [`event_time`]) is recorded
+//! with the wall-clock time when they're written to the data sink, so that
+//! the event-time latency of each window can be analyzed after the run.
+
+use crate::error::{FlockError, Result};
+use crate::runtime::function_name::query_code_of;
+use crate::stream::Window;
+use datafusion::arrow::array::{Array, BooleanArray, StringArray, TimestampMillisecondArray};
+use datafusion::arrow::compute::{cast, filter_record_batch};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use datafusion::arrow::record_batch::RecordBatch;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+/// The column of the start of the window.
+pub const WINDOW_START_COLUMN: &str = "_window_start";
+
+/// The column of the end of the window.
+pub const WINDOW_END_COLUMN: &str = "_window_end";
+
+/// The column of the query code.
+pub const QUERY_CODE_COLUMN: &str = "_query_code";
+
+/// The bounds of a window in milliseconds since the epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WindowBounds {
+    /// The start of the window, or `None` if it depends on the data.
+    pub start: Option<i64>,
+    /// The end of the window.
+    pub end:   i64,
+}
+
+/// Returns the bounds of the window of the given id.
+///
+/// # Arguments
+/// * `window_id` - The id of the window written to the data sink (see
+///   [`crate::datasink::results::window_id`]).
+/// * `window` - The window of the query, if any.
+pub fn window_bounds(window_id: &str, window: Option<&Window>) -> Result<WindowBounds> {
+    let end = window_id
+        .split('-')
+        .nth(1)
+        .and_then(|ts| ts.parse::<i64>().ok())
+        .ok_or_else(|| FlockError::DataSink(format!("Invalid window id: {}", window_id)))?
+        * 1000;
+    let start = window
+        .and_then(Window::length)
+        .map(|length| end - length.as_millis() as i64);
+    Ok(WindowBounds { start, end })
+}
+
+/// Returns the schema with the window columns appended.
+pub fn with_window_schema(schema: &SchemaRef) -> SchemaRef {
+    let mut fields = schema.fields().clone();
+    fields.push(Field::new(
+        WINDOW_START_COLUMN,
+        DataType::Timestamp(TimeUnit::Millisecond, None),
+        true,
+    ));
+    fields.push(Field::new(
+        WINDOW_END_COLUMN,
+        DataType::Timestamp(TimeUnit::Millisecond, None),
+        false,
+    ));
+    fields.push(Field::new(QUERY_CODE_COLUMN, DataType::Utf8, false));
+    Arc::new(Schema::new_with_metadata(fields, schema.metadata().clone()))
+}
+
+/// Appends the window columns to the output of the last stage.
+///
+/// # Arguments
+/// * `batches` - The output of the last stage.
+/// * `window_id` - The id of the window of the output.
+/// * `window` - The window of the query, if any.
+pub fn with_window_columns(
+    batches: Vec<RecordBatch>,
+    window_id: &str,
+    window: Option<&Window>,
+) -> Result<Vec<RecordBatch>> {
+    let bounds = window_bounds(window_id, window)?;
+    let query_code = query_code_of(window_id);
+    batches
+        .into_iter()
+        .map(|batch| {
+            let rows = batch.num_rows();
+            let mut columns = batch.columns().to_vec();
+            columns.push(Arc::new(TimestampMillisecondArray::from(vec![
+                bounds.start;
+                rows
+            ])));
+            columns.push(Arc::new(TimestampMillisecondArray::from(vec![
+                bounds.end;
+                rows
+            ])));
+            columns.push(Arc::new(StringArray::from(vec![query_code; rows])));
+            Ok(RecordBatch::try_new(
+                with_window_schema(&batch.schema()),
+                columns,
+            )?)
+        })
+        .collect()
+}
+
+/// Returns the event time of the results of a window in milliseconds since the
+/// epoch, i.e. the end of the window in the `_window_end` column, or else the
+/// latest event timestamp of the results, e.g. for the element-wise queries
+/// that have no window. Returns `None` if the results have neither.
+pub fn event_time(batches: &[RecordBatch]) -> Option<i64> {
+    let schema = batches.first()?.schema();
+    let columns = match schema.index_of(WINDOW_END_COLUMN) {
+        Ok(index) => vec![index],
+        Err(_) => schema
+            .fields()
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| {
+                matches!(f.data_type(), DataType::Timestamp(..)) && f.name() != WINDOW_START_COLUMN
+            })
+            .map(|(i, _)| i)
+            .collect(),
+    };
+    batches
+        .iter()
+        .flat_map(|batch| {
+            columns.iter().filter_map(move |&i| {
+                let array = cast(
+                    batch.column(i),
+                    &DataType::Timestamp(TimeUnit::Millisecond, None),
+                )
+                .ok()?;
+                let array = array.as_any().downcast_ref::<TimestampMillisecondArray>()?;
+                array.iter().flatten().max()
+            })
+        })
+        .max()
+}
+
+/// Splits the results with the window columns by their windows, e.g. to align
+/// them with the results of the windows computed elsewhere.
+pub fn split_by_window(
+    batches: &[RecordBatch],
+) -> Result<BTreeMap<WindowBounds, Vec<RecordBatch>>> {
+    let mut windows = BTreeMap::new();
+    for batch in batches {
+        let column = |name: &str| -> Result<&TimestampMillisecondArray> {
+            let schema = batch.schema();
+            let index = schema.index_of(name)?;
+            batch
+                .column(index)
+                .as_any()
+                .downcast_ref::<TimestampMillisecondArray>()
+                .ok_or_else(|| FlockError::DataSink(format!("{} isn't a timestamp column", name)))
+        };
+        let (start, end) = (column(WINDOW_START_COLUMN)?, column(WINDOW_END_COLUMN)?);
+        let bounds = |i: usize| WindowBounds {
+            start: start.is_valid(i).then(|| start.value(i)),
+            end:   end.value(i),
+        };
+        let distinct = (0..batch.num_rows()).map(bounds).collect::<BTreeSet<_>>();
+        for window in distinct {
+            let mask = (0..batch.num_rows())
+                .map(|i| Some(bounds(i) == window))
+                .collect::<BooleanArray>();
+            windows
+                .entry(window)
+                .or_insert_with(Vec::new)
+                .push(filter_record_batch(batch, &mask)?);
+        }
+    }
+    Ok(windows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::window::{hopping_window, session_window, tumbling_window};
+    use datafusion::arrow::array::{Int64Array, TimestampSecondArray};
+    use datafusion::arrow::compute::concat;
+
+    const WINDOW_ID: &str = "q4-1650000000-218735128523183619391499820347984139655-02";
+
+    fn output() -> Vec<RecordBatch> {
+        let schema = Arc::new(Schema::new(vec![Field::new("c", DataType::Int64, false)]));
+        vec![
+            RecordBatch::try_new(schema.clone(), vec![Arc::new(Int64Array::from(vec![1, 2]))])
+                .unwrap(),
+            RecordBatch::new_empty(schema),
+        ]
+    }
+
+    fn timestamps(batch: &RecordBatch, name: &str) -> Vec<Option<i64>> {
+        let schema = batch.schema();
+        batch
+            .column(schema.index_of(name).unwrap())
+            .as_any()
+            .downcast_ref::<TimestampMillisecondArray>()
+            .unwrap()
+            .iter()
+            .collect()
+    }
+
+    #[test]
+    fn tumbling_window_columns() -> Result<()> {
+        let batches = with_window_columns(output(), WINDOW_ID, Some(&tumbling_window(10)))?;
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[1].num_rows(), 0);
+        let batch = &batches[0];
+        assert_eq!(batch.num_columns(), 4);
+        assert_eq!(
+            batch.schema().field(1),
+            &Field::new(
+                WINDOW_START_COLUMN,
+                DataType::Timestamp(TimeUnit::Millisecond, None),
+                true
+            )
+        );
+        assert_eq!(
+            timestamps(batch, WINDOW_START_COLUMN),
+            vec![Some(1_649_999_990_000); 2]
+        );
+        assert_eq!(
+            timestamps(batch, WINDOW_END_COLUMN),
+            vec![Some(1_650_000_000_000); 2]
+        );
+        let query_code = batch
+            .column(3)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap()
+            .iter()
+            .collect::<Vec<_>>();
+        assert_eq!(query_code, vec![Some("q4"); 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn hopping_window_columns() -> Result<()> {
+        // The hopping window lasts 10 seconds, and moves forward by 2 seconds.
+        let window = hopping_window(10, 2);
+        assert_eq!(
+            window_bounds(WINDOW_ID, Some(&window))?,
+            WindowBounds {
+                start: Some(1_649_999_990_000),
+                end:   1_650_000_000_000,
+            }
+        );
+        let batches = with_window_columns(output(), WINDOW_ID, Some(&window))?;
+        assert_eq!(
+            timestamps(&batches[0], WINDOW_START_COLUMN),
+            vec![Some(1_649_999_990_000); 2]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn session_window_columns() -> Result<()> {
+        // The start of a session window depends on the data.
+        let batches = with_window_columns(output(), WINDOW_ID, Some(&session_window(5)))?;
+        assert_eq!(timestamps(&batches[0], WINDOW_START_COLUMN), vec![None; 2]);
+        assert_eq!(
+            timestamps(&batches[0], WINDOW_END_COLUMN),
+            vec![Some(1_650_000_000_000); 2]
+        );
+
+        assert!(window_bounds("q4", None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn event_time_of_results() -> Result<()> {
+        // The end of the window wins over the event timestamps.
+        let batches = with_window_columns(output(), WINDOW_ID, Some(&tumbling_window(10)))?;
+        assert_eq!(event_time(&batches), Some(1_650_000_000_000));
+
+        // The latest event timestamp of the element-wise results, in any unit.
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("c", DataType::Int64, false),
+            Field::new(
+                "date_time",
+                DataType::Timestamp(TimeUnit::Millisecond, None),
+                true,
+            ),
+            Field::new(
+                "event_time",
+                DataType::Timestamp(TimeUnit::Second, None),
+                true,
+            ),
+        ]));
+        let batch = |c: Vec<i64>, millis: Vec<Option<i64>>, seconds: Vec<Option<i64>>| {
+            RecordBatch::try_new(
+                schema.clone(),
+                vec![
+                    Arc::new(Int64Array::from(c)),
+                    Arc::new(TimestampMillisecondArray::from(millis)),
+                    Arc::new(TimestampSecondArray::from(seconds)),
+                ],
+            )
+            .unwrap()
+        };
+        let batches = vec![
+            batch(vec![1, 2], vec![Some(1_000), None], vec![Some(1), Some(2)]),
+            batch(vec![3], vec![Some(1_500)], vec![None]),
+        ];
+        assert_eq!(event_time(&batches), Some(2_000));
+        assert_eq!(event_time(&batches[1..]), Some(1_500));
+
+        // The results without timestamps have no event time.
+        assert_eq!(event_time(&output()), None);
+        assert_eq!(event_time(&[]), None);
+        Ok(())
+    }
+
+    #[test]
+    fn split_results_by_window() -> Result<()> {
+        let window = tumbling_window(10);
+        let mut batches = with_window_columns(output(), WINDOW_ID, Some(&window))?;
+        batches.extend(with_window_columns(
+            output(),
+            "q4-1650000010-1-00",
+            Some(&window),
+        )?);
+        let schema = batches[0].schema();
+        let columns = (0..schema.fields().len())
+            .map(|i| {
+                concat(
+                    &batches
+                        .iter()
+                        .map(|b| b.column(i).as_ref())
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let merged = RecordBatch::try_new(schema, columns)?;
+
+        let windows = split_by_window(&[merged])?;
+        assert_eq!(windows.len(), 2);
+        for (bounds, end) in windows.keys().zip([1_650_000_000_000, 1_650_000_010_000]) {
+            assert_eq!(bounds.end, end);
+            assert_eq!(bounds.start, Some(end - 10_000));
+        }
+        assert!(windows.values().all(|batches| batches
+            .iter()
+            .map(|b| b.num_rows())
+            .sum::<usize>()
+            == 2));
+        Ok(())
+    }
+}
@@ -14,11 +14,19 @@
 //! This module provides different data sinks for the Flock runtime to write
 //! data to.
 
+pub mod enrich;
+pub mod results;
+pub mod websocket;
+
+use crate::aws::client::CloudClient;
 use crate::aws::s3;
 use crate::configs::*;
+use crate::datasink::websocket::{frames_key_prefix, Frame, WebSocketSink};
 use crate::encoding::Encoding;
 use crate::error::{FlockError, Result};
-use crate::runtime::payload::DataFrame;
+use crate::runtime::function_name::{query_code_of, query_key};
+use crate::runtime::payload::{DataFrame, Payload};
+use crate::runtime::response::{response_data, Response};
 use crate::transmute::*;
 use datafusion::arrow::csv;
 use datafusion::arrow::datatypes::Schema;
@@ -42,6 +50,24 @@ use std::sync::Arc;
 use tokio::task::{self, JoinHandle};
 use uuid::Uuid;
 
+/// The maximum size of the synchronous invocation response of AWS Lambda
+/// (6 MB). Larger results are written to S3 instead.
+pub const FLOCK_MAX_RESPONSE_SIZE: usize = 6 * 1024 * 1024;
+
+/// Returns the inline results in the body of the response of a synchronous
+/// invocation, or `None` if the function doesn't return its results through
+/// the [`DataSinkType::Response`] data sink.
+pub fn inline_response(body: &[u8]) -> Result<Option<Value>> {
+    let value = Response::from_slice(body)?.into_result()?.into_data();
+    Ok((value["sink_type"] == json!(DataSinkType::Response)).then(|| value))
+}
+
+/// Returns the S3 key of the results of the query written to the S3 data
+/// sink, i.e. `<query code>/sink`.
+pub fn sink_key(query_code: &str) -> String {
+    query_key(query_code, "sink")
+}
+
 /// Flock data format for data sink.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum DataSinkFormat {
@@ -75,6 +101,18 @@ pub enum DataSinkType {
     SQS,
     /// Write to AWS EFS.
     EFS,
+    /// Return the results inline to the synchronous caller. If the results
+    /// exceed the response limit, they are written to AWS S3 instead.
+    Response,
+    /// Push the results of each window to the client over a WebSocket
+    /// connection of AWS API Gateway. See [`websocket`] for details.
+    WebSocket {
+        /// The endpoint of the API Gateway Management API, i.e.
+        /// `https://{api-id}.execute-api.{region}.amazonaws.com/{stage}`.
+        api_endpoint:  String,
+        /// The connection id of the client.
+        connection_id: String,
+    },
 }
 
 impl Default for DataSinkType {
@@ -92,6 +130,7 @@ impl DataSinkType {
             "dynamodb" => Ok(DataSinkType::DynamoDB),
             "sqs" => Ok(DataSinkType::SQS),
             "efs" => Ok(DataSinkType::EFS),
+            "response" => Ok(DataSinkType::Response),
             _ => Err(FlockError::DataSink(format!(
                 "Unknown data sink type: {}",
                 data_sink
@@ -115,6 +154,14 @@ pub struct DataSink {
     /// The last actor in the dag that wrote to the data sink.
     /// Client can use this to fetch the logs for AWS WatchLogs.
     pub function_name:  String,
+    /// The event time of the results in milliseconds since the epoch (see
+    /// [`enrich::event_time`]).
+    #[serde(default)]
+    pub event_time:     Option<i64>,
+    /// The wall-clock time in milliseconds since the epoch when the results
+    /// are written to the data sink.
+    #[serde(default)]
+    pub written_at:     Option<i64>,
 }
 
 impl DataSink {
@@ -125,6 +172,7 @@ impl DataSink {
         encoding: Encoding,
     ) -> Self {
         Self {
+            event_time: enrich::event_time(&record_batches),
             record_batches,
             encoding,
             function_name,
@@ -132,29 +180,49 @@ impl DataSink {
         }
     }
 
-    /// Write the record batches to the data sink.
+    /// Write the record batches to the data sink. The S3 objects are written
+    /// with the given client.
     pub async fn write(
         &mut self,
         sink_type: DataSinkType,
         sink_format: DataSinkFormat,
+        client: &dyn CloudClient,
     ) -> Result<Value> {
+        self.written_at = Some(chrono::Utc::now().timestamp_millis());
         match sink_type {
             DataSinkType::Blackhole => {}
             DataSinkType::SQS => {
                 self.write_to_sqs().await?;
             }
-            DataSinkType::S3 => {
-                self.write_to_s3().await?;
+            DataSinkType::S3 | DataSinkType::Response => {
+                let key = sink_key(query_code_of(&self.function_name));
+                self.write_to_s3(&key, client).await?;
             }
             DataSinkType::EFS => {
                 self.write_to_efs(sink_format).await?;
             }
+            DataSinkType::WebSocket {
+                ref api_endpoint,
+                ref connection_id,
+            } => {
+                let delivery = WebSocketSink::new(api_endpoint, connection_id)?
+                    .push(&self.function_name, &self.record_batches)
+                    .await?;
+                return Ok(json!({
+                    "name": self.function_name.clone(),
+                    "sink_type": sink_type,
+                    "status": "success",
+                    "delivery": delivery,
+                }));
+            }
             _ => unimplemented!(),
         }
         Ok(json!({"name": self.function_name.clone(), "sink_type": sink_type, "status": "success"}))
     }
 
-    /// Read the record batches from the data sink.
+    /// Read the record batches from the data sink. The S3 sink object is
+    /// written after the window is committed to the [`results::ResultStore`],
+    /// so it's always the output of a complete window.
     pub async fn read(
         function_name: String,
         sink_type: DataSinkType,
@@ -166,12 +234,140 @@ impl DataSink {
                 ..Default::default()
             }),
             DataSinkType::SQS => DataSink::read_from_sqs(function_name).await,
-            DataSinkType::S3 => DataSink::read_from_s3(function_name).await,
+            DataSinkType::S3 | DataSinkType::Response => {
+                DataSink::read_from_s3(function_name).await
+            }
             DataSinkType::EFS => DataSink::read_from_efs(function_name, sink_format).await,
+            DataSinkType::WebSocket { .. } => DataSink::read_from_websocket(function_name).await,
             _ => unimplemented!(),
         }
     }
 
+    /// Serialize the record batches into the response of the synchronous
+    /// invocation. The record batches are encoded as a [`Payload`], so the
+    /// caller can decode them with [`Payload::to_record_batch`].
+    ///
+    /// Returns `None` if the serialized response exceeds `limit` bytes.
+    pub fn to_response(&self, limit: usize) -> Result<Option<Value>> {
+        let payload = to_payload(&self.record_batches, &[], Default::default(), true)?;
+        let response = json!({
+            "name": self.function_name.clone(),
+            "sink_type": DataSinkType::Response,
+            "truncated": false,
+            "payload": payload,
+            "event_time": self.event_time,
+            "written_at": self
+                .written_at
+                .unwrap_or_else(|| chrono::Utc::now().timestamp_millis()),
+        });
+        if serde_json::to_vec(&response)?.len() > limit {
+            Ok(None)
+        } else {
+            Ok(Some(response))
+        }
+    }
+
+    /// The response of the synchronous invocation when the record batches are
+    /// too large to be returned inline. It points to the S3 object `key` that
+    /// holds the results.
+    pub fn truncated_response(&self, key: &str) -> Value {
+        json!({
+            "name": self.function_name.clone(),
+            "sink_type": DataSinkType::Response,
+            "truncated": true,
+            "bucket": FLOCK_S3_BUCKET.clone(),
+            "key": key,
+            "event_time": self.event_time,
+            "written_at": self.written_at,
+        })
+    }
+
+    /// Return the record batches inline to the synchronous caller, or write
+    /// them to the S3 object `key` with the given client if the response would
+    /// exceed `limit` bytes.
+    pub async fn write_to_response(
+        &mut self,
+        limit: usize,
+        key: &str,
+        client: &dyn CloudClient,
+    ) -> Result<Value> {
+        self.written_at = Some(chrono::Utc::now().timestamp_millis());
+        match self.to_response(limit)? {
+            Some(response) => Ok(response),
+            None => {
+                self.write_to_s3(key, client).await?;
+                Ok(self.truncated_response(key))
+            }
+        }
+    }
+
+    /// Merges the inline results of several windows into the response of a
+    /// single synchronous invocation. The record batches of all windows are
+    /// returned inline, or written to S3 with the given client if they exceed
+    /// `limit` bytes. The response carries the event time and the write time
+    /// of the last window.
+    pub async fn merge_responses(
+        mut responses: Vec<Value>,
+        limit: usize,
+        client: &dyn CloudClient,
+    ) -> Result<Value> {
+        if responses.len() <= 1 {
+            return Ok(responses.pop().unwrap_or(Value::Null));
+        }
+        let mut sink = DataSink::default();
+        for response in responses {
+            let data = DataSink::from_response(response).await?;
+            sink.function_name = data.function_name;
+            sink.record_batches.extend(data.record_batches);
+            sink.event_time = data.event_time;
+            sink.written_at = data.written_at;
+        }
+        match sink.to_response(limit)? {
+            Some(response) => Ok(response),
+            None => {
+                let key = sink_key(query_code_of(&sink.function_name));
+                sink.write_to_s3(&key, client).await?;
+                Ok(sink.truncated_response(&key))
+            }
+        }
+    }
+
+    /// Decode the response of the synchronous invocation returned by
+    /// [`DataSink::write_to_response`], which may be wrapped in the response
+    /// envelopes of the functions (see [`crate::runtime::response`]). The
+    /// results are fetched from S3 if the response is truncated.
+    pub async fn from_response(response: Value) -> Result<DataSink> {
+        let response = response_data(response);
+        let function_name = response["name"]
+            .as_str()
+            .ok_or_else(|| FlockError::DataSink("No function name in the response".to_string()))?
+            .to_string();
+
+        if response["truncated"].as_bool().unwrap_or(false) {
+            let (bucket, key) = match (response["bucket"].as_str(), response["key"].as_str()) {
+                (Some(bucket), Some(key)) => (bucket, key),
+                _ => {
+                    return Err(FlockError::DataSink(
+                        "No S3 pointer in the truncated response".to_string(),
+                    ))
+                }
+            };
+            let body = s3::get_object(bucket, key).await?;
+            let mut data: DataSink = serde_json::from_slice(&body)?;
+            data.decode_record_batches()?;
+            return Ok(data);
+        }
+
+        let payload = Payload::from_value(response["payload"].clone())?;
+        Ok(DataSink {
+            function_name,
+            record_batches: payload.to_record_batch()?.0,
+            event_time: response["event_time"].as_i64(),
+            written_at: response["written_at"].as_i64(),
+            ..Default::default()
+        })
+    }
+
     /// This is an internal function that is used to decode `self.encoded_data`
     /// to `self.record_batches` for future use.
     fn decode_record_batches(&mut self) -> Result<()> {
@@ -234,7 +430,7 @@ impl DataSink {
         // A queue name can have up to 80 characters.
         // Valid values: alphanumeric characters, hyphens (-), and underscores (_).
         // A FIFO queue name must end with the .fifo suffix.
-        let queue_name = self.function_name.split('-').next().unwrap();
+        let queue_name = query_code_of(&self.function_name);
 
         let mut attrs = HashMap::new();
         // The length of time, in seconds, for which Amazon SQS retains a message.
@@ -244,7 +440,7 @@ impl DataSink {
         // Developer Guide.
         attrs.insert("FifoQueue".to_string(), "true".to_string());
 
-        let queue_url = FLOCK_SQS_CLIENT
+        let queue_url = sqs_client("")
             .create_queue(CreateQueueRequest {
                 queue_name: format!("{}.fifo", queue_name),
                 attributes: Some(attrs),
@@ -255,7 +451,7 @@ impl DataSink {
             .queue_url
             .expect("queue_url not found");
 
-        FLOCK_SQS_CLIENT
+        sqs_client("")
             .send_message(SendMessageRequest {
                 queue_url,
                 message_body: serde_json::to_string(&self).unwrap(),
@@ -269,11 +465,12 @@ impl DataSink {
         Ok(())
     }
 
-    async fn write_to_s3(&mut self) -> Result<()> {
+    async fn write_to_s3(&mut self, key: &str, client: &dyn CloudClient) -> Result<()> {
         self.encode_record_batches();
 
-        let s3_key = self.function_name.split('-').next().unwrap();
-        s3::put_object(&FLOCK_S3_BUCKET, s3_key, serde_json::to_vec(&self)?).await?;
+        client
+            .s3_put(&FLOCK_S3_BUCKET, key, serde_json::to_vec(&self)?)
+            .await?;
 
         Ok(())
     }
@@ -316,8 +513,8 @@ impl DataSink {
     }
 
     async fn read_from_sqs(function_name: String) -> Result<DataSink> {
-        let queue_name = function_name.split('-').next().unwrap();
-        let queue_url = FLOCK_SQS_CLIENT
+        let queue_name = query_code_of(&function_name);
+        let queue_url = sqs_client("")
             .get_queue_url(GetQueueUrlRequest {
                 queue_name: format!("{}.fifo", queue_name),
                 ..Default::default()
@@ -327,7 +524,7 @@ impl DataSink {
             .queue_url
             .expect("Queue URL not found");
 
-        let messages = FLOCK_SQS_CLIENT
+        let messages = sqs_client("")
             .receive_message(ReceiveMessageRequest {
                 queue_url: queue_url.to_string(),
                 max_number_of_messages: Some(1),
@@ -350,14 +547,31 @@ impl DataSink {
     }
 
     async fn read_from_s3(function_name: String) -> Result<DataSink> {
-        let s3_key = function_name.split('-').next().unwrap();
-        let body = s3::get_object(&FLOCK_S3_BUCKET, s3_key).await?;
+        let s3_key = sink_key(query_code_of(&function_name));
+        let body = s3::get_object(&FLOCK_S3_BUCKET, &s3_key).await?;
         let mut data: DataSink = serde_json::from_slice(&body)?;
         data.decode_record_batches()?;
 
         Ok(data)
     }
 
+    /// The results pushed over the WebSocket connection are consumed by the
+    /// client, so only the frames that couldn't be pushed are read from S3.
+    async fn read_from_websocket(function_name: String) -> Result<DataSink> {
+        let prefix = frames_key_prefix(query_code_of(&function_name));
+        let mut record_batches = vec![];
+        for key in s3::get_matched_keys(&FLOCK_S3_BUCKET, &prefix).await? {
+            let frame: Frame =
+                serde_json::from_slice(&s3::get_object(&FLOCK_S3_BUCKET, &key).await?)?;
+            record_batches.extend(frame.into_record_batches().await?);
+        }
+        Ok(DataSink {
+            function_name,
+            record_batches,
+            ..Default::default()
+        })
+    }
+
     async fn read_from_efs(function_name: String, sink_format: DataSinkFormat) -> Result<DataSink> {
         let fs_path = Path::new(&*FLOCK_EFS_MOUNT_PATH).join(function_name.clone());
         let ctx = Box::new(ExecutionContext::new());
@@ -414,3 +628,103 @@ impl DataSink {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aws::client::FakeCloudClient;
+    use datafusion::arrow::array::UInt32Array;
+    use datafusion::arrow::datatypes::{DataType, Field};
+    use datafusion::arrow::util::pretty::pretty_format_batches;
+
+    fn create_batches(num_batches: usize) -> Vec<RecordBatch> {
+        let schema = Arc::new(Schema::new(vec![Field::new("c0", DataType::UInt32, false)]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![Arc::new(UInt32Array::from(vec![1, 2, 3, 4, 5, 6, 7, 8]))],
+        )
+        .unwrap();
+        vec![batch; num_batches]
+    }
+
+    #[tokio::test]
+    async fn response_inline() -> Result<()> {
+        let batches = create_batches(4);
+        let sink = DataSink::new("q1-00".to_string(), batches.clone(), Encoding::default());
+
+        let response = sink.to_response(FLOCK_MAX_RESPONSE_SIZE)?.unwrap();
+        assert_eq!(response["truncated"], json!(false));
+        assert_eq!(response["sink_type"], json!(DataSinkType::Response));
+
+        let data = DataSink::from_response(response).await?;
+        assert_eq!(data.function_name, "q1-00");
+        assert_eq!(
+            pretty_format_batches(&data.record_batches)?.to_string(),
+            pretty_format_batches(&batches)?.to_string()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn response_overflow_to_s3() -> Result<()> {
+        let sink = DataSink::new("q1-00".to_string(), create_batches(4), Encoding::default());
+
+        let size = serde_json::to_vec(&sink.to_response(FLOCK_MAX_RESPONSE_SIZE)?.unwrap())?.len();
+        assert!(sink.to_response(size)?.is_some());
+        assert!(sink.to_response(size - 1)?.is_none());
+
+        let response = sink.truncated_response(&sink_key("q1"));
+        assert_eq!(response["truncated"], json!(true));
+        assert_eq!(response["bucket"], json!(FLOCK_S3_BUCKET.clone()));
+        assert_eq!(response["key"], json!("q1/sink"));
+
+        let mut response = response;
+        response.as_object_mut().unwrap().remove("key");
+        assert!(DataSink::from_response(response).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn merge_inline_responses() -> Result<()> {
+        let client = FakeCloudClient::new();
+        let window = |time: i64| {
+            let mut sink =
+                DataSink::new("q1-00".to_string(), create_batches(2), Encoding::default());
+            sink.event_time = Some(time);
+            sink.written_at = Some(time + 100);
+            let response = Response::ok(
+                "q1-00",
+                sink.to_response(FLOCK_MAX_RESPONSE_SIZE).unwrap().unwrap(),
+            );
+            inline_response(&serde_json::to_vec(&response.to_value()).unwrap())
+                .unwrap()
+                .unwrap()
+        };
+
+        // A single window is returned as it is.
+        let response =
+            DataSink::merge_responses(vec![window(1)], FLOCK_MAX_RESPONSE_SIZE, &client).await?;
+        assert_eq!(response, window(1));
+        assert_eq!(
+            DataSink::merge_responses(vec![], FLOCK_MAX_RESPONSE_SIZE, &client).await?,
+            Value::Null
+        );
+
+        let response =
+            DataSink::merge_responses(vec![window(1), window(2)], FLOCK_MAX_RESPONSE_SIZE, &client)
+                .await?;
+        let data = DataSink::from_response(response).await?;
+        assert_eq!(data.function_name, "q1-00");
+        assert_eq!(data.record_batches.len(), 4);
+        assert_eq!((data.event_time, data.written_at), (Some(2), Some(102)));
+
+        // The other data sinks return no inline results.
+        let response = Response::ok("q1-00", json!({ "sink_type": DataSinkType::S3 }));
+        assert!(inline_response(&serde_json::to_vec(&response.to_value())?)?.is_none());
+        assert!(inline_response(b"")?.is_none());
+
+        Ok(())
+    }
+}
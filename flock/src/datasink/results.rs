@@ -0,0 +1,873 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The results of the completed windows, served over Arrow Flight.
+//!
+//! When the last stage of a query writes a window to the S3 or EFS data sink,
+//! it also keeps the results of the window as Arrow IPC files, so that the
+//! consumers can read them without decoding the Flock-encoded sink objects.
+//!
+//! |                        Layout                          |
+//! |--------------------------------------------------------|
+//! |  <query code>/results/<window id>/_tmp/part-<n>.arrow  |
+//! |  <query code>/results/<window id>/_tmp/progress.json   |
+//! |  <query code>/results/<window id>/manifest.json        |
+//!
+//! The layout is the same under the S3 bucket and the EFS mount path (see
+//! [`ResultStore`]). The window id is the one recorded by the completion
+//! protocol, i.e. `<qid>-<shuffle id>`.
+//!
+//! A window is committed in two phases: the parts are written first, and the
+//! [`WindowManifest`] listing the parts with their sizes and checksums is
+//! written once all parts are durable. The consumers only read the windows
+//! with a manifest, so a window whose writer crashed in between is never read
+//! partially. The window id is derived from the window, so the retried
+//! invocation writes the same keys, and it skips the window if its manifest is
+//! already written.
+//!
+//! Each part is retried with an exponential backoff on its own, and a part
+//! that still fails doesn't stop the others from being written. The
+//! [`WindowProgress`] of the window then records the parts written and the
+//! missing ones, and the invocation fails without committing the window. The
+//! retried invocation reads the progress and only writes the missing parts,
+//! so a large window isn't written again from the start.
+//!
+//! The `flock-results-server` binary serves a [`ResultStore`] over Arrow
+//! Flight: `ListFlights` lists the windows of the query code in the criteria,
+//! and `DoGet` streams the window of the ticket `<query code>/<window id>`.
+//! [`ResultsClient`] is the client of the server.
+
+use crate::aws::client::CloudClient;
+use crate::datasink::enrich::event_time;
+use crate::datasink::DataSinkType;
+use crate::error::{FlockError, Result};
+use crate::runtime::deadline::{self, SystemClock};
+use crate::runtime::function_name::query_key;
+use crate::runtime::metrics::{self, Metric};
+use crate::runtime::payload::Uuid;
+use datafusion::arrow::datatypes::Schema;
+use datafusion::arrow::ipc::reader::FileReader;
+use datafusion::arrow::ipc::writer::{FileWriter, IpcWriteOptions};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::arrow_flight::flight_service_client::FlightServiceClient;
+use datafusion::arrow_flight::utils::{flight_data_from_arrow_batch, flight_data_to_arrow_batch};
+use datafusion::arrow_flight::{Criteria, FlightData, SchemaAsIpc, Ticket};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::transport::Channel;
+use tonic::Streaming;
+
+/// The key prefix of the results under the prefix of the query.
+const RESULTS_KEY_PREFIX: &str = "results/";
+
+/// The name of the manifest of a committed window.
+const MANIFEST_NAME: &str = "manifest.json";
+
+/// The key prefix of the parts under the prefix of the window, which are only
+/// read through the manifest.
+const PARTS_KEY_PREFIX: &str = "_tmp/";
+
+/// The name of the progress of a window whose parts aren't all written.
+const PROGRESS_NAME: &str = "progress.json";
+
+/// The extension of the Arrow IPC files of the windows.
+const RESULTS_EXTENSION: &str = ".arrow";
+
+/// The number of attempts to write a part before it's left to the retried
+/// invocation.
+const PART_WRITE_ATTEMPTS: usize = 3;
+
+/// The backoff before the first retry of a part, which doubles on every retry.
+const PART_WRITE_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Returns the key prefix of the results of the query.
+pub fn results_key_prefix(query_code: &str) -> String {
+    query_key(query_code, RESULTS_KEY_PREFIX)
+}
+
+/// Returns the key of the manifest of the window, which marks the results of
+/// the window as complete.
+pub fn results_key(query_code: &str, window_id: &str) -> String {
+    format!(
+        "{}{}/{}",
+        results_key_prefix(query_code),
+        window_id,
+        MANIFEST_NAME
+    )
+}
+
+/// Returns the key of the part of the results of the window.
+pub fn part_key(query_code: &str, window_id: &str, part: usize) -> String {
+    format!(
+        "{}{}/{}part-{:05}{}",
+        results_key_prefix(query_code),
+        window_id,
+        PARTS_KEY_PREFIX,
+        part,
+        RESULTS_EXTENSION
+    )
+}
+
+/// Returns the key of the progress of the window, which lists the parts
+/// written before the window failed to be committed.
+pub fn progress_key(query_code: &str, window_id: &str) -> String {
+    format!(
+        "{}{}/{}{}",
+        results_key_prefix(query_code),
+        window_id,
+        PARTS_KEY_PREFIX,
+        PROGRESS_NAME
+    )
+}
+
+/// Returns the query code and the window id of the key of the manifest, or
+/// `None` if the key isn't the manifest of a window.
+pub fn parse_results_key(key: &str) -> Option<(String, String)> {
+    let (query_code, name) = key.split_once('/')?;
+    let window_id = name
+        .strip_prefix(RESULTS_KEY_PREFIX)?
+        .strip_suffix(MANIFEST_NAME)?
+        .strip_suffix('/')?;
+    if query_code.is_empty() || window_id.is_empty() || window_id.contains('/') {
+        return None;
+    }
+    Some((query_code.to_string(), window_id.to_string()))
+}
+
+/// Returns the CRC-32 checksum of the part.
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(bytes);
+    crc.sum()
+}
+
+/// A part of the results of a window.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestPart {
+    /// The key of the part under the store.
+    pub key:      String,
+    /// The size of the part in bytes.
+    pub size:     u64,
+    /// The CRC-32 checksum of the part.
+    pub checksum: u32,
+}
+
+/// The manifest of a committed window, which lists the parts of its results.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowManifest {
+    /// The id of the window.
+    pub window_id:    String,
+    /// The parts of the results in order.
+    pub parts:        Vec<ManifestPart>,
+    /// The number of rows of the results.
+    pub num_rows:     usize,
+    /// The time in milliseconds since the epoch when the window is committed.
+    pub committed_at: i64,
+    /// The event time of the results in milliseconds since the epoch (see
+    /// [`crate::datasink::enrich::event_time`]).
+    #[serde(default)]
+    pub event_time:   Option<i64>,
+}
+
+/// The parts of a window written so far, which is kept when some parts fail to
+/// be written, so that the retried invocation only writes the missing parts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowProgress {
+    /// The id of the window.
+    pub window_id: String,
+    /// The parts written so far.
+    pub written:   Vec<ManifestPart>,
+    /// The indices of the parts that failed to be written.
+    pub missing:   Vec<usize>,
+}
+
+/// Returns the id of the window written to the data sink, which is the same
+/// as the one recorded by the completion protocol.
+pub fn window_id(uuid: &Uuid, shuffle_id: Option<usize>) -> String {
+    format!("{}-{:02}", uuid.qid, shuffle_id.unwrap_or(0))
+}
+
+/// Returns the ticket of `DoGet` for the window.
+pub fn window_ticket(query_code: &str, window_id: &str) -> Ticket {
+    Ticket {
+        ticket: format!("{}/{}", query_code, window_id).into_bytes(),
+    }
+}
+
+/// Returns the query code and the window id of the ticket.
+pub fn parse_window_ticket(ticket: &Ticket) -> Result<(String, String)> {
+    std::str::from_utf8(&ticket.ticket)
+        .ok()
+        .and_then(|t| t.split_once('/'))
+        .filter(|(q, w)| !q.is_empty() && !w.is_empty())
+        .map(|(q, w)| (q.to_string(), w.to_string()))
+        .ok_or_else(|| {
+            FlockError::DataSink(format!(
+                "Invalid ticket of the window: {}",
+                String::from_utf8_lossy(&ticket.ticket)
+            ))
+        })
+}
+
+/// Encodes the record batches of the window as an Arrow IPC file.
+pub fn encode_window(batches: &[RecordBatch]) -> Result<Vec<u8>> {
+    let schema = batches
+        .first()
+        .map(|b| b.schema())
+        .ok_or_else(|| FlockError::DataSink("No results of the window to write".to_string()))?;
+    let mut bytes = vec![];
+    {
+        let mut writer = FileWriter::try_new(&mut bytes, &schema)?;
+        for batch in batches {
+            writer.write(batch)?;
+        }
+        writer.finish()?;
+    }
+    Ok(bytes)
+}
+
+/// Decodes the record batches of the window from the Arrow IPC file.
+pub fn decode_window(bytes: Vec<u8>) -> Result<Vec<RecordBatch>> {
+    let reader = FileReader::try_new(Cursor::new(bytes))?;
+    reader
+        .map(|batch| batch.map_err(FlockError::Arrow))
+        .collect()
+}
+
+/// Where the results of the windows are kept.
+#[derive(Debug, Clone)]
+pub enum ResultStore {
+    /// The S3 bucket, accessed with the client.
+    S3 {
+        /// The name of the bucket.
+        bucket: String,
+        /// The client of the S3 calls.
+        client: Arc<dyn CloudClient>,
+    },
+    /// The local directory, e.g. the EFS mount path.
+    Directory(PathBuf),
+}
+
+impl ResultStore {
+    /// Returns the store of the data sink, or `None` if the data sink doesn't
+    /// keep the results of the windows.
+    pub fn for_sink(sink_type: &DataSinkType, client: Arc<dyn CloudClient>) -> Option<Self> {
+        match sink_type {
+            DataSinkType::S3 => Some(ResultStore::S3 {
+                bucket: crate::configs::FLOCK_S3_BUCKET.clone(),
+                client,
+            }),
+            DataSinkType::EFS => Some(ResultStore::Directory(PathBuf::from(
+                &*crate::configs::FLOCK_EFS_MOUNT_PATH,
+            ))),
+            _ => None,
+        }
+    }
+
+    /// Writes the results of the window, and commits the window once all its
+    /// parts are written. The window is skipped if it's already committed,
+    /// e.g. by the failed attempt of a retried invocation.
+    pub async fn put(
+        &self,
+        query_code: &str,
+        window_id: &str,
+        batches: &[RecordBatch],
+    ) -> Result<()> {
+        if self.manifest(query_code, window_id).await?.is_some() {
+            return Ok(());
+        }
+        let parts = self.write_parts(query_code, window_id, batches).await?;
+        let num_rows = batches.iter().map(|b| b.num_rows()).sum();
+        self.commit(query_code, window_id, parts, num_rows, event_time(batches))
+            .await
+    }
+
+    /// Writes each record batch of the window as a part of its results. The
+    /// parts aren't read until the window is committed (see
+    /// [`ResultStore::commit`]).
+    ///
+    /// The parts recorded in the progress of the window by a failed attempt are
+    /// skipped if they're the same. If some parts still fail after their
+    /// retries, the progress is recorded and an error listing the missing parts
+    /// is returned.
+    pub async fn write_parts(
+        &self,
+        query_code: &str,
+        window_id: &str,
+        batches: &[RecordBatch],
+    ) -> Result<Vec<ManifestPart>> {
+        let progress = self.progress(query_code, window_id).await?;
+        let mut parts = vec![];
+        let mut missing = vec![];
+        let mut errors = vec![];
+        for (i, batch) in batches.iter().enumerate() {
+            let key = part_key(query_code, window_id, i);
+            let bytes = encode_window(std::slice::from_ref(batch))?;
+            let part = ManifestPart {
+                key:      key.clone(),
+                size:     bytes.len() as u64,
+                checksum: checksum(&bytes),
+            };
+            if progress
+                .as_ref()
+                .map_or(false, |p| p.written.contains(&part))
+            {
+                parts.push(part);
+                continue;
+            }
+            match self.write_with_backoff(&key, bytes).await {
+                Ok(()) => parts.push(part),
+                Err(e) => {
+                    missing.push(i);
+                    errors.push(format!("{}: {}", key, e));
+                }
+            }
+        }
+
+        if !missing.is_empty() {
+            let progress = WindowProgress {
+                window_id: window_id.to_string(),
+                written:   parts,
+                missing:   missing.clone(),
+            };
+            self.write(
+                &progress_key(query_code, window_id),
+                serde_json::to_vec(&progress)?,
+            )
+            .await?;
+            return Err(FlockError::DataSink(format!(
+                "The parts {:?} of the window {} aren't written: {}",
+                missing,
+                window_id,
+                errors.join("; ")
+            )));
+        }
+        Ok(parts)
+    }
+
+    /// Returns the progress of the window recorded by a failed attempt, or
+    /// `None` if no attempt failed.
+    pub async fn progress(
+        &self,
+        query_code: &str,
+        window_id: &str,
+    ) -> Result<Option<WindowProgress>> {
+        match self.read(&progress_key(query_code, window_id)).await? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes the part, and retries the failed writes with an exponential
+    /// backoff.
+    async fn write_with_backoff(&self, key: &str, bytes: Vec<u8>) -> Result<()> {
+        let mut retries = 0;
+        loop {
+            match self.write(key, bytes.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(e) if retries + 1 < PART_WRITE_ATTEMPTS => {
+                    let base = PART_WRITE_BACKOFF.as_millis() as u64;
+                    let jitter = rand::thread_rng().gen_range(0..=base);
+                    let backoff = Duration::from_millis((base << retries) + jitter);
+                    // The part is left to the retried invocation if the backoff
+                    // would exceed the deadline of the invocation.
+                    if let Some(deadline) = deadline::current() {
+                        if !deadline.allows(&SystemClock, backoff) {
+                            return Err(e);
+                        }
+                    }
+                    metrics::scope().incr(Metric::Retries);
+                    tokio::time::sleep(backoff).await;
+                    retries += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Commits the window by writing its manifest. It's a no-op if the window
+    /// is already committed, so the parts listed by the first commit are the
+    /// ones read.
+    pub async fn commit(
+        &self,
+        query_code: &str,
+        window_id: &str,
+        parts: Vec<ManifestPart>,
+        num_rows: usize,
+        event_time: Option<i64>,
+    ) -> Result<()> {
+        if self.manifest(query_code, window_id).await?.is_some() {
+            return Ok(());
+        }
+        let manifest = WindowManifest {
+            window_id: window_id.to_string(),
+            parts,
+            num_rows,
+            committed_at: chrono::Utc::now().timestamp_millis(),
+            event_time,
+        };
+        self.write(
+            &results_key(query_code, window_id),
+            serde_json::to_vec(&manifest)?,
+        )
+        .await
+    }
+
+    /// Returns the manifest of the window, or `None` if the window isn't
+    /// committed.
+    pub async fn manifest(
+        &self,
+        query_code: &str,
+        window_id: &str,
+    ) -> Result<Option<WindowManifest>> {
+        match self.read(&results_key(query_code, window_id)).await? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the results of the window, or `None` if the window isn't
+    /// committed. The parts are checked against the sizes and the checksums
+    /// in the manifest.
+    pub async fn get(&self, query_code: &str, window_id: &str) -> Result<Option<Vec<RecordBatch>>> {
+        let manifest = match self.manifest(query_code, window_id).await? {
+            Some(manifest) => manifest,
+            None => return Ok(None),
+        };
+        let mut batches = vec![];
+        for part in manifest.parts {
+            let bytes = self.read(&part.key).await?.ok_or_else(|| {
+                FlockError::DataSink(format!("The part {} of the window is missing", part.key))
+            })?;
+            if bytes.len() as u64 != part.size || checksum(&bytes) != part.checksum {
+                return Err(FlockError::DataSink(format!(
+                    "The part {} of the window doesn't match its manifest",
+                    part.key
+                )));
+            }
+            batches.extend(decode_window(bytes)?);
+        }
+        Ok(Some(batches))
+    }
+
+    /// Writes the object under the store.
+    async fn write(&self, key: &str, bytes: Vec<u8>) -> Result<()> {
+        match self {
+            ResultStore::S3 { bucket, client } => client.s3_put(bucket, key, bytes).await,
+            ResultStore::Directory(root) => {
+                let path = root.join(key);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                // The file is renamed into place, so it's never read partially.
+                let tmp = path.with_extension("tmp");
+                std::fs::write(&tmp, bytes)?;
+                Ok(std::fs::rename(tmp, path)?)
+            }
+        }
+    }
+
+    /// Reads the object under the store, or returns `None` if it doesn't exist.
+    async fn read(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self {
+            ResultStore::S3 { bucket, client } => {
+                if !client.s3_list(bucket, key).await?.iter().any(|k| k == key) {
+                    return Ok(None);
+                }
+                client.s3_get(bucket, key).await.map(Some)
+            }
+            ResultStore::Directory(root) => match std::fs::read(root.join(key)) {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+        }
+    }
+
+    /// Returns the query code and the id of the latest committed window, or
+    /// `None` if no window is committed.
+    pub async fn latest(&self, query_code: &str) -> Result<Option<(String, String)>> {
+        let mut latest = None;
+        for (query, window) in self.windows(query_code).await? {
+            if let Some(manifest) = self.manifest(&query, &window).await? {
+                if latest
+                    .as_ref()
+                    .map_or(true, |(t, _)| manifest.committed_at >= *t)
+                {
+                    latest = Some((manifest.committed_at, (query, window)));
+                }
+            }
+        }
+        Ok(latest.map(|(_, window)| window))
+    }
+
+    /// Returns the query codes and the window ids of the committed windows in
+    /// sorted order.
+    ///
+    /// # Arguments
+    /// * `query_code` - The query whose windows are listed, or an empty string
+    ///   to list the windows of all queries.
+    pub async fn windows(&self, query_code: &str) -> Result<Vec<(String, String)>> {
+        let mut windows = match self {
+            ResultStore::S3 { bucket, client } => {
+                let prefix = if query_code.is_empty() {
+                    String::new()
+                } else {
+                    results_key_prefix(query_code)
+                };
+                client
+                    .s3_list(bucket, &prefix)
+                    .await?
+                    .iter()
+                    .filter_map(|key| parse_results_key(key))
+                    .collect::<Vec<_>>()
+            }
+            ResultStore::Directory(root) => {
+                let queries = if query_code.is_empty() {
+                    match std::fs::read_dir(root) {
+                        Ok(entries) => entries
+                            .filter_map(|e| e.ok()?.file_name().into_string().ok())
+                            .collect(),
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+                        Err(e) => return Err(e.into()),
+                    }
+                } else {
+                    vec![query_code.to_string()]
+                };
+                let mut windows = vec![];
+                for query in queries {
+                    let entries = match std::fs::read_dir(root.join(results_key_prefix(&query))) {
+                        Ok(entries) => entries,
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                        Err(e) => return Err(e.into()),
+                    };
+                    windows.extend(entries.filter_map(|e| {
+                        let name = e.ok()?.file_name().into_string().ok()?;
+                        let key = results_key(&query, &name);
+                        root.join(&key)
+                            .exists()
+                            .then(|| parse_results_key(&key))
+                            .flatten()
+                    }));
+                }
+                windows
+            }
+        };
+        windows.sort();
+        Ok(windows)
+    }
+}
+
+/// The client of the results server.
+#[derive(Debug, Clone)]
+pub struct ResultsClient {
+    client: FlightServiceClient<Channel>,
+}
+
+impl ResultsClient {
+    /// Connects to the results server, e.g. `http://localhost:50051`.
+    pub async fn connect<T: Into<String>>(endpoint: T) -> Result<Self> {
+        let endpoint = endpoint.into();
+        let client = FlightServiceClient::connect(endpoint.clone())
+            .await
+            .map_err(|e| FlockError::DataSink(format!("{}: {}", endpoint, e)))?;
+        Ok(Self { client })
+    }
+
+    /// Returns the ids of the windows of the query written to the data sink.
+    pub async fn list_windows(&mut self, query_code: &str) -> Result<Vec<String>> {
+        let mut stream = self
+            .client
+            .list_flights(Criteria {
+                expression: query_code.as_bytes().to_vec(),
+            })
+            .await
+            .map_err(|e| FlockError::DataSink(e.to_string()))?
+            .into_inner();
+        let mut windows = vec![];
+        while let Some(info) = stream
+            .message()
+            .await
+            .map_err(|e| FlockError::DataSink(e.to_string()))?
+        {
+            if let Some(descriptor) = info.flight_descriptor {
+                if let [query, window] = descriptor.path.as_slice() {
+                    if query == query_code {
+                        windows.push(window.clone());
+                    }
+                }
+            }
+        }
+        Ok(windows)
+    }
+
+    /// Returns the results of the window of the query.
+    pub async fn get_window(
+        &mut self,
+        query_code: &str,
+        window_id: &str,
+    ) -> Result<Vec<RecordBatch>> {
+        let mut stream = self
+            .client
+            .do_get(window_ticket(query_code, window_id))
+            .await
+            .map_err(|e| FlockError::DataSink(e.to_string()))?
+            .into_inner();
+
+        // The first message carries the schema of the window.
+        let schema = match next_message(&mut stream).await? {
+            Some(data) => Arc::new(Schema::try_from(&data)?),
+            None => return Ok(vec![]),
+        };
+        let mut batches = vec![];
+        while let Some(data) = next_message(&mut stream).await? {
+            batches.push(flight_data_to_arrow_batch(&data, schema.clone(), &[])?);
+        }
+        Ok(batches)
+    }
+}
+
+/// Returns the next message of the stream of `DoGet`.
+async fn next_message(stream: &mut Streaming<FlightData>) -> Result<Option<FlightData>> {
+    stream
+        .message()
+        .await
+        .map_err(|e| FlockError::DataSink(e.to_string()))
+}
+
+/// Returns the messages of `DoGet` for the results of the window: the schema
+/// first, and then the record batches.
+pub fn window_flight_data(batches: &[RecordBatch]) -> Vec<FlightData> {
+    let options = IpcWriteOptions::default();
+    let schema = match batches.first() {
+        Some(batch) => batch.schema(),
+        None => return vec![],
+    };
+    let mut messages = vec![SchemaAsIpc::new(&schema, &options).into()];
+    for batch in batches {
+        let (dictionaries, data) = flight_data_from_arrow_batch(batch, &options);
+        messages.extend(dictionaries);
+        messages.push(data);
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aws::client::FakeCloudClient;
+    use crate::tests::{arbitrary, int64_string_batch};
+    use proptest::prelude::*;
+
+    #[test]
+    fn results_keys() {
+        assert_eq!(
+            results_key("q1", "q1-1-2-00"),
+            "q1/results/q1-1-2-00/manifest.json"
+        );
+        assert_eq!(
+            part_key("q1", "q1-1-2-00", 3),
+            "q1/results/q1-1-2-00/_tmp/part-00003.arrow"
+        );
+        assert_eq!(
+            progress_key("q1", "q1-1-2-00"),
+            "q1/results/q1-1-2-00/_tmp/progress.json"
+        );
+        assert_eq!(
+            parse_results_key("q1/results/q1-1-2-00/_tmp/progress.json"),
+            None
+        );
+        assert_eq!(
+            parse_results_key("q1/results/q1-1-2-00/manifest.json"),
+            Some(("q1".to_string(), "q1-1-2-00".to_string()))
+        );
+        assert_eq!(parse_results_key("q1/sink"), None);
+        assert_eq!(parse_results_key("q1/results/manifest.json"), None);
+        assert_eq!(parse_results_key("q1/results/a/b/manifest.json"), None);
+        assert_eq!(
+            parse_results_key("q1/results/q1-1-2-00/_tmp/part-00000.arrow"),
+            None
+        );
+
+        let ticket = window_ticket("q1", "q1-1-2-00");
+        assert_eq!(
+            parse_window_ticket(&ticket).unwrap(),
+            ("q1".to_string(), "q1-1-2-00".to_string())
+        );
+        assert!(parse_window_ticket(&Ticket {
+            ticket: b"q1".to_vec(),
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn result_store_round_trip() -> Result<()> {
+        let batches = vec![
+            int64_string_batch(vec![1, 2, 3]),
+            int64_string_batch(vec![4]),
+        ];
+        assert_eq!(decode_window(encode_window(&batches)?)?, batches);
+
+        let client = Arc::new(FakeCloudClient::new());
+        let s3 = ResultStore::S3 {
+            bucket: "flock-results".to_string(),
+            client: client.clone(),
+        };
+        let root = std::env::temp_dir().join(format!("flock-results-{}", uuid::Uuid::new_v4()));
+        let directory = ResultStore::Directory(root.clone());
+        for store in [&s3, &directory] {
+            store.put("q1", "w-01", &batches).await?;
+            store.put("q1", "w-00", &batches[1..]).await?;
+            store.put("q2", "w-00", &batches[..1]).await?;
+            assert_eq!(store.get("q1", "w-01").await?, Some(batches.clone()));
+            assert_eq!(store.get("q1", "w-02").await?, None);
+            assert_eq!(
+                store.windows("q1").await?,
+                vec![
+                    ("q1".to_string(), "w-00".to_string()),
+                    ("q1".to_string(), "w-01".to_string()),
+                ]
+            );
+            assert_eq!(store.windows("").await?.len(), 3);
+        }
+        assert!(client
+            .object("flock-results", "q1/results/w-01/manifest.json")
+            .is_some());
+        assert!(client
+            .object("flock-results", "q1/results/w-01/_tmp/part-00001.arrow")
+            .is_some());
+        assert!(root.join("q2/results/w-00/manifest.json").exists());
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ignore_uncommitted_windows() -> Result<()> {
+        let batches = vec![
+            int64_string_batch(vec![1, 2, 3]),
+            int64_string_batch(vec![4]),
+        ];
+        let client = Arc::new(FakeCloudClient::new());
+        let s3 = ResultStore::S3 {
+            bucket: "flock-results".to_string(),
+            client: client.clone(),
+        };
+        let root = std::env::temp_dir().join(format!("flock-results-{}", uuid::Uuid::new_v4()));
+        let directory = ResultStore::Directory(root.clone());
+        for store in [&s3, &directory] {
+            store.put("q1", "w-00", &batches).await?;
+
+            // The writer crashes after writing the parts of the window but
+            // before its manifest.
+            let parts = store.write_parts("q1", "w-01", &batches).await?;
+            assert_eq!(parts.len(), 2);
+            assert_eq!(store.get("q1", "w-01").await?, None);
+            assert_eq!(
+                store.windows("q1").await?,
+                vec![("q1".to_string(), "w-00".to_string())]
+            );
+            assert_eq!(
+                store.latest("q1").await?,
+                Some(("q1".to_string(), "w-00".to_string()))
+            );
+
+            // The retried invocation commits the window.
+            store.put("q1", "w-01", &batches).await?;
+            assert_eq!(store.get("q1", "w-01").await?, Some(batches.clone()));
+            assert_eq!(store.windows("q1").await?.len(), 2);
+            assert_eq!(store.manifest("q1", "w-01").await?.unwrap().num_rows, 4);
+
+            // The committed window isn't written again.
+            store.put("q1", "w-01", &batches[..1]).await?;
+            store.commit("q1", "w-01", vec![], 0, None).await?;
+            assert_eq!(store.get("q1", "w-01").await?, Some(batches.clone()));
+
+            // The part that doesn't match the manifest is rejected.
+            let part = store.manifest("q1", "w-00").await?.unwrap().parts[1].clone();
+            store.write(&part.key, encode_window(&batches)?).await?;
+            assert!(store.get("q1", "w-00").await.is_err());
+        }
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resume_from_missing_parts() -> Result<()> {
+        let batches = (0..9)
+            .map(|i| int64_string_batch(vec![i, i + 1]))
+            .collect::<Vec<_>>();
+        let client = Arc::new(FakeCloudClient::new());
+        let store = ResultStore::S3 {
+            bucket: "flock-results".to_string(),
+            client: client.clone(),
+        };
+
+        // The middle part fails on every attempt, and the others are written.
+        let middle = part_key("q1", "w-00", 4);
+        client.fail_next(&format!("flock-results/{}", middle), PART_WRITE_ATTEMPTS);
+        let err = store.put("q1", "w-00", &batches).await.unwrap_err();
+        assert!(err.to_string().contains(&middle));
+        assert_eq!(store.manifest("q1", "w-00").await?, None);
+        assert_eq!(store.get("q1", "w-00").await?, None);
+        assert!(client.object("flock-results", &middle).is_none());
+
+        let progress = store.progress("q1", "w-00").await?.unwrap();
+        assert_eq!(progress.missing, vec![4]);
+        assert_eq!(progress.written.len(), 8);
+        assert!(progress.written.iter().all(|p| p.key != middle));
+
+        // The retried invocation only writes the missing part and the manifest.
+        let puts = client.puts().len();
+        store.put("q1", "w-00", &batches).await?;
+        assert_eq!(
+            client.puts()[puts..].to_vec(),
+            vec![middle, results_key("q1", "w-00")]
+        );
+
+        let manifest = store.manifest("q1", "w-00").await?.unwrap();
+        assert_eq!(manifest.parts.len(), 9);
+        assert_eq!(manifest.num_rows, 18);
+        assert_eq!(store.get("q1", "w-00").await?, Some(batches.clone()));
+        assert_eq!(
+            client
+                .keys("flock-results")
+                .iter()
+                .filter(|k| k.ends_with(MANIFEST_NAME))
+                .count(),
+            1
+        );
+
+        // The parts that succeed after a retry need no progress.
+        client.fail_next(&format!("flock-results/{}", part_key("q1", "w-01", 4)), 1);
+        store.put("q1", "w-01", &batches).await?;
+        assert_eq!(store.progress("q1", "w-01").await?, None);
+        assert_eq!(store.get("q1", "w-01").await?, Some(batches));
+        Ok(())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        #[test]
+        fn window_round_trip(
+            batches in arbitrary::arb_batches(4).prop_filter("no results", |b| !b.is_empty())
+        ) {
+            let bytes = encode_window(&batches).unwrap();
+            prop_assert_eq!(batches, decode_window(bytes).unwrap());
+        }
+    }
+}
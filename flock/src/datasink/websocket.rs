@@ -0,0 +1,543 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The WebSocket data sink pushes the results of each window to the client of
+//! an interactive query, instead of having the client poll the data sink.
+//!
+//! The client connects to an API Gateway WebSocket API, and the final stage of
+//! the query posts the results of each window as a JSON [`Frame`] to the
+//! connection with the API Gateway Management API. API Gateway limits the size
+//! of a frame to 128 KB, so larger results are written to S3 and a pointer
+//! frame is sent instead. If the connection is gone, e.g. the client is
+//! reconnecting, the frame is written to S3 as well, and the client collects it
+//! by listing the frames of the query.
+//!
+//! |                  S3 Layout                  |
+//! |---------------------------------------------|
+//! |  <query code>/websocket/connection          |
+//! |  <query code>/websocket/frames/<frame id>   |
+//!
+//! A client that reconnects registers its new connection id under
+//! `connection`, so that the sink can move over to the new connection.
+
+use crate::aws::s3;
+use crate::configs::*;
+use crate::error::{FlockError, Result};
+use crate::runtime::function_name::{query_code_of, query_key};
+use crate::runtime::payload::Payload;
+use crate::transmute::to_payload;
+use async_trait::async_trait;
+use datafusion::arrow::record_batch::RecordBatch;
+use log::{info, warn};
+use rusoto_apigatewaymanagementapi::{
+    ApiGatewayManagementApi, ApiGatewayManagementApiClient, PostToConnectionError,
+    PostToConnectionRequest,
+};
+use rusoto_core::{Region, RusotoError};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// The maximum size of a WebSocket frame of API Gateway (128 KB).
+pub const FLOCK_MAX_FRAME_SIZE: usize = 128 * 1024;
+
+/// The S3 key prefix of the WebSocket data sink under the prefix of the
+/// query.
+const WEBSOCKET_KEY_PREFIX: &str = "websocket/";
+
+/// Returns the address of the stage of the API Gateway WebSocket API without
+/// the scheme, i.e. `{api-id}.execute-api.{region}.amazonaws.com/{stage}`.
+pub fn api_address(api_id: &str, stage: &str) -> Result<String> {
+    let region = parse_region(&flock_region())?;
+    Ok(format!(
+        "{}.execute-api.{}.amazonaws.com/{}",
+        api_id,
+        region.name(),
+        stage
+    ))
+}
+
+/// Returns the S3 key prefix of the frames of the query.
+pub fn frames_key_prefix(query_code: &str) -> String {
+    query_key(query_code, &format!("{}frames/", WEBSOCKET_KEY_PREFIX))
+}
+
+/// Returns the S3 key of the frame.
+pub fn frame_key(query_code: &str, frame_id: &str) -> String {
+    format!("{}{}", frames_key_prefix(query_code), frame_id)
+}
+
+/// Returns the S3 key of the connection id registered by the client.
+pub fn connection_key(query_code: &str) -> String {
+    query_key(query_code, &format!("{}connection", WEBSOCKET_KEY_PREFIX))
+}
+
+/// The message pushed to the client.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Frame {
+    /// The results of a window.
+    Batches {
+        /// The unique id of the frame.
+        id:       String,
+        /// The function that wrote the results.
+        function: String,
+        /// The record batches of the window.
+        payload:  Payload,
+    },
+    /// The results of a window that exceed the frame limit. The S3 object
+    /// holds the [`Frame::Batches`] frame of the results.
+    Pointer {
+        /// The unique id of the frame.
+        id:       String,
+        /// The function that wrote the results.
+        function: String,
+        /// The S3 bucket of the frame.
+        bucket:   String,
+        /// The S3 key of the frame.
+        key:      String,
+    },
+}
+
+impl Frame {
+    /// Returns the unique id of the frame.
+    pub fn id(&self) -> &str {
+        match self {
+            Frame::Batches { id, .. } | Frame::Pointer { id, .. } => id,
+        }
+    }
+
+    /// Returns the record batches of the frame. The pointer frames are
+    /// resolved by fetching the frame from S3.
+    pub async fn into_record_batches(self) -> Result<Vec<RecordBatch>> {
+        match self {
+            Frame::Batches { payload, .. } => Ok(payload.to_record_batch()?.0),
+            Frame::Pointer { bucket, key, .. } => {
+                let frame: Frame = serde_json::from_slice(&s3::get_object(&bucket, &key).await?)?;
+                match frame {
+                    Frame::Batches { payload, .. } => Ok(payload.to_record_batch()?.0),
+                    Frame::Pointer { .. } => Err(FlockError::DataSink(format!(
+                        "The frame {} points to another pointer frame",
+                        key
+                    ))),
+                }
+            }
+        }
+    }
+}
+
+/// How the results of a window are delivered to the client.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Delivery {
+    /// The results are pushed in a frame.
+    Frame,
+    /// The results are written to S3, and a pointer frame is pushed.
+    Pointer,
+    /// The connection is gone, and the results are written to S3.
+    Stored,
+}
+
+/// The client of the WebSocket connections.
+#[async_trait]
+pub trait ConnectionClient: Send + Sync {
+    /// Sends the data to the connection. Returns false if the connection is
+    /// gone.
+    async fn post_to_connection(&self, connection_id: &str, data: Vec<u8>) -> Result<bool>;
+}
+
+/// The connections of an API Gateway WebSocket API.
+pub struct ApiGatewayConnection {
+    client: ApiGatewayManagementApiClient,
+}
+
+impl ApiGatewayConnection {
+    /// Creates the client of the connections with the endpoint of the API,
+    /// i.e. `https://{api-id}.execute-api.{region}.amazonaws.com/{stage}`.
+    pub fn new(api_endpoint: &str) -> Result<Self> {
+        let region = parse_region(&flock_region())?;
+        Ok(Self {
+            client: ApiGatewayManagementApiClient::new(Region::Custom {
+                name:     region.name().to_string(),
+                endpoint: api_endpoint.trim_end_matches('/').to_string(),
+            }),
+        })
+    }
+}
+
+#[async_trait]
+impl ConnectionClient for ApiGatewayConnection {
+    async fn post_to_connection(&self, connection_id: &str, data: Vec<u8>) -> Result<bool> {
+        match self
+            .client
+            .post_to_connection(PostToConnectionRequest {
+                connection_id: connection_id.to_string(),
+                data:          data.into(),
+            })
+            .await
+        {
+            Ok(()) => Ok(true),
+            Err(RusotoError::Service(PostToConnectionError::Gone(_))) => Ok(false),
+            Err(e) => Err(FlockError::AWS(e.to_string())),
+        }
+    }
+}
+
+/// The storage of the frames that can't be pushed to the client.
+#[async_trait]
+pub trait FrameStore: Send + Sync {
+    /// Stores the frame, and returns its bucket and key.
+    async fn put_frame(
+        &self,
+        query_code: &str,
+        frame_id: &str,
+        data: Vec<u8>,
+    ) -> Result<(String, String)>;
+
+    /// Returns the latest connection id registered by the client.
+    async fn connection_id(&self, query_code: &str) -> Result<Option<String>>;
+}
+
+/// Stores the frames in the S3 bucket of Flock.
+pub struct S3FrameStore;
+
+#[async_trait]
+impl FrameStore for S3FrameStore {
+    async fn put_frame(
+        &self,
+        query_code: &str,
+        frame_id: &str,
+        data: Vec<u8>,
+    ) -> Result<(String, String)> {
+        let key = frame_key(query_code, frame_id);
+        s3::put_object(&FLOCK_S3_BUCKET, &key, data).await?;
+        Ok((FLOCK_S3_BUCKET.clone(), key))
+    }
+
+    async fn connection_id(&self, query_code: &str) -> Result<Option<String>> {
+        let keys = s3::get_matched_keys(&FLOCK_S3_BUCKET, &connection_key(query_code)).await?;
+        if keys.is_empty() {
+            return Ok(None);
+        }
+        let body = s3::get_object(&FLOCK_S3_BUCKET, &connection_key(query_code)).await?;
+        Ok(Some(String::from_utf8_lossy(&body).trim().to_string()))
+    }
+}
+
+/// Pushes the results of the windows to a WebSocket connection.
+pub struct WebSocketSink {
+    connection_id: Mutex<String>,
+    client:        Arc<dyn ConnectionClient>,
+    store:         Arc<dyn FrameStore>,
+}
+
+impl WebSocketSink {
+    /// Creates a WebSocket sink for the connection of the API Gateway
+    /// WebSocket API.
+    pub fn new(api_endpoint: &str, connection_id: &str) -> Result<Self> {
+        Ok(Self::with_clients(
+            connection_id,
+            Arc::new(ApiGatewayConnection::new(api_endpoint)?),
+            Arc::new(S3FrameStore),
+        ))
+    }
+
+    /// Creates a WebSocket sink with the given connection client and frame
+    /// store.
+    pub fn with_clients(
+        connection_id: &str,
+        client: Arc<dyn ConnectionClient>,
+        store: Arc<dyn FrameStore>,
+    ) -> Self {
+        Self {
+            connection_id: Mutex::new(connection_id.to_string()),
+            client,
+            store,
+        }
+    }
+
+    /// Returns the connection id that the frames are pushed to.
+    pub fn connection_id(&self) -> String {
+        self.connection_id.lock().unwrap().clone()
+    }
+
+    /// Pushes the results of a window written by the function to the client.
+    pub async fn push(&self, function_name: &str, batches: &[RecordBatch]) -> Result<Delivery> {
+        let query_code = query_code_of(function_name);
+        let id = Uuid::new_v4().to_string();
+        let frame = Frame::Batches {
+            id:       id.clone(),
+            function: function_name.to_string(),
+            payload:  to_payload(batches, &[], Uuid::default(), true)?,
+        };
+        let data = serde_json::to_vec(&frame)?;
+
+        if data.len() > FLOCK_MAX_FRAME_SIZE {
+            let (bucket, key) = self.store.put_frame(query_code, &id, data).await?;
+            let pointer = Frame::Pointer {
+                id,
+                function: function_name.to_string(),
+                bucket,
+                key,
+            };
+            // The frame is already in S3, so the client collects it even if
+            // the pointer frame is lost.
+            self.post(query_code, serde_json::to_vec(&pointer)?).await?;
+            return Ok(Delivery::Pointer);
+        }
+
+        if self.post(query_code, data.clone()).await? {
+            Ok(Delivery::Frame)
+        } else {
+            warn!(
+                "The connection of {} is gone, the frame {} is stored",
+                query_code, id
+            );
+            self.store.put_frame(query_code, &id, data).await?;
+            Ok(Delivery::Stored)
+        }
+    }
+
+    /// Sends the data to the connection. If the connection is gone, the data
+    /// is sent again to the connection that the client registered after it
+    /// reconnected, if any.
+    async fn post(&self, query_code: &str, data: Vec<u8>) -> Result<bool> {
+        let connection_id = self.connection_id();
+        if self
+            .client
+            .post_to_connection(&connection_id, data.clone())
+            .await?
+        {
+            return Ok(true);
+        }
+        match self.store.connection_id(query_code).await? {
+            Some(latest) if latest != connection_id => {
+                info!("Switch the connection of {} to {}", query_code, latest);
+                *self.connection_id.lock().unwrap() = latest.clone();
+                self.client.post_to_connection(&latest, data).await
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::array::UInt32Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use std::collections::{HashMap, HashSet};
+
+    /// A connection client that records the frames sent to the open
+    /// connections.
+    #[derive(Default)]
+    struct MockConnections {
+        open: Mutex<HashSet<String>>,
+        sent: Mutex<Vec<(String, Vec<u8>)>>,
+    }
+
+    impl MockConnections {
+        fn open(connection_ids: &[&str]) -> Arc<Self> {
+            let connections = Self::default();
+            for id in connection_ids {
+                connections.open.lock().unwrap().insert(id.to_string());
+            }
+            Arc::new(connections)
+        }
+
+        fn frames(&self, connection_id: &str) -> Vec<Frame> {
+            self.sent
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(id, _)| id == connection_id)
+                .map(|(_, data)| serde_json::from_slice(data).unwrap())
+                .collect()
+        }
+    }
+
+    #[async_trait]
+    impl ConnectionClient for MockConnections {
+        async fn post_to_connection(&self, connection_id: &str, data: Vec<u8>) -> Result<bool> {
+            assert!(data.len() <= FLOCK_MAX_FRAME_SIZE);
+            if !self.open.lock().unwrap().contains(connection_id) {
+                return Ok(false);
+            }
+            self.sent
+                .lock()
+                .unwrap()
+                .push((connection_id.to_string(), data));
+            Ok(true)
+        }
+    }
+
+    /// An in-memory frame store.
+    #[derive(Default)]
+    struct MockStore {
+        frames:     Mutex<HashMap<String, Vec<u8>>>,
+        connection: Mutex<Option<String>>,
+    }
+
+    #[async_trait]
+    impl FrameStore for MockStore {
+        async fn put_frame(
+            &self,
+            query_code: &str,
+            frame_id: &str,
+            data: Vec<u8>,
+        ) -> Result<(String, String)> {
+            let key = frame_key(query_code, frame_id);
+            self.frames.lock().unwrap().insert(key.clone(), data);
+            Ok(("mock".to_string(), key))
+        }
+
+        async fn connection_id(&self, _: &str) -> Result<Option<String>> {
+            Ok(self.connection.lock().unwrap().clone())
+        }
+    }
+
+    fn create_batches(num_rows: usize) -> Vec<RecordBatch> {
+        let schema = Arc::new(Schema::new(vec![Field::new("c0", DataType::UInt32, false)]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![Arc::new(UInt32Array::from(
+                (0..num_rows as u32)
+                    .map(|i| i.wrapping_mul(2_654_435_761))
+                    .collect::<Vec<_>>(),
+            ))],
+        )
+        .unwrap();
+        vec![batch]
+    }
+
+    fn num_rows(frame: Frame) -> usize {
+        match frame {
+            Frame::Batches { payload, .. } => payload
+                .to_record_batch()
+                .unwrap()
+                .0
+                .iter()
+                .map(|b| b.num_rows())
+                .sum(),
+            Frame::Pointer { .. } => panic!("unexpected pointer frame"),
+        }
+    }
+
+    #[tokio::test]
+    async fn push_small_results() -> Result<()> {
+        let connections = MockConnections::open(&["abc="]);
+        let store = Arc::new(MockStore::default());
+        let sink = WebSocketSink::with_clients("abc=", connections.clone(), store.clone());
+
+        assert_eq!(
+            sink.push("q1-02", &create_batches(10)).await?,
+            Delivery::Frame
+        );
+        assert_eq!(
+            sink.push("q1-02", &create_batches(20)).await?,
+            Delivery::Frame
+        );
+
+        let frames = connections.frames("abc=");
+        assert_eq!(frames.len(), 2);
+        assert_ne!(frames[0].id(), frames[1].id());
+        assert!(matches!(&frames[0], Frame::Batches { function, .. } if function == "q1-02"));
+        assert_eq!(
+            frames.into_iter().map(num_rows).collect::<Vec<_>>(),
+            vec![10, 20]
+        );
+        assert!(store.frames.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn push_pointer_for_large_results() -> Result<()> {
+        let connections = MockConnections::open(&["abc="]);
+        let store = Arc::new(MockStore::default());
+        let sink = WebSocketSink::with_clients("abc=", connections.clone(), store.clone());
+
+        assert_eq!(
+            sink.push("q1-02", &create_batches(100_000)).await?,
+            Delivery::Pointer
+        );
+
+        let frames = connections.frames("abc=");
+        assert_eq!(frames.len(), 1);
+        match &frames[0] {
+            Frame::Pointer {
+                id, bucket, key, ..
+            } => {
+                assert_eq!(bucket, "mock");
+                assert_eq!(key, &frame_key("q1", id));
+                let stored = store.frames.lock().unwrap()[key].clone();
+                assert!(stored.len() > FLOCK_MAX_FRAME_SIZE);
+                let frame: Frame = serde_json::from_slice(&stored)?;
+                assert_eq!(frame.id(), id);
+                assert_eq!(num_rows(frame), 100_000);
+            }
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn store_frames_of_gone_connections() -> Result<()> {
+        let connections = MockConnections::open(&[]);
+        let store = Arc::new(MockStore::default());
+        let sink = WebSocketSink::with_clients("abc=", connections.clone(), store.clone());
+
+        assert_eq!(
+            sink.push("q1-02", &create_batches(10)).await?,
+            Delivery::Stored
+        );
+        let frames = store.frames.lock().unwrap().clone();
+        assert_eq!(frames.len(), 1);
+        let (key, data) = frames.into_iter().next().unwrap();
+        assert!(key.starts_with(&frames_key_prefix("q1")));
+        assert_eq!(num_rows(serde_json::from_slice(&data)?), 10);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn switch_to_reconnected_client() -> Result<()> {
+        let connections = MockConnections::open(&["def="]);
+        let store = Arc::new(MockStore::default());
+        *store.connection.lock().unwrap() = Some("def=".to_string());
+        let sink = WebSocketSink::with_clients("abc=", connections.clone(), store.clone());
+
+        assert_eq!(
+            sink.push("q1-02", &create_batches(10)).await?,
+            Delivery::Frame
+        );
+        assert_eq!(sink.connection_id(), "def=");
+        assert_eq!(connections.frames("def=").len(), 1);
+        assert!(store.frames.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn frame_format() -> Result<()> {
+        let frame = Frame::Pointer {
+            id:       "1".to_string(),
+            function: "q1-02".to_string(),
+            bucket:   "flock".to_string(),
+            key:      frame_key("q1", "1"),
+        };
+        let value = serde_json::to_value(&frame)?;
+        assert_eq!(value["type"], "pointer");
+        assert_eq!(value["key"], "q1/websocket/frames/1");
+        assert_eq!(serde_json::from_value::<Frame>(value)?, frame);
+        assert_eq!(connection_key("q1"), "q1/websocket/connection");
+        Ok(())
+    }
+}
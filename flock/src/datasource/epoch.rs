@@ -12,6 +12,12 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 //! A simple date time for data sources.
+//!
+//! An [`Epoch`] is a count of milliseconds since the Unix epoch for the event
+//! date times, or the number of the epoch for the generated events. It is an
+//! explicit `u64`, so the arithmetic doesn't depend on the width of `usize`,
+//! and the operators panic on overflow in release builds too instead of
+//! wrapping around silently.
 
 use serde::{Deserialize, Serialize};
 
@@ -19,17 +25,28 @@ use serde::{Deserialize, Serialize};
 #[derive(
     Eq, PartialEq, Ord, PartialOrd, Clone, Serialize, Deserialize, Debug, Hash, Copy, Default,
 )]
-pub struct Epoch(pub usize);
+pub struct Epoch(pub u64);
 
 impl Epoch {
     /// Creates a new date time.
-    pub fn new(date_time: usize) -> Epoch {
+    pub fn new(date_time: u64) -> Epoch {
         Epoch(date_time)
     }
+
+    /// Adds two date times, returning `None` on overflow.
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(Epoch)
+    }
+
+    /// Subtracts two date times, returning `None` if `other` is later than
+    /// `self`.
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Epoch)
+    }
 }
 
 impl ::std::ops::Deref for Epoch {
-    type Target = usize;
+    type Target = u64;
     fn deref(&self) -> &Self::Target {
         &self.0
     }
@@ -39,7 +56,8 @@ impl ::std::ops::Add for Epoch {
     type Output = Self;
 
     fn add(self, other: Self) -> Self {
-        Epoch(self.0 + other.0)
+        self.checked_add(other)
+            .unwrap_or_else(|| panic!("{:?} + {:?} overflows", self, other))
     }
 }
 
@@ -47,6 +65,34 @@ impl ::std::ops::Sub for Epoch {
     type Output = Self;
 
     fn sub(self, other: Self) -> Self {
-        Epoch(self.0 - other.0)
+        self.checked_sub(other)
+            .unwrap_or_else(|| panic!("{:?} - {:?} underflows", self, other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_arithmetic() {
+        let (a, b) = (Epoch::new(1_436_918_400_000), Epoch::new(1_000));
+        assert_eq!(a + b, Epoch::new(1_436_918_401_000));
+        assert_eq!(a - b, Epoch::new(1_436_918_399_000));
+        assert_eq!(Epoch::new(u64::MAX).checked_add(Epoch::new(1)), None);
+        assert_eq!(b.checked_sub(a), None);
+        assert_eq!(b.checked_sub(b), Some(Epoch::default()));
+    }
+
+    #[test]
+    #[should_panic(expected = "overflows")]
+    fn add_overflow_panics() {
+        let _ = Epoch::new(u64::MAX) + Epoch::new(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "underflows")]
+    fn sub_underflow_panics() {
+        let _ = Epoch::new(0) - Epoch::new(1);
     }
 }
@@ -116,7 +116,7 @@ pub fn to_batch(event: KafkaEvent) -> Vec<RecordBatch> {
     }
 
     // transform data to record batch in Arrow
-    let batch_size = 1024;
+    let batch_size = adaptive_batch_size(&input, *FLOCK_SOURCE_BATCH_BYTES);
     let mut reader = json::Reader::new(
         BufReader::with_capacity(input.len(), &input[..]),
         Arc::new(schema),
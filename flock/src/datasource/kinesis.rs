@@ -16,12 +16,14 @@
 
 use aws_lambda_events::event::kinesis::KinesisEvent;
 
+use datafusion::arrow::array::{ArrayRef, StringArray, TimestampMillisecondArray};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
 use datafusion::arrow::json::{self, reader::infer_json_schema};
 use datafusion::arrow::record_batch::RecordBatch;
 
 use crate::prelude::*;
+use log::warn;
 use rayon::prelude::*;
-use rusoto_core::Region;
 use rusoto_kinesis::{DescribeStreamInput, Kinesis, KinesisClient};
 use rusoto_lambda::CreateEventSourceMappingRequest;
 use serde::{Deserialize, Serialize};
@@ -32,9 +34,38 @@ use std::sync::Arc;
 #[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub struct KinesisSource {
     /// The name of the Amazon Kinesis data stream.
-    pub stream_name: String,
+    pub stream_name:      String,
     /// The windows group stream elements by time or rows.
-    pub window:      Window,
+    pub window:           Window,
+    /// If true, the metadata of the Kinesis records are appended to the
+    /// record batches as the columns [`PARTITION_KEY_COLUMN`],
+    /// [`SEQUENCE_NUMBER_COLUMN`] and [`ARRIVAL_TIME_COLUMN`].
+    #[serde(default)]
+    pub metadata_columns: bool,
+}
+
+/// The column of the partition keys of the Kinesis records.
+pub const PARTITION_KEY_COLUMN: &str = "_partition_key";
+
+/// The column of the sequence numbers of the Kinesis records.
+pub const SEQUENCE_NUMBER_COLUMN: &str = "_sequence_number";
+
+/// The column of the approximate arrival timestamps of the Kinesis records.
+pub const ARRIVAL_TIME_COLUMN: &str = "_arrival_time";
+
+/// Returns the schema with the metadata columns of the Kinesis records
+/// appended, which is the schema of the stream's table if the metadata columns
+/// are on.
+pub fn with_metadata_columns(schema: &SchemaRef) -> SchemaRef {
+    let mut fields = schema.fields().clone();
+    fields.push(Field::new(PARTITION_KEY_COLUMN, DataType::Utf8, true));
+    fields.push(Field::new(SEQUENCE_NUMBER_COLUMN, DataType::Utf8, true));
+    fields.push(Field::new(
+        ARRIVAL_TIME_COLUMN,
+        DataType::Timestamp(TimeUnit::Millisecond, None),
+        false,
+    ));
+    Arc::new(Schema::new_with_metadata(fields, schema.metadata().clone()))
 }
 
 impl KinesisSource {
@@ -50,7 +81,7 @@ pub async fn create_event_source_mapping_request(
     function_name: &str,
     window_in_seconds: i64,
 ) -> Result<CreateEventSourceMappingRequest> {
-    let client = KinesisClient::new(Region::default());
+    let client: KinesisClient = new_client("");
     let output = client
         .describe_stream(DescribeStreamInput {
             stream_name: stream_name.to_string(),
@@ -87,43 +118,513 @@ pub async fn create_event_source_mapping_request(
     })
 }
 
+/// The magic bytes at the start of the aggregated records of the Kinesis
+/// Producer Library (KPL).
+pub const KPL_AGGREGATED_RECORD_MAGIC: [u8; 4] = [0xF3, 0x89, 0x9A, 0xC2];
+
+/// The length of the MD5 checksum at the end of the KPL aggregated records.
+const KPL_CHECKSUM_LEN: usize = 16;
+
+/// Expands the aggregated records of the Kinesis Producer Library (KPL) into
+/// their user records.
+///
+/// An aggregated record is the magic bytes, followed by the `AggregatedRecord`
+/// protobuf message and the MD5 checksum of the message. The records without
+/// the magic bytes pass through unchanged, and so do the aggregated records
+/// that fail the checksum or can't be parsed, same as the KPL deaggregation
+/// modules.
+///
+/// <https://github.com/awslabs/amazon-kinesis-producer/blob/master/aggregation-format.md>
+pub fn deaggregate(records: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    records
+        .into_iter()
+        .flat_map(|record| {
+            if !record.starts_with(&KPL_AGGREGATED_RECORD_MAGIC)
+                || record.len() < KPL_AGGREGATED_RECORD_MAGIC.len() + KPL_CHECKSUM_LEN
+            {
+                return vec![record];
+            }
+
+            let (message, checksum) = record[KPL_AGGREGATED_RECORD_MAGIC.len()..]
+                .split_at(record.len() - KPL_AGGREGATED_RECORD_MAGIC.len() - KPL_CHECKSUM_LEN);
+            if md5::compute(message).0 != checksum {
+                warn!("The checksum of the KPL aggregated record doesn't match.");
+                return vec![record];
+            }
+
+            // message AggregatedRecord {
+            //   repeated string partition_key_table     = 1;
+            //   repeated string explicit_hash_key_table = 2;
+            //   repeated Record records                 = 3;
+            // }
+            //
+            // message Record {
+            //   required uint64 partition_key_index     = 1;
+            //   optional uint64 explicit_hash_key_index = 2;
+            //   required bytes  data                    = 3;
+            //   repeated Tag    tags                    = 4;
+            // }
+            let user_records = protobuf_fields(message, 3).and_then(|records| {
+                records
+                    .into_iter()
+                    .map(|r| {
+                        protobuf_fields(r, 3)?
+                            .pop()
+                            .map(|data| data.to_vec())
+                            .ok_or_else(|| {
+                                FlockError::Internal("The KPL user record has no data.".to_string())
+                            })
+                    })
+                    .collect::<Result<Vec<_>>>()
+            });
+            match user_records {
+                Ok(user_records) => user_records,
+                Err(e) => {
+                    warn!("Failed to parse the KPL aggregated record: {}", e);
+                    vec![record]
+                }
+            }
+        })
+        .collect()
+}
+
+/// Returns the length-delimited fields with the given field number in the
+/// protobuf message.
+fn protobuf_fields(mut message: &[u8], field_number: u64) -> Result<Vec<&[u8]>> {
+    let mut fields = vec![];
+    while !message.is_empty() {
+        let key = protobuf_varint(&mut message)?;
+        let len = match key & 0x07 {
+            0 => {
+                protobuf_varint(&mut message)?;
+                0
+            }
+            1 => 8,
+            2 => protobuf_varint(&mut message)? as usize,
+            5 => 4,
+            wire_type => {
+                return Err(FlockError::Internal(format!(
+                    "Unsupported protobuf wire type: {}",
+                    wire_type
+                )))
+            }
+        };
+        if len > message.len() {
+            return Err(FlockError::Internal(
+                "The protobuf message is truncated.".to_string(),
+            ));
+        }
+        let (value, rest) = message.split_at(len);
+        if key >> 3 == field_number && key & 0x07 == 2 {
+            fields.push(value);
+        }
+        message = rest;
+    }
+    Ok(fields)
+}
+
+/// Reads a base 128 varint from the protobuf message.
+fn protobuf_varint(message: &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    for (i, byte) in message.iter().enumerate().take(10) {
+        value |= ((byte & 0x7F) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            *message = &message[i + 1..];
+            return Ok(value);
+        }
+    }
+    Err(FlockError::Internal(
+        "Failed to parse the protobuf varint.".to_string(),
+    ))
+}
+
 /// Converts Kinesis event to record batch in Arrow.
-pub fn to_batch(event: KinesisEvent) -> Vec<RecordBatch> {
+///
+/// The aggregated records of the Kinesis Producer Library are expanded into
+/// their user records first, see [`deaggregate`].
+///
+/// # Arguments
+/// * `event` - The Kinesis event.
+/// * `metadata_columns` - If true, the partition key, the sequence number and
+///   the approximate arrival timestamp of the Kinesis record are appended to
+///   each row parsed from it, see [`with_metadata_columns`].
+pub fn to_batch(event: KinesisEvent, metadata_columns: bool) -> Vec<RecordBatch> {
+    let mut partition_keys = vec![];
+    let mut sequence_numbers = vec![];
+    let mut arrival_times = vec![];
+    let mut records = vec![];
+    for r in event.records {
+        let blobs = deaggregate(vec![r.kinesis.data.0]);
+        if metadata_columns {
+            // A blob may hold several JSON lines, and the blank lines are
+            // skipped by the JSON reader.
+            let rows = blobs
+                .iter()
+                .flat_map(|blob| blob.split(|b| *b == b'\n'))
+                .filter(|line| line.iter().any(|b| !b.is_ascii_whitespace()))
+                .count();
+            let arrival_time = r.kinesis.approximate_arrival_timestamp.0.timestamp_millis();
+            for _ in 0..rows {
+                partition_keys.push(r.kinesis.partition_key.clone());
+                sequence_numbers.push(r.kinesis.sequence_number.clone());
+                arrival_times.push(arrival_time);
+            }
+        }
+        records.extend(blobs);
+    }
+
+    // The aggregated records may hold no user records at all.
+    if records.is_empty() {
+        return vec![];
+    }
+
     // infer schema based on the first record
-    let record: &[u8] = &event.records[0].kinesis.data.0.clone();
+    let record: &[u8] = &records[0];
     let mut reader = BufReader::new(record);
     let schema = Arc::new(infer_json_schema(&mut reader, Some(1)).unwrap());
 
-    // The default batch size when using the
-    // [`ReaderBuilder`](json::Reader::ReaderBuilder) is 1024 records
-    let batch_size = 1024;
-    let input: &[u8] = &event
-        .records
+    let input: &[u8] = &records
         .into_par_iter()
         .flat_map(|r| {
-            r.kinesis
-                .data
-                .0
-                .into_iter()
+            r.into_iter()
                 .chain(vec![10].into_iter())
                 .collect::<Vec<_>>()
         })
         .collect::<Vec<_>>();
+    let batch_size = adaptive_batch_size(input, *FLOCK_SOURCE_BATCH_BYTES);
 
     // transform data to record batch in Arrow
     reader = BufReader::with_capacity(input.len(), input);
-    let mut reader = json::Reader::from_buf_reader(reader, schema, batch_size, None);
+    let mut reader = json::Reader::from_buf_reader(reader, schema.clone(), batch_size, None);
 
     let mut batches = vec![];
     while let Some(batch) = reader.next().unwrap() {
         batches.push(batch);
     }
+    if !metadata_columns {
+        return batches;
+    }
+
+    let metadata: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from(partition_keys)),
+        Arc::new(StringArray::from(sequence_numbers)),
+        Arc::new(TimestampMillisecondArray::from(arrival_times)),
+    ];
+    let schema = with_metadata_columns(&schema);
+    let mut offset = 0;
     batches
+        .into_iter()
+        .map(|batch| {
+            let mut columns = batch.columns().to_vec();
+            columns.extend(
+                metadata
+                    .iter()
+                    .map(|array| array.slice(offset, batch.num_rows())),
+            );
+            offset += batch.num_rows();
+            RecordBatch::try_new(schema.clone(), columns).unwrap()
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use aws_lambda_events::encodings::Base64Data;
+    use datafusion::arrow::array::Int64Array;
+    use datafusion::arrow::datatypes::DataType;
+    use datafusion::datasource::MemTable;
+
+    /// Encodes a base 128 varint.
+    fn varint(mut value: u64) -> Vec<u8> {
+        let mut bytes = vec![];
+        loop {
+            if value < 0x80 {
+                bytes.push(value as u8);
+                return bytes;
+            }
+            bytes.push((value & 0x7F) as u8 | 0x80);
+            value >>= 7;
+        }
+    }
+
+    /// Encodes a length-delimited protobuf field.
+    fn field(field_number: u64, value: &[u8]) -> Vec<u8> {
+        let mut bytes = varint(field_number << 3 | 2);
+        bytes.extend(varint(value.len() as u64));
+        bytes.extend(value);
+        bytes
+    }
+
+    /// Builds a KPL aggregated record of the given user records.
+    fn aggregate(user_records: &[&str]) -> Vec<u8> {
+        let mut message = field(1, b"partition-key");
+        for data in user_records {
+            // partition_key_index = 0
+            let mut record = varint(1 << 3);
+            record.extend(varint(0));
+            record.extend(field(3, data.as_bytes()));
+            message.extend(field(3, &record));
+        }
+
+        let mut bytes = KPL_AGGREGATED_RECORD_MAGIC.to_vec();
+        bytes.extend(&message);
+        bytes.extend(&md5::compute(&message).0);
+        bytes
+    }
+
+    #[test]
+    fn deaggregate_kpl_records() {
+        let user_records = [r#"{"a": 1}"#, r#"{"a": 2}"#, r#"{"a": 3}"#];
+        let records = deaggregate(vec![
+            br#"{"a": 0}"#.to_vec(),
+            aggregate(&user_records),
+            br#"{"a": 4}"#.to_vec(),
+        ]);
+        assert_eq!(
+            records,
+            vec![
+                br#"{"a": 0}"#.to_vec(),
+                br#"{"a": 1}"#.to_vec(),
+                br#"{"a": 2}"#.to_vec(),
+                br#"{"a": 3}"#.to_vec(),
+                br#"{"a": 4}"#.to_vec(),
+            ]
+        );
+
+        // The records failing the checksum pass through unchanged.
+        let mut corrupted = aggregate(&user_records);
+        let len = corrupted.len();
+        corrupted[len - 1] ^= 0xFF;
+        assert_eq!(deaggregate(vec![corrupted.clone()]), vec![corrupted]);
+
+        // An aggregated record may have no user records at all.
+        assert!(deaggregate(vec![aggregate(&[])]).is_empty());
+    }
+
+    /// Builds a Kinesis event of the given JSON records.
+    fn kinesis_event(records: Vec<String>) -> KinesisEvent {
+        let data = include_bytes!("../tests/data/example-kinesis-event.json");
+        let mut event: KinesisEvent = serde_json::from_slice(data).unwrap();
+        let template = event.records[0].clone();
+        event.records = records
+            .into_iter()
+            .map(|record| {
+                let mut r = template.clone();
+                r.kinesis.data = Base64Data(record.into_bytes());
+                r
+            })
+            .collect();
+        event
+    }
+
+    /// Checks that the full batches hold as many rows of `width` bytes as fit
+    /// in the target batch size.
+    fn assert_batch_bytes(batches: &[RecordBatch], num_rows: usize, width: usize) {
+        let target = *FLOCK_SOURCE_BATCH_BYTES;
+        let rows = batches[0].num_rows();
+        assert!(rows * width <= target && (rows + 1) * width > target);
+        assert!(batches[..batches.len() - 1]
+            .iter()
+            .all(|b| b.num_rows() == rows));
+        assert!(batches.last().unwrap().num_rows() <= rows);
+        assert_eq!(
+            num_rows,
+            batches.iter().map(|b| b.num_rows()).sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn kinesis_adaptive_batch_size() {
+        // Narrow records of 3 integer columns.
+        let narrow = (100_000..200_000)
+            .map(|i| format!(r#"{{"a":{},"b":{},"c":{}}}"#, i, i, i))
+            .collect::<Vec<_>>();
+        let width = narrow[0].len() + 1;
+        let batches = to_batch(kinesis_event(narrow), false);
+        assert_eq!(3, batches[0].num_columns());
+        assert!(batches.len() > 1);
+        assert_batch_bytes(&batches, 100_000, width);
+
+        // Wide records of 50 string columns.
+        let wide = (0..1_000)
+            .map(|i| {
+                let columns = (0..50)
+                    .map(|c| format!(r#""c{:02}":"{:0100}""#, c, i))
+                    .collect::<Vec<_>>();
+                format!("{{{}}}", columns.join(","))
+            })
+            .collect::<Vec<_>>();
+        let width = wide[0].len() + 1;
+        let batches = to_batch(kinesis_event(wide), false);
+        assert_eq!(50, batches[0].num_columns());
+        assert!(batches.len() > 1);
+        assert_batch_bytes(&batches, 1_000, width);
+    }
+
+    #[test]
+    fn kinesis_event_with_empty_aggregated_records() {
+        let data = include_bytes!("../tests/data/example-kinesis-event.json");
+        let mut event: KinesisEvent = serde_json::from_slice(data).unwrap();
+        event
+            .records
+            .iter_mut()
+            .for_each(|r| r.kinesis.data = Base64Data(aggregate(&[])));
+
+        assert!(to_batch(event.clone(), false).is_empty());
+        assert!(to_batch(event, true).is_empty());
+    }
+
+    #[test]
+    fn kinesis_event_with_aggregated_records() {
+        let data = include_bytes!("../tests/data/example-kinesis-event.json");
+        let mut event: KinesisEvent = serde_json::from_slice(data).unwrap();
+        event.records[0].kinesis.data = Base64Data(aggregate(&[
+            r#"{"a": 1, "b": "x"}"#,
+            r#"{"a": 2, "b": "y"}"#,
+        ]));
+        event.records[1].kinesis.data = Base64Data(br#"{"a": 3, "b": "z"}"#.to_vec());
+
+        let batches = to_batch(event, false);
+        assert_eq!(1, batches.len());
+        assert_eq!(3, batches[0].num_rows());
+        assert_eq!(2, batches[0].num_columns());
+
+        let a = batches[0]
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(a.values(), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn kinesis_event_with_metadata_columns() -> Result<()> {
+        let data = include_bytes!("../tests/data/example-kinesis-event.json");
+        let mut event: KinesisEvent = serde_json::from_slice(data).unwrap();
+        let mut record = event.records[1].clone();
+        record.kinesis.partition_key = Some("s2".to_string());
+        record.kinesis.sequence_number = Some("3".to_string());
+        event.records.push(record);
+
+        // The first record expands to three rows: two KPL user records, one
+        // of which has two lines. The last record has a blank line.
+        event.records[0].kinesis.data =
+            Base64Data(aggregate(&[r#"{"a": 1}"#, "{\"a\": 2}\n{\"a\": 3}"]));
+        event.records[1].kinesis.data = Base64Data(br#"{"a": 4}"#.to_vec());
+        event.records[2].kinesis.data = Base64Data(b"{\"a\": 5}\n\n{\"a\": 6}\n".to_vec());
+
+        let batches = to_batch(event.clone(), true);
+        assert_eq!(1, batches.len());
+        let schema = batches[0].schema();
+        assert_eq!(
+            schema.as_ref(),
+            with_metadata_columns(&Arc::new(Schema::new(vec![Field::new(
+                "a",
+                DataType::Int64,
+                true
+            )])))
+            .as_ref()
+        );
+
+        let expected = vec![
+            "+---+----------------+----------------------------------------------------------+-------------------------+",
+            "| a | _partition_key | _sequence_number                                         | _arrival_time           |",
+            "+---+----------------+----------------------------------------------------------+-------------------------+",
+            "| 1 | s1             | 49568167373333333333333333333333333333333333333333333333 | 2016-12-02 01:18:43.477 |",
+            "| 2 | s1             | 49568167373333333333333333333333333333333333333333333333 | 2016-12-02 01:18:43.477 |",
+            "| 3 | s1             | 49568167373333333333333333333333333333333333333333333333 | 2016-12-02 01:18:43.477 |",
+            "| 4 | s1             | 49568167373333333334444444444444444444444444444444444444 | 2016-12-04 08:52:03.477 |",
+            "| 5 | s2             | 3                                                        | 2016-12-04 08:52:03.477 |",
+            "| 6 | s2             | 3                                                        | 2016-12-04 08:52:03.477 |",
+            "+---+----------------+----------------------------------------------------------+-------------------------+",
+        ];
+        crate::assert_batches_eq!(&expected, &batches);
+
+        // The metadata columns stay aligned across the batches of the reader.
+        let mut event = event;
+        event.records.truncate(1);
+        event.records[0].kinesis.data = Base64Data(
+            (0..1500)
+                .map(|i| format!("{{\"a\": {}}}", i))
+                .collect::<Vec<_>>()
+                .join("\n")
+                .into_bytes(),
+        );
+        let batches = to_batch(event, true);
+        assert_eq!(2, batches.len());
+        assert_eq!(476, batches[1].num_rows());
+        assert_eq!(
+            "s1",
+            batches[1]
+                .column(1)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap()
+                .value(475)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kinesis_event_with_nested_records() -> Result<()> {
+        let data = include_bytes!("../tests/data/example-kinesis-event.json");
+        let mut event: KinesisEvent = serde_json::from_slice(data).unwrap();
+        event.records[0].kinesis.data = Base64Data(
+            br#"{"id": 1, "address": {"city": "Boston", "zip": "02110"}, "tags": ["a", "b"]}"#
+                .to_vec(),
+        );
+        event.records[1].kinesis.data = Base64Data(
+            br#"{"id": 2, "address": {"city": "Austin", "zip": "73301"}, "tags": ["c"]}"#.to_vec(),
+        );
+
+        let batches = to_batch(event, false);
+        let schema = batches[0].schema();
+        assert!(matches!(
+            schema.field_with_name("address")?.data_type(),
+            DataType::Struct(_)
+        ));
+        assert!(matches!(
+            schema.field_with_name("tags")?.data_type(),
+            DataType::List(_)
+        ));
+
+        // The nested records are sent to the next function in the payload.
+        let uuid = UuidBuilder::new_with_ts("q1-00", 1, 1).next_uuid();
+        let payload = to_payload(&batches, &[], uuid, false)?;
+        let payload: Payload = serde_json::from_slice(&serde_json::to_vec(&payload)?)?;
+        let (batches, _) = payload.to_record_batch()?;
+
+        let mut ctx = datafusion::execution::context::ExecutionContext::new();
+        let table = MemTable::try_new(schema.clone(), vec![vec![RecordBatch::new_empty(schema)]])?;
+        ctx.register_table("t", Arc::new(table))?;
+        let plan = ctx
+            .sql("SELECT id, address['city'] AS city FROM t ORDER BY id")
+            .await?
+            .to_logical_plan();
+        let plan = ctx.optimize(&plan)?;
+        let plan = ctx.create_physical_plan(&plan).await?;
+
+        let ctx = ExecutionContext {
+            plan: CloudExecutionPlan::new(vec![plan], None),
+            name: "q1-00".to_string(),
+            next: CloudFunction::Sink(DataSinkType::Blackhole),
+            ..Default::default()
+        };
+        ctx.feed_data_sources(vec![vec![batches]]).await?;
+        let output = ctx.execute().await?;
+
+        let expected = vec![
+            "+----+--------+",
+            "| id | city   |",
+            "+----+--------+",
+            "| 1  | Boston |",
+            "| 2  | Austin |",
+            "+----+--------+",
+        ];
+        crate::assert_batches_eq!(&expected, &output[0]);
+        Ok(())
+    }
 
     #[test]
     #[ignore]
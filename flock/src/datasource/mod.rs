@@ -13,9 +13,14 @@
 
 //! A data source is the location where data that is being used originates from.
 
+#[cfg(feature = "kafka")]
 use self::kafka::KafkaSource;
+#[cfg(feature = "kinesis")]
 use self::kinesis::KinesisSource;
+#[cfg(feature = "nexmark")]
 use self::nexmark::NEXMarkSource;
+use self::s3::S3ObjectsSource;
+#[cfg(feature = "ysb")]
 use self::ysb::YSBSource;
 use crate::error::Result;
 use crate::runtime::payload::{Payload, Uuid};
@@ -58,21 +63,28 @@ pub trait DataStream {
 }
 
 /// A Data Source for either stream processing or batch processing.
+///
+/// The variants of the data sources that are not enabled by the cargo features
+/// are compiled out, which keeps the function binary small.
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub enum DataSource {
     /// Amazon Kinesis Data Streams (KDS) is a massively scalable and durable
     /// real-time data streaming service.
+    #[cfg(feature = "kinesis")]
     KinesisEvent(KinesisSource),
     /// Apache Kafka is a community distributed event streaming platform capable
     /// of handling trillions of events a day.
+    #[cfg(feature = "kafka")]
     KafkaEvent(KafkaSource),
     /// Nexmark is a suite of pipelines inspired by the continuous data stream
     /// queries, which includes multiple queries over a three entities model
     /// representing on online auction system.
     /// We use Nexmark benchmark to measure the performance of our system.
+    #[cfg(feature = "nexmark")]
     NEXMarkEvent(NEXMarkSource),
     /// The Yahoo Streaming Benchmark is a well-known benchmark used in industry
     /// to evaluate streaming systems.
+    #[cfg(feature = "ysb")]
     YSBEvent(YSBSource),
     /// Amazon Simple Queue Service (SQS) is a fully managed message queuing
     /// service that enables you to decouple and scale microservices,
@@ -101,7 +113,11 @@ pub enum DataSource {
     /// Data source for unit tests.
     Json,
     /// AWS S3 for baseline benchmark.
+    #[cfg(feature = "nexmark")]
     S3(NEXMarkSource),
+    /// The objects under a prefix of an S3 bucket, e.g. the historical logs
+    /// stored as gzip-compressed newline-delimited JSON.
+    S3Objects(S3ObjectsSource),
     /// Data source from the local memory.
     Memory,
     /// benchmarking on x86_64 and arm64.
@@ -118,6 +134,7 @@ impl Default for DataSource {
 
 impl DataSource {
     /// Return Kinesis type with default settings.
+    #[cfg(feature = "kinesis")]
     pub fn kinesis() -> Self {
         DataSource::KinesisEvent(KinesisSource::default())
     }
@@ -125,8 +142,14 @@ impl DataSource {
 
 pub mod config;
 pub mod epoch;
+#[cfg(feature = "kafka")]
 pub mod kafka;
+#[cfg(feature = "kinesis")]
 pub mod kinesis;
+#[cfg(feature = "nexmark")]
 pub mod nexmark;
+pub mod s3;
+#[cfg(feature = "tpch")]
 pub mod tpch;
+#[cfg(feature = "ysb")]
 pub mod ysb;
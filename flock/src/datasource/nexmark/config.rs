@@ -17,7 +17,7 @@ use crate::datasource::config::Config;
 use std::f64::consts::PI;
 
 /// Base time unit for the NexMark benchmark.
-pub const BASE_TIME: usize = 1_436_918_400_000;
+pub const BASE_TIME: u64 = 1_436_918_400_000;
 
 fn split_string_arg(string: String) -> Vec<String> {
     string.split(',').map(String::from).collect::<Vec<String>>()
@@ -57,7 +57,7 @@ pub struct NEXMarkConfig {
     /// the event number is used to determine the event timestamp.
     pub first_event_number:      usize,
     /// Time for first event (ms since epoch).
-    pub base_time:               usize,
+    pub base_time:               u64,
     /// Delay before changing the current inter-event delay.
     pub step_length:             usize,
     /// Number of events per epoch.
@@ -66,11 +66,11 @@ pub struct NEXMarkConfig {
     pub events_per_epoch:        usize,
     /// True period of epoch in milliseconds. Derived from above. (Ie time to
     /// run through cycle for all interEventDelayUs entries).
-    pub epoch_period:            f32,
+    pub epoch_period:            u64,
     /// Delay between events, in microseconds.
     /// If the array has more than one entry then the rate is changed every
     /// step_length, and wraps around.
-    pub inter_event_delays:      Vec<f32>,
+    pub inter_event_delays:      Vec<f64>,
     // Originally constants
     /// Auction categories.
     pub num_categories:          usize,
@@ -114,6 +114,9 @@ pub struct NEXMarkConfig {
     /// Number of event generators to use. Each generates events in its own
     /// timeline.
     pub num_event_generators:    usize,
+    /// The seed mixed into the per-event random number generators. The events
+    /// are reproducible for the same seed.
+    pub seed:                    u64,
 }
 
 impl NEXMarkConfig {
@@ -142,6 +145,7 @@ impl NEXMarkConfig {
         let person_id_lead = config.get_as_or("person-id-lead", 10);
         let sine_approx_steps = config.get_as_or("sine-approx-steps", 10);
         let base_time = config.get_as_or("base-time", BASE_TIME);
+        let seed = config.get_as_or("seed", 0);
         let us_states = split_string_arg(config.get_or("us-states", "az,ca,id,or,wa,wy"));
         let us_cities = split_string_arg(config.get_or(
             "us-cities",
@@ -167,10 +171,10 @@ impl NEXMarkConfig {
         );
         let next_rate = config.get_as_or("next-event-rate", first_rate);
         let us_per_unit = config.get_as_or("us-per-unit", 1_000_000); // Rate is in μs
-        let generators = config.get_as_or("threads", 1) as f32;
+        let generators = config.get_as_or("threads", 1) as f64;
         // Calculate inter event delays array.
         let mut inter_event_delays = Vec::new();
-        let rate_to_period = |r| (us_per_unit) as f32 / r as f32;
+        let rate_to_period = |r| (us_per_unit) as f64 / r as f64;
         if first_rate == next_rate {
             inter_event_delays.push(rate_to_period(first_rate) * generators);
         } else {
@@ -197,14 +201,13 @@ impl NEXMarkConfig {
             sine_approx_steps
         };
         let step_length = (rate_period + n - 1) / n;
+        // Every cycle lasts exactly `step_length` seconds, whatever its rate.
         let mut events_per_epoch = 0;
-        let mut epoch_period = 0.0;
+        let mut epoch_period = 0;
         if inter_event_delays.len() > 1 {
             for inter_event_delay in &inter_event_delays {
-                let num_events_for_this_cycle =
-                    (step_length * 1_000_000) as f32 / inter_event_delay;
-                events_per_epoch += num_events_for_this_cycle.round() as usize;
-                epoch_period += (num_events_for_this_cycle * inter_event_delay) / 1000.0;
+                events_per_epoch += events_in_cycle(step_length, *inter_event_delay);
+                epoch_period += step_length as u64 * 1000;
             }
         }
         NEXMarkConfig {
@@ -241,34 +244,51 @@ impl NEXMarkConfig {
             first_names,
             last_names,
             num_event_generators: generators as usize,
+            seed,
         }
     }
 
-    /// Returns a new event timestamp.
-    pub fn event_timestamp(&self, event_number: usize) -> usize {
-        if self.inter_event_delays.len() == 1 {
-            return self.base_time
-                + ((event_number as f32 * self.inter_event_delays[0]) / 1000.0).round() as usize;
-        }
-
-        let epoch = event_number / self.events_per_epoch;
-        let mut event_i = event_number % self.events_per_epoch;
-        let mut offset_in_epoch = 0.0;
-        for inter_event_delay in &self.inter_event_delays {
-            let num_events_for_this_cycle =
-                (self.step_length * 1_000_000) as f32 / inter_event_delay;
-            if self.out_of_order_group_size < num_events_for_this_cycle.round() as usize {
-                let offset_in_cycle = event_i as f32 * inter_event_delay;
-                return self.base_time
-                    + (epoch as f32 * self.epoch_period
-                        + offset_in_epoch
-                        + offset_in_cycle / 1000.0)
-                        .round() as usize;
+    /// Returns a new event timestamp, in milliseconds since the Unix epoch.
+    ///
+    /// The timestamps are non-decreasing in the event number for any
+    /// configuration. The epochs and the cycles start at whole milliseconds,
+    /// so only the offset of the event in its cycle is computed in floating
+    /// point.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the timestamp doesn't fit into `u64` milliseconds.
+    pub fn event_timestamp(&self, event_number: usize) -> u64 {
+        let offset = if self.inter_event_delays.len() == 1 {
+            Some(offset_in_cycle(event_number, self.inter_event_delays[0]))
+        } else {
+            let events_per_epoch = self.events_per_epoch.max(1);
+            let epoch = (event_number / events_per_epoch) as u64;
+            let mut event_i = event_number % events_per_epoch;
+            // If no cycle has any event, every event starts the next epoch.
+            let mut offset_in_epoch = self.epoch_period;
+            for (cycle, inter_event_delay) in self.inter_event_delays.iter().enumerate() {
+                let num_events_for_this_cycle =
+                    events_in_cycle(self.step_length, *inter_event_delay);
+                if event_i < num_events_for_this_cycle {
+                    offset_in_epoch = (cycle * self.step_length) as u64 * 1000
+                        + offset_in_cycle(event_i, *inter_event_delay);
+                    break;
+                }
+                event_i -= num_events_for_this_cycle;
             }
-            event_i -= num_events_for_this_cycle.round() as usize;
-            offset_in_epoch += (num_events_for_this_cycle * inter_event_delay) / 1000.0;
-        }
-        0
+            epoch
+                .checked_mul(self.epoch_period)
+                .and_then(|t| t.checked_add(offset_in_epoch))
+        };
+        offset
+            .and_then(|t| self.base_time.checked_add(t))
+            .unwrap_or_else(|| {
+                panic!(
+                    "The timestamp of the event {} overflows u64 milliseconds",
+                    event_number
+                )
+            })
     }
 
     /// Returns the next adjusted event.
@@ -279,9 +299,21 @@ impl NEXMarkConfig {
     }
 }
 
+/// Returns the number of events in a cycle of `step_length` seconds.
+fn events_in_cycle(step_length: usize, inter_event_delay: f64) -> usize {
+    ((step_length * 1_000_000) as f64 / inter_event_delay).round() as usize
+}
+
+/// Returns the offset of the `event_i`-th event in its cycle, in milliseconds.
+/// The offset of the last event rounds to at most the length of the cycle.
+fn offset_in_cycle(event_i: usize, inter_event_delay: f64) -> u64 {
+    ((event_i as f64 * inter_event_delay) / 1000.0).round() as u64
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
     use std::io::Result;
 
     #[test]
@@ -329,4 +361,113 @@ mod tests {
         nexmark_cfg.event_timestamp(2048);
         nexmark_cfg.next_adjusted_event(100000);
     }
+
+    fn nexmark_config(args: &[(&str, &str)]) -> NEXMarkConfig {
+        let mut config = Config::new();
+        args.iter()
+            .for_each(|(k, v)| config.insert(k, v.to_string()));
+        NEXMarkConfig::new(&config)
+    }
+
+    #[test]
+    fn event_timestamp_goldens() {
+        // 10,000 events per second on a single generator.
+        let nex = nexmark_config(&[]);
+        assert_eq!(nex.event_timestamp(0), BASE_TIME);
+        assert_eq!(nex.event_timestamp(5), BASE_TIME + 1);
+        assert_eq!(nex.event_timestamp(15), BASE_TIME + 2);
+        assert_eq!(nex.event_timestamp(2048), BASE_TIME + 205);
+
+        let nex = nexmark_config(&[("threads", "8")]);
+        assert_eq!(nex.event_timestamp(2048), BASE_TIME + 1638);
+
+        // The first cycle of the sine wave runs at 10,000 events per second for
+        // 60 seconds, and the second one at 9,094 events per second.
+        let nex = nexmark_config(&[("next-event-rate", "512")]);
+        assert_eq!(nex.events_per_epoch, 3_153_600);
+        assert_eq!(nex.epoch_period, 600_000);
+        assert_eq!(nex.event_timestamp(2048), BASE_TIME + 205);
+        assert_eq!(nex.event_timestamp(599_999), BASE_TIME + 60_000);
+        assert_eq!(nex.event_timestamp(600_000), BASE_TIME + 60_000);
+        assert_eq!(nex.event_timestamp(600_100), BASE_TIME + 60_011);
+
+        let nex = nexmark_config(&[("next-event-rate", "512"), ("rate-shape", "square")]);
+        assert_eq!(nex.events_per_epoch, 3_153_600);
+        assert_eq!(nex.epoch_period, 600_000);
+        assert_eq!(nex.event_timestamp(2048), BASE_TIME + 205);
+        assert_eq!(nex.event_timestamp(3_000_000), BASE_TIME + 300_000);
+        assert_eq!(nex.event_timestamp(3_000_010), BASE_TIME + 300_020);
+        assert_eq!(nex.event_timestamp(3_153_599), BASE_TIME + 599_998);
+        assert_eq!(nex.event_timestamp(3_153_600), BASE_TIME + 600_000);
+        assert_eq!(nex.event_timestamp(3_153_610), BASE_TIME + 600_001);
+    }
+
+    #[test]
+    fn event_timestamp_precision() {
+        // f32 can't tell these event numbers apart.
+        let nex = nexmark_config(&[]);
+        let n = 10_000_000_000;
+        assert_eq!(nex.event_timestamp(n), BASE_TIME + 1_000_000_000);
+        assert_eq!(nex.event_timestamp(n + 7), BASE_TIME + 1_000_000_001);
+        assert_eq!(nex.event_timestamp(n + 10), BASE_TIME + 1_000_000_001);
+
+        let nex = nexmark_config(&[("next-event-rate", "512"), ("rate-shape", "square")]);
+        let epoch = n / nex.events_per_epoch;
+        let start = epoch * nex.events_per_epoch;
+        assert_eq!(
+            nex.event_timestamp(start),
+            BASE_TIME + epoch as u64 * 600_000
+        );
+        assert_eq!(
+            nex.event_timestamp(start + 3_000_010),
+            BASE_TIME + epoch as u64 * 600_000 + 300_020
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "overflows u64 milliseconds")]
+    fn event_timestamp_overflow() {
+        nexmark_config(&[("first-event-rate", "1")]).event_timestamp(usize::MAX);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn event_timestamps_are_non_decreasing(
+            first_rate in 1..100_000usize,
+            next_rate in 1..100_000usize,
+            threads in 1..16usize,
+            rate_period in 1..1_200usize,
+            square in any::<bool>(),
+            mut events in prop::collection::vec(0..10_000_000_000usize, 1..32),
+        ) {
+            let nex = nexmark_config(&[
+                ("first-event-rate", &first_rate.to_string()),
+                ("next-event-rate", &next_rate.to_string()),
+                ("threads", &threads.to_string()),
+                ("rate-period", &rate_period.to_string()),
+                ("rate-shape", if square { "square" } else { "sine" }),
+            ]);
+            // Adds the events around the cycle and epoch boundaries.
+            let events_per_epoch = nex.events_per_epoch.max(1);
+            let mut boundaries = vec![0];
+            for delay in &nex.inter_event_delays {
+                let last = boundaries[boundaries.len() - 1];
+                boundaries.push(last + events_in_cycle(nex.step_length, *delay));
+            }
+            for e in events.clone() {
+                let start = e / events_per_epoch * events_per_epoch;
+                for b in &boundaries {
+                    events.extend([(start + b).saturating_sub(1), start + b, start + b + 1]);
+                }
+            }
+            events.sort_unstable();
+
+            let timestamps = events.iter().map(|e| nex.event_timestamp(*e)).collect::<Vec<_>>();
+            for (e, t) in events.windows(2).zip(timestamps.windows(2)) {
+                prop_assert!(t[0] <= t[1], "events {:?} have timestamps {:?}", e, t);
+            }
+        }
+    }
 }
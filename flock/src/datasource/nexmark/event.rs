@@ -15,13 +15,19 @@
 
 use crate::datasource::epoch::Epoch;
 use crate::datasource::nexmark::config::NEXMarkConfig;
+use crate::error::{FlockError, Result};
+use datafusion::arrow::array::{ArrayRef, Int32Array, StringArray, TimestampMillisecondArray};
 use datafusion::arrow::datatypes::{DataType, Field, Schema, TimeUnit};
+use datafusion::arrow::record_batch::RecordBatch;
 use rand::rngs::SmallRng;
 use rand::seq::SliceRandom;
 use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use std::cmp::{max, min};
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::sync::Arc;
 
 const MIN_STRING_LENGTH: usize = 3;
 
@@ -86,7 +92,7 @@ impl Event {
         let id = nex.first_event_id
             + nex.next_adjusted_event(events_so_far)
             + (100_000 / nex.num_event_generators) * sub_idx;
-        let mut rng = SmallRng::seed_from_u64(id as u64);
+        let mut rng = SmallRng::seed_from_u64(id as u64 ^ nex.seed);
         if rem < nex.person_proportion {
             Event::Person(Person::new(id, timestamp, &mut rng, nex))
         } else if rem < nex.person_proportion + nex.auction_proportion {
@@ -305,8 +311,8 @@ impl Auction {
             (nex.in_flight_auctions * nex.proportion_denominator) / nex.auction_proportion;
         let future_auction = nex.event_timestamp(current_event + events_for_auctions);
 
-        let horizon = future_auction - time.0;
-        Epoch(1 + rng.gen_range(0..max(horizon * 2, 1)))
+        let horizon = future_auction.saturating_sub(time.0);
+        Epoch(1 + rng.gen_range(0..max(horizon.saturating_mul(2), 1)))
     }
 }
 
@@ -384,6 +390,197 @@ pub fn side_input_schema() -> Schema {
     )
 }
 
+/// Returns the schema of the maximum bid price of a window, which is the input
+/// of the second stage and the side input of the last stage of the broadcast
+/// join in NEXMark Q7.
+pub fn max_price_schema() -> Schema {
+    let mut metadata = HashMap::new();
+    metadata.insert("name".to_string(), "max_price".to_string());
+    Schema::new_with_metadata(
+        vec![Field::new("maxprice", DataType::Int32, true)],
+        metadata,
+    )
+}
+
+/// Converts an id, a price or a timestamp to the value of the Arrow column.
+fn to_column<T: TryFrom<U>, U: Copy + Display>(value: U, field: &str) -> Result<T> {
+    T::try_from(value)
+        .map_err(|_| FlockError::Internal(format!("The {} {} is out of range.", field, value)))
+}
+
+/// Converts the value of the Arrow column to an id, a price or a timestamp.
+fn from_column<T: Copy + Into<i64>, U: TryFrom<i64>>(value: T, field: &str) -> Result<U> {
+    let value = value.into();
+    U::try_from(value)
+        .map_err(|_| FlockError::Internal(format!("The {} {} is negative.", field, value)))
+}
+
+/// Returns the column of the record batch by its name.
+fn column<'a, T: 'static>(batch: &'a RecordBatch, name: &str) -> Result<&'a T> {
+    batch
+        .column(batch.schema().index_of(name)?)
+        .as_any()
+        .downcast_ref::<T>()
+        .ok_or_else(|| FlockError::Internal(format!("The column {} has a wrong type.", name)))
+}
+
+/// Converts the `Person` events to a record batch with `Person`'s schema.
+pub fn persons_to_batch(persons: &[Person]) -> Result<RecordBatch> {
+    let strings = |f: fn(&Person) -> &str| -> ArrayRef {
+        Arc::new(StringArray::from(persons.iter().map(f).collect::<Vec<_>>()))
+    };
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(Int32Array::from(
+            persons
+                .iter()
+                .map(|p| to_column(p.p_id, "p_id"))
+                .collect::<Result<Vec<i32>>>()?,
+        )),
+        strings(|p| p.name.as_str()),
+        strings(|p| p.email_address.as_str()),
+        strings(|p| p.credit_card.as_str()),
+        strings(|p| p.city.as_str()),
+        strings(|p| p.state.as_str()),
+        Arc::new(TimestampMillisecondArray::from(
+            persons
+                .iter()
+                .map(|p| to_column(*p.p_date_time, "p_date_time"))
+                .collect::<Result<Vec<i64>>>()?,
+        )),
+    ];
+    Ok(RecordBatch::try_new(Arc::new(Person::schema()), columns)?)
+}
+
+/// Reads the `Person` events back from a record batch with `Person`'s schema.
+pub fn persons_from_batch(batch: &RecordBatch) -> Result<Vec<Person>> {
+    let p_id = column::<Int32Array>(batch, "p_id")?;
+    let name = column::<StringArray>(batch, "name")?;
+    let email_address = column::<StringArray>(batch, "email_address")?;
+    let credit_card = column::<StringArray>(batch, "credit_card")?;
+    let city = column::<StringArray>(batch, "city")?;
+    let state = column::<StringArray>(batch, "state")?;
+    let p_date_time = column::<TimestampMillisecondArray>(batch, "p_date_time")?;
+    (0..batch.num_rows())
+        .map(|i| {
+            Ok(Person {
+                p_id:          from_column(p_id.value(i), "p_id")?,
+                name:          name.value(i).to_owned(),
+                email_address: email_address.value(i).to_owned(),
+                credit_card:   credit_card.value(i).to_owned(),
+                city:          city.value(i).to_owned(),
+                state:         state.value(i).to_owned(),
+                p_date_time:   Epoch(from_column(p_date_time.value(i), "p_date_time")?),
+            })
+        })
+        .collect()
+}
+
+/// Converts the `Auction` events to a record batch with `Auction`'s schema.
+pub fn auctions_to_batch(auctions: &[Auction]) -> Result<RecordBatch> {
+    let ints = |f: fn(&Auction) -> usize, field: &str| -> Result<ArrayRef> {
+        Ok(Arc::new(Int32Array::from(
+            auctions
+                .iter()
+                .map(|a| to_column(f(a), field))
+                .collect::<Result<Vec<i32>>>()?,
+        )))
+    };
+    let times = |f: fn(&Auction) -> Epoch, field: &str| -> Result<ArrayRef> {
+        Ok(Arc::new(TimestampMillisecondArray::from(
+            auctions
+                .iter()
+                .map(|a| to_column(*f(a), field))
+                .collect::<Result<Vec<i64>>>()?,
+        )))
+    };
+    let strings = |f: fn(&Auction) -> &str| -> ArrayRef {
+        Arc::new(StringArray::from(
+            auctions.iter().map(f).collect::<Vec<_>>(),
+        ))
+    };
+    let columns = vec![
+        ints(|a| a.a_id, "a_id")?,
+        strings(|a| a.item_name.as_str()),
+        strings(|a| a.description.as_str()),
+        ints(|a| a.initial_bid, "initial_bid")?,
+        ints(|a| a.reserve, "reserve")?,
+        times(|a| a.a_date_time, "a_date_time")?,
+        times(|a| a.expires, "expires")?,
+        ints(|a| a.seller, "seller")?,
+        ints(|a| a.category, "category")?,
+    ];
+    Ok(RecordBatch::try_new(Arc::new(Auction::schema()), columns)?)
+}
+
+/// Reads the `Auction` events back from a record batch with `Auction`'s
+/// schema.
+pub fn auctions_from_batch(batch: &RecordBatch) -> Result<Vec<Auction>> {
+    let a_id = column::<Int32Array>(batch, "a_id")?;
+    let item_name = column::<StringArray>(batch, "item_name")?;
+    let description = column::<StringArray>(batch, "description")?;
+    let initial_bid = column::<Int32Array>(batch, "initial_bid")?;
+    let reserve = column::<Int32Array>(batch, "reserve")?;
+    let a_date_time = column::<TimestampMillisecondArray>(batch, "a_date_time")?;
+    let expires = column::<TimestampMillisecondArray>(batch, "expires")?;
+    let seller = column::<Int32Array>(batch, "seller")?;
+    let category = column::<Int32Array>(batch, "category")?;
+    (0..batch.num_rows())
+        .map(|i| {
+            Ok(Auction {
+                a_id:        from_column(a_id.value(i), "a_id")?,
+                item_name:   item_name.value(i).to_owned(),
+                description: description.value(i).to_owned(),
+                initial_bid: from_column(initial_bid.value(i), "initial_bid")?,
+                reserve:     from_column(reserve.value(i), "reserve")?,
+                a_date_time: Epoch(from_column(a_date_time.value(i), "a_date_time")?),
+                expires:     Epoch(from_column(expires.value(i), "expires")?),
+                seller:      from_column(seller.value(i), "seller")?,
+                category:    from_column(category.value(i), "category")?,
+            })
+        })
+        .collect()
+}
+
+/// Converts the `Bid` events to a record batch with `Bid`'s schema.
+pub fn bids_to_batch(bids: &[Bid]) -> Result<RecordBatch> {
+    let ints = |f: fn(&Bid) -> usize, field: &str| -> Result<ArrayRef> {
+        Ok(Arc::new(Int32Array::from(
+            bids.iter()
+                .map(|b| to_column(f(b), field))
+                .collect::<Result<Vec<i32>>>()?,
+        )))
+    };
+    let columns = vec![
+        ints(|b| b.auction, "auction")?,
+        ints(|b| b.bidder, "bidder")?,
+        ints(|b| b.price, "price")?,
+        Arc::new(TimestampMillisecondArray::from(
+            bids.iter()
+                .map(|b| to_column(*b.b_date_time, "b_date_time"))
+                .collect::<Result<Vec<i64>>>()?,
+        )),
+    ];
+    Ok(RecordBatch::try_new(Arc::new(Bid::schema()), columns)?)
+}
+
+/// Reads the `Bid` events back from a record batch with `Bid`'s schema.
+pub fn bids_from_batch(batch: &RecordBatch) -> Result<Vec<Bid>> {
+    let auction = column::<Int32Array>(batch, "auction")?;
+    let bidder = column::<Int32Array>(batch, "bidder")?;
+    let price = column::<Int32Array>(batch, "price")?;
+    let b_date_time = column::<TimestampMillisecondArray>(batch, "b_date_time")?;
+    (0..batch.num_rows())
+        .map(|i| {
+            Ok(Bid {
+                auction:     from_column(auction.value(i), "auction")?,
+                bidder:      from_column(bidder.value(i), "bidder")?,
+                price:       from_column(price.value(i), "price")?,
+                b_date_time: Epoch(from_column(b_date_time.value(i), "b_date_time")?),
+            })
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -431,4 +628,46 @@ mod tests {
         println!("{:?}", Auction::schema());
         println!("{:?}", Bid::schema());
     }
+
+    #[test]
+    fn convert_events_to_batches() -> Result<()> {
+        let mut config = Config::new();
+        config.insert("person-proportion", "30".to_string());
+        config.insert("auction-proportion", "30".to_string());
+        config.insert("bid-proportion", "40".to_string());
+
+        let mut nex = NEXMarkConfig::new(&config);
+        let (mut persons, mut auctions, mut bids) = (vec![], vec![], vec![]);
+        (0..100).for_each(|i| match Event::new(i, 0, &mut nex) {
+            Event::Person(p) => persons.push(p),
+            Event::Auction(a) => auctions.push(a),
+            Event::Bid(b) => bids.push(b),
+        });
+
+        let batch = persons_to_batch(&persons)?;
+        assert_eq!(batch.num_rows(), persons.len());
+        assert_eq!(persons_from_batch(&batch)?, persons);
+
+        let batch = auctions_to_batch(&auctions)?;
+        assert_eq!(batch.num_rows(), auctions.len());
+        assert_eq!(auctions_from_batch(&batch)?, auctions);
+
+        let batch = bids_to_batch(&bids)?;
+        assert_eq!(batch.num_rows(), bids.len());
+        assert_eq!(bids_from_batch(&batch)?, bids);
+
+        // The ids must fit in the Int32 columns.
+        let bid = Bid {
+            auction:     usize::MAX,
+            bidder:      1,
+            price:       100,
+            b_date_time: Epoch(0),
+        };
+        assert!(bids_to_batch(&[bid]).is_err());
+
+        // The schema of the record batch must match the event type.
+        assert!(persons_from_batch(&batch).is_err());
+
+        Ok(())
+    }
 }
@@ -29,6 +29,9 @@ pub struct NEXMarkGenerator {
     pub events:  usize,
     /// How long an experiment is supposed to run.
     pub seconds: usize,
+    /// The tables whose events are shipped. The events of the other tables are
+    /// still generated to keep the event ids and timestamps, but dropped.
+    pub tables:  Vec<String>,
 }
 
 impl NEXMarkGenerator {
@@ -38,6 +41,11 @@ impl NEXMarkGenerator {
             config:  NEXMarkConfig::new(config),
             events:  0,
             seconds: config.get_as_or("seconds", 60),
+            tables:  config
+                .get_or("tables", "person,auction,bid")
+                .split(',')
+                .map(String::from)
+                .collect(),
         }
     }
 
@@ -67,6 +75,9 @@ impl NEXMarkGenerator {
         let mut p_num = 0;
         let mut a_num = 0;
         let mut b_num = 0;
+        let has_table = |table: &str| self.tables.iter().any(|t| t == table);
+        let (persons, auctions, bids) =
+            (has_table("person"), has_table("auction"), has_table("bid"));
         loop {
             let time = self
                 .config
@@ -74,24 +85,25 @@ impl NEXMarkGenerator {
             let next_epoch = (time - self.config.base_time) / 1000;
             let event = Event::new(self.events, p, &mut self.config);
 
-            if next_epoch < self.seconds && next_epoch == epoch {
+            if next_epoch < self.seconds as u64 && next_epoch == epoch {
                 self.events += 1;
                 match event {
-                    Event::Person(person) => {
+                    Event::Person(person) if persons => {
                         p_buf.extend(serde_json::to_vec(&person).unwrap());
                         p_buf.extend(vec![10]);
                         p_num += 1;
                     }
-                    Event::Auction(auction) => {
+                    Event::Auction(auction) if auctions => {
                         a_buf.extend(serde_json::to_vec(&auction).unwrap());
                         a_buf.extend(vec![10]);
                         a_num += 1;
                     }
-                    Event::Bid(bid) => {
+                    Event::Bid(bid) if bids => {
                         b_buf.extend(serde_json::to_vec(&bid).unwrap());
                         b_buf.extend(vec![10]);
                         b_num += 1;
                     }
+                    _ => {}
                 }
             } else {
                 break;
@@ -104,6 +116,43 @@ impl NEXMarkGenerator {
         ))
     }
 
+    /// Returns the epoch of the event with the given number of events before
+    /// it.
+    fn epoch_of(&self, events: usize) -> u64 {
+        (self
+            .config
+            .event_timestamp(events + self.config.first_event_id)
+            - self.config.base_time)
+            / 1000
+    }
+
+    /// Skips the events before the given epoch without generating them, so the
+    /// next epoch produced is the given one. The event timestamps only depend
+    /// on the event numbers, so the skipped generator produces the same events
+    /// as one that walked through the earlier epochs.
+    pub fn skip_to_epoch(&mut self, epoch: usize) {
+        let epoch = epoch as u64;
+        if self.epoch_of(self.events) >= epoch {
+            return;
+        }
+        // Finds the first event of the epoch by a binary search over the event
+        // numbers, whose timestamps are non-decreasing.
+        let (mut lo, mut hi) = (self.events, self.events.max(1));
+        while self.epoch_of(hi) < epoch {
+            lo = hi;
+            hi *= 2;
+        }
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.epoch_of(mid) < epoch {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        self.events = lo;
+    }
+
     /// Produces the events in the next epoch (for testing).
     pub fn next(&mut self, p: usize) -> Result<(Epoch, Vec<Event>)> {
         let mut data = Vec::with_capacity((1000.0 / self.config.inter_event_delays[0]) as usize);
@@ -120,7 +169,7 @@ impl NEXMarkGenerator {
             let next_epoch = (time - self.config.base_time) / 1000;
             let event = Event::new(self.events, p, &mut self.config);
 
-            if next_epoch < self.seconds && next_epoch == epoch {
+            if next_epoch < self.seconds as u64 && next_epoch == epoch {
                 self.events += 1;
                 data.push(event);
             } else {
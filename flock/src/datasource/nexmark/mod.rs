@@ -74,18 +74,22 @@ pub mod nexmark;
 mod queries;
 
 pub use self::config::NEXMarkConfig;
-pub use self::event::{side_input_schema, Auction, Bid, Person};
-pub use self::nexmark::{NEXMarkEvent, NEXMarkSource, NEXMarkStream};
+pub use self::event::{
+    auctions_from_batch, auctions_to_batch, bids_from_batch, bids_to_batch, max_price_schema,
+    persons_from_batch, persons_to_batch, side_input_schema, Auction, Bid, Person,
+};
+pub use self::nexmark::{
+    GeneratorEvents, NEXMarkEpochs, NEXMarkEvent, NEXMarkLazyStream, NEXMarkSource, NEXMarkStream,
+};
 use crate::configs::FLOCK_TARGET_PARTITIONS;
 use crate::error::Result;
+use crate::query::{register_stream, Table};
 use datafusion::arrow::datatypes::Schema;
-use datafusion::arrow::record_batch::RecordBatch;
-use datafusion::datasource::MemTable;
 use datafusion::execution::context::{ExecutionConfig, ExecutionContext};
 use std::sync::Arc;
 
 /// The NEXMark tables.
-pub const NEXMARK_TABLES: &[&str] = &["person", "auction", "bid", "side_input"];
+pub const NEXMARK_TABLES: &[&str] = &["person", "auction", "bid", "side_input", "max_price"];
 
 /// Get the schema for a given NEXMark table.
 pub fn get_nexmark_schema(table: &str) -> Schema {
@@ -94,44 +98,54 @@ pub fn get_nexmark_schema(table: &str) -> Schema {
         "auction" => Auction::schema(),
         "bid" => Bid::schema(),
         "side_input" => side_input_schema(),
+        "max_price" => max_price_schema(),
         _ => unimplemented!(),
     }
 }
 
+/// Returns the NEXMark tables referenced by the given query.
+///
+/// The data source generators only ship the events of these tables, and the
+/// other tables are not registered for the query, so that no empty leaves are
+/// left in the query plan.
+pub fn nexmark_tables_for_query(query_number: usize) -> &'static [&'static str] {
+    match query_number {
+        0 | 1 | 2 | 5 | 10 | 11 | 12 => &["bid"],
+        7 => &["bid", "max_price"],
+        3 | 8 => &["person", "auction"],
+        4 | 6 | 9 => &["auction", "bid"],
+        13 => &["bid", "side_input"],
+        _ => NEXMARK_TABLES,
+    }
+}
+
+/// Returns the NEXMark query whose relations are the given tables, so that a
+/// query that isn't one of the benchmark queries is sent the events of its
+/// tables in the same layout.
+pub fn nexmark_query_for_tables(tables: &[&str]) -> Option<usize> {
+    let mut tables = tables.to_vec();
+    tables.sort_unstable();
+    tables.dedup();
+    (0..=13).find(|query_number| {
+        let mut relations = nexmark_tables_for_query(*query_number).to_vec();
+        relations.sort_unstable();
+        relations == tables
+    })
+}
+
+/// Register a NEXMark table with empty data.
+fn register_nexmark_table(ctx: &mut ExecutionContext, table: &str) -> Result<()> {
+    register_stream(ctx, table, Arc::new(get_nexmark_schema(table)))
+}
+
 /// Register the NEXMark tables with empty data.
 pub async fn register_nexmark_tables_with_config(
     config: ExecutionConfig,
 ) -> Result<ExecutionContext> {
     let mut ctx = ExecutionContext::with_config(config);
-    let person_schema = Arc::new(Person::schema());
-    let person_table = MemTable::try_new(
-        person_schema.clone(),
-        vec![vec![RecordBatch::new_empty(person_schema)]],
-    )?;
-    ctx.register_table("person", Arc::new(person_table))?;
-
-    let auction_schema = Arc::new(Auction::schema());
-    let auction_table = MemTable::try_new(
-        auction_schema.clone(),
-        vec![vec![RecordBatch::new_empty(auction_schema)]],
-    )?;
-    ctx.register_table("auction", Arc::new(auction_table))?;
-
-    let bid_schema = Arc::new(Bid::schema());
-    let bid_table = MemTable::try_new(
-        bid_schema.clone(),
-        vec![vec![RecordBatch::new_empty(bid_schema)]],
-    )?;
-    ctx.register_table("bid", Arc::new(bid_table))?;
-
-    // For NEXMark Q13
-    let side_input_schema = Arc::new(side_input_schema());
-    let side_input_table = MemTable::try_new(
-        side_input_schema.clone(),
-        vec![vec![RecordBatch::new_empty(side_input_schema)]],
-    )?;
-    ctx.register_table("side_input", Arc::new(side_input_table))?;
-
+    for table in NEXMARK_TABLES {
+        register_nexmark_table(&mut ctx, table)?;
+    }
     Ok(ctx)
 }
 
@@ -140,3 +154,108 @@ pub async fn register_nexmark_tables() -> Result<ExecutionContext> {
     let config = ExecutionConfig::new().with_target_partitions(*FLOCK_TARGET_PARTITIONS);
     register_nexmark_tables_with_config(config).await
 }
+
+/// Register the NEXMark tables referenced by the given query with empty data.
+pub async fn register_nexmark_tables_for_query_with_config(
+    config: ExecutionConfig,
+    query_number: usize,
+) -> Result<ExecutionContext> {
+    let mut ctx = ExecutionContext::with_config(config);
+    for table in nexmark_tables_for_query(query_number) {
+        register_nexmark_table(&mut ctx, table)?;
+    }
+    Ok(ctx)
+}
+
+/// Register the NEXMark tables with empty data together with the user tables,
+/// e.g. the replayed results of a query, whose names must differ from the
+/// NEXMark tables and each other.
+pub async fn register_nexmark_tables_with_user_tables(
+    tables: &[Table],
+) -> Result<ExecutionContext> {
+    let mut ctx = register_nexmark_tables().await?;
+    for table in tables {
+        register_stream(&mut ctx, &table.0, table.1.clone())?;
+    }
+    Ok(ctx)
+}
+
+/// Register the NEXMark tables referenced by the given query with empty data.
+pub async fn register_nexmark_tables_for_query(query_number: usize) -> Result<ExecutionContext> {
+    let config = ExecutionConfig::new().with_target_partitions(*FLOCK_TARGET_PARTITIONS);
+    register_nexmark_tables_for_query_with_config(config, query_number).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::plan::{physical_plan, stream_name, PlanProperties};
+    use datafusion::physical_plan::displayable;
+
+    #[tokio::test]
+    async fn register_tables_for_query() -> Result<()> {
+        for (query_number, sql) in [
+            (
+                1,
+                "SELECT auction, bidder, 0.908 * price AS price, b_date_time FROM bid",
+            ),
+            (2, "SELECT auction, price FROM bid WHERE auction % 123 = 0"),
+        ] {
+            let ctx = register_nexmark_tables_for_query(query_number).await?;
+            assert!(ctx.table("bid").is_ok());
+            assert!(ctx.table("person").is_err());
+            assert!(ctx.table("auction").is_err());
+            assert!(ctx.table("side_input").is_err());
+
+            // The query plan is the same as the one with all tables registered.
+            let plan = physical_plan(&ctx, sql).await?;
+            let expected = physical_plan(&register_nexmark_tables().await?, sql).await?;
+            assert_eq!(
+                displayable(plan.as_ref()).indent().to_string(),
+                displayable(expected.as_ref()).indent().to_string()
+            );
+        }
+
+        let ctx = register_nexmark_tables_for_query(13).await?;
+        assert!(ctx.table("side_input").is_ok());
+
+        let ctx = register_nexmark_tables_for_query(7).await?;
+        assert!(ctx.table("max_price").is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn query_for_tables() {
+        assert_eq!(nexmark_query_for_tables(&["bid"]), Some(0));
+        assert_eq!(
+            nexmark_query_for_tables(&["bid", "auction", "bid"]),
+            Some(4)
+        );
+        assert_eq!(nexmark_query_for_tables(&["auction", "person"]), Some(3));
+        assert_eq!(nexmark_query_for_tables(&["replay"]), None);
+    }
+
+    #[tokio::test]
+    async fn register_user_tables() -> Result<()> {
+        // The replayed bids keep the stream name of the NEXMark bids in their
+        // schema, which is replaced by the name of the user table.
+        let replay = Table::new("replay", Arc::new(Bid::schema()));
+        let ctx = register_nexmark_tables_with_user_tables(&[replay.clone()]).await?;
+        let plan = physical_plan(&ctx, "SELECT auction FROM replay").await?;
+        let leaves = PlanProperties::analyze(&plan).leaf_schemas;
+        assert_eq!(stream_name(&leaves[0]), Some("replay"));
+
+        // The stream names must be unique.
+        let bid = Table::new("bid", Arc::new(Bid::schema()));
+        assert!(register_nexmark_tables_with_user_tables(&[bid])
+            .await
+            .is_err());
+        assert!(
+            register_nexmark_tables_with_user_tables(&[replay.clone(), replay])
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
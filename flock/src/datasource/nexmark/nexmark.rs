@@ -18,6 +18,7 @@ use crate::datasource::config::Config;
 use crate::datasource::epoch::Epoch;
 use crate::datasource::nexmark::event::{Auction, Bid, Person};
 use crate::datasource::nexmark::generator::NEXMarkGenerator;
+use crate::datasource::nexmark::nexmark_tables_for_query;
 use crate::datasource::DataStream;
 use crate::datasource::RelationPartitions;
 use crate::error::FlockError;
@@ -30,10 +31,10 @@ use datafusion::arrow::record_batch::RecordBatch;
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use log::info;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use std::thread;
 
 lazy_static! {
     static ref NEXMARK_BID: SchemaRef = Arc::new(Bid::schema());
@@ -51,6 +52,10 @@ type SourceId = usize;
 type Partition = usize;
 type NumEvents = usize;
 
+/// The encoded Person, Auction and Bid events produced by a generator in an
+/// epoch, along with the number of events of each table.
+pub type GeneratorEvents = ((Vec<u8>, usize), (Vec<u8>, usize), (Vec<u8>, usize));
+
 /// A struct to temporarily store three types of events along with the
 /// timelines.
 #[derive(Debug, Default)]
@@ -107,7 +112,7 @@ impl NEXMarkStream {
             source,
             ..Default::default()
         };
-        let epoch = Epoch::new(time);
+        let epoch = Epoch::new(time as u64);
 
         let mut num_persons = 0;
         if let Some(map) = self.persons.get(&epoch) {
@@ -259,19 +264,19 @@ impl DataStream for NEXMarkStream {
                 &[],
                 uuid,
                 sync,
-            ),
+            )?,
             3 | 8 => to_payload(
                 &event_bytes_to_batch(&event.persons, NEXMARK_PERSON.clone(), batch_size),
                 &event_bytes_to_batch(&event.auctions, NEXMARK_AUCTION.clone(), batch_size),
                 uuid,
                 sync,
-            ),
+            )?,
             4 | 6 | 9 => to_payload(
                 &event_bytes_to_batch(&event.auctions, NEXMARK_AUCTION.clone(), batch_size),
                 &event_bytes_to_batch(&event.bids, NEXMARK_BID.clone(), batch_size),
                 uuid,
                 sync,
-            ),
+            )?,
             _ => unimplemented!(),
         };
         payload.query_number = query_number;
@@ -310,6 +315,12 @@ impl NEXMarkSource {
         NEXMarkSource { config, window }
     }
 
+    /// Only ships the events of the tables referenced by the given query.
+    pub fn select_tables_for_query(&mut self, query_number: usize) {
+        self.config
+            .insert("tables", nexmark_tables_for_query(query_number).join(","));
+    }
+
     /// Assigns each event with the specific type for the upcoming processing.
     fn assgin_events(
         events: &mut NEXMarkStream,
@@ -354,6 +365,9 @@ impl NEXMarkSource {
     }
 
     /// Generates data events for Nexmark benchmark.
+    ///
+    /// All epochs are kept in memory. Use [`NEXMarkSource::stream_epochs`] to
+    /// generate the events epoch by epoch instead.
     pub fn generate_data(&self) -> Result<NEXMarkStream> {
         let partitions: usize = self.config.get_as_or("threads", 100);
         let seconds: usize = self.config.get_as_or("seconds", 10);
@@ -363,29 +377,30 @@ impl NEXMarkSource {
             seconds, partitions
         );
 
-        let generator = NEXMarkGenerator::new(&self.config);
-        let events_handle = Arc::new(Mutex::new(NEXMarkStream::new()));
-
-        let mut threads = vec![];
-        for p in 0..partitions {
-            let mut generator = generator.clone();
-            let events_handle = Arc::clone(&events_handle);
-            threads.push(thread::spawn(move || loop {
-                let (t, d) = generator.next_epoch(p).unwrap();
-                if !((d.0).0.is_empty() && (d.1).0.is_empty() && (d.2).0.is_empty()) {
-                    let mut events = events_handle.lock().unwrap();
-                    NEXMarkSource::assgin_events(&mut events, t, p, d.0, d.1, d.2);
-                } else {
-                    break;
-                }
-            }));
-        }
-        for t in threads.drain(..) {
-            t.join().unwrap();
+        let mut events = NEXMarkStream::new();
+        for (t, epoch) in self.stream_epochs(self.config.get_as_or("seed", 0)) {
+            for (p, (persons, auctions, bids)) in epoch.into_iter().enumerate() {
+                NEXMarkSource::assgin_events(&mut events, t, p, persons, auctions, bids);
+            }
         }
 
-        let mut events = events_handle.lock().unwrap();
-        Ok(std::mem::take(&mut events))
+        Ok(events)
+    }
+
+    /// Generates the events lazily, one epoch at a time. The generators of an
+    /// epoch run in parallel, and only the epoch being yielded is held in
+    /// memory.
+    ///
+    /// The events are deterministic for the given seed, so the epochs are the
+    /// same as the ones collected by [`NEXMarkSource::generate_data`] with the
+    /// `seed` option.
+    pub fn stream_epochs(&self, seed: u64) -> NEXMarkEpochs {
+        let partitions: usize = self.config.get_as_or("threads", 100);
+        let mut generator = NEXMarkGenerator::new(&self.config);
+        generator.config.seed = seed;
+        NEXMarkEpochs {
+            generators: vec![generator; partitions],
+        }
     }
 
     /// Counts the number of events. (for testing)
@@ -398,19 +413,25 @@ impl NEXMarkSource {
                     .map(|s| {
                         events
                             .persons
-                            .get(&Epoch::new(s))
+                            .get(&Epoch::new(s as u64))
                             .unwrap()
                             .get(&p)
                             .unwrap()
                             .1
                             + events
                                 .auctions
-                                .get(&Epoch::new(s))
+                                .get(&Epoch::new(s as u64))
+                                .unwrap()
+                                .get(&p)
+                                .unwrap()
+                                .1
+                            + events
+                                .bids
+                                .get(&Epoch::new(s as u64))
                                 .unwrap()
                                 .get(&p)
                                 .unwrap()
                                 .1
-                            + events.bids.get(&Epoch::new(s)).unwrap().get(&p).unwrap().1
                     })
                     .sum::<usize>()
             })
@@ -418,6 +439,124 @@ impl NEXMarkSource {
     }
 }
 
+/// An iterator over the NEXMark epochs, created by
+/// [`NEXMarkSource::stream_epochs`]. Each item holds the events of every
+/// generator in the epoch, indexed by the generator id.
+#[derive(Clone)]
+pub struct NEXMarkEpochs {
+    generators: Vec<NEXMarkGenerator>,
+}
+
+impl NEXMarkEpochs {
+    /// Starts the epochs at the given epoch instead of the first one. The
+    /// earlier epochs are skipped without generating their events.
+    pub fn starting_at(mut self, epoch: usize) -> Self {
+        self.generators
+            .iter_mut()
+            .for_each(|generator| generator.skip_to_epoch(epoch));
+        self
+    }
+}
+
+impl Iterator for NEXMarkEpochs {
+    type Item = (Epoch, Vec<GeneratorEvents>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let seconds = self.generators.first()?.seconds;
+        let mut epochs = self
+            .generators
+            .par_iter_mut()
+            .enumerate()
+            .map(|(p, generator)| generator.next_epoch(p).unwrap())
+            .collect::<Vec<_>>();
+
+        // The events of the unused tables are dropped, so the epoch is empty only
+        // if the generators run out of time, which they do at the same epoch as
+        // they share the same timeline.
+        let t = epochs[0].0;
+        if *t >= seconds as u64 {
+            self.generators.clear();
+            return None;
+        }
+
+        Some((t, epochs.drain(..).map(|(_, events)| events).collect()))
+    }
+}
+
+/// A [`DataStream`] that generates the NEXMark epochs on demand instead of
+/// upfront. Only the `retain` most recent epochs are kept, so the epochs must
+/// be selected in ascending order, which is how the window launchers walk the
+/// stream.
+pub struct NEXMarkLazyStream {
+    state:  Mutex<(NEXMarkEpochs, NEXMarkStream)>,
+    retain: usize,
+}
+
+impl NEXMarkLazyStream {
+    /// Creates a lazy stream over the given epochs.
+    pub fn new(epochs: NEXMarkEpochs, retain: usize) -> Self {
+        NEXMarkLazyStream {
+            state:  Mutex::new((epochs, NEXMarkStream::new())),
+            retain: retain.max(1),
+        }
+    }
+
+    /// Generates the epochs up to the given time, and evicts the ones that fall
+    /// behind the retained window.
+    fn advance(&self, time: usize) -> std::sync::MutexGuard<(NEXMarkEpochs, NEXMarkStream)> {
+        let mut state = self.state.lock().unwrap();
+        let (epochs, events) = &mut *state;
+
+        let mut next = events.bids.keys().map(|t| **t + 1).max().unwrap_or(0);
+        while next <= time as u64 {
+            match epochs.next() {
+                Some((t, epoch)) => {
+                    for (p, (persons, auctions, bids)) in epoch.into_iter().enumerate() {
+                        NEXMarkSource::assgin_events(events, t, p, persons, auctions, bids);
+                    }
+                    next = *t + 1;
+                }
+                None => break,
+            }
+        }
+
+        let retain = self.retain;
+        let keep = |t: &Epoch| **t + retain as u64 > time as u64;
+        events.persons.retain(|t, _| keep(t));
+        events.auctions.retain(|t, _| keep(t));
+        events.bids.retain(|t, _| keep(t));
+
+        state
+    }
+}
+
+impl DataStream for NEXMarkLazyStream {
+    fn select_event_to_payload(
+        &self,
+        time: usize,
+        generator: usize,
+        query_number: Option<usize>,
+        uuid: Uuid,
+        sync: bool,
+    ) -> Result<Payload> {
+        self.advance(time)
+            .1
+            .select_event_to_payload(time, generator, query_number, uuid, sync)
+    }
+
+    fn select_event_to_batches(
+        &self,
+        time: usize,
+        generator: usize,
+        query_number: Option<usize>,
+        sync: bool,
+    ) -> Result<(RelationPartitions, RelationPartitions)> {
+        self.advance(time)
+            .1
+            .select_event_to_batches(time, generator, query_number, sync)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -453,6 +592,90 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn generate_tables_for_query() -> Result<()> {
+        for query_number in [1, 2] {
+            let mut nex = NEXMarkSource::new(2, 4, 1_000, Window::ElementWise);
+            let all = nex.generate_data()?;
+
+            nex.select_tables_for_query(query_number);
+            let events = nex.generate_data()?;
+
+            let bytes = |events: &NEXMarkStream| {
+                [&events.persons, &events.auctions, &events.bids]
+                    .iter()
+                    .flat_map(|m| m.values().flat_map(|s| s.values().map(|(b, _)| b.len())))
+                    .sum::<usize>()
+            };
+            assert!(bytes(&events) < bytes(&all));
+
+            // The bids are the same as the ones shipped with all tables.
+            for time in 0..2 {
+                for source in 0..4 {
+                    let (expected, (_, _, num_bids)) = all.select(time, source).unwrap();
+                    let (event, nums) = events.select(time, source).unwrap();
+                    assert!(event.persons.is_empty() && event.auctions.is_empty());
+                    assert_eq!(event.bids, expected.bids);
+                    assert_eq!(nums, (0, 0, num_bids));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn stream_epochs() -> Result<()> {
+        let mut nex = NEXMarkSource::new(3, 4, 1_000, Window::ElementWise);
+        nex.config.insert("seed", 7.to_string());
+        let events = nex.generate_data()?;
+
+        // The streamed epochs are the same as the collected ones.
+        let mut epochs = 0;
+        for (t, epoch) in nex.stream_epochs(7) {
+            assert_eq!(epoch.len(), 4);
+            for (p, (persons, auctions, bids)) in epoch.into_iter().enumerate() {
+                let (expected, nums) = events.select(*t as usize, p).unwrap();
+                assert_eq!(persons.0, expected.persons);
+                assert_eq!(auctions.0, expected.auctions);
+                assert_eq!(bids.0, expected.bids);
+                assert_eq!((persons.1, auctions.1, bids.1), nums);
+            }
+            epochs += 1;
+        }
+        assert_eq!(epochs, 3);
+
+        // The seed changes the events, but not the number of them.
+        let (t, a) = nex.stream_epochs(7).next().unwrap();
+        let (u, b) = nex.stream_epochs(8).next().unwrap();
+        assert_eq!(t, u);
+        assert_ne!(a[0].2 .0, b[0].2 .0);
+        assert_eq!(a[0].2 .1, b[0].2 .1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn lazy_stream() -> Result<()> {
+        let nex = NEXMarkSource::new(5, 2, 1_000, Window::ElementWise);
+        let events = nex.generate_data()?;
+        let stream = NEXMarkLazyStream::new(nex.stream_epochs(0), 2);
+
+        for time in 0..5 {
+            for generator in 0..2 {
+                let (a, _) = stream.select_event_to_batches(time, generator, Some(1), true)?;
+                let (b, _) = events.select_event_to_batches(time, generator, Some(1), true)?;
+                assert_eq!(
+                    pretty_format_batches(&a.concat())?.to_string(),
+                    pretty_format_batches(&b.concat())?.to_string()
+                );
+            }
+            let state = stream.state.lock().unwrap();
+            assert!(state.1.bids.len() <= 2);
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_nexmark_serialization() -> Result<()> {
         let mut config = Config::new();
@@ -17,7 +17,7 @@ fn main() {}
 #[cfg(test)]
 mod tests {
     use crate::datasource::epoch::Epoch;
-    use crate::datasource::nexmark::event::Bid;
+    use crate::datasource::nexmark::event::{bids_from_batch, Bid};
     use crate::datasource::nexmark::NEXMarkSource;
     use crate::error::Result;
     use crate::runtime::plan::physical_plan;
@@ -42,9 +42,14 @@ mod tests {
         // sequential processing
         for i in 0..events.bids.len() {
             // events to record batches
-            let bm = events.bids.get(&Epoch::new(i)).unwrap();
+            let bm = events.bids.get(&Epoch::new(i as u64)).unwrap();
             let (bids, _) = bm.get(&0).unwrap();
             let batches = event_bytes_to_batch(bids, schema.clone(), 1024);
+            let input = batches
+                .iter()
+                .map(bids_from_batch)
+                .collect::<Result<Vec<_>>>()?
+                .concat();
 
             // register memory table
             let mut ctx = datafusion::execution::context::ExecutionContext::new();
@@ -57,6 +62,14 @@ mod tests {
 
             // show output
             println!("{}", pretty_format_batches(&batches)?);
+
+            // The query passes all bids through.
+            let output = batches
+                .iter()
+                .map(bids_from_batch)
+                .collect::<Result<Vec<_>>>()?
+                .concat();
+            assert_eq!(output, input);
         }
 
         Ok(())
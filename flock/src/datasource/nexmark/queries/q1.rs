@@ -17,15 +17,14 @@ fn main() {}
 #[cfg(test)]
 mod tests {
     use crate::datasource::epoch::Epoch;
-    use crate::datasource::nexmark::event::Bid;
+    use crate::datasource::nexmark::event::{bids_from_batch, Bid};
     use crate::datasource::nexmark::NEXMarkSource;
     use crate::error::Result;
-    use crate::runtime::plan::physical_plan;
     use crate::stream::Window;
+    use crate::testing::TestPipeline;
     use crate::transmute::event_bytes_to_batch;
+    use datafusion::arrow::array::{Float64Array, Int32Array, TimestampMillisecondArray};
     use datafusion::arrow::util::pretty::pretty_format_batches;
-    use datafusion::datasource::MemTable;
-    use datafusion::physical_plan::collect;
     use std::sync::Arc;
 
     #[tokio::test]
@@ -43,21 +42,58 @@ mod tests {
         // sequential processing
         for i in 0..events.bids.len() {
             // events to record batches
-            let bm = events.bids.get(&Epoch::new(i)).unwrap();
+            let bm = events.bids.get(&Epoch::new(i as u64)).unwrap();
             let (bids, _) = bm.get(&0).unwrap();
             let batches = event_bytes_to_batch(bids, schema.clone(), 1024);
+            let input = batches
+                .iter()
+                .map(bids_from_batch)
+                .collect::<Result<Vec<_>>>()?
+                .concat();
 
-            // register memory table
-            let mut ctx = datafusion::execution::context::ExecutionContext::new();
-            let table = MemTable::try_new(schema.clone(), vec![batches])?;
-            ctx.register_table("bid", Arc::new(table))?;
-
-            // optimize query plan and execute it
-            let physical_plan = physical_plan(&ctx, sql).await?;
-            let batches = collect(physical_plan).await?;
+            // plan the query and execute it
+            let batches = TestPipeline::new()
+                .sql(sql)
+                .table("bid", schema.clone())
+                .source_batches("bid", batches)
+                .run_local()
+                .await?;
 
             // show output
             println!("{}", pretty_format_batches(&batches)?);
+
+            // Only the price is discounted.
+            let mut expected = input.iter();
+            for batch in &batches {
+                let auction = batch
+                    .column(0)
+                    .as_any()
+                    .downcast_ref::<Int32Array>()
+                    .unwrap();
+                let bidder = batch
+                    .column(1)
+                    .as_any()
+                    .downcast_ref::<Int32Array>()
+                    .unwrap();
+                let price = batch
+                    .column(2)
+                    .as_any()
+                    .downcast_ref::<Float64Array>()
+                    .unwrap();
+                let b_date_time = batch
+                    .column(3)
+                    .as_any()
+                    .downcast_ref::<TimestampMillisecondArray>()
+                    .unwrap();
+                for i in 0..batch.num_rows() {
+                    let bid = expected.next().unwrap();
+                    assert_eq!(auction.value(i) as usize, bid.auction);
+                    assert_eq!(bidder.value(i) as usize, bid.bidder);
+                    assert!((price.value(i) - 0.908 * bid.price as f64).abs() < 1e-6);
+                    assert_eq!(b_date_time.value(i) as u64, *bid.b_date_time);
+                }
+            }
+            assert!(expected.next().is_none());
         }
 
         Ok(())
@@ -103,7 +103,7 @@ mod tests {
 
         // sequential processing
         for i in 0..seconds {
-            let bm = events.bids.get(&Epoch::new(i)).unwrap();
+            let bm = events.bids.get(&Epoch::new(i as u64)).unwrap();
             let (bids, _) = bm.get(&0).unwrap();
             let batches = vec![event_bytes_to_batch(bids, schema.clone(), 1024)];
 
@@ -99,7 +99,7 @@ mod tests {
         // sequential processing
         for i in 0..seconds {
             // events to record batches
-            let bm = events.bids.get(&Epoch::new(i)).unwrap();
+            let bm = events.bids.get(&Epoch::new(i as u64)).unwrap();
             let (bids, _) = bm.get(&0).unwrap();
             let bids_batches = event_bytes_to_batch(bids, bid_schema.clone(), 1024);
 
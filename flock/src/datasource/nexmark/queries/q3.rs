@@ -20,12 +20,11 @@ mod tests {
     use crate::datasource::nexmark::event::{Auction, Person};
     use crate::datasource::nexmark::NEXMarkSource;
     use crate::error::Result;
-    use crate::runtime::plan::physical_plan;
     use crate::stream::Window;
+    use crate::testing::TestPipeline;
     use crate::transmute::event_bytes_to_batch;
+    use datafusion::arrow::array::StringArray;
     use datafusion::arrow::util::pretty::pretty_format_batches;
-    use datafusion::datasource::MemTable;
-    use datafusion::physical_plan::collect;
     use indoc::indoc;
     use std::sync::Arc;
 
@@ -60,30 +59,37 @@ mod tests {
         // sequential processing
         for i in 0..seconds {
             // events to record batches
-            let am = events.auctions.get(&Epoch::new(i)).unwrap();
+            let am = events.auctions.get(&Epoch::new(i as u64)).unwrap();
             let (auctions, _) = am.get(&0).unwrap();
             let auctions_batches = event_bytes_to_batch(auctions, auction_schema.clone(), 1024);
 
-            let pm = events.persons.get(&Epoch::new(i)).unwrap();
+            let pm = events.persons.get(&Epoch::new(i as u64)).unwrap();
             let (persons, _) = pm.get(&0).unwrap();
             let person_batches = event_bytes_to_batch(persons, person_schema.clone(), 1024);
 
-            // register memory tables
-            let mut ctx = datafusion::execution::context::ExecutionContext::new();
-            let auction_table = MemTable::try_new(auction_schema.clone(), vec![auctions_batches])?;
-            ctx.deregister_table("auction")?;
-            ctx.register_table("auction", Arc::new(auction_table))?;
-
-            let person_table = MemTable::try_new(person_schema.clone(), vec![person_batches])?;
-            ctx.deregister_table("person")?;
-            ctx.register_table("person", Arc::new(person_table))?;
-
-            // optimize query plan and execute it
-            let physical_plan = physical_plan(&ctx, sql).await?;
-            let batches = collect(physical_plan).await?;
+            // plan the query and execute it
+            let batches = TestPipeline::new()
+                .sql(sql)
+                .table("auction", auction_schema.clone())
+                .table("person", person_schema.clone())
+                .source_batches("auction", auctions_batches)
+                .source_batches("person", person_batches)
+                .run_local()
+                .await?;
 
             // show output
             println!("{}", pretty_format_batches(&batches)?);
+
+            // Only the sellers in the given states are joined.
+            for batch in &batches {
+                let state = batch
+                    .column(2)
+                    .as_any()
+                    .downcast_ref::<StringArray>()
+                    .unwrap();
+                (0..batch.num_rows())
+                    .for_each(|i| assert!(["or", "id", "ca"].contains(&state.value(i))));
+            }
         }
 
         Ok(())
@@ -20,9 +20,12 @@ mod tests {
     use crate::datasource::nexmark::event::Bid;
     use crate::datasource::nexmark::NEXMarkSource;
     use crate::error::{FlockError, Result};
-    use crate::runtime::plan::physical_plan;
+    use crate::runtime::arena::WindowState;
+    use crate::runtime::plan::{argmax_key, physical_plan, stats_keys};
     use crate::stream::Window;
     use crate::transmute::event_bytes_to_batch;
+    use datafusion::arrow::array::{Int32Array, UInt64Array};
+    use datafusion::arrow::record_batch::RecordBatch;
     use datafusion::arrow::util::pretty::pretty_format_batches;
     use datafusion::datasource::MemTable;
     use datafusion::physical_plan::collect;
@@ -90,7 +93,7 @@ mod tests {
                 if j >= seconds {
                     break;
                 }
-                let bm = events.bids.get(&Epoch::new(j)).unwrap();
+                let bm = events.bids.get(&Epoch::new(j as u64)).unwrap();
                 let (bids, _) = bm.get(&0).unwrap();
                 bids_batches.push(event_bytes_to_batch(bids, bid_schema.clone(), 1024));
             }
@@ -133,4 +136,79 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn local_incremental_query_5() -> Result<()> {
+        let seconds = 8;
+        let window = 4;
+        let hop = 2;
+        let nex = NEXMarkSource::new(seconds, 1, 1000, Window::Hopping((window, hop)));
+        let events = nex.generate_data()?;
+
+        let sql = indoc! {"
+            SELECT auction,
+                   num
+            FROM   (SELECT auction,
+                           Count(*) AS num
+                    FROM   bid
+                    GROUP  BY auction) AS AuctionBids
+                INNER JOIN (SELECT Max(num) AS maxn
+                            FROM   (SELECT auction,
+                                           Count(*) AS num
+                                    FROM   bid
+                                    GROUP  BY auction) AS CountBids) AS MaxBids
+                        ON num = maxn;
+        "};
+
+        let rows = |batches: &[RecordBatch]| -> Vec<(i32, u64)> {
+            let mut rows = batches
+                .iter()
+                .flat_map(|b| {
+                    let auctions = b.column(0).as_any().downcast_ref::<Int32Array>().unwrap();
+                    let nums = b.column(1).as_any().downcast_ref::<UInt64Array>().unwrap();
+                    (0..b.num_rows()).map(move |i| (auctions.value(i), nums.value(i)))
+                })
+                .collect::<Vec<_>>();
+            rows.sort_unstable();
+            rows
+        };
+
+        let bid_schema = Arc::new(Bid::schema());
+        let mut state = WindowState::new();
+        for start in (0..=seconds - window).step_by(hop) {
+            let qid = format!("q5-{}", start);
+            let mut bids_batches = vec![];
+            for pane in start..start + window {
+                let (bids, _) = events
+                    .bids
+                    .get(&Epoch::new(pane as u64))
+                    .unwrap()
+                    .get(&0)
+                    .unwrap();
+                let batches = event_bytes_to_batch(bids, bid_schema.clone(), 1024);
+                if state.is_sealed(pane) {
+                    state.reuse(&qid, pane);
+                } else {
+                    state.accumulate(&qid, pane, "auction", &batches)?;
+                }
+                bids_batches.extend(batches);
+            }
+
+            // brute-force recomputation of the whole window
+            let mut ctx = datafusion::execution::context::ExecutionContext::new();
+            let bid_table = MemTable::try_new(bid_schema.clone(), vec![bids_batches])?;
+            ctx.register_table("bid", Arc::new(bid_table))?;
+            let physical_plan = physical_plan(&ctx, sql).await?;
+            assert_eq!(argmax_key(&physical_plan), Some("auction".to_string()));
+            assert!(stats_keys(&[physical_plan.clone()]).contains(&"auction".to_string()));
+            let expected = collect(physical_plan.clone()).await?;
+
+            let output = state
+                .seal(&qid, start..start + window, physical_plan.schema())?
+                .unwrap();
+            assert_eq!(rows(&[output]), rows(&expected));
+        }
+
+        Ok(())
+    }
 }
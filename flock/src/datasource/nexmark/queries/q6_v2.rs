@@ -68,11 +68,11 @@ mod tests {
         // sequential processing
         for i in 0..seconds {
             // events to record batches
-            let am = events.auctions.get(&Epoch::new(i)).unwrap();
+            let am = events.auctions.get(&Epoch::new(i as u64)).unwrap();
             let (auctions, _) = am.get(&0).unwrap();
             let auctions_batches = event_bytes_to_batch(auctions, auction_schema.clone(), 1024);
 
-            let bm = events.bids.get(&Epoch::new(i)).unwrap();
+            let bm = events.bids.get(&Epoch::new(i as u64)).unwrap();
             let (bids, _) = bm.get(&0).unwrap();
             let bids_batches = event_bytes_to_batch(bids, bid_schema.clone(), 1024);
 
@@ -17,47 +17,104 @@ fn main() {}
 #[cfg(test)]
 mod tests {
     use crate::datasource::epoch::Epoch;
-    use crate::datasource::nexmark::event::Bid;
+    use crate::datasource::nexmark::event::{bids_from_batch, max_price_schema, Bid};
     use crate::datasource::nexmark::NEXMarkSource;
     use crate::error::Result;
+    use crate::runtime::broadcast::side_input_to_csv;
     use crate::runtime::plan::physical_plan;
     use crate::stream::Schedule;
     use crate::stream::Window;
     use crate::transmute::event_bytes_to_batch;
+    use datafusion::arrow::csv::ReaderBuilder;
+    use datafusion::arrow::datatypes::SchemaRef;
+    use datafusion::arrow::record_batch::RecordBatch;
     use datafusion::arrow::util::pretty::pretty_format_batches;
     use datafusion::datasource::MemTable;
+    use datafusion::execution::context::ExecutionContext as DataFusionExecutionContext;
     use datafusion::physical_plan::collect;
     use indoc::indoc;
+    use std::io::Cursor;
     use std::sync::Arc;
 
+    /// The query of a single function that joins all bids of the window.
+    static SQL: &str = indoc! {"
+        SELECT  auction,
+                price,
+                bidder,
+                b_date_time
+        FROM    bid
+                JOIN (SELECT Max(price) AS maxprice
+                      FROM   bid) AS B1
+                  ON price = maxprice;
+    "};
+
+    /// Stage A: the maximum price of each partition.
+    static PARTIAL_MAX_SQL: &str = "SELECT Max(price) AS maxprice FROM bid";
+
+    /// Stage B: the maximum price of the window.
+    static GLOBAL_MAX_SQL: &str = "SELECT Max(maxprice) AS maxprice FROM max_price";
+
+    /// Stage C: the bids of each partition at the maximum price.
+    static PROBE_SQL: &str = indoc! {"
+        SELECT  auction,
+                price,
+                bidder,
+                b_date_time
+        FROM    bid
+                JOIN max_price
+                  ON price = maxprice;
+    "};
+
+    /// Runs the query over the given tables.
+    async fn execute(sql: &str, tables: Vec<(&str, Vec<RecordBatch>)>) -> Result<Vec<RecordBatch>> {
+        let mut ctx = DataFusionExecutionContext::new();
+        for (name, batches) in tables {
+            let schema = batches[0].schema();
+            let table = MemTable::try_new(schema, vec![batches])?;
+            ctx.register_table(name, Arc::new(table))?;
+        }
+        let physical_plan = physical_plan(&ctx, sql).await?;
+        Ok(collect(physical_plan).await?)
+    }
+
+    /// Encodes the maximum price of the window to CSV and reads it back, as the
+    /// probe functions read the broadcast side input.
+    fn broadcast(batches: &[RecordBatch], schema: SchemaRef) -> Result<Vec<RecordBatch>> {
+        let bytes = side_input_to_csv(batches)?;
+        let reader = ReaderBuilder::new()
+            .with_schema(schema)
+            .has_header(true)
+            .build(Cursor::new(bytes))?;
+        Ok(reader.collect::<std::result::Result<Vec<_>, _>>()?)
+    }
+
+    fn sorted_bids(batches: &[RecordBatch]) -> Result<Vec<Bid>> {
+        let mut bids = vec![];
+        for batch in batches {
+            bids.extend(bids_from_batch(batch)?);
+        }
+        bids.sort();
+        Ok(bids)
+    }
+
     #[tokio::test]
     async fn local_query_7() -> Result<()> {
         // benchmark configuration
-        let seconds = 4;
+        let seconds = 20;
         let threads = 1;
         let event_per_second = 1000;
         let nex = NEXMarkSource::new(
             seconds,
             threads,
             event_per_second,
-            Window::Tumbling(Schedule::Seconds(2)),
+            Window::Tumbling(Schedule::Seconds(10)),
         );
 
         // data source generation
         let events = nex.generate_data()?;
 
-        let sql = indoc! {"
-            SELECT  auction,
-                    price,
-                    bidder,
-                    b_date_time
-            FROM    bid
-                    JOIN (SELECT Max(price) AS maxprice
-                          FROM   bid) AS B1
-                      ON price = maxprice;
-        "};
-
         let schema = Arc::new(Bid::schema());
+        let max_price_schema = Arc::new(max_price_schema());
         let window_size = match nex.window {
             Window::Tumbling(Schedule::Seconds(sec)) => sec,
             _ => unreachable!(),
@@ -65,27 +122,62 @@ mod tests {
 
         // sequential processing
         for j in 0..seconds / window_size {
-            let mut batches = vec![];
+            // moves the tumbling window, and each second of the window is a
+            // partition sent to a different function.
             let d = j * window_size;
-            // moves the tumbling window
-            for i in d..d + window_size {
-                let bm = events.bids.get(&Epoch::new(i)).unwrap();
-                let (bids, _) = bm.get(&0).unwrap();
-                batches.push(event_bytes_to_batch(bids, schema.clone(), 1024));
+            let partitions = (d..d + window_size)
+                .map(|i| {
+                    let bm = events.bids.get(&Epoch::new(i as u64)).unwrap();
+                    let (bids, _) = bm.get(&0).unwrap();
+                    event_bytes_to_batch(bids, schema.clone(), 1024)
+                })
+                .collect::<Vec<_>>();
+
+            // brute force: the bids of the window at the maximum price
+            let bids = sorted_bids(&partitions.concat())?;
+            let max_price = bids.iter().map(|b| b.price).max().unwrap();
+            let expected = bids
+                .into_iter()
+                .filter(|b| b.price == max_price)
+                .collect::<Vec<_>>();
+            assert!(!expected.is_empty());
+
+            // the query in a single function
+            let output = execute(SQL, vec![("bid", partitions.concat())]).await?;
+            assert_eq!(sorted_bids(&output)?, expected);
+
+            // stage A: the maximum price of each partition
+            let mut partial = vec![];
+            for partition in &partitions {
+                partial.extend(execute(PARTIAL_MAX_SQL, vec![("bid", partition.clone())]).await?);
             }
+            assert_eq!(
+                partial.iter().map(|b| b.num_rows()).sum::<usize>(),
+                window_size
+            );
 
-            // register memory tables
-            let mut ctx = datafusion::execution::context::ExecutionContext::new();
-            let table = MemTable::try_new(schema.clone(), batches)?;
-            ctx.deregister_table("bid")?;
-            ctx.register_table("bid", Arc::new(table))?;
+            // stage B: the maximum price of the window, broadcast as the side input
+            let global = execute(GLOBAL_MAX_SQL, vec![("max_price", partial)]).await?;
+            let side_input = broadcast(&global, max_price_schema.clone())?;
 
-            // optimize query plan and execute it
-            let physical_plan = physical_plan(&ctx, sql).await?;
-            let batches = collect(physical_plan).await?;
+            // stage C: the bids of each partition at the maximum price
+            let mut output = vec![];
+            for partition in &partitions {
+                output.extend(
+                    execute(
+                        PROBE_SQL,
+                        vec![
+                            ("bid", partition.clone()),
+                            ("max_price", side_input.clone()),
+                        ],
+                    )
+                    .await?,
+                );
+            }
+            assert_eq!(sorted_bids(&output)?, expected);
 
             // show output
-            println!("{}", pretty_format_batches(&batches)?);
+            println!("{}", pretty_format_batches(&output)?);
         }
 
         Ok(())
@@ -74,11 +74,11 @@ mod tests {
             let d = j * window_size;
             // moves the tumbling window
             for i in d..d + window_size {
-                let am = events.auctions.get(&Epoch::new(i)).unwrap();
+                let am = events.auctions.get(&Epoch::new(i as u64)).unwrap();
                 let (auctions, _) = am.get(&0).unwrap();
                 auctions_batches.push(event_bytes_to_batch(auctions, auction_schema.clone(), 1024));
 
-                let pm = events.persons.get(&Epoch::new(i)).unwrap();
+                let pm = events.persons.get(&Epoch::new(i as u64)).unwrap();
                 let (persons, _) = pm.get(&0).unwrap();
                 person_batches.push(event_bytes_to_batch(persons, person_schema.clone(), 1024));
             }
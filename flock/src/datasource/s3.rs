@@ -10,3 +10,523 @@
 //
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Amazon S3 objects as a batch data source, e.g. the historical logs stored as
+//! gzip-compressed newline-delimited JSON.
+//!
+//! Each object under the prefix is decompressed while it is downloaded and
+//! decoded into its own partition, so that a large prefix maps naturally to
+//! the partitions of the relation. The objects are decoded a record batch at a
+//! time, and only the leading records that the schema is inferred from are
+//! buffered. Parquet objects are spooled to a temporary file, since their
+//! footer is at the end.
+
+use crate::aws::client::CloudClient;
+use crate::aws::s3;
+use crate::configs::*;
+use crate::datasource::RelationPartitions;
+use crate::error::{FlockError, Result};
+use crate::transmute::{schema_from_bytes, schema_to_bytes};
+use datafusion::arrow::csv::{self, reader::infer_reader_schema};
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::json::{self, reader::infer_json_schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::parquet::arrow::{ArrowReader, ParquetFileArrowReader};
+use datafusion::parquet::file::serialized_reader::SerializedFileReader;
+use flate2::read::MultiGzDecoder;
+use log::info;
+use rusoto_s3::{GetObjectRequest, S3};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+/// The number of rows in each record batch.
+const BATCH_SIZE: usize = 1024;
+
+/// The number of records read to infer the schema if it isn't given.
+const INFER_SCHEMA_RECORDS: usize = 1024;
+
+/// The format of the S3 objects.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub enum S3ObjectFormat {
+    /// Newline-delimited JSON, one record per line.
+    Ndjson,
+    /// Comma-separated values with a header line.
+    Csv,
+    /// Apache Parquet. The schema is always read from the file footer.
+    Parquet,
+}
+
+impl Default for S3ObjectFormat {
+    fn default() -> Self {
+        S3ObjectFormat::Ndjson
+    }
+}
+
+impl S3ObjectFormat {
+    /// Returns the format with the given name: `ndjson`, `csv` or `parquet`.
+    pub fn new(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "ndjson" | "json" => Ok(S3ObjectFormat::Ndjson),
+            "csv" => Ok(S3ObjectFormat::Csv),
+            "parquet" => Ok(S3ObjectFormat::Parquet),
+            _ => Err(FlockError::NotImplemented(format!(
+                "Unsupported S3 object format: {}",
+                name
+            ))),
+        }
+    }
+}
+
+/// The compression of the S3 objects.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub enum S3ObjectCompression {
+    /// The objects are compressed with gzip. Concatenated gzip members are
+    /// decompressed as a single stream.
+    Gzip,
+    /// The objects are compressed with zstd. It requires the `zstd` feature.
+    Zstd,
+    /// The objects are not compressed.
+    None,
+}
+
+impl Default for S3ObjectCompression {
+    fn default() -> Self {
+        S3ObjectCompression::None
+    }
+}
+
+impl S3ObjectCompression {
+    /// Returns the compression with the given name: `gzip`, `zstd` or `none`.
+    pub fn new(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "gzip" | "gz" => Ok(S3ObjectCompression::Gzip),
+            "zstd" | "zst" => Ok(S3ObjectCompression::Zstd),
+            "none" | "" => Ok(S3ObjectCompression::None),
+            _ => Err(FlockError::NotImplemented(format!(
+                "Unsupported S3 object compression: {}",
+                name
+            ))),
+        }
+    }
+
+    /// Wraps the reader of the compressed object into a decompressing reader.
+    fn decoder<'a, R: Read + 'a>(&self, reader: R) -> Result<Box<dyn Read + 'a>> {
+        match self {
+            S3ObjectCompression::Gzip => Ok(Box::new(MultiGzDecoder::new(reader))),
+            #[cfg(feature = "zstd")]
+            S3ObjectCompression::Zstd => Ok(Box::new(zstd::stream::read::Decoder::new(reader)?)),
+            #[cfg(not(feature = "zstd"))]
+            S3ObjectCompression::Zstd => Err(FlockError::NotImplemented(
+                "zstd decompression requires the `zstd` feature".to_string(),
+            )),
+            S3ObjectCompression::None => Ok(Box::new(reader)),
+        }
+    }
+}
+
+/// The S3 objects under a prefix to read as a relation.
+#[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct S3ObjectsSource {
+    /// The name of the S3 bucket.
+    pub bucket:      String,
+    /// The key prefix of the objects to read.
+    pub prefix:      String,
+    /// The explicit schema of the objects in Arrow IPC format. If it's not
+    /// given, the schema is inferred from the leading records of each object.
+    #[serde(default)]
+    pub schema:      Option<Vec<u8>>,
+    /// The format of the objects.
+    pub format:      S3ObjectFormat,
+    /// The compression of the objects.
+    pub compression: S3ObjectCompression,
+}
+
+impl S3ObjectsSource {
+    /// Creates a new S3 objects source.
+    pub fn new(
+        bucket: &str,
+        prefix: &str,
+        format: S3ObjectFormat,
+        compression: S3ObjectCompression,
+    ) -> Self {
+        Self {
+            bucket: bucket.to_owned(),
+            prefix: prefix.to_owned(),
+            schema: None,
+            format,
+            compression,
+        }
+    }
+
+    /// Sets the explicit schema of the objects.
+    pub fn with_schema(mut self, schema: SchemaRef) -> Self {
+        self.schema = Some(schema_to_bytes(schema));
+        self
+    }
+
+    /// Returns the explicit schema of the objects if it's given.
+    pub fn schema(&self) -> Result<Option<SchemaRef>> {
+        self.schema
+            .as_ref()
+            .map(|bytes| schema_from_bytes(bytes))
+            .transpose()
+    }
+
+    /// Reads all objects under the prefix. Each object is a partition of the
+    /// relation, in the order of the keys. The empty objects are skipped.
+    pub async fn fetch_data(&self) -> Result<RelationPartitions> {
+        let keys = s3::get_matched_keys(&self.bucket, &self.prefix)
+            .await?
+            .into_iter()
+            .filter(|key| !key.ends_with('/'))
+            .collect::<Vec<_>>();
+        info!(
+            "Reading {} objects from s3://{}/{}",
+            keys.len(),
+            self.bucket,
+            self.prefix
+        );
+
+        let tasks = keys
+            .into_iter()
+            .map(|key| {
+                let source = self.clone();
+                tokio::spawn(async move { source.fetch_object(&key).await })
+            })
+            .collect::<Vec<_>>();
+
+        let mut partitions = vec![];
+        for task in futures::future::join_all(tasks).await {
+            let batches = task.map_err(|e| FlockError::Internal(e.to_string()))??;
+            if !batches.is_empty() {
+                partitions.push(batches);
+            }
+        }
+        Ok(partitions)
+    }
+
+    /// Reads a single object into record batches.
+    pub async fn fetch_object(&self, key: &str) -> Result<Vec<RecordBatch>> {
+        let body = s3_client("")
+            .get_object(GetObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_owned(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| FlockError::AWS(e.to_string()))?
+            .body
+            .ok_or_else(|| FlockError::AWS(format!("The body of {} is empty", key)))?;
+
+        // The object is decompressed and decoded while it's downloaded.
+        let source = self.clone();
+        tokio::task::spawn_blocking(move || source.read(body.into_blocking_read()))
+            .await
+            .map_err(|e| FlockError::Internal(e.to_string()))?
+    }
+
+    /// Returns the keys of the objects under the prefix, in the order of the
+    /// keys.
+    pub async fn list_keys(&self, client: &dyn CloudClient) -> Result<Vec<String>> {
+        Ok(client
+            .s3_list(&self.bucket, &self.prefix)
+            .await?
+            .into_iter()
+            .filter(|key| !key.ends_with('/'))
+            .collect())
+    }
+
+    /// Reads the given objects through the cloud client. Each object is a
+    /// partition of the relation, and the empty objects are skipped.
+    pub async fn fetch_keys(
+        &self,
+        client: &dyn CloudClient,
+        keys: &[String],
+    ) -> Result<RelationPartitions> {
+        let mut partitions = vec![];
+        for key in keys {
+            let body = client.s3_get(&self.bucket, key).await?;
+            let batches = self.read(&body[..])?;
+            if !batches.is_empty() {
+                partitions.push(batches);
+            }
+        }
+        Ok(partitions)
+    }
+
+    /// Decompresses and decodes an object into record batches.
+    pub fn read<R: Read>(&self, reader: R) -> Result<Vec<RecordBatch>> {
+        let reader = self.compression.decoder(reader)?;
+        let schema = self.schema()?;
+
+        match (self.format, schema) {
+            (S3ObjectFormat::Ndjson, Some(schema)) => {
+                let reader = json::Reader::new(reader, schema, BATCH_SIZE, None);
+                Ok(reader.collect::<std::result::Result<Vec<_>, _>>()?)
+            }
+            (S3ObjectFormat::Ndjson, None) => {
+                // The records that the schema is inferred from are read again
+                // before the rest of the stream.
+                let (head, rest) = leading_lines(reader, INFER_SCHEMA_RECORDS)?;
+                let schema =
+                    infer_json_schema(&mut BufReader::new(&head[..]), Some(INFER_SCHEMA_RECORDS))?;
+                let reader = json::Reader::new(
+                    Cursor::new(head).chain(rest),
+                    Arc::new(schema),
+                    BATCH_SIZE,
+                    None,
+                );
+                Ok(reader.collect::<std::result::Result<Vec<_>, _>>()?)
+            }
+            (S3ObjectFormat::Csv, schema) => {
+                let (schema, reader): (SchemaRef, Box<dyn Read + '_>) = match schema {
+                    Some(schema) => (schema, reader),
+                    None => {
+                        // The header line and the records that the schema is inferred
+                        // from are read again before the rest of the stream.
+                        let (head, rest) = leading_lines(reader, INFER_SCHEMA_RECORDS + 1)?;
+                        let (schema, _) = infer_reader_schema(
+                            &mut &head[..],
+                            b',',
+                            Some(INFER_SCHEMA_RECORDS),
+                            true,
+                        )?;
+                        (Arc::new(schema), Box::new(Cursor::new(head).chain(rest)))
+                    }
+                };
+                let reader = csv::Reader::new(reader, schema, true, None, BATCH_SIZE, None, None);
+                Ok(reader.collect::<std::result::Result<Vec<_>, _>>()?)
+            }
+            (S3ObjectFormat::Parquet, _) => {
+                let path = std::env::temp_dir().join(format!("flock-s3-{}", uuid::Uuid::new_v4()));
+                let batches = read_parquet(reader, &path);
+                let _ = std::fs::remove_file(&path);
+                batches
+            }
+        }
+    }
+
+    /// Infers the schema of the objects from the first object under the
+    /// prefix, so that all objects are decoded with the same schema.
+    pub async fn infer_schema(&self, client: &dyn CloudClient) -> Result<SchemaRef> {
+        let keys = self.list_keys(client).await?;
+        let key = keys.first().ok_or_else(|| {
+            FlockError::Execution(format!(
+                "No objects under s3://{}/{}",
+                self.bucket, self.prefix
+            ))
+        })?;
+        let body = client.s3_get(&self.bucket, key).await?;
+        self.read(&body[..])?
+            .first()
+            .map(|batch| batch.schema())
+            .ok_or_else(|| {
+                FlockError::Execution(format!("No records in s3://{}/{}", self.bucket, key))
+            })
+    }
+}
+
+/// Reads up to the given number of lines from the stream. Returns the lines
+/// read and the rest of the stream.
+fn leading_lines<R: Read>(reader: R, lines: usize) -> Result<(Vec<u8>, BufReader<R>)> {
+    let mut reader = BufReader::new(reader);
+    let mut head = vec![];
+    for _ in 0..lines {
+        if reader.read_until(b'\n', &mut head)? == 0 {
+            break;
+        }
+    }
+    Ok((head, reader))
+}
+
+/// Reads a Parquet object a row group at a time. The footer of the Parquet
+/// file is at the end of the object, so the object is spooled to the given
+/// file first instead of being buffered in memory.
+fn read_parquet<R: Read>(mut reader: R, path: &Path) -> Result<Vec<RecordBatch>> {
+    std::io::copy(&mut reader, &mut File::create(path)?)?;
+    let file_reader = SerializedFileReader::new(File::open(path)?)?;
+    let mut arrow_reader = ParquetFileArrowReader::new(Arc::new(file_reader));
+    let reader = arrow_reader.get_record_reader(BATCH_SIZE)?;
+    Ok(reader.collect::<std::result::Result<Vec<_>, _>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_batches_eq;
+    use crate::aws::client::FakeCloudClient;
+    use crate::datasource::DataSource;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+
+    const CLICKS: &[u8] = include_bytes!("../tests/data/clicks.ndjson.gz");
+
+    #[test]
+    fn read_gzip_ndjson_with_inferred_schema() -> Result<()> {
+        let source = S3ObjectsSource::new(
+            "flock-clicks",
+            "2021/10/01/",
+            S3ObjectFormat::Ndjson,
+            S3ObjectCompression::Gzip,
+        );
+        let batches = source.read(CLICKS)?;
+        assert_eq!(batches.len(), 1);
+
+        let schema = batches[0].schema();
+        assert_eq!(
+            schema.field_with_name("user_id")?.data_type(),
+            &DataType::Int64
+        );
+        assert_eq!(schema.field_with_name("page")?.data_type(), &DataType::Utf8);
+        assert_eq!(schema.field_with_name("ts")?.data_type(), &DataType::Int64);
+        assert_eq!(
+            schema.field_with_name("duration")?.data_type(),
+            &DataType::Float64
+        );
+        assert_eq!(batches[0].num_rows(), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_gzip_ndjson_with_explicit_schema() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("user_id", DataType::Int32, false),
+            Field::new("page", DataType::Utf8, false),
+        ]));
+        let source = S3ObjectsSource::new(
+            "flock-clicks",
+            "2021/10/01/",
+            S3ObjectFormat::Ndjson,
+            S3ObjectCompression::Gzip,
+        )
+        .with_schema(schema.clone());
+        assert_eq!(source.schema()?, Some(schema.clone()));
+
+        let batches = source.read(CLICKS)?;
+        assert_eq!(batches[0].schema(), schema);
+
+        let expected = vec![
+            "+---------+-----------+",
+            "| user_id | page      |",
+            "+---------+-----------+",
+            "| 1       | /home     |",
+            "| 2       | /cart     |",
+            "| 1       | /checkout |",
+            "| 3       | /home     |",
+            "| 2       | /home     |",
+            "+---------+-----------+",
+        ];
+        assert_batches_eq!(expected, &batches);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_uncompressed_csv() -> Result<()> {
+        let source = S3ObjectsSource::new(
+            "flock-clicks",
+            "csv/",
+            S3ObjectFormat::Csv,
+            S3ObjectCompression::None,
+        );
+        let batches = source.read("user_id,page\n1,/home\n2,/cart\n".as_bytes())?;
+        assert_eq!(batches[0].num_rows(), 2);
+        assert_eq!(batches[0].num_columns(), 2);
+
+        // The compression must match the objects.
+        let source = S3ObjectsSource::new(
+            "flock-clicks",
+            "2021/10/01/",
+            S3ObjectFormat::Ndjson,
+            S3ObjectCompression::None,
+        );
+        assert!(source.read(CLICKS).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_streams_past_the_inferred_records() -> Result<()> {
+        let rows = INFER_SCHEMA_RECORDS + 500;
+        let ndjson = (0..rows)
+            .map(|i| format!("{{\"user_id\":{},\"page\":\"/home\"}}\n", i))
+            .collect::<String>();
+        let source = S3ObjectsSource::new(
+            "flock-clicks",
+            "json/",
+            S3ObjectFormat::Ndjson,
+            S3ObjectCompression::None,
+        );
+        let batches = source.read(ndjson.as_bytes())?;
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), rows);
+
+        let csv = std::iter::once("user_id,page\n".to_string())
+            .chain((0..rows).map(|i| format!("{},/home\n", i)))
+            .collect::<String>();
+        let source = S3ObjectsSource::new(
+            "flock-clicks",
+            "csv/",
+            S3ObjectFormat::Csv,
+            S3ObjectCompression::None,
+        );
+        let batches = source.read(csv.as_bytes())?;
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), rows);
+        assert_eq!(
+            batches[1].schema().field_with_name("user_id")?.data_type(),
+            &DataType::Int64
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn infer_schema_from_first_object() -> Result<()> {
+        let client = FakeCloudClient::new();
+        let source = S3ObjectsSource::new(
+            "flock-clicks",
+            "2021/10/01/",
+            S3ObjectFormat::Ndjson,
+            S3ObjectCompression::Gzip,
+        );
+        assert!(source.infer_schema(&client).await.is_err());
+
+        client.put_object(
+            "flock-clicks",
+            "2021/10/01/part-0.ndjson.gz",
+            CLICKS.to_vec(),
+        );
+        let schema = source.infer_schema(&client).await?;
+        assert_eq!(schema.fields().len(), 4);
+        assert_eq!(schema.field_with_name("page")?.data_type(), &DataType::Utf8);
+        Ok(())
+    }
+
+    #[test]
+    fn serde_s3_objects_source() -> Result<()> {
+        let source = DataSource::S3Objects(
+            S3ObjectsSource::new(
+                "flock-clicks",
+                "2021/10/01/",
+                S3ObjectFormat::new("ndjson")?,
+                S3ObjectCompression::new("gzip")?,
+            )
+            .with_schema(Arc::new(Schema::new(vec![Field::new(
+                "user_id",
+                DataType::Int64,
+                false,
+            )]))),
+        );
+        let json = serde_json::to_string(&source)?;
+        assert_eq!(serde_json::from_str::<DataSource>(&json)?, source);
+
+        assert!(S3ObjectFormat::new("avro").is_err());
+        assert!(S3ObjectCompression::new("bzip2").is_err());
+
+        Ok(())
+    }
+}
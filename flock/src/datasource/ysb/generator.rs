@@ -76,7 +76,7 @@ impl YSBGenerator {
 
         let mut rng = rand::rngs::StdRng::seed_from_u64(0xDEAD); // Predictable RNG clutch
         let mut data = Vec::with_capacity((1000.0 / self.timestep) as usize);
-        let epoch = self.time as usize / 1000;
+        let epoch = self.time as u64 / 1000;
 
         let mut num = 0;
         while self.time < ((epoch + 1) * 1000) as f64 && self.time < self.max_time as f64 {
@@ -91,7 +91,7 @@ impl YSBGenerator {
                     .clone(),
                 ad_type:    ad_types.choose(&mut rng).unwrap().to_string(),
                 event_type: event_types.choose(&mut rng).unwrap().to_string(),
-                event_time: Epoch(self.time as usize),
+                event_time: Epoch(self.time as u64),
                 ip_address: String::from("0.0.0.0"),
             };
 
@@ -119,7 +119,7 @@ impl YSBGenerator {
 
         let mut rng = rand::rngs::StdRng::seed_from_u64(0xDEAD); // Predictable RNG clutch
         let mut data = Vec::with_capacity((1000.0 / self.timestep) as usize);
-        let epoch = self.time as usize / 1000;
+        let epoch = self.time as u64 / 1000;
 
         while self.time < ((epoch + 1) * 1000) as f64 && self.time < self.max_time as f64 {
             data.push(AdEvent {
@@ -133,7 +133,7 @@ impl YSBGenerator {
                     .clone(),
                 ad_type:    ad_types.choose(&mut rng).unwrap().to_string(),
                 event_type: event_types.choose(&mut rng).unwrap().to_string(),
-                event_time: Epoch(self.time as usize),
+                event_time: Epoch(self.time as u64),
                 ip_address: String::from("0.0.0.0"),
             });
             self.time += self.timestep;
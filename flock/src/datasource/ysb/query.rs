@@ -68,7 +68,7 @@ mod tests {
             let d = i * window_size;
             // moves the tumbling window
             for i in d..d + window_size {
-                let m = stream.events.get(&Epoch::new(i)).unwrap();
+                let m = stream.events.get(&Epoch::new(i as u64)).unwrap();
                 let (ad_events, _) = m.get(&0).unwrap();
                 batches.push(event_bytes_to_batch(
                     ad_events,
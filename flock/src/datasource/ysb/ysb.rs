@@ -87,7 +87,7 @@ impl YSBStream {
             source,
             ..Default::default()
         };
-        let epoch = Epoch::new(time);
+        let epoch = Epoch::new(time as u64);
 
         let mut ad_events_num = 0;
         if let Some(map) = self.events.get(&epoch) {
@@ -204,7 +204,7 @@ impl DataStream for YSBStream {
             &event_bytes_to_batch(&campaigns, YSB_CAMPAIGN.clone(), batch_size),
             uuid,
             sync,
-        ))
+        )?)
     }
 }
 
@@ -315,7 +315,7 @@ impl YSBSource {
                     .map(|s| {
                         stream
                             .events
-                            .get(&Epoch::new(s))
+                            .get(&Epoch::new(s as u64))
                             .unwrap()
                             .get(&p)
                             .unwrap()
@@ -23,12 +23,13 @@
 //! work on other cloud functions that then together execute the query in a
 //! distributed dataflow model.
 
-use crate::configs::FLOCK_CONF;
+use crate::configs::{FLOCK_CONF, FLOCK_GROUP_THRESHOLD};
 use crate::encoding::Encoding;
 use crate::error::{FlockError, Result};
 use crate::runtime::context::CloudFunction;
 use crate::runtime::context::ExecutionContext;
 use crate::runtime::payload::Uuid;
+use crate::runtime::stats::PayloadStats;
 use crate::transmute::*;
 use async_trait::async_trait;
 use datafusion::arrow::record_batch::RecordBatch;
@@ -85,7 +86,25 @@ impl Executor for LambdaExecutor {}
 impl LambdaExecutor {
     /// Choose an optimal strategy according to the size of the batch and the
     /// attributes of the query.
-    pub fn choose_strategy(ctx: &ExecutionContext, batch: &[RecordBatch]) -> ExecutionStrategy {
+    ///
+    /// If the statistics of the input estimate the number of groups, the joins
+    /// and aggregations are distributed only if the estimate exceeds the group
+    /// threshold, regardless of the size of the batch.
+    pub fn choose_strategy(
+        ctx: &ExecutionContext,
+        batch: &[RecordBatch],
+        stats: Option<&PayloadStats>,
+    ) -> ExecutionStrategy {
+        if let Some(stats) = stats.filter(|s| s.estimated_groups().is_some()) {
+            if contain_join(&ctx.plan) || contain_aggregate(&ctx.plan) {
+                return if stats.exceeds_group_threshold(*FLOCK_GROUP_THRESHOLD) {
+                    ExecutionStrategy::Distributed
+                } else {
+                    ExecutionStrategy::Centralized
+                };
+            }
+        }
+
         let size: usize = batch
             .par_iter()
             .map(|r| {
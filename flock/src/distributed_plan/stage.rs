@@ -17,6 +17,7 @@
 extern crate daggy;
 use crate::error::{FlockError, Result};
 use crate::runtime::context::{CloudFunctionType, ExecutionContext};
+use crate::runtime::plan::PlanProperties;
 use daggy::{Dag, NodeIndex, Walker};
 use datafusion::physical_plan::displayable;
 use datafusion::physical_plan::memory::MemoryExec;
@@ -32,11 +33,14 @@ type DistributedPlan = Dag<QueryStage, QueryStageEdge>;
 #[derive(Debug, Clone)]
 pub struct QueryStage {
     /// Subplans of the query statement.
-    pub stage:         Vec<Arc<dyn ExecutionPlan>>,
+    pub stage:            Vec<Arc<dyn ExecutionPlan>>,
     /// Function type in cloud environment.
-    pub function_type: CloudFunctionType,
+    pub function_type:    CloudFunctionType,
     /// The cloud execution context for this query stage.
-    pub context:       Option<ExecutionContext>,
+    pub context:          Option<ExecutionContext>,
+    /// True if the stage hash partitions its output for the next stage, i.e.
+    /// the stage is cut below a partitioned final aggregate or join.
+    pub is_shuffle_stage: bool,
 }
 
 impl QueryStage {
@@ -63,6 +67,7 @@ impl QueryStage {
             stage,
             function_type,
             context: None,
+            is_shuffle_stage: false,
         }
     }
 }
@@ -73,6 +78,7 @@ impl From<Vec<Arc<dyn ExecutionPlan>>> for QueryStage {
             stage,
             function_type: CloudFunctionType::Lambda,
             context: None,
+            is_shuffle_stage: false,
         }
     }
 }
@@ -230,16 +236,18 @@ impl QueryDag {
         parent: NodeIndex,
         nodes: Vec<Value>,
         function_type: CloudFunctionType,
+        is_shuffle_stage: bool,
     ) -> Result<NodeIndex> {
         let stage = nodes
             .into_iter()
-            .map(|node| serde_json::from_value(node).unwrap())
-            .collect();
+            .map(|node| Ok(serde_json::from_value(node)?))
+            .collect::<Result<Vec<_>>>()?;
         if parent == NodeIndex::end() {
             Ok(self.add_node(QueryStage {
                 stage,
                 function_type,
                 context: None,
+                is_shuffle_stage,
             }))
         } else {
             // TODO: call add_parent instead of add_child
@@ -249,110 +257,208 @@ impl QueryDag {
                     stage,
                     function_type,
                     context: None,
+                    is_shuffle_stage,
                 },
             ))
         }
     }
 }
 
+/// The operators that only move the rows between the partitions, which may sit
+/// between a final aggregate and its partial aggregate.
+const EXCHANGE_OPERATORS: &[&str] = &[
+    "coalesce_batches_exec",
+    "coalesce_partitions_exec",
+    "repartition_exec",
+];
+
+/// The serialized subplans of a query stage, the type of the function that
+/// executes them, and whether the stage shuffles its output (see
+/// [`QueryStage::is_shuffle_stage`]).
+type StagePlans = (Vec<Value>, CloudFunctionType, bool);
+
+/// Returns the error for the operator that can't be partitioned safely.
+fn dag_partition_error(operator: &str, reason: &str) -> FlockError {
+    FlockError::DagPartition {
+        operator: operator.to_string(),
+        reason:   reason.to_string(),
+    }
+}
+
 /// Build a DAG from a query plan.
 ///
+/// The plan is cut below the operators that need all the rows of their input:
+///
+/// * a final aggregate, which is split from its partial aggregate. Only the
+///   exchange operators may sit between them.
+/// * a window function, which is kept in one stage with the sort of its input.
+/// * a sort.
+/// * a join, and a union with an input that has to be split, whose inputs are
+///   partitioned on their own and zipped into the upstream stages (see
+///   [`partition_inputs`]).
+///
 /// # Arguments
 /// * `plan` - The query plan.
 ///
 /// # Returns
 /// * `QueryDag` - the DAG representation of the query plan.
+///
+/// # Errors
+/// [`FlockError::DagPartition`] if an operator of the plan can't be
+/// partitioned safely, e.g. a cross join whose inputs have to be split, or a
+/// final aggregate that isn't fed by its partial aggregate.
 pub fn build_query_dag(plan: Arc<dyn ExecutionPlan>) -> Result<QueryDag> {
     build_query_dag_from_serde_json(plan)
 }
 
 fn build_query_dag_from_serde_json(plan: Arc<dyn ExecutionPlan>) -> Result<QueryDag> {
     let mut dag = QueryDag::new();
-    let mut root = serde_json::to_value(&plan).unwrap();
-    let mut json = &mut root;
     let mut leaf = NodeIndex::end();
-    let mut curr = plan.clone();
+    for (nodes, function_type, is_shuffle_stage) in partition_plan(plan)? {
+        leaf = dag.insert(leaf, nodes, function_type, is_shuffle_stage)?;
+    }
+    assert!(dag.node_count() >= 1);
+
+    Ok(dag)
+}
+
+/// Partitions the plan into the subplans of the query stages, from the stage
+/// of the root operator down to the stage that reads the data sources.
+fn partition_plan(plan: Arc<dyn ExecutionPlan>) -> Result<Vec<StagePlans>> {
+    let mut stages = vec![];
+    let mut root = serde_json::to_value(&plan)?;
+    let mut json = &mut root;
+    let mut curr = plan;
+    // True if a final aggregate is cut from its input, and its partial
+    // aggregate isn't reached yet.
+    let mut pending_partial = false;
+    // True if the current stage feeds a partitioned operator of the stage
+    // above it, which reads the partitions of its input one by one.
+    let mut is_shuffle = false;
     loop {
-        match json["execution_plan"].as_str() {
-            Some("hash_aggregate_exec") => match json["mode"].as_str() {
+        let operator = json["execution_plan"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let is_partial =
+            operator == "hash_aggregate_exec" && json["mode"].as_str() == Some("Partial");
+        if pending_partial && !is_partial && !EXCHANGE_OPERATORS.contains(&operator.as_str()) {
+            return Err(dag_partition_error(
+                &operator,
+                "the input of the final aggregate isn't its partial aggregate",
+            ));
+        }
+
+        // The function type of the stage that is cut below the operator.
+        let cut = match operator.as_str() {
+            "hash_aggregate_exec" => match json["mode"].as_str() {
                 Some("Final") | Some("FinalPartitioned") => {
-                    // Split the plan into two subplans
-                    let object = (*json["input"].take().as_object().ok_or_else(|| {
-                        FlockError::QueryStage(
-                            "Failed to parse input for HashAggregateExec".to_string(),
-                        )
-                    })?)
-                    .clone();
-                    // Add a input for the new subplan
-                    let input: Arc<dyn ExecutionPlan> = Arc::new(MemoryExec::try_new(
-                        &[],
-                        curr.children()[0].schema().clone(),
-                        None,
-                    )?);
-                    json["input"] = serde_json::to_value(input)?;
-                    // Add the new subplan to DAG
-                    leaf = dag.insert(leaf, vec![root], CloudFunctionType::Group)?;
-                    // Point to the next subplan
-                    root = Value::Object(object);
-                    json = &mut root;
+                    pending_partial = true;
+                    Some(CloudFunctionType::Group)
+                }
+                Some("Partial") => {
+                    pending_partial = false;
+                    None
                 }
-                Some("Partial") => json = &mut json["input"],
-                _ => {
-                    println!("{:?}", json["aggregate_mode"].as_str());
-                    return Err(FlockError::QueryStage(
-                        "Failed to parse aggregate mode for HashAggregateExec".to_string(),
+                mode => {
+                    return Err(dag_partition_error(
+                        &operator,
+                        &format!("unknown aggregate mode {:?}", mode),
                     ));
                 }
             },
-            Some("hash_join_exec") => {
-                let left_obj = (*json["left"].take().as_object().ok_or_else(|| {
-                    FlockError::QueryStage(
-                        "Failed to parse left input for HashJoinExec".to_string(),
-                    )
-                })?)
-                .clone();
-                let right_obj = (*json["right"].take().as_object().ok_or_else(|| {
-                    FlockError::QueryStage(
-                        "Failed to parse right input for HashJoinExec".to_string(),
-                    )
-                })?)
-                .clone();
+            "window_agg_exec" => {
+                // The window functions read their input sorted by the partition
+                // and order keys, so the sort stays in the stage of the window.
+                if json["input"]["execution_plan"].as_str() == Some("sort_exec") {
+                    json = &mut json["input"];
+                    curr = curr.children()[0].clone();
+                }
+                Some(CloudFunctionType::Group)
+            }
+            "sort_exec" => Some(CloudFunctionType::Group),
+            "hash_join_exec" => {
+                // Each input of the join stage is fed by the upstream stages.
+                let partitioned = json["mode"].as_str() == Some("Partitioned");
+                for (side, child) in ["left", "right"].iter().zip(curr.children()) {
+                    let input: Arc<dyn ExecutionPlan> =
+                        Arc::new(MemoryExec::try_new(&[], child.schema(), None)?);
+                    json[*side] = serde_json::to_value(input)?;
+                }
+                stages.push((vec![root], CloudFunctionType::Lambda, is_shuffle));
+                let mut inputs = partition_inputs(&curr.children())?;
+                if let Some(input) = inputs.first_mut() {
+                    input.2 = partitioned;
+                }
+                stages.extend(inputs);
+                return Ok(stages);
+            }
+            "union_exec" => {
+                // Keep the union and its inputs within one stage, unless one of
+                // the inputs has to be split into multiple stages by itself.
+                if !curr
+                    .children()
+                    .iter()
+                    .any(|input| PlanProperties::analyze(input).has_stage_boundary())
+                {
+                    break;
+                }
 
-                let left: Arc<dyn ExecutionPlan> =
-                    Arc::new(MemoryExec::try_new(&[], curr.children()[0].schema(), None)?);
-                let right: Arc<dyn ExecutionPlan> =
-                    Arc::new(MemoryExec::try_new(&[], curr.children()[1].schema(), None)?);
-
-                json["left"] = serde_json::to_value(left)?;
-                json["right"] = serde_json::to_value(right)?;
-
-                leaf = dag.insert(leaf, vec![root], CloudFunctionType::Lambda)?;
-                dag.insert(
-                    leaf,
-                    vec![Value::Object(left_obj), Value::Object(right_obj)],
-                    CloudFunctionType::Lambda,
-                )?;
-                return Ok(dag);
+                // Each input of the union stage is fed by the upstream stages.
+                json["inputs"] = Value::Array(
+                    curr.children()
+                        .iter()
+                        .map(|child| {
+                            let input: Arc<dyn ExecutionPlan> =
+                                Arc::new(MemoryExec::try_new(&[], child.schema(), None)?);
+                            Ok(serde_json::to_value(input)?)
+                        })
+                        .collect::<Result<Vec<_>>>()?,
+                );
+                stages.push((vec![root], CloudFunctionType::Lambda, is_shuffle));
+                stages.extend(partition_inputs(&curr.children())?);
+                return Ok(stages);
             }
-            Some("sort_exec") => {
-                let object = (*json["input"].take().as_object().ok_or_else(|| {
-                    FlockError::QueryStage("Failed to parse input for SortExec".to_string())
-                })?)
+            _ if curr.children().len() > 1 => {
+                // The other operators with multiple inputs, e.g. a cross join,
+                // can't be cut below, so they stay in one stage with their
+                // inputs.
+                if curr
+                    .children()
+                    .iter()
+                    .any(|input| PlanProperties::analyze(input).has_stage_boundary())
+                {
+                    return Err(dag_partition_error(
+                        &operator,
+                        "the inputs of the operator have to be split into multiple stages",
+                    ));
+                }
+                break;
+            }
+            _ => None,
+        };
+
+        match cut {
+            Some(function_type) => {
+                // Split the plan into two subplans
+                let object = (*json["input"]
+                    .take()
+                    .as_object()
+                    .ok_or_else(|| dag_partition_error(&operator, "failed to parse the input"))?)
                 .clone();
                 // Add a input for the new subplan
-                let input: Arc<dyn ExecutionPlan> = Arc::new(MemoryExec::try_new(
-                    &[],
-                    curr.children()[0].schema().clone(),
-                    None,
-                )?);
+                let input: Arc<dyn ExecutionPlan> =
+                    Arc::new(MemoryExec::try_new(&[], curr.children()[0].schema(), None)?);
                 json["input"] = serde_json::to_value(input)?;
-                // Add the new subplan to DAG
-                leaf = dag.insert(leaf, vec![root], CloudFunctionType::Group)?;
+                let partitioned = operator == "hash_aggregate_exec"
+                    && json["mode"].as_str() == Some("FinalPartitioned");
+                stages.push((vec![root], function_type, is_shuffle));
+                is_shuffle = partitioned;
                 // Point to the next subplan
                 root = Value::Object(object);
                 json = &mut root;
             }
-            _ => json = &mut json["input"],
+            None => json = &mut json["input"],
         }
         if !json.is_object() {
             break;
@@ -360,10 +466,45 @@ fn build_query_dag_from_serde_json(plan: Arc<dyn ExecutionPlan>) -> Result<Query
         curr = curr.children()[0].clone();
     }
 
-    dag.insert(leaf, vec![root], CloudFunctionType::Lambda)?;
-    assert!(dag.node_count() >= 1);
+    stages.push((vec![root], CloudFunctionType::Lambda, is_shuffle));
 
-    Ok(dag)
+    Ok(stages)
+}
+
+/// Partitions the inputs of a join or a union on their own, and zips their
+/// stages from the bottom up, so that every input reads its data source in the
+/// bottom stage. The inputs with fewer stages pass their outputs through the
+/// upper stages with an empty memory source.
+fn partition_inputs(inputs: &[Arc<dyn ExecutionPlan>]) -> Result<Vec<StagePlans>> {
+    let partitions = inputs
+        .iter()
+        .map(|input| partition_plan(input.clone()))
+        .collect::<Result<Vec<_>>>()?;
+    let depth = partitions.iter().map(|p| p.len()).max().unwrap_or_default();
+
+    (0..depth)
+        .map(|level| {
+            let mut nodes = vec![];
+            let mut function_type = CloudFunctionType::Lambda;
+            let mut is_shuffle = false;
+            for (input, stages) in inputs.iter().zip(&partitions) {
+                let padding = depth - stages.len();
+                if level < padding {
+                    let passthrough: Arc<dyn ExecutionPlan> =
+                        Arc::new(MemoryExec::try_new(&[], input.schema(), None)?);
+                    nodes.push(serde_json::to_value(passthrough)?);
+                } else {
+                    let (plans, stage_type, shuffle) = &stages[level - padding];
+                    nodes.extend(plans.iter().cloned());
+                    is_shuffle |= *shuffle;
+                    if *stage_type == CloudFunctionType::Group {
+                        function_type = CloudFunctionType::Group;
+                    }
+                }
+            }
+            Ok((nodes, function_type, is_shuffle))
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -382,6 +523,15 @@ mod tests {
     use datafusion::physical_plan::displayable;
     use std::sync::Arc;
 
+    use crate::datasource::epoch::Epoch;
+    use crate::datasource::nexmark::event::{Auction, Bid};
+    use crate::datasource::nexmark::NEXMarkSource;
+    use crate::runtime::plan::{physical_plan, CloudExecutionPlan};
+    use crate::stream::Window;
+    use crate::transmute::event_bytes_to_batch;
+    use datafusion::arrow::util::pretty::pretty_format_batches;
+    use indoc::indoc;
+
     async fn quick_init(sql: &str) -> Result<QueryDag> {
         let schema = Arc::new(Schema::new(vec![
             Field::new("c1", DataType::Int64, false),
@@ -578,7 +728,11 @@ mod tests {
         //       HashAggregateExec: mode=Partial, gby=[], aggr=[MIN(test_table.c1), AVG(test_table.c4), COUNT(test_table.c3)]
         //         RepartitionExec: partitioning=RoundRobinBatch(16)
         //           MemoryExec: partitions=1, partition_sizes=[1]
-        quick_init(sql).await?;
+        let dag = quick_init(sql).await?;
+
+        // The partial aggregates are merged into one partition.
+        assert_eq!(2, dag.node_count());
+        assert!(dag.get_all_stages().iter().all(|s| !s.is_shuffle_stage));
         Ok(())
     }
 
@@ -613,12 +767,18 @@ mod tests {
             .contains("HashAggregateExec: mode=FinalPartitioned"));
         assert!(subplan.get_plan_str().contains("MemoryExec"));
 
+        assert!(!subplan.is_shuffle_stage);
+
         subplan = iter.next().unwrap();
         assert!(subplan
             .get_plan_str()
             .contains("HashAggregateExec: mode=Partial"));
         assert!(subplan.get_plan_str().contains("MemoryExec"));
 
+        // The partial aggregates are hash partitioned by the group keys for
+        // the final aggregate.
+        assert!(subplan.is_shuffle_stage);
+
         Ok(())
     }
 
@@ -948,4 +1108,385 @@ mod tests {
 
         Ok(())
     }
+
+    // Union
+    // Mem -> Proj -> Union <- Proj <- Mem
+    #[tokio::test]
+    async fn union_all_in_one_stage() -> Result<()> {
+        let sql = concat!(
+            "SELECT c1, c2 FROM test_table ",
+            "UNION ALL ",
+            "SELECT neg, c2 FROM test_table"
+        );
+        let dag = &mut quick_init(sql).await?;
+
+        assert_eq!(1, dag.node_count());
+        assert_eq!(0, dag.edge_count());
+
+        let subplan = dag.node_weight(NodeIndex::new(0)).unwrap();
+        assert_eq!(1, subplan.len());
+        assert!(subplan.get_plan_str().contains("UnionExec"));
+        assert_eq!(2, subplan.get_plan_str().matches("MemoryExec").count());
+
+        Ok(())
+    }
+
+    // Union
+    // Mem -> HashAgg -> HashAgg -> Union <- Proj <- Mem
+    #[tokio::test]
+    async fn union_all_with_aggregate() -> Result<()> {
+        let sql = concat!(
+            "SELECT c3, MAX(c1) FROM test_table GROUP BY c3 ",
+            "UNION ALL ",
+            "SELECT c5, neg FROM test_table"
+        );
+        let dag = &mut quick_init(sql).await?;
+
+        assert_eq!(3, dag.node_count());
+        assert_eq!(2, dag.edge_count());
+
+        // The union stage reads both inputs from the upstream stage.
+        let subplan = dag.node_weight(NodeIndex::new(0)).unwrap();
+        assert_eq!(1, subplan.len());
+        assert!(subplan.get_plan_str().contains("UnionExec"));
+        assert!(!subplan.get_plan_str().contains("HashAggregateExec"));
+        assert_eq!(2, subplan.get_plan_str().matches("MemoryExec").count());
+
+        // The upstream stages hold one subplan per union input. The input
+        // without the aggregate passes its output through the upper one.
+        let subplan = dag.node_weight(NodeIndex::new(1)).unwrap();
+        assert_eq!(2, subplan.len());
+        assert!(subplan
+            .get_plan_str()
+            .contains("HashAggregateExec: mode=FinalPartitioned"));
+        assert!(!subplan.get_plan_str().contains("mode=Partial"));
+        assert_eq!(2, subplan.get_plan_str().matches("MemoryExec").count());
+        assert_eq!(
+            vec!["memory_exec".to_string()],
+            PlanProperties::analyze(&subplan[1]).operators
+        );
+
+        let subplan = dag.node_weight(NodeIndex::new(2)).unwrap();
+        assert_eq!(2, subplan.len());
+        assert!(subplan.get_plan_str().contains("mode=Partial"));
+        assert!(!subplan.get_plan_str().contains("mode=FinalPartitioned"));
+        assert!(subplan.get_plan_str().contains("ProjectionExec"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cross_join_with_aggregate() -> Result<()> {
+        let sql = concat!(
+            "SELECT * FROM ",
+            "(SELECT c3, MAX(c1) FROM test_table GROUP BY c3) AS a ",
+            "CROSS JOIN ",
+            "(SELECT c5 FROM test_table) AS b"
+        );
+        match quick_init(sql).await {
+            Err(FlockError::DagPartition { operator, .. }) => {
+                assert_eq!("cross_join_exec", operator)
+            }
+            other => panic!("unexpected result: {:?}", other.map(|dag| dag.node_count())),
+        }
+
+        // The cross join stays in one stage with the inputs without boundaries.
+        let sql = concat!(
+            "SELECT * FROM ",
+            "(SELECT c3 FROM test_table WHERE c1 > 91) AS a ",
+            "CROSS JOIN ",
+            "(SELECT c5 FROM test_table) AS b"
+        );
+        assert_eq!(1, quick_init(sql).await?.node_count());
+
+        Ok(())
+    }
+
+    /// Returns the physical plan of the NEXMark query, and the data sources of
+    /// the auctions and the bids of the first second.
+    async fn nexmark_plan(
+        sql: &str,
+    ) -> Result<(
+        Arc<dyn ExecutionPlan>,
+        Vec<Vec<RecordBatch>>,
+        Vec<Vec<RecordBatch>>,
+    )> {
+        let nex = NEXMarkSource::new(1, 1, 1000, Window::ElementWise);
+        let events = nex.generate_data()?;
+
+        let auction_schema = Arc::new(Auction::schema());
+        let bid_schema = Arc::new(Bid::schema());
+        let (auctions, _) = events
+            .auctions
+            .get(&Epoch::new(0))
+            .unwrap()
+            .get(&0)
+            .unwrap();
+        let auctions = vec![event_bytes_to_batch(auctions, auction_schema.clone(), 1024)];
+        let (bids, _) = events.bids.get(&Epoch::new(0)).unwrap().get(&0).unwrap();
+        let bids = vec![event_bytes_to_batch(bids, bid_schema.clone(), 1024)];
+
+        let mut ctx = ExecutionContext::new();
+        let auction_table = MemTable::try_new(auction_schema, auctions.clone())?;
+        ctx.register_table("auction", Arc::new(auction_table))?;
+        let bid_table = MemTable::try_new(bid_schema, bids.clone())?;
+        ctx.register_table("bid", Arc::new(bid_table))?;
+
+        let plan = physical_plan(&ctx, sql).await?;
+        println!(
+            "=== Physical Plan ===\n{}\n",
+            displayable(plan.as_ref()).indent()
+        );
+        Ok((plan, auctions, bids))
+    }
+
+    /// Returns the operators of the query stage in the depth-first order.
+    fn stage_operators(dag: &QueryDag, node: usize) -> Vec<String> {
+        PlanProperties::analyze_all(dag.get_node(NodeIndex::new(node)).unwrap()).operators
+    }
+
+    /// Executes the query stages of the DAG one by one from the bottom up,
+    /// like the cloud functions do, and returns the output of the last stage.
+    async fn simulate(
+        dag: &QueryDag,
+        sources: Vec<Vec<Vec<RecordBatch>>>,
+    ) -> Result<Vec<RecordBatch>> {
+        let mut inputs = sources;
+        for node in (0..dag.node_count()).rev() {
+            let stage = dag.get_node(NodeIndex::new(node)).unwrap();
+            let ctx = crate::runtime::context::ExecutionContext {
+                plan: CloudExecutionPlan::new(stage.to_vec(), None),
+                ..Default::default()
+            };
+            ctx.feed_data_sources(inputs).await?;
+            inputs = ctx
+                .execute()
+                .await?
+                .into_iter()
+                .map(|batches| vec![batches])
+                .collect();
+        }
+        Ok(inputs.pop().unwrap().pop().unwrap())
+    }
+
+    /// Returns the sorted lines of the pretty-printed batches.
+    fn sorted_lines(batches: &[RecordBatch]) -> Result<Vec<String>> {
+        let mut lines = pretty_format_batches(batches)?
+            .lines()
+            .map(|line| line.to_string())
+            .collect::<Vec<_>>();
+        lines.sort();
+        Ok(lines)
+    }
+
+    #[tokio::test]
+    async fn nexmark_q6_v2_stages() -> Result<()> {
+        let sql = indoc! {"
+            SELECT seller,
+                   Avg(final)
+            FROM   (SELECT ROW_NUMBER()
+                            OVER (
+                                PARTITION BY seller
+                                ORDER BY date_time DESC) AS row,
+                            seller,
+                            final
+                    FROM   (SELECT  seller,
+                                    Max(price)       AS final,
+                                    Max(b_date_time) AS date_time
+                            FROM    auction
+                                    INNER JOIN bid
+                                            ON a_id = auction
+                            WHERE  b_date_time BETWEEN a_date_time AND expires
+                            GROUP  BY a_id,
+                                    seller) AS Q) AS R
+            WHERE  row <= 10
+            GROUP  BY seller;
+        "};
+        let (plan, _, _) = nexmark_plan(sql).await?;
+        let dag = QueryDag::from(plan)?;
+        for (i, stage) in dag.get_all_stages().iter().rev().enumerate() {
+            println!("=== Query Stage {} ===\n{}", i, stage.get_plan_str());
+        }
+
+        assert_eq!(5, dag.node_count());
+        assert_eq!(4, dag.edge_count());
+
+        // The final aggregate by seller.
+        let stage = dag.get_node(NodeIndex::new(0)).unwrap();
+        assert_eq!(CloudFunctionType::Group, stage.function_type);
+        assert!(stage
+            .get_plan_str()
+            .contains("HashAggregateExec: mode=FinalPartitioned"));
+        let operators = stage_operators(&dag, 0);
+        assert_eq!(
+            1,
+            operators
+                .iter()
+                .filter(|o| *o == "hash_aggregate_exec")
+                .count()
+        );
+        assert_eq!(Some(&"memory_exec".to_string()), operators.last());
+
+        // The window function with its sort, and the partial aggregate by seller.
+        let stage = dag.get_node(NodeIndex::new(1)).unwrap();
+        assert_eq!(CloudFunctionType::Group, stage.function_type);
+        assert!(stage
+            .get_plan_str()
+            .contains("HashAggregateExec: mode=Partial"));
+        let operators = stage_operators(&dag, 1);
+        let window = operators
+            .iter()
+            .position(|o| o == "window_agg_exec")
+            .expect("the window function isn't in the stage");
+        assert_eq!(
+            vec!["window_agg_exec", "sort_exec", "memory_exec"],
+            operators[window..]
+        );
+        assert_eq!(
+            1,
+            operators
+                .iter()
+                .filter(|o| *o == "hash_aggregate_exec")
+                .count()
+        );
+
+        // The final aggregate by auction and seller.
+        let stage = dag.get_node(NodeIndex::new(2)).unwrap();
+        assert_eq!(CloudFunctionType::Group, stage.function_type);
+        assert!(stage
+            .get_plan_str()
+            .contains("HashAggregateExec: mode=FinalPartitioned"));
+        let operators = stage_operators(&dag, 2);
+        assert!(operators.contains(&"coalesce_partitions_exec".to_string()));
+        assert!(!operators.contains(&"sort_exec".to_string()));
+        assert!(!operators.contains(&"hash_join_exec".to_string()));
+
+        // The join, and the partial aggregate by auction and seller.
+        let stage = dag.get_node(NodeIndex::new(3)).unwrap();
+        assert!(stage
+            .get_plan_str()
+            .contains("HashAggregateExec: mode=Partial"));
+        let operators = stage_operators(&dag, 3);
+        assert_eq!(
+            1,
+            operators.iter().filter(|o| *o == "hash_join_exec").count()
+        );
+        assert_eq!(2, operators.iter().filter(|o| *o == "memory_exec").count());
+
+        // The inputs of the join.
+        let stage = dag.get_node(NodeIndex::new(4)).unwrap();
+        assert_eq!(CloudFunctionType::Lambda, stage.function_type);
+        assert_eq!(2, stage.len());
+        let operators = stage_operators(&dag, 4);
+        assert!(!operators.contains(&"hash_aggregate_exec".to_string()));
+        assert_eq!(2, operators.iter().filter(|o| *o == "memory_exec").count());
+
+        Ok(())
+    }
+
+    /// NEXMark Q5: the auctions with the most bids.
+    const NEXMARK_Q5: &str = indoc! {"
+        SELECT auction,
+               num
+        FROM   (SELECT auction,
+                       Count(*) AS num
+                FROM   bid
+                GROUP  BY auction) AS AuctionBids
+               INNER JOIN (SELECT Max(num) AS maxn
+                           FROM   (SELECT auction,
+                                          Count(*) AS num
+                                   FROM   bid
+                                   GROUP  BY auction) AS CountBids) AS MaxBids
+                       ON num = maxn;
+    "};
+
+    #[tokio::test]
+    async fn nexmark_q5_stages() -> Result<()> {
+        let sql = NEXMARK_Q5;
+        let (plan, _, _) = nexmark_plan(sql).await?;
+        let dag = QueryDag::from(plan)?;
+        for (i, stage) in dag.get_all_stages().iter().rev().enumerate() {
+            println!("=== Query Stage {} ===\n{}", i, stage.get_plan_str());
+        }
+
+        assert_eq!(4, dag.node_count());
+        assert_eq!(3, dag.edge_count());
+
+        // The join of the bid counts and their maximum.
+        let operators = stage_operators(&dag, 0);
+        assert_eq!(
+            1,
+            operators.iter().filter(|o| *o == "hash_join_exec").count()
+        );
+        assert_eq!(2, operators.iter().filter(|o| *o == "memory_exec").count());
+        assert!(!operators.contains(&"hash_aggregate_exec".to_string()));
+
+        // The maximum of the bid counts, while the bid counts pass through.
+        let stage = dag.get_node(NodeIndex::new(1)).unwrap();
+        assert_eq!(CloudFunctionType::Group, stage.function_type);
+        assert_eq!(2, stage.len());
+        assert!(stage
+            .iter()
+            .any(|plan| PlanProperties::analyze(plan).operators == vec!["memory_exec"]));
+        assert!(stage
+            .get_plan_str()
+            .contains("HashAggregateExec: mode=Final,"));
+        let operators = stage_operators(&dag, 1);
+        assert_eq!(
+            1,
+            operators
+                .iter()
+                .filter(|o| *o == "hash_aggregate_exec")
+                .count()
+        );
+
+        // The final bid counts of both inputs, and the partial maximum.
+        let stage = dag.get_node(NodeIndex::new(2)).unwrap();
+        assert_eq!(CloudFunctionType::Group, stage.function_type);
+        assert_eq!(2, stage.len());
+        assert!(stage
+            .get_plan_str()
+            .contains("HashAggregateExec: mode=FinalPartitioned"));
+        let operators = stage_operators(&dag, 2);
+        assert_eq!(
+            3,
+            operators
+                .iter()
+                .filter(|o| *o == "hash_aggregate_exec")
+                .count()
+        );
+        assert_eq!(2, operators.iter().filter(|o| *o == "memory_exec").count());
+
+        // The partial bid counts of both inputs.
+        let stage = dag.get_node(NodeIndex::new(3)).unwrap();
+        assert_eq!(CloudFunctionType::Lambda, stage.function_type);
+        assert_eq!(2, stage.len());
+        assert!(!stage.get_plan_str().contains("mode=Final"));
+        let operators = stage_operators(&dag, 3);
+        assert_eq!(
+            2,
+            operators
+                .iter()
+                .filter(|o| *o == "hash_aggregate_exec")
+                .count()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nexmark_q5_distributed() -> Result<()> {
+        let sql = NEXMARK_Q5;
+        let (plan, _, bids) = nexmark_plan(sql).await?;
+        let dag = QueryDag::from(plan.clone())?;
+
+        // Both inputs of the join read the bids.
+        let distributed = simulate(&dag, vec![bids.clone(), bids]).await?;
+        let local = collect(plan).await?;
+
+        assert!(local.iter().map(|b| b.num_rows()).sum::<usize>() > 0);
+        assert_eq!(sorted_lines(&local)?, sorted_lines(&distributed)?);
+
+        Ok(())
+    }
 }
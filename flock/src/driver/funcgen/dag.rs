@@ -17,6 +17,7 @@
 extern crate daggy;
 use daggy::{Dag, NodeIndex, Walker};
 
+use crate::driver::funcgen::estimate::{estimate_dag, Selectivity, SourceRate, VolumeEstimate};
 use crate::error::{FlockError, Result};
 use datafusion::arrow::datatypes::Schema;
 use datafusion::physical_plan::memory::MemoryExec;
@@ -47,6 +48,9 @@ pub struct DagNode {
     pub plan:        Arc<dyn ExecutionPlan>,
     /// Function concurrency in cloud environment.
     pub concurrency: usize,
+    /// The estimated data volume of the subplan for each window, if it's
+    /// estimated (see [`QueryDag::estimate_volumes`]).
+    pub estimate:    Option<VolumeEstimate>,
 }
 
 impl DagNode {
@@ -61,6 +65,7 @@ impl From<Arc<dyn ExecutionPlan>> for DagNode {
         DagNode {
             plan:        p.clone(),
             concurrency: CONCURRENCY_8,
+            estimate:    None,
         }
     }
 }
@@ -181,6 +186,15 @@ impl QueryDag {
         children
     }
 
+    /// Annotates all nodes with the estimates of their data volume for each
+    /// window (see [`estimate_dag`]).
+    pub fn estimate_volumes(&mut self, rate: &SourceRate, selectivity: &Selectivity) {
+        let estimates = estimate_dag(&self.dag, |node| vec![node.plan.clone()], rate, selectivity);
+        for (node, estimate) in estimates {
+            self.dag[node].estimate = Some(estimate);
+        }
+    }
+
     /// Return the internal daggy.
     pub fn context(&mut self) -> &mut DagPlan {
         &mut self.dag
@@ -200,6 +214,7 @@ impl QueryDag {
             Ok(self.add_node(DagNode {
                 plan: serde_json::from_value(node)?,
                 concurrency,
+                estimate: None,
             }))
         } else {
             // TODO: call add_parent instead of add_child
@@ -208,6 +223,7 @@ impl QueryDag {
                 DagNode {
                     plan: serde_json::from_value(node)?,
                     concurrency,
+                    estimate: None,
                 },
             ))
         }
@@ -304,7 +320,7 @@ mod tests {
         let input = include_str!("../../tests/data/example-kinesis-event-1.json");
         let input: KinesisEvent = serde_json::from_str(input).unwrap();
 
-        let partitions = vec![kinesis::to_batch(input)];
+        let partitions = vec![kinesis::to_batch(input, false)];
 
         let mut ctx = ExecutionContext::new();
 
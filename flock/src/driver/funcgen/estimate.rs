@@ -0,0 +1,515 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The estimates of the data volume of the query stages, which are used to
+//! size the memory of the cloud functions before any data is seen.
+//!
+//! The rows of each window enter the source stage at the event rate of the
+//! data source, and flow through the operators of the stages with the default
+//! selectivity of each operator type. The bytes are the rows times the row
+//! widths of the schemas, where the variable-width values are assumed to take
+//! [`VARIABLE_WIDTH`] bytes.
+
+use crate::configs::FLOCK_MEMORY_TABLE;
+use crate::error::{FlockError, Result};
+use daggy::{Dag, NodeIndex, Walker};
+use datafusion::arrow::datatypes::{DataType, Schema};
+use datafusion::physical_plan::cross_join::CrossJoinExec;
+use datafusion::physical_plan::filter::FilterExec;
+use datafusion::physical_plan::hash_aggregate::HashAggregateExec;
+use datafusion::physical_plan::hash_join::HashJoinExec;
+use datafusion::physical_plan::ExecutionPlan;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The assumed width in bytes of the variable-width values, e.g. strings.
+pub const VARIABLE_WIDTH: usize = 32;
+
+/// The price of AWS Lambda per GB-second on x86_64.
+pub const X86_64_PRICE_PER_GB_SECOND: f64 = 0.000_016_666_7;
+
+/// The price of AWS Lambda per GB-second on arm64.
+pub const ARM64_PRICE_PER_GB_SECOND: f64 = 0.000_013_333_4;
+
+/// The rate of the data source.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceRate {
+    /// The number of events per second.
+    pub events_per_second: usize,
+    /// The length of the window in seconds.
+    pub window_seconds:    usize,
+}
+
+impl SourceRate {
+    /// Returns the number of events of each window.
+    pub fn window_rows(&self) -> f64 {
+        (self.events_per_second * self.window_seconds) as f64
+    }
+}
+
+/// The default selectivity of each operator type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Selectivity {
+    /// The fraction of the rows kept by a filter.
+    pub filter: f64,
+    /// The output rows of a join per row of its larger input.
+    pub join:   f64,
+    /// The number of groups of an aggregate with group-by columns.
+    pub groups: f64,
+}
+
+impl Default for Selectivity {
+    fn default() -> Self {
+        Selectivity {
+            filter: 0.25,
+            join:   1.0,
+            groups: 1000.0,
+        }
+    }
+}
+
+/// The estimated data volume of a query stage for each window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VolumeEstimate {
+    /// The number of input rows.
+    pub input_rows:   u64,
+    /// The size of the input in bytes.
+    pub input_bytes:  u64,
+    /// The number of output rows.
+    pub output_rows:  u64,
+    /// The size of the output in bytes.
+    pub output_bytes: u64,
+}
+
+impl VolumeEstimate {
+    /// Returns the larger of the input and the output size in bytes, which is
+    /// held in memory at once.
+    pub fn peak_bytes(&self) -> u64 {
+        self.input_bytes.max(self.output_bytes)
+    }
+}
+
+impl fmt::Display for VolumeEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "input: {} rows ({} bytes), output: {} rows ({} bytes)",
+            self.input_rows, self.input_bytes, self.output_rows, self.output_bytes
+        )
+    }
+}
+
+/// Returns the estimated width in bytes of a value of the data type.
+pub fn type_width(data_type: &DataType) -> usize {
+    match data_type {
+        DataType::Null => 0,
+        DataType::Boolean | DataType::Int8 | DataType::UInt8 => 1,
+        DataType::Int16 | DataType::UInt16 | DataType::Float16 => 2,
+        DataType::Int32
+        | DataType::UInt32
+        | DataType::Float32
+        | DataType::Date32
+        | DataType::Time32(_) => 4,
+        DataType::Decimal(_, _) => 16,
+        DataType::FixedSizeBinary(n) => *n as usize,
+        DataType::FixedSizeList(field, n) => type_width(field.data_type()) * *n as usize,
+        DataType::Struct(fields) => fields.iter().map(|f| type_width(f.data_type())).sum(),
+        DataType::Dictionary(key, _) => type_width(key),
+        DataType::Utf8
+        | DataType::LargeUtf8
+        | DataType::Binary
+        | DataType::LargeBinary
+        | DataType::List(_)
+        | DataType::LargeList(_) => VARIABLE_WIDTH,
+        _ => 8,
+    }
+}
+
+/// Returns the estimated width in bytes of a row of the schema.
+pub fn row_width(schema: &Schema) -> usize {
+    schema
+        .fields()
+        .iter()
+        .map(|f| type_width(f.data_type()))
+        .sum()
+}
+
+/// Returns the estimated output rows of the plan, whose leaves share the input
+/// rows evenly.
+fn estimate_rows(plan: &Arc<dyn ExecutionPlan>, leaf_rows: f64, selectivity: &Selectivity) -> f64 {
+    let children = plan
+        .children()
+        .iter()
+        .map(|child| estimate_rows(child, leaf_rows, selectivity))
+        .collect::<Vec<_>>();
+    if children.is_empty() {
+        return leaf_rows;
+    }
+
+    let any = plan.as_any();
+    if any.is::<FilterExec>() {
+        children[0] * selectivity.filter
+    } else if any.is::<HashJoinExec>() {
+        children.iter().cloned().fold(0.0, f64::max) * selectivity.join
+    } else if any.is::<CrossJoinExec>() {
+        children.iter().product()
+    } else if let Some(agg) = any.downcast_ref::<HashAggregateExec>() {
+        if agg.group_expr().is_empty() {
+            1.0
+        } else {
+            children[0].min(selectivity.groups)
+        }
+    } else {
+        children.iter().sum()
+    }
+}
+
+/// Returns the number of leaves of the plan.
+fn num_leaves(plan: &Arc<dyn ExecutionPlan>) -> usize {
+    match plan.children() {
+        children if children.is_empty() => 1,
+        children => children.iter().map(num_leaves).sum(),
+    }
+}
+
+/// Returns the input bytes of the leaves of the plan, which share the input
+/// rows evenly.
+fn leaf_bytes(plan: &Arc<dyn ExecutionPlan>, leaf_rows: f64) -> f64 {
+    match plan.children() {
+        children if children.is_empty() => leaf_rows * row_width(&plan.schema()) as f64,
+        children => children.iter().map(|c| leaf_bytes(c, leaf_rows)).sum(),
+    }
+}
+
+/// Estimates the data volume of a query stage.
+///
+/// # Arguments
+/// * `plans` - The execution plans of the stage.
+/// * `input` - The input rows and bytes of the stage, i.e. the output of the
+///   former stages, or `None` if the stage reads the data source, whose input
+///   bytes are then estimated from the schemas of its leaves.
+/// * `rows` - The number of input rows of the source stage.
+/// * `selectivity` - The default selectivity of the operators.
+pub fn estimate_stage(
+    plans: &[Arc<dyn ExecutionPlan>],
+    input: Option<(u64, u64)>,
+    rows: f64,
+    selectivity: &Selectivity,
+) -> VolumeEstimate {
+    let input_rows = input.map_or(rows, |(rows, _)| rows as f64);
+    let leaves = plans.iter().map(num_leaves).sum::<usize>().max(1);
+    let leaf_rows = input_rows / leaves as f64;
+    let input_bytes = input.map_or_else(
+        || plans.iter().map(|p| leaf_bytes(p, leaf_rows)).sum::<f64>(),
+        |(_, bytes)| bytes as f64,
+    );
+
+    let mut output_rows = 0.0;
+    let mut output_bytes = 0.0;
+    for plan in plans {
+        let rows = estimate_rows(plan, leaf_rows, selectivity);
+        output_rows += rows;
+        output_bytes += rows * row_width(&plan.schema()) as f64;
+    }
+    VolumeEstimate {
+        input_rows:   input_rows.round() as u64,
+        input_bytes:  input_bytes.round() as u64,
+        output_rows:  output_rows.round() as u64,
+        output_bytes: output_bytes.round() as u64,
+    }
+}
+
+/// Estimates the data volume of all nodes of the DAG, whose edges point from
+/// the stages to the stages of their input. The nodes without children read
+/// the data source at the given rate, and the others read the output of their
+/// children.
+///
+/// # Arguments
+/// * `dag` - The DAG of the query stages.
+/// * `plans` - Returns the execution plans of a node.
+/// * `rate` - The rate of the data source.
+/// * `selectivity` - The default selectivity of the operators.
+pub fn estimate_dag<N, E, F>(
+    dag: &Dag<N, E>,
+    plans: F,
+    rate: &SourceRate,
+    selectivity: &Selectivity,
+) -> HashMap<NodeIndex, VolumeEstimate>
+where
+    F: Fn(&N) -> Vec<Arc<dyn ExecutionPlan>>,
+{
+    fn visit<N, E, F>(
+        dag: &Dag<N, E>,
+        node: NodeIndex,
+        plans: &F,
+        rate: &SourceRate,
+        selectivity: &Selectivity,
+        estimates: &mut HashMap<NodeIndex, VolumeEstimate>,
+    ) -> VolumeEstimate
+    where
+        F: Fn(&N) -> Vec<Arc<dyn ExecutionPlan>>,
+    {
+        if let Some(estimate) = estimates.get(&node) {
+            return *estimate;
+        }
+        let children = dag
+            .children(node)
+            .iter(dag)
+            .map(|(_, n)| n)
+            .collect::<Vec<_>>();
+        let input = (!children.is_empty()).then(|| {
+            children
+                .into_iter()
+                .map(|n| visit(dag, n, plans, rate, selectivity, estimates))
+                .fold((0, 0), |(rows, bytes), e| {
+                    (rows + e.output_rows, bytes + e.output_bytes)
+                })
+        });
+        let estimate = estimate_stage(&plans(&dag[node]), input, rate.window_rows(), selectivity);
+        estimates.insert(node, estimate);
+        estimate
+    }
+
+    let mut estimates = HashMap::new();
+    for i in 0..dag.node_count() {
+        visit(
+            dag,
+            NodeIndex::new(i),
+            &plans,
+            rate,
+            selectivity,
+            &mut estimates,
+        );
+    }
+    estimates
+}
+
+/// Returns the estimated cost of a run in USD, i.e. the GB-seconds of the
+/// deployed functions over the whole run. It's an upper bound of the billed
+/// cost, which is only meant to compare the runs with the same options.
+///
+/// # Arguments
+/// * `latency` - The end-to-end latency of the run.
+/// * `functions` - The memory size in MB and the number of the deployed
+///   functions of each kind.
+/// * `architecture` - The architecture of the functions.
+pub fn estimated_cost(latency: Duration, functions: &[(i64, usize)], architecture: &str) -> f64 {
+    let price = if architecture == "arm64" {
+        ARM64_PRICE_PER_GB_SECOND
+    } else {
+        X86_64_PRICE_PER_GB_SECOND
+    };
+    let gigabytes = functions
+        .iter()
+        .map(|(memory_size, count)| *memory_size as f64 / 1024.0 * *count as f64)
+        .sum::<f64>();
+    gigabytes * latency.as_secs_f64() * price
+}
+
+/// The table that maps the estimated data volume of a stage to the memory size
+/// of its functions, e.g. `64:256,512:1769,*:3008` for 256 MB below 64 MB of
+/// data, 1769 MB below 512 MB, and 3008 MB otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryTable {
+    /// The upper bounds of the data volume in bytes, exclusive, and the memory
+    /// sizes in MB, in ascending order.
+    pub tiers:   Vec<(u64, i64)>,
+    /// The memory size in MB above the last bound.
+    pub largest: i64,
+}
+
+impl Default for MemoryTable {
+    fn default() -> Self {
+        MemoryTable {
+            tiers:   vec![(64 << 20, 256), (512 << 20, 1769)],
+            largest: 3008,
+        }
+    }
+}
+
+impl MemoryTable {
+    /// Returns the memory table in the configuration.
+    pub fn from_conf() -> Result<Self> {
+        FLOCK_MEMORY_TABLE.parse()
+    }
+
+    /// Returns the memory size in MB of the functions of the stage.
+    pub fn memory_size(&self, estimate: &VolumeEstimate) -> i64 {
+        let bytes = estimate.peak_bytes();
+        self.tiers
+            .iter()
+            .find(|(bound, _)| bytes < *bound)
+            .map_or(self.largest, |(_, memory_size)| *memory_size)
+    }
+}
+
+impl FromStr for MemoryTable {
+    type Err = FlockError;
+
+    /// Parses the comma separated `<volume MB>:<memory MB>` pairs, the last of
+    /// which is `*:<memory MB>`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            FlockError::Execution(format!(
+                "Invalid memory table {:?}, expected e.g. 64:256,512:1769,*:3008",
+                s
+            ))
+        };
+        let mut tiers = vec![];
+        let mut largest = None;
+        for tier in s.split(',').map(|t| t.trim()) {
+            let (bound, memory_size) = tier.split_once(':').ok_or_else(invalid)?;
+            let memory_size = memory_size.trim().parse::<i64>().map_err(|_| invalid())?;
+            match (bound.trim(), largest) {
+                ("*", None) => largest = Some(memory_size),
+                (bound, None) => {
+                    let bound = bound.parse::<u64>().map_err(|_| invalid())? << 20;
+                    if tiers.last().map_or(false, |(last, _)| *last >= bound) {
+                        return Err(invalid());
+                    }
+                    tiers.push((bound, memory_size));
+                }
+                (_, Some(_)) => return Err(invalid()),
+            }
+        }
+        Ok(MemoryTable {
+            tiers,
+            largest: largest.ok_or_else(invalid)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasource::nexmark::register_nexmark_tables;
+    use crate::driver::funcgen::dag::QueryDag;
+    use crate::runtime::plan::physical_plan;
+    use datafusion::arrow::datatypes::{Field, TimeUnit};
+
+    #[test]
+    fn row_widths() {
+        let schema = Schema::new(vec![
+            Field::new("a", DataType::Int32, false),
+            Field::new("b", DataType::Utf8, false),
+            Field::new("c", DataType::Timestamp(TimeUnit::Millisecond, None), false),
+            Field::new("d", DataType::Boolean, false),
+        ]);
+        assert_eq!(4 + VARIABLE_WIDTH + 8 + 1, row_width(&schema));
+        assert_eq!(0, row_width(&Schema::empty()));
+    }
+
+    #[test]
+    fn estimate_cost() {
+        let latency = Duration::from_secs(10);
+        let cost = estimated_cost(latency, &[(1024, 2)], "x86_64");
+        assert!((cost - 20.0 * X86_64_PRICE_PER_GB_SECOND).abs() < 1e-12);
+        assert!(estimated_cost(latency, &[(1024, 2)], "arm64") < cost);
+        assert_eq!(estimated_cost(latency, &[], "x86_64"), 0.0);
+    }
+
+    #[tokio::test]
+    async fn nexmark_q3_volumes() -> Result<()> {
+        let ctx = register_nexmark_tables().await?;
+        let sql = include_str!("../../../../benchmarks/src/nexmark/query/q3.sql");
+        let plan = physical_plan(&ctx, sql).await?;
+
+        // The DAG of Q3 is split at the join: node 1 joins the filtered auctions
+        // and persons, and node 0 projects the joined rows.
+        let mut dag = QueryDag::from(&plan);
+        assert_eq!(2, dag.node_count());
+        let rate = SourceRate {
+            events_per_second: 1000,
+            window_seconds:    10,
+        };
+        dag.estimate_volumes(&rate, &Selectivity::default());
+
+        // The 10,000 events of the window are split evenly among the two leaves:
+        // - auction: a_id, seller, category = 3 * 4 = 12 bytes per row
+        // - person: p_id (4), name, city, state (3 * 32) = 100 bytes per row
+        // Each filter keeps a quarter of the rows, and the join emits as many rows
+        // as its larger input, with both schemas (112 bytes per row).
+        let join = dag.get_node(NodeIndex::new(1)).unwrap().estimate.unwrap();
+        assert_eq!(
+            VolumeEstimate {
+                input_rows:   10_000,
+                input_bytes:  5_000 * 12 + 5_000 * 100,
+                output_rows:  1_250,
+                output_bytes: 1_250 * 112,
+            },
+            join
+        );
+
+        // The projection keeps name, city, state and a_id (100 bytes per row).
+        let projection = dag.get_node(NodeIndex::new(0)).unwrap().estimate.unwrap();
+        assert_eq!(
+            VolumeEstimate {
+                input_rows:   1_250,
+                input_bytes:  1_250 * 112,
+                output_rows:  1_250,
+                output_bytes: 1_250 * 100,
+            },
+            projection
+        );
+
+        let table = MemoryTable::default();
+        assert_eq!(256, table.memory_size(&join));
+        assert_eq!(256, table.memory_size(&projection));
+
+        // 100,000 events per second over a minute are 336 MB of input.
+        let rate = SourceRate {
+            events_per_second: 100_000,
+            window_seconds:    60,
+        };
+        dag.estimate_volumes(&rate, &Selectivity::default());
+        let join = dag.get_node(NodeIndex::new(1)).unwrap().estimate.unwrap();
+        assert_eq!(336_000_000, join.input_bytes);
+        assert_eq!(1769, table.memory_size(&join));
+
+        Ok(())
+    }
+
+    #[test]
+    fn memory_table() -> Result<()> {
+        let table = "64:256,512:1769,*:3008".parse::<MemoryTable>()?;
+        assert_eq!(MemoryTable::default(), table);
+
+        let estimate = |bytes: u64| VolumeEstimate {
+            input_bytes: bytes,
+            ..Default::default()
+        };
+        assert_eq!(256, table.memory_size(&estimate(0)));
+        assert_eq!(256, table.memory_size(&estimate((64 << 20) - 1)));
+        assert_eq!(1769, table.memory_size(&estimate(64 << 20)));
+        assert_eq!(3008, table.memory_size(&estimate(512 << 20)));
+
+        let table = "*:1024".parse::<MemoryTable>()?;
+        assert_eq!(1024, table.memory_size(&estimate(u64::MAX)));
+
+        for invalid in [
+            "",
+            "64:256",
+            "512:1769,64:256,*:3008",
+            "*:3008,64:256",
+            "64:256,*:1769,*:3008",
+            "64MB:256,*:3008",
+            "64:256MB,*:3008",
+        ] {
+            assert!(invalid.parse::<MemoryTable>().is_err(), "{}", invalid);
+        }
+        Ok(())
+    }
+}
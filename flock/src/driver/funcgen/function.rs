@@ -80,6 +80,7 @@ impl QueryFlow {
             DagNode {
                 plan:        plan.clone(),
                 concurrency: CONCURRENCY_1,
+                estimate:    None,
             },
         );
     }
@@ -153,6 +154,11 @@ impl QueryFlow {
                                 CloudFunction::Lambda(name)
                             }
                         },
+                        state_persistence: StatePersistence::for_stage(&[dag
+                            .get_node(parent)
+                            .unwrap()
+                            .plan
+                            .clone()]),
                         ..Default::default()
                     },
                 );
@@ -14,4 +14,5 @@
 //! Convert the physical plan into lambda functions for cloud execution.
 
 pub mod dag;
+pub mod estimate;
 // pub mod function;
@@ -0,0 +1,560 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The column-level lineage of a query.
+//!
+//! The analyzer walks the physical plan of the query, and tracks where each
+//! output column comes from: the projections map their output columns to the
+//! columns referenced by their expressions, the aggregations map the group-by
+//! columns and the aggregates to their inputs, the joins concatenate the
+//! columns of both sides, and the window functions append their results to
+//! the input columns. Each output column of the query is reported with the set
+//! of the stream columns it's derived from, and the operators that transform
+//! it in each stage of the query, numbered like the subplans of `flock-cli
+//! nexmark plan`, i.e. the stage reading the data source is stage 0 (see
+//! [`QueryDag`](crate::driver::funcgen::dag::QueryDag)).
+
+use crate::error::{FlockError, Result};
+use crate::query::Table;
+use datafusion::arrow::array::StringArray;
+use datafusion::arrow::datatypes::{DataType, Field, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::arrow::util::pretty::pretty_format_batches;
+use datafusion::physical_plan::expressions::Column;
+use datafusion::physical_plan::hash_aggregate::{AggregateMode, HashAggregateExec};
+use datafusion::physical_plan::hash_join::HashJoinExec;
+use datafusion::physical_plan::memory::MemoryExec;
+use datafusion::physical_plan::projection::ProjectionExec;
+use datafusion::physical_plan::windows::WindowAggExec;
+use datafusion::physical_plan::{ExecutionPlan, PhysicalExpr};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+/// The stream name of the columns read by a leaf that doesn't match any
+/// stream of the query.
+pub const UNKNOWN_STREAM: &str = "<unknown>";
+
+/// A column of a stream.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct SourceColumn {
+    /// The name of the stream.
+    pub stream: String,
+    /// The name of the column in the stream.
+    pub column: String,
+}
+
+/// An operator that transforms a column.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Transform {
+    /// The stage of the operator, starting from the stage reading the data
+    /// source.
+    pub stage:    usize,
+    /// The name of the operator, e.g. `HashAggregateExec`.
+    pub operator: String,
+    /// The expression that computes the column, or the join condition.
+    pub expr:     String,
+}
+
+/// The lineage of an output column of the query.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ColumnLineage {
+    /// The name of the output column.
+    pub column:     String,
+    /// The stream columns that the column is derived from.
+    pub sources:    BTreeSet<SourceColumn>,
+    /// The operators that transform the column, in the order of the stages.
+    pub transforms: BTreeSet<Transform>,
+}
+
+/// The column-level lineage of a query returned by [`QueryLineage::analyze`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct QueryLineage {
+    /// The number of stages of the query.
+    pub stages:  usize,
+    /// The lineage of the output columns in the order of the output schema.
+    pub columns: Vec<ColumnLineage>,
+}
+
+/// The lineage of a column of an intermediate plan. The stages of the
+/// transforms are the depths of the stages from the root of the plan until
+/// the analysis is finished.
+#[derive(Debug, Clone, Default)]
+struct Lineage {
+    sources:    BTreeSet<SourceColumn>,
+    transforms: BTreeSet<Transform>,
+}
+
+impl Lineage {
+    /// Merges the lineages of the input columns.
+    fn union<'a>(lineages: impl IntoIterator<Item = &'a Lineage>) -> Lineage {
+        let mut union = Lineage::default();
+        for lineage in lineages {
+            union.sources.extend(lineage.sources.iter().cloned());
+            union.transforms.extend(lineage.transforms.iter().cloned());
+        }
+        union
+    }
+
+    /// Adds the operator to the transforms of the column.
+    fn transform(mut self, depth: usize, operator: &str, expr: String) -> Lineage {
+        self.transforms.insert(Transform {
+            stage: depth,
+            operator: operator.to_owned(),
+            expr,
+        });
+        self
+    }
+}
+
+impl QueryLineage {
+    /// Returns the lineage of the output columns of the plan.
+    ///
+    /// # Arguments
+    /// * `plan` - The physical plan of the query.
+    /// * `streams` - The streams that the query reads, whose schemas identify
+    ///   the leaves of the plan.
+    pub fn analyze(plan: &Arc<dyn ExecutionPlan>, streams: &[Table]) -> Result<QueryLineage> {
+        let max_depth = stage_depth(plan, 0, true);
+        let lineages = visit(plan, 0, true, streams)?;
+        let columns = plan
+            .schema()
+            .fields()
+            .iter()
+            .zip(lineages.into_iter())
+            .map(|(field, lineage)| ColumnLineage {
+                column:     field.name().clone(),
+                sources:    lineage.sources,
+                transforms: lineage
+                    .transforms
+                    .into_iter()
+                    .map(|t| Transform {
+                        stage: max_depth - t.stage,
+                        ..t
+                    })
+                    .collect(),
+            })
+            .collect();
+        Ok(QueryLineage {
+            stages: max_depth + 1,
+            columns,
+        })
+    }
+
+    /// Returns the lineage as a record batch with a row per output column.
+    pub fn to_batch(&self) -> Result<RecordBatch> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("column", DataType::Utf8, false),
+            Field::new("sources", DataType::Utf8, false),
+            Field::new("transforms", DataType::Utf8, false),
+        ]));
+        let column = |f: &dyn Fn(&ColumnLineage) -> String| {
+            Arc::new(StringArray::from(
+                self.columns.iter().map(f).collect::<Vec<_>>(),
+            ))
+        };
+        Ok(RecordBatch::try_new(
+            schema,
+            vec![
+                column(&|c| c.column.clone()),
+                column(&|c| {
+                    c.sources
+                        .iter()
+                        .map(|s| format!("{}.{}", s.stream, s.column))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                }),
+                column(&|c| {
+                    c.transforms
+                        .iter()
+                        .map(|t| format!("[{}] {}: {}", t.stage, t.operator, t.expr))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }),
+            ],
+        )?)
+    }
+
+    /// Returns the lineage as a pretty table.
+    pub fn to_table(&self) -> Result<String> {
+        Ok(pretty_format_batches(&[self.to_batch()?])?.to_string())
+    }
+
+    /// Returns the lineage as a JSON document.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Returns the lineage of the output column.
+    pub fn column(&self, name: &str) -> Option<&ColumnLineage> {
+        self.columns.iter().find(|c| c.column == name)
+    }
+}
+
+/// Returns true if the aggregation is the final one, whose input is split
+/// into another stage.
+fn is_final_aggregate(plan: &Arc<dyn ExecutionPlan>) -> bool {
+    matches!(
+        plan.as_any().downcast_ref::<HashAggregateExec>(),
+        Some(agg) if *agg.mode() != AggregateMode::Partial
+    )
+}
+
+/// Returns the depth of the deepest stage of the plan, following the way the
+/// plan is split into subplans along its first inputs: the input of a final
+/// aggregation is a new stage, and the first join is a stage of its own.
+///
+/// # Arguments
+/// * `plan` - The plan.
+/// * `depth` - The depth of the stage of the plan.
+/// * `chain` - Whether the plan is on the path of the first inputs.
+fn stage_depth(plan: &Arc<dyn ExecutionPlan>, depth: usize, chain: bool) -> usize {
+    let (depth, chain) = node_depth(plan, depth, chain);
+    plan.children()
+        .iter()
+        .enumerate()
+        .map(|(i, child)| {
+            let (depth, chain) = child_depth(plan, depth, chain, i);
+            stage_depth(child, depth, chain)
+        })
+        .max()
+        .unwrap_or(depth)
+}
+
+/// Returns the depth of the stage of the plan itself, i.e. the first join on
+/// the path of the first inputs starts a new stage.
+fn node_depth(plan: &Arc<dyn ExecutionPlan>, depth: usize, chain: bool) -> (usize, bool) {
+    if chain && plan.as_any().is::<HashJoinExec>() {
+        (depth + 1, false)
+    } else {
+        (depth, chain)
+    }
+}
+
+/// Returns the depth of the stage of the i-th input of the plan.
+fn child_depth(
+    plan: &Arc<dyn ExecutionPlan>,
+    depth: usize,
+    chain: bool,
+    i: usize,
+) -> (usize, bool) {
+    match i {
+        0 if chain && is_final_aggregate(plan) => (depth + 1, true),
+        0 => (depth, chain),
+        _ => (depth, false),
+    }
+}
+
+/// Returns the indices of the input columns referenced by the expression.
+///
+/// The physical expressions don't expose their children, but the columns are
+/// displayed as `<name>@<index>`, e.g. `CAST(price@2 AS Float64)`.
+fn column_indices(expr: &dyn PhysicalExpr) -> Vec<usize> {
+    if let Some(column) = expr.as_any().downcast_ref::<Column>() {
+        return vec![column.index()];
+    }
+    let display = expr.to_string();
+    display
+        .match_indices('@')
+        .filter_map(|(i, _)| {
+            let digits = display[i + 1..]
+                .chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect::<String>();
+            digits.parse().ok()
+        })
+        .collect()
+}
+
+/// Returns the lineage of the input columns referenced by the expressions.
+fn referenced<'a>(
+    exprs: impl IntoIterator<Item = &'a Arc<dyn PhysicalExpr>>,
+    input: &[Lineage],
+) -> Lineage {
+    Lineage::union(
+        exprs
+            .into_iter()
+            .flat_map(|e| column_indices(e.as_ref()))
+            .filter_map(|i| input.get(i)),
+    )
+}
+
+/// Returns the lineage of each output column of the plan.
+fn visit(
+    plan: &Arc<dyn ExecutionPlan>,
+    depth: usize,
+    chain: bool,
+    streams: &[Table],
+) -> Result<Vec<Lineage>> {
+    let (depth, chain) = node_depth(plan, depth, chain);
+    let inputs = plan
+        .children()
+        .iter()
+        .enumerate()
+        .map(|(i, child)| {
+            let (depth, chain) = child_depth(plan, depth, chain, i);
+            visit(child, depth, chain, streams)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let name = |plan: &dyn ExecutionPlan| -> String {
+        let name = format!("{:?}", plan);
+        name.split(|c: char| !c.is_alphanumeric())
+            .next()
+            .unwrap_or_default()
+            .to_owned()
+    };
+    let fields = plan.schema().fields().clone();
+
+    if plan.as_any().is::<MemoryExec>() {
+        return Ok(leaf(&plan.schema(), streams));
+    }
+
+    if let Some(projection) = plan.as_any().downcast_ref::<ProjectionExec>() {
+        return Ok(projection
+            .expr()
+            .iter()
+            .map(|(expr, _)| {
+                let lineage = referenced([expr], &inputs[0]);
+                if expr.as_any().is::<Column>() {
+                    lineage
+                } else {
+                    lineage.transform(depth, "ProjectionExec", expr.to_string())
+                }
+            })
+            .collect());
+    }
+
+    if let Some(agg) = plan.as_any().downcast_ref::<HashAggregateExec>() {
+        let input = &inputs[0];
+        let groups = agg.group_expr().len();
+        let mut lineages = vec![];
+        if *agg.mode() == AggregateMode::Partial {
+            for (expr, _) in agg.group_expr() {
+                lineages.push(referenced([expr], input));
+            }
+            for aggr in agg.aggr_expr() {
+                let lineage = referenced(aggr.expressions().iter(), input).transform(
+                    depth,
+                    "HashAggregateExec",
+                    aggr.name().to_owned(),
+                );
+                // The partial aggregate has a column per state field.
+                for _ in 0..aggr.state_fields()?.len() {
+                    lineages.push(lineage.clone());
+                }
+            }
+        } else {
+            // The input of the final aggregation is the output of the partial one.
+            lineages.extend(input[..groups].iter().cloned());
+            let mut offset = groups;
+            for aggr in agg.aggr_expr() {
+                let states = aggr.state_fields()?.len();
+                let lineage = Lineage::union(&input[offset..offset + states]).transform(
+                    depth,
+                    "HashAggregateExec",
+                    aggr.name().to_owned(),
+                );
+                lineages.push(lineage);
+                offset += states;
+            }
+        }
+        return Ok(lineages);
+    }
+
+    if let Some(join) = plan.as_any().downcast_ref::<HashJoinExec>() {
+        let on = join
+            .on()
+            .iter()
+            .map(|(l, r)| format!("{} = {}", l.name(), r.name()))
+            .collect::<Vec<_>>()
+            .join(" AND ");
+        // The semi and anti joins only emit the columns of the left side.
+        let columns = inputs[0]
+            .iter()
+            .chain(inputs[1].iter())
+            .take(fields.len())
+            .map(|lineage| lineage.clone().transform(depth, "HashJoinExec", on.clone()))
+            .collect();
+        return Ok(columns);
+    }
+
+    if let Some(window) = plan.as_any().downcast_ref::<WindowAggExec>() {
+        let input = &inputs[0];
+        let mut lineages = input.clone();
+        for expr in window.window_expr() {
+            let mut exprs = expr.expressions();
+            exprs.extend(expr.partition_by().iter().cloned());
+            exprs.extend(expr.order_by().iter().map(|s| s.expr.clone()));
+            lineages.push(referenced(exprs.iter(), input).transform(
+                depth,
+                "WindowAggExec",
+                expr.name().to_owned(),
+            ));
+        }
+        return Ok(lineages);
+    }
+
+    // The other operators, e.g. the filters, the repartitions and the sorts,
+    // keep the columns of their inputs.
+    match inputs.as_slice() {
+        [input] if input.len() == fields.len() => Ok(input.clone()),
+        _ if inputs.iter().all(|input| input.len() == fields.len()) && !inputs.is_empty() => Ok((0
+            ..fields.len())
+            .map(|i| Lineage::union(inputs.iter().map(|input| &input[i])))
+            .collect()),
+        _ if inputs.is_empty() => Err(FlockError::Plan(format!(
+            "Unsupported leaf of the plan: {}",
+            name(plan.as_ref())
+        ))),
+        _ => {
+            // The output columns can't be matched with the input columns.
+            let lineage = Lineage::union(inputs.iter().flatten());
+            Ok(fields.iter().map(|_| lineage.clone()).collect())
+        }
+    }
+}
+
+/// Returns the lineage of the columns read by a leaf of the plan. The leaf is
+/// matched with the stream that has all of its columns.
+fn leaf(schema: &Schema, streams: &[Table]) -> Vec<Lineage> {
+    let stream = streams
+        .iter()
+        .find(|table| {
+            schema
+                .fields()
+                .iter()
+                .all(|f| table.1.field_with_name(f.name()).is_ok())
+        })
+        .map_or(UNKNOWN_STREAM, |table| table.0.as_str());
+    schema
+        .fields()
+        .iter()
+        .map(|field| Lineage {
+            sources:    BTreeSet::from([SourceColumn {
+                stream: stream.to_owned(),
+                column: field.name().clone(),
+            }]),
+            transforms: BTreeSet::new(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasource::nexmark::{get_nexmark_schema, register_nexmark_tables, NEXMARK_TABLES};
+    use crate::runtime::plan::physical_plan;
+
+    async fn nexmark_lineage(sql: &str) -> Result<QueryLineage> {
+        let ctx = register_nexmark_tables().await?;
+        let plan = physical_plan(&ctx, sql).await?;
+        let streams = NEXMARK_TABLES
+            .iter()
+            .map(|t| Table::new(*t, Arc::new(get_nexmark_schema(t))))
+            .collect::<Vec<_>>();
+        QueryLineage::analyze(&plan, &streams)
+    }
+
+    fn sources(lineage: &ColumnLineage) -> Vec<(&str, &str)> {
+        lineage
+            .sources
+            .iter()
+            .map(|s| (s.stream.as_str(), s.column.as_str()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn nexmark_q3_lineage() -> Result<()> {
+        let sql = include_str!("../../../benchmarks/src/nexmark/query/q3.sql");
+        let lineage = nexmark_lineage(sql).await?;
+        assert_eq!(2, lineage.stages);
+
+        let columns = lineage
+            .columns
+            .iter()
+            .map(|c| c.column.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["name", "city", "state", "a_id"], columns);
+        assert_eq!(
+            vec![("person", "name")],
+            sources(lineage.column("name").unwrap())
+        );
+        assert_eq!(
+            vec![("auction", "a_id")],
+            sources(lineage.column("a_id").unwrap())
+        );
+
+        // Every column goes through the join of the first stage.
+        for column in &lineage.columns {
+            assert!(column.transforms.iter().any(|t| t.stage == 0
+                && t.operator == "HashJoinExec"
+                && t.expr.contains("seller")
+                && t.expr.contains("p_id")));
+        }
+
+        let json = lineage.to_json()?;
+        assert!(json.contains("\"stream\": \"person\""));
+        let table = lineage.to_table()?;
+        assert!(table.contains("person.city"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nexmark_q4_lineage() -> Result<()> {
+        let sql = include_str!("../../../benchmarks/src/nexmark/query/q4.sql");
+        let lineage = nexmark_lineage(sql).await?;
+        assert_eq!(2, lineage.columns.len());
+
+        let category = &lineage.columns[0];
+        assert_eq!("category", category.column);
+        assert_eq!(vec![("auction", "category")], sources(category));
+
+        // The average of the final prices is derived from the bids, and the
+        // aggregates transform it in different stages.
+        let average = &lineage.columns[1];
+        assert!(sources(average).contains(&("bid", "price")));
+        let aggregates = average
+            .transforms
+            .iter()
+            .filter(|t| t.operator == "HashAggregateExec")
+            .map(|t| t.stage)
+            .collect::<BTreeSet<_>>();
+        assert!(aggregates.len() > 1);
+        assert!(average
+            .transforms
+            .iter()
+            .any(|t| t.operator == "HashJoinExec"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nexmark_q7_lineage() -> Result<()> {
+        let sql = include_str!("../../../benchmarks/src/nexmark/query/q7.sql");
+        let lineage = nexmark_lineage(sql).await?;
+
+        let columns = lineage
+            .columns
+            .iter()
+            .map(|c| c.column.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["auction", "price", "bidder", "b_date_time"], columns);
+        for column in &lineage.columns {
+            assert_eq!(vec![("bid", column.column.as_str())], sources(column));
+            assert!(column
+                .transforms
+                .iter()
+                .any(|t| t.operator == "HashJoinExec" && t.expr.contains("maxprice")));
+        }
+        Ok(())
+    }
+}
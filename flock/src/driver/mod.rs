@@ -17,5 +17,7 @@
 #[cfg(feature = "build")]
 pub mod build;
 pub mod funcgen;
+pub mod lineage;
+pub mod stepfunctions;
 
 // pub use funcgen::function::QueryFlow;
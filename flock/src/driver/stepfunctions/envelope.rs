@@ -0,0 +1,235 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The input envelope that the Step Functions state machine wraps around the
+//! payload of each function invocation.
+
+use super::collect_results;
+use crate::error::Result;
+use crate::runtime::function_name::{query_code_of, query_key};
+use crate::runtime::metadata::QueryMetadata;
+use crate::runtime::payload::{Payload, Uuid};
+use crate::transmute::to_payload;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The key of the envelope in the function input. The inputs without the key
+/// are the plain payloads of the direct invocations.
+pub const SFN_ENVELOPE_KEY: &str = "flock_sfn";
+
+/// The metadata key of the execution step that the payload is unwrapped from.
+/// The functions invoked by the state machine return their results as the
+/// state output, which is limited to [`SFN_MAX_RESPONSE_SIZE`].
+pub const SFN_STEP_METADATA_KEY: &str = "sfn_step";
+
+/// The maximum size of the results that a function returns to the state
+/// machine inline. The state output of Step Functions is limited to 256 KB,
+/// and some room is left for the response envelope of the function. Larger
+/// results are written to S3, and the response points to them.
+pub const SFN_MAX_RESPONSE_SIZE: usize = 240 * 1024;
+
+/// The execution step that invokes the function.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StepInfo {
+    /// The name of the state machine execution.
+    pub execution: String,
+    /// The index of the query stage in the execution order.
+    pub stage:     usize,
+}
+
+/// The function input of the Step Functions execution mode.
+///
+/// The first stage receives the payloads prepared by the driver, and the
+/// following stages receive the responses of the former stage, which are
+/// merged into a single payload.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Envelope {
+    /// The execution step that invokes the function.
+    #[serde(rename = "flock_sfn")]
+    pub step:    StepInfo,
+    /// The payload of the first stage.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<Payload>,
+    /// The responses of the former stage.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub results: Vec<Value>,
+}
+
+impl StepInfo {
+    /// Returns the id of the step, i.e. `<execution>-<stage>`.
+    pub fn id(&self) -> String {
+        format!("{}-{:02}", self.execution, self.stage)
+    }
+}
+
+impl Envelope {
+    /// Unwraps the payload of the envelope. The responses of the former stage
+    /// belong to the same window of the execution, so they are merged into a
+    /// single payload which completes the window. Returns `None` if the former
+    /// stage produced no results.
+    pub async fn into_payload(self) -> Result<Option<Payload>> {
+        if self.payload.is_some() {
+            return Ok(self.payload);
+        }
+
+        let batches = collect_results(self.results).await?;
+        if batches.is_empty() {
+            return Ok(None);
+        }
+
+        let uuid = Uuid {
+            qid:     self.step.id(),
+            seq_num: 1,
+            seq_len: 1,
+        };
+        Ok(Some(to_payload(&batches, &[], uuid, true)?))
+    }
+}
+
+/// Unwraps the function input: the envelope of the Step Functions execution
+/// mode, or the plain payload of the direct invocations. The payloads of the
+/// envelope carry the execution step in their metadata.
+pub async fn unwrap_payload(event: Value) -> Result<Option<Payload>> {
+    if event.get(SFN_ENVELOPE_KEY).is_some() {
+        let envelope = serde_json::from_value::<Envelope>(event)?;
+        let step = envelope.step.id();
+        Ok(envelope.into_payload().await?.map(|mut payload| {
+            payload
+                .metadata
+                .get_or_insert_with(QueryMetadata::default)
+                .insert(SFN_STEP_METADATA_KEY.to_string(), step);
+            payload
+        }))
+    } else {
+        Ok(Some(Payload::from_value(event)?))
+    }
+}
+
+/// Returns the execution step that the payload is unwrapped from, or `None`
+/// if the function isn't invoked by the state machine.
+pub fn sfn_step(metadata: &Option<QueryMetadata>) -> Option<&str> {
+    metadata
+        .as_ref()
+        .and_then(|m| m.get(SFN_STEP_METADATA_KEY))
+        .map(|s| s.as_str())
+}
+
+/// Returns the S3 key `<query code>/sfn/<step>/<window>` that the results of
+/// the window are written to if they exceed [`SFN_MAX_RESPONSE_SIZE`]. The
+/// responses of the functions of a step point to distinct objects, which are
+/// read back when the next step unwraps its payload.
+pub fn spill_key(function_name: &str, step: &str, window: &str) -> String {
+    query_key(
+        query_code_of(function_name),
+        &format!("sfn/{}/{}", step, window),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasink::DataSink;
+    use crate::datasource::DataSource;
+    use crate::encoding::Encoding;
+    use datafusion::arrow::array::Int32Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn batch(values: Vec<i32>) -> Result<RecordBatch> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        Ok(RecordBatch::try_new(
+            schema,
+            vec![Arc::new(Int32Array::from(values))],
+        )?)
+    }
+
+    #[tokio::test]
+    async fn unwrap_plain_payload() -> Result<()> {
+        let payload = Payload {
+            query_number: Some(5),
+            ..Default::default()
+        };
+        let event = serde_json::to_value(&payload)?;
+        let unwrapped = unwrap_payload(event).await?.unwrap();
+        assert_eq!(unwrapped.query_number, Some(5));
+        assert_eq!(sfn_step(&unwrapped.metadata), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unwrap_first_stage_envelope() -> Result<()> {
+        let envelope = Envelope {
+            step:    StepInfo {
+                execution: "q5-1".to_string(),
+                stage:     0,
+            },
+            payload: Some(to_payload(
+                &[batch(vec![1, 2, 3])?],
+                &[],
+                Uuid {
+                    qid:     "q5-00".to_string(),
+                    seq_num: 1,
+                    seq_len: 1,
+                },
+                true,
+            )?),
+            results: vec![],
+        };
+        let event = serde_json::to_value(&envelope)?;
+        let payload = unwrap_payload(event).await?.unwrap();
+        assert_eq!(payload.uuid.qid, "q5-00");
+        assert_eq!(payload.to_record_batch()?.0[0].num_rows(), 3);
+        assert_eq!(sfn_step(&payload.metadata), Some("q5-1-00"));
+        assert_eq!(
+            spill_key("q5-00", "q5-1-00", "q5-00-0"),
+            "q5/sfn/q5-1-00/q5-00-0"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unwrap_merged_results() -> Result<()> {
+        let response = |values| -> Result<Value> {
+            let sink = DataSink::new(
+                "q5-00".to_string(),
+                vec![batch(values)?],
+                Encoding::default(),
+            );
+            Ok(sink.to_response(usize::MAX)?.unwrap())
+        };
+        let event = json!({
+            SFN_ENVELOPE_KEY: { "execution": "q5-1", "stage": 1 },
+            "results": [response(vec![1, 2])?, Value::Null, response(vec![3])?],
+        });
+
+        let payload = unwrap_payload(event).await?.unwrap();
+        assert_eq!(payload.uuid.qid, "q5-1-01");
+        assert_eq!((payload.uuid.seq_num, payload.uuid.seq_len), (1, 1));
+        assert_eq!(payload.get_window_id(), ("q5-1-01".to_string(), 0));
+        assert_eq!(payload.datasource, DataSource::Payload(true));
+        payload.validate(true, None)?;
+
+        let batches = payload.to_record_batch()?.0;
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 3);
+
+        // The former stage produced no results.
+        let event = json!({
+            SFN_ENVELOPE_KEY: { "execution": "q5-1", "stage": 1 },
+            "results": [Value::Null],
+        });
+        assert!(unwrap_payload(event).await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,540 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! AWS Step Functions as an alternative coordinator of the query stages.
+//!
+//! By default, each cloud function invokes the functions of the next stage
+//! directly. This is fragile for long multi-stage queries (e.g. batch runs over
+//! S3 data) whose total execution exceeds the 15-minute limit of AWS Lambda:
+//! a stage that fails after the retries silently stalls the query. In the Step
+//! Functions mode, every stage returns its results to a state machine instead,
+//! which invokes the next stage with the results, retries the failed
+//! invocations, and reports the failure of the query.
+//!
+//! The state machine of a query consists of:
+//!
+//! - a `Map` state for the first stage, which fans out the input payloads
+//!   prepared by the driver to the functions of the stage;
+//! - a `Task` state for each following stage, which invokes the stage with all
+//!   the results of the former stage (see [`Envelope`]);
+//! - a `Fail` state that the stages fall back to once the retries are
+//!   exhausted.
+//!
+//! The intermediate results are passed through the state machine, so they are
+//! subject to the 256 KB limit of the state data in Step Functions. The input
+//! payloads of the first stage are stored in S3 (see [`upload_payloads`]), and
+//! the results larger than [`SFN_MAX_RESPONSE_SIZE`] are written to S3 behind
+//! a pointer (see [`spill_key`]).
+
+mod envelope;
+pub use envelope::{
+    sfn_step, spill_key, unwrap_payload, Envelope, StepInfo, SFN_ENVELOPE_KEY,
+    SFN_MAX_RESPONSE_SIZE, SFN_STEP_METADATA_KEY,
+};
+
+use crate::aws::{s3, stepfunctions};
+use crate::configs::*;
+use crate::datasink::{DataSink, DataSinkType};
+use crate::datasource::DataStream;
+use crate::distributed_plan::QueryDag;
+use crate::error::{FlockError, Result};
+use crate::runtime::context::{CloudFunction, CloudFunctionType};
+use crate::runtime::metadata::S3Pointer;
+use crate::runtime::payload::{Payload, UuidBuilder};
+use crate::runtime::response::response_data;
+use daggy::NodeIndex;
+use datafusion::arrow::record_batch::RecordBatch;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The errors of the Lambda service that the state machine retries.
+const LAMBDA_RETRY_ERRORS: [&str; 4] = [
+    "Lambda.ServiceException",
+    "Lambda.AWSLambdaException",
+    "Lambda.SdkClientException",
+    "Lambda.TooManyRequestsException",
+];
+
+/// The name of the state that the stages fall back to once the retries are
+/// exhausted.
+const FAIL_STATE: &str = "QueryFailed";
+
+/// The coordinator of the query stages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Coordinator {
+    /// Each cloud function invokes the functions of the next stage directly.
+    Direct,
+    /// An AWS Step Functions state machine invokes the query stages.
+    StepFunctions,
+}
+
+impl Default for Coordinator {
+    fn default() -> Self {
+        Coordinator::Direct
+    }
+}
+
+impl fmt::Display for Coordinator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Coordinator::Direct => write!(f, "direct"),
+            Coordinator::StepFunctions => write!(f, "step-functions"),
+        }
+    }
+}
+
+impl FromStr for Coordinator {
+    type Err = FlockError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "direct" => Ok(Coordinator::Direct),
+            "step-functions" | "stepfunctions" => Ok(Coordinator::StepFunctions),
+            _ => Err(FlockError::Execution(format!(
+                "Invalid coordinator: {}. Expected direct or step-functions",
+                s
+            ))),
+        }
+    }
+}
+
+/// A query stage in the state machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SfnStage {
+    /// The function name of the stage, e.g. `q5-01`.
+    pub name:       String,
+    /// The size of the function group, or `None` for a single function.
+    pub group_size: Option<usize>,
+}
+
+impl SfnStage {
+    /// Returns the names of the functions of the stage.
+    pub fn members(&self) -> Vec<String> {
+        match self.group_size {
+            Some(size) => (0..size)
+                .map(|j| format!("{}-{:02}", self.name, j))
+                .collect(),
+            None => vec![self.name.clone()],
+        }
+    }
+
+    /// Returns the function that receives the payload with the given query id.
+    /// The payloads of the same window are sent to the same group member, as
+    /// the consistent hashing in the direct mode does.
+    pub fn route(&self, qid: &str) -> String {
+        let members = self.members();
+        let mut hasher = DefaultHasher::new();
+        qid.hash(&mut hasher);
+        members[hasher.finish() as usize % members.len()].clone()
+    }
+}
+
+/// The AWS Step Functions state machine of a query.
+#[derive(Debug, Clone)]
+pub struct StateMachine {
+    /// The first component of the function names.
+    pub query_code: String,
+    /// The query stages in the execution order.
+    pub stages:     Vec<SfnStage>,
+    /// The ARN of the state machine once it is deployed.
+    pub arn:        Option<String>,
+}
+
+impl StateMachine {
+    /// Creates the state machine of the query stages in the DAG.
+    ///
+    /// # Arguments
+    /// * `dag` - The query stages created by the launcher.
+    /// * `query_code` - The first component of the function names.
+    /// * `group_size` - The size of the function groups.
+    pub fn new(dag: &QueryDag, query_code: &str, group_size: usize) -> Self {
+        let count = dag.node_count();
+        // The root of the DAG is the last stage to execute.
+        let stages = (0..count)
+            .rev()
+            .map(|i| {
+                let node = dag.get_node(NodeIndex::new(i)).unwrap();
+                SfnStage {
+                    name:       format!("{}-{:02}", query_code, count - 1 - i),
+                    group_size: if node.get_function_type() == CloudFunctionType::Group {
+                        Some(group_size)
+                    } else {
+                        None
+                    },
+                }
+            })
+            .collect();
+        StateMachine {
+            query_code: query_code.to_owned(),
+            stages,
+            arn: None,
+        }
+    }
+
+    /// Returns the name of the state machine.
+    pub fn name(&self) -> String {
+        format!("flock-{}", self.query_code)
+    }
+
+    /// Returns the Amazon States Language definition of the state machine.
+    pub fn definition(&self) -> Value {
+        let mut states = serde_json::Map::new();
+        let state_name = |k: usize| format!("Stage{:02}", k);
+
+        for (k, stage) in self.stages.iter().enumerate() {
+            let mut state = if k == 0 {
+                // The input payloads are routed to the functions by the driver.
+                let invoke = format!("Invoke{:02}", k);
+                json!({
+                    "Type": "Map",
+                    "ItemsPath": "$.items",
+                    "MaxConcurrency": stage.group_size.unwrap_or(0),
+                    "Iterator": {
+                        "StartAt": invoke,
+                        "States": {
+                            invoke: {
+                                "Type": "Task",
+                                "Resource": "arn:aws:states:::lambda:invoke",
+                                "Parameters": {
+                                    "FunctionName.$": "$.function",
+                                    "Payload": {
+                                        SFN_ENVELOPE_KEY: step_info(k),
+                                        "payload.$": "$.payload",
+                                    },
+                                },
+                                "OutputPath": "$.Payload",
+                                "Retry": retry_policy(),
+                                "End": true,
+                            },
+                        },
+                    },
+                    "ResultSelector": { "results.$": "$" },
+                })
+            } else {
+                // All the results of the former stage are merged into a single window,
+                // which the consistent hashing sends to a single group member anyway.
+                json!({
+                    "Type": "Task",
+                    "Resource": "arn:aws:states:::lambda:invoke",
+                    "Parameters": {
+                        "FunctionName": stage.members()[0],
+                        "Payload": {
+                            SFN_ENVELOPE_KEY: step_info(k),
+                            "results.$": "$.results",
+                        },
+                    },
+                    "ResultSelector": { "results.$": "States.Array($.Payload)" },
+                    "Retry": retry_policy(),
+                })
+            };
+
+            let obj = state.as_object_mut().unwrap();
+            obj.insert(
+                "Catch".to_string(),
+                json!([{
+                    "ErrorEquals": ["States.ALL"],
+                    "ResultPath": "$.error",
+                    "Next": FAIL_STATE,
+                }]),
+            );
+            if k + 1 < self.stages.len() {
+                obj.insert("Next".to_string(), json!(state_name(k + 1)));
+            } else {
+                obj.insert("End".to_string(), json!(true));
+            }
+            states.insert(state_name(k), state);
+        }
+
+        states.insert(
+            FAIL_STATE.to_string(),
+            json!({
+                "Type": "Fail",
+                "Error": "Flock.QueryFailed",
+                "Cause": "A query stage failed after the retries",
+            }),
+        );
+
+        json!({
+            "Comment": format!("Flock query {}", self.query_code),
+            "StartAt": state_name(0),
+            "States": states,
+        })
+    }
+
+    /// Returns the input of an execution, which routes the payloads to the
+    /// functions of the first stage.
+    pub fn input(&self, payloads: Vec<Payload>) -> Result<Value> {
+        let stage = self.stages.first().ok_or_else(|| {
+            FlockError::Execution("The state machine has no query stages".to_string())
+        })?;
+        let items = payloads
+            .into_iter()
+            .map(|payload| {
+                Ok(json!({
+                    "function": stage.route(&payload.uuid.qid),
+                    "payload": serde_json::to_value(&payload)?,
+                }))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(json!({ "items": items }))
+    }
+
+    /// Creates the state machine, or updates its definition if it already
+    /// exists.
+    pub async fn deploy(&mut self) -> Result<String> {
+        let definition = serde_json::to_string(&self.definition())?;
+        let arn = stepfunctions::create_or_update_state_machine(&self.name(), &definition).await?;
+        self.arn = Some(arn.clone());
+        Ok(arn)
+    }
+
+    /// Starts an execution with the input payloads of the first stage, and
+    /// waits for its completion.
+    ///
+    /// # Returns
+    /// The responses of the last stage.
+    pub async fn execute(&self, payloads: Vec<Payload>, timeout: Duration) -> Result<Vec<Value>> {
+        let arn = self.arn.as_ref().ok_or_else(|| {
+            FlockError::Execution(format!("State machine {} is not deployed", self.name()))
+        })?;
+        let execution = format!(
+            "{}-{}",
+            self.query_code,
+            chrono::Utc::now().timestamp_millis()
+        );
+        let execution_arn =
+            stepfunctions::start_execution(arn, &execution, &self.input(payloads)?).await?;
+        let output = stepfunctions::wait_for_execution(&execution_arn, timeout).await?;
+        Ok(serde_json::from_value(output["results"].clone()).unwrap_or_default())
+    }
+}
+
+/// Returns the execution step in the function input of the `k`th stage.
+fn step_info(k: usize) -> Value {
+    json!({
+        "execution.$": "$$.Execution.Name",
+        "stage": k,
+    })
+}
+
+/// Returns the retry policy of the function invocations, which follows the
+/// retries of the direct invocations (see [`crate::aws::lambda`]).
+fn retry_policy() -> Value {
+    json!([{
+        "ErrorEquals": LAMBDA_RETRY_ERRORS,
+        "IntervalSeconds": ((*FLOCK_LAMBDA_MAX_BACKOFF + 999) / 1000).max(1),
+        "MaxAttempts": *FLOCK_LAMBDA_MAX_RETRIES,
+        "BackoffRate": 1.0,
+    }])
+}
+
+/// Rewires the query stages for the Step Functions mode: every stage except
+/// the last one returns its results to the state machine, instead of invoking
+/// the next stage directly. The last stage still writes to the data sink of
+/// the query.
+pub fn use_step_functions(dag: &mut QueryDag) {
+    // The root of the DAG is the last stage to execute.
+    for i in 1..dag.node_count() {
+        if let Some(ctx) = dag
+            .get_node_mut(NodeIndex::new(i))
+            .unwrap()
+            .context
+            .as_mut()
+        {
+            ctx.next = CloudFunction::Sink(DataSinkType::Response);
+        }
+    }
+}
+
+/// Returns the input payloads of the first stage: one payload for each epoch
+/// of each generator of the stream.
+///
+/// # Arguments
+/// * `stream` - The events of the data source.
+/// * `query_code` - The first component of the function names.
+/// * `query_number` - The query number of the benchmark.
+/// * `generators` - The number of data generators.
+/// * `seconds` - The number of epochs of each generator.
+pub fn epoch_payloads(
+    stream: &dyn DataStream,
+    query_code: &str,
+    query_number: Option<usize>,
+    generators: usize,
+    seconds: usize,
+) -> Result<Vec<Payload>> {
+    let timestamp = chrono::Utc::now().timestamp();
+    let mut payloads = vec![];
+    for generator in 0..generators {
+        for epoch in 0..seconds {
+            // Each payload is a window of its own, which the first stage
+            // completes in a single invocation.
+            let uuid = UuidBuilder::new_with_ts(query_code, timestamp, 1).next_uuid();
+            payloads.push(stream.select_event_to_payload(
+                epoch,
+                generator,
+                query_number,
+                uuid,
+                true,
+            )?);
+        }
+    }
+    Ok(payloads)
+}
+
+/// Stores the input payloads of the first stage in S3, since the input of an
+/// execution is limited to 256 KB. The returned payloads point to the S3
+/// objects, and the functions read the data from S3 on invocation.
+pub async fn upload_payloads(query_code: &str, payloads: Vec<Payload>) -> Result<Vec<Payload>> {
+    let tasks = payloads
+        .into_iter()
+        .enumerate()
+        .map(|(i, payload)| {
+            let key = format!("{}/sfn/{:04}", query_code, i);
+            tokio::spawn(async move {
+                s3::put_object(&FLOCK_S3_BUCKET, &key, serde_json::to_vec(&payload)?).await?;
+                let mut metadata = payload.metadata.unwrap_or_default();
+                metadata.s3 = Some(S3Pointer {
+                    bucket: FLOCK_S3_BUCKET.clone(),
+                    key,
+                });
+                Ok(Payload {
+                    uuid: payload.uuid,
+                    window_id: payload.window_id,
+                    datasource: payload.datasource,
+                    query_number: payload.query_number,
+                    metadata: Some(metadata),
+                    ..Default::default()
+                })
+            })
+        })
+        .collect::<Vec<tokio::task::JoinHandle<Result<Payload>>>>();
+    futures::future::join_all(tasks)
+        .await
+        .into_iter()
+        .map(|r| r.map_err(|e| FlockError::Execution(e.to_string()))?)
+        .collect()
+}
+
+/// Decodes the responses of the last stage into record batches. The responses
+/// of the other data sinks carry no data and are skipped.
+pub async fn collect_results(results: Vec<Value>) -> Result<Vec<RecordBatch>> {
+    let mut batches = vec![];
+    for response in results
+        .into_iter()
+        .map(response_data)
+        .filter(|r| r["sink_type"] == json!(DataSinkType::Response))
+    {
+        batches.extend(DataSink::from_response(response).await?.record_batches);
+    }
+    Ok(batches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_machine() -> StateMachine {
+        StateMachine {
+            query_code: "q5".to_string(),
+            stages:     vec![
+                SfnStage {
+                    name:       "q5-00".to_string(),
+                    group_size: None,
+                },
+                SfnStage {
+                    name:       "q5-01".to_string(),
+                    group_size: Some(4),
+                },
+            ],
+            arn:        None,
+        }
+    }
+
+    #[test]
+    fn coordinator_from_str() -> Result<()> {
+        assert_eq!("direct".parse::<Coordinator>()?, Coordinator::Direct);
+        assert_eq!(
+            "step-functions".parse::<Coordinator>()?,
+            Coordinator::StepFunctions
+        );
+        assert_eq!(Coordinator::StepFunctions.to_string(), "step-functions");
+        assert_eq!(Coordinator::default(), Coordinator::Direct);
+        assert!("sqs".parse::<Coordinator>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn state_machine_definition() {
+        let definition = state_machine().definition();
+        assert_eq!(definition["StartAt"], "Stage00");
+
+        let states = &definition["States"];
+        let stage0 = &states["Stage00"];
+        assert_eq!(stage0["Type"], "Map");
+        assert_eq!(stage0["ItemsPath"], "$.items");
+        assert_eq!(stage0["Next"], "Stage01");
+        assert_eq!(stage0["Catch"][0]["Next"], FAIL_STATE);
+
+        let invoke = &stage0["Iterator"]["States"]["Invoke00"];
+        assert_eq!(invoke["Parameters"]["FunctionName.$"], "$.function");
+        assert_eq!(
+            invoke["Parameters"]["Payload"][SFN_ENVELOPE_KEY]["stage"],
+            0
+        );
+        assert_eq!(invoke["Retry"][0]["MaxAttempts"], *FLOCK_LAMBDA_MAX_RETRIES);
+
+        let stage1 = &states["Stage01"];
+        assert_eq!(stage1["Type"], "Task");
+        assert_eq!(stage1["Parameters"]["FunctionName"], "q5-01-00");
+        assert_eq!(stage1["Parameters"]["Payload"]["results.$"], "$.results");
+        assert_eq!(stage1["End"], true);
+        assert!(stage1.get("Next").is_none());
+
+        assert_eq!(states[FAIL_STATE]["Type"], "Fail");
+    }
+
+    #[test]
+    fn state_machine_input() -> Result<()> {
+        let mut machine = state_machine();
+        machine.stages.swap(0, 1);
+
+        let payloads = (0..8)
+            .map(|i| Payload {
+                uuid: crate::runtime::payload::Uuid {
+                    qid:     format!("q5-{}", i),
+                    seq_num: 1,
+                    seq_len: 1,
+                },
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let input = machine.input(payloads.clone())?;
+        let items = input["items"].as_array().unwrap();
+        assert_eq!(items.len(), 8);
+
+        let members = machine.stages[0].members();
+        for (item, payload) in items.iter().zip(payloads.iter()) {
+            let function = item["function"].as_str().unwrap();
+            assert!(members.contains(&function.to_string()));
+            // The payloads of the same window go to the same member.
+            assert_eq!(function, machine.stages[0].route(&payload.uuid.qid));
+            assert_eq!(item["payload"]["uuid"]["qid"], json!(payload.uuid.qid));
+        }
+        Ok(())
+    }
+}
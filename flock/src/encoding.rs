@@ -17,10 +17,58 @@
 //! For example, it can be used to reduce the size of the payload sent between
 //! the cloud functions, and reduce the size of all environment variables to
 //! less than 4KB as well.
+//!
+//! The codecs are enabled by the cargo features `snappy`, `lz4` and `zstd`. If
+//! a payload requests a codec that is not compiled into the binary, encoding
+//! and decoding fail with [`FlockError::NotImplemented`].
+//!
+//! Each query stage compresses its payloads with its own [`StageEncoding`].
+//! The receivers read the codec from each payload, so the stages of a query
+//! don't need to agree on it.
+//!
+//! The tiny payloads, e.g. those of the elementwise queries, compress poorly
+//! on their own, since each payload is compressed without any shared context.
+//! [`Encoding::ZstdDict`] compresses them with a Zstd dictionary trained for
+//! the query instead (see [`crate::runtime::dictionary`]). The dictionaries
+//! are registered in the function instance by their ids, and a stage falls
+//! back to plain Zstd until its dictionary is registered. Each dictionary is
+//! digested once per function instance rather than once per payload (see
+//! [`encoder_dictionary`] and [`decoder_dictionary`]).
 
+use super::configs::{FLOCK_AGGREGATE_ZSTD_LEVEL, FLOCK_ENCODING_THRESHOLD};
 use super::error::{FlockError, Result};
+use lazy_static::lazy_static;
+#[cfg(feature = "lz4")]
 use lz4::block::CompressionMode;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+#[cfg(feature = "zstd")]
+use std::io::{Read, Write};
+use std::sync::{Arc, RwLock};
+#[cfg(feature = "zstd")]
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+lazy_static! {
+    /// The Zstd dictionaries registered in the function instance by their ids.
+    static ref ZSTD_DICTIONARIES: RwLock<HashMap<u32, Arc<Vec<u8>>>> = RwLock::new(HashMap::new());
+    /// The prepared Zstd dictionaries of the compressors by their ids and
+    /// compression levels, which bake the level in.
+    #[cfg(feature = "zstd")]
+    static ref ZSTD_ENCODER_DICTIONARIES: RwLock<HashMap<(u32, i32), Arc<EncoderDictionary<'static>>>> =
+        RwLock::new(HashMap::new());
+    /// The prepared Zstd dictionaries of the decompressors by their ids.
+    #[cfg(feature = "zstd")]
+    static ref ZSTD_DECODER_DICTIONARIES: RwLock<HashMap<u32, Arc<DecoderDictionary<'static>>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// The magic number of the Zstd dictionaries, which is followed by the id of
+/// the dictionary.
+const ZSTD_DICT_MAGIC: u32 = 0xEC30_A437;
+
+/// The maximum size in bytes of the decompressed data.
+#[cfg(feature = "zstd")]
+const MAX_DECOMPRESSED_SIZE: usize = 10485760;
 
 /// This function encodes the given data into a byte array.
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -44,59 +92,298 @@ pub enum Encoding {
     /// A fast lossless compression algorithm, targeting real-time compression
     /// scenarios at zlib-level and better compression ratios. <https://github.com/facebook/zstd>
     Zstd,
+    /// Zstd with a dictionary trained from the sample payloads of the query,
+    /// which must be registered by [`register_dictionary`] on both sides.
+    ZstdDict {
+        /// The id of the dictionary, which is recorded in the dictionary.
+        dict_id: u32,
+    },
     /// No compression/decompression applied to the context.
     None,
 }
 
 impl Default for Encoding {
     fn default() -> Encoding {
-        Encoding::Zstd
+        if cfg!(feature = "zstd") {
+            Encoding::Zstd
+        } else {
+            Encoding::None
+        }
     }
 }
 
 impl Encoding {
     /// Compress the given data using the encoding type.
     pub fn compress(&self, s: &[u8]) -> Result<Vec<u8>> {
+        self.compress_with_level(s, None)
+    }
+
+    /// Compress the given data using the encoding type at the given level, or
+    /// at the default level of the codec if `None`. The level is ignored by
+    /// the codecs without levels, and isn't needed to decompress the data.
+    pub fn compress_with_level(&self, s: &[u8], level: Option<i32>) -> Result<Vec<u8>> {
         Ok(match *self {
+            #[cfg(feature = "snappy")]
             Encoding::Snappy => {
                 let mut encoder = snap::raw::Encoder::new();
                 encoder
                     .compress_vec(s)
                     .map_err(|e| FlockError::Execution(e.to_string()))?
             }
+            #[cfg(feature = "lz4")]
             Encoding::Lz4 => {
-                // TODO(gangliao): more flexible way to set the compression level
-                lz4::block::compress(s, Some(CompressionMode::HIGHCOMPRESSION(6)), true)
+                let level = level.unwrap_or(6);
+                lz4::block::compress(s, Some(CompressionMode::HIGHCOMPRESSION(level)), true)
                     .map_err(|e| FlockError::Execution(e.to_string()))?
             }
-            Encoding::Zstd => {
-                zstd::block::compress(s, 3).map_err(|e| FlockError::Execution(e.to_string()))?
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => zstd::block::compress(s, level.unwrap_or(3))
+                .map_err(|e| FlockError::Execution(e.to_string()))?,
+            #[cfg(feature = "zstd")]
+            Encoding::ZstdDict { dict_id } => {
+                let dict = encoder_dictionary(dict_id, level.unwrap_or(3))?;
+                let mut encoder =
+                    zstd::stream::Encoder::with_prepared_dictionary(Vec::new(), &dict)
+                        .map_err(|e| FlockError::Execution(e.to_string()))?;
+                encoder
+                    .write_all(s)
+                    .map_err(|e| FlockError::Execution(e.to_string()))?;
+                encoder
+                    .finish()
+                    .map_err(|e| FlockError::Execution(e.to_string()))?
             }
             Encoding::None => s.into(),
-            _ => unimplemented!(),
+            _ => return Err(self.not_enabled()),
         })
     }
 
     /// Decompress the given data using the encoding type.
     pub fn decompress(&self, s: &[u8]) -> Result<Vec<u8>> {
         Ok(match *self {
+            #[cfg(feature = "snappy")]
             Encoding::Snappy => {
                 let mut decoder = snap::raw::Decoder::new();
                 decoder
                     .decompress_vec(s)
                     .map_err(|e| FlockError::Execution(e.to_string()))?
             }
+            #[cfg(feature = "lz4")]
             Encoding::Lz4 => {
                 lz4::block::decompress(s, None).map_err(|e| FlockError::Execution(e.to_string()))?
             }
-            Encoding::Zstd => zstd::block::decompress(s, 10485760)
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => zstd::block::decompress(s, MAX_DECOMPRESSED_SIZE)
                 .map_err(|e| FlockError::Execution(e.to_string()))?,
-            Encoding::None => s.into(),
-            _ => {
-                unimplemented!();
+            #[cfg(feature = "zstd")]
+            Encoding::ZstdDict { dict_id } => {
+                let dict = decoder_dictionary(dict_id)?;
+                let decoder = zstd::stream::read::Decoder::with_prepared_dictionary(s, &dict)
+                    .map_err(|e| FlockError::Execution(e.to_string()))?;
+                let mut buffer = vec![];
+                decoder
+                    .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+                    .read_to_end(&mut buffer)
+                    .map_err(|e| FlockError::Execution(e.to_string()))?;
+                if buffer.len() > MAX_DECOMPRESSED_SIZE {
+                    return Err(FlockError::Execution(format!(
+                        "The decompressed data is larger than {} bytes",
+                        MAX_DECOMPRESSED_SIZE
+                    )));
+                }
+                buffer
             }
+            Encoding::None => s.into(),
+            _ => return Err(self.not_enabled()),
         })
     }
+
+    /// Returns the codec that the data can be compressed with in the function
+    /// instance. The dictionary codec falls back to plain Zstd until its
+    /// dictionary is registered.
+    pub fn available(&self) -> Encoding {
+        match *self {
+            Encoding::ZstdDict { dict_id } if !has_dictionary(dict_id) => Encoding::Zstd,
+            _ => self.clone(),
+        }
+    }
+
+    /// Returns the error for the codecs that are not compiled into the binary.
+    fn not_enabled(&self) -> FlockError {
+        FlockError::NotImplemented(format!(
+            "{:?} encoding is not enabled in this build of Flock",
+            self
+        ))
+    }
+}
+
+/// Returns the id of the Zstd dictionary, or `None` if the bytes are not a
+/// Zstd dictionary.
+pub fn dictionary_id(dict: &[u8]) -> Option<u32> {
+    let word = |i: usize| {
+        dict.get(i..i + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    match word(0) {
+        Some(ZSTD_DICT_MAGIC) => word(4),
+        _ => None,
+    }
+}
+
+/// Trains a Zstd dictionary of at most `max_size` bytes from the samples, e.g.
+/// the Arrow Flight data of the sample payloads of a query.
+pub fn train_dictionary(samples: &[Vec<u8>], max_size: usize) -> Result<Vec<u8>> {
+    #[cfg(feature = "zstd")]
+    {
+        zstd::dict::from_samples(samples, max_size)
+            .map_err(|e| FlockError::Execution(format!("Failed to train the dictionary: {}", e)))
+    }
+    #[cfg(not(feature = "zstd"))]
+    {
+        let _ = (samples, max_size);
+        Err(Encoding::Zstd.not_enabled())
+    }
+}
+
+/// Registers the Zstd dictionary in the function instance, so that the
+/// payloads of [`Encoding::ZstdDict`] with its id can be encoded and decoded.
+///
+/// # Returns
+/// The id of the dictionary.
+pub fn register_dictionary(dict: Vec<u8>) -> Result<u32> {
+    let dict_id = dictionary_id(&dict)
+        .ok_or_else(|| FlockError::Execution("The bytes are not a Zstd dictionary".to_string()))?;
+    ZSTD_DICTIONARIES
+        .write()
+        .unwrap()
+        .insert(dict_id, Arc::new(dict));
+    // The dictionaries prepared from the former bytes of the id are stale.
+    #[cfg(feature = "zstd")]
+    {
+        ZSTD_ENCODER_DICTIONARIES
+            .write()
+            .unwrap()
+            .retain(|(id, _), _| *id != dict_id);
+        ZSTD_DECODER_DICTIONARIES.write().unwrap().remove(&dict_id);
+    }
+    Ok(dict_id)
+}
+
+/// Returns true if the Zstd dictionary is registered in the function instance.
+pub fn has_dictionary(dict_id: u32) -> bool {
+    ZSTD_DICTIONARIES.read().unwrap().contains_key(&dict_id)
+}
+
+/// Returns the registered Zstd dictionary.
+#[cfg(feature = "zstd")]
+fn dictionary(dict_id: u32) -> Result<Arc<Vec<u8>>> {
+    ZSTD_DICTIONARIES
+        .read()
+        .unwrap()
+        .get(&dict_id)
+        .cloned()
+        .ok_or_else(|| {
+            FlockError::Execution(format!("The Zstd dictionary {} is not registered", dict_id))
+        })
+}
+
+/// Returns the registered Zstd dictionary prepared for the compression at the
+/// given level, which is prepared once and then shared by the payloads.
+#[cfg(feature = "zstd")]
+fn encoder_dictionary(dict_id: u32, level: i32) -> Result<Arc<EncoderDictionary<'static>>> {
+    if let Some(dict) = ZSTD_ENCODER_DICTIONARIES
+        .read()
+        .unwrap()
+        .get(&(dict_id, level))
+    {
+        return Ok(dict.clone());
+    }
+    let dict = Arc::new(EncoderDictionary::copy(&dictionary(dict_id)?, level));
+    ZSTD_ENCODER_DICTIONARIES
+        .write()
+        .unwrap()
+        .insert((dict_id, level), dict.clone());
+    Ok(dict)
+}
+
+/// Returns the registered Zstd dictionary prepared for the decompression,
+/// which is prepared once and then shared by the payloads.
+#[cfg(feature = "zstd")]
+fn decoder_dictionary(dict_id: u32) -> Result<Arc<DecoderDictionary<'static>>> {
+    if let Some(dict) = ZSTD_DECODER_DICTIONARIES.read().unwrap().get(&dict_id) {
+        return Ok(dict.clone());
+    }
+    let dict = Arc::new(DecoderDictionary::copy(&dictionary(dict_id)?));
+    ZSTD_DECODER_DICTIONARIES
+        .write()
+        .unwrap()
+        .insert(dict_id, dict.clone());
+    Ok(dict)
+}
+
+/// The encoding of the payloads sent by a query stage to the next stage.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct StageEncoding {
+    /// The codec of the payloads.
+    pub codec:     Encoding,
+    /// The compression level of the codec, or `None` for its default level.
+    pub level:     Option<i32>,
+    /// The payloads whose Arrow Flight data is smaller than this many bytes
+    /// are sent uncompressed, regardless of the codec, since compressing them
+    /// costs more than it saves. 0 always uses the codec.
+    pub threshold: usize,
+}
+
+impl Default for StageEncoding {
+    fn default() -> Self {
+        StageEncoding::from(Encoding::default())
+    }
+}
+
+impl From<Encoding> for StageEncoding {
+    fn from(codec: Encoding) -> Self {
+        StageEncoding {
+            codec,
+            level: None,
+            threshold: 0,
+        }
+    }
+}
+
+impl StageEncoding {
+    /// Returns the encoding of a stage that skips the compression of the small
+    /// payloads (see [`FLOCK_ENCODING_THRESHOLD`]).
+    pub fn adaptive(codec: Encoding) -> Self {
+        StageEncoding {
+            threshold: *FLOCK_ENCODING_THRESHOLD,
+            ..StageEncoding::from(codec)
+        }
+    }
+
+    /// Returns the encoding of a stage by its next stage. The payloads handed
+    /// off to an aggregate stage are large, so they're compressed harder with
+    /// Zstd (see [`FLOCK_AGGREGATE_ZSTD_LEVEL`]) if it's enabled. The other
+    /// stages use the default codec. Either way, the small payloads are sent
+    /// uncompressed.
+    pub fn for_stage(aggregate_handoff: bool) -> Self {
+        if aggregate_handoff && cfg!(feature = "zstd") {
+            StageEncoding {
+                level: Some(*FLOCK_AGGREGATE_ZSTD_LEVEL),
+                ..StageEncoding::adaptive(Encoding::Zstd)
+            }
+        } else {
+            StageEncoding::adaptive(Encoding::default())
+        }
+    }
+
+    /// Returns the codec of a payload with the given size of Arrow Flight data
+    /// in bytes (see [`Encoding::available`]).
+    pub fn codec_for(&self, size: usize) -> Encoding {
+        if size < self.threshold {
+            Encoding::None
+        } else {
+            self.codec.available()
+        }
+    }
 }
 
 #[cfg(test)]
@@ -186,4 +473,98 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn unsupported_encoding() {
+        assert!(matches!(
+            Encoding::Zlib.compress(b"flock"),
+            Err(FlockError::NotImplemented(_))
+        ));
+        assert!(matches!(
+            Encoding::Zlib.decompress(b"flock"),
+            Err(FlockError::NotImplemented(_))
+        ));
+        assert_eq!(Encoding::None.compress(b"flock").unwrap(), b"flock");
+    }
+
+    #[test]
+    fn unregistered_dictionary() {
+        let encoding = Encoding::ZstdDict { dict_id: 7 };
+        assert_eq!(encoding.available(), Encoding::Zstd);
+        assert_eq!(
+            StageEncoding::from(encoding.clone()).codec_for(0),
+            Encoding::Zstd
+        );
+        assert!(encoding.decompress(b"flock").is_err());
+        assert_eq!(dictionary_id(b"flock flock"), None);
+        assert!(register_dictionary(b"flock flock".to_vec()).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn prepare_dictionary_once() -> Result<()> {
+        let samples = (0..4096)
+            .map(|i| {
+                format!(
+                    "{{\"auction\":{},\"bidder\":{},\"price\":{}}}",
+                    i,
+                    i % 97,
+                    i * 7
+                )
+            })
+            .map(String::into_bytes)
+            .collect::<Vec<_>>();
+        let dict_id = register_dictionary(train_dictionary(&samples, 4096)?)?;
+        let encoding = Encoding::ZstdDict { dict_id };
+        for sample in &samples[..8] {
+            assert_eq!(&encoding.decompress(&encoding.compress(sample)?)?, sample);
+        }
+
+        // The payloads share the dictionaries prepared by the first one.
+        assert!(Arc::ptr_eq(
+            &encoder_dictionary(dict_id, 3)?,
+            &encoder_dictionary(dict_id, 3)?
+        ));
+        assert!(!Arc::ptr_eq(
+            &encoder_dictionary(dict_id, 3)?,
+            &encoder_dictionary(dict_id, 9)?
+        ));
+        assert!(Arc::ptr_eq(
+            &decoder_dictionary(dict_id)?,
+            &decoder_dictionary(dict_id)?
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn stage_encoding() -> Result<()> {
+        let encoding = StageEncoding {
+            threshold: 1024,
+            ..StageEncoding::from(Encoding::Snappy)
+        };
+        assert_eq!(encoding.codec_for(1023), Encoding::None);
+        assert_eq!(encoding.codec_for(1024), Encoding::Snappy);
+        assert_eq!(
+            StageEncoding::from(Encoding::Snappy).codec_for(0),
+            Encoding::Snappy
+        );
+
+        let stage = StageEncoding::for_stage(false);
+        assert_eq!(stage.codec, Encoding::default());
+        assert_eq!(stage.threshold, *FLOCK_ENCODING_THRESHOLD);
+        if cfg!(feature = "zstd") {
+            let stage = StageEncoding::for_stage(true);
+            assert_eq!(stage.codec, Encoding::Zstd);
+            assert_eq!(stage.level, Some(*FLOCK_AGGREGATE_ZSTD_LEVEL));
+        }
+
+        let data = b"flock flock flock flock flock flock flock flock".repeat(64);
+        for en in [Encoding::Lz4, Encoding::Zstd].iter() {
+            let fast = en.compress_with_level(&data, Some(1))?;
+            let best = en.compress_with_level(&data, Some(12))?;
+            assert_eq!(en.decompress(&fast)?, data);
+            assert_eq!(en.decompress(&best)?, data);
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,363 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! This module contains the optional envelope encryption of the query data in
+//! transit between the cloud functions and at rest in S3.
+//!
+//! If the [`ExecutionContext`] carries [`Encryption::Kms`], every invocation
+//! generates a data key under the customer-managed KMS key, and encrypts the
+//! record batches of its outgoing payloads and the objects it writes to S3
+//! with AES-256-GCM under the data key. The data key wrapped by the KMS key
+//! and the nonce are stored alongside the ciphertext in an [`Envelope`]. The
+//! receivers unwrap the data key with KMS and decrypt the data transparently.
+//! The unwrapped data keys are cached for the invocation, so an invocation
+//! calls KMS once to generate its data key, and once per upstream data key.
+//!
+//! The GCM tag authenticates the ciphertext, so any tampering with the data,
+//! the nonce or the wrapped key fails the decryption. Without the setting,
+//! the encryption is a no-op.
+//!
+//! [`ExecutionContext`]: crate::runtime::context::ExecutionContext
+
+use crate::aws::kms::{AwsKmsClient, DataKey, KeyManagementClient};
+use crate::configs::FLOCK_KMS_KEY_ID;
+use crate::error::{FlockError, Result};
+use crate::runtime::payload::Payload;
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use lazy_static::lazy_static;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// The length of the AES-256 data keys in bytes.
+pub const DATA_KEY_LEN: usize = 32;
+
+/// The length of the AES-GCM nonces in bytes.
+pub const NONCE_LEN: usize = 12;
+
+/// The prefix of the encrypted objects in S3, which tells them apart from the
+/// plaintext ones.
+const ENVELOPE_MAGIC: &[u8] = b"FLOCK-ENVELOPE-1\n";
+
+lazy_static! {
+    static ref INVOCATION_CIPHER: RwLock<Option<Arc<EnvelopeCipher>>> = RwLock::new(None);
+}
+
+/// The encryption of the query data.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Encryption {
+    /// The data is encrypted with the data keys generated under the KMS key.
+    Kms {
+        /// The id, ARN or alias of the customer-managed KMS key.
+        key_id: String,
+    },
+    /// The data is not encrypted by Flock.
+    None,
+}
+
+impl Default for Encryption {
+    fn default() -> Encryption {
+        Encryption::None
+    }
+}
+
+impl Encryption {
+    /// Returns the encryption of the configured KMS key, if any.
+    pub fn from_conf() -> Self {
+        if FLOCK_KMS_KEY_ID.is_empty() {
+            Encryption::None
+        } else {
+            Encryption::Kms {
+                key_id: FLOCK_KMS_KEY_ID.clone(),
+            }
+        }
+    }
+}
+
+/// The encrypted data with the wrapped data key and the nonce.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Envelope {
+    /// The KMS key that wraps the data key.
+    pub key_id:      String,
+    /// The data key encrypted by the KMS key.
+    #[serde(with = "serde_bytes")]
+    pub wrapped_key: Vec<u8>,
+    /// The nonce of AES-GCM.
+    #[serde(with = "serde_bytes")]
+    pub nonce:       Vec<u8>,
+    /// The encrypted data followed by the GCM tag.
+    #[serde(with = "serde_bytes")]
+    pub ciphertext:  Vec<u8>,
+}
+
+/// Encrypts the data with a fresh nonce under the key.
+///
+/// # Returns
+/// The nonce and the ciphertext followed by the GCM tag.
+pub(crate) fn aes_gcm_seal(key: &[u8], plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut nonce = vec![0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let ciphertext = Aes256Gcm::new(Key::from_slice(key))
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| FlockError::Execution("Failed to encrypt the data.".to_string()))?;
+    Ok((nonce, ciphertext))
+}
+
+/// Decrypts the ciphertext, and fails if the GCM tag doesn't authenticate it.
+pub(crate) fn aes_gcm_open(key: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    if key.len() != DATA_KEY_LEN || nonce.len() != NONCE_LEN {
+        return Err(FlockError::Execution(
+            "Failed to decrypt the data: invalid key or nonce.".to_string(),
+        ));
+    }
+    Aes256Gcm::new(Key::from_slice(key))
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| {
+            FlockError::Execution(
+                "Failed to decrypt the data: the data or its key is not authentic.".to_string(),
+            )
+        })
+}
+
+/// The envelope encryption of an invocation. It holds the data key of the
+/// outgoing data, and caches the unwrapped data keys of the incoming data.
+#[derive(Debug)]
+pub struct EnvelopeCipher {
+    key_id:    String,
+    client:    Arc<dyn KeyManagementClient>,
+    data_key:  DataKey,
+    /// The plaintext data keys by their wrapped keys.
+    unwrapped: Mutex<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl EnvelopeCipher {
+    /// Creates the cipher with a new data key under the KMS key.
+    pub async fn new(key_id: &str, client: Arc<dyn KeyManagementClient>) -> Result<Self> {
+        let data_key = client.generate_data_key(key_id).await?;
+        let unwrapped = HashMap::from([(data_key.wrapped.clone(), data_key.plaintext.clone())]);
+        Ok(Self {
+            key_id: key_id.to_string(),
+            client,
+            data_key,
+            unwrapped: Mutex::new(unwrapped),
+        })
+    }
+
+    /// Encrypts the data under the data key of the invocation.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Envelope> {
+        let (nonce, ciphertext) = aes_gcm_seal(&self.data_key.plaintext, plaintext)?;
+        Ok(Envelope {
+            key_id: self.key_id.clone(),
+            wrapped_key: self.data_key.wrapped.clone(),
+            nonce,
+            ciphertext,
+        })
+    }
+
+    /// Unwraps the data key of the envelope with KMS, unless it's cached.
+    pub async fn unwrap_key(&self, envelope: &Envelope) -> Result<()> {
+        if self
+            .unwrapped
+            .lock()
+            .unwrap()
+            .contains_key(&envelope.wrapped_key)
+        {
+            return Ok(());
+        }
+        let plaintext = self
+            .client
+            .decrypt(&envelope.key_id, &envelope.wrapped_key)
+            .await?;
+        self.unwrapped
+            .lock()
+            .unwrap()
+            .insert(envelope.wrapped_key.clone(), plaintext);
+        Ok(())
+    }
+
+    /// Decrypts the envelope, whose data key must be unwrapped already (see
+    /// [`EnvelopeCipher::unwrap_key`]).
+    pub fn decrypt(&self, envelope: &Envelope) -> Result<Vec<u8>> {
+        let unwrapped = self.unwrapped.lock().unwrap();
+        let key = unwrapped.get(&envelope.wrapped_key).ok_or_else(|| {
+            FlockError::Execution("The data key of the envelope is not unwrapped.".to_string())
+        })?;
+        aes_gcm_open(key, &envelope.nonce, &envelope.ciphertext)
+    }
+
+    /// Unwraps the data key of the envelope and decrypts it.
+    pub async fn open(&self, envelope: &Envelope) -> Result<Vec<u8>> {
+        self.unwrap_key(envelope).await?;
+        self.decrypt(envelope)
+    }
+
+    /// Encrypts the bytes of an S3 object.
+    pub fn seal_bytes(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut sealed = ENVELOPE_MAGIC.to_vec();
+        sealed.extend(serde_json::to_vec(&self.encrypt(bytes)?)?);
+        Ok(sealed)
+    }
+
+    /// Decrypts the bytes of an S3 object, which are returned as they are if
+    /// they are not encrypted.
+    pub async fn open_bytes(&self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        match bytes.strip_prefix(ENVELOPE_MAGIC) {
+            Some(envelope) => self.open(&serde_json::from_slice(envelope)?).await,
+            None => Ok(bytes),
+        }
+    }
+}
+
+/// Creates the cipher of the current function invocation, which generates a
+/// new data key if the context is encrypted.
+pub async fn begin(encryption: &Encryption) -> Result<()> {
+    let cipher = match encryption {
+        Encryption::Kms { key_id } => Some(Arc::new(
+            EnvelopeCipher::new(key_id, Arc::new(AwsKmsClient)).await?,
+        )),
+        Encryption::None => None,
+    };
+    *INVOCATION_CIPHER.write().unwrap() = cipher;
+    Ok(())
+}
+
+/// Returns the cipher of the current function invocation, if the data is
+/// encrypted.
+pub fn cipher() -> Option<Arc<EnvelopeCipher>> {
+    INVOCATION_CIPHER.read().unwrap().clone()
+}
+
+/// Encrypts the bytes of an S3 object with the cipher of the invocation, if
+/// any.
+pub fn seal_bytes(bytes: Vec<u8>) -> Result<Vec<u8>> {
+    match cipher() {
+        Some(cipher) => cipher.seal_bytes(&bytes),
+        None => Ok(bytes),
+    }
+}
+
+/// Returns true if the bytes of an S3 object, or a prefix of them, are
+/// encrypted.
+pub fn is_sealed(bytes: &[u8]) -> bool {
+    bytes.starts_with(ENVELOPE_MAGIC)
+}
+
+/// Decrypts the bytes of an S3 object with the cipher of the invocation, if
+/// they are encrypted.
+pub async fn open_bytes(bytes: Vec<u8>) -> Result<Vec<u8>> {
+    if !bytes.starts_with(ENVELOPE_MAGIC) {
+        return Ok(bytes);
+    }
+    match cipher() {
+        Some(cipher) => cipher.open_bytes(bytes).await,
+        None => Err(FlockError::Execution(
+            "The object is encrypted, but the function has no encryption key.".to_string(),
+        )),
+    }
+}
+
+/// Decrypts the record batches of the incoming payload with the cipher of the
+/// invocation, if they are encrypted.
+pub async fn open_payload(payload: &mut Payload) -> Result<()> {
+    if payload.sealed.is_none() {
+        return Ok(());
+    }
+    match cipher() {
+        Some(cipher) => payload.open(&cipher).await,
+        None => Err(FlockError::Execution(
+            "The payload is encrypted, but the function has no encryption key.".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aws::kms::FakeKmsClient;
+
+    #[tokio::test]
+    async fn envelope_round_trip() -> Result<()> {
+        let kms = Arc::new(FakeKmsClient::new());
+        let sender = EnvelopeCipher::new("alias/flock", kms.clone()).await?;
+        let receiver = EnvelopeCipher::new("alias/flock", kms.clone()).await?;
+        assert_eq!(kms.generate_calls(), 2);
+
+        let envelope = sender.encrypt(b"select * from bid")?;
+        assert_ne!(envelope.ciphertext, b"select * from bid".to_vec());
+        assert_eq!(envelope.nonce.len(), NONCE_LEN);
+        // The nonces are never reused under the same data key.
+        assert_ne!(sender.encrypt(b"select * from bid")?.nonce, envelope.nonce);
+
+        // The data key of the sender is unwrapped once per invocation.
+        assert_eq!(receiver.open(&envelope).await?, b"select * from bid");
+        assert_eq!(receiver.open(&envelope).await?, b"select * from bid");
+        assert_eq!(kms.decrypt_calls(), 1);
+
+        // The sender knows its own data key.
+        assert_eq!(sender.open(&envelope).await?, b"select * from bid");
+        assert_eq!(kms.decrypt_calls(), 1);
+
+        // The S3 objects are prefixed, and the plaintext ones pass through.
+        let sealed = sender.seal_bytes(b"state")?;
+        assert!(sealed.starts_with(ENVELOPE_MAGIC));
+        assert_eq!(receiver.open_bytes(sealed).await?, b"state");
+        assert_eq!(receiver.open_bytes(b"state".to_vec()).await?, b"state");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrong_key_fails() -> Result<()> {
+        let kms = Arc::new(FakeKmsClient::new());
+        let sender = EnvelopeCipher::new("alias/flock", kms.clone()).await?;
+        let receiver = EnvelopeCipher::new("alias/other", kms.clone()).await?;
+
+        let mut envelope = sender.encrypt(b"payload")?;
+        envelope.key_id = "alias/other".to_string();
+        assert!(receiver.open(&envelope).await.is_err());
+
+        // A data key of another invocation doesn't authenticate the data.
+        let mut envelope = sender.encrypt(b"payload")?;
+        envelope.wrapped_key = receiver.data_key.wrapped.clone();
+        assert!(receiver.open(&envelope).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn detect_tampering() -> Result<()> {
+        let kms = Arc::new(FakeKmsClient::new());
+        let cipher = EnvelopeCipher::new("alias/flock", kms).await?;
+        let envelope = cipher.encrypt(b"payload")?;
+
+        let mut tampered = envelope.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert!(cipher.open(&tampered).await.is_err());
+
+        // The GCM tag is the last 16 bytes of the ciphertext.
+        let mut tampered = envelope.clone();
+        *tampered.ciphertext.last_mut().unwrap() ^= 1;
+        assert!(cipher.open(&tampered).await.is_err());
+
+        let mut tampered = envelope.clone();
+        tampered.nonce[0] ^= 1;
+        assert!(cipher.open(&tampered).await.is_err());
+
+        let mut tampered = envelope.clone();
+        tampered.wrapped_key[0] ^= 1;
+        assert!(cipher.open(&tampered).await.is_err());
+
+        assert_eq!(cipher.open(&envelope).await?, b"payload");
+        Ok(())
+    }
+}
@@ -16,6 +16,7 @@
 use datafusion::arrow::error::ArrowError;
 use datafusion::error::DataFusionError;
 use datafusion::parquet::errors::ParquetError;
+use serde_json::{json, Value};
 
 use std::error;
 use std::fmt::{Display, Formatter};
@@ -24,6 +25,9 @@ use std::result;
 
 use sqlparser::parser::ParserError;
 
+use crate::runtime::payload::Uuid;
+use crate::runtime::version::WireFormat;
+
 /// Result type for operations that could result in an [FlockError]
 pub type Result<T> = result::Result<T, FlockError>;
 
@@ -63,6 +67,18 @@ pub enum FlockError {
     /// Error returned when the DAG partition failed in Flock.
     /// This error should not happen in normal usage of Flock.
     QueryStage(String),
+    /// Error returned when the DAG builder can't cut the plan into query
+    /// stages safely at the operator, by the name that the serializer tags it
+    /// with, e.g. a cross join whose inputs have to be split.
+    DagPartition { operator: String, reason: String },
+    /// Error returned when a payload or a cloud environment is written with a
+    /// version of its wire format that this binary can't read, e.g. by a newer
+    /// deployment in the middle of an upgrade.
+    FormatVersion {
+        format:  WireFormat,
+        found:   u16,
+        current: u16,
+    },
     /// Error returned during execution of the query.
     /// Examples include files not found, errors in parsing certain types.
     Execution(String),
@@ -73,6 +89,144 @@ pub enum FlockError {
     DataSink(String),
     /// Error returned when accessing the AWS services fails.
     AWS(String),
+    /// Error returned when the function runs out of its execution budget
+    /// before the deadline (see [`crate::runtime::deadline`]).
+    Timeout(String),
+    /// Error returned when a field of the payload with the uuid can't be
+    /// decoded, e.g. a hand-crafted event or a payload of another version.
+    Payload(Uuid, PayloadError),
+}
+
+/// The field of a malformed payload that failed to decode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PayloadError {
+    /// The schema bytes of the relation, i.e. 0 for `schema` and 1 for
+    /// `schema2`, aren't an Arrow IPC schema.
+    Schema { relation: usize, reason: String },
+    /// The data frame at the index of the relation, i.e. 0 for `data` and 1
+    /// for `data2`, can't be decompressed or converted to a record batch.
+    DataFrame {
+        relation: usize,
+        index:    usize,
+        reason:   String,
+    },
+    /// The encrypted data frames can't be decrypted.
+    Sealed(String),
+    /// The payload isn't expected by the receiving stage, i.e. it's from
+    /// another stage than the upstream ones, or of another schema than the
+    /// inputs of the stage (see [`crate::runtime::topology`]).
+    Topology {
+        sender:   String,
+        receiver: String,
+        reason:   String,
+    },
+}
+
+impl PayloadError {
+    /// Returns the name of the field that failed to decode.
+    pub fn field(&self) -> &'static str {
+        match self {
+            PayloadError::Schema { .. } => "Schema",
+            PayloadError::DataFrame { .. } => "DataFrame",
+            PayloadError::Sealed(..) => "Sealed",
+            PayloadError::Topology { .. } => "Topology",
+        }
+    }
+}
+
+impl Display for PayloadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let relation = |r: &usize| if *r == 0 { "" } else { "2" };
+        match self {
+            PayloadError::Schema {
+                relation: r,
+                reason,
+            } => {
+                write!(f, "invalid schema{}: {}", relation(r), reason)
+            }
+            PayloadError::DataFrame {
+                relation: r,
+                index,
+                reason,
+            } => write!(f, "invalid data{}[{}]: {}", relation(r), index, reason),
+            PayloadError::Sealed(reason) => write!(f, "invalid sealed data: {}", reason),
+            PayloadError::Topology {
+                sender,
+                receiver,
+                reason,
+            } => write!(
+                f,
+                "unexpected payload from {} at {}: {}",
+                sender, receiver, reason
+            ),
+        }
+    }
+}
+
+impl FlockError {
+    /// Returns the kind of the error, i.e. the name of its variant, and the
+    /// failed field of a malformed payload, e.g. `Payload.Schema`.
+    pub fn kind(&self) -> String {
+        match self {
+            FlockError::LambdaError(_) => "LambdaError",
+            FlockError::IoError(_) => "IoError",
+            FlockError::Parquet(_) => "Parquet",
+            FlockError::SQL(_) => "SQL",
+            FlockError::Arrow(_) => "Arrow",
+            FlockError::DataFusion(_) => "DataFusion",
+            FlockError::Base64(_) => "Base64",
+            FlockError::SerdeJson(_) => "SerdeJson",
+            FlockError::NotImplemented(_) => "NotImplemented",
+            FlockError::Internal(_) => "Internal",
+            FlockError::Plan(_) => "Plan",
+            FlockError::QueryStage(_) => "QueryStage",
+            FlockError::DagPartition { .. } => "DagPartition",
+            FlockError::FormatVersion { .. } => "FormatVersion",
+            FlockError::Execution(_) => "Execution",
+            FlockError::FunctionGeneration(_) => "FunctionGeneration",
+            FlockError::DataSink(_) => "DataSink",
+            FlockError::AWS(_) => "AWS",
+            FlockError::Timeout(_) => "Timeout",
+            FlockError::Payload(_, e) => return format!("Payload.{}", e.field()),
+        }
+        .to_string()
+    }
+
+    /// Returns true if the same invocation may succeed on another attempt,
+    /// e.g. after the throttling of the AWS calls or a timeout. The malformed
+    /// payloads and the invalid plans fail the same way on every attempt.
+    pub fn is_retryable(&self) -> bool {
+        !matches!(
+            self,
+            FlockError::Payload(..)
+                | FlockError::SerdeJson(_)
+                | FlockError::Base64(_)
+                | FlockError::SQL(_)
+                | FlockError::Plan(_)
+                | FlockError::QueryStage(_)
+                | FlockError::DagPartition { .. }
+                | FlockError::FormatVersion { .. }
+                | FlockError::FunctionGeneration(_)
+                | FlockError::NotImplemented(_)
+        )
+    }
+
+    /// Returns the structured error object of the function response, with the
+    /// kind and the message of the error, and the uuid of the payload if the
+    /// error is about a payload.
+    pub fn to_json(&self) -> Value {
+        let uuid = match self {
+            FlockError::Payload(uuid, _) => json!(uuid),
+            _ => Value::Null,
+        };
+        json!({
+            "error": {
+                "kind": self.kind(),
+                "message": self.to_string(),
+                "uuid": uuid,
+            }
+        })
+    }
 }
 
 impl From<io::Error> for FlockError {
@@ -153,12 +307,40 @@ impl Display for FlockError {
             FlockError::QueryStage(ref desc) => {
                 write!(f, "Error during DAG partitioning: {}", desc)
             }
+            FlockError::DagPartition {
+                ref operator,
+                ref reason,
+            } => write!(
+                f,
+                "Error during DAG partitioning at {}: {}",
+                operator, reason
+            ),
+            FlockError::FormatVersion {
+                ref format,
+                found,
+                current,
+            } => write!(
+                f,
+                "Unsupported {} format version {}: this binary writes version {} and \
+                    reads versions {} to {}",
+                format,
+                found,
+                current,
+                format.oldest_readable(),
+                current
+            ),
             FlockError::Execution(ref desc) => write!(f, "Execution error: {}", desc),
             FlockError::FunctionGeneration(ref desc) => {
                 write!(f, "Function generation error: {}", desc)
             }
             FlockError::DataSink(ref desc) => write!(f, "Data sink error: {}", desc),
             FlockError::AWS(ref desc) => write!(f, "AWS error: {}", desc),
+            FlockError::Timeout(ref desc) => write!(f, "Deadline exceeded: {}", desc),
+            FlockError::Payload(ref uuid, ref desc) => write!(
+                f,
+                "Malformed payload {} ({}/{}): {}",
+                uuid.qid, uuid.seq_num, uuid.seq_len, desc
+            ),
         }
     }
 }
@@ -14,22 +14,43 @@
 //! This crate responsibles for executing queries on AWS Lambda Functions.
 
 extern crate daggy;
+use crate::aws::client::CloudClient;
+use crate::aws::reconcile::{
+    reconcile_functions, FunctionAction, FunctionSpec, Polling, ReconcileReport,
+};
 use crate::configs::*;
 use crate::datasink::DataSinkType;
 use crate::distributed_plan::DistributedPlanner;
 use crate::distributed_plan::QueryDag;
-use crate::error::Result;
+use crate::driver::funcgen::estimate::{
+    estimate_dag, MemoryTable, Selectivity, SourceRate, VolumeEstimate,
+};
+use crate::encoding::{Encoding, StageEncoding};
+use crate::encryption::Encryption;
+use crate::error::{FlockError, Result};
 use crate::launcher::{ExecutionMode, Launcher};
 use crate::query::Query;
+use crate::runtime::capability;
 use crate::runtime::context::*;
-use crate::runtime::plan::CloudExecutionPlan;
+use crate::runtime::dictionary::{store_dictionary, ZstdDictionary};
+use crate::runtime::distribution::SessionAffinity;
+use crate::runtime::function_name::validate_query_code;
+use crate::runtime::lint::{lint_dag, LintReport, LintRule};
+use crate::runtime::multiplex::{shared_code, topology_signature, FunctionRegistry, QueryContexts};
+use crate::runtime::plan::{argmax_key, stats_keys, CloudExecutionPlan, PlanProperties};
+use crate::runtime::result_cache::plan_hash;
+use crate::runtime::running_aggregate::RunningAggregate;
+use crate::runtime::source_filter::SourceFilter;
+use crate::runtime::topology::StageTopology;
 use crate::state::*;
+use crate::stream::Window;
 use async_trait::async_trait;
 use daggy::NodeIndex;
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::physical_plan::ExecutionPlan;
-use log::debug;
+use log::{debug, info};
 use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 
@@ -38,16 +59,71 @@ use std::sync::Arc;
 #[derive(Debug)]
 pub struct AwsLambdaLauncher {
     /// The first component of the function name.
-    pub query_code:    Option<String>,
+    pub query_code:           Option<String>,
     /// The DAG of a given query.
-    pub dag:           QueryDag,
+    pub dag:                  QueryDag,
     /// The data sink type of a given query.
-    pub sink_type:     DataSinkType,
+    pub sink_type:            DataSinkType,
     /// The entire execution plan. This can be used to execute the query
     /// in a single Lambda function.
-    pub plan:          Arc<dyn ExecutionPlan>,
+    pub plan:                 Arc<dyn ExecutionPlan>,
     /// The state backend to use.
-    pub state_backend: Arc<dyn StateBackend>,
+    pub state_backend:        Arc<dyn StateBackend>,
+    /// The window of the query carried in the cloud contexts.
+    pub window:               Option<Window>,
+    /// The first component of the function names shared by the queries of the
+    /// same topology. `None` if the query deploys its own functions.
+    pub shared_code:          Option<String>,
+    /// If true, the Kinesis records carry their metadata as columns (see
+    /// [`KinesisSource`](crate::datasource::kinesis::KinesisSource)).
+    pub metadata_columns:     bool,
+    /// If true, the last stage appends the window columns to its output (see
+    /// [`crate::datasink::enrich`]).
+    pub window_columns:       bool,
+    /// The concurrency reserved for each function that isn't a member of a
+    /// function group. `None` if the functions share the unreserved
+    /// concurrency of the account.
+    pub reserved_concurrency: Option<i64>,
+    /// The memory sizes in MB of the functions of each stage by the name of
+    /// the stage's function or function group. The other functions get the
+    /// memory size given to [`AwsLambdaLauncher::create_cloud_functions`].
+    pub memory_sizes:         HashMap<String, i64>,
+    /// The encoding of the payloads between all stages. `None` if each stage
+    /// chooses its own by the next stage (see [`StageEncoding::for_stage`]).
+    pub encoding:             Option<StageEncoding>,
+    /// If true, the last stage caches its output per window (see
+    /// [`crate::runtime::result_cache`]).
+    pub result_cache:         bool,
+    /// When the stages write the payloads of their next stages to the state
+    /// backend. `None` if each stage gets the policy of its next stage (see
+    /// [`StatePersistence::for_stage`]).
+    pub state_persistence:    Option<StatePersistence>,
+    /// The running aggregates of the last stage (see
+    /// [`crate::runtime::running_aggregate`]).
+    pub running_aggregate:    Option<RunningAggregate>,
+    /// The key columns that the stage shuffling to the aggregation distributes
+    /// its output by (see [`crate::runtime::distribution`]). Empty if the
+    /// output partitions are sent by their positions.
+    pub distribution_keys:    Vec<String>,
+    /// The routing of the output to the session windows by the session key
+    /// (see [`SessionAffinity`]). It applies to the stages feeding a function
+    /// group.
+    pub session_affinity:     Option<SessionAffinity>,
+    /// If true, the functions run the plans of their stages embedded in the
+    /// function binary, and their contexts carry no plans (see
+    /// [`crate::runtime::embedded`]).
+    pub embedded_plans:       bool,
+    /// If true, the source function filters the events by the filters of the
+    /// first stage before it sends them (see
+    /// [`crate::runtime::source_filter`]).
+    pub source_filter:        bool,
+    /// If true, the stages send their payloads to the queues of the next
+    /// stages instead of invoking them (see [`crate::runtime::transport`]).
+    pub via_queue:            bool,
+    /// The Zstd dictionary trained for the payloads of the query. If it's
+    /// set, the stages that compress their payloads with Zstd use the
+    /// dictionary instead (see [`crate::runtime::dictionary`]).
+    pub zstd_dict:            Option<Vec<u8>>,
 }
 
 #[async_trait]
@@ -68,6 +144,9 @@ impl Launcher for AwsLambdaLauncher {
             query.sql().hash(&mut hasher);
             query_code = Some(hasher.finish().to_string());
         }
+        if let Some(code) = &query_code {
+            validate_query_code(code)?;
+        }
 
         let state_backend = query.state_backend();
 
@@ -77,13 +156,31 @@ impl Launcher for AwsLambdaLauncher {
             sink_type,
             query_code,
             state_backend,
+            window: None,
+            shared_code: None,
+            metadata_columns: query.metadata_columns(),
+            window_columns: false,
+            reserved_concurrency: None,
+            memory_sizes: HashMap::new(),
+            encoding: None,
+            result_cache: false,
+            state_persistence: None,
+            running_aggregate: None,
+            distribution_keys: query.distribution_keys().to_vec(),
+            session_affinity: query
+                .session_key()
+                .map(|key| SessionAffinity::new(key, *FLOCK_SESSION_BUCKETS)),
+            embedded_plans: false,
+            source_filter: false,
+            via_queue: false,
+            zstd_dict: None,
         })
     }
 
+    /// Creates the cloud contexts of the query stages. The cloud functions are
+    /// created asynchronously by [`AwsLambdaLauncher::create_cloud_functions`].
     fn deploy(&mut self) -> Result<()> {
-        self.create_cloud_contexts(*FLOCK_FUNCTION_CONCURRENCY)?;
-        self.create_cloud_functions()?;
-        Ok(())
+        self.create_cloud_contexts(*FLOCK_FUNCTION_CONCURRENCY)
     }
 
     /// Invoke the data source function for the given query.
@@ -112,25 +209,135 @@ impl AwsLambdaLauncher {
     where
         T: Into<String>,
     {
+        let query_code = query_code.into();
+        validate_query_code(&query_code)?;
         let planner = DistributedPlanner::new();
         let dag = planner.plan_query_stages(plan.clone()).await?;
         Ok(AwsLambdaLauncher {
-            query_code: Some(query_code.into()),
+            query_code: Some(query_code),
             plan,
             dag,
             sink_type,
             state_backend,
+            window: None,
+            shared_code: None,
+            metadata_columns: false,
+            window_columns: false,
+            reserved_concurrency: None,
+            memory_sizes: HashMap::new(),
+            encoding: None,
+            result_cache: false,
+            state_persistence: None,
+            running_aggregate: None,
+            distribution_keys: vec![],
+            session_affinity: None,
+            embedded_plans: false,
+            source_filter: false,
+            via_queue: false,
+            zstd_dict: None,
         })
     }
 
     /// Initialize the query code for the query.
-    pub fn set_query_code(&mut self, query: &Query) {
-        self.query_code = query.query_code();
-        if self.query_code.is_none() {
+    pub fn set_query_code(&mut self, query: &Query) -> Result<()> {
+        let query_code = query.query_code().unwrap_or_else(|| {
             let mut hasher = DefaultHasher::new();
             query.sql().hash(&mut hasher);
-            self.query_code = Some(hasher.finish().to_string());
+            hasher.finish().to_string()
+        });
+        validate_query_code(&query_code)?;
+        self.query_code = Some(query_code);
+        Ok(())
+    }
+
+    /// Shares the cloud functions with the other queries of the same topology,
+    /// i.e. the functions are named after the topology signature of the query
+    /// instead of its query code. Returns the topology signature.
+    pub fn multiplex(&mut self, group_size: usize) -> String {
+        let signature = topology_signature(&self.dag, group_size);
+        self.shared_code = Some(shared_code(&signature));
+        signature
+    }
+
+    /// Returns the first component of the function names of the query.
+    fn function_code(&self) -> &str {
+        self.shared_code
+            .as_deref()
+            .or_else(|| self.query_code.as_deref())
+            .expect("query code not set")
+    }
+
+    /// Returns the Zstd dictionary of the query carried in its contexts, which
+    /// is stored under the query code even if the functions are shared.
+    fn zstd_dictionary(&self) -> Result<Option<ZstdDictionary>> {
+        self.zstd_dict
+            .as_ref()
+            .map(|dict| {
+                let query_code = self.query_code.as_deref().expect("query code not set");
+                ZstdDictionary::new(dict, &FLOCK_S3_BUCKET, query_code)
+            })
+            .transpose()
+    }
+
+    /// Returns the contexts of the query stages to carry in the payload
+    /// metadata of the multiplexed query.
+    pub fn query_contexts(&self) -> Result<QueryContexts> {
+        let count = self.dag.node_count();
+        let stages = (0..count)
+            .rev()
+            .map(|i| {
+                let node = self.dag.get_node(NodeIndex::new(i)).unwrap();
+                let ctx = node
+                    .context
+                    .as_ref()
+                    .ok_or_else(|| FlockError::Internal("Cloud context not set.".to_string()))?;
+                marshal(ctx, Encoding::default())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(QueryContexts {
+            query_code: self.query_code.clone().expect("query code not set"),
+            stages,
+            first: 0,
+        })
+    }
+
+    /// Deploys the query on the functions shared by the queries of the same
+    /// topology. If the topology signature is already in the registry, the
+    /// deployment is skipped.
+    ///
+    /// # Returns
+    /// The names of all functions of the query.
+    pub async fn deploy_multiplexed(
+        &mut self,
+        client: &dyn CloudClient,
+        registry: &FunctionRegistry,
+        group_size: usize,
+        memory_size: i64,
+        architecture: &str,
+    ) -> Result<Vec<String>> {
+        let signature = self.multiplex(group_size);
+        self.create_cloud_contexts(group_size)?;
+        if let Some(functions) = registry.lookup(&signature).await? {
+            debug!("Reusing the functions of the topology: {}", signature);
+            return Ok(functions);
+        }
+        let functions = self
+            .create_cloud_functions(client, group_size, memory_size, architecture, true)
+            .await?;
+        registry.register(&signature, &functions).await?;
+        Ok(functions)
+    }
+
+    /// Lints the query stages before they are deployed (see
+    /// [`crate::runtime::lint`]).
+    pub fn lint(&self) -> LintReport {
+        let mut report = lint_dag(&self.dag);
+        // The contexts of the embedded stages carry no plans, so their size
+        // isn't bound by the plans.
+        if self.embedded_plans {
+            report.issues.retain(|i| i.rule != LintRule::ContextSize);
         }
+        report
     }
 
     /// Create the cloud contexts for the query.
@@ -141,6 +348,8 @@ impl AwsLambdaLauncher {
 
         // Creates the cloud contexts for the distributed mode
         {
+            let query_code = self.function_code().to_owned();
+            let dictionary = self.zstd_dictionary()?;
             let dag = &mut self.dag;
             let count = dag.node_count();
             assert!(count < 100);
@@ -148,10 +357,45 @@ impl AwsLambdaLauncher {
             let func_types = (0..count)
                 .map(|i| dag.get_node(NodeIndex::new(i)).unwrap().get_function_type())
                 .collect::<Vec<CloudFunctionType>>();
+            let keys = (0..count)
+                .map(|i| stats_keys(&dag.get_node(NodeIndex::new(i)).unwrap().stage))
+                .collect::<Vec<Vec<String>>>();
+            let persistence = (0..count)
+                .map(|i| {
+                    self.state_persistence.unwrap_or_else(|| {
+                        StatePersistence::for_stage(&dag.get_node(NodeIndex::new(i)).unwrap().stage)
+                    })
+                })
+                .collect::<Vec<StatePersistence>>();
+            // The distribution keys apply to the stages that shuffle their output to
+            // the aggregation of a function group.
+            let distributed = (0..count)
+                .map(|i| {
+                    i > 0
+                        && func_types[i - 1] == CloudFunctionType::Group
+                        && PlanProperties::analyze_all(
+                            &dag.get_node(NodeIndex::new(i - 1)).unwrap().stage,
+                        )
+                        .has_final_aggregate
+                })
+                .collect::<Vec<bool>>();
+            if !self.distribution_keys.is_empty() && !distributed.contains(&true) {
+                return Err(FlockError::Plan(format!(
+                    "No query stage shuffles its output to an aggregation, so the query can't be \
+                     distributed by {:?}",
+                    self.distribution_keys
+                )));
+            }
+
+            // The first stage is the last node of the DAG.
+            let source_filters = if self.source_filter {
+                SourceFilter::detect(&dag.get_node(NodeIndex::new(count - 1)).unwrap().stage)?
+            } else {
+                vec![]
+            };
 
             (0..count).rev().for_each(|i| {
                 let node = dag.get_node_mut(NodeIndex::new(i)).unwrap();
-                let query_code = self.query_code.as_ref().expect("query code not set");
 
                 let next = if i == 0 {
                     CloudFunction::Sink(self.sink_type.clone())
@@ -163,12 +407,70 @@ impl AwsLambdaLauncher {
                 } else {
                     CloudFunction::Lambda(format!("{}-{:02}", query_code, count - 1 - (i - 1)))
                 };
+                // The first stage is fed by the data source, whichever function it is.
+                let upstreams = if i + 1 == count {
+                    vec![]
+                } else {
+                    vec![format!("{}-{:02}", query_code, count - 2 - i)]
+                };
+                let encoding = self.encoding.clone().unwrap_or_else(|| {
+                    StageEncoding::for_stage(matches!(next, CloudFunction::Group(_)))
+                });
+                let encoding = match &dictionary {
+                    Some(dictionary) => dictionary.encoding(encoding),
+                    None => encoding,
+                };
+
+                // The results of the last stage are written to the sink directly.
+                let via_queue = self.via_queue && !matches!(next, CloudFunction::Sink(_));
+                let session_affinity = self
+                    .session_affinity
+                    .clone()
+                    .filter(|_| matches!(next, CloudFunction::Group(_)));
 
                 let ctx = ExecutionContext {
                     plan: CloudExecutionPlan::new(node.stage.clone(), None),
                     name: format!("{}-{:02}", query_code, count - 1 - i),
                     next,
+                    via_queue,
                     state_backend: self.state_backend.clone(),
+                    region: flock_region(),
+                    argmax_key: None,
+                    window: self.window.clone(),
+                    stats_keys: if i == 0 { vec![] } else { keys[i - 1].clone() },
+                    distribution_keys: if distributed[i] {
+                        self.distribution_keys.clone()
+                    } else {
+                        vec![]
+                    },
+                    session_affinity,
+                    encryption: Encryption::from_conf(),
+                    encoding,
+                    zstd_dict: dictionary.clone(),
+                    metadata_columns: self.metadata_columns,
+                    window_columns: i == 0 && self.window_columns,
+                    result_cache: (i == 0 && self.result_cache).then(|| plan_hash(&node.stage)),
+                    state_persistence: if i == 0 {
+                        StatePersistence::default()
+                    } else {
+                        persistence[i - 1]
+                    },
+                    running_aggregate: if i == 0 {
+                        self.running_aggregate.clone()
+                    } else {
+                        None
+                    },
+                    topology: Some(StageTopology {
+                        warn_only: *FLOCK_TOPOLOGY_WARN_ONLY,
+                        ..StageTopology::for_stage(&node.stage, upstreams)
+                    }),
+                    source_filters: if i + 1 == count {
+                        source_filters.clone()
+                    } else {
+                        vec![]
+                    },
+                    is_shuffle_stage: Some(node.is_shuffle_stage),
+                    ..Default::default()
                 };
 
                 node.context = Some(ctx);
@@ -179,29 +481,252 @@ impl AwsLambdaLauncher {
         {
             let query_code = self.query_code.as_ref().expect("query code not set");
             let _data_source_ctx = ExecutionContext {
-                plan:          CloudExecutionPlan::new(vec![FLOCK_EMPTY_PLAN.clone()], None),
-                name:          FLOCK_DATA_SOURCE_FUNC_NAME.clone(),
-                next:          CloudFunction::Group((
+                plan: CloudExecutionPlan::new(vec![FLOCK_EMPTY_PLAN.clone()], None),
+                name: FLOCK_DATA_SOURCE_FUNC_NAME.clone(),
+                next: CloudFunction::Group((
                     format!("{}-{:02}", query_code, 0),
                     *FLOCK_FUNCTION_CONCURRENCY,
                 )),
                 state_backend: self.state_backend.clone(),
+                region: flock_region(),
+                argmax_key: argmax_key(&self.plan),
+                window: self.window.clone(),
+                stats_keys: stats_keys(&[self.plan.clone()]),
+                encryption: Encryption::from_conf(),
+                metadata_columns: self.metadata_columns,
+                ..Default::default()
             };
             let _worker_ctx = ExecutionContext {
                 // TODO: add option to store the execution plan in S3.
-                plan:          CloudExecutionPlan::new(vec![self.plan.clone()], None),
-                name:          format!("{}-{:02}", query_code, 0),
-                next:          CloudFunction::Sink(self.sink_type.clone()),
+                plan: CloudExecutionPlan::new(vec![self.plan.clone()], None),
+                name: format!("{}-{:02}", query_code, 0),
+                next: CloudFunction::Sink(self.sink_type.clone()),
                 state_backend: self.state_backend.clone(),
+                region: flock_region(),
+                argmax_key: argmax_key(&self.plan),
+                window: self.window.clone(),
+                stats_keys: vec![],
+                encryption: Encryption::from_conf(),
+                metadata_columns: self.metadata_columns,
+                window_columns: self.window_columns,
+                result_cache: self.result_cache.then(|| plan_hash(&[self.plan.clone()])),
+                running_aggregate: self.running_aggregate.clone(),
+                ..Default::default()
             };
         }
 
         Ok(())
     }
 
-    /// Create the cloud functions for the query.
-    fn create_cloud_functions(&self) -> Result<()> {
-        unimplemented!();
+    /// Returns the contexts of all functions of the query, and whether each
+    /// function is a member of a function group.
+    fn function_contexts(&self, group_size: usize) -> Result<Vec<(ExecutionContext, bool)>> {
+        let count = self.dag.node_count();
+        let mut contexts = vec![];
+        for i in (0..count).rev() {
+            let node = self.dag.get_node(NodeIndex::new(i)).unwrap();
+            let ctx = node
+                .context
+                .clone()
+                .ok_or_else(|| FlockError::Internal("Cloud context not set.".to_string()))?;
+            if node.get_function_type() == CloudFunctionType::Group {
+                (0..group_size).for_each(|j| {
+                    let mut member = ctx.clone();
+                    member.name = format!("{}-{:02}", ctx.name, j);
+                    contexts.push((member, true));
+                });
+            } else {
+                contexts.push((ctx, false));
+            }
+        }
+        Ok(contexts)
+    }
+
+    /// Returns the estimated data volume of each stage for each window, by the
+    /// name of the stage's function or function group, from the source to the
+    /// last stage (see [`crate::driver::funcgen::estimate`]).
+    pub fn estimate_volumes(
+        &self,
+        rate: &SourceRate,
+        selectivity: &Selectivity,
+    ) -> Result<Vec<(String, VolumeEstimate)>> {
+        let estimates = estimate_dag(&*self.dag, |stage| stage.stage.clone(), rate, selectivity);
+        (0..self.dag.node_count())
+            .rev()
+            .map(|i| {
+                let node = self.dag.get_node(NodeIndex::new(i)).unwrap();
+                let ctx = node
+                    .context
+                    .as_ref()
+                    .ok_or_else(|| FlockError::Internal("Cloud context not set.".to_string()))?;
+                Ok((ctx.name.clone(), estimates[&NodeIndex::new(i)]))
+            })
+            .collect()
+    }
+
+    /// Sizes the memory of the functions of each stage by its estimated data
+    /// volume for each window.
+    pub fn size_memory(&mut self, rate: &SourceRate, table: &MemoryTable) -> Result<()> {
+        self.memory_sizes = self
+            .estimate_volumes(rate, &Selectivity::default())?
+            .into_iter()
+            .map(|(name, estimate)| {
+                let memory_size = table.memory_size(&estimate);
+                debug!("{}: {} => {} MB", name, estimate, memory_size);
+                (name, memory_size)
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Returns the reserved concurrency of the functions of the query. Each
+    /// member of a function group aggregates its own windows, so its
+    /// concurrency is 1. The other functions get
+    /// [`AwsLambdaLauncher::reserved_concurrency`] if it's set.
+    pub fn concurrency_settings(&self, group_size: usize) -> Result<Vec<(String, i64)>> {
+        Ok(self
+            .function_contexts(group_size)?
+            .into_iter()
+            .filter_map(
+                |(ctx, is_member)| match (is_member, self.reserved_concurrency) {
+                    (true, _) => Some((ctx.name, 1)),
+                    (false, Some(concurrency)) => Some((ctx.name, concurrency)),
+                    (false, None) => None,
+                },
+            )
+            .collect())
+    }
+
+    /// Reserves the concurrency of the functions of the query (see
+    /// [`AwsLambdaLauncher::concurrency_settings`]).
+    pub async fn reserve_concurrency(
+        &self,
+        client: &dyn CloudClient,
+        group_size: usize,
+    ) -> Result<()> {
+        for (function, concurrency) in self.concurrency_settings(group_size)? {
+            client.put_concurrency(&function, concurrency).await?;
+            debug!(
+                "Reserved concurrency {} of lambda function: {}",
+                concurrency, function
+            );
+        }
+        Ok(())
+    }
+
+    /// Probes the capabilities of the deployed functions of the query, and
+    /// fails if the binary of any function lacks a scalar function that the
+    /// plan of its stage calls (see [`crate::runtime::capability`]).
+    pub async fn verify_capabilities(
+        &self,
+        client: &dyn CloudClient,
+        group_size: usize,
+    ) -> Result<()> {
+        let contexts = self.function_contexts(group_size)?;
+        let errors =
+            futures::future::join_all(contexts.iter().map(|(ctx, _)| {
+                capability::handshake(client, &ctx.name, &ctx.plan.execution_plans)
+            }))
+            .await
+            .into_iter()
+            .filter_map(|r| r.err().map(|e| e.to_string()))
+            .collect::<Vec<_>>();
+        if errors.is_empty() {
+            debug!("Verified the capabilities of {} functions", contexts.len());
+            Ok(())
+        } else {
+            Err(FlockError::FunctionGeneration(errors.join("\n")))
+        }
+    }
+
+    /// Create the cloud functions for the query. The functions left by a
+    /// former deployment of the query are reconciled with their contexts (see
+    /// [`AwsLambdaLauncher::reconcile_cloud_functions`]).
+    ///
+    /// # Arguments
+    /// * `client` - The client that creates the functions.
+    /// * `group_size` - The number of functions in each function group.
+    /// * `memory_size` - The memory size of the lambda functions, unless their
+    ///   stages are sized by [`AwsLambdaLauncher::size_memory`].
+    /// * `architecture` - The architecture of the lambda functions.
+    /// * `reuse` - Whether to reuse the existing functions with the same names
+    ///   instead of updating their code.
+    ///
+    /// # Returns
+    /// The names of all functions of the query.
+    pub async fn create_cloud_functions(
+        &self,
+        client: &dyn CloudClient,
+        group_size: usize,
+        memory_size: i64,
+        architecture: &str,
+        reuse: bool,
+    ) -> Result<Vec<String>> {
+        let report = self
+            .reconcile_cloud_functions(client, group_size, memory_size, architecture, reuse)
+            .await?;
+        info!("{}", report);
+        report.check()?;
+        self.verify_capabilities(client, group_size).await?;
+        self.reserve_concurrency(client, group_size).await?;
+        Ok(report.functions())
+    }
+
+    /// Reconciles the cloud functions of the query with their contexts: the
+    /// missing functions are created, and the existing ones are updated where
+    /// they differ (see [`crate::aws::reconcile`]).
+    ///
+    /// # Arguments
+    /// * `client` - The client that creates and updates the functions.
+    /// * `group_size` - The number of functions in each function group.
+    /// * `memory_size` - The memory size of the lambda functions, unless their
+    ///   stages are sized by [`AwsLambdaLauncher::size_memory`].
+    /// * `architecture` - The architecture of the lambda functions.
+    /// * `reuse` - Whether to leave the existing functions as they are.
+    ///
+    /// # Returns
+    /// The action taken on each function.
+    pub async fn reconcile_cloud_functions(
+        &self,
+        client: &dyn CloudClient,
+        group_size: usize,
+        memory_size: i64,
+        architecture: &str,
+        reuse: bool,
+    ) -> Result<ReconcileReport> {
+        if let (Some(dictionary), Some(dict)) = (self.zstd_dictionary()?, &self.zstd_dict) {
+            store_dictionary(client, &dictionary, dict.clone()).await?;
+        }
+        let mut specs = vec![];
+        let mut reused = vec![];
+        for (ctx, is_member) in self.function_contexts(group_size)? {
+            let stage = if is_member {
+                ctx.name
+                    .rsplit_once('-')
+                    .map_or(&*ctx.name, |(stage, _)| stage)
+            } else {
+                &ctx.name
+            };
+            let memory_size = self.memory_sizes.get(stage).copied().unwrap_or(memory_size);
+            let embedded_stage = self.embedded_plans.then(|| stage.to_owned());
+            if reuse && client.function_exists(&ctx.name).await {
+                debug!("Reusing lambda function: {}", ctx.name);
+                reused.push(ctx.name);
+                continue;
+            }
+            specs.push(
+                FunctionSpec::new(ctx, memory_size, architecture)
+                    .with_embedded_stage(embedded_stage),
+            );
+        }
+
+        let mut report = reconcile_functions(client, &specs, Polling::default()).await?;
+        report.actions.extend(
+            reused
+                .into_iter()
+                .map(|name| (name, FunctionAction::Unchanged)),
+        );
+        Ok(report)
     }
 }
 
@@ -211,6 +736,7 @@ mod tests {
 
     use crate::assert_batches_eq;
     use crate::assert_batches_sorted_eq;
+    use crate::aws::client::FakeCloudClient;
     use crate::datasource::nexmark::event::{Auction, Bid, Person};
     use crate::datasource::nexmark::NEXMarkSource;
     use crate::datasource::ysb::event::{AdEvent, Campaign};
@@ -275,6 +801,45 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn aws_launcher_reserve_concurrency() -> Result<()> {
+        let query = init_query()?;
+        let mut launcher = AwsLambdaLauncher::new(&query).await?;
+        launcher.create_cloud_contexts(2)?;
+
+        // The members of the function groups always reserve one execution.
+        let client = FakeCloudClient::new();
+        launcher.reserve_concurrency(&client, 2).await?;
+        for stage in launcher.dag.get_all_stages() {
+            let name = &stage.context.as_ref().unwrap().name;
+            if stage.get_function_type() == CloudFunctionType::Group {
+                assert_eq!(client.concurrency(&format!("{}-00", name)), Some(1));
+                assert_eq!(client.concurrency(&format!("{}-01", name)), Some(1));
+            } else {
+                assert_eq!(client.concurrency(name), None);
+            }
+        }
+
+        launcher.reserved_concurrency = Some(10);
+        let client = FakeCloudClient::new();
+        launcher.reserve_concurrency(&client, 2).await?;
+        for (function, concurrency) in launcher.concurrency_settings(2)? {
+            assert_eq!(client.concurrency(&function), Some(concurrency));
+        }
+        for stage in launcher.dag.get_all_stages() {
+            if stage.get_function_type() == CloudFunctionType::Lambda {
+                let name = &stage.context.as_ref().unwrap().name;
+                assert_eq!(client.concurrency(name), Some(10));
+            }
+        }
+
+        // The configuration calls fail as the AWS calls do.
+        let (function, _) = launcher.concurrency_settings(2)?.remove(0);
+        client.fail_next(&function, 1);
+        assert!(launcher.reserve_concurrency(&client, 2).await.is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn aws_launcher_execute_stages() -> Result<()> {
         let query = init_query()?;
@@ -304,7 +869,7 @@ mod tests {
         let mut input = vec![vec![vec![batch1]], vec![vec![batch2]]];
         for (i, stage) in stages.into_iter().enumerate() {
             println!("=== Query Stage {:02} ===", i);
-            let mut ctx = stage.context.clone().unwrap();
+            let ctx = stage.context.clone().unwrap();
             ctx.feed_data_sources(input).await?;
             input = ctx
                 .execute()
@@ -423,7 +988,7 @@ mod tests {
         let input = vec![vec![auctions_batches], vec![person_batches]];
 
         // === Query Stage 0 ===
-        let mut ctx = stages[0].context.clone().unwrap();
+        let ctx = stages[0].context.clone().unwrap();
         assert!(ctx.is_shuffling().await?);
         assert!(!ctx.is_last_stage().await?);
         ctx.feed_data_sources(input.clone()).await?;
@@ -434,7 +999,7 @@ mod tests {
 
         // === Query Stage 1 ===
         let num_partitions = output[0].len();
-        let mut ctx = stages[1].context.clone().unwrap();
+        let ctx = stages[1].context.clone().unwrap();
         assert!(!ctx.is_shuffling().await?);
         assert!(!ctx.is_last_stage().await?);
         let mut output1 = vec![];
@@ -448,7 +1013,7 @@ mod tests {
         }
 
         // === Query Stage 2 ===
-        let mut ctx = stages[2].context.clone().unwrap();
+        let ctx = stages[2].context.clone().unwrap();
         assert!(!ctx.is_shuffling().await?);
         assert!(ctx.is_last_stage().await?);
         let output1 = output1.into_iter().flatten().collect::<Vec<_>>();
@@ -462,7 +1027,7 @@ mod tests {
 
         // Local execution mode
         let mut launcher = LocalLauncher::new(&query).await?;
-        launcher.feed_data_sources(input);
+        launcher.feed_data_sources(input)?;
         let batches = launcher.collect().await?;
 
         assert_batches_eq!(expected, &batches);
@@ -577,7 +1142,7 @@ mod tests {
         let input = vec![vec![auctions_batches], vec![bids_batches]];
 
         // === Query Stage 0 ===
-        let mut ctx = stages[0].context.clone().unwrap();
+        let ctx = stages[0].context.clone().unwrap();
         ctx.feed_data_sources(input.clone()).await?;
         // We **MUST USE** execute_partitioned() instead of execute() here.
         let output0 = ctx.execute_partitioned().await?;
@@ -586,7 +1151,7 @@ mod tests {
 
         // === Query Stage 1 ===
         let num_partitions = output0[0].len();
-        let mut ctx = stages[1].context.clone().unwrap();
+        let ctx = stages[1].context.clone().unwrap();
         let mut output1 = vec![];
         for i in 0..num_partitions {
             ctx.feed_data_sources(vec![
@@ -603,7 +1168,7 @@ mod tests {
 
         // === Query Stage 2 ===
         let num_partitions = output1[0].len();
-        let mut ctx = stages[2].context.clone().unwrap();
+        let ctx = stages[2].context.clone().unwrap();
         let mut output2 = vec![];
 
         // Shuffling output1
@@ -635,7 +1200,7 @@ mod tests {
 
         // === Query Stage 3 ===
         let num_partitions = output2[0].len();
-        let mut ctx = stages[3].context.clone().unwrap();
+        let ctx = stages[3].context.clone().unwrap();
         let mut output3 = vec![];
         // Shuffling output2
         //
@@ -669,7 +1234,7 @@ mod tests {
 
         // Local execution mode
         let mut launcher = LocalLauncher::new(&query).await?;
-        launcher.feed_data_sources(input);
+        launcher.feed_data_sources(input)?;
         let batches = launcher.collect().await?;
 
         assert_batches_sorted_eq!(expected, &batches);
@@ -769,7 +1334,7 @@ mod tests {
         let input = vec![vec![ad_events_batches], vec![campaigns_batches]];
 
         // === Query Stage 0 ===
-        let mut ctx = stages[0].context.clone().unwrap();
+        let ctx = stages[0].context.clone().unwrap();
         ctx.feed_data_sources(input.clone()).await?;
         // We **MUST USE** execute_partitioned() instead of execute() here.
         let output0 = ctx.execute_partitioned().await?;
@@ -778,7 +1343,7 @@ mod tests {
 
         // === Query Stage 1 ===
         let num_partitions = output0[0].len();
-        let mut ctx = stages[1].context.clone().unwrap();
+        let ctx = stages[1].context.clone().unwrap();
         let mut output1 = vec![];
         for i in 0..num_partitions {
             ctx.feed_data_sources(vec![
@@ -795,7 +1360,7 @@ mod tests {
 
         // === Query Stage 2 ===
         let num_partitions = output1[0].len();
-        let mut ctx = stages[2].context.clone().unwrap();
+        let ctx = stages[2].context.clone().unwrap();
         let mut output2 = vec![];
 
         // Shuffling output1
@@ -835,7 +1400,7 @@ mod tests {
 
         // Local execution mode
         let mut launcher = LocalLauncher::new(&query).await?;
-        launcher.feed_data_sources(input);
+        launcher.feed_data_sources(input)?;
         let batches = launcher.collect().await?;
 
         assert_batches_sorted_eq!(expected, &batches);
@@ -16,14 +16,11 @@
 use crate::error::{FlockError, Result};
 use crate::launcher::{ExecutionMode, Launcher};
 use crate::query::Query;
+use crate::runtime::plan::{feed_memory_sources, feed_named_sources, FeedReport};
 use async_trait::async_trait;
-use datafusion::arrow::datatypes::{Schema, SchemaRef};
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::physical_plan::collect;
-use datafusion::physical_plan::memory::MemoryExec;
 use datafusion::physical_plan::ExecutionPlan;
-use std::collections::HashSet;
-use std::collections::VecDeque;
 use std::sync::Arc;
 
 /// LocalLauncher executes the query locally.
@@ -39,7 +36,7 @@ impl Launcher for LocalLauncher {
         Self: Sized,
     {
         Ok(LocalLauncher {
-            execution_plan: query.plan().unwrap(),
+            execution_plan: query.plan()?,
         })
     }
 
@@ -58,84 +55,26 @@ impl Launcher for LocalLauncher {
 }
 
 impl LocalLauncher {
-    /// Compare two execution plans' schemas.
-    /// Returns true if they are belong to the same plan node.
+    /// Feeds the query with data. The leaves without a matching data source
+    /// are fed with empty partitions.
     ///
     /// # Arguments
-    /// * `schema1` - The first schema.
-    /// * `schema2` - The second schema.
+    /// * `sources` - A list of data sources.
     ///
     /// # Returns
-    /// * `true` - If the schemas belong to the same plan node.
-    fn compare_schema(schema1: SchemaRef, schema2: SchemaRef) -> bool {
-        let (superset, subset) = if schema1.fields().len() >= schema2.fields().len() {
-            (schema1, schema2)
-        } else {
-            (schema2, schema1)
-        };
-
-        let fields = superset
-            .fields()
-            .iter()
-            .map(|f| f.name())
-            .collect::<HashSet<_>>();
-
-        subset.fields().iter().all(|f| fields.contains(&f.name()))
+    /// Which data source fed which leaf (see [`feed_memory_sources`]).
+    pub fn feed_data_sources(&mut self, sources: Vec<Vec<Vec<RecordBatch>>>) -> Result<FeedReport> {
+        feed_memory_sources(vec![self.execution_plan.clone()], sources)
     }
 
-    /// Feeds the query with data.
-    ///
-    /// # Arguments
-    /// * `sources` - A list of data sources.
-    pub fn feed_data_sources(&mut self, mut sources: Vec<Vec<Vec<RecordBatch>>>) {
-        // Breadth-first search
-        let mut queue = VecDeque::new();
-        queue.push_back(self.execution_plan.clone());
-
-        let mut found = false;
-        let mut index = 0xFFFFFFFF;
-        while !queue.is_empty() {
-            let mut plan = queue.pop_front().unwrap();
-            if plan.children().is_empty() {
-                for (i, partition) in sources.iter().enumerate() {
-                    let mut schema = Arc::new(Schema::new(vec![]));
-                    let mut flag = false;
-                    for p in partition.iter().filter(|p| !p.is_empty()) {
-                        if let Some(b) = p.iter().next() {
-                            schema = b.schema();
-                            flag = true;
-                            break;
-                        }
-                    }
-                    if !flag {
-                        continue;
-                    }
-
-                    if LocalLauncher::compare_schema(plan.schema(), schema) {
-                        index = i;
-                        found = true;
-                        break;
-                    }
-                }
-
-                if found {
-                    unsafe {
-                        Arc::get_mut_unchecked(&mut plan)
-                            .as_mut_any()
-                            .downcast_mut::<MemoryExec>()
-                            .unwrap()
-                            .set_partitions(sources.remove(index));
-                        index = 0xFFFFFFFF;
-                        found = false;
-                    }
-                }
-            }
-
-            plan.children()
-                .iter()
-                .enumerate()
-                .for_each(|(i, _)| queue.push_back(plan.children()[i].clone()));
-        }
+    /// Feeds the query with the data of the given streams, e.g. the tables of
+    /// the same schema (see [`feed_named_sources`]).
+    pub fn feed_named_data_sources(
+        &mut self,
+        sources: Vec<Vec<Vec<RecordBatch>>>,
+        names: &[Option<String>],
+    ) -> Result<FeedReport> {
+        feed_named_sources(vec![self.execution_plan.clone()], sources, names)
     }
 
     /// Collects the results of the query.
@@ -154,6 +93,8 @@ mod tests {
     use crate::datasource::DataSource;
     use crate::query::QueryType;
     use crate::query::Table;
+    use crate::runtime::context::{CloudFunction, ExecutionContext};
+    use crate::runtime::plan::CloudExecutionPlan;
     use crate::state::*;
     use datafusion::arrow::array::*;
     use datafusion::arrow::datatypes::{DataType, Field, Schema};
@@ -217,7 +158,7 @@ mod tests {
             ],
         )?;
 
-        launcher.feed_data_sources(vec![vec![vec![batch]]]);
+        launcher.feed_data_sources(vec![vec![vec![batch]]])?;
         let batches = launcher.collect().await?;
 
         let expected = vec![
@@ -232,4 +173,169 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn local_launcher_with_join() -> Result<()> {
+        let schema1 = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Utf8, false),
+            Field::new("b", DataType::Int32, false),
+        ]));
+        let schema2 = Arc::new(Schema::new(vec![
+            Field::new("c", DataType::Utf8, false),
+            Field::new("d", DataType::Int32, false),
+        ]));
+        let batch1 = RecordBatch::try_new(
+            schema1.clone(),
+            vec![
+                Arc::new(StringArray::from(vec!["a", "b", "c", "d"])),
+                Arc::new(Int32Array::from(vec![1, 10, 10, 100])),
+            ],
+        )?;
+        let batch2 = RecordBatch::try_new(
+            schema2.clone(),
+            vec![
+                Arc::new(StringArray::from(vec!["a", "b", "c", "d"])),
+                Arc::new(Int32Array::from(vec![1, 10, 10, 100])),
+            ],
+        )?;
+
+        let sql = "SELECT a, b, d FROM t1 JOIN t2 ON a = c ORDER BY a ASC LIMIT 3";
+        let query = Query::new(
+            sql,
+            vec![
+                Table("t1".to_owned(), schema1),
+                Table("t2".to_owned(), schema2),
+            ],
+            DataSource::Memory,
+            DataSinkType::Blackhole,
+            None,
+            QueryType::OLAP,
+            Arc::new(HashMapStateBackend::new()),
+        );
+        // The sources are passed in a different order than the join children.
+        let sources = || vec![vec![vec![batch2.clone()]], vec![vec![batch1.clone()]]];
+
+        let ctx = ExecutionContext {
+            plan: CloudExecutionPlan::new(vec![query.plan()?], None),
+            name: "test".to_string(),
+            next: CloudFunction::Sink(DataSinkType::Blackhole),
+            ..Default::default()
+        };
+        let ctx_report = ctx.feed_data_sources(sources()).await?;
+        let ctx_batches = ctx.execute().await?;
+
+        let mut launcher = LocalLauncher::new(&query).await?;
+        let report = launcher.feed_data_sources(sources())?;
+        let batches = launcher.collect().await?;
+
+        // Each join child is fed by the data source of its table.
+        assert_eq!(report.leaves.len(), 2);
+        assert!(report.unused.is_empty());
+        for leaf in &report.leaves {
+            let expected = match leaf.source {
+                Some(0) => "c",
+                Some(1) => "a",
+                source => panic!("unexpected data source: {:?}", source),
+            };
+            assert_eq!(leaf.schema.field(0).name(), expected);
+        }
+        assert_eq!(ctx_report.fed(), report.fed());
+
+        let expected = vec![
+            "+---+----+----+",
+            "| a | b  | d  |",
+            "+---+----+----+",
+            "| a | 1  | 1  |",
+            "| b | 10 | 10 |",
+            "| c | 10 | 10 |",
+            "+---+----+----+",
+        ];
+        assert_batches_eq!(&expected, &ctx_batches[0]);
+        assert_batches_eq!(&expected, &batches);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "nexmark")]
+    #[tokio::test]
+    async fn local_launcher_with_union() -> Result<()> {
+        use crate::datasource::config::Config;
+        use crate::datasource::nexmark::event::Event;
+        use crate::datasource::nexmark::*;
+
+        let mut config = Config::new();
+        config.insert("person-proportion", "30".to_string());
+        config.insert("auction-proportion", "30".to_string());
+        config.insert("bid-proportion", "40".to_string());
+
+        let mut nex = NEXMarkConfig::new(&config);
+        let (mut auctions, mut bids) = (vec![], vec![]);
+        (0..100).for_each(|i| match Event::new(i, 0, &mut nex) {
+            Event::Auction(a) => auctions.push(a),
+            Event::Bid(b) => bids.push(b),
+            Event::Person(_) => {}
+        });
+
+        let sql = "SELECT auction, price FROM bid UNION ALL SELECT a_id, initial_bid FROM auction";
+        let query = Query::new(
+            sql,
+            vec![
+                Table("auction".to_owned(), Arc::new(Auction::schema())),
+                Table("bid".to_owned(), Arc::new(Bid::schema())),
+            ],
+            DataSource::Memory,
+            DataSinkType::Blackhole,
+            None,
+            QueryType::OLAP,
+            Arc::new(HashMapStateBackend::new()),
+        );
+
+        let mut launcher = LocalLauncher::new(&query).await?;
+
+        // The sources are passed in a different order than the union children.
+        launcher.feed_data_sources(vec![
+            vec![vec![auctions_to_batch(&auctions)?]],
+            vec![vec![bids_to_batch(&bids)?]],
+        ])?;
+        let batches = launcher.collect().await?;
+
+        let mut rows = vec![];
+        for batch in &batches {
+            assert_eq!(batch.schema().field(0).name(), "auction");
+            assert_eq!(batch.schema().field(1).name(), "price");
+            let ids = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<Int32Array>()
+                .unwrap();
+            let prices = batch
+                .column(1)
+                .as_any()
+                .downcast_ref::<Int32Array>()
+                .unwrap();
+            rows.extend(
+                ids.values()
+                    .iter()
+                    .copied()
+                    .zip(prices.values().iter().copied()),
+            );
+        }
+        rows.sort_unstable();
+
+        let mut expected = bids
+            .iter()
+            .map(|b| (b.auction as i32, b.price as i32))
+            .chain(
+                auctions
+                    .iter()
+                    .map(|a| (a.a_id as i32, a.initial_bid as i32)),
+            )
+            .collect::<Vec<_>>();
+        expected.sort_unstable();
+
+        assert_eq!(rows.len(), bids.len() + auctions.len());
+        assert_eq!(rows, expected);
+
+        Ok(())
+    }
 }
@@ -26,6 +26,7 @@
 
 //! [Flock](https://github.com/flock-lab/flock) is a cloud-native, distributed, fault-tolerant, and highly-available streaming query engine that supports SQL on cloud function services.
 
+pub mod api;
 pub mod aws;
 pub mod configs;
 pub mod datasink;
@@ -33,6 +34,7 @@ pub mod datasource;
 pub mod distributed_plan;
 pub mod driver;
 pub mod encoding;
+pub mod encryption;
 pub mod error;
 pub mod launcher;
 pub mod prelude;
@@ -41,5 +43,9 @@ pub mod runtime;
 pub mod state;
 pub mod stream;
 pub mod test_util;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testing;
 pub mod tests;
 pub mod transmute;
+
+pub use api::{run_query, update_query, DeployOptions, QueryHandle};
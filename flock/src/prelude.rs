@@ -21,15 +21,27 @@
 //! use flock::prelude::*;
 //! ```
 
+pub use crate::api::{
+    dry_run, run_query, update_query, DeployOptions, DeployTarget, DryRunReport, QueryHandle,
+};
 pub use crate::configs::*;
-pub use crate::datasink::{DataSink, DataSinkFormat, DataSinkType};
-pub use crate::datasource::{nexmark, tpch, ysb, DataSource, DataStream, RelationPartitions};
-pub use crate::encoding::Encoding;
+pub use crate::datasink::{DataSink, DataSinkFormat, DataSinkType, FLOCK_MAX_RESPONSE_SIZE};
+#[cfg(feature = "nexmark")]
+pub use crate::datasource::nexmark;
+#[cfg(feature = "tpch")]
+pub use crate::datasource::tpch;
+#[cfg(feature = "ysb")]
+pub use crate::datasource::ysb;
+pub use crate::datasource::{DataSource, DataStream, RelationPartitions};
+pub use crate::encoding::{Encoding, StageEncoding};
 pub use crate::error::{FlockError, Result};
 pub use crate::launcher::aws::AwsLambdaLauncher;
 pub use crate::query::{Query, QueryType, StreamType, Table};
 pub use crate::runtime::arena::{Arena, HashAggregateStatus, WindowSession};
-pub use crate::runtime::context::{self, CloudFunction, CloudFunctionType, ExecutionContext};
+pub use crate::runtime::context::{
+    self, CloudFunction, CloudFunctionType, ExecutionContext, StatePersistence,
+};
+pub use crate::runtime::metadata::QueryMetadata;
 pub use crate::runtime::payload::{DataFrame, Payload, Uuid, UuidBuilder};
 pub use crate::runtime::plan::{physical_plan, CloudExecutionPlan};
 pub use crate::state::*;
@@ -17,6 +17,7 @@
 use crate::datasink::DataSinkType;
 use crate::datasource::DataSource;
 use crate::error::{FlockError, Result};
+use crate::runtime::plan::with_stream_name;
 use crate::state::*;
 use datafusion::arrow::datatypes::SchemaRef;
 use datafusion::arrow::record_batch::RecordBatch;
@@ -49,6 +50,26 @@ impl Debug for Table {
     }
 }
 
+/// Registers the table of the stream with empty data. The schema of the table
+/// is named after the stream (see [`with_stream_name`]), so that the data
+/// sources are routed to the leaves of the stream by name.
+///
+/// # Returns
+/// An error if a table of the same name is already registered, since the
+/// stream names must be unique per query.
+pub fn register_stream(ctx: &mut ExecutionContext, name: &str, schema: SchemaRef) -> Result<()> {
+    if ctx.table(name).is_ok() {
+        return Err(FlockError::Plan(format!(
+            "The stream {} is registered more than once",
+            name
+        )));
+    }
+    let schema = with_stream_name(&schema, name);
+    let mem_table = MemTable::try_new(schema.clone(), vec![vec![RecordBatch::new_empty(schema)]])?;
+    ctx.register_table(name, Arc::new(mem_table))?;
+    Ok(())
+}
+
 /// The stream type for the query.
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
@@ -84,40 +105,51 @@ pub struct Query {
     /// SQL is a domain-specific language used in programming and designed for
     /// managing data held in a relational database management system, or for
     /// stream processing in a relational data stream management system.
-    pub sql:           String,
+    pub sql:               String,
     /// Table defines the incoming data stream. Each table that is the skeleton
-    /// structure that represents the logical view of streaming data.
-    pub tables:        Vec<Table>,
+    /// structure that represents the logical view of streaming data. The table
+    /// names are the stream names, which must be unique.
+    pub tables:            Vec<Table>,
     /// A streaming data source.
-    pub datasource:    DataSource,
+    pub datasource:        DataSource,
     /// A sink for the output of the query.
-    pub datasink:      DataSinkType,
+    pub datasink:          DataSinkType,
     /// This is used to specify the function name for benchmarking. Otherwise,
     /// the function name is generated from `sql`. To make the debugging easier,
     /// we define human-readable function name for benchmarking.
-    pub query_code:    Option<String>,
+    pub query_code:        Option<String>,
     /// The query type.
-    pub query_type:    QueryType,
+    pub query_type:        QueryType,
     /// The state backend to use.
-    pub state_backend: Arc<dyn StateBackend>,
+    pub state_backend:     Arc<dyn StateBackend>,
+    /// The key columns that the output of the shuffling stage is distributed
+    /// by to the next function group (see [`Query::distribute_by`]). Empty if
+    /// the output partitions are sent to the group by their positions.
+    pub distribution_keys: Vec<String>,
+    /// The session key that the output is routed by to the session windows of
+    /// the next function group (see [`Query::session_by`]).
+    pub session_key:       Option<String>,
 }
 
 impl Default for Query {
     fn default() -> Self {
         Query {
-            sql:           String::new(),
-            tables:        vec![],
-            datasource:    DataSource::default(),
-            datasink:      DataSinkType::default(),
-            query_code:    None,
-            query_type:    QueryType::default(),
-            state_backend: Arc::new(HashMapStateBackend::new()),
+            sql:               String::new(),
+            tables:            vec![],
+            datasource:        DataSource::default(),
+            datasink:          DataSinkType::default(),
+            query_code:        None,
+            query_type:        QueryType::default(),
+            state_backend:     Arc::new(HashMapStateBackend::new()),
+            distribution_keys: vec![],
+            session_key:       None,
         }
     }
 }
 
 impl Query {
-    /// Creates a new query.
+    /// Creates a new query. The tables of the same name are reported when the
+    /// query is planned (see [`register_stream`]).
     pub fn new<T>(
         sql: T,
         tables: Vec<Table>,
@@ -138,9 +170,45 @@ impl Query {
             query_code: query_code.map(|x| x.into()),
             query_type,
             state_backend,
+            distribution_keys: vec![],
+            session_key: None,
         }
     }
 
+    /// Distributes the output of the stage that shuffles to the aggregation by
+    /// the hash of the given key columns, e.g.
+    /// `distribute_by(["campaign_id"])`, so that a key always lands on the
+    /// same member of the function group. The keys must be the hash
+    /// partitioning columns of the stage, which is checked by the lint (see
+    /// [`crate::runtime::lint`]).
+    pub fn distribute_by<I, S>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.distribution_keys = keys.into_iter().map(|k| k.into()).collect();
+        self
+    }
+
+    /// Returns the key columns that the shuffled output is distributed by.
+    pub fn distribution_keys(&self) -> &[String] {
+        &self.distribution_keys
+    }
+
+    /// Routes the output of the stages feeding the session windows by the
+    /// given session key column, e.g. `session_by("bidder")`, so that all
+    /// events of a session land on the same member of the function group (see
+    /// [`SessionAffinity`](crate::runtime::distribution::SessionAffinity)).
+    pub fn session_by(mut self, key: impl Into<String>) -> Self {
+        self.session_key = Some(key.into());
+        self
+    }
+
+    /// Returns the session key that the output is routed by.
+    pub fn session_key(&self) -> Option<&str> {
+        self.session_key.as_deref()
+    }
+
     /// Returns a SQL query.
     pub fn sql(&self) -> String {
         self.sql.to_owned()
@@ -161,6 +229,28 @@ impl Query {
         self.datasink.clone()
     }
 
+    /// Returns true if the Kinesis records of the query carry their metadata
+    /// as columns.
+    pub fn metadata_columns(&self) -> bool {
+        match &self.datasource {
+            #[cfg(feature = "kinesis")]
+            DataSource::KinesisEvent(source) => source.metadata_columns,
+            _ => false,
+        }
+    }
+
+    /// Returns the schema of the table registered in the planner, which has
+    /// the metadata columns of the Kinesis records appended if they are on.
+    fn table_schema(&self, table: &Table) -> SchemaRef {
+        match &self.datasource {
+            #[cfg(feature = "kinesis")]
+            DataSource::KinesisEvent(source) if source.metadata_columns => {
+                crate::datasource::kinesis::with_metadata_columns(&table.1)
+            }
+            _ => table.1.clone(),
+        }
+    }
+
     /// Returns the state backend for a given query.
     /// The state backend is used to store the state of the query.
     pub fn state_backend(&self) -> Arc<dyn StateBackend> {
@@ -171,11 +261,7 @@ impl Query {
     pub fn plan(&self) -> Result<Arc<dyn ExecutionPlan>> {
         let mut ctx = ExecutionContext::new();
         for table in &self.tables {
-            let mem_table = MemTable::try_new(
-                table.1.clone(),
-                vec![vec![RecordBatch::new_empty(table.1.clone())]],
-            )?;
-            ctx.register_table(table.0.as_ref(), Arc::new(mem_table))?;
+            register_stream(&mut ctx, &table.0, self.table_schema(table))?;
         }
 
         let plan = ctx.create_logical_plan(self.sql.as_ref())?;
@@ -202,11 +288,7 @@ impl Query {
         let config = ExecutionConfig::new().with_target_partitions(shuffle_partitions);
         let mut ctx = ExecutionContext::with_config(config);
         for table in &self.tables {
-            let mem_table = MemTable::try_new(
-                table.1.clone(),
-                vec![vec![RecordBatch::new_empty(table.1.clone())]],
-            )?;
-            ctx.register_table(table.0.as_ref(), Arc::new(mem_table))?;
+            register_stream(&mut ctx, &table.0, self.table_schema(table))?;
         }
 
         let plan = ctx.create_logical_plan(self.sql.as_ref())?;
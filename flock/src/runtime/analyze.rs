@@ -0,0 +1,305 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The analyze mode runs a query once and annotates each stage of the query
+//! DAG with the observed row counts and timings, similar to `EXPLAIN ANALYZE`
+//! in DataFusion but across cloud functions.
+//!
+//! Each function invocation reports its [`StageMetrics`] to S3 under the key
+//! prefix `<query code>/analyze/`, no matter whether it was invoked
+//! synchronously or asynchronously (e.g. the members of a function group). The
+//! driver then merges all reports into an [`AnalyzeReport`] and renders the
+//! annotated DAG.
+
+use crate::aws::s3;
+use crate::configs::FLOCK_S3_BUCKET;
+use crate::error::Result;
+use crate::runtime::context::ExecutionContext;
+use crate::runtime::function_name::{query_key, FunctionName};
+use crate::runtime::metadata::QueryMetadata;
+use crate::runtime::payload::Payload;
+use crate::runtime::plan::CloudExecutionPlan;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::physical_plan::ExecutionPlan;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// The metadata key to enable the analyze mode in the function payload.
+pub const ANALYZE_METADATA_KEY: &str = "analyze";
+
+/// Returns true if the analyze mode is enabled in the payload metadata.
+pub fn is_analyze(metadata: &Option<QueryMetadata>) -> bool {
+    metadata
+        .as_ref()
+        .and_then(|m| m.get(ANALYZE_METADATA_KEY))
+        .map(|v| v == "true")
+        .unwrap_or(false)
+}
+
+/// The S3 key prefix of the analyze reports for the given query.
+pub fn analyze_key_prefix(query_code: &str) -> String {
+    query_key(query_code, "analyze/")
+}
+
+/// The telemetry of a query stage observed in the cloud functions.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StageMetrics {
+    /// The index of the stage in the query DAG.
+    pub stage:         usize,
+    /// The function names that executed the stage.
+    pub functions:     BTreeSet<String>,
+    /// The number of function invocations.
+    pub invocations:   usize,
+    /// The number of input rows.
+    pub rows_in:       usize,
+    /// The number of output rows.
+    pub rows_out:      usize,
+    /// The execution time of the physical plan in milliseconds.
+    pub execute_ms:    u64,
+    /// The size of the incoming payloads in bytes.
+    pub payload_bytes: usize,
+}
+
+impl StageMetrics {
+    /// Creates a new stage metrics for a single invocation of the function.
+    ///
+    /// The stage index is derived from the function name: `<query
+    /// code>-<plan index>[-<group index>]`.
+    pub fn new(function_name: &str) -> Self {
+        let stage = FunctionName::parse(function_name)
+            .map(|name| name.plan_index)
+            .unwrap_or_default();
+        let mut functions = BTreeSet::new();
+        functions.insert(function_name.to_string());
+        Self {
+            stage,
+            functions,
+            invocations: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Records the size of the incoming payload.
+    pub fn record_payload(&mut self, payload: &Payload) {
+        self.payload_bytes += payload.get_data_size();
+    }
+
+    /// Records the number of input rows.
+    pub fn record_input(&mut self, input: &[Vec<Vec<RecordBatch>>]) {
+        self.rows_in += input
+            .iter()
+            .flatten()
+            .flatten()
+            .map(|b| b.num_rows())
+            .sum::<usize>();
+    }
+
+    /// Records the number of output rows.
+    pub fn record_output(&mut self, output: &[Vec<RecordBatch>]) {
+        self.rows_out += output.iter().flatten().map(|b| b.num_rows()).sum::<usize>();
+    }
+
+    /// Merges the metrics of another invocation of the same stage.
+    pub fn merge(&mut self, other: &StageMetrics) {
+        self.functions.extend(other.functions.iter().cloned());
+        self.invocations += other.invocations;
+        self.rows_in += other.rows_in;
+        self.rows_out += other.rows_out;
+        self.execute_ms += other.execute_ms;
+        self.payload_bytes += other.payload_bytes;
+    }
+
+    /// Reports the metrics of the current invocation to S3.
+    ///
+    /// # Arguments
+    /// * `query_code` - The query code of the function.
+    /// * `invocation_id` - The unique identifier of the current invocation.
+    pub async fn report(&self, query_code: &str, invocation_id: &str) -> Result<()> {
+        let function_name = self.functions.iter().next().cloned().unwrap_or_default();
+        let key = format!(
+            "{}{}/{}",
+            analyze_key_prefix(query_code),
+            function_name,
+            invocation_id
+        );
+        s3::put_object(&FLOCK_S3_BUCKET, &key, serde_json::to_vec(self)?).await
+    }
+}
+
+/// The merged telemetry of all stages of a query.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AnalyzeReport {
+    stages: BTreeMap<usize, StageMetrics>,
+}
+
+impl AnalyzeReport {
+    /// Creates an empty report.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the metrics of a single invocation to the report.
+    pub fn add(&mut self, metrics: StageMetrics) {
+        match self.stages.get_mut(&metrics.stage) {
+            Some(m) => m.merge(&metrics),
+            None => {
+                self.stages.insert(metrics.stage, metrics);
+            }
+        }
+    }
+
+    /// Returns the merged metrics of the given stage.
+    pub fn get(&self, stage: usize) -> Option<&StageMetrics> {
+        self.stages.get(&stage)
+    }
+
+    /// Returns the merged metrics of all executed stages in the order of the
+    /// stage index.
+    pub fn stages(&self) -> impl Iterator<Item = &StageMetrics> {
+        self.stages.values()
+    }
+
+    /// Fetches all metrics reported by the functions of the given query.
+    pub async fn fetch(query_code: &str) -> Result<Self> {
+        let mut report = AnalyzeReport::new();
+        for key in s3::get_matched_keys(&FLOCK_S3_BUCKET, &analyze_key_prefix(query_code)).await? {
+            report.add(serde_json::from_slice(
+                &s3::get_object(&FLOCK_S3_BUCKET, &key).await?,
+            )?);
+        }
+        Ok(report)
+    }
+
+    /// Removes the metrics of the previous runs of the given query.
+    pub async fn clear(query_code: &str) -> Result<()> {
+        s3::delete_matched_objects(&FLOCK_S3_BUCKET, &analyze_key_prefix(query_code)).await
+    }
+
+    /// Renders the query stages with the annotations inline per stage.
+    ///
+    /// # Arguments
+    /// * `stages` - The displayable plans of the query stages. The index of the
+    ///   plan is the stage index (the plan index in the function name).
+    pub fn render(&self, stages: &[String]) -> String {
+        let mut output = String::new();
+        for (i, plan) in stages.iter().enumerate() {
+            let annotation = match self.get(i) {
+                Some(m) => format!(
+                    "functions={}, invocations={}, rows_in={}, rows_out={}, execute={}ms, \
+                     payload={}B",
+                    m.functions.len(),
+                    m.invocations,
+                    m.rows_in,
+                    m.rows_out,
+                    m.execute_ms,
+                    m.payload_bytes
+                ),
+                None => "not executed".to_string(),
+            };
+            writeln!(output, "=== Stage {} === [{}]", i, annotation).unwrap();
+            writeln!(output, "{}", plan.trim_end()).unwrap();
+            writeln!(output).unwrap();
+        }
+        output
+    }
+}
+
+/// Runs the query stages in the current process, and collects the telemetry of
+/// each stage. This is useful to inspect a query without deploying it to the
+/// cloud, e.g. `EXPLAIN ANALYZE` in fsql.
+///
+/// # Arguments
+/// * `stages` - The subplans of the query stages in topological order.
+/// * `sources` - The input data sources of the first stage.
+pub async fn analyze_locally(
+    stages: Vec<Vec<Arc<dyn ExecutionPlan>>>,
+    mut sources: Vec<Vec<Vec<RecordBatch>>>,
+) -> Result<AnalyzeReport> {
+    let mut report = AnalyzeReport::new();
+    for (i, stage) in stages.into_iter().enumerate() {
+        let ctx = ExecutionContext {
+            plan: CloudExecutionPlan::new(stage, None),
+            name: format!("local-{:02}", i),
+            ..Default::default()
+        };
+
+        let mut metrics = StageMetrics::new(&ctx.name);
+        metrics.record_input(&sources);
+        let start = Instant::now();
+        ctx.feed_data_sources(sources).await?;
+        let output = ctx.execute().await?;
+        metrics.execute_ms = start.elapsed().as_millis() as u64;
+        metrics.record_output(&output);
+        report.add(metrics);
+
+        // The outputs of the current stage are the inputs of the next stage.
+        sources = output.into_iter().map(|batches| vec![batches]).collect();
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_stage_metrics() {
+        let mut report = AnalyzeReport::new();
+        for (name, rows) in [
+            ("q4-00", 10),
+            ("q4-01-00", 4),
+            ("q4-01-01", 6),
+            ("q4-00", 5),
+        ] {
+            let mut m = StageMetrics::new(name);
+            m.rows_in = rows;
+            m.rows_out = rows / 2;
+            m.execute_ms = 3;
+            report.add(m);
+        }
+
+        let stage0 = report.get(0).unwrap();
+        assert_eq!(stage0.invocations, 2);
+        assert_eq!(stage0.functions.len(), 1);
+        assert_eq!(stage0.rows_in, 15);
+        assert_eq!(stage0.rows_out, 7);
+
+        let stage1 = report.get(1).unwrap();
+        assert_eq!(stage1.invocations, 2);
+        assert_eq!(stage1.functions.len(), 2);
+        assert_eq!(stage1.rows_in, 10);
+        assert_eq!(stage1.execute_ms, 6);
+
+        let output = report.render(&[
+            "ProjectionExec\n".to_string(),
+            "HashAggregateExec\n".to_string(),
+            "SortExec\n".to_string(),
+        ]);
+        assert!(output.contains(
+            "=== Stage 0 === [functions=1, invocations=2, rows_in=15, rows_out=7, execute=6ms, \
+             payload=0B]"
+        ));
+        assert!(output.contains("=== Stage 2 === [not executed]\nSortExec"));
+    }
+
+    #[test]
+    fn analyze_metadata() {
+        assert!(!is_analyze(&None));
+        let mut metadata = QueryMetadata::default();
+        metadata.insert(ANALYZE_METADATA_KEY.to_string(), "true".to_string());
+        assert!(is_analyze(&Some(metadata)));
+    }
+}
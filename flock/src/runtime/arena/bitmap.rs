@@ -33,32 +33,50 @@ pub struct Bitmap {
 impl Bitmap {
     /// Creates a new bitmap with the given capacity.
     pub fn new(num_bits: usize) -> Self {
-        let num_bytes = num_bits / 8 + if num_bits % 8 > 0 { 1 } else { 0 };
-        let r = num_bytes % 64;
-        let len = if r == 0 {
-            num_bytes
-        } else {
-            num_bytes + 64 - r
-        };
-
+        let len = padded_len(num_bits);
         let mut buf = MutableBuffer::new(len);
         buf.extend_from_slice(&vec![0x00; len]);
         Bitmap { bits: buf }
     }
 
-    /// Returns `true` if the bit is set, `false` otherwise.
+    /// Returns `true` if the bit is set, `false` otherwise. The bits beyond the
+    /// capacity are not set.
     pub fn is_set(&self, i: usize) -> bool {
-        assert!(i < (self.bits.len() << 3));
-        unsafe { bit_util::get_bit_raw(self.bits.as_ptr(), i) }
+        i < (self.bits.len() << 3) && unsafe { bit_util::get_bit_raw(self.bits.as_ptr(), i) }
     }
 
-    /// Sets the bit at the given index.
+    /// Sets the bit at the given index. The bitmap grows if the index is beyond
+    /// its capacity, e.g. if the window was sized by a flush that overtook its
+    /// payloads.
     pub fn set(&mut self, i: usize) {
-        assert!(i < (self.bits.len() << 3));
+        if i >= (self.bits.len() << 3) {
+            let len = padded_len(i + 1);
+            self.bits
+                .extend_from_slice(&vec![0x00; len - self.bits.len()]);
+        }
         unsafe {
             bit_util::set_bit_raw(self.bits.as_mut_ptr(), i);
         }
     }
+
+    /// Returns the indices of the bits that are set in ascending order.
+    pub fn ones(&self) -> Vec<usize> {
+        (0..self.bits.len() << 3)
+            .filter(|i| self.is_set(*i))
+            .collect()
+    }
+}
+
+/// Returns the number of bytes of `num_bits` bits, padded to a multiple of 64
+/// bytes.
+fn padded_len(num_bits: usize) -> usize {
+    let num_bytes = num_bits / 8 + if num_bits % 8 > 0 { 1 } else { 0 };
+    let r = num_bytes % 64;
+    if r == 0 {
+        num_bytes
+    } else {
+        num_bytes + 64 - r
+    }
 }
 
 #[cfg(test)]
@@ -76,6 +94,14 @@ mod tests {
 
         bitmap.set(100);
         assert!(bitmap.is_set(100));
+        assert_eq!(bitmap.ones(), vec![0, 100]);
+
+        // The bits beyond the capacity are unset until the bitmap grows.
+        assert!(!bitmap.is_set(4096));
+        bitmap.set(4096);
+        assert!(bitmap.is_set(4096));
+        assert_eq!(bitmap.bits.len() % 64, 0);
+        assert_eq!(bitmap.ones(), vec![0, 100, 4096]);
 
         Ok(())
     }
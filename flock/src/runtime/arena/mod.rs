@@ -14,13 +14,50 @@
 //! The global data structure inside the lambda function is used to aggregate
 //! the data frames of the previous stage of dataflow to ensure the integrity of
 //! the window data for stream processing.
+//!
+//! The arena accounts for the bytes of the encoded data frames that each open
+//! window holds in memory, which are reported by [`Arena::stats`]. When the
+//! arena grows over `arena_spill_fraction` of the function memory (see
+//! [`spill_threshold`]), the largest incomplete window is spilled to the state
+//! backend with [`Arena::spill`], and read back with [`Arena::restore`] once
+//! the window is complete.
+//!
+//! If the data source stops in the middle of a window, the window never
+//! receives the rest of its payloads. The data source then sends a flush
+//! payload (see [`flush_payload`]) with the number of payloads it actually
+//! produced for the window, and [`Arena::flush`] shrinks the window to that
+//! size, so that the partial window is emitted once they have arrived.
+//!
+//! Before the function binary is upgraded, the open windows are moved out of
+//! the arena into a snapshot in the state backend, and restored into the arena
+//! of the new binary (see [`snapshot`]).
 
 mod bitmap;
 pub use bitmap::Bitmap;
 
+mod processed;
+pub use processed::{done_marker_key, DoneMarker, ProcessedWindows};
+
+mod session_state;
+pub use session_state::{
+    Session, SessionMetadata, SessionSnapshot, SessionState, END_OF_INPUT_WATERMARK,
+    SESSION_GAP_METADATA_KEY, SESSION_KEY_METADATA_KEY, SESSION_TIME_METADATA_KEY,
+    UPSTREAMS_METADATA_KEY, UPSTREAM_METADATA_KEY,
+};
+
+pub mod snapshot;
+pub use snapshot::{ArenaSnapshot, WindowSnapshot};
+
+mod window_state;
+pub use window_state::{WindowState, PANE_METADATA_KEY, WINDOW_METADATA_KEY};
+
 use crate::encoding::Encoding;
 use crate::error::{FlockError, Result};
-use crate::runtime::payload::{DataFrame, Payload};
+use crate::runtime::metadata::QueryMetadata;
+use crate::runtime::metrics::{self, Metric};
+use crate::runtime::payload::{DataFrame, Payload, Uuid};
+use crate::runtime::stats::PayloadStats;
+use crate::state::StateBackend;
 use crate::transmute::*;
 use datafusion::arrow::datatypes::SchemaRef;
 use datafusion::arrow::record_batch::RecordBatch;
@@ -28,8 +65,11 @@ use datafusion::arrow_flight::utils::flight_data_to_arrow_batch;
 use datafusion::arrow_flight::FlightData;
 use hashbrown::HashMap;
 use rayon::prelude::*;
+use serde::Serialize;
 use std::ops::{Deref, DerefMut};
+use std::time::Instant;
 use tokio::task::JoinHandle;
+use tracing::{error, warn};
 
 type QueryId = String;
 type ShuffleId = usize;
@@ -37,6 +77,16 @@ type ShuffleId = usize;
 /// The window identifier to identify the window in the global arena.
 pub type WindowId = (QueryId, ShuffleId);
 
+/// The key prefix of the data fragments spilled to the state backend.
+pub const SPILL_KEY_PREFIX: &str = "arena-spill";
+
+/// The metadata key of the number of payloads that the data source actually
+/// produced for the window before it stopped.
+pub const FLUSH_METADATA_KEY: &str = "flush";
+
+/// The metadata key that marks the output of a flushed (partial) window.
+pub const FLUSHED_METADATA_KEY: &str = "flushed";
+
 /// The aggregator function has three status to determine the next step.
 #[derive(PartialEq)]
 pub enum HashAggregateStatus {
@@ -82,9 +132,38 @@ pub struct WindowSession {
     pub bitmap:         Bitmap,
     /// The compression method.
     pub encoding:       Encoding,
+    /// The merged statistics of the first relation. `None` if a payload with
+    /// data doesn't carry the statistics.
+    pub r1_stats:       Option<PayloadStats>,
+    /// The merged statistics of the second relation.
+    pub r2_stats:       Option<PayloadStats>,
+    /// The size in bytes of the encoded data frames held in memory.
+    pub bytes:          usize,
+    /// The keys of the data fragments spilled to the state backend.
+    pub spilled:        Vec<String>,
+    /// When the first data fragment of the window was received.
+    pub created:        Instant,
+    /// True if the data source has flushed the window, i.e. [`Self::size`]
+    /// is the number of payloads actually produced for the window.
+    pub flushed:        bool,
+    /// The ids of the payloads received by their sequence numbers, which tell
+    /// a redelivered payload from a distinct payload that reused the sequence
+    /// number (see [`Payload::payload_id`]).
+    pub payload_ids:    HashMap<usize, String>,
 }
 
 impl WindowSession {
+    /// Returns the number of data fragments received, including the spilled
+    /// ones.
+    pub fn received(&self) -> usize {
+        self.r1_flight_data.len() + self.spilled.len()
+    }
+
+    /// Returns true if all data fragments of the window are received.
+    pub fn is_complete(&self) -> bool {
+        self.size == self.received()
+    }
+
     /// Return the schema of data fragments in the temporal window.
     pub fn schema(&self) -> Result<(SchemaRef, Option<SchemaRef>)> {
         if self.r1_schema.is_empty() {
@@ -176,11 +255,146 @@ impl Arena {
     /// Return true if the temporal window is empty.
     pub fn is_complete(&self, window_id: &WindowId) -> bool {
         self.get(window_id)
-            .map(|window| window.size == window.r1_flight_data.len())
+            .map(|window| window.is_complete())
+            .unwrap_or(false)
+    }
+
+    /// Returns true if the data source has flushed the temporal window.
+    pub fn is_flushed(&self, window_id: &WindowId) -> bool {
+        self.get(window_id)
+            .map(|window| window.flushed)
             .unwrap_or(false)
     }
 
-    /// Collect the data fragments for temporal windows.
+    /// Returns the size in bytes of the data frames held by all windows.
+    pub fn total_bytes(&self) -> usize {
+        self.values().map(|window| window.bytes).sum()
+    }
+
+    /// Returns the open windows, the largest first.
+    pub fn stats(&self) -> ArenaStats {
+        let mut windows = self
+            .iter()
+            .map(|((qid, shuffle_id), window)| WindowStats {
+                query_id:   qid.clone(),
+                shuffle_id: *shuffle_id,
+                bytes:      window.bytes,
+                received:   window.received(),
+                spilled:    window.spilled.len(),
+                size:       window.size,
+                age_ms:     window.created.elapsed().as_millis() as u64,
+            })
+            .collect::<Vec<_>>();
+        windows.sort_by(|a, b| {
+            b.bytes
+                .cmp(&a.bytes)
+                .then_with(|| (&a.query_id, a.shuffle_id).cmp(&(&b.query_id, b.shuffle_id)))
+        });
+        ArenaStats {
+            total_bytes: self.total_bytes(),
+            windows,
+        }
+    }
+
+    /// Returns the window to spill if the arena holds more than `threshold`
+    /// bytes: the largest incomplete window with data frames in memory.
+    pub fn spill_candidate(&self, threshold: usize) -> Option<WindowId> {
+        if self.total_bytes() <= threshold {
+            return None;
+        }
+        self.iter()
+            .filter(|(_, window)| !window.is_complete() && window.bytes > 0)
+            .max_by_key(|(_, window)| window.bytes)
+            .map(|(window_id, _)| window_id.clone())
+    }
+
+    /// Writes the data fragments of the window held in memory to the state
+    /// backend, and drops them from the arena.
+    ///
+    /// # Arguments
+    /// * `window_id` - The window to spill.
+    /// * `backend` - The state backend to write the data fragments to.
+    /// * `bucket` - The bucket of the state backend.
+    ///
+    /// # Returns
+    /// The number of bytes released from the arena.
+    pub async fn spill(
+        &mut self,
+        window_id: &WindowId,
+        backend: &dyn StateBackend,
+        bucket: &str,
+    ) -> Result<usize> {
+        let window = match self.get_mut(window_id) {
+            Some(window) => window,
+            None => return Ok(0),
+        };
+        let mut released = 0;
+        while let (Some(data), Some(data2)) =
+            (window.r1_flight_data.pop(), window.r2_flight_data.pop())
+        {
+            let key = format!(
+                "{}/{}/{}/{}",
+                SPILL_KEY_PREFIX,
+                window_id.0,
+                window_id.1,
+                window.spilled.len()
+            );
+            let bytes = frames_bytes(&data) + frames_bytes(&data2);
+            let payload = Payload {
+                data,
+                data2,
+                schema: window.r1_schema.clone(),
+                schema2: window.r2_schema.clone(),
+                encoding: window.encoding.clone(),
+                ..Default::default()
+            };
+            let result = match serde_json::to_vec(&payload) {
+                Ok(bytes) => backend.write(bucket.to_string(), key.clone(), bytes).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                // The fragment stays in memory if it can't be spilled.
+                window.r1_flight_data.push(payload.data);
+                window.r2_flight_data.push(payload.data2);
+                window.bytes -= released;
+                return Err(e);
+            }
+            window.spilled.push(key);
+            released += bytes;
+        }
+        window.bytes -= released;
+        Ok(released)
+    }
+
+    /// Reads the data fragments of the window spilled by [`Arena::spill`]
+    /// back into the arena.
+    pub async fn restore(
+        &mut self,
+        window_id: &WindowId,
+        backend: &dyn StateBackend,
+        bucket: &str,
+    ) -> Result<()> {
+        let window = match self.get_mut(window_id) {
+            Some(window) if !window.spilled.is_empty() => window,
+            _ => return Ok(()),
+        };
+        let payloads = backend
+            .read(bucket.to_string(), window.spilled.clone())
+            .await?;
+        window.spilled.clear();
+        for payload in payloads {
+            window.bytes += frames_bytes(&payload.data) + frames_bytes(&payload.data2);
+            window.r1_flight_data.push(payload.data);
+            window.r2_flight_data.push(payload.data2);
+        }
+        Ok(())
+    }
+
+    /// Collect the data fragments for temporal windows. The fragments of a
+    /// payload compressed by another codec than the window's, e.g. a small
+    /// payload that skipped the compression (see
+    /// [`StageEncoding`](crate::encoding::StageEncoding)), are re-encoded by
+    /// the codec of the window.
     ///
     /// # Arguments
     /// * `payload` - The payload of the data frame.
@@ -188,36 +402,94 @@ impl Arena {
     /// # Returns
     /// * Return true if the window data collection is complete, otherwise
     ///   return false. Uuid is also returned no matter whether the window data
-    ///   collection is complete.
-    pub fn collect(&mut self, payload: Payload) -> HashAggregateStatus {
+    ///   collection is complete. An error is returned if the fragments can't be
+    ///   re-encoded, and the payload is not collected.
+    pub fn collect(&mut self, payload: Payload) -> Result<HashAggregateStatus> {
         let uuid = payload.uuid.clone();
         let window_id = payload.get_window_id();
-        match &mut (*self).get_mut(&window_id) {
+        if let Some(seq_len) = flush_len(&payload.metadata) {
+            return Ok(self.flush(&window_id, seq_len));
+        }
+        Ok(match &mut (*self).get_mut(&window_id) {
             Some(window) => {
-                assert!(uuid.seq_len == window.size);
+                // The payloads of a flushed window still carry the original size.
+                if !window.flushed && uuid.seq_len != window.size {
+                    return Err(FlockError::Execution(format!(
+                        "Payload {} of window {:?} is one of {} payloads, but the window has {}.",
+                        uuid.seq_num, window_id, uuid.seq_len, window.size
+                    )));
+                }
                 if !window.bitmap.is_set(uuid.seq_num) {
-                    window.r1_flight_data.push(payload.data);
-                    window.r2_flight_data.push(payload.data2);
+                    if window.r1_schema.is_empty() && window.r2_schema.is_empty() {
+                        // The window was opened by the flush.
+                        window.r1_schema = payload.schema;
+                        window.r2_schema = payload.schema2;
+                        window.encoding = payload.encoding.clone();
+                    }
+                    let data = reencode(payload.data, &payload.encoding, &window.encoding)?;
+                    let data2 = reencode(payload.data2, &payload.encoding, &window.encoding)?;
+                    merge_stats(
+                        &mut window.r1_stats,
+                        &window.r1_flight_data,
+                        payload.stats,
+                        &data,
+                    );
+                    merge_stats(
+                        &mut window.r2_stats,
+                        &window.r2_flight_data,
+                        payload.stats2,
+                        &data2,
+                    );
+                    window.bytes += frames_bytes(&data) + frames_bytes(&data2);
+                    window.r1_flight_data.push(data);
+                    window.r2_flight_data.push(data2);
                     assert!(window.r1_flight_data.len() == window.r2_flight_data.len());
                     window.bitmap.set(uuid.seq_num);
-                    if window.size == window.r1_flight_data.len() {
+                    if !payload.payload_id.is_empty() {
+                        window.payload_ids.insert(uuid.seq_num, payload.payload_id);
+                    }
+                    if window.is_complete() {
                         HashAggregateStatus::Ready
                     } else {
                         HashAggregateStatus::NotReady
                     }
                 } else {
+                    if let Some(received) = window.payload_ids.get(&uuid.seq_num) {
+                        if !payload.payload_id.is_empty() && *received != payload.payload_id {
+                            // The data of the distinct payload is dropped, which
+                            // is a bug of the sender rather than a redelivery.
+                            error!(
+                                "Payload {} reused the sequence number {} of payload {} in \
+                                 window {:?}, and its data is dropped",
+                                payload.payload_id, uuid.seq_num, received, window_id
+                            );
+                            metrics::scope().incr(Metric::ConflictingPayloads);
+                        }
+                    }
                     HashAggregateStatus::Processed
                 }
             }
             None => {
+                let held = frames_bytes(&payload.data) + frames_bytes(&payload.data2);
+                let mut payload_ids = HashMap::new();
+                if !payload.payload_id.is_empty() {
+                    payload_ids.insert(uuid.seq_num, payload.payload_id);
+                }
                 let mut window = WindowSession {
-                    size:           uuid.seq_len,
+                    size: uuid.seq_len,
                     r1_flight_data: vec![payload.data],
                     r2_flight_data: vec![payload.data2],
-                    r1_schema:      payload.schema,
-                    r2_schema:      payload.schema2,
-                    bitmap:         Bitmap::new(uuid.seq_len + 1), // Starts from 1.
-                    encoding:       payload.encoding,
+                    r1_schema: payload.schema,
+                    r2_schema: payload.schema2,
+                    bitmap: Bitmap::new(uuid.seq_len + 1), // Starts from 1.
+                    encoding: payload.encoding,
+                    r1_stats: payload.stats,
+                    r2_stats: payload.stats2,
+                    bytes: held,
+                    spilled: vec![],
+                    created: Instant::now(),
+                    flushed: false,
+                    payload_ids,
                 };
                 // SEQ_NUM is used to indicate the data existence in the window via bitmap.
                 window.bitmap.set(uuid.seq_num);
@@ -228,10 +500,163 @@ impl Arena {
                     HashAggregateStatus::NotReady
                 }
             }
+        })
+    }
+
+    /// Adjusts the size of the temporal window to the number of payloads that
+    /// the data source actually produced for it, and re-evaluates whether the
+    /// window is complete.
+    ///
+    /// # Arguments
+    /// * `window_id` - The window flushed by the data source.
+    /// * `seq_len` - The number of payloads produced for the window.
+    ///
+    /// # Returns
+    /// `Ready` if all produced payloads have arrived, and `Processed` if the
+    /// window has no payloads at all. A flush that reports fewer payloads than
+    /// the window already holds, e.g. a stale flush reordered after the flush
+    /// of a retried data source, doesn't shrink the window.
+    pub fn flush(&mut self, window_id: &WindowId, seq_len: usize) -> HashAggregateStatus {
+        match self.get_mut(window_id) {
+            Some(window) => {
+                if window.received() > seq_len {
+                    warn!(
+                        "The flush of window {:?} reports {} payloads, but {} have arrived.",
+                        window_id,
+                        seq_len,
+                        window.received()
+                    );
+                }
+                window.size = seq_len.max(window.received());
+                window.flushed = true;
+                if window.is_complete() {
+                    HashAggregateStatus::Ready
+                } else {
+                    HashAggregateStatus::NotReady
+                }
+            }
+            None if seq_len == 0 => HashAggregateStatus::Processed,
+            None => {
+                // The flush overtook the payloads of the window.
+                let window = WindowSession {
+                    size:           seq_len,
+                    r1_flight_data: vec![],
+                    r1_schema:      vec![],
+                    r2_flight_data: vec![],
+                    r2_schema:      vec![],
+                    bitmap:         Bitmap::new(seq_len + 1),
+                    encoding:       Encoding::default(),
+                    r1_stats:       None,
+                    r2_stats:       None,
+                    bytes:          0,
+                    spilled:        vec![],
+                    created:        Instant::now(),
+                    flushed:        true,
+                    payload_ids:    HashMap::new(),
+                };
+                self.insert(window_id.clone(), window);
+                HashAggregateStatus::NotReady
+            }
         }
     }
 }
 
+/// Returns the flush payload of the window, which tells the function of the
+/// window the number of payloads that the data source actually produced for
+/// it (see [`Arena::flush`]).
+///
+/// # Arguments
+/// * `qid` - The query id of the window.
+/// * `shuffle_id` - The shuffle id of the window, if shuffled.
+/// * `seq_len` - The number of payloads produced for the window.
+/// * `sync` - Whether the function is invoked synchronously.
+pub fn flush_payload(qid: &str, shuffle_id: Option<usize>, seq_len: usize, sync: bool) -> Payload {
+    let uuid = Uuid {
+        qid:     qid.to_owned(),
+        seq_num: 0,
+        seq_len: 0,
+    };
+    let mut payload = to_payload(&[], &[], uuid, sync)
+        .expect("An empty payload is neither compressed nor sealed.");
+    if let Some(shuffle_id) = shuffle_id {
+        payload.set_shuffle_id(shuffle_id);
+    }
+    let mut metadata = QueryMetadata::default();
+    metadata.insert(FLUSH_METADATA_KEY.to_string(), seq_len.to_string());
+    payload.metadata = Some(metadata);
+    payload
+}
+
+/// Returns the number of payloads produced for the window if the payload is a
+/// flush (see [`flush_payload`]).
+pub fn flush_len(metadata: &Option<QueryMetadata>) -> Option<usize> {
+    metadata
+        .as_ref()
+        .and_then(|m| m.get(FLUSH_METADATA_KEY))
+        .and_then(|n| n.parse().ok())
+}
+
+/// The memory held by an open window in the arena.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WindowStats {
+    /// The query id of the window.
+    pub query_id:   String,
+    /// The shuffle id of the window.
+    pub shuffle_id: usize,
+    /// The size in bytes of the data frames held in memory.
+    pub bytes:      usize,
+    /// The number of data fragments received.
+    pub received:   usize,
+    /// The number of data fragments spilled to the state backend.
+    pub spilled:    usize,
+    /// The number of data fragments of the complete window.
+    pub size:       usize,
+    /// The time in milliseconds since the first data fragment was received.
+    pub age_ms:     u64,
+}
+
+/// The memory held by the arena, returned by [`Arena::stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ArenaStats {
+    /// The size in bytes of the data frames held by all windows.
+    pub total_bytes: usize,
+    /// The open windows, the largest first.
+    pub windows:     Vec<WindowStats>,
+}
+
+/// Returns the size in bytes of the arena above which the windows are
+/// spilled, or `None` if the spilling is disabled.
+///
+/// # Arguments
+/// * `memory_mb` - The memory size of the function in MB.
+/// * `fraction` - The fraction of the function memory for the arena.
+pub fn spill_threshold(memory_mb: usize, fraction: f64) -> Option<usize> {
+    (fraction > 0.0).then(|| (memory_mb as f64 * 1024.0 * 1024.0 * fraction) as usize)
+}
+
+/// Returns the size in bytes of the encoded data frames.
+fn frames_bytes(frames: &[DataFrame]) -> usize {
+    frames.iter().map(|f| f.header.len() + f.body.len()).sum()
+}
+
+/// Merges the statistics of a payload into the statistics of the window. The
+/// payloads without data don't carry statistics, and are skipped.
+fn merge_stats(
+    window_stats: &mut Option<PayloadStats>,
+    window_data: &[Vec<DataFrame>],
+    stats: Option<PayloadStats>,
+    data: &[DataFrame],
+) {
+    if data.is_empty() {
+        return;
+    }
+    match (window_stats.as_mut(), stats) {
+        (Some(a), Some(b)) => a.merge(&b),
+        (None, Some(b)) if window_data.iter().all(|d| d.is_empty()) => *window_stats = Some(b),
+        _ => *window_stats = None,
+    }
+}
+
 impl Deref for Arena {
     type Target = HashMap<WindowId, WindowSession>;
 
@@ -251,9 +676,55 @@ mod tests {
     use super::*;
     use crate::error::Result;
     use crate::runtime::payload::UuidBuilder;
-    use crate::transmute::to_payload;
+    use crate::transmute::{to_payload, to_payload_with_encoding};
+    use async_trait::async_trait;
     use datafusion::arrow::csv;
     use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use serde::Deserialize;
+    use std::any::Any;
+    use std::collections::HashMap as StdHashMap;
+    use std::sync::Mutex;
+
+    /// A state backend that keeps the spilled fragments in memory.
+    #[derive(Default, Debug, Serialize, Deserialize)]
+    struct SpillStateBackend {
+        #[serde(skip)]
+        objects: Mutex<StdHashMap<String, Vec<u8>>>,
+    }
+
+    #[async_trait]
+    #[typetag::serde(name = "spill_state_backend")]
+    impl StateBackend for SpillStateBackend {
+        fn name(&self) -> String {
+            "SpillStateBackend".to_string()
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_mut_any(&mut self) -> &mut dyn Any {
+            self
+        }
+
+        async fn write(&self, bucket: String, key: String, bytes: Vec<u8>) -> Result<()> {
+            self.objects
+                .lock()
+                .unwrap()
+                .insert(format!("{}/{}", bucket, key), bytes);
+            Ok(())
+        }
+
+        async fn read(&self, bucket: String, keys: Vec<String>) -> Result<Vec<Payload>> {
+            let objects = self.objects.lock().unwrap();
+            keys.iter()
+                .map(|key| {
+                    let bytes = &objects[&format!("{}/{}", bucket, key)];
+                    Ok(serde_json::from_slice(bytes)?)
+                })
+                .collect()
+        }
+    }
 
     fn init_batches() -> Vec<RecordBatch> {
         let schema = Schema::new(vec![
@@ -293,8 +764,8 @@ mod tests {
 
         let mut arena = Arena::new();
         batches.into_iter().enumerate().for_each(|(i, batch)| {
-            let payload = to_payload(&[batch], &[], uuids.get(i + 1), false);
-            let status = arena.collect(payload);
+            let payload = to_payload(&[batch], &[], uuids.get(i + 1), false)?;
+            let status = arena.collect(payload)?;
             if i < 7 {
                 assert!(status == HashAggregateStatus::NotReady);
             } else {
@@ -317,4 +788,331 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn arena_accounting() -> Result<()> {
+        let batches = init_batches();
+        let uuids = UuidBuilder::new_with_ts("q5-accounting", 1024, 4);
+        let mut arena = Arena::new();
+        let mut expected = 0;
+        for (i, batch) in batches.iter().take(3).enumerate() {
+            let payload = to_payload(&[batch.clone()], &[], uuids.get(i + 1), false)?;
+            expected += frames_bytes(&payload.data) + frames_bytes(&payload.data2);
+            arena.collect(payload)?;
+        }
+        assert!(expected > 0);
+
+        // The duplicated fragments are not counted twice.
+        arena.collect(to_payload(&[batches[0].clone()], &[], uuids.get(1), false)?)?;
+        assert_eq!(arena.total_bytes(), expected);
+
+        let small = UuidBuilder::new_with_ts("q5-small", 1024, 2);
+        let payload = to_payload(&[batches[3].clone()], &[], small.get(1), false)?;
+        let small_bytes = frames_bytes(&payload.data);
+        arena.collect(payload)?;
+
+        let stats = arena.stats();
+        assert_eq!(stats.total_bytes, expected + small_bytes);
+        assert_eq!(stats.windows.len(), 2);
+        assert_eq!(stats.windows[0].query_id, uuids.get(1).qid);
+        assert_eq!(stats.windows[0].bytes, expected);
+        assert_eq!((stats.windows[0].received, stats.windows[0].size), (3, 4));
+        assert_eq!(stats.windows[1].bytes, small_bytes);
+
+        // The largest incomplete window is spilled first.
+        assert_eq!(arena.spill_candidate(expected + small_bytes), None);
+        assert_eq!(arena.spill_candidate(expected), Some((uuids.get(1).qid, 0)));
+        assert_eq!(spill_threshold(1024, 0.5), Some(512 * 1024 * 1024));
+        assert_eq!(spill_threshold(1024, 0.0), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn arena_spill_and_restore() -> Result<()> {
+        let batches = init_batches();
+        let num_rows = batches.iter().map(|b| b.num_rows()).sum::<usize>();
+        let uuids = UuidBuilder::new_with_ts("q5-spill", 1024, batches.len());
+        let window_id = (uuids.get(1).qid, 0);
+        let backend = SpillStateBackend::default();
+
+        let mut arena = Arena::new();
+        for (i, batch) in batches.iter().enumerate().take(5) {
+            arena.collect(to_payload(&[batch.clone()], &[], uuids.get(i + 1), false)?)?;
+        }
+        let bytes = arena.total_bytes();
+        assert_eq!(arena.spill(&window_id, &backend, "state").await?, bytes);
+        assert_eq!(arena.total_bytes(), 0);
+        assert_eq!(arena.spill_candidate(0), None);
+        let stats = arena.stats();
+        assert_eq!(
+            (stats.windows[0].received, stats.windows[0].spilled),
+            (5, 5)
+        );
+
+        // The window is completed by the fragments received after the spill.
+        for (i, batch) in batches.iter().enumerate().skip(5) {
+            let status =
+                arena.collect(to_payload(&[batch.clone()], &[], uuids.get(i + 1), false)?)?;
+            assert!((status == HashAggregateStatus::Ready) == (i == batches.len() - 1));
+        }
+        assert!(arena.is_complete(&window_id));
+
+        arena.restore(&window_id, &backend, "state").await?;
+        assert_eq!(arena.get(&window_id).unwrap().spilled.len(), 0);
+        assert_eq!(arena.total_bytes(), arena.get(&window_id).unwrap().bytes);
+        let input = arena.take(&window_id).await?;
+        assert_eq!(input[0].len(), batches.len());
+        assert_eq!(
+            input[0]
+                .iter()
+                .flatten()
+                .map(|b| b.num_rows())
+                .sum::<usize>(),
+            num_rows
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn arena_merges_stats() -> Result<()> {
+        let batches = init_batches();
+        let num_rows = batches.iter().map(|b| b.num_rows()).sum::<usize>();
+        let keys = vec!["city".to_string()];
+        let uuids = UuidBuilder::new_with_ts("q5-stats", 1024, batches.len());
+
+        let mut arena = Arena::new();
+        batches.iter().enumerate().for_each(|(i, batch)| {
+            let mut payload = to_payload(&[batch.clone()], &[], uuids.get(i + 1), false)?;
+            payload.stats = PayloadStats::new(&[batch.clone()], &keys, usize::MAX);
+            arena.collect(payload)?;
+        });
+
+        let window_id = (uuids.get(1).qid, 0);
+        let stats = arena.get(&window_id).unwrap().r1_stats.clone().unwrap();
+        assert_eq!(stats.num_rows, num_rows);
+        assert!(stats.distinct_count("city").is_some());
+        assert!(arena.get(&window_id).unwrap().r2_stats.is_none());
+
+        // The statistics are dropped if a payload with data doesn't carry them.
+        let uuids = UuidBuilder::new_with_ts("q5-partial", 1024, 2);
+        let mut payload = to_payload(&[batches[0].clone()], &[], uuids.get(1), false)?;
+        payload.stats = PayloadStats::new(&[batches[0].clone()], &keys, usize::MAX);
+        arena.collect(payload)?;
+        arena.collect(to_payload(&[batches[1].clone()], &[], uuids.get(2), false)?)?;
+        assert!(arena
+            .get(&(uuids.get(1).qid, 0))
+            .unwrap()
+            .r1_stats
+            .is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn arena_flush_partial_window() -> Result<()> {
+        let batches = init_batches();
+
+        // The data source stopped after 3 of the 8 payloads of the window.
+        let uuids = UuidBuilder::new_with_ts("q5-flush", 1024, 8);
+        let window_id = (uuids.get(1).qid, 0);
+        let mut arena = Arena::new();
+        for (i, batch) in batches.iter().take(2).enumerate() {
+            arena.collect(to_payload(&[batch.clone()], &[], uuids.get(i + 1), false)?)?;
+        }
+        let flush = flush_payload(&window_id.0, None, 3, false);
+        assert!(flush.validate(true, None).is_ok());
+        assert!(arena.collect(flush)? == HashAggregateStatus::NotReady);
+        assert!(arena.is_flushed(&window_id));
+        let status = arena.collect(to_payload(&[batches[2].clone()], &[], uuids.get(3), false)?)?;
+        assert!(status == HashAggregateStatus::Ready);
+        assert_eq!(arena.get(&window_id).unwrap().r1_flight_data.len(), 3);
+
+        // The flush may overtake the payloads of the window.
+        let uuids = UuidBuilder::new_with_ts("q5-overtaken", 1024, 8);
+        let window_id = (uuids.get(1).qid, 0);
+        let flush = flush_payload(&window_id.0, None, 1, false);
+        assert!(arena.collect(flush)? == HashAggregateStatus::NotReady);
+        let status = arena.collect(to_payload(&[batches[0].clone()], &[], uuids.get(1), false)?)?;
+        assert!(status == HashAggregateStatus::Ready);
+        assert!(arena.get(&window_id).unwrap().schema().is_ok());
+
+        // The functions that received no payloads of the window ignore it.
+        let flush = flush_payload("q5-empty", None, 0, false);
+        assert!(arena.collect(flush)? == HashAggregateStatus::Processed);
+        assert!(!arena.contains_key(&("q5-empty".to_owned(), 0)));
+        Ok(())
+    }
+
+    #[test]
+    fn arena_reordered_flushes() -> Result<()> {
+        let batches = init_batches();
+
+        // The function received the payloads 5 and 1000 of a shuffled window,
+        // and the flush overtook both of them.
+        let uuids = UuidBuilder::new_with_ts("q5-shuffled", 1024, 1024);
+        let window_id = (uuids.get(1).qid, 0);
+        let mut arena = Arena::new();
+        let flush = flush_payload(&window_id.0, None, 2, false);
+        assert!(arena.collect(flush.clone())? == HashAggregateStatus::NotReady);
+        // The flush is duplicated.
+        assert!(arena.collect(flush)? == HashAggregateStatus::NotReady);
+        let status = arena.collect(to_payload(
+            &[batches[0].clone()],
+            &[],
+            uuids.get(1000),
+            false,
+        )?)?;
+        assert!(status == HashAggregateStatus::NotReady);
+        let status = arena.collect(to_payload(&[batches[1].clone()], &[], uuids.get(5), false)?)?;
+        assert!(status == HashAggregateStatus::Ready);
+        assert_eq!(arena.get_bitmap(&window_id).unwrap().ones(), vec![5, 1000]);
+
+        // A stale flush doesn't shrink the window below its payloads.
+        let uuids = UuidBuilder::new_with_ts("q5-stale", 1024, 8);
+        let window_id = (uuids.get(1).qid, 0);
+        for (i, batch) in batches.iter().take(3).enumerate() {
+            arena.collect(to_payload(&[batch.clone()], &[], uuids.get(i + 1), false)?)?;
+        }
+        let flush = flush_payload(&window_id.0, None, 4, false);
+        assert!(arena.collect(flush)? == HashAggregateStatus::NotReady);
+        let stale = flush_payload(&window_id.0, None, 2, false);
+        assert!(arena.collect(stale)? == HashAggregateStatus::Ready);
+        assert_eq!(arena.get(&window_id).unwrap().size, 3);
+
+        // The payloads of an unflushed window must agree on its size.
+        let uuids = UuidBuilder::new_with_ts("q5-sizes", 1024, 8);
+        arena.collect(to_payload(&[batches[0].clone()], &[], uuids.get(1), false)?)?;
+        let mut uuid = uuids.get(2);
+        uuid.seq_len = 4;
+        assert!(arena
+            .collect(to_payload(&[batches[1].clone()], &[], uuid, false)?)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn arena_window_from_two_emitters() -> Result<()> {
+        let batches = init_batches();
+        let window_id = "q5-1650000000-7-03";
+
+        // The payloads of the window are emitted by two invocations of the data
+        // source, e.g. straddling a second boundary, whose qids don't depend on
+        // the wall clock.
+        let first = UuidBuilder::for_window("q5-00", window_id, 8);
+        let second = UuidBuilder::for_window("q5-00", window_id, 8);
+        assert_eq!(first.qid, second.qid);
+
+        let mut arena = Arena::new();
+        for (i, batch) in batches.iter().enumerate() {
+            let uuids = if i < 5 { &first } else { &second };
+            let status =
+                arena.collect(to_payload(&[batch.clone()], &[], uuids.get(i + 1), false)?)?;
+            if i < 7 {
+                assert!(status == HashAggregateStatus::NotReady);
+            } else {
+                assert!(status == HashAggregateStatus::Ready);
+            }
+        }
+        assert_eq!(arena.len(), 1);
+        assert_eq!(arena.get(&(first.qid, 0)).unwrap().r1_flight_data.len(), 8);
+        Ok(())
+    }
+
+    #[test]
+    fn arena_conflicting_payloads() -> Result<()> {
+        let batches = init_batches();
+        let uuids = UuidBuilder::new_with_ts("q5-conflict", 1024, 3);
+
+        let mut arena = Arena::new();
+        let first = to_payload(&[batches[0].clone()], &[], uuids.get(1), false)?;
+        let window_id = first.get_window_id();
+        let payload_id = first.payload_id.clone();
+        let redelivered = first.clone();
+        assert!(arena.collect(first)? == HashAggregateStatus::NotReady);
+
+        // The redelivery of the same payload is a duplicate.
+        assert!(arena.collect(redelivered)? == HashAggregateStatus::Processed);
+        // So is a distinct payload that reused the sequence number, whose data
+        // is dropped, but the conflict is recorded.
+        let distinct = to_payload(&[batches[1].clone()], &[], uuids.get(1), false)?;
+        assert_ne!(distinct.payload_id, payload_id);
+        assert!(arena.collect(distinct)? == HashAggregateStatus::Processed);
+
+        let window = arena.get(&window_id).unwrap();
+        assert_eq!(window.r1_flight_data.len(), 1);
+        assert_eq!(window.payload_ids.get(&1), Some(&payload_id));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn arena_mixed_encodings() -> Result<()> {
+        let batches = init_batches();
+        let num_rows = batches.iter().map(|b| b.num_rows()).sum::<usize>();
+        let uuids = UuidBuilder::new_with_ts("q5-encoding", 1024, batches.len());
+        let window_id = (uuids.get(1).qid, 0);
+
+        // The small payloads of an adaptive stage skip the compression.
+        let mut arena = Arena::new();
+        for (i, batch) in batches.iter().enumerate() {
+            let encoding = if i % 2 == 0 {
+                Encoding::Snappy
+            } else {
+                Encoding::None
+            };
+            arena.collect(to_payload_with_encoding(
+                &[batch.clone()],
+                &[],
+                uuids.get(i + 1),
+                false,
+                &[],
+                encoding,
+            )?)?;
+        }
+        assert_eq!(arena.get(&window_id).unwrap().encoding, Encoding::Snappy);
+
+        let output = arena.take(&window_id).await?;
+        assert_eq!(
+            output[0]
+                .iter()
+                .flatten()
+                .map(|b| b.num_rows())
+                .sum::<usize>(),
+            num_rows
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn arena_corrupt_payload() -> Result<()> {
+        let batches = init_batches();
+        let uuids = UuidBuilder::new_with_ts("q5-corrupt", 1024, 2);
+        let window_id = (uuids.get(1).qid, 0);
+
+        let mut arena = Arena::new();
+        let payload = to_payload_with_encoding(
+            &[batches[0].clone()],
+            &[],
+            uuids.get(1),
+            false,
+            &[],
+            Encoding::None,
+        )?;
+        assert!(arena.collect(payload)? == HashAggregateStatus::NotReady);
+
+        // The payload can't be re-encoded by the codec of the window, so it's
+        // rejected instead of aborting the function, and it can be redelivered.
+        let mut payload = to_payload_with_encoding(
+            &[batches[1].clone()],
+            &[],
+            uuids.get(2),
+            false,
+            &[],
+            Encoding::Snappy,
+        )?;
+        payload.data[0].body = vec![0xff; 16];
+        assert!(arena.collect(payload).is_err());
+        assert!(!arena.get_bitmap(&window_id).unwrap().is_set(2));
+        assert_eq!(arena.get(&window_id).unwrap().r1_flight_data.len(), 1);
+        Ok(())
+    }
 }
@@ -0,0 +1,287 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The set of windows that have been processed by the function, which is used
+//! to suppress the duplicate payloads of a window that has been triggered.
+//!
+//! The set is bounded, and the least recently used windows are evicted first.
+//! Since the set is per function instance, a fresh container (or an evicted
+//! window) knows nothing about the windows processed before. Therefore, when a
+//! window completes, a small done marker is written to the state backend, and
+//! the marker is checked before deciding that a window is unprocessed.
+
+use super::WindowId;
+use crate::error::Result;
+use crate::state::StateBackend;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// Returns the key of the done marker of a window in the state backend.
+///
+/// The key format is `<query id>/<function name>/<shuffle id>/done`.
+pub fn done_marker_key(qid: &str, function_name: &str, shuffle_id: usize) -> String {
+    format!("{}/{}/{:02}/done", qid, function_name, shuffle_id)
+}
+
+/// The location of the done marker of a window in the state backend.
+pub struct DoneMarker<'a> {
+    backend: &'a dyn StateBackend,
+    bucket:  String,
+    key:     String,
+}
+
+impl<'a> DoneMarker<'a> {
+    /// Creates the done marker of the window processed by the given function.
+    ///
+    /// # Arguments
+    /// * `backend` - The state backend to store the marker.
+    /// * `bucket` - The bucket of the query states.
+    /// * `function_name` - The name of the current function.
+    /// * `window_id` - The window identifier.
+    pub fn new(
+        backend: &'a dyn StateBackend,
+        bucket: &str,
+        function_name: &str,
+        window_id: &WindowId,
+    ) -> Self {
+        Self {
+            backend,
+            bucket: bucket.to_owned(),
+            key: done_marker_key(&window_id.0, function_name, window_id.1),
+        }
+    }
+}
+
+/// A bounded LRU set of the processed windows.
+#[derive(Debug)]
+pub struct ProcessedWindows {
+    capacity: usize,
+    tick:     u64,
+    windows:  HashMap<WindowId, u64>,
+    lru:      BTreeMap<u64, WindowId>,
+}
+
+impl ProcessedWindows {
+    /// Creates an empty set which holds at most `capacity` windows.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            tick:     0,
+            windows:  HashMap::new(),
+            lru:      BTreeMap::new(),
+        }
+    }
+
+    /// Returns the number of windows in the set.
+    pub fn len(&self) -> usize {
+        self.windows.len()
+    }
+
+    /// Returns true if the set contains no windows.
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    /// Returns true if the window is in the set, and marks it as the most
+    /// recently used one.
+    pub fn contains(&mut self, window_id: &WindowId) -> bool {
+        match self.windows.get(window_id).copied() {
+            Some(tick) => {
+                self.lru.remove(&tick);
+                self.touch(window_id.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Adds the window to the set. If the set is full, the least recently used
+    /// window is evicted.
+    pub fn insert(&mut self, window_id: WindowId) {
+        if let Some(tick) = self.windows.get(&window_id).copied() {
+            self.lru.remove(&tick);
+        } else if self.windows.len() >= self.capacity {
+            if let Some(tick) = self.lru.keys().next().copied() {
+                let evicted = self.lru.remove(&tick).unwrap();
+                self.windows.remove(&evicted);
+            }
+        }
+        self.touch(window_id);
+    }
+
+    fn touch(&mut self, window_id: WindowId) {
+        self.tick += 1;
+        self.lru.insert(self.tick, window_id.clone());
+        self.windows.insert(window_id, self.tick);
+    }
+
+    /// Returns true if the window has been processed, either by the current
+    /// function instance or by a previous one that left a done marker.
+    ///
+    /// # Arguments
+    /// * `windows` - The processed windows of the current function instance.
+    /// * `window_id` - The window identifier.
+    /// * `marker` - The done marker of the window. `None` skips the marker
+    ///   check, e.g. for non-aggregate stages where reprocessing is harmless.
+    pub async fn is_processed(
+        windows: &Mutex<Self>,
+        window_id: &WindowId,
+        marker: Option<&DoneMarker<'_>>,
+    ) -> Result<bool> {
+        if windows.lock().unwrap().contains(window_id) {
+            return Ok(true);
+        }
+        if let Some(marker) = marker {
+            if marker
+                .backend
+                .marker_exists(marker.bucket.clone(), marker.key.clone())
+                .await?
+            {
+                windows.lock().unwrap().insert(window_id.clone());
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Records that the window has been processed, and writes its done marker
+    /// to the state backend if any.
+    pub async fn mark_processed(
+        windows: &Mutex<Self>,
+        window_id: WindowId,
+        marker: Option<&DoneMarker<'_>>,
+    ) -> Result<()> {
+        windows.lock().unwrap().insert(window_id);
+        if let Some(marker) = marker {
+            marker
+                .backend
+                .write_marker(marker.bucket.clone(), marker.key.clone())
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::payload::Payload;
+    use async_trait::async_trait;
+    use serde::{Deserialize, Serialize};
+    use std::any::Any;
+    use std::collections::HashSet;
+
+    /// A state backend that keeps the markers in memory, which plays the role
+    /// of the durable storage shared by all function instances.
+    #[derive(Default, Debug, Serialize, Deserialize)]
+    struct MarkerStateBackend {
+        #[serde(skip)]
+        markers: Mutex<HashSet<String>>,
+    }
+
+    #[async_trait]
+    #[typetag::serde(name = "marker_state_backend")]
+    impl StateBackend for MarkerStateBackend {
+        fn name(&self) -> String {
+            "MarkerStateBackend".to_string()
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_mut_any(&mut self) -> &mut dyn Any {
+            self
+        }
+
+        async fn write(&self, _: String, _: String, _: Vec<u8>) -> Result<()> {
+            unreachable!()
+        }
+
+        async fn read(&self, _: String, _: Vec<String>) -> Result<Vec<Payload>> {
+            unreachable!()
+        }
+
+        async fn write_marker(&self, bucket: String, key: String) -> Result<()> {
+            self.markers
+                .lock()
+                .unwrap()
+                .insert(format!("{}/{}", bucket, key));
+            Ok(())
+        }
+
+        async fn marker_exists(&self, bucket: String, key: String) -> Result<bool> {
+            Ok(self
+                .markers
+                .lock()
+                .unwrap()
+                .contains(&format!("{}/{}", bucket, key)))
+        }
+    }
+
+    fn window(shuffle_id: usize) -> WindowId {
+        ("q4-1642991536-2187351285".to_string(), shuffle_id)
+    }
+
+    #[test]
+    fn evict_least_recently_used_windows() {
+        let mut windows = ProcessedWindows::new(2);
+        windows.insert(window(0));
+        windows.insert(window(1));
+        assert!(windows.contains(&window(0)));
+
+        // window 1 is the least recently used one.
+        windows.insert(window(2));
+        assert_eq!(windows.len(), 2);
+        assert!(windows.contains(&window(0)));
+        assert!(!windows.contains(&window(1)));
+        assert!(windows.contains(&window(2)));
+
+        // re-inserting an existing window doesn't evict anything.
+        windows.insert(window(2));
+        assert_eq!(windows.len(), 2);
+        assert!(windows.contains(&window(0)));
+    }
+
+    #[tokio::test]
+    async fn suppress_duplicates_across_restart() -> Result<()> {
+        let backend = MarkerStateBackend::default();
+        let bucket = "q4-1642991536-2187351285";
+        let function_name = "q4-01-03";
+        let window_id = window(7);
+        let marker = DoneMarker::new(&backend, bucket, function_name, &window_id);
+        assert_eq!(marker.key, "q4-1642991536-2187351285/q4-01-03/07/done");
+
+        // The window is processed by the first container.
+        let windows = Mutex::new(ProcessedWindows::new(16));
+        assert!(!ProcessedWindows::is_processed(&windows, &window_id, Some(&marker)).await?);
+        ProcessedWindows::mark_processed(&windows, window_id.clone(), Some(&marker)).await?;
+        assert!(ProcessedWindows::is_processed(&windows, &window_id, Some(&marker)).await?);
+
+        // A fresh container receives a duplicate payload of the same window.
+        let windows = Mutex::new(ProcessedWindows::new(16));
+        assert!(ProcessedWindows::is_processed(&windows, &window_id, Some(&marker)).await?);
+        assert_eq!(windows.lock().unwrap().len(), 1);
+
+        // Without the marker check, the window is reprocessed.
+        let windows = Mutex::new(ProcessedWindows::new(16));
+        assert!(!ProcessedWindows::is_processed(&windows, &window_id, None).await?);
+
+        // The other windows are not affected by the marker.
+        let other = window(8);
+        let marker = DoneMarker::new(&backend, bucket, function_name, &other);
+        assert!(!ProcessedWindows::is_processed(&windows, &other, Some(&marker)).await?);
+
+        Ok(())
+    }
+}
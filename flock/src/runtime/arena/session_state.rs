@@ -0,0 +1,507 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The open session windows of the aggregation stage (NEXMark Q11).
+//!
+//! The events of a session can be routed through different upstream functions,
+//! and arrive out of order. Therefore, the sessions are not closed by the local
+//! view of the arriving payloads. Instead, the rows are grouped by the session
+//! key, and the overlapping or adjacent sessions of a key are merged as the new
+//! payloads arrive. A session is only emitted once the stage-wide watermark,
+//! the minimum of the watermarks of all upstreams, passes the event time of its
+//! last event plus the session gap. Once the data sources have sent all their
+//! events, they send [`END_OF_INPUT_WATERMARK`], which closes all sessions that
+//! are still open.
+
+use crate::datasink::results::{decode_window, encode_window};
+use crate::error::{FlockError, Result};
+use datafusion::arrow::array::{Array, Int32Array, TimestampMillisecondArray, UInt32Array};
+use datafusion::arrow::compute::take;
+use datafusion::arrow::record_batch::RecordBatch;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The metadata key of the group-by column of the session windows.
+pub const SESSION_KEY_METADATA_KEY: &str = "session_key";
+
+/// The metadata key of the event time column of the session windows.
+pub const SESSION_TIME_METADATA_KEY: &str = "session_time";
+
+/// The metadata key of the session gap in milliseconds.
+pub const SESSION_GAP_METADATA_KEY: &str = "session_gap";
+
+/// The metadata key of the upstream function that sends the payload.
+pub const UPSTREAM_METADATA_KEY: &str = "upstream";
+
+/// The metadata key of the number of upstream functions of the stage.
+pub const UPSTREAMS_METADATA_KEY: &str = "upstreams";
+
+/// The watermark that a data source sends after its last events. Once all
+/// upstreams have sent it, the open sessions are flushed.
+pub const END_OF_INPUT_WATERMARK: i64 = i64::MAX;
+
+/// The session window settings carried in the payload metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionMetadata {
+    /// The group-by column.
+    pub key:       String,
+    /// The event time column.
+    pub time:      String,
+    /// The session gap in milliseconds.
+    pub gap:       i64,
+    /// The upstream function that sends the payload.
+    pub upstream:  String,
+    /// The number of upstream functions of the stage.
+    pub upstreams: usize,
+}
+
+impl SessionMetadata {
+    /// Parses the session window settings from the payload metadata. Returns
+    /// `None` if the payload doesn't belong to a session window.
+    pub fn from_metadata(metadata: &HashMap<String, String>) -> Option<Self> {
+        Some(Self {
+            key:       metadata.get(SESSION_KEY_METADATA_KEY)?.to_owned(),
+            time:      metadata.get(SESSION_TIME_METADATA_KEY)?.to_owned(),
+            gap:       metadata.get(SESSION_GAP_METADATA_KEY)?.parse().ok()?,
+            upstream:  metadata.get(UPSTREAM_METADATA_KEY)?.to_owned(),
+            upstreams: metadata.get(UPSTREAMS_METADATA_KEY)?.parse().ok()?,
+        })
+    }
+
+    /// Writes the session window settings to the payload metadata.
+    pub fn to_metadata(&self, metadata: &mut HashMap<String, String>) {
+        metadata.insert(SESSION_KEY_METADATA_KEY.to_string(), self.key.clone());
+        metadata.insert(SESSION_TIME_METADATA_KEY.to_string(), self.time.clone());
+        metadata.insert(SESSION_GAP_METADATA_KEY.to_string(), self.gap.to_string());
+        metadata.insert(UPSTREAM_METADATA_KEY.to_string(), self.upstream.clone());
+        metadata.insert(
+            UPSTREAMS_METADATA_KEY.to_string(),
+            self.upstreams.to_string(),
+        );
+    }
+}
+
+/// A session window of a key.
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// The event time of the first event in the session.
+    pub start:   i64,
+    /// The event time of the last event in the session.
+    pub last:    i64,
+    /// The events of the session.
+    pub batches: Vec<RecordBatch>,
+}
+
+impl Session {
+    /// Returns true if the two sessions overlap, or are within the gap.
+    fn overlaps(&self, other: &Session, gap: i64) -> bool {
+        self.start <= other.last + gap && other.start <= self.last + gap
+    }
+
+    /// Merges the other session into the current one.
+    fn merge(&mut self, other: Session) {
+        self.start = self.start.min(other.start);
+        self.last = self.last.max(other.last);
+        self.batches.extend(other.batches);
+    }
+}
+
+/// A session window in a [`SessionSnapshot`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SessionEvents {
+    /// The session key.
+    key:    i32,
+    /// The event time of the first event in the session.
+    start:  i64,
+    /// The event time of the last event in the session.
+    last:   i64,
+    /// The events of the session as an Arrow IPC file.
+    #[serde(with = "serde_bytes")]
+    events: Vec<u8>,
+}
+
+/// The open session windows and the watermarks of the upstreams kept in the
+/// snapshot of a draining function (see [`SessionState::snapshot`]).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    /// The open sessions sorted by the key and the start time.
+    sessions:   Vec<SessionEvents>,
+    /// The latest watermark of each upstream.
+    watermarks: HashMap<String, i64>,
+}
+
+impl SessionSnapshot {
+    /// Returns the number of open sessions in the snapshot.
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Returns true if the snapshot has neither sessions nor watermarks.
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty() && self.watermarks.is_empty()
+    }
+
+    /// Merges the other snapshot into the current one. The watermarks never
+    /// go back.
+    pub fn merge(&mut self, other: SessionSnapshot) {
+        self.sessions.extend(other.sessions);
+        self.sessions.sort_by_key(|s| (s.key, s.start));
+        for (upstream, watermark) in other.watermarks {
+            let current = self.watermarks.entry(upstream).or_insert(watermark);
+            *current = (*current).max(watermark);
+        }
+    }
+}
+
+/// The open session windows and the watermarks of the upstreams.
+#[derive(Debug, Default)]
+pub struct SessionState {
+    /// The open sessions per key. The sessions of a key never overlap.
+    sessions:   HashMap<i32, Vec<Session>>,
+    /// The latest watermark of each upstream.
+    watermarks: HashMap<String, i64>,
+}
+
+impl SessionState {
+    /// Creates an empty session state.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of open sessions.
+    pub fn len(&self) -> usize {
+        self.sessions.values().map(|s| s.len()).sum()
+    }
+
+    /// Returns true if there are no open sessions.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds the rows of a payload to the open sessions of their keys, and
+    /// merges the sessions that overlap or are adjacent within the gap.
+    ///
+    /// # Arguments
+    /// * `key` - The name of the group-by column.
+    /// * `time` - The name of the event time column.
+    /// * `gap` - The session gap in milliseconds.
+    /// * `batches` - The record batches of the payload.
+    pub fn accumulate(
+        &mut self,
+        key: &str,
+        time: &str,
+        gap: i64,
+        batches: &[RecordBatch],
+    ) -> Result<()> {
+        for batch in batches {
+            let keys = batch
+                .column(batch.schema().index_of(key)?)
+                .as_any()
+                .downcast_ref::<Int32Array>()
+                .ok_or_else(|| FlockError::Internal(format!("The column {} is not Int32.", key)))?;
+            let times = batch
+                .column(batch.schema().index_of(time)?)
+                .as_any()
+                .downcast_ref::<TimestampMillisecondArray>()
+                .ok_or_else(|| {
+                    FlockError::Internal(format!("The column {} is not a timestamp.", time))
+                })?;
+
+            let mut rows: HashMap<i32, Vec<(i64, u32)>> = HashMap::new();
+            for i in 0..batch.num_rows() {
+                if keys.is_valid(i) && times.is_valid(i) {
+                    rows.entry(keys.value(i))
+                        .or_default()
+                        .push((times.value(i), i as u32));
+                }
+            }
+
+            for (k, mut rows) in rows {
+                // The rows of a key in the payload can span several sessions.
+                rows.sort_unstable();
+                let mut begin = 0;
+                for end in 1..=rows.len() {
+                    if end < rows.len() && rows[end].0 - rows[end - 1].0 <= gap {
+                        continue;
+                    }
+                    let indices =
+                        UInt32Array::from(rows[begin..end].iter().map(|r| r.1).collect::<Vec<_>>());
+                    let columns = batch
+                        .columns()
+                        .iter()
+                        .map(|c| take(c.as_ref(), &indices, None))
+                        .collect::<std::result::Result<Vec<_>, _>>()?;
+                    let session = Session {
+                        start:   rows[begin].0,
+                        last:    rows[end - 1].0,
+                        batches: vec![RecordBatch::try_new(batch.schema(), columns)?],
+                    };
+                    self.insert(k, session, gap);
+                    begin = end;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Inserts a session of the key, and merges it with the open sessions.
+    fn insert(&mut self, key: i32, mut session: Session, gap: i64) {
+        let sessions = self.sessions.entry(key).or_default();
+        // Since the open sessions don't overlap, a single pass is enough: an open
+        // session overlapping the merged session overlaps the new session.
+        let (merged, mut open): (Vec<_>, Vec<_>) =
+            sessions.drain(..).partition(|s| s.overlaps(&session, gap));
+        merged.into_iter().for_each(|s| session.merge(s));
+        open.push(session);
+        *sessions = open;
+    }
+
+    /// Advances the watermark of the upstream. The watermarks never go back.
+    pub fn advance_watermark(&mut self, upstream: &str, watermark: i64) {
+        let current = self
+            .watermarks
+            .entry(upstream.to_owned())
+            .or_insert(watermark);
+        *current = (*current).max(watermark);
+    }
+
+    /// Returns the stage-wide watermark, i.e., the minimum of the watermarks of
+    /// all upstreams. Returns `None` if some upstreams haven't reported their
+    /// watermarks yet.
+    pub fn watermark(&self, upstreams: usize) -> Option<i64> {
+        if self.watermarks.len() < upstreams {
+            return None;
+        }
+        self.watermarks.values().min().copied()
+    }
+
+    /// Returns the snapshot of the open sessions and the watermarks.
+    pub fn snapshot(&self) -> Result<SessionSnapshot> {
+        let mut sessions = self
+            .sessions
+            .iter()
+            .flat_map(|(key, sessions)| sessions.iter().map(move |s| (*key, s)))
+            .map(|(key, session)| {
+                Ok(SessionEvents {
+                    key,
+                    start: session.start,
+                    last: session.last,
+                    events: encode_window(&session.batches)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        sessions.sort_by_key(|s| (s.key, s.start));
+        Ok(SessionSnapshot {
+            sessions,
+            watermarks: self.watermarks.clone(),
+        })
+    }
+
+    /// Restores the sessions and the watermarks of the snapshot. The state is
+    /// expected to be empty, since the sessions are not merged again.
+    pub fn restore(&mut self, snapshot: SessionSnapshot) -> Result<()> {
+        for session in snapshot.sessions {
+            self.sessions.entry(session.key).or_default().push(Session {
+                start:   session.start,
+                last:    session.last,
+                batches: decode_window(session.events)?,
+            });
+        }
+        for (upstream, watermark) in snapshot.watermarks {
+            self.advance_watermark(&upstream, watermark);
+        }
+        Ok(())
+    }
+
+    /// Removes and returns the sessions closed by the watermark, i.e., the
+    /// sessions whose last event time plus the gap is before the watermark.
+    /// The sessions are sorted by the key and the start time.
+    pub fn emit(&mut self, watermark: i64, gap: i64) -> Vec<(i32, Session)> {
+        let mut closed = vec![];
+        self.sessions.retain(|key, sessions| {
+            let (done, open): (Vec<_>, Vec<_>) = sessions
+                .drain(..)
+                .partition(|s| s.last.saturating_add(gap) < watermark);
+            closed.extend(done.into_iter().map(|s| (*key, s)));
+            *sessions = open;
+            !sessions.is_empty()
+        });
+        closed.sort_unstable_by_key(|(key, s)| (*key, s.start));
+        closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema, TimeUnit};
+    use std::sync::Arc;
+
+    fn bids(bids: Vec<(i32, i64)>) -> Vec<RecordBatch> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("bidder", DataType::Int32, false),
+            Field::new(
+                "b_date_time",
+                DataType::Timestamp(TimeUnit::Millisecond, None),
+                false,
+            ),
+        ]));
+        let (bidders, times): (Vec<i32>, Vec<i64>) = bids.into_iter().unzip();
+        vec![RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(bidders)),
+                Arc::new(TimestampMillisecondArray::from(times)),
+            ],
+        )
+        .unwrap()]
+    }
+
+    fn num_rows(session: &Session) -> usize {
+        session.batches.iter().map(|b| b.num_rows()).sum()
+    }
+
+    #[test]
+    fn merge_session_across_upstreams() -> Result<()> {
+        let gap = 10_000;
+        let mut state = SessionState::new();
+
+        // The events of bidder 7 are split across two upstream payloads, and the
+        // payload of upstream 0 carrying the later events arrives first.
+        state.accumulate(
+            "bidder",
+            "b_date_time",
+            gap,
+            &bids(vec![(7, 12_000), (7, 10_000)]),
+        )?;
+        state.advance_watermark("0", 30_000);
+        assert_eq!(state.watermark(2), None);
+
+        state.accumulate(
+            "bidder",
+            "b_date_time",
+            gap,
+            &bids(vec![(7, 11_000), (8, 1_000), (7, 5_000)]),
+        )?;
+        state.advance_watermark("1", 16_000);
+        assert_eq!(state.len(), 2);
+
+        // Only the session of bidder 8 is closed by the stage-wide watermark.
+        let closed = state.emit(state.watermark(2).unwrap(), gap);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(
+            (closed[0].0, closed[0].1.start, closed[0].1.last),
+            (8, 1_000, 1_000)
+        );
+
+        state.advance_watermark("1", 40_000);
+        assert_eq!(state.watermark(2), Some(30_000));
+        let closed = state.emit(state.watermark(2).unwrap(), gap);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(
+            (closed[0].0, closed[0].1.start, closed[0].1.last),
+            (7, 5_000, 12_000)
+        );
+        assert_eq!(num_rows(&closed[0].1), 4);
+        assert!(state.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn bridge_sessions() -> Result<()> {
+        let gap = 10_000;
+        let mut state = SessionState::new();
+
+        // Two sessions of the same bidder in a single payload.
+        state.accumulate(
+            "bidder",
+            "b_date_time",
+            gap,
+            &bids(vec![(1, 0), (1, 15_000)]),
+        )?;
+        assert_eq!(state.len(), 2);
+
+        // A late event bridges the two sessions.
+        state.accumulate("bidder", "b_date_time", gap, &bids(vec![(1, 8_000)]))?;
+        assert_eq!(state.len(), 1);
+
+        // The watermarks never go back.
+        state.advance_watermark("0", 30_000);
+        state.advance_watermark("0", 20_000);
+        assert_eq!(state.watermark(1), Some(30_000));
+
+        let closed = state.emit(30_000, gap);
+        assert_eq!((closed[0].1.start, closed[0].1.last), (0, 15_000));
+        assert_eq!(num_rows(&closed[0].1), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn flush_sessions_at_end_of_input() -> Result<()> {
+        let gap = 10_000;
+        let mut state = SessionState::new();
+        state.accumulate(
+            "bidder",
+            "b_date_time",
+            gap,
+            &bids(vec![(1, 25_000), (2, 29_000)]),
+        )?;
+        state.advance_watermark("0", 30_000);
+        state.advance_watermark("1", 30_000);
+        assert!(state.emit(state.watermark(2).unwrap(), gap).is_empty());
+
+        // The last sessions are only closed once every upstream has finished.
+        state.advance_watermark("0", END_OF_INPUT_WATERMARK);
+        assert!(state.emit(state.watermark(2).unwrap(), gap).is_empty());
+        state.advance_watermark("1", END_OF_INPUT_WATERMARK);
+        let closed = state.emit(state.watermark(2).unwrap(), gap);
+        assert_eq!(closed.len(), 2);
+        assert!(state.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn restore_sessions_from_snapshot() -> Result<()> {
+        let gap = 10_000;
+        let mut state = SessionState::new();
+        state.accumulate(
+            "bidder",
+            "b_date_time",
+            gap,
+            &bids(vec![(1, 0), (2, 1_000), (1, 4_000)]),
+        )?;
+        state.advance_watermark("0", 5_000);
+
+        let snapshot = state.snapshot()?;
+        assert_eq!(snapshot.len(), 2);
+
+        // The restored state closes the sessions like the original one.
+        let mut restored = SessionState::new();
+        restored.restore(snapshot)?;
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored.watermark(1), Some(5_000));
+
+        restored.accumulate("bidder", "b_date_time", gap, &bids(vec![(1, 8_000)]))?;
+        restored.advance_watermark("0", 30_000);
+        let closed = restored.emit(30_000, gap);
+        assert_eq!(
+            closed
+                .iter()
+                .map(|(key, s)| (*key, s.start, s.last, num_rows(s)))
+                .collect::<Vec<_>>(),
+            vec![(1, 0, 8_000, 3), (2, 1_000, 1_000, 1)]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,455 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The snapshot of the arena for the upgrades of the function binary.
+//!
+//! Upgrading the function binary replaces the warm function instances, and
+//! the open windows held by their arenas would be lost, e.g. the session
+//! windows spanning the upgrade. Before the upgrade, `flock-cli lambda
+//! --upgrade` invokes each function of the query with a drain payload (see
+//! [`drain_payload`]). The function moves the open windows of its arena and
+//! its open session windows into an [`ArenaSnapshot`], writes it to the state
+//! backend under `<query code>/snapshots/<function>/`, and marks itself
+//! draining: the payloads that still reach the old binary are appended to the
+//! snapshot (see [`append`]) instead of the arena. The first invocation of the
+//! new binary claims the snapshot (see [`claim`]), restores it into its arena
+//! and replays the appended payloads before processing its own payload.
+//!
+//! The data fragments are kept as the payloads that carried them, and the
+//! events of the sessions as Arrow IPC files, so that the snapshot reuses the
+//! encoding of the payloads between the functions. The spilled fragments stay
+//! where they were spilled to (see [`Arena::spill`]).
+
+use super::{frames_bytes, Arena, Bitmap, SessionSnapshot, WindowId, WindowSession};
+use crate::encoding::Encoding;
+use crate::error::Result;
+use crate::runtime::function_name::query_key;
+use crate::runtime::metadata::QueryMetadata;
+use crate::runtime::payload::{Payload, Uuid};
+use crate::runtime::stats::PayloadStats;
+use crate::state::StateBackend;
+use crate::transmute::to_payload;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+/// The key prefix of the snapshots under the prefix of the query.
+pub const SNAPSHOT_KEY_PREFIX: &str = "snapshots/";
+
+/// The metadata key that asks the function to drain before its upgrade.
+pub const DRAIN_METADATA_KEY: &str = "drain";
+
+/// An open window of the arena kept in the snapshot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WindowSnapshot {
+    /// The window in the arena.
+    pub window_id:   WindowId,
+    /// The number of data fragments of the complete window.
+    pub size:        usize,
+    /// The sequence numbers of the data fragments received.
+    pub received:    Vec<usize>,
+    /// The schema of the first relation.
+    #[serde(with = "serde_bytes")]
+    pub schema:      Vec<u8>,
+    /// The schema of the second relation.
+    #[serde(with = "serde_bytes")]
+    pub schema2:     Vec<u8>,
+    /// The compression method of the data fragments.
+    pub encoding:    Encoding,
+    /// The merged statistics of the first relation.
+    pub stats:       Option<PayloadStats>,
+    /// The merged statistics of the second relation.
+    pub stats2:      Option<PayloadStats>,
+    /// The data fragments held in memory, each as a payload.
+    pub fragments:   Vec<Payload>,
+    /// The keys of the data fragments spilled to the state backend.
+    pub spilled:     Vec<String>,
+    /// True if the data source has flushed the window.
+    pub flushed:     bool,
+    /// The ids of the payloads received by their sequence numbers.
+    #[serde(default)]
+    pub payload_ids: Vec<(usize, String)>,
+}
+
+impl WindowSnapshot {
+    /// Creates the snapshot of the window taken from the arena.
+    fn new(window_id: WindowId, window: WindowSession) -> Self {
+        let uuid = Uuid {
+            qid:     window_id.0.clone(),
+            seq_num: 0,
+            seq_len: window.size,
+        };
+        let fragments = window
+            .r1_flight_data
+            .into_iter()
+            .zip(window.r2_flight_data.into_iter())
+            .map(|(data, data2)| Payload {
+                data,
+                data2,
+                schema: window.r1_schema.clone(),
+                schema2: window.r2_schema.clone(),
+                uuid: uuid.clone(),
+                encoding: window.encoding.clone(),
+                window_id: window_id.clone(),
+                ..Default::default()
+            })
+            .collect();
+        Self {
+            received: window.bitmap.ones(),
+            size: window.size,
+            schema: window.r1_schema,
+            schema2: window.r2_schema,
+            encoding: window.encoding,
+            stats: window.r1_stats,
+            stats2: window.r2_stats,
+            fragments,
+            spilled: window.spilled,
+            flushed: window.flushed,
+            payload_ids: window.payload_ids.into_iter().collect(),
+            window_id,
+        }
+    }
+
+    /// Returns the window of the arena restored from the snapshot. The data
+    /// fragments aren't matched with their sequence numbers, since the arena
+    /// only needs the sequence numbers to drop the duplicates.
+    fn into_window(self) -> (WindowId, WindowSession) {
+        let num_bits = self
+            .received
+            .iter()
+            .copied()
+            .max()
+            .unwrap_or(0)
+            .max(self.size)
+            + 1;
+        let mut bitmap = Bitmap::new(num_bits);
+        self.received
+            .iter()
+            .for_each(|seq_num| bitmap.set(*seq_num));
+        let (r1_flight_data, r2_flight_data): (Vec<_>, Vec<_>) = self
+            .fragments
+            .into_iter()
+            .map(|payload| (payload.data, payload.data2))
+            .unzip();
+        let bytes = r1_flight_data
+            .iter()
+            .chain(r2_flight_data.iter())
+            .map(|frames| frames_bytes(frames))
+            .sum();
+        let window = WindowSession {
+            size: self.size,
+            r1_flight_data,
+            r1_schema: self.schema,
+            r2_flight_data,
+            r2_schema: self.schema2,
+            bitmap,
+            encoding: self.encoding,
+            r1_stats: self.stats,
+            r2_stats: self.stats2,
+            bytes,
+            spilled: self.spilled,
+            created: Instant::now(),
+            flushed: self.flushed,
+            payload_ids: self.payload_ids.into_iter().collect(),
+        };
+        (self.window_id, window)
+    }
+}
+
+/// The state of a draining function written to the state backend.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ArenaSnapshot {
+    /// The open windows of the arena.
+    pub windows:  Vec<WindowSnapshot>,
+    /// The open session windows.
+    #[serde(default)]
+    pub sessions: SessionSnapshot,
+    /// The payloads received after the function started to drain.
+    #[serde(default)]
+    pub pending:  Vec<Payload>,
+}
+
+impl ArenaSnapshot {
+    /// Returns true if the snapshot holds no state.
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty() && self.sessions.is_empty() && self.pending.is_empty()
+    }
+
+    /// Merges the other snapshot into the current one, e.g. if the function
+    /// is drained twice before the new binary claims the snapshot.
+    pub fn merge(&mut self, other: ArenaSnapshot) {
+        self.windows.extend(other.windows);
+        self.sessions.merge(other.sessions);
+        self.pending.extend(other.pending);
+    }
+}
+
+impl Arena {
+    /// Takes all open windows out of the arena, sorted by the window id.
+    pub fn take_snapshot(&mut self) -> Vec<WindowSnapshot> {
+        let mut windows = self
+            .0
+            .drain()
+            .map(|(window_id, window)| WindowSnapshot::new(window_id, window))
+            .collect::<Vec<_>>();
+        windows.sort_by(|a, b| a.window_id.cmp(&b.window_id));
+        windows
+    }
+
+    /// Restores the windows of the snapshot into the arena. A window of the
+    /// snapshot replaces the window of the same id, so the snapshot must be
+    /// restored before the function collects any payload.
+    pub fn restore_snapshot(&mut self, windows: Vec<WindowSnapshot>) {
+        for snapshot in windows {
+            let (window_id, window) = snapshot.into_window();
+            self.insert(window_id, window);
+        }
+    }
+}
+
+/// Returns the key of the snapshot of the function, i.e.
+/// `<query code>/snapshots/<function>/arena`.
+pub fn snapshot_key(query_code: &str, function: &str) -> String {
+    query_key(
+        query_code,
+        &format!("{}{}/arena", SNAPSHOT_KEY_PREFIX, function),
+    )
+}
+
+/// Returns the drain payload that asks the function to snapshot its arena
+/// before the upgrade of its binary.
+///
+/// # Arguments
+/// * `query_code` - The query code of the function.
+pub fn drain_payload(query_code: &str) -> Payload {
+    let uuid = Uuid {
+        qid:     query_code.to_owned(),
+        seq_num: 0,
+        seq_len: 0,
+    };
+    let mut payload = to_payload(&[], &[], uuid, true)
+        .expect("An empty payload is neither compressed nor sealed.");
+    let mut metadata = QueryMetadata::default();
+    metadata.insert(DRAIN_METADATA_KEY.to_string(), "true".to_string());
+    payload.metadata = Some(metadata);
+    payload
+}
+
+/// Returns true if the payload is a drain payload (see [`drain_payload`]).
+pub fn is_drain(metadata: &Option<QueryMetadata>) -> bool {
+    metadata
+        .as_ref()
+        .and_then(|m| m.get(DRAIN_METADATA_KEY))
+        .map(String::as_str)
+        == Some("true")
+}
+
+/// Merges the snapshot into the snapshot of the function in the state
+/// backend. The merge is retried if another invocation updates the snapshot
+/// in between.
+///
+/// # Arguments
+/// * `state_backend` - The state backend of the function.
+/// * `bucket` - The bucket of the snapshots.
+/// * `key` - The key of the snapshot (see [`snapshot_key`]).
+/// * `snapshot` - The snapshot to merge.
+pub async fn merge(
+    state_backend: &dyn StateBackend,
+    bucket: &str,
+    key: &str,
+    snapshot: &ArenaSnapshot,
+) -> Result<()> {
+    loop {
+        let (mut current, version) = read(state_backend, bucket, key).await?;
+        current.merge(snapshot.clone());
+        if state_backend
+            .write_checkpoint(
+                bucket.to_string(),
+                key.to_string(),
+                serde_json::to_vec(&current)?,
+                version,
+            )
+            .await?
+        {
+            return Ok(());
+        }
+    }
+}
+
+/// Appends a payload received by the draining function to its snapshot.
+pub async fn append(
+    state_backend: &dyn StateBackend,
+    bucket: &str,
+    key: &str,
+    payload: Payload,
+) -> Result<()> {
+    let snapshot = ArenaSnapshot {
+        pending: vec![payload],
+        ..Default::default()
+    };
+    merge(state_backend, bucket, key, &snapshot).await
+}
+
+/// Takes the snapshot of the function out of the state backend. Returns
+/// `None` if there is no snapshot, or another instance of the function has
+/// claimed it, so that a snapshot is restored only once.
+pub async fn claim(
+    state_backend: &dyn StateBackend,
+    bucket: &str,
+    key: &str,
+) -> Result<Option<ArenaSnapshot>> {
+    let (snapshot, version) = read(state_backend, bucket, key).await?;
+    if snapshot.is_empty() {
+        return Ok(None);
+    }
+    let empty = serde_json::to_vec(&ArenaSnapshot::default())?;
+    if state_backend
+        .write_checkpoint(bucket.to_string(), key.to_string(), empty, version)
+        .await?
+    {
+        Ok(Some(snapshot))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Reads the snapshot and its version from the state backend.
+async fn read(
+    state_backend: &dyn StateBackend,
+    bucket: &str,
+    key: &str,
+) -> Result<(ArenaSnapshot, Option<String>)> {
+    match state_backend
+        .read_checkpoint(bucket.to_string(), key.to_string())
+        .await?
+    {
+        Some(checkpoint) => Ok((
+            serde_json::from_slice(&checkpoint.bytes)?,
+            Some(checkpoint.version),
+        )),
+        None => Ok((ArenaSnapshot::default(), None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::arena::{HashAggregateStatus, SessionState};
+    use crate::runtime::payload::UuidBuilder;
+    use crate::state::HashMapStateBackend;
+    use crate::tests::int64_batch;
+    use datafusion::arrow::array::Int64Array;
+
+    fn payloads(qid: &str, len: usize) -> Vec<Payload> {
+        let uuids = UuidBuilder::new_with_ts(qid, 1024, len);
+        (1..=len)
+            .map(|i| {
+                to_payload(&[int64_batch(vec![i as i64; i])], &[], uuids.get(i), false).unwrap()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn restore_window_across_upgrade() -> Result<()> {
+        let backend = HashMapStateBackend::new();
+        let key = snapshot_key("qsnap", "qsnap-01-00");
+        let payloads = payloads("qsnap-00", 4);
+        let window_id = payloads[0].get_window_id();
+
+        // The old binary receives the first half of the window, and drains.
+        let mut arena = Arena::new();
+        for payload in &payloads[..2] {
+            assert!(arena.collect(payload.clone())? == HashAggregateStatus::NotReady);
+        }
+        let snapshot = ArenaSnapshot {
+            windows: arena.take_snapshot(),
+            ..Default::default()
+        };
+        assert!(arena.is_empty());
+        merge(&backend, "bucket", &key, &snapshot).await?;
+
+        // A payload reaches the old binary while it drains.
+        append(&backend, "bucket", &key, payloads[2].clone()).await?;
+
+        // The new binary restores the window, and the snapshot is claimed once.
+        let snapshot = claim(&backend, "bucket", &key).await?.unwrap();
+        assert!(claim(&backend, "bucket", &key).await?.is_none());
+        assert_eq!(snapshot.pending.len(), 1);
+
+        let mut arena = Arena::new();
+        arena.restore_snapshot(snapshot.windows);
+        assert_eq!(arena.get_bitmap(&window_id).unwrap().ones(), vec![1, 2]);
+        for payload in snapshot.pending {
+            assert!(arena.collect(payload)? == HashAggregateStatus::NotReady);
+        }
+        // The duplicates of the fragments before the upgrade are dropped.
+        assert!(arena.collect(payloads[0].clone())? == HashAggregateStatus::Processed);
+        assert!(arena.collect(payloads[3].clone())? == HashAggregateStatus::Ready);
+
+        let batches = arena.take(&window_id).await?;
+        let mut values = batches[0]
+            .iter()
+            .flatten()
+            .flat_map(|b| {
+                let column = b.column(0).as_any().downcast_ref::<Int64Array>().unwrap();
+                column.values().to_vec()
+            })
+            .collect::<Vec<_>>();
+        values.sort_unstable();
+        assert_eq!(values, vec![1, 2, 2, 3, 3, 3, 4, 4, 4, 4]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restore_flushed_window_and_sessions() -> Result<()> {
+        let backend = HashMapStateBackend::new();
+        let key = snapshot_key("qsnap", "qsnap-02-00");
+        let payloads = payloads("qsnap-01", 4);
+        let window_id = payloads[0].get_window_id();
+
+        // The data source stopped after 2 payloads of the window.
+        let mut arena = Arena::new();
+        arena.collect(payloads[0].clone())?;
+        arena.flush(&window_id, 2);
+
+        let mut sessions = SessionState::new();
+        sessions.advance_watermark("0", 1_000);
+        let snapshot = ArenaSnapshot {
+            windows:  arena.take_snapshot(),
+            sessions: sessions.snapshot()?,
+            pending:  vec![],
+        };
+        merge(&backend, "bucket", &key, &snapshot).await?;
+
+        let snapshot = claim(&backend, "bucket", &key).await?.unwrap();
+        let mut sessions = SessionState::new();
+        sessions.restore(snapshot.sessions)?;
+        assert_eq!(sessions.watermark(1), Some(1_000));
+
+        let mut arena = Arena::new();
+        arena.restore_snapshot(snapshot.windows);
+        assert!(arena.is_flushed(&window_id));
+        assert!(arena.collect(payloads[1].clone())? == HashAggregateStatus::Ready);
+        Ok(())
+    }
+
+    #[test]
+    fn drain_payload_metadata() {
+        let payload = drain_payload("qsnap");
+        assert!(is_drain(&payload.metadata));
+        assert!(!is_drain(&payloads("qsnap-00", 1)[0].metadata));
+        assert_eq!(
+            snapshot_key("qsnap", "qsnap-01-00"),
+            "qsnap/snapshots/qsnap-01-00/arena"
+        );
+    }
+}
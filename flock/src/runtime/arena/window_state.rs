@@ -0,0 +1,300 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The incremental state of hopping windows for the queries that count the
+//! rows per key and emit the keys with the maximum count (NEXMark Q5).
+//!
+//! A hopping window consists of panes, and consecutive windows share most of
+//! their panes. Instead of recomputing the whole window every hop, the counts
+//! per key are maintained per pane. When the window slides, the counts of the
+//! expired panes are subtracted and the counts of the new panes are added, and
+//! then the argmax is emitted.
+//!
+//! The data source tags each payload with its pane and window (see
+//! [`PANE_METADATA_KEY`] and [`WINDOW_METADATA_KEY`]). The payloads of a pane
+//! that has already been counted are not decoded again. If such a pane has been
+//! expired before the window completes, the window falls back to the full
+//! recomputation.
+
+use crate::error::{FlockError, Result};
+use datafusion::arrow::array::{Int32Array, UInt64Array};
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::record_batch::RecordBatch;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Range;
+use std::sync::Arc;
+
+/// The metadata key of the pane (epoch) of the payload.
+pub const PANE_METADATA_KEY: &str = "pane";
+
+/// The metadata key of the hopping window of the payload, e.g. `10-20`.
+pub const WINDOW_METADATA_KEY: &str = "window";
+
+type QueryId = String;
+type PaneId = usize;
+type Counts = HashMap<i32, u64>;
+
+/// The panes of a window that hasn't completed yet.
+#[derive(Debug, Default)]
+struct PendingWindow {
+    /// The counts of the panes that are counted by the current window.
+    counts: BTreeMap<PaneId, Counts>,
+    /// The panes that were counted by the previous windows.
+    reused: BTreeSet<PaneId>,
+}
+
+/// The incremental state of the hopping windows.
+#[derive(Debug, Default)]
+pub struct WindowState {
+    /// The counts per key of the completed panes.
+    panes:   BTreeMap<PaneId, Counts>,
+    /// The sum of the counts of all completed panes.
+    totals:  Counts,
+    /// The windows that are still collecting their payloads.
+    pending: HashMap<QueryId, PendingWindow>,
+}
+
+impl WindowState {
+    /// Creates an empty window state.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns true if the pane has been counted by a previous window.
+    pub fn is_sealed(&self, pane: PaneId) -> bool {
+        self.panes.contains_key(&pane)
+    }
+
+    /// Records that the window reuses the counts of a sealed pane, so that the
+    /// payload of the pane doesn't need to be decoded.
+    pub fn reuse(&mut self, qid: &str, pane: PaneId) {
+        self.pending
+            .entry(qid.to_owned())
+            .or_default()
+            .reused
+            .insert(pane);
+    }
+
+    /// Counts the rows per key of a payload of the pane for the given window.
+    ///
+    /// # Arguments
+    /// * `qid` - The query id of the window.
+    /// * `pane` - The pane of the payload.
+    /// * `key` - The name of the group-by column.
+    /// * `batches` - The record batches of the payload.
+    pub fn accumulate(
+        &mut self,
+        qid: &str,
+        pane: PaneId,
+        key: &str,
+        batches: &[RecordBatch],
+    ) -> Result<()> {
+        let counts = self
+            .pending
+            .entry(qid.to_owned())
+            .or_default()
+            .counts
+            .entry(pane)
+            .or_default();
+        for batch in batches {
+            let index = batch.schema().index_of(key)?;
+            let keys = batch
+                .column(index)
+                .as_any()
+                .downcast_ref::<Int32Array>()
+                .ok_or_else(|| FlockError::Internal(format!("The column {} is not Int32.", key)))?;
+            keys.iter()
+                .flatten()
+                .for_each(|k| *counts.entry(k).or_default() += 1);
+        }
+        Ok(())
+    }
+
+    /// Seals the window once all its payloads have been collected, and returns
+    /// the keys with the maximum count in the window.
+    ///
+    /// The panes before the window are expired. If the window reuses a pane
+    /// that has been expired in the meantime (e.g. the windows complete out of
+    /// order), the state can't answer the window and `None` is returned, so
+    /// that the caller falls back to the full recomputation.
+    ///
+    /// # Arguments
+    /// * `qid` - The query id of the window.
+    /// * `window` - The panes of the window.
+    /// * `schema` - The output schema: the key column and the count column.
+    pub fn seal(
+        &mut self,
+        qid: &str,
+        window: Range<PaneId>,
+        schema: SchemaRef,
+    ) -> Result<Option<RecordBatch>> {
+        let pending = self.pending.remove(qid).unwrap_or_default();
+
+        // The new panes are complete, so they can be reused by the next windows.
+        for (pane, counts) in pending.counts {
+            if self.panes.contains_key(&pane) || pane < window.start {
+                continue;
+            }
+            counts
+                .iter()
+                .for_each(|(k, c)| *self.totals.entry(*k).or_default() += c);
+            self.panes.insert(pane, counts);
+        }
+
+        if pending
+            .reused
+            .iter()
+            .any(|pane| !self.panes.contains_key(pane))
+        {
+            return Ok(None);
+        }
+
+        // Subtract the counts of the expired panes.
+        let expired = self
+            .panes
+            .range(..window.start)
+            .map(|(pane, _)| *pane)
+            .collect::<Vec<_>>();
+        for pane in expired {
+            let counts = self.panes.remove(&pane).unwrap();
+            for (k, c) in counts {
+                let total = self.totals.get_mut(&k).unwrap();
+                *total -= c;
+                if *total == 0 {
+                    self.totals.remove(&k);
+                }
+            }
+        }
+
+        let argmax = if self.panes.range(window.end..).next().is_none() {
+            argmax(&self.totals)
+        } else {
+            // The state is ahead of the window, so the window is counted from
+            // its panes only.
+            let mut totals = Counts::new();
+            self.panes.range(window).for_each(|(_, counts)| {
+                counts
+                    .iter()
+                    .for_each(|(k, c)| *totals.entry(*k).or_default() += c)
+            });
+            argmax(&totals)
+        };
+
+        let (keys, counts): (Vec<i32>, Vec<u64>) = argmax.into_iter().unzip();
+        Ok(Some(RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(keys)),
+                Arc::new(UInt64Array::from(counts)),
+            ],
+        )?))
+    }
+}
+
+/// Returns the keys with the maximum count, sorted by key.
+fn argmax(totals: &Counts) -> Vec<(i32, u64)> {
+    let max = match totals.values().max() {
+        Some(max) => *max,
+        None => return vec![],
+    };
+    let mut keys = totals
+        .iter()
+        .filter(|(_, c)| **c == max)
+        .map(|(k, c)| (*k, *c))
+        .collect::<Vec<_>>();
+    keys.sort_unstable();
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+
+    fn bids(auctions: Vec<i32>) -> Vec<RecordBatch> {
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "auction",
+            DataType::Int32,
+            false,
+        )]));
+        vec![RecordBatch::try_new(schema, vec![Arc::new(Int32Array::from(auctions))]).unwrap()]
+    }
+
+    fn output_schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("auction", DataType::Int32, false),
+            Field::new("num", DataType::UInt64, false),
+        ]))
+    }
+
+    fn rows(batch: &RecordBatch) -> Vec<(i32, u64)> {
+        let keys = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap();
+        let counts = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        (0..batch.num_rows())
+            .map(|i| (keys.value(i), counts.value(i)))
+            .collect()
+    }
+
+    #[test]
+    fn slide_window_with_ties() -> Result<()> {
+        let mut state = WindowState::new();
+
+        // window [0, 2): auction 1 -> 2, auction 2 -> 2
+        state.accumulate("w0", 0, "auction", &bids(vec![1, 2]))?;
+        state.accumulate("w0", 1, "auction", &bids(vec![1, 2, 3]))?;
+        let batch = state.seal("w0", 0..2, output_schema())?.unwrap();
+        assert_eq!(rows(&batch), vec![(1, 2), (2, 2)]);
+
+        // window [1, 3): pane 1 is reused, auction 3 -> 3
+        assert!(state.is_sealed(1));
+        state.reuse("w1", 1);
+        state.accumulate("w1", 2, "auction", &bids(vec![3, 3]))?;
+        let batch = state.seal("w1", 1..3, output_schema())?.unwrap();
+        assert_eq!(rows(&batch), vec![(3, 3)]);
+        assert!(!state.is_sealed(0));
+
+        // window [2, 4): empty pane 3
+        state.reuse("w2", 2);
+        let batch = state.seal("w2", 2..4, output_schema())?.unwrap();
+        assert_eq!(rows(&batch), vec![(3, 2)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fall_back_if_reused_pane_expired() -> Result<()> {
+        let mut state = WindowState::new();
+        state.accumulate("w0", 0, "auction", &bids(vec![1]))?;
+        state.accumulate("w0", 1, "auction", &bids(vec![2]))?;
+        state.seal("w0", 0..2, output_schema())?.unwrap();
+
+        // window [1, 3) reuses pane 1, but window [2, 4) completes first.
+        state.reuse("w1", 1);
+        state.accumulate("w2", 2, "auction", &bids(vec![3]))?;
+        state.accumulate("w2", 3, "auction", &bids(vec![3]))?;
+        let batch = state.seal("w2", 2..4, output_schema())?.unwrap();
+        assert_eq!(rows(&batch), vec![(3, 2)]);
+
+        state.accumulate("w1", 2, "auction", &bids(vec![3]))?;
+        assert!(state.seal("w1", 1..3, output_schema())?.is_none());
+        Ok(())
+    }
+}
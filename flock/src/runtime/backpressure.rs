@@ -0,0 +1,451 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The backpressure of the data source functions.
+//!
+//! If the data source emits windows faster than the downstream functions can
+//! drain them, the asynchronous invocations pile up and exhaust the Lambda
+//! concurrency of the account. Before emitting the next window, the data
+//! source checks the number of in-flight windows of the query, i.e. the windows
+//! started by the data sources but not yet written to the data sink. Once it
+//! reaches the high watermark, the data source waits until it drops to the low
+//! watermark:
+//!
+//! - In sync mode, the function sleeps and polls the in-flight windows again.
+//! - In async mode, the function re-invokes itself after the poll interval to
+//!   resume from the window it stopped at, so that it doesn't hold a function
+//!   instance while it's throttled.
+//!
+//! The windows are tracked with the markers of the completion protocol (see
+//! [`crate::runtime::completion`]). Each data source function throttles on its
+//! own windows: the windows it started are listed once, and then only the
+//! windows still in flight are checked at each poll.
+
+use crate::aws::{lambda, s3};
+use crate::configs::*;
+use crate::error::Result;
+use crate::runtime::completion::{
+    completed_window_key, completion_key_prefix, completion_window_id, in_flight_window_ids,
+    report_window_start,
+};
+use crate::runtime::metadata::QueryMetadata;
+use crate::runtime::metrics::{self, Metric};
+use crate::runtime::payload::Payload;
+use async_trait::async_trait;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// The metadata key of the window that the rescheduled data source function
+/// resumes from.
+pub const RESUME_WINDOW_METADATA_KEY: &str = "resume_window";
+
+/// Returns the window that the data source function resumes from, if it was
+/// rescheduled by the backpressure.
+pub fn resume_window(metadata: &Option<QueryMetadata>) -> Option<usize> {
+    metadata
+        .as_ref()
+        .and_then(|m| m.get(RESUME_WINDOW_METADATA_KEY))
+        .and_then(|v| v.parse::<usize>().ok())
+}
+
+/// Tracks the windows in flight of a query.
+#[async_trait]
+pub trait WindowTracker: Send + Sync {
+    /// Records that the data source has started to emit the window.
+    async fn record_start(&self, window: usize) -> Result<()>;
+    /// Returns the number of windows started but not yet written to the data
+    /// sink.
+    async fn in_flight(&self) -> Result<usize>;
+}
+
+/// Tracks the windows of a data source function with the markers of the
+/// completion protocol in S3. The windows started by the previous invocations
+/// of the function are listed once, and the windows in flight are then kept in
+/// memory, so that each poll only checks whether they are completed.
+#[derive(Debug, Clone)]
+pub struct CompletionTracker {
+    query_code: String,
+    generator:  usize,
+    in_flight:  Arc<Mutex<Option<BTreeSet<usize>>>>,
+}
+
+impl CompletionTracker {
+    /// Creates a tracker for the given data source function of the query.
+    pub fn new(query_code: &str, generator: usize) -> Self {
+        Self {
+            query_code: query_code.to_owned(),
+            generator,
+            in_flight: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Returns the windows of the data source function in flight, which are
+    /// listed from S3 at the first call.
+    async fn windows(&self) -> Result<BTreeSet<usize>> {
+        if let Some(windows) = self.in_flight.lock().unwrap().as_ref() {
+            return Ok(windows.clone());
+        }
+        let prefix = completion_key_prefix(&self.query_code);
+        let keys = s3::get_matched_keys(&FLOCK_S3_BUCKET, &prefix).await?;
+        let windows = generator_windows(
+            self.generator,
+            in_flight_window_ids(keys.iter().map(|key| key.trim_start_matches(&prefix))),
+        );
+        *self.in_flight.lock().unwrap() = Some(windows.clone());
+        Ok(windows)
+    }
+}
+
+/// Returns the windows of the data source function among the logical ids of
+/// the windows (see [`completion_window_id`]).
+fn generator_windows<'a>(
+    generator: usize,
+    ids: impl IntoIterator<Item = &'a str>,
+) -> BTreeSet<usize> {
+    let prefix = format!("{}-", generator);
+    ids.into_iter()
+        .filter_map(|id| id.strip_prefix(&prefix)?.parse::<usize>().ok())
+        .collect()
+}
+
+#[async_trait]
+impl WindowTracker for CompletionTracker {
+    async fn record_start(&self, window: usize) -> Result<()> {
+        report_window_start(&self.query_code, self.generator, window).await?;
+        self.windows().await?;
+        if let Some(windows) = self.in_flight.lock().unwrap().as_mut() {
+            windows.insert(window);
+        }
+        Ok(())
+    }
+
+    async fn in_flight(&self) -> Result<usize> {
+        let mut completed = vec![];
+        for window in self.windows().await? {
+            let key = completed_window_key(
+                &self.query_code,
+                &completion_window_id(self.generator, window),
+            );
+            if s3::object_exists(&FLOCK_S3_BUCKET, &key).await? {
+                completed.push(window);
+            }
+        }
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let windows = in_flight.get_or_insert_with(BTreeSet::new);
+        completed.iter().for_each(|window| {
+            windows.remove(window);
+        });
+        Ok(windows.len())
+    }
+}
+
+/// Whether the data source can emit the next window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// The window can be emitted.
+    Proceed,
+    /// The data source is throttled in async mode, and must reschedule itself
+    /// to resume from the window.
+    Reschedule,
+}
+
+/// The backpressure state of a data source function.
+#[derive(Debug, Clone)]
+pub struct Backpressure {
+    high:          usize,
+    low:           usize,
+    poll_interval: Duration,
+    throttled:     bool,
+}
+
+impl Backpressure {
+    /// Creates the backpressure with the given watermarks. A high watermark of
+    /// 0 disables the backpressure.
+    ///
+    /// # Arguments
+    /// * `high` - The number of in-flight windows to start throttling at.
+    /// * `low` - The number of in-flight windows to stop throttling at. It is
+    ///   capped below the high watermark.
+    /// * `poll_interval` - The interval to poll the in-flight windows.
+    pub fn new(high: usize, low: usize, poll_interval: Duration) -> Self {
+        Self {
+            high,
+            low: low.min(high.saturating_sub(1)),
+            poll_interval,
+            throttled: false,
+        }
+    }
+
+    /// Creates the backpressure with the watermarks in `FLOCK_CONF["lambda"]`.
+    pub fn from_conf() -> Self {
+        Self::new(
+            *FLOCK_INFLIGHT_HIGH_WATERMARK,
+            *FLOCK_INFLIGHT_LOW_WATERMARK,
+            Duration::from_millis(*FLOCK_BACKPRESSURE_POLL_INTERVAL),
+        )
+    }
+
+    /// Sets whether the data source starts throttled, i.e. it was rescheduled
+    /// and must wait for the low watermark.
+    pub fn with_throttled(mut self, throttled: bool) -> Self {
+        self.throttled = throttled;
+        self
+    }
+
+    /// Returns true if the backpressure is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.high > 0
+    }
+
+    /// Returns the poll interval of the in-flight windows.
+    pub fn poll_interval(&self) -> Duration {
+        self.poll_interval
+    }
+
+    /// Returns true if the data source must wait with the given number of
+    /// in-flight windows. Once throttled at the high watermark, the data source
+    /// waits until the number drops to the low watermark.
+    pub fn must_wait(&mut self, in_flight: usize) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        if self.throttled {
+            self.throttled = in_flight > self.low;
+        } else {
+            self.throttled = in_flight >= self.high;
+        }
+        self.throttled
+    }
+
+    /// Waits until the data source can emit the next window.
+    ///
+    /// # Arguments
+    /// * `tracker` - The tracker of the in-flight windows.
+    /// * `sync` - Whether the data source is invoked synchronously. In async
+    ///   mode, the data source isn't blocked, and [`Admission::Reschedule`] is
+    ///   returned instead.
+    pub async fn acquire(&mut self, tracker: &dyn WindowTracker, sync: bool) -> Result<Admission> {
+        if !self.is_enabled() {
+            return Ok(Admission::Proceed);
+        }
+
+        let start = Instant::now();
+        let mut waited = false;
+        loop {
+            let in_flight = tracker.in_flight().await?;
+            if !self.must_wait(in_flight) {
+                break;
+            }
+            metrics::scope().incr(Metric::BackpressureWaits);
+            if !sync {
+                info!(
+                    "{} windows in flight, rescheduling the data source.",
+                    in_flight
+                );
+                return Ok(Admission::Reschedule);
+            }
+            if !waited {
+                info!(
+                    "{} windows in flight, throttling the data source.",
+                    in_flight
+                );
+                waited = true;
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+
+        if waited {
+            metrics::scope().add(
+                Metric::BackpressureDuration,
+                start.elapsed().as_millis() as f64,
+            );
+        }
+        Ok(Admission::Proceed)
+    }
+
+    /// Re-invokes the data source function asynchronously after the poll
+    /// interval, to resume from the given window.
+    ///
+    /// # Arguments
+    /// * `function_name` - The name of the data source function.
+    /// * `payload` - The payload of the current invocation.
+    /// * `window` - The index of the window to resume from.
+    pub async fn reschedule(
+        &self,
+        function_name: &str,
+        payload: &Payload,
+        window: usize,
+    ) -> Result<()> {
+        let mut payload = payload.clone();
+        payload
+            .metadata
+            .get_or_insert_with(QueryMetadata::default)
+            .insert(RESUME_WINDOW_METADATA_KEY.to_string(), window.to_string());
+        tokio::time::sleep(self.poll_interval).await;
+        lambda::invoke_function(
+            function_name,
+            &FLOCK_LAMBDA_ASYNC_CALL,
+            Some(serde_json::to_vec(&payload)?.into()),
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// A simulated query whose data sink writes a window every few polls.
+    #[derive(Debug, Default)]
+    struct SlowSink {
+        inner: Mutex<SlowSinkState>,
+    }
+
+    #[derive(Debug, Default)]
+    struct SlowSinkState {
+        started:       Vec<usize>,
+        completed:     usize,
+        polls:         usize,
+        max_in_flight: usize,
+    }
+
+    impl SlowSink {
+        /// The number of polls per window written to the data sink.
+        const DRAIN_POLLS: usize = 3;
+    }
+
+    #[async_trait]
+    impl WindowTracker for SlowSink {
+        async fn record_start(&self, window: usize) -> Result<()> {
+            let mut state = self.inner.lock().unwrap();
+            state.started.push(window);
+            state.max_in_flight = state
+                .max_in_flight
+                .max(state.started.len() - state.completed);
+            Ok(())
+        }
+
+        async fn in_flight(&self) -> Result<usize> {
+            let mut state = self.inner.lock().unwrap();
+            state.polls += 1;
+            if state.polls % Self::DRAIN_POLLS == 0 && state.completed < state.started.len() {
+                state.completed += 1;
+            }
+            Ok(state.started.len() - state.completed)
+        }
+    }
+
+    /// Emits the windows, and returns the window to resume from if the data
+    /// source is rescheduled.
+    async fn run_source(
+        backpressure: &mut Backpressure,
+        tracker: &SlowSink,
+        windows: std::ops::Range<usize>,
+        sync: bool,
+    ) -> Result<Option<usize>> {
+        for window in windows {
+            match backpressure.acquire(tracker, sync).await? {
+                Admission::Proceed => tracker.record_start(window).await?,
+                Admission::Reschedule => return Ok(Some(window)),
+            }
+        }
+        Ok(None)
+    }
+
+    #[test]
+    fn watermarks() {
+        let mut backpressure = Backpressure::new(4, 2, Duration::from_millis(1));
+        assert!(!backpressure.must_wait(3));
+        assert!(backpressure.must_wait(4));
+        // Throttled until the low watermark.
+        assert!(backpressure.must_wait(3));
+        assert!(!backpressure.must_wait(2));
+        assert!(!backpressure.must_wait(3));
+
+        // The low watermark is capped below the high watermark.
+        let mut backpressure = Backpressure::new(2, 5, Duration::from_millis(1));
+        assert!(backpressure.must_wait(2));
+        assert!(!backpressure.must_wait(1));
+
+        let mut disabled = Backpressure::new(0, 0, Duration::from_millis(1));
+        assert!(!disabled.is_enabled());
+        assert!(!disabled.must_wait(1000));
+    }
+
+    #[tokio::test]
+    async fn slow_sink_throttles_sync_source() -> Result<()> {
+        let tracker = SlowSink::default();
+        let mut backpressure = Backpressure::new(4, 2, Duration::from_millis(1));
+        assert_eq!(
+            run_source(&mut backpressure, &tracker, 0..20, true).await?,
+            None
+        );
+
+        let state = tracker.inner.lock().unwrap();
+        // No window is skipped or emitted twice.
+        assert_eq!(state.started, (0..20).collect::<Vec<_>>());
+        assert!(state.max_in_flight <= 4);
+        // The source polled more than once per window while it was throttled.
+        assert!(state.polls > 20);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn slow_sink_reschedules_async_source() -> Result<()> {
+        let tracker = SlowSink::default();
+        let mut resume = 0;
+        let mut invocations = 0;
+        loop {
+            // The rescheduled invocation starts throttled.
+            let mut backpressure =
+                Backpressure::new(4, 2, Duration::from_millis(1)).with_throttled(invocations > 0);
+            invocations += 1;
+            match run_source(&mut backpressure, &tracker, resume..20, false).await? {
+                Some(window) => resume = window,
+                None => break,
+            }
+        }
+
+        let state = tracker.inner.lock().unwrap();
+        assert_eq!(state.started, (0..20).collect::<Vec<_>>());
+        assert!(state.max_in_flight <= 4);
+        assert!(invocations > 1);
+        Ok(())
+    }
+
+    #[test]
+    fn windows_of_generator() {
+        let ids = ["0-1", "0-2", "1-0", "10-3", "0-x", "0"];
+        assert_eq!(
+            generator_windows(0, ids),
+            [1, 2].into_iter().collect::<BTreeSet<_>>()
+        );
+        assert_eq!(
+            generator_windows(1, ids),
+            [0].into_iter().collect::<BTreeSet<_>>()
+        );
+        assert!(generator_windows(2, ids).is_empty());
+    }
+
+    #[test]
+    fn resume_window_metadata() {
+        assert_eq!(resume_window(&None), None);
+        let mut metadata = QueryMetadata::default();
+        metadata.insert(RESUME_WINDOW_METADATA_KEY.to_string(), "7".to_string());
+        assert_eq!(resume_window(&Some(metadata)), Some(7));
+    }
+}
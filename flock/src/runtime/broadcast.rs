@@ -0,0 +1,453 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The broadcast join joins the input of a window against a small result
+//! computed over the whole window, e.g. the maximum bid price in NEXMark Q7,
+//! without shipping all the input of the window to a single function.
+//!
+//! The join runs in three stages:
+//!
+//! 1. The stash stage ([`BroadcastRole::Stash`]) writes each input payload to
+//!    S3 under `<query code>/broadcast/<qid>/<shuffle id>/input/<seq num>`, and
+//!    sends its partial result (e.g. the maximum price of the partition) to the
+//!    next stage.
+//! 2. The broadcast stage ([`BroadcastRole::Broadcast`]) combines the partial
+//!    results of the window, writes the result to S3 as the side input of the
+//!    window, and invokes the next function once for each stashed payload.
+//! 3. The probe stage reads the stashed payload (see [`QueryMetadata::s3`]) and
+//!    the side input (see [`QueryMetadata::side_input`]) like any other query,
+//!    and joins them.
+//!
+//! The build side of a join, i.e. the second relation of the payloads of a
+//! window, can be broadcast in the same way without the extra stages. The
+//! sender of the window ([`BroadcastRole::Build`], or the windows whose second
+//! relation is at most `broadcast_threshold` bytes) writes the relation once to
+//! S3 under `<query code>/broadcast/<stage>/<qid>/<shuffle id>` (see
+//! [`broadcast_build_side`]), and the payloads only carry a pointer to it (see
+//! [`Payload::broadcast`]). The object is never overwritten, so the first
+//! sender of the window wins, e.g. over its retries. The join stage reads the
+//! build side once the window is ready, and caches it across its invocations
+//! (see [`BuildSideCache`]).
+
+use crate::aws::client::CloudClient;
+use crate::configs::{FLOCK_BROADCAST_CACHE_SIZE, FLOCK_S3_BUCKET};
+use crate::encryption;
+use crate::error::Result;
+use crate::runtime::arena::WindowId;
+use crate::runtime::function_name::{query_code_of, query_key};
+use crate::runtime::metadata::{QueryMetadata, S3Pointer, SideInput};
+use crate::runtime::payload::{DataFrame, Payload};
+use crate::transmute::{reencode, schema_to_bytes};
+use datafusion::arrow::csv;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::record_batch::RecordBatch;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+lazy_static! {
+    /// The build sides cached by the function.
+    pub static ref BUILD_SIDE_CACHE: BuildSideCache = BuildSideCache::new(*FLOCK_BROADCAST_CACHE_SIZE);
+}
+
+/// The maximum number of the windows whose build side pointers are kept until
+/// the windows are ready.
+const MAX_PENDING_WINDOWS: usize = 1024;
+
+/// The role of the function in a broadcast join.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BroadcastRole {
+    /// The function stashes its input payloads for the probe stage.
+    Stash,
+    /// The function broadcasts its output to the probe stage as the side
+    /// input of the window.
+    Broadcast,
+    /// The function broadcasts the second relation of the payloads it sends,
+    /// i.e. the build side of the join of the next stage, through S3.
+    Build,
+}
+
+/// The S3 key prefix of the broadcast join for the given window, which is
+/// under the prefix of the query of the window.
+pub fn broadcast_key_prefix(window_id: &WindowId) -> String {
+    query_key(
+        query_code_of(&window_id.0),
+        &format!("broadcast/{}/{}/", window_id.0, window_id.1),
+    )
+}
+
+/// The S3 key of the stashed input payload of the window.
+pub fn stash_key(window_id: &WindowId, seq_num: usize) -> String {
+    format!("{}input/{}", broadcast_key_prefix(window_id), seq_num)
+}
+
+/// The S3 key of the side input of the window.
+pub fn side_input_key(window_id: &WindowId) -> String {
+    format!("{}side_input.csv", broadcast_key_prefix(window_id))
+}
+
+/// Encodes the record batches as a CSV file with the header, which is read
+/// back by the probe stage as the side input.
+pub fn side_input_to_csv(batches: &[RecordBatch]) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    {
+        let mut writer = csv::Writer::new(&mut bytes);
+        for batch in batches {
+            writer.write(batch)?;
+        }
+    }
+    Ok(bytes)
+}
+
+/// The S3 key of the build side of the window broadcast by the stage.
+pub fn build_side_key(stage: &str, window_id: &WindowId) -> String {
+    query_key(
+        query_code_of(&window_id.0),
+        &format!("broadcast/{}/{}/{}", stage, window_id.0, window_id.1),
+    )
+}
+
+/// Returns true if the second relation of the window is broadcast, i.e. if the
+/// stage has the hint, or if the relation is at most `threshold` bytes. The
+/// windows without a second relation are never broadcast.
+pub fn is_small_side(role: Option<BroadcastRole>, bytes: usize, threshold: usize) -> bool {
+    bytes > 0 && (role == Some(BroadcastRole::Build) || bytes <= threshold)
+}
+
+/// Returns the size in bytes of the second relation of the payloads.
+pub fn build_side_bytes(payloads: &[Payload]) -> usize {
+    payloads
+        .iter()
+        .flat_map(|p| p.data2.iter())
+        .map(|f| f.header.len() + f.body.len())
+        .sum()
+}
+
+/// Moves the second relation of the payloads of a window to S3, and points
+/// the payloads to it. The relation is only written if the object of the
+/// window is missing, so the first sender of the window wins. The encrypted
+/// payloads are sealed as a whole, so they are left as they are.
+///
+/// # Arguments
+/// * `client` - The client of the S3 calls.
+/// * `bucket` - The bucket of the build side.
+/// * `stage` - The stage of the sender.
+/// * `payloads` - The payloads of the same window.
+///
+/// # Returns
+/// True if this call wrote the build side.
+pub async fn broadcast_build_side(
+    client: &dyn CloudClient,
+    bucket: &str,
+    stage: &str,
+    payloads: &mut [Payload],
+) -> Result<bool> {
+    let first = match payloads.iter().find(|p| !p.data2.is_empty()) {
+        Some(first) => first,
+        None => return Ok(false),
+    };
+    let key = build_side_key(stage, &first.get_window_id());
+    let mut build_side = Payload {
+        uuid: first.uuid.clone(),
+        schema: first.schema2.clone(),
+        encoding: first.encoding.clone(),
+        ..Default::default()
+    };
+    for payload in payloads.iter_mut() {
+        let data: Vec<DataFrame> = std::mem::take(&mut payload.data2);
+        build_side
+            .data
+            .extend(reencode(data, &payload.encoding, &build_side.encoding)?);
+        payload.stats2 = None;
+        payload.broadcast = Some(S3Pointer {
+            bucket: bucket.to_string(),
+            key:    key.clone(),
+        });
+    }
+    client
+        .s3_put_if_missing(
+            bucket,
+            &key,
+            encryption::seal_bytes(serde_json::to_vec(&build_side)?)?,
+        )
+        .await
+}
+
+/// The build sides of the last windows read by the function, and the pointers
+/// of the windows that are not ready yet.
+#[derive(Debug)]
+pub struct BuildSideCache {
+    capacity: usize,
+    entries:  Mutex<VecDeque<(String, Vec<RecordBatch>)>>,
+    pending:  Mutex<VecDeque<(WindowId, S3Pointer)>>,
+}
+
+impl BuildSideCache {
+    /// Creates a cache of at most `capacity` build sides. Nothing is cached if
+    /// the capacity is 0.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::new()),
+            pending: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Keeps the build side pointer of the window until the window is ready,
+    /// since the payload that completes the window may not carry it, e.g. the
+    /// flush of the data source.
+    pub fn remember(&self, window_id: &WindowId, pointer: &S3Pointer) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.iter().any(|(w, _)| w == window_id) {
+            return;
+        }
+        pending.push_back((window_id.clone(), pointer.clone()));
+        while pending.len() > MAX_PENDING_WINDOWS {
+            pending.pop_front();
+        }
+    }
+
+    /// Removes and returns the build side pointer of the window.
+    pub fn take_pointer(&self, window_id: &WindowId) -> Option<S3Pointer> {
+        let mut pending = self.pending.lock().unwrap();
+        let i = pending.iter().position(|(w, _)| w == window_id)?;
+        pending.remove(i).map(|(_, pointer)| pointer)
+    }
+
+    /// Returns the build side from the cache, or reads it from S3. The object
+    /// of a window is never overwritten, so the cached build side is never
+    /// stale.
+    pub async fn get_or_read(
+        &self,
+        client: &dyn CloudClient,
+        pointer: &S3Pointer,
+    ) -> Result<Vec<RecordBatch>> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(i) = entries.iter().position(|(k, _)| *k == pointer.key) {
+                let entry = entries.remove(i).unwrap();
+                let batches = entry.1.clone();
+                entries.push_back(entry);
+                return Ok(batches);
+            }
+        }
+
+        let bytes =
+            encryption::open_bytes(client.s3_get(&pointer.bucket, &pointer.key).await?).await?;
+        let (batches, _) = Payload::from_slice(&bytes)?.to_record_batch()?;
+        if self.capacity > 0 {
+            let mut entries = self.entries.lock().unwrap();
+            entries.push_back((pointer.key.clone(), batches.clone()));
+            while entries.len() > self.capacity {
+                entries.pop_front();
+            }
+        }
+        Ok(batches)
+    }
+
+    /// Returns the number of the cached build sides.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Returns true if no build side is cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Returns the metadata of the probe function for the stashed payload of the
+/// window, which points to the payload and the side input of the window.
+///
+/// # Arguments
+/// * `metadata` - The metadata of the current invocation.
+/// * `window_id` - The window of the broadcast join.
+/// * `seq_num` - The sequence number of the stashed payload.
+/// * `schema` - The schema of the side input.
+pub fn probe_metadata(
+    metadata: &Option<QueryMetadata>,
+    window_id: &WindowId,
+    seq_num: usize,
+    schema: SchemaRef,
+) -> QueryMetadata {
+    let mut metadata = metadata.clone().unwrap_or_default();
+    metadata.s3 = Some(S3Pointer {
+        bucket: FLOCK_S3_BUCKET.clone(),
+        key:    stash_key(window_id, seq_num),
+    });
+    metadata.side_input = Some(SideInput {
+        s3_key:     side_input_key(window_id),
+        format:     "csv".to_string(),
+        schema:     base64::encode(schema_to_bytes(schema)),
+        projection: vec![],
+        predicate:  None,
+    });
+    metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aws::client::FakeCloudClient;
+    use crate::runtime::metadata::InvocationType;
+    use crate::runtime::payload::UuidBuilder;
+    use crate::transmute::schema_from_bytes;
+    use crate::transmute::to_payload;
+    use datafusion::arrow::array::Int32Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    #[test]
+    fn broadcast_keys() {
+        let window_id = ("q7-1650000000-42".to_string(), 0);
+        assert_eq!(
+            broadcast_key_prefix(&window_id),
+            "q7/broadcast/q7-1650000000-42/0/"
+        );
+        assert_eq!(
+            stash_key(&window_id, 3),
+            "q7/broadcast/q7-1650000000-42/0/input/3"
+        );
+        assert_eq!(
+            side_input_key(&window_id),
+            "q7/broadcast/q7-1650000000-42/0/side_input.csv"
+        );
+    }
+
+    #[test]
+    fn side_input_round_trip() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "maxprice",
+            DataType::Int32,
+            true,
+        )]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int32Array::from(vec![Some(9_999)]))],
+        )?;
+
+        let bytes = side_input_to_csv(&[batch.clone()])?;
+        assert_eq!(
+            String::from_utf8(bytes.clone()).unwrap(),
+            "maxprice\n9999\n"
+        );
+
+        let mut reader = csv::ReaderBuilder::new()
+            .with_schema(schema)
+            .has_header(true)
+            .build(Cursor::new(bytes))?;
+        assert_eq!(reader.next().unwrap()?, batch);
+        assert!(reader.next().is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn broadcast_build_side_once() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "price",
+            DataType::Int32,
+            true,
+        )]));
+        let batch = |v: i32| {
+            RecordBatch::try_new(schema.clone(), vec![Arc::new(Int32Array::from(vec![v]))]).unwrap()
+        };
+        let window = || {
+            let mut builder = UuidBuilder::new_with_ts("q13-00", 1650000000, 4);
+            (0..4)
+                .map(|i| {
+                    to_payload(&[batch(i)], &[batch(i * 10)], builder.next_uuid(), false).unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+        let client = FakeCloudClient::new();
+
+        let mut payloads = window();
+        let before = serde_json::to_vec(&payloads)?.len();
+        assert!(broadcast_build_side(&client, "flock", "q13-00", &mut payloads).await?);
+        assert!(serde_json::to_vec(&payloads)?.len() < before);
+        let pointer = payloads[0].broadcast.clone().unwrap();
+        assert_eq!(
+            pointer.key,
+            build_side_key("q13-00", &payloads[0].get_window_id())
+        );
+        assert!(payloads
+            .iter()
+            .all(|p| p.data2.is_empty() && p.broadcast.as_ref() == Some(&pointer)));
+
+        // The retry of the window doesn't overwrite the build side.
+        let mut retried = window();
+        assert!(!broadcast_build_side(&client, "flock", "q13-00", &mut retried).await?);
+        assert_eq!(retried[0].broadcast.as_ref(), Some(&pointer));
+
+        // The build side is read once, and then served by the cache.
+        let cache = BuildSideCache::new(2);
+        let batches = cache.get_or_read(&client, &pointer).await?;
+        let prices = batches
+            .iter()
+            .flat_map(|b| {
+                b.column(0)
+                    .as_any()
+                    .downcast_ref::<Int32Array>()
+                    .unwrap()
+                    .values()
+                    .to_vec()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(prices, vec![0, 10, 20, 30]);
+        let calls = client.calls();
+        assert_eq!(cache.get_or_read(&client, &pointer).await?, batches);
+        assert_eq!(client.calls(), calls);
+        assert_eq!(cache.len(), 1);
+
+        // The pointer is kept until the window is ready.
+        let window_id = payloads[0].get_window_id();
+        cache.remember(&window_id, &pointer);
+        assert_eq!(cache.take_pointer(&window_id), Some(pointer));
+        assert_eq!(cache.take_pointer(&window_id), None);
+
+        // The windows without a second relation are never broadcast.
+        assert!(is_small_side(Some(BroadcastRole::Build), 1, 0));
+        assert!(is_small_side(None, 1024, 4096));
+        assert!(!is_small_side(None, 1024, 0));
+        assert!(!is_small_side(Some(BroadcastRole::Build), 0, 4096));
+        Ok(())
+    }
+
+    #[test]
+    fn probe_metadata_points_to_window() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "maxprice",
+            DataType::Int32,
+            true,
+        )]));
+        let window_id = ("q7-1650000000-42".to_string(), 0);
+        let metadata = Some(QueryMetadata {
+            invocation_type: Some(InvocationType::Async),
+            ..Default::default()
+        });
+
+        let probe = probe_metadata(&metadata, &window_id, 2, schema.clone());
+        assert_eq!(probe.invocation_type, Some(InvocationType::Async));
+        assert_eq!(probe.s3.as_ref().unwrap().key, stash_key(&window_id, 2));
+        let side_input = probe.side_input.unwrap();
+        assert_eq!(side_input.s3_key, side_input_key(&window_id));
+        assert_eq!(side_input.format, "csv");
+        assert_eq!(
+            schema_from_bytes(&base64::decode(&side_input.schema)?)?,
+            schema
+        );
+        Ok(())
+    }
+}
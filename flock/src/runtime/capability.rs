@@ -0,0 +1,455 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The capability handshake between the deployment and the cloud functions.
+//!
+//! Some scalar functions of DataFusion are only compiled with its cargo
+//! features, e.g. `lpad` with `unicode_expressions`. A function binary built
+//! without the feature still plans the query, and the missing function only
+//! surfaces when the window is computed, if at all. Each cloud function answers
+//! the probe payload `{"probe": "capabilities"}` with the [`Capabilities`] of
+//! its binary, and the deployment sends the probe after the function is
+//! created or updated, and checks that the binary has every function that the
+//! plan of its stage calls (see [`handshake`]).
+
+use crate::aws::client::CloudClient;
+use crate::configs::*;
+use crate::error::{FlockError, Result};
+use crate::runtime::response::Response;
+use datafusion::execution::context::ExecutionContext as DataFusionExecutionContext;
+use datafusion::physical_plan::filter::FilterExec;
+use datafusion::physical_plan::hash_aggregate::HashAggregateExec;
+use datafusion::physical_plan::projection::ProjectionExec;
+use datafusion::physical_plan::sort::SortExec;
+use datafusion::physical_plan::windows::WindowAggExec;
+use datafusion::physical_plan::{ExecutionPlan, PhysicalExpr};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use tracing::warn;
+
+/// The value of the `probe` key of the payload that asks the function for the
+/// capabilities of its binary.
+pub const CAPABILITY_PROBE: &str = "capabilities";
+
+/// The version of the flock crate that the binary is built with.
+pub const FLOCK_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// A scalar function that is only compiled with a cargo feature of DataFusion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GatedFunction {
+    /// The SQL name of the function.
+    pub name:    &'static str,
+    /// The cargo feature of DataFusion that compiles the function.
+    pub feature: &'static str,
+    /// The call of the function that the binary evaluates to detect it.
+    pub probe:   &'static str,
+}
+
+macro_rules! gated {
+    ($NAME:expr, $FEATURE:expr, $PROBE:expr) => {
+        GatedFunction {
+            name:    $NAME,
+            feature: $FEATURE,
+            probe:   $PROBE,
+        }
+    };
+}
+
+/// The scalar functions gated by the cargo features of DataFusion. The other
+/// functions are compiled into every binary.
+pub const GATED_FUNCTIONS: &[GatedFunction] = &[
+    gated!("md5", "crypto_expressions", "md5('a')"),
+    gated!("sha224", "crypto_expressions", "sha224('a')"),
+    gated!("sha256", "crypto_expressions", "sha256('a')"),
+    gated!("sha384", "crypto_expressions", "sha384('a')"),
+    gated!("sha512", "crypto_expressions", "sha512('a')"),
+    gated!(
+        "regexp_match",
+        "regex_expressions",
+        "regexp_match('a', 'a')"
+    ),
+    gated!(
+        "regexp_replace",
+        "regex_expressions",
+        "regexp_replace('a', 'a', 'b')"
+    ),
+    gated!(
+        "character_length",
+        "unicode_expressions",
+        "character_length('a')"
+    ),
+    gated!("left", "unicode_expressions", "left('a', 1)"),
+    gated!("lpad", "unicode_expressions", "lpad('a', 2)"),
+    gated!("reverse", "unicode_expressions", "reverse('a')"),
+    gated!("right", "unicode_expressions", "right('a', 1)"),
+    gated!("rpad", "unicode_expressions", "rpad('a', 2)"),
+    gated!(
+        "split_part",
+        "unicode_expressions",
+        "split_part('a,b', ',', 2)"
+    ),
+    gated!("strpos", "unicode_expressions", "strpos('a', 'a')"),
+    gated!("substr", "unicode_expressions", "substr('a', 1)"),
+    gated!(
+        "translate",
+        "unicode_expressions",
+        "translate('a', 'a', 'b')"
+    ),
+];
+
+/// Returns the gated function of the name, e.g. `split_part`. The physical
+/// expressions print the functions without the underscores, e.g.
+/// `splitpart(..)`, and `char_length` is an alias of `character_length`, so
+/// the names are compared without the underscores and the case.
+pub fn gated_function(name: &str) -> Option<&'static GatedFunction> {
+    let normalize = |name: &str| name.replace('_', "").to_lowercase();
+    let name = match normalize(name).as_str() {
+        "charlength" | "length" => "characterlength".to_string(),
+        name => name.to_string(),
+    };
+    GATED_FUNCTIONS.iter().find(|f| normalize(f.name) == name)
+}
+
+/// The capabilities of a function binary.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// The version of the flock crate.
+    pub flock_version: String,
+    /// The cargo features of DataFusion whose functions are all compiled.
+    pub features:      BTreeSet<String>,
+    /// The gated functions compiled into the binary.
+    pub functions:     BTreeSet<String>,
+}
+
+impl Capabilities {
+    /// Creates the capabilities of the binary with the gated functions.
+    pub fn new<I, S>(flock_version: &str, functions: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let functions = functions
+            .into_iter()
+            .map(Into::into)
+            .collect::<BTreeSet<String>>();
+        let features = GATED_FUNCTIONS
+            .iter()
+            .map(|f| f.feature)
+            .filter(|feature| {
+                GATED_FUNCTIONS
+                    .iter()
+                    .filter(|f| f.feature == *feature)
+                    .all(|f| functions.contains(f.name))
+            })
+            .map(|feature| feature.to_string())
+            .collect();
+        Self {
+            flock_version: flock_version.to_string(),
+            features,
+            functions,
+        }
+    }
+
+    /// Detects the capabilities of the running binary, by evaluating the probe
+    /// of each gated function. A function that isn't compiled fails to
+    /// evaluate.
+    pub async fn detect() -> Self {
+        let mut functions = vec![];
+        for function in GATED_FUNCTIONS {
+            let mut ctx = DataFusionExecutionContext::new();
+            let sql = format!("SELECT {}", function.probe);
+            let result = match ctx.sql(&sql).await {
+                Ok(df) => df.collect().await.map(|_| ()),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => functions.push(function.name),
+                Err(e) => warn!("{} isn't compiled: {}", function.name, e),
+            }
+        }
+        Self::new(FLOCK_VERSION, functions)
+    }
+
+    /// Returns the functions that the plans call but the binary doesn't have.
+    pub fn missing(&self, plans: &[Arc<dyn ExecutionPlan>]) -> Vec<&'static GatedFunction> {
+        required_functions(plans)
+            .into_iter()
+            .filter(|f| !self.functions.contains(f.name))
+            .collect()
+    }
+}
+
+/// Returns the probe payload of the capabilities.
+pub fn probe_payload() -> Value {
+    json!({ "probe": CAPABILITY_PROBE })
+}
+
+/// Returns true if the payload is the probe of the capabilities.
+pub fn is_probe(payload: &Value) -> bool {
+    payload.get("probe") == Some(&json!(CAPABILITY_PROBE))
+}
+
+/// Returns the gated functions called by the physical expressions of the
+/// plans, in the order of their names.
+pub fn required_functions(plans: &[Arc<dyn ExecutionPlan>]) -> Vec<&'static GatedFunction> {
+    let mut nodes = plans.to_vec();
+    let mut names = BTreeSet::new();
+    while let Some(node) = nodes.pop() {
+        for expr in expressions(&node) {
+            names.extend(function_names(&expr.to_string()));
+        }
+        nodes.extend(node.children());
+    }
+    let mut functions = names
+        .iter()
+        .filter_map(|name| gated_function(name))
+        .collect::<Vec<_>>();
+    functions.sort_by_key(|f| f.name);
+    functions.dedup();
+    functions
+}
+
+/// Returns the physical expressions evaluated by the operator.
+fn expressions(plan: &Arc<dyn ExecutionPlan>) -> Vec<Arc<dyn PhysicalExpr>> {
+    let any = plan.as_any();
+    if let Some(projection) = any.downcast_ref::<ProjectionExec>() {
+        projection.expr().iter().map(|(e, _)| e.clone()).collect()
+    } else if let Some(filter) = any.downcast_ref::<FilterExec>() {
+        vec![filter.predicate().clone()]
+    } else if let Some(agg) = any.downcast_ref::<HashAggregateExec>() {
+        agg.group_expr()
+            .iter()
+            .map(|(e, _)| e.clone())
+            .chain(agg.aggr_expr().iter().flat_map(|e| e.expressions()))
+            .collect()
+    } else if let Some(sort) = any.downcast_ref::<SortExec>() {
+        sort.expr().iter().map(|e| e.expr.clone()).collect()
+    } else if let Some(window) = any.downcast_ref::<WindowAggExec>() {
+        window
+            .window_expr()
+            .iter()
+            .flat_map(|e| {
+                let mut exprs = e.expressions();
+                exprs.extend(e.partition_by().iter().cloned());
+                exprs.extend(e.order_by().iter().map(|s| s.expr.clone()));
+                exprs
+            })
+            .collect()
+    } else {
+        vec![]
+    }
+}
+
+/// Returns the names of the functions called in the printed expression, i.e.
+/// the identifiers followed by a parenthesis, including the nested calls.
+fn function_names(expr: &str) -> Vec<String> {
+    expr.match_indices('(')
+        .filter_map(|(i, _)| {
+            let name = expr[..i]
+                .chars()
+                .rev()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+                .collect::<String>();
+            (!name.is_empty()).then(|| name.chars().rev().collect())
+        })
+        .collect()
+}
+
+/// Checks that the function binary has every gated function called by the
+/// plans of its stage.
+///
+/// # Arguments
+/// * `function` - The name of the function.
+/// * `plans` - The plans of the function's stage.
+/// * `capabilities` - The capabilities reported by the function.
+pub fn check(
+    function: &str,
+    plans: &[Arc<dyn ExecutionPlan>],
+    capabilities: &Capabilities,
+) -> Result<()> {
+    if capabilities.flock_version != FLOCK_VERSION {
+        warn!(
+            "The function {} runs flock {}, but the query is deployed with flock {}",
+            function, capabilities.flock_version, FLOCK_VERSION
+        );
+    }
+    let missing = capabilities.missing(plans);
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(FlockError::FunctionGeneration(format!(
+        "The function {} can't run its plan. Its binary (flock {}) is built without {}. \
+         Rebuild flock-function with the DataFusion features enabled.",
+        function,
+        capabilities.flock_version,
+        missing
+            .iter()
+            .map(|f| format!("{} (feature `{}`)", f.name, f.feature))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )))
+}
+
+/// Sends the probe to the deployed function, and checks its capabilities
+/// against the plans of its stage (see [`check`]).
+///
+/// # Returns
+/// The capabilities reported by the function.
+pub async fn handshake(
+    client: &dyn CloudClient,
+    function: &str,
+    plans: &[Arc<dyn ExecutionPlan>],
+) -> Result<Capabilities> {
+    let bytes = client
+        .invoke(
+            function,
+            &FLOCK_LAMBDA_SYNC_CALL,
+            serde_json::to_vec(&probe_payload())?,
+        )
+        .await?
+        .unwrap_or_default();
+    let data = Response::from_slice(&bytes)?
+        .into_result()
+        .map_err(|e| {
+            FlockError::FunctionGeneration(format!(
+                "The function {} doesn't answer the capability probe, and its binary may \
+                 predate it: {}",
+                function, e
+            ))
+        })?
+        .into_data();
+    let capabilities: Capabilities =
+        serde_json::from_value(data["capabilities"].clone()).map_err(|_| {
+            FlockError::FunctionGeneration(format!(
+                "The function {} doesn't answer the capability probe, and its binary may \
+                 predate it",
+                function
+            ))
+        })?;
+    check(function, plans, &capabilities)?;
+    Ok(capabilities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aws::client::FakeCloudClient;
+    use crate::datasource::nexmark::register_nexmark_tables;
+    use crate::runtime::plan::physical_plan;
+
+    async fn plan(sql: &str) -> Result<Arc<dyn ExecutionPlan>> {
+        let ctx = register_nexmark_tables().await?;
+        physical_plan(&ctx, sql).await
+    }
+
+    /// The capabilities of a binary built without the given functions.
+    fn capabilities_without(excluded: &[&str]) -> Capabilities {
+        Capabilities::new(
+            FLOCK_VERSION,
+            GATED_FUNCTIONS
+                .iter()
+                .map(|f| f.name)
+                .filter(|name| !excluded.contains(name)),
+        )
+    }
+
+    #[tokio::test]
+    async fn walk_plan_functions() -> Result<()> {
+        let plan = plan(
+            "SELECT p_id, split_part(email_address, '@', 2) AS domain FROM person \
+             WHERE md5(credit_card) <> '' AND upper(reverse(name)) <> ''",
+        )
+        .await?;
+        let names = required_functions(&[plan])
+            .iter()
+            .map(|f| f.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["md5", "reverse", "split_part"]);
+
+        let plan = plan("SELECT auction, SUM(price) FROM bid GROUP BY auction").await?;
+        assert!(required_functions(&[plan]).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn match_function_names() {
+        assert_eq!(
+            function_names("splitpart(lower(a@0), @, 2) <> "),
+            vec!["splitpart", "lower"]
+        );
+        assert_eq!(gated_function("splitpart").unwrap().name, "split_part");
+        assert_eq!(
+            gated_function("CHAR_LENGTH").unwrap().name,
+            "character_length"
+        );
+        assert!(gated_function("lower").is_none());
+    }
+
+    #[tokio::test]
+    async fn reject_missing_capability() -> Result<()> {
+        let plans = vec![plan("SELECT p_id, split_part(email_address, '@', 2) FROM person").await?];
+
+        let capabilities = capabilities_without(&["split_part"]);
+        assert!(!capabilities.features.contains("unicode_expressions"));
+        assert!(capabilities.features.contains("crypto_expressions"));
+        let err = check("q13-00", &plans, &capabilities).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("q13-00"));
+        assert!(message.contains("split_part (feature `unicode_expressions`)"));
+
+        // The functions that the plan doesn't call aren't required.
+        check("q13-00", &plans, &capabilities_without(&["md5", "lpad"]))?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn probe_deployed_function() -> Result<()> {
+        let plans = vec![plan("SELECT p_id, split_part(email_address, '@', 2) FROM person").await?];
+        let client = FakeCloudClient::new();
+
+        // The function built without the feature fails the deployment.
+        let answer = |capabilities: &Capabilities| {
+            let response = Response::ok("q13-00", json!({ "capabilities": capabilities }));
+            serde_json::to_vec(&response.to_value()).unwrap()
+        };
+        client.set_response("q13-00", answer(&capabilities_without(&["split_part"])));
+        assert!(handshake(&client, "q13-00", &plans).await.is_err());
+        let invocations = client.invocations();
+        assert_eq!(invocations.len(), 1);
+        assert_eq!(invocations[0].invocation_type, *FLOCK_LAMBDA_SYNC_CALL);
+        assert!(is_probe(&serde_json::from_slice(&invocations[0].payload)?));
+
+        client.set_response("q13-00", answer(&capabilities_without(&[])));
+        let capabilities = handshake(&client, "q13-00", &plans).await?;
+        assert!(capabilities.functions.contains("split_part"));
+
+        // The binary that predates the probe fails to read it as a payload.
+        let error = FlockError::Execution("missing field `uuid`".to_string());
+        let response = serde_json::to_vec(&Response::error("q13-00", &error).to_value())?;
+        client.set_response("q13-00", response);
+        let err = handshake(&client, "q13-00", &plans).await.unwrap_err();
+        assert!(err.to_string().contains("capability probe"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn detect_compiled_capabilities() -> Result<()> {
+        let capabilities = Capabilities::detect().await;
+        assert_eq!(capabilities.flock_version, FLOCK_VERSION);
+        // The crate is built with the default features of DataFusion.
+        assert_eq!(capabilities, capabilities_without(&[]));
+        Ok(())
+    }
+}
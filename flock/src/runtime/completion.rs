@@ -0,0 +1,381 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The completion protocol lets the driver of an asynchronous run know when
+//! all windows of the query have been processed.
+//!
+//! Each data source function reports the number of windows it will produce to
+//! S3 under the key prefix `<query code>/completion/sources/`, and the last
+//! stage of the query records every window it writes to the data sink under
+//! `<query code>/completion/windows/`. The windows are recorded by their
+//! logical ids stamped by the data sources (see
+//! [`COMPLETION_WINDOW_METADATA_KEY`]), so that a window written by several
+//! payloads, e.g. the partitions of a shuffle, is only counted once. The
+//! driver polls the [`CompletionManifest`] until all windows are accounted
+//! for. A data source
+//! function whose chain of continuations is cut short overwrites its report
+//! with the windows it actually emitted (see
+//! [`crate::runtime::continuation`]), so the driver always reads the number
+//! reported by the last invocation.
+//!
+//! The data source functions also record the windows they start under
+//! `<query code>/completion/started/` by the same logical ids, so that they can
+//! throttle themselves on the windows in flight, i.e. the windows started but
+//! not yet recorded as completed (see [`crate::runtime::backpressure`]).
+
+use crate::aws::client::CloudClient;
+use crate::aws::s3;
+use crate::configs::FLOCK_S3_BUCKET;
+use crate::error::Result;
+use crate::runtime::arena::UPSTREAM_METADATA_KEY;
+use crate::runtime::function_name::query_key;
+use crate::runtime::metadata::QueryMetadata;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The metadata key to enable the completion protocol in the function payload.
+pub const COMPLETION_METADATA_KEY: &str = "completion";
+
+/// The metadata key of the logical window of the payload, which the last
+/// stage records as completed (see [`completion_window_id`]).
+pub const COMPLETION_WINDOW_METADATA_KEY: &str = "completion_window";
+
+/// Returns true if the completion protocol is enabled in the payload metadata.
+pub fn is_completion(metadata: &Option<QueryMetadata>) -> bool {
+    metadata
+        .as_ref()
+        .and_then(|m| m.get(COMPLETION_METADATA_KEY))
+        .map(|v| v == "true")
+        .unwrap_or(false)
+}
+
+/// Returns the index of the data source function (generator) in the payload
+/// metadata.
+pub fn generator_index(metadata: &Option<QueryMetadata>) -> usize {
+    metadata
+        .as_ref()
+        .and_then(|m| m.get(UPSTREAM_METADATA_KEY))
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(0)
+}
+
+/// Returns the logical id of the window emitted by the data source function,
+/// i.e. `<generator>-<window>`.
+pub fn completion_window_id(generator: usize, window: usize) -> String {
+    format!("{}-{}", generator, window)
+}
+
+/// Stamps the logical window into the payload metadata if the completion
+/// protocol is enabled.
+pub fn stamp_window(metadata: &mut Option<QueryMetadata>, generator: usize, window: usize) {
+    if is_completion(metadata) {
+        if let Some(metadata) = metadata.as_mut() {
+            metadata.insert(
+                COMPLETION_WINDOW_METADATA_KEY.to_string(),
+                completion_window_id(generator, window),
+            );
+        }
+    }
+}
+
+/// Returns the logical window stamped into the payload metadata.
+pub fn completion_window(metadata: &Option<QueryMetadata>) -> Option<&str> {
+    metadata
+        .as_ref()
+        .and_then(|m| m.get(COMPLETION_WINDOW_METADATA_KEY))
+        .map(|w| w.as_str())
+}
+
+/// The S3 key prefix of the completion manifest for the given query.
+pub fn completion_key_prefix(query_code: &str) -> String {
+    query_key(query_code, "completion/")
+}
+
+/// The S3 key of the marker of the window written to the data sink.
+pub fn completed_window_key(query_code: &str, window: &str) -> String {
+    format!("{}windows/{}", completion_key_prefix(query_code), window)
+}
+
+/// The S3 key of the marker of the window started by the data source function.
+pub fn started_window_key(query_code: &str, window: &str) -> String {
+    format!("{}started/{}", completion_key_prefix(query_code), window)
+}
+
+/// Returns the logical ids of the windows that are started but not yet
+/// written to the data sink.
+///
+/// # Arguments
+/// * `names` - The names of the markers relative to the completion prefix.
+pub fn in_flight_window_ids<'a>(names: impl IntoIterator<Item = &'a str>) -> BTreeSet<&'a str> {
+    let (mut started, mut completed) = (BTreeSet::new(), BTreeSet::new());
+    for name in names {
+        if let Some(window) = name.strip_prefix("started/") {
+            started.insert(window);
+        } else if let Some(window) = name.strip_prefix("windows/") {
+            completed.insert(window);
+        }
+    }
+    started.difference(&completed).copied().collect()
+}
+
+/// Returns the logical ids of the windows of the query in flight, listed from
+/// the markers in S3.
+pub async fn in_flight_windows(
+    client: &dyn CloudClient,
+    query_code: &str,
+) -> Result<BTreeSet<String>> {
+    let prefix = completion_key_prefix(query_code);
+    let keys = client.s3_list(&FLOCK_S3_BUCKET, &prefix).await?;
+    Ok(
+        in_flight_window_ids(keys.iter().map(|key| key.trim_start_matches(&prefix)))
+            .into_iter()
+            .map(|window| window.to_string())
+            .collect(),
+    )
+}
+
+/// The windows that a data source function will produce.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SourceReport {
+    /// The total number of data source functions (generators) of the run.
+    pub generators: usize,
+    /// The number of windows produced by this data source function. It is
+    /// `None` if the number depends on the data, e.g. the session windows.
+    pub windows:    Option<usize>,
+}
+
+impl SourceReport {
+    /// Reports the windows of the data source function to S3.
+    ///
+    /// # Arguments
+    /// * `query_code` - The query code of the run.
+    /// * `generator` - The index of the data source function.
+    pub async fn report(&self, query_code: &str, generator: usize) -> Result<()> {
+        let key = format!("{}sources/{}", completion_key_prefix(query_code), generator);
+        s3::put_object(&FLOCK_S3_BUCKET, &key, serde_json::to_vec(self)?).await
+    }
+}
+
+/// Records that the window has been written to the data sink. The window is
+/// recorded again if several payloads write it, which is idempotent.
+///
+/// # Arguments
+/// * `query_code` - The query code of the run.
+/// * `window` - The logical id of the window (see [`completion_window_id`]).
+pub async fn report_window(query_code: &str, window: &str) -> Result<()> {
+    let key = completed_window_key(query_code, window);
+    s3::put_object(&FLOCK_S3_BUCKET, &key, vec![]).await
+}
+
+/// Records that the data source function has started to emit the window.
+///
+/// # Arguments
+/// * `query_code` - The query code of the run.
+/// * `generator` - The index of the data source function.
+/// * `window` - The index of the window in the data source function.
+pub async fn report_window_start(query_code: &str, generator: usize, window: usize) -> Result<()> {
+    let key = started_window_key(query_code, &completion_window_id(generator, window));
+    s3::put_object(&FLOCK_S3_BUCKET, &key, vec![]).await
+}
+
+/// The progress of an asynchronous run observed by the driver.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompletionManifest {
+    sources: BTreeMap<usize, SourceReport>,
+    windows: BTreeSet<String>,
+}
+
+impl CompletionManifest {
+    /// Creates an empty manifest.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the report of a data source function to the manifest.
+    pub fn add_source(&mut self, generator: usize, report: SourceReport) {
+        self.sources.insert(generator, report);
+    }
+
+    /// Adds a window written to the data sink to the manifest.
+    pub fn add_window(&mut self, window_id: &str) {
+        self.windows.insert(window_id.to_string());
+    }
+
+    /// Returns the logical ids of the windows written to the data sink in
+    /// sorted order.
+    pub fn window_ids(&self) -> impl Iterator<Item = &str> {
+        self.windows.iter().map(|w| w.as_str())
+    }
+
+    /// Returns the number of windows written to the data sink.
+    pub fn completed_windows(&self) -> usize {
+        self.windows.len()
+    }
+
+    /// Returns the total number of windows that the data source functions will
+    /// produce. It is `None` until all data source functions have reported,
+    /// or if any of them produces a data-dependent number of windows.
+    pub fn expected_windows(&self) -> Option<usize> {
+        let generators = self.sources.values().map(|s| s.generators).max()?;
+        if self.sources.len() < generators {
+            return None;
+        }
+        self.sources.values().map(|s| s.windows).sum()
+    }
+
+    /// Returns true if all windows are accounted for, i.e. the number of the
+    /// distinct windows written to the data sink is the expected number.
+    ///
+    /// # Arguments
+    /// * `expected_windows` - The number of windows to wait for. If `None`, the
+    ///   number reported by the data source functions is used.
+    pub fn is_complete(&self, expected_windows: Option<usize>) -> bool {
+        match expected_windows.or_else(|| self.expected_windows()) {
+            Some(expected) => self.completed_windows() == expected,
+            None => false,
+        }
+    }
+
+    /// Fetches the manifest of the given query from S3.
+    pub async fn fetch(query_code: &str) -> Result<Self> {
+        let prefix = completion_key_prefix(query_code);
+        let mut manifest = CompletionManifest::new();
+        for key in s3::get_matched_keys(&FLOCK_S3_BUCKET, &prefix).await? {
+            let name = key.trim_start_matches(&prefix);
+            if let Some(generator) = name.strip_prefix("sources/") {
+                if let Ok(generator) = generator.parse::<usize>() {
+                    manifest.add_source(
+                        generator,
+                        serde_json::from_slice(&s3::get_object(&FLOCK_S3_BUCKET, &key).await?)?,
+                    );
+                }
+            } else if let Some(window_id) = name.strip_prefix("windows/") {
+                manifest.add_window(window_id);
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Removes the manifest of the previous runs of the given query.
+    pub async fn clear(client: &dyn CloudClient, query_code: &str) -> Result<()> {
+        client
+            .s3_delete(&FLOCK_S3_BUCKET, &completion_key_prefix(query_code))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completion_manifest() {
+        let mut manifest = CompletionManifest::new();
+        assert_eq!(manifest.expected_windows(), None);
+        assert!(!manifest.is_complete(None));
+
+        let report = SourceReport {
+            generators: 2,
+            windows:    Some(3),
+        };
+        manifest.add_source(0, report.clone());
+        // The second generator hasn't reported yet.
+        assert_eq!(manifest.expected_windows(), None);
+        manifest.add_source(1, report);
+        assert_eq!(manifest.expected_windows(), Some(6));
+
+        // The partitions of a shuffled window record the same window.
+        for window_id in ["0-0", "0-1", "0-2", "0-0", "1-0", "1-1"] {
+            manifest.add_window(window_id);
+        }
+        assert_eq!(manifest.completed_windows(), 5);
+        assert!(!manifest.is_complete(None));
+        assert!(manifest.is_complete(Some(5)));
+        assert!(!manifest.is_complete(Some(4)));
+
+        manifest.add_window("1-2");
+        assert!(manifest.is_complete(None));
+    }
+
+    #[test]
+    fn completion_manifest_overwritten_report() {
+        let mut manifest = CompletionManifest::new();
+        let report = |windows| SourceReport {
+            generators: 1,
+            windows:    Some(windows),
+        };
+        manifest.add_source(0, report(6));
+        manifest.add_window("0-0");
+        manifest.add_window("0-1");
+        assert!(!manifest.is_complete(None));
+
+        // The last continuation reports the windows it actually emitted.
+        manifest.add_source(0, report(2));
+        assert_eq!(manifest.expected_windows(), Some(2));
+        assert!(manifest.is_complete(None));
+    }
+
+    #[test]
+    fn completion_manifest_unknown_windows() {
+        let mut manifest = CompletionManifest::new();
+        manifest.add_source(
+            0,
+            SourceReport {
+                generators: 1,
+                windows:    None,
+            },
+        );
+        manifest.add_window("0-0");
+        assert_eq!(manifest.expected_windows(), None);
+        assert!(!manifest.is_complete(None));
+        assert!(manifest.is_complete(Some(1)));
+    }
+
+    #[test]
+    fn in_flight_windows_by_logical_id() {
+        let names = [
+            "sources/0",
+            "started/0-0",
+            "started/0-1",
+            "started/0-2",
+            "started/1-0",
+            "windows/0-0",
+            "windows/1-0",
+            // The window completed before its start marker is listed.
+            "windows/1-1",
+        ];
+        assert_eq!(
+            in_flight_window_ids(names),
+            ["0-1", "0-2"].into_iter().collect::<BTreeSet<_>>()
+        );
+        assert!(in_flight_window_ids(["windows/0-0", "windows/0-1"]).is_empty());
+    }
+
+    #[test]
+    fn stamp_logical_window() {
+        let mut metadata = None;
+        stamp_window(&mut metadata, 1, 3);
+        assert_eq!(completion_window(&metadata), None);
+
+        let mut metadata = Some(QueryMetadata::default());
+        stamp_window(&mut metadata, 1, 3);
+        assert_eq!(completion_window(&metadata), None);
+
+        metadata
+            .as_mut()
+            .unwrap()
+            .insert(COMPLETION_METADATA_KEY.to_string(), "true".to_string());
+        stamp_window(&mut metadata, 1, 3);
+        assert_eq!(completion_window(&metadata), Some("1-3"));
+    }
+}
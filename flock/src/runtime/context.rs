@@ -14,23 +14,36 @@
 //! When the lambda function is called for the first time, it deserializes the
 //! corresponding execution context from the cloud environment variable.
 
+use crate::aws::client::{AwsCloudClient, CloudClient};
+use crate::configs::state_bucket_name;
 use crate::datasink::DataSinkType;
-use crate::encoding::Encoding;
+use crate::encoding::{Encoding, StageEncoding};
+use crate::encryption::Encryption;
 use crate::error::{FlockError, Result};
-use crate::runtime::plan::CloudExecutionPlan;
+use crate::runtime::broadcast::BroadcastRole;
+use crate::runtime::dictionary::ZstdDictionary;
+use crate::runtime::distribution::SessionAffinity;
+use crate::runtime::function_name::{query_code_of, FunctionName};
+use crate::runtime::plan::{
+    feed_memory_sources, feed_named_sources, CloudExecutionPlan, FeedReport, PlanProperties,
+};
+use crate::runtime::running_aggregate::RunningAggregate;
+use crate::runtime::source_filter::SourceFilter;
+use crate::runtime::topology::StageTopology;
+use crate::runtime::version::{legacy_format_version, WireFormat, CONTEXT_FORMAT_VERSION};
 use crate::state::*;
-use datafusion::arrow::datatypes::{Schema, SchemaRef};
+use crate::stream::Window;
+use datafusion::arrow::datatypes::SchemaRef;
 use datafusion::arrow::record_batch::RecordBatch;
-use datafusion::physical_plan::coalesce_batches::CoalesceBatchesExec;
 use datafusion::physical_plan::memory::MemoryExec;
-use datafusion::physical_plan::repartition::RepartitionExec;
 use datafusion::physical_plan::ExecutionPlan;
 use datafusion::physical_plan::{collect, collect_partitioned};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::str::FromStr;
 use std::sync::Arc;
 use tokio::task::JoinHandle;
+use tracing::warn;
 
 type CloudFunctionName = String;
 type GroupSize = usize;
@@ -42,10 +55,65 @@ pub struct CloudEnvironment {
     /// Lambda execution context.
     /// `context` is the serialized version of `ExecutionContext`.
     #[serde(with = "serde_bytes")]
-    pub context:  Vec<u8>,
+    pub context:        Vec<u8>,
     /// Compress `ExecutionContext` to guarantee the total size
     /// of all environment variables doesn't exceed 4 KB.
-    pub encoding: Encoding,
+    pub encoding:       Encoding,
+    /// The serialization format of `context`. The environments written before
+    /// the format was recorded are in JSON.
+    #[serde(default = "legacy_context_format")]
+    pub format:         ContextFormat,
+    /// The version of the wire format of the environment (see
+    /// [`crate::runtime::version`]).
+    #[serde(default = "legacy_format_version")]
+    pub format_version: u16,
+}
+
+/// The serialization format of `ExecutionContext` in the cloud environment.
+///
+/// The context holds the trait objects serialized by `typetag`, i.e. the
+/// [`StateBackend`] and the physical plans. Their internally tagged
+/// representation is read back with `deserialize_any`, which only the
+/// self-describing formats support: bincode isn't self-describing and fails to
+/// read them, so the compact format is MessagePack. Its structs are encoded as
+/// maps rather than arrays, so that the fields skipped by the serializers and
+/// the fields added later with `#[serde(default)]` still line up.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub enum ContextFormat {
+    /// JSON, which the environments deployed by the older versions use.
+    Json,
+    /// MessagePack, which the new deployments use by default.
+    MessagePack,
+}
+
+impl Default for ContextFormat {
+    fn default() -> Self {
+        ContextFormat::MessagePack
+    }
+}
+
+fn legacy_context_format() -> ContextFormat {
+    ContextFormat::Json
+}
+
+impl ContextFormat {
+    /// Serializes the context in the format.
+    pub fn encode(&self, ctx: &ExecutionContext) -> Result<Vec<u8>> {
+        match self {
+            ContextFormat::Json => Ok(serde_json::to_vec(ctx)?),
+            ContextFormat::MessagePack => rmp_serde::to_vec_named(ctx)
+                .map_err(|e| FlockError::Internal(format!("Failed to encode the context: {}", e))),
+        }
+    }
+
+    /// Deserializes the context in the format.
+    pub fn decode(&self, bytes: &[u8]) -> Result<ExecutionContext> {
+        match self {
+            ContextFormat::Json => Ok(serde_json::from_slice(bytes)?),
+            ContextFormat::MessagePack => rmp_serde::from_slice(bytes)
+                .map_err(|e| FlockError::Internal(format!("Failed to decode the context: {}", e))),
+        }
+    }
 }
 
 /// The cloud function type.
@@ -98,11 +166,63 @@ impl Default for CloudFunction {
     }
 }
 
+/// When a function writes the payloads of the next stage to the state backend
+/// besides invoking the next function with them.
+///
+/// The aggregate stages read the payloads of a window back from the state
+/// backend if some of them are lost, so their inputs are always written. The
+/// element-wise stages don't need them, and the write only adds latency.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub enum StatePersistence {
+    /// The payloads are written while the next function is invoked.
+    Always,
+    /// The payload is written only if the invocation of the next function
+    /// fails after its retries.
+    OnFailureOnly,
+    /// The payloads are never written.
+    Never,
+}
+
+impl Default for StatePersistence {
+    fn default() -> Self {
+        StatePersistence::Always
+    }
+}
+
+impl StatePersistence {
+    /// Returns the policy of the payloads sent to the stage: `Always` if the
+    /// stage aggregates, joins or sorts its input, and `Never` if it's
+    /// element-wise.
+    pub fn for_stage(plans: &[Arc<dyn ExecutionPlan>]) -> Self {
+        if PlanProperties::analyze_all(plans).is_stateful() {
+            StatePersistence::Always
+        } else {
+            StatePersistence::Never
+        }
+    }
+}
+
+impl FromStr for StatePersistence {
+    type Err = FlockError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(StatePersistence::Always),
+            "on_failure" | "on-failure" | "onfailureonly" => Ok(StatePersistence::OnFailureOnly),
+            "never" => Ok(StatePersistence::Never),
+            _ => Err(FlockError::Execution(format!(
+                "Invalid state persistence: {}. Expected always, on-failure or never",
+                s
+            ))),
+        }
+    }
+}
+
 /// Cloud execution context.
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ExecutionContext {
     /// The execution plan on cloud.
-    pub plan:          CloudExecutionPlan,
+    pub plan:              CloudExecutionPlan,
     /// Cloud Function name in the current execution context.
     ///
     /// |      Cloud Function Naming Convention       |
@@ -123,20 +243,157 @@ pub struct ExecutionContext {
     /// at a certain moment.
     ///
     /// SX72HzqFz1Qij4bP-00-00
-    pub name:          CloudFunctionName,
+    pub name:              CloudFunctionName,
     /// Lambda function name(s) for next invocation(s).
-    pub next:          CloudFunction,
+    pub next:              CloudFunction,
+    /// If true, the payloads to the next functions are sent to their SQS
+    /// queues instead of invoking them (see [`crate::runtime::transport`]).
+    #[serde(default)]
+    pub via_queue:         bool,
     /// The current state of the execution context.
-    pub state_backend: Arc<dyn StateBackend>,
+    pub state_backend:     Arc<dyn StateBackend>,
+    /// The AWS region where the cloud function is deployed. The cloud function
+    /// constructs its service clients for this region even if its default
+    /// region differs. An empty string means the default region.
+    #[serde(default)]
+    pub region:            String,
+    /// The group-by column of the query if its hopping windows are evaluated
+    /// incrementally, i.e. the plan emits the keys with the maximum count (see
+    /// [`argmax_key`](crate::runtime::plan::argmax_key)). `None` means the
+    /// whole window is recomputed every hop.
+    #[serde(default)]
+    pub argmax_key:        Option<String>,
+    /// The window of the query, which the source and the aggregate functions
+    /// read at runtime instead of the window compiled into the function.
+    /// `None` means the function falls back to its own default.
+    #[serde(default)]
+    pub window:            Option<Window>,
+    /// The key columns of the joins and aggregations in the next stage, whose
+    /// distinct counts are estimated in the payload statistics (see
+    /// [`stats_keys`](crate::runtime::plan::stats_keys)).
+    #[serde(default)]
+    pub stats_keys:        Vec<String>,
+    /// The key columns that the output is distributed by to the members of
+    /// the next function group (see [`crate::runtime::distribution`]). Empty
+    /// if the output partitions are sent by their positions.
+    #[serde(default)]
+    pub distribution_keys: Vec<String>,
+    /// The routing of the output to the session windows of the next function
+    /// group by the session key (see [`SessionAffinity`]). `None` if the next
+    /// stage doesn't aggregate session windows.
+    #[serde(default)]
+    pub session_affinity:  Option<SessionAffinity>,
+    /// The role of the function in a broadcast join (see
+    /// [`broadcast`](crate::runtime::broadcast)). `None` means the function
+    /// invokes the next functions with its output as usual.
+    #[serde(default)]
+    pub broadcast:         Option<BroadcastRole>,
+    /// The encryption of the payloads and the state of the query (see
+    /// [`encryption`](crate::encryption)).
+    #[serde(default)]
+    pub encryption:        Encryption,
+    /// The encoding of the payloads to the next functions (see
+    /// [`StageEncoding`]), which the function compresses its output with.
+    #[serde(default)]
+    pub encoding:          StageEncoding,
+    /// The Zstd dictionary of the query, which the function registers before
+    /// it encodes or decodes the payloads (see
+    /// [`crate::runtime::dictionary`]). `None` if the query has no dictionary.
+    #[serde(default)]
+    pub zstd_dict:         Option<ZstdDictionary>,
+    /// If true, the metadata of the Kinesis records are appended to the
+    /// batches of the Kinesis events as columns (see
+    /// [`with_metadata_columns`](crate::datasource::kinesis::with_metadata_columns)).
+    #[serde(default)]
+    pub metadata_columns:  bool,
+    /// If true, the last stage appends the window bounds and the query code to
+    /// its output as columns (see [`crate::datasink::enrich`]).
+    #[serde(default)]
+    pub window_columns:    bool,
+    /// The hash of the plan of the last stage if its output is cached (see
+    /// [`crate::runtime::result_cache`]). `None` means the output of every
+    /// window is computed.
+    #[serde(default)]
+    pub result_cache:      Option<String>,
+    /// When the function writes the payloads of the next stage to the state
+    /// backend (see [`StatePersistence`]).
+    #[serde(default)]
+    pub state_persistence: StatePersistence,
+    /// The running aggregates of the last stage, which are accumulated over
+    /// the windows of the whole run (see
+    /// [`crate::runtime::running_aggregate`]).
+    #[serde(default)]
+    pub running_aggregate: Option<RunningAggregate>,
+    /// The expected upstream stages and input schemas of the function, which
+    /// the received payloads are checked against (see
+    /// [`crate::runtime::topology`]). `None` means any payload is accepted.
+    #[serde(default)]
+    pub topology:          Option<StageTopology>,
+    /// The filters of the first stage that the source function of a stream
+    /// evaluates before it sends the records (see
+    /// [`crate::runtime::source_filter`]). Empty for the other functions.
+    #[serde(default)]
+    pub source_filters:    Vec<SourceFilter>,
+    /// Whether the stage hash partitions its output for the next stage, as
+    /// the DAG builder cut the query (see
+    /// [`crate::distributed_plan::QueryStage::is_shuffle_stage`]). `None`
+    /// means the plans are analyzed instead (see
+    /// [`ExecutionContext::is_shuffling`]).
+    #[serde(default)]
+    pub is_shuffle_stage:  Option<bool>,
+    /// The query code of the multiplexed query that the function serves in
+    /// the invocation (see [`crate::runtime::multiplex`]). The shared function
+    /// is named after the topology of the query, so the objects of the query
+    /// are keyed on this query code instead (see
+    /// [`ExecutionContext::query_code`]). It's set when the context of the
+    /// query is resolved, and isn't serialized.
+    #[serde(skip)]
+    pub query_code:        Option<String>,
+    /// The client of the AWS calls of the function, which is replaced by a
+    /// fake client in the tests. It's not serialized, and the deserialized
+    /// context calls AWS.
+    #[serde(skip, default = "default_cloud_client")]
+    pub cloud_client:      Arc<dyn CloudClient>,
+    /// The properties of the plans, computed on the first call of
+    /// [`ExecutionContext::properties`] and reset when the plans are set. It's
+    /// not serialized.
+    #[serde(skip)]
+    pub properties:        Option<Arc<PlanProperties>>,
+}
+
+fn default_cloud_client() -> Arc<dyn CloudClient> {
+    Arc::new(AwsCloudClient)
 }
 
 impl Default for ExecutionContext {
     fn default() -> Self {
         ExecutionContext {
-            plan:          CloudExecutionPlan::default(),
-            name:          CloudFunctionName::default(),
-            next:          CloudFunction::default(),
-            state_backend: Arc::new(HashMapStateBackend::default()),
+            plan:              CloudExecutionPlan::default(),
+            name:              CloudFunctionName::default(),
+            next:              CloudFunction::default(),
+            via_queue:         false,
+            state_backend:     Arc::new(HashMapStateBackend::default()),
+            region:            String::new(),
+            argmax_key:        None,
+            window:            None,
+            stats_keys:        vec![],
+            distribution_keys: vec![],
+            session_affinity:  None,
+            broadcast:         None,
+            encryption:        Encryption::None,
+            encoding:          StageEncoding::default(),
+            zstd_dict:         None,
+            metadata_columns:  false,
+            window_columns:    false,
+            result_cache:      None,
+            state_persistence: StatePersistence::default(),
+            running_aggregate: None,
+            topology:          None,
+            source_filters:    vec![],
+            is_shuffle_stage:  None,
+            query_code:        None,
+            cloud_client:      default_cloud_client(),
+            properties:        None,
         }
     }
 }
@@ -145,6 +402,25 @@ impl PartialEq for ExecutionContext {
     fn eq(&self, other: &ExecutionContext) -> bool {
         self.name == other.name
             && self.next == other.next
+            && self.via_queue == other.via_queue
+            && self.region == other.region
+            && self.argmax_key == other.argmax_key
+            && self.window == other.window
+            && self.stats_keys == other.stats_keys
+            && self.distribution_keys == other.distribution_keys
+            && self.session_affinity == other.session_affinity
+            && self.broadcast == other.broadcast
+            && self.encryption == other.encryption
+            && self.encoding == other.encoding
+            && self.zstd_dict == other.zstd_dict
+            && self.metadata_columns == other.metadata_columns
+            && self.window_columns == other.window_columns
+            && self.result_cache == other.result_cache
+            && self.state_persistence == other.state_persistence
+            && self.running_aggregate == other.running_aggregate
+            && self.topology == other.topology
+            && self.source_filters == other.source_filters
+            && self.is_shuffle_stage == other.is_shuffle_stage
             && serde_json::to_string(&self.plan).unwrap()
                 == serde_json::to_string(&other.plan).unwrap()
     }
@@ -154,22 +430,51 @@ impl ExecutionContext {
     /// Returns the execution plan of the current execution context.
     ///
     /// if it's `EmptyExec`, the plan is not stored in the environment
-    /// variable. In this case, we need to load the plan from S3. If the
-    /// plan is already loaded, then we don't need to load it again.
-    pub async fn plan(&mut self) -> Result<Vec<Arc<dyn ExecutionPlan>>> {
+    /// variable, and it must be loaded from S3 by [`Self::load_plan`] or
+    /// [`Self::properties`] first. The plans are fed and executed in place, so
+    /// the context shared by the invocations of a function never loads them
+    /// again.
+    pub async fn plan(&self) -> Result<Vec<Arc<dyn ExecutionPlan>>> {
+        self.plan.loaded_execution_plans()
+    }
+
+    /// Loads the execution plan from S3 if it's not loaded yet.
+    pub async fn load_plan(&mut self) -> Result<Vec<Arc<dyn ExecutionPlan>>> {
         self.plan.get_execution_plans().await
     }
 
     /// Sets the execution plan of the current execution context.
     pub async fn set_plan(&mut self, plan: CloudExecutionPlan) {
         self.plan = plan;
+        self.properties = None;
+    }
+
+    /// Returns the properties of the plans, which are computed once and
+    /// cached in the context. The plans are loaded from S3 if they are not
+    /// loaded yet.
+    pub async fn properties(&mut self) -> Result<Arc<PlanProperties>> {
+        if let Some(properties) = &self.properties {
+            return Ok(properties.clone());
+        }
+        let properties = Arc::new(PlanProperties::analyze_all(&self.load_plan().await?));
+        self.properties = Some(properties.clone());
+        Ok(properties)
+    }
+
+    /// Returns the properties of the plans cached in the context, or analyzes
+    /// the loaded plans if they aren't cached yet (see [`Self::properties`]).
+    pub async fn plan_properties(&self) -> Result<Arc<PlanProperties>> {
+        match &self.properties {
+            Some(properties) => Ok(properties.clone()),
+            None => Ok(Arc::new(PlanProperties::analyze_all(&self.plan().await?))),
+        }
     }
 
     /// Executes the physical plan.
     ///
     /// `execute` must be called after the execution of `feed_one_source` or
     /// `feed_two_source` or `feed_data_sources`.
-    pub async fn execute(&mut self) -> Result<Vec<Vec<RecordBatch>>> {
+    pub async fn execute(&self) -> Result<Vec<Vec<RecordBatch>>> {
         let tasks = self
             .plan()
             .await?
@@ -194,7 +499,7 @@ impl ExecutionContext {
     ///
     /// `execute_partitioned` must be called after the execution of
     /// `feed_one_source` or `feed_two_source` or `feed_data_sources`.
-    pub async fn execute_partitioned(&mut self) -> Result<Vec<Vec<Vec<RecordBatch>>>> {
+    pub async fn execute_partitioned(&self) -> Result<Vec<Vec<Vec<RecordBatch>>>> {
         let tasks = self
             .plan()
             .await?
@@ -219,12 +524,12 @@ impl ExecutionContext {
     ///
     /// # Arguments
     /// * `index` - The index of the subplan.
-    pub async fn schema(&mut self, index: usize) -> Result<SchemaRef> {
-        Ok(self.plan.get_execution_plans().await?[index].schema())
+    pub async fn schema(&self, index: usize) -> Result<SchemaRef> {
+        Ok(self.plan().await?[index].schema())
     }
 
     /// Clean the data source in the given context.
-    pub async fn clean_data_sources(&mut self) -> Result<()> {
+    pub async fn clean_data_sources(&self) -> Result<()> {
         // Breadth-first search
         let mut queue = VecDeque::new();
         self.plan().await?.into_iter().for_each(|plan| {
@@ -253,87 +558,60 @@ impl ExecutionContext {
         Ok(())
     }
 
-    /// Feeds all data sources to the execution plan.
+    /// Feeds all data sources to the execution plan. The leaves without a
+    /// matching data source are fed with empty partitions.
+    ///
+    /// Returns which data source fed which leaf (see [`feed_memory_sources`]).
     pub async fn feed_data_sources(
-        &mut self,
-        mut sources: Vec<Vec<Vec<RecordBatch>>>,
-    ) -> Result<()> {
-        // Breadth-first search
-        let mut queue = VecDeque::new();
-        self.plan().await?.into_iter().for_each(|plan| {
-            queue.push_back(plan);
-        });
+        &self,
+        sources: Vec<Vec<Vec<RecordBatch>>>,
+    ) -> Result<FeedReport> {
+        feed_memory_sources(self.plan().await?, sources)
+    }
 
-        let num_partitions = sources[0].len();
-        let mut found = false;
-        let mut index = 0xFFFFFFFF;
-        while !queue.is_empty() {
-            let mut plan = queue.pop_front().unwrap();
-            if plan.children().is_empty() {
-                for (i, partition) in sources.iter().enumerate() {
-                    let mut schema = Arc::new(Schema::new(vec![]));
-                    let mut flag = false;
-                    for p in partition.iter().filter(|p| !p.is_empty()) {
-                        if let Some(b) = p.iter().next() {
-                            schema = b.schema();
-                            flag = true;
-                            break;
-                        }
-                    }
-                    if !flag {
-                        continue;
-                    }
-
-                    if compare_schema(plan.schema(), schema) {
-                        index = i;
-                        found = true;
-                        break;
-                    }
-                }
+    /// Feeds all data sources with the given stream names to the execution
+    /// plan (see [`feed_named_sources`]).
+    pub async fn feed_named_data_sources(
+        &self,
+        sources: Vec<Vec<Vec<RecordBatch>>>,
+        names: &[Option<String>],
+    ) -> Result<FeedReport> {
+        feed_named_sources(self.plan().await?, sources, names)
+    }
 
-                if found {
-                    unsafe {
-                        Arc::get_mut_unchecked(&mut plan)
-                            .as_mut_any()
-                            .downcast_mut::<MemoryExec>()
-                            .unwrap()
-                            .set_partitions(sources.remove(index));
-                        index = 0xFFFFFFFF;
-                        found = false;
-                    }
-                } else {
-                    let batches = (0..num_partitions)
-                        .map(|_| RecordBatch::new_empty(plan.schema()))
-                        .collect::<Vec<RecordBatch>>();
-                    unsafe {
-                        Arc::get_mut_unchecked(&mut plan)
-                            .as_mut_any()
-                            .downcast_mut::<MemoryExec>()
-                            .unwrap()
-                            .set_partitions(vec![batches]);
-                    }
+    /// Checks whether the execution plan needs to be shuffled. The flag set by
+    /// the DAG builder wins, and the analysis of the plans is only a fallback,
+    /// which is logged if it disagrees with the flag.
+    pub async fn is_shuffling(&self) -> Result<bool> {
+        assert!(!self.plan.execution_plans.is_empty());
+        let detected = match &self.properties {
+            Some(properties) => properties.is_shuffling,
+            None => PlanProperties::analyze_all(&self.plan.execution_plans).is_shuffling,
+        };
+        match self.is_shuffle_stage {
+            Some(expected) => {
+                if expected != detected {
+                    warn!(
+                        "The stage {} is built as {}a shuffle stage, but its plans {}shuffle.",
+                        self.name,
+                        if expected { "" } else { "not " },
+                        if detected { "" } else { "don't " },
+                    );
                 }
+                Ok(expected)
             }
-
-            plan.children()
-                .iter()
-                .enumerate()
-                .for_each(|(i, _)| queue.push_back(plan.children()[i].clone()));
+            None => Ok(detected),
         }
-
-        Ok(())
     }
 
-    /// Checks whether the execution plan needs to be shuffled.
-    pub async fn is_shuffling(&self) -> Result<bool> {
-        assert!(!self.plan.execution_plans.is_empty());
-        Ok(self.plan.execution_plans.iter().all(|p| {
-            p.as_any().downcast_ref::<CoalesceBatchesExec>().is_some()
-                && !p.children().is_empty()
-                && p.children()
-                    .iter()
-                    .all(|c| c.as_any().downcast_ref::<RepartitionExec>().is_some())
-        }))
+    /// Returns the number of the partitions that the stage shuffles its output
+    /// to, or `None` if its plans don't shuffle (see
+    /// [`crate::runtime::plan::shuffle_partitions`]).
+    pub fn shuffle_partitions(&self) -> Option<usize> {
+        match &self.properties {
+            Some(properties) => properties.shuffle_partitions,
+            None => PlanProperties::analyze_all(&self.plan.execution_plans).shuffle_partitions,
+        }
     }
 
     /// Checks whether the execution plan is the last one.
@@ -344,6 +622,37 @@ impl ExecutionContext {
         }
     }
 
+    /// Returns the S3 bucket name of the state backend for the given query id.
+    ///
+    /// If the region is specified, the bucket name is `<query id>-<region>`
+    /// to avoid collisions when the same query runs in multiple regions.
+    pub fn state_bucket(&self, qid: &str) -> String {
+        state_bucket_name(qid, &self.region)
+    }
+
+    /// Returns the query code that the objects of the query in S3 are keyed on,
+    /// i.e. the query code of the multiplexed query if the function serves
+    /// one, or else the query code of the function name.
+    pub fn query_code(&self) -> &str {
+        self.query_code
+            .as_deref()
+            .unwrap_or_else(|| query_code_of(&self.name))
+    }
+
+    /// Returns the function name under the query code of the query (see
+    /// [`ExecutionContext::query_code`]), e.g. `q1-01-00` for the shared
+    /// function `mux_<signature>-01-00` serving the query `q1`.
+    pub fn logical_name(&self) -> String {
+        match &self.query_code {
+            Some(query_code) => format!(
+                "{}{}",
+                query_code,
+                &self.name[query_code_of(&self.name).len()..]
+            ),
+            None => self.name.clone(),
+        }
+    }
+
     /// Check the current function type.
     ///
     /// If the function name is "<query code>-<plan index>-<group index>",
@@ -351,32 +660,51 @@ impl ExecutionContext {
     /// If the function name is "<query code>-<plan index>",
     /// then it is a lambda-type function.
     pub fn is_aggregate(&self) -> bool {
-        let dash_count = self.name.matches('-').count();
-        if dash_count == 2 {
-            true
-        } else if dash_count == 1 {
-            false
-        } else {
-            panic!("Invalid function name: {}", self.name);
+        match FunctionName::parse(&self.name) {
+            Ok(name) => name.is_group_member(),
+            Err(e) => panic!("{}", e),
         }
     }
+
+    /// Returns true if the function takes the fast path of the element-wise
+    /// windows. Each payload of an element-wise query is independent, so the
+    /// stage that neither aggregates nor feeds a function group executes the
+    /// payload and forwards its output right away, without the arena, the
+    /// processed windows and the hash ring.
+    pub fn is_pipelined(&self) -> bool {
+        self.window == Some(Window::ElementWise)
+            && self.broadcast.is_none()
+            && !matches!(self.next, CloudFunction::Group(_))
+            && !self.is_aggregate()
+    }
 }
 
-/// Serializes `ExecutionContext` from client-side.
+/// Serializes `ExecutionContext` from client-side in the default format.
 pub fn marshal(ctx: &ExecutionContext, encoding: Encoding) -> Result<String> {
+    marshal_with_format(ctx, encoding, ContextFormat::default())
+}
+
+/// Serializes `ExecutionContext` from client-side in the given format.
+pub fn marshal_with_format(
+    ctx: &ExecutionContext,
+    encoding: Encoding,
+    format: ContextFormat,
+) -> Result<String> {
+    let encoded = format.encode(ctx)?;
+    // The codecs that are not compiled into the binary fail to compress.
     Ok(match encoding {
-        Encoding::Snappy | Encoding::Lz4 | Encoding::Zstd => {
-            let encoded: Vec<u8> = serde_json::to_vec(ctx)?;
-            serde_json::to_string(&CloudEnvironment {
-                context: encoding.compress(&encoded)?,
-                encoding,
-            })?
-        }
         Encoding::None => serde_json::to_string(&CloudEnvironment {
-            context: serde_json::to_vec(ctx)?,
+            context: encoded,
             encoding,
+            format,
+            format_version: CONTEXT_FORMAT_VERSION,
+        })?,
+        _ => serde_json::to_string(&CloudEnvironment {
+            context: encoding.compress(&encoded)?,
+            encoding,
+            format,
+            format_version: CONTEXT_FORMAT_VERSION,
         })?,
-        _ => unimplemented!(),
     })
 }
 
@@ -385,45 +713,34 @@ pub fn unmarshal<T>(encoded_ctx: T) -> Result<ExecutionContext>
 where
     T: AsRef<str>,
 {
-    let env: CloudEnvironment = serde_json::from_str(encoded_ctx.as_ref())?;
-
-    Ok(match env.encoding {
-        Encoding::Snappy | Encoding::Lz4 | Encoding::Zstd => {
+    let bytes = encoded_ctx.as_ref().as_bytes();
+    let env: CloudEnvironment = serde_json::from_slice(bytes)
+        .map_err(|e| WireFormat::CloudEnvironment.decode_error(bytes, e.into()))?;
+    WireFormat::CloudEnvironment.check(env.format_version)?;
+
+    match env.encoding {
+        Encoding::None => env.format.decode(&env.context),
+        _ => {
             let encoded = env.encoding.decompress(&env.context)?;
-            serde_json::from_slice(&encoded)?
+            env.format.decode(&encoded)
         }
-        Encoding::None => serde_json::from_slice(&env.context)?,
-        _ => unimplemented!(),
-    })
-}
-
-/// Compare two execution plans' schemas.
-/// Returns true if they are belong to the same plan node.
-fn compare_schema(schema1: SchemaRef, schema2: SchemaRef) -> bool {
-    let (superset, subset) = if schema1.fields().len() >= schema2.fields().len() {
-        (schema1, schema2)
-    } else {
-        (schema2, schema1)
-    };
-
-    let fields = superset
-        .fields()
-        .iter()
-        .map(|f| f.name())
-        .collect::<HashSet<_>>();
-
-    subset.fields().iter().all(|f| fields.contains(&f.name()))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::assert_batches_eq;
+    use crate::distributed_plan::QueryDag;
     use crate::error::Result;
+    use crate::stream::Schedule;
+    use crate::tests::arbitrary;
+    use daggy::NodeIndex;
     use datafusion::arrow::array::*;
     use datafusion::arrow::datatypes::{DataType, Field, Schema};
     use datafusion::arrow::record_batch::RecordBatch;
     use datafusion::datasource::MemTable;
+    use proptest::prelude::*;
 
     #[tokio::test]
     async fn feed_one_data_source() -> Result<()> {
@@ -480,7 +797,7 @@ mod tests {
         let plan: Arc<dyn ExecutionPlan> = serde_json::from_str(&plan)?;
 
         // Feed record batches back to the plan
-        let mut ctx = ExecutionContext {
+        let ctx = ExecutionContext {
             plan: CloudExecutionPlan::new(vec![plan], None),
             name: "test".to_string(),
             next: CloudFunction::Sink(DataSinkType::Blackhole),
@@ -561,7 +878,7 @@ mod tests {
         let plan: Arc<dyn ExecutionPlan> = serde_json::from_str(&plan)?;
 
         // Feed record batches back to the plan
-        let mut ctx = ExecutionContext {
+        let ctx = ExecutionContext {
             plan: CloudExecutionPlan::new(vec![plan], None),
             name: "test".to_string(),
             next: CloudFunction::Sink(DataSinkType::Blackhole),
@@ -590,4 +907,386 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn feed_multi_plan_stages() -> Result<()> {
+        let schema1 = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Utf8, false),
+            Field::new("b", DataType::Int32, false),
+        ]));
+        let schema2 = Arc::new(Schema::new(vec![
+            Field::new("c", DataType::Utf8, false),
+            Field::new("d", DataType::Int32, false),
+        ]));
+
+        // define data.
+        let batch1 = RecordBatch::try_new(
+            schema1.clone(),
+            vec![
+                Arc::new(StringArray::from(vec!["a", "b", "c", "d"])),
+                Arc::new(Int32Array::from(vec![1, 10, 10, 100])),
+            ],
+        )?;
+        // define data.
+        let batch2 = RecordBatch::try_new(
+            schema2.clone(),
+            vec![
+                Arc::new(StringArray::from(vec!["a", "b", "c", "d"])),
+                Arc::new(Int32Array::from(vec![1, 10, 10, 100])),
+            ],
+        )?;
+
+        let mut ctx = datafusion::execution::context::ExecutionContext::new();
+
+        let table1 =
+            MemTable::try_new(schema1.clone(), vec![vec![RecordBatch::new_empty(schema1)]])?;
+        let table2 =
+            MemTable::try_new(schema2.clone(), vec![vec![RecordBatch::new_empty(schema2)]])?;
+
+        ctx.register_table("t1", Arc::new(table1))?;
+        ctx.register_table("t2", Arc::new(table2))?;
+
+        let sql = concat!(
+            "SELECT a, MAX(b) FROM t1 GROUP BY a ",
+            "UNION ALL ",
+            "SELECT c, d FROM t2"
+        );
+
+        let logical_plan = ctx.create_logical_plan(sql)?;
+        let logical_plan = ctx.optimize(&logical_plan)?;
+        let physical_plan = ctx.create_physical_plan(&logical_plan).await?;
+
+        // The union stage and the upstream stages with one subplan per input.
+        let dag = QueryDag::from(physical_plan)?;
+        assert_eq!(3, dag.node_count());
+
+        let union_ctx = ExecutionContext {
+            plan: CloudExecutionPlan::new(dag.get_node(NodeIndex::new(0)).unwrap().to_vec(), None),
+            name: "test-00".to_string(),
+            next: CloudFunction::Sink(DataSinkType::Blackhole),
+            ..Default::default()
+        };
+        let final_ctx = ExecutionContext {
+            plan: CloudExecutionPlan::new(dag.get_node(NodeIndex::new(1)).unwrap().to_vec(), None),
+            name: "test-01".to_string(),
+            next: CloudFunction::Lambda("test-00".to_string()),
+            ..Default::default()
+        };
+        let input_ctx = ExecutionContext {
+            plan: CloudExecutionPlan::new(dag.get_node(NodeIndex::new(2)).unwrap().to_vec(), None),
+            name: "test-02".to_string(),
+            next: CloudFunction::Lambda("test-01".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(2, final_ctx.plan().await?.len());
+        assert_eq!(2, input_ctx.plan().await?.len());
+
+        for encoding in [Encoding::default(), Encoding::None] {
+            assert_eq!(
+                union_ctx,
+                unmarshal(&marshal(&union_ctx, encoding.clone())?)?
+            );
+            assert_eq!(
+                final_ctx,
+                unmarshal(&marshal(&final_ctx, encoding.clone())?)?
+            );
+            assert_eq!(input_ctx, unmarshal(&marshal(&input_ctx, encoding)?)?);
+        }
+
+        // The data sources are assigned to the subplans by their schemas.
+        input_ctx
+            .feed_data_sources(vec![vec![vec![batch2]], vec![vec![batch1]]])
+            .await?;
+        let mut outputs = input_ctx.execute().await?;
+        assert_eq!(2, outputs.len());
+
+        // The partial aggregates are merged, and the other input passes through.
+        let right = outputs.pop().unwrap();
+        let left = outputs.pop().unwrap();
+        final_ctx
+            .feed_data_sources(vec![vec![right], vec![left]])
+            .await?;
+        let mut outputs = final_ctx.execute().await?;
+        assert_eq!(2, outputs.len());
+
+        let right = outputs.pop().unwrap();
+        let left = outputs.pop().unwrap();
+        union_ctx
+            .feed_data_sources(vec![vec![right], vec![left]])
+            .await?;
+        let batches = union_ctx.execute().await?;
+
+        let expected = vec![
+            "+---+-----------+",
+            "| a | MAX(t1.b) |",
+            "+---+-----------+",
+            "| a | 1         |",
+            "| a | 1         |",
+            "| b | 10        |",
+            "| b | 10        |",
+            "| c | 10        |",
+            "| c | 10        |",
+            "| d | 100       |",
+            "| d | 100       |",
+            "+---+-----------+",
+        ];
+
+        crate::assert_batches_sorted_eq!(&expected, &batches[0]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn marshal_context_with_region() -> Result<()> {
+        let ctx = ExecutionContext {
+            name: "q4-00".to_string(),
+            region: "eu-central-1".to_string(),
+            ..Default::default()
+        };
+
+        for encoding in [Encoding::Zstd, Encoding::None] {
+            let de_ctx = unmarshal(&marshal(&ctx, encoding)?)?;
+            assert_eq!(ctx, de_ctx);
+            assert_eq!(de_ctx.region, "eu-central-1");
+            assert_eq!(
+                de_ctx.state_bucket("q4-1642991536-2187351285"),
+                "q4-1642991536-2187351285-eu-central-1"
+            );
+        }
+
+        // The region is optional in the serialized context.
+        let mut value = serde_json::to_value(&ctx)?;
+        value.as_object_mut().unwrap().remove("region");
+        let de_ctx: ExecutionContext = serde_json::from_value(value)?;
+        assert!(de_ctx.region.is_empty());
+        assert_eq!(de_ctx.state_bucket("q4-1642991536"), "q4-1642991536");
+
+        Ok(())
+    }
+
+    #[test]
+    fn query_code_of_multiplexed_query() -> Result<()> {
+        let mut ctx = ExecutionContext {
+            name: "mux_0123456789abcdef-01-02".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(ctx.query_code(), "mux_0123456789abcdef");
+        assert_eq!(ctx.logical_name(), ctx.name);
+
+        // The objects of the multiplexed query are keyed on its own query code.
+        ctx.query_code = Some("q3".to_string());
+        assert_eq!(ctx.query_code(), "q3");
+        assert_eq!(ctx.logical_name(), "q3-01-02");
+
+        // The query code is set at resolution, and isn't serialized.
+        let de_ctx = unmarshal(&marshal(&ctx, Encoding::None)?)?;
+        assert_eq!(de_ctx.query_code, None);
+        assert_eq!(de_ctx.name, ctx.name);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prefer_shuffle_flag_of_dag() -> Result<()> {
+        use crate::runtime::plan::physical_plan;
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Utf8, false),
+            Field::new("b", DataType::Int32, false),
+        ]));
+        let mut df_ctx = datafusion::execution::context::ExecutionContext::new();
+        let table = MemTable::try_new(schema.clone(), vec![vec![RecordBatch::new_empty(schema)]])?;
+        df_ctx.register_table("t", Arc::new(table))?;
+        let plan = physical_plan(&df_ctx, "SELECT a, SUM(b) FROM t GROUP BY a").await?;
+        let dag = QueryDag::from(plan)?;
+
+        let stage_context = |i: usize, is_shuffle_stage: Option<bool>| {
+            let stage = dag.get_node(NodeIndex::new(i)).unwrap();
+            ExecutionContext {
+                plan: CloudExecutionPlan::new(stage.stage.clone(), None),
+                is_shuffle_stage,
+                ..Default::default()
+            }
+        };
+        // The partial aggregate stage shuffles, and the final one doesn't.
+        for (i, shuffles) in [(0, false), (1, true)] {
+            assert_eq!(
+                dag.get_node(NodeIndex::new(i)).unwrap().is_shuffle_stage,
+                shuffles
+            );
+            assert_eq!(stage_context(i, None).is_shuffling().await?, shuffles);
+            assert_eq!(
+                stage_context(i, Some(shuffles)).is_shuffling().await?,
+                shuffles
+            );
+        }
+        assert!(stage_context(1, None).shuffle_partitions().is_some());
+
+        // The flag of the DAG builder wins over the analysis of the plans.
+        assert!(!stage_context(1, Some(false)).is_shuffling().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn state_persistence_of_stages() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Utf8, false),
+            Field::new("b", DataType::Int32, false),
+        ]));
+        let mut df_ctx = datafusion::execution::context::ExecutionContext::new();
+        let table = MemTable::try_new(schema.clone(), vec![vec![RecordBatch::new_empty(schema)]])?;
+        df_ctx.register_table("t", Arc::new(table))?;
+
+        for (sql, expected) in [
+            (
+                "SELECT a, MAX(b) FROM t GROUP BY a",
+                StatePersistence::Always,
+            ),
+            ("SELECT a FROM t ORDER BY b", StatePersistence::Always),
+            ("SELECT a, b FROM t WHERE b > 10", StatePersistence::Never),
+        ] {
+            let logical_plan = df_ctx.create_logical_plan(sql)?;
+            let logical_plan = df_ctx.optimize(&logical_plan)?;
+            let plan = df_ctx.create_physical_plan(&logical_plan).await?;
+            assert_eq!(StatePersistence::for_stage(&[plan]), expected);
+        }
+
+        assert_eq!(
+            "on-failure".parse::<StatePersistence>()?,
+            StatePersistence::OnFailureOnly
+        );
+        assert!("sometimes".parse::<StatePersistence>().is_err());
+
+        // The contexts deployed before the policy was recorded always write the
+        // payloads.
+        let ctx = ExecutionContext {
+            name: "q4-00".to_string(),
+            state_persistence: StatePersistence::Never,
+            ..Default::default()
+        };
+        let mut value = serde_json::to_value(&ctx)?;
+        value.as_object_mut().unwrap().remove("state_persistence");
+        let de_ctx: ExecutionContext = serde_json::from_value(value)?;
+        assert_eq!(de_ctx.state_persistence, StatePersistence::Always);
+        Ok(())
+    }
+
+    #[cfg(feature = "nexmark")]
+    #[tokio::test]
+    async fn context_formats_of_nexmark_q4() -> Result<()> {
+        use crate::datasource::nexmark::event::{Auction, Bid};
+        use std::time::Instant;
+
+        let mut df_ctx = datafusion::execution::context::ExecutionContext::new();
+        for (name, schema) in [("auction", Auction::schema()), ("bid", Bid::schema())] {
+            let schema = Arc::new(schema);
+            let table =
+                MemTable::try_new(schema.clone(), vec![vec![RecordBatch::new_empty(schema)]])?;
+            df_ctx.register_table(name, Arc::new(table))?;
+        }
+        let sql = concat!(
+            "SELECT category, Avg(final) FROM (",
+            "SELECT Max(price) AS final, category FROM auction ",
+            "INNER JOIN bid ON a_id = auction ",
+            "WHERE b_date_time BETWEEN a_date_time AND expires ",
+            "GROUP BY a_id, category) AS Q ",
+            "GROUP BY category"
+        );
+        let logical_plan = df_ctx.create_logical_plan(sql)?;
+        let logical_plan = df_ctx.optimize(&logical_plan)?;
+        let physical_plan = df_ctx.create_physical_plan(&logical_plan).await?;
+        let ctx = ExecutionContext {
+            plan: CloudExecutionPlan::new(vec![physical_plan], None),
+            name: "q4-00".to_string(),
+            next: CloudFunction::Group(("q4-01".to_string(), 8)),
+            ..Default::default()
+        };
+
+        let rounds = 20;
+        let mut sizes = vec![];
+        for format in [ContextFormat::Json, ContextFormat::MessagePack] {
+            let raw = format.encode(&ctx)?.len();
+            let encoded = marshal_with_format(&ctx, Encoding::Zstd, format)?;
+            assert_eq!(ctx, unmarshal(&encoded)?);
+            let start = Instant::now();
+            for _ in 0..rounds {
+                unmarshal(&encoded)?;
+            }
+            println!(
+                "{:?}: {} bytes, {} bytes in the environment, {:?} per unmarshal",
+                format,
+                raw,
+                encoded.len(),
+                start.elapsed() / rounds
+            );
+            sizes.push(raw);
+        }
+        assert!(sizes[1] < sizes[0]);
+
+        // The environments written before the format was recorded are in JSON.
+        let mut env: serde_json::Value = serde_json::from_str(&marshal_with_format(
+            &ctx,
+            Encoding::None,
+            ContextFormat::Json,
+        )?)?;
+        env.as_object_mut().unwrap().remove("format");
+        assert_eq!(ctx, unmarshal(env.to_string())?);
+        Ok(())
+    }
+
+    fn arb_next() -> impl Strategy<Value = CloudFunction> {
+        prop_oneof![
+            "[a-z]{1,8}-0[0-9]".prop_map(CloudFunction::Lambda),
+            ("[a-z]{1,8}-0[0-9]", 1..16usize).prop_map(CloudFunction::Group),
+            Just(CloudFunction::Sink(DataSinkType::Blackhole)),
+        ]
+    }
+
+    fn arb_window() -> impl Strategy<Value = Option<Window>> {
+        prop_oneof![
+            Just(None),
+            (1..3600usize).prop_map(|s| Some(Window::Tumbling(Schedule::Seconds(s)))),
+            (1..3600usize, 1..60usize).prop_map(|w| Some(Window::Hopping(w))),
+            (1..3600usize, 1..60usize).prop_map(|w| Some(Window::Sliding(w))),
+        ]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn marshal_random_contexts(
+            name in "[a-z]{1,8}-0[0-9]",
+            next in arb_next(),
+            region in "[a-z]{2}-[a-z]{4,9}-[1-3]",
+            argmax_key in proptest::option::of("[a-z_]{1,12}"),
+            stats_keys in proptest::collection::vec("[a-z_]{1,12}", 0..4),
+            metadata_columns in any::<bool>(),
+            window in arb_window(),
+            encoding in arbitrary::arb_encoding(),
+            json in any::<bool>(),
+        ) {
+            let ctx = ExecutionContext {
+                name,
+                next,
+                region,
+                argmax_key,
+                stats_keys,
+                metadata_columns,
+                window,
+                ..Default::default()
+            };
+            let format = if json { ContextFormat::Json } else { ContextFormat::MessagePack };
+            let encoded = marshal_with_format(&ctx, encoding, format).unwrap();
+            prop_assert_eq!(ctx, unmarshal(&encoded).unwrap());
+        }
+    }
+
+    #[test]
+    fn marshal_with_unsupported_encoding() {
+        let ctx = ExecutionContext::default();
+        assert!(matches!(
+            marshal(&ctx, Encoding::Zlib),
+            Err(FlockError::NotImplemented(_))
+        ));
+    }
 }
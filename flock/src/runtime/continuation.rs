@@ -0,0 +1,340 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The continuations of the data source functions.
+//!
+//! A data source function emits the windows of the whole run within a single
+//! invocation, so the length of a run is capped by the Lambda timeout. With the
+//! continuations, the data source function emits at most a fixed number of
+//! windows per invocation, and then asynchronously invokes itself to resume
+//! from the next window before it returns. The continuation payload carries:
+//!
+//! - the window to resume from, under the same metadata key as the rescheduled
+//!   data sources (see [`crate::runtime::backpressure`]). The NEXMark
+//!   generators start at the first epoch of the window;
+//! - the data source of the run, i.e. the number of seconds and the seed, so
+//!   the events of the window are the same as in a single invocation;
+//! - the uuid of the first invocation, after which the windows are named;
+//! - the number of the invocation in the chain, which stops the chain after the
+//!   maximum number of invocations to guard against runaway loops.
+//!
+//! The data source functions report the number of windows they emit to the
+//! completion protocol (see [`crate::runtime::completion`]). If the chain is
+//! cut short by the maximum number of invocations, the last invocation
+//! overwrites the report with the number of windows actually emitted.
+
+use crate::aws::lambda;
+use crate::configs::*;
+use crate::error::Result;
+use crate::runtime::backpressure::{resume_window, RESUME_WINDOW_METADATA_KEY};
+use crate::runtime::metadata::QueryMetadata;
+use crate::runtime::payload::Payload;
+
+/// The metadata key of the number of the data source function's invocation in
+/// the chain of continuations, starting from 0.
+pub const CONTINUATION_METADATA_KEY: &str = "continuation";
+
+/// The metadata key of the window at which the invocation of the data source
+/// function continues. It is carried over to the invocations rescheduled by
+/// the backpressure, so they keep the windows of the invocation.
+pub const CONTINUATION_END_METADATA_KEY: &str = "continuation_end";
+
+/// What the data source function does at a window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// The window is emitted by the invocation.
+    Emit,
+    /// The invocation invokes the data source function to resume from the
+    /// window, and returns.
+    Continue,
+    /// The chain has reached the maximum number of invocations, and the data
+    /// source stops before the window.
+    Stop,
+}
+
+/// The continuation state of an invocation of the data source function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Continuation {
+    windows_per_invocation: usize,
+    max_invocations:        usize,
+    invocation:             usize,
+    end:                    usize,
+    handover:               bool,
+}
+
+impl Continuation {
+    /// Creates the continuation state of the invocation from its payload
+    /// metadata.
+    ///
+    /// # Arguments
+    /// * `windows_per_invocation` - The number of windows emitted by each
+    ///   invocation. 0 disables the continuations.
+    /// * `max_invocations` - The maximum number of invocations of the chain.
+    /// * `metadata` - The metadata of the data source function's payload.
+    pub fn new(
+        windows_per_invocation: usize,
+        max_invocations: usize,
+        metadata: &Option<QueryMetadata>,
+    ) -> Self {
+        let get = |key: &str| {
+            metadata
+                .as_ref()
+                .and_then(|m| m.get(key))
+                .and_then(|v| v.parse::<usize>().ok())
+        };
+        let invocation = get(CONTINUATION_METADATA_KEY).unwrap_or(0);
+        let end = get(CONTINUATION_END_METADATA_KEY);
+        Self {
+            windows_per_invocation,
+            max_invocations: max_invocations.max(1),
+            invocation,
+            end: end
+                .unwrap_or_else(|| resume_window(metadata).unwrap_or(0) + windows_per_invocation),
+            // The continuation payloads don't carry the end of the previous
+            // invocation, unlike the rescheduled ones.
+            handover: invocation > 0 && end.is_none(),
+        }
+    }
+
+    /// Creates the continuation state with the settings in
+    /// `FLOCK_CONF["lambda"]`.
+    pub fn from_conf(metadata: &Option<QueryMetadata>) -> Self {
+        Self::new(
+            *FLOCK_SOURCE_WINDOWS_PER_INVOCATION,
+            *FLOCK_SOURCE_MAX_INVOCATIONS,
+            metadata,
+        )
+    }
+
+    /// Returns true if the continuations are enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.windows_per_invocation > 0
+    }
+
+    /// Returns the number of the invocation in the chain, starting from 0.
+    pub fn invocation(&self) -> usize {
+        self.invocation
+    }
+
+    /// Returns true if the invocation was started by the previous invocation
+    /// of the chain, rather than rescheduled by the backpressure.
+    pub fn is_handover(&self) -> bool {
+        self.handover
+    }
+
+    /// Returns true if the invocation is the last one that the chain allows.
+    fn is_last(&self) -> bool {
+        self.invocation + 1 >= self.max_invocations
+    }
+
+    /// Returns what the data source function does at the window.
+    pub fn step(&self, window: usize) -> Step {
+        if !self.is_enabled() || window < self.end {
+            Step::Emit
+        } else if self.is_last() {
+            Step::Stop
+        } else {
+            Step::Continue
+        }
+    }
+
+    /// Returns the number of windows that the chain emits in total, given the
+    /// number of windows of the run. The last invocation stops at its end.
+    pub fn total_windows(&self, windows: Option<usize>) -> Option<usize> {
+        if self.is_enabled() && self.is_last() {
+            windows.map(|w| w.min(self.end))
+        } else {
+            windows
+        }
+    }
+
+    /// Records the end of the invocation in the payload metadata, so that the
+    /// invocations rescheduled by the backpressure keep it.
+    pub fn stamp(&self, metadata: &mut Option<QueryMetadata>) {
+        if self.is_enabled() {
+            metadata.get_or_insert_with(QueryMetadata::default).insert(
+                CONTINUATION_END_METADATA_KEY.to_string(),
+                self.end.to_string(),
+            );
+        }
+    }
+
+    /// Returns the payload of the next invocation of the chain, which resumes
+    /// from the window.
+    pub fn payload(&self, payload: &Payload, window: usize) -> Payload {
+        let mut payload = payload.clone();
+        let metadata = payload.metadata.get_or_insert_with(QueryMetadata::default);
+        metadata.insert(RESUME_WINDOW_METADATA_KEY.to_string(), window.to_string());
+        metadata.insert(
+            CONTINUATION_METADATA_KEY.to_string(),
+            (self.invocation + 1).to_string(),
+        );
+        metadata.remove(CONTINUATION_END_METADATA_KEY);
+        payload
+    }
+
+    /// Invokes the data source function to resume from the window.
+    ///
+    /// # Arguments
+    /// * `function_name` - The name of the data source function.
+    /// * `payload` - The payload of the current invocation.
+    /// * `window` - The window to resume from.
+    pub async fn invoke(
+        &self,
+        function_name: &str,
+        payload: &Payload,
+        window: usize,
+    ) -> Result<()> {
+        lambda::invoke_function(
+            function_name,
+            &FLOCK_LAMBDA_ASYNC_CALL,
+            Some(serde_json::to_vec(&self.payload(payload, window))?.into()),
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasink::results::window_id;
+    use crate::datasource::nexmark::{NEXMarkLazyStream, NEXMarkSource};
+    use crate::datasource::DataStream;
+    use crate::runtime::payload::UuidBuilder;
+    use crate::stream::{Schedule, Window};
+    use std::collections::BTreeMap;
+
+    /// The windows emitted by a simulated chain of data source invocations.
+    struct Simulation {
+        invocations: usize,
+        reported:    Option<usize>,
+        windows:     BTreeMap<String, Vec<Vec<u8>>>,
+    }
+
+    /// Simulates the invocations of the NEXMark data source function of Q3 with
+    /// tumbling windows of 2 seconds over 12 seconds, and returns the payloads
+    /// of each window by window id.
+    fn simulate(windows_per_invocation: usize, max_invocations: usize) -> Result<Simulation> {
+        let (seconds, window_size) = (12, 2);
+        let window = Window::Tumbling(Schedule::Seconds(window_size));
+        let mut source = NEXMarkSource::new(seconds, 1, 1000, window.clone());
+        source.select_tables_for_query(3);
+
+        let mut simulation = Simulation {
+            invocations: 0,
+            reported:    None,
+            windows:     BTreeMap::new(),
+        };
+        let mut pending = vec![Payload {
+            uuid: UuidBuilder::new_with_ts("q3-00", 1_600_000_000, 1).next_uuid(),
+            ..Default::default()
+        }];
+        while let Some(mut payload) = pending.pop() {
+            simulation.invocations += 1;
+            let continuation =
+                Continuation::new(windows_per_invocation, max_invocations, &payload.metadata);
+            continuation.stamp(&mut payload.metadata);
+            simulation.reported = continuation.total_windows(window.num_windows(seconds));
+
+            let first = resume_window(&payload.metadata).unwrap_or(0);
+            let epochs = source
+                .stream_epochs(7)
+                .starting_at(window.window_start(first).unwrap());
+            let stream = NEXMarkLazyStream::new(epochs, 2);
+            for w in first..window.num_windows(seconds).unwrap() {
+                match continuation.step(w) {
+                    Step::Emit => {}
+                    Step::Continue => {
+                        pending.push(continuation.payload(&payload, w));
+                        break;
+                    }
+                    Step::Stop => break,
+                }
+                let id = window_id(&payload.uuid, Some(w));
+                let mut uuid_builder = UuidBuilder::for_window("q3-00", &id, window_size);
+                let payloads = (w * window_size..(w + 1) * window_size)
+                    .map(|t| {
+                        let uuid = uuid_builder.next_uuid();
+                        let payload = stream.select_event_to_payload(t, 0, Some(3), uuid, false)?;
+                        Ok(serde_json::to_vec(&payload)?)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                simulation.windows.insert(id, payloads);
+            }
+        }
+        Ok(simulation)
+    }
+
+    #[test]
+    fn continuation_steps() {
+        let continuation = Continuation::new(2, 3, &None);
+        assert!(continuation.is_enabled());
+        assert!(!continuation.is_handover());
+        assert_eq!(continuation.step(1), Step::Emit);
+        assert_eq!(continuation.step(2), Step::Continue);
+        assert_eq!(continuation.total_windows(Some(10)), Some(10));
+
+        // The last invocation of the chain stops at its end.
+        let next = Continuation::new(2, 3, &continuation.payload(&Payload::default(), 2).metadata);
+        assert!(next.is_handover());
+        assert_eq!(next.invocation(), 1);
+        let last = Continuation::new(2, 3, &next.payload(&Payload::default(), 4).metadata);
+        assert_eq!(last.invocation(), 2);
+        assert_eq!(last.step(5), Step::Emit);
+        assert_eq!(last.step(6), Step::Stop);
+        assert_eq!(last.total_windows(Some(10)), Some(6));
+        assert_eq!(last.total_windows(Some(5)), Some(5));
+
+        // The rescheduled invocation keeps the end of the invocation.
+        let mut metadata = next.payload(&Payload::default(), 4).metadata;
+        last.stamp(&mut metadata);
+        metadata
+            .as_mut()
+            .unwrap()
+            .insert(RESUME_WINDOW_METADATA_KEY.to_string(), "5".to_string());
+        let rescheduled = Continuation::new(2, 3, &metadata);
+        assert!(!rescheduled.is_handover());
+        assert_eq!(rescheduled.step(6), Step::Stop);
+
+        let disabled = Continuation::new(0, 1, &None);
+        assert_eq!(disabled.step(100), Step::Emit);
+        assert_eq!(disabled.total_windows(Some(10)), Some(10));
+    }
+
+    #[test]
+    fn continuations_emit_same_windows() -> Result<()> {
+        let single = simulate(0, 100)?;
+        assert_eq!(single.invocations, 1);
+        assert_eq!(single.windows.len(), 6);
+        assert_eq!(single.reported, Some(6));
+
+        // 3 invocations of 2 windows each.
+        let chained = simulate(2, 100)?;
+        assert_eq!(chained.invocations, 3);
+        assert_eq!(chained.reported, Some(6));
+        assert_eq!(chained.windows, single.windows);
+
+        // The chain is cut short by the maximum number of invocations, and the
+        // last invocation reports the windows actually emitted.
+        let capped = simulate(2, 2)?;
+        assert_eq!(capped.invocations, 2);
+        assert_eq!(capped.reported, Some(4));
+        assert_eq!(capped.windows.len(), 4);
+        assert!(capped
+            .windows
+            .iter()
+            .all(|(id, payloads)| single.windows.get(id) == Some(payloads)));
+        Ok(())
+    }
+}
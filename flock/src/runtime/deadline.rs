@@ -0,0 +1,340 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The execution deadlines of the cloud functions.
+//!
+//! The driver stamps the deadline of the query into the payload metadata of
+//! the data source, and the data source derives the deadline of each window
+//! from the window length. Every function bounds its invocation by the
+//! earliest of the propagated deadline and the deadline of its own Lambda
+//! context, minus a safety margin, so that it fails with
+//! [`FlockError::Timeout`] instead of being killed by the runtime:
+//!
+//! - the retries of the synchronous invocations stop once the next backoff
+//!   would exceed the deadline;
+//! - the output of an expired invocation is not fanned out to the next stage;
+//! - the synchronous invocations of the next stage are stamped with the reduced
+//!   deadline of the caller, since the caller waits for them.
+//!
+//! The deadlines are wall-clock times in milliseconds since the Unix epoch,
+//! which are comparable across the functions. The time is read through a
+//! [`Clock`], so that the budget arithmetic can be tested with a fake clock.
+
+use crate::configs::FLOCK_DEADLINE_MARGIN;
+use crate::error::{FlockError, Result};
+use crate::runtime::metadata::QueryMetadata;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The metadata key of the deadline in milliseconds since the Unix epoch.
+pub const DEADLINE_METADATA_KEY: &str = "deadline";
+
+lazy_static! {
+    static ref INVOCATION_DEADLINE: Mutex<Option<Deadline>> = Mutex::new(None);
+}
+
+/// The source of the current time.
+pub trait Clock: Send + Sync {
+    /// Returns the current time in milliseconds since the Unix epoch.
+    fn now(&self) -> i64;
+}
+
+/// The wall clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        Utc::now().timestamp_millis()
+    }
+}
+
+/// A point in time by which the work must be done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Deadline(i64);
+
+impl Deadline {
+    /// Creates the deadline at the given time in milliseconds since the Unix
+    /// epoch.
+    pub fn at(millis: i64) -> Self {
+        Self(millis)
+    }
+
+    /// Creates the deadline after the timeout from now.
+    pub fn after(clock: &dyn Clock, timeout: Duration) -> Self {
+        Self(clock.now().saturating_add(timeout.as_millis() as i64))
+    }
+
+    /// Creates the deadline of a window, i.e. the window length multiplied by
+    /// the factor from now. Returns `None` if the factor is not positive.
+    pub fn for_window(clock: &dyn Clock, window: Duration, factor: f64) -> Option<Self> {
+        (factor > 0.0).then(|| Self::after(clock, window.mul_f64(factor)))
+    }
+
+    /// Returns the deadline of the current invocation, i.e. the earliest of
+    /// the deadline of the Lambda context and the propagated deadline, minus
+    /// the safety margin to report the timeout.
+    ///
+    /// # Arguments
+    /// * `context` - The deadline of the Lambda context in milliseconds since
+    ///   the Unix epoch.
+    /// * `propagated` - The deadline in the payload metadata.
+    /// * `margin` - The time reserved to fail gracefully.
+    pub fn invocation(context: i64, propagated: Option<Deadline>, margin: Duration) -> Self {
+        Self::earliest(Some(Self(context)), propagated)
+            .unwrap()
+            .reduce(margin)
+    }
+
+    /// Returns the earlier of the two deadlines.
+    pub fn earliest(a: Option<Deadline>, b: Option<Deadline>) -> Option<Self> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Returns the deadline of the propagated metadata, if any.
+    pub fn from_metadata(metadata: &Option<QueryMetadata>) -> Result<Option<Self>> {
+        match metadata.as_ref().and_then(|m| m.get(DEADLINE_METADATA_KEY)) {
+            Some(value) => value.parse::<i64>().map(|t| Some(Self(t))).map_err(|_| {
+                FlockError::Internal(format!("Invalid deadline in the metadata: {}", value))
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Stamps the deadline into the metadata, replacing the existing one.
+    pub fn stamp(&self, metadata: &mut Option<QueryMetadata>) {
+        metadata
+            .get_or_insert_with(QueryMetadata::default)
+            .insert(DEADLINE_METADATA_KEY.to_string(), self.0.to_string());
+    }
+
+    /// Returns the time in milliseconds since the Unix epoch.
+    pub fn millis(&self) -> i64 {
+        self.0
+    }
+
+    /// Returns the deadline moved earlier by the margin.
+    pub fn reduce(&self, margin: Duration) -> Self {
+        Self(self.0.saturating_sub(margin.as_millis() as i64))
+    }
+
+    /// Returns the time left until the deadline, which is zero once the
+    /// deadline has passed.
+    pub fn remaining(&self, clock: &dyn Clock) -> Duration {
+        Duration::from_millis(self.0.saturating_sub(clock.now()).max(0) as u64)
+    }
+
+    /// Returns true if the deadline has passed.
+    pub fn is_expired(&self, clock: &dyn Clock) -> bool {
+        clock.now() >= self.0
+    }
+
+    /// Returns true if the work of the given duration can still finish
+    /// before the deadline.
+    pub fn allows(&self, clock: &dyn Clock, duration: Duration) -> bool {
+        self.remaining(clock) > duration
+    }
+
+    /// Returns the remaining budget of the stage, or the timeout error if the
+    /// deadline has passed.
+    pub fn check(&self, clock: &dyn Clock, stage: &str) -> Result<Duration> {
+        if self.is_expired(clock) {
+            Err(self.exceeded(clock, stage))
+        } else {
+            Ok(self.remaining(clock))
+        }
+    }
+
+    /// Returns the timeout error of the stage.
+    pub fn exceeded(&self, clock: &dyn Clock, stage: &str) -> FlockError {
+        FlockError::Timeout(format!(
+            "{} exceeded the deadline {} by {} ms",
+            stage,
+            self.0,
+            clock.now().saturating_sub(self.0).max(0)
+        ))
+    }
+}
+
+/// Records the deadline of the current function invocation, which bounds the
+/// retries of its synchronous invocations (see [`crate::aws::lambda`]).
+pub fn begin(deadline: Option<Deadline>) {
+    *INVOCATION_DEADLINE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = deadline;
+}
+
+/// Returns the deadline of the current function invocation, if any.
+pub fn current() -> Option<Deadline> {
+    *INVOCATION_DEADLINE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Returns the effective deadline of the payload, i.e. the earliest of the
+/// propagated deadline and the deadline of the current invocation.
+pub fn effective(metadata: &Option<QueryMetadata>) -> Result<Option<Deadline>> {
+    Ok(Deadline::earliest(
+        Deadline::from_metadata(metadata)?,
+        current(),
+    ))
+}
+
+/// Returns the metadata of the payloads sent to the next stage. The
+/// synchronous invocations inherit the deadline of the caller minus the
+/// safety margin, since the caller waits for them. The asynchronous ones
+/// keep the propagated deadline.
+pub fn downstream_metadata(
+    metadata: Option<QueryMetadata>,
+    deadline: Option<Deadline>,
+    sync: bool,
+) -> Option<QueryMetadata> {
+    let mut metadata = metadata;
+    if let (Some(deadline), true) = (deadline, sync) {
+        deadline
+            .reduce(Duration::from_millis(*FLOCK_DEADLINE_MARGIN))
+            .stamp(&mut metadata);
+    }
+    metadata
+}
+
+/// Returns the metadata of a window's payloads with the deadline of the
+/// window, unless the propagated deadline is earlier.
+pub fn window_metadata(
+    metadata: &Option<QueryMetadata>,
+    clock: &dyn Clock,
+    window: Duration,
+    factor: f64,
+) -> Result<Option<QueryMetadata>> {
+    let mut metadata = metadata.clone();
+    if let Some(window) = Deadline::for_window(clock, window, factor) {
+        let deadline = Deadline::earliest(Deadline::from_metadata(&metadata)?, Some(window));
+        deadline.unwrap().stamp(&mut metadata);
+    }
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    /// The clock that only moves when it's told to.
+    struct FakeClock(AtomicI64);
+
+    impl FakeClock {
+        fn new(now: i64) -> Self {
+            Self(AtomicI64::new(now))
+        }
+
+        fn advance(&self, millis: i64) {
+            self.0.fetch_add(millis, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> i64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    #[test]
+    fn compute_budget() -> Result<()> {
+        let clock = FakeClock::new(10_000);
+        let margin = Duration::from_millis(500);
+
+        // The Lambda context is the earliest deadline.
+        let deadline = Deadline::invocation(15_000, Some(Deadline::at(20_000)), margin);
+        assert_eq!(deadline, Deadline::at(14_500));
+        assert_eq!(
+            deadline.check(&clock, "q1-01")?,
+            Duration::from_millis(4_500)
+        );
+
+        // The propagated deadline is the earliest one.
+        let deadline = Deadline::invocation(15_000, Some(Deadline::at(12_000)), margin);
+        assert_eq!(deadline.remaining(&clock), Duration::from_millis(1_500));
+        assert!(deadline.allows(&clock, Duration::from_millis(1_000)));
+        assert!(!deadline.allows(&clock, Duration::from_millis(1_500)));
+
+        clock.advance(1_499);
+        assert!(!deadline.is_expired(&clock));
+        clock.advance(1);
+        assert!(deadline.is_expired(&clock));
+        assert_eq!(deadline.remaining(&clock), Duration::ZERO);
+
+        clock.advance(250);
+        match deadline.check(&clock, "q1-01") {
+            Err(FlockError::Timeout(desc)) => {
+                assert_eq!(desc, "q1-01 exceeded the deadline 11500 by 250 ms")
+            }
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+
+        // Without the propagated deadline, only the Lambda context counts.
+        let deadline = Deadline::invocation(15_000, None, margin);
+        assert_eq!(deadline, Deadline::at(14_500));
+        Ok(())
+    }
+
+    #[test]
+    fn propagate_deadlines() -> Result<()> {
+        let clock = FakeClock::new(1_000);
+        assert_eq!(Deadline::from_metadata(&None)?, None);
+
+        let mut metadata = None;
+        Deadline::after(&clock, Duration::from_secs(60)).stamp(&mut metadata);
+        assert_eq!(
+            Deadline::from_metadata(&metadata)?,
+            Some(Deadline::at(61_000))
+        );
+
+        // The window deadline is only stamped if it's earlier.
+        let window = Duration::from_secs(10);
+        let stamped = window_metadata(&metadata, &clock, window, 2.0)?;
+        assert_eq!(
+            Deadline::from_metadata(&stamped)?,
+            Some(Deadline::at(21_000))
+        );
+        let stamped = window_metadata(&metadata, &clock, window, 10.0)?;
+        assert_eq!(
+            Deadline::from_metadata(&stamped)?,
+            Some(Deadline::at(61_000))
+        );
+        let stamped = window_metadata(&None, &clock, window, 0.0)?;
+        assert_eq!(Deadline::from_metadata(&stamped)?, None);
+
+        // The synchronous invocations inherit the reduced deadline.
+        let deadline = Some(Deadline::at(30_000));
+        let sync = downstream_metadata(metadata.clone(), deadline, true);
+        assert_eq!(
+            Deadline::from_metadata(&sync)?,
+            Some(Deadline::at(30_000 - *FLOCK_DEADLINE_MARGIN as i64))
+        );
+        let not_sync = downstream_metadata(metadata, deadline, false);
+        assert_eq!(
+            Deadline::from_metadata(&not_sync)?,
+            Some(Deadline::at(61_000))
+        );
+
+        let mut invalid = QueryMetadata::default();
+        invalid.insert(DEADLINE_METADATA_KEY.to_string(), "soon".to_string());
+        assert!(Deadline::from_metadata(&Some(invalid)).is_err());
+        Ok(())
+    }
+}
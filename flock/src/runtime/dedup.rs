@@ -0,0 +1,383 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The deduplication of the Kinesis records at the data source.
+//!
+//! The Kinesis event source mapping delivers the records of a shard again after
+//! the function errors and the shard rebalancing, which inflates the counts of
+//! the aggregations downstream. The source function keeps a [`ShardCheckpoint`]
+//! of each shard in the state backend: the highest sequence number processed,
+//! and the sequence numbers processed within the lateness window before it. A
+//! record at or below the highest sequence number is dropped if it's older than
+//! the lateness window or already processed, so that the out-of-order batches
+//! of a shard are still accepted once.
+//!
+//! The checkpoints are read once per shard by [`deduplicate`] before the
+//! records are processed, and written once per shard by
+//! [`Deduplication::commit`] after the records are sent downstream. A failed
+//! invocation leaves the checkpoints untouched, and its records are accepted
+//! again on the retry. The writes are conditional on the version read; if
+//! another invocation of the same shard committed in between, the checkpoints
+//! are merged and written again. The invocations of different shards never
+//! share a checkpoint.
+
+use crate::error::{FlockError, Result};
+use crate::runtime::function_name::query_key;
+use crate::state::StateBackend;
+use aws_lambda_events::event::kinesis::{KinesisEvent, KinesisEventRecord};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+/// The S3 key prefix of the shard checkpoints under the query code.
+pub const DEDUP_KEY_PREFIX: &str = "dedup/";
+
+/// The maximum number of attempts to write a checkpoint that is updated
+/// concurrently.
+const MAX_COMMIT_ATTEMPTS: usize = 5;
+
+/// Returns the key of the checkpoint of the shard, i.e.
+/// `<query code>/dedup/<shard id>`.
+pub fn dedup_key(query_code: &str, shard_id: &str) -> String {
+    query_key(query_code, &format!("{}{}", DEDUP_KEY_PREFIX, shard_id))
+}
+
+/// Returns the shard id of the record, which is the prefix of its event id,
+/// e.g. `shardId-000000000000` of
+/// `shardId-000000000000:
+/// 49568167373333333333333333333333333333333333333333333333`.
+pub fn shard_id(record: &KinesisEventRecord) -> Option<&str> {
+    record.event_id.as_deref()?.split(':').next()
+}
+
+/// Compares two sequence numbers, which are decimal strings of up to 128
+/// digits.
+pub fn cmp_sequence_numbers(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.trim_start_matches('0'), b.trim_start_matches('0'));
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+/// The progress of a shard.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardCheckpoint {
+    /// The highest sequence number processed, or `None` if no record of the
+    /// shard is processed yet.
+    pub sequence_number: Option<String>,
+    /// The approximate arrival time in milliseconds of the record with the
+    /// highest sequence number.
+    pub arrival_time:    i64,
+    /// The sequence numbers and the arrival times of the processed records
+    /// within the lateness window.
+    pub recent:          Vec<(String, i64)>,
+}
+
+impl ShardCheckpoint {
+    /// Returns true if the record isn't processed yet, and marks it processed.
+    ///
+    /// # Arguments
+    /// * `sequence_number` - The sequence number of the record.
+    /// * `arrival_time` - The approximate arrival time of the record in
+    ///   milliseconds.
+    /// * `lateness` - The lateness window in milliseconds.
+    pub fn admit(&mut self, sequence_number: &str, arrival_time: i64, lateness: i64) -> bool {
+        let admitted = match &self.sequence_number {
+            Some(highest)
+                if cmp_sequence_numbers(sequence_number, highest) != Ordering::Greater =>
+            {
+                arrival_time >= self.arrival_time - lateness
+                    && !self.recent.iter().any(|(s, _)| s == sequence_number)
+            }
+            _ => {
+                self.sequence_number = Some(sequence_number.to_string());
+                self.arrival_time = arrival_time;
+                true
+            }
+        };
+        if admitted {
+            self.recent
+                .push((sequence_number.to_string(), arrival_time));
+            self.prune(lateness);
+        }
+        admitted
+    }
+
+    /// Merges the progress of the other checkpoint of the same shard.
+    pub fn merge(&mut self, other: &ShardCheckpoint, lateness: i64) {
+        if let Some(sequence_number) = &other.sequence_number {
+            let higher = match &self.sequence_number {
+                Some(highest) => {
+                    cmp_sequence_numbers(sequence_number, highest) == Ordering::Greater
+                }
+                None => true,
+            };
+            if higher {
+                self.sequence_number = Some(sequence_number.clone());
+                self.arrival_time = other.arrival_time;
+            }
+        }
+        for record in &other.recent {
+            if !self.recent.contains(record) {
+                self.recent.push(record.clone());
+            }
+        }
+        self.prune(lateness);
+    }
+
+    /// Forgets the processed records older than the lateness window.
+    fn prune(&mut self, lateness: i64) {
+        let horizon = self.arrival_time - lateness;
+        self.recent.retain(|(_, t)| *t >= horizon);
+    }
+}
+
+/// The checkpoints of the shards of an invocation, which are written after
+/// the admitted records are processed.
+#[derive(Debug, Default)]
+pub struct Deduplication {
+    /// The number of records dropped.
+    pub duplicates: usize,
+    /// The key, the version read and the new progress of each shard.
+    checkpoints:    Vec<(String, Option<String>, ShardCheckpoint)>,
+    /// The lateness window in milliseconds.
+    lateness:       i64,
+}
+
+/// Drops the records of the event that are already processed. The records
+/// without a shard id or a sequence number are kept.
+///
+/// # Arguments
+/// * `state_backend` - The state backend of the checkpoints.
+/// * `bucket` - The bucket of the checkpoints.
+/// * `query_code` - The query code of the source function.
+/// * `event` - The Kinesis event of the invocation.
+/// * `lateness` - The lateness window of the out-of-order records.
+///
+/// # Returns
+/// The checkpoints to commit once the admitted records are sent downstream.
+pub async fn deduplicate(
+    state_backend: &dyn StateBackend,
+    bucket: &str,
+    query_code: &str,
+    event: &mut KinesisEvent,
+    lateness: Duration,
+) -> Result<Deduplication> {
+    let lateness = lateness.as_millis() as i64;
+    let shards = event
+        .records
+        .iter()
+        .filter_map(|r| shard_id(r).map(String::from))
+        .collect::<BTreeSet<_>>();
+
+    let mut admitted = vec![true; event.records.len()];
+    let mut checkpoints = vec![];
+    for shard in shards {
+        let key = dedup_key(query_code, &shard);
+        let current = state_backend
+            .read_checkpoint(bucket.to_string(), key.clone())
+            .await?;
+        let mut checkpoint = match &current {
+            Some(current) => serde_json::from_slice(&current.bytes)?,
+            None => ShardCheckpoint::default(),
+        };
+        let before = checkpoint.clone();
+        for (i, record) in event.records.iter().enumerate() {
+            if shard_id(record) != Some(shard.as_str()) {
+                continue;
+            }
+            if let Some(sequence_number) = &record.kinesis.sequence_number {
+                let arrival_time = record
+                    .kinesis
+                    .approximate_arrival_timestamp
+                    .0
+                    .timestamp_millis();
+                admitted[i] = checkpoint.admit(sequence_number, arrival_time, lateness);
+            }
+        }
+        // The checkpoint is unchanged if all records of the shard are dropped.
+        if checkpoint != before {
+            checkpoints.push((key, current.map(|c| c.version), checkpoint));
+        }
+    }
+
+    let duplicates = admitted.iter().filter(|a| !**a).count();
+    let mut admitted = admitted.into_iter();
+    event.records.retain(|_| admitted.next().unwrap());
+    Ok(Deduplication {
+        duplicates,
+        checkpoints,
+        lateness,
+    })
+}
+
+impl Deduplication {
+    /// Writes the checkpoints of the shards. If a checkpoint was updated by
+    /// another invocation in between, it's merged with the newer one and
+    /// written again.
+    pub async fn commit(self, state_backend: &dyn StateBackend, bucket: &str) -> Result<()> {
+        for (key, mut version, mut checkpoint) in self.checkpoints {
+            let mut attempts = 0;
+            while !state_backend
+                .write_checkpoint(
+                    bucket.to_string(),
+                    key.clone(),
+                    serde_json::to_vec(&checkpoint)?,
+                    version.clone(),
+                )
+                .await?
+            {
+                attempts += 1;
+                if attempts >= MAX_COMMIT_ATTEMPTS {
+                    return Err(FlockError::Execution(format!(
+                        "Failed to write the checkpoint {} after {} attempts",
+                        key, attempts
+                    )));
+                }
+                warn!("The checkpoint {} was updated concurrently.", key);
+                version = match state_backend
+                    .read_checkpoint(bucket.to_string(), key.clone())
+                    .await?
+                {
+                    Some(current) => {
+                        let newer: ShardCheckpoint = serde_json::from_slice(&current.bytes)?;
+                        checkpoint.merge(&newer, self.lateness);
+                        Some(current.version)
+                    }
+                    None => None,
+                };
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::HashMapStateBackend;
+    use aws_lambda_events::encodings::{Base64Data, SecondTimestamp};
+    use chrono::{TimeZone, Utc};
+
+    /// Returns a record of the shard arriving at the second.
+    fn record(shard: &str, seq: u64, second: i64) -> KinesisEventRecord {
+        let data = include_bytes!("../tests/data/example-kinesis-event.json");
+        let event: KinesisEvent = serde_json::from_slice(data).unwrap();
+        let mut record = event.records[0].clone();
+        let sequence_number = format!("4956816737{:046}", seq);
+        record.event_id = Some(format!("{}:{}", shard, sequence_number));
+        record.kinesis.sequence_number = Some(sequence_number);
+        record.kinesis.approximate_arrival_timestamp = SecondTimestamp(Utc.timestamp(second, 0));
+        record.kinesis.data = Base64Data(format!("{{\"seq\": {}}}", seq).into_bytes());
+        record
+    }
+
+    #[test]
+    fn sequence_numbers() {
+        assert_eq!(cmp_sequence_numbers("9", "10"), Ordering::Less);
+        assert_eq!(cmp_sequence_numbers("0010", "10"), Ordering::Equal);
+        assert_eq!(
+            cmp_sequence_numbers(
+                "49568167373333333334444444444444444444444444444444444444",
+                "49568167373333333333333333333333333333333333333333333333"
+            ),
+            Ordering::Greater
+        );
+        assert_eq!(
+            shard_id(&record("shardId-000000000001", 1, 0)),
+            Some("shardId-000000000001")
+        );
+    }
+
+    #[test]
+    fn shard_checkpoint_lateness() {
+        let mut checkpoint = ShardCheckpoint::default();
+        assert!(checkpoint.admit("10", 100_000, 5_000));
+        assert!(checkpoint.admit("12", 101_000, 5_000));
+        // Redelivered.
+        assert!(!checkpoint.admit("10", 100_000, 5_000));
+        assert!(!checkpoint.admit("12", 101_000, 5_000));
+        // Out of order, but within the lateness window.
+        assert!(checkpoint.admit("11", 97_000, 5_000));
+        assert!(!checkpoint.admit("11", 97_000, 5_000));
+        // Out of order beyond the lateness window.
+        assert!(!checkpoint.admit("9", 95_000, 5_000));
+
+        // The records older than the lateness window are forgotten.
+        assert!(checkpoint.admit("20", 110_000, 5_000));
+        assert_eq!(checkpoint.recent, vec![("20".to_string(), 110_000)]);
+
+        let mut other = ShardCheckpoint::default();
+        assert!(other.admit("21", 111_000, 5_000));
+        checkpoint.merge(&other, 5_000);
+        assert_eq!(checkpoint.sequence_number.as_deref(), Some("21"));
+        assert!(!checkpoint.admit("20", 110_000, 5_000));
+        assert!(!checkpoint.admit("21", 111_000, 5_000));
+    }
+
+    #[tokio::test]
+    async fn deduplicate_concurrent_shards() -> Result<()> {
+        let state_backend = HashMapStateBackend::new();
+        let lateness = Duration::from_secs(60);
+        let shard_0 = "shardId-000000000000";
+        let shard_1 = "shardId-000000000001";
+
+        // The invocations of the two shards run concurrently.
+        let mut events = (0..2)
+            .map(|i| KinesisEvent {
+                records: (1..=3)
+                    .map(|seq| record([shard_0, shard_1][i], seq, 1000 + seq as i64))
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+        let (left, right) = events.split_at_mut(1);
+        let (d0, d1) = futures::future::try_join(
+            deduplicate(&state_backend, "flock", "dedup", &mut left[0], lateness),
+            deduplicate(&state_backend, "flock", "dedup", &mut right[0], lateness),
+        )
+        .await?;
+        assert_eq!((d0.duplicates, d1.duplicates), (0, 0));
+        futures::future::try_join(
+            d0.commit(&state_backend, "flock"),
+            d1.commit(&state_backend, "flock"),
+        )
+        .await?;
+
+        // A stale invocation of the first shard commits after a newer one:
+        // the checkpoints are merged instead of overwritten.
+        let mut stale = KinesisEvent {
+            records: vec![record(shard_0, 4, 1004)],
+        };
+        let mut newer = KinesisEvent {
+            records: vec![record(shard_0, 5, 1005)],
+        };
+        let stale = deduplicate(&state_backend, "flock", "dedup", &mut stale, lateness).await?;
+        let newer = deduplicate(&state_backend, "flock", "dedup", &mut newer, lateness).await?;
+        newer.commit(&state_backend, "flock").await?;
+        stale.commit(&state_backend, "flock").await?;
+
+        let mut redelivered = KinesisEvent {
+            records: (1..=6)
+                .map(|seq| record(shard_0, seq, 1000 + seq as i64))
+                .collect(),
+        };
+        let d = deduplicate(&state_backend, "flock", "dedup", &mut redelivered, lateness).await?;
+        assert_eq!(d.duplicates, 5);
+        assert_eq!(redelivered.records.len(), 1);
+        assert_eq!(
+            redelivered.records[0].kinesis.sequence_number,
+            record(shard_0, 6, 0).kinesis.sequence_number
+        );
+        Ok(())
+    }
+}
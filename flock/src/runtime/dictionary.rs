@@ -0,0 +1,332 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The Zstd dictionaries of the queries whose payloads are too small to be
+//! compressed well on their own, e.g. the single-row payloads of NEXMark Q1.
+//!
+//! The driver trains the dictionary of a query from the Arrow Flight data of
+//! sample payloads, e.g. those of its data source (see [`source_samples`],
+//! [`payload_samples`] and
+//! [`train_dictionary`](crate::encoding::train_dictionary)), writes it to S3
+//! under `<query code>/zstd-dict` next to the other objects of the query (see
+//! [`store_dictionary`]), and carries its pointer in the execution contexts of
+//! the query (see [`ExecutionContext::zstd_dict`]). Each function instance
+//! reads the dictionary once, and registers it before the payloads are
+//! encoded or decoded (see [`load_dictionary`]).
+//!
+//! Each payload records its codec, so the payloads compressed with the
+//! dictionary and those compressed without it can be mixed in a window, e.g.
+//! the payloads sent before the dictionary is registered fall back to plain
+//! Zstd (see [`Encoding::available`]).
+
+use crate::aws::client::CloudClient;
+#[cfg(feature = "nexmark")]
+use crate::datasource::nexmark::NEXMarkSource;
+#[cfg(feature = "nexmark")]
+use crate::datasource::DataStream;
+use crate::datasource::{DataSource, RelationPartitions};
+use crate::encoding::{
+    dictionary_id, has_dictionary, register_dictionary, Encoding, StageEncoding,
+};
+use crate::encryption;
+use crate::error::{FlockError, Result};
+use crate::runtime::context::ExecutionContext;
+use crate::runtime::function_name::query_key;
+use crate::runtime::metadata::S3Pointer;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::arrow_flight::utils::flight_data_from_arrow_batch;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+/// The maximum size in bytes of the dictionaries trained by the driver. The
+/// dictionary is read once by each function instance, so it's kept small.
+pub const MAX_DICTIONARY_SIZE: usize = 16 * 1024;
+
+/// The maximum number of sample batches that the driver trains the dictionary
+/// from.
+pub const MAX_SAMPLE_BATCHES: usize = 1024;
+
+/// The S3 key of the dictionary of the query.
+pub fn dictionary_key(query_code: &str) -> String {
+    query_key(query_code, "zstd-dict")
+}
+
+/// The Zstd dictionary of a query, which is carried in the execution contexts
+/// of the query.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ZstdDictionary {
+    /// The id of the dictionary, which the payloads compressed with it record
+    /// in their codec.
+    pub dict_id: u32,
+    /// The S3 object of the dictionary.
+    pub s3:      S3Pointer,
+}
+
+impl ZstdDictionary {
+    /// Returns the dictionary of the query stored in the bucket.
+    pub fn new(dict: &[u8], bucket: &str, query_code: &str) -> Result<Self> {
+        let dict_id = dictionary_id(dict).ok_or_else(|| {
+            FlockError::Execution("The bytes are not a Zstd dictionary".to_string())
+        })?;
+        Ok(ZstdDictionary {
+            dict_id,
+            s3: S3Pointer {
+                bucket: bucket.to_string(),
+                key:    dictionary_key(query_code),
+            },
+        })
+    }
+
+    /// Returns the encoding of a stage that compresses its payloads with the
+    /// dictionary instead of plain Zstd. The dictionary compresses the small
+    /// payloads well, so they are compressed regardless of their size. The
+    /// other codecs are left as they are.
+    pub fn encoding(&self, encoding: StageEncoding) -> StageEncoding {
+        match encoding.codec {
+            Encoding::Zstd | Encoding::ZstdDict { .. } => StageEncoding {
+                codec: Encoding::ZstdDict {
+                    dict_id: self.dict_id,
+                },
+                threshold: 0,
+                ..encoding
+            },
+            _ => encoding,
+        }
+    }
+}
+
+/// Returns the samples of the Arrow Flight data of the batches to train the
+/// dictionary from, i.e. the header and the body of each batch, which are
+/// compressed separately.
+pub fn payload_samples(batches: &[RecordBatch]) -> Vec<Vec<u8>> {
+    let options = datafusion::arrow::ipc::writer::IpcWriteOptions::default();
+    batches
+        .iter()
+        .flat_map(|b| {
+            let (_, flight_data) = flight_data_from_arrow_batch(b, &options);
+            vec![flight_data.data_header, flight_data.data_body]
+        })
+        .filter(|sample| !sample.is_empty())
+        .collect()
+}
+
+/// Returns the samples of the payloads of the data source to train the
+/// dictionary of the query from, i.e. the batches of the memory data source
+/// if they are given, or else those of the first epoch of the NEXMark data
+/// source. The samples are empty if the data source can't be sampled.
+///
+/// # Arguments
+/// * `datasource` - The data source of the query.
+/// * `query_number` - The NEXMark query whose events the data source generates.
+/// * `sources` - The relations of the memory data source, if any.
+#[cfg_attr(not(feature = "nexmark"), allow(unused_variables))]
+pub fn source_samples(
+    datasource: &DataSource,
+    query_number: Option<usize>,
+    sources: &[RelationPartitions],
+) -> Result<Vec<Vec<u8>>> {
+    let batches: Vec<RecordBatch> = if !sources.is_empty() {
+        sources.iter().flatten().flatten().cloned().collect()
+    } else {
+        match datasource {
+            #[cfg(feature = "nexmark")]
+            DataSource::NEXMarkEvent(source) => nexmark_batches(source, query_number)?,
+            _ => vec![],
+        }
+    };
+    Ok(payload_samples(
+        &batches[..batches.len().min(MAX_SAMPLE_BATCHES)],
+    ))
+}
+
+/// Returns the batches of the first epoch of one NEXMark generator, which are
+/// split into the payloads of the asynchronous data source function.
+#[cfg(feature = "nexmark")]
+fn nexmark_batches(
+    source: &NEXMarkSource,
+    query_number: Option<usize>,
+) -> Result<Vec<RecordBatch>> {
+    let query_number = match query_number {
+        Some(query_number) => query_number,
+        None => return Ok(vec![]),
+    };
+    let mut source = source.clone();
+    // Each generator produces its share of the events of the epoch.
+    let generators: usize = source.config.get_as_or("threads", 1).max(1);
+    let eps: usize = source.config.get_as_or("events-per-second", 1000);
+    source.config.insert("threads", 1.to_string());
+    source.config.insert("seconds", 1.to_string());
+    source
+        .config
+        .insert("events-per-second", (eps / generators).max(1).to_string());
+    source.select_tables_for_query(query_number);
+    let (r1, r2) =
+        source
+            .generate_data()?
+            .select_event_to_batches(0, 0, Some(query_number), false)?;
+    Ok(r1.into_iter().chain(r2).flatten().collect())
+}
+
+/// Writes the dictionary of the query to S3, where the functions of the query
+/// read it from.
+pub async fn store_dictionary(
+    client: &dyn CloudClient,
+    dictionary: &ZstdDictionary,
+    dict: Vec<u8>,
+) -> Result<()> {
+    client
+        .s3_put(
+            &dictionary.s3.bucket,
+            &dictionary.s3.key,
+            encryption::seal_bytes(dict)?,
+        )
+        .await
+}
+
+/// Registers the dictionary of the context in the function instance. The
+/// dictionary is only read from S3 if it's not registered yet.
+pub async fn load_dictionary(ctx: &ExecutionContext) -> Result<()> {
+    let dictionary = match &ctx.zstd_dict {
+        Some(dictionary) if !has_dictionary(dictionary.dict_id) => dictionary,
+        _ => return Ok(()),
+    };
+    let bytes = ctx
+        .cloud_client
+        .s3_get(&dictionary.s3.bucket, &dictionary.s3.key)
+        .await?;
+    let dict_id = register_dictionary(encryption::open_bytes(bytes).await?)?;
+    if dict_id != dictionary.dict_id {
+        return Err(FlockError::Execution(format!(
+            "The Zstd dictionary {} has the id {}",
+            dictionary.s3.key, dict_id
+        )));
+    }
+    info!("Registered the Zstd dictionary {}", dict_id);
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg(all(feature = "zstd", feature = "nexmark"))]
+mod tests {
+    use super::*;
+    use crate::aws::client::FakeCloudClient;
+    use crate::datasource::nexmark::event::Bid;
+    use crate::datasource::nexmark::NEXMarkSource;
+    use crate::encoding::train_dictionary;
+    use crate::runtime::payload::{Payload, Uuid, UuidBuilder};
+    use crate::stream::Window;
+    use crate::transmute::{event_bytes_to_batch, to_stage_payload};
+    use std::sync::Arc;
+
+    /// The single-row batches of the bids, which are the payloads of Q1.
+    fn q1_batches() -> Result<Vec<RecordBatch>> {
+        let stream = NEXMarkSource::new(1, 1, 10_000, Window::ElementWise).generate_data()?;
+        let (events, _) = stream.select(0, 0).expect("Failed to select event.");
+        let batches = event_bytes_to_batch(&events.bids, Arc::new(Bid::schema()), 1);
+        assert!(batches.len() >= 800);
+        Ok(batches)
+    }
+
+    fn compressed_size(batches: &[RecordBatch], encoding: &StageEncoding, uuid: &Uuid) -> usize {
+        batches
+            .iter()
+            .map(|b| {
+                to_stage_payload(&[b.clone()], &[], uuid.clone(), false, &[], encoding).unwrap()
+            })
+            .flat_map(|p| p.data)
+            .map(|d| d.header.len() + d.body.len())
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn compress_q1_payloads() -> Result<()> {
+        let batches = q1_batches()?;
+        let (samples, batches) = batches.split_at(400);
+        let dict = train_dictionary(&payload_samples(samples), MAX_DICTIONARY_SIZE)?;
+
+        let client = Arc::new(FakeCloudClient::new());
+        let dictionary = ZstdDictionary::new(&dict, "flock", "q1")?;
+        assert_eq!(dictionary.s3.key, "q1/zstd-dict");
+        store_dictionary(client.as_ref(), &dictionary, dict).await?;
+
+        let zstd = StageEncoding::from(Encoding::Zstd);
+        let encoding = dictionary.encoding(zstd.clone());
+        let uuid = UuidBuilder::new_with_ts("q1-00-00", 0, 1).get(0);
+        // The stage falls back to plain Zstd until the dictionary is registered.
+        assert_eq!(encoding.codec_for(0), Encoding::Zstd);
+
+        let ctx = ExecutionContext {
+            zstd_dict: Some(dictionary.clone()),
+            cloud_client: client,
+            ..Default::default()
+        };
+        load_dictionary(&ctx).await?;
+        assert_eq!(encoding.codec_for(0), encoding.codec);
+
+        let raw = compressed_size(batches, &StageEncoding::from(Encoding::None), &uuid);
+        let plain = compressed_size(batches, &zstd, &uuid);
+        let trained = compressed_size(batches, &encoding, &uuid);
+        let (plain_ratio, trained_ratio) = (raw as f64 / plain as f64, raw as f64 / trained as f64);
+        println!(
+            "Q1 payloads: {} bytes, zstd ratio: {:.3}, zstd with dictionary ratio: {:.3}",
+            raw, plain_ratio, trained_ratio
+        );
+        assert!(trained_ratio > plain_ratio * 1.5);
+        Ok(())
+    }
+
+    #[test]
+    fn sample_source_payloads() -> Result<()> {
+        let source = DataSource::NEXMarkEvent(NEXMarkSource::new(10, 4, 4000, Window::ElementWise));
+        let samples = source_samples(&source, Some(1), &[])?;
+        assert!(!samples.is_empty());
+        assert!(samples.len() <= 2 * MAX_SAMPLE_BATCHES);
+        // The events of the data source depend on the query.
+        assert!(source_samples(&source, None, &[])?.is_empty());
+
+        // The batches of the memory data source are sampled as they are.
+        let batches = q1_batches()?;
+        let sources = vec![vec![batches[..10].to_vec()], vec![batches[10..20].to_vec()]];
+        assert_eq!(
+            source_samples(&DataSource::SqsEvent, None, &sources)?,
+            payload_samples(&batches[..20])
+        );
+        assert!(source_samples(&DataSource::SqsEvent, None, &[])?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn decode_mixed_payloads() -> Result<()> {
+        let batches = q1_batches()?;
+        let dict = train_dictionary(&payload_samples(&batches[400..800]), MAX_DICTIONARY_SIZE)?;
+        let dict_id = register_dictionary(dict)?;
+
+        let uuid = UuidBuilder::new_with_ts("q1-00-00", 0, 1).get(0);
+        let encodings = [
+            StageEncoding::from(Encoding::ZstdDict { dict_id }),
+            StageEncoding::from(Encoding::Zstd),
+            StageEncoding::from(Encoding::None),
+        ];
+        for (i, batch) in batches[..30].iter().enumerate() {
+            let encoding = &encodings[i % encodings.len()];
+            let payload =
+                to_stage_payload(&[batch.clone()], &[], uuid.clone(), false, &[], encoding)?;
+            assert_eq!(payload.encoding, encoding.codec);
+
+            let payload = Payload::from_slice(&serde_json::to_vec(&payload)?)?;
+            let (output, _) = payload.to_record_batch()?;
+            assert_eq!(output, vec![batch.clone()]);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The distribution of the shuffled output by the keys of the query.
+//!
+//! The hash partitioning of the shuffling stage places a row in the output
+//! partition of its hash modulo the number of partitions, and the partitions
+//! are sent to the members of the next function group by their positions, so
+//! a key lands on the same member only if every upstream function has the same
+//! number of output partitions. If the query declares its distribution keys
+//! (see [`Query::distribute_by`](crate::query::Query::distribute_by)), the rows
+//! are placed by the hash of their key values modulo the size of the group
+//! instead, and a key always lands on the same member of the group.
+//!
+//! The session windows need the same placement for their session keys, but
+//! across the windows too, since a session spans the windows of its upstream
+//! functions. The stage feeding the session windows partitions its output by
+//! the hash of the session key modulo a fixed number of buckets, and each
+//! bucket is sent to the member that the bucket maps to on the hash ring (see
+//! [`SessionAffinity`]).
+
+use crate::configs::FLOCK_SESSION_BUCKETS;
+use crate::error::Result;
+use crate::runtime::metadata::QueryMetadata;
+use crate::runtime::skew::{row_keys, take_rows};
+use datafusion::arrow::record_batch::RecordBatch;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Returns the member of the function group that the key is distributed to.
+pub fn key_member(key: &[String], members: usize) -> usize {
+    // `DefaultHasher::new` uses fixed keys, so all functions place the key on
+    // the same member.
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % members.max(1) as u64) as usize
+}
+
+/// Redistributes the rows of the output partitions to the members of the
+/// function group by their key values.
+///
+/// # Arguments
+/// * `partitions` - The output partitions of the shuffling stage.
+/// * `columns` - The distribution keys of the query.
+/// * `members` - The number of the members of the next function group.
+///
+/// # Returns
+/// One partition per member of the group, in the order of the members.
+pub fn distribute(
+    partitions: Vec<Vec<RecordBatch>>,
+    columns: &[String],
+    members: usize,
+) -> Result<Vec<Vec<RecordBatch>>> {
+    let members = members.max(1);
+    let mut output = vec![vec![]; members];
+    for batch in partitions.into_iter().flatten() {
+        let mut indices = vec![vec![]; members];
+        for (row, key) in row_keys(&batch, columns)?.iter().enumerate() {
+            indices[key_member(key, members)].push(row as u32);
+        }
+        for (member, indices) in indices.into_iter().enumerate() {
+            if !indices.is_empty() {
+                output[member].push(take_rows(&batch, indices)?);
+            }
+        }
+    }
+    Ok(output)
+}
+
+/// The routing of the output to the session windows of the next function
+/// group by the session key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionAffinity {
+    /// The column of the session key, e.g. `bidder` of NEXMark Q11.
+    pub key:     String,
+    /// The number of buckets of the session keys.
+    pub buckets: usize,
+}
+
+impl SessionAffinity {
+    /// Creates the routing by the session key with the given number of
+    /// buckets.
+    pub fn new(key: impl Into<String>, buckets: usize) -> Self {
+        Self {
+            key:     key.into(),
+            buckets: buckets.max(1),
+        }
+    }
+
+    /// Returns the routing of the function, which is set in its context by
+    /// the launcher, or else derived from the session keys in the metadata of
+    /// the payload with the configured number of buckets.
+    pub fn infer(
+        affinity: Option<&SessionAffinity>,
+        metadata: &Option<QueryMetadata>,
+    ) -> Option<SessionAffinity> {
+        affinity.cloned().or_else(|| {
+            metadata
+                .as_ref()
+                .and_then(|m| m.session_keys.as_ref())
+                .filter(|keys| !keys.key.is_empty())
+                .map(|keys| SessionAffinity::new(&keys.key, *FLOCK_SESSION_BUCKETS))
+        })
+    }
+
+    /// Returns the key of the bucket on the hash ring of the next function
+    /// group. It doesn't depend on the window, so a bucket maps to the same
+    /// member in all windows.
+    pub fn ring_key(bucket: usize) -> String {
+        format!("session-bucket-{}", bucket)
+    }
+
+    /// Returns the bucket of the session key.
+    pub fn bucket(&self, key: &[String]) -> usize {
+        key_member(key, self.buckets)
+    }
+
+    /// Partitions the output by the hash of the session key.
+    ///
+    /// # Returns
+    /// One partition per bucket, in the order of the buckets.
+    pub fn partition(&self, partitions: Vec<Vec<RecordBatch>>) -> Result<Vec<Vec<RecordBatch>>> {
+        distribute(partitions, std::slice::from_ref(&self.key), self.buckets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::metadata::SessionKeys;
+    use datafusion::arrow::array::{Int64Array, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    /// Returns the partial counts of the YSB campaigns, hash partitioned into
+    /// the given number of partitions like the output of an upstream function.
+    fn partial_counts(counts: &[(&str, i64)], partitions: usize) -> Vec<Vec<RecordBatch>> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("campaign_id", DataType::Utf8, false),
+            Field::new("COUNT(UInt8(1))", DataType::Int64, false),
+        ]));
+        let mut output = vec![vec![]; partitions];
+        for (i, (campaign, count)) in counts.iter().enumerate() {
+            output[i % partitions].push(
+                RecordBatch::try_new(
+                    schema.clone(),
+                    vec![
+                        Arc::new(StringArray::from(vec![*campaign])),
+                        Arc::new(Int64Array::from(vec![*count])),
+                    ],
+                )
+                .unwrap(),
+            );
+        }
+        output
+    }
+
+    #[test]
+    fn distribute_ysb_campaigns() -> Result<()> {
+        let campaigns = (0..20)
+            .map(|i| format!("campaign-{:02}", i))
+            .collect::<Vec<_>>();
+        let counts = campaigns
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (c.as_str(), i as i64 + 1))
+            .collect::<Vec<_>>();
+        let keys = vec!["campaign_id".to_string()];
+
+        // The upstream functions have different numbers of output partitions,
+        // and a campaign is at different positions in their outputs.
+        let mut reversed = counts.clone();
+        reversed.reverse();
+        let members = 4;
+        let upstreams = vec![
+            distribute(partial_counts(&counts, 3), &keys, members)?,
+            distribute(partial_counts(&reversed, 5), &keys, members)?,
+            distribute(partial_counts(&counts, 8), &keys, members)?,
+        ];
+
+        // Each member finalizes the counts of the campaigns it receives.
+        let mut totals = HashMap::new();
+        for member in 0..members {
+            let mut finals: HashMap<String, i64> = HashMap::new();
+            for output in &upstreams {
+                assert_eq!(output.len(), members);
+                for batch in &output[member] {
+                    let keys = row_keys(batch, &keys)?;
+                    let counts = batch
+                        .column(1)
+                        .as_any()
+                        .downcast_ref::<Int64Array>()
+                        .unwrap();
+                    for (row, key) in keys.iter().enumerate() {
+                        assert_eq!(key_member(key, members), member);
+                        *finals.entry(key[0].clone()).or_default() += counts.value(row);
+                    }
+                }
+            }
+            for (campaign, count) in finals {
+                // A campaign is finalized by a single member.
+                assert!(totals.insert(campaign, count).is_none());
+            }
+        }
+
+        // The results are the same as the counts of a single function.
+        assert_eq!(totals.len(), campaigns.len());
+        for (i, campaign) in campaigns.iter().enumerate() {
+            assert_eq!(totals[campaign], 3 * (i as i64 + 1));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn partition_by_session_key() -> Result<()> {
+        let affinity = SessionAffinity::new("campaign_id", 8);
+        let counts = (0..20)
+            .map(|i| format!("campaign-{:02}", i))
+            .collect::<Vec<_>>();
+        let counts = counts.iter().map(|c| (c.as_str(), 1)).collect::<Vec<_>>();
+
+        // The buckets of a key are the same whatever the upstream output.
+        let first = affinity.partition(partial_counts(&counts, 3))?;
+        let second = affinity.partition(partial_counts(&counts[5..], 7))?;
+        let mut buckets = HashMap::new();
+        for output in [first, second] {
+            assert_eq!(output.len(), 8);
+            for (bucket, batches) in output.iter().enumerate() {
+                for batch in batches {
+                    for key in row_keys(batch, &["campaign_id".to_string()])? {
+                        assert_eq!(affinity.bucket(&key), bucket);
+                        assert_eq!(*buckets.entry(key).or_insert(bucket), bucket);
+                    }
+                }
+            }
+        }
+        assert_eq!(buckets.len(), 20);
+
+        // The routing of the context wins over the session keys of the payload.
+        let metadata = Some(QueryMetadata {
+            session_keys: Some(SessionKeys {
+                key:  "bidder".to_string(),
+                name: "bid".to_string(),
+            }),
+            ..Default::default()
+        });
+        assert_eq!(
+            SessionAffinity::infer(Some(&affinity), &metadata),
+            Some(affinity)
+        );
+        assert_eq!(
+            SessionAffinity::infer(None, &metadata),
+            Some(SessionAffinity::new("bidder", *FLOCK_SESSION_BUCKETS))
+        );
+        assert_eq!(SessionAffinity::infer(None, &None), None);
+        Ok(())
+    }
+}
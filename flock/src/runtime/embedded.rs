@@ -0,0 +1,210 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The plans of the query stages embedded into the function binary.
+//!
+//! A function deserializes the plans of its stage from the environment on its
+//! cold start, and the plans too large for the 4 KB environment are loaded
+//! from S3. For the deployments of a fixed query, the plans can be compiled
+//! into the function binary instead:
+//!
+//! 1. `flock-cli nexmark plan --export-dir <dir>` writes the plans of each
+//!    stage to `<dir>/<stage>.json` (see [`export_plans`]), e.g. `q3-00.json`.
+//! 2. The build script of `flock-function` embeds the files of the directory in
+//!    [`FLOCK_EMBEDDED_PLANS`] into the binary.
+//! 3. The functions are deployed with the contexts without plans (see
+//!    [`without_plans`]) and the stage to run in [`FLOCK_EMBEDDED_STAGE`], so
+//!    the environment only carries the lightweight fields of the context, e.g.
+//!    the name, the next function and the state backend.
+//!
+//! Without [`FLOCK_EMBEDDED_STAGE`], the function loads its plans from the
+//! environment as before.
+
+use crate::error::{FlockError, Result};
+use crate::runtime::context::ExecutionContext;
+use crate::runtime::plan::CloudExecutionPlan;
+use datafusion::physical_plan::ExecutionPlan;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The environment variable of the function that names the embedded stage it
+/// runs, e.g. `q3-00`.
+pub const FLOCK_EMBEDDED_STAGE: &str = "FLOCK_EMBEDDED_STAGE";
+
+/// The environment variable of the build that points to the directory of the
+/// exported plans to embed into the function binary.
+pub const FLOCK_EMBEDDED_PLANS: &str = "FLOCK_EMBEDDED_PLANS";
+
+/// The extension of the exported plans.
+const PLAN_EXTENSION: &str = "json";
+
+/// Returns the serialized plans of the stage, which are the bytes both the
+/// environment and the function binary carry.
+pub fn stage_plans(ctx: &ExecutionContext) -> Result<String> {
+    Ok(serde_json::to_string(&ctx.plan.execution_plans)?)
+}
+
+/// Writes the plans of each stage to `<dir>/<stage>.json`, and returns the
+/// paths of the files.
+pub fn export_plans<'a, I>(contexts: I, dir: &Path) -> Result<Vec<PathBuf>>
+where
+    I: IntoIterator<Item = &'a ExecutionContext>,
+{
+    std::fs::create_dir_all(dir)?;
+    contexts
+        .into_iter()
+        .map(|ctx| {
+            let path = dir.join(format!("{}.{}", ctx.name, PLAN_EXTENSION));
+            std::fs::write(&path, stage_plans(ctx)?)?;
+            Ok(path)
+        })
+        .collect()
+}
+
+/// Returns the embedded stage that the function runs, or `None` if the
+/// function loads its plans from the environment.
+pub fn embedded_stage() -> Option<String> {
+    std::env::var(FLOCK_EMBEDDED_STAGE)
+        .ok()
+        .filter(|stage| !stage.is_empty())
+}
+
+/// Returns the plans of the stage among the embedded plans.
+///
+/// # Arguments
+/// * `plans` - The serialized plans embedded into the binary, by stage name.
+/// * `stage` - The name of the stage.
+pub fn embedded_plans(plans: &[(&str, &str)], stage: &str) -> Result<Vec<Arc<dyn ExecutionPlan>>> {
+    let (_, json) = plans
+        .iter()
+        .find(|(name, _)| *name == stage)
+        .ok_or_else(|| {
+            FlockError::Plan(format!(
+                "The stage {} isn't embedded in the function binary, which embeds {:?}",
+                stage,
+                plans.iter().map(|(name, _)| *name).collect::<Vec<_>>()
+            ))
+        })?;
+    Ok(serde_json::from_str(json)?)
+}
+
+/// Replaces the plans of the context with the embedded plans of the stage.
+pub async fn embed(ctx: &mut ExecutionContext, plans: &[(&str, &str)], stage: &str) -> Result<()> {
+    let plans = embedded_plans(plans, stage)?;
+    ctx.set_plan(CloudExecutionPlan::new(plans, None)).await;
+    Ok(())
+}
+
+/// Returns the context without its plans, which the functions that run an
+/// embedded stage are deployed with.
+pub fn without_plans(ctx: &ExecutionContext) -> ExecutionContext {
+    ExecutionContext {
+        plan: CloudExecutionPlan::default(),
+        properties: None,
+        ..ctx.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasink::DataSinkType;
+    use crate::datasource::DataSource;
+    use crate::encoding::Encoding;
+    use crate::launcher::{AwsLambdaLauncher, Launcher};
+    use crate::query::{Query, QueryType, Table};
+    use crate::runtime::context::{marshal, unmarshal};
+    use crate::state::HashMapStateBackend;
+    use datafusion::arrow::array::{Int32Array, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::arrow::util::pretty::pretty_format_batches;
+
+    #[tokio::test]
+    async fn embedded_stage_runs_the_same_plans() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Utf8, false),
+            Field::new("b", DataType::Int32, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(vec!["a", "b", "a", "c"])),
+                Arc::new(Int32Array::from(vec![1, 2, 3, 4])),
+            ],
+        )?;
+        let query = Query::new(
+            "SELECT a, SUM(b) AS s FROM t1 WHERE b > 1 GROUP BY a",
+            vec![Table("t1".to_owned(), schema)],
+            DataSource::Memory,
+            DataSinkType::Blackhole,
+            None,
+            QueryType::OLAP,
+            Arc::new(HashMapStateBackend::new()),
+        );
+        let mut launcher = AwsLambdaLauncher::new(&query).await?;
+        launcher.create_cloud_contexts(2)?;
+        let contexts = launcher
+            .dag
+            .get_all_stages()
+            .iter()
+            .map(|stage| stage.context.clone().unwrap())
+            .collect::<Vec<_>>();
+
+        let dir = std::env::temp_dir().join(format!("flock-plans-{}", uuid::Uuid::new_v4()));
+        let paths = export_plans(&contexts, &dir)?;
+        assert_eq!(paths.len(), contexts.len());
+        let files = paths
+            .iter()
+            .map(|path| {
+                let stage = path.file_stem().unwrap().to_str().unwrap().to_owned();
+                Ok((stage, std::fs::read_to_string(path)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let plans = files
+            .iter()
+            .map(|(stage, json)| (stage.as_str(), json.as_str()))
+            .collect::<Vec<_>>();
+
+        for (i, ctx) in contexts.iter().enumerate() {
+            let dynamic_env = marshal(ctx, Encoding::default())?;
+            let embedded_env = marshal(&without_plans(ctx), Encoding::default())?;
+            assert!(embedded_env.len() < dynamic_env.len());
+
+            let dynamic = unmarshal(&dynamic_env)?;
+            let mut embedded = unmarshal(&embedded_env)?;
+            assert!(embedded.plan.execution_plans.is_empty());
+            embed(&mut embedded, &plans, &ctx.name).await?;
+            assert_eq!(stage_plans(&embedded)?, stage_plans(&dynamic)?);
+            assert_eq!(embedded, dynamic);
+
+            // The first stage reads the table, and both contexts compute the
+            // same output from it.
+            if i == 0 {
+                dynamic
+                    .feed_data_sources(vec![vec![vec![batch.clone()]]])
+                    .await?;
+                embedded
+                    .feed_data_sources(vec![vec![vec![batch.clone()]]])
+                    .await?;
+                assert_eq!(
+                    pretty_format_batches(&dynamic.execute().await?.concat())?.to_string(),
+                    pretty_format_batches(&embedded.execute().await?.concat())?.to_string()
+                );
+            }
+        }
+        assert!(embedded_plans(&plans, "q0-99").is_err());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
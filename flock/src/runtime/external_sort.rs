@@ -0,0 +1,666 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The external sort of the `ORDER BY ... LIMIT` stages on large windows.
+//!
+//! A group function holds the whole window in the arena before it executes
+//! the plan, so a top-k query over a large window needs the memory of the
+//! window, although it only emits `LIMIT` rows. If the plan of the stage is a
+//! limited sort (see [`ExternalSortSpec::from_plans`]) and the window is
+//! estimated to outgrow `external_sort_budget`, the function sorts the rows of
+//! each payload on arrival instead (see [`ExternalSorter::push`]), and keeps
+//! the first `LIMIT` rows of each sorted run. Once the runs held in memory
+//! exceed the budget, they are spilled to `external_sort_dir` as Arrow IPC
+//! files. When the window is complete, the runs are merged in a k-way merge
+//! that stops at the limit (see [`ExternalSorter::finish`]), reading the
+//! spilled runs batch by batch.
+//!
+//! If the runs can't be spilled, i.e. the spill directory can't be written or
+//! the spilled runs would exceed `external_sort_disk_limit`, the sorter keeps
+//! them in memory, like the arena would have kept the window.
+
+use crate::configs::{
+    FLOCK_EXTERNAL_SORT_BUDGET, FLOCK_EXTERNAL_SORT_DIR, FLOCK_EXTERNAL_SORT_DISK_LIMIT,
+};
+use crate::error::{FlockError, Result};
+use crate::runtime::metrics::{self, Metric};
+use crate::runtime::plan::{feed_memory_sources, PlanProperties};
+use datafusion::arrow::array::ArrayRef;
+use datafusion::arrow::compute::{concat, SortOptions};
+use datafusion::arrow::ipc::reader::FileReader;
+use datafusion::arrow::ipc::writer::FileWriter;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::physical_plan::coalesce_batches::CoalesceBatchesExec;
+use datafusion::physical_plan::coalesce_partitions::CoalescePartitionsExec;
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::limit::{GlobalLimitExec, LocalLimitExec};
+use datafusion::physical_plan::sort::SortExec;
+use datafusion::physical_plan::sort_preserving_merge::SortPreservingMergeExec;
+use datafusion::physical_plan::{collect, ExecutionPlan};
+use datafusion::scalar::ScalarValue;
+use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// The sort of a stage that can be evaluated externally.
+#[derive(Debug, Clone)]
+pub struct ExternalSortSpec {
+    /// The sort expressions of the sort.
+    pub expr:  Vec<PhysicalSortExpr>,
+    /// The input of the sort, which transforms the rows of the window one by
+    /// one, so that it can be evaluated on each payload separately.
+    pub input: Arc<dyn ExecutionPlan>,
+    /// The number of rows emitted by the stage.
+    pub limit: usize,
+}
+
+impl ExternalSortSpec {
+    /// Returns the external sort of the stage if its plan is a limited sort,
+    /// i.e. a `SortExec` under the limits, the coalesces and the merges of the
+    /// sorted partitions, whose input neither aggregates, joins, sorts nor
+    /// limits the rows.
+    ///
+    /// # Arguments
+    /// * `plans` - The plans of the stage.
+    /// * `properties` - The properties of the plans (see
+    ///   [`PlanProperties::analyze_all`]).
+    pub fn from_plans(
+        plans: &[Arc<dyn ExecutionPlan>],
+        properties: &PlanProperties,
+    ) -> Option<ExternalSortSpec> {
+        if plans.len() != 1 || !properties.has_sort_limit() {
+            return None;
+        }
+        if properties.has_join || properties.has_aggregate() || properties.has_window_fn {
+            return None;
+        }
+
+        let mut limit: Option<usize> = None;
+        let mut node = plans[0].clone();
+        loop {
+            let any = node.as_any();
+            let node_limit = if let Some(exec) = any.downcast_ref::<GlobalLimitExec>() {
+                Some(exec.limit())
+            } else if let Some(exec) = any.downcast_ref::<LocalLimitExec>() {
+                Some(exec.limit())
+            } else if let Some(sort) = any.downcast_ref::<SortExec>() {
+                let input = PlanProperties::analyze(sort.input());
+                if input.has_sort || input.has_limit {
+                    return None;
+                }
+                return limit.map(|limit| ExternalSortSpec {
+                    expr: sort.expr().to_vec(),
+                    input: sort.input().clone(),
+                    limit,
+                });
+            } else if any.is::<CoalescePartitionsExec>()
+                || any.is::<CoalesceBatchesExec>()
+                || any.is::<SortPreservingMergeExec>()
+            {
+                None
+            } else {
+                return None;
+            };
+            if let Some(node_limit) = node_limit {
+                limit = Some(limit.map_or(node_limit, |l| l.min(node_limit)));
+            }
+            node = node.children().into_iter().next()?;
+        }
+    }
+
+    /// Returns true if the window, whose first payload has the given record
+    /// batches, is estimated to outgrow the budget.
+    ///
+    /// # Arguments
+    /// * `batches` - The record batches of the first payload of the window.
+    /// * `seq_len` - The number of payloads of the window.
+    /// * `budget` - The memory budget in bytes.
+    pub fn exceeds_budget(batches: &[RecordBatch], seq_len: usize, budget: usize) -> bool {
+        batches_bytes(batches).saturating_mul(seq_len) > budget
+    }
+
+    /// Sorts the record batches of a payload with the input of the sort, and
+    /// keeps the first `limit` rows.
+    async fn sort(&self, batches: Vec<RecordBatch>) -> Result<Vec<RecordBatch>> {
+        let input = if self.input.output_partitioning().partition_count() > 1 {
+            Arc::new(CoalescePartitionsExec::new(self.input.clone()))
+        } else {
+            self.input.clone()
+        };
+        let plan: Arc<dyn ExecutionPlan> = Arc::new(GlobalLimitExec::new(
+            Arc::new(SortExec::try_new(self.expr.clone(), input)?),
+            self.limit,
+        ));
+        feed_memory_sources(vec![plan.clone()], vec![vec![batches]])?;
+        let run = collect(plan.clone()).await;
+        // The leaves are shared with the plan of the stage, so they don't keep
+        // the payload alive.
+        feed_memory_sources(vec![plan], vec![])?;
+        Ok(run?
+            .into_iter()
+            .filter(|batch| batch.num_rows() > 0)
+            .collect())
+    }
+}
+
+/// A sorted run of the window.
+#[derive(Debug)]
+enum SortedRun {
+    /// The run held in memory.
+    Memory(Vec<RecordBatch>),
+    /// The run spilled to the local disk as an Arrow IPC file.
+    Disk(PathBuf),
+}
+
+/// The external sort of a window.
+#[derive(Debug)]
+pub struct ExternalSorter {
+    spec:         ExternalSortSpec,
+    /// The size in bytes of the runs held in memory before they are spilled.
+    budget:       usize,
+    /// The directory of the spilled runs of the window.
+    dir:          PathBuf,
+    /// False once the runs can't be spilled to the directory.
+    can_spill:    bool,
+    /// The size in bytes of the runs that may be spilled to the directory.
+    disk_limit:   usize,
+    runs:         Vec<SortedRun>,
+    memory_bytes: usize,
+    disk_bytes:   usize,
+}
+
+impl ExternalSorter {
+    /// Creates the external sort of a window.
+    ///
+    /// # Arguments
+    /// * `spec` - The sort of the stage.
+    /// * `budget` - The size in bytes of the runs held in memory.
+    /// * `dir` - The directory of the spilled runs, under which each window has
+    ///   its own directory.
+    /// * `disk_limit` - The size in bytes of the runs that may be spilled.
+    pub fn new(
+        spec: ExternalSortSpec,
+        budget: usize,
+        dir: impl AsRef<Path>,
+        disk_limit: usize,
+    ) -> ExternalSorter {
+        let dir = dir.as_ref().join(format!("sort-{}", uuid::Uuid::new_v4()));
+        let can_spill = match fs::create_dir_all(&dir) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!(
+                    "The sorted runs can't be spilled to {}, and are kept in memory: {}",
+                    dir.display(),
+                    e
+                );
+                false
+            }
+        };
+        ExternalSorter {
+            spec,
+            budget,
+            dir,
+            can_spill,
+            disk_limit,
+            runs: vec![],
+            memory_bytes: 0,
+            disk_bytes: 0,
+        }
+    }
+
+    /// Creates the external sort of a window with the budget and the spill
+    /// directory in `FLOCK_CONF["lambda"]`.
+    pub fn from_conf(spec: ExternalSortSpec) -> ExternalSorter {
+        Self::new(
+            spec,
+            *FLOCK_EXTERNAL_SORT_BUDGET,
+            FLOCK_EXTERNAL_SORT_DIR.as_str(),
+            *FLOCK_EXTERNAL_SORT_DISK_LIMIT,
+        )
+    }
+
+    /// Sorts the record batches of a payload into a run, and spills the runs
+    /// held in memory once they exceed the budget.
+    pub async fn push(&mut self, batches: Vec<RecordBatch>) -> Result<()> {
+        let run = self.spec.sort(batches).await?;
+        if run.is_empty() {
+            return Ok(());
+        }
+        self.memory_bytes += batches_bytes(&run);
+        self.runs.push(SortedRun::Memory(run));
+        if self.memory_bytes > self.budget {
+            self.spill();
+        }
+        Ok(())
+    }
+
+    /// Returns the number of the sorted runs of the window.
+    pub fn num_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Returns the number of the sorted runs spilled to the local disk.
+    pub fn num_spilled(&self) -> usize {
+        self.runs
+            .iter()
+            .filter(|run| matches!(run, SortedRun::Disk(_)))
+            .count()
+    }
+
+    /// Spills the runs held in memory to the local disk, until the disk limit
+    /// is reached or a spill fails.
+    fn spill(&mut self) {
+        for (i, run) in self.runs.iter_mut().enumerate() {
+            if !self.can_spill {
+                return;
+            }
+            let batches = match run {
+                SortedRun::Memory(batches) => batches,
+                SortedRun::Disk(_) => continue,
+            };
+            let bytes = batches_bytes(batches);
+            if self.disk_bytes + bytes > self.disk_limit {
+                warn!(
+                    "The spilled runs would exceed {} bytes, and are kept in memory.",
+                    self.disk_limit
+                );
+                self.can_spill = false;
+                return;
+            }
+            let path = self.dir.join(format!("run-{}.arrow", i));
+            match write_run(&path, batches) {
+                Ok(()) => {
+                    *run = SortedRun::Disk(path);
+                    self.memory_bytes -= bytes;
+                    self.disk_bytes += bytes;
+                    metrics::scope().incr(Metric::SortedRunSpills);
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to spill the sorted run to {}, and the runs are kept in memory: \
+                         {:?}",
+                        path.display(),
+                        e
+                    );
+                    let _ = fs::remove_file(&path);
+                    self.can_spill = false;
+                }
+            }
+        }
+    }
+
+    /// Merges the sorted runs of the window, and returns the first `limit`
+    /// rows in the order of the sort.
+    pub fn finish(&mut self) -> Result<Vec<RecordBatch>> {
+        let expr = self.spec.expr.clone();
+        let options = expr.iter().map(|e| e.options).collect::<Vec<_>>();
+        let mut cursors = vec![];
+        for run in self.runs.drain(..) {
+            let batches: Box<dyn Iterator<Item = Result<RecordBatch>>> = match run {
+                SortedRun::Memory(batches) => Box::new(batches.into_iter().map(Ok)),
+                SortedRun::Disk(path) => Box::new(
+                    FileReader::try_new(BufReader::new(File::open(&path)?))?
+                        .map(|batch| batch.map_err(FlockError::Arrow)),
+                ),
+            };
+            if let Some(cursor) = RunCursor::try_new(&expr, batches)? {
+                cursors.push(cursor);
+            }
+        }
+        info!(
+            "Merging {} sorted runs ({} bytes spilled).",
+            cursors.len(),
+            self.disk_bytes
+        );
+
+        // The earlier runs win the ties.
+        let mut rows = vec![];
+        while rows.len() < self.spec.limit {
+            let next = cursors
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| compare_rows(&a.current, &b.current, &options))
+                .map(|(i, _)| i);
+            let i = match next {
+                Some(i) => i,
+                None => break,
+            };
+            rows.push(cursors[i].batch.slice(cursors[i].row, 1));
+            if !cursors[i].advance()? {
+                cursors.remove(i);
+            }
+        }
+        self.memory_bytes = 0;
+
+        if rows.is_empty() {
+            return Ok(vec![]);
+        }
+        let schema = rows[0].schema();
+        let columns = (0..schema.fields().len())
+            .map(|i| {
+                let arrays = rows
+                    .iter()
+                    .map(|b| b.column(i).as_ref())
+                    .collect::<Vec<_>>();
+                concat(&arrays)
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(vec![RecordBatch::try_new(schema, columns)?])
+    }
+}
+
+impl Drop for ExternalSorter {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// The current row of a sorted run in the k-way merge.
+struct RunCursor<'a> {
+    expr:    &'a [PhysicalSortExpr],
+    batches: Box<dyn Iterator<Item = Result<RecordBatch>>>,
+    batch:   RecordBatch,
+    keys:    Vec<ArrayRef>,
+    row:     usize,
+    /// The sort keys of the current row.
+    current: Vec<ScalarValue>,
+}
+
+impl<'a> RunCursor<'a> {
+    /// Returns the cursor at the first row of the run, or `None` if the run is
+    /// empty.
+    fn try_new(
+        expr: &'a [PhysicalSortExpr],
+        mut batches: Box<dyn Iterator<Item = Result<RecordBatch>>>,
+    ) -> Result<Option<RunCursor<'a>>> {
+        match next_batch(expr, &mut batches)? {
+            Some((batch, keys)) => {
+                let mut cursor = RunCursor {
+                    expr,
+                    batches,
+                    batch,
+                    keys,
+                    row: 0,
+                    current: vec![],
+                };
+                cursor.current = cursor.key_values()?;
+                Ok(Some(cursor))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Moves to the next row of the run, and returns false at the end of it.
+    fn advance(&mut self) -> Result<bool> {
+        self.row += 1;
+        if self.row == self.batch.num_rows() {
+            match next_batch(self.expr, &mut self.batches)? {
+                Some((batch, keys)) => {
+                    self.batch = batch;
+                    self.keys = keys;
+                    self.row = 0;
+                }
+                None => return Ok(false),
+            }
+        }
+        self.current = self.key_values()?;
+        Ok(true)
+    }
+
+    fn key_values(&self) -> Result<Vec<ScalarValue>> {
+        self.keys
+            .iter()
+            .map(|key| Ok(ScalarValue::try_from_array(key, self.row)?))
+            .collect()
+    }
+}
+
+/// Returns the next non-empty batch of a run with its sort keys.
+fn next_batch(
+    expr: &[PhysicalSortExpr],
+    batches: &mut Box<dyn Iterator<Item = Result<RecordBatch>>>,
+) -> Result<Option<(RecordBatch, Vec<ArrayRef>)>> {
+    for batch in batches {
+        let batch = batch?;
+        if batch.num_rows() == 0 {
+            continue;
+        }
+        let keys = expr
+            .iter()
+            .map(|e| Ok(e.expr.evaluate(&batch)?.into_array(batch.num_rows())))
+            .collect::<Result<Vec<_>>>()?;
+        return Ok(Some((batch, keys)));
+    }
+    Ok(None)
+}
+
+/// Compares the sort keys of two rows by the options of the sort.
+fn compare_rows(left: &[ScalarValue], right: &[ScalarValue], options: &[SortOptions]) -> Ordering {
+    for ((l, r), options) in left.iter().zip(right).zip(options) {
+        let ordering = match (l.is_null(), r.is_null()) {
+            (true, true) => Ordering::Equal,
+            (true, false) if options.nulls_first => Ordering::Less,
+            (true, false) => Ordering::Greater,
+            (false, true) if options.nulls_first => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            (false, false) => {
+                let ordering = l.partial_cmp(r).unwrap_or(Ordering::Equal);
+                if options.descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Writes a sorted run to an Arrow IPC file.
+fn write_run(path: &Path, batches: &[RecordBatch]) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let mut writer = FileWriter::try_new(&mut file, &batches[0].schema())?;
+    for batch in batches {
+        writer.write(batch)?;
+    }
+    writer.finish()?;
+    drop(writer);
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(())
+}
+
+/// Returns the size in bytes of the record batches in memory.
+fn batches_bytes(batches: &[RecordBatch]) -> usize {
+    batches
+        .iter()
+        .flat_map(|batch| batch.columns())
+        .map(|array| array.get_array_memory_size())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::array::{Array, Int64Array, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+    use datafusion::physical_plan::expressions::col;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("auction", DataType::Int64, false),
+            Field::new("price", DataType::Int64, true),
+            Field::new("bidder", DataType::Utf8, false),
+        ]))
+    }
+
+    /// The payloads of a window, with one batch each.
+    fn payloads(num_payloads: usize, rows: usize) -> Vec<Vec<RecordBatch>> {
+        let mut rng = StdRng::seed_from_u64(42);
+        (0..num_payloads)
+            .map(|p| {
+                let auctions = (0..rows).map(|i| (p * rows + i) as i64).collect::<Vec<_>>();
+                let prices = (0..rows)
+                    .map(|_| (rng.gen_range(0..20) != 0).then(|| rng.gen_range(0..1000)))
+                    .collect::<Vec<_>>();
+                let bidders = (0..rows)
+                    .map(|i| format!("bidder-{}", i % 7))
+                    .collect::<Vec<_>>();
+                vec![RecordBatch::try_new(
+                    schema(),
+                    vec![
+                        Arc::new(Int64Array::from(auctions)),
+                        Arc::new(Int64Array::from(prices)),
+                        Arc::new(StringArray::from(bidders)),
+                    ],
+                )
+                .unwrap()]
+            })
+            .collect()
+    }
+
+    /// `ORDER BY price DESC NULLS LAST, auction LIMIT <limit>`
+    fn top_k_plan(limit: usize) -> Result<Arc<dyn ExecutionPlan>> {
+        let schema = schema();
+        let leaf = Arc::new(MemoryExec::try_new(&[vec![]], schema.clone(), None)?);
+        let expr = vec![
+            PhysicalSortExpr {
+                expr:    col("price", &schema)?,
+                options: SortOptions {
+                    descending:  true,
+                    nulls_first: false,
+                },
+            },
+            PhysicalSortExpr {
+                expr:    col("auction", &schema)?,
+                options: SortOptions::default(),
+            },
+        ];
+        Ok(Arc::new(GlobalLimitExec::new(
+            Arc::new(SortExec::try_new(expr, leaf)?),
+            limit,
+        )))
+    }
+
+    fn rows(batches: &[RecordBatch]) -> Vec<(i64, Option<i64>, String)> {
+        batches
+            .iter()
+            .flat_map(|batch| {
+                let column = |i: usize| batch.column(i).as_any();
+                let auctions = column(0).downcast_ref::<Int64Array>().unwrap();
+                let prices = column(1).downcast_ref::<Int64Array>().unwrap();
+                let bidders = column(2).downcast_ref::<StringArray>().unwrap();
+                (0..batch.num_rows())
+                    .map(|i| {
+                        (
+                            auctions.value(i),
+                            prices.is_valid(i).then(|| prices.value(i)),
+                            bidders.value(i).to_string(),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    async fn in_memory_sort(
+        plan: &Arc<dyn ExecutionPlan>,
+        window: &[Vec<RecordBatch>],
+    ) -> Result<Vec<RecordBatch>> {
+        feed_memory_sources(vec![plan.clone()], vec![vec![window.concat()]])?;
+        Ok(collect(plan.clone()).await?)
+    }
+
+    #[test]
+    fn detect_limited_sorts() -> Result<()> {
+        let plan = top_k_plan(10)?;
+        let spec = ExternalSortSpec::from_plans(&[plan.clone()], &PlanProperties::analyze(&plan));
+        assert_eq!(spec.map(|s| (s.limit, s.expr.len())), Some((10, 2)));
+
+        // A sort without a limit is left to the arena.
+        let sort = plan.children()[0].clone();
+        assert!(
+            ExternalSortSpec::from_plans(&[sort.clone()], &PlanProperties::analyze(&sort))
+                .is_none()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn external_sort_equals_in_memory_sort() -> Result<()> {
+        let window = payloads(24, 500);
+        let plan = top_k_plan(100)?;
+        let expected = rows(&in_memory_sort(&plan, &window).await?);
+        assert_eq!(expected.len(), 100);
+
+        // The budget is a fraction of the window, so most runs are spilled.
+        let budget = batches_bytes(&window.concat()) / 8;
+        assert!(ExternalSortSpec::exceeds_budget(
+            &window[0],
+            window.len(),
+            budget
+        ));
+        let spec =
+            ExternalSortSpec::from_plans(&[plan.clone()], &PlanProperties::analyze(&plan)).unwrap();
+        let root = std::env::temp_dir().join(format!("flock-sort-{}", uuid::Uuid::new_v4()));
+        let mut sorter = ExternalSorter::new(spec, budget, &root, usize::MAX);
+        for batches in &window {
+            sorter.push(batches.clone()).await?;
+        }
+        assert_eq!(sorter.num_runs(), window.len());
+        assert!(sorter.num_spilled() > 0);
+        assert_eq!(rows(&sorter.finish()?), expected);
+
+        // The spilled runs are removed with the sorter.
+        drop(sorter);
+        assert_eq!(fs::read_dir(&root)?.count(), 0);
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_runs_in_memory_without_disk() -> Result<()> {
+        let window = payloads(8, 300);
+        let plan = top_k_plan(50)?;
+        let expected = rows(&in_memory_sort(&plan, &window).await?);
+        let spec =
+            ExternalSortSpec::from_plans(&[plan.clone()], &PlanProperties::analyze(&plan)).unwrap();
+
+        // The spill directory can't be created under a file, and the disk limit
+        // is too small for any run.
+        let file = std::env::temp_dir().join(format!("flock-sort-{}", uuid::Uuid::new_v4()));
+        fs::write(&file, b"")?;
+        let sorters = vec![
+            ExternalSorter::new(spec.clone(), 0, &file, usize::MAX),
+            ExternalSorter::new(spec, 0, std::env::temp_dir(), 1),
+        ];
+        for mut sorter in sorters {
+            for batches in &window {
+                sorter.push(batches.clone()).await?;
+            }
+            assert_eq!(sorter.num_runs(), window.len());
+            assert_eq!(sorter.num_spilled(), 0);
+            assert_eq!(rows(&sorter.finish()?), expected);
+        }
+        fs::remove_file(&file)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! This module contains the [`FunctionName`] type, which parses and formats
+//! the names of the cloud functions of the query stages.
+//!
+//! |      Cloud Function Naming Convention       |
+//! |---------------------------------------------|
+//! |  query code  -  plan index  -  group index  |
+//!
+//! The plan index and the group index are 2-digit numbers, and the group index
+//! is only present for the members of a function group. The query code must
+//! not contain '-', so that the fields are delimited unambiguously.
+//!
+//! The query code is also the first component of the S3 keys of the query in
+//! the Flock bucket (see [`query_key`]), so that the queries sharing the bucket
+//! never write to each other's keys.
+
+use crate::error::{FlockError, Result};
+use chrono::NaiveDateTime;
+use std::fmt;
+use std::str::FromStr;
+
+/// The maximum length of a function name in AWS Lambda.
+const MAX_FUNCTION_NAME_LEN: usize = 64;
+
+/// The parsed name of a cloud function of a query stage.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FunctionName {
+    /// The query code, which identifies the query.
+    pub query_code:  String,
+    /// The index of the query stage in the DAG, i.e. `00` for the first stage
+    /// to execute.
+    pub plan_index:  usize,
+    /// The index of the function in its group. `None` if the function isn't a
+    /// member of a function group.
+    pub group_index: Option<usize>,
+}
+
+impl FunctionName {
+    /// Creates the name of a single function.
+    pub fn lambda(query_code: &str, plan_index: usize) -> Result<Self> {
+        Self::new(query_code, plan_index, None)
+    }
+
+    /// Creates the name of a member of a function group.
+    pub fn group_member(query_code: &str, plan_index: usize, group_index: usize) -> Result<Self> {
+        Self::new(query_code, plan_index, Some(group_index))
+    }
+
+    fn new(query_code: &str, plan_index: usize, group_index: Option<usize>) -> Result<Self> {
+        validate_query_code(query_code)?;
+        if plan_index > 99 || group_index.map_or(false, |g| g > 99) {
+            return Err(FlockError::FunctionGeneration(format!(
+                "The plan index {} or the group index {:?} exceeds 99",
+                plan_index, group_index
+            )));
+        }
+        let name = FunctionName {
+            query_code: query_code.to_owned(),
+            plan_index,
+            group_index,
+        };
+        if name.to_string().len() > MAX_FUNCTION_NAME_LEN {
+            return Err(FlockError::FunctionGeneration(format!(
+                "The function name {} is longer than {} characters",
+                name, MAX_FUNCTION_NAME_LEN
+            )));
+        }
+        Ok(name)
+    }
+
+    /// Parses a function name: `<query code>-<plan index>[-<group index>]`.
+    ///
+    /// The names generated by the deprecated `QueryFlow`, i.e. `<query
+    /// code>-<plan index>-<timestamp>`, are parsed as single functions.
+    pub fn parse(name: &str) -> Result<Self> {
+        let invalid = |reason: &str| {
+            FlockError::FunctionGeneration(format!("Invalid function name {}: {}", name, reason))
+        };
+
+        let mut parts = name.splitn(3, '-');
+        let query_code = parts.next().unwrap_or_default();
+        validate_query_code(query_code).map_err(|_| invalid("invalid query code"))?;
+        let plan_index = parts
+            .next()
+            .and_then(parse_index)
+            .ok_or_else(|| invalid("the plan index must be a 2-digit number"))?;
+        let group_index = match parts.next() {
+            None => None,
+            Some(s) => match parse_index(s) {
+                Some(group_index) => Some(group_index),
+                None if NaiveDateTime::from_str(s.trim_end_matches('Z')).is_ok() => None,
+                None => return Err(invalid("the group index must be a 2-digit number")),
+            },
+        };
+
+        Ok(FunctionName {
+            query_code: query_code.to_owned(),
+            plan_index,
+            group_index,
+        })
+    }
+
+    /// Returns true if the function is a member of a function group.
+    pub fn is_group_member(&self) -> bool {
+        self.group_index.is_some()
+    }
+}
+
+impl fmt::Display for FunctionName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{:02}", self.query_code, self.plan_index)?;
+        if let Some(group_index) = self.group_index {
+            write!(f, "-{:02}", group_index)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for FunctionName {
+    type Err = FlockError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        FunctionName::parse(s)
+    }
+}
+
+/// Returns an error if the query code is empty or contains characters other
+/// than ASCII letters, digits and '_'. In particular, '-' is the delimiter of
+/// the fields of the function names.
+pub fn validate_query_code(query_code: &str) -> Result<()> {
+    if query_code.is_empty()
+        || !query_code
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(FlockError::FunctionGeneration(format!(
+            "Invalid query code {:?}: only ASCII letters, digits and '_' are allowed",
+            query_code
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the query code of a function name. The names of the other functions
+/// (e.g. the data source function) are returned as a whole.
+pub fn query_code_of(name: &str) -> &str {
+    // The query code doesn't contain '-' (see `validate_query_code`).
+    name.split_once('-')
+        .map_or(name, |(query_code, _)| query_code)
+}
+
+/// Returns the S3 key `<query code>/<key>` of an object of the query in the
+/// Flock bucket. All objects that the runtime writes to the bucket are under
+/// the prefix of their query, so they can be listed or deleted per query.
+pub fn query_key(query_code: &str, key: &str) -> String {
+    format!("{}/{}", query_code, key)
+}
+
+/// Parses a 2-digit index.
+fn parse_index(s: &str) -> Option<usize> {
+    if s.len() == 2 && s.bytes().all(|b| b.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_function_names() -> Result<()> {
+        let lambda = FunctionName::parse("q5-00")?;
+        assert_eq!(lambda, FunctionName::lambda("q5", 0)?);
+        assert!(!lambda.is_group_member());
+        assert_eq!(lambda.to_string(), "q5-00");
+
+        let member = FunctionName::parse("ysb_2-01-15")?;
+        assert_eq!(member, FunctionName::group_member("ysb_2", 1, 15)?);
+        assert!(member.is_group_member());
+        assert_eq!(member.to_string(), "ysb_2-01-15");
+
+        // The hashed query codes of the launcher.
+        let name = FunctionName::parse("10758357735536040893-02")?;
+        assert_eq!(name.query_code, "10758357735536040893");
+        assert_eq!(name.plan_index, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_legacy_function_names() -> Result<()> {
+        let name = FunctionName::parse("SX72HzqFz1Qij4bP-00-2021-01-28T19:27:50.298504836")?;
+        assert_eq!(name.query_code, "SX72HzqFz1Qij4bP");
+        assert_eq!(name.plan_index, 0);
+        assert!(!name.is_group_member());
+
+        let name = FunctionName::parse("SX72HzqFz1Qij4bP-03-2021-01-28T19:27:50.298504836Z")?;
+        assert_eq!(name.plan_index, 3);
+        assert_eq!(name.group_index, None);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_function_names() {
+        for name in [
+            "",
+            "q5",
+            "q5-",
+            "q5-0",
+            "q5-000",
+            "q5-ab",
+            "-00",
+            "q5-00-1",
+            "q5-00-01-02",
+            "q5-00-xyz",
+            "flock_datasource",
+            "q/5-00",
+        ] {
+            assert!(FunctionName::parse(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn unlucky_query_codes() {
+        // A query code with '-' would be parsed as a different function.
+        assert!(FunctionName::lambda("ab-12", 0).is_err());
+        assert!(FunctionName::group_member("a-b", 1, 2).is_err());
+        assert!(validate_query_code("Zm9v-YmFy").is_err());
+        assert!(validate_query_code("Zm9v+YmFy").is_err());
+        assert!(validate_query_code("Zm9v_YmFy").is_ok());
+
+        assert!(FunctionName::lambda("q5", 100).is_err());
+        assert!(FunctionName::group_member("q5", 1, 100).is_err());
+        assert!(FunctionName::lambda(&"q".repeat(64), 0).is_err());
+    }
+
+    #[test]
+    fn query_code_of_names() {
+        assert_eq!(query_code_of("q5-00-01"), "q5");
+        assert_eq!(query_code_of("q5-01"), "q5");
+        assert_eq!(query_code_of("flock_datasource"), "flock_datasource");
+    }
+}
@@ -0,0 +1,398 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The lint pass over the query stages before they are deployed.
+//!
+//! Some operators of DataFusion don't survive the serde round trip of the
+//! execution context, or aren't supported by the cloud functions, which would
+//! otherwise be discovered only when the deployed function panics.
+//! [`lint_dag`] walks the plan of each query stage, and reports:
+//!
+//! * the operators missing from [`SUPPORTED_OPERATORS`] as warnings;
+//! * the marshalled contexts over the 4 KB limit of the environment variables
+//!   of AWS Lambda as warnings;
+//! * the plans that don't round-trip through `serde_json` as errors;
+//! * the leaves that aren't `MemoryExec`, which the functions can't feed with
+//!   the payloads, as errors;
+//! * the shuffling stages that don't hash partition their output by the
+//!   distribution keys of the query, as errors.
+//!
+//! The warnings are bypassed with `--force`, but the errors never are.
+
+use crate::distributed_plan::QueryDag;
+use crate::encoding::Encoding;
+use crate::error::{FlockError, Result};
+use crate::runtime::context::{marshal, ExecutionContext};
+use crate::runtime::plan::{operator_name, partition_keys, SUPPORTED_OPERATORS};
+use daggy::NodeIndex;
+use datafusion::physical_plan::memory::MemoryExec;
+use datafusion::physical_plan::ExecutionPlan;
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+/// The maximum size in bytes of all environment variables of an AWS Lambda
+/// function, where the marshalled execution context is stored.
+pub const ENVIRONMENT_LIMIT: usize = 4096;
+
+/// The severity of a lint issue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum LintLevel {
+    /// The query may fail at runtime. It's deployed with `--force`.
+    Warning,
+    /// The query can't run on the cloud functions.
+    Error,
+}
+
+/// The check that found a lint issue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum LintRule {
+    /// The operator isn't in [`SUPPORTED_OPERATORS`].
+    UnsupportedOperator,
+    /// The plan doesn't round-trip through `serde_json`.
+    RoundTrip,
+    /// The leaf of the plan isn't a `MemoryExec`.
+    Leaf,
+    /// The marshalled context exceeds [`ENVIRONMENT_LIMIT`].
+    ContextSize,
+    /// The stage isn't hash partitioned by its distribution keys.
+    DistributionKeys,
+}
+
+/// An issue found in a query stage.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LintIssue {
+    /// The severity of the issue.
+    pub level:   LintLevel,
+    /// The check that found the issue.
+    pub rule:    LintRule,
+    /// The plan index of the query stage.
+    pub stage:   usize,
+    /// The description of the issue.
+    pub message: String,
+}
+
+impl LintIssue {
+    fn new(level: LintLevel, rule: LintRule, stage: usize, message: String) -> Self {
+        Self {
+            level,
+            rule,
+            stage,
+            message,
+        }
+    }
+}
+
+impl Display for LintIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} in stage {:02} ({:?}): {}",
+            self.level, self.stage, self.rule, self.message
+        )
+    }
+}
+
+/// The issues found in the query stages.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct LintReport {
+    /// The issues in the order of the stages.
+    pub issues: Vec<LintIssue>,
+}
+
+impl LintReport {
+    /// Returns the issues of the given severity.
+    pub fn issues_of(&self, level: LintLevel) -> Vec<&LintIssue> {
+        self.issues.iter().filter(|i| i.level == level).collect()
+    }
+
+    /// Returns an error listing the issues that block the deployment: the
+    /// errors, and the warnings unless `force` is set.
+    pub fn check(&self, force: bool) -> Result<()> {
+        let blocking = self
+            .issues
+            .iter()
+            .filter(|i| i.level == LintLevel::Error || !force)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>();
+        if blocking.is_empty() {
+            Ok(())
+        } else {
+            Err(FlockError::Plan(format!(
+                "The query plan failed the lint{}:\n{}",
+                if force {
+                    ""
+                } else {
+                    " (use --force to bypass the warnings)"
+                },
+                blocking.join("\n")
+            )))
+        }
+    }
+}
+
+/// Lints the plan of a query stage.
+///
+/// # Arguments
+/// * `stage` - The plan index of the query stage.
+/// * `plan` - The plan of the query stage.
+pub fn lint_plan(stage: usize, plan: &Arc<dyn ExecutionPlan>) -> Vec<LintIssue> {
+    let mut issues = vec![];
+    if let Err(e) = round_trip(plan) {
+        issues.push(LintIssue::new(
+            LintLevel::Error,
+            LintRule::RoundTrip,
+            stage,
+            e.to_string(),
+        ));
+        return issues;
+    }
+
+    let mut nodes = vec![plan.clone()];
+    while let Some(node) = nodes.pop() {
+        let name = operator_name(&node).unwrap_or_default();
+        if !SUPPORTED_OPERATORS.contains(&name.as_str()) {
+            issues.push(LintIssue::new(
+                LintLevel::Warning,
+                LintRule::UnsupportedOperator,
+                stage,
+                format!("{} isn't supported by the cloud functions", name),
+            ));
+        }
+        if node.children().is_empty() && !node.as_any().is::<MemoryExec>() {
+            issues.push(LintIssue::new(
+                LintLevel::Error,
+                LintRule::Leaf,
+                stage,
+                format!("the leaf {} can't be fed with the payloads", name),
+            ));
+        }
+        nodes.extend(node.children());
+    }
+    issues
+}
+
+/// Checks that the plan is the same after the serde round trip.
+fn round_trip(plan: &Arc<dyn ExecutionPlan>) -> Result<()> {
+    let value = serde_json::to_value(plan)
+        .map_err(|e| FlockError::Plan(format!("the plan can't be serialized: {}", e)))?;
+    let plan: Arc<dyn ExecutionPlan> = serde_json::from_value(value.clone())
+        .map_err(|e| FlockError::Plan(format!("the plan can't be deserialized: {}", e)))?;
+    if serde_json::to_value(&plan)? != value {
+        return Err(FlockError::Plan(
+            "the plan changes in the serde round trip".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Lints the distribution keys of a query stage, which must be the hash
+/// partitioning columns of its plans in any order. Otherwise, the rows of a
+/// group of the next stage are spread across the members of the function
+/// group, and the results are wrong.
+fn lint_distribution(stage: usize, ctx: &ExecutionContext) -> Option<LintIssue> {
+    if ctx.distribution_keys.is_empty() {
+        return None;
+    }
+    let mut declared = ctx.distribution_keys.clone();
+    declared.sort();
+    let message = match partition_keys(&ctx.plan.execution_plans) {
+        Some(mut keys) => {
+            keys.sort();
+            if keys == declared {
+                return None;
+            }
+            format!(
+                "the output is hash partitioned by {:?}, not by the distribution keys {:?}",
+                keys, declared
+            )
+        }
+        None => format!(
+            "the output isn't hash partitioned, so it can't be distributed by {:?}",
+            declared
+        ),
+    };
+    Some(LintIssue::new(
+        LintLevel::Error,
+        LintRule::DistributionKeys,
+        stage,
+        message,
+    ))
+}
+
+/// Lints the execution context of a query stage: its plans, its distribution
+/// keys and the size of the marshalled context.
+pub fn lint_context(stage: usize, ctx: &ExecutionContext) -> Vec<LintIssue> {
+    let mut issues = ctx
+        .plan
+        .execution_plans
+        .iter()
+        .flat_map(|plan| lint_plan(stage, plan))
+        .collect::<Vec<_>>();
+    issues.extend(lint_distribution(stage, ctx));
+    match marshal(ctx, Encoding::default()) {
+        Ok(env) if env.len() > ENVIRONMENT_LIMIT => issues.push(LintIssue::new(
+            LintLevel::Warning,
+            LintRule::ContextSize,
+            stage,
+            format!(
+                "the marshalled context has {} bytes, over the {} bytes of the environment \
+                 variables",
+                env.len(),
+                ENVIRONMENT_LIMIT
+            ),
+        )),
+        Ok(_) => {}
+        Err(e) => issues.push(LintIssue::new(
+            LintLevel::Error,
+            LintRule::RoundTrip,
+            stage,
+            format!("the context can't be marshalled: {}", e),
+        )),
+    }
+    issues
+}
+
+/// Lints the query stages of the DAG. The contexts of the stages are linted
+/// if they are created, and their plans otherwise.
+pub fn lint_dag(dag: &QueryDag) -> LintReport {
+    let count = dag.node_count();
+    let issues = (0..count)
+        .rev()
+        .flat_map(|i| {
+            let stage = count - 1 - i;
+            let node = dag.get_node(NodeIndex::new(i)).unwrap();
+            match &node.context {
+                Some(ctx) => lint_context(stage, ctx),
+                None => node
+                    .stage
+                    .iter()
+                    .flat_map(|plan| lint_plan(stage, plan))
+                    .collect(),
+            }
+        })
+        .collect();
+    LintReport { issues }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasink::DataSinkType;
+    use crate::datasource::DataSource;
+    use crate::launcher::{AwsLambdaLauncher, Launcher};
+    use crate::query::{Query, QueryType, Table};
+    use crate::state::HashMapStateBackend;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::physical_plan::empty::EmptyExec;
+
+    async fn launcher(sql: &str) -> Result<AwsLambdaLauncher> {
+        launcher_with_keys(sql, &[]).await
+    }
+
+    async fn launcher_with_keys(sql: &str, keys: &[&str]) -> Result<AwsLambdaLauncher> {
+        let schema1 = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Utf8, false),
+            Field::new("b", DataType::Int32, false),
+        ]));
+        let schema2 = Arc::new(Schema::new(vec![
+            Field::new("c", DataType::Utf8, false),
+            Field::new("d", DataType::Int32, false),
+        ]));
+        let query = Query::new(
+            sql,
+            vec![Table::new("t1", schema1), Table::new("t2", schema2)],
+            DataSource::Memory,
+            DataSinkType::Blackhole,
+            None,
+            QueryType::OLAP,
+            Arc::new(HashMapStateBackend::new()),
+        )
+        .distribute_by(keys.iter().copied());
+        let mut launcher = AwsLambdaLauncher::new(&query).await?;
+        launcher.create_cloud_contexts(2)?;
+        Ok(launcher)
+    }
+
+    #[tokio::test]
+    async fn lint_supported_query() -> Result<()> {
+        let launcher = launcher("SELECT a, SUM(b) FROM t1 WHERE b > 1 GROUP BY a").await?;
+        let report = launcher.lint();
+        assert!(report.issues_of(LintLevel::Error).is_empty());
+        assert!(!report
+            .issues
+            .iter()
+            .any(|i| i.rule == LintRule::UnsupportedOperator));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lint_cross_join() -> Result<()> {
+        let launcher = launcher("SELECT a, d FROM t1 CROSS JOIN t2").await?;
+        let report = launcher.lint();
+        let issue = report
+            .issues
+            .iter()
+            .find(|i| i.rule == LintRule::UnsupportedOperator)
+            .expect("the cross join isn't flagged");
+        assert_eq!(issue.level, LintLevel::Warning);
+        assert!(issue.message.contains("cross_join_exec"));
+
+        // The warnings block the deployment unless it's forced.
+        let err = report.check(false).unwrap_err();
+        assert!(err.to_string().contains("cross_join_exec"));
+        assert!(report.issues_of(LintLevel::Error).is_empty());
+        report.check(true)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lint_distribution_keys() -> Result<()> {
+        let sql = "SELECT a, SUM(b) FROM t1 GROUP BY a";
+        let launcher = launcher_with_keys(sql, &["a"]).await?;
+        assert!(launcher.lint().issues_of(LintLevel::Error).is_empty());
+
+        // The output is hash partitioned by the group-by column `a`.
+        let launcher = launcher_with_keys(sql, &["b"]).await?;
+        let report = launcher.lint();
+        let issue = report
+            .issues
+            .iter()
+            .find(|i| i.rule == LintRule::DistributionKeys)
+            .expect("the distribution keys aren't checked");
+        assert_eq!(issue.level, LintLevel::Error);
+        assert!(issue.message.contains("[\"a\"]"), "{}", issue.message);
+        assert!(report.check(true).is_err());
+
+        // No stage shuffles its output to an aggregation.
+        assert!(launcher_with_keys("SELECT a FROM t1", &["a"])
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn lint_leaf() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let plan: Arc<dyn ExecutionPlan> = Arc::new(EmptyExec::new(false, schema));
+        let issues = lint_plan(1, &plan);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].rule, LintRule::Leaf);
+        assert_eq!(issues[0].stage, 1);
+
+        // The errors are never bypassed.
+        let report = LintReport { issues };
+        assert!(report.check(true).is_err());
+        Ok(())
+    }
+}
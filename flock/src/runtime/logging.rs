@@ -0,0 +1,220 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The structured logging of the cloud functions with [`tracing`].
+//!
+//! Each invocation of a cloud function runs in an [`invocation_span`] whose
+//! fields identify the query stage and the window of the payload, so that the
+//! journey of a window across the functions can be followed by filtering on
+//! the fields in CloudWatch Logs Insights, e.g.
+//!
+//! ```text
+//! fields @timestamp, fields.message
+//! | filter span.window_id = "q7-1650000000-42/0"
+//! | sort @timestamp
+//! ```
+//!
+//! The cloud functions write the events as JSON lines (see
+//! [`init_function_logging`]). The levels are set per module by the
+//! `FLOCK_LOG` environment variable with the syntax of `RUST_LOG`, e.g.
+//! `FLOCK_LOG=info,flock::runtime::plan=debug`.
+//!
+//! The library consumers that don't install a `tracing` subscriber still
+//! receive the events as `log` records, e.g. with `env_logger`.
+
+use crate::error::{FlockError, Result};
+use crate::runtime::function_name::{query_code_of, FunctionName};
+use crate::runtime::payload::Payload;
+use std::future::Future;
+use tokio::task::JoinHandle;
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
+use tracing_subscriber::EnvFilter;
+
+/// The environment variable of the log levels.
+pub const FLOCK_LOG_ENV: &str = "FLOCK_LOG";
+
+/// The default log level if `FLOCK_LOG` is not set.
+const DEFAULT_LOG_LEVEL: &str = "info";
+
+/// Returns the filter of the log levels set by `FLOCK_LOG`.
+pub fn env_filter() -> EnvFilter {
+    EnvFilter::try_from_env(FLOCK_LOG_ENV).unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_LEVEL))
+}
+
+/// Installs the global subscriber of the cloud functions, which writes the
+/// events with the fields of their invocation span as JSON lines to stdout,
+/// where CloudWatch Logs picks them up. The `log` records of the dependencies
+/// are converted to `tracing` events.
+pub fn init_function_logging() -> Result<()> {
+    tracing_log::LogTracer::init().map_err(|e| FlockError::Internal(e.to_string()))?;
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .with_env_filter(env_filter())
+        .with_current_span(true)
+        .with_span_list(false)
+        .finish();
+    tracing::subscriber::set_global_default(subscriber)
+        .map_err(|e| FlockError::Internal(e.to_string()))
+}
+
+/// Returns the span of the invocation of the cloud function with the payload.
+///
+/// The span has the fields:
+/// * `query_code` - The query code of the function name.
+/// * `plan_index` - The index of the query stage, if the function is a worker.
+/// * `group_index` - The index of the function in its group, if any.
+/// * `window_id` - The window of the payload, i.e. `<qid>/<shuffle id>`.
+/// * `seq_num` - The position of the payload in the window.
+/// * `uuid` - The query id of the payload's uuid, which is shared by all
+///   payloads of the window.
+/// * `payload_id` - The id of the payload, if any, which is shared by all
+///   delivery attempts of the payload.
+/// * `delivery_attempt` - The delivery attempt of the payload, if it has an id.
+pub fn invocation_span(function_name: &str, payload: &Payload) -> Span {
+    let (qid, shuffle_id) = payload.get_window_id();
+    let span = tracing::info_span!(
+        "invocation",
+        function = function_name,
+        query_code = query_code_of(function_name),
+        plan_index = Empty,
+        group_index = Empty,
+        window_id = %format!("{}/{}", qid, shuffle_id),
+        seq_num = payload.uuid.seq_num,
+        uuid = %payload.uuid.qid,
+        payload_id = Empty,
+        delivery_attempt = Empty,
+    );
+    if !payload.payload_id.is_empty() {
+        span.record("payload_id", &payload.payload_id.as_str());
+        span.record("delivery_attempt", &payload.delivery_attempt);
+    }
+    if let Ok(name) = FunctionName::parse(function_name) {
+        span.record("plan_index", &name.plan_index);
+        if let Some(group_index) = name.group_index {
+            span.record("group_index", &group_index);
+        }
+    }
+    span
+}
+
+/// Spawns the task in the current span, so that its events carry the fields
+/// of the invocation.
+pub fn spawn_in_span<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(future.in_current_span())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::payload::UuidBuilder;
+    use serde_json::Value;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tracing::info;
+
+    /// The writer that captures the output of the subscriber.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Capture {
+        fn lines(&self) -> Vec<Value> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn events_carry_invocation_fields() -> Result<()> {
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_env_filter(EnvFilter::new("info"))
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let uuid = UuidBuilder::new_with_ts("q7-01", 1650000000, 4).get(3);
+        let mut payload = Payload {
+            uuid: uuid.clone(),
+            payload_id: "4d5e6f70-0000-4000-8000-000000000001".to_string(),
+            delivery_attempt: 2,
+            ..Default::default()
+        };
+        payload.set_shuffle_id(2);
+
+        let span = invocation_span("q7-01-05", &payload);
+        async {
+            info!("Receiving a data packet");
+        }
+        .instrument(span)
+        .await;
+        info!("Outside of the invocation");
+
+        let lines = capture.lines();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["fields"]["message"], "Receiving a data packet");
+        let span = &lines[0]["span"];
+        assert_eq!(span["name"], "invocation");
+        assert_eq!(span["function"], "q7-01-05");
+        assert_eq!(span["query_code"], "q7");
+        assert_eq!(span["plan_index"], 1);
+        assert_eq!(span["group_index"], 5);
+        assert_eq!(span["window_id"], format!("{}/2", uuid.qid));
+        assert_eq!(span["seq_num"], 3);
+        assert_eq!(span["uuid"], uuid.qid);
+        assert_eq!(span["payload_id"], "4d5e6f70-0000-4000-8000-000000000001");
+        assert_eq!(span["delivery_attempt"], 2);
+        assert!(lines[1].get("span").is_none());
+
+        // The data source function isn't a query stage.
+        let span = invocation_span("flock_datasource", &Payload::default());
+        let _enter = span.enter();
+        info!("Generating the events");
+        let lines = capture.lines();
+        assert_eq!(lines[2]["span"]["query_code"], "flock_datasource");
+        assert!(lines[2]["span"].get("plan_index").is_none());
+        assert!(lines[2]["span"].get("payload_id").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn filter_levels_by_module() {
+        std::env::set_var(FLOCK_LOG_ENV, "warn,flock::runtime::plan=debug");
+        let filter = env_filter().to_string();
+        assert!(filter.contains("flock::runtime::plan=debug"));
+        assert!(filter.contains("warn"));
+        std::env::remove_var(FLOCK_LOG_ENV);
+        assert_eq!(env_filter().to_string(), DEFAULT_LOG_LEVEL);
+    }
+}
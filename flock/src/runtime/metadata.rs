@@ -0,0 +1,882 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! This module contains the [`QueryMetadata`] type, which is the extra
+//! metadata carried in the function payload.
+//!
+//! The settings that the cloud functions understand are typed fields, and the
+//! remaining keys, such as the window and the session states, are kept in a
+//! string map of extensions. The legacy flat string map is still accepted:
+//! complete groups of the legacy keys (e.g. `s3_bucket` and `s3_key`) are
+//! converted into the typed fields, and the rest is kept as extensions.
+
+use crate::driver::stepfunctions::SFN_STEP_METADATA_KEY;
+use crate::error::{FlockError, Result};
+use crate::runtime::analyze::ANALYZE_METADATA_KEY;
+use crate::runtime::arena::{
+    FLUSHED_METADATA_KEY, FLUSH_METADATA_KEY, PANE_METADATA_KEY, SESSION_GAP_METADATA_KEY,
+    SESSION_KEY_METADATA_KEY, SESSION_TIME_METADATA_KEY, UPSTREAMS_METADATA_KEY,
+    UPSTREAM_METADATA_KEY, WINDOW_METADATA_KEY,
+};
+use crate::runtime::backpressure::RESUME_WINDOW_METADATA_KEY;
+use crate::runtime::completion::{COMPLETION_METADATA_KEY, COMPLETION_WINDOW_METADATA_KEY};
+use crate::runtime::deadline::DEADLINE_METADATA_KEY;
+use crate::runtime::multiplex::CONTEXT_METADATA_KEY;
+use crate::runtime::schedule::{SCAN_END_METADATA_KEY, SCAN_PERIOD_METADATA_KEY};
+use crate::runtime::skew::{SALT_COMBINE_METADATA_KEY, SALT_METADATA_KEY};
+use crate::runtime::source_filter::SOURCE_FILTER_METADATA_KEY;
+use crate::runtime::switchover::{
+    DUAL_WRITE_METADATA_KEY, ROUTE_GENERATION_METADATA_KEY, ROUTE_METADATA_KEY,
+};
+use crate::runtime::topology::SENDER_METADATA_KEY;
+use crate::runtime::trace::TRACE_METADATA_KEY;
+use datafusion::arrow::datatypes::Schema;
+use datafusion::logical_plan::{col, Expr};
+use datafusion::physical_plan::functions::BuiltinScalarFunction;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::{from_value, Value};
+use sqlparser::ast::{SelectItem, SetExpr, Statement, TableFactor};
+use sqlparser::dialect::GenericDialect;
+use sqlparser::parser::Parser;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+
+/// The metadata key of the next function group, selected by the consistent
+/// hashing of the upstream function.
+pub const WORKERS_METADATA_KEY: &str = "workers";
+
+/// The extension keys that are understood by the cloud functions. Any other
+/// key is reported as a warning in strict mode.
+pub const KNOWN_EXTENSION_KEYS: [&str; 27] = [
+    ANALYZE_METADATA_KEY,
+    COMPLETION_METADATA_KEY,
+    COMPLETION_WINDOW_METADATA_KEY,
+    CONTEXT_METADATA_KEY,
+    PANE_METADATA_KEY,
+    WINDOW_METADATA_KEY,
+    SESSION_KEY_METADATA_KEY,
+    SESSION_TIME_METADATA_KEY,
+    SESSION_GAP_METADATA_KEY,
+    UPSTREAM_METADATA_KEY,
+    UPSTREAMS_METADATA_KEY,
+    WORKERS_METADATA_KEY,
+    RESUME_WINDOW_METADATA_KEY,
+    SALT_METADATA_KEY,
+    SALT_COMBINE_METADATA_KEY,
+    ROUTE_METADATA_KEY,
+    ROUTE_GENERATION_METADATA_KEY,
+    DUAL_WRITE_METADATA_KEY,
+    DEADLINE_METADATA_KEY,
+    SCAN_END_METADATA_KEY,
+    SCAN_PERIOD_METADATA_KEY,
+    FLUSH_METADATA_KEY,
+    FLUSHED_METADATA_KEY,
+    TRACE_METADATA_KEY,
+    SENDER_METADATA_KEY,
+    SOURCE_FILTER_METADATA_KEY,
+    SFN_STEP_METADATA_KEY,
+];
+
+/// The legacy metadata keys of the S3 pointer.
+const S3_KEYS: [&str; 2] = ["s3_bucket", "s3_key"];
+
+/// The legacy metadata keys of the side input.
+const SIDE_INPUT_KEYS: [&str; 3] = [
+    "side_input_s3_key",
+    "side_input_format",
+    "side_input_schema",
+];
+
+/// The invocation type of the next cloud functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InvocationType {
+    /// The next functions are invoked synchronously.
+    Sync,
+    /// The next functions are invoked asynchronously.
+    Async,
+}
+
+/// The S3 object that holds the input data of the query.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct S3Pointer {
+    /// The S3 bucket name.
+    pub bucket: String,
+    /// The S3 object key.
+    pub key:    String,
+}
+
+/// The side input of the query stored in S3 (used in NEXMark Q13).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SideInput {
+    /// The S3 object key in the Flock bucket.
+    pub s3_key:     String,
+    /// The file format of the side input, e.g. `csv`.
+    pub format:     String,
+    /// The base64 encoded schema of the side input.
+    pub schema:     String,
+    /// The columns to read from the side input. All columns are read if it's
+    /// empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub projection: Vec<String>,
+    /// The predicate on the rows to keep, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub predicate:  Option<SideInputPredicate>,
+}
+
+/// The predicate on the rows of the side input, which is applied while the
+/// side input is read. The values are parsed as the type of the column.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum SideInputPredicate {
+    /// `column = value`.
+    Eq {
+        /// The column name.
+        column: String,
+        /// The value of the column.
+        value:  String,
+    },
+    /// `min <= column <= max`. A missing bound is unbounded.
+    Range {
+        /// The column name.
+        column: String,
+        /// The lower bound, inclusive.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min:    Option<String>,
+        /// The upper bound, inclusive.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max:    Option<String>,
+    },
+}
+
+impl SideInputPredicate {
+    /// Returns the column of the predicate.
+    pub fn column(&self) -> &str {
+        match self {
+            SideInputPredicate::Eq { column, .. } | SideInputPredicate::Range { column, .. } => {
+                column
+            }
+        }
+    }
+}
+
+/// The group keys of the session windows (used in NEXMark Q11 and Q12).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionKeys {
+    /// The column name of the session group key.
+    pub key:  String,
+    /// The table name of the session group key.
+    pub name: String,
+}
+
+/// The expressions of the columns that the cloud functions can add to the
+/// input data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColumnExpr {
+    /// The current time, i.e. the process time of the input data.
+    #[serde(rename = "now()")]
+    Now,
+}
+
+impl ColumnExpr {
+    /// Returns the logical expression of the column.
+    pub fn to_expr(self) -> Expr {
+        match self {
+            ColumnExpr::Now => Expr::ScalarFunction {
+                fun:  BuiltinScalarFunction::Now,
+                args: vec![],
+            },
+        }
+    }
+}
+
+/// The column added to the input data before the session windows (used in
+/// NEXMark Q12 to add the process time field).
+///
+/// The cloud functions build the projection themselves, so no SQL from the
+/// payload is planned or executed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AddColumn {
+    /// The name of the new column.
+    pub name: String,
+    /// The expression of the new column.
+    pub expr: ColumnExpr,
+}
+
+impl AddColumn {
+    /// The maximum length of the column name.
+    pub const MAX_NAME_LEN: usize = 64;
+
+    /// Creates the process time column, i.e. `now() AS name`.
+    pub fn process_time(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            expr: ColumnExpr::Now,
+        }
+    }
+
+    /// Checks that the column name is a plain identifier.
+    pub fn validate(&self) -> Result<()> {
+        let mut chars = self.name.chars();
+        let valid = self.name.len() <= Self::MAX_NAME_LEN
+            && chars
+                .next()
+                .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(FlockError::Execution(format!(
+                "Invalid column name to add to the input data: {:?}",
+                self.name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the projection of all the columns of the input data followed by
+    /// the new column.
+    pub fn projection(&self, schema: &Schema) -> Result<Vec<Expr>> {
+        self.validate()?;
+        if schema.field_with_name(&self.name).is_ok() {
+            return Err(FlockError::Execution(format!(
+                "The column {} already exists in the input data.",
+                self.name
+            )));
+        }
+        let mut projection = schema
+            .fields()
+            .iter()
+            .map(|f| col(f.name()))
+            .collect::<Vec<_>>();
+        projection.push(self.expr.to_expr().alias(&self.name));
+        Ok(projection)
+    }
+
+    /// Converts the legacy query `SELECT *, now() AS name FROM table` into the
+    /// column. Any other statement is rejected, e.g. the joins, the
+    /// subqueries, the filters or the other tables.
+    pub fn from_sql(sql: &str, table: &str) -> Result<Self> {
+        let invalid = || {
+            FlockError::Execution(format!(
+                "Unsupported query to add a column to the input data: {}",
+                sql
+            ))
+        };
+        let statements = Parser::parse_sql(&GenericDialect {}, sql)?;
+        let query = match statements.as_slice() {
+            [Statement::Query(query)] => query,
+            _ => return Err(invalid()),
+        };
+        let select = match &query.body {
+            SetExpr::Select(select) => select,
+            _ => return Err(invalid()),
+        };
+        let alias = match select.projection.as_slice() {
+            [SelectItem::Wildcard, SelectItem::ExprWithAlias { alias, .. }] => alias,
+            _ => return Err(invalid()),
+        };
+        let name = match select.from.as_slice() {
+            [from] => match &from.relation {
+                TableFactor::Table { name, .. } => name,
+                _ => return Err(invalid()),
+            },
+            _ => return Err(invalid()),
+        };
+        // The other clauses of the query are rejected by comparing it with the
+        // only allowed shape.
+        let expected = format!("SELECT *, now() AS {} FROM {}", alias, table);
+        if !name.to_string().eq_ignore_ascii_case(table)
+            || !query.to_string().eq_ignore_ascii_case(&expected)
+        {
+            return Err(invalid());
+        }
+
+        let column = Self::process_time(&alias.value);
+        column.validate()?;
+        Ok(column)
+    }
+}
+
+/// The extra metadata of the function payload.
+///
+/// `QueryMetadata` dereferences to the map of extensions, so the extension
+/// keys can be read and written as in a plain string map.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct QueryMetadata {
+    /// The invocation type of the next cloud functions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invocation_type: Option<InvocationType>,
+    /// The S3 object that holds the input data.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub s3:              Option<S3Pointer>,
+    /// The side input of the query.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub side_input:      Option<SideInput>,
+    /// The group keys of the session windows.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_keys:    Option<SessionKeys>,
+    /// The column to add to the input data (used in NEXMark Q12).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub add_column:      Option<AddColumn>,
+    /// The remaining metadata.
+    #[serde(flatten)]
+    pub extensions:      HashMap<String, String>,
+}
+
+impl Deref for QueryMetadata {
+    type Target = HashMap<String, String>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.extensions
+    }
+}
+
+impl DerefMut for QueryMetadata {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.extensions
+    }
+}
+
+impl From<HashMap<String, String>> for QueryMetadata {
+    fn from(metadata: HashMap<String, String>) -> Self {
+        Self::from_legacy(metadata)
+    }
+}
+
+/// Removes the given keys from the map if all of them have non-empty values.
+fn take_group<const N: usize>(
+    map: &mut HashMap<String, String>,
+    keys: [&str; N],
+) -> Option<[String; N]> {
+    if !keys
+        .iter()
+        .all(|k| map.get(*k).map(|v| !v.is_empty()).unwrap_or(false))
+    {
+        return None;
+    }
+    Some(keys.map(|k| map.remove(k).unwrap()))
+}
+
+impl QueryMetadata {
+    /// Creates the metadata from the legacy flat string map.
+    ///
+    /// The complete groups of the legacy keys are converted into the typed
+    /// fields. The incomplete groups and the other keys are kept as extensions.
+    pub fn from_legacy(mut metadata: HashMap<String, String>) -> Self {
+        let invocation_type = match metadata.get("invocation_type").map(|s| s.as_str()) {
+            Some("sync") => Some(InvocationType::Sync),
+            Some("async") => Some(InvocationType::Async),
+            _ => None,
+        };
+        if invocation_type.is_some() {
+            metadata.remove("invocation_type");
+        }
+
+        let s3 = take_group(&mut metadata, S3_KEYS).map(|[bucket, key]| S3Pointer { bucket, key });
+        let side_input =
+            take_group(&mut metadata, SIDE_INPUT_KEYS).map(|[s3_key, format, schema]| SideInput {
+                s3_key,
+                format,
+                schema,
+                projection: vec![],
+                predicate: None,
+            });
+        // The optional pushdowns of the side input, i.e. the comma separated
+        // column names and the JSON encoded predicate.
+        let side_input = side_input.map(|mut side_input| {
+            if let Some(projection) = metadata.remove("side_input_projection") {
+                side_input.projection = projection
+                    .split(',')
+                    .map(|c| c.trim().to_string())
+                    .filter(|c| !c.is_empty())
+                    .collect();
+            }
+            if let Some(predicate) = metadata.get("side_input_predicate") {
+                if let Ok(predicate) = serde_json::from_str(predicate) {
+                    side_input.predicate = Some(predicate);
+                    metadata.remove("side_input_predicate");
+                }
+            }
+            side_input
+        });
+        let session_keys = take_group(&mut metadata, ["session_key", "session_name"])
+            .map(|[key, name]| SessionKeys { key, name });
+        // The JSON encoded column, or the legacy query that adds the process
+        // time field if it has the only allowed shape.
+        let mut add_column = None;
+        if let Some(column) = metadata.get("add_column") {
+            if let Ok(column) = serde_json::from_str(column) {
+                add_column = Some(column);
+                metadata.remove("add_column");
+            }
+        } else if let (Some(sql), Some(keys)) =
+            (metadata.get("add_process_time_query"), &session_keys)
+        {
+            if let Ok(column) = AddColumn::from_sql(sql, &keys.name) {
+                add_column = Some(column);
+                metadata.remove("add_process_time_query");
+            }
+        }
+
+        Self {
+            invocation_type,
+            s3,
+            side_input,
+            session_keys,
+            add_column,
+            extensions: metadata,
+        }
+    }
+
+    /// Returns the metadata as the legacy flat string map.
+    pub fn to_legacy(&self) -> HashMap<String, String> {
+        let mut metadata = self.extensions.clone();
+        if let Some(invocation_type) = self.invocation_type {
+            let invocation_type = match invocation_type {
+                InvocationType::Sync => "sync",
+                InvocationType::Async => "async",
+            };
+            metadata.insert("invocation_type".to_string(), invocation_type.to_string());
+        }
+        if let Some(s3) = &self.s3 {
+            metadata.insert("s3_bucket".to_string(), s3.bucket.clone());
+            metadata.insert("s3_key".to_string(), s3.key.clone());
+        }
+        if let Some(side_input) = &self.side_input {
+            metadata.insert("side_input_s3_key".to_string(), side_input.s3_key.clone());
+            metadata.insert("side_input_format".to_string(), side_input.format.clone());
+            metadata.insert("side_input_schema".to_string(), side_input.schema.clone());
+            if !side_input.projection.is_empty() {
+                metadata.insert(
+                    "side_input_projection".to_string(),
+                    side_input.projection.join(","),
+                );
+            }
+            if let Some(predicate) = &side_input.predicate {
+                metadata.insert(
+                    "side_input_predicate".to_string(),
+                    serde_json::to_string(predicate).unwrap(),
+                );
+            }
+        }
+        if let Some(session_keys) = &self.session_keys {
+            metadata.insert("session_key".to_string(), session_keys.key.clone());
+            metadata.insert("session_name".to_string(), session_keys.name.clone());
+        }
+        if let Some(column) = &self.add_column {
+            metadata.insert(
+                "add_column".to_string(),
+                serde_json::to_string(column).unwrap(),
+            );
+        }
+        metadata
+    }
+
+    /// Validates the metadata and returns the warnings.
+    ///
+    /// In strict mode, the unknown extension keys are reported as warnings,
+    /// and the incomplete or invalid settings are reported as errors. Nothing
+    /// is checked otherwise, and the incomplete settings are ignored by the
+    /// cloud functions as before.
+    pub fn validate(&self, strict: bool) -> Result<Vec<String>> {
+        if !strict {
+            return Ok(vec![]);
+        }
+
+        let missing = |keys: &[&str]| keys.iter().any(|k| self.extensions.contains_key(*k));
+        if let Some(invocation_type) = self.extensions.get("invocation_type") {
+            return Err(FlockError::Execution(format!(
+                "Invalid invocation_type in the metadata: {}",
+                invocation_type
+            )));
+        }
+        if let Some(predicate) = self.extensions.get("side_input_predicate") {
+            return Err(FlockError::Execution(format!(
+                "Invalid side_input_predicate in the metadata: {}",
+                predicate
+            )));
+        }
+        if missing(&S3_KEYS)
+            || self
+                .s3
+                .as_ref()
+                .map_or(false, |s3| s3.bucket.is_empty() || s3.key.is_empty())
+        {
+            return Err(FlockError::Execution(
+                "Both s3_bucket and s3_key are required in the metadata".to_string(),
+            ));
+        }
+        if missing(&SIDE_INPUT_KEYS)
+            || self.side_input.as_ref().map_or(false, |s| {
+                s.s3_key.is_empty() || s.format.is_empty() || s.schema.is_empty()
+            })
+        {
+            return Err(FlockError::Execution(
+                "side_input_s3_key, side_input_format and side_input_schema are required in the \
+                 metadata"
+                    .to_string(),
+            ));
+        }
+        // `session_key` alone is the group key of the session state.
+        if missing(&["session_name"])
+            || self
+                .session_keys
+                .as_ref()
+                .map_or(false, |s| s.key.is_empty() || s.name.is_empty())
+        {
+            return Err(FlockError::Execution(
+                "Both session_key and session_name are required in the metadata".to_string(),
+            ));
+        }
+        // The cloud functions never execute the legacy queries, nor the columns
+        // that fail to parse.
+        for key in ["add_column", "add_process_time_query"] {
+            if let Some(value) = self.extensions.get(key) {
+                return Err(FlockError::Execution(format!(
+                    "Invalid {} in the metadata: {}",
+                    key, value
+                )));
+            }
+        }
+        if let Some(column) = &self.add_column {
+            column.validate()?;
+        }
+
+        let mut warnings = self
+            .extensions
+            .keys()
+            .filter(|k| !KNOWN_EXTENSION_KEYS.contains(&k.as_str()))
+            .map(|k| format!("Unknown metadata key: {}", k))
+            .collect::<Vec<_>>();
+        warnings.sort();
+        Ok(warnings)
+    }
+}
+
+impl<'de> Deserialize<'de> for QueryMetadata {
+    /// Accepts both the structured metadata and the legacy flat string map.
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let map = HashMap::<String, Value>::deserialize(deserializer)?;
+        let mut legacy = HashMap::new();
+        let mut typed = QueryMetadata::default();
+        for (key, value) in map {
+            match value {
+                Value::Null => {}
+                Value::String(s) => {
+                    legacy.insert(key, s);
+                }
+                v if key == "s3" => typed.s3 = Some(from_value(v).map_err(de::Error::custom)?),
+                v if key == "side_input" => {
+                    typed.side_input = Some(from_value(v).map_err(de::Error::custom)?)
+                }
+                v if key == "session_keys" => {
+                    typed.session_keys = Some(from_value(v).map_err(de::Error::custom)?)
+                }
+                v if key == "add_column" => {
+                    typed.add_column = Some(from_value(v).map_err(de::Error::custom)?)
+                }
+                v => {
+                    return Err(de::Error::custom(format!(
+                        "metadata value of {} must be a string: {}",
+                        key, v
+                    )));
+                }
+            }
+        }
+
+        let legacy = QueryMetadata::from_legacy(legacy);
+        Ok(QueryMetadata {
+            invocation_type: legacy.invocation_type,
+            s3:              typed.s3.or(legacy.s3),
+            side_input:      typed.side_input.or(legacy.side_input),
+            session_keys:    typed.session_keys.or(legacy.session_keys),
+            add_column:      typed.add_column.or(legacy.add_column),
+            extensions:      legacy.extensions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::datatypes::{DataType, Field};
+    use serde_json::json;
+
+    fn typed_metadata() -> QueryMetadata {
+        let mut metadata = QueryMetadata {
+            invocation_type: Some(InvocationType::Async),
+            s3: Some(S3Pointer {
+                bucket: "nexmark".to_string(),
+                key:    "q4".to_string(),
+            }),
+            side_input: Some(SideInput {
+                s3_key:     "side_input.csv".to_string(),
+                format:     "csv".to_string(),
+                schema:     "c2NoZW1h".to_string(),
+                projection: vec![],
+                predicate:  None,
+            }),
+            session_keys: Some(SessionKeys {
+                key:  "bidder".to_string(),
+                name: "bid".to_string(),
+            }),
+            add_column: Some(AddColumn::process_time("p_time")),
+            ..Default::default()
+        };
+        metadata.insert(ANALYZE_METADATA_KEY.to_string(), "true".to_string());
+        metadata
+    }
+
+    #[test]
+    fn query_metadata_round_trip() -> Result<()> {
+        let metadata = typed_metadata();
+        let value = serde_json::to_value(&metadata)?;
+        assert_eq!(value["invocation_type"], "async");
+        assert_eq!(value["s3"], json!({ "bucket": "nexmark", "key": "q4" }));
+        assert_eq!(
+            value["session_keys"],
+            json!({ "key": "bidder", "name": "bid" })
+        );
+        assert_eq!(value[ANALYZE_METADATA_KEY], "true");
+
+        let de: QueryMetadata = serde_json::from_value(value)?;
+        assert_eq!(de, metadata);
+        assert_eq!(de.get(ANALYZE_METADATA_KEY).unwrap(), "true");
+
+        // The legacy map round trip.
+        assert_eq!(QueryMetadata::from_legacy(metadata.to_legacy()), metadata);
+        Ok(())
+    }
+
+    #[test]
+    fn query_metadata_legacy_compat() -> Result<()> {
+        let legacy = json!({
+            "invocation_type": "async",
+            "s3_bucket": "nexmark",
+            "s3_key": "q4",
+            "side_input_s3_key": "side_input.csv",
+            "side_input_format": "csv",
+            "side_input_schema": "c2NoZW1h",
+            "session_key": "bidder",
+            "session_name": "bid",
+            "add_process_time_query": "SELECT *, now() as p_time FROM bid",
+            "analyze": "true",
+        });
+        let metadata: QueryMetadata = serde_json::from_value(legacy.clone())?;
+        assert_eq!(metadata, typed_metadata());
+
+        // The legacy query is restored as the column descriptor.
+        let mut legacy = legacy;
+        legacy["add_column"] = json!(r#"{"name":"p_time","expr":"now()"}"#);
+        legacy
+            .as_object_mut()
+            .unwrap()
+            .remove("add_process_time_query");
+        assert_eq!(
+            serde_json::to_value(metadata.to_legacy())?,
+            legacy,
+            "the legacy map is restored"
+        );
+        assert_eq!(serde_json::from_value::<QueryMetadata>(legacy)?, metadata);
+
+        // The incomplete groups are kept as extensions.
+        let mut map = HashMap::new();
+        map.insert("s3_bucket".to_string(), "nexmark".to_string());
+        map.insert("session_key".to_string(), "bidder".to_string());
+        map.insert("invocation_type".to_string(), "sync".to_string());
+        let metadata = QueryMetadata::from(map);
+        assert_eq!(metadata.invocation_type, Some(InvocationType::Sync));
+        assert!(metadata.s3.is_none());
+        assert!(metadata.session_keys.is_none());
+        assert_eq!(metadata.get("s3_bucket").unwrap(), "nexmark");
+        assert_eq!(metadata.get(SESSION_KEY_METADATA_KEY).unwrap(), "bidder");
+
+        // The extension values must be strings.
+        assert!(serde_json::from_value::<QueryMetadata>(json!({ "pane": 1 })).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn side_input_pushdown() -> Result<()> {
+        let mut metadata = typed_metadata();
+        let side_input = metadata.side_input.as_mut().unwrap();
+        side_input.projection = vec!["key".to_string(), "value".to_string()];
+        side_input.predicate = Some(SideInputPredicate::Range {
+            column: "key".to_string(),
+            min:    Some("10".to_string()),
+            max:    None,
+        });
+
+        let value = serde_json::to_value(&metadata)?;
+        assert_eq!(value["side_input"]["projection"], json!(["key", "value"]));
+        assert_eq!(
+            value["side_input"]["predicate"],
+            json!({ "op": "range", "column": "key", "min": "10" })
+        );
+        assert_eq!(serde_json::from_value::<QueryMetadata>(value)?, metadata);
+
+        let legacy = metadata.to_legacy();
+        assert_eq!(legacy["side_input_projection"], "key,value");
+        assert_eq!(QueryMetadata::from_legacy(legacy.clone()), metadata);
+
+        // The invalid predicate is kept as an extension and rejected in strict
+        // mode.
+        let mut legacy = legacy;
+        legacy.insert("side_input_predicate".to_string(), "{".to_string());
+        let metadata = QueryMetadata::from_legacy(legacy);
+        assert_eq!(metadata.side_input.as_ref().unwrap().predicate, None);
+        assert!(metadata.validate(false)?.is_empty());
+        assert!(metadata.validate(true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn query_metadata_strict_mode() -> Result<()> {
+        let mut metadata = typed_metadata();
+        metadata.insert("unknown".to_string(), "value".to_string());
+        assert!(metadata.validate(false)?.is_empty());
+        assert_eq!(
+            metadata.validate(true)?,
+            vec!["Unknown metadata key: unknown".to_string()]
+        );
+
+        // A session state only has the group key.
+        let mut metadata = QueryMetadata::default();
+        metadata.insert(SESSION_KEY_METADATA_KEY.to_string(), "bidder".to_string());
+        metadata.insert(
+            SESSION_TIME_METADATA_KEY.to_string(),
+            "b_date_time".to_string(),
+        );
+        assert!(metadata.validate(true)?.is_empty());
+
+        // The incomplete settings are rejected.
+        let mut map = HashMap::new();
+        map.insert("s3_key".to_string(), "q4".to_string());
+        let metadata = QueryMetadata::from(map);
+        assert!(metadata.validate(false).is_ok());
+        assert!(metadata.validate(true).is_err());
+
+        let mut map = HashMap::new();
+        map.insert("invocation_type".to_string(), "later".to_string());
+        assert!(QueryMetadata::from(map).validate(true).is_err());
+
+        let mut metadata = typed_metadata();
+        metadata.side_input.as_mut().unwrap().format.clear();
+        assert!(metadata.validate(true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn add_column_descriptor() -> Result<()> {
+        let value = json!({ "add_column": { "name": "p_time", "expr": "now()" } });
+        let metadata: QueryMetadata = serde_json::from_value(value.clone())?;
+        assert_eq!(metadata.add_column, Some(AddColumn::process_time("p_time")));
+        assert_eq!(serde_json::to_value(&metadata)?, value);
+
+        // Only the known expressions are accepted.
+        for column in [
+            json!({ "name": "p_time", "expr": "random()" }),
+            json!({ "name": "p_time", "expr": "now() FROM person" }),
+            json!({ "name": "p_time", "expr": "now()", "table": "person" }),
+        ] {
+            assert!(
+                serde_json::from_value::<QueryMetadata>(json!({ "add_column": column })).is_err()
+            );
+        }
+
+        // The column names must be plain identifiers.
+        for name in [
+            "",
+            "1st",
+            "p time",
+            "p_time\"; DROP TABLE bid; --",
+            &"p".repeat(65),
+        ] {
+            let column = AddColumn::process_time(name);
+            assert!(column.validate().is_err(), "{}", name);
+            let metadata = QueryMetadata {
+                add_column: Some(column),
+                ..Default::default()
+            };
+            assert!(metadata.validate(true).is_err());
+        }
+
+        // The projection keeps all the columns and adds the new one.
+        let schema = Schema::new(vec![
+            Field::new("auction", DataType::Int32, false),
+            Field::new("bidder", DataType::Int32, false),
+        ]);
+        let projection = AddColumn::process_time("p_time").projection(&schema)?;
+        assert_eq!(
+            projection,
+            vec![
+                col("auction"),
+                col("bidder"),
+                ColumnExpr::Now.to_expr().alias("p_time")
+            ]
+        );
+        assert!(AddColumn::process_time("bidder")
+            .projection(&schema)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn add_column_rejects_queries() -> Result<()> {
+        assert_eq!(
+            AddColumn::from_sql("SELECT *, now() as p_time FROM bid", "bid")?,
+            AddColumn::process_time("p_time")
+        );
+        assert_eq!(
+            AddColumn::from_sql("select *, NOW() AS p_time from bid;", "bid")?,
+            AddColumn::process_time("p_time")
+        );
+
+        for sql in [
+            "DROP TABLE bid",
+            "SELECT * FROM bid",
+            "SELECT *, now() as p_time FROM person",
+            "SELECT *, now() as p_time FROM bid; DROP TABLE bid",
+            "SELECT *, now() as p_time FROM bid WHERE bidder = 1",
+            "SELECT *, now() as p_time FROM bid LIMIT 10",
+            "SELECT DISTINCT *, now() as p_time FROM bid",
+            "SELECT *, now() as p_time FROM bid, person",
+            "SELECT *, now() as p_time FROM bid JOIN person ON bidder = id",
+            "SELECT *, now() as p_time FROM (SELECT * FROM bid CROSS JOIN bid)",
+            "SELECT *, (SELECT max(price) FROM bid) as p_time FROM bid",
+            "SELECT *, random() as p_time FROM bid",
+            "SELECT *, now() as \"p time\" FROM bid",
+            "SELECT *, now() as p_time FROM bid UNION ALL SELECT *, now() FROM bid",
+            "WITH b AS (SELECT * FROM bid) SELECT *, now() as p_time FROM bid",
+            "not a query",
+        ] {
+            assert!(AddColumn::from_sql(sql, "bid").is_err(), "{}", sql);
+        }
+
+        // The rejected legacy query is never converted, and it's reported in
+        // strict mode.
+        let legacy = json!({
+            "session_key": "bidder",
+            "session_name": "bid",
+            "add_process_time_query": "SELECT *, now() as p_time FROM bid, person",
+        });
+        let metadata: QueryMetadata = serde_json::from_value(legacy)?;
+        assert!(metadata.add_column.is_none());
+        assert!(metadata.validate(false)?.is_empty());
+        assert!(metadata.validate(true).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,359 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The runtime metrics of the cloud functions are published to CloudWatch in
+//! the [embedded metric format] (EMF), i.e. a structured log line that
+//! CloudWatch Logs extracts into custom metrics, which is cheaper than calling
+//! `PutMetricData` from every invocation.
+//!
+//! The counters are buffered in a process-wide [`MetricsScope`] and flushed as
+//! a single log line per invocation. Any code in the runtime can add counters
+//! through [`scope()`] without threading the scope through the call stack:
+//!
+//! ```
+//! use flock::runtime::metrics::{self, Metric};
+//!
+//! metrics::scope().incr(Metric::Retries);
+//! ```
+//!
+//! The metrics are disabled entirely if the environment variable
+//! `FLOCK_METRICS` is set to `off`.
+//!
+//! [embedded metric format]: https://docs.aws.amazon.com/AmazonCloudWatch/latest/monitoring/CloudWatch_Embedded_Metric_Format_Specification.html
+
+use crate::runtime::function_name::FunctionName;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+
+/// The environment variable to turn off the runtime metrics.
+pub const FLOCK_METRICS_ENV: &str = "FLOCK_METRICS";
+
+/// The CloudWatch namespace of the runtime metrics.
+pub const FLOCK_METRICS_NAMESPACE: &str = "Flock";
+
+lazy_static! {
+    /// Whether the runtime metrics are enabled in the current process.
+    pub static ref FLOCK_METRICS_ENABLED: bool = std::env::var(FLOCK_METRICS_ENV)
+        .map(|v| !v.eq_ignore_ascii_case("off"))
+        .unwrap_or(true);
+    static ref METRICS_SCOPE: Mutex<MetricsScope> = Mutex::new(MetricsScope::new());
+}
+
+/// The runtime metrics of a cloud function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Metric {
+    /// The number of function invocations.
+    Invocations,
+    /// The number of input rows of the physical plan.
+    RowsProcessed,
+    /// The execution time of the physical plan in milliseconds.
+    ExecuteDuration,
+    /// The size of the incoming payloads in bytes.
+    PayloadBytes,
+    /// The number of completed windows at the aggregation stage.
+    WindowsCompleted,
+    /// The number of payloads spilled to the state backend.
+    Spills,
+    /// The number of retried function invocations.
+    Retries,
+    /// The number of rows written to the data sink.
+    SinkRows,
+    /// The estimated number of groups of the input (see
+    /// [`crate::runtime::stats`]).
+    EstimatedGroups,
+    /// The number of times the data source waited for the in-flight windows
+    /// to drain (see [`crate::runtime::backpressure`]).
+    BackpressureWaits,
+    /// The time the data source waited for the in-flight windows to drain in
+    /// milliseconds.
+    BackpressureDuration,
+    /// The number of hot keys split across the salted sub-partitions (see
+    /// [`crate::runtime::skew`]).
+    SaltedKeys,
+    /// The number of invocations that failed with the deadline exceeded (see
+    /// [`crate::runtime::deadline`]).
+    Timeouts,
+    /// The number of payloads that took the fast path of the element-wise
+    /// windows (see
+    /// [`crate::runtime::context::ExecutionContext::is_pipelined`]).
+    PipelinedPayloads,
+    /// The size in bytes of the window state held by the arena.
+    ArenaBytes,
+    /// The number of open windows in the arena.
+    ArenaWindows,
+    /// The number of windows spilled from the arena to the state backend.
+    ArenaSpills,
+    /// The number of redelivered Kinesis records dropped by the data source
+    /// (see [`crate::runtime::dedup`]).
+    DuplicateRecords,
+    /// The number of windows whose output is read from the result cache (see
+    /// [`crate::runtime::result_cache`]).
+    ResultCacheHits,
+    /// The number of sorted runs spilled to the local disk by the external
+    /// sort (see [`crate::runtime::external_sort`]).
+    SortedRunSpills,
+    /// The number of distinct payloads that reused the sequence number of
+    /// another payload in the same window (see
+    /// [`crate::runtime::payload::Payload::payload_id`]).
+    ConflictingPayloads,
+}
+
+impl Metric {
+    /// The metric name in CloudWatch.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Invocations => "Invocations",
+            Metric::RowsProcessed => "RowsProcessed",
+            Metric::ExecuteDuration => "ExecuteDuration",
+            Metric::PayloadBytes => "PayloadBytes",
+            Metric::WindowsCompleted => "WindowsCompleted",
+            Metric::Spills => "Spills",
+            Metric::Retries => "Retries",
+            Metric::SinkRows => "SinkRows",
+            Metric::EstimatedGroups => "EstimatedGroups",
+            Metric::BackpressureWaits => "BackpressureWaits",
+            Metric::BackpressureDuration => "BackpressureDuration",
+            Metric::SaltedKeys => "SaltedKeys",
+            Metric::Timeouts => "Timeouts",
+            Metric::PipelinedPayloads => "PipelinedPayloads",
+            Metric::ArenaBytes => "ArenaBytes",
+            Metric::ArenaWindows => "ArenaWindows",
+            Metric::ArenaSpills => "ArenaSpills",
+            Metric::DuplicateRecords => "DuplicateRecords",
+            Metric::ResultCacheHits => "ResultCacheHits",
+            Metric::SortedRunSpills => "SortedRunSpills",
+            Metric::ConflictingPayloads => "ConflictingPayloads",
+        }
+    }
+
+    /// The metric unit in CloudWatch.
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Metric::ExecuteDuration | Metric::BackpressureDuration => "Milliseconds",
+            Metric::PayloadBytes | Metric::ArenaBytes => "Bytes",
+            _ => "Count",
+        }
+    }
+}
+
+/// The dimensions of the runtime metrics, derived from the function name
+/// `<query code>-<plan index>[-<group index>]`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsDimensions {
+    /// The query code, e.g. `q4`.
+    pub query_code:    String,
+    /// The plan index of the query stage, e.g. `00`.
+    pub plan_index:    String,
+    /// The cloud function type, `lambda` or `group`.
+    pub function_type: String,
+}
+
+impl MetricsDimensions {
+    /// Creates the metrics dimensions of the given function.
+    pub fn new(function_name: &str) -> Self {
+        match FunctionName::parse(function_name) {
+            Ok(name) => Self {
+                plan_index:    format!("{:02}", name.plan_index),
+                function_type: if name.is_group_member() {
+                    "group"
+                } else {
+                    "lambda"
+                }
+                .to_string(),
+                query_code:    name.query_code,
+            },
+            Err(_) => Self {
+                query_code:    function_name.to_string(),
+                plan_index:    String::new(),
+                function_type: "lambda".to_string(),
+            },
+        }
+    }
+}
+
+/// The buffered runtime metrics of the current function invocation.
+#[derive(Debug, Default)]
+pub struct MetricsScope {
+    dimensions: MetricsDimensions,
+    values:     BTreeMap<Metric, f64>,
+}
+
+impl MetricsScope {
+    /// Creates an empty metrics scope.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a new invocation of the given function. The buffered metrics of
+    /// the previous invocation are discarded.
+    pub fn begin(&mut self, function_name: &str) {
+        self.dimensions = MetricsDimensions::new(function_name);
+        self.values.clear();
+        self.incr(Metric::Invocations);
+    }
+
+    /// Adds the value to the given metric.
+    pub fn add(&mut self, metric: Metric, value: f64) {
+        if *FLOCK_METRICS_ENABLED {
+            *self.values.entry(metric).or_default() += value;
+        }
+    }
+
+    /// Increments the given metric by one.
+    pub fn incr(&mut self, metric: Metric) {
+        self.add(metric, 1.0);
+    }
+
+    /// Returns the buffered value of the given metric.
+    pub fn get(&self, metric: Metric) -> Option<f64> {
+        self.values.get(&metric).copied()
+    }
+
+    /// Returns the dimensions of the current invocation.
+    pub fn dimensions(&self) -> &MetricsDimensions {
+        &self.dimensions
+    }
+
+    /// Returns the buffered metrics as an EMF record, or `None` if there is
+    /// nothing to report.
+    ///
+    /// # Arguments
+    /// * `timestamp` - The number of milliseconds since the UNIX epoch.
+    pub fn to_emf(&self, timestamp: i64) -> Option<Value> {
+        if self.values.is_empty() || self.dimensions.query_code.is_empty() {
+            return None;
+        }
+
+        let mut record = Map::new();
+        record.insert(
+            "_aws".to_string(),
+            json!({
+                "Timestamp": timestamp,
+                "CloudWatchMetrics": [{
+                    "Namespace": FLOCK_METRICS_NAMESPACE,
+                    "Dimensions": [["query_code", "plan_index", "function_type"]],
+                    "Metrics": self
+                        .values
+                        .keys()
+                        .map(|m| json!({ "Name": m.name(), "Unit": m.unit() }))
+                        .collect::<Vec<_>>(),
+                }],
+            }),
+        );
+        record.insert("query_code".to_string(), json!(self.dimensions.query_code));
+        record.insert("plan_index".to_string(), json!(self.dimensions.plan_index));
+        record.insert(
+            "function_type".to_string(),
+            json!(self.dimensions.function_type),
+        );
+        self.values.iter().for_each(|(m, v)| {
+            record.insert(m.name().to_string(), json!(v));
+        });
+
+        Some(Value::Object(record))
+    }
+
+    /// Writes the buffered metrics to the standard output as an EMF log line,
+    /// and clears the buffer.
+    pub fn flush(&mut self) {
+        if let Some(record) = self.to_emf(Utc::now().timestamp_millis()) {
+            println!("{}", record);
+        }
+        self.values.clear();
+    }
+}
+
+/// Returns the metrics scope of the current function invocation.
+///
+/// The scope is locked until the returned guard is dropped, so it should not
+/// be held across an `.await`.
+pub fn scope() -> MutexGuard<'static, MetricsScope> {
+    METRICS_SCOPE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emit_embedded_metric_format() {
+        let mut scope = MetricsScope::new();
+        assert!(scope.to_emf(0).is_none());
+
+        scope.begin("q4-01-02");
+        scope.add(Metric::RowsProcessed, 100.0);
+        scope.add(Metric::RowsProcessed, 20.0);
+        scope.add(Metric::ExecuteDuration, 15.0);
+        scope.add(Metric::PayloadBytes, 4096.0);
+        scope.incr(Metric::Retries);
+
+        let record = scope.to_emf(1_600_000_000_000).unwrap();
+        let aws = &record["_aws"];
+        assert_eq!(aws["Timestamp"], 1_600_000_000_000i64);
+
+        let directive = &aws["CloudWatchMetrics"][0];
+        assert_eq!(directive["Namespace"], FLOCK_METRICS_NAMESPACE);
+        assert_eq!(
+            directive["Dimensions"],
+            json!([["query_code", "plan_index", "function_type"]])
+        );
+        assert_eq!(
+            directive["Metrics"],
+            json!([
+                { "Name": "Invocations", "Unit": "Count" },
+                { "Name": "RowsProcessed", "Unit": "Count" },
+                { "Name": "ExecuteDuration", "Unit": "Milliseconds" },
+                { "Name": "PayloadBytes", "Unit": "Bytes" },
+                { "Name": "Retries", "Unit": "Count" },
+            ])
+        );
+
+        // Every dimension and metric in the directive has a root member.
+        assert_eq!(record["query_code"], "q4");
+        assert_eq!(record["plan_index"], "01");
+        assert_eq!(record["function_type"], "group");
+        assert_eq!(record["Invocations"], 1.0);
+        assert_eq!(record["RowsProcessed"], 120.0);
+        assert_eq!(record["ExecuteDuration"], 15.0);
+        assert_eq!(record["PayloadBytes"], 4096.0);
+        assert_eq!(record["Retries"], 1.0);
+        assert!(record.get("Spills").is_none());
+
+        // The buffer is cleared after the flush.
+        scope.flush();
+        assert!(scope.to_emf(0).is_none());
+        assert_eq!(scope.dimensions().query_code, "q4");
+    }
+
+    #[test]
+    fn metrics_dimensions() {
+        let dimensions = MetricsDimensions::new("q7-00");
+        assert_eq!(dimensions.query_code, "q7");
+        assert_eq!(dimensions.plan_index, "00");
+        assert_eq!(dimensions.function_type, "lambda");
+
+        let dimensions = MetricsDimensions::new("q7-01-15");
+        assert_eq!(dimensions.plan_index, "01");
+        assert_eq!(dimensions.function_type, "group");
+
+        let dimensions = MetricsDimensions::new("flock_datasource");
+        assert_eq!(dimensions.query_code, "flock_datasource");
+        assert_eq!(dimensions.plan_index, "");
+        assert_eq!(dimensions.function_type, "lambda");
+    }
+}
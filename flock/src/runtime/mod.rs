@@ -15,7 +15,41 @@
 //! such as execution plan and the next lambda functions, which instructs the
 //! lambda instance to perform the correct operation.
 
+pub mod analyze;
 pub mod arena;
+pub mod backpressure;
+pub mod broadcast;
+pub mod capability;
+pub mod completion;
 pub mod context;
+pub mod continuation;
+pub mod deadline;
+#[cfg(feature = "kinesis")]
+pub mod dedup;
+pub mod dictionary;
+pub mod distribution;
+pub mod embedded;
+pub mod external_sort;
+pub mod function_name;
+pub mod lint;
+pub mod logging;
+pub mod metadata;
+pub mod metrics;
+pub mod multiplex;
 pub mod payload;
+pub mod peek;
 pub mod plan;
+pub mod response;
+pub mod result_cache;
+pub mod running_aggregate;
+pub mod scaling;
+pub mod schedule;
+pub mod side_input;
+pub mod skew;
+pub mod source_filter;
+pub mod stats;
+pub mod switchover;
+pub mod topology;
+pub mod trace;
+pub mod transport;
+pub mod version;
@@ -0,0 +1,464 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Query multiplexing: the queries of the same topology share one set of
+//! deployed cloud functions.
+//!
+//! The functions are deployed per *topology signature*, i.e. the hash of the
+//! shape of the query DAG and the operators of its stages, ignoring the
+//! arguments of the operators such as the constants of the predicates. The
+//! functions are named after the signature instead of the query code (see
+//! [`shared_code`]).
+//!
+//! Since a function serves the stages of many queries, the execution context
+//! of each query stage is carried in the payload metadata under
+//! [`CONTEXT_METADATA_KEY`] (see [`QueryContexts`]), and the function instance
+//! caches the unmarshalled contexts by query code in a [`ContextCache`]. Each
+//! function forwards the contexts of the stages after its own only (see
+//! [`QueryContexts::downstream`]), and keys the objects of the query in S3 on
+//! the query code of the query rather than the shared function name. The
+//! driver looks up the [`FunctionRegistry`] in S3 before the deployment, so
+//! that a query with a known signature skips the deployment entirely.
+
+use crate::aws::client::{AwsCloudClient, CloudClient};
+use crate::configs::FLOCK_S3_BUCKET;
+use crate::distributed_plan::QueryDag;
+use crate::error::{FlockError, Result};
+use crate::runtime::context::ExecutionContext;
+use crate::runtime::function_name::query_key;
+use crate::runtime::metadata::QueryMetadata;
+use daggy::{NodeIndex, Walker};
+use datafusion::physical_plan::{displayable, ExecutionPlan};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+/// The metadata key of the execution contexts of the multiplexed query.
+pub const CONTEXT_METADATA_KEY: &str = "context";
+
+/// The S3 key of the registered functions under the prefix of the shared
+/// functions.
+const REGISTRY_KEY: &str = "registry.json";
+
+/// Returns the topology signature of the query DAG, which is a 16-digit hex
+/// string.
+///
+/// The signature covers the function types of the stages, the edges of the
+/// DAG, the operator trees of the stages and the size of the function
+/// groups, i.e. everything that the names and the routing of the deployed
+/// functions depend on. The plans themselves are delivered in the payloads,
+/// so the arguments of the operators are left out.
+pub fn topology_signature(dag: &QueryDag, group_size: usize) -> String {
+    let mut hasher = DefaultHasher::new();
+    group_size.hash(&mut hasher);
+    for i in 0..dag.node_count() {
+        let node = NodeIndex::new(i);
+        let stage = dag.get_node(node).unwrap();
+        format!("{:?}", stage.get_function_type()).hash(&mut hasher);
+        dag.parents(node)
+            .iter(&**dag)
+            .map(|(_, n)| n.index())
+            .collect::<Vec<_>>()
+            .hash(&mut hasher);
+        stage
+            .stage
+            .iter()
+            .for_each(|plan| operators(plan.as_ref()).hash(&mut hasher));
+    }
+    format!("{:016x}", hasher.finish())
+}
+
+/// Returns the operators of the plan in pre-order with their depths, e.g.
+/// `1:FilterExec`.
+fn operators(plan: &dyn ExecutionPlan) -> Vec<String> {
+    displayable(plan)
+        .indent()
+        .to_string()
+        .lines()
+        .map(|line| {
+            let depth = (line.len() - line.trim_start().len()) / 2;
+            let name = line.trim().split(':').next().unwrap_or_default();
+            format!("{}:{}", depth, name)
+        })
+        .collect()
+}
+
+/// Returns the first component of the names of the functions shared by the
+/// queries with the given topology signature.
+pub fn shared_code(signature: &str) -> String {
+    format!("mux_{}", signature)
+}
+
+/// The execution contexts of the stages of a multiplexed query, carried in
+/// the payload metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryContexts {
+    /// The query code of the query, which keys the cached contexts in the
+    /// function instances.
+    pub query_code: String,
+    /// The marshalled execution contexts of the query stages, indexed by the
+    /// plan index from [`QueryContexts::first`].
+    pub stages:     Vec<String>,
+    /// The plan index of the first stage in `stages`. The contexts of the
+    /// stages before it are dropped by the upstream functions.
+    #[serde(default)]
+    pub first:      usize,
+}
+
+impl QueryContexts {
+    /// Adds the contexts to the metadata of the payload.
+    pub fn attach(&self, metadata: &mut QueryMetadata) -> Result<()> {
+        metadata.insert(
+            CONTEXT_METADATA_KEY.to_string(),
+            serde_json::to_string(self)?,
+        );
+        Ok(())
+    }
+
+    /// Returns the contexts carried in the metadata of the payload, if any.
+    pub fn from_metadata(metadata: &Option<QueryMetadata>) -> Result<Option<Self>> {
+        match metadata.as_ref().and_then(|m| m.get(CONTEXT_METADATA_KEY)) {
+            Some(contexts) => Ok(Some(serde_json::from_str(contexts)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the contexts that the function of the stage with the given plan
+    /// index forwards to the next stage, i.e. the contexts of the stages after
+    /// its own. The stages are indexed in the reverse order of the DAG, so the
+    /// next stages always have the greater plan indices.
+    pub fn downstream(&self, plan_index: usize) -> Self {
+        let skip = (plan_index + 1)
+            .saturating_sub(self.first)
+            .min(self.stages.len());
+        Self {
+            query_code: self.query_code.clone(),
+            stages:     self.stages[skip..].to_vec(),
+            first:      self.first + skip,
+        }
+    }
+
+    /// Returns the marshalled context of the stage with the given plan index.
+    pub fn stage(&self, plan_index: usize) -> Result<&str> {
+        plan_index
+            .checked_sub(self.first)
+            .and_then(|i| self.stages.get(i))
+            .map(|ctx| ctx.as_str())
+            .ok_or_else(|| {
+                FlockError::Internal(format!(
+                    "The query {} has no stage {} in the payload",
+                    self.query_code, plan_index
+                ))
+            })
+    }
+}
+
+/// A bounded LRU cache of the unmarshalled execution contexts of the
+/// multiplexed queries in a function instance, keyed by query code.
+///
+/// The digest of the marshalled context is kept with the context, so that a
+/// query resubmitted with a new plan under the same query code doesn't run
+/// with the stale context.
+#[derive(Debug)]
+pub struct ContextCache {
+    capacity: usize,
+    tick:     u64,
+    contexts: HashMap<String, (u64, u64, Arc<ExecutionContext>)>,
+    lru:      BTreeMap<u64, String>,
+}
+
+impl ContextCache {
+    /// Creates an empty cache which holds at most `capacity` contexts.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            tick:     0,
+            contexts: HashMap::new(),
+            lru:      BTreeMap::new(),
+        }
+    }
+
+    /// Returns the number of contexts in the cache.
+    pub fn len(&self) -> usize {
+        self.contexts.len()
+    }
+
+    /// Returns true if the cache contains no contexts.
+    pub fn is_empty(&self) -> bool {
+        self.contexts.is_empty()
+    }
+
+    /// Returns the context of the query if it was unmarshalled from the same
+    /// marshalled context, and marks it as the most recently used one.
+    pub fn get(&mut self, query_code: &str, encoded_ctx: &str) -> Option<Arc<ExecutionContext>> {
+        let (tick, digest, ctx) = self.contexts.get(query_code)?.clone();
+        if digest != Self::digest(encoded_ctx) {
+            return None;
+        }
+        self.lru.remove(&tick);
+        self.touch(query_code, digest, ctx.clone());
+        Some(ctx)
+    }
+
+    /// Adds the context of the query to the cache. If the cache is full, the
+    /// least recently used context is evicted.
+    pub fn insert(&mut self, query_code: &str, encoded_ctx: &str, ctx: Arc<ExecutionContext>) {
+        if let Some((tick, ..)) = self.contexts.get(query_code) {
+            let tick = *tick;
+            self.lru.remove(&tick);
+        } else if self.contexts.len() >= self.capacity {
+            if let Some(tick) = self.lru.keys().next().copied() {
+                let evicted = self.lru.remove(&tick).unwrap();
+                self.contexts.remove(&evicted);
+            }
+        }
+        self.touch(query_code, Self::digest(encoded_ctx), ctx);
+    }
+
+    fn touch(&mut self, query_code: &str, digest: u64, ctx: Arc<ExecutionContext>) {
+        self.tick += 1;
+        self.lru.insert(self.tick, query_code.to_string());
+        self.contexts
+            .insert(query_code.to_string(), (self.tick, digest, ctx));
+    }
+
+    fn digest(encoded_ctx: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        encoded_ctx.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// The registry of the deployed functions, which maps the topology signatures
+/// to the names of the functions shared by the queries of the topology.
+///
+/// Each signature is an S3 object `mux_<signature>/registry.json` holding the
+/// list of the function names, i.e. it's under the prefix of the first
+/// component of the shared function names.
+#[derive(Debug)]
+pub struct FunctionRegistry {
+    client: Arc<dyn CloudClient>,
+    bucket: String,
+}
+
+impl Default for FunctionRegistry {
+    fn default() -> Self {
+        Self::new(Arc::new(AwsCloudClient), &FLOCK_S3_BUCKET)
+    }
+}
+
+impl FunctionRegistry {
+    /// Creates the registry in the given bucket.
+    pub fn new(client: Arc<dyn CloudClient>, bucket: &str) -> Self {
+        Self {
+            client,
+            bucket: bucket.to_string(),
+        }
+    }
+
+    fn key(signature: &str) -> String {
+        query_key(&shared_code(signature), REGISTRY_KEY)
+    }
+
+    /// Returns the names of the functions deployed for the signature, or
+    /// `None` if the signature isn't registered.
+    pub async fn lookup(&self, signature: &str) -> Result<Option<Vec<String>>> {
+        let key = Self::key(signature);
+        if !self
+            .client
+            .s3_list(&self.bucket, &key)
+            .await?
+            .iter()
+            .any(|k| *k == key)
+        {
+            return Ok(None);
+        }
+        let body = self.client.s3_get(&self.bucket, &key).await?;
+        Ok(Some(serde_json::from_slice(&body)?))
+    }
+
+    /// Registers the names of the functions deployed for the signature.
+    pub async fn register(&self, signature: &str, functions: &[String]) -> Result<()> {
+        self.client
+            .s3_put(
+                &self.bucket,
+                &Self::key(signature),
+                serde_json::to_vec(functions)?,
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aws::client::FakeCloudClient;
+    use crate::datasink::DataSinkType;
+    use crate::datasource::nexmark::register_nexmark_tables;
+    use crate::launcher::AwsLambdaLauncher;
+    use crate::runtime::context::{self, CloudFunction};
+    use crate::state::HashMapStateBackend;
+
+    async fn launcher(query_code: &str, sql: &str) -> Result<AwsLambdaLauncher> {
+        let mut ctx = register_nexmark_tables().await?;
+        let plan = ctx.sql(sql).await?.to_logical_plan();
+        let plan = ctx.optimize(&plan)?;
+        let plan = ctx.create_physical_plan(&plan).await?;
+        AwsLambdaLauncher::try_new(
+            query_code,
+            plan,
+            DataSinkType::Blackhole,
+            Arc::new(HashMapStateBackend::new()),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn topology_signature_ignores_constants() -> Result<()> {
+        let q1 = launcher(
+            "q1",
+            "SELECT auction, price FROM bid WHERE auction % 123 = 0",
+        )
+        .await?;
+        let q2 = launcher("q2", "SELECT auction, price FROM bid WHERE auction % 7 = 0").await?;
+        let q3 = launcher("q3", "SELECT auction, MAX(price) FROM bid GROUP BY auction").await?;
+
+        let signature = topology_signature(&q1.dag, 8);
+        assert_eq!(signature.len(), 16);
+        assert_eq!(signature, topology_signature(&q2.dag, 8));
+        assert_ne!(signature, topology_signature(&q3.dag, 8));
+        assert_ne!(signature, topology_signature(&q1.dag, 4));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn multiplexed_queries_share_function_names() -> Result<()> {
+        let mut contexts = vec![];
+        let mut names = vec![];
+        for (query_code, sql) in [
+            (
+                "q1",
+                "SELECT auction, price FROM bid WHERE auction % 123 = 0",
+            ),
+            ("q2", "SELECT auction, price FROM bid WHERE auction % 7 = 0"),
+        ] {
+            let mut launcher = launcher(query_code, sql).await?;
+            let signature = launcher.multiplex(8);
+            launcher.create_cloud_contexts(8)?;
+            let query_contexts = launcher.query_contexts()?;
+            assert_eq!(query_contexts.query_code, query_code);
+            assert_eq!(query_contexts.stages.len(), launcher.dag.node_count());
+
+            let ctx = context::unmarshal(query_contexts.stage(0)?)?;
+            assert_eq!(ctx.name, format!("{}-00", shared_code(&signature)));
+            assert!(matches!(ctx.next, CloudFunction::Sink(_)));
+            names.push(ctx.name);
+            contexts.push(query_contexts);
+        }
+        // The queries run on the same functions with their own plans.
+        assert_eq!(names[0], names[1]);
+        assert_ne!(contexts[0].stages, contexts[1].stages);
+        Ok(())
+    }
+
+    #[test]
+    fn query_contexts_in_metadata() -> Result<()> {
+        let contexts = QueryContexts {
+            query_code: "q1".to_string(),
+            stages:     vec!["ctx0".to_string(), "ctx1".to_string()],
+            first:      0,
+        };
+        let mut metadata = QueryMetadata::default();
+        assert_eq!(QueryContexts::from_metadata(&Some(metadata.clone()))?, None);
+        assert_eq!(QueryContexts::from_metadata(&None)?, None);
+
+        contexts.attach(&mut metadata)?;
+        let decoded = QueryContexts::from_metadata(&Some(metadata))?.unwrap();
+        assert_eq!(decoded, contexts);
+        assert_eq!(decoded.stage(1)?, "ctx1");
+        assert!(decoded.stage(2).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn forward_downstream_contexts() -> Result<()> {
+        let contexts = QueryContexts {
+            query_code: "q1".to_string(),
+            stages:     vec!["ctx0".to_string(), "ctx1".to_string(), "ctx2".to_string()],
+            first:      0,
+        };
+        // The source function forwards the contexts of the next stages.
+        let next = contexts.downstream(0);
+        assert_eq!(next.stages, vec!["ctx1", "ctx2"]);
+        assert_eq!(next.stage(1)?, "ctx1");
+        assert!(next.stage(0).is_err());
+
+        // The last stage receives its own context only.
+        let last = next.downstream(1);
+        assert_eq!(last.query_code, "q1");
+        assert_eq!(last.stages, vec!["ctx2"]);
+        assert_eq!(last.stage(2)?, "ctx2");
+        assert!(last.downstream(2).stages.is_empty());
+
+        // The payloads of the former releases index the stages from 0.
+        let former: QueryContexts =
+            serde_json::from_str(r#"{"query_code":"q1","stages":["ctx0","ctx1"]}"#)?;
+        assert_eq!(former.first, 0);
+        assert_eq!(former.stage(1)?, "ctx1");
+        Ok(())
+    }
+
+    #[test]
+    fn evict_least_recently_used_contexts() {
+        let ctx = |name: &str| {
+            Arc::new(ExecutionContext {
+                name: name.to_string(),
+                ..Default::default()
+            })
+        };
+        let mut cache = ContextCache::new(2);
+        assert!(cache.is_empty());
+        cache.insert("q1", "ctx1", ctx("q1"));
+        cache.insert("q2", "ctx2", ctx("q2"));
+        assert_eq!(cache.get("q1", "ctx1").unwrap().name, "q1");
+
+        // q2 is the least recently used one.
+        cache.insert("q3", "ctx3", ctx("q3"));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("q2", "ctx2").is_none());
+        assert!(cache.get("q1", "ctx1").is_some());
+        assert!(cache.get("q3", "ctx3").is_some());
+
+        // A new plan of the same query isn't served by the stale context.
+        assert!(cache.get("q1", "ctx1-v2").is_none());
+        cache.insert("q1", "ctx1-v2", ctx("q1-v2"));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("q1", "ctx1-v2").unwrap().name, "q1-v2");
+    }
+
+    #[tokio::test]
+    async fn register_deployed_functions() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let registry = FunctionRegistry::new(client.clone(), "flock");
+        let functions = vec!["mux_1-00".to_string(), "mux_1-01-00".to_string()];
+
+        assert_eq!(registry.lookup("1").await?, None);
+        registry.register("1", &functions).await?;
+        assert_eq!(registry.lookup("1").await?, Some(functions));
+        assert_eq!(registry.lookup("11").await?, None);
+        assert_eq!(client.keys("flock"), vec!["mux_1/registry.json"]);
+        Ok(())
+    }
+}
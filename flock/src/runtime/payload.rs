@@ -18,14 +18,25 @@
 
 use crate::datasource::DataSource;
 use crate::encoding::Encoding;
+use crate::encryption::{self, Envelope, EnvelopeCipher};
+use crate::error::{FlockError, PayloadError, Result};
+use crate::runtime::arena::WindowId;
+use crate::runtime::function_name::query_code_of;
+use crate::runtime::metadata::{QueryMetadata, S3Pointer};
+use crate::runtime::stats::PayloadStats;
+use crate::runtime::version::{
+    legacy_format_version, WireFormat, LEGACY_FORMAT_VERSION, PAYLOAD_FORMAT_VERSION,
+};
 use crate::transmute::*;
 use datafusion::arrow::datatypes::Schema;
+use datafusion::arrow::ipc::root_as_message;
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::arrow_flight::utils::flight_data_to_arrow_batch;
 use datafusion::arrow_flight::FlightData;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use siphasher::sip128::{Hasher128, SipHasher13};
+use std::hash::Hasher;
 use std::sync::Arc;
 use uuid::Uuid as RandomId;
 
@@ -44,7 +55,7 @@ pub struct UuidBuilder {
 impl UuidBuilder {
     /// Returns a new UuidBuilder.
     pub fn new_with_ts(function_name: &str, timestamp: i64, len: usize) -> Self {
-        let query_code = function_name.split('-').next().unwrap();
+        let query_code = query_code_of(function_name);
         Self {
             qid: format!(
                 "{}-{}-{}",
@@ -59,7 +70,7 @@ impl UuidBuilder {
 
     /// Returns a new UuidBuilder.
     pub fn new_with_ts_uuid(function_name: &str, timestamp: i64, uuid: u128, len: usize) -> Self {
-        let query_code = function_name.split('-').next().unwrap();
+        let query_code = query_code_of(function_name);
         Self {
             qid: format!("{}-{}-{}", query_code, timestamp, uuid),
             pos: 1,
@@ -67,6 +78,31 @@ impl UuidBuilder {
         }
     }
 
+    /// Returns a new UuidBuilder whose qid is derived from the query code and
+    /// the window id rather than the wall clock, so that the payloads of the
+    /// same window emitted by different invocations share the qid.
+    ///
+    /// The window id is expected to start with the qid of the upstream, e.g.
+    /// [`crate::datasink::results::window_id`], whose timestamp is kept in the
+    /// new qid so that the state buckets can still be collected by age.
+    pub fn for_window(function_name: &str, window_id: &str, len: usize) -> Self {
+        let query_code = query_code_of(function_name);
+        let timestamp = window_id
+            .split('-')
+            .nth(1)
+            .and_then(|ts| ts.parse::<i64>().ok())
+            .unwrap_or_default();
+        // SipHash-1-3 with fixed keys is specified, unlike `DefaultHasher`, so
+        // the id is the same in all cloud functions, whichever toolchain built
+        // them.
+        let mut hasher = SipHasher13::new_with_keys(0, 0);
+        hasher.write(query_code.as_bytes());
+        hasher.write_u8(0xff);
+        hasher.write(window_id.as_bytes());
+        let hash = hasher.finish128().as_u128();
+        Self::new_with_ts_uuid(function_name, timestamp, hash, len)
+    }
+
     /// Returns the next Uuid for the next payload.
     pub fn next_uuid(&mut self) -> Uuid {
         assert!(self.pos <= self.len);
@@ -107,8 +143,8 @@ pub struct Uuid {
     /// used to pick the next function to execute using consistent hashing.
     pub qid:     String,
     /// `seq_num` represents the position of the data fragment in the time
-    /// window, starting from 0, which will be recorded in the
-    /// `WindowSession::bitmap`.
+    /// window, starting from 1, which will be recorded in the
+    /// `WindowSession::bitmap`. It must not exceed `seq_len`.
     pub seq_num: usize,
     /// `seq_len` represents the total number of fragments after the data is
     /// fragmented into different payloads.
@@ -129,76 +165,324 @@ pub struct DataFrame {
 
 /// `Payload` is the wire format of the function's payload passed between
 /// cloud functions.
-#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct Payload {
     /// The record batches are encoded in the Arrow Flight Data format.
-    pub data:         Vec<DataFrame>,
+    pub data:             Vec<DataFrame>,
     /// The schema of the record batches in binary format.
-    pub schema:       Vec<u8>,
+    pub schema:           Vec<u8>,
     /// The record batches for the 2nd relation.
-    pub data2:        Vec<DataFrame>,
+    pub data2:            Vec<DataFrame>,
     /// The schema of the record batches for the 2nd relation.
-    pub schema2:      Vec<u8>,
+    pub schema2:          Vec<u8>,
     /// The UUID of the payload.
-    pub uuid:         Uuid,
+    pub uuid:             Uuid,
     /// The encoding and compression method.
     /// Note: using this value to guarantee the total size of payload doesn't
     /// exceed 256 KB due to the limitation of AWS Lambda's async invocation.
-    pub encoding:     Encoding,
+    pub encoding:         Encoding,
     /// Where the payload is coming from.
-    pub datasource:   DataSource,
+    pub datasource:       DataSource,
     /// The Nexmark query number for the benchmarking purposes.
-    pub query_number: Option<usize>,
+    pub query_number:     Option<usize>,
     /// The shuffle id. This is used to identify the shuffled data for the
     /// aggregation in the next cloud function.
-    pub shuffle_id:   Option<usize>,
+    pub shuffle_id:       Option<usize>,
+    /// The window that the payload belongs to in the arena of the next cloud
+    /// function. The payloads of older versions don't carry it, and their
+    /// window id is derived from the query id and the shuffle id instead.
+    #[serde(default)]
+    pub window_id:        WindowId,
     /// The extra metadata for the payload.
-    pub metadata:     Option<HashMap<String, String>>,
+    pub metadata:         Option<QueryMetadata>,
+    /// The event time watermark of the upstream function in milliseconds. All
+    /// events before the watermark have been sent by the upstream function.
+    #[serde(default)]
+    pub watermark:        Option<i64>,
+    /// The statistics of the record batches if `payload_stats` is enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats:            Option<PayloadStats>,
+    /// The statistics of the record batches for the 2nd relation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats2:           Option<PayloadStats>,
+    /// The encrypted record batches of both relations if the query data is
+    /// encrypted (see [`crate::encryption`]), in which case `data` and `data2`
+    /// are empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed:           Option<Envelope>,
+    /// The stream names of the relations, i.e. of `data` and `data2` in order,
+    /// if the sender knows them. They route the relations to the leaves of the
+    /// plan ahead of the stream names in the schema metadata (see
+    /// [`crate::runtime::plan::feed_named_sources`]).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub streams:          Vec<Option<String>>,
+    /// The identifier of the payload, a UUIDv4 generated once when the payload
+    /// is built (see [`crate::transmute::to_stage_payload`]). It's kept when
+    /// the payload is sent again or written to S3, so that a retried
+    /// invocation can be linked to its original. Empty for the payloads of
+    /// older versions.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub payload_id:       String,
+    /// The delivery attempt of the payload, starting from 1, which is
+    /// incremented when the sender retries it (see [`next_attempt`]). The
+    /// retries of the asynchronous invocations by Lambda resend the same
+    /// attempt. 0 if the payload has no id.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub delivery_attempt: u32,
+    /// The S3 object of the second relation of the window, i.e. the build side
+    /// of the join, if the sender broadcasts it through S3 instead of putting
+    /// it in the payloads of the window (see [`crate::runtime::broadcast`]).
+    /// `data2` is empty then.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub broadcast:        Option<S3Pointer>,
+    /// The version of the wire format of the payload (see
+    /// [`crate::runtime::version`]). The payloads of older versions don't
+    /// carry it.
+    #[serde(default = "legacy_format_version")]
+    pub format_version:   u16,
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Self {
+            data:             vec![],
+            schema:           vec![],
+            data2:            vec![],
+            schema2:          vec![],
+            uuid:             Uuid::default(),
+            encoding:         Encoding::default(),
+            datasource:       DataSource::default(),
+            query_number:     None,
+            shuffle_id:       None,
+            window_id:        WindowId::default(),
+            metadata:         None,
+            watermark:        None,
+            stats:            None,
+            stats2:           None,
+            sealed:           None,
+            streams:          vec![],
+            payload_id:       String::new(),
+            delivery_attempt: 0,
+            broadcast:        None,
+            format_version:   PAYLOAD_FORMAT_VERSION,
+        }
+    }
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+/// Returns a new payload id (see [`Payload::payload_id`]).
+pub fn new_payload_id() -> String {
+    RandomId::new_v4().to_string()
+}
+
+/// Returns the serialized payload of the next delivery attempt, i.e. the same
+/// payload with its attempt incremented, or `None` if the bytes aren't a
+/// payload with an id, e.g. the payload of a data source.
+pub fn next_attempt(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut value: serde_json::Value = serde_json::from_slice(bytes).ok()?;
+    let object = value.as_object_mut()?;
+    object
+        .get("payload_id")
+        .and_then(|id| id.as_str())
+        .filter(|id| !id.is_empty())?;
+    let attempt = object
+        .get("delivery_attempt")
+        .and_then(|attempt| attempt.as_u64())
+        .unwrap_or(1);
+    object.insert("delivery_attempt".to_string(), (attempt + 1).into());
+    serde_json::to_vec(&value).ok()
 }
 
 impl Payload {
-    /// Convert incoming payload to record batch in Arrow.
-    pub fn to_record_batch(self) -> (Vec<RecordBatch>, Vec<RecordBatch>) {
-        let record_batch = |df: Vec<DataFrame>, schema: Arc<Schema>| -> Vec<RecordBatch> {
-            df.into_par_iter()
-                .map(|d| {
-                    flight_data_to_arrow_batch(
-                        &FlightData {
-                            data_body:         d.body,
-                            data_header:       d.header,
-                            app_metadata:      vec![],
-                            flight_descriptor: None,
-                        },
-                        schema.clone(),
-                        &[],
-                    )
-                    .unwrap()
-                })
-                .collect()
+    /// Deserializes the payload from its JSON bytes, and checks that this
+    /// binary can read its version. The payloads of a newer version fail with
+    /// [`FlockError::FormatVersion`] even if they don't parse.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        let payload: Payload = serde_json::from_slice(bytes)
+            .map_err(|e| WireFormat::Payload.decode_error(bytes, e.into()))?;
+        WireFormat::Payload.check(payload.format_version)?;
+        Ok(payload)
+    }
+
+    /// Deserializes the payload from a JSON value, e.g. the event of the
+    /// function, and checks that this binary can read its version.
+    pub fn from_value(value: serde_json::Value) -> Result<Self> {
+        let found = match value.get("format_version") {
+            Some(version) => version.as_u64().map(|v| v.min(u16::MAX as u64) as u16),
+            None => Some(LEGACY_FORMAT_VERSION),
         };
+        if let Some(found) = found {
+            WireFormat::Payload.check(found)?;
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Convert incoming payload to record batch in Arrow.
+    ///
+    /// The encrypted payload is decrypted with the cipher of the invocation,
+    /// which must have unwrapped its data key (see [`Payload::open`]).
+    ///
+    /// # Returns
+    /// A [`FlockError::Payload`] error naming the field that failed to decode
+    /// if the payload is malformed.
+    pub fn to_record_batch(mut self) -> Result<(Vec<RecordBatch>, Vec<RecordBatch>)> {
+        if self.sealed.is_some() {
+            let cipher = encryption::cipher().ok_or_else(|| {
+                self.malformed(PayloadError::Sealed(
+                    "the function has no encryption key".to_string(),
+                ))
+            })?;
+            if let Err(e) = self.unseal(&cipher) {
+                return Err(self.malformed(PayloadError::Sealed(e.to_string())));
+            }
+        }
 
         let mut res = (vec![], vec![]);
         if !self.data.is_empty() {
-            let dataframe = unmarshal(self.data, self.encoding.clone());
-            let schema = schema_from_bytes(&self.schema).unwrap();
-            res.0 = record_batch(dataframe, schema);
+            res.0 = decode_relation(&self.uuid, &self.encoding, 0, self.data, &self.schema)?;
         }
         if !self.data2.is_empty() {
-            let dataframe = unmarshal(self.data2, self.encoding.clone());
-            let schema = schema_from_bytes(&self.schema2).unwrap();
-            res.1 = record_batch(dataframe, schema);
+            res.1 = decode_relation(&self.uuid, &self.encoding, 1, self.data2, &self.schema2)?;
         }
-        res
+        Ok(res)
+    }
+
+    /// Returns the error of the malformed payload.
+    fn malformed(&self, error: PayloadError) -> FlockError {
+        FlockError::Payload(self.uuid.clone(), error)
     }
 
     /// Return true if the records in the payload are empty.
     pub fn is_empty_data(&self) -> bool {
-        self.data.is_empty() && self.data2.is_empty()
+        self.data.is_empty() && self.data2.is_empty() && self.sealed.is_none()
+    }
+
+    /// Returns the size of the encoded data frames in the payload in bytes.
+    pub fn get_data_size(&self) -> usize {
+        self.data
+            .iter()
+            .chain(self.data2.iter())
+            .map(|d| d.header.len() + d.body.len())
+            .sum::<usize>()
+            + self.sealed.as_ref().map_or(0, |e| e.ciphertext.len())
+    }
+
+    /// Encrypts the record batches of the payload under the data key of the
+    /// cipher. The schemas and the metadata stay in plaintext, so that the
+    /// payload can be routed and validated without the data key.
+    pub fn seal(&mut self, cipher: &EnvelopeCipher) -> Result<()> {
+        if self.sealed.is_some() || (self.data.is_empty() && self.data2.is_empty()) {
+            return Ok(());
+        }
+        let data = (
+            std::mem::take(&mut self.data),
+            std::mem::take(&mut self.data2),
+        );
+        self.sealed = Some(cipher.encrypt(&serde_json::to_vec(&data)?)?);
+        Ok(())
+    }
+
+    /// Decrypts the record batches of the payload if they are encrypted, and
+    /// unwraps the data key of the sender with KMS unless it's cached.
+    pub async fn open(&mut self, cipher: &EnvelopeCipher) -> Result<()> {
+        if let Some(envelope) = &self.sealed {
+            cipher.unwrap_key(envelope).await?;
+        }
+        self.unseal(cipher)
+    }
+
+    /// Decrypts the record batches of the payload with the cached data keys.
+    fn unseal(&mut self, cipher: &EnvelopeCipher) -> Result<()> {
+        if let Some(envelope) = &self.sealed {
+            let (data, data2) = serde_json::from_slice(&cipher.decrypt(envelope)?)?;
+            self.data = data;
+            self.data2 = data2;
+            self.sealed = None;
+        }
+        Ok(())
+    }
+
+    /// Returns the stream names of both relations, which are `None` if the
+    /// sender didn't name them.
+    pub fn stream_names(&self) -> Vec<Option<String>> {
+        (0..2)
+            .map(|i| self.streams.get(i).cloned().flatten())
+            .collect()
     }
 
     /// Returns the window id of the payload.
-    pub fn get_window_id(&self) -> (String, usize) {
-        (self.get_query_id(), self.get_shuffle_id())
+    pub fn get_window_id(&self) -> WindowId {
+        if self.window_id.0.is_empty() {
+            (self.get_query_id(), self.get_shuffle_id())
+        } else {
+            self.window_id.clone()
+        }
+    }
+
+    /// Sets the shuffle id of the payload, which also assigns the payload to
+    /// the window of the shuffled partition.
+    pub fn set_shuffle_id(&mut self, shuffle_id: usize) {
+        self.shuffle_id = Some(shuffle_id);
+        self.window_id = (self.get_query_id(), shuffle_id);
+    }
+
+    /// Checks the invariants of the incoming payload.
+    ///
+    /// # Arguments
+    /// * `aggregate` - Whether the payload is received by an aggregate stage,
+    ///   which collects the payloads by their window ids.
+    /// * `ring_size` - The size of the hash ring that the shuffled partitions
+    ///   are sent to, if known.
+    ///
+    /// # Returns
+    /// An error listing all offending fields if any invariant is violated.
+    pub fn validate(&self, aggregate: bool, ring_size: Option<usize>) -> Result<()> {
+        let mut violations = vec![];
+
+        let (seq_num, seq_len) = (self.uuid.seq_num, self.uuid.seq_len);
+        if seq_num > seq_len || (seq_len > 0 && seq_num == 0) {
+            violations.push(format!(
+                "uuid.seq_num ({}) is not in the range 1..={} (uuid.seq_len)",
+                seq_num, seq_len
+            ));
+        }
+
+        if aggregate && self.get_window_id().0.is_empty() {
+            violations.push("window_id is missing for the aggregate stage".to_string());
+        }
+
+        if !self.window_id.0.is_empty() && self.window_id.1 != self.get_shuffle_id() {
+            violations.push(format!(
+                "window_id.1 ({}) doesn't match shuffle_id ({:?})",
+                self.window_id.1, self.shuffle_id
+            ));
+        }
+
+        match (self.shuffle_id, ring_size) {
+            (Some(0), _) => {
+                violations.push("shuffle_id (0) doesn't start from 1".to_string());
+            }
+            (Some(shuffle_id), Some(ring_size)) if shuffle_id > ring_size => {
+                violations.push(format!(
+                    "shuffle_id ({}) exceeds the ring size ({})",
+                    shuffle_id, ring_size
+                ));
+            }
+            _ => {}
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(FlockError::Execution(format!(
+                "Invalid payload {}: {}",
+                self.uuid.qid,
+                violations.join("; ")
+            )))
+        }
     }
 
     /// Returns the squence number of the payload.
@@ -238,16 +522,113 @@ impl Payload {
     }
 }
 
+/// Decodes the data frames of the relation into record batches.
+///
+/// # Arguments
+/// * `uuid` - The uuid of the payload.
+/// * `encoding` - The compression of the data frames.
+/// * `relation` - 0 for the data frames of `data`, and 1 for `data2`.
+/// * `data` - The data frames of the relation.
+/// * `schema` - The schema bytes of the relation.
+fn decode_relation(
+    uuid: &Uuid,
+    encoding: &Encoding,
+    relation: usize,
+    data: Vec<DataFrame>,
+    schema: &[u8],
+) -> Result<Vec<RecordBatch>> {
+    let schema = schema_from_bytes(schema).map_err(|e| {
+        FlockError::Payload(
+            uuid.clone(),
+            PayloadError::Schema {
+                relation,
+                reason: e.to_string(),
+            },
+        )
+    })?;
+    data.into_par_iter()
+        .enumerate()
+        .map(|(index, d)| {
+            decode_data_frame(d, encoding, schema.clone()).map_err(|reason| {
+                FlockError::Payload(
+                    uuid.clone(),
+                    PayloadError::DataFrame {
+                        relation,
+                        index,
+                        reason,
+                    },
+                )
+            })
+        })
+        .collect()
+}
+
+/// Decodes the data frame into a record batch of the schema.
+///
+/// The lengths of the buffers are checked against the body before the batch
+/// is read, since Arrow panics on a truncated body.
+fn decode_data_frame(
+    d: DataFrame,
+    encoding: &Encoding,
+    schema: Arc<Schema>,
+) -> std::result::Result<RecordBatch, String> {
+    let (header, body) = match encoding {
+        Encoding::None => (d.header, d.body),
+        _ => (
+            encoding.decompress(&d.header).map_err(|e| e.to_string())?,
+            encoding.decompress(&d.body).map_err(|e| e.to_string())?,
+        ),
+    };
+    let message = root_as_message(&header).map_err(|e| format!("invalid header: {}", e))?;
+    let batch = message
+        .header_as_record_batch()
+        .ok_or_else(|| "the header is not a record batch".to_string())?;
+    let body_len = batch
+        .buffers()
+        .map(|buffers| {
+            buffers
+                .iter()
+                .map(|b| b.offset() + b.length())
+                .max()
+                .unwrap_or(0)
+        })
+        .unwrap_or(0);
+    if body_len < 0 || body_len as usize > body.len() {
+        return Err(format!(
+            "the body has {} bytes, but the buffers need {}",
+            body.len(),
+            body_len
+        ));
+    }
+    flight_data_to_arrow_batch(
+        &FlightData {
+            data_body:         body,
+            data_header:       header,
+            app_metadata:      vec![],
+            flight_descriptor: None,
+        },
+        schema,
+        &[],
+    )
+    .map_err(|e| e.to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::aws::client::{CloudClient, FakeCloudClient};
+    use crate::aws::kms::FakeKmsClient;
     use crate::error::Result;
-    use datafusion::arrow::array::{Array, StructArray};
+    use crate::tests::arbitrary;
+    use datafusion::arrow::array::{
+        Array, ArrayRef, Int64Array, ListArray, StringArray, StructArray,
+    };
     use datafusion::arrow::csv;
-    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::datatypes::{DataType, Field, Int64Type, Schema};
     use datafusion::arrow::json;
     use datafusion::arrow_flight::utils::flight_data_from_arrow_batch;
-    use serde_json::Value;
+    use proptest::prelude::*;
+    use serde_json::{json, Value};
     use std::sync::Arc;
     use std::time::Instant;
 
@@ -277,6 +658,36 @@ mod tests {
         }
     }
 
+    #[test]
+    fn uuid_builder_for_window() {
+        let window_id = "q3-1650000000-42-01";
+        let a = UuidBuilder::for_window("q3-01-00", window_id, 4);
+        let b = UuidBuilder::for_window("q3-01-03", window_id, 4);
+        assert_eq!(a.qid, b.qid);
+        assert_eq!(a.get(3), b.get(3));
+
+        // The qid is named like the others, with the timestamp of the window.
+        let parts = a.qid.split('-').collect::<Vec<_>>();
+        assert_eq!(parts[..2], ["q3", "1650000000"]);
+        assert!(parts[2].parse::<u128>().is_ok());
+
+        // The other windows and queries have their own qids.
+        assert_ne!(
+            UuidBuilder::for_window("q3-01-00", "q3-1650000000-42-02", 4).qid,
+            a.qid
+        );
+        assert_ne!(UuidBuilder::for_window("q4-01-00", window_id, 4).qid, a.qid);
+        assert!(UuidBuilder::for_window("q3-01-00", "window", 4)
+            .qid
+            .starts_with("q3-0-"));
+
+        // The qid doesn't depend on the toolchain that built the function.
+        assert_eq!(
+            a.qid,
+            "q3-1650000000-272297318152813089246564627027958362557"
+        );
+    }
+
     #[test]
     fn flight_data_compression_ratio_1() {
         let schema = Schema::new(vec![
@@ -483,7 +894,7 @@ mod tests {
 
         let payload1: Payload = serde_json::from_value(value.clone())?;
         let now = Instant::now();
-        let (de_batches, _) = json_value_to_batch(value);
+        let (de_batches, _) = json_value_to_batch(value)?;
         println!(
             "serde value to batch (with decompression) - time: {} ms",
             now.elapsed().as_millis()
@@ -519,6 +930,43 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn keep_payload_id_across_deliveries() -> Result<()> {
+        let batches = init_batches();
+        let mut uuid_builder = UuidBuilder::new_with_ts("q2-00", 1, 2);
+        let payload = to_payload(&batches, &[], uuid_builder.next_uuid(), false)?;
+        assert_eq!(payload.payload_id.len(), 36);
+        assert_eq!(payload.delivery_attempt, 1);
+        let other = to_payload(&batches, &[], uuid_builder.next_uuid(), false)?;
+        assert_ne!(payload.payload_id, other.payload_id);
+
+        // The serialization.
+        let bytes = serde_json::to_vec(&payload)?;
+        assert_eq!(serde_json::from_slice::<Payload>(&bytes)?, payload);
+
+        // The payload spilled to S3.
+        let client = FakeCloudClient::new();
+        client
+            .s3_put("flock-bucket", "q2/payload", bytes.clone())
+            .await?;
+        let spilled: Payload =
+            serde_json::from_slice(&client.s3_get("flock-bucket", "q2/payload").await?)?;
+        assert_eq!(spilled, payload);
+
+        // The retries of the sender.
+        let retried = next_attempt(&next_attempt(&bytes).unwrap()).unwrap();
+        let retried: Payload = serde_json::from_slice(&retried)?;
+        assert_eq!(retried.payload_id, payload.payload_id);
+        assert_eq!(retried.delivery_attempt, 3);
+        assert_eq!(retried.data, payload.data);
+
+        // The payloads without an id, e.g. of the data source.
+        let bytes = serde_json::to_vec(&Payload::default())?;
+        assert!(next_attempt(&bytes).is_none());
+        assert!(!String::from_utf8(bytes).unwrap().contains("payload_id"));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn schema_ipc() -> Result<()> {
         let batches = init_batches();
@@ -535,6 +983,119 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn nested_types_payload() -> Result<()> {
+        let address = StructArray::from(vec![
+            (
+                Field::new("city", DataType::Utf8, true),
+                Arc::new(StringArray::from(vec![
+                    Some("Boston"),
+                    None,
+                    Some("Austin"),
+                ])) as ArrayRef,
+            ),
+            (
+                Field::new("zip", DataType::Int64, true),
+                Arc::new(Int64Array::from(vec![Some(2110), Some(20742), None])) as ArrayRef,
+            ),
+        ]);
+        let phones = ListArray::from_iter_primitive::<Int64Type, _, _>(vec![
+            Some(vec![Some(1), Some(2)]),
+            Some(vec![]),
+            None,
+        ]);
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("address", address.data_type().clone(), true),
+            Field::new("phones", phones.data_type().clone(), true),
+        ]));
+        let batch =
+            RecordBatch::try_new(schema.clone(), vec![Arc::new(address), Arc::new(phones)])?;
+
+        // The nested fields are kept in the IPC schema.
+        assert_eq!(schema_from_bytes(&schema_to_bytes(schema.clone()))?, schema);
+
+        let uuid = UuidBuilder::new_with_ts("q1-00", 1, 1).next_uuid();
+        let payload = to_payload(&[batch.clone()], &[], uuid, false)?;
+        let payload: Payload = serde_json::from_slice(&serde_json::to_vec(&payload)?)?;
+        let (batches, _) = payload.to_record_batch()?;
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].schema(), schema);
+        assert_eq!(batches[0].columns(), batch.columns());
+
+        let address = batches[0]
+            .column(0)
+            .as_any()
+            .downcast_ref::<StructArray>()
+            .unwrap();
+        let city = address
+            .column_by_name("city")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(city.value(0), "Boston");
+        assert!(city.is_null(1));
+        let phones = batches[0]
+            .column(1)
+            .as_any()
+            .downcast_ref::<ListArray>()
+            .unwrap();
+        assert_eq!(phones.value_length(0), 2);
+        assert!(phones.is_null(2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn encrypted_payload() -> Result<()> {
+        let kms = Arc::new(FakeKmsClient::new());
+        let sender = EnvelopeCipher::new("alias/flock", kms.clone()).await?;
+        let receiver = EnvelopeCipher::new("alias/flock", kms.clone()).await?;
+
+        let batches = init_batches();
+        let uuid = UuidBuilder::new_with_ts("q1-00", 1, 1).next_uuid();
+        let plaintext = to_payload(&batches, &batches, uuid, false)?;
+        let mut payload = plaintext.clone();
+        payload.seal(&sender)?;
+        assert!(payload.data.is_empty() && payload.data2.is_empty());
+        assert!(!payload.is_empty_data());
+        assert!(payload.get_data_size() > plaintext.get_data_size());
+        assert_eq!(payload.schema, plaintext.schema);
+
+        // The receiver decrypts the payload on the wire.
+        let mut received: Payload = serde_json::from_slice(&serde_json::to_vec(&payload)?)?;
+        received.open(&receiver).await?;
+        assert_eq!(received, plaintext);
+        let (r1, r2) = received.to_record_batch()?;
+        assert_eq!(r1.len(), batches.len());
+        assert_eq!(r2[0].columns(), batches[0].columns());
+
+        // The plaintext payload is opened as it is, and nothing is sealed
+        // without data.
+        let mut received = plaintext.clone();
+        received.open(&receiver).await?;
+        assert_eq!(received, plaintext);
+        let mut empty = Payload::default();
+        empty.seal(&sender)?;
+        assert!(empty.sealed.is_none() && empty.is_empty_data());
+
+        // Another KMS key can't unwrap the data key.
+        let stranger = EnvelopeCipher::new("alias/other", kms.clone()).await?;
+        let mut received = payload.clone();
+        received.sealed.as_mut().unwrap().key_id = "alias/other".to_string();
+        assert!(received.open(&stranger).await.is_err());
+
+        // The tampered data fails the GCM tag, and the payload stays sealed.
+        let mut received = payload.clone();
+        received.sealed.as_mut().unwrap().ciphertext[7] ^= 1;
+        assert!(received.open(&receiver).await.is_err());
+        assert!(received.sealed.is_some());
+
+        // The receiver unwrapped the data key of the sender only once, and the
+        // other call is the failed one of the other KMS key.
+        assert_eq!(kms.decrypt_calls(), 2);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn uuid() -> Result<()> {
         let mut uuid_builder =
@@ -544,11 +1105,272 @@ mod tests {
         let batches = init_batches();
         let bytes = to_bytes(&batches[0], uuid_builder.next_uuid(), Encoding::default());
         let value: Value = serde_json::from_slice(&bytes)?;
-        let (de_batches, _) = json_value_to_batch(value);
+        let (de_batches, _) = json_value_to_batch(value)?;
 
         assert_eq!(batches[0].schema(), de_batches[0].schema());
         assert_eq!(batches[0].columns(), de_batches[0].columns());
 
         Ok(())
     }
+
+    // Corrupts the payload on the wire, and returns the error of the decoding.
+    fn corrupted_payload(batch: &RecordBatch, corrupt: impl Fn(&mut Value)) -> FlockError {
+        let uuid = UuidBuilder::new_with_ts("q1-00", 1, 1).next_uuid();
+        let payload = to_payload(&[batch.clone()], &[batch.clone()], uuid, false).unwrap();
+        let mut value = serde_json::to_value(&payload).unwrap();
+        corrupt(&mut value);
+        let payload: Payload = serde_json::from_value(value).unwrap();
+        payload.to_record_batch().unwrap_err()
+    }
+
+    #[test]
+    fn malformed_payload() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![Arc::new(Int64Array::from((0..1024).collect::<Vec<i64>>()))],
+        )?;
+
+        // The schema bytes of the 1st relation are garbage.
+        let err = corrupted_payload(&batch, |v| v["schema"] = json!([1, 2, 3]));
+        assert!(matches!(
+            err,
+            FlockError::Payload(_, PayloadError::Schema { relation: 0, .. })
+        ));
+        assert_eq!(err.kind(), "Payload.Schema");
+
+        // The schema bytes of the 2nd relation are truncated.
+        let err = corrupted_payload(&batch, |v| {
+            let schema = v["schema2"].as_array_mut().unwrap();
+            schema.truncate(schema.len() / 2);
+        });
+        assert!(matches!(
+            err,
+            FlockError::Payload(_, PayloadError::Schema { relation: 1, .. })
+        ));
+        assert_eq!(err.kind(), "Payload.Schema");
+
+        // The header of the data frame is not a flatbuffer message.
+        let err = corrupted_payload(&batch, |v| v["data"][0]["header"] = json!(vec![0u8; 8]));
+        assert!(matches!(
+            err,
+            FlockError::Payload(
+                _,
+                PayloadError::DataFrame {
+                    relation: 0,
+                    index: 0,
+                    ..
+                }
+            )
+        ));
+
+        // The body of the data frame is cut off, which must not panic in Arrow.
+        let err = corrupted_payload(&batch, |v| {
+            let bytes = |v: &Value| {
+                let bytes: Vec<u8> = serde_json::from_value(v.clone()).unwrap();
+                Encoding::default().decompress(&bytes).unwrap()
+            };
+            v["encoding"] = json!(Encoding::None);
+            for relation in ["data", "data2"] {
+                let frame = &mut v[relation][0];
+                let (header, body) = (bytes(&frame["header"]), bytes(&frame["body"]));
+                frame["header"] = json!(header);
+                frame["body"] = json!(body[..body.len() / 2]);
+            }
+        });
+        match &err {
+            FlockError::Payload(
+                uuid,
+                PayloadError::DataFrame {
+                    relation: 0,
+                    index: 0,
+                    reason,
+                },
+            ) => {
+                assert_eq!(uuid.seq_num, 1);
+                assert!(reason.contains("the body has"));
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert_eq!(err.kind(), "Payload.DataFrame");
+
+        // The payload is sealed, but the function has no encryption key.
+        let err = corrupted_payload(&batch, |v| {
+            v["sealed"] = json!({
+                "key_id": "alias/flock",
+                "wrapped_key": [1, 2, 3],
+                "nonce": vec![0u8; 12],
+                "ciphertext": [4, 5, 6],
+            })
+        });
+        let uuid = match &err {
+            FlockError::Payload(uuid, PayloadError::Sealed(_)) => uuid.clone(),
+            _ => panic!("unexpected error: {}", err),
+        };
+
+        // The error is returned to the caller as a structured object.
+        let value = err.to_json();
+        assert_eq!(value["error"]["kind"], json!("Payload.Sealed"));
+        assert_eq!(value["error"]["uuid"], serde_json::to_value(&uuid)?);
+        assert!(value["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("invalid sealed data"));
+        Ok(())
+    }
+
+    fn window_payload(seq_num: usize, seq_len: usize) -> Payload {
+        Payload {
+            uuid: Uuid {
+                qid: "q1-1643678938-1".to_string(),
+                seq_num,
+                seq_len,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn validate_payload() -> Result<()> {
+        let mut payload = window_payload(1, 2);
+        payload.validate(true, Some(8))?;
+        assert_eq!(payload.get_window_id(), ("q1-1643678938-1".to_string(), 0));
+
+        payload.set_shuffle_id(8);
+        payload.validate(true, Some(8))?;
+        assert_eq!(payload.get_window_id(), ("q1-1643678938-1".to_string(), 8));
+
+        // The payloads of older versions don't have the window id.
+        let mut value = serde_json::to_value(&payload)?;
+        value.as_object_mut().unwrap().remove("window_id");
+        let legacy: Payload = serde_json::from_value(value)?;
+        assert_eq!(legacy.window_id, WindowId::default());
+        assert_eq!(legacy.get_window_id(), payload.get_window_id());
+        legacy.validate(true, Some(8))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_format_version() -> Result<()> {
+        let payload = window_payload(1, 2);
+        assert_eq!(payload.format_version, PAYLOAD_FORMAT_VERSION);
+        let bytes = serde_json::to_vec(&payload)?;
+        assert_eq!(Payload::from_slice(&bytes)?, payload);
+
+        // The payloads of the previous version are still read.
+        let mut value = serde_json::to_value(&payload)?;
+        value["format_version"] = json!(PAYLOAD_FORMAT_VERSION - 1);
+        let previous = Payload::from_value(value.clone())?;
+        assert_eq!(previous.format_version, PAYLOAD_FORMAT_VERSION - 1);
+
+        // The payloads of newer versions are rejected, even if their fields
+        // changed in a way that fails to parse.
+        value["format_version"] = json!(PAYLOAD_FORMAT_VERSION + 1);
+        value["uuid"] = json!("q1-1643678938-1/1/2");
+        for err in [
+            Payload::from_value(value.clone()).unwrap_err(),
+            Payload::from_slice(&serde_json::to_vec(&value)?).unwrap_err(),
+        ] {
+            assert!(matches!(
+                err,
+                FlockError::FormatVersion {
+                    format: WireFormat::Payload,
+                    found,
+                    current: PAYLOAD_FORMAT_VERSION,
+                } if found == PAYLOAD_FORMAT_VERSION + 1
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn validate_seq_num() {
+        for (seq_num, seq_len) in [(0, 2), (3, 2), (1, 0)] {
+            let err = window_payload(seq_num, seq_len)
+                .validate(false, None)
+                .unwrap_err();
+            assert!(err.to_string().contains("uuid.seq_num"));
+        }
+        // The payloads from the data sources are not fragmented.
+        assert!(window_payload(0, 0).validate(false, None).is_ok());
+    }
+
+    #[test]
+    fn validate_window_id() {
+        let payload = Payload::default();
+        assert!(payload.validate(false, None).is_ok());
+        let err = payload.validate(true, None).unwrap_err();
+        assert!(err.to_string().contains("window_id is missing"));
+
+        let mut payload = window_payload(1, 1);
+        payload.set_shuffle_id(2);
+        payload.shuffle_id = Some(3);
+        let err = payload.validate(true, None).unwrap_err();
+        assert!(err.to_string().contains("window_id.1 (2)"));
+    }
+
+    #[test]
+    fn validate_shuffle_id() {
+        let mut payload = window_payload(1, 1);
+        payload.set_shuffle_id(9);
+        assert!(payload.validate(true, None).is_ok());
+        let err = payload.validate(true, Some(8)).unwrap_err();
+        assert!(err.to_string().contains("exceeds the ring size (8)"));
+
+        payload.set_shuffle_id(0);
+        let err = payload.validate(true, Some(8)).unwrap_err();
+        assert!(err.to_string().contains("shuffle_id (0)"));
+
+        // All offending fields are reported at once.
+        let mut payload = window_payload(5, 2);
+        payload.set_shuffle_id(9);
+        let err = payload.validate(true, Some(8)).unwrap_err().to_string();
+        assert!(err.contains("uuid.seq_num (5)") && err.contains("shuffle_id (9)"));
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn payload_round_trip(
+            r1 in arbitrary::arb_batches(4),
+            r2 in arbitrary::arb_batches(2),
+            encoding in arbitrary::arb_encoding(),
+        ) {
+            let uuid = UuidBuilder::new_with_ts("proptest-00", 1, 1).next_uuid();
+            let payload = to_payload_with_encoding(&r1, &r2, uuid, false, &[], encoding).unwrap();
+            let bytes = serde_json::to_vec(&payload).unwrap();
+            let payload: Payload = serde_json::from_slice(&bytes).unwrap();
+            let (de_r1, de_r2) = payload.to_record_batch().unwrap();
+            prop_assert_eq!(de_r1, r1);
+            prop_assert_eq!(de_r2, r2);
+        }
+    }
+
+    #[test]
+    fn round_trip_empty_batches() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Utf8, true),
+            Field::new("b", DataType::Int64, true),
+        ]));
+        let empty = RecordBatch::new_empty(schema.clone());
+        let nulls = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(StringArray::from(vec![None::<&str>; 3])),
+                Arc::new(Int64Array::from(vec![None; 3])),
+            ],
+        )?;
+        let uuid = UuidBuilder::new_with_ts("proptest-00", 1, 1).next_uuid();
+
+        let value = batch_to_json_value(&[], uuid.clone(), Encoding::None);
+        assert_eq!(json_value_to_batch(value)?, (vec![], vec![]));
+
+        let batches = vec![empty, nulls];
+        let value = batch_to_json_value(&batches, uuid, Encoding::Zstd);
+        assert_eq!(json_value_to_batch(value)?.0, batches);
+        Ok(())
+    }
 }
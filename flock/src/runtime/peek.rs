@@ -0,0 +1,262 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The sampled output of a running stage.
+//!
+//! [`crate::api::peek`], i.e. `flock-cli lambda peek`, writes a [`PeekMarker`]
+//! to the S3 object `<query code>/peek/<stage>`. While the marker exists, the
+//! function of the stage writes the first rows of its output of each window to
+//! `<query code>/peek-results/<stage>/<window>`, and then forwards the output
+//! as usual. The function checks the marker at most once every
+//! `peek_interval` seconds, and the sample is capped at `peek_max_bytes`
+//! bytes.
+
+use crate::aws::client::{AwsCloudClient, CloudClient};
+use crate::configs::*;
+use crate::error::Result;
+use crate::runtime::payload::{Payload, Uuid};
+use crate::transmute::to_payload;
+use datafusion::arrow::array::UInt32Array;
+use datafusion::arrow::compute::take;
+use datafusion::arrow::record_batch::RecordBatch;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// The request to sample the output of a stage.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeekMarker {
+    /// The number of rows to sample from the output of each window.
+    pub rows:      usize,
+    /// The time in milliseconds when the marker was written.
+    pub timestamp: i64,
+}
+
+/// Returns the first rows of the output. The rows are copied, so the sample
+/// doesn't share the buffers of the output.
+pub fn sample(output: &[Vec<RecordBatch>], rows: usize) -> Result<Vec<RecordBatch>> {
+    let mut remaining = rows;
+    let mut sample = vec![];
+    for batch in output.iter().flatten() {
+        if remaining == 0 {
+            break;
+        }
+        let n = remaining.min(batch.num_rows());
+        if n == 0 {
+            continue;
+        }
+        let indices = UInt32Array::from((0..n as u32).collect::<Vec<_>>());
+        let columns = batch
+            .columns()
+            .iter()
+            .map(|c| take(c.as_ref(), &indices, None))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        sample.push(RecordBatch::try_new(batch.schema(), columns)?);
+        remaining -= n;
+    }
+    Ok(sample)
+}
+
+/// The peek markers and the sampled outputs of the stages in an S3 bucket.
+#[derive(Debug, Clone)]
+pub struct Peeks {
+    client: Arc<dyn CloudClient>,
+    bucket: String,
+}
+
+impl Default for Peeks {
+    fn default() -> Self {
+        Self::new(Arc::new(AwsCloudClient), &FLOCK_S3_BUCKET)
+    }
+}
+
+impl Peeks {
+    /// Creates the peek markers in the given bucket.
+    pub fn new(client: Arc<dyn CloudClient>, bucket: &str) -> Self {
+        Self {
+            client,
+            bucket: bucket.to_string(),
+        }
+    }
+
+    fn marker_key(query_code: &str, stage: usize) -> String {
+        format!("{}/peek/{:02}", query_code, stage)
+    }
+
+    fn sample_prefix(query_code: &str, stage: usize) -> String {
+        format!("{}/peek-results/{:02}/", query_code, stage)
+    }
+
+    /// Requests the samples of the stage, and removes its previous samples.
+    pub async fn request(&self, query_code: &str, stage: usize, marker: &PeekMarker) -> Result<()> {
+        self.client
+            .s3_delete(&self.bucket, &Self::sample_prefix(query_code, stage))
+            .await?;
+        self.client
+            .s3_put(
+                &self.bucket,
+                &Self::marker_key(query_code, stage),
+                serde_json::to_vec(marker)?,
+            )
+            .await
+    }
+
+    /// Returns the marker of the stage, if any.
+    pub async fn marker(&self, query_code: &str, stage: usize) -> Result<Option<PeekMarker>> {
+        let key = Self::marker_key(query_code, stage);
+        if !self
+            .client
+            .s3_list(&self.bucket, &key)
+            .await?
+            .iter()
+            .any(|k| *k == key)
+        {
+            return Ok(None);
+        }
+        let body = self.client.s3_get(&self.bucket, &key).await?;
+        Ok(Some(serde_json::from_slice(&body)?))
+    }
+
+    /// Removes the marker and the samples of the stage.
+    pub async fn remove(&self, query_code: &str, stage: usize) -> Result<()> {
+        self.client
+            .s3_delete(&self.bucket, &Self::marker_key(query_code, stage))
+            .await?;
+        self.client
+            .s3_delete(&self.bucket, &Self::sample_prefix(query_code, stage))
+            .await
+    }
+
+    /// Writes the first rows of the output of the window. The number of rows
+    /// is halved until the sample fits in `max_bytes` bytes.
+    ///
+    /// # Returns
+    /// The number of rows in the sample, or `None` if not even a single row
+    /// fits.
+    pub async fn write_sample(
+        &self,
+        query_code: &str,
+        stage: usize,
+        uuid: &Uuid,
+        output: &[Vec<RecordBatch>],
+        rows: usize,
+        max_bytes: usize,
+    ) -> Result<Option<usize>> {
+        let mut rows = rows.min(output.iter().flatten().map(|b| b.num_rows()).sum());
+        while rows > 0 {
+            let batches = sample(output, rows)?;
+            let bytes = serde_json::to_vec(&to_payload(&batches, &[], uuid.clone(), false)?)?;
+            if bytes.len() <= max_bytes {
+                let key = format!("{}{}", Self::sample_prefix(query_code, stage), uuid.qid);
+                self.client.s3_put(&self.bucket, &key, bytes).await?;
+                return Ok(Some(rows));
+            }
+            rows /= 2;
+        }
+        Ok(None)
+    }
+
+    /// Returns the windows of the samples of the stage.
+    pub async fn windows(&self, query_code: &str, stage: usize) -> Result<Vec<String>> {
+        let prefix = Self::sample_prefix(query_code, stage);
+        Ok(self
+            .client
+            .s3_list(&self.bucket, &prefix)
+            .await?
+            .into_iter()
+            .filter_map(|k| k.strip_prefix(&prefix).map(|w| w.to_string()))
+            .collect())
+    }
+
+    /// Returns the sample of the window.
+    pub async fn read_sample(
+        &self,
+        query_code: &str,
+        stage: usize,
+        window: &str,
+    ) -> Result<Vec<RecordBatch>> {
+        let key = format!("{}{}", Self::sample_prefix(query_code, stage), window);
+        let body = self.client.s3_get(&self.bucket, &key).await?;
+        let payload = Payload::from_slice(&body)?;
+        Ok(payload.to_record_batch()?.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aws::client::FakeCloudClient;
+    use crate::runtime::payload::UuidBuilder;
+    use crate::tests::int64_batch;
+
+    #[test]
+    fn sample_first_rows() -> Result<()> {
+        let output = vec![
+            vec![int64_batch(vec![1, 2]), int64_batch(vec![])],
+            vec![int64_batch(vec![3, 4, 5])],
+        ];
+        let sampled = sample(&output, 4)?;
+        assert_eq!(sampled.len(), 2);
+        assert_eq!(sampled[0], output[0][0]);
+        assert_eq!(sampled[1], int64_batch(vec![3, 4]));
+        assert_eq!(sample(&output, 10)?.len(), 2);
+        assert!(sample(&output, 0)?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_and_read_samples() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let peeks = Peeks::new(client.clone(), "flock");
+        assert_eq!(peeks.marker("q1", 2).await?, None);
+
+        let marker = PeekMarker {
+            rows:      3,
+            timestamp: 1,
+        };
+        peeks.request("q1", 2, &marker).await?;
+        assert_eq!(peeks.marker("q1", 2).await?, Some(marker));
+        assert_eq!(peeks.marker("q1", 1).await?, None);
+
+        let uuid = UuidBuilder::new_with_ts("q1-01", 1, 1).next_uuid();
+        let output = vec![vec![int64_batch((0..1000).collect())]];
+        let rows = peeks
+            .write_sample("q1", 2, &uuid, &output, 3, *FLOCK_PEEK_MAX_BYTES)
+            .await?;
+        assert_eq!(rows, Some(3));
+        assert_eq!(peeks.windows("q1", 2).await?, vec![uuid.qid.clone()]);
+        assert_eq!(
+            peeks.read_sample("q1", 2, &uuid.qid).await?,
+            vec![int64_batch(vec![0, 1, 2])]
+        );
+
+        // The sample is cut down to the size limit.
+        let output = vec![vec![int64_batch((0..10000).map(|i| i * i).collect())]];
+        let full = serde_json::to_vec(&to_payload(&output[0], &[], uuid.clone(), false)?)?;
+        let rows = peeks
+            .write_sample("q1", 2, &uuid, &output, 10000, full.len() / 2)
+            .await?
+            .unwrap();
+        assert!(rows < 10000);
+        let sampled = peeks.read_sample("q1", 2, &uuid.qid).await?;
+        assert_eq!(sampled[0].num_rows(), rows);
+        assert_eq!(
+            peeks.write_sample("q1", 2, &uuid, &output, 3, 16).await?,
+            None
+        );
+
+        peeks.remove("q1", 2).await?;
+        assert_eq!(peeks.marker("q1", 2).await?, None);
+        assert!(peeks.windows("q1", 2).await?.is_empty());
+        Ok(())
+    }
+}
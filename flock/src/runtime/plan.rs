@@ -16,21 +16,53 @@
 //! store.
 
 use crate::aws::s3;
-use crate::error::Result;
+use crate::driver::funcgen::estimate::row_width;
+use crate::error::{FlockError, Result};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::execution::context::ExecutionContext;
 use datafusion::physical_plan::displayable;
 use datafusion::physical_plan::empty::EmptyExec;
-use datafusion::physical_plan::hash_aggregate::HashAggregateExec;
+use datafusion::physical_plan::expressions::Column;
+use datafusion::physical_plan::hash_aggregate::{AggregateMode, HashAggregateExec};
 use datafusion::physical_plan::hash_join::HashJoinExec;
+use datafusion::physical_plan::limit::{GlobalLimitExec, LocalLimitExec};
+use datafusion::physical_plan::memory::MemoryExec;
+use datafusion::physical_plan::repartition::RepartitionExec;
 use datafusion::physical_plan::sort::SortExec;
-use datafusion::physical_plan::ExecutionPlan;
-use log::info;
+use datafusion::physical_plan::windows::WindowAggExec;
+use datafusion::physical_plan::{ExecutionPlan, Partitioning};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::sync::Arc;
+use tracing::info;
 
 type S3BUCKET = String;
 type S3KEY = String;
 
+/// The schema metadata key of the name of the stream that the table reads.
+pub const STREAM_NAME_METADATA_KEY: &str = "name";
+
+/// Returns the name of the stream in the schema metadata, if any.
+pub fn stream_name(schema: &Schema) -> Option<&str> {
+    schema
+        .metadata()
+        .get(STREAM_NAME_METADATA_KEY)
+        .map(|s| s.as_str())
+}
+
+/// Returns the schema with the name of the stream in its metadata, which
+/// replaces the name that the schema may already carry, e.g. the schema of a
+/// user table copied from a NEXMark table.
+pub fn with_stream_name(schema: &SchemaRef, name: &str) -> SchemaRef {
+    if stream_name(schema) == Some(name) {
+        return schema.clone();
+    }
+    let mut metadata = schema.metadata().clone();
+    metadata.insert(STREAM_NAME_METADATA_KEY.to_string(), name.to_string());
+    Arc::new(Schema::new_with_metadata(schema.fields().clone(), metadata))
+}
+
 /// The execution plan on cloud.
 #[derive(Default, Clone, Deserialize, Serialize)]
 pub struct CloudExecutionPlan {
@@ -74,10 +106,7 @@ impl CloudExecutionPlan {
 
     /// Returns the execution plan.
     pub async fn get_execution_plans(&mut self) -> Result<Vec<Arc<dyn ExecutionPlan>>> {
-        if self.execution_plans.is_empty()
-            || (self.execution_plans.len() == 1
-                && self.execution_plans[0].as_any().is::<EmptyExec>())
-        {
+        if !self.is_loaded() {
             if self.object_storage.is_some() {
                 info!("Loading plan from S3 {:?}", self.object_storage);
                 let (bucket, key) = self.object_storage.as_ref().unwrap();
@@ -89,30 +118,550 @@ impl CloudExecutionPlan {
         }
         Ok(self.execution_plans.clone())
     }
+
+    /// Returns the execution plan if it's loaded, or an error if it's still
+    /// in S3 (see [`CloudExecutionPlan::get_execution_plans`]).
+    pub fn loaded_execution_plans(&self) -> Result<Vec<Arc<dyn ExecutionPlan>>> {
+        if !self.is_loaded() {
+            return Err(FlockError::Internal(format!(
+                "The query plan in S3 {:?} is not loaded.",
+                self.object_storage
+            )));
+        }
+        Ok(self.execution_plans.clone())
+    }
+
+    /// Returns true if the execution plan isn't a placeholder of the plan
+    /// stored in S3.
+    fn is_loaded(&self) -> bool {
+        !(self.execution_plans.is_empty()
+            || (self.execution_plans.len() == 1
+                && self.execution_plans[0].as_any().is::<EmptyExec>()))
+    }
+}
+
+/// The operators that the cloud functions execute, by the names that the
+/// serializer tags them with. The other operators either don't survive the
+/// serde round trip of the execution context, or aren't supported on the
+/// fragments of the windows, and they are flagged by [`crate::runtime::lint`]
+/// before the query is deployed.
+pub const SUPPORTED_OPERATORS: &[&str] = &[
+    "coalesce_batches_exec",
+    "coalesce_partitions_exec",
+    "empty_exec",
+    "filter_exec",
+    "global_limit_exec",
+    "hash_aggregate_exec",
+    "hash_join_exec",
+    "local_limit_exec",
+    "memory_exec",
+    "projection_exec",
+    "repartition_exec",
+    "sort_exec",
+    "sort_preserving_merge_exec",
+    "union_exec",
+];
+
+/// Returns the name that the serializer tags the operator with, e.g.
+/// `hash_join_exec`.
+pub fn operator_name(plan: &Arc<dyn ExecutionPlan>) -> Result<String> {
+    let value = serde_json::to_value(plan)?;
+    value["execution_plan"]
+        .as_str()
+        .map(|name| name.to_string())
+        .ok_or_else(|| FlockError::Plan("The serialized operator has no name".to_string()))
 }
 
-macro_rules! query_has_op_function {
-    ($OPERATOR:ident, $FUNC:ident) => {
-        /// Returns true if the current execution plan contains a given operator.
-        pub fn $FUNC(plan: &Arc<dyn ExecutionPlan>) -> bool {
-            let mut curr = plan.clone();
-            loop {
-                if curr.as_any().downcast_ref::<$OPERATOR>().is_some() {
-                    return true;
+/// The properties of the plans of a query stage that the functions and the
+/// planner branch on, computed in one walk of the plans (see
+/// [`PlanProperties::analyze`]). The functions compute them once per context
+/// load (see [`ExecutionContext::properties`](crate::runtime::context::ExecutionContext::properties)).
+#[derive(Debug, Clone)]
+pub struct PlanProperties {
+    /// True if the plans join their inputs.
+    pub has_join:              bool,
+    /// True if the plans aggregate their inputs partially.
+    pub has_partial_aggregate: bool,
+    /// True if the plans merge the partial aggregates, i.e. the aggregation in
+    /// the `Final` or `FinalPartitioned` mode.
+    pub has_final_aggregate:   bool,
+    /// True if the plans evaluate window functions.
+    pub has_window_fn:         bool,
+    /// True if the plans sort their inputs.
+    pub has_sort:              bool,
+    /// True if the plans limit their outputs.
+    pub has_limit:             bool,
+    /// True if any plan hash partitions its output for the next function
+    /// group (see [`shuffle_partitions`]).
+    pub is_shuffling:          bool,
+    /// The number of the partitions that the first shuffling plan hashes its
+    /// output to, or `None` if no plan shuffles.
+    pub shuffle_partitions:    Option<usize>,
+    /// The schemas of the leaves in the breadth-first order of the plans, which
+    /// is the order they are fed in (see [`feed_memory_sources`]).
+    pub leaf_schemas:          Vec<SchemaRef>,
+    /// The output partitioning of the first plan.
+    pub output_partitioning:   Partitioning,
+    /// The estimated width in bytes of an output row of the first plan (see
+    /// [`row_width`]).
+    pub row_width:             usize,
+    /// The names of the operators in the depth-first order of the serialized
+    /// plans, by the names that the serializer tags them with (see
+    /// [`operator_name`]).
+    pub operators:             Vec<String>,
+}
+
+impl PlanProperties {
+    /// Analyzes the plan.
+    pub fn analyze(plan: &Arc<dyn ExecutionPlan>) -> Self {
+        Self::analyze_all(std::slice::from_ref(plan))
+    }
+
+    /// Analyzes the plans of a query stage. The flags are set if any plan has
+    /// the property.
+    pub fn analyze_all(plans: &[Arc<dyn ExecutionPlan>]) -> Self {
+        let mut properties = PlanProperties {
+            has_join:              false,
+            has_partial_aggregate: false,
+            has_final_aggregate:   false,
+            has_window_fn:         false,
+            has_sort:              false,
+            has_limit:             false,
+            is_shuffling:          false,
+            shuffle_partitions:    None,
+            leaf_schemas:          vec![],
+            output_partitioning:   Partitioning::UnknownPartitioning(0),
+            row_width:             0,
+            operators:             vec![],
+        };
+        if let Some(plan) = plans.first() {
+            properties.output_partitioning = plan.output_partitioning();
+            properties.row_width = row_width(&plan.schema());
+        }
+
+        // Depth-first search
+        let mut nodes = plans.iter().rev().cloned().collect::<Vec<_>>();
+        while let Some(node) = nodes.pop() {
+            let any = node.as_any();
+            if let Some(agg) = any.downcast_ref::<HashAggregateExec>() {
+                match agg.mode() {
+                    AggregateMode::Partial => properties.has_partial_aggregate = true,
+                    _ => properties.has_final_aggregate = true,
                 }
-                if curr.children().is_empty() {
-                    break;
+            }
+            properties.has_join |= any.is::<HashJoinExec>();
+            properties.has_window_fn |= any.is::<WindowAggExec>();
+            properties.has_sort |= any.is::<SortExec>();
+            properties.has_limit |= any.is::<GlobalLimitExec>() || any.is::<LocalLimitExec>();
+            nodes.extend(node.children().into_iter().rev());
+        }
+
+        // The serialized plans are walked once instead of serializing every
+        // subplan to name its operator.
+        fn names(value: &serde_json::Value, operators: &mut Vec<String>) {
+            match value {
+                serde_json::Value::Object(object) => {
+                    if let Some(name) = object.get("execution_plan").and_then(|v| v.as_str()) {
+                        operators.push(name.to_owned());
+                    }
+                    object.values().for_each(|v| names(v, operators));
                 }
-                curr = curr.children()[0].clone();
+                serde_json::Value::Array(values) => values.iter().for_each(|v| names(v, operators)),
+                _ => {}
+            }
+        }
+        for plan in plans {
+            if let Ok(value) = serde_json::to_value(plan) {
+                names(&value, &mut properties.operators);
+            }
+        }
+
+        // Breadth-first search
+        let mut queue = plans.iter().cloned().collect::<VecDeque<_>>();
+        while let Some(node) = queue.pop_front() {
+            if node.children().is_empty() {
+                properties.leaf_schemas.push(node.schema());
             }
-            false
+            queue.extend(node.children());
         }
+
+        properties.shuffle_partitions = plans.iter().find_map(shuffle_partitions);
+        properties.is_shuffling = properties.shuffle_partitions.is_some();
+        properties
+    }
+
+    /// Returns true if the plans aggregate their inputs, partially or finally.
+    pub fn has_aggregate(&self) -> bool {
+        self.has_partial_aggregate || self.has_final_aggregate
+    }
+
+    /// Returns true if the plans sort and limit their outputs, i.e. they keep
+    /// the top rows.
+    pub fn has_sort_limit(&self) -> bool {
+        self.has_sort && self.has_limit
+    }
+
+    /// Returns true if the plans keep the state across the payloads of a
+    /// window, i.e. they aggregate, join or sort their inputs.
+    pub fn is_stateful(&self) -> bool {
+        self.has_aggregate() || self.has_join || self.has_sort
+    }
+
+    /// Returns true if the plans are split into multiple query stages, i.e.
+    /// they join or sort their inputs, or merge the partial aggregates (see
+    /// [`crate::distributed_plan::stage::build_query_dag`]).
+    pub fn has_stage_boundary(&self) -> bool {
+        self.has_join || self.has_sort || self.has_final_aggregate
+    }
+}
+
+/// Returns the hash `RepartitionExec` that the output partitioning of the plan
+/// comes from. The operators above it must keep the partitions of their input,
+/// e.g. a projection, a filter or the coalescing of the batches, so that the
+/// detection doesn't depend on the exact operators that the planner wraps the
+/// repartition in. The stateful operators consume the partitioning instead.
+fn shuffle_repartition(plan: &Arc<dyn ExecutionPlan>) -> Option<Arc<dyn ExecutionPlan>> {
+    let mut node = plan.clone();
+    loop {
+        let any = node.as_any();
+        if any.is::<RepartitionExec>() {
+            return match node.output_partitioning() {
+                Partitioning::Hash(..) | Partitioning::HashDiff(..) => Some(node),
+                _ => None,
+            };
+        }
+        if any.is::<HashAggregateExec>()
+            || any.is::<HashJoinExec>()
+            || any.is::<SortExec>()
+            || any.is::<WindowAggExec>()
+            || any.is::<GlobalLimitExec>()
+        {
+            return None;
+        }
+        let children = node.children();
+        if children.len() != 1
+            || children[0].output_partitioning().partition_count()
+                != node.output_partitioning().partition_count()
+        {
+            return None;
+        }
+        node = children[0].clone();
+    }
+}
+
+/// Returns the number of the partitions that the plan shuffles its output to
+/// the next function group, i.e. its output is hash partitioned by a
+/// `RepartitionExec`, or `None` if the plan doesn't shuffle.
+pub fn shuffle_partitions(plan: &Arc<dyn ExecutionPlan>) -> Option<usize> {
+    shuffle_repartition(plan).map(|r| r.output_partitioning().partition_count())
+}
+
+/// Returns true if the plan contains a sort.
+pub fn contain_sort(plan: &Arc<dyn ExecutionPlan>) -> bool {
+    PlanProperties::analyze(plan).has_sort
+}
+
+/// Returns true if the plan contains a join.
+pub fn contain_join(plan: &Arc<dyn ExecutionPlan>) -> bool {
+    PlanProperties::analyze(plan).has_join
+}
+
+/// Returns true if the plan contains an aggregation.
+pub fn contain_aggregate(plan: &Arc<dyn ExecutionPlan>) -> bool {
+    PlanProperties::analyze(plan).has_aggregate()
+}
+
+/// The leaf of the plans fed by [`feed_memory_sources`].
+#[derive(Debug, Clone)]
+pub struct LeafFeed {
+    /// The schema of the leaf node.
+    pub schema: SchemaRef,
+    /// The index of the data source that fed the leaf, or `None` if the leaf
+    /// was fed with empty partitions.
+    pub source: Option<usize>,
+}
+
+/// Which data sources fed which leaves of the plans.
+#[derive(Debug, Clone, Default)]
+pub struct FeedReport {
+    /// The leaf nodes in the breadth-first order of the plans.
+    pub leaves: Vec<LeafFeed>,
+    /// The indices of the data sources that didn't match any leaf node.
+    pub unused: Vec<usize>,
+}
+
+impl FeedReport {
+    /// Returns the index of the data source that fed the leaf.
+    pub fn source_of(&self, leaf: usize) -> Option<usize> {
+        self.leaves.get(leaf).and_then(|l| l.source)
+    }
+
+    /// Returns the pairs of the leaf index and the data source index of the
+    /// fed leaves.
+    pub fn fed(&self) -> Vec<(usize, usize)> {
+        self.leaves
+            .iter()
+            .enumerate()
+            .filter_map(|(i, l)| l.source.map(|s| (i, s)))
+            .collect()
+    }
+}
+
+/// Feeds the data sources to the memory leaves of the plans.
+///
+/// The leaves are visited in the breadth-first order, and each leaf takes the
+/// data source whose fields are a superset or subset of the leaf's, which
+/// isn't fed to any other leaf. The leaves without a matching data source are
+/// fed with empty partitions.
+///
+/// The data sources are named after the stream names in the metadata of their
+/// schemas (see [`feed_named_sources`]).
+///
+/// # Arguments
+/// * `plans` - The execution plans to feed.
+/// * `sources` - The partitions of each data source.
+///
+/// # Returns
+/// Which data source fed which leaf, where the data sources are identified by
+/// their indices in `sources`.
+pub fn feed_memory_sources(
+    plans: Vec<Arc<dyn ExecutionPlan>>,
+    sources: Vec<Vec<Vec<RecordBatch>>>,
+) -> Result<FeedReport> {
+    feed_named_sources(plans, sources, &[])
+}
+
+/// Feeds the data sources with the given stream names to the memory leaves of
+/// the plans like [`feed_memory_sources`].
+///
+/// A leaf takes the data source of the same stream first, and otherwise the
+/// data source that matches its schema. The stream of a data source is the
+/// given name, e.g. from the payload (see
+/// [`crate::runtime::payload::Payload::streams`]), or else the stream name in
+/// the metadata of its schema, which may collide with the names of the other
+/// streams, e.g. if a user table reuses the schema of a NEXMark table. The fed
+/// record batches take the stream name of the leaf.
+///
+/// # Arguments
+/// * `plans` - The execution plans to feed.
+/// * `sources` - The partitions of each data source.
+/// * `names` - The stream names of the data sources by their indices, which may
+///   be shorter than `sources`.
+///
+/// # Returns
+/// An error listing the candidates if the data sources of different streams
+/// match the schema of a leaf equally well, and none of them is the stream of
+/// the leaf.
+pub fn feed_named_sources(
+    plans: Vec<Arc<dyn ExecutionPlan>>,
+    mut sources: Vec<Vec<Vec<RecordBatch>>>,
+    names: &[Option<String>],
+) -> Result<FeedReport> {
+    let num_partitions = sources.first().map_or(1, |s| s.len());
+    let mut indices = (0..sources.len()).collect::<Vec<_>>();
+    let mut names = sources
+        .iter()
+        .enumerate()
+        .map(|(i, partitions)| {
+            names.get(i).cloned().flatten().or_else(|| {
+                partitions
+                    .iter()
+                    .flatten()
+                    .next()
+                    .and_then(|batch| stream_name(&batch.schema()).map(String::from))
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut report = FeedReport::default();
+
+    // Breadth-first search
+    let mut queue = plans.into_iter().collect::<VecDeque<_>>();
+    while let Some(mut plan) = queue.pop_front() {
+        if plan.children().is_empty() {
+            let schema = plan.schema();
+            let (partitions, source) = match find_data_source(schema.clone(), &sources, &names)? {
+                Some(index) => {
+                    names.remove(index);
+                    (
+                        with_leaf_stream_name(&schema, sources.remove(index))?,
+                        Some(indices.remove(index)),
+                    )
+                }
+                None => (
+                    vec![(0..num_partitions)
+                        .map(|_| RecordBatch::new_empty(schema.clone()))
+                        .collect()],
+                    None,
+                ),
+            };
+            if !plan.as_any().is::<MemoryExec>() {
+                return Err(FlockError::Execution(format!(
+                    "The leaf node is not a memory source: {}",
+                    displayable(plan.as_ref()).indent()
+                )));
+            }
+            unsafe {
+                Arc::get_mut_unchecked(&mut plan)
+                    .as_mut_any()
+                    .downcast_mut::<MemoryExec>()
+                    .unwrap()
+                    .set_partitions(partitions);
+            }
+            report.leaves.push(LeafFeed { schema, source });
+        }
+        queue.extend(plan.children());
+    }
+
+    report.unused = indices;
+    Ok(report)
+}
+
+/// Finds the data source that feeds the leaf node with the given schema.
+///
+/// A data source matches the leaf node if its field names are a superset or
+/// subset of the leaf node's. If multiple data sources match, for example the
+/// children of a union that read from different streams, the one of the same
+/// stream as the leaf, or else with the same field names, wins. The first one
+/// wins the ties, unless they are of different streams.
+///
+/// Returns the index of the data source in `sources`.
+fn find_data_source(
+    schema: SchemaRef,
+    sources: &[Vec<Vec<RecordBatch>>],
+    names: &[Option<String>],
+) -> Result<Option<usize>> {
+    let name = stream_name(&schema);
+    let fields = schema
+        .fields()
+        .iter()
+        .map(|f| f.name())
+        .collect::<HashSet<_>>();
+
+    let candidates = sources
+        .iter()
+        .enumerate()
+        .filter_map(|(i, partitions)| {
+            partitions
+                .iter()
+                .flatten()
+                .next()
+                .map(|batch| (i, batch.schema()))
+        })
+        .filter(|(_, source)| compare_schema(schema.clone(), source.clone()))
+        .map(|(i, source)| {
+            let same_fields = source.fields().len() == fields.len()
+                && source.fields().iter().all(|f| fields.contains(&f.name()));
+            (i, same_fields)
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(&(i, _)) = candidates
+        .iter()
+        .find(|(i, _)| name.is_some() && names[*i].as_deref() == name)
+    {
+        return Ok(Some(i));
+    }
+
+    let best = match candidates.iter().map(|(_, same_fields)| *same_fields).max() {
+        Some(best) => best,
+        None => return Ok(None),
+    };
+    let candidates = candidates
+        .into_iter()
+        .filter(|(_, same_fields)| *same_fields == best)
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    let streams = candidates
+        .iter()
+        .filter_map(|i| names[*i].as_deref())
+        .collect::<BTreeSet<_>>();
+    if streams.len() > 1 {
+        return Err(FlockError::Execution(format!(
+            "The data source of the leaf of the stream {} is ambiguous, the candidates are \
+             the streams {}",
+            name.unwrap_or("<unnamed>"),
+            streams.into_iter().collect::<Vec<_>>().join(", ")
+        )));
+    }
+    Ok(candidates.first().copied())
+}
+
+/// Sets the stream name of the record batches fed to the leaf to the one of
+/// the leaf, so that the batches of the streams with colliding names match the
+/// schema of the plan.
+fn with_leaf_stream_name(
+    schema: &SchemaRef,
+    partitions: Vec<Vec<RecordBatch>>,
+) -> Result<Vec<Vec<RecordBatch>>> {
+    let name = match stream_name(schema) {
+        Some(name) => name,
+        None => return Ok(partitions),
+    };
+    partitions
+        .into_iter()
+        .map(|batches| {
+            batches
+                .into_iter()
+                .map(|batch| {
+                    if stream_name(&batch.schema()) == Some(name) {
+                        return Ok(batch);
+                    }
+                    Ok(RecordBatch::try_new(
+                        with_stream_name(&batch.schema(), name),
+                        batch.columns().to_vec(),
+                    )?)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Compare two execution plans' schemas.
+/// Returns true if they are belong to the same plan node, i.e. the fields of
+/// one schema are a subset of the other's, and the fields of the same name
+/// have the same type.
+fn compare_schema(schema1: SchemaRef, schema2: SchemaRef) -> bool {
+    compare_fields(schema1.fields(), schema2.fields())
+}
+
+/// Returns true if the smaller list of fields is a subset of the larger one.
+fn compare_fields(fields1: &[Field], fields2: &[Field]) -> bool {
+    let (superset, subset) = if fields1.len() >= fields2.len() {
+        (fields1, fields2)
+    } else {
+        (fields2, fields1)
     };
+
+    let fields = superset
+        .iter()
+        .map(|f| (f.name(), f.data_type()))
+        .collect::<HashMap<_, _>>();
+
+    subset.iter().all(|f| {
+        fields
+            .get(f.name())
+            .map_or(false, |t| compare_type(f.data_type(), t))
+    })
 }
 
-query_has_op_function!(SortExec, contain_sort);
-query_has_op_function!(HashJoinExec, contain_join);
-query_has_op_function!(HashAggregateExec, contain_aggregate);
+/// Returns true if the two types are the same. The children of the struct and
+/// list types are compared recursively, ignoring their nullability, and the
+/// struct types must have the same fields.
+fn compare_type(type1: &DataType, type2: &DataType) -> bool {
+    match (type1, type2) {
+        (DataType::Struct(fields1), DataType::Struct(fields2)) => {
+            fields1.len() == fields2.len() && compare_fields(fields1, fields2)
+        }
+        (DataType::List(field1), DataType::List(field2))
+        | (DataType::LargeList(field1), DataType::LargeList(field2)) => {
+            compare_type(field1.data_type(), field2.data_type())
+        }
+        (DataType::FixedSizeList(field1, size1), DataType::FixedSizeList(field2, size2)) => {
+            size1 == size2 && compare_type(field1.data_type(), field2.data_type())
+        }
+        _ => type1 == type2,
+    }
+}
 
 /// A wrapper to generate the execution plan from a SQL query.
 pub async fn physical_plan<T: AsRef<str>>(
@@ -123,3 +672,455 @@ pub async fn physical_plan<T: AsRef<str>>(
     let logical_plan = ctx.optimize(&logical_plan)?;
     Ok(ctx.create_physical_plan(&logical_plan).await?)
 }
+
+/// Returns the key columns of the plans, i.e. the group-by columns of the
+/// aggregations and the join columns of the hash joins, whose distinct counts
+/// are estimated in the payload statistics of the former stage (see
+/// [`crate::runtime::stats`]).
+pub fn stats_keys(plans: &[Arc<dyn ExecutionPlan>]) -> Vec<String> {
+    fn visit(plan: &Arc<dyn ExecutionPlan>, keys: &mut Vec<String>) {
+        if let Some(agg) = plan.as_any().downcast_ref::<HashAggregateExec>() {
+            keys.extend(agg.group_expr().iter().map(|(_, name)| name.clone()));
+        }
+        if let Some(join) = plan.as_any().downcast_ref::<HashJoinExec>() {
+            join.on().iter().for_each(|(l, r)| {
+                keys.push(l.name().to_string());
+                keys.push(r.name().to_string());
+            });
+        }
+        plan.children().iter().for_each(|child| visit(child, keys));
+    }
+
+    let mut keys = vec![];
+    plans.iter().for_each(|plan| visit(plan, &mut keys));
+    let mut seen = std::collections::HashSet::new();
+    keys.retain(|k| seen.insert(k.clone()));
+    keys
+}
+
+/// Returns the hash partitioning columns of the shuffling plans (see
+/// [`PlanProperties::is_shuffling`]), i.e. the columns whose hash places the
+/// rows in the output partitions. `None` means a plan doesn't hash partition
+/// its output by columns, or the plans partition it by different columns.
+pub fn partition_keys(plans: &[Arc<dyn ExecutionPlan>]) -> Option<Vec<String>> {
+    let mut keys: Option<Vec<String>> = None;
+    for plan in plans {
+        let repartition = shuffle_repartition(plan)?;
+        let columns = match repartition.output_partitioning() {
+            Partitioning::Hash(exprs, _) | Partitioning::HashDiff(exprs, _) => exprs
+                .iter()
+                .map(|e| {
+                    e.as_any()
+                        .downcast_ref::<Column>()
+                        .map(|c| c.name().to_string())
+                })
+                .collect::<Option<Vec<_>>>()?,
+            _ => return None,
+        };
+        // A projection above the repartition may rename or drop the columns.
+        if columns.iter().any(|c| plan.schema().index_of(c).is_err()) {
+            return None;
+        }
+        match &keys {
+            Some(keys) if *keys != columns => return None,
+            Some(_) => {}
+            None => keys = Some(columns),
+        }
+    }
+    keys
+}
+
+/// Returns the group-by column if the plan counts the rows per key and joins
+/// the counts with their maximum, i.e. it emits the keys with the maximum count
+/// (NEXMark Q5). Such a plan can be evaluated incrementally over hopping
+/// windows.
+pub fn argmax_key(plan: &Arc<dyn ExecutionPlan>) -> Option<String> {
+    type Aggregate = (Vec<String>, Vec<String>);
+    fn visit(plan: &Arc<dyn ExecutionPlan>, aggs: &mut Vec<Aggregate>, join: &mut bool) {
+        if let Some(agg) = plan.as_any().downcast_ref::<HashAggregateExec>() {
+            aggs.push((
+                agg.group_expr()
+                    .iter()
+                    .map(|(_, name)| name.clone())
+                    .collect(),
+                agg.aggr_expr()
+                    .iter()
+                    .map(|e| e.name().to_string())
+                    .collect(),
+            ));
+        }
+        *join |= plan.as_any().is::<HashJoinExec>();
+        plan.children()
+            .iter()
+            .for_each(|child| visit(child, aggs, join));
+    }
+
+    let mut aggs = vec![];
+    let mut join = false;
+    visit(plan, &mut aggs, &mut join);
+    let has_max = aggs
+        .iter()
+        .any(|(keys, exprs)| keys.is_empty() && exprs.iter().any(|e| e.starts_with("MAX(")));
+    let mut keys = aggs
+        .into_iter()
+        .filter(|(keys, exprs)| keys.len() == 1 && exprs.iter().all(|e| e.starts_with("COUNT(")))
+        .map(|(mut keys, _)| keys.remove(0))
+        .collect::<Vec<_>>();
+    keys.dedup();
+    if join && has_max && keys.len() == 1 {
+        keys.pop()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasource::nexmark::register_nexmark_tables;
+    use crate::datasource::nexmark::Bid;
+    use crate::query::{Query, Table};
+    use datafusion::arrow::array::{
+        Int32Array, Int64Array, StringArray, TimestampMillisecondArray,
+    };
+    use datafusion::physical_plan::union::UnionExec;
+
+    #[tokio::test]
+    async fn analyze_nexmark_plans() -> Result<()> {
+        let ctx = register_nexmark_tables().await?;
+        let analyze = |plan: &Arc<dyn ExecutionPlan>| {
+            let properties = PlanProperties::analyze(plan);
+            assert_eq!(properties.row_width, row_width(&plan.schema()));
+            assert!(properties.output_partitioning.partition_count() >= 1);
+            properties
+        };
+
+        // Q1: a projection over the bids.
+        let plan = physical_plan(
+            &ctx,
+            include_str!("../../../benchmarks/src/nexmark/query/q1.sql"),
+        )
+        .await?;
+        let q1 = analyze(&plan);
+        assert!(!q1.has_join && !q1.has_aggregate() && !q1.has_window_fn && !q1.has_sort);
+        assert!(!q1.is_stateful() && !q1.has_stage_boundary());
+        assert_eq!(q1.leaf_schemas.len(), 1);
+        assert!(q1.operators.contains(&"projection_exec".to_owned()));
+        assert!(q1.operators.contains(&"memory_exec".to_owned()));
+        assert!(!contain_join(&plan) && !contain_aggregate(&plan) && !contain_sort(&plan));
+
+        // Q3: a join of the auctions and the persons.
+        let plan = physical_plan(
+            &ctx,
+            include_str!("../../../benchmarks/src/nexmark/query/q3.sql"),
+        )
+        .await?;
+        let q3 = analyze(&plan);
+        assert!(q3.has_join && !q3.has_aggregate() && !q3.has_window_fn);
+        assert!(q3.is_stateful() && q3.has_stage_boundary());
+        assert_eq!(q3.leaf_schemas.len(), 2);
+        assert!(q3.operators.contains(&"hash_join_exec".to_owned()));
+        assert!(contain_join(&plan));
+
+        // Q4: a join followed by two aggregations.
+        let plan = physical_plan(
+            &ctx,
+            include_str!("../../../benchmarks/src/nexmark/query/q4.sql"),
+        )
+        .await?;
+        let q4 = analyze(&plan);
+        assert!(q4.has_join && q4.has_partial_aggregate && q4.has_final_aggregate);
+        assert!(!q4.has_window_fn && !q4.has_limit);
+        assert_eq!(q4.leaf_schemas.len(), 2);
+        assert!(contain_aggregate(&plan));
+
+        // Q6: a join followed by window functions over the sorted bids.
+        let plan = physical_plan(
+            &ctx,
+            include_str!("../../../benchmarks/src/nexmark/query/q6.sql"),
+        )
+        .await?;
+        let q6 = analyze(&plan);
+        assert!(q6.has_join && q6.has_partial_aggregate && q6.has_final_aggregate);
+        assert!(q6.has_window_fn && q6.has_sort && !q6.has_limit);
+        assert!(contain_sort(&plan));
+
+        // A top-k query.
+        let plan = physical_plan(
+            &ctx,
+            "SELECT auction, price FROM bid ORDER BY price DESC LIMIT 10",
+        )
+        .await?;
+        let topk = analyze(&plan);
+        assert!(topk.has_sort_limit() && !topk.has_join && !topk.has_aggregate());
+
+        // The flags of a stage are set if any of its plans has the property.
+        let q1_plan = physical_plan(
+            &ctx,
+            include_str!("../../../benchmarks/src/nexmark/query/q1.sql"),
+        )
+        .await?;
+        let stage = PlanProperties::analyze_all(&[q1_plan, plan]);
+        assert!(stage.has_sort_limit() && !stage.is_shuffling);
+        assert_eq!(stage.leaf_schemas.len(), 2);
+        assert_eq!(
+            stage.operators.len(),
+            q1.operators.len() + topk.operators.len()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn detect_shuffling_plans() -> Result<()> {
+        use datafusion::physical_plan::coalesce_batches::CoalesceBatchesExec;
+        use datafusion::physical_plan::projection::ProjectionExec;
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("c1", DataType::Int64, false),
+            Field::new("c2", DataType::Int64, false),
+        ]));
+        let memory: Arc<dyn ExecutionPlan> =
+            Arc::new(MemoryExec::try_new(&[vec![]], schema.clone(), None)?);
+        let repartition = |partitioning: Partitioning| -> Result<Arc<dyn ExecutionPlan>> {
+            let repartition = Arc::new(RepartitionExec::try_new(memory.clone(), partitioning)?);
+            Ok(Arc::new(CoalesceBatchesExec::new(repartition, 4096)))
+        };
+        let hash = || Partitioning::Hash(vec![Arc::new(Column::new("c1", 0))], 8);
+
+        // The exact shape of the planner.
+        let plan = repartition(hash())?;
+        let properties = PlanProperties::analyze(&plan);
+        assert!(properties.is_shuffling);
+        assert_eq!(properties.shuffle_partitions, Some(8));
+        assert_eq!(partition_keys(&[plan]), Some(vec!["c1".to_string()]));
+
+        // A projection wrapped around the repartition keeps its partitions.
+        let projected: Arc<dyn ExecutionPlan> = Arc::new(ProjectionExec::try_new(
+            vec![
+                (Arc::new(Column::new("c2", 1)), "c2".to_string()),
+                (Arc::new(Column::new("c1", 0)), "c1".to_string()),
+            ],
+            repartition(hash())?,
+        )?);
+        let properties = PlanProperties::analyze(&projected);
+        assert!(properties.is_shuffling);
+        assert_eq!(properties.shuffle_partitions, Some(8));
+        assert_eq!(
+            partition_keys(&[projected.clone()]),
+            Some(vec!["c1".to_string()])
+        );
+
+        // The keys renamed by the projection aren't the partition keys.
+        let renamed: Arc<dyn ExecutionPlan> = Arc::new(ProjectionExec::try_new(
+            vec![(Arc::new(Column::new("c1", 0)), "key".to_string())],
+            repartition(hash())?,
+        )?);
+        assert!(PlanProperties::analyze(&renamed).is_shuffling);
+        assert_eq!(partition_keys(&[renamed]), None);
+
+        // The round-robin repartition doesn't shuffle.
+        let plan = repartition(Partitioning::RoundRobinBatch(8))?;
+        assert!(!PlanProperties::analyze(&plan).is_shuffling);
+
+        // The stage is shuffling if any of its plans shuffles.
+        let stage = PlanProperties::analyze_all(&[memory.clone(), projected]);
+        assert!(stage.is_shuffling);
+        assert_eq!(stage.shuffle_partitions, Some(8));
+
+        // The final aggregate consumes the hash partitions of its input, so
+        // its stage doesn't shuffle.
+        let ctx = register_nexmark_tables().await?;
+        let plan =
+            physical_plan(&ctx, "SELECT auction, COUNT(*) FROM bid GROUP BY auction").await?;
+        assert!(PlanProperties::analyze(&plan).has_final_aggregate);
+        assert!(!PlanProperties::analyze(&plan).is_shuffling);
+        Ok(())
+    }
+
+    #[test]
+    fn report_fed_leaves() -> Result<()> {
+        let schema =
+            |name: &str| Arc::new(Schema::new(vec![Field::new(name, DataType::Int64, false)]));
+        let leaf = |name: &str| -> Arc<dyn ExecutionPlan> {
+            Arc::new(MemoryExec::try_new(&[vec![]], schema(name), None).unwrap())
+        };
+        let source = |name: &str| {
+            let batch =
+                RecordBatch::try_new(schema(name), vec![Arc::new(Int64Array::from(vec![1]))])
+                    .unwrap();
+            vec![vec![batch]]
+        };
+
+        let plan: Arc<dyn ExecutionPlan> =
+            Arc::new(UnionExec::new(vec![leaf("a"), leaf("b"), leaf("c")]));
+        let report = feed_memory_sources(vec![plan], vec![source("b"), source("a"), source("d")])?;
+
+        // The sources are matched by their schemas regardless of their order,
+        // and the leaf without a matching source is fed with empty partitions.
+        assert_eq!(report.leaves.len(), 3);
+        assert_eq!(report.fed(), vec![(0, 1), (1, 0)]);
+        assert_eq!(report.source_of(2), None);
+        assert_eq!(report.leaves[2].schema.field(0).name(), "c");
+        assert_eq!(report.unused, vec![2]);
+
+        // The leaves must be memory sources.
+        let plan: Arc<dyn ExecutionPlan> = Arc::new(EmptyExec::new(false, schema("a")));
+        assert!(feed_memory_sources(vec![plan], vec![source("a")]).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn feed_streams_of_the_same_schema() -> Result<()> {
+        // The replayed bids are registered as a user table, and their schema
+        // keeps the stream name of the NEXMark bids.
+        let query = Query {
+            sql: concat!(
+                "SELECT 'live' AS src, auction FROM bid ",
+                "UNION ALL ",
+                "SELECT 'replay' AS src, auction FROM replay"
+            )
+            .to_string(),
+            tables: vec![
+                Table::new("bid", Arc::new(Bid::schema())),
+                Table::new("replay", Arc::new(Bid::schema())),
+            ],
+            ..Default::default()
+        };
+        let bids = |auctions: Vec<i32>| {
+            let n = auctions.len();
+            let batch = RecordBatch::try_new(
+                Arc::new(Bid::schema()),
+                vec![
+                    Arc::new(Int32Array::from(auctions)),
+                    Arc::new(Int32Array::from(vec![0; n])),
+                    Arc::new(Int32Array::from(vec![0; n])),
+                    Arc::new(TimestampMillisecondArray::from(vec![0; n])),
+                ],
+            )
+            .unwrap();
+            vec![vec![batch]]
+        };
+
+        // The stream names of the payload route the sources, even though both
+        // schemas are named after the NEXMark bids.
+        let plan = query.plan()?;
+        let names = [Some("replay".to_string()), Some("bid".to_string())];
+        let report = feed_named_sources(
+            vec![plan.clone()],
+            vec![bids(vec![10, 20, 30]), bids(vec![1, 2])],
+            &names,
+        )?;
+        assert_eq!(report.fed().len(), 2);
+        assert!(report.unused.is_empty());
+
+        let mut rows = vec![];
+        for batch in datafusion::physical_plan::collect(plan).await? {
+            let src = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap();
+            let auction = batch
+                .column(1)
+                .as_any()
+                .downcast_ref::<Int32Array>()
+                .unwrap();
+            rows.extend(
+                (0..batch.num_rows()).map(|i| (src.value(i).to_string(), auction.value(i))),
+            );
+        }
+        rows.sort();
+        let expected = [
+            ("live", 1),
+            ("live", 2),
+            ("replay", 10),
+            ("replay", 20),
+            ("replay", 30),
+        ];
+        assert_eq!(
+            rows,
+            expected
+                .iter()
+                .map(|(src, auction)| (src.to_string(), *auction))
+                .collect::<Vec<_>>()
+        );
+
+        // The sources of other streams that match the schema equally well are
+        // ambiguous.
+        let names = [Some("ask".to_string()), Some("offer".to_string())];
+        let err = feed_named_sources(
+            vec![query.plan()?],
+            vec![bids(vec![1]), bids(vec![2])],
+            &names,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("ask, offer"), "{}", err);
+
+        // The stream names of a query are unique.
+        let query = Query {
+            tables: vec![
+                Table::new("bid", Arc::new(Bid::schema())),
+                Table::new("bid", Arc::new(Bid::schema())),
+            ],
+            ..query
+        };
+        assert!(query.plan().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn compare_nested_schemas() {
+        let address = |zip: DataType| {
+            DataType::Struct(vec![
+                Field::new("city", DataType::Utf8, true),
+                Field::new("zip", zip, true),
+            ])
+        };
+        let schema = |fields: Vec<Field>| Arc::new(Schema::new(fields));
+        let person = schema(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("address", address(DataType::Utf8), true),
+            Field::new(
+                "phones",
+                DataType::List(Box::new(Field::new("item", DataType::Utf8, true))),
+                true,
+            ),
+        ]);
+
+        // The projected subset of the fields matches.
+        assert!(compare_schema(
+            person.clone(),
+            schema(vec![Field::new("address", address(DataType::Utf8), false)])
+        ));
+        // The list items are compared regardless of their names.
+        assert!(compare_schema(
+            person.clone(),
+            schema(vec![Field::new(
+                "phones",
+                DataType::List(Box::new(Field::new("element", DataType::Utf8, false))),
+                true,
+            )])
+        ));
+        // The nested fields are compared by their types.
+        assert!(!compare_schema(
+            person.clone(),
+            schema(vec![Field::new("address", address(DataType::Int64), true)])
+        ));
+        assert!(!compare_schema(
+            person.clone(),
+            schema(vec![Field::new(
+                "address",
+                DataType::Struct(vec![Field::new("city", DataType::Utf8, true)]),
+                true,
+            )])
+        ));
+        assert!(!compare_schema(
+            person,
+            schema(vec![Field::new(
+                "phones",
+                DataType::List(Box::new(Field::new("item", DataType::Int64, true))),
+                true,
+            )])
+        ));
+    }
+}
@@ -0,0 +1,418 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The response envelope of the cloud functions.
+//!
+//! Every invocation of a function returns the same envelope, so that the
+//! drivers can tell the windows that aren't complete yet from the broken
+//! stages:
+//!
+//! ```json
+//! {
+//!   "status": "ok" | "not_ready" | "duplicate" | "error",
+//!   "function": "<function name>",
+//!   "window": "<window id>",
+//!   "uuid": { .. },
+//!   "payload_id": "<payload id>",
+//!   "delivery_attempt": 1,
+//!   "data": { .. },
+//!   "error": { "kind": "..", "message": "..", "retryable": false }
+//! }
+//! ```
+//!
+//! The retryable errors still fail the invocation, so that Lambda retries the
+//! asynchronous invocations. Their response is the error object of the Lambda
+//! runtime, which [`Response::from_value`] reads as a retryable error.
+//!
+//! For one release, the keys of the `data` object are also copied to the top
+//! level of the envelope unless they clash with its own keys, so that the
+//! drivers reading the responses of the former release keep working.
+
+use crate::error::{FlockError, Result};
+use crate::runtime::arena::HashAggregateStatus;
+use crate::runtime::payload::Uuid;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The status of the invocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// The payload is processed, and its output is sent downstream.
+    Ok,
+    /// The payload is collected, but its window isn't complete yet.
+    NotReady,
+    /// The window of the payload is already processed.
+    Duplicate,
+    /// The invocation failed.
+    Error,
+}
+
+impl From<&HashAggregateStatus> for Status {
+    fn from(status: &HashAggregateStatus) -> Self {
+        match status {
+            HashAggregateStatus::Ready => Status::Ok,
+            HashAggregateStatus::NotReady => Status::NotReady,
+            HashAggregateStatus::Processed => Status::Duplicate,
+        }
+    }
+}
+
+/// The error of a failed invocation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResponseError {
+    /// The kind of the error (see [`FlockError::kind`]).
+    pub kind:      String,
+    /// The message of the error.
+    pub message:   String,
+    /// If true, the same payload may succeed on another attempt.
+    pub retryable: bool,
+}
+
+/// The response envelope of a function invocation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    /// The status of the invocation.
+    pub status:           Status,
+    /// The name of the function that responds.
+    pub function:         String,
+    /// The id of the window of the payload, if the payload belongs to one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window:           Option<String>,
+    /// The uuid of the payload.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid:             Option<Uuid>,
+    /// The id of the payload, if it has one (see
+    /// [`crate::runtime::payload::Payload::payload_id`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_id:       Option<String>,
+    /// The delivery attempt of the payload, if it has an id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivery_attempt: Option<u32>,
+    /// The result of the invocation, e.g. the response of the next function
+    /// in the synchronous mode, or the output of the data sink.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data:             Option<Value>,
+    /// The error of the invocation if the status is `error`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error:            Option<ResponseError>,
+}
+
+/// The keys of the envelope, which the keys of the data never overwrite.
+const ENVELOPE_KEYS: [&str; 8] = [
+    "status",
+    "function",
+    "window",
+    "uuid",
+    "payload_id",
+    "delivery_attempt",
+    "data",
+    "error",
+];
+
+impl Response {
+    /// Creates a response with the status.
+    pub fn new(status: Status, function: impl Into<String>) -> Self {
+        Response {
+            status,
+            function: function.into(),
+            window: None,
+            uuid: None,
+            payload_id: None,
+            delivery_attempt: None,
+            data: None,
+            error: None,
+        }
+    }
+
+    /// The payload is processed. The data is omitted if it's `null`.
+    pub fn ok(function: impl Into<String>, data: Value) -> Self {
+        Response::new(Status::Ok, function).with_data(data)
+    }
+
+    /// The window of the payload isn't complete yet.
+    pub fn not_ready(function: impl Into<String>) -> Self {
+        Response::new(Status::NotReady, function)
+    }
+
+    /// The window of the payload is already processed.
+    pub fn duplicate(function: impl Into<String>) -> Self {
+        Response::new(Status::Duplicate, function)
+    }
+
+    /// The invocation failed with the error. The uuid of a malformed payload
+    /// is kept in the response.
+    pub fn error(function: impl Into<String>, e: &FlockError) -> Self {
+        let mut response = Response::new(Status::Error, function);
+        if let FlockError::Payload(uuid, _) = e {
+            response.uuid = Some(uuid.clone());
+        }
+        response.error = Some(ResponseError {
+            kind:      e.kind(),
+            message:   e.to_string(),
+            retryable: e.is_retryable(),
+        });
+        response
+    }
+
+    /// Sets the window of the payload.
+    pub fn with_window(mut self, window: impl Into<String>) -> Self {
+        self.window = Some(window.into());
+        self
+    }
+
+    /// Sets the uuid of the payload.
+    pub fn with_uuid(mut self, uuid: Uuid) -> Self {
+        self.uuid = Some(uuid);
+        self
+    }
+
+    /// Sets the id and the delivery attempt of the payload. They are omitted
+    /// if the payload has no id.
+    pub fn with_delivery(mut self, payload_id: impl Into<String>, attempt: u32) -> Self {
+        let payload_id = payload_id.into();
+        if !payload_id.is_empty() {
+            self.payload_id = Some(payload_id);
+            self.delivery_attempt = Some(attempt);
+        }
+        self
+    }
+
+    /// Sets the data of the response. The data is omitted if it's `null`.
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = if data.is_null() { None } else { Some(data) };
+        self
+    }
+
+    /// Returns true if the payload is processed.
+    pub fn is_ok(&self) -> bool {
+        self.status == Status::Ok
+    }
+
+    /// Serializes the envelope, with the keys of the data object also at the
+    /// top level for the drivers of the former release.
+    pub fn to_value(&self) -> Value {
+        let mut value = serde_json::to_value(self).expect("the response is serializable");
+        if let (Value::Object(map), Some(Value::Object(data))) = (&mut value, &self.data) {
+            data.iter()
+                .filter(|(key, _)| !ENVELOPE_KEYS.contains(&key.as_str()))
+                .for_each(|(key, v)| {
+                    map.entry(key.clone()).or_insert_with(|| v.clone());
+                });
+        }
+        value
+    }
+
+    /// Reads the response of a function. The error object of the Lambda
+    /// runtime is a retryable error, and the responses of the former release
+    /// are the data of a successful invocation.
+    pub fn from_value(mut value: Value) -> Result<Response> {
+        if is_envelope(&value) {
+            // The keys copied from the data are dropped.
+            if let Some(map) = value.as_object_mut() {
+                map.retain(|key, _| ENVELOPE_KEYS.contains(&key.as_str()));
+            }
+            return Ok(serde_json::from_value(value)?);
+        }
+        if let (Some(kind), Some(message)) = (
+            value.get("errorType").and_then(Value::as_str),
+            value.get("errorMessage").and_then(Value::as_str),
+        ) {
+            let mut response = Response::new(Status::Error, "");
+            response.error = Some(ResponseError {
+                kind:      kind.to_string(),
+                message:   message.to_string(),
+                retryable: true,
+            });
+            return Ok(response);
+        }
+        Ok(Response::ok("", value))
+    }
+
+    /// Reads the response from the payload of the invocation, which is empty
+    /// if the function returned nothing.
+    pub fn from_slice(bytes: &[u8]) -> Result<Response> {
+        if bytes.is_empty() {
+            return Ok(Response::ok("", Value::Null));
+        }
+        Response::from_value(serde_json::from_slice(bytes)?)
+    }
+
+    /// Returns the data of the response, or of the innermost response if the
+    /// data is the response of the next function in the synchronous mode.
+    pub fn into_data(self) -> Value {
+        response_data(self.data.unwrap_or(Value::Null))
+    }
+
+    /// Returns an error if the invocation failed.
+    pub fn into_result(self) -> Result<Response> {
+        match &self.error {
+            Some(error) => Err(FlockError::Execution(format!(
+                "{} failed with {}: {}",
+                self.function, error.kind, error.message
+            ))),
+            None => Ok(self),
+        }
+    }
+}
+
+/// Returns true if the value is a response envelope.
+pub fn is_envelope(value: &Value) -> bool {
+    matches!(value.get("function"), Some(Value::String(_)))
+        && matches!(
+            value.get("status").and_then(Value::as_str),
+            Some("ok" | "not_ready" | "duplicate" | "error")
+        )
+}
+
+/// Returns the data of the response of a function, which may be wrapped in the
+/// envelopes of the functions that invoked it synchronously. The responses of
+/// the former release are returned as they are.
+pub fn response_data(mut value: Value) -> Value {
+    while is_envelope(&value) {
+        value = value
+            .as_object_mut()
+            .and_then(|map| map.remove("data"))
+            .unwrap_or(Value::Null);
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::PayloadError;
+    use crate::runtime::payload::UuidBuilder;
+    use serde_json::json;
+
+    fn uuid() -> Uuid {
+        UuidBuilder::new_with_ts_uuid("q3-00", 1, 7, 2).next_uuid()
+    }
+
+    #[test]
+    fn serialize_every_status() -> Result<()> {
+        let responses = vec![
+            Response::ok("q3-01-00", json!({ "rows": 3 }))
+                .with_window("q3-1-7-00")
+                .with_uuid(uuid()),
+            Response::not_ready("q3-01-00")
+                .with_window("q3-1-7-00")
+                .with_data(json!({ "arena": { "windows": 1 } })),
+            Response::duplicate("q3-01-00").with_window("q3-1-7-00"),
+            Response::error("q3-01-00", &FlockError::AWS("throttled".to_string())),
+        ];
+
+        let values = responses.iter().map(Response::to_value).collect::<Vec<_>>();
+        assert_eq!(values[0]["status"], json!("ok"));
+        assert_eq!(values[0]["function"], json!("q3-01-00"));
+        assert_eq!(values[0]["window"], json!("q3-1-7-00"));
+        assert_eq!(values[0]["uuid"], serde_json::to_value(uuid())?);
+        assert_eq!(values[0]["data"], json!({ "rows": 3 }));
+        assert!(values[0].get("error").is_none());
+
+        assert_eq!(values[1]["status"], json!("not_ready"));
+        assert_eq!(values[1]["data"]["arena"]["windows"], json!(1));
+        assert!(values[1].get("uuid").is_none());
+
+        assert_eq!(values[2]["status"], json!("duplicate"));
+        assert!(values[2].get("data").is_none());
+
+        assert_eq!(values[3]["status"], json!("error"));
+        assert_eq!(
+            values[3]["error"],
+            json!({ "kind": "AWS", "message": "AWS error: throttled", "retryable": true })
+        );
+
+        for (response, value) in responses.into_iter().zip(values) {
+            assert!(is_envelope(&value));
+            assert_eq!(Response::from_value(value)?, response);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn carry_payload_delivery() -> Result<()> {
+        let response = Response::not_ready("q3-01-00")
+            .with_uuid(uuid())
+            .with_delivery("4d5e6f70-0000-4000-8000-000000000001", 2);
+        let value = response.to_value();
+        assert_eq!(
+            value["payload_id"],
+            json!("4d5e6f70-0000-4000-8000-000000000001")
+        );
+        assert_eq!(value["delivery_attempt"], json!(2));
+        assert_eq!(Response::from_value(value)?, response);
+
+        // The payloads without an id, e.g. of the data source.
+        let value = Response::ok("q3-00", Value::Null)
+            .with_delivery("", 0)
+            .to_value();
+        assert!(value.get("payload_id").is_none());
+        assert!(value.get("delivery_attempt").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn error_of_malformed_payload() {
+        let e = FlockError::Payload(uuid(), PayloadError::Sealed("bad key".to_string()));
+        let response = Response::error("q3-01-00", &e);
+        assert_eq!(response.uuid, Some(uuid()));
+        let error = response.error.clone().unwrap();
+        assert_eq!(error.kind, "Payload.Sealed");
+        assert!(!error.retryable);
+        assert!(response.into_result().is_err());
+    }
+
+    #[test]
+    fn keep_legacy_keys() -> Result<()> {
+        // The data sink response of the former release.
+        let sink = json!({ "name": "q3-01", "truncated": false, "function": "next" });
+        let value = Response::ok("q3-01-00", sink.clone()).to_value();
+        assert_eq!(value["name"], json!("q3-01"));
+        assert_eq!(value["truncated"], json!(false));
+        // The keys of the envelope win over the keys of the data.
+        assert_eq!(value["function"], json!("q3-01-00"));
+
+        let response = Response::from_value(value.clone())?;
+        assert_eq!(response.data, Some(sink.clone()));
+        assert_eq!(response_data(value), sink);
+
+        // The responses of the former release are the data of an ok response.
+        let response = Response::from_value(sink.clone())?;
+        assert!(response.is_ok());
+        assert_eq!(response.into_data(), sink);
+        assert_eq!(Response::from_slice(b"")?.into_data(), Value::Null);
+        Ok(())
+    }
+
+    #[test]
+    fn unwrap_nested_responses() -> Result<()> {
+        // The source invokes the worker synchronously, which returns the output
+        // of the data sink.
+        let sink = json!({ "sink_type": "Response", "payload": {} });
+        let worker = Response::ok("q3-00", sink.clone()).to_value();
+        let source = Response::ok("flock_datasource", worker).to_value();
+        assert_eq!(source["sink_type"], json!("Response"));
+        assert_eq!(Response::from_value(source)?.into_data(), sink);
+        Ok(())
+    }
+
+    #[test]
+    fn read_lambda_runtime_error() -> Result<()> {
+        let value = json!({ "errorType": "&str", "errorMessage": "Timeout error" });
+        let response = Response::from_value(value)?;
+        assert_eq!(response.status, Status::Error);
+        assert!(response.error.unwrap().retryable);
+        Ok(())
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The result cache of the last stage of a query.
+//!
+//! When iterating on the data sinks or the validation of the results, the same
+//! seeded benchmark is run over and over, and every window is executed again
+//! with the same input. If the result cache is enabled (see
+//! [`DeployOptions::with_result_cache`](crate::api::DeployOptions::with_result_cache)),
+//! the last stage looks up the output of the window by its [`cache_key`]
+//! before executing the plan, and stores the output on a miss.
+//!
+//! The cache key is the digest of the query code, the window id and the
+//! checksums of the input batches. The cached output also records the
+//! [`plan_hash`] of the stage that computed it, and the output of another plan
+//! is never reused, e.g. after the query is changed under the same query code.
+//!
+//! The cached outputs are kept as checkpoints in the state backend under
+//! `<query code>/result-cache/`, which `flock-cli s3 gc --result-cache` purges.
+
+use crate::datasink::results::{decode_window, encode_window};
+use crate::error::{FlockError, Result};
+use crate::runtime::arena::WindowId;
+use crate::runtime::function_name::query_key;
+use crate::state::StateBackend;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::physical_plan::{displayable, ExecutionPlan};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+/// The key prefix of the cached outputs under the prefix of the query.
+pub const RESULT_CACHE_KEY_PREFIX: &str = "result-cache/";
+
+/// The output of a window kept in the state backend.
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedOutput {
+    /// The hash of the plan that computed the output.
+    plan_hash: String,
+    /// The output of each plan of the stage as an Arrow IPC file, or empty if
+    /// the plan has no output.
+    output:    Vec<ByteBuf>,
+}
+
+/// Returns the key prefix of the cached outputs of the query.
+pub fn result_cache_prefix(query_code: &str) -> String {
+    query_key(query_code, RESULT_CACHE_KEY_PREFIX)
+}
+
+/// Returns true if the S3 key is a cached output of any query.
+pub fn is_result_cache_key(key: &str) -> bool {
+    matches!(key.split_once('/'), Some((_, name)) if name.starts_with(RESULT_CACHE_KEY_PREFIX))
+}
+
+/// Returns the hash of the plans of a stage.
+pub fn plan_hash(plans: &[Arc<dyn ExecutionPlan>]) -> String {
+    let mut hasher = DefaultHasher::new();
+    for plan in plans {
+        format!("{}", displayable(plan.as_ref()).indent()).hash(&mut hasher);
+    }
+    format!("{:016x}", hasher.finish())
+}
+
+/// Returns the key of the cached output of the window, i.e.
+/// `<query code>/result-cache/<digest>`.
+///
+/// The payloads of a window arrive in any order, so the checksums of the input
+/// batches are sorted within each relation before they're hashed.
+pub fn cache_key(
+    query_code: &str,
+    window_id: &WindowId,
+    input: &[Vec<Vec<RecordBatch>>],
+) -> Result<String> {
+    let mut hasher = DefaultHasher::new();
+    query_code.hash(&mut hasher);
+    window_id.hash(&mut hasher);
+    for relation in input {
+        let mut checksums = relation
+            .iter()
+            .flatten()
+            .map(checksum)
+            .collect::<Result<Vec<_>>>()?;
+        checksums.sort_unstable();
+        checksums.hash(&mut hasher);
+    }
+    Ok(format!(
+        "{}{:016x}",
+        result_cache_prefix(query_code),
+        hasher.finish()
+    ))
+}
+
+/// Returns the checksum of the record batch.
+fn checksum(batch: &RecordBatch) -> Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    encode_window(&[batch.clone()])?.hash(&mut hasher);
+    Ok(hasher.finish())
+}
+
+/// Returns the cached output of the window, or `None` if the window isn't
+/// cached or its output was computed by another plan.
+pub async fn lookup(
+    state_backend: &dyn StateBackend,
+    bucket: &str,
+    key: &str,
+    plan_hash: &str,
+) -> Result<Option<Vec<Vec<RecordBatch>>>> {
+    let checkpoint = match state_backend
+        .read_checkpoint(bucket.to_string(), key.to_string())
+        .await?
+    {
+        Some(checkpoint) => checkpoint,
+        None => return Ok(None),
+    };
+    let cached: CachedOutput = rmp_serde::from_slice(&checkpoint.bytes)
+        .map_err(|e| FlockError::Internal(format!("Failed to decode the cached output: {}", e)))?;
+    if cached.plan_hash != plan_hash {
+        warn!(
+            "The cached output {} was computed by another plan ({} != {}).",
+            key, cached.plan_hash, plan_hash
+        );
+        return Ok(None);
+    }
+    cached
+        .output
+        .into_iter()
+        .map(|bytes| {
+            if bytes.is_empty() {
+                Ok(vec![])
+            } else {
+                decode_window(bytes.into_vec())
+            }
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
+}
+
+/// Stores the output of the window, replacing the output of another plan.
+pub async fn store(
+    state_backend: &dyn StateBackend,
+    bucket: &str,
+    key: &str,
+    plan_hash: &str,
+    output: &[Vec<RecordBatch>],
+) -> Result<()> {
+    let cached = CachedOutput {
+        plan_hash: plan_hash.to_string(),
+        output:    output
+            .iter()
+            .map(|batches| {
+                if batches.is_empty() {
+                    Ok(ByteBuf::new())
+                } else {
+                    encode_window(batches).map(ByteBuf::from)
+                }
+            })
+            .collect::<Result<Vec<_>>>()?,
+    };
+    let bytes = rmp_serde::to_vec_named(&cached)
+        .map_err(|e| FlockError::Internal(format!("Failed to encode the cached output: {}", e)))?;
+    let version = state_backend
+        .read_checkpoint(bucket.to_string(), key.to_string())
+        .await?
+        .map(|c| c.version);
+    // Another invocation of the same window may have cached its output in
+    // between, which is the same output.
+    if !state_backend
+        .write_checkpoint(bucket.to_string(), key.to_string(), bytes, version)
+        .await?
+    {
+        warn!("The cached output {} was written concurrently.", key);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::HashMapStateBackend;
+    use crate::tests::int64_batch;
+
+    #[test]
+    fn result_cache_keys() -> Result<()> {
+        let window_id = ("q1-0001".to_string(), 0);
+        let input = vec![vec![
+            vec![int64_batch(vec![1, 2])],
+            vec![int64_batch(vec![3])],
+        ]];
+        let key = cache_key("q1", &window_id, &input)?;
+        assert!(key.starts_with("q1/result-cache/"));
+        assert!(is_result_cache_key(&key));
+        assert!(!is_result_cache_key("q1/results/q1-0001-00.arrow"));
+
+        // The order of the input batches doesn't matter.
+        let reordered = vec![vec![
+            vec![int64_batch(vec![3])],
+            vec![int64_batch(vec![1, 2])],
+        ]];
+        assert_eq!(cache_key("q1", &window_id, &reordered)?, key);
+
+        // The query, the window and the input do.
+        assert_ne!(cache_key("q2", &window_id, &input)?, key);
+        let other_window = ("q1-0002".to_string(), 0);
+        assert_ne!(cache_key("q1", &other_window, &input)?, key);
+        let other_input = vec![vec![
+            vec![int64_batch(vec![1, 2])],
+            vec![int64_batch(vec![4])],
+        ]];
+        assert_ne!(cache_key("q1", &window_id, &other_input)?, key);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lookup_cached_output() -> Result<()> {
+        let state_backend = HashMapStateBackend::default();
+        let key = "result_cache_test/result-cache/0000000000000001";
+        assert!(lookup(&state_backend, "bucket", key, "p1").await?.is_none());
+
+        let output = vec![vec![int64_batch(vec![1, 2, 3])], vec![]];
+        store(&state_backend, "bucket", key, "p1", &output).await?;
+        let cached = lookup(&state_backend, "bucket", key, "p1").await?.unwrap();
+        assert_eq!(cached.len(), 2);
+        assert_eq!(cached[0][0].num_rows(), 3);
+        assert!(cached[1].is_empty());
+
+        // The output of another plan isn't reused, and it's replaced.
+        assert!(lookup(&state_backend, "bucket", key, "p2").await?.is_none());
+        store(&state_backend, "bucket", key, "p2", &output[..1]).await?;
+        assert_eq!(
+            lookup(&state_backend, "bucket", key, "p2")
+                .await?
+                .unwrap()
+                .len(),
+            1
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,833 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The running aggregates accumulated over the whole run of a query.
+//!
+//! Every window of a query is computed independently, so a query can't
+//! express the aggregates of all windows so far, e.g. the total number of bids
+//! processed. A query with a [`RunningAggregate`] keeps an [`Accumulator`] in
+//! the state backend: the last stage merges the output of each window into the
+//! accumulator, and emits the cumulative values along with the output of the
+//! window, as the columns `cumulative_<name>` joined on the group-by columns.
+//!
+//! The accumulator is updated by read-modify-write with the optimistic
+//! concurrency of the checkpoints (see [`StateBackend::write_checkpoint`]): if
+//! another window was merged in between, the accumulator is read again and the
+//! window is merged once more. The accumulator records the windows merged, so
+//! the window of a retried invocation isn't counted twice. Nothing is cached in
+//! the function instance, so a function that starts cold, e.g. after a crash
+//! or a redeployment, continues from the accumulator in the state backend.
+//!
+//! The running aggregates are written as `CUMULATIVE(<aggregate>)` in the
+//! select list of the query (see [`rewrite_cumulative`]), e.g.
+//!
+//! ```sql
+//! SELECT category, CUMULATIVE(COUNT(*)) AS bids FROM bid GROUP BY category
+//! ```
+//!
+//! emits the number of bids of each category in the window as `bids`, and in
+//! all windows so far as `cumulative_bids`.
+
+use crate::error::{FlockError, Result};
+use crate::runtime::function_name::query_key;
+use crate::state::StateBackend;
+use datafusion::arrow::array::{Array, ArrayRef, Float64Array, Int64Array};
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::{DataType, Field, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::arrow::util::display::array_value_to_string;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sqlparser::parser::ParserError;
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// The S3 key of the accumulator under the query code.
+pub const RUNNING_AGGREGATE_KEY: &str = "running-aggregate";
+
+/// The prefix of the names of the cumulative columns.
+pub const CUMULATIVE_PREFIX: &str = "cumulative_";
+
+/// The maximum number of attempts to merge a window into an accumulator that
+/// is updated concurrently.
+const MAX_MERGE_ATTEMPTS: usize = 16;
+
+/// Returns the key of the accumulator of the query, i.e.
+/// `<query code>/running-aggregate`.
+pub fn running_aggregate_key(query_code: &str) -> String {
+    query_key(query_code, RUNNING_AGGREGATE_KEY)
+}
+
+/// The aggregate function of a running aggregate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RunningFunction {
+    /// The number of rows of all windows.
+    Count,
+    /// The sum of all windows.
+    Sum,
+    /// The average of all windows, i.e. their sum over their number of rows.
+    Avg,
+    /// The minimum of all windows.
+    Min,
+    /// The maximum of all windows.
+    Max,
+}
+
+impl FromStr for RunningFunction {
+    type Err = FlockError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "count" => Ok(RunningFunction::Count),
+            "sum" => Ok(RunningFunction::Sum),
+            "avg" => Ok(RunningFunction::Avg),
+            "min" => Ok(RunningFunction::Min),
+            "max" => Ok(RunningFunction::Max),
+            _ => Err(FlockError::Plan(format!(
+                "Unsupported running aggregate: {}. Expected COUNT, SUM, AVG, MIN or MAX",
+                s
+            ))),
+        }
+    }
+}
+
+/// A cumulative column emitted with the output of the windows.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunningColumn {
+    /// The name of the cumulative column.
+    pub name:     String,
+    /// The aggregate function.
+    pub function: RunningFunction,
+    /// The column of the window's output merged into the accumulator: the
+    /// number of rows of `Count`, the sum of `Sum` and `Avg`, and the minimum
+    /// or the maximum of `Min` and `Max`.
+    pub input:    String,
+    /// The column of the window's output with the number of rows summed by
+    /// `Avg`.
+    pub count:    Option<String>,
+}
+
+/// The running aggregates of a query, which the last stage accumulates over
+/// the whole run.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunningAggregate {
+    /// The group-by columns of the window's output. The aggregates are
+    /// accumulated over all rows if it's empty.
+    pub group_by: Vec<String>,
+    /// The cumulative columns.
+    pub columns:  Vec<RunningColumn>,
+    /// The columns of the window's output that are only merged into the
+    /// accumulator, e.g. the sums and the counts of the averages, which are
+    /// dropped from the output.
+    pub hidden:   Vec<String>,
+}
+
+/// The partial aggregates of a group over the windows merged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Partial {
+    /// The sum of the input.
+    pub sum:   f64,
+    /// The number of rows of `Avg`.
+    pub count: f64,
+    /// The minimum of the input.
+    pub min:   Option<f64>,
+    /// The maximum of the input.
+    pub max:   Option<f64>,
+}
+
+impl Partial {
+    /// Returns the value of the running aggregate, or `None` if no window has
+    /// a value of the group yet.
+    fn value(&self, function: RunningFunction) -> Option<f64> {
+        match function {
+            RunningFunction::Count | RunningFunction::Sum => Some(self.sum),
+            RunningFunction::Avg => (self.count > 0.0).then(|| self.sum / self.count),
+            RunningFunction::Min => self.min,
+            RunningFunction::Max => self.max,
+        }
+    }
+}
+
+/// The accumulator of the running aggregates of a query, which is kept in the
+/// state backend.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Accumulator {
+    /// The ids of the windows merged.
+    pub windows: BTreeSet<String>,
+    /// The partial aggregates of each cumulative column by group.
+    pub groups:  BTreeMap<String, Vec<Partial>>,
+}
+
+/// Returns the indices of the columns in the batch.
+fn column_indices(batch: &RecordBatch, names: &[String]) -> Result<Vec<usize>> {
+    names
+        .iter()
+        .map(|name| {
+            batch.schema().index_of(name).map_err(|_| {
+                FlockError::Execution(format!(
+                    "The output of the window has no column {} of the running aggregate",
+                    name
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Returns the column of the batch as `Float64`.
+fn float_column(batch: &RecordBatch, name: &str) -> Result<Float64Array> {
+    let index = column_indices(batch, &[name.to_string()])?[0];
+    let array = cast(batch.column(index), &DataType::Float64)?;
+    Ok(array
+        .as_any()
+        .downcast_ref::<Float64Array>()
+        .unwrap()
+        .clone())
+}
+
+/// Returns the group of the row, i.e. its values of the group-by columns.
+fn group_key(batch: &RecordBatch, indices: &[usize], row: usize) -> Result<String> {
+    Ok(indices
+        .iter()
+        .map(|i| array_value_to_string(batch.column(*i), row))
+        .collect::<std::result::Result<Vec<_>, _>>()?
+        .join("\u{1f}"))
+}
+
+/// Returns the value of the row, or `None` if it's null.
+fn value(array: &Float64Array, row: usize) -> Option<f64> {
+    (!array.is_null(row)).then(|| array.value(row))
+}
+
+impl Accumulator {
+    /// Reads the accumulator of the query from the state backend, with the
+    /// version to write it back. A missing accumulator is empty.
+    pub async fn load(
+        state_backend: &dyn StateBackend,
+        bucket: &str,
+        query_code: &str,
+    ) -> Result<(Option<String>, Accumulator)> {
+        match state_backend
+            .read_checkpoint(bucket.to_string(), running_aggregate_key(query_code))
+            .await?
+        {
+            Some(checkpoint) => Ok((
+                Some(checkpoint.version),
+                serde_json::from_slice(&checkpoint.bytes)?,
+            )),
+            None => Ok((None, Accumulator::default())),
+        }
+    }
+
+    /// Writes the accumulator of the query if it's still at the version read.
+    /// Returns false if another window was merged in between.
+    pub async fn store(
+        &self,
+        state_backend: &dyn StateBackend,
+        bucket: &str,
+        query_code: &str,
+        version: Option<String>,
+    ) -> Result<bool> {
+        state_backend
+            .write_checkpoint(
+                bucket.to_string(),
+                running_aggregate_key(query_code),
+                serde_json::to_vec(self)?,
+                version,
+            )
+            .await
+    }
+
+    /// Merges the output of the window. Returns false if the window is
+    /// already merged.
+    pub fn merge(
+        &mut self,
+        running: &RunningAggregate,
+        window_id: &str,
+        batches: &[RecordBatch],
+    ) -> Result<bool> {
+        if self.windows.contains(window_id) {
+            return Ok(false);
+        }
+        for batch in batches {
+            let keys = column_indices(batch, &running.group_by)?;
+            let inputs = running
+                .columns
+                .iter()
+                .map(|c| {
+                    Ok((
+                        float_column(batch, &c.input)?,
+                        c.count
+                            .as_ref()
+                            .map(|count| float_column(batch, count))
+                            .transpose()?,
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for row in 0..batch.num_rows() {
+                let partials = self
+                    .groups
+                    .entry(group_key(batch, &keys, row)?)
+                    .or_insert_with(|| vec![Partial::default(); running.columns.len()]);
+                for (partial, (input, count)) in partials.iter_mut().zip(&inputs) {
+                    if let Some(v) = value(input, row) {
+                        partial.sum += v;
+                        partial.min = Some(partial.min.map_or(v, |m| m.min(v)));
+                        partial.max = Some(partial.max.map_or(v, |m| m.max(v)));
+                    }
+                    if let Some(c) = count.as_ref().and_then(|count| value(count, row)) {
+                        partial.count += c;
+                    }
+                }
+            }
+        }
+        self.windows.insert(window_id.to_string());
+        Ok(true)
+    }
+
+    /// Returns the output of the window with the cumulative columns of its
+    /// groups, without the hidden columns.
+    pub fn cumulative(
+        &self,
+        running: &RunningAggregate,
+        batches: &[RecordBatch],
+    ) -> Result<Vec<RecordBatch>> {
+        batches
+            .iter()
+            .map(|batch| {
+                let keys = column_indices(batch, &running.group_by)?;
+                let groups = (0..batch.num_rows())
+                    .map(|row| group_key(batch, &keys, row))
+                    .collect::<Result<Vec<_>>>()?;
+
+                let schema = batch.schema();
+                let mut fields = vec![];
+                let mut columns = vec![];
+                for (field, column) in schema.fields().iter().zip(batch.columns()) {
+                    if !running.hidden.contains(field.name()) {
+                        fields.push(field.clone());
+                        columns.push(column.clone());
+                    }
+                }
+                for (i, c) in running.columns.iter().enumerate() {
+                    let values = groups
+                        .iter()
+                        .map(|g| self.groups.get(g).and_then(|p| p[i].value(c.function)));
+                    let (data_type, array): (DataType, ArrayRef) = match c.function {
+                        RunningFunction::Count => (
+                            DataType::Int64,
+                            Arc::new(values.map(|v| v.map(|v| v as i64)).collect::<Int64Array>()),
+                        ),
+                        _ => (
+                            DataType::Float64,
+                            Arc::new(values.collect::<Float64Array>()),
+                        ),
+                    };
+                    fields.push(Field::new(&c.name, data_type, true));
+                    columns.push(array);
+                }
+                Ok(RecordBatch::try_new(
+                    Arc::new(Schema::new(fields)),
+                    columns,
+                )?)
+            })
+            .collect()
+    }
+}
+
+/// Merges the output of the window into the accumulator of the query in the
+/// state backend, and returns the output with the cumulative columns. The
+/// window is merged again if the accumulator is updated concurrently, and it's
+/// merged only once if the invocation is retried.
+///
+/// # Arguments
+/// * `state_backend` - The state backend of the accumulator.
+/// * `bucket` - The bucket of the accumulator.
+/// * `query_code` - The query code of the last stage.
+/// * `window_id` - The id of the window (see
+///   [`crate::datasink::results::window_id`]).
+/// * `running` - The running aggregates of the query.
+/// * `batches` - The output of the window.
+pub async fn accumulate(
+    state_backend: &dyn StateBackend,
+    bucket: &str,
+    query_code: &str,
+    window_id: &str,
+    running: &RunningAggregate,
+    batches: &[RecordBatch],
+) -> Result<Vec<RecordBatch>> {
+    for attempt in 1..=MAX_MERGE_ATTEMPTS {
+        let (version, mut accumulator) =
+            Accumulator::load(state_backend, bucket, query_code).await?;
+        if !accumulator.merge(running, window_id, batches)?
+            || accumulator
+                .store(state_backend, bucket, query_code, version)
+                .await?
+        {
+            return accumulator.cumulative(running, batches);
+        }
+        warn!(
+            "The running aggregate of {} was updated concurrently (attempt {}).",
+            query_code, attempt
+        );
+    }
+    Err(FlockError::Execution(format!(
+        "Failed to merge the window {} into the running aggregate of {} after {} attempts",
+        window_id, query_code, MAX_MERGE_ATTEMPTS
+    )))
+}
+
+/// Returns the depth of the parentheses at each byte of the SQL, or `None`
+/// inside the string literals and the quoted identifiers.
+fn depths(sql: &str) -> Vec<Option<usize>> {
+    let mut depth = 0;
+    let mut quote = None;
+    sql.bytes()
+        .map(|b| match quote {
+            Some(q) => {
+                if b == q {
+                    quote = None;
+                }
+                None
+            }
+            None => match b {
+                b'\'' | b'"' => {
+                    quote = Some(b);
+                    None
+                }
+                b'(' => {
+                    depth += 1;
+                    Some(depth - 1)
+                }
+                b')' => {
+                    depth = depth.saturating_sub(1);
+                    Some(depth)
+                }
+                _ => Some(depth),
+            },
+        })
+        .collect()
+}
+
+/// Returns true if the byte is part of an identifier.
+fn is_word(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+/// Returns true if the keyword starts at the position as a whole word.
+fn keyword_at(sql: &str, at: usize, keyword: &str) -> bool {
+    let bytes = sql.as_bytes();
+    sql.get(at..at + keyword.len())
+        .map_or(false, |s| s.eq_ignore_ascii_case(keyword))
+        && (at == 0 || !is_word(bytes[at - 1]))
+        && bytes.get(at + keyword.len()).map_or(true, |b| !is_word(*b))
+}
+
+/// Returns the position of the next keyword from the position, outside the
+/// quotes and at the depth if any.
+fn find_keyword(
+    sql: &str,
+    depths: &[Option<usize>],
+    keyword: &str,
+    from: usize,
+    depth: Option<usize>,
+) -> Option<usize> {
+    (from..sql.len()).find(|i| {
+        depths[*i].is_some()
+            && (depth.is_none() || depths[*i] == depth)
+            && keyword_at(sql, *i, keyword)
+    })
+}
+
+/// Returns the position of the first byte after the whitespaces.
+fn skip_whitespace(sql: &str, from: usize) -> usize {
+    from + sql[from..].len() - sql[from..].trim_start().len()
+}
+
+/// Returns the position of the parenthesis that closes the one at `open`.
+fn closing_paren(sql: &str, depths: &[Option<usize>], open: usize) -> Result<usize> {
+    (open + 1..sql.len())
+        .find(|i| sql.as_bytes()[*i] == b')' && depths[*i] == depths[open])
+        .ok_or_else(|| FlockError::SQL(ParserError::ParserError("Unbalanced parentheses".into())))
+}
+
+/// Returns the group-by columns of the outermost query, without their
+/// qualifiers.
+fn group_by_columns(sql: &str, depths: &[Option<usize>]) -> Vec<String> {
+    let mut from = 0;
+    let mut start = None;
+    while let Some(i) = find_keyword(sql, depths, "GROUP", from, Some(0)) {
+        let by = skip_whitespace(sql, i + "GROUP".len());
+        if keyword_at(sql, by, "BY") {
+            start = Some(by + "BY".len());
+        }
+        from = i + "GROUP".len();
+    }
+    let start = match start {
+        Some(start) => start,
+        None => return vec![],
+    };
+    let end = ["HAVING", "ORDER", "LIMIT", "WINDOW"]
+        .iter()
+        .filter_map(|keyword| find_keyword(sql, depths, keyword, start, Some(0)))
+        .chain((start..sql.len()).find(|i| sql.as_bytes()[*i] == b';' && depths[*i].is_some()))
+        .min()
+        .unwrap_or(sql.len());
+
+    let mut columns = vec![];
+    let mut column = start;
+    for i in start..=end {
+        if i == end || (sql.as_bytes()[i] == b',' && depths[i] == Some(0)) {
+            let name = sql[column..i].trim();
+            let name = name.rsplit('.').next().unwrap_or(name).trim_matches('"');
+            if !name.is_empty() {
+                columns.push(name.to_string());
+            }
+            column = i + 1;
+        }
+    }
+    columns
+}
+
+/// Rewrites the running aggregates `CUMULATIVE(<function>(<args>)) [AS
+/// <alias>]` in the select list of the query to the aggregates of the window,
+/// and returns the [`RunningAggregate`] of the query, or `None` if it has no
+/// running aggregates. The name of a running aggregate defaults to its
+/// function, and the cumulative column is named `cumulative_<name>`.
+///
+/// The running aggregates are grouped by the group-by columns of the outermost
+/// query, which must be in its select list under the same names.
+pub fn rewrite_cumulative(sql: &str) -> Result<(String, Option<RunningAggregate>)> {
+    let depths = depths(sql);
+    let mut running = RunningAggregate::default();
+    let mut rewritten = String::new();
+    let mut copied = 0;
+    let mut from = 0;
+    while let Some(start) = find_keyword(sql, &depths, "CUMULATIVE", from, None) {
+        from = start + "CUMULATIVE".len();
+        let open = skip_whitespace(sql, from);
+        if sql.as_bytes().get(open) != Some(&b'(') {
+            continue;
+        }
+        let close = closing_paren(sql, &depths, open)?;
+        let inner = sql[open + 1..close].trim();
+        let (function, args) = match inner.find('(') {
+            Some(paren) if inner.ends_with(')') => {
+                (inner[..paren].trim(), &inner[paren + 1..inner.len() - 1])
+            }
+            _ => {
+                return Err(FlockError::Plan(format!(
+                    "Expected an aggregate in CUMULATIVE({})",
+                    inner
+                )))
+            }
+        };
+
+        let mut end = close + 1;
+        let after = skip_whitespace(sql, end);
+        let alias = if keyword_at(sql, after, "AS") {
+            let alias = skip_whitespace(sql, after + "AS".len());
+            end = match sql.as_bytes().get(alias) {
+                Some(b'"') => sql[alias + 1..]
+                    .find('"')
+                    .map_or(sql.len(), |i| alias + i + 2),
+                _ => (alias..sql.len())
+                    .find(|i| !is_word(sql.as_bytes()[*i]))
+                    .unwrap_or(sql.len()),
+            };
+            sql[alias..end].trim_matches('"').to_string()
+        } else {
+            function.to_lowercase()
+        };
+        let name = format!("{}{}", CUMULATIVE_PREFIX, alias);
+        if running.columns.iter().any(|c| c.name == name) {
+            return Err(FlockError::Plan(format!(
+                "Duplicate running aggregate {}. Use AS to name them apart",
+                alias
+            )));
+        }
+
+        let function_type = RunningFunction::from_str(function)?;
+        rewritten.push_str(&sql[copied..start]);
+        if function_type == RunningFunction::Avg {
+            let (sum, count) = (format!("__{}_sum", alias), format!("__{}_count", alias));
+            rewritten.push_str(&format!(
+                "{}({}) AS {}, SUM({}) AS {}, COUNT({}) AS {}",
+                function, args, alias, args, sum, args, count
+            ));
+            running.columns.push(RunningColumn {
+                name,
+                function: function_type,
+                input: sum.clone(),
+                count: Some(count.clone()),
+            });
+            running.hidden.extend([sum, count]);
+        } else {
+            rewritten.push_str(&format!("{}({}) AS {}", function, args, alias));
+            running.columns.push(RunningColumn {
+                name,
+                function: function_type,
+                input: alias,
+                count: None,
+            });
+        }
+        copied = end;
+        from = end;
+    }
+
+    if running.columns.is_empty() {
+        return Ok((sql.to_string(), None));
+    }
+    rewritten.push_str(&sql[copied..]);
+    running.group_by = group_by_columns(sql, &depths);
+    Ok((rewritten, Some(running)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::HashMapStateBackend;
+    use datafusion::arrow::array::StringArray;
+
+    fn window(categories: &[&str], counts: &[i64], sums: &[f64]) -> Vec<RecordBatch> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("category", DataType::Utf8, false),
+            Field::new("num", DataType::Int64, false),
+            Field::new("avg_final", DataType::Float64, false),
+            Field::new("__avg_final_sum", DataType::Float64, false),
+            Field::new("__avg_final_count", DataType::Int64, false),
+        ]));
+        let avgs = sums
+            .iter()
+            .zip(counts)
+            .map(|(s, c)| s / *c as f64)
+            .collect::<Vec<_>>();
+        vec![RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(StringArray::from(categories.to_vec())),
+                Arc::new(Int64Array::from(counts.to_vec())),
+                Arc::new(Float64Array::from(avgs)),
+                Arc::new(Float64Array::from(sums.to_vec())),
+                Arc::new(Int64Array::from(counts.to_vec())),
+            ],
+        )
+        .unwrap()]
+    }
+
+    fn running() -> RunningAggregate {
+        rewrite_cumulative(
+            "SELECT category, CUMULATIVE(COUNT(final)) AS num, CUMULATIVE(AVG(final)) AS \
+             avg_final FROM q GROUP BY category",
+        )
+        .unwrap()
+        .1
+        .unwrap()
+    }
+
+    fn cumulative_count(batches: &[RecordBatch], category: &str) -> Option<i64> {
+        batches.iter().find_map(|batch| {
+            let categories = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap();
+            let counts = batch
+                .column(batch.schema().index_of("cumulative_num").unwrap())
+                .as_any()
+                .downcast_ref::<Int64Array>()
+                .unwrap();
+            (0..batch.num_rows())
+                .find(|i| categories.value(*i) == category)
+                .map(|i| counts.value(i))
+        })
+    }
+
+    #[test]
+    fn rewrite_cumulative_aggregates() -> Result<()> {
+        let sql = "SELECT B.category AS category, CUMULATIVE(count(*)) AS bids, \
+                   CUMULATIVE(AVG(price)), CUMULATIVE(MAX(price)) AS \"top\" \
+                   FROM (SELECT * FROM bid GROUP BY auction) AS B \
+                   GROUP BY B.category ORDER BY category";
+        let (rewritten, running) = rewrite_cumulative(sql)?;
+        assert_eq!(
+            rewritten,
+            "SELECT B.category AS category, count(*) AS bids, AVG(price) AS avg, SUM(price) AS \
+             __avg_sum, COUNT(price) AS __avg_count, MAX(price) AS top FROM (SELECT * FROM bid \
+             GROUP BY auction) AS B GROUP BY B.category ORDER BY category"
+        );
+        let running = running.unwrap();
+        assert_eq!(running.group_by, vec!["category"]);
+        assert_eq!(running.hidden, vec!["__avg_sum", "__avg_count"]);
+        assert_eq!(
+            running
+                .columns
+                .iter()
+                .map(|c| (c.name.as_str(), c.function, c.input.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("cumulative_bids", RunningFunction::Count, "bids"),
+                ("cumulative_avg", RunningFunction::Avg, "__avg_sum"),
+                ("cumulative_top", RunningFunction::Max, "top"),
+            ]
+        );
+
+        let (rewritten, running) = rewrite_cumulative("SELECT 'CUMULATIVE(COUNT(*))' FROM t")?;
+        assert_eq!(rewritten, "SELECT 'CUMULATIVE(COUNT(*))' FROM t");
+        assert!(running.is_none());
+
+        assert!(rewrite_cumulative("SELECT CUMULATIVE(MEDIAN(x)) FROM t").is_err());
+        assert!(
+            rewrite_cumulative("SELECT CUMULATIVE(SUM(x)), CUMULATIVE(SUM(y)) FROM t").is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn merge_windows() -> Result<()> {
+        let running = running();
+        let mut accumulator = Accumulator::default();
+        assert!(accumulator.merge(&running, "w0", &window(&["a", "b"], &[2, 1], &[10.0, 3.0]))?);
+        assert!(accumulator.merge(&running, "w1", &window(&["a"], &[3], &[20.0]))?);
+        assert!(!accumulator.merge(&running, "w1", &window(&["a"], &[3], &[20.0]))?);
+
+        let output = accumulator.cumulative(&running, &window(&["a"], &[3], &[20.0]))?;
+        let schema = output[0].schema();
+        let names = schema
+            .fields()
+            .iter()
+            .map(|f| f.name().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "category",
+                "num",
+                "avg_final",
+                "cumulative_num",
+                "cumulative_avg_final"
+            ]
+        );
+        assert_eq!(cumulative_count(&output, "a"), Some(5));
+        let avg = output[0]
+            .column(4)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap()
+            .value(0);
+        assert!((avg - 6.0).abs() < f64::EPSILON);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn merge_concurrent_updates() -> Result<()> {
+        let state_backend = HashMapStateBackend::new();
+        let running = running();
+        let qc = "running-race";
+        accumulate(
+            &state_backend,
+            "flock",
+            qc,
+            "w0",
+            &running,
+            &window(&["a"], &[1], &[1.0]),
+        )
+        .await?;
+
+        // A stale read loses to the window merged after it, and is retried.
+        let (stale, mut accumulator) = Accumulator::load(&state_backend, "flock", qc).await?;
+        accumulate(
+            &state_backend,
+            "flock",
+            qc,
+            "w1",
+            &running,
+            &window(&["a"], &[2], &[2.0]),
+        )
+        .await?;
+        accumulator.merge(&running, "w2", &window(&["a"], &[4], &[4.0]))?;
+        assert!(
+            !accumulator
+                .store(&state_backend, "flock", qc, stale)
+                .await?
+        );
+
+        let output = accumulate(
+            &state_backend,
+            "flock",
+            qc,
+            "w2",
+            &running,
+            &window(&["a"], &[4], &[4.0]),
+        )
+        .await?;
+        assert_eq!(cumulative_count(&output, "a"), Some(7));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn merge_windows_in_parallel() -> Result<()> {
+        let state_backend = Arc::new(HashMapStateBackend::new());
+        let running = Arc::new(running());
+        let tasks = (0..MAX_MERGE_ATTEMPTS)
+            .map(|i| {
+                let (state_backend, running) = (state_backend.clone(), running.clone());
+                tokio::spawn(async move {
+                    accumulate(
+                        state_backend.as_ref(),
+                        "flock",
+                        "running-parallel",
+                        &format!("w{}", i),
+                        &running,
+                        &window(&["a"], &[1], &[1.0]),
+                    )
+                    .await
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap()?;
+        }
+
+        let (_, accumulator) =
+            Accumulator::load(state_backend.as_ref(), "flock", "running-parallel").await?;
+        assert_eq!(accumulator.windows.len(), MAX_MERGE_ATTEMPTS);
+        assert_eq!(accumulator.groups["a"][0].sum, MAX_MERGE_ATTEMPTS as f64);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recover_after_restart() -> Result<()> {
+        let running = running();
+        let qc = "running-restart";
+        let batches = window(&["a", "b"], &[1, 2], &[1.0, 2.0]);
+        accumulate(
+            &HashMapStateBackend::new(),
+            "flock",
+            qc,
+            "w0",
+            &running,
+            &batches,
+        )
+        .await?;
+
+        // A cold function continues from the accumulator in the state backend,
+        // and the retried window isn't counted twice.
+        let state_backend = HashMapStateBackend::new();
+        accumulate(&state_backend, "flock", qc, "w1", &running, &batches).await?;
+        let output = accumulate(&state_backend, "flock", qc, "w1", &running, &batches).await?;
+        assert_eq!(cumulative_count(&output, "a"), Some(2));
+        assert_eq!(cumulative_count(&output, "b"), Some(4));
+        Ok(())
+    }
+}
@@ -0,0 +1,427 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The adaptive size of the function groups.
+//!
+//! The driver deploys each function group with an initial size. At runtime,
+//! the function feeding a group observes the volume of each window it sends to
+//! the group with a [`ScalingMonitor`]. After a number of windows, the
+//! [`ScalingPolicy`] decides whether the group has too few or too many
+//! members for the observed volume, and the monitor writes a [`ScalingHint`]
+//! to the S3 object `<query code>/scaling/<stage>.json`.
+//!
+//! The hint is applied by [`crate::api::resize_group`], i.e. `flock-cli lambda
+//! --scale`. It deploys the additional group members, and publishes the route
+//! of the query with the resized hash ring (see [`Route::resize`]). The
+//! generation of the route is the version of the ring: the data source hands
+//! itself over to the new ring at the next window boundary, so the new windows
+//! are hashed to the resized group while the windows in flight complete on the
+//! old ring.
+
+use crate::aws::client::{AwsCloudClient, CloudClient};
+use crate::configs::*;
+use crate::error::{FlockError, Result};
+use crate::runtime::context::CloudFunction;
+use crate::runtime::function_name::{query_key, FunctionName};
+use crate::runtime::switchover::Route;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// The S3 key prefix of the scaling hints under the prefix of the query.
+const SCALING_KEY_PREFIX: &str = "scaling/";
+
+/// The minimum size of a function group. The hash ring of a single function
+/// routes to the group name instead of its member `<group>-00`.
+pub const MIN_GROUP_SIZE: usize = 2;
+
+/// The maximum size of a function group, since the group index has 2 digits.
+pub const MAX_GROUP_SIZE: usize = 100;
+
+/// The requested size of a function group.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScalingHint {
+    /// The query code of the function group.
+    pub query_code:   String,
+    /// The plan index of the function group.
+    pub stage:        usize,
+    /// The size of the group when the hint was written.
+    pub group_size:   usize,
+    /// The requested size of the group.
+    pub target_size:  usize,
+    /// The mean volume of the observed windows in bytes.
+    pub window_bytes: usize,
+    /// The time in milliseconds when the hint was written.
+    pub timestamp:    i64,
+}
+
+/// Decides the size of a function group from the volume of its windows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScalingPolicy {
+    /// The number of windows observed before each decision. 0 disables the
+    /// scaling.
+    pub windows:          usize,
+    /// The window volume in bytes that each member of the group is sized for.
+    pub bytes_per_member: usize,
+    /// The maximum size of the group.
+    pub max_size:         usize,
+}
+
+impl ScalingPolicy {
+    /// Returns the policy of the Flock configuration.
+    pub fn from_conf() -> Self {
+        Self {
+            windows:          *FLOCK_SCALING_WINDOWS,
+            bytes_per_member: *FLOCK_SCALING_BYTES_PER_MEMBER,
+            max_size:         *FLOCK_SCALING_MAX_GROUP_SIZE,
+        }
+    }
+
+    /// Returns true if the function groups are resized at runtime.
+    pub fn is_enabled(&self) -> bool {
+        self.windows > 0 && self.bytes_per_member > 0
+    }
+
+    /// Returns the new size of the group, or `None` if the group should keep
+    /// its size.
+    ///
+    /// The group grows as soon as the mean window volume needs more members,
+    /// but it only shrinks once half of its members would do, so that the
+    /// size doesn't flap around a boundary.
+    ///
+    /// # Arguments
+    /// * `current` - The current size of the group.
+    /// * `volumes` - The volumes of the observed windows in bytes.
+    pub fn decide(&self, current: usize, volumes: &[usize]) -> Option<usize> {
+        if !self.is_enabled() || volumes.is_empty() {
+            return None;
+        }
+        let mean = volumes.iter().sum::<usize>() / volumes.len();
+        let max_size = self.max_size.clamp(MIN_GROUP_SIZE, MAX_GROUP_SIZE);
+        let ideal = ((mean + self.bytes_per_member - 1) / self.bytes_per_member)
+            .clamp(MIN_GROUP_SIZE, max_size);
+        (ideal > current || ideal * 2 <= current).then(|| ideal)
+    }
+}
+
+/// Observes the windows sent to a function group by the function instance.
+///
+/// The payloads of a window are expected back to back, so a window is closed
+/// once the payload of another window is observed.
+#[derive(Debug)]
+pub struct ScalingMonitor {
+    query_code: String,
+    stage:      usize,
+    group_size: usize,
+    policy:     ScalingPolicy,
+    window:     Option<(String, usize)>,
+    volumes:    VecDeque<usize>,
+    hinted:     Option<usize>,
+}
+
+impl ScalingMonitor {
+    /// Creates the monitor of the function group.
+    ///
+    /// # Arguments
+    /// * `group` - The function group fed by the function.
+    /// * `policy` - The scaling policy.
+    pub fn new(group: &CloudFunction, policy: ScalingPolicy) -> Result<Self> {
+        let (name, group_size) = match group {
+            CloudFunction::Group((name, size)) => (name, *size),
+            _ => {
+                return Err(FlockError::Internal(format!(
+                    "{:?} is not a function group",
+                    group
+                )))
+            }
+        };
+        let name = FunctionName::parse(name)?;
+        Ok(Self {
+            query_code: name.query_code,
+            stage: name.plan_index,
+            group_size,
+            policy,
+            window: None,
+            volumes: VecDeque::new(),
+            hinted: None,
+        })
+    }
+
+    /// Returns the size of the group that the monitor observes.
+    pub fn group_size(&self) -> usize {
+        self.group_size
+    }
+
+    /// Records the payload sent to the group, and returns the scaling hint if
+    /// the closed window completes a round of observations that asks for
+    /// another size than the last hint.
+    ///
+    /// # Arguments
+    /// * `window` - The window of the payload.
+    /// * `bytes` - The volume of the payload in bytes.
+    /// * `now` - The current time in milliseconds.
+    pub fn observe(&mut self, window: &str, bytes: usize, now: i64) -> Option<ScalingHint> {
+        match &mut self.window {
+            Some((id, volume)) if id == window => {
+                *volume += bytes;
+                return None;
+            }
+            _ => {}
+        }
+        let (_, volume) = self.window.replace((window.to_owned(), bytes))?;
+        self.volumes.push_back(volume);
+        if self.volumes.len() < self.policy.windows {
+            return None;
+        }
+        let volumes = self.volumes.drain(..).collect::<Vec<_>>();
+        let target_size = self.policy.decide(self.group_size, &volumes)?;
+        if self.hinted == Some(target_size) {
+            return None;
+        }
+        self.hinted = Some(target_size);
+        Some(ScalingHint {
+            query_code: self.query_code.clone(),
+            stage: self.stage,
+            group_size: self.group_size,
+            target_size,
+            window_bytes: volumes.iter().sum::<usize>() / volumes.len(),
+            timestamp: now,
+        })
+    }
+}
+
+/// The scaling hints of the function groups, which are the S3 objects
+/// `scaling/<query code>/<stage>.json`.
+#[derive(Debug, Clone)]
+pub struct ScalingHints {
+    client: Arc<dyn CloudClient>,
+    bucket: String,
+}
+
+impl Default for ScalingHints {
+    fn default() -> Self {
+        Self::new(Arc::new(AwsCloudClient), &FLOCK_S3_BUCKET)
+    }
+}
+
+impl ScalingHints {
+    /// Creates the scaling hints in the given bucket.
+    pub fn new(client: Arc<dyn CloudClient>, bucket: &str) -> Self {
+        Self {
+            client,
+            bucket: bucket.to_string(),
+        }
+    }
+
+    fn key(query_code: &str, stage: usize) -> String {
+        query_key(
+            query_code,
+            &format!("{}{:02}.json", SCALING_KEY_PREFIX, stage),
+        )
+    }
+
+    /// Writes the hint, replacing the previous hint of the group.
+    pub async fn write(&self, hint: &ScalingHint) -> Result<()> {
+        self.client
+            .s3_put(
+                &self.bucket,
+                &Self::key(&hint.query_code, hint.stage),
+                serde_json::to_vec(hint)?,
+            )
+            .await
+    }
+
+    /// Returns the latest hint of the group, if any.
+    pub async fn read(&self, query_code: &str, stage: usize) -> Result<Option<ScalingHint>> {
+        let key = Self::key(query_code, stage);
+        if !self
+            .client
+            .s3_list(&self.bucket, &key)
+            .await?
+            .iter()
+            .any(|k| *k == key)
+        {
+            return Ok(None);
+        }
+        let body = self.client.s3_get(&self.bucket, &key).await?;
+        Ok(Some(serde_json::from_slice(&body)?))
+    }
+}
+
+/// Returns the function group of the stage that can be resized on the route,
+/// and its size if the route knows it.
+///
+/// Only the group fed by the data source is resized at a window boundary,
+/// since the data source is the one that hands itself over to the new ring.
+/// It's either the workers of the route, or the second stage of a query
+/// deployed by [`crate::api::run_query`], whose entry stage reads the data
+/// source.
+pub fn scalable_group(route: &Route, stage: usize) -> Result<(String, Option<usize>)> {
+    let (name, size) = match &route.active.workers {
+        Some(CloudFunction::Group((name, size))) => (name.clone(), Some(*size)),
+        Some(workers) => {
+            return Err(FlockError::NotImplemented(format!(
+                "The data source of {} feeds {:?} instead of a function group",
+                route.active.query_code, workers
+            )))
+        }
+        None => (
+            FunctionName::lambda(&route.active.query_code, 1)?.to_string(),
+            None,
+        ),
+    };
+    if FunctionName::parse(&name)?.plan_index != stage {
+        return Err(FlockError::NotImplemented(format!(
+            "Only the function group fed by the data source can be resized, i.e. {}",
+            name
+        )));
+    }
+    Ok((name, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aws::client::FakeCloudClient;
+    use crate::runtime::metadata::{QueryMetadata, WORKERS_METADATA_KEY};
+    use crate::runtime::payload::Payload;
+    use crate::runtime::switchover::{
+        RouteFollower, RouteTable, RouteTarget, ROUTE_GENERATION_METADATA_KEY, ROUTE_METADATA_KEY,
+    };
+    use std::time::Duration;
+
+    const MB: usize = 1 << 20;
+
+    fn policy() -> ScalingPolicy {
+        ScalingPolicy {
+            windows:          2,
+            bytes_per_member: MB,
+            max_size:         16,
+        }
+    }
+
+    #[test]
+    fn decide_group_size() {
+        let policy = policy();
+        assert_eq!(policy.decide(8, &[]), None);
+        // Grow as soon as the members are short.
+        assert_eq!(policy.decide(8, &[9 * MB, 9 * MB]), Some(9));
+        assert_eq!(policy.decide(8, &[8 * MB, 8 * MB]), None);
+        assert_eq!(policy.decide(8, &[100 * MB]), Some(16));
+        // Shrink only once half of the members would do.
+        assert_eq!(policy.decide(8, &[5 * MB, 5 * MB]), None);
+        assert_eq!(policy.decide(8, &[3 * MB, 5 * MB]), Some(4));
+        assert_eq!(policy.decide(8, &[0, 0]), Some(MIN_GROUP_SIZE));
+        assert_eq!(policy.decide(2, &[0, 0]), None);
+
+        let disabled = ScalingPolicy {
+            windows: 0,
+            ..policy
+        };
+        assert_eq!(disabled.decide(8, &[100 * MB]), None);
+    }
+
+    #[tokio::test]
+    async fn monitor_window_volume() -> Result<()> {
+        let group = CloudFunction::Group(("q1-01".to_string(), 4));
+        let mut monitor = ScalingMonitor::new(&group, policy())?;
+        assert!(
+            ScalingMonitor::new(&CloudFunction::Lambda("q1-01".to_string()), policy()).is_err()
+        );
+
+        // The windows are closed by the payloads of the next window.
+        assert_eq!(monitor.observe("w1", 3 * MB, 0), None);
+        assert_eq!(monitor.observe("w1", 3 * MB, 0), None);
+        assert_eq!(monitor.observe("w2", 4 * MB, 0), None);
+        let hint = monitor.observe("w3", 5 * MB, 1000).unwrap();
+        assert_eq!(
+            hint,
+            ScalingHint {
+                query_code:   "q1".to_string(),
+                stage:        1,
+                group_size:   4,
+                target_size:  5,
+                window_bytes: 5 * MB,
+                timestamp:    1000,
+            }
+        );
+
+        // The same request is not repeated.
+        assert_eq!(monitor.observe("w4", 5 * MB, 0), None);
+        assert_eq!(monitor.observe("w5", 0, 0), None);
+
+        let client = Arc::new(FakeCloudClient::new());
+        let hints = ScalingHints::new(client.clone(), "flock");
+        assert_eq!(hints.read("q1", 1).await?, None);
+        hints.write(&hint).await?;
+        assert_eq!(client.keys("flock"), vec!["q1/scaling/01.json"]);
+        assert_eq!(hints.read("q1", 1).await?, Some(hint));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hand_off_resized_ring() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let table = RouteTable::new(client, "flock");
+        let route = Route::new(RouteTarget::entry("q1"));
+        table.publish("q1", &route).await?;
+
+        assert_eq!(scalable_group(&route, 1)?, ("q1-01".to_string(), None));
+        assert!(scalable_group(&route, 2).is_err());
+
+        // The data source of ring version 0 emits to the group in its context.
+        let mut metadata = QueryMetadata::default();
+        metadata.insert(ROUTE_METADATA_KEY.to_string(), "q1".to_string());
+        let payload = Payload {
+            metadata: Some(metadata),
+            ..Default::default()
+        };
+        let mut follower = RouteFollower::new("q1", 0, table.clone(), Duration::ZERO);
+        assert_eq!(follower.poll().await?, None);
+
+        let resized = route.resize("q1-01", 8, 1000);
+        table.publish("q1", &resized).await?;
+        assert_eq!(resized.generation, 1);
+        assert_eq!(resized.active.source, route.active.source);
+        assert_eq!(resized.overlap_until, 1000);
+        assert_eq!(scalable_group(&resized, 1)?, ("q1-01".to_string(), Some(8)));
+
+        // The data source hands itself over to the resized ring at the window
+        // boundary, and stops emitting to the old ring at once.
+        let polled = follower.poll().await?.unwrap();
+        assert_eq!(polled, resized);
+        let handed = polled.hand_off(&payload, 5)?;
+        let m = handed.metadata.as_ref().unwrap();
+        assert_eq!(m.get(ROUTE_GENERATION_METADATA_KEY).unwrap(), "1");
+        assert_eq!(
+            serde_json::from_str::<CloudFunction>(m.get(WORKERS_METADATA_KEY).unwrap())?,
+            CloudFunction::Group(("q1-01".to_string(), 8))
+        );
+        follower.retire(&polled);
+        assert!(follower.is_expired(1000));
+
+        // The resumed data source follows the new ring version, which is
+        // resized again from the size in the route.
+        let resumed = RouteFollower::from_metadata(&handed.metadata).unwrap();
+        assert!(!resumed.is_expired(i64::MAX));
+        let resized = resized.resize("q1-01", 16, 2000);
+        assert_eq!(
+            resized.previous.as_ref().unwrap().workers,
+            polled.active.workers
+        );
+        table.publish("q1", &resized).await?;
+        let mut follower = RouteFollower::new("q1", 1, table, Duration::ZERO);
+        assert_eq!(follower.poll().await?.unwrap().generation, 2);
+        Ok(())
+    }
+}
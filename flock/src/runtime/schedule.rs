@@ -0,0 +1,272 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The periodic batch queries over the S3 objects.
+//!
+//! [`crate::api::schedule_query`] creates an EventBridge rule that invokes the
+//! source function of the query, i.e. `<query code>-00`, on the schedule
+//! expression. The payload of each run is rendered from the scheduled event by
+//! [`scheduled_input`]: it reads the [`S3ObjectsSource`], and its metadata
+//! carries the time of the event as `scan_end` and the period of the schedule
+//! in seconds as `scan_period`.
+//!
+//! The source function only reads the objects whose time partitions, e.g.
+//! `logs/2021/10/01/05/` or `logs/year=2021/month=10/day=01/`, overlap the
+//! [`ScanRange`] `[scan_end - scan_period, scan_end)`. The objects without a
+//! time partition are always read.
+
+use crate::datasource::s3::S3ObjectsSource;
+use crate::datasource::DataSource;
+use crate::error::{FlockError, Result};
+use crate::runtime::metadata::QueryMetadata;
+use crate::runtime::payload::Payload;
+use chrono::{DateTime, Duration as Delta, TimeZone, Utc};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The metadata key of the end of the scanned time range in RFC 3339.
+pub const SCAN_END_METADATA_KEY: &str = "scan_end";
+
+/// The metadata key of the length of the scanned time range in seconds.
+pub const SCAN_PERIOD_METADATA_KEY: &str = "scan_period";
+
+/// The maximum length of the input template of an EventBridge target.
+pub const INPUT_TEMPLATE_LIMIT: usize = 8192;
+
+/// The identifier of the lambda target of the schedule rules.
+pub const SCHEDULE_TARGET_ID: &str = "flock-source";
+
+/// Returns the name of the EventBridge rule of the schedule.
+pub fn rule_name(query_code: &str, name: &str) -> String {
+    format!("{}{}", rule_prefix(query_code), name)
+}
+
+/// Returns the prefix of the names of the EventBridge rules of the query.
+pub fn rule_prefix(query_code: &str) -> String {
+    format!("flock-{}-", query_code)
+}
+
+/// The time range scanned by a scheduled run, `[start, end)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanRange {
+    /// The start of the range, inclusive.
+    pub start: DateTime<Utc>,
+    /// The end of the range, exclusive.
+    pub end:   DateTime<Utc>,
+}
+
+impl ScanRange {
+    /// Returns the range of the scheduled run in the metadata, or `None` if
+    /// the payload isn't from a schedule.
+    ///
+    /// # Arguments
+    /// * `metadata` - The metadata of the payload.
+    /// * `now` - The end of the range if the metadata doesn't carry it.
+    pub fn from_metadata(
+        metadata: &Option<QueryMetadata>,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Self>> {
+        let metadata = match metadata {
+            Some(metadata) => metadata,
+            None => return Ok(None),
+        };
+        let period = match metadata.get(SCAN_PERIOD_METADATA_KEY) {
+            Some(period) => period.parse::<i64>().map_err(|_| {
+                FlockError::Execution(format!("Invalid scan period in the metadata: {}", period))
+            })?,
+            None => return Ok(None),
+        };
+        let end = match metadata.get(SCAN_END_METADATA_KEY) {
+            Some(end) => DateTime::parse_from_rfc3339(end)
+                .map_err(|_| {
+                    FlockError::Execution(format!("Invalid scan end in the metadata: {}", end))
+                })?
+                .with_timezone(&Utc),
+            None => now,
+        };
+        Ok(Some(Self {
+            start: end - Delta::seconds(period),
+            end,
+        }))
+    }
+
+    /// Returns true if the range overlaps `[start, end)`.
+    pub fn overlaps(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+        start < self.end && end > self.start
+    }
+}
+
+/// Returns the time interval of the partition of the S3 key, `[start, end)`.
+///
+/// The partition is given by the directories of the key: the first one that
+/// is a four-digit year, followed by the optional month, day and hour. The
+/// Hive-style directories, e.g. `year=2021`, are accepted too.
+pub fn partition_range(key: &str) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let mut segments = key.split('/').collect::<Vec<_>>();
+    segments.pop(); // the object name
+    let fields = segments
+        .iter()
+        .map(|s| s.rsplit('=').next().unwrap_or(s))
+        .skip_while(|s| !(s.len() == 4 && s.bytes().all(|b| b.is_ascii_digit())))
+        .take(4)
+        .map_while(|s| s.parse::<u32>().ok())
+        .collect::<Vec<_>>();
+
+    let year = *fields.first()? as i32;
+    let month = fields.get(1).copied();
+    let day = fields.get(2).copied();
+    let hour = fields.get(3).copied();
+    let start = Utc
+        .ymd_opt(year, month.unwrap_or(1), day.unwrap_or(1))
+        .single()?
+        .and_hms_opt(hour.unwrap_or(0), 0, 0)?;
+    let end = match (month, day, hour) {
+        (None, _, _) => Utc.ymd_opt(year + 1, 1, 1).single()?.and_hms(0, 0, 0),
+        (Some(12), None, _) => Utc.ymd_opt(year + 1, 1, 1).single()?.and_hms(0, 0, 0),
+        (Some(month), None, _) => Utc.ymd_opt(year, month + 1, 1).single()?.and_hms(0, 0, 0),
+        (Some(_), Some(_), None) => start + Delta::days(1),
+        (Some(_), Some(_), Some(_)) => start + Delta::hours(1),
+    };
+    Some((start, end))
+}
+
+/// Returns the keys whose partitions overlap the range. The keys without a
+/// time partition are kept.
+pub fn prune_keys(keys: Vec<String>, range: &ScanRange) -> Vec<String> {
+    keys.into_iter()
+        .filter(|key| match partition_range(key) {
+            Some((start, end)) => range.overlaps(start, end),
+            None => true,
+        })
+        .collect()
+}
+
+/// Returns the input transformer of the schedule rule: the JSON path of the
+/// time of the scheduled event, and the template of the payload of the source
+/// function.
+///
+/// # Arguments
+/// * `source` - The S3 objects to scan.
+/// * `period` - The time range scanned by each run.
+pub fn scheduled_input(
+    source: &S3ObjectsSource,
+    period: Duration,
+) -> Result<(HashMap<String, String>, String)> {
+    let mut metadata = QueryMetadata::default();
+    metadata.insert(
+        SCAN_PERIOD_METADATA_KEY.to_string(),
+        period.as_secs().to_string(),
+    );
+    metadata.insert(SCAN_END_METADATA_KEY.to_string(), "<time>".to_string());
+    let payload = Payload {
+        datasource: DataSource::S3Objects(source.clone()),
+        metadata: Some(metadata),
+        ..Default::default()
+    };
+    let template = serde_json::to_string(&payload)?;
+    if template.len() > INPUT_TEMPLATE_LIMIT {
+        return Err(FlockError::Execution(format!(
+            "The scheduled payload has {} bytes, over the {} bytes of the input template",
+            template.len(),
+            INPUT_TEMPLATE_LIMIT
+        )));
+    }
+    let paths = vec![("time".to_string(), "$.time".to_string())]
+        .into_iter()
+        .collect();
+    Ok((paths, template))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasource::s3::{S3ObjectCompression, S3ObjectFormat};
+
+    fn time(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn partition_pruning() -> Result<()> {
+        assert_eq!(
+            partition_range("logs/2021/10/01/05/part-0.json.gz"),
+            Some((time("2021-10-01T05:00:00Z"), time("2021-10-01T06:00:00Z")))
+        );
+        assert_eq!(
+            partition_range("logs/year=2021/month=12/part-0.json"),
+            Some((time("2021-12-01T00:00:00Z"), time("2022-01-01T00:00:00Z")))
+        );
+        assert_eq!(
+            partition_range("logs/2021/10/31/part-0.json"),
+            Some((time("2021-10-31T00:00:00Z"), time("2021-11-01T00:00:00Z")))
+        );
+        assert_eq!(partition_range("logs/latest/part-0.json"), None);
+        assert_eq!(partition_range("logs/2021/13/part-0.json"), None);
+
+        let mut metadata = QueryMetadata::default();
+        metadata.insert(SCAN_PERIOD_METADATA_KEY.to_string(), "3600".to_string());
+        metadata.insert(
+            SCAN_END_METADATA_KEY.to_string(),
+            "2021-10-01T02:00:00Z".to_string(),
+        );
+        let range = ScanRange::from_metadata(&Some(metadata), Utc::now())?.unwrap();
+        assert_eq!(range.start, time("2021-10-01T01:00:00Z"));
+
+        let keys = vec![
+            "logs/2021/10/01/00/part-0.json",
+            "logs/2021/10/01/01/part-0.json",
+            "logs/2021/10/01/01/part-1.json",
+            "logs/2021/10/01/02/part-0.json",
+            "logs/2021/10/01/part-0.json",
+            "logs/2021/09/30/23/part-0.json",
+            "logs/manifest.json",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        assert_eq!(
+            prune_keys(keys, &range),
+            vec![
+                "logs/2021/10/01/01/part-0.json",
+                "logs/2021/10/01/01/part-1.json",
+                "logs/2021/10/01/part-0.json",
+                "logs/manifest.json",
+            ]
+        );
+
+        // The payloads without a schedule scan all objects.
+        assert_eq!(ScanRange::from_metadata(&None, Utc::now())?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn scheduled_input_template() -> Result<()> {
+        let source = S3ObjectsSource::new(
+            "flock-clicks",
+            "logs/",
+            S3ObjectFormat::Ndjson,
+            S3ObjectCompression::None,
+        );
+        let (paths, template) = scheduled_input(&source, Duration::from_secs(3600))?;
+        assert_eq!(paths["time"], "$.time");
+
+        // EventBridge substitutes the time of the event into the template.
+        let input = template.replace("<time>", "2021-10-01T02:00:00Z");
+        let payload: Payload = serde_json::from_str(&input)?;
+        assert_eq!(payload.datasource, DataSource::S3Objects(source));
+        let range = ScanRange::from_metadata(&payload.metadata, Utc::now())?.unwrap();
+        assert_eq!(range.end, time("2021-10-01T02:00:00Z"));
+        assert_eq!(range.start, time("2021-10-01T01:00:00Z"));
+        Ok(())
+    }
+}
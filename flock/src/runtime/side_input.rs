@@ -0,0 +1,562 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The side input of a query stored in S3 (see [`SideInput`]).
+//!
+//! The side input is read in ranged GETs of `side_input_chunk_size` bytes
+//! instead of a single GET of the whole object. Each chunk is cut at its last
+//! line break and parsed with the Arrow CSV reader, which only parses the
+//! columns of the projection and the predicate. The predicate is applied to
+//! each batch before it's buffered, so the function only keeps the filtered
+//! and projected rows of a large side table in memory.
+//!
+//! The filtered side inputs are cached across the invocations of the function
+//! by [`SideInputCache`], keyed by the S3 key, the entity tag of the object,
+//! the projection and the predicate.
+//!
+//! The encrypted side inputs can't be decrypted by range, so they are read in
+//! a single GET, and then parsed and filtered in the same way.
+
+use crate::aws::client::CloudClient;
+use crate::configs::*;
+use crate::encryption;
+use crate::error::{FlockError, Result};
+use crate::runtime::metadata::{SideInput, SideInputPredicate};
+use crate::transmute::{adaptive_batch_size, schema_from_bytes};
+use datafusion::arrow::array::BooleanArray;
+use datafusion::arrow::compute::filter_record_batch;
+use datafusion::arrow::csv::reader::ReaderBuilder;
+use datafusion::arrow::datatypes::{Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::logical_plan::Operator;
+use datafusion::physical_plan::expressions::{binary, col, lit};
+use datafusion::physical_plan::PhysicalExpr;
+use datafusion::scalar::ScalarValue;
+use lazy_static::lazy_static;
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    /// The filtered side inputs cached by the function.
+    pub static ref SIDE_INPUT_CACHE: SideInputCache =
+        SideInputCache::new(*FLOCK_SIDE_INPUT_CACHE_SIZE, *FLOCK_SIDE_INPUT_CHUNK_SIZE);
+}
+
+/// The statistics of a side input read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadStats {
+    /// The number of the S3 GETs.
+    pub chunks:       usize,
+    /// The number of the rows parsed.
+    pub rows_read:    usize,
+    /// The number of the rows kept by the predicate.
+    pub rows_kept:    usize,
+    /// The maximum size in bytes of the unparsed CSV held in memory at once.
+    pub max_buffered: usize,
+}
+
+/// The columns parsed from the side input and the filter on them.
+struct Pushdown {
+    /// The schema of the side input.
+    schema:    SchemaRef,
+    /// The indices of the parsed columns in the schema of the side input.
+    columns:   Vec<usize>,
+    /// The predicate over the parsed columns.
+    filter:    Option<Arc<dyn PhysicalExpr>>,
+    /// The indices of the projected columns in the parsed columns.
+    output:    Vec<usize>,
+    /// The schema of the projected columns.
+    projected: SchemaRef,
+}
+
+impl Pushdown {
+    fn try_new(schema: SchemaRef, side_input: &SideInput) -> Result<Self> {
+        let projection = if side_input.projection.is_empty() {
+            schema.fields().iter().map(|f| f.name().clone()).collect()
+        } else {
+            side_input.projection.clone()
+        };
+
+        // The predicate column is parsed even if it isn't projected.
+        let mut columns = projection
+            .iter()
+            .map(|c| c.as_str())
+            .chain(side_input.predicate.as_ref().map(|p| p.column()))
+            .map(|c| schema.index_of(c))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        columns.sort_unstable();
+        columns.dedup();
+        let parsed = Schema::new(columns.iter().map(|i| schema.field(*i).clone()).collect());
+
+        let filter = match &side_input.predicate {
+            Some(predicate) => Self::filter(predicate, &parsed)?,
+            None => None,
+        };
+        let output = projection
+            .iter()
+            .map(|c| parsed.index_of(c))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let projected = Arc::new(Schema::new(
+            output.iter().map(|i| parsed.field(*i).clone()).collect(),
+        ));
+
+        Ok(Self {
+            schema,
+            columns,
+            filter,
+            output,
+            projected,
+        })
+    }
+
+    /// Returns the physical expression of the predicate, or `None` if the
+    /// range is unbounded.
+    fn filter(
+        predicate: &SideInputPredicate,
+        schema: &Schema,
+    ) -> Result<Option<Arc<dyn PhysicalExpr>>> {
+        let column = predicate.column();
+        let data_type = schema.field_with_name(column)?.data_type();
+        let compare = |op: Operator, value: &str| -> Result<Arc<dyn PhysicalExpr>> {
+            let value = ScalarValue::try_from_string(value.to_string(), data_type)?;
+            Ok(binary(col(column, schema)?, op, lit(value), schema)?)
+        };
+
+        let exprs = match predicate {
+            SideInputPredicate::Eq { value, .. } => vec![compare(Operator::Eq, value)?],
+            SideInputPredicate::Range { min, max, .. } => {
+                let mut exprs = vec![];
+                if let Some(min) = min {
+                    exprs.push(compare(Operator::GtEq, min)?);
+                }
+                if let Some(max) = max {
+                    exprs.push(compare(Operator::LtEq, max)?);
+                }
+                exprs
+            }
+        };
+        let mut exprs = exprs.into_iter();
+        let first = match exprs.next() {
+            Some(expr) => expr,
+            None => return Ok(None),
+        };
+        Ok(Some(exprs.try_fold(first, |acc, expr| {
+            binary(acc, Operator::And, expr, schema)
+        })?))
+    }
+
+    /// Filters and projects the parsed batch.
+    fn apply(&self, batch: RecordBatch) -> Result<RecordBatch> {
+        let batch = match &self.filter {
+            Some(filter) => {
+                let mask = filter.evaluate(&batch)?.into_array(batch.num_rows());
+                let mask = mask
+                    .as_any()
+                    .downcast_ref::<BooleanArray>()
+                    .ok_or_else(|| {
+                        FlockError::Execution(
+                            "The predicate of the side input isn't a boolean expression"
+                                .to_string(),
+                        )
+                    })?;
+                filter_record_batch(&batch, mask)?
+            }
+            None => batch,
+        };
+        Ok(RecordBatch::try_new(
+            self.projected.clone(),
+            self.output
+                .iter()
+                .map(|i| batch.column(*i).clone())
+                .collect(),
+        )?)
+    }
+
+    /// Parses the complete lines of the CSV, and returns the filtered and
+    /// projected batches.
+    fn parse(&self, csv: &[u8], header: bool, stats: &mut ReadStats) -> Result<Vec<RecordBatch>> {
+        // The header isn't a row, so it's left out of the row width estimate.
+        let rows = match header {
+            true => csv
+                .iter()
+                .position(|b| *b == b'\n')
+                .map_or(&csv[csv.len()..], |i| &csv[i + 1..]),
+            false => csv,
+        };
+        let batch_size = adaptive_batch_size(rows, *FLOCK_SOURCE_BATCH_BYTES);
+        let reader = ReaderBuilder::new()
+            .with_schema(self.schema.clone())
+            .has_header(header)
+            .with_delimiter(b',')
+            .with_batch_size(batch_size)
+            .with_projection(self.columns.clone())
+            .build(Cursor::new(csv))?;
+
+        let mut batches = vec![];
+        for batch in reader {
+            let batch = batch.map_err(|e| {
+                FlockError::Execution(format!("Error reading batch from side input: {}", e))
+            })?;
+            stats.rows_read += batch.num_rows();
+            let batch = self.apply(batch)?;
+            stats.rows_kept += batch.num_rows();
+            if batch.num_rows() > 0 {
+                batches.push(batch);
+            }
+        }
+        Ok(batches)
+    }
+}
+
+/// Reads the side input from the S3 bucket in ranged GETs of `chunk_size`
+/// bytes, and returns the filtered and projected rows.
+pub async fn read_side_input(
+    client: &dyn CloudClient,
+    bucket: &str,
+    side_input: &SideInput,
+    chunk_size: u64,
+) -> Result<(Vec<RecordBatch>, ReadStats)> {
+    let size = client.s3_head(bucket, &side_input.s3_key).await?.size;
+    read_chunks(client, bucket, side_input, size, chunk_size).await
+}
+
+async fn read_chunks(
+    client: &dyn CloudClient,
+    bucket: &str,
+    side_input: &SideInput,
+    size: u64,
+    chunk_size: u64,
+) -> Result<(Vec<RecordBatch>, ReadStats)> {
+    if side_input.format != "csv" {
+        return Err(FlockError::NotImplemented(format!(
+            "The side input format {} isn't supported",
+            side_input.format
+        )));
+    }
+    let schema = schema_from_bytes(&base64::decode(&side_input.schema)?)?;
+    let pushdown = Pushdown::try_new(schema, side_input)?;
+    let key = &side_input.s3_key;
+
+    let mut stats = ReadStats::default();
+    let mut batches = vec![];
+    let mut buffer = vec![];
+    let mut header = true;
+    let mut offset = 0;
+    while offset < size {
+        let end = (offset + chunk_size.max(1)).min(size);
+        let chunk = client.s3_get_range(bucket, key, offset..end).await?;
+        stats.chunks += 1;
+        if offset == 0 && encryption::is_sealed(&chunk) {
+            let bytes = encryption::open_bytes(client.s3_get(bucket, key).await?).await?;
+            stats.chunks += 1;
+            stats.max_buffered = bytes.len();
+            let batches = pushdown.parse(&bytes, true, &mut stats)?;
+            return Ok((batches, stats));
+        }
+        offset = end;
+        buffer.extend(chunk);
+        stats.max_buffered = stats.max_buffered.max(buffer.len());
+
+        // The partial line at the end of the chunk is kept for the next one.
+        let complete = if offset < size {
+            match buffer.iter().rposition(|b| *b == b'\n') {
+                Some(i) => i + 1,
+                None => continue,
+            }
+        } else {
+            buffer.len()
+        };
+        let rest = buffer.split_off(complete);
+        batches.extend(pushdown.parse(&buffer, header, &mut stats)?);
+        header = false;
+        buffer = rest;
+    }
+    Ok((batches, stats))
+}
+
+/// The key of a cached side input: the S3 key, the entity tag of the object,
+/// the projection and the predicate.
+type CacheKey = (String, String, Vec<String>, Option<SideInputPredicate>);
+
+/// The filtered side inputs of the last objects read by the function.
+#[derive(Debug)]
+pub struct SideInputCache {
+    capacity:   usize,
+    chunk_size: u64,
+    entries:    Mutex<VecDeque<(CacheKey, Vec<RecordBatch>)>>,
+}
+
+impl SideInputCache {
+    /// Creates a cache of at most `capacity` side inputs, which are read in
+    /// ranged GETs of `chunk_size` bytes. Nothing is cached if the capacity
+    /// is 0.
+    pub fn new(capacity: usize, chunk_size: u64) -> Self {
+        Self {
+            capacity,
+            chunk_size,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Returns the filtered side input from the cache, or reads it from the
+    /// S3 bucket if the object is overwritten or not cached.
+    pub async fn get_or_read(
+        &self,
+        client: &dyn CloudClient,
+        bucket: &str,
+        side_input: &SideInput,
+    ) -> Result<Vec<RecordBatch>> {
+        let meta = client.s3_head(bucket, &side_input.s3_key).await?;
+        let key = (
+            side_input.s3_key.clone(),
+            meta.etag,
+            side_input.projection.clone(),
+            side_input.predicate.clone(),
+        );
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(i) = entries.iter().position(|(k, _)| *k == key) {
+                let entry = entries.remove(i).unwrap();
+                let batches = entry.1.clone();
+                entries.push_back(entry);
+                return Ok(batches);
+            }
+        }
+
+        let (batches, _) =
+            read_chunks(client, bucket, side_input, meta.size, self.chunk_size).await?;
+        if self.capacity > 0 {
+            let mut entries = self.entries.lock().unwrap();
+            // The stale versions of the object are never read again.
+            entries.retain(|(k, _)| k.0 != key.0 || k.1 == key.1);
+            entries.push_back((key, batches.clone()));
+            while entries.len() > self.capacity {
+                entries.pop_front();
+            }
+        }
+        Ok(batches)
+    }
+
+    /// Returns the number of the cached side inputs.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Returns true if no side input is cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aws::client::FakeCloudClient;
+    use crate::runtime::broadcast::side_input_to_csv;
+    use crate::transmute::schema_to_bytes;
+    use datafusion::arrow::array::{Array, Int64Array, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field};
+
+    const ROWS: usize = 20000;
+    const CHUNK_SIZE: u64 = 64 * 1024;
+
+    fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("key", DataType::Int64, false),
+            Field::new("value", DataType::Utf8, false),
+            Field::new("padding", DataType::Utf8, false),
+        ]))
+    }
+
+    /// A wide CSV of about 1 MB, where the padding column is never read.
+    fn csv() -> Vec<u8> {
+        let batch = RecordBatch::try_new(
+            schema(),
+            vec![
+                Arc::new(Int64Array::from((0..ROWS as i64).collect::<Vec<_>>())),
+                Arc::new(StringArray::from(
+                    (0..ROWS)
+                        .map(|i| format!("v{}", i % 100))
+                        .collect::<Vec<_>>(),
+                )),
+                Arc::new(StringArray::from(vec!["x".repeat(32); ROWS])),
+            ],
+        )
+        .unwrap();
+        side_input_to_csv(&[batch]).unwrap()
+    }
+
+    fn side_input(
+        s3_key: &str,
+        projection: &[&str],
+        predicate: Option<SideInputPredicate>,
+    ) -> SideInput {
+        SideInput {
+            s3_key: s3_key.to_string(),
+            format: "csv".to_string(),
+            schema: base64::encode(schema_to_bytes(schema())),
+            projection: projection.iter().map(|c| c.to_string()).collect(),
+            predicate,
+        }
+    }
+
+    fn keys(batches: &[RecordBatch], column: usize) -> Vec<i64> {
+        batches
+            .iter()
+            .flat_map(|b| {
+                let array = b
+                    .column(column)
+                    .as_any()
+                    .downcast_ref::<Int64Array>()
+                    .unwrap();
+                (0..array.len()).map(|i| array.value(i)).collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn read_side_input_in_chunks() -> Result<()> {
+        let client = FakeCloudClient::new();
+        let csv = csv();
+        client.put_object("flock", "side_input.csv", csv.clone());
+
+        // The unfiltered read matches the read of the whole object.
+        let (batches, stats) = read_side_input(
+            &client,
+            "flock",
+            &side_input("side_input.csv", &[], None),
+            CHUNK_SIZE,
+        )
+        .await?;
+        let expected = ReaderBuilder::new()
+            .with_schema(schema())
+            .has_header(true)
+            .build(Cursor::new(csv.clone()))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        assert_eq!(keys(&batches, 0), keys(&expected, 0));
+        assert_eq!(batches[0].schema(), schema());
+        assert_eq!(stats.rows_read, ROWS);
+        assert_eq!(stats.rows_kept, ROWS);
+        assert_eq!(
+            stats.chunks,
+            (csv.len() as u64 + CHUNK_SIZE - 1) as usize / CHUNK_SIZE as usize
+        );
+        assert!(stats.chunks > 10);
+
+        // The object is never held in memory at once.
+        assert!(client
+            .ranges()
+            .iter()
+            .all(|r| r.end - r.start <= CHUNK_SIZE));
+        assert!(stats.max_buffered < 2 * CHUNK_SIZE as usize);
+
+        // Only the projected rows in the range are kept.
+        let (batches, stats) = read_side_input(
+            &client,
+            "flock",
+            &side_input(
+                "side_input.csv",
+                &["value", "key"],
+                Some(SideInputPredicate::Range {
+                    column: "key".to_string(),
+                    min:    Some("100".to_string()),
+                    max:    Some("199".to_string()),
+                }),
+            ),
+            CHUNK_SIZE,
+        )
+        .await?;
+        assert_eq!(stats.rows_read, ROWS);
+        assert_eq!(stats.rows_kept, 100);
+        assert_eq!(keys(&batches, 1), (100..200).collect::<Vec<_>>());
+        let fields = batches[0].schema().fields().clone();
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0].name(), "value");
+
+        // The predicate column isn't projected.
+        let (batches, stats) = read_side_input(
+            &client,
+            "flock",
+            &side_input(
+                "side_input.csv",
+                &["key"],
+                Some(SideInputPredicate::Eq {
+                    column: "value".to_string(),
+                    value:  "v7".to_string(),
+                }),
+            ),
+            CHUNK_SIZE,
+        )
+        .await?;
+        assert_eq!(stats.rows_kept, ROWS / 100);
+        assert_eq!(batches[0].num_columns(), 1);
+        assert!(keys(&batches, 0).iter().all(|k| k % 100 == 7));
+
+        // The unknown columns are rejected.
+        assert!(read_side_input(
+            &client,
+            "flock",
+            &side_input("side_input.csv", &["price"], None),
+            CHUNK_SIZE,
+        )
+        .await
+        .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cache_filtered_side_input() -> Result<()> {
+        let client = FakeCloudClient::new();
+        client.put_object("flock", "side_input.csv", csv());
+        let cache = SideInputCache::new(2, CHUNK_SIZE);
+        let predicate = Some(SideInputPredicate::Range {
+            column: "key".to_string(),
+            min:    None,
+            max:    Some("9".to_string()),
+        });
+        let filtered = side_input("side_input.csv", &["key"], predicate);
+
+        let batches = cache.get_or_read(&client, "flock", &filtered).await?;
+        assert_eq!(keys(&batches, 0), (0..10).collect::<Vec<_>>());
+        let reads = client.ranges().len();
+        assert_eq!(
+            cache.get_or_read(&client, "flock", &filtered).await?,
+            batches
+        );
+        assert_eq!(client.ranges().len(), reads, "the side input is cached");
+
+        // Another projection is another entry.
+        let all = side_input("side_input.csv", &[], None);
+        let batches = cache.get_or_read(&client, "flock", &all).await?;
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), ROWS);
+        assert_eq!(cache.len(), 2);
+
+        // The overwritten object is read again, and the stale entries are
+        // dropped.
+        let csv = csv();
+        let end = csv
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| **b == b'\n')
+            .nth(11)
+            .unwrap()
+            .0;
+        client.put_object("flock", "side_input.csv", csv[..=end].to_vec());
+        let batches = cache.get_or_read(&client, "flock", &filtered).await?;
+        assert!(client.ranges().len() > reads);
+        assert_eq!(keys(&batches, 0), (0..10).collect::<Vec<_>>());
+        assert_eq!(cache.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,672 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The skew mitigation of the hash shuffles.
+//!
+//! A hot key (e.g. the hot auctions of NEXMark) sends most rows of a shuffle to
+//! a single function of the next group, which becomes the straggler of every
+//! window. If a partition of the shuffle has more rows than a multiple of the
+//! mean partition size, the rows of its most frequent key are split across `k`
+//! salted sub-partitions `(key, salt)`. The salt 0 stays at the partition that
+//! the key is hashed to, i.e. the home partition of the key, and the others are
+//! merged into the least loaded partitions, so that the number of fragments of
+//! each window doesn't change.
+//!
+//! The payloads are marked with the salted keys (see [`SALT_METADATA_KEY`]).
+//! The function of a salt only aggregates a part of the key's rows, so it
+//! sends its output of the key to the home function (see
+//! [`SALT_COMBINE_METADATA_KEY`]), which combines the outputs of all salts
+//! before finalizing the window. The combine step aggregates the outputs again
+//! (see [`combiners`]), so the salting is only correct for the plans whose
+//! aggregates are decomposable, i.e. `COUNT`, `SUM`, `MIN` and `MAX`.
+
+use crate::error::{FlockError, Result};
+use crate::runtime::arena::WindowId;
+use crate::runtime::metadata::QueryMetadata;
+use datafusion::arrow::array::UInt32Array;
+use datafusion::arrow::compute::take;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::arrow::util::display::array_value_to_string;
+use datafusion::datasource::MemTable;
+use datafusion::execution::context::ExecutionContext as DataFusionExecutionContext;
+use datafusion::logical_plan::{max, min, sum, Column, Expr};
+use datafusion::physical_plan::expressions::Column as ColumnExpr;
+use datafusion::physical_plan::hash_aggregate::HashAggregateExec;
+use datafusion::physical_plan::projection::ProjectionExec;
+use datafusion::physical_plan::ExecutionPlan;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+
+/// The metadata key of the salted keys of the payload.
+pub const SALT_METADATA_KEY: &str = "salted_keys";
+
+/// The metadata key of the partition whose output of the salted keys is sent
+/// to the home function.
+pub const SALT_COMBINE_METADATA_KEY: &str = "salt_combine";
+
+/// A hot key whose rows are split across the salted sub-partitions.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SaltedKey {
+    /// The key columns of the shuffle.
+    pub columns:       Vec<String>,
+    /// The values of the key columns.
+    pub key:           Vec<String>,
+    /// The partitions of the salted sub-partitions, indexed by the salt. The
+    /// salt 0 is the home partition of the key.
+    pub partitions:    Vec<usize>,
+    /// The function of the home partition, which combines the outputs of the
+    /// salts.
+    #[serde(default)]
+    pub home_function: String,
+}
+
+impl SaltedKey {
+    /// Returns the home partition of the key.
+    pub fn home(&self) -> usize {
+        self.partitions[0]
+    }
+
+    /// Returns the salting factor of the key.
+    pub fn salts(&self) -> usize {
+        self.partitions.len()
+    }
+}
+
+/// Returns the salted keys of the payload.
+pub fn salted_keys(metadata: &Option<QueryMetadata>) -> Vec<SaltedKey> {
+    metadata
+        .as_ref()
+        .and_then(|m| m.get(SALT_METADATA_KEY))
+        .and_then(|s| serde_json::from_str(s).ok())
+        .unwrap_or_default()
+}
+
+/// Marks the payload with the salted keys.
+pub fn set_salted_keys(metadata: &mut Option<QueryMetadata>, keys: &[SaltedKey]) -> Result<()> {
+    if !keys.is_empty() {
+        metadata
+            .get_or_insert_with(QueryMetadata::default)
+            .insert(SALT_METADATA_KEY.to_string(), serde_json::to_string(keys)?);
+    }
+    Ok(())
+}
+
+/// Returns the partition that sends its output of the salted keys to the home
+/// function, if the payload is such an output.
+pub fn combine_sender(metadata: &Option<QueryMetadata>) -> Option<usize> {
+    metadata
+        .as_ref()?
+        .get(SALT_COMBINE_METADATA_KEY)?
+        .parse()
+        .ok()
+}
+
+/// Removes the salted keys from the metadata, since they only concern the
+/// current stage.
+pub fn remove_salts(metadata: &mut Option<QueryMetadata>) {
+    if let Some(metadata) = metadata.as_mut() {
+        metadata.remove(SALT_METADATA_KEY);
+        metadata.remove(SALT_COMBINE_METADATA_KEY);
+    }
+}
+
+/// Returns the number of rows of each partition.
+pub fn partition_rows(partitions: &[Vec<RecordBatch>]) -> Vec<usize> {
+    partitions
+        .iter()
+        .map(|p| p.iter().map(|b| b.num_rows()).sum())
+        .collect()
+}
+
+/// Returns the partitions that have more rows than `threshold` times the mean.
+/// A threshold of 0 disables the detection.
+pub fn hot_partitions(rows: &[usize], threshold: f64) -> Vec<usize> {
+    if threshold <= 0.0 || rows.is_empty() {
+        return vec![];
+    }
+    let mean = rows.iter().sum::<usize>() as f64 / rows.len() as f64;
+    rows.iter()
+        .enumerate()
+        .filter(|(_, r)| **r as f64 > threshold * mean)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Returns the values of the key columns of each row.
+pub(crate) fn row_keys(batch: &RecordBatch, columns: &[String]) -> Result<Vec<Vec<String>>> {
+    let schema = batch.schema();
+    let arrays = columns
+        .iter()
+        .map(|c| schema.index_of(c).map(|i| batch.column(i).clone()))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok((0..batch.num_rows())
+        .map(|row| {
+            arrays
+                .iter()
+                .map(|a| array_value_to_string(a, row))
+                .collect::<std::result::Result<Vec<_>, _>>()
+        })
+        .collect::<std::result::Result<Vec<_>, _>>()?)
+}
+
+/// Returns the rows of the batch at the given indices.
+pub(crate) fn take_rows(batch: &RecordBatch, indices: Vec<u32>) -> Result<RecordBatch> {
+    let indices = UInt32Array::from(indices);
+    let columns = batch
+        .columns()
+        .iter()
+        .map(|c| take(c.as_ref(), &indices, None))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(RecordBatch::try_new(batch.schema(), columns)?)
+}
+
+/// Splits the most frequent key of each hot partition across `salts` salted
+/// sub-partitions.
+///
+/// # Arguments
+/// * `partitions` - The output partitions of the hash shuffle.
+/// * `columns` - The key columns of the shuffle.
+/// * `threshold` - The multiple of the mean partition size above which a
+///   partition is hot.
+/// * `salts` - The salting factor of the hot keys.
+///
+/// # Returns
+/// The partitions with the salted sub-partitions, and the salted keys.
+pub fn salt_partitions(
+    mut partitions: Vec<Vec<RecordBatch>>,
+    columns: &[String],
+    threshold: f64,
+    salts: usize,
+) -> Result<(Vec<Vec<RecordBatch>>, Vec<SaltedKey>)> {
+    if salts < 2 || columns.is_empty() {
+        return Ok((partitions, vec![]));
+    }
+
+    let mut salted = vec![];
+    for p in hot_partitions(&partition_rows(&partitions), threshold) {
+        let batches = std::mem::take(&mut partitions[p]);
+        let keys = batches
+            .iter()
+            .map(|b| row_keys(b, columns))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut counts: HashMap<&Vec<String>, usize> = HashMap::new();
+        keys.iter()
+            .flatten()
+            .for_each(|k| *counts.entry(k).or_default() += 1);
+        // The ties are broken by the key values, so that the choice is stable.
+        let hot_key = match counts
+            .into_iter()
+            .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(a.0)))
+        {
+            Some((key, count)) if count > 1 => key.clone(),
+            _ => {
+                partitions[p] = batches;
+                continue;
+            }
+        };
+
+        // The salts are sent to the least loaded partitions.
+        let rows = partition_rows(&partitions);
+        let mut others = (0..partitions.len())
+            .filter(|i| *i != p)
+            .collect::<Vec<_>>();
+        others.sort_by_key(|i| (rows[*i], *i));
+        let targets = std::iter::once(p)
+            .chain(others.into_iter().take(salts - 1))
+            .collect::<Vec<_>>();
+        if targets.len() < 2 {
+            partitions[p] = batches;
+            continue;
+        }
+
+        let mut next_salt = 0;
+        let mut sub_partitions = vec![vec![]; targets.len()];
+        for (batch, keys) in batches.iter().zip(keys.iter()) {
+            let mut indices = vec![vec![]; targets.len()];
+            for (row, key) in keys.iter().enumerate() {
+                let salt = if *key == hot_key {
+                    next_salt = (next_salt + 1) % targets.len();
+                    next_salt
+                } else {
+                    0
+                };
+                indices[salt].push(row as u32);
+            }
+            for (salt, indices) in indices.into_iter().enumerate() {
+                if !indices.is_empty() {
+                    sub_partitions[salt].push(take_rows(batch, indices)?);
+                }
+            }
+        }
+        sub_partitions
+            .into_iter()
+            .zip(targets.iter())
+            .for_each(|(batches, t)| partitions[*t].extend(batches));
+
+        salted.push(SaltedKey {
+            columns:       columns.to_vec(),
+            key:           hot_key,
+            partitions:    targets,
+            home_function: String::new(),
+        });
+    }
+
+    Ok((partitions, salted))
+}
+
+/// Splits the rows of the salted keys from the partitions.
+///
+/// # Returns
+/// The partitions without the rows of the salted keys, and the rows of the
+/// salted keys.
+pub fn split_salted(
+    partitions: Vec<Vec<RecordBatch>>,
+    keys: &[SaltedKey],
+) -> Result<(Vec<Vec<RecordBatch>>, Vec<RecordBatch>)> {
+    if keys.is_empty() {
+        return Ok((partitions, vec![]));
+    }
+    // The salted keys of a window come from the same shuffle.
+    let columns = &keys[0].columns;
+    let salted = keys.iter().map(|k| &k.key).collect::<HashSet<_>>();
+
+    let mut rows = vec![];
+    let partitions = partitions
+        .into_iter()
+        .map(|batches| {
+            let mut rest = vec![];
+            for batch in batches {
+                let (mut hot, mut cold) = (vec![], vec![]);
+                for (row, key) in row_keys(&batch, columns)?.iter().enumerate() {
+                    if salted.contains(key) {
+                        hot.push(row as u32);
+                    } else {
+                        cold.push(row as u32);
+                    }
+                }
+                if hot.is_empty() {
+                    rest.push(batch);
+                    continue;
+                }
+                rows.push(take_rows(&batch, hot)?);
+                if !cold.is_empty() {
+                    rest.push(take_rows(&batch, cold)?);
+                }
+            }
+            Ok(rest)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok((partitions, rows))
+}
+
+/// The aggregate function that combines the outputs of the salts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Combiner {
+    /// The partial counts and sums are added up.
+    Sum,
+    /// The minimum of the partial minimums.
+    Min,
+    /// The maximum of the partial maximums.
+    Max,
+}
+
+impl Combiner {
+    /// Returns the combiner of the aggregate expression, or `None` if the
+    /// aggregate isn't decomposable.
+    pub fn of(aggregate: &str) -> Option<Self> {
+        if aggregate.starts_with("COUNT(") || aggregate.starts_with("SUM(") {
+            Some(Combiner::Sum)
+        } else if aggregate.starts_with("MIN(") {
+            Some(Combiner::Min)
+        } else if aggregate.starts_with("MAX(") {
+            Some(Combiner::Max)
+        } else {
+            None
+        }
+    }
+
+    fn aggregate(&self, expr: Expr) -> Expr {
+        match self {
+            Combiner::Sum => sum(expr),
+            Combiner::Min => min(expr),
+            Combiner::Max => max(expr),
+        }
+    }
+}
+
+/// Returns the combiners of the output columns of the plan, i.e. the
+/// decomposable aggregates of its topmost aggregation, renamed by the
+/// projections above it.
+pub fn combiners(plan: &Arc<dyn ExecutionPlan>) -> HashMap<String, Combiner> {
+    let mut projections: Vec<Vec<(String, String)>> = vec![];
+    let mut curr = plan.clone();
+    loop {
+        if let Some(agg) = curr.as_any().downcast_ref::<HashAggregateExec>() {
+            let mut combiners = agg
+                .aggr_expr()
+                .iter()
+                .filter_map(|e| Combiner::of(&e.name()).map(|c| (e.name().to_string(), c)))
+                .collect::<HashMap<_, _>>();
+            for projection in projections.iter().rev() {
+                combiners = projection
+                    .iter()
+                    .filter_map(|(from, to)| combiners.get(from).map(|c| (to.clone(), *c)))
+                    .collect();
+            }
+            return combiners;
+        }
+        if let Some(projection) = curr.as_any().downcast_ref::<ProjectionExec>() {
+            projections.push(
+                projection
+                    .expr()
+                    .iter()
+                    .filter_map(|(e, alias)| {
+                        e.as_any()
+                            .downcast_ref::<ColumnExpr>()
+                            .map(|c| (c.name().to_string(), alias.clone()))
+                    })
+                    .collect(),
+            );
+        }
+        if curr.children().is_empty() {
+            return HashMap::new();
+        }
+        curr = curr.children()[0].clone();
+    }
+}
+
+/// Combines the outputs of the salts, i.e. aggregates the rows of each key
+/// again with the combiners of the output columns.
+pub async fn combine_salts(
+    batches: Vec<RecordBatch>,
+    columns: &[String],
+    combiners: &HashMap<String, Combiner>,
+) -> Result<Vec<RecordBatch>> {
+    if batches.iter().all(|b| b.num_rows() == 0) {
+        return Ok(vec![]);
+    }
+
+    let schema = batches[0].schema();
+    let column = |name: &str| Expr::Column(Column::from_name(name));
+    let mut group_expr = vec![];
+    let mut aggr_expr = vec![];
+    let mut projection = vec![];
+    for field in schema.fields() {
+        let name = field.name();
+        if columns.contains(name) {
+            group_expr.push(column(name));
+            projection.push(column(name));
+        } else if let Some(combiner) = combiners.get(name) {
+            let partial = format!("__salt_{}", aggr_expr.len());
+            aggr_expr.push(combiner.aggregate(column(name)).alias(&partial));
+            // The partial sums are widened by the aggregation.
+            projection.push(
+                Expr::Cast {
+                    expr:      Box::new(column(&partial)),
+                    data_type: field.data_type().clone(),
+                }
+                .alias(name),
+            );
+        } else {
+            return Err(FlockError::Execution(format!(
+                "The column {} can't be combined across the salts.",
+                name
+            )));
+        }
+    }
+
+    let ctx = DataFusionExecutionContext::new();
+    let table = MemTable::try_new(schema, vec![batches])?;
+    Ok(ctx
+        .read_table(Arc::new(table))?
+        .aggregate(group_expr, aggr_expr)?
+        .select(projection)?
+        .collect()
+        .await?)
+}
+
+/// The window that waits for the outputs of the salts of its keys.
+#[derive(Debug, Default)]
+struct PendingCombine {
+    /// The salted keys whose home is the window.
+    keys:     Vec<SaltedKey>,
+    /// The output of the window, once it's executed.
+    output:   Option<Vec<Vec<RecordBatch>>>,
+    /// The partitions whose outputs are expected.
+    expected: BTreeSet<usize>,
+    /// The outputs of the salts received so far.
+    received: HashMap<usize, Vec<RecordBatch>>,
+}
+
+/// The salted keys of the windows, and the windows that wait for the outputs
+/// of their salts.
+#[derive(Debug, Default)]
+pub struct SaltState {
+    marks:   HashMap<WindowId, HashSet<SaltedKey>>,
+    pending: HashMap<WindowId, PendingCombine>,
+}
+
+impl SaltState {
+    /// Creates an empty salt state.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the salted keys of a fragment of the window. The upstream
+    /// functions salt their keys independently, so the keys of all fragments
+    /// are merged.
+    pub fn mark(&mut self, window: &WindowId, keys: Vec<SaltedKey>) {
+        self.marks.entry(window.clone()).or_default().extend(keys);
+    }
+
+    /// Removes and returns the salted keys of the window.
+    pub fn take_marks(&mut self, window: &WindowId) -> Vec<SaltedKey> {
+        self.marks
+            .remove(window)
+            .map(|keys| keys.into_iter().collect())
+            .unwrap_or_default()
+    }
+
+    /// Holds the output of the window until the outputs of the salts of its
+    /// keys arrive.
+    pub fn wait(&mut self, window: &WindowId, keys: Vec<SaltedKey>, output: Vec<Vec<RecordBatch>>) {
+        let home = keys[0].home();
+        let pending = self.pending.entry(window.clone()).or_default();
+        pending.expected.extend(
+            keys.iter()
+                .flat_map(|k| k.partitions.iter().copied())
+                .filter(|p| *p != home),
+        );
+        pending.keys.extend(keys);
+        pending.output = Some(output);
+    }
+
+    /// Receives the output of the salted keys from the given partition.
+    pub fn receive(&mut self, window: &WindowId, partition: usize, batches: Vec<RecordBatch>) {
+        self.pending
+            .entry(window.clone())
+            .or_default()
+            .received
+            .insert(partition, batches);
+    }
+
+    /// Returns the salted keys, the output of the window, and the outputs of
+    /// the salts, once all of them have arrived.
+    #[allow(clippy::type_complexity)]
+    pub fn take_ready(
+        &mut self,
+        window: &WindowId,
+    ) -> Option<(Vec<SaltedKey>, Vec<Vec<RecordBatch>>, Vec<RecordBatch>)> {
+        let pending = self.pending.get(window)?;
+        if pending.output.is_none()
+            || !pending
+                .expected
+                .iter()
+                .all(|p| pending.received.contains_key(p))
+        {
+            return None;
+        }
+        let pending = self.pending.remove(window)?;
+        Some((
+            pending.keys,
+            pending.output?,
+            pending.received.into_values().flatten().collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::array::{Int32Array, Int64Array};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+
+    fn batch(keys: Vec<i32>, values: Vec<i64>) -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("auction", DataType::Int32, false),
+            Field::new("price", DataType::Int64, false),
+        ]));
+        RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(keys)),
+                Arc::new(Int64Array::from(values)),
+            ],
+        )
+        .unwrap()
+    }
+
+    /// The hot auction 0 has 800 of the 1000 bids, which are all hashed to the
+    /// partition 0.
+    fn skewed_partitions() -> Vec<Vec<RecordBatch>> {
+        let hot = (0..800)
+            .map(|_| 0)
+            .chain((1..=50).map(|k| k * 4))
+            .collect::<Vec<i32>>();
+        let values = (0..hot.len() as i64).collect::<Vec<_>>();
+        let mut partitions = vec![vec![batch(hot, values)]];
+        for p in 1..4 {
+            let keys = (0..50).map(|k| k * 4 + p).collect::<Vec<i32>>();
+            partitions.push(vec![batch(keys, vec![p as i64; 50])]);
+        }
+        partitions
+    }
+
+    fn sum_by_auction() -> HashMap<String, Combiner> {
+        vec![("price".to_string(), Combiner::Sum)]
+            .into_iter()
+            .collect()
+    }
+
+    fn sorted_rows(batches: &[RecordBatch]) -> Vec<Vec<String>> {
+        let columns = vec!["auction".to_string(), "price".to_string()];
+        let mut rows = batches
+            .iter()
+            .flat_map(|b| row_keys(b, &columns).unwrap())
+            .collect::<Vec<_>>();
+        rows.sort();
+        rows
+    }
+
+    #[test]
+    fn detect_hot_partitions() {
+        assert_eq!(hot_partitions(&[850, 50, 50, 50], 2.0), vec![0]);
+        assert!(hot_partitions(&[850, 50, 50, 50], 0.0).is_empty());
+        assert!(hot_partitions(&[100, 100, 100], 2.0).is_empty());
+        assert!(hot_partitions(&[], 2.0).is_empty());
+    }
+
+    #[test]
+    fn salting_evens_out_partitions() -> Result<()> {
+        let columns = vec!["auction".to_string()];
+        let (partitions, salted) = salt_partitions(skewed_partitions(), &columns, 2.0, 4)?;
+
+        assert_eq!(salted.len(), 1);
+        assert_eq!(salted[0].key, vec!["0".to_string()]);
+        assert_eq!(salted[0].home(), 0);
+        assert_eq!(salted[0].salts(), 4);
+
+        let rows = partition_rows(&partitions);
+        assert_eq!(rows.iter().sum::<usize>(), 1000);
+        assert_eq!(rows, vec![250, 250, 250, 250]);
+        assert!(hot_partitions(&rows, 2.0).is_empty());
+
+        // The partitions without hot keys are left untouched.
+        let (partitions, salted) = salt_partitions(partitions, &columns, 2.0, 4)?;
+        assert!(salted.is_empty());
+        assert_eq!(partition_rows(&partitions), rows);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn salted_results_match_unsalted() -> Result<()> {
+        let columns = vec!["auction".to_string()];
+        let combiners = sum_by_auction();
+
+        // Each partition is aggregated by a function of the next group.
+        let mut unsalted = vec![];
+        for partition in skewed_partitions() {
+            unsalted.extend(combine_salts(partition, &columns, &combiners).await?);
+        }
+
+        let (partitions, salted) = salt_partitions(skewed_partitions(), &columns, 2.0, 4)?;
+        let mut outputs = vec![];
+        for partition in partitions {
+            outputs.push(vec![combine_salts(partition, &columns, &combiners).await?]);
+        }
+        // The functions of the salts send their outputs of the key to the home
+        // function, which combines them before finalizing the window.
+        let (rest, rows) = split_salted(outputs, &salted)?;
+        assert_eq!(rows.iter().map(|b| b.num_rows()).sum::<usize>(), 4);
+        let mut results = rest.into_iter().flatten().collect::<Vec<_>>();
+        results.extend(combine_salts(rows, &columns, &combiners).await?);
+
+        assert_eq!(sorted_rows(&results), sorted_rows(&unsalted));
+        Ok(())
+    }
+
+    #[test]
+    fn wait_for_salts() {
+        let window = ("q1-1-1".to_string(), 1);
+        let key = SaltedKey {
+            columns:       vec!["auction".to_string()],
+            key:           vec!["7".to_string()],
+            partitions:    vec![0, 2, 3],
+            home_function: "q1-01-00".to_string(),
+        };
+
+        let mut metadata = None;
+        set_salted_keys(&mut metadata, &[key.clone()]).unwrap();
+        assert_eq!(salted_keys(&metadata), vec![key.clone()]);
+        assert_eq!(combine_sender(&metadata), None);
+
+        let mut state = SaltState::new();
+        state.mark(&window, vec![key.clone()]);
+        state.mark(&window, vec![key.clone()]);
+        assert_eq!(state.take_marks(&window), vec![key.clone()]);
+        assert!(state.take_marks(&window).is_empty());
+
+        state.receive(&window, 2, vec![batch(vec![7], vec![1])]);
+        state.wait(&window, vec![key], vec![vec![batch(vec![7], vec![2])]]);
+        assert!(state.take_ready(&window).is_none());
+        state.receive(&window, 3, vec![batch(vec![7], vec![3])]);
+        let (keys, output, received) = state.take_ready(&window).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(output.len(), 1);
+        assert_eq!(received.len(), 2);
+        assert!(state.take_ready(&window).is_none());
+    }
+}
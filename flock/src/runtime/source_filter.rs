@@ -0,0 +1,694 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The filters pushed down from the first query stage to the data source.
+//!
+//! A selective query, e.g. NEXMark q2 which keeps one bid out of 123, has its
+//! first stage discard most of the generated events after they were encoded,
+//! sent and decoded. If the filter of the first stage sits right above a leaf
+//! of the plan, its predicate only references the columns of the source, so
+//! the data source can evaluate it before it builds the payloads (see
+//! [`SourceFilter::detect`]). The predicates are serialized into the metadata
+//! of the payload that starts the data source function (see
+//! [`SOURCE_FILTER_METADATA_KEY`]), so the same functions can be started with
+//! and without the pushdown, and into the context of the source function of a
+//! stream, which is invoked by the event source mapping without a payload.
+//!
+//! The predicates within a subset of the expressions, i.e. the comparisons,
+//! the modulo, the conjunctions and the disjunctions of the columns and the
+//! constants, are evaluated row by row by a native closure (see
+//! [`FilterExpr`]). The other predicates are evaluated by the filter of the
+//! first stage itself over the events of the window (see
+//! [`Predicate::Plan`]). The first stage keeps its filter either way, so the
+//! pushdown only ever reduces the payloads, never the results.
+
+use crate::error::{FlockError, Result};
+use crate::runtime::metadata::QueryMetadata;
+use crate::runtime::plan::feed_memory_sources;
+use crate::runtime::topology::schema_fingerprint;
+use datafusion::arrow::array::{
+    Array, BooleanArray, Float32Array, Float64Array, Int16Array, Int32Array, Int64Array, Int8Array,
+    StringArray, UInt16Array, UInt32Array, UInt8Array,
+};
+use datafusion::arrow::compute::filter_record_batch;
+use datafusion::arrow::datatypes::{DataType, Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::logical_plan::Operator;
+use datafusion::physical_plan::coalesce_batches::CoalesceBatchesExec;
+use datafusion::physical_plan::expressions::{BinaryExpr, CastExpr, Column, Literal};
+use datafusion::physical_plan::filter::FilterExec;
+use datafusion::physical_plan::memory::MemoryExec;
+use datafusion::physical_plan::repartition::RepartitionExec;
+use datafusion::physical_plan::{collect, ExecutionPlan, PhysicalExpr};
+use datafusion::scalar::ScalarValue;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// The metadata key of the filters pushed down to the data source, i.e. a JSON
+/// array of [`SourceFilter`].
+pub const SOURCE_FILTER_METADATA_KEY: &str = "source_filter";
+
+/// A value of the native evaluation. The integers of all widths are compared
+/// as `Int`, and with the floats as `Float`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum FilterValue {
+    /// An integer.
+    Int(i64),
+    /// A floating-point number.
+    Float(f64),
+    /// A string.
+    Utf8(String),
+    /// A boolean.
+    Boolean(bool),
+}
+
+impl FilterValue {
+    /// Returns the value as a float, if it's a number.
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            FilterValue::Int(v) => Some(*v as f64),
+            FilterValue::Float(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    /// Compares the values of the same kind, or the numbers.
+    fn compare(&self, other: &FilterValue) -> Option<Ordering> {
+        match (self, other) {
+            (FilterValue::Int(a), FilterValue::Int(b)) => Some(a.cmp(b)),
+            (FilterValue::Utf8(a), FilterValue::Utf8(b)) => Some(a.cmp(b)),
+            (FilterValue::Boolean(a), FilterValue::Boolean(b)) => Some(a.cmp(b)),
+            (a, b) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        }
+    }
+}
+
+/// A comparison of two values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Comparison {
+    /// `=`
+    Eq,
+    /// `!=`
+    NotEq,
+    /// `<`
+    Lt,
+    /// `<=`
+    LtEq,
+    /// `>`
+    Gt,
+    /// `>=`
+    GtEq,
+}
+
+impl Comparison {
+    /// Returns the comparison of the operator, if it's one.
+    fn from_operator(op: &Operator) -> Option<Self> {
+        match op {
+            Operator::Eq => Some(Comparison::Eq),
+            Operator::NotEq => Some(Comparison::NotEq),
+            Operator::Lt => Some(Comparison::Lt),
+            Operator::LtEq => Some(Comparison::LtEq),
+            Operator::Gt => Some(Comparison::Gt),
+            Operator::GtEq => Some(Comparison::GtEq),
+            _ => None,
+        }
+    }
+
+    fn holds(&self, ordering: Ordering) -> bool {
+        match self {
+            Comparison::Eq => ordering == Ordering::Equal,
+            Comparison::NotEq => ordering != Ordering::Equal,
+            Comparison::Lt => ordering == Ordering::Less,
+            Comparison::LtEq => ordering != Ordering::Greater,
+            Comparison::Gt => ordering == Ordering::Greater,
+            Comparison::GtEq => ordering != Ordering::Less,
+        }
+    }
+}
+
+/// An expression of the subset evaluated natively by the data source.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "expr")]
+pub enum FilterExpr {
+    /// The column of the source with the name.
+    Column { name: String },
+    /// A constant.
+    Literal { value: FilterValue },
+    /// The remainder of the division of two numbers.
+    Modulo {
+        left:  Box<FilterExpr>,
+        right: Box<FilterExpr>,
+    },
+    /// The comparison of two values.
+    Compare {
+        op:    Comparison,
+        left:  Box<FilterExpr>,
+        right: Box<FilterExpr>,
+    },
+    /// The conjunction of two predicates.
+    And {
+        left:  Box<FilterExpr>,
+        right: Box<FilterExpr>,
+    },
+    /// The disjunction of two predicates.
+    Or {
+        left:  Box<FilterExpr>,
+        right: Box<FilterExpr>,
+    },
+}
+
+/// The closure that evaluates an expression on a row of a record batch. `None`
+/// is the SQL `NULL`.
+type Evaluator = Box<dyn Fn(&RecordBatch, usize) -> Option<FilterValue> + Send + Sync>;
+
+impl FilterExpr {
+    /// Translates the physical expression, if it's within the subset.
+    ///
+    /// # Arguments
+    /// * `expr` - The physical expression.
+    /// * `schema` - The input schema of the expression.
+    pub fn translate(expr: &Arc<dyn PhysicalExpr>, schema: &Schema) -> Option<Self> {
+        let any = expr.as_any();
+        if let Some(column) = any.downcast_ref::<Column>() {
+            return Some(FilterExpr::Column {
+                name: column.name().to_string(),
+            });
+        }
+        if let Some(literal) = any.downcast_ref::<Literal>() {
+            return Some(FilterExpr::Literal {
+                value: scalar_value(literal.value())?,
+            });
+        }
+        if let Some(cast) = any.downcast_ref::<CastExpr>() {
+            // The widening casts of the type coercion don't change the values.
+            let from = cast.expr().data_type(schema).ok()?;
+            return match (from, cast.cast_type()) {
+                (from, DataType::Int64) if is_integer(&from) => {
+                    FilterExpr::translate(cast.expr(), schema)
+                }
+                (from, DataType::Float64) if is_integer(&from) || from == DataType::Float32 => {
+                    FilterExpr::translate(cast.expr(), schema)
+                }
+                _ => None,
+            };
+        }
+        let binary = any.downcast_ref::<BinaryExpr>()?;
+        let left = Box::new(FilterExpr::translate(binary.left(), schema)?);
+        let right = Box::new(FilterExpr::translate(binary.right(), schema)?);
+        match binary.op() {
+            Operator::Modulo => Some(FilterExpr::Modulo { left, right }),
+            Operator::And => Some(FilterExpr::And { left, right }),
+            Operator::Or => Some(FilterExpr::Or { left, right }),
+            op => Some(FilterExpr::Compare {
+                op: Comparison::from_operator(op)?,
+                left,
+                right,
+            }),
+        }
+    }
+
+    /// Compiles the expression into a closure over the record batches of the
+    /// schema.
+    pub fn compile(&self, schema: &Schema) -> Result<Evaluator> {
+        let evaluator: Evaluator = match self {
+            FilterExpr::Column { name } => {
+                let index = schema.index_of(name)?;
+                column_reader(index, schema.field(index).data_type())?
+            }
+            FilterExpr::Literal { value } => {
+                let value = value.clone();
+                Box::new(move |_: &RecordBatch, _: usize| Some(value.clone()))
+            }
+            FilterExpr::Modulo { left, right } => {
+                let (left, right) = (left.compile(schema)?, right.compile(schema)?);
+                Box::new(move |batch: &RecordBatch, row: usize| {
+                    match (left(batch, row)?, right(batch, row)?) {
+                        (FilterValue::Int(a), FilterValue::Int(b)) => {
+                            a.checked_rem(b).map(FilterValue::Int)
+                        }
+                        (a, b) => Some(FilterValue::Float(a.as_f64()? % b.as_f64()?)),
+                    }
+                })
+            }
+            FilterExpr::Compare { op, left, right } => {
+                let (op, left, right) = (*op, left.compile(schema)?, right.compile(schema)?);
+                Box::new(move |batch: &RecordBatch, row: usize| {
+                    let ordering = left(batch, row)?.compare(&right(batch, row)?)?;
+                    Some(FilterValue::Boolean(op.holds(ordering)))
+                })
+            }
+            FilterExpr::And { left, right } => {
+                let (left, right) = (left.compile(schema)?, right.compile(schema)?);
+                Box::new(move |batch: &RecordBatch, row: usize| {
+                    match (boolean(left(batch, row)), boolean(right(batch, row))) {
+                        (Some(false), _) | (_, Some(false)) => Some(FilterValue::Boolean(false)),
+                        (Some(true), Some(true)) => Some(FilterValue::Boolean(true)),
+                        _ => None,
+                    }
+                })
+            }
+            FilterExpr::Or { left, right } => {
+                let (left, right) = (left.compile(schema)?, right.compile(schema)?);
+                Box::new(move |batch: &RecordBatch, row: usize| {
+                    match (boolean(left(batch, row)), boolean(right(batch, row))) {
+                        (Some(true), _) | (_, Some(true)) => Some(FilterValue::Boolean(true)),
+                        (Some(false), Some(false)) => Some(FilterValue::Boolean(false)),
+                        _ => None,
+                    }
+                })
+            }
+        };
+        Ok(evaluator)
+    }
+}
+
+/// Returns the boolean of the value, if it's one.
+fn boolean(value: Option<FilterValue>) -> Option<bool> {
+    match value {
+        Some(FilterValue::Boolean(b)) => Some(b),
+        _ => None,
+    }
+}
+
+fn is_integer(data_type: &DataType) -> bool {
+    matches!(
+        data_type,
+        DataType::Int8
+            | DataType::Int16
+            | DataType::Int32
+            | DataType::Int64
+            | DataType::UInt8
+            | DataType::UInt16
+            | DataType::UInt32
+    )
+}
+
+/// Returns the value of the constant, if it's of a supported type.
+fn scalar_value(value: &ScalarValue) -> Option<FilterValue> {
+    match value {
+        ScalarValue::Int8(Some(v)) => Some(FilterValue::Int(*v as i64)),
+        ScalarValue::Int16(Some(v)) => Some(FilterValue::Int(*v as i64)),
+        ScalarValue::Int32(Some(v)) => Some(FilterValue::Int(*v as i64)),
+        ScalarValue::Int64(Some(v)) => Some(FilterValue::Int(*v)),
+        ScalarValue::UInt8(Some(v)) => Some(FilterValue::Int(*v as i64)),
+        ScalarValue::UInt16(Some(v)) => Some(FilterValue::Int(*v as i64)),
+        ScalarValue::UInt32(Some(v)) => Some(FilterValue::Int(*v as i64)),
+        ScalarValue::Float32(Some(v)) => Some(FilterValue::Float(*v as f64)),
+        ScalarValue::Float64(Some(v)) => Some(FilterValue::Float(*v)),
+        ScalarValue::Utf8(Some(v)) => Some(FilterValue::Utf8(v.clone())),
+        ScalarValue::Boolean(Some(v)) => Some(FilterValue::Boolean(*v)),
+        _ => None,
+    }
+}
+
+/// Returns the closure that reads the column at the index.
+fn column_reader(index: usize, data_type: &DataType) -> Result<Evaluator> {
+    macro_rules! reader {
+        ($array:ty, $variant:ident, $native:ty) => {
+            Box::new(move |batch: &RecordBatch, row: usize| {
+                let array = batch.column(index).as_any().downcast_ref::<$array>()?;
+                (!array.is_null(row)).then(|| FilterValue::$variant(array.value(row) as $native))
+            }) as Evaluator
+        };
+    }
+    let reader: Evaluator = match data_type {
+        DataType::Int8 => reader!(Int8Array, Int, i64),
+        DataType::Int16 => reader!(Int16Array, Int, i64),
+        DataType::Int32 => reader!(Int32Array, Int, i64),
+        DataType::Int64 => reader!(Int64Array, Int, i64),
+        DataType::UInt8 => reader!(UInt8Array, Int, i64),
+        DataType::UInt16 => reader!(UInt16Array, Int, i64),
+        DataType::UInt32 => reader!(UInt32Array, Int, i64),
+        DataType::Float32 => reader!(Float32Array, Float, f64),
+        DataType::Float64 => reader!(Float64Array, Float, f64),
+        DataType::Boolean => Box::new(move |batch: &RecordBatch, row: usize| {
+            let array = batch
+                .column(index)
+                .as_any()
+                .downcast_ref::<BooleanArray>()?;
+            (!array.is_null(row)).then(|| FilterValue::Boolean(array.value(row)))
+        }),
+        DataType::Utf8 => Box::new(move |batch: &RecordBatch, row: usize| {
+            let array = batch.column(index).as_any().downcast_ref::<StringArray>()?;
+            (!array.is_null(row)).then(|| FilterValue::Utf8(array.value(row).to_string()))
+        }),
+        data_type => {
+            return Err(FlockError::Execution(format!(
+                "The column {} of {:?} can't be filtered natively",
+                index, data_type
+            )))
+        }
+    };
+    Ok(reader)
+}
+
+/// How a pushed-down predicate is evaluated.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum Predicate {
+    /// The predicate within the native subset.
+    Native(FilterExpr),
+    /// The serialized filter of the first stage over an empty memory leaf of
+    /// the source schema, which is fed the events of the window.
+    Plan(String),
+}
+
+/// The predicate pushed down to the data source for the relation of a schema.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SourceFilter {
+    /// The fingerprint of the schema of the filtered relation (see
+    /// [`schema_fingerprint`]).
+    pub fingerprint: String,
+    /// The predicate that the rows of the relation must satisfy.
+    pub predicate:   Predicate,
+}
+
+impl SourceFilter {
+    /// Returns the filter of the predicate over the relation of the schema.
+    pub fn try_new(predicate: &Arc<dyn PhysicalExpr>, schema: SchemaRef) -> Result<Self> {
+        let predicate = match FilterExpr::translate(predicate, &schema) {
+            Some(expr) => Predicate::Native(expr),
+            None => {
+                let leaf = Arc::new(MemoryExec::try_new(&[vec![]], schema.clone(), None)?);
+                let plan: Arc<dyn ExecutionPlan> =
+                    Arc::new(FilterExec::try_new(predicate.clone(), leaf)?);
+                Predicate::Plan(serde_json::to_string(&plan)?)
+            }
+        };
+        Ok(SourceFilter {
+            fingerprint: schema_fingerprint(&schema),
+            predicate,
+        })
+    }
+
+    /// Returns the filters that can be pushed down from the plans of the first
+    /// stage to the data source.
+    ///
+    /// A filter is pushed down if it's above a memory leaf, with at most the
+    /// repartitions and the coalesced batches in between, which keep the
+    /// schema of the leaf. If several leaves have the same schema, e.g. in a
+    /// self-join, the filter is only pushed down if all of them have the same
+    /// filter.
+    pub fn detect(plans: &[Arc<dyn ExecutionPlan>]) -> Result<Vec<SourceFilter>> {
+        fn visit(
+            plan: &Arc<dyn ExecutionPlan>,
+            filter: Option<&Arc<dyn PhysicalExpr>>,
+            leaves: &mut Vec<(SchemaRef, Option<Arc<dyn PhysicalExpr>>)>,
+        ) {
+            let any = plan.as_any();
+            if any.downcast_ref::<MemoryExec>().is_some() {
+                leaves.push((plan.schema(), filter.cloned()));
+            } else if let Some(exec) = any.downcast_ref::<FilterExec>() {
+                visit(exec.input(), Some(exec.predicate()), leaves);
+            } else if any.downcast_ref::<RepartitionExec>().is_some()
+                || any.downcast_ref::<CoalesceBatchesExec>().is_some()
+            {
+                plan.children()
+                    .iter()
+                    .for_each(|child| visit(child, filter, leaves));
+            } else {
+                plan.children()
+                    .iter()
+                    .for_each(|child| visit(child, None, leaves));
+            }
+        }
+
+        let mut leaves = vec![];
+        plans.iter().for_each(|plan| visit(plan, None, &mut leaves));
+
+        let mut filters: BTreeMap<String, Option<SourceFilter>> = BTreeMap::new();
+        for (schema, predicate) in leaves {
+            let filter = match predicate {
+                Some(predicate) => Some(SourceFilter::try_new(&predicate, schema.clone())?),
+                None => None,
+            };
+            let fingerprint = schema_fingerprint(&schema);
+            match filters.get(&fingerprint) {
+                Some(other) if *other != filter => {
+                    filters.insert(fingerprint, None);
+                }
+                Some(_) => {}
+                None => {
+                    filters.insert(fingerprint, filter);
+                }
+            }
+        }
+        Ok(filters.into_iter().filter_map(|(_, f)| f).collect())
+    }
+
+    /// Returns true if the filter applies to the relation of the schema.
+    pub fn matches(&self, schema: &Schema) -> bool {
+        self.fingerprint == schema_fingerprint(schema)
+    }
+
+    /// Returns the rows of the batches that satisfy the predicate, without the
+    /// empty batches, unless all of them are empty, in which case an empty
+    /// batch keeps the schema of the relation.
+    pub async fn filter(&self, batches: Vec<RecordBatch>) -> Result<Vec<RecordBatch>> {
+        let schema = match batches.first() {
+            Some(batch) => batch.schema(),
+            None => return Ok(batches),
+        };
+        let filtered = match &self.predicate {
+            Predicate::Native(expr) => {
+                let evaluate = expr.compile(&schema)?;
+                batches
+                    .iter()
+                    .map(|batch| {
+                        let mask = (0..batch.num_rows())
+                            .map(|row| evaluate(batch, row) == Some(FilterValue::Boolean(true)))
+                            .collect::<Vec<_>>();
+                        Ok(filter_record_batch(batch, &BooleanArray::from(mask))?)
+                    })
+                    .collect::<Result<Vec<_>>>()?
+            }
+            Predicate::Plan(plan) => {
+                let plan: Arc<dyn ExecutionPlan> = serde_json::from_str(plan)?;
+                feed_memory_sources(vec![plan.clone()], vec![vec![batches]])?;
+                collect(plan).await?
+            }
+        };
+        let mut batches = filtered
+            .into_iter()
+            .filter(|batch| batch.num_rows() > 0)
+            .collect::<Vec<_>>();
+        if batches.is_empty() {
+            batches.push(RecordBatch::new_empty(schema));
+        }
+        Ok(batches)
+    }
+}
+
+/// Stamps the filters into the metadata of the payload that starts the data
+/// source function.
+pub fn stamp(filters: &[SourceFilter], metadata: &mut Option<QueryMetadata>) -> Result<()> {
+    if !filters.is_empty() {
+        metadata.get_or_insert_with(QueryMetadata::default).insert(
+            SOURCE_FILTER_METADATA_KEY.to_string(),
+            serde_json::to_string(filters)?,
+        );
+    }
+    Ok(())
+}
+
+/// Returns the filters in the metadata of the data source function. The
+/// malformed filters are ignored with a warning, since the first stage keeps
+/// its filters anyway.
+pub fn from_metadata(metadata: &Option<QueryMetadata>) -> Vec<SourceFilter> {
+    let value = match metadata
+        .as_ref()
+        .and_then(|m| m.get(SOURCE_FILTER_METADATA_KEY))
+    {
+        Some(value) => value,
+        None => return vec![],
+    };
+    serde_json::from_str(value).unwrap_or_else(|e| {
+        warn!("Ignored the malformed source filters: {}", e);
+        vec![]
+    })
+}
+
+/// Filters the batches of a relation by the filter of its schema, if any.
+pub async fn filter_batches(
+    filters: &[SourceFilter],
+    batches: Vec<RecordBatch>,
+) -> Result<Vec<RecordBatch>> {
+    let filter = match batches.first() {
+        Some(batch) => filters.iter().find(|f| f.matches(&batch.schema())),
+        None => None,
+    };
+    match filter {
+        Some(filter) => filter.filter(batches).await,
+        None => Ok(batches),
+    }
+}
+
+/// Filters the partitions of a relation by the filter of its schema, if any.
+/// The partitions left without rows are dropped, except the first one, which
+/// keeps the schema of the relation.
+pub async fn filter_partitions(
+    filters: &[SourceFilter],
+    partitions: Vec<Vec<RecordBatch>>,
+) -> Result<Vec<Vec<RecordBatch>>> {
+    if filters.is_empty() || partitions.is_empty() {
+        return Ok(partitions);
+    }
+    let rows = |partitions: &[Vec<RecordBatch>]| {
+        partitions
+            .iter()
+            .flatten()
+            .map(|b| b.num_rows())
+            .sum::<usize>()
+    };
+    let before = rows(&partitions);
+    let mut filtered = vec![];
+    for partition in partitions {
+        filtered.push(filter_batches(filters, partition).await?);
+    }
+    let first = filtered[0].clone();
+    let mut filtered = filtered
+        .into_iter()
+        .filter(|p| p.iter().any(|b| b.num_rows() > 0))
+        .collect::<Vec<_>>();
+    if filtered.is_empty() {
+        filtered.push(first);
+    }
+    info!(
+        "[OK] The source filters kept {} of {} rows.",
+        rows(&filtered),
+        before
+    );
+    Ok(filtered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasource::nexmark::register_nexmark_tables;
+    use crate::datasource::nexmark::NEXMarkSource;
+    use crate::datasource::DataStream;
+    use crate::runtime::payload::UuidBuilder;
+    use crate::runtime::plan::physical_plan;
+    use crate::stream::Window;
+    use crate::transmute::to_payload;
+    use datafusion::arrow::util::pretty::pretty_format_batches;
+
+    /// Returns the bids of the first second of the NEXMark stream.
+    fn bids() -> Result<Vec<Vec<RecordBatch>>> {
+        let stream = NEXMarkSource::new(1, 1, 10_000, Window::ElementWise).generate_data()?;
+        Ok(stream.select_event_to_batches(0, 0, Some(2), false)?.0)
+    }
+
+    /// Returns the sorted rows of the plan over the partitions.
+    async fn execute(
+        plan: &Arc<dyn ExecutionPlan>,
+        partitions: Vec<Vec<RecordBatch>>,
+    ) -> Result<Vec<String>> {
+        feed_memory_sources(vec![plan.clone()], vec![partitions])?;
+        let output = collect(plan.clone()).await?;
+        let mut rows = pretty_format_batches(&output)?
+            .lines()
+            .map(|l| l.to_string())
+            .collect::<Vec<_>>();
+        rows.sort();
+        Ok(rows)
+    }
+
+    /// Returns the bytes of the payloads of the partitions.
+    fn payload_bytes(partitions: &[Vec<RecordBatch>]) -> Result<usize> {
+        let uuid = UuidBuilder::new_with_ts("q2-00", 0, 1).next_uuid();
+        let mut bytes = 0;
+        for partition in partitions {
+            bytes += serde_json::to_vec(&to_payload(partition, &[], uuid.clone(), false)?)?.len();
+        }
+        Ok(bytes)
+    }
+
+    #[tokio::test]
+    async fn push_q2_filter_to_source() -> Result<()> {
+        let ctx = register_nexmark_tables().await?;
+        let plan = physical_plan(
+            &ctx,
+            include_str!("../../../benchmarks/src/nexmark/query/q2.sql"),
+        )
+        .await?;
+        let filters = SourceFilter::detect(&[plan.clone()])?;
+        assert_eq!(filters.len(), 1);
+        assert!(matches!(filters[0].predicate, Predicate::Native(_)));
+
+        // The filters survive the metadata of the data source.
+        let mut metadata = None;
+        stamp(&filters, &mut metadata)?;
+        assert_eq!(from_metadata(&metadata), filters);
+
+        let unpushed = bids()?;
+        let pushed = filter_partitions(&filters, unpushed.clone()).await?;
+        assert_eq!(
+            execute(&plan, unpushed.clone()).await?,
+            execute(&plan, pushed.clone()).await?
+        );
+
+        // Q2 keeps a bid out of 123.
+        let (before, after) = (payload_bytes(&unpushed)?, payload_bytes(&pushed)?);
+        println!(
+            "q2 payloads: {} bytes in {} payloads unpushed, {} bytes in {} payloads pushed",
+            before,
+            unpushed.len(),
+            after,
+            pushed.len()
+        );
+        assert!(after * 10 < before);
+
+        // Both filters of the q3 join are pushed down, but no filter of q1.
+        let plan = physical_plan(
+            &ctx,
+            include_str!("../../../benchmarks/src/nexmark/query/q3.sql"),
+        )
+        .await?;
+        assert_eq!(SourceFilter::detect(&[plan])?.len(), 2);
+        let plan = physical_plan(
+            &ctx,
+            include_str!("../../../benchmarks/src/nexmark/query/q1.sql"),
+        )
+        .await?;
+        assert!(SourceFilter::detect(&[plan])?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fall_back_to_filter_plan() -> Result<()> {
+        let ctx = register_nexmark_tables().await?;
+        let plan = physical_plan(
+            &ctx,
+            "SELECT auction, price FROM bid WHERE auction * 2 + bidder > 2000",
+        )
+        .await?;
+        let filters = SourceFilter::detect(&[plan.clone()])?;
+        assert_eq!(filters.len(), 1);
+        assert!(matches!(filters[0].predicate, Predicate::Plan(_)));
+
+        let unpushed = bids()?;
+        let pushed = filter_partitions(&filters, unpushed.clone()).await?;
+        assert_eq!(
+            execute(&plan, unpushed).await?,
+            execute(&plan, pushed).await?
+        );
+
+        // The relations of the other schemas aren't filtered.
+        let plan = physical_plan(&ctx, "SELECT * FROM person").await?;
+        assert!(!filters[0].matches(&plan.schema()));
+        Ok(())
+    }
+}
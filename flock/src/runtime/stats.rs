@@ -0,0 +1,493 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! This module contains the cheap statistics of the record batches in the
+//! payloads, which tell the receiving function the cardinality of its input
+//! before executing the plan.
+//!
+//! The statistics are computed by [`to_payload`](crate::transmute::to_payload)
+//! if `payload_stats` is enabled in the configuration. The distinct counts of
+//! the key columns, i.e. the group-by and join columns of the next stage (see
+//! [`stats_keys`](crate::runtime::plan::stats_keys)), are estimated with
+//! HyperLogLog sketches, which are merged across the payloads of a window.
+
+use crate::configs::*;
+use datafusion::arrow::array::*;
+use datafusion::arrow::compute::{max, min};
+use datafusion::arrow::datatypes::*;
+use datafusion::arrow::record_batch::RecordBatch;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// The number of index bits of the HyperLogLog sketches. 256 registers give a
+/// standard error of about 6.5%, which is enough to tell a few groups from
+/// many groups.
+const HLL_PRECISION: u32 = 8;
+
+/// The number of registers of the HyperLogLog sketches.
+const HLL_REGISTERS: usize = 1 << HLL_PRECISION;
+
+/// A HyperLogLog sketch to estimate the number of distinct values.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog {
+            registers: vec![0; HLL_REGISTERS],
+        }
+    }
+}
+
+impl HyperLogLog {
+    /// Creates an empty sketch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a value to the sketch.
+    pub fn insert<T: Hash + ?Sized>(&mut self, value: &T) {
+        // `DefaultHasher::new` uses fixed keys, so the sketches of different
+        // functions can be merged.
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+        let index = (hash >> (64 - HLL_PRECISION)) as usize;
+        // The sentinel bit bounds the rank if the remaining bits are all zeros.
+        let rank = ((hash << HLL_PRECISION) | (1 << (HLL_PRECISION - 1))).leading_zeros() + 1;
+        if let Some(register) = self.registers.get_mut(index) {
+            *register = (*register).max(rank as u8);
+        }
+    }
+
+    /// Merges another sketch into this one.
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        self.registers
+            .iter_mut()
+            .zip(other.registers.iter())
+            .for_each(|(a, b)| *a = (*a).max(*b));
+    }
+
+    /// Returns the estimated number of distinct values.
+    pub fn estimate(&self) -> usize {
+        let m = HLL_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum = self
+            .registers
+            .iter()
+            .map(|&r| 2f64.powi(-(r as i32)))
+            .sum::<f64>();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            // Linear counting is more accurate for the small cardinalities.
+            (m * (m / zeros as f64).ln()).round() as usize
+        } else {
+            estimate.round() as usize
+        }
+    }
+}
+
+/// The statistics of a column.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ColumnStats {
+    /// The column name.
+    pub name:       String,
+    /// The number of null values.
+    pub null_count: usize,
+    /// The distinct values of a key column. `None` if the column isn't a key
+    /// column of the next stage, or its data type isn't supported.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distinct:   Option<HyperLogLog>,
+}
+
+/// The statistics of the record batches of a relation in the payload.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PayloadStats {
+    /// The number of rows.
+    pub num_rows:   usize,
+    /// Whether the distinct values are sampled from the batches, in which case
+    /// the distinct counts are underestimated.
+    pub sampled:    bool,
+    /// The statistics of the columns.
+    pub columns:    Vec<ColumnStats>,
+    /// The minimum and maximum event time in milliseconds, i.e. the first
+    /// millisecond timestamp column of the relation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_time: Option<(i64, i64)>,
+}
+
+impl PayloadStats {
+    /// Computes the statistics of the record batches. Returns `None` if there
+    /// are no batches.
+    ///
+    /// # Arguments
+    /// * `batches` - The record batches of a relation.
+    /// * `keys` - The key columns whose distinct counts are estimated.
+    /// * `sample_rows` - The maximum number of rows sampled from each batch to
+    ///   estimate the distinct counts. The row and null counts and the event
+    ///   time are always computed on the whole batch.
+    pub fn new(batches: &[RecordBatch], keys: &[String], sample_rows: usize) -> Option<Self> {
+        let schema = batches.first()?.schema();
+        let time_column = schema
+            .fields()
+            .iter()
+            .position(|f| matches!(f.data_type(), DataType::Timestamp(TimeUnit::Millisecond, _)));
+
+        let mut stats = PayloadStats {
+            columns: schema
+                .fields()
+                .iter()
+                .map(|f| ColumnStats {
+                    name:       f.name().clone(),
+                    null_count: 0,
+                    distinct:   if is_key(f.name(), keys) {
+                        Some(HyperLogLog::new())
+                    } else {
+                        None
+                    },
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        for batch in batches {
+            stats.num_rows += batch.num_rows();
+            // The rows beyond the threshold are sampled evenly to cap the cost.
+            let step = batch.num_rows().saturating_sub(1) / sample_rows.max(1) + 1;
+            stats.sampled |= step > 1;
+            for (column, array) in stats.columns.iter_mut().zip(batch.columns()) {
+                column.null_count += array.null_count();
+                let supported = match column.distinct.as_mut() {
+                    Some(hll) => insert_values(hll, array, step),
+                    None => true,
+                };
+                if !supported {
+                    column.distinct = None;
+                }
+            }
+            if let Some(i) = time_column {
+                let array = as_primitive_array::<TimestampMillisecondType>(batch.column(i));
+                if let (Some(lo), Some(hi)) = (min(array), max(array)) {
+                    stats.event_time = merge_range(stats.event_time, Some((lo, hi)));
+                }
+            }
+        }
+
+        Some(stats)
+    }
+
+    /// Merges the statistics of another payload of the same relation. The
+    /// distinct counts of a column are only kept if both sides estimate them.
+    pub fn merge(&mut self, other: &PayloadStats) {
+        if self.columns.is_empty() {
+            self.columns = other.columns.clone();
+        } else {
+            for column in self.columns.iter_mut() {
+                match other.columns.iter().find(|c| c.name == column.name) {
+                    Some(c) => {
+                        column.null_count += c.null_count;
+                        match (column.distinct.as_mut(), c.distinct.as_ref()) {
+                            (Some(a), Some(b)) => a.merge(b),
+                            _ => column.distinct = None,
+                        }
+                    }
+                    None => column.distinct = None,
+                }
+            }
+        }
+        self.num_rows += other.num_rows;
+        self.sampled |= other.sampled;
+        self.event_time = merge_range(self.event_time, other.event_time);
+    }
+
+    /// Returns the estimated number of distinct values of the column, or
+    /// `None` if the column isn't a key column.
+    pub fn distinct_count(&self, column: &str) -> Option<usize> {
+        self.columns
+            .iter()
+            .find(|c| is_key(&c.name, &[column.to_string()]))
+            .and_then(|c| c.distinct.as_ref())
+            .map(|hll| hll.estimate().min(self.num_rows))
+    }
+
+    /// Returns the estimated number of groups, i.e. the distinct combinations
+    /// of the key columns. It's bounded by the number of rows. Returns `None`
+    /// if no distinct counts are estimated.
+    pub fn estimated_groups(&self) -> Option<usize> {
+        let counts = self
+            .columns
+            .iter()
+            .filter_map(|c| c.distinct.as_ref())
+            .map(|hll| hll.estimate().max(1))
+            .collect::<Vec<_>>();
+        if counts.is_empty() {
+            return None;
+        }
+        Some(
+            counts
+                .into_iter()
+                .fold(1_usize, |acc, n| acc.saturating_mul(n))
+                .min(self.num_rows),
+        )
+    }
+
+    /// Returns true if the estimated number of groups exceeds the threshold,
+    /// in which case the input is better processed by the distributed
+    /// execution. The unknown group count doesn't exceed any threshold.
+    pub fn exceeds_group_threshold(&self, threshold: usize) -> bool {
+        self.estimated_groups()
+            .map_or(false, |groups| groups > threshold)
+    }
+
+    /// Returns true if pre-aggregating the input, e.g. with a combiner before
+    /// the shuffle, reduces the number of rows at least by the given factor.
+    pub fn is_reducible(&self, factor: f64) -> bool {
+        self.estimated_groups().map_or(false, |groups| {
+            groups as f64 * factor <= self.num_rows as f64
+        })
+    }
+}
+
+/// Computes the statistics of the record batches if `payload_stats` is enabled
+/// in the configuration.
+///
+/// # Arguments
+/// * `batches` - The record batches of a relation.
+/// * `keys` - The key columns of the next stage.
+pub fn payload_stats(batches: &[RecordBatch], keys: &[String]) -> Option<PayloadStats> {
+    if *FLOCK_PAYLOAD_STATS {
+        PayloadStats::new(batches, keys, *FLOCK_STATS_SAMPLE_ROWS)
+    } else {
+        None
+    }
+}
+
+/// Returns true if the column is one of the keys. The keys may be qualified by
+/// the relation name.
+fn is_key(column: &str, keys: &[String]) -> bool {
+    keys.iter()
+        .any(|k| k == column || k.rsplit('.').next() == Some(column))
+}
+
+/// Returns the union of two ranges.
+fn merge_range(a: Option<(i64, i64)>, b: Option<(i64, i64)>) -> Option<(i64, i64)> {
+    match (a, b) {
+        (Some((lo1, hi1)), Some((lo2, hi2))) => Some((lo1.min(lo2), hi1.max(hi2))),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+/// Adds every `step`th value of the array to the sketch. Returns false if the
+/// data type isn't supported.
+fn insert_values(hll: &mut HyperLogLog, array: &ArrayRef, step: usize) -> bool {
+    macro_rules! insert_primitive {
+        ($ARROW_TYPE:ty) => {{
+            let array = as_primitive_array::<$ARROW_TYPE>(array);
+            (0..array.len())
+                .step_by(step)
+                .filter(|&i| array.is_valid(i))
+                .for_each(|i| hll.insert(&array.value(i)));
+        }};
+    }
+
+    match array.data_type() {
+        DataType::Int8 => insert_primitive!(Int8Type),
+        DataType::Int16 => insert_primitive!(Int16Type),
+        DataType::Int32 => insert_primitive!(Int32Type),
+        DataType::Int64 => insert_primitive!(Int64Type),
+        DataType::UInt8 => insert_primitive!(UInt8Type),
+        DataType::UInt16 => insert_primitive!(UInt16Type),
+        DataType::UInt32 => insert_primitive!(UInt32Type),
+        DataType::UInt64 => insert_primitive!(UInt64Type),
+        DataType::Date32 => insert_primitive!(Date32Type),
+        DataType::Date64 => insert_primitive!(Date64Type),
+        DataType::Timestamp(TimeUnit::Second, _) => insert_primitive!(TimestampSecondType),
+        DataType::Timestamp(TimeUnit::Millisecond, _) => {
+            insert_primitive!(TimestampMillisecondType)
+        }
+        DataType::Timestamp(TimeUnit::Microsecond, _) => {
+            insert_primitive!(TimestampMicrosecondType)
+        }
+        DataType::Timestamp(TimeUnit::Nanosecond, _) => {
+            insert_primitive!(TimestampNanosecondType)
+        }
+        DataType::Boolean => {
+            let array = as_boolean_array(array);
+            (0..array.len())
+                .step_by(step)
+                .filter(|&i| array.is_valid(i))
+                .for_each(|i| hll.insert(&array.value(i)));
+        }
+        DataType::Utf8 => {
+            let array = as_string_array(array);
+            (0..array.len())
+                .step_by(step)
+                .filter(|&i| array.is_valid(i))
+                .for_each(|i| hll.insert(array.value(i)));
+        }
+        DataType::LargeUtf8 => {
+            let array = as_largestring_array(array);
+            (0..array.len())
+                .step_by(step)
+                .filter(|&i| array.is_valid(i))
+                .for_each(|i| hll.insert(array.value(i)));
+        }
+        _ => return false,
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Result;
+    use std::sync::Arc;
+
+    /// Returns a batch of `n` rows, whose key column has `groups` distinct
+    /// values and a null every 10 rows.
+    fn batch(n: usize, groups: usize, start: i64) -> Result<RecordBatch> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("auction", DataType::Int32, true),
+            Field::new("bidder", DataType::Utf8, false),
+            Field::new(
+                "b_date_time",
+                DataType::Timestamp(TimeUnit::Millisecond, None),
+                false,
+            ),
+        ]));
+        let auction = Int32Array::from(
+            (0..n)
+                .map(|i| {
+                    if i % 10 == 9 {
+                        None
+                    } else {
+                        Some((i % groups) as i32)
+                    }
+                })
+                .collect::<Vec<_>>(),
+        );
+        let bidder = StringArray::from((0..n).map(|i| format!("bidder-{}", i)).collect::<Vec<_>>());
+        let time =
+            TimestampMillisecondArray::from((0..n).map(|i| start + i as i64).collect::<Vec<_>>());
+        Ok(RecordBatch::try_new(
+            schema,
+            vec![Arc::new(auction), Arc::new(bidder), Arc::new(time)],
+        )?)
+    }
+
+    fn assert_close(estimate: usize, expected: usize) {
+        let error = (estimate as f64 - expected as f64).abs() / expected as f64;
+        assert!(
+            error < 0.2,
+            "estimate {} is not close to {}",
+            estimate,
+            expected
+        );
+    }
+
+    #[test]
+    fn hyperloglog_estimate() {
+        let mut hll = HyperLogLog::new();
+        assert_eq!(hll.estimate(), 0);
+
+        (0..10).cycle().take(1000).for_each(|i| hll.insert(&i));
+        assert_close(hll.estimate(), 10);
+
+        let mut a = HyperLogLog::new();
+        let mut b = HyperLogLog::new();
+        (0..20_000_i64).for_each(|i| a.insert(&i));
+        (10_000..30_000_i64).for_each(|i| b.insert(&i));
+        assert_close(a.estimate(), 20_000);
+        a.merge(&b);
+        assert_close(a.estimate(), 30_000);
+    }
+
+    #[test]
+    fn stats_of_batches() -> Result<()> {
+        let keys = vec!["auction".to_string(), "bid.bidder".to_string()];
+        let batches = vec![batch(1000, 50, 0)?, batch(1000, 50, 5000)?];
+        let stats = PayloadStats::new(&batches, &keys, usize::MAX).unwrap();
+
+        assert_eq!(stats.num_rows, 2000);
+        assert!(!stats.sampled);
+        assert_eq!(stats.columns[0].null_count, 200);
+        assert_eq!(stats.columns[1].null_count, 0);
+        assert_close(stats.distinct_count("auction").unwrap(), 50);
+        assert_close(stats.distinct_count("bidder").unwrap(), 1000);
+        assert_eq!(stats.distinct_count("b_date_time"), None);
+        assert_eq!(stats.event_time, Some((0, 5999)));
+
+        // The distinct values are sampled beyond the threshold, but the other
+        // statistics are exact.
+        let sampled = PayloadStats::new(&batches, &keys, 100).unwrap();
+        assert!(sampled.sampled);
+        assert_eq!(sampled.num_rows, 2000);
+        assert_eq!(sampled.columns[0].null_count, 200);
+        assert_eq!(sampled.event_time, Some((0, 5999)));
+        assert!(sampled.distinct_count("bidder").unwrap() < 200);
+
+        assert!(PayloadStats::new(&[], &keys, 100).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn merge_stats() -> Result<()> {
+        let keys = vec!["auction".to_string()];
+        let mut stats = PayloadStats::default();
+        stats.merge(&PayloadStats::new(&[batch(1000, 20, 100)?], &keys, usize::MAX).unwrap());
+        stats.merge(&PayloadStats::new(&[batch(500, 40, 0)?], &keys, usize::MAX).unwrap());
+
+        assert_eq!(stats.num_rows, 1500);
+        assert_eq!(stats.columns[0].null_count, 150);
+        assert_close(stats.distinct_count("auction").unwrap(), 40);
+        assert_eq!(stats.event_time, Some((0, 1099)));
+
+        // The distinct counts are dropped if a payload doesn't estimate them.
+        stats.merge(&PayloadStats::new(&[batch(10, 5, 0)?], &[], usize::MAX).unwrap());
+        assert_eq!(stats.distinct_count("auction"), None);
+        assert_eq!(stats.estimated_groups(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn strategy_by_distinct_count() -> Result<()> {
+        let keys = vec!["auction".to_string()];
+        let threshold = 100;
+
+        let few = PayloadStats::new(&[batch(5000, 10, 0)?], &keys, usize::MAX).unwrap();
+        assert_close(few.estimated_groups().unwrap(), 10);
+        assert!(!few.exceeds_group_threshold(threshold));
+        assert!(few.is_reducible(2.0));
+
+        let many = PayloadStats::new(&[batch(5000, 4000, 0)?], &keys, usize::MAX).unwrap();
+        assert!(many.exceeds_group_threshold(threshold));
+        assert!(!many.is_reducible(2.0));
+
+        // Multiple keys multiply the groups, bounded by the number of rows.
+        let keys = vec!["auction".to_string(), "bidder".to_string()];
+        let stats = PayloadStats::new(&[batch(5000, 10, 0)?], &keys, usize::MAX).unwrap();
+        assert_eq!(stats.estimated_groups(), Some(5000));
+        assert!(stats.exceeds_group_threshold(threshold));
+
+        // Without the distinct counts, the strategy doesn't change.
+        let unknown = PayloadStats::new(&[batch(5000, 4000, 0)?], &[], usize::MAX).unwrap();
+        assert!(!unknown.exceeds_group_threshold(threshold));
+        assert!(!unknown.is_reducible(2.0));
+        Ok(())
+    }
+}
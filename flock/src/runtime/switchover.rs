@@ -0,0 +1,425 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The blue/green switchover of a running query to a new dataflow.
+//!
+//! A query started by [`crate::api::run_query`] follows a [`Route`] named
+//! after its query code, i.e. the S3 object `<query code>/route.json`. The
+//! route points to the function set that the data source emits the windows
+//! to. To update the query, the new function set is deployed under a new
+//! query code, and the switched route is published. The data source polls
+//! the route before each window, and once the route changes, it hands its
+//! stream over to the source function of the new set at the window boundary,
+//! so that every window is processed by exactly one of the two sets.
+//!
+//! If the window boundaries can't be relied on, the route carries an overlap
+//! period. During the overlap, the old data source keeps emitting to the old
+//! set after the handover, i.e. the windows are written to both sets, and it
+//! stops once the overlap expires. The old set is torn down after its
+//! in-flight windows are drained.
+
+use crate::aws::client::{AwsCloudClient, CloudClient};
+use crate::configs::FLOCK_S3_BUCKET;
+use crate::error::Result;
+use crate::runtime::backpressure::RESUME_WINDOW_METADATA_KEY;
+use crate::runtime::context::CloudFunction;
+use crate::runtime::function_name::query_key;
+use crate::runtime::metadata::{QueryMetadata, WORKERS_METADATA_KEY};
+use crate::runtime::payload::Payload;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The metadata key of the route that the data source follows.
+pub const ROUTE_METADATA_KEY: &str = "route";
+
+/// The metadata key of the generation of the route that the data source
+/// emits to.
+pub const ROUTE_GENERATION_METADATA_KEY: &str = "route_generation";
+
+/// The metadata key of the time in milliseconds until which the replaced data
+/// source keeps emitting to the old function set.
+pub const DUAL_WRITE_METADATA_KEY: &str = "dual_write_until";
+
+/// The S3 key of the route under the prefix of the query.
+const ROUTE_KEY: &str = "route.json";
+
+/// The minimum interval between two polls of the route by a data source.
+const ROUTE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The function set that a route points to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RouteTarget {
+    /// The query code of the function set.
+    pub query_code: String,
+    /// The function that the data source is handed over to.
+    pub source:     String,
+    /// The next functions of the data source, which override the next
+    /// functions in its execution context if set.
+    pub workers:    Option<CloudFunction>,
+}
+
+impl RouteTarget {
+    /// Returns the function set deployed by [`crate::api::run_query`], whose
+    /// entry stage reads the data source itself.
+    pub fn entry(query_code: &str) -> Self {
+        Self {
+            query_code: query_code.to_owned(),
+            source:     format!("{}-{:02}", query_code, 0),
+            workers:    None,
+        }
+    }
+}
+
+/// The function set that the data source of a query emits to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Route {
+    /// The number of switchovers of the route.
+    pub generation:    u64,
+    /// The function set that receives the windows.
+    pub active:        RouteTarget,
+    /// The replaced function set, which is drained and torn down.
+    pub previous:      Option<RouteTarget>,
+    /// The time in milliseconds until which the windows are written to the
+    /// replaced function set as well.
+    pub overlap_until: i64,
+}
+
+impl Route {
+    /// Creates the route of a query that has never been updated.
+    pub fn new(active: RouteTarget) -> Self {
+        Self {
+            generation: 0,
+            active,
+            previous: None,
+            overlap_until: 0,
+        }
+    }
+
+    /// Returns the query code of the next function set of the route, e.g.
+    /// `q1_v2` for the second update of the query `q1`.
+    pub fn next_query_code(&self, name: &str) -> String {
+        format!("{}_v{}", name, self.generation + 1)
+    }
+
+    /// Returns the route switched to the given function set.
+    ///
+    /// # Arguments
+    /// * `target` - The new function set.
+    /// * `overlap` - The period that the windows are written to both sets.
+    /// * `now` - The current time in milliseconds.
+    pub fn switch(&self, target: RouteTarget, overlap: Duration, now: i64) -> Self {
+        Self {
+            generation:    self.generation + 1,
+            active:        target,
+            previous:      Some(self.active.clone()),
+            overlap_until: now + overlap.as_millis() as i64,
+        }
+    }
+
+    /// Returns the route whose data source emits to the function group of
+    /// the new size (see [`crate::runtime::scaling`]). The data source hands
+    /// itself over to the resized ring without an overlap, so every window is
+    /// hashed to the members of a single ring version.
+    ///
+    /// # Arguments
+    /// * `group` - The function group fed by the data source.
+    /// * `size` - The new size of the group.
+    /// * `now` - The current time in milliseconds.
+    pub fn resize(&self, group: &str, size: usize, now: i64) -> Self {
+        self.switch(
+            RouteTarget {
+                workers: Some(CloudFunction::Group((group.to_owned(), size))),
+                ..self.active.clone()
+            },
+            Duration::ZERO,
+            now,
+        )
+    }
+
+    /// Returns the payload that hands the data source over to the active
+    /// function set, resuming from the given window.
+    pub fn hand_off(&self, payload: &Payload, window: usize) -> Result<Payload> {
+        let mut payload = payload.clone();
+        let metadata = payload.metadata.get_or_insert_with(QueryMetadata::default);
+        metadata.insert(RESUME_WINDOW_METADATA_KEY.to_string(), window.to_string());
+        metadata.insert(
+            ROUTE_GENERATION_METADATA_KEY.to_string(),
+            self.generation.to_string(),
+        );
+        metadata.remove(DUAL_WRITE_METADATA_KEY);
+        match &self.active.workers {
+            Some(workers) => {
+                metadata.insert(
+                    WORKERS_METADATA_KEY.to_string(),
+                    serde_json::to_string(workers)?,
+                );
+            }
+            None => {
+                metadata.remove(WORKERS_METADATA_KEY);
+            }
+        }
+        Ok(payload)
+    }
+
+    /// Marks the metadata of the replaced data source, so that it neither
+    /// hands over again nor emits after the overlap once it's rescheduled.
+    pub fn retire(&self, metadata: &mut Option<QueryMetadata>) {
+        let metadata = metadata.get_or_insert_with(QueryMetadata::default);
+        metadata.insert(
+            ROUTE_GENERATION_METADATA_KEY.to_string(),
+            self.generation.to_string(),
+        );
+        metadata.insert(
+            DUAL_WRITE_METADATA_KEY.to_string(),
+            self.overlap_until.to_string(),
+        );
+    }
+}
+
+/// Returns the value of the metadata key parsed as a number.
+fn parse_metadata<T: std::str::FromStr>(metadata: &Option<QueryMetadata>, key: &str) -> Option<T> {
+    metadata
+        .as_ref()
+        .and_then(|m| m.get(key))
+        .and_then(|v| v.parse::<T>().ok())
+}
+
+/// The routes of the queries, which are the S3 objects `routes/<name>.json`.
+#[derive(Debug, Clone)]
+pub struct RouteTable {
+    client: Arc<dyn CloudClient>,
+    bucket: String,
+}
+
+impl Default for RouteTable {
+    fn default() -> Self {
+        Self::new(Arc::new(AwsCloudClient), &FLOCK_S3_BUCKET)
+    }
+}
+
+impl RouteTable {
+    /// Creates the route table in the given bucket.
+    pub fn new(client: Arc<dyn CloudClient>, bucket: &str) -> Self {
+        Self {
+            client,
+            bucket: bucket.to_string(),
+        }
+    }
+
+    fn key(name: &str) -> String {
+        query_key(name, ROUTE_KEY)
+    }
+
+    /// Returns the route of the query, or `None` if the query has never been
+    /// updated.
+    pub async fn lookup(&self, name: &str) -> Result<Option<Route>> {
+        let key = Self::key(name);
+        if !self
+            .client
+            .s3_list(&self.bucket, &key)
+            .await?
+            .iter()
+            .any(|k| *k == key)
+        {
+            return Ok(None);
+        }
+        let body = self.client.s3_get(&self.bucket, &key).await?;
+        Ok(Some(serde_json::from_slice(&body)?))
+    }
+
+    /// Publishes the route of the query. The S3 object is replaced at once, so
+    /// the data sources observe either the old or the new route.
+    pub async fn publish(&self, name: &str, route: &Route) -> Result<()> {
+        self.client
+            .s3_put(&self.bucket, &Self::key(name), serde_json::to_vec(route)?)
+            .await
+    }
+}
+
+/// The route followed by a data source function.
+#[derive(Debug)]
+pub struct RouteFollower {
+    name:             String,
+    generation:       u64,
+    dual_write_until: Option<i64>,
+    table:            RouteTable,
+    poll_interval:    Duration,
+    last_poll:        Option<Instant>,
+}
+
+impl RouteFollower {
+    /// Creates the follower of the route.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the route.
+    /// * `generation` - The generation of the route the data source emits to.
+    /// * `table` - The route table.
+    /// * `poll_interval` - The minimum interval between two polls.
+    pub fn new(name: &str, generation: u64, table: RouteTable, poll_interval: Duration) -> Self {
+        Self {
+            name: name.to_owned(),
+            generation,
+            dual_write_until: None,
+            table,
+            poll_interval,
+            last_poll: None,
+        }
+    }
+
+    /// Returns the follower of the route in the payload metadata, if the data
+    /// source follows a route.
+    pub fn from_metadata(metadata: &Option<QueryMetadata>) -> Option<Self> {
+        let name = metadata.as_ref()?.get(ROUTE_METADATA_KEY)?;
+        let mut follower = Self::new(
+            name,
+            parse_metadata(metadata, ROUTE_GENERATION_METADATA_KEY).unwrap_or(0),
+            RouteTable::default(),
+            ROUTE_POLL_INTERVAL,
+        );
+        follower.dual_write_until = parse_metadata(metadata, DUAL_WRITE_METADATA_KEY);
+        Some(follower)
+    }
+
+    /// Returns the route if it has been switched since the data source
+    /// started to emit to its function set. A retired data source doesn't
+    /// poll the route anymore.
+    pub async fn poll(&mut self) -> Result<Option<Route>> {
+        if self.dual_write_until.is_some()
+            || self
+                .last_poll
+                .map_or(false, |t| t.elapsed() < self.poll_interval)
+        {
+            return Ok(None);
+        }
+        self.last_poll = Some(Instant::now());
+        Ok(self
+            .table
+            .lookup(&self.name)
+            .await?
+            .filter(|route| route.generation > self.generation))
+    }
+
+    /// Retires the data source after it's handed over to the switched route.
+    pub fn retire(&mut self, route: &Route) {
+        self.generation = route.generation;
+        self.dual_write_until = Some(route.overlap_until);
+    }
+
+    /// Returns true if the data source is retired and its overlap has
+    /// expired, i.e. it must stop emitting.
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.dual_write_until.map_or(false, |until| now >= until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aws::client::FakeCloudClient;
+
+    #[test]
+    fn switch_routes() {
+        let route = Route::new(RouteTarget::entry("q1"));
+        assert_eq!(route.active.source, "q1-00");
+        assert_eq!(route.next_query_code("q1"), "q1_v1");
+
+        let switched = route.switch(RouteTarget::entry("q1_v1"), Duration::from_secs(5), 1000);
+        assert_eq!(switched.generation, 1);
+        assert_eq!(switched.active.query_code, "q1_v1");
+        assert_eq!(switched.previous, Some(route.active.clone()));
+        assert_eq!(switched.overlap_until, 6000);
+        assert_eq!(switched.next_query_code("q1"), "q1_v2");
+
+        let switched = switched.switch(RouteTarget::entry("q1_v2"), Duration::ZERO, 7000);
+        assert_eq!(switched.previous.unwrap().query_code, "q1_v1");
+        assert_eq!(switched.overlap_until, 7000);
+    }
+
+    #[test]
+    fn hand_off_and_retire() -> Result<()> {
+        let mut metadata = QueryMetadata::default();
+        metadata.insert(ROUTE_METADATA_KEY.to_string(), "q1".to_string());
+        metadata.insert(WORKERS_METADATA_KEY.to_string(), "stale".to_string());
+        let payload = Payload {
+            metadata: Some(metadata),
+            ..Default::default()
+        };
+
+        let workers = CloudFunction::Group(("q1_v1-00".to_string(), 8));
+        let route = Route::new(RouteTarget::entry("q1")).switch(
+            RouteTarget {
+                workers: Some(workers.clone()),
+                ..RouteTarget::entry("q1_v1")
+            },
+            Duration::from_secs(5),
+            1000,
+        );
+        let handed = route.hand_off(&payload, 3)?;
+        let m = handed.metadata.as_ref().unwrap();
+        assert_eq!(m.get(ROUTE_METADATA_KEY).unwrap(), "q1");
+        assert_eq!(m.get(RESUME_WINDOW_METADATA_KEY).unwrap(), "3");
+        assert_eq!(m.get(ROUTE_GENERATION_METADATA_KEY).unwrap(), "1");
+        assert_eq!(
+            serde_json::from_str::<CloudFunction>(m.get(WORKERS_METADATA_KEY).unwrap())?,
+            workers
+        );
+
+        // The entry stage of the deployed query reads its next functions from
+        // the execution context.
+        let route = route.switch(RouteTarget::entry("q1_v2"), Duration::ZERO, 2000);
+        let handed = route.hand_off(&handed, 7)?;
+        let m = handed.metadata.as_ref().unwrap();
+        assert!(m.get(WORKERS_METADATA_KEY).is_none());
+        assert_eq!(m.get(ROUTE_GENERATION_METADATA_KEY).unwrap(), "2");
+
+        let mut retired = payload.metadata;
+        route.retire(&mut retired);
+        let follower = RouteFollower::from_metadata(&retired).unwrap();
+        assert_eq!(follower.generation, 2);
+        assert!(!follower.is_expired(1999));
+        assert!(follower.is_expired(2000));
+        assert!(RouteFollower::from_metadata(&None).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn follow_switched_route() -> Result<()> {
+        let client = Arc::new(FakeCloudClient::new());
+        let table = RouteTable::new(client.clone(), "flock");
+        let mut follower = RouteFollower::new("q1", 0, table.clone(), Duration::ZERO);
+        assert_eq!(follower.poll().await?, None);
+
+        let route = Route::new(RouteTarget::entry("q1"));
+        table.publish("q1", &route).await?;
+        assert_eq!(table.lookup("q1").await?, Some(route.clone()));
+        assert_eq!(follower.poll().await?, None);
+
+        let route = route.switch(RouteTarget::entry("q1_v1"), Duration::from_secs(1), 0);
+        table.publish("q1", &route).await?;
+        assert_eq!(client.keys("flock"), vec!["q1/route.json"]);
+        assert_eq!(follower.poll().await?, Some(route.clone()));
+
+        // The retired data source doesn't hand over twice.
+        follower.retire(&route);
+        assert_eq!(follower.poll().await?, None);
+        assert!(!follower.is_expired(999));
+        assert!(follower.is_expired(1000));
+
+        // The polls are rate limited.
+        let mut follower = RouteFollower::new("q1", 0, table, Duration::from_secs(60));
+        assert!(follower.poll().await?.is_some());
+        assert_eq!(follower.poll().await?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The topology guard of the query stages.
+//!
+//! The function of a query stage only expects the payloads of its upstream
+//! stages, with the schemas of the leaves of its plan. A function pointed at
+//! the wrong successor, e.g. by the stale metadata of a data source, may still
+//! decode the payloads if their schemas happen to be compatible, and silently
+//! compute garbage. To fail fast instead, each function stamps its name into
+//! the metadata of the payloads it sends (see [`stamp_sender`]), and the
+//! context of the next stage carries the expected upstream stages and the
+//! fingerprints of the expected input schemas (see [`StageTopology`]), which
+//! are computed when the contexts are built.
+//!
+//! A mismatched payload is rejected with a [`PayloadError::Topology`] error
+//! naming both stages, unless the topology only warns about the mismatches,
+//! e.g. in the deployments that intentionally feed a stage from several
+//! queries. The payloads without a sender, e.g. of the older functions, are
+//! only checked by their schemas.
+
+use crate::error::{FlockError, PayloadError, Result};
+use crate::runtime::function_name::FunctionName;
+use crate::runtime::metadata::QueryMetadata;
+use crate::runtime::payload::Payload;
+use crate::runtime::plan::PlanProperties;
+use crate::transmute::schema_from_bytes;
+use datafusion::arrow::datatypes::Schema;
+use datafusion::physical_plan::ExecutionPlan;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use tracing::warn;
+
+/// The metadata key of the name of the function that sends the payload.
+pub const SENDER_METADATA_KEY: &str = "sender";
+
+/// The expected inputs of a query stage.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StageTopology {
+    /// The stages that send the payloads to the stage, i.e. the names of their
+    /// functions without the group index (see [`stage_name`]). Empty if any
+    /// sender is expected.
+    pub upstreams:    Vec<String>,
+    /// The fingerprints of the schemas of the leaves of the plans (see
+    /// [`schema_fingerprint`]).
+    pub fingerprints: Vec<String>,
+    /// If true, the mismatched payloads are accepted with a warning.
+    #[serde(default)]
+    pub warn_only:    bool,
+}
+
+impl StageTopology {
+    /// Returns the topology of the stage with the given plans and upstream
+    /// stages.
+    pub fn for_stage(plans: &[Arc<dyn ExecutionPlan>], upstreams: Vec<String>) -> Self {
+        let mut fingerprints = PlanProperties::analyze_all(plans)
+            .leaf_schemas
+            .iter()
+            .map(|schema| schema_fingerprint(schema))
+            .collect::<Vec<_>>();
+        fingerprints.sort();
+        fingerprints.dedup();
+        StageTopology {
+            upstreams: upstreams.iter().map(|name| stage_name(name)).collect(),
+            fingerprints,
+            warn_only: false,
+        }
+    }
+
+    /// Checks the sender and the schemas of the payload received by the
+    /// function.
+    ///
+    /// # Arguments
+    /// * `receiver` - The name of the function that receives the payload.
+    /// * `payload` - The received payload.
+    ///
+    /// # Returns
+    /// A [`PayloadError::Topology`] error if the payload is from an unexpected
+    /// stage or of an unexpected schema, unless the topology only warns.
+    pub fn check(&self, receiver: &str, payload: &Payload) -> Result<()> {
+        let sender = sender(&payload.metadata);
+        let reason = match self.mismatch(sender, payload) {
+            Some(reason) => reason,
+            None => return Ok(()),
+        };
+        let error = FlockError::Payload(
+            payload.uuid.clone(),
+            PayloadError::Topology {
+                sender: sender.unwrap_or("unknown").to_string(),
+                receiver: receiver.to_string(),
+                reason,
+            },
+        );
+        if self.warn_only {
+            warn!("{}", error);
+            Ok(())
+        } else {
+            Err(error)
+        }
+    }
+
+    /// Returns why the payload doesn't match the topology, if it doesn't.
+    fn mismatch(&self, sender: Option<&str>, payload: &Payload) -> Option<String> {
+        if let Some(sender) = sender {
+            let stage = stage_name(sender);
+            if !self.upstreams.is_empty() && !self.upstreams.contains(&stage) {
+                return Some(format!(
+                    "the stage {} isn't upstream, expected {:?}",
+                    stage, self.upstreams
+                ));
+            }
+        }
+        if self.fingerprints.is_empty() {
+            return None;
+        }
+        [&payload.schema, &payload.schema2]
+            .iter()
+            .enumerate()
+            .filter(|(_, bytes)| !bytes.is_empty())
+            .find_map(|(relation, bytes)| {
+                let fingerprint = match schema_from_bytes(bytes) {
+                    Ok(schema) => schema_fingerprint(&schema),
+                    // The malformed schema is reported by the decoding.
+                    Err(_) => return None,
+                };
+                (!self.fingerprints.contains(&fingerprint)).then(|| {
+                    format!(
+                        "the schema {} of the relation {} isn't an input of the stage, expected \
+                         {:?}",
+                        fingerprint, relation, self.fingerprints
+                    )
+                })
+            })
+    }
+}
+
+/// Returns the fingerprint of the schema, i.e. the hash of the names and the
+/// data types of its fields in order. The nullability and the metadata, e.g.
+/// the stream name, are left out, since the plans may derive them differently
+/// from the senders.
+pub fn schema_fingerprint(schema: &Schema) -> String {
+    let mut hasher = DefaultHasher::new();
+    for field in schema.fields() {
+        field.name().hash(&mut hasher);
+        format!("{:?}", field.data_type()).hash(&mut hasher);
+    }
+    format!("{:016x}", hasher.finish())
+}
+
+/// Returns the name of the stage of the function, i.e. the function name
+/// without the group index, e.g. `q3-00` for `q3-00-07`. The names that aren't
+/// of a query stage, e.g. of the data source function, are returned as is.
+pub fn stage_name(function_name: &str) -> String {
+    match FunctionName::parse(function_name) {
+        Ok(name) => FunctionName {
+            group_index: None,
+            ..name
+        }
+        .to_string(),
+        Err(_) => function_name.to_string(),
+    }
+}
+
+/// Returns the name of the function that sent the payload, if it's known.
+pub fn sender(metadata: &Option<QueryMetadata>) -> Option<&str> {
+    metadata
+        .as_ref()?
+        .get(SENDER_METADATA_KEY)
+        .map(|s| s.as_str())
+}
+
+/// Stamps the name of the sending function into the metadata of the payloads
+/// to the next stage, replacing the sender of the received payload.
+pub fn stamp_sender(metadata: &mut Option<QueryMetadata>, sender: &str) {
+    metadata
+        .get_or_insert_with(QueryMetadata::default)
+        .insert(SENDER_METADATA_KEY.to_string(), sender.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::payload::Uuid;
+    use crate::transmute::to_payload;
+    use datafusion::arrow::array::{Int64Array, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field, SchemaRef};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::physical_plan::memory::MemoryExec;
+
+    /// The input of the aggregator of q4: `(category, price)`.
+    fn q4_schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("category", DataType::Int64, false),
+            Field::new("price", DataType::Int64, false),
+        ]))
+    }
+
+    /// The output of a stage of q3: `(name, id)`, of the same field count.
+    fn q3_schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("name", DataType::Utf8, false),
+            Field::new("id", DataType::Int64, false),
+        ]))
+    }
+
+    fn payload(sender: Option<&str>, schema: SchemaRef) -> Payload {
+        let first = if schema.field(0).data_type() == &DataType::Utf8 {
+            Arc::new(StringArray::from(vec!["Alice"])) as _
+        } else {
+            Arc::new(Int64Array::from(vec![10])) as _
+        };
+        let batch =
+            RecordBatch::try_new(schema, vec![first, Arc::new(Int64Array::from(vec![1]))]).unwrap();
+        let mut payload = to_payload(&[batch], &[], Uuid::default(), false).unwrap();
+        if let Some(sender) = sender {
+            stamp_sender(&mut payload.metadata, sender);
+        }
+        payload
+    }
+
+    fn q4_aggregator() -> StageTopology {
+        let leaf: Arc<dyn ExecutionPlan> =
+            Arc::new(MemoryExec::try_new(&[vec![]], q4_schema(), None).unwrap());
+        StageTopology::for_stage(&[leaf], vec!["q4-00".to_string()])
+    }
+
+    #[test]
+    fn accept_upstream_payloads() -> Result<()> {
+        let topology = q4_aggregator();
+        assert_eq!(stage_name("q4-00-07"), "q4-00");
+        topology.check("q4-01-00", &payload(Some("q4-00-07"), q4_schema()))?;
+        topology.check("q4-01-00", &payload(Some("q4-00"), q4_schema()))?;
+        // The payloads of the older functions don't name the sender.
+        topology.check("q4-01-00", &payload(None, q4_schema()))?;
+        Ok(())
+    }
+
+    #[test]
+    fn reject_wrong_successor() {
+        // The q3 workers are pointed at the q4 aggregator.
+        let topology = q4_aggregator();
+        let error = topology
+            .check("q4-01-00", &payload(Some("q3-01-02"), q3_schema()))
+            .unwrap_err();
+        assert_eq!(error.kind(), "Payload.Topology");
+        assert!(!error.is_retryable());
+        match error {
+            FlockError::Payload(
+                _,
+                PayloadError::Topology {
+                    sender, receiver, ..
+                },
+            ) => {
+                assert_eq!(sender, "q3-01-02");
+                assert_eq!(receiver, "q4-01-00");
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // A stale sender of the right stage, but with the schema of q3.
+        let error = topology
+            .check("q4-01-00", &payload(Some("q4-00-01"), q3_schema()))
+            .unwrap_err();
+        assert!(error.to_string().contains("isn't an input of the stage"));
+
+        // The flexible deployments only warn.
+        let topology = StageTopology {
+            warn_only: true,
+            ..topology
+        };
+        assert!(topology
+            .check("q4-01-00", &payload(Some("q3-01-02"), q3_schema()))
+            .is_ok());
+    }
+}
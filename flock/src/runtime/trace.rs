@@ -0,0 +1,661 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The distributed traces of the windows with OpenTelemetry.
+//!
+//! Each window of a query is a trace, whose id is derived from the query code
+//! and the window id, so all the functions that process the window report to
+//! the same trace without any coordination. The data source function opens the
+//! root span of the window (see [`begin_window`]), and every invocation of a
+//! query stage is a span (see [`begin`]) whose parent is the span of the
+//! function that sent the payload. The context of the parent span travels in
+//! the payload metadata as a W3C `traceparent`, e.g.
+//!
+//! ```text
+//! 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01
+//! ```
+//!
+//! The spans record the function name, the plan index of the query stage and
+//! the key attributes of the invocation, such as the rows and the bytes of its
+//! output and the strategy of its fan-out. They are buffered during the
+//! invocation and exported at its end (see [`end`]) to the endpoint in
+//! `FLOCK_CONF["otel"]`: an OTLP/HTTP collector with the `otel` feature, or
+//! `stdout`. If the endpoint is empty, the tracing is disabled and all the
+//! functions of this module are no-ops.
+
+use crate::configs::FLOCK_OTEL_ENDPOINT;
+use crate::error::Result;
+use crate::runtime::function_name::{query_code_of, FunctionName};
+use crate::runtime::metadata::QueryMetadata;
+use crate::runtime::payload::Payload;
+use async_trait::async_trait;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use tracing::warn;
+
+/// The metadata key of the context of the parent span.
+pub const TRACE_METADATA_KEY: &str = "traceparent";
+
+lazy_static! {
+    static ref EXPORTER: RwLock<Option<Arc<dyn SpanExporter>>> = RwLock::new(exporter_from_conf());
+    static ref INVOCATION_SPANS: Mutex<InvocationSpans> = Mutex::new(InvocationSpans::default());
+}
+
+/// The context of a span, which is propagated to its children.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceContext {
+    /// The id of the trace, i.e. of the window.
+    pub trace_id: u128,
+    /// The id of the span.
+    pub span_id:  u64,
+}
+
+impl TraceContext {
+    /// Returns the context of the root span of the window. The ids are derived
+    /// from the query code and the window id, so the retries of the data
+    /// source report to the same trace.
+    pub fn for_window(query_code: &str, window_id: &str) -> Self {
+        let hash = |salt: u8| {
+            let mut hasher = DefaultHasher::new();
+            (query_code, window_id, salt).hash(&mut hasher);
+            hasher.finish()
+        };
+        Self {
+            trace_id: ((hash(0) as u128) << 64 | hash(1) as u128).max(1),
+            span_id:  hash(2).max(1),
+        }
+    }
+
+    /// Parses the W3C `traceparent`.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        match traceparent.split('-').collect::<Vec<_>>().as_slice() {
+            ["00", trace_id, span_id, _] if trace_id.len() == 32 && span_id.len() == 16 => {
+                let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+                let span_id = u64::from_str_radix(span_id, 16).ok()?;
+                (trace_id != 0 && span_id != 0).then(|| Self { trace_id, span_id })
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the W3C `traceparent` of the sampled span.
+    pub fn traceparent(&self) -> String {
+        format!("00-{:032x}-{:016x}-01", self.trace_id, self.span_id)
+    }
+
+    /// Returns the context in the metadata, if any. A malformed context is
+    /// ignored, since the tracing must not fail the invocation.
+    pub fn from_metadata(metadata: &Option<QueryMetadata>) -> Option<Self> {
+        let value = metadata.as_ref()?.get(TRACE_METADATA_KEY)?;
+        let context = Self::parse(value);
+        if context.is_none() {
+            warn!("Invalid {} in the metadata: {}", TRACE_METADATA_KEY, value);
+        }
+        context
+    }
+
+    /// Stamps the context into the metadata, replacing the existing one.
+    pub fn stamp(&self, metadata: &mut Option<QueryMetadata>) {
+        metadata
+            .get_or_insert_with(QueryMetadata::default)
+            .insert(TRACE_METADATA_KEY.to_string(), self.traceparent());
+    }
+}
+
+/// The value of a span attribute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttributeValue {
+    /// An integer, e.g. the number of rows.
+    Int(i64),
+    /// A string, e.g. the function name.
+    String(String),
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self {
+        AttributeValue::Int(value)
+    }
+}
+
+impl From<usize> for AttributeValue {
+    fn from(value: usize) -> Self {
+        AttributeValue::Int(value as i64)
+    }
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue::String(value.to_string())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        AttributeValue::String(value)
+    }
+}
+
+/// A span of a window trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanData {
+    /// The id of the trace.
+    pub trace_id:       u128,
+    /// The id of the span.
+    pub span_id:        u64,
+    /// The id of the parent span, or `None` for the root span of the window.
+    pub parent_span_id: Option<u64>,
+    /// The name of the function.
+    pub name:           String,
+    /// The attributes of the span.
+    pub attributes:     BTreeMap<String, AttributeValue>,
+    /// The start time in nanoseconds since the Unix epoch.
+    pub start_nanos:    u64,
+    /// The end time in nanoseconds since the Unix epoch, or 0 if the span is
+    /// still open.
+    pub end_nanos:      u64,
+}
+
+impl SpanData {
+    /// Starts the span of the function with the context.
+    fn start(function_name: &str, context: TraceContext, parent_span_id: Option<u64>) -> Self {
+        let mut span = Self {
+            trace_id: context.trace_id,
+            span_id: context.span_id,
+            parent_span_id,
+            name: function_name.to_string(),
+            attributes: BTreeMap::new(),
+            start_nanos: now_nanos(),
+            end_nanos: 0,
+        };
+        span.set("function", function_name);
+        span.set("query_code", query_code_of(function_name));
+        if let Ok(name) = FunctionName::parse(function_name) {
+            span.set("plan_index", name.plan_index);
+            if let Some(group_index) = name.group_index {
+                span.set("group_index", group_index);
+            }
+        }
+        span
+    }
+
+    /// Returns the context of the span for its children.
+    pub fn context(&self) -> TraceContext {
+        TraceContext {
+            trace_id: self.trace_id,
+            span_id:  self.span_id,
+        }
+    }
+
+    /// Sets the attribute, replacing the existing one.
+    pub fn set(&mut self, key: &str, value: impl Into<AttributeValue>) {
+        self.attributes.insert(key.to_string(), value.into());
+    }
+
+    /// Adds the value to the integer attribute.
+    pub fn add(&mut self, key: &str, value: i64) {
+        let total = match self.attributes.get(key) {
+            Some(AttributeValue::Int(total)) => total + value,
+            _ => value,
+        };
+        self.set(key, total);
+    }
+
+    fn finish(mut self) -> Self {
+        self.end_nanos = now_nanos();
+        self
+    }
+
+    /// Returns the span in the OTLP JSON encoding.
+    pub fn to_otlp(&self) -> Value {
+        let mut span = json!({
+            "traceId": format!("{:032x}", self.trace_id),
+            "spanId": format!("{:016x}", self.span_id),
+            "name": self.name,
+            // SPAN_KIND_SERVER, since every span is a function invocation.
+            "kind": 2,
+            "startTimeUnixNano": self.start_nanos.to_string(),
+            "endTimeUnixNano": self.end_nanos.to_string(),
+            "attributes": self
+                .attributes
+                .iter()
+                .map(|(key, value)| otlp_attribute(key, value))
+                .collect::<Vec<_>>(),
+        });
+        if let Some(parent) = self.parent_span_id {
+            span["parentSpanId"] = json!(format!("{:016x}", parent));
+        }
+        span
+    }
+}
+
+fn otlp_attribute(key: &str, value: &AttributeValue) -> Value {
+    match value {
+        AttributeValue::Int(value) => {
+            json!({ "key": key, "value": { "intValue": value.to_string() } })
+        }
+        AttributeValue::String(value) => json!({ "key": key, "value": { "stringValue": value } }),
+    }
+}
+
+/// Returns the OTLP/HTTP JSON request that exports the spans.
+pub fn otlp_request(spans: &[SpanData]) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [otlp_attribute("service.name", &"flock".into())],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "flock" },
+                "spans": spans.iter().map(SpanData::to_otlp).collect::<Vec<_>>(),
+            }],
+        }],
+    })
+}
+
+fn now_nanos() -> u64 {
+    Utc::now().timestamp_nanos().max(0) as u64
+}
+
+/// The exporter of the finished spans.
+#[async_trait]
+pub trait SpanExporter: Send + Sync {
+    /// Exports the spans of an invocation.
+    async fn export(&self, spans: &[SpanData]) -> Result<()>;
+}
+
+/// The exporter that keeps the spans in memory (for testing).
+#[derive(Debug, Default)]
+pub struct InMemoryExporter {
+    spans: Mutex<Vec<SpanData>>,
+}
+
+impl InMemoryExporter {
+    /// Returns the exported spans.
+    pub fn spans(&self) -> Vec<SpanData> {
+        self.spans.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl SpanExporter for InMemoryExporter {
+    async fn export(&self, spans: &[SpanData]) -> Result<()> {
+        self.spans.lock().unwrap().extend_from_slice(spans);
+        Ok(())
+    }
+}
+
+/// The exporter that writes the spans to stdout in the OTLP JSON encoding, one
+/// line per span, where CloudWatch Logs picks them up.
+#[derive(Debug, Default)]
+pub struct StdoutExporter;
+
+#[async_trait]
+impl SpanExporter for StdoutExporter {
+    async fn export(&self, spans: &[SpanData]) -> Result<()> {
+        spans
+            .iter()
+            .for_each(|span| println!("{}", otlp_request(std::slice::from_ref(span))));
+        Ok(())
+    }
+}
+
+/// The exporter that posts the spans to an OTLP/HTTP collector.
+#[cfg(feature = "otel")]
+#[derive(Debug)]
+pub struct OtlpExporter {
+    url:    String,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "otel")]
+impl OtlpExporter {
+    /// Creates the exporter to the collector, e.g. `http://localhost:4318`.
+    pub fn new(endpoint: &str) -> Self {
+        Self {
+            url:    format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+#[async_trait]
+impl SpanExporter for OtlpExporter {
+    async fn export(&self, spans: &[SpanData]) -> Result<()> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(&otlp_request(spans))?)
+            .send()
+            .await
+            .map_err(|e| crate::error::FlockError::Internal(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(crate::error::FlockError::Internal(format!(
+                "The collector {} rejected the spans: {}",
+                self.url,
+                response.status()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the exporter of the endpoint in `FLOCK_CONF["otel"]`.
+fn exporter_from_conf() -> Option<Arc<dyn SpanExporter>> {
+    match FLOCK_OTEL_ENDPOINT.as_str() {
+        "" => None,
+        "stdout" => Some(Arc::new(StdoutExporter)),
+        #[cfg(feature = "otel")]
+        endpoint => Some(Arc::new(OtlpExporter::new(endpoint))),
+        #[cfg(not(feature = "otel"))]
+        endpoint => {
+            warn!(
+                "The spans aren't exported to {} without the otel feature.",
+                endpoint
+            );
+            None
+        }
+    }
+}
+
+/// Replaces the exporter of the spans, e.g. by an [`InMemoryExporter`] in the
+/// tests. `None` disables the tracing.
+pub fn set_exporter(exporter: Option<Arc<dyn SpanExporter>>) {
+    *EXPORTER.write().unwrap() = exporter;
+}
+
+fn exporter() -> Option<Arc<dyn SpanExporter>> {
+    EXPORTER.read().unwrap().clone()
+}
+
+/// Whether the spans are exported.
+pub fn is_enabled() -> bool {
+    exporter().is_some()
+}
+
+/// The spans of the current invocation.
+#[derive(Default)]
+struct InvocationSpans {
+    /// The span of the invocation of a query stage.
+    invocation: Option<SpanData>,
+    /// The root span of the window that the data source is emitting.
+    window:     Option<SpanData>,
+    /// The finished spans to export.
+    finished:   Vec<SpanData>,
+}
+
+impl InvocationSpans {
+    fn current(&mut self) -> Option<&mut SpanData> {
+        self.invocation.as_mut().or(self.window.as_mut())
+    }
+}
+
+fn spans() -> MutexGuard<'static, InvocationSpans> {
+    INVOCATION_SPANS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Opens the span of the invocation with the payload, as a child of the span
+/// in its metadata. The invocations without a propagated context, e.g. of the
+/// data sources, have no span of their own.
+pub fn begin(function_name: &str, payload: &Payload) {
+    let mut spans = spans();
+    *spans = InvocationSpans::default();
+    let parent = match TraceContext::from_metadata(&payload.metadata) {
+        Some(parent) if is_enabled() => parent,
+        _ => return,
+    };
+    let context = TraceContext {
+        trace_id: parent.trace_id,
+        span_id:  rand::random::<u64>().max(1),
+    };
+    let mut span = SpanData::start(function_name, context, Some(parent.span_id));
+    let (qid, shuffle_id) = payload.get_window_id();
+    span.set("window_id", format!("{}/{}", qid, shuffle_id));
+    span.set("input_bytes", payload.get_data_size());
+    spans.invocation = Some(span);
+}
+
+/// Opens the root span of the window that the data source function emits, and
+/// finishes the one of the previous window. Returns the context to stamp into
+/// the payloads of the window, or `None` if the tracing is disabled.
+pub fn begin_window(
+    function_name: &str,
+    query_code: &str,
+    window_id: &str,
+) -> Option<TraceContext> {
+    if !is_enabled() {
+        return None;
+    }
+    let context = TraceContext::for_window(query_code, window_id);
+    let mut span = SpanData::start(function_name, context, None);
+    span.set("query_code", query_code);
+    span.set("window_id", window_id);
+    let mut spans = spans();
+    if let Some(previous) = spans.window.replace(span) {
+        spans.finished.push(previous.finish());
+    }
+    Some(context)
+}
+
+/// Records the attribute on the span of the invocation, if any.
+pub fn record(key: &str, value: impl Into<AttributeValue>) {
+    if let Some(span) = spans().current() {
+        span.set(key, value);
+    }
+}
+
+/// Adds the value to the integer attribute on the span of the invocation, if
+/// any, e.g. to count the bytes of all the outgoing payloads.
+pub fn add(key: &str, value: i64) {
+    if let Some(span) = spans().current() {
+        span.add(key, value);
+    }
+}
+
+/// Returns the metadata of the payloads sent to the next stage, whose parent
+/// span is the span of the current invocation.
+pub fn downstream_metadata(metadata: Option<QueryMetadata>) -> Option<QueryMetadata> {
+    let mut metadata = metadata;
+    let context = spans().current().map(|span| span.context());
+    if let Some(context) = context {
+        context.stamp(&mut metadata);
+    }
+    metadata
+}
+
+/// Finishes the spans of the invocation and exports them. The export errors
+/// are only logged, since the tracing must not fail the invocation.
+pub async fn end() {
+    let spans = {
+        let spans = std::mem::take(&mut *spans());
+        let open = spans.window.into_iter().chain(spans.invocation);
+        spans
+            .finished
+            .into_iter()
+            .chain(open.map(SpanData::finish))
+            .collect::<Vec<_>>()
+    };
+    if let (Some(exporter), false) = (exporter(), spans.is_empty()) {
+        if let Err(e) = exporter.export(&spans).await {
+            warn!("Failed to export the spans: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::payload::UuidBuilder;
+    use crate::transmute::to_payload;
+    use datafusion::arrow::array::Int64Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+
+    #[test]
+    fn traceparent_round_trip() {
+        let context = TraceContext::for_window("q7", "q7-1650000000-7/0");
+        assert_eq!(context, TraceContext::for_window("q7", "q7-1650000000-7/0"));
+        assert_ne!(context, TraceContext::for_window("q7", "q7-1650000000-7/1"));
+        assert_ne!(context, TraceContext::for_window("q8", "q7-1650000000-7/0"));
+
+        let traceparent = context.traceparent();
+        assert_eq!(traceparent.len(), 55);
+        assert_eq!(TraceContext::parse(&traceparent), Some(context));
+        assert_eq!(
+            TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            Some(TraceContext {
+                trace_id: 0x4bf92f3577b34da6a3ce929d0e0e4736,
+                span_id:  0x00f067aa0ba902b7,
+            })
+        );
+
+        let mut metadata = None;
+        context.stamp(&mut metadata);
+        assert_eq!(TraceContext::from_metadata(&metadata), Some(context));
+        for invalid in [
+            "",
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473x-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(TraceContext::parse(invalid), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn two_stage_span_tree() -> Result<()> {
+        let exporter = Arc::new(InMemoryExporter::default());
+        set_exporter(Some(exporter.clone()));
+
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(Int64Array::from(vec![1, 2, 3]))])?;
+        let windows = (0..2)
+            .map(|w| format!("q7-1650000000-7/{}", w))
+            .collect::<Vec<_>>();
+
+        // The data source sends each window to two functions of the first stage,
+        // which send their output to the second stage.
+        let mut stage0 = vec![];
+        begin("flock_datasource", &Payload::default());
+        for window in &windows {
+            let mut metadata = None;
+            begin_window("flock_datasource", "q7", window)
+                .unwrap()
+                .stamp(&mut metadata);
+            let mut uuid_builder = UuidBuilder::for_window("q7-00", window, 2);
+            for i in 0..2 {
+                let mut payload =
+                    to_payload(&[batch.clone()], &[], uuid_builder.next_uuid(), false)?;
+                payload.metadata = metadata.clone();
+                stage0.push((format!("q7-00-{:02}", i), payload));
+            }
+        }
+        end().await;
+
+        let mut stage1 = vec![];
+        for (function_name, payload) in &stage0 {
+            begin(function_name, payload);
+            record("strategy", "forward");
+            let mut next = to_payload(&[batch.clone()], &[], payload.uuid.clone(), false)?;
+            next.metadata = downstream_metadata(payload.metadata.clone());
+            stage1.push(next);
+            end().await;
+        }
+        for payload in &stage1 {
+            begin("q7-01-00", payload);
+            end().await;
+        }
+        set_exporter(None);
+
+        let spans = exporter.spans();
+        assert_eq!(spans.len(), 10);
+        assert!(spans.iter().all(|s| s.start_nanos <= s.end_nanos));
+        for window in &windows {
+            let root = TraceContext::for_window("q7", window);
+            let trace = spans
+                .iter()
+                .filter(|s| s.trace_id == root.trace_id)
+                .collect::<Vec<_>>();
+            assert_eq!(trace.len(), 5);
+            let children = |parent: u64| {
+                trace
+                    .iter()
+                    .filter(|s| s.parent_span_id == Some(parent))
+                    .collect::<Vec<_>>()
+            };
+
+            let roots = trace
+                .iter()
+                .filter(|s| s.parent_span_id.is_none())
+                .collect::<Vec<_>>();
+            assert_eq!(roots.len(), 1);
+            assert_eq!(roots[0].span_id, root.span_id);
+            assert_eq!(roots[0].name, "flock_datasource");
+            assert_eq!(
+                roots[0].attributes["window_id"],
+                AttributeValue::String(window.clone())
+            );
+
+            let stage0 = children(root.span_id);
+            assert_eq!(stage0.len(), 2);
+            for span in stage0 {
+                assert!(span.name.starts_with("q7-00-"));
+                assert_eq!(span.attributes["plan_index"], AttributeValue::Int(0));
+                assert_eq!(span.attributes["strategy"], AttributeValue::from("forward"));
+                assert!(matches!(span.attributes["output_bytes"], AttributeValue::Int(b) if b > 0));
+
+                let stage1 = children(span.span_id);
+                assert_eq!(stage1.len(), 1);
+                assert_eq!(stage1[0].name, "q7-01-00");
+                assert_eq!(stage1[0].attributes["plan_index"], AttributeValue::Int(1));
+                assert!(children(stage1[0].span_id).is_empty());
+            }
+        }
+
+        // The spans are encoded for the OTLP collector.
+        let request = otlp_request(&spans);
+        let otlp = request["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        assert_eq!(otlp.len(), 10);
+        assert!(
+            otlp.iter()
+                .filter(|s| s.get("parentSpanId").is_none())
+                .count()
+                == 2
+        );
+
+        // Without an exporter, nothing is traced.
+        let mut metadata = None;
+        TraceContext::for_window("q7", &windows[0]).stamp(&mut metadata);
+        let payload = Payload {
+            metadata: metadata.clone(),
+            ..Default::default()
+        };
+        begin("q7-00-00", &payload);
+        assert_eq!(downstream_metadata(metadata.clone()), metadata);
+        assert_eq!(begin_window("flock_datasource", "q7", &windows[0]), None);
+        end().await;
+        assert_eq!(exporter.spans().len(), 10);
+        Ok(())
+    }
+}
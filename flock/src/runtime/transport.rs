@@ -0,0 +1,198 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The transport of the payloads between the query stages.
+//!
+//! A stage invokes the functions of its next stage directly by default, which
+//! couples the throughput of the stages: a slow function of the next stage
+//! blocks the synchronous invocations, and the asynchronous invocations are
+//! lost once Lambda gives up retrying them. With the queue transport, the stage
+//! sends each payload to the SQS queue of the receiving function instead (see
+//! [`queue_name`]), and the event source mapping of the queue invokes the
+//! function with a batch of the payloads (see [`queue_messages`]). A payload
+//! larger than `max_message_size` is written to S3, and its message only points
+//! to it (see [`QueuePointer`]). The data source still invokes the first
+//! stage directly, so only the functions of the later stages have queues.
+//!
+//! The standard queues neither keep the order of the messages nor deliver them
+//! exactly once. The transport doesn't need either: the payloads of a window
+//! are assembled by their uuids in the arena of the receiving function, and the
+//! payloads delivered again are dropped there like the retried invocations.
+//! The queue transport is asynchronous, so the results of the last stage can't
+//! be returned in the response of a synchronous invocation.
+
+use crate::aws::client::CloudClient;
+use crate::configs::{FLOCK_S3_BUCKET, FLOCK_SQS_MAX_MESSAGE_SIZE};
+use crate::error::{FlockError, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// The key of the message body that points to the payload in S3.
+pub const QUEUE_POINTER_KEY: &str = "queue_pointer";
+
+/// The S3 object of a payload too large for a queue message.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct QueuePointer {
+    /// The S3 bucket name.
+    pub bucket: String,
+    /// The S3 object key.
+    pub key:    String,
+}
+
+/// Returns the name of the queue that the function receives its payloads
+/// from, e.g. `q4-01-00-queue`.
+pub fn queue_name(function: &str) -> String {
+    format!("{}-queue", function)
+}
+
+/// Returns the prefix of the S3 keys of the payloads sent to the queues of
+/// the query's functions.
+pub fn pointer_prefix(query_code: &str) -> String {
+    format!("queues/{}-", query_code)
+}
+
+/// Returns the S3 key of the payload sent to the queue. The key is derived
+/// from the payload, so a retried send overwrites the same object.
+fn pointer_key(queue: &str, bytes: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    format!("queues/{}/{:016x}", queue, hasher.finish())
+}
+
+/// Sends the serialized payload to the function, and returns the payload of the
+/// response if any.
+///
+/// # Arguments
+/// * `client` - The client of the AWS calls.
+/// * `via_queue` - Whether the payload is sent to the queue of the function
+///   instead of invoking it.
+/// * `function` - The name of the function.
+/// * `invocation_type` - `Event` or `RequestResponse`, if the function is
+///   invoked.
+/// * `bytes` - The serialized payload.
+pub async fn deliver(
+    client: &dyn CloudClient,
+    via_queue: bool,
+    function: &str,
+    invocation_type: &str,
+    bytes: Vec<u8>,
+) -> Result<Option<Vec<u8>>> {
+    if !via_queue {
+        return client.invoke(function, invocation_type, bytes).await;
+    }
+    let queue = queue_name(function);
+    let body = if bytes.len() > *FLOCK_SQS_MAX_MESSAGE_SIZE {
+        let pointer = QueuePointer {
+            bucket: FLOCK_S3_BUCKET.to_string(),
+            key:    pointer_key(&queue, &bytes),
+        };
+        client.s3_put(&pointer.bucket, &pointer.key, bytes).await?;
+        json!({ QUEUE_POINTER_KEY: pointer }).to_string()
+    } else {
+        String::from_utf8(bytes).map_err(|e| {
+            FlockError::Execution(format!("The payload to {} isn't JSON: {}", queue, e))
+        })?
+    };
+    client.send_message(&queue, body).await?;
+    Ok(None)
+}
+
+/// Returns the bodies of the messages if the function is invoked by the event
+/// source mapping of a queue, or `None` otherwise.
+pub fn queue_messages(event: &Value) -> Option<Vec<String>> {
+    let records = event.get("Records")?.as_array()?;
+    if records.is_empty() || records.iter().any(|r| r["eventSource"] != "aws:sqs") {
+        return None;
+    }
+    records
+        .iter()
+        .map(|r| r["body"].as_str().map(|body| body.to_string()))
+        .collect()
+}
+
+/// Returns the payload of the message, which is read from S3 if the message
+/// points to it.
+pub async fn open_message(client: &dyn CloudClient, body: &str) -> Result<Value> {
+    let value: Value = serde_json::from_str(body)?;
+    match value.get(QUEUE_POINTER_KEY) {
+        Some(pointer) => {
+            let pointer: QueuePointer = serde_json::from_value(pointer.clone())?;
+            let bytes = client.s3_get(&pointer.bucket, &pointer.key).await?;
+            Ok(serde_json::from_slice(&bytes)?)
+        }
+        None => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aws::client::FakeCloudClient;
+
+    /// Returns the SQS event of the messages.
+    fn sqs_event(bodies: &[String]) -> Value {
+        let records = bodies
+            .iter()
+            .enumerate()
+            .map(|(i, body)| {
+                json!({
+                    "messageId": i.to_string(),
+                    "eventSource": "aws:sqs",
+                    "body": body,
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({ "Records": records })
+    }
+
+    #[tokio::test]
+    async fn send_payloads_through_queue() -> Result<()> {
+        let client = FakeCloudClient::new();
+        let small = json!({"data": "x"}).to_string().into_bytes();
+        deliver(&client, false, "q1-01", "Event", small.clone()).await?;
+        deliver(&client, true, "q1-01", "Event", small.clone()).await?;
+        assert_eq!(client.invocations().len(), 1);
+        assert_eq!(client.messages().len(), 1);
+        assert_eq!(client.messages()[0].queue, "q1-01-queue");
+
+        // The oversized payload is written to S3 under the prefix of the query.
+        let large = json!({ "data": "x".repeat(*FLOCK_SQS_MAX_MESSAGE_SIZE) })
+            .to_string()
+            .into_bytes();
+        deliver(&client, true, "q1-01", "Event", large.clone()).await?;
+        let messages = client.messages();
+        assert!(messages[1].body.len() < 1024);
+        let keys = client.keys(&FLOCK_S3_BUCKET);
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0].starts_with(&pointer_prefix("q1")));
+
+        let bodies = messages.into_iter().map(|m| m.body).collect::<Vec<_>>();
+        let event = sqs_event(&bodies);
+        let bodies = queue_messages(&event).unwrap();
+        assert_eq!(
+            open_message(&client, &bodies[0]).await?,
+            serde_json::from_slice::<Value>(&small)?
+        );
+        assert_eq!(
+            open_message(&client, &bodies[1]).await?,
+            serde_json::from_slice::<Value>(&large)?
+        );
+
+        // The direct payloads and the other event sources aren't queue messages.
+        assert!(queue_messages(&json!({"data": "x"})).is_none());
+        assert!(queue_messages(&json!({"Records": [{"eventSource": "aws:kinesis"}]})).is_none());
+        Ok(())
+    }
+}
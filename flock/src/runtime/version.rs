@@ -0,0 +1,163 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The versions of the wire formats that cross the boundary between the
+//! deployment and the cloud functions, i.e. the [`Payload`] passed between the
+//! functions and the [`CloudEnvironment`] that carries the execution context.
+//!
+//! During an upgrade, the functions of the old and the new deployment run side
+//! by side, so a function has to read what the previous version wrote. The
+//! policy is that a binary writes the current version of a format, and reads
+//! the current and the previous version. A payload or an environment of a
+//! newer version is rejected with [`FlockError::FormatVersion`], which names
+//! both versions, instead of failing somewhere deep in the decoding.
+//!
+//! The values written before the versions were introduced don't carry one,
+//! and are read as [`LEGACY_FORMAT_VERSION`]. When a format changes, bump its
+//! constant here, and keep the fixtures of the previous version in
+//! `flock/tests/fixtures` (see `flock/tests/compatibility.rs`).
+//!
+//! [`Payload`]: crate::runtime::payload::Payload
+//! [`CloudEnvironment`]: crate::runtime::context::CloudEnvironment
+
+use crate::error::{FlockError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The version of the wire format of the payloads that this binary writes.
+///
+/// * 2: the version is recorded in the payload.
+/// * 3: the second relation may be broadcast through S3 (see
+///   [`crate::runtime::broadcast`]).
+/// * 4: the data frames may be compressed with the Zstd dictionary of the query
+///   (see [`crate::runtime::dictionary`]).
+pub const PAYLOAD_FORMAT_VERSION: u16 = 4;
+
+/// The version of the wire format of the cloud environments that this binary
+/// writes.
+pub const CONTEXT_FORMAT_VERSION: u16 = 2;
+
+/// The version of the values that are written without a version.
+pub const LEGACY_FORMAT_VERSION: u16 = 1;
+
+/// A versioned wire format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WireFormat {
+    /// The payload passed between the cloud functions.
+    Payload,
+    /// The cloud environment of the cloud functions.
+    CloudEnvironment,
+}
+
+impl WireFormat {
+    /// Returns the version of the format that this binary writes.
+    pub fn current(&self) -> u16 {
+        match self {
+            WireFormat::Payload => PAYLOAD_FORMAT_VERSION,
+            WireFormat::CloudEnvironment => CONTEXT_FORMAT_VERSION,
+        }
+    }
+
+    /// Returns the oldest version of the format that this binary reads.
+    pub fn oldest_readable(&self) -> u16 {
+        self.current().saturating_sub(1).max(LEGACY_FORMAT_VERSION)
+    }
+
+    /// Returns an error if this binary can't read the given version of the
+    /// format.
+    pub fn check(&self, found: u16) -> Result<()> {
+        if (self.oldest_readable()..=self.current()).contains(&found) {
+            Ok(())
+        } else {
+            Err(FlockError::FormatVersion {
+                format: *self,
+                found,
+                current: self.current(),
+            })
+        }
+    }
+
+    /// Returns the error of a value that failed to decode. If the value is of
+    /// a version that this binary can't read, the version is the cause, and
+    /// the error names it instead of the parse failure.
+    pub fn decode_error(&self, bytes: &[u8], error: FlockError) -> FlockError {
+        match probe(bytes).map(|found| self.check(found)) {
+            Some(Err(e)) => e,
+            _ => error,
+        }
+    }
+}
+
+impl fmt::Display for WireFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WireFormat::Payload => write!(f, "payload"),
+            WireFormat::CloudEnvironment => write!(f, "cloud environment"),
+        }
+    }
+}
+
+/// The version of the values that are written without a version, as the
+/// default of the `format_version` fields.
+pub fn legacy_format_version() -> u16 {
+    LEGACY_FORMAT_VERSION
+}
+
+#[derive(Deserialize)]
+struct Versioned {
+    #[serde(default = "legacy_format_version")]
+    format_version: u16,
+}
+
+/// Returns the version of a JSON value, without decoding the rest of it, or
+/// `None` if the bytes aren't a JSON object.
+pub fn probe(bytes: &[u8]) -> Option<u16> {
+    serde_json::from_slice::<Versioned>(bytes)
+        .ok()
+        .map(|v| v.format_version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readable_versions() {
+        let format = WireFormat::Payload;
+        assert!(format.check(PAYLOAD_FORMAT_VERSION).is_ok());
+        assert!(format.check(PAYLOAD_FORMAT_VERSION - 1).is_ok());
+        assert!(format.check(0).is_err());
+
+        match format.check(PAYLOAD_FORMAT_VERSION + 1) {
+            Err(FlockError::FormatVersion { found, current, .. }) => {
+                assert_eq!(found, PAYLOAD_FORMAT_VERSION + 1);
+                assert_eq!(current, PAYLOAD_FORMAT_VERSION);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn probe_version() {
+        assert_eq!(probe(br#"{"format_version": 7, "data": []}"#), Some(7));
+        assert_eq!(probe(br#"{"data": []}"#), Some(LEGACY_FORMAT_VERSION));
+        assert_eq!(probe(b"not json"), None);
+
+        let error = WireFormat::CloudEnvironment.decode_error(
+            br#"{"format_version": 9, "context": "?"}"#,
+            FlockError::Internal("parse failure".to_string()),
+        );
+        assert_eq!(error.kind(), "FormatVersion");
+        assert!(error.to_string().contains("version 9"));
+    }
+}
@@ -0,0 +1,418 @@
+// // Copyright (c) 2020-present, UMD Database Group.
+// //
+// // This program is free software: you can use, redistribute, and/or modify
+// // it under the terms of the GNU Affero General Public License, version 3
+// // or later ("AGPL"), as published by the Free Software Foundation.
+// //
+// // This program is distributed in the hope that it will be useful, but
+// WITHOUT // ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+// or // FITNESS FOR A PARTICULAR PURPOSE.
+// //
+// // You should have received a copy of the GNU Affero General Public License
+// // along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The cleanup of the state buckets of the S3 state backend.
+//!
+//! Each query id leaves a bucket full of the data partitions behind (see
+//! [`crate::state::S3StateBackend`]). Deleting them synchronously at the end of
+//! a run can take minutes, so the driver kicks off a [`StateCleanup`] once the
+//! completion protocol reports that the query is completed (see
+//! [`crate::api::QueryHandle::cleanup`]).
+//!
+//! The cleanup lists the keys of the bucket page by page, and deletes each page
+//! with a single `DeleteObjects` request of up to 1000 keys. After each page,
+//! the last deleted key is saved to the checkpoint object
+//! `<query code>/cleanup/<bucket>` in the Flock bucket, so that an interrupted
+//! cleanup resumes the listing after it instead of starting over. The deletes
+//! are rate limited to leave the request rate of S3 to the running queries.
+//! Once the bucket is empty, the bucket and the checkpoint are deleted.
+//!
+//! The functions record each state bucket they create under
+//! `<query code>/buckets/<bucket>` in the Flock bucket (see
+//! [`create_state_bucket`]), so that the cleanup of a query deletes exactly
+//! the buckets that the query created.
+
+use crate::aws::client::{AwsCloudClient, CloudClient};
+use crate::aws::s3;
+use crate::configs::*;
+use crate::error::Result;
+use crate::runtime::function_name::{query_code_of, query_key};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The S3 key prefix of the cleanup checkpoints under the prefix of the query.
+const CLEANUP_KEY_PREFIX: &str = "cleanup/";
+
+/// The S3 key prefix of the state buckets created by the query, under the
+/// prefix of the query.
+const BUCKETS_KEY_PREFIX: &str = "buckets/";
+
+/// The progress of the cleanup of a bucket.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CleanupCheckpoint {
+    /// The last deleted key. The listing resumes after it.
+    pub last_key: Option<String>,
+    /// The number of objects deleted so far.
+    pub deleted:  usize,
+}
+
+/// The outcome of a cleanup run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CleanupReport {
+    /// The number of objects deleted, including the ones of the former runs.
+    pub deleted:        usize,
+    /// True if the bucket is deleted. Otherwise, the run was stopped after the
+    /// maximum number of pages and the next run resumes from the checkpoint.
+    pub bucket_deleted: bool,
+}
+
+/// Deletes the state buckets of the queries.
+#[derive(Debug, Clone)]
+pub struct StateCleanup {
+    client:             Arc<dyn CloudClient>,
+    /// The bucket of the checkpoints.
+    bucket:             String,
+    /// The number of objects deleted per second at most.
+    deletes_per_second: usize,
+    /// The number of pages deleted per run at most, if any.
+    max_pages:          Option<usize>,
+}
+
+impl Default for StateCleanup {
+    fn default() -> Self {
+        Self::new(Arc::new(AwsCloudClient), &FLOCK_S3_BUCKET)
+    }
+}
+
+impl StateCleanup {
+    /// Creates the cleanup that saves the checkpoints in the given bucket.
+    pub fn new(client: Arc<dyn CloudClient>, bucket: &str) -> Self {
+        Self {
+            client,
+            bucket: bucket.to_string(),
+            deletes_per_second: *FLOCK_S3_CLEANUP_DELETES_PER_SECOND,
+            max_pages: None,
+        }
+    }
+
+    /// Limits the number of objects deleted per second. 0 disables the limit.
+    pub fn with_rate_limit(mut self, deletes_per_second: usize) -> Self {
+        self.deletes_per_second = deletes_per_second;
+        self
+    }
+
+    /// Stops each run after the given number of pages, e.g. before the cloud
+    /// function running the cleanup times out. The next run resumes from the
+    /// checkpoint.
+    pub fn with_max_pages(mut self, max_pages: usize) -> Self {
+        self.max_pages = Some(max_pages);
+        self
+    }
+
+    /// Returns the S3 key of the checkpoint of the bucket.
+    pub fn checkpoint_key(bucket: &str) -> String {
+        query_key(
+            query_code_of(bucket),
+            &format!("{}{}", CLEANUP_KEY_PREFIX, bucket),
+        )
+    }
+
+    /// Returns the S3 key that records the bucket as created by its query.
+    pub fn registry_key(bucket: &str) -> String {
+        query_key(
+            query_code_of(bucket),
+            &format!("{}{}", BUCKETS_KEY_PREFIX, bucket),
+        )
+    }
+
+    /// Records the state bucket as created by its query.
+    pub async fn register(&self, bucket: &str) -> Result<()> {
+        self.client
+            .s3_put(&self.bucket, &Self::registry_key(bucket), vec![])
+            .await
+    }
+
+    async fn checkpoint(&self, key: &str) -> Result<CleanupCheckpoint> {
+        if !self
+            .client
+            .s3_list(&self.bucket, key)
+            .await?
+            .iter()
+            .any(|k| k == key)
+        {
+            return Ok(CleanupCheckpoint::default());
+        }
+        let body = self.client.s3_get(&self.bucket, key).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Deletes all objects of the bucket, and then the bucket itself. If a
+    /// former run was interrupted, the cleanup resumes from its checkpoint.
+    pub async fn run(&self, bucket: &str) -> Result<CleanupReport> {
+        let key = Self::checkpoint_key(bucket);
+        let mut checkpoint = self.checkpoint(&key).await?;
+        let (start, mut deleted) = (Instant::now(), 0);
+        let mut pages = 0;
+        loop {
+            if self.max_pages.map_or(false, |max| pages >= max) {
+                return Ok(CleanupReport {
+                    deleted:        checkpoint.deleted,
+                    bucket_deleted: false,
+                });
+            }
+            let (keys, truncated) = self
+                .client
+                .s3_list_page(bucket, "", checkpoint.last_key.clone())
+                .await?;
+            if !keys.is_empty() {
+                deleted += keys.len();
+                if self.deletes_per_second > 0 {
+                    let due =
+                        Duration::from_secs_f64(deleted as f64 / self.deletes_per_second as f64);
+                    if let Some(wait) = due.checked_sub(start.elapsed()) {
+                        tokio::time::sleep(wait).await;
+                    }
+                }
+                self.client.s3_delete_objects(bucket, &keys).await?;
+                checkpoint.deleted += keys.len();
+                checkpoint.last_key = keys.last().cloned();
+                self.client
+                    .s3_put(&self.bucket, &key, serde_json::to_vec(&checkpoint)?)
+                    .await?;
+            }
+            pages += 1;
+            if !truncated {
+                break;
+            }
+        }
+        self.client.s3_delete_bucket(bucket).await?;
+        // The keys are deleted by their exact names, as other buckets of the
+        // query may share their prefixes.
+        self.client
+            .s3_delete_objects(&self.bucket, &[key, Self::registry_key(bucket)])
+            .await?;
+        info!(
+            "[OK] Deleted {} objects and the bucket {} in {:?}.",
+            checkpoint.deleted,
+            bucket,
+            start.elapsed()
+        );
+        Ok(CleanupReport {
+            deleted:        checkpoint.deleted,
+            bucket_deleted: true,
+        })
+    }
+
+    /// Deletes the state buckets recorded as created by the query.
+    pub async fn run_query(&self, query_code: &str) -> Result<Vec<CleanupReport>> {
+        let prefix = query_key(query_code, BUCKETS_KEY_PREFIX);
+        let mut reports = vec![];
+        for key in self.client.s3_list(&self.bucket, &prefix).await? {
+            if let Some(bucket) = key.strip_prefix(&prefix) {
+                reports.push(self.run(bucket).await?);
+            }
+        }
+        Ok(reports)
+    }
+
+    /// Deletes the state buckets of all queries that started more than
+    /// `older_than` ago, e.g. the ones left behind by the runs that skipped the
+    /// cleanup. Returns the deleted buckets.
+    pub async fn run_orphaned(&self, older_than: Duration) -> Result<Vec<String>> {
+        let now = chrono::Utc::now().timestamp();
+        let buckets = orphaned_buckets(&self.client.s3_list_buckets().await?, now, older_than);
+        for bucket in &buckets {
+            self.run(bucket).await?;
+        }
+        Ok(buckets)
+    }
+}
+
+/// Returns the start time in seconds of the query whose state bucket is named
+/// `<query code>-<timestamp>-<random id>`, or `None` if the bucket isn't a
+/// state bucket.
+fn state_bucket_timestamp(bucket: &str) -> Option<i64> {
+    let parts = bucket.split('-').collect::<Vec<_>>();
+    match parts.as_slice() {
+        [query_code, timestamp, random_id]
+            if !query_code.is_empty() && random_id.parse::<u128>().is_ok() =>
+        {
+            timestamp.parse::<i64>().ok()
+        }
+        _ => None,
+    }
+}
+
+/// Returns true if the bucket is named after a query id.
+pub fn is_state_bucket(bucket: &str) -> bool {
+    state_bucket_timestamp(bucket).is_some()
+}
+
+/// Returns the state buckets of the queries that started more than
+/// `older_than` before `now` (in seconds).
+pub fn orphaned_buckets(buckets: &[String], now: i64, older_than: Duration) -> Vec<String> {
+    buckets
+        .iter()
+        .filter(|bucket| {
+            state_bucket_timestamp(bucket).map_or(false, |ts| {
+                now.saturating_sub(ts) > older_than.as_secs() as i64
+            })
+        })
+        .cloned()
+        .collect()
+}
+
+/// Creates the state bucket of a query id, and records it as created by the
+/// query, so that the bucket is deleted with the query (see
+/// [`StateCleanup::run_query`]).
+pub async fn create_state_bucket(bucket: &str) -> Result<()> {
+    s3::create_bucket(bucket).await?;
+    StateCleanup::default().register(bucket).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aws::client::FakeCloudClient;
+
+    const BUCKET: &str = "q3-1643678938-1";
+
+    fn client(keys: usize) -> Arc<FakeCloudClient> {
+        let client = Arc::new(FakeCloudClient::new());
+        (1..=keys).for_each(|i| {
+            client.put_object(BUCKET, &format!("state/02/01/{}", i), vec![]);
+        });
+        client
+    }
+
+    #[tokio::test]
+    async fn cleanup_across_pages() -> Result<()> {
+        let client = client(2600);
+        let cleanup = StateCleanup::new(client.clone(), "flock").with_rate_limit(0);
+        let report = cleanup.run(BUCKET).await?;
+        assert_eq!(
+            report,
+            CleanupReport {
+                deleted:        2600,
+                bucket_deleted: true,
+            }
+        );
+        assert!(client.keys(BUCKET).is_empty());
+        assert_eq!(client.deleted_buckets(), vec![BUCKET]);
+        // The checkpoint is removed with the bucket.
+        assert!(client.keys("flock").is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resume_cleanup_from_checkpoint() -> Result<()> {
+        let client = client(2600);
+        let mut keys = client.keys(BUCKET);
+        let cleanup = StateCleanup::new(client.clone(), "flock")
+            .with_rate_limit(0)
+            .with_max_pages(2);
+        let report = cleanup.run(BUCKET).await?;
+        assert_eq!(report.deleted, 2000);
+        assert!(!report.bucket_deleted);
+        assert_eq!(client.keys(BUCKET), keys.split_off(2000));
+
+        let checkpoint_key = StateCleanup::checkpoint_key(BUCKET);
+        assert_eq!(checkpoint_key, "q3/cleanup/q3-1643678938-1");
+        let checkpoint: CleanupCheckpoint =
+            serde_json::from_slice(&client.object("flock", &checkpoint_key).unwrap())?;
+        assert_eq!(checkpoint.deleted, 2000);
+        assert_eq!(checkpoint.last_key.as_ref(), keys.last());
+
+        // The next run lists the keys after the checkpoint, and counts the
+        // objects deleted by the former run.
+        let cleanup = StateCleanup::new(client.clone(), "flock").with_rate_limit(0);
+        let report = cleanup.run(BUCKET).await?;
+        assert_eq!(report.deleted, 2600);
+        assert!(report.bucket_deleted);
+        assert!(client.keys(BUCKET).is_empty());
+        assert!(client.object("flock", &checkpoint_key).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resume_cleanup_after_failure() -> Result<()> {
+        let client = client(2500);
+        let cleanup = StateCleanup::new(client.clone(), "flock").with_rate_limit(0);
+        cleanup.clone().with_max_pages(1).run(BUCKET).await?;
+        assert_eq!(client.keys(BUCKET).len(), 1500);
+
+        // The failed run leaves the checkpoint as it was.
+        client.fail_next(BUCKET, 1);
+        assert!(cleanup.run(BUCKET).await.is_err());
+        let report = cleanup.run(BUCKET).await?;
+        assert_eq!(report.deleted, 2500);
+        assert_eq!(client.deleted_buckets(), vec![BUCKET]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cleanup_rate_limit() -> Result<()> {
+        let client = client(2500);
+        let cleanup = StateCleanup::new(client.clone(), "flock").with_rate_limit(5000);
+        let start = Instant::now();
+        cleanup.run(BUCKET).await?;
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cleanup_query_buckets() -> Result<()> {
+        let client = client(10);
+        client.put_object("q3-1643678999-2", "state/02/01/1", vec![]);
+        client.put_object("q3-1643678999-20", "state/02/01/1", vec![]);
+        client.put_object("q31-1643678999-3", "state/02/01/1", vec![]);
+        client.put_object("flock", "q3/completion/windows/w-00", vec![]);
+        let cleanup = StateCleanup::new(client.clone(), "flock").with_rate_limit(0);
+        for bucket in [BUCKET, "q3-1643678999-2", "q31-1643678999-3"] {
+            cleanup.register(bucket).await?;
+        }
+        assert_eq!(
+            StateCleanup::registry_key(BUCKET),
+            "q3/buckets/q3-1643678938-1"
+        );
+
+        // Only the buckets recorded as created by the query are deleted, along
+        // with their records.
+        assert_eq!(cleanup.run_query("q3").await?.len(), 2);
+        assert_eq!(client.deleted_buckets(), vec![BUCKET, "q3-1643678999-2"]);
+        assert_eq!(client.keys("q3-1643678999-20").len(), 1);
+        assert_eq!(client.keys("q31-1643678999-3").len(), 1);
+        assert_eq!(
+            client.keys("flock"),
+            vec!["q3/completion/windows/w-00", "q31/buckets/q31-1643678999-3"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn find_orphaned_buckets() {
+        let day = 24 * 3600;
+        let now = 1643678938 + 8 * day;
+        let buckets = [
+            "q3-1643678938-1",
+            "q5-1643678938-218735128523183619391499820347984139655",
+            "ysb-1644370138-2",
+            "flock-lab",
+            "flock-lab-data",
+            "q3-1643678938-abc",
+        ]
+        .iter()
+        .map(|b| b.to_string())
+        .collect::<Vec<_>>();
+        assert_eq!(
+            orphaned_buckets(&buckets, now, Duration::from_secs(7 * day as u64)),
+            vec![
+                "q3-1643678938-1",
+                "q5-1643678938-218735128523183619391499820347984139655"
+            ]
+        );
+        assert!(orphaned_buckets(&buckets, now, Duration::from_secs(9 * day as u64)).is_empty());
+    }
+}
@@ -60,17 +60,29 @@
 //! of magnitude slower than the memory state backends.
 
 mod s3;
-pub use s3::S3StateBackend;
+pub use s3::{state_key, state_key_prefix, S3StateBackend, STATE_KEY_PREFIX};
 
 mod efs;
 pub use efs::EfsStateBackend;
 
+mod recovery;
+pub use recovery::{recover_partitions, RecoveryBudget, RecoveryReport};
+
+mod cleanup;
+pub use cleanup::{
+    create_state_bucket, is_state_bucket, orphaned_buckets, CleanupCheckpoint, CleanupReport,
+    StateCleanup,
+};
+
 use crate::error::Result;
 use crate::runtime::payload::Payload;
 use async_trait::async_trait;
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use std::any::Any;
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::sync::Mutex;
 
 /// The state backend trait defines the interface for state backends.
 #[async_trait]
@@ -88,6 +100,51 @@ pub trait StateBackend: Debug + Send + Sync {
     async fn write(&self, bucket: String, key: String, payload_bytes: Vec<u8>) -> Result<()>;
     /// Reads payloads from the state backend.
     async fn read(&self, bucket: String, keys: Vec<String>) -> Result<Vec<Payload>>;
+    /// Writes an empty marker object to the state backend. The marker is used
+    /// to record progress that must outlive the function instance, e.g. a
+    /// window has been processed. By default, markers are not persisted.
+    async fn write_marker(&self, _bucket: String, _key: String) -> Result<()> {
+        Ok(())
+    }
+    /// Returns true if the marker exists in the state backend.
+    async fn marker_exists(&self, _bucket: String, _key: String) -> Result<bool> {
+        Ok(false)
+    }
+    /// Reads a small checkpoint object, e.g. the progress of a Kinesis shard,
+    /// with the version that [`write_checkpoint`](Self::write_checkpoint)
+    /// compares against. Returns `None` if the checkpoint isn't written. By
+    /// default, checkpoints are not persisted.
+    async fn read_checkpoint(&self, _bucket: String, _key: String) -> Result<Option<Checkpoint>> {
+        Ok(None)
+    }
+    /// Writes the checkpoint only if it's still at the version read by the
+    /// caller, or still missing if the version is `None`. Returns false if
+    /// another writer updated it in between.
+    async fn write_checkpoint(
+        &self,
+        _bucket: String,
+        _key: String,
+        _bytes: Vec<u8>,
+        _version: Option<String>,
+    ) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+/// A checkpoint object read from the state backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// The content of the checkpoint.
+    pub bytes:   Vec<u8>,
+    /// The version of the checkpoint, which changes on every write.
+    pub version: String,
+}
+
+lazy_static! {
+    /// The checkpoints held by the function instance of the
+    /// HashMapStateBackend, keyed by bucket and key.
+    static ref CHECKPOINTS: Mutex<HashMap<(String, String), (Vec<u8>, u64)>> =
+        Mutex::new(HashMap::new());
 }
 
 /// The default state backend.
@@ -120,6 +177,36 @@ impl StateBackend for HashMapStateBackend {
     async fn read(&self, _: String, _: Vec<String>) -> Result<Vec<Payload>> {
         unreachable!()
     }
+
+    async fn read_checkpoint(&self, bucket: String, key: String) -> Result<Option<Checkpoint>> {
+        Ok(CHECKPOINTS
+            .lock()
+            .unwrap()
+            .get(&(bucket, key))
+            .map(|(bytes, version)| Checkpoint {
+                bytes:   bytes.clone(),
+                version: version.to_string(),
+            }))
+    }
+
+    async fn write_checkpoint(
+        &self,
+        bucket: String,
+        key: String,
+        bytes: Vec<u8>,
+        version: Option<String>,
+    ) -> Result<bool> {
+        let mut checkpoints = CHECKPOINTS.lock().unwrap();
+        let current = checkpoints
+            .get(&(bucket.clone(), key.clone()))
+            .map(|(_, v)| v.to_string());
+        if current != version {
+            return Ok(false);
+        }
+        let next = version.map_or(0, |v| v.parse::<u64>().unwrap_or_default() + 1);
+        checkpoints.insert((bucket, key), (bytes, next));
+        Ok(true)
+    }
 }
 
 impl HashMapStateBackend {
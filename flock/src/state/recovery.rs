@@ -0,0 +1,417 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The straggler recovery reads the data partitions of an incomplete window
+//! from the state backend, which the upstream functions checkpointed but
+//! whose invocations haven't reached the aggregator yet.
+//!
+//! Hundreds of the members of a function group may recover their windows at
+//! the same time, so the reads are bounded to avoid the S3 request storms: the
+//! data partitions are read a few at a time, the reads throttled by S3 with
+//! `SlowDown` back off exponentially and halve the concurrency of the next
+//! chunks, and each invocation reads at most a budget of keys or spends at
+//! most a budget of time. The payloads are fed to the arena chunk by chunk, so
+//! that the recovery stops as soon as the window is complete, and the arena
+//! bitmap records the ingested partitions, so that a later invocation only
+//! reads the rest.
+
+use crate::configs::*;
+use crate::error::{FlockError, Result};
+use crate::runtime::deadline::{self, SystemClock};
+use crate::runtime::metrics::{self, Metric};
+use crate::runtime::payload::Payload;
+use crate::state::StateBackend;
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+/// The number of retries of a throttled read before the error is returned.
+const MAX_RETRIES: usize = 5;
+
+/// The longest backoff between two attempts of a read.
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// The bounds of the straggler recovery of one invocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryBudget {
+    /// The number of data partitions read at once.
+    pub concurrency: usize,
+    /// The number of data partitions read at most.
+    pub max_keys:    usize,
+    /// The time spent at most, after which the chunk in flight is the last.
+    pub max_time:    Duration,
+    /// The backoff before the first retry of a throttled read, which doubles
+    /// on every retry.
+    pub backoff:     Duration,
+}
+
+impl Default for RecoveryBudget {
+    fn default() -> Self {
+        Self {
+            concurrency: *FLOCK_S3_RECOVERY_CONCURRENCY,
+            max_keys:    *FLOCK_S3_RECOVERY_MAX_KEYS,
+            max_time:    Duration::from_millis(*FLOCK_S3_RECOVERY_MAX_MILLIS),
+            backoff:     Duration::from_millis(50),
+        }
+    }
+}
+
+/// The progress of the straggler recovery of one invocation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// The number of data partitions read.
+    pub read:      usize,
+    /// The number of data partitions left to a later invocation.
+    pub remaining: usize,
+    /// The number of retries of the throttled reads.
+    pub retries:   usize,
+    /// Whether the window is complete.
+    pub complete:  bool,
+}
+
+/// Reads the data partitions of a window within the budget, and feeds them to
+/// the arena chunk by chunk.
+///
+/// # Arguments
+/// * `backend` - The state backend of the data partitions.
+/// * `bucket` - The state bucket of the query.
+/// * `keys` - The keys of the missing data partitions.
+/// * `budget` - The bounds of the recovery.
+/// * `ingest` - Feeds a payload to the arena, and returns true once the window
+///   is complete.
+///
+/// # Returns
+/// The progress of the recovery, or the first error of the reads that isn't
+/// resolved by the retries or of the payloads that can't be fed, after the
+/// other payloads of its chunk are fed.
+pub async fn recover_partitions<F>(
+    backend: &dyn StateBackend,
+    bucket: &str,
+    keys: Vec<String>,
+    budget: &RecoveryBudget,
+    mut ingest: F,
+) -> Result<RecoveryReport>
+where
+    F: FnMut(Payload) -> Result<bool>,
+{
+    let start = Instant::now();
+    let mut report = RecoveryReport::default();
+    let mut concurrency = budget.concurrency.max(1);
+    let mut pending = keys.into_iter();
+
+    while !report.complete && report.read < budget.max_keys && start.elapsed() < budget.max_time {
+        let chunk = pending
+            .by_ref()
+            .take(concurrency.min(budget.max_keys - report.read))
+            .collect::<Vec<_>>();
+        if chunk.is_empty() {
+            break;
+        }
+
+        let results = futures::future::join_all(
+            chunk
+                .iter()
+                .map(|key| read_with_backoff(backend, bucket, key, budget)),
+        )
+        .await;
+
+        let mut error = None;
+        let mut throttled = false;
+        for result in results {
+            match result {
+                Ok((payload, retries)) => {
+                    report.read += 1;
+                    report.retries += retries;
+                    throttled |= retries > 0;
+                    if let Some(payload) = payload {
+                        match ingest(payload) {
+                            Ok(complete) => report.complete |= complete,
+                            Err(e) => {
+                                error.get_or_insert(e);
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = error {
+            return Err(e);
+        }
+        if throttled {
+            concurrency = (concurrency / 2).max(1);
+        }
+    }
+
+    report.remaining = pending.count();
+    Ok(report)
+}
+
+/// Reads a data partition, and retries the reads throttled by S3 with an
+/// exponential backoff.
+///
+/// # Returns
+/// The payload of the data partition and the number of retries.
+async fn read_with_backoff(
+    backend: &dyn StateBackend,
+    bucket: &str,
+    key: &str,
+    budget: &RecoveryBudget,
+) -> Result<(Option<Payload>, usize)> {
+    let mut retries = 0;
+    loop {
+        match backend.read(bucket.to_owned(), vec![key.to_owned()]).await {
+            Ok(mut payloads) => return Ok((payloads.pop(), retries)),
+            Err(e) if is_slow_down(&e) && retries < MAX_RETRIES => {
+                let backoff = backoff(budget.backoff, retries);
+                // The retries stop early if the backoff would exceed the deadline
+                // of the invocation, which leaves the rest to a later invocation.
+                if let Some(deadline) = deadline::current() {
+                    if !deadline.allows(&SystemClock, backoff) {
+                        return Err(e);
+                    }
+                }
+                metrics::scope().incr(Metric::Retries);
+                tokio::time::sleep(backoff).await;
+                retries += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Returns the backoff before the retry, i.e. the base backoff doubled on
+/// every retry with a random jitter of up to the base backoff.
+fn backoff(base: Duration, retries: usize) -> Duration {
+    let base = base.as_millis() as u64;
+    let jitter = rand::thread_rng().gen_range(0..=base);
+    Duration::from_millis(base.saturating_mul(1 << retries.min(16)) + jitter).min(MAX_BACKOFF)
+}
+
+/// Returns true if S3 asked to reduce the request rate.
+fn is_slow_down(e: &FlockError) -> bool {
+    match e {
+        FlockError::AWS(message) => {
+            message.contains("SlowDown") || message.contains("reduce your request rate")
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::arena::{Arena, WindowId};
+    use crate::runtime::payload::UuidBuilder;
+    use crate::transmute::to_payload;
+    use async_trait::async_trait;
+    use datafusion::arrow::array::Int64Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use serde::{Deserialize, Serialize};
+    use std::any::Any;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// A state backend that throttles the reads above the concurrency limit
+    /// like S3 does with `SlowDown`.
+    #[derive(Default, Debug, Serialize, Deserialize)]
+    struct ThrottledStateBackend {
+        #[serde(skip)]
+        objects:   Mutex<HashMap<String, Payload>>,
+        limit:     usize,
+        #[serde(skip)]
+        in_flight: AtomicUsize,
+        #[serde(skip)]
+        peak:      AtomicUsize,
+        #[serde(skip)]
+        reads:     AtomicUsize,
+        #[serde(skip)]
+        throttled: AtomicUsize,
+    }
+
+    #[async_trait]
+    #[typetag::serde(name = "throttled_state_backend")]
+    impl StateBackend for ThrottledStateBackend {
+        fn name(&self) -> String {
+            "ThrottledStateBackend".to_string()
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_mut_any(&mut self) -> &mut dyn Any {
+            self
+        }
+
+        async fn write(&self, _bucket: String, _key: String, _bytes: Vec<u8>) -> Result<()> {
+            Ok(())
+        }
+
+        async fn read(&self, _bucket: String, keys: Vec<String>) -> Result<Vec<Payload>> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            if in_flight > self.limit {
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                self.throttled.fetch_add(1, Ordering::SeqCst);
+                return Err(FlockError::AWS(
+                    "SlowDown: Please reduce your request rate.".to_string(),
+                ));
+            }
+            self.peak.fetch_max(in_flight, Ordering::SeqCst);
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            let objects = self.objects.lock().unwrap();
+            Ok(keys.iter().map(|key| objects[key].clone()).collect())
+        }
+    }
+
+    /// Returns the backend with the data partitions of a window of the given
+    /// size, and the id of the window.
+    fn partitions(size: usize, limit: usize) -> (ThrottledStateBackend, WindowId, Vec<String>) {
+        let schema = Arc::new(Schema::new(vec![Field::new("c", DataType::Int64, false)]));
+        let uuids = UuidBuilder::new_with_ts("q4-recovery", 1024, size);
+        let mut objects = HashMap::new();
+        let mut keys = vec![];
+        for i in 1..=size {
+            let batch = RecordBatch::try_new(
+                schema.clone(),
+                vec![Arc::new(Int64Array::from(vec![i as i64]))],
+            )
+            .unwrap();
+            let key = format!("state/02/00/{:02}", i);
+            objects.insert(
+                key.clone(),
+                to_payload(&[batch], &[], uuids.get(i), false).unwrap(),
+            );
+            keys.push(key);
+        }
+        let window_id = objects[&keys[0]].get_window_id();
+        let backend = ThrottledStateBackend {
+            objects: Mutex::new(objects),
+            limit,
+            ..Default::default()
+        };
+        (backend, window_id, keys)
+    }
+
+    fn budget(concurrency: usize, max_keys: usize) -> RecoveryBudget {
+        RecoveryBudget {
+            concurrency,
+            max_keys,
+            max_time: Duration::from_secs(60),
+            backoff: Duration::from_millis(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn recover_under_rate_limit() -> Result<()> {
+        // S3 throttles the reads above 4 at once, and the recovery starts with 8.
+        let (backend, window_id, keys) = partitions(40, 4);
+        let mut arena = Arena::new();
+        let report = recover_partitions(&backend, "bucket", keys, &budget(8, 1000), |payload| {
+            arena.collect(payload)?;
+            Ok(arena.is_complete(&window_id))
+        })
+        .await?;
+
+        assert!(report.complete && arena.is_complete(&window_id));
+        assert_eq!(report.read, 40);
+        assert_eq!(report.remaining, 0);
+        // Only the first chunk is throttled, since the concurrency is halved
+        // afterwards, and each partition is read once.
+        assert!(backend.throttled.load(Ordering::SeqCst) >= 4);
+        assert_eq!(report.retries, backend.throttled.load(Ordering::SeqCst));
+        assert_eq!(backend.reads.load(Ordering::SeqCst), 40);
+        assert!(backend.peak.load(Ordering::SeqCst) <= 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recover_within_budget() -> Result<()> {
+        let (backend, window_id, keys) = partitions(40, 8);
+        let mut arena = Arena::new();
+
+        // The first invocation reads 16 partitions and leaves the rest.
+        let report = recover_partitions(
+            &backend,
+            "bucket",
+            keys.clone(),
+            &budget(8, 16),
+            |payload| {
+                arena.collect(payload)?;
+                Ok(arena.is_complete(&window_id))
+            },
+        )
+        .await?;
+        assert!(!report.complete);
+        assert_eq!((report.read, report.remaining), (16, 24));
+
+        // The next invocation skips the partitions ingested in the bitmap.
+        let bitmap = arena.get_bitmap(&window_id).unwrap();
+        let keys = keys
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| !bitmap.is_set(i + 1))
+            .map(|(_, key)| key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys.len(), 24);
+        let report = recover_partitions(&backend, "bucket", keys, &budget(8, 16), |payload| {
+            arena.collect(payload)?;
+            Ok(arena.is_complete(&window_id))
+        })
+        .await?;
+        assert!(!report.complete);
+        assert_eq!((report.read, report.remaining), (16, 8));
+
+        // No partition is read twice across the invocations.
+        assert_eq!(backend.reads.load(Ordering::SeqCst), 32);
+        assert_eq!(backend.throttled.load(Ordering::SeqCst), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recover_stops_on_errors() -> Result<()> {
+        // S3 throttles every read, so the retries give up after a few attempts
+        // instead of amplifying the load.
+        let (backend, window_id, keys) = partitions(8, 0);
+        let mut arena = Arena::new();
+        let result = recover_partitions(&backend, "bucket", keys, &budget(2, 100), |payload| {
+            arena.collect(payload)?;
+            Ok(arena.is_complete(&window_id))
+        })
+        .await;
+        assert!(matches!(result, Err(FlockError::AWS(_))));
+        assert_eq!(
+            backend.throttled.load(Ordering::SeqCst),
+            2 * (MAX_RETRIES + 1)
+        );
+        assert!(arena.get_bitmap(&window_id).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let base = Duration::from_millis(50);
+        assert!((50..=100).contains(&(backoff(base, 0).as_millis() as u64)));
+        assert!((200..=250).contains(&(backoff(base, 2).as_millis() as u64)));
+        assert_eq!(backoff(base, 10), MAX_BACKOFF);
+        assert!(is_slow_down(&FlockError::AWS(
+            "<Code>SlowDown</Code>".to_string()
+        )));
+        assert!(!is_slow_down(&FlockError::AWS("NoSuchKey".to_string())));
+    }
+}
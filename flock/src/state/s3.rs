@@ -13,31 +13,67 @@
 
 //! Use S3 state backend to manage the state of the execution engine.
 
-use super::StateBackend;
+use super::{Checkpoint, StateBackend};
 use crate::aws::s3;
-use crate::error::Result;
-use crate::runtime::arena::Bitmap;
+use crate::encryption;
+use crate::error::{FlockError, Result};
+use crate::runtime::arena::{Bitmap, WindowId};
 use crate::runtime::payload::Payload;
+use crate::transmute::to_payload;
 use async_trait::async_trait;
+use datafusion::arrow::record_batch::RecordBatch;
 use serde::{Deserialize, Serialize};
 use std::any::Any;
 use tokio::task::JoinHandle;
 
+/// The S3 key prefix of all query states. An S3 lifecycle rule on this prefix
+/// can expire the states of the old queries.
+pub const STATE_KEY_PREFIX: &str = "state/";
+
+/// Returns the S3 key prefix `state/<plan index>/<shuffle id>/` of the data
+/// partitions of a window.
+pub fn state_key_prefix(plan_index: usize, shuffle_id: usize) -> String {
+    format!("{}{:02}/{:02}/", STATE_KEY_PREFIX, plan_index, shuffle_id)
+}
+
+/// Returns the S3 key of a data partition. The sequence number is negative if
+/// the data partition is empty.
+pub fn state_key(plan_index: usize, shuffle_id: usize, seq_num: i32) -> String {
+    format!("{}{:02}", state_key_prefix(plan_index, shuffle_id), seq_num)
+}
+
+/// The merged partial states of a window written by the aggregator.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct CompactedState {
+    /// The number of relations of the window, which is kept even if the
+    /// relation has no data.
+    relations: usize,
+    /// The record batches of all data partitions, one relation per data field.
+    payload:   Payload,
+}
+
 /// S3StateBackend is a state backend that stores query states in Amazon S3.
 ///
 /// S3 bucket name is the qid of the function payload:
 ///
 /// | query code | timestamp  | random string |
 ///
-/// S3 key composed of the following parts:
+/// S3 key composed of the following parts, under the prefix `state/` so that
+/// the old states can be expired by an S3 lifecycle rule:
 ///
-/// | plan index | shuffle id | sequence id   |
+/// | state | plan index | shuffle id | sequence id   |
 ///
 /// If the corresponding data partition is empty, we add a negative sign to the
 /// sequence id. This is the reason why the sequence id starts from 1, because
 /// we want to distinguish empty data partitions from non-empty ones.
 ///
-/// | plan index | shuffle id | -sequence id   |
+/// | state | plan index | shuffle id | -sequence id  |
+///
+/// Once the aggregator has assembled a window, the merged partial states are
+/// written to a single compacted object, which is preferred over the data
+/// partitions by the recovery:
+///
+/// | state | plan index | shuffle id | compacted-<query id> |
 ///
 /// Note: Parts of component are derived from cloud function name. The cloud
 /// function name has three parts: | query code | plan index | group index |.
@@ -63,7 +99,7 @@ impl StateBackend for S3StateBackend {
     }
 
     async fn write(&self, bucket: String, key: String, payload_bytes: Vec<u8>) -> Result<()> {
-        s3::put_object(&bucket, &key, payload_bytes).await
+        s3::put_object(&bucket, &key, encryption::seal_bytes(payload_bytes)?).await
     }
 
     async fn read(&self, bucket: String, keys: Vec<String>) -> Result<Vec<Payload>> {
@@ -72,16 +108,63 @@ impl StateBackend for S3StateBackend {
             .map(|key| {
                 let b = bucket.clone();
                 tokio::spawn(async move {
-                    Ok(serde_json::from_slice(&s3::get_object(&b, &key).await?)?)
+                    let bytes = encryption::open_bytes(s3::get_object(&b, &key).await?).await?;
+                    let mut payload = Payload::from_slice(&bytes)?;
+                    encryption::open_payload(&mut payload).await?;
+                    Ok(payload)
                 })
             })
             .collect::<Vec<JoinHandle<Result<Payload>>>>();
 
-        Ok(futures::future::join_all(tasks)
+        futures::future::join_all(tasks)
             .await
             .into_iter()
-            .map(|r| r.unwrap().unwrap())
-            .collect())
+            .map(|r| r.map_err(|e| FlockError::Internal(e.to_string()))?)
+            .collect()
+    }
+
+    async fn write_marker(&self, bucket: String, key: String) -> Result<()> {
+        s3::put_object(&bucket, &key, vec![]).await
+    }
+
+    async fn marker_exists(&self, bucket: String, key: String) -> Result<bool> {
+        Ok(s3::get_matched_keys(&bucket, &key)
+            .await?
+            .into_iter()
+            .any(|k| k == key))
+    }
+
+    async fn read_checkpoint(&self, bucket: String, key: String) -> Result<Option<Checkpoint>> {
+        if !self.marker_exists(bucket.clone(), key.clone()).await? {
+            return Ok(None);
+        }
+        // The entity tag is read before the body, so that a concurrent write
+        // in between fails the next conditional write instead of being lost.
+        let (_, version) = s3::head_object(&bucket, &key).await?;
+        let bytes = s3::get_object(&bucket, &key).await?;
+        Ok(Some(Checkpoint { bytes, version }))
+    }
+
+    /// The S3 SDK has no conditional put, so the entity tag is compared right
+    /// before the put. The checkpoints of different Kinesis shards never share
+    /// a key, and the writers of the same shard are serialized by the event
+    /// source mapping, which leaves this narrow window to the retries.
+    async fn write_checkpoint(
+        &self,
+        bucket: String,
+        key: String,
+        bytes: Vec<u8>,
+        version: Option<String>,
+    ) -> Result<bool> {
+        let current = match self.marker_exists(bucket.clone(), key.clone()).await? {
+            true => Some(s3::head_object(&bucket, &key).await?.1),
+            false => None,
+        };
+        if current != version {
+            return Ok(false);
+        }
+        s3::put_object(&bucket, &key, bytes).await?;
+        Ok(true)
     }
 }
 
@@ -104,17 +187,12 @@ impl S3StateBackend {
     /// * `prefix` - The S3 key prefix to store each data partition.
     ///
     /// # Returns
-    /// A vector of S3 keys in usize format.
+    /// A vector of the sequence numbers of the S3 keys.
     pub async fn read_s3_keys(&self, bucket: &str, prefix: &str) -> Result<Vec<i32>> {
-        Ok(s3::get_matched_keys(bucket, prefix)
+        Ok(s3::get_matched_keys(bucket, &key_prefix(prefix))
             .await?
-            .into_iter()
-            .map(|key| {
-                let mut key_parts = key.split('/');
-                key_parts.next(); // skip the plan index
-                key_parts.next(); // skip the shuffle id
-                key_parts.next().unwrap().parse::<i32>().unwrap()
-            })
+            .iter()
+            .filter_map(|key| parse_seq_num(key))
             .collect())
     }
 
@@ -127,7 +205,11 @@ impl S3StateBackend {
     /// # Returns
     /// The number of S3 keys.
     pub async fn get_s3_key_num(&self, bucket: &str, prefix: &str) -> Result<usize> {
-        Ok(s3::get_matched_keys(bucket, prefix).await?.len())
+        Ok(s3::get_matched_keys(bucket, &key_prefix(prefix))
+            .await?
+            .iter()
+            .filter(|key| parse_seq_num(key).is_some())
+            .count())
     }
 
     /// Returns the latest checkpointed keys.
@@ -138,33 +220,360 @@ impl S3StateBackend {
     /// * `old_keys` - The keys that have been checkpointed before.
     ///
     /// # Returns
-    /// * The difference between the latest checkpointed keys and the old keys.
+    /// * The difference between the latest checkpointed keys and the old keys,
+    ///   sorted by the sequence number.
     pub async fn new_s3_keys(
         &self,
         bucket: &str,
         prefix: &str,
         old_keys: &Bitmap,
     ) -> Result<Vec<String>> {
-        Ok(self
-            .read_s3_keys(bucket, prefix)
-            .await?
-            .into_iter()
-            .filter(|seq_num| !old_keys.is_set((*seq_num).abs() as usize))
-            .map(|seq_num| format!("{:02}/{:02}", prefix, seq_num))
-            .collect())
+        Ok(self.partition_keys(self.list_keys(bucket, prefix).await?, old_keys))
+    }
+
+    /// Lists the S3 keys of the data partitions and the compacted objects with
+    /// a single request, so that the recovery can both check the compacted
+    /// object and find the new data partitions from the same listing.
+    ///
+    /// # Arguments
+    /// * `bucket` - The S3 bucket to store the checkpoint.
+    /// * `prefix` - The S3 key prefix to store data partitions.
+    pub async fn list_keys(&self, bucket: &str, prefix: &str) -> Result<Vec<String>> {
+        s3::get_matched_keys(bucket, &key_prefix(prefix)).await
+    }
+
+    /// Returns the listed keys of the data partitions that are not in the
+    /// bitmap, sorted by the sequence number.
+    ///
+    /// # Arguments
+    /// * `keys` - The keys returned by [`S3StateBackend::list_keys`].
+    /// * `old_keys` - The keys that have been checkpointed before.
+    pub fn partition_keys(&self, keys: Vec<String>, old_keys: &Bitmap) -> Vec<String> {
+        new_keys(keys, old_keys)
+    }
+
+    /// Writes the merged partial states of a window to a single compacted
+    /// object, so that the recovery reads one object instead of all data
+    /// partitions of the window.
+    ///
+    /// # Arguments
+    /// * `bucket` - The S3 bucket to store the checkpoint.
+    /// * `prefix` - The S3 key prefix to store data partitions.
+    /// * `window_id` - The window that has been assembled.
+    /// * `input` - The record batches of the window assembled by the arena.
+    pub async fn write_compacted(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        window_id: &WindowId,
+        input: &[Vec<Vec<RecordBatch>>],
+    ) -> Result<()> {
+        s3::put_object(
+            bucket,
+            &compacted_key(prefix, window_id),
+            encryption::seal_bytes(serde_json::to_vec(&compact(input)?)?)?,
+        )
+        .await
     }
+
+    /// Reads the compacted object of a window if it is in the listed keys. The
+    /// compacted object is only written once the window has been assembled, so
+    /// its presence means the window has been reported complete.
+    ///
+    /// # Arguments
+    /// * `bucket` - The S3 bucket to store the checkpoint.
+    /// * `prefix` - The S3 key prefix to store data partitions.
+    /// * `window_id` - The window to recover.
+    /// * `keys` - The keys returned by [`S3StateBackend::list_keys`].
+    ///
+    /// # Returns
+    /// The record batches of the window in the same layout as the arena.
+    pub async fn read_compacted(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        window_id: &WindowId,
+        keys: &[String],
+    ) -> Result<Option<Vec<Vec<Vec<RecordBatch>>>>> {
+        let key = compacted_key(prefix, window_id);
+        if !keys.iter().any(|k| *k == key) {
+            return Ok(None);
+        }
+        let mut state: CompactedState = serde_json::from_slice(
+            &encryption::open_bytes(s3::get_object(bucket, &key).await?).await?,
+        )?;
+        encryption::open_payload(&mut state.payload).await?;
+        Ok(Some(restore(state)?))
+    }
+}
+
+/// Returns the S3 key `<prefix>/compacted-<query id>` of the compacted object
+/// of a window. It isn't a sequence number, so the compacted object is never
+/// taken for a data partition.
+fn compacted_key(prefix: &str, window_id: &WindowId) -> String {
+    format!("{}compacted-{}", key_prefix(prefix), window_id.0)
+}
+
+/// Merges the data partitions of a window into a single payload.
+fn compact(input: &[Vec<Vec<RecordBatch>>]) -> Result<CompactedState> {
+    let relation = |i: usize| -> Vec<RecordBatch> {
+        input
+            .get(i)
+            .map(|partitions| partitions.iter().flatten().cloned().collect())
+            .unwrap_or_default()
+    };
+    Ok(CompactedState {
+        relations: input.len(),
+        payload:   to_payload(&relation(0), &relation(1), Default::default(), false)?,
+    })
+}
+
+/// Restores the record batches of a window from the compacted state. All
+/// record batches of a relation are in a single partition.
+fn restore(state: CompactedState) -> Result<Vec<Vec<Vec<RecordBatch>>>> {
+    let (r1, r2) = state.payload.to_record_batch()?;
+    Ok([r1, r2]
+        .into_iter()
+        .take(state.relations)
+        .map(|batches| {
+            if batches.is_empty() {
+                vec![]
+            } else {
+                vec![batches]
+            }
+        })
+        .collect())
+}
+
+/// Returns the S3 key prefix `<plan index>/<shuffle id>/` of the data
+/// partitions, so that the listing doesn't match other shuffle ids with the
+/// same leading digits.
+fn key_prefix(prefix: &str) -> String {
+    if prefix.ends_with('/') {
+        prefix.to_owned()
+    } else {
+        format!("{}/", prefix)
+    }
+}
+
+/// Parses the sequence number of the S3 key `<plan index>/<shuffle id>/<seq>`.
+/// The sequence number is negative if the data partition is empty.
+fn parse_seq_num(key: &str) -> Option<i32> {
+    key.rsplit('/').next()?.parse::<i32>().ok()
+}
+
+/// Returns the keys whose sequence numbers are not in the bitmap, sorted by
+/// the sequence number. The sequence numbers are compared instead of the key
+/// strings, so the empty data partitions and the zero-padded sequence numbers
+/// are matched as well.
+fn new_keys(keys: Vec<String>, old_keys: &Bitmap) -> Vec<String> {
+    let mut keys = keys
+        .into_iter()
+        .filter_map(|key| parse_seq_num(&key).map(|seq_num| (seq_num.unsigned_abs(), key)))
+        .filter(|(seq_num, _)| !old_keys.is_set(*seq_num as usize))
+        .collect::<Vec<_>>();
+    keys.sort_by_key(|(seq_num, _)| *seq_num);
+    keys.into_iter().map(|(_, key)| key).collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::runtime::arena::Arena;
+    use crate::runtime::payload::UuidBuilder;
+    use datafusion::arrow::csv;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::util::pretty::pretty_format_batches;
+    use rusoto_s3::{ListObjectsV2Output, Object};
+    use std::sync::Arc;
+
+    fn uk_cities() -> Vec<RecordBatch> {
+        let schema = Schema::new(vec![
+            Field::new("city", DataType::Utf8, false),
+            Field::new("lat", DataType::Float64, false),
+            Field::new("lng", DataType::Float64, false),
+        ]);
+        let records: &[u8] = include_str!("../tests/data/uk_cities_with_headers.csv").as_bytes();
+        csv::Reader::new(records, Arc::new(schema), true, None, 5, None, None)
+            .map(|batch| batch.unwrap())
+            .collect()
+    }
+
+    /// Recovers the window from the data partitions written by the upstream
+    /// functions, i.e. the uncompacted state.
+    async fn recover_from_partitions(partitions: &[Vec<u8>]) -> Result<Vec<Vec<Vec<RecordBatch>>>> {
+        let mut arena = Arena::new();
+        let mut window_id = None;
+        for bytes in partitions {
+            let payload: Payload = serde_json::from_slice(bytes)?;
+            window_id = Some(payload.get_window_id());
+            arena.collect(payload)?;
+        }
+        arena.take(&window_id.unwrap()).await
+    }
+
+    /// Returns the rows of each relation of the window.
+    fn rows(input: &[Vec<Vec<RecordBatch>>]) -> Vec<String> {
+        input
+            .iter()
+            .map(|partitions| {
+                pretty_format_batches(&partitions.concat())
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn recover_from_compacted_state() -> Result<()> {
+        let batches = uk_cities();
+        let uuids = UuidBuilder::new_with_ts("q4-01-00", 1024, batches.len());
+        let partitions = batches
+            .iter()
+            .enumerate()
+            .map(|(i, batch)| {
+                serde_json::to_vec(
+                    &to_payload(&[batch.clone()], &[], uuids.get(i + 1), false).unwrap(),
+                )
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        let uncompacted = recover_from_partitions(&partitions).await?;
+        assert_eq!(uncompacted[0].len(), batches.len());
+
+        // The aggregator compacts the window once it has been assembled.
+        let bytes = serde_json::to_vec(&compact(&uncompacted)?)?;
+        let compacted = restore(serde_json::from_slice(&bytes)?)?;
+        assert_eq!(compacted.len(), uncompacted.len());
+        assert_eq!(compacted[0].len(), 1);
+        assert_eq!(rows(&compacted), rows(&uncompacted));
+
+        Ok(())
+    }
+
+    #[test]
+    fn compacted_state_layout() -> Result<()> {
+        let compacted = restore(compact(&[vec![], vec![]])?)?;
+        assert_eq!(compacted.len(), 2);
+        assert!(compacted.iter().all(|relation| relation.is_empty()));
+
+        let window_id = ("q4-1642991536-2187".to_string(), 1);
+        let key = compacted_key(&state_key_prefix(2, 1), &window_id);
+        assert_eq!(key, "state/02/01/compacted-q4-1642991536-2187");
+        // The compacted object is never taken for a data partition.
+        assert_eq!(parse_seq_num(&key), None);
+        assert_eq!(state_key(2, 1, 3), "state/02/01/03");
+        assert_eq!(state_key(2, 1, -3), "state/02/01/-3");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recover_from_a_single_listing() -> Result<()> {
+        let backend = S3StateBackend::new();
+        let prefix = state_key_prefix(2, 1);
+        let window_id = ("q4-1642991536-2187".to_string(), 1);
+        let mut keys = vec![state_key(2, 1, 3), state_key(2, 1, -2), state_key(2, 1, 1)];
+
+        // No compacted object is listed, so nothing is read from S3.
+        assert!(backend
+            .read_compacted("bucket", &prefix, &window_id, &keys)
+            .await?
+            .is_none());
+
+        // The same listing gives the new data partitions, without the compacted
+        // object once the window has been reported complete.
+        keys.push(compacted_key(&prefix, &window_id));
+        let mut old_keys = Bitmap::new(9);
+        old_keys.set(1);
+        assert_eq!(
+            backend.partition_keys(keys, &old_keys),
+            vec![state_key(2, 1, -2), state_key(2, 1, 3)]
+        );
+        Ok(())
+    }
+
+    /// Mocks the `ListObjectsV2` API, which returns at most 1000 keys per page.
+    async fn list_page(keys: &[String], token: Option<String>) -> Result<ListObjectsV2Output> {
+        let start = token.map(|t| t.parse::<usize>().unwrap()).unwrap_or(0);
+        let end = (start + 1000).min(keys.len());
+        Ok(ListObjectsV2Output {
+            contents: Some(
+                keys[start..end]
+                    .iter()
+                    .map(|key| Object {
+                        key: Some(key.clone()),
+                        ..Default::default()
+                    })
+                    .collect(),
+            ),
+            is_truncated: Some(end < keys.len()),
+            next_continuation_token: (end < keys.len()).then(|| end.to_string()),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn new_s3_keys_across_pages() -> Result<()> {
+        // 2500 data partitions, every 10th of them is empty. S3 lists the keys
+        // in the lexicographical order.
+        let mut all_keys = (1..=2500)
+            .map(|i| {
+                if i % 10 == 0 {
+                    format!("02/01/-{}", i)
+                } else {
+                    format!("02/01/{}", i)
+                }
+            })
+            .collect::<Vec<_>>();
+        all_keys.sort();
+
+        let mut pages = 0;
+        let keys = s3::list_all_keys(|token| {
+            pages += 1;
+            list_page(&all_keys, token)
+        })
+        .await?;
+        assert_eq!(pages, 3);
+        assert_eq!(keys.len(), 2500);
+
+        // The bitmap has the payloads 1..=2000, including the empty ones.
+        let mut old_keys = Bitmap::new(2501);
+        (1..=2000).for_each(|i| old_keys.set(i));
+        let keys = new_keys(keys, &old_keys);
+        assert_eq!(keys.len(), 500);
+        assert_eq!(keys[0], "02/01/2001");
+        assert_eq!(keys[9], "02/01/-2010");
+        assert_eq!(keys[499], "02/01/-2500");
+        assert!(keys
+            .iter()
+            .map(|key| parse_seq_num(key).unwrap().abs())
+            .eq(2001..=2500));
+        Ok(())
+    }
+
+    #[test]
+    fn new_keys_with_zero_padding() {
+        let mut old_keys = Bitmap::new(9);
+        old_keys.set(1);
+        old_keys.set(2);
+        let keys = vec![
+            "02/01/03".to_string(),
+            "02/01/02".to_string(),
+            "02/01/-01".to_string(),
+            "02/01/-4".to_string(),
+            "02/01/".to_string(),
+        ];
+        assert_eq!(new_keys(keys, &old_keys), vec!["02/01/03", "02/01/-4"]);
+        assert_eq!(key_prefix("02/01"), "02/01/");
+        assert_eq!(key_prefix("02/01/"), "02/01/");
+    }
 
     #[tokio::test]
     #[ignore]
     async fn test_read_s3_keys() {
         let s3_state_backend = S3StateBackend::new();
         let bucket = "q4-1642991536-218735128523183619391499820347984139655";
-        let prefix = "02/01";
+        let prefix = "state/02/01";
         s3_state_backend
             .read_s3_keys(bucket, prefix)
             .await
@@ -180,7 +589,7 @@ mod tests {
     async fn test_new_s3_keys() {
         let s3_state_backend = S3StateBackend::new();
         let bucket = "q4-1642991536-218735128523183619391499820347984139655";
-        let prefix = "02/01";
+        let prefix = "state/02/01";
         let mut old_keys = Bitmap::new(9);
         s3_state_backend
             .new_s3_keys(bucket, prefix, &old_keys)
@@ -209,7 +618,7 @@ mod tests {
     async fn test_s3_read_objects() {
         let s3_state_backend = S3StateBackend::new();
         let bucket = "q4-1642991536-218735128523183619391499820347984139655";
-        let prefix = "02/01";
+        let prefix = "state/02/01";
         let keys = s3_state_backend
             .read_s3_keys(bucket, prefix)
             .await
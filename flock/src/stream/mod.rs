@@ -15,4 +15,4 @@
 //! sources.
 
 pub mod window;
-pub use window::{Schedule, Window};
+pub use window::{Schedule, Window, MAX_SESSION_GAP};
@@ -27,7 +27,12 @@
 //! Reference:
 //! <https://docs.microsoft.com/en-us/stream-analytics-query/windowing-azure-stream-analytics>
 
+use crate::error::{FlockError, Result};
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
 
 type Slide = usize; // seconds
 type WindowSize = usize; // seconds
@@ -75,8 +80,18 @@ pub enum Schedule {
     Rows(usize),
 }
 
+/// The maximum session gap in seconds, which is bounded by the maximum
+/// execution time of AWS Lambda functions (15 minutes).
+pub const MAX_SESSION_GAP: usize = 900;
+
 /// A enum `Window` to define different window types.
+///
+/// It is serialized with named fields, e.g.
+/// `{"type":"hopping","size":3,"hop":2}`, and parsed from the command line as
+/// `hopping:3:2` (see [`Window::from_str`]). The externally tagged format of
+/// the former releases, e.g. `{"Hopping":[3,2]}`, is still deserialized.
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(into = "WindowSpec", try_from = "WindowFormat")]
 pub enum Window {
     /// A query that aggregates data using distinct time-based windows that open
     /// and close at regular intervals. In this case, each record on an
@@ -118,6 +133,371 @@ impl Default for Window {
     }
 }
 
+impl Window {
+    /// Returns the number of windows that the data source produces in the
+    /// given number of seconds, or `None` if the number depends on the data
+    /// (e.g. session windows).
+    pub fn num_windows(&self, seconds: usize) -> Option<usize> {
+        match self {
+            Window::ElementWise => Some(seconds),
+            // The last tumbling window is partial if it isn't a multiple.
+            Window::Tumbling(Schedule::Seconds(window_size)) => {
+                Some((seconds + window_size - 1) / window_size)
+            }
+            Window::Hopping((_, hop_size)) => Some((seconds + hop_size - 1) / hop_size),
+            _ => None,
+        }
+    }
+
+    /// Returns the second at which the window with the given index starts, or
+    /// `None` if it depends on the data (e.g. session windows).
+    pub fn window_start(&self, window: usize) -> Option<usize> {
+        match self {
+            Window::ElementWise => Some(window),
+            Window::Tumbling(Schedule::Seconds(window_size)) => Some(window * window_size),
+            Window::Hopping((_, hop_size)) => Some(window * hop_size),
+            _ => None,
+        }
+    }
+
+    /// Returns how long each window lasts, or `None` if it depends on the data
+    /// (e.g. session windows).
+    pub fn length(&self) -> Option<Duration> {
+        match self {
+            Window::Tumbling(schedule) => schedule.period(),
+            Window::Hopping((window_size, _)) | Window::Sliding((window_size, _)) => {
+                Some(Duration::from_secs(*window_size as u64))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Window {
+    /// Checks the window parameters: the window sizes must be nonzero, the hop
+    /// (slide) can't exceed the window size, and the session gap must be in
+    /// the range `1..=MAX_SESSION_GAP` seconds.
+    pub fn validate(&self) -> Result<()> {
+        let nonzero = |kind: &str, schedule: &Schedule| match schedule {
+            Schedule::Seconds(0) | Schedule::Rows(0) => Err(FlockError::Execution(format!(
+                "The {} window size must be nonzero",
+                kind
+            ))),
+            _ => Ok(()),
+        };
+        match self {
+            Window::Tumbling(schedule) => nonzero("tumbling", schedule),
+            Window::Global(schedule) => nonzero("global", schedule),
+            Window::Hopping((size, step)) | Window::Sliding((size, step)) => {
+                let kind = if matches!(self, Window::Hopping(_)) {
+                    "hop"
+                } else {
+                    "slide"
+                };
+                if *size == 0 || *step == 0 {
+                    Err(FlockError::Execution(format!(
+                        "The window size ({}) and the {} ({}) must be nonzero",
+                        size, kind, step
+                    )))
+                } else if step > size {
+                    Err(FlockError::Execution(format!(
+                        "The {} ({}) exceeds the window size ({})",
+                        kind, step, size
+                    )))
+                } else {
+                    Ok(())
+                }
+            }
+            Window::Session(Schedule::Seconds(gap)) if *gap == 0 || *gap > MAX_SESSION_GAP => {
+                Err(FlockError::Execution(format!(
+                    "The session gap ({}s) is not in the range 1..={}s",
+                    gap, MAX_SESSION_GAP
+                )))
+            }
+            Window::Session(schedule) => nonzero("session", schedule),
+            Window::Stagger | Window::ElementWise => Ok(()),
+        }
+    }
+}
+
+impl Schedule {
+    /// Returns the schedule expression of the EventBridge rule, e.g.
+    /// `rate(5 minutes)` or `cron(0/5 * * * ? *)`. The schedules in seconds
+    /// must be whole minutes, which is the finest granularity of the rules.
+    pub fn expression(&self) -> Result<String> {
+        match self {
+            Schedule::Rate(_) | Schedule::Cron(_) => Ok(self.to_string()),
+            Schedule::Seconds(seconds) if *seconds > 0 && seconds % 60 == 0 => {
+                let minutes = seconds / 60;
+                Ok(format!(
+                    "rate({} {})",
+                    minutes,
+                    if minutes == 1 { "minute" } else { "minutes" }
+                ))
+            }
+            _ => Err(FlockError::Execution(format!(
+                "{} can't be scheduled, the schedule must be a rate, a cron expression or whole \
+                 minutes",
+                self
+            ))),
+        }
+    }
+
+    /// Returns the period of the schedule, or `None` for the cron expressions
+    /// and the row-based schedules.
+    pub fn period(&self) -> Option<Duration> {
+        match self {
+            Schedule::Seconds(seconds) => Some(Duration::from_secs(*seconds as u64)),
+            Schedule::Rate(rate) => {
+                let mut parts = rate.split_whitespace();
+                let value = parts.next()?.parse::<u64>().ok()?;
+                let unit = match parts.next()?.trim_end_matches('s') {
+                    "second" => 1,
+                    "minute" => 60,
+                    "hour" => 3600,
+                    "day" => 86400,
+                    _ => return None,
+                };
+                Some(Duration::from_secs(value * unit))
+            }
+            Schedule::Cron(_) | Schedule::Rows(_) => None,
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = FlockError;
+
+    /// Parses the schedule expression, e.g. `rate(5 minutes)`,
+    /// `cron(0/5 * * * ? *)`, or a number of seconds.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let inner = |prefix: &str| {
+            s.strip_prefix(prefix)
+                .and_then(|s| s.strip_suffix(')'))
+                .map(|s| s.trim().to_string())
+        };
+        if let Some(rate) = inner("rate(") {
+            Ok(Schedule::Rate(rate))
+        } else if let Some(cron) = inner("cron(") {
+            Ok(Schedule::Cron(cron))
+        } else {
+            s.parse::<usize>().map(Schedule::Seconds).map_err(|_| {
+                FlockError::Execution(format!(
+                    "Invalid schedule {:?}, expected rate(..), cron(..) or seconds",
+                    s
+                ))
+            })
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::Seconds(seconds) => write!(f, "{}", seconds),
+            Schedule::Rate(rate) => write!(f, "rate({})", rate),
+            Schedule::Cron(cron) => write!(f, "cron({})", cron),
+            Schedule::Rows(rows) => write!(f, "rows({})", rows),
+        }
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Window::Tumbling(schedule) => write!(f, "tumbling:{}", schedule),
+            Window::Hopping((size, hop)) => write!(f, "hopping:{}:{}", size, hop),
+            Window::Sliding((size, slide)) => write!(f, "sliding:{}:{}", size, slide),
+            Window::Session(schedule) => write!(f, "session:{}", schedule),
+            Window::Global(schedule) => write!(f, "global:{}", schedule),
+            Window::Stagger => write!(f, "stagger"),
+            Window::ElementWise => write!(f, "elementwise"),
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = FlockError;
+
+    /// Parses the window from the command line, e.g. `tumbling:10`,
+    /// `hopping:3:2`, `sliding:3:1`, `session:10`, `global:10`, `stagger` and
+    /// `elementwise`. The sizes are in seconds.
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = s.trim().split(':').collect::<Vec<_>>();
+        let seconds = |i: usize| {
+            parts[i].parse::<usize>().map_err(|_| {
+                FlockError::Execution(format!("Invalid number of seconds {:?} in {}", parts[i], s))
+            })
+        };
+        let window = match (parts[0].to_lowercase().as_str(), parts.len()) {
+            ("tumbling", 2) => Window::Tumbling(Schedule::Seconds(seconds(1)?)),
+            ("hopping", 3) => Window::Hopping((seconds(1)?, seconds(2)?)),
+            ("sliding", 3) => Window::Sliding((seconds(1)?, seconds(2)?)),
+            ("session", 2) => Window::Session(Schedule::Seconds(seconds(1)?)),
+            ("global", 2) => Window::Global(Schedule::Seconds(seconds(1)?)),
+            ("stagger", 1) => Window::Stagger,
+            ("elementwise" | "element_wise", 1) => Window::ElementWise,
+            _ => {
+                return Err(FlockError::Execution(format!(
+                    concat!(
+                        "Invalid window: {}. Expected tumbling:<size>, hopping:<size>:<hop>, ",
+                        "sliding:<size>:<slide>, session:<gap>, global:<size>, stagger or ",
+                        "elementwise"
+                    ),
+                    s
+                )))
+            }
+        };
+        window.validate()?;
+        Ok(window)
+    }
+}
+
+/// The serialization format of [`Window`] with named fields. The windows on
+/// schedules other than seconds are serialized with the `schedule` field
+/// instead of the size.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WindowSpec {
+    Tumbling {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        size:     Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        schedule: Option<Schedule>,
+    },
+    Hopping {
+        size: usize,
+        hop:  usize,
+    },
+    Sliding {
+        size:  usize,
+        slide: usize,
+    },
+    Session {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        gap:      Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        schedule: Option<Schedule>,
+    },
+    Global {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        size:     Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        schedule: Option<Schedule>,
+    },
+    Stagger,
+    ElementWise,
+}
+
+/// The externally tagged format of [`Window`] before the named fields.
+#[derive(Debug, Clone, Deserialize)]
+enum LegacyWindow {
+    Tumbling(Schedule),
+    Hopping((WindowSize, Hop)),
+    Sliding((WindowSize, Slide)),
+    Session(Schedule),
+    Global(Schedule),
+    Stagger,
+    ElementWise,
+}
+
+/// The formats that [`Window`] is deserialized from, so that the payloads in
+/// flight from the functions of a former release still decode.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum WindowFormat {
+    Spec(WindowSpec),
+    Legacy(LegacyWindow),
+}
+
+/// Splits the schedule into the number of seconds and the other schedules.
+fn split_schedule(schedule: Schedule) -> (Option<usize>, Option<Schedule>) {
+    match schedule {
+        Schedule::Seconds(seconds) => (Some(seconds), None),
+        schedule => (None, Some(schedule)),
+    }
+}
+
+/// Merges the number of seconds and the other schedules into the schedule.
+fn merge_schedule(seconds: Option<usize>, schedule: Option<Schedule>) -> Result<Schedule> {
+    match (seconds, schedule) {
+        (Some(seconds), None) => Ok(Schedule::Seconds(seconds)),
+        (None, Some(schedule)) => Ok(schedule),
+        _ => Err(FlockError::Execution(
+            "The window requires either a size in seconds or a schedule".to_string(),
+        )),
+    }
+}
+
+impl From<Window> for WindowSpec {
+    fn from(window: Window) -> Self {
+        match window {
+            Window::Tumbling(schedule) => {
+                let (size, schedule) = split_schedule(schedule);
+                WindowSpec::Tumbling { size, schedule }
+            }
+            Window::Hopping((size, hop)) => WindowSpec::Hopping { size, hop },
+            Window::Sliding((size, slide)) => WindowSpec::Sliding { size, slide },
+            Window::Session(schedule) => {
+                let (gap, schedule) = split_schedule(schedule);
+                WindowSpec::Session { gap, schedule }
+            }
+            Window::Global(schedule) => {
+                let (size, schedule) = split_schedule(schedule);
+                WindowSpec::Global { size, schedule }
+            }
+            Window::Stagger => WindowSpec::Stagger,
+            Window::ElementWise => WindowSpec::ElementWise,
+        }
+    }
+}
+
+impl TryFrom<WindowSpec> for Window {
+    type Error = FlockError;
+
+    fn try_from(spec: WindowSpec) -> Result<Self> {
+        let window = match spec {
+            WindowSpec::Tumbling { size, schedule } => {
+                Window::Tumbling(merge_schedule(size, schedule)?)
+            }
+            WindowSpec::Hopping { size, hop } => Window::Hopping((size, hop)),
+            WindowSpec::Sliding { size, slide } => Window::Sliding((size, slide)),
+            WindowSpec::Session { gap, schedule } => {
+                Window::Session(merge_schedule(gap, schedule)?)
+            }
+            WindowSpec::Global { size, schedule } => {
+                Window::Global(merge_schedule(size, schedule)?)
+            }
+            WindowSpec::Stagger => Window::Stagger,
+            WindowSpec::ElementWise => Window::ElementWise,
+        };
+        window.validate()?;
+        Ok(window)
+    }
+}
+
+impl TryFrom<WindowFormat> for Window {
+    type Error = FlockError;
+
+    /// The windows in the former format were never validated, so they are
+    /// taken as they are.
+    fn try_from(format: WindowFormat) -> Result<Self> {
+        match format {
+            WindowFormat::Spec(spec) => Window::try_from(spec),
+            WindowFormat::Legacy(legacy) => Ok(match legacy {
+                LegacyWindow::Tumbling(schedule) => Window::Tumbling(schedule),
+                LegacyWindow::Hopping(hopping) => Window::Hopping(hopping),
+                LegacyWindow::Sliding(sliding) => Window::Sliding(sliding),
+                LegacyWindow::Session(schedule) => Window::Session(schedule),
+                LegacyWindow::Global(schedule) => Window::Global(schedule),
+                LegacyWindow::Stagger => Window::Stagger,
+                LegacyWindow::ElementWise => Window::ElementWise,
+            }),
+        }
+    }
+}
+
 /// Returns a new tumbling window.
 pub fn tumbling_window(sec: usize) -> Window {
     Window::Tumbling(Schedule::Seconds(sec))
@@ -147,3 +527,139 @@ pub fn global_window(sec: usize) -> Window {
 pub fn element_wise_window() -> Window {
     Window::ElementWise
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_serde_round_trip() -> Result<()> {
+        let windows = vec![
+            (
+                Window::Tumbling(Schedule::Seconds(10)),
+                r#"{"type":"tumbling","size":10}"#,
+            ),
+            (
+                Window::Hopping((3, 2)),
+                r#"{"type":"hopping","size":3,"hop":2}"#,
+            ),
+            (
+                Window::Sliding((3, 1)),
+                r#"{"type":"sliding","size":3,"slide":1}"#,
+            ),
+            (
+                Window::Session(Schedule::Seconds(10)),
+                r#"{"type":"session","gap":10}"#,
+            ),
+            (
+                Window::Global(Schedule::Rows(100)),
+                r#"{"type":"global","schedule":{"Rows":100}}"#,
+            ),
+            (Window::Stagger, r#"{"type":"stagger"}"#),
+            (Window::ElementWise, r#"{"type":"element_wise"}"#),
+        ];
+        for (window, json) in windows {
+            assert_eq!(serde_json::to_string(&window)?, json);
+            assert_eq!(serde_json::from_str::<Window>(json)?, window);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn deserialize_former_window_format() -> Result<()> {
+        let windows = vec![
+            (
+                Window::Tumbling(Schedule::Seconds(10)),
+                r#"{"Tumbling":{"Seconds":10}}"#,
+            ),
+            (Window::Hopping((3, 2)), r#"{"Hopping":[3,2]}"#),
+            (Window::Sliding((3, 1)), r#"{"Sliding":[3,1]}"#),
+            (
+                Window::Session(Schedule::Seconds(10)),
+                r#"{"Session":{"Seconds":10}}"#,
+            ),
+            (
+                Window::Global(Schedule::Rows(100)),
+                r#"{"Global":{"Rows":100}}"#,
+            ),
+            (Window::Stagger, r#""Stagger""#),
+            (Window::ElementWise, r#""ElementWise""#),
+        ];
+        for (window, json) in windows {
+            assert_eq!(serde_json::from_str::<Window>(json)?, window);
+        }
+        assert!(serde_json::from_str::<Window>(r#"{"Hopping":[3]}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn schedule_expression() -> Result<()> {
+        let schedule = "rate(5 minutes)".parse::<Schedule>()?;
+        assert_eq!(schedule, Schedule::Rate("5 minutes".to_string()));
+        assert_eq!(schedule.expression()?, "rate(5 minutes)");
+        assert_eq!(schedule.period(), Some(Duration::from_secs(300)));
+        assert_eq!(
+            "rate(1 hour)".parse::<Schedule>()?.period(),
+            Some(Duration::from_secs(3600))
+        );
+
+        let schedule = "cron(0/5 8-17 ? * MON-FRI *)".parse::<Schedule>()?;
+        assert_eq!(schedule.expression()?, "cron(0/5 8-17 ? * MON-FRI *)");
+        assert_eq!(schedule.period(), None);
+
+        assert_eq!(Schedule::Seconds(60).expression()?, "rate(1 minute)");
+        assert_eq!(Schedule::Seconds(600).expression()?, "rate(10 minutes)");
+        assert!(Schedule::Seconds(90).expression().is_err());
+        assert!(Schedule::Rows(10).expression().is_err());
+        assert!("every 5 minutes".parse::<Schedule>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn window_from_str() -> Result<()> {
+        for s in [
+            "tumbling:10",
+            "hopping:3:2",
+            "sliding:3:1",
+            "session:10",
+            "global:10",
+            "stagger",
+            "elementwise",
+        ] {
+            let window = s.parse::<Window>()?;
+            assert_eq!(window.to_string(), s);
+        }
+        assert_eq!("Hopping:3:2".parse::<Window>()?, Window::Hopping((3, 2)));
+        assert_eq!("element_wise".parse::<Window>()?, Window::ElementWise);
+
+        for s in ["", "hopping:3", "tumbling:x", "tumbling:1:2", "sessions:10"] {
+            assert!(s.parse::<Window>().is_err(), "{}", s);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn window_validation() {
+        assert!(Window::Hopping((3, 3)).validate().is_ok());
+        assert!(Window::Hopping((2, 3)).validate().is_err());
+        assert!(Window::Hopping((0, 0)).validate().is_err());
+        assert!(Window::Sliding((2, 3)).validate().is_err());
+        assert!(Window::Tumbling(Schedule::Seconds(0)).validate().is_err());
+        assert!(Window::Global(Schedule::Rows(0)).validate().is_err());
+        assert!(Window::Session(Schedule::Seconds(0)).validate().is_err());
+        assert!(Window::Session(Schedule::Seconds(MAX_SESSION_GAP))
+            .validate()
+            .is_ok());
+        assert!(Window::Session(Schedule::Seconds(MAX_SESSION_GAP + 1))
+            .validate()
+            .is_err());
+
+        // The invalid windows are rejected on deserialization as well.
+        assert!(serde_json::from_str::<Window>(r#"{"type":"hopping","size":2,"hop":3}"#).is_err());
+        assert!(serde_json::from_str::<Window>(r#"{"type":"tumbling"}"#).is_err());
+        assert!(serde_json::from_str::<Window>(
+            r#"{"type":"tumbling","size":1,"schedule":{"Rows":1}}"#
+        )
+        .is_err());
+    }
+}
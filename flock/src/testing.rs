@@ -0,0 +1,554 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! A fluent harness to write the end-to-end tests of the queries, which is
+//! also available to the crates built on Flock with the `test-utils` feature.
+//!
+//! [`TestPipeline`] plans the query with DataFusion, feeds it with the batches
+//! of its tables window by window, and asserts its results with
+//! [`assert_batches_eq`](crate::assert_batches_eq) or
+//! [`assert_batches_sorted_eq`](crate::assert_batches_sorted_eq), which print
+//! the expected and actual lines on failure:
+//!
+//! ```ignore
+//! TestPipeline::new()
+//!     .sql("SELECT auction, COUNT(*) AS n FROM bid GROUP BY auction")
+//!     .table("bid", Arc::new(Bid::schema()))
+//!     .window(Window::Tumbling(Schedule::Seconds(3)))
+//!     .source_batches("bid", batches)
+//!     .expect_sorted(vec![
+//!         "+---------+---+",
+//!         "| auction | n |",
+//!         "+---------+---+",
+//!         "| 1000    | 2 |",
+//!         "+---------+---+",
+//!     ])
+//!     .run_local()
+//!     .await?;
+//! ```
+//!
+//! [`TestPipeline::run_local`] executes the whole query with the
+//! [`LocalLauncher`], and [`TestPipeline::run_distributed`] executes its query
+//! stages in process the way the cloud functions do: the output partitions of
+//! a shuffling stage are sent to the members of the next function group by
+//! their positions, and the output of the other stages to a single function.
+//!
+//! The harness supports:
+//!
+//! * multiple tables, which are fed by their names, so the tables of the same
+//!   schema don't get each other's batches;
+//! * tumbling windows over the event time, i.e. the first timestamp column of
+//!   each table. Every window from the first to the last is executed, and the
+//!   tables without rows in a window, or all of them in an empty window, are
+//!   fed with empty partitions. The results of all windows are asserted
+//!   together;
+//! * expected errors (see [`TestPipeline::expect_error`]), which are matched
+//!   against the errors of the planning and the execution of the query.
+
+use crate::datasink::DataSinkType;
+use crate::datasource::DataSource;
+use crate::error::{FlockError, Result};
+use crate::launcher::{AwsLambdaLauncher, Launcher, LocalLauncher};
+use crate::query::{Query, QueryType, Table};
+use crate::runtime::plan::PlanProperties;
+use crate::state::HashMapStateBackend;
+use crate::stream::{Schedule, Window};
+use datafusion::arrow::array::{Array, BooleanArray, Int64Array};
+use datafusion::arrow::compute::{cast, filter_record_batch};
+use datafusion::arrow::datatypes::{DataType, SchemaRef, TimeUnit};
+use datafusion::arrow::record_batch::RecordBatch;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+pub use crate::tests::{int64_batch, int64_schema, int64_string_batch};
+
+/// The number of functions in each function group of the distributed run.
+const GROUP_SIZE: usize = 2;
+
+/// The assertion on the results of a [`TestPipeline`].
+#[derive(Debug, Clone)]
+enum Expectation {
+    /// The formatted results are the lines in the same order.
+    Lines(Vec<String>),
+    /// The formatted results are the lines in any order of the rows.
+    SortedLines(Vec<String>),
+    /// The query has no results.
+    Empty,
+    /// The query fails with an error that contains the message.
+    Error(String),
+}
+
+/// A fluent harness that runs a query on the given batches and asserts its
+/// results.
+#[derive(Debug, Clone, Default)]
+pub struct TestPipeline {
+    /// The SQL query.
+    sql:         String,
+    /// The tables of the query.
+    tables:      Vec<Table>,
+    /// The window of the query. `None` means all batches are in one window.
+    window:      Option<Window>,
+    /// The batches of each table by the table name.
+    sources:     Vec<(String, Vec<RecordBatch>)>,
+    /// The assertion on the results.
+    expectation: Option<Expectation>,
+}
+
+impl TestPipeline {
+    /// Creates an empty pipeline.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the SQL query.
+    pub fn sql<T: Into<String>>(mut self, sql: T) -> Self {
+        self.sql = sql.into();
+        self
+    }
+
+    /// Adds a table of the query.
+    pub fn table<T: Into<String>>(mut self, name: T, schema: SchemaRef) -> Self {
+        self.tables.push(Table::new(name, schema));
+        self
+    }
+
+    /// Sets the window of the query. Only the tumbling windows in seconds are
+    /// supported besides the element-wise window.
+    pub fn window(mut self, window: Window) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// Adds the batches of a table, which are split into the windows by their
+    /// event time.
+    pub fn source_batches<T: Into<String>>(mut self, name: T, batches: Vec<RecordBatch>) -> Self {
+        let name = name.into();
+        match self.sources.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, source)) => source.extend(batches),
+            None => self.sources.push((name, batches)),
+        }
+        self
+    }
+
+    /// Expects the formatted results to be the lines in the same order.
+    pub fn expect<T: AsRef<str>>(mut self, lines: Vec<T>) -> Self {
+        self.expectation = Some(Expectation::Lines(
+            lines.iter().map(|l| l.as_ref().to_owned()).collect(),
+        ));
+        self
+    }
+
+    /// Expects the formatted results to be the lines in any order of the rows.
+    pub fn expect_sorted<T: AsRef<str>>(mut self, lines: Vec<T>) -> Self {
+        self.expectation = Some(Expectation::SortedLines(
+            lines.iter().map(|l| l.as_ref().to_owned()).collect(),
+        ));
+        self
+    }
+
+    /// Expects the query to have no results in any window.
+    pub fn expect_empty(mut self) -> Self {
+        self.expectation = Some(Expectation::Empty);
+        self
+    }
+
+    /// Expects the query to fail with an error that contains the message.
+    pub fn expect_error<T: Into<String>>(mut self, message: T) -> Self {
+        self.expectation = Some(Expectation::Error(message.into()));
+        self
+    }
+
+    /// Runs the query with the [`LocalLauncher`] window by window, and asserts
+    /// its results.
+    ///
+    /// # Returns
+    /// The results of all windows, or nothing if the error is expected.
+    ///
+    /// # Panics
+    /// If the results don't meet the expectation.
+    pub async fn run_local(self) -> Result<Vec<RecordBatch>> {
+        let result = self.execute_local().await;
+        self.check(result)
+    }
+
+    /// Runs the query stages in process the way the cloud functions do, and
+    /// asserts the results.
+    ///
+    /// # Arguments
+    /// * `stages` - The number of the query stages that the query is expected
+    ///   to be split into.
+    ///
+    /// # Returns
+    /// The results of all windows, or nothing if the error is expected.
+    ///
+    /// # Panics
+    /// If the results don't meet the expectation.
+    pub async fn run_distributed(self, stages: usize) -> Result<Vec<RecordBatch>> {
+        let result = self.execute_distributed(stages).await;
+        self.check(result)
+    }
+
+    /// Returns the query of the pipeline.
+    fn query(&self) -> Query {
+        Query {
+            sql: self.sql.clone(),
+            tables: self.tables.clone(),
+            datasource: DataSource::Memory,
+            datasink: DataSinkType::Blackhole,
+            query_type: QueryType::OLAP,
+            state_backend: Arc::new(HashMapStateBackend::new()),
+            ..Default::default()
+        }
+    }
+
+    async fn execute_local(&self) -> Result<Vec<RecordBatch>> {
+        let query = self.query();
+        let mut results = vec![];
+        for (sources, names) in self.windows()? {
+            // The plan is fed in place, so every window gets its own plan.
+            let mut launcher = LocalLauncher::new(&query).await?;
+            launcher.feed_named_data_sources(sources, &names)?;
+            results.extend(launcher.collect().await?);
+        }
+        Ok(results)
+    }
+
+    async fn execute_distributed(&self, stages: usize) -> Result<Vec<RecordBatch>> {
+        let query = self.query();
+        let mut results = vec![];
+        for (sources, names) in self.windows()? {
+            let mut launcher = AwsLambdaLauncher::new(&query).await?;
+            launcher.create_cloud_contexts(GROUP_SIZE)?;
+            let dag = launcher.dag.get_all_stages();
+            if dag.len() != stages {
+                return Err(FlockError::Plan(format!(
+                    "The query is split into {} stages, but {} are expected",
+                    dag.len(),
+                    stages
+                )));
+            }
+
+            // The output of each function of the stage: plans, partitions, batches.
+            let mut outputs: Vec<Vec<Vec<Vec<RecordBatch>>>> = vec![];
+            for (i, stage) in dag.iter().enumerate() {
+                let ctx = stage
+                    .context
+                    .clone()
+                    .ok_or_else(|| FlockError::Internal("Cloud context not set.".to_string()))?;
+                let inputs = if i == 0 {
+                    vec![(sources.clone(), names.clone())]
+                } else {
+                    let plans = dag[i - 1].stage.as_slice();
+                    next_inputs(std::mem::take(&mut outputs), plans)
+                };
+                for (sources, names) in inputs {
+                    ctx.feed_named_data_sources(sources, &names).await?;
+                    outputs.push(ctx.execute_partitioned().await?);
+                    ctx.clean_data_sources().await?;
+                }
+            }
+            results.extend(
+                outputs
+                    .into_iter()
+                    .flat_map(|plans| plans.into_iter().take(1).flatten().flatten()),
+            );
+        }
+        Ok(results)
+    }
+
+    /// Splits the batches of the tables into the windows.
+    ///
+    /// # Returns
+    /// The sources and their table names of each window.
+    fn windows(&self) -> Result<Vec<(Vec<Vec<Vec<RecordBatch>>>, Vec<Option<String>>)>> {
+        let size = match &self.window {
+            None | Some(Window::ElementWise) => None,
+            Some(Window::Tumbling(Schedule::Seconds(seconds))) => Some(*seconds as i64 * 1000),
+            Some(window) => {
+                return Err(FlockError::NotImplemented(format!(
+                    "The test pipeline doesn't support the window {}",
+                    window
+                )))
+            }
+        };
+        let size = match size {
+            Some(size) => size,
+            None => {
+                let (sources, names): (Vec<_>, Vec<_>) = self
+                    .sources
+                    .iter()
+                    .filter(|(_, batches)| !batches.is_empty())
+                    .map(|(name, batches)| (vec![batches.clone()], Some(name.clone())))
+                    .unzip();
+                return Ok(vec![(sources, names)]);
+            }
+        };
+
+        // The window of each row of each table.
+        let mut ids = vec![];
+        for (name, batches) in &self.sources {
+            let ids_of_table = batches
+                .iter()
+                .map(|batch| {
+                    let time = event_time(batch).ok_or_else(|| {
+                        FlockError::Plan(format!("The table {} has no event time column", name))
+                    })?;
+                    Ok(time
+                        .iter()
+                        .map(|t| t.unwrap_or(0).div_euclid(size))
+                        .collect())
+                })
+                .collect::<Result<Vec<Vec<i64>>>>()?;
+            ids.push(ids_of_table);
+        }
+        let all = ids.iter().flatten().flatten().collect::<BTreeSet<_>>();
+        let (first, last) = match (all.iter().next(), all.iter().last()) {
+            (Some(first), Some(last)) => (**first, **last),
+            _ => return Ok(vec![(vec![], vec![])]),
+        };
+
+        (first..=last)
+            .map(|window| {
+                let mut sources = vec![];
+                let mut names = vec![];
+                for ((name, batches), ids) in self.sources.iter().zip(ids.iter()) {
+                    let mut partition = vec![];
+                    for (batch, ids) in batches.iter().zip(ids.iter()) {
+                        let mask = BooleanArray::from(
+                            ids.iter().map(|id| *id == window).collect::<Vec<_>>(),
+                        );
+                        let batch = filter_record_batch(batch, &mask)?;
+                        if batch.num_rows() > 0 {
+                            partition.push(batch);
+                        }
+                    }
+                    if !partition.is_empty() {
+                        sources.push(vec![partition]);
+                        names.push(Some(name.clone()));
+                    }
+                }
+                Ok((sources, names))
+            })
+            .collect()
+    }
+
+    /// Checks the result against the expectation.
+    fn check(&self, result: Result<Vec<RecordBatch>>) -> Result<Vec<RecordBatch>> {
+        match (&self.expectation, result) {
+            (Some(Expectation::Error(message)), Ok(batches)) => panic!(
+                "The query succeeded with {} rows, but the error {:?} is expected",
+                batches.iter().map(|b| b.num_rows()).sum::<usize>(),
+                message
+            ),
+            (Some(Expectation::Error(message)), Err(e)) => {
+                crate::assert_contains!(e.to_string(), message.as_str());
+                Ok(vec![])
+            }
+            (_, Err(e)) => Err(e),
+            (Some(Expectation::Lines(lines)), Ok(batches)) => {
+                crate::assert_batches_eq!(
+                    lines.iter().map(|l| l.as_str()).collect::<Vec<_>>(),
+                    &batches
+                );
+                Ok(batches)
+            }
+            (Some(Expectation::SortedLines(lines)), Ok(batches)) => {
+                crate::assert_batches_sorted_eq!(
+                    lines.iter().map(|l| l.as_str()).collect::<Vec<_>>(),
+                    &batches
+                );
+                Ok(batches)
+            }
+            (Some(Expectation::Empty), Ok(batches)) => {
+                let rows = batches.iter().map(|b| b.num_rows()).sum::<usize>();
+                assert_eq!(rows, 0, "The query has {} rows, but none is expected", rows);
+                Ok(batches)
+            }
+            (None, Ok(batches)) => Ok(batches),
+        }
+    }
+}
+
+/// Returns the inputs of the functions of the next stage.
+///
+/// The output partitions of a shuffling stage are sent to the members of the
+/// function group by their positions, and the member concatenates the
+/// fragments of all upstream functions. The output of the other stages is
+/// sent to a single function.
+///
+/// # Arguments
+/// * `outputs` - The output of each function of the stage.
+/// * `plans` - The plans of the stage.
+fn next_inputs(
+    outputs: Vec<Vec<Vec<Vec<RecordBatch>>>>,
+    plans: &[Arc<dyn datafusion::physical_plan::ExecutionPlan>],
+) -> Vec<(Vec<Vec<Vec<RecordBatch>>>, Vec<Option<String>>)> {
+    let relations = outputs.first().map(|o| o.len()).unwrap_or(0);
+    let input = |select: &dyn Fn(&Vec<Vec<RecordBatch>>) -> Vec<RecordBatch>| {
+        let sources = (0..relations)
+            .map(|p| vec![outputs.iter().flat_map(|o| select(&o[p])).collect()])
+            .collect::<Vec<Vec<Vec<RecordBatch>>>>();
+        (sources, vec![])
+    };
+    if PlanProperties::analyze_all(plans).is_shuffling {
+        let members = outputs
+            .iter()
+            .flatten()
+            .map(|partitions| partitions.len())
+            .max()
+            .unwrap_or(0);
+        (0..members)
+            .map(|j| input(&|partitions| partitions.get(j).cloned().unwrap_or_default()))
+            .collect()
+    } else {
+        vec![input(&|partitions| partitions.concat())]
+    }
+}
+
+/// Returns the event time in milliseconds of the rows, i.e. the values of the
+/// first timestamp column, or `None` if the batch has no timestamp column.
+fn event_time(batch: &RecordBatch) -> Option<Int64Array> {
+    let column = batch
+        .columns()
+        .iter()
+        .find(|c| matches!(c.data_type(), DataType::Timestamp(_, _)))?;
+    let millis = cast(column, &DataType::Timestamp(TimeUnit::Millisecond, None)).ok()?;
+    let millis = cast(&millis, &DataType::Int64).ok()?;
+    millis.as_any().downcast_ref::<Int64Array>().cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::array::{Int32Array, StringArray, TimestampMillisecondArray};
+    use datafusion::arrow::datatypes::{Field, Schema};
+
+    fn table(names: (&str, &str), keys: Vec<&str>, values: Vec<i32>) -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new(names.0, DataType::Utf8, false),
+            Field::new(names.1, DataType::Int32, false),
+        ]));
+        RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(StringArray::from(keys)),
+                Arc::new(Int32Array::from(values)),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn run_multiple_tables() -> Result<()> {
+        let t1 = table(("a", "b"), vec!["a", "b", "c", "d"], vec![1, 10, 10, 100]);
+        let t2 = table(("c", "d"), vec!["a", "b", "c", "x"], vec![2, 20, 30, 200]);
+        TestPipeline::new()
+            .sql("SELECT a, b, d FROM t1 JOIN t2 ON a = c")
+            .table("t1", t1.schema())
+            .table("t2", t2.schema())
+            .source_batches("t1", vec![t1])
+            .source_batches("t2", vec![t2])
+            .expect_sorted(vec![
+                "+---+----+----+",
+                "| a | b  | d  |",
+                "+---+----+----+",
+                "| a | 1  | 2  |",
+                "| b | 10 | 20 |",
+                "| c | 10 | 30 |",
+                "+---+----+----+",
+            ])
+            .run_local()
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_empty_windows() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Utf8, false),
+            Field::new(
+                "ts",
+                DataType::Timestamp(TimeUnit::Millisecond, None),
+                false,
+            ),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(vec!["x", "y", "x"])),
+                Arc::new(TimestampMillisecondArray::from(vec![0, 500, 2500])),
+            ],
+        )?;
+
+        // The second window has no rows, but it's still executed.
+        TestPipeline::new()
+            .sql("SELECT COUNT(*) AS n FROM events")
+            .table("events", schema)
+            .window(Window::Tumbling(Schedule::Seconds(1)))
+            .source_batches("events", vec![batch])
+            .expect(vec![
+                "+---+", //
+                "| n |", "+---+", "| 2 |", "| 0 |", "| 1 |", "+---+",
+            ])
+            .run_local()
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_expected_error() -> Result<()> {
+        let t1 = table(("a", "b"), vec!["a"], vec![1]);
+        TestPipeline::new()
+            .sql("SELECT missing FROM t1")
+            .table("t1", t1.schema())
+            .source_batches("t1", vec![t1.clone()])
+            .expect_error("missing")
+            .run_local()
+            .await?;
+
+        // The tumbling windows need the event time of the rows.
+        TestPipeline::new()
+            .sql("SELECT a FROM t1")
+            .table("t1", t1.schema())
+            .window(Window::Tumbling(Schedule::Seconds(1)))
+            .source_batches("t1", vec![t1])
+            .expect_error("no event time column")
+            .run_local()
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_distributed_aggregation() -> Result<()> {
+        let t1 = table(("a", "b"), vec!["a", "b", "a", "c"], vec![1, 2, 3, 4]);
+        let pipeline = TestPipeline::new()
+            .sql("SELECT a, SUM(b) AS s FROM t1 GROUP BY a")
+            .table("t1", t1.schema())
+            .source_batches("t1", vec![t1])
+            .expect_sorted(vec![
+                "+---+---+",
+                "| a | s |",
+                "+---+---+",
+                "| a | 4 |",
+                "| b | 2 |",
+                "| c | 4 |",
+                "+---+---+",
+            ]);
+
+        // The partial and the final aggregation are the two query stages.
+        pipeline.clone().run_local().await?;
+        pipeline.clone().run_distributed(2).await?;
+        assert!(pipeline.run_distributed(3).await.is_err());
+        Ok(())
+    }
+}
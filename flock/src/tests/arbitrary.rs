@@ -0,0 +1,185 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The [proptest] strategies of the record batches and the encodings, which
+//! are shared by the property-based tests of the payloads, the contexts and
+//! the data sinks.
+//!
+//! The batches are generated over a constrained set of Arrow types: integers,
+//! floats, strings, timestamps and booleans, each of which may be nullable.
+//! The edge cases that the hand-built batches tend to miss, i.e. the empty
+//! strings, the all-null columns and the batches with no rows, are generated
+//! on purpose.
+
+use crate::encoding::Encoding;
+use datafusion::arrow::array::{
+    ArrayRef, BooleanArray, Float64Array, Int32Array, Int64Array, StringArray,
+    TimestampMillisecondArray,
+};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use datafusion::arrow::record_batch::RecordBatch;
+use proptest::collection::{btree_map, vec};
+use proptest::prelude::*;
+use proptest::sample::select;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// The maximum number of rows of a generated batch.
+pub const MAX_ROWS: usize = 1000;
+
+/// Returns a strategy of the Arrow types of the generated columns.
+pub fn arb_data_type() -> impl Strategy<Value = DataType> {
+    select(vec![
+        DataType::Int32,
+        DataType::Int64,
+        DataType::Float64,
+        DataType::Utf8,
+        DataType::Timestamp(TimeUnit::Millisecond, None),
+        DataType::Boolean,
+    ])
+}
+
+/// Returns a strategy of the encodings enabled in this build.
+pub fn arb_encoding() -> impl Strategy<Value = Encoding> {
+    let encodings = [
+        Encoding::None,
+        Encoding::Snappy,
+        Encoding::Lz4,
+        Encoding::Zstd,
+    ]
+    .into_iter()
+    .filter(|e| e.compress(b"").is_ok())
+    .collect::<Vec<_>>();
+    select(encodings)
+}
+
+/// Returns a strategy of the schema and field metadata, which is empty half of
+/// the time.
+fn arb_metadata() -> impl Strategy<Value = BTreeMap<String, String>> {
+    prop_oneof![
+        Just(BTreeMap::new()),
+        btree_map("[a-z]{1,8}", "[a-z0-9 ]{0,8}", 1..3),
+    ]
+}
+
+/// Returns a strategy of the schemas with up to `max_columns` columns.
+pub fn arb_schema(max_columns: usize) -> impl Strategy<Value = SchemaRef> {
+    let field = (arb_data_type(), any::<bool>(), arb_metadata());
+    (vec(field, 1..=max_columns), arb_metadata()).prop_map(|(fields, metadata)| {
+        let fields = fields
+            .into_iter()
+            .enumerate()
+            .map(|(i, (data_type, nullable, metadata))| {
+                let mut field = Field::new(&format!("c{}", i), data_type, nullable);
+                if !metadata.is_empty() {
+                    field.set_metadata(Some(metadata));
+                }
+                field
+            })
+            .collect();
+        Arc::new(Schema::new_with_metadata(
+            fields,
+            metadata.into_iter().collect::<HashMap<_, _>>(),
+        ))
+    })
+}
+
+/// Returns a strategy of the validity of the values of a column: all valid if
+/// the column isn't nullable, and otherwise either all null or random.
+fn arb_validity(nullable: bool, rows: usize) -> BoxedStrategy<Vec<bool>> {
+    if nullable {
+        prop_oneof![Just(vec![false; rows]), vec(any::<bool>(), rows)].boxed()
+    } else {
+        Just(vec![true; rows]).boxed()
+    }
+}
+
+/// Returns a strategy of the arrays of the given type with `rows` values.
+pub fn arb_array(data_type: &DataType, nullable: bool, rows: usize) -> BoxedStrategy<ArrayRef> {
+    /// Masks the values by the validity.
+    fn mask<T>(values: Vec<T>, validity: Vec<bool>) -> Vec<Option<T>> {
+        values
+            .into_iter()
+            .zip(validity)
+            .map(|(v, valid)| valid.then(|| v))
+            .collect()
+    }
+
+    let validity = arb_validity(nullable, rows);
+    match data_type {
+        DataType::Int32 => (vec(any::<i32>(), rows), validity)
+            .prop_map(|(v, valid)| {
+                Arc::new(mask(v, valid).into_iter().collect::<Int32Array>()) as ArrayRef
+            })
+            .boxed(),
+        DataType::Int64 => (vec(any::<i64>(), rows), validity)
+            .prop_map(|(v, valid)| {
+                Arc::new(mask(v, valid).into_iter().collect::<Int64Array>()) as ArrayRef
+            })
+            .boxed(),
+        // The floats are finite, since NaN doesn't equal itself.
+        DataType::Float64 => (vec(-1e12..1e12f64, rows), validity)
+            .prop_map(|(v, valid)| {
+                Arc::new(mask(v, valid).into_iter().collect::<Float64Array>()) as ArrayRef
+            })
+            .boxed(),
+        DataType::Utf8 => (vec("[a-z0-9 ]{0,8}|\\PC{0,4}", rows), validity)
+            .prop_map(|(v, valid)| {
+                Arc::new(mask(v, valid).into_iter().collect::<StringArray>()) as ArrayRef
+            })
+            .boxed(),
+        DataType::Timestamp(TimeUnit::Millisecond, None) => {
+            (vec(0..4_102_444_800_000i64, rows), validity)
+                .prop_map(|(v, valid)| {
+                    Arc::new(
+                        mask(v, valid)
+                            .into_iter()
+                            .collect::<TimestampMillisecondArray>(),
+                    ) as ArrayRef
+                })
+                .boxed()
+        }
+        DataType::Boolean => (vec(any::<bool>(), rows), validity)
+            .prop_map(|(v, valid)| {
+                Arc::new(mask(v, valid).into_iter().collect::<BooleanArray>()) as ArrayRef
+            })
+            .boxed(),
+        _ => unimplemented!("{:?} is not generated", data_type),
+    }
+}
+
+/// Returns a strategy of the batches of the schema with `rows` rows.
+pub fn arb_batch_of(schema: SchemaRef, rows: usize) -> impl Strategy<Value = RecordBatch> {
+    let columns = schema
+        .fields()
+        .iter()
+        .map(|f| arb_array(f.data_type(), f.is_nullable(), rows))
+        .collect::<Vec<_>>();
+    columns.prop_map(move |columns| RecordBatch::try_new(schema.clone(), columns).unwrap())
+}
+
+/// Returns a strategy of the batches with up to 8 columns and up to
+/// [`MAX_ROWS`] rows.
+pub fn arb_batch() -> impl Strategy<Value = RecordBatch> {
+    (arb_schema(8), 0..=MAX_ROWS).prop_flat_map(|(schema, rows)| arb_batch_of(schema, rows))
+}
+
+/// Returns a strategy of up to `max_batches` batches of the same schema, such
+/// as the batches of a relation in a payload.
+pub fn arb_batches(max_batches: usize) -> impl Strategy<Value = Vec<RecordBatch>> {
+    (arb_schema(8), vec(0..=MAX_ROWS, 0..=max_batches)).prop_flat_map(|(schema, rows)| {
+        rows.into_iter()
+            .map(|rows| arb_batch_of(schema.clone(), rows))
+            .collect::<Vec<_>>()
+    })
+}
@@ -133,7 +133,9 @@ pub fn random_kinesis_event(num: usize) -> (Value, SchemaRef) {
 /// the schema of the data records in the event.
 pub fn random_event(datasource: &DataSource, num: usize) -> (Value, SchemaRef) {
     match &datasource {
+        #[cfg(feature = "kinesis")]
         DataSource::KinesisEvent(_) => random_kinesis_event(num),
+        #[cfg(feature = "kafka")]
         DataSource::KafkaEvent(_) => unimplemented!(),
         _ => unimplemented!(),
     }
@@ -177,6 +179,34 @@ pub fn random_batches(
         .collect()
 }
 
+/// Returns the schema of [`int64_batch`], i.e. a single Int64 column `c1`.
+pub fn int64_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![Field::new("c1", DataType::Int64, false)]))
+}
+
+/// Returns a record batch of the values in a single Int64 column `c1`.
+pub fn int64_batch(values: Vec<i64>) -> RecordBatch {
+    RecordBatch::try_new(int64_schema(), vec![Arc::new(Int64Array::from(values))]).unwrap()
+}
+
+/// Returns a record batch of the values in the Int64 column `a`, and of their
+/// string forms in the Utf8 column `b`.
+pub fn int64_string_batch(values: Vec<i64>) -> RecordBatch {
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("a", DataType::Int64, false),
+        Field::new("b", DataType::Utf8, false),
+    ]));
+    let names = values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+    RecordBatch::try_new(
+        schema,
+        vec![
+            Arc::new(Int64Array::from(values)),
+            Arc::new(StringArray::from(names)),
+        ],
+    )
+    .unwrap()
+}
+
 /// Register a table
 pub fn register_table(schema: &SchemaRef, table_name: &str) -> ExecutionContext {
     let mut ctx = ExecutionContext::new();
@@ -246,5 +276,8 @@ mod tests {
     }
 }
 
+#[cfg(test)]
+pub mod arbitrary;
 pub mod kinesis;
+#[cfg(feature = "nexmark")]
 pub mod nexmark;
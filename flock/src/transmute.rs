@@ -14,10 +14,15 @@
 //! This module contains various utility functions.
 
 use crate::datasource::DataSource;
-use crate::encoding::Encoding;
+use crate::encoding::{Encoding, StageEncoding};
+use crate::encryption;
 use crate::error::{FlockError, Result};
-use crate::runtime::payload::{DataFrame, Payload, Uuid};
+use crate::runtime::payload::{new_payload_id, DataFrame, Payload, Uuid};
+use crate::runtime::stats::payload_stats;
+use crate::runtime::trace;
+use datafusion::arrow::compute::concat;
 use datafusion::arrow::datatypes::{Schema, SchemaRef};
+use datafusion::arrow::error::Result as ArrowResult;
 use datafusion::arrow::json;
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::arrow_flight::utils::flight_data_from_arrow_batch;
@@ -33,6 +38,83 @@ use serde_json::Value;
 use std::io::BufReader;
 use std::sync::Arc;
 
+/// The minimum number of input batches to concatenate them directly in
+/// [`coalesce_batches`] instead of building a physical plan.
+const CONCAT_MIN_BATCHES: usize = 64;
+
+/// The maximum average number of rows per input batch to concatenate them
+/// directly in [`coalesce_batches`] instead of building a physical plan.
+const CONCAT_MAX_BATCH_ROWS: usize = 32;
+
+/// Returns true if the partitions consist of many tiny batches, such as one
+/// batch per upstream event payload, which are cheaper to concatenate directly.
+pub fn is_small_batches(input_partitions: &[Vec<RecordBatch>]) -> bool {
+    let num_batches = input_partitions.iter().map(|p| p.len()).sum::<usize>();
+    let num_rows = input_partitions
+        .iter()
+        .flatten()
+        .map(|b| b.num_rows())
+        .sum::<usize>();
+    num_batches >= CONCAT_MIN_BATCHES && num_rows <= num_batches * CONCAT_MAX_BATCH_ROWS
+}
+
+/// Concatenates the columns of the buffered batches into a single batch.
+fn concat_buffered(schema: &SchemaRef, buffer: &[RecordBatch]) -> Result<RecordBatch> {
+    let columns = (0..schema.fields().len())
+        .map(|i| {
+            let arrays = buffer
+                .iter()
+                .map(|b| b.column(i).as_ref())
+                .collect::<Vec<_>>();
+            concat(&arrays)
+        })
+        .collect::<ArrowResult<Vec<_>>>()?;
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+/// Combines tiny batches into batches of at least `target_rows` rows (except
+/// for the last one) by concatenating their columns directly, without
+/// constructing a physical plan. The output is the same as the one of
+/// [`coalesce_batches`].
+///
+/// It falls back to the plan-based path if any batch has more than
+/// `target_rows` rows or a different schema.
+pub async fn concat_small_batches(
+    schema: SchemaRef,
+    batches: Vec<RecordBatch>,
+    target_rows: usize,
+) -> Result<Vec<RecordBatch>> {
+    if batches
+        .iter()
+        .any(|b| b.num_rows() > target_rows || *b.schema() != *schema)
+    {
+        return Ok(coalesce_with_plan(vec![batches], schema, target_rows)
+            .await?
+            .remove(0));
+    }
+
+    let mut output = vec![];
+    let mut buffer = vec![];
+    let mut buffered_rows = 0;
+    for batch in batches {
+        if batch.num_rows() >= target_rows && buffer.is_empty() {
+            output.push(batch);
+        } else if batch.num_rows() > 0 {
+            buffered_rows += batch.num_rows();
+            buffer.push(batch);
+            if buffered_rows >= target_rows {
+                output.push(concat_buffered(&schema, &buffer)?);
+                buffer.clear();
+                buffered_rows = 0;
+            }
+        }
+    }
+    if !buffer.is_empty() {
+        output.push(concat_buffered(&schema, &buffer)?);
+    }
+    Ok(output)
+}
+
 /// Combines small batches into larger batches for more efficient use of
 /// vectorized processing by upstream operators
 pub async fn coalesce_batches(
@@ -52,6 +134,24 @@ pub async fn coalesce_batches(
         return Err(FlockError::Execution("No schema found".to_string()));
     }
 
+    if is_small_batches(&input_partitions) {
+        let mut output_partitions = Vec::with_capacity(input_partitions.len());
+        for batches in input_partitions {
+            output_partitions
+                .push(concat_small_batches(schema.clone(), batches, target_batch_size).await?);
+        }
+        return Ok(output_partitions);
+    }
+
+    coalesce_with_plan(input_partitions, schema, target_batch_size).await
+}
+
+/// Combines small batches into larger batches with `CoalesceBatchesExec`.
+async fn coalesce_with_plan(
+    input_partitions: Vec<Vec<RecordBatch>>,
+    schema: SchemaRef,
+    target_batch_size: usize,
+) -> Result<Vec<Vec<RecordBatch>>> {
     let exec = MemoryExec::try_new(&input_partitions, schema, None)?;
     let exec: Arc<dyn ExecutionPlan> =
         Arc::new(CoalesceBatchesExec::new(Arc::new(exec), target_batch_size));
@@ -111,7 +211,7 @@ pub async fn repartition(
 /// Deserialize `DataFrame` from cloud functions.
 pub fn unmarshal(data: Vec<DataFrame>, encoding: Encoding) -> Vec<DataFrame> {
     match encoding {
-        Encoding::Snappy | Encoding::Lz4 | Encoding::Zstd => data
+        Encoding::Snappy | Encoding::Lz4 | Encoding::Zstd | Encoding::ZstdDict { .. } => data
             .par_iter()
             .map(|d| DataFrame {
                 header: encoding.decompress(&d.header).unwrap(),
@@ -138,8 +238,8 @@ pub fn schema_from_bytes(bytes: &[u8]) -> Result<Arc<Schema>> {
 }
 
 /// Convert incoming payload to record batches in Arrow format.
-pub fn json_value_to_batch(event: Value) -> (Vec<RecordBatch>, Vec<RecordBatch>) {
-    let payload: Payload = serde_json::from_value(event).unwrap();
+pub fn json_value_to_batch(event: Value) -> Result<(Vec<RecordBatch>, Vec<RecordBatch>)> {
+    let payload = Payload::from_value(event)?;
     payload.to_record_batch()
 }
 
@@ -166,7 +266,10 @@ pub fn batch_to_json_value(batches: &[RecordBatch], uuid: Uuid, encoding: Encodi
 
     serde_json::to_value(&Payload {
         data: data_frames,
-        schema: schema_to_bytes(batches[0].schema()),
+        schema: batches
+            .first()
+            .map(|b| schema_to_bytes(b.schema()))
+            .unwrap_or_default(),
         uuid,
         encoding,
         ..Default::default()
@@ -180,44 +283,134 @@ pub fn to_payload(
     batch2: &[RecordBatch],
     uuid: Uuid,
     sync: bool,
-) -> Payload {
+) -> Result<Payload> {
+    to_payload_with_keys(batch1, batch2, uuid, sync, &[])
+}
+
+/// Convert record batches to payload, whose statistics estimate the distinct
+/// counts of the given key columns (see [`crate::runtime::stats`]). The record
+/// batches are encrypted if the invocation has a cipher (see
+/// [`crate::encryption`]).
+pub fn to_payload_with_keys(
+    batch1: &[RecordBatch],
+    batch2: &[RecordBatch],
+    uuid: Uuid,
+    sync: bool,
+    keys: &[String],
+) -> Result<Payload> {
+    to_payload_with_encoding(batch1, batch2, uuid, sync, keys, Encoding::default())
+}
+
+/// Convert record batches to payload compressed by the given encoding.
+pub fn to_payload_with_encoding(
+    batch1: &[RecordBatch],
+    batch2: &[RecordBatch],
+    uuid: Uuid,
+    sync: bool,
+    keys: &[String],
+    encoding: Encoding,
+) -> Result<Payload> {
+    to_stage_payload(batch1, batch2, uuid, sync, keys, &encoding.into())
+}
+
+/// Convert record batches to payload compressed by the encoding of the query
+/// stage. The codec is picked by the size of the Arrow Flight data of both
+/// relations (see [`StageEncoding::codec_for`]), and it's recorded in the
+/// payload for the receiver. The rows and the bytes of the payload are added
+/// to the span of the invocation (see [`crate::runtime::trace`]). Each payload
+/// gets a new id at its first delivery attempt (see [`Payload::payload_id`]).
+/// It fails if the codec is not compiled in, if its Zstd dictionary is not
+/// registered in the function (see [`crate::encoding::register_dictionary`]),
+/// or if the payload can't be sealed by the cipher of the invocation.
+pub fn to_stage_payload(
+    batch1: &[RecordBatch],
+    batch2: &[RecordBatch],
+    uuid: Uuid,
+    sync: bool,
+    keys: &[String],
+    encoding: &StageEncoding,
+) -> Result<Payload> {
     let options = datafusion::arrow::ipc::writer::IpcWriteOptions::default();
-    let encoding = Encoding::default();
-    let dataframe = |batches: &[RecordBatch]| -> Vec<DataFrame> {
+    let flight_data = |batches: &[RecordBatch]| -> Vec<DataFrame> {
         batches
             .par_iter()
             .map(|b| {
                 let (_, flight_data) = flight_data_from_arrow_batch(b, &options);
-                if encoding != Encoding::None {
-                    DataFrame {
-                        header: encoding.compress(&flight_data.data_header).unwrap(),
-                        body:   encoding.compress(&flight_data.data_body).unwrap(),
-                    }
-                } else {
-                    DataFrame {
-                        header: flight_data.data_header,
-                        body:   flight_data.data_body,
-                    }
+                DataFrame {
+                    header: flight_data.data_header,
+                    body:   flight_data.data_body,
                 }
             })
             .collect()
     };
+    let (data1, data2) = (flight_data(batch1), flight_data(batch2));
+    let size = data1
+        .iter()
+        .chain(data2.iter())
+        .map(|d| d.header.len() + d.body.len())
+        .sum();
+    let codec = encoding.codec_for(size);
+    let rows = batch1
+        .iter()
+        .chain(batch2)
+        .map(|b| b.num_rows())
+        .sum::<usize>();
+    trace::add("output_rows", rows as i64);
+    trace::add("output_bytes", size as i64);
+    let dataframe = |data: Vec<DataFrame>| -> Result<Vec<DataFrame>> {
+        if codec == Encoding::None {
+            return Ok(data);
+        }
+        data.into_par_iter()
+            .map(|d| {
+                Ok(DataFrame {
+                    header: codec.compress_with_level(&d.header, encoding.level)?,
+                    body:   codec.compress_with_level(&d.body, encoding.level)?,
+                })
+            })
+            .collect()
+    };
 
     let mut payload = Payload {
+        window_id: (uuid.qid.clone(), 0),
         uuid,
-        encoding: encoding.clone(),
+        encoding: codec.clone(),
         datasource: DataSource::Payload(sync),
+        payload_id: new_payload_id(),
+        delivery_attempt: 1,
         ..Default::default()
     };
     if !batch1.is_empty() {
-        payload.data = dataframe(batch1);
+        payload.data = dataframe(data1)?;
         payload.schema = schema_to_bytes(batch1[0].schema());
+        payload.stats = payload_stats(batch1, keys);
     }
     if !batch2.is_empty() {
-        payload.data2 = dataframe(batch2);
+        payload.data2 = dataframe(data2)?;
         payload.schema2 = schema_to_bytes(batch2[0].schema());
+        payload.stats2 = payload_stats(batch2, keys);
+    }
+    if let Some(cipher) = encryption::cipher() {
+        payload.seal(&cipher)?;
     }
-    payload
+    Ok(payload)
+}
+
+/// Re-encodes the data frames from one codec to another, e.g. to merge the
+/// payloads of a window that are compressed by different codecs. It fails if
+/// a data frame is corrupt, or if either codec can't be used in the function.
+pub fn reencode(data: Vec<DataFrame>, from: &Encoding, to: &Encoding) -> Result<Vec<DataFrame>> {
+    if from == to {
+        return Ok(data);
+    }
+    data.into_par_iter()
+        .map(|d| {
+            Ok(DataFrame {
+                header: to.compress(&from.decompress(&d.header)?)?,
+                body:   to.compress(&from.decompress(&d.body)?)?,
+            })
+        })
+        .collect()
 }
 
 /// Convert record batch to bytes for network transmission.
@@ -251,6 +444,37 @@ pub fn to_bytes(batch: &RecordBatch, uuid: Uuid, encoding: Encoding) -> bytes::B
     .into()
 }
 
+/// The number of records sampled to estimate the row width in
+/// [`adaptive_batch_size`].
+const BATCH_SIZE_SAMPLES: usize = 16;
+
+/// The minimum number of rows per batch of [`adaptive_batch_size`].
+pub const MIN_BATCH_ROWS: usize = 64;
+
+/// The maximum number of rows per batch of [`adaptive_batch_size`].
+pub const MAX_BATCH_ROWS: usize = 65536;
+
+/// Returns the number of rows per batch for reading the newline-delimited
+/// records, so that each batch holds about `target_bytes` of serialized
+/// records.
+///
+/// The row width is estimated from the first few non-blank records, and the
+/// number of rows is clamped to [`MIN_BATCH_ROWS`] and [`MAX_BATCH_ROWS`].
+/// The line breaks count towards the row width.
+pub fn adaptive_batch_size(input: &[u8], target_bytes: usize) -> usize {
+    let widths = input
+        .split(|b| *b == b'\n')
+        .filter(|line| line.iter().any(|b| !b.is_ascii_whitespace()))
+        .take(BATCH_SIZE_SAMPLES)
+        .map(|line| line.len() + 1)
+        .collect::<Vec<_>>();
+    if widths.is_empty() {
+        return MAX_BATCH_ROWS;
+    }
+    let width = widths.iter().sum::<usize>() / widths.len();
+    (target_bytes / width).clamp(MIN_BATCH_ROWS, MAX_BATCH_ROWS)
+}
+
 /// Converts events to record batches in Arrow format.
 pub fn event_bytes_to_batch(
     events: &[u8],
@@ -268,10 +492,15 @@ pub fn event_bytes_to_batch(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::datasource::nexmark::event::{Auction, Bid};
+    use crate::datasource::nexmark::NEXMarkSource;
     use crate::error::FlockError;
-    use datafusion::arrow::array::UInt32Array;
+    use crate::runtime::payload::UuidBuilder;
+    use crate::stream::Window;
+    use datafusion::arrow::array::{Int64Array, UInt32Array};
     use datafusion::arrow::datatypes::{DataType, Field, Schema};
     use datafusion::physical_plan::expressions::col;
+    use std::time::Instant;
     use tokio::task::JoinHandle;
 
     fn test_schema() -> Arc<Schema> {
@@ -316,6 +545,188 @@ mod tests {
         Ok(())
     }
 
+    fn create_tiny_batches(schema: &Arc<Schema>, num_batches: usize) -> Vec<RecordBatch> {
+        (0..num_batches)
+            .map(|i| {
+                // 0 to 8 rows per batch, like the payloads of single events.
+                let rows = (0..(i % 9) as u32).collect::<Vec<_>>();
+                RecordBatch::try_new(schema.clone(), vec![Arc::new(UInt32Array::from(rows))])
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn concat_small_batches_equivalence() -> Result<()> {
+        let schema = test_schema();
+        for (num_batches, target_rows) in [(1, 4), (100, 1), (1000, 16), (1000, 100), (64, 4096)] {
+            let batches = create_tiny_batches(&schema, num_batches);
+            let expected = coalesce_with_plan(vec![batches.clone()], schema.clone(), target_rows)
+                .await?
+                .remove(0);
+            let output = concat_small_batches(schema.clone(), batches, target_rows).await?;
+            assert_eq!(expected, output);
+        }
+
+        // `coalesce_batches` concatenates the tiny batches directly.
+        let partitions = vec![create_tiny_batches(&schema, 1000); 2];
+        assert!(is_small_batches(&partitions));
+        let expected = coalesce_with_plan(partitions.clone(), schema.clone(), 16).await?;
+        assert_eq!(expected, coalesce_batches(partitions, 16).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concat_small_batches_fallback() -> Result<()> {
+        let schema = test_schema();
+
+        // The batches are larger than the target size.
+        let batches = create_vec_batches(&schema, 10);
+        let output = concat_small_batches(schema.clone(), batches, 4).await?;
+        assert_eq!(10, output.len());
+        assert!(output.iter().all(|b| b.num_rows() == 8));
+
+        // The schemas of the batches disagree.
+        let other = Arc::new(Schema::new(vec![Field::new("c0", DataType::Int64, false)]));
+        let mut batches = create_tiny_batches(&schema, 10);
+        batches.push(RecordBatch::try_new(
+            other,
+            vec![Arc::new(Int64Array::from(vec![1, 2, 3]))],
+        )?);
+        assert!(concat_small_batches(schema, batches, 16).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn concat_small_batches_speedup() -> Result<()> {
+        let schema = test_schema();
+        let batches = create_tiny_batches(&schema, 100_000);
+        let rounds = 10;
+
+        let now = Instant::now();
+        for _ in 0..rounds {
+            coalesce_with_plan(vec![batches.clone()], schema.clone(), 16384).await?;
+        }
+        let plan_time = now.elapsed();
+
+        let now = Instant::now();
+        for _ in 0..rounds {
+            concat_small_batches(schema.clone(), batches.clone(), 16384).await?;
+        }
+        let concat_time = now.elapsed();
+
+        println!(
+            "Coalesce {} tiny batches: plan-based {:?}, direct concat {:?}, speedup {:.2}x",
+            batches.len(),
+            plan_time / rounds,
+            concat_time / rounds,
+            plan_time.as_secs_f64() / concat_time.as_secs_f64()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn adaptive_batch_size_bounds() {
+        // 10 bytes per row, including the line break.
+        let narrow = "{\"a\":123}\n".repeat(100);
+        assert_eq!(100, adaptive_batch_size(narrow.as_bytes(), 1000));
+        assert_eq!(
+            MAX_BATCH_ROWS,
+            adaptive_batch_size(narrow.as_bytes(), 1 << 30)
+        );
+        assert_eq!(MIN_BATCH_ROWS, adaptive_batch_size(narrow.as_bytes(), 10));
+
+        // Only the leading records are sampled, and blank lines are skipped.
+        let mut input = "\n\n".to_string();
+        input.push_str(&narrow);
+        input.push_str(&format!("{}\n", "x".repeat(1 << 20)));
+        assert_eq!(100, adaptive_batch_size(input.as_bytes(), 1000));
+
+        assert_eq!(MAX_BATCH_ROWS, adaptive_batch_size(b"", 1000));
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn stage_encoding_serialization() -> Result<()> {
+        let stream = NEXMarkSource::new(1, 1, 10_000, Window::ElementWise).generate_data()?;
+        let (events, _) = stream.select(0, 0).expect("Failed to select event.");
+        let bid_schema = Arc::new(Bid::schema());
+        let auction_schema = Arc::new(Auction::schema());
+
+        // NEXMark Q1 maps each bid, so its payloads carry a single row, while the
+        // payloads of Q4 carry all the auctions and bids of the epoch to the join.
+        let q1 = (
+            event_bytes_to_batch(&events.bids, bid_schema.clone(), 1)[..1].to_vec(),
+            vec![],
+        );
+        let q4 = (
+            event_bytes_to_batch(&events.auctions, auction_schema, 1024),
+            event_bytes_to_batch(&events.bids, bid_schema, 1024),
+        );
+
+        let modes = [
+            ("none", StageEncoding::from(Encoding::None)),
+            ("zstd", StageEncoding::from(Encoding::Zstd)),
+            (
+                "zstd-9",
+                StageEncoding {
+                    level: Some(9),
+                    ..StageEncoding::from(Encoding::Zstd)
+                },
+            ),
+            ("adaptive", StageEncoding::for_stage(true)),
+        ];
+        let uuid = UuidBuilder::new_with_ts("SX72HzqFz1Qij4bP-00-00", 0, 1).get(1);
+        let rounds = 10;
+        for (query, (batch1, batch2), adaptive) in
+            [("q1", &q1, Encoding::None), ("q4", &q4, Encoding::Zstd)]
+        {
+            let num_rows = batch1
+                .iter()
+                .chain(batch2)
+                .map(|b| b.num_rows())
+                .sum::<usize>();
+            for (mode, encoding) in &modes {
+                let now = Instant::now();
+                let mut bytes = vec![];
+                for _ in 0..rounds {
+                    let payload =
+                        to_stage_payload(batch1, batch2, uuid.clone(), false, &[], encoding)?;
+                    bytes = serde_json::to_vec(&payload)?;
+                }
+                let elapsed = now.elapsed() / rounds;
+
+                let payload: Payload = serde_json::from_slice(&bytes)?;
+                if *mode == "adaptive" {
+                    assert_eq!(payload.encoding, adaptive);
+                }
+                let (output1, output2) = payload.to_record_batch()?;
+                assert_eq!(
+                    output1
+                        .iter()
+                        .chain(&output2)
+                        .map(|b| b.num_rows())
+                        .sum::<usize>(),
+                    num_rows
+                );
+                println!(
+                    "{} ({} rows), {}: {:?} per payload, {} bytes",
+                    query,
+                    num_rows,
+                    mode,
+                    elapsed,
+                    bytes.len()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn one_to_many_round_robin() -> Result<()> {
         // define input partitions
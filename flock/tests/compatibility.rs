@@ -0,0 +1,268 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Checks that the current binary still reads the payloads and the cloud
+//! environments written by the previous version of their wire formats (see
+//! `flock::runtime::version`). The fixtures of each version are kept in
+//! `tests/fixtures`. When a format version is bumped, write the fixtures of
+//! the new version with
+//!
+//! ```bash
+//! FLOCK_WRITE_FIXTURES=1 cargo test --test compatibility -- --ignored
+//! ```
+
+use datafusion::arrow::record_batch::RecordBatch;
+use flock::assert_batches_eq;
+use flock::aws::client::FakeCloudClient;
+use flock::datasource::DataSource;
+use flock::encoding::{register_dictionary, train_dictionary, Encoding};
+use flock::error::{FlockError, Result};
+use flock::runtime::broadcast::{broadcast_build_side, build_side_key};
+use flock::runtime::context::{
+    marshal_with_format, unmarshal, CloudFunction, ContextFormat, ExecutionContext,
+};
+use flock::runtime::dictionary::payload_samples;
+use flock::runtime::metadata::S3Pointer;
+use flock::runtime::payload::{Payload, Uuid};
+use flock::runtime::version::WireFormat;
+use flock::transmute::to_payload_with_encoding;
+use serde_json::Value;
+use std::path::PathBuf;
+
+fn fixture(name: &str, version: u16) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(format!("{}_v{}.json", name, version))
+}
+
+/// The Zstd dictionary that the payload fixture of the version is compressed
+/// with, if any.
+fn dictionary_fixture(version: u16) -> PathBuf {
+    fixture("zstd_dict", version).with_extension("bin")
+}
+
+fn read_fixture(name: &str, version: u16) -> String {
+    let path = fixture(name, version);
+    std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("failed to read {}: {}", path.display(), e))
+        .trim()
+        .to_string()
+}
+
+fn readable_versions(format: WireFormat) -> Vec<u16> {
+    (format.oldest_readable()..=format.current()).collect()
+}
+
+fn fixture_uuid() -> Uuid {
+    Uuid {
+        qid:     "q1-1643678938-1".to_string(),
+        seq_num: 0,
+        seq_len: 1,
+    }
+}
+
+/// The batch of the payload fixtures.
+fn fixture_batch() -> RecordBatch {
+    use datafusion::arrow::array::{Int64Array, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use std::sync::Arc;
+
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("c1", DataType::Utf8, false),
+        Field::new("c2", DataType::Int64, false),
+    ]));
+    RecordBatch::try_new(
+        schema,
+        vec![
+            Arc::new(StringArray::from(vec!["a", "b", "c"])),
+            Arc::new(Int64Array::from(vec![1, 2, 3])),
+        ],
+    )
+    .unwrap()
+}
+
+/// The samples to train the Zstd dictionary of the payload fixtures from, i.e.
+/// the Arrow Flight data of the fixture batch with other values.
+fn fixture_samples() -> Vec<Vec<u8>> {
+    use datafusion::arrow::array::Int64Array;
+    use std::sync::Arc;
+
+    let batch = fixture_batch();
+    let batches = (1..=1000)
+        .map(|i| {
+            RecordBatch::try_new(
+                batch.schema(),
+                vec![
+                    batch.column(0).clone(),
+                    Arc::new(Int64Array::from(vec![1 + i, 2 + i, 3 + i])),
+                ],
+            )
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+    payload_samples(&batches)
+}
+
+/// The build side broadcast by the payload fixtures since version 3.
+fn fixture_build_side() -> S3Pointer {
+    S3Pointer {
+        bucket: "flock-lab".to_string(),
+        key:    build_side_key("q1-00", &(fixture_uuid().qid, 0)),
+    }
+}
+
+/// The execution context of the context fixtures.
+fn fixture_context() -> ExecutionContext {
+    ExecutionContext {
+        name: "q1-00".to_string(),
+        next: CloudFunction::Group(("q1-01".to_string(), 8)),
+        region: "us-east-1".to_string(),
+        stats_keys: vec!["c1".to_string()],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn read_payload_fixtures() -> Result<()> {
+    for version in readable_versions(WireFormat::Payload) {
+        // The data frames are compressed with the Zstd dictionary of the query
+        // since version 4, which the functions register before decoding them.
+        if let Ok(dict) = std::fs::read(dictionary_fixture(version)) {
+            register_dictionary(dict)?;
+        }
+        let bytes = read_fixture("payload", version);
+        let payload = Payload::from_slice(bytes.as_bytes())?;
+        assert_eq!(payload.format_version, version);
+        assert_eq!(payload.uuid, fixture_uuid());
+        assert_eq!(payload.datasource, DataSource::Payload(false));
+        assert_eq!(payload.delivery_attempt, 1);
+        if version >= 3 {
+            assert_eq!(payload.broadcast, Some(fixture_build_side()));
+        }
+        if version >= 4 {
+            assert!(matches!(payload.encoding, Encoding::ZstdDict { .. }));
+        }
+
+        // The events of the functions are decoded from JSON values.
+        let value: Value = serde_json::from_str(&bytes)?;
+        assert_eq!(Payload::from_value(value)?, payload);
+
+        let (batches, batches2) = payload.to_record_batch()?;
+        assert!(batches2.is_empty());
+        let expected = vec![
+            "+----+----+",
+            "| c1 | c2 |",
+            "+----+----+",
+            "| a  | 1  |",
+            "| b  | 2  |",
+            "| c  | 3  |",
+            "+----+----+",
+        ];
+        assert_batches_eq!(&expected, &batches);
+    }
+    Ok(())
+}
+
+#[test]
+fn read_context_fixtures() -> Result<()> {
+    let expected = fixture_context();
+    for version in readable_versions(WireFormat::CloudEnvironment) {
+        let ctx = unmarshal(read_fixture("context", version))?;
+        assert_eq!(ctx.name, expected.name);
+        assert_eq!(ctx.next, expected.next);
+        assert_eq!(ctx.region, expected.region);
+        assert_eq!(ctx.stats_keys, expected.stats_keys);
+        assert_eq!(ctx.state_backend.name(), "HashMapStateBackend");
+    }
+    Ok(())
+}
+
+#[test]
+fn reject_future_versions() -> Result<()> {
+    let format = WireFormat::Payload;
+    let mut value: Value = serde_json::from_str(&read_fixture("payload", format.current()))?;
+    value["format_version"] = (format.current() + 1).into();
+    let err = Payload::from_value(value).unwrap_err();
+    assert!(
+        matches!(err, FlockError::FormatVersion { found, .. } if found == format.current() + 1)
+    );
+    assert!(!err.is_retryable());
+
+    let format = WireFormat::CloudEnvironment;
+    let mut value: Value = serde_json::from_str(&read_fixture("context", format.current()))?;
+    value["format_version"] = (format.current() + 1).into();
+    // A future environment may change its fields in a way that fails to parse.
+    value["context"] = "opaque".into();
+    let err = unmarshal(value.to_string()).unwrap_err();
+    assert!(
+        matches!(err, FlockError::FormatVersion { found, .. } if found == format.current() + 1)
+    );
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "Unsupported cloud environment format version {}: this binary writes version {} \
+             and reads versions {} to {}",
+            format.current() + 1,
+            format.current(),
+            format.oldest_readable(),
+            format.current()
+        )
+    );
+
+    Ok(())
+}
+
+/// Writes the fixtures of the current versions of the formats.
+#[tokio::test]
+#[ignore]
+async fn write_fixtures() -> Result<()> {
+    if std::env::var("FLOCK_WRITE_FIXTURES").is_err() {
+        return Ok(());
+    }
+
+    let dict = train_dictionary(&fixture_samples(), 1024)?;
+    let dict_id = register_dictionary(dict.clone())?;
+    std::fs::write(dictionary_fixture(WireFormat::Payload.current()), dict)?;
+    let mut payload = to_payload_with_encoding(
+        &[fixture_batch()],
+        &[fixture_batch()],
+        fixture_uuid(),
+        false,
+        &[],
+        Encoding::ZstdDict { dict_id },
+    )?;
+    payload.payload_id = "6f1c2a4e-0d7b-4b8e-9a51-3c2f8e7d9b10".to_string();
+    payload.delivery_attempt = 1;
+    let build_side = fixture_build_side();
+    broadcast_build_side(
+        &FakeCloudClient::new(),
+        &build_side.bucket,
+        "q1-00",
+        std::slice::from_mut(&mut payload),
+    )
+    .await?;
+    assert_eq!(payload.broadcast, Some(build_side));
+    std::fs::write(
+        fixture("payload", WireFormat::Payload.current()),
+        serde_json::to_string(&payload)? + "\n",
+    )?;
+
+    let env = marshal_with_format(&fixture_context(), Encoding::None, ContextFormat::Json)?;
+    std::fs::write(
+        fixture("context", WireFormat::CloudEnvironment.current()),
+        env + "\n",
+    )?;
+
+    Ok(())
+}
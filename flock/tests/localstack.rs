@@ -0,0 +1,185 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Runs the AWS code paths against LocalStack. The tests are ignored by
+//! default, and they only run if `FLOCK_AWS_ENDPOINT_URL` points to a running
+//! LocalStack, e.g.
+//!
+//! ```bash
+//! docker run -d -p 4566:4566 localstack/localstack
+//! FLOCK_AWS_ENDPOINT_URL=http://localhost:4566 cargo test --test localstack -- --ignored
+//! ```
+
+use bytes::Bytes;
+use flock::aws::{lambda, s3};
+use flock::configs::{
+    aws_endpoint, new_client, FLOCK_CONF, FLOCK_LAMBDA_ASYNC_CALL, FLOCK_S3_BUCKET,
+    FLOCK_S3_X86_64_KEY,
+};
+use flock::error::{FlockError, Result};
+use flock::runtime::context::ExecutionContext;
+use flock::runtime::payload::{Payload, UuidBuilder};
+use flock::state::{S3StateBackend, StateBackend};
+use rusoto_iam::{CreateRoleRequest, Iam, IamClient};
+
+/// An empty zip archive, i.e. only the end of its central directory, which
+/// stands in for the deployment package of the functions.
+const ZIP_STUB: [u8; 22] = [
+    0x50, 0x4b, 0x05, 0x06, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+/// Returns a name that doesn't collide with the previous runs.
+fn unique_name(prefix: &str) -> String {
+    format!("{}-{}", prefix, uuid::Uuid::new_v4().to_simple())
+}
+
+/// Creates the execution role of the functions if it's missing.
+async fn create_role() -> Result<()> {
+    let iam: IamClient = new_client("");
+    let request = CreateRoleRequest {
+        role_name: FLOCK_CONF["aws"]["role"].to_string(),
+        assume_role_policy_document: r#"{"Version":"2012-10-17","Statement":[{"Effect":"Allow","Principal":{"Service":"lambda.amazonaws.com"},"Action":"sts:AssumeRole"}]}"#.to_string(),
+        ..Default::default()
+    };
+    match iam.create_role(request).await {
+        Err(e) if !e.to_string().contains("EntityAlreadyExists") => {
+            Err(FlockError::AWS(e.to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[tokio::test]
+#[ignore]
+async fn s3_state_backend_round_trip() -> Result<()> {
+    if aws_endpoint().is_none() {
+        return Ok(());
+    }
+
+    let bucket = unique_name("flock-state");
+    s3::create_bucket_if_missing(&bucket).await?;
+    assert!(s3::bucket_exists(&bucket).await?);
+
+    let backend = S3StateBackend::new();
+    let mut uuids = UuidBuilder::new_with_ts("q1", 1642991536, 3);
+    let payloads = (0..3)
+        .map(|_| Payload {
+            uuid: uuids.next_uuid(),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    let mut keys = vec![];
+    for (i, payload) in payloads.iter().enumerate() {
+        let key = format!("q1/{:02}", i);
+        backend
+            .write(bucket.clone(), key.clone(), serde_json::to_vec(payload)?)
+            .await?;
+        keys.push(key);
+    }
+    assert_eq!(backend.read(bucket.clone(), keys).await?, payloads);
+
+    let marker = "q1/done".to_string();
+    assert!(
+        !backend
+            .marker_exists(bucket.clone(), marker.clone())
+            .await?
+    );
+    backend.write_marker(bucket.clone(), marker.clone()).await?;
+    assert!(backend.marker_exists(bucket.clone(), marker).await?);
+
+    // The checkpoint is only written at the version that it was read at.
+    let key = "q1/checkpoint".to_string();
+    assert!(backend
+        .read_checkpoint(bucket.clone(), key.clone())
+        .await?
+        .is_none());
+    assert!(
+        backend
+            .write_checkpoint(bucket.clone(), key.clone(), b"1".to_vec(), None)
+            .await?
+    );
+    let checkpoint = backend
+        .read_checkpoint(bucket.clone(), key.clone())
+        .await?
+        .unwrap();
+    assert_eq!(checkpoint.bytes, b"1".to_vec());
+    assert!(
+        !backend
+            .write_checkpoint(bucket.clone(), key.clone(), b"2".to_vec(), None)
+            .await?
+    );
+    assert!(
+        backend
+            .write_checkpoint(bucket.clone(), key, b"2".to_vec(), Some(checkpoint.version))
+            .await?
+    );
+
+    s3::delete_bucket(&bucket).await?;
+    assert!(!s3::bucket_exists(&bucket).await?);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn create_and_invoke_function() -> Result<()> {
+    if aws_endpoint().is_none() {
+        return Ok(());
+    }
+
+    s3::create_bucket_if_missing(&FLOCK_S3_BUCKET).await?;
+    s3::put_object(&FLOCK_S3_BUCKET, &FLOCK_S3_X86_64_KEY, ZIP_STUB.to_vec()).await?;
+    let (size, _) = s3::head_object(&FLOCK_S3_BUCKET, &FLOCK_S3_X86_64_KEY).await?;
+    assert_eq!(size, ZIP_STUB.len() as u64);
+    create_role().await?;
+
+    let ctx = ExecutionContext {
+        name: unique_name("flock-function"),
+        ..Default::default()
+    };
+    assert_eq!(
+        lambda::create_function(&ctx, 128, "x86_64").await?,
+        ctx.name
+    );
+    assert!(lambda::function_exists(&ctx.name).await);
+
+    // The asynchronous invocations are accepted before the stub fails to run.
+    let payload = Some(Bytes::from(serde_json::to_vec(&Payload::default())?));
+    let response = lambda::invoke_function(&ctx.name, &FLOCK_LAMBDA_ASYNC_CALL, payload).await?;
+    assert_eq!(response.status_code, Some(202));
+
+    lambda::delete_function(&ctx.name).await?;
+    assert!(!lambda::function_exists(&ctx.name).await);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn invoke_missing_function() -> Result<()> {
+    if aws_endpoint().is_none() {
+        return Ok(());
+    }
+
+    let name = unique_name("flock-missing");
+    assert!(!lambda::function_exists(&name).await);
+    let result = lambda::invoke_function(&name, &FLOCK_LAMBDA_ASYNC_CALL, None).await;
+    assert!(matches!(result, Err(FlockError::AWS(_))), "{:?}", result);
+    assert!(matches!(
+        lambda::function_arn(&name).await,
+        Err(FlockError::AWS(_))
+    ));
+    assert!(matches!(
+        lambda::delete_function(&name).await,
+        Err(FlockError::AWS(_))
+    ));
+    Ok(())
+}
@@ -0,0 +1,104 @@
+// Copyright (c) 2020-present, UMD Database Group.
+//
+// This program is free software: you can use, redistribute, and/or modify
+// it under the terms of the GNU Affero General Public License, version 3
+// or later ("AGPL"), as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or
+// FITNESS FOR A PARTICULAR PURPOSE.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Checks that streaming the NEXMark epochs holds a bounded amount of memory,
+//! no matter how many epochs are generated. The allocations are counted by a
+//! global allocator, so the test lives in its own binary.
+
+use flock::datasource::nexmark::NEXMarkSource;
+use flock::stream::Window;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// An allocator that tracks the live and the peak number of bytes.
+struct CountingAllocator;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let live = LIVE.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(live, Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        LIVE.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            if new_size > layout.size() {
+                let grown = new_size - layout.size();
+                let live = LIVE.fetch_add(grown, Ordering::SeqCst) + grown;
+                PEAK.fetch_max(live, Ordering::SeqCst);
+            } else {
+                LIVE.fetch_sub(layout.size() - new_size, Ordering::SeqCst);
+            }
+        }
+        new_ptr
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn stream_epochs_with_bounded_memory() {
+    // Warms up the thread pool, so its allocations are not counted.
+    NEXMarkSource::new(1, 2, 100, Window::ElementWise)
+        .stream_epochs(0)
+        .for_each(drop);
+
+    let source = NEXMarkSource::new(60, 2, 4_000, Window::ElementWise);
+    let epochs = source.stream_epochs(0);
+
+    let base = LIVE.load(Ordering::SeqCst);
+    PEAK.store(base, Ordering::SeqCst);
+
+    let mut num_epochs = 0;
+    let mut total_bytes = 0;
+    let mut max_epoch_bytes = 0;
+    for (_, epoch) in epochs {
+        let bytes = epoch
+            .iter()
+            .map(|(p, a, b)| p.0.len() + a.0.len() + b.0.len())
+            .sum::<usize>();
+        total_bytes += bytes;
+        max_epoch_bytes = max_epoch_bytes.max(bytes);
+        num_epochs += 1;
+    }
+    let peak = PEAK.load(Ordering::SeqCst) - base;
+
+    assert_eq!(num_epochs, 60);
+    // The buffers grow by doubling, so an epoch may take up to twice its bytes
+    // while it is generated, but never the bytes of the whole stream.
+    assert!(
+        peak <= 3 * max_epoch_bytes,
+        "peak: {}, epoch: {}",
+        peak,
+        max_epoch_bytes
+    );
+    assert!(
+        peak * 10 < total_bytes,
+        "peak: {}, total: {}",
+        peak,
+        total_bytes
+    );
+}
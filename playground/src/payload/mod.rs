@@ -178,7 +178,7 @@ mod tests {
         // Arrrow Flight Payload.
         {
             let now = Instant::now();
-            let payload = to_payload(&batches_1, &batches_2, Uuid::default(), true);
+            let payload = to_payload(&batches_1, &batches_2, Uuid::default(), true).unwrap();
             let ser_payload = serde_json::to_vec(&payload).unwrap();
             println!(
                 "Arrow Flight Payload: {} bytes, Serialize: {} ms",
@@ -188,7 +188,7 @@ mod tests {
 
             let now = Instant::now();
             let payload: Payload = serde_json::from_slice(&ser_payload).unwrap();
-            let (batches_1, batches_2) = payload.to_record_batch();
+            let (batches_1, batches_2) = payload.to_record_batch().unwrap();
             println!(
                 "Arrow Flight Payload: {} bytes, Deserialize: {} ms",
                 ser_payload.len(),